@@ -1,5 +1,5 @@
 #!/bin/bash
-# Build the test EFI application
+# Build the test EFI applications (hello and the self-test)
 #
 # Usage: ./scripts/build-test-app.sh
 
@@ -18,6 +18,14 @@ mkdir -p "$PROJECT_DIR/test"
 cp "$PROJECT_DIR/test/hello/target/x86_64-unknown-uefi/release/hello-efi.efi" \
    "$PROJECT_DIR/test/hello.efi"
 
+echo "Building self-test EFI application..."
+
+cd "$PROJECT_DIR/test/selftest"
+cargo build --release
+
+cp "$PROJECT_DIR/test/selftest/target/x86_64-unknown-uefi/release/selftest.efi" \
+   "$PROJECT_DIR/test/selftest.efi"
+
 echo ""
-echo "Test application built: $PROJECT_DIR/test/hello.efi"
-ls -la "$PROJECT_DIR/test/hello.efi"
+echo "Test applications built:"
+ls -la "$PROJECT_DIR/test/hello.efi" "$PROJECT_DIR/test/selftest.efi"
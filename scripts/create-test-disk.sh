@@ -82,6 +82,14 @@ else
     fi
 fi
 
+# Install the self-test; CrabEFI offers it as "CrabEFI Diagnostics"
+SELFTEST_APP="$PROJECT_DIR/test/selftest.efi"
+if [ -f "$SELFTEST_APP" ]; then
+    echo "Installing self-test application: $SELFTEST_APP"
+    sudo mkdir -p "$MOUNT_POINT/EFI/CRABEFI"
+    sudo cp "$SELFTEST_APP" "$MOUNT_POINT/EFI/CRABEFI/SELFTEST.EFI"
+fi
+
 # Create a startup script for UEFI Shell
 cat << 'EOF' | sudo tee "$MOUNT_POINT/startup.nsh" > /dev/null
 @echo -off
@@ -0,0 +1,79 @@
+#!/usr/bin/env python3
+# Wrap the CrabEFI ELF into a bzImage for loaders using the Linux 32-bit boot
+# protocol, like GRUB's `linux` command
+#
+# Usage: ./scripts/make-bzimage.py [crabefi.elf] [crabefi.bzImage]
+#
+# The image has no real-mode setup code: loaders that run it, like QEMU's
+# -kernel with a bzImage, stop there. Boot the ELF through its Multiboot
+# header with those instead.
+
+import os
+import struct
+import subprocess
+import sys
+import tempfile
+
+PROJECT_DIR = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+ELF = os.path.join(PROJECT_DIR, "target/x86_64-unknown-none/release/crabefi")
+
+# Where the protected-mode part is loaded
+LOAD_ADDRESS = 0x100000
+# Boot sector plus one setup sector
+SETUP_SECTS = 1
+# Boot protocol 2.12: has pref_address and init_size
+VERSION = 0x020C
+LOADED_HIGH = 0x01
+
+
+def symbols(elf):
+    output = subprocess.run(["nm", elf], check=True, capture_output=True, text=True).stdout
+    syms = {}
+    for line in output.splitlines():
+        fields = line.split()
+        # Undefined symbols have no value
+        if len(fields) == 3:
+            syms[fields[2]] = int(fields[0], 16)
+    return syms
+
+
+def main():
+    elf = sys.argv[1] if len(sys.argv) > 1 else ELF
+    output = sys.argv[2] if len(sys.argv) > 2 else os.path.splitext(elf)[0] + ".bzImage"
+
+    syms = symbols(elf)
+    if syms["__runtime_code_start"] != LOAD_ADDRESS:
+        sys.exit("CrabEFI isn't linked at {:#x}".format(LOAD_ADDRESS))
+
+    # Everything up to the page tables, the entry code clears those and BSS
+    with tempfile.NamedTemporaryFile() as flat:
+        subprocess.run(["objcopy", "-O", "binary", elf, flat.name], check=True)
+        kernel = flat.read()
+    size = syms["_page_tables_start"] - LOAD_ADDRESS
+    kernel = kernel[:size].ljust(size, b"\0")
+
+    setup = bytearray(512 * (SETUP_SECTS + 1))
+    struct.pack_into("<BHI", setup, 0x1F1, SETUP_SECTS, 0, (len(kernel) + 15) // 16)
+    struct.pack_into("<HH", setup, 0x1FA, 0xFFFF, 0)  # vid_mode, root_dev
+    struct.pack_into("<H", setup, 0x1FE, 0xAA55)  # boot_flag
+    # Real-mode entry: jump over the header to cli; hlt; jmp $-1
+    setup[0x200:0x202] = b"\xeb\x66"
+    setup[0x268:0x26C] = b"\xfa\xf4\xeb\xfd"
+    setup[0x202:0x206] = b"HdrS"
+    struct.pack_into("<H", setup, 0x206, VERSION)
+    struct.pack_into("<B", setup, 0x211, LOADED_HIGH)  # loadflags
+    struct.pack_into("<I", setup, 0x214, syms["linux_start"])  # code32_start
+    struct.pack_into("<I", setup, 0x22C, 0x37FFFFFF)  # initrd_addr_max
+    struct.pack_into("<IBBH", setup, 0x230, 0x1000, 0, 12, 0)  # not relocatable
+    struct.pack_into("<I", setup, 0x238, 255)  # cmdline_size
+    struct.pack_into("<Q", setup, 0x258, LOAD_ADDRESS)  # pref_address
+    struct.pack_into("<I", setup, 0x260, syms["_end"] - LOAD_ADDRESS)  # init_size
+
+    with open(output, "wb") as f:
+        f.write(setup)
+        f.write(kernel)
+    print("Wrote {} ({} KiB)".format(output, (len(setup) + len(kernel)) // 1024))
+
+
+if __name__ == "__main__":
+    main()
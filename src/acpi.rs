@@ -0,0 +1,393 @@
+//! ACPI table lookup
+//!
+//! coreboot provides the ACPI tables; CrabEFI only installs them for the OS
+//! and reads the few it needs itself: the FADT and FACS for S3 resume, the
+//! MADT for the processor list and the SRAT for hot-pluggable memory. The
+//! tables CrabEFI generates, like the BGRT of the boot logo, are added with
+//! [`add_table`].
+
+use crate::efi::allocator::{self, AllocateType, MemoryType, PAGE_SIZE};
+use crate::state::MAX_PROCESSORS;
+use core::ops::Range;
+use heapless::Vec;
+use r_efi::efi;
+use spin::Mutex;
+
+/// Size of the common table header
+const HEADER_SIZE: usize = 36;
+
+/// Size of an ACPI 2.0 RSDP, the first 20 bytes are the ACPI 1.0 RSDP
+const RSDP_SIZE: usize = 36;
+
+/// Address and contents of the RSDP coreboot built, saved before
+/// [`add_table`] first changes it
+static ORIGINAL_RSDP: Mutex<Option<(u64, [u8; RSDP_SIZE])>> = Mutex::new(None);
+
+/// MADT: offset of the interrupt controller structures
+const MADT_ENTRIES_OFFSET: usize = 44;
+
+/// MADT structure types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_X2APIC: u8 = 9;
+
+/// MADT processor flag: the processor is enabled
+const MADT_ENABLED: u32 = 1 << 0;
+
+/// SRAT: offset of the affinity structures
+const SRAT_ENTRIES_OFFSET: usize = 48;
+
+/// SRAT Memory Affinity structure type and size
+const SRAT_MEMORY_AFFINITY: u8 = 1;
+const SRAT_MEMORY_AFFINITY_SIZE: usize = 40;
+
+/// SRAT memory flags: the structure is used, the memory is hot-pluggable
+const SRAT_ENABLED: u32 = 1 << 0;
+const SRAT_HOT_PLUGGABLE: u32 = 1 << 1;
+
+/// Most hot-pluggable ranges taken from the SRAT
+pub const MAX_HOT_PLUGGABLE: usize = 32;
+
+/// Read a possibly unaligned value from physical memory
+///
+/// # Safety
+///
+/// `address` must be mapped and readable for `size_of::<T>()` bytes.
+unsafe fn read<T: Copy>(address: u64) -> T {
+    unsafe { core::ptr::read_unaligned(address as *const T) }
+}
+
+/// Root table of `rsdp`: the XSDT if there is one, otherwise the RSDT
+///
+/// Returns the table address and the size of its entries.
+///
+/// # Safety
+///
+/// `rsdp` must point to the ACPI RSDP of this boot.
+unsafe fn root_table(rsdp: u64) -> Option<(u64, u64)> {
+    unsafe {
+        if read::<[u8; 8]>(rsdp) != *b"RSD PTR " {
+            return None;
+        }
+
+        let revision = read::<u8>(rsdp + 15);
+        let xsdt = if revision >= 2 {
+            read::<u64>(rsdp + 24)
+        } else {
+            0
+        };
+        let (table, entry_size) = if xsdt != 0 {
+            (xsdt, 8)
+        } else {
+            (read::<u32>(rsdp + 16) as u64, 4)
+        };
+        (table != 0).then_some((table, entry_size))
+    }
+}
+
+/// Read entry `index` of a root table
+///
+/// # Safety
+///
+/// `table` must point to a root table with entries of `entry_size` bytes.
+unsafe fn root_entry(table: u64, entry_size: u64, index: u64) -> u64 {
+    let entry = table + HEADER_SIZE as u64 + index * entry_size;
+    unsafe {
+        if entry_size == 8 {
+            read::<u64>(entry)
+        } else {
+            read::<u32>(entry) as u64
+        }
+    }
+}
+
+/// Find an ACPI table by signature through the RSDT or XSDT
+///
+/// # Safety
+///
+/// `rsdp` must point to the ACPI RSDP of this boot.
+pub unsafe fn find_table(rsdp: u64, signature: &[u8; 4]) -> Option<u64> {
+    unsafe {
+        let (table, entry_size) = root_table(rsdp)?;
+        let length = read::<u32>(table + 4) as u64;
+        let entries = length.saturating_sub(HEADER_SIZE as u64) / entry_size;
+        (0..entries).find_map(|i| {
+            let address = root_entry(table, entry_size, i);
+            (address != 0 && read::<[u8; 4]>(address) == *signature).then_some(address)
+        })
+    }
+}
+
+/// Set the checksum byte at `offset` so that `bytes` sum to zero
+pub fn set_checksum(bytes: &mut [u8], offset: usize) {
+    bytes[offset] = 0;
+    let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    bytes[offset] = sum.wrapping_neg();
+}
+
+/// List `table` in the root table of `rsdp`
+///
+/// The root table coreboot built has no room for another entry, so it is
+/// copied with the entry added, or with an older table of the same
+/// signature replaced, and the RSDP is pointed at the copy. SSDTs are
+/// always added.
+///
+/// # Safety
+///
+/// `rsdp` must point to the ACPI RSDP of this boot and `table` to a
+/// complete ACPI table below 4 GiB that stays in place.
+pub unsafe fn add_table(rsdp: u64, table: u64) -> efi::Status {
+    unsafe {
+        let Some((root, entry_size)) = root_table(rsdp) else {
+            return efi::Status::NOT_FOUND;
+        };
+        let signature = read::<[u8; 4]>(table);
+        let length = read::<u32>(root + 4) as u64;
+        let entries = length.saturating_sub(HEADER_SIZE as u64) / entry_size;
+        // SSDTs are the one table that may be listed more than once
+        let replaced = (0..entries).filter(|_| signature != *b"SSDT").find(|&i| {
+            let address = root_entry(root, entry_size, i);
+            address != 0 && read::<[u8; 4]>(address) == signature
+        });
+        let new_entries = if replaced.is_some() {
+            entries
+        } else {
+            entries + 1
+        };
+        let new_length = HEADER_SIZE as u64 + new_entries * entry_size;
+
+        let mut copy = u32::MAX as u64;
+        let status = allocator::allocate_pages(
+            AllocateType::AllocateMaxAddress,
+            MemoryType::AcpiReclaimMemory,
+            new_length.div_ceil(PAGE_SIZE),
+            &mut copy,
+        );
+        if status != efi::Status::SUCCESS {
+            return status;
+        }
+
+        let bytes = core::slice::from_raw_parts_mut(copy as *mut u8, new_length as usize);
+        bytes.fill(0);
+        bytes[..HEADER_SIZE].copy_from_slice(&table_bytes(root)[..HEADER_SIZE]);
+        bytes[4..8].copy_from_slice(&(new_length as u32).to_le_bytes());
+        for i in 0..new_entries {
+            let address = if Some(i) == replaced || i == entries {
+                table
+            } else {
+                root_entry(root, entry_size, i)
+            };
+            let offset = HEADER_SIZE + (i * entry_size) as usize;
+            bytes[offset..offset + entry_size as usize]
+                .copy_from_slice(&address.to_le_bytes()[..entry_size as usize]);
+        }
+        set_checksum(bytes, 9);
+
+        ORIGINAL_RSDP
+            .lock()
+            .get_or_insert_with(|| (rsdp, read::<[u8; RSDP_SIZE]>(rsdp)));
+
+        // Point the RSDP at the copy: the checksum covers the first 20
+        // bytes, the extended checksum the whole ACPI 2.0 structure
+        if entry_size == 8 {
+            core::ptr::write_unaligned((rsdp + 24) as *mut u64, copy);
+        } else {
+            core::ptr::write_unaligned((rsdp + 16) as *mut u32, copy as u32);
+        }
+        set_checksum(core::slice::from_raw_parts_mut(rsdp as *mut u8, 20), 8);
+        if read::<u8>(rsdp + 15) >= 2 {
+            let length = read::<u32>(rsdp + 20) as usize;
+            set_checksum(core::slice::from_raw_parts_mut(rsdp as *mut u8, length), 32);
+        }
+
+        log::info!(
+            "ACPI: added {} at {:#x}, root table moved to {:#x}",
+            core::str::from_utf8(&signature).unwrap_or("????"),
+            table,
+            copy
+        );
+        efi::Status::SUCCESS
+    }
+}
+
+/// Undo [`add_table`], pointing the RSDP back at coreboot's root table
+///
+/// For handing the tables to another coreboot payload, which considers the
+/// memory of the added tables free.
+///
+/// # Safety
+///
+/// Nothing may use the RSDP while it is restored.
+pub unsafe fn restore_rsdp() {
+    let Some((rsdp, original)) = ORIGINAL_RSDP.lock().take() else {
+        return;
+    };
+    let size = if original[15] >= 2 { RSDP_SIZE } else { 20 };
+    unsafe {
+        core::ptr::copy_nonoverlapping(original.as_ptr(), rsdp as *mut u8, size);
+    }
+}
+
+/// Get a table as a byte slice, using the length from its header
+///
+/// # Safety
+///
+/// `table` must point to an ACPI table that stays mapped and unchanged.
+pub unsafe fn table_bytes(table: u64) -> &'static [u8] {
+    unsafe {
+        let length = read::<u32>(table + 4) as usize;
+        core::slice::from_raw_parts(table as *const u8, length)
+    }
+}
+
+/// APIC IDs of the enabled processors listed in the MADT, in table order
+///
+/// A processor described by both a Local APIC and a Local x2APIC structure
+/// is listed once.
+pub fn madt_apic_ids(madt: &[u8]) -> Vec<u32, MAX_PROCESSORS> {
+    let mut ids = Vec::new();
+    let mut offset = MADT_ENTRIES_OFFSET;
+    while let Some(header) = madt.get(offset..offset + 2) {
+        let (kind, len) = (header[0], header[1] as usize);
+        let Some(entry) = madt.get(offset..offset + len).filter(|_| len >= 2) else {
+            break;
+        };
+        offset += len;
+
+        let (id, flags) = match (kind, entry.len()) {
+            (MADT_LOCAL_APIC, 8..) => (
+                entry[3] as u32,
+                u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]),
+            ),
+            (MADT_LOCAL_X2APIC, 16..) => (
+                u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]),
+                u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]),
+            ),
+            _ => continue,
+        };
+        if flags & MADT_ENABLED != 0 && !ids.contains(&id) && ids.push(id).is_err() {
+            log::warn!("MADT: more than {} processors", MAX_PROCESSORS);
+            break;
+        }
+    }
+    ids
+}
+
+/// Memory ranges the SRAT marks hot-pluggable, in table order
+pub fn srat_hot_pluggable(srat: &[u8]) -> Vec<Range<u64>, MAX_HOT_PLUGGABLE> {
+    let le_u32 = |b: &[u8], at: usize| u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]]);
+    let mut ranges = Vec::new();
+    let mut offset = SRAT_ENTRIES_OFFSET;
+    while let Some(header) = srat.get(offset..offset + 2) {
+        let (kind, len) = (header[0], header[1] as usize);
+        let Some(entry) = srat.get(offset..offset + len).filter(|_| len >= 2) else {
+            break;
+        };
+        offset += len;
+
+        if kind != SRAT_MEMORY_AFFINITY || len < SRAT_MEMORY_AFFINITY_SIZE {
+            continue;
+        }
+        let flags = le_u32(entry, 28);
+        if flags & (SRAT_ENABLED | SRAT_HOT_PLUGGABLE) != SRAT_ENABLED | SRAT_HOT_PLUGGABLE {
+            continue;
+        }
+        let base = le_u32(entry, 8) as u64 | (le_u32(entry, 12) as u64) << 32;
+        let length = le_u32(entry, 16) as u64 | (le_u32(entry, 20) as u64) << 32;
+        if length == 0 {
+            continue;
+        }
+        if ranges.push(base..base.saturating_add(length)).is_err() {
+            log::warn!("SRAT: more than {} hot-pluggable ranges", MAX_HOT_PLUGGABLE);
+            break;
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_enabled_processors() {
+        let mut madt = std::vec![0u8; MADT_ENTRIES_OFFSET];
+        // BSP and one AP, a disabled processor, an x2APIC duplicate, an I/O
+        // APIC and a high x2APIC ID
+        madt.extend([MADT_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+        madt.extend([MADT_LOCAL_APIC, 8, 1, 2, 1, 0, 0, 0]);
+        madt.extend([MADT_LOCAL_APIC, 8, 2, 4, 0, 0, 0, 0]);
+        madt.extend([
+            MADT_LOCAL_X2APIC,
+            16,
+            0,
+            0,
+            2,
+            0,
+            0,
+            0,
+            1,
+            0,
+            0,
+            0,
+            1,
+            0,
+            0,
+            0,
+        ]);
+        madt.extend([1, 12, 0, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0]);
+        madt.extend([
+            MADT_LOCAL_X2APIC,
+            16,
+            0,
+            0,
+            0,
+            1,
+            0,
+            0,
+            1,
+            0,
+            0,
+            0,
+            3,
+            0,
+            0,
+            0,
+        ]);
+        // Truncated structure
+        madt.extend([MADT_LOCAL_APIC, 8, 3]);
+
+        assert_eq!(madt_apic_ids(&madt).as_slice(), [0, 2, 0x100]);
+    }
+
+    #[test]
+    fn lists_hot_pluggable_memory() {
+        let memory = |base: u64, length: u64, flags: u32| {
+            let mut entry = std::vec![SRAT_MEMORY_AFFINITY, SRAT_MEMORY_AFFINITY_SIZE as u8];
+            entry.resize(8, 0);
+            entry.extend(base.to_le_bytes());
+            entry.extend(length.to_le_bytes());
+            entry.extend([0; 4]);
+            entry.extend(flags.to_le_bytes());
+            entry.resize(SRAT_MEMORY_AFFINITY_SIZE, 0);
+            entry
+        };
+        let mut srat = std::vec![0u8; SRAT_ENTRIES_OFFSET];
+        // A processor affinity structure, boot node memory, a hot-pluggable
+        // node above 4 GiB and a disabled hot-pluggable one
+        srat.extend([0, 16, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        srat.extend(memory(0, 0x8000_0000, SRAT_ENABLED));
+        srat.extend(memory(
+            0x10_0000_0000,
+            0x4_0000_0000,
+            SRAT_ENABLED | SRAT_HOT_PLUGGABLE,
+        ));
+        srat.extend(memory(0x20_0000_0000, 0x1000, SRAT_HOT_PLUGGABLE));
+        // Truncated structure
+        srat.extend([SRAT_MEMORY_AFFINITY, 40, 0]);
+
+        assert_eq!(
+            srat_hot_pluggable(&srat).as_slice(),
+            [0x10_0000_0000..0x14_0000_0000]
+        );
+    }
+}
@@ -1,8 +1,12 @@
 //! Assembly entry point for CrabEFI
 //!
 //! This module contains the 32-bit to 64-bit transition code using global_asm!.
-//! Coreboot calls payloads in 32-bit protected mode.
+//! Coreboot calls payloads in 32-bit protected mode. Multiboot and Multiboot2
+//! headers and a Linux 32-bit boot protocol entry also let QEMU's `-kernel`
+//! and GRUB start CrabEFI without coreboot, see
+//! [`fallback`](crate::coreboot::fallback).
 
+#[cfg(not(feature = "std"))]
 use core::arch::global_asm;
 
 // Static page tables in BSS - will be initialized at runtime
@@ -24,6 +28,11 @@ impl PageTable {
 #[unsafe(link_section = ".page_tables")]
 pub static mut PML4: PageTable = PageTable::empty();
 
+/// Top level table with 5-level paging, its first entry points to PML4
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".page_tables")]
+pub static mut PML5: PageTable = PageTable::empty();
+
 #[unsafe(no_mangle)]
 #[unsafe(link_section = ".page_tables")]
 pub static mut PDPT: PageTable = PageTable::empty();
@@ -59,21 +68,93 @@ pub static GDT64: Gdt64 = Gdt64 {
 };
 
 // Assembly entry point - Intel syntax
+//
+// Left out of host builds (`std` feature), where `_start` belongs to the C runtime.
+#[cfg(not(feature = "std"))]
 global_asm!(
     r#"
 .section .entry32, "ax"
 .code32
 
+// Multiboot header, the address fields let loaders that only take 32-bit
+// ELF images load the image as a flat binary
+.align 4
+multiboot_header:
+    .long 0x1BADB002                   // Magic
+    .long 0x00010002                   // Flags: memory info, address fields
+    .long -(0x1BADB002 + 0x00010002)   // Checksum
+    .long multiboot_header             // header_addr
+    .long multiboot_header             // load_addr
+    .long _page_tables_start           // load_end_addr
+    .long _end                         // bss_end_addr
+    .long multiboot_start              // entry_addr
+
+// Multiboot2 header, with the same address fields
+.align 8
+multiboot2_header:
+    .long 0xE85250D6                   // Magic
+    .long 0                            // Architecture: i386 protected mode
+    .long multiboot2_header_end - multiboot2_header
+    .long -(0xE85250D6 + (multiboot2_header_end - multiboot2_header))
+    .short 2, 0                        // Address tag
+    .long 24
+    .long multiboot2_header            // header_addr
+    .long __runtime_code_start         // load_addr
+    .long _page_tables_start           // load_end_addr
+    .long _end                         // bss_end_addr
+    .short 3, 0                        // Entry address tag
+    .long 12
+    .long multiboot_start              // entry_addr
+    .long 0                            // Padding to 8 bytes
+    .short 0, 0                        // End tag
+    .long 8
+multiboot2_header_end:
+
+// Both Multiboot entries: EAX holds the magic, EBX the information structure
+multiboot_start:
+    cli
+    mov ebp, eax
+    mov esi, ebx
+    xor ebx, ebx              // No coreboot table
+    lea esp, [_stack_top]
+    jmp .Lentry
+
+// Linux 32-bit boot protocol entry (code32_start): ESI holds the boot
+// parameters, see scripts/make-bzimage.py
+.global linux_start
+linux_start:
+    cli
+    mov ebp, {linux_magic}
+
+    // Unlike Multiboot loaders, Linux ones don't clear what follows the
+    // image, and only some page table entries are set below
+    cld
+    lea edi, [_page_tables_start]
+    lea ecx, [_page_tables_end]
+    sub ecx, edi
+    shr ecx, 2
+    xor eax, eax
+    rep stosd
+
+    xor ebx, ebx              // No coreboot table
+    lea esp, [_stack_top]
+    jmp .Lentry
+
 .global _start
 _start:
     cli
 
     // Save coreboot table pointer from stack
     mov ebx, [esp + 4]
+    xor ebp, ebp              // No other loader's information
+    xor esi, esi
 
+.Lentry:
     // Check for long mode support
+    push ebx                  // CPUID clobbers the coreboot table pointer
     mov eax, 0x80000001
     cpuid
+    pop ebx
     test edx, 0x20000000      // Bit 29 = Long Mode
     jz .Lno_long_mode
 
@@ -121,9 +202,45 @@ _start:
     or eax, 0x20              // CR4.PAE (bit 5)
     mov cr4, eax
 
+    // With the `la57` feature, use 5-level paging if the CPU has it
+    mov eax, {la57}
+    test eax, eax
+    jz .Lfour_level
+    push ebx                  // CPUID clobbers the coreboot table pointer
+    xor eax, eax
+    cpuid
+    cmp eax, 7
+    jb .Lno_la57
+    mov eax, 7
+    xor ecx, ecx
+    cpuid
+    test ecx, 0x10000         // Bit 16 = LA57
+    jz .Lno_la57
+    pop ebx
+
+    // Set up PML5[0] -> PML4
+    lea edi, [PML5]
+    lea eax, [PML4]
+    or eax, 0x03              // Present + Writable
+    mov [edi], eax
+    mov dword ptr [edi + 4], 0
+
+    mov eax, cr4
+    or eax, 0x1000            // CR4.LA57 (bit 12)
+    mov cr4, eax
+
+    // Load PML5 into CR3
+    lea eax, [PML5]
+    mov cr3, eax
+    jmp .Lpaging_root_loaded
+
+.Lno_la57:
+    pop ebx
+.Lfour_level:
     // Load PML4 into CR3
     lea eax, [PML4]
     mov cr3, eax
+.Lpaging_root_loaded:
 
     // Enable long mode in EFER MSR
     mov ecx, 0xC0000080       // EFER MSR
@@ -207,6 +324,8 @@ long_mode_start:
     xor rax, rax
     mov eax, edi
     mov rdi, rax
+    mov esi, esi              // Loader information, zero-extended
+    mov edx, ebp              // Loader magic
 
     // Call Rust
     call rust_main
@@ -214,5 +333,7 @@ long_mode_start:
 .Lhalt:
     hlt
     jmp .Lhalt
-"#
+"#,
+    la57 = const cfg!(feature = "la57") as u32,
+    linux_magic = const crate::coreboot::fallback::LINUX_MAGIC,
 );
@@ -60,6 +60,9 @@ struct IdtPointer {
 /// The IDT - 256 entries for all possible interrupts
 static mut IDT: [IdtEntry; 256] = [IdtEntry::empty(); 256];
 
+/// The IDT of the APs, without interrupt stacks as they have no TSS
+static mut AP_IDT: [IdtEntry; 256] = [IdtEntry::empty(); 256];
+
 /// Exception names for logging
 static EXCEPTION_NAMES: [&str; 32] = [
     "Division Error (#DE)",
@@ -125,20 +128,68 @@ pub fn init() {
         (*idt)[20].set_handler(exception_20 as *const () as u64);
         (*idt)[21].set_handler(exception_21_ec as *const () as u64);
 
-        // Load the IDT
-        let idt_ptr = IdtPointer {
-            limit: (core::mem::size_of::<[IdtEntry; 256]>() - 1) as u16,
-            base: idt as u64,
-        };
+        // A stack overflow ends in a double fault, which can't use the
+        // exhausted stack
+        *addr_of_mut!(AP_IDT) = *idt;
+        super::stack::init();
+        (*idt)[2].ist = super::stack::NMI_IST;
+        (*idt)[8].ist = super::stack::DOUBLE_FAULT_IST;
+    }
+    load(addr_of_mut!(IDT));
+
+    log::info!("IDT initialized with exception handlers");
+}
+
+/// Load the IDT set up by [`init`] on an application processor
+pub fn load_ap() {
+    load(addr_of_mut!(AP_IDT));
+}
 
+/// Load `idt` on the current processor
+fn load(idt: *mut [IdtEntry; 256]) {
+    let idt_ptr = IdtPointer {
+        limit: (core::mem::size_of::<[IdtEntry; 256]>() - 1) as u16,
+        base: idt as u64,
+    };
+
+    unsafe {
         asm!("lidt [{}]", in(reg) &idt_ptr, options(nostack));
     }
+}
 
-    log::info!("IDT initialized with exception handlers");
+/// Register state saved by the exception entry stubs
+///
+/// The layout mirrors the push order of the stubs below, followed by the
+/// frame the CPU pushes on exception entry.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
 }
 
 /// Read CR2 (page fault linear address)
-fn read_cr2() -> u64 {
+pub fn read_cr2() -> u64 {
     let value: u64;
     unsafe {
         asm!("mov {}, cr2", out(reg) value, options(nostack));
@@ -146,45 +197,36 @@ fn read_cr2() -> u64 {
     value
 }
 
-/// Common exception handler - logs and halts
-#[unsafe(no_mangle)]
-extern "C" fn exception_handler(vector: u64, error_code: u64, rip: u64, cs: u64, rflags: u64) {
-    let name = if vector < 32 {
+/// Get the name of an exception vector
+pub fn exception_name(vector: u64) -> &'static str {
+    if vector < 32 {
         EXCEPTION_NAMES[vector as usize]
     } else {
         "Unknown"
-    };
-
-    log::error!("==================== CPU EXCEPTION ====================");
-    log::error!("Exception: {} (vector {})", name, vector);
-    log::error!("Error code: {:#x}", error_code);
-    log::error!("RIP: {:#x}, CS: {:#x}", rip, cs);
-    log::error!("RFLAGS: {:#x}", rflags);
+    }
+}
 
-    if vector == 14 {
-        // Page fault - show CR2 (faulting address)
-        let cr2 = read_cr2();
-        log::error!("CR2 (fault address): {:#x}", cr2);
-        log::error!(
-            "Page fault flags: {} {} {}",
-            if error_code & 1 != 0 {
-                "PRESENT"
-            } else {
-                "NOT_PRESENT"
-            },
-            if error_code & 2 != 0 { "WRITE" } else { "READ" },
-            if error_code & 4 != 0 {
-                "USER"
-            } else {
-                "KERNEL"
-            }
-        );
+/// Common exception handler
+///
+/// Reports the crash and halts. With the `gdbstub` feature, breakpoints and
+/// single-step traps go straight to the debugger, and fatal exceptions enter
+/// it after the crash report; the entry stub resumes with the (possibly
+/// modified) frame once the debugger returns.
+#[unsafe(no_mangle)]
+extern "C" fn exception_handler(frame: &mut ExceptionFrame) {
+    #[cfg(feature = "gdbstub")]
+    if matches!(frame.vector, 1 | 3) {
+        crate::gdbstub::handle_exception(frame);
+        return;
     }
 
-    log::error!("========================================================");
-    log::error!("System halted.");
+    crate::crash::report_exception(frame);
+
+    #[cfg(feature = "gdbstub")]
+    crate::gdbstub::handle_exception(frame);
 
     // Halt forever
+    #[cfg(not(feature = "gdbstub"))]
     loop {
         unsafe {
             asm!("cli; hlt", options(nostack, nomem));
@@ -215,15 +257,25 @@ macro_rules! exception_no_error {
                 "push r13",
                 "push r14",
                 "push r15",
-                "mov rdi, {vector}", // vector
-                "mov rsi, [rsp + 128]", // error_code (fake)
-                "mov rdx, [rsp + 136]", // rip
-                "mov rcx, [rsp + 144]", // cs
-                "mov r8, [rsp + 152]",  // rflags
+                "mov rdi, rsp",      // &ExceptionFrame
                 "call {handler}",
-                "2:",
-                "hlt",
-                "jmp 2b",
+                "pop r15",           // Restore (possibly modified) registers
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rbp",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+                "add rsp, 16",       // Drop vector and error code
+                "iretq",
                 vector = const $vector,
                 handler = sym exception_handler,
             );
@@ -242,6 +294,8 @@ macro_rules! exception_no_error {
 //   rsp + 136: rip (pushed by CPU)
 //   rsp + 144: cs (pushed by CPU)
 //   rsp + 152: rflags (pushed by CPU)
+//   rsp + 160: rsp (pushed by CPU)
+//   rsp + 168: ss (pushed by CPU)
 macro_rules! exception_with_error {
     ($name:ident, $vector:expr) => {
         #[unsafe(naked)]
@@ -263,15 +317,25 @@ macro_rules! exception_with_error {
                 "push r13",
                 "push r14",
                 "push r15",
-                "mov rdi, {vector}", // vector
-                "mov rsi, [rsp + 128]", // error_code (CPU pushed before our handler)
-                "mov rdx, [rsp + 136]", // rip
-                "mov rcx, [rsp + 144]", // cs
-                "mov r8, [rsp + 152]",  // rflags
+                "mov rdi, rsp",      // &ExceptionFrame
                 "call {handler}",
-                "2:",
-                "hlt",
-                "jmp 2b",
+                "pop r15",           // Restore (possibly modified) registers
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rbp",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+                "add rsp, 16",       // Drop vector and error code
+                "iretq",
                 vector = const $vector,
                 handler = sym exception_handler,
             );
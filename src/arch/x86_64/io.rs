@@ -105,3 +105,28 @@ pub unsafe fn outl(port: u16, value: u32) {
         options(nostack, preserves_flags)
     );
 }
+
+/// Raise a software SMI by writing the low byte of `eax` to an I/O port
+///
+/// `eax` and `ebx` are passed to the SMI handler, which may replace `eax`
+/// with a return value. Returns `eax` after the SMI.
+///
+/// # Safety
+///
+/// The SMI handler acts on the registers; they must hold a command it
+/// expects, with any pointers valid for it.
+#[inline]
+pub unsafe fn outb_smi(port: u16, eax: u32, ebx: u32) -> u32 {
+    let mut eax = eax;
+    // rbx is reserved by LLVM and can't be an operand, swap it in and out
+    core::arch::asm!(
+        "xchg {ebx}, rbx",
+        "out dx, al",
+        "xchg {ebx}, rbx",
+        ebx = inout(reg) ebx as u64 => _,
+        in("dx") port,
+        inout("eax") eax,
+        options(nostack)
+    );
+    eax
+}
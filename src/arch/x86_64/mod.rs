@@ -8,8 +8,13 @@ pub mod entry;
 pub mod idt;
 pub mod io;
 pub mod paging;
+pub mod payload;
 pub mod port_regs;
+pub mod protect;
+pub mod smp;
 pub mod sse;
+pub mod stack;
+pub mod wake;
 
 /// CPU feature flags
 pub struct CpuFeatures {
@@ -23,6 +28,12 @@ pub struct CpuFeatures {
     pub nx: bool,
     /// 1GB pages support
     pub page_1gb: bool,
+    /// Supervisor Mode Execution Prevention
+    pub smep: bool,
+    /// Supervisor Mode Access Prevention
+    pub smap: bool,
+    /// User Mode Instruction Prevention
+    pub umip: bool,
 }
 
 impl CpuFeatures {
@@ -34,6 +45,9 @@ impl CpuFeatures {
             long_mode: false,
             nx: false,
             page_1gb: false,
+            smep: false,
+            smap: false,
+            umip: false,
         };
 
         unsafe {
@@ -72,6 +86,14 @@ impl CpuFeatures {
             features.page_1gb = (extended & (1 << 26)) != 0;
         }
 
+        // CPUID function 7: structured extended feature flags
+        if core::arch::x86_64::__cpuid(0).eax >= 7 {
+            let leaf7 = core::arch::x86_64::__cpuid_count(7, 0);
+            features.smep = (leaf7.ebx & (1 << 7)) != 0;
+            features.smap = (leaf7.ebx & (1 << 20)) != 0;
+            features.umip = (leaf7.ecx & (1 << 2)) != 0;
+        }
+
         features
     }
 }
@@ -163,6 +185,35 @@ pub unsafe fn write_cr4(value: u64) {
     core::arch::asm!("mov cr4, {}", in(reg) value);
 }
 
+/// Read a model-specific register
+///
+/// # Safety
+///
+/// The MSR must exist on this processor, reading an unknown MSR raises #GP.
+#[inline]
+pub unsafe fn read_msr(msr: u32) -> u64 {
+    let lo: u32;
+    let hi: u32;
+    core::arch::asm!("rdmsr", in("ecx") msr, out("eax") lo, out("edx") hi, options(nostack));
+    ((hi as u64) << 32) | (lo as u64)
+}
+
+/// Write a model-specific register
+///
+/// # Safety
+///
+/// The MSR must exist on this processor and `value` must be valid for it.
+#[inline]
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    core::arch::asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nostack)
+    );
+}
+
 /// Read the Time Stamp Counter (TSC)
 ///
 /// Returns the current value of the processor's time-stamp counter,
@@ -4,7 +4,10 @@
 //! required for x86_64 long mode. Initial setup is done in assembly,
 //! but this module can modify the page tables later.
 
+use core::ops::Range;
+
 use crate::coreboot::memory::MemoryRegion;
+use crate::efi::allocator;
 
 /// Page table entry flags
 pub mod flags {
@@ -20,6 +23,9 @@ pub mod flags {
     pub const NO_EXECUTE: u64 = 1 << 63;
 }
 
+/// Physical address bits of a page table entry
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
 /// Page sizes
 pub const PAGE_SIZE_4K: u64 = 4096;
 pub const PAGE_SIZE_2M: u64 = 2 * 1024 * 1024;
@@ -84,9 +90,8 @@ impl PageTable {
 
 /// Initialize paging based on the memory map
 ///
-/// The initial page tables set up in assembly identity-map the first 64GB.
-/// This function can be used to refine the mapping based on the actual
-/// memory map from coreboot.
+/// The initial page tables set up in assembly identity-map the first 64GB,
+/// [`map_memory`] maps the regions above once the allocator is up.
 pub fn init(memory_map: &[MemoryRegion]) {
     log::debug!(
         "Paging initialized with {} memory regions",
@@ -126,6 +131,363 @@ pub fn flush_tlb_all() {
     }
 }
 
+/// CR4.LA57: 5-level paging
+pub const CR4_LA57: u64 = 1 << 12;
+
+/// Number of paging levels, 5 with LA57 and 4 otherwise
+pub fn levels() -> usize {
+    match super::read_cr4() & CR4_LA57 {
+        0 => 4,
+        _ => 5,
+    }
+}
+
+/// End of the lower half of the address space `levels` paging levels reach
+///
+/// Identity mapped addresses must be canonical, so the upper half is out.
+pub fn address_limit(levels: usize) -> u64 {
+    1 << (12 + 9 * levels - 1)
+}
+
+/// The entry of `table` at `level` translating `addr`
+///
+/// # Safety
+///
+/// `table` must be the identity mapped address of a page table.
+unsafe fn entry_at(table: u64, level: usize, addr: u64) -> &'static mut PageTableEntry {
+    let index = ((addr >> (12 + 9 * level)) & 0x1FF) as usize;
+    unsafe { &mut *(table as *mut PageTableEntry).add(index) }
+}
+
+/// Check whether a virtual address is mapped by the current page tables
+///
+/// Walks the tables referenced by CR3, so this also works after a loaded
+/// image has installed its own page tables (as long as they are themselves
+/// identity mapped).
+pub fn is_mapped(virt: u64) -> bool {
+    page_flags(virt).is_some()
+}
+
+/// Map or unmap a single 4KB page of the identity mapping
+///
+/// A 1GB or 2MB page covering `addr` is first split into 512 pages of the
+/// next size down, taking new 4KB tables from `allocate_table`. Returns
+/// false if the address isn't mapped or no table could be allocated.
+pub fn set_page_present(
+    addr: u64,
+    present: bool,
+    allocate_table: impl FnMut() -> Option<u64>,
+) -> bool {
+    match present {
+        true => set_page_flags(addr, flags::PRESENT, 0, allocate_table),
+        false => set_page_flags(addr, 0, flags::PRESENT, allocate_table),
+    }
+}
+
+/// Whether `entry` at `level` maps a page rather than pointing to a table
+fn is_leaf(entry: &PageTableEntry, level: usize) -> bool {
+    level == 0 || (level < 3 && entry.raw() & flags::HUGE_PAGE != 0)
+}
+
+/// Replace the large page `entry` at `level` by a table of 512 pages of the
+/// next size down, with the same flags
+///
+/// Returns false if no table could be allocated. The caller flushes the TLB.
+fn split(
+    entry: &mut PageTableEntry,
+    level: usize,
+    allocate_table: &mut impl FnMut() -> Option<u64>,
+) -> bool {
+    let Some(new_table) = allocate_table() else {
+        return false;
+    };
+    let child_size = PAGE_SIZE_4K << (9 * (level - 1));
+    let base = entry.raw() & ADDRESS_MASK & !(child_size * 512 - 1);
+    let mut child_flags = entry.raw() & !ADDRESS_MASK;
+    if level == 1 {
+        // Bit 7 of a 4KB page entry is PAT, not the page size
+        child_flags &= !flags::HUGE_PAGE;
+    }
+    for i in 0..512 {
+        let child = PageTableEntry::new(base + i * child_size, child_flags);
+        unsafe { *(new_table as *mut PageTableEntry).add(i as usize) = child };
+    }
+    *entry = PageTableEntry::new(new_table, flags::PRESENT | flags::WRITABLE);
+    true
+}
+
+/// Set and clear flags of a single 4KB page of the identity mapping
+///
+/// Splits large pages like [`set_page_present`]. The tables above the page
+/// stay writable and executable, so the flags of the page alone decide.
+pub fn set_page_flags(
+    addr: u64,
+    set: u64,
+    clear: u64,
+    mut allocate_table: impl FnMut() -> Option<u64>,
+) -> bool {
+    let mut table = super::read_cr3() & ADDRESS_MASK;
+
+    for level in (1..levels()).rev() {
+        let entry = unsafe { entry_at(table, level, addr) };
+        if !entry.is_present() {
+            return false;
+        }
+
+        if is_leaf(entry, level) {
+            if !split(entry, level, &mut allocate_table) {
+                return false;
+            }
+            flush_tlb_all();
+        }
+        table = entry.phys_addr();
+    }
+
+    let entry =
+        unsafe { &mut *(table as *mut PageTableEntry).add(((addr >> 12) & 0x1FF) as usize) };
+    let entry_flags = (entry.raw() & !ADDRESS_MASK | set) & !clear;
+    *entry = PageTableEntry::new(entry.phys_addr(), entry_flags);
+    flush_tlb_page(addr);
+    true
+}
+
+/// Set and clear flags of the pages mapping `range` of the identity mapping
+///
+/// Large pages inside the range change as a whole, only those straddling
+/// its ends are split, and only if their flags change. Unmapped parts are
+/// skipped. Returns false if no table could be allocated.
+pub fn set_range_flags(
+    range: Range<u64>,
+    set: u64,
+    clear: u64,
+    mut allocate_table: impl FnMut() -> Option<u64>,
+) -> bool {
+    let root = super::read_cr3() & ADDRESS_MASK;
+    let done = update_range(root, levels() - 1, range, set, clear, &mut allocate_table);
+    flush_tlb_all();
+    done
+}
+
+/// Set and clear flags of the pages mapping `range` below `table` at `level`
+fn update_range(
+    table: u64,
+    level: usize,
+    range: Range<u64>,
+    set: u64,
+    clear: u64,
+    allocate_table: &mut impl FnMut() -> Option<u64>,
+) -> bool {
+    let size = PAGE_SIZE_4K << (9 * level);
+    let mut addr = range.start & !(size - 1);
+
+    while addr < range.end {
+        let next = addr + size;
+        let entry = unsafe { entry_at(table, level, addr) };
+        let inner = range.start.max(addr)..range.end.min(next);
+        if entry.is_present() {
+            let raw = entry.raw();
+            let wanted = (raw | set) & !clear;
+            if !is_leaf(entry, level) {
+                if !update_range(
+                    entry.phys_addr(),
+                    level - 1,
+                    inner,
+                    set,
+                    clear,
+                    allocate_table,
+                ) {
+                    return false;
+                }
+            } else if wanted != raw {
+                if inner == (addr..next) {
+                    *entry = PageTableEntry(wanted);
+                } else if split(entry, level, allocate_table) {
+                    let child = entry.phys_addr();
+                    if !update_range(child, level - 1, inner, set, clear, allocate_table) {
+                        return false;
+                    }
+                } else {
+                    return false;
+                }
+            }
+        }
+        addr = next;
+    }
+    true
+}
+
+/// Call `f` with the range and effective flags of each mapped page
+///
+/// Walks the whole identity mapping, large pages come as one range.
+pub fn for_each_page(mut f: impl FnMut(Range<u64>, u64)) {
+    let root = super::read_cr3() & ADDRESS_MASK;
+    let levels = levels();
+    visit(root, levels - 1, 0, flags::WRITABLE, &mut f);
+}
+
+/// Call `f` for the pages below `table` at `level`, which maps from `base`
+fn visit(table: u64, level: usize, base: u64, above: u64, f: &mut impl FnMut(Range<u64>, u64)) {
+    let size = PAGE_SIZE_4K << (9 * level);
+    for index in 0..512 {
+        let entry = unsafe { *(table as *const PageTableEntry).add(index) };
+        if !entry.is_present() {
+            continue;
+        }
+        let start = base + index as u64 * size;
+        let effective = combine_flags(above, entry.raw() & !ADDRESS_MASK);
+        if is_leaf(&entry, level) {
+            f(start..start + size, effective);
+        } else {
+            visit(entry.phys_addr(), level - 1, start, effective, f);
+        }
+    }
+}
+
+/// Combine the flags of a table entry with those of the levels above it
+///
+/// A page is only writable if every level allows writes, and is
+/// non-executable if any level says so.
+fn combine_flags(above: u64, entry: u64) -> u64 {
+    let writable = above & entry & flags::WRITABLE;
+    let no_execute = (above | entry) & flags::NO_EXECUTE;
+    entry & !(flags::WRITABLE | flags::NO_EXECUTE) | writable | no_execute
+}
+
+/// Effective flags of the page mapping `virt`, or None if it isn't mapped
+pub fn page_flags(virt: u64) -> Option<u64> {
+    walk(super::read_cr3() & ADDRESS_MASK, levels(), virt)
+}
+
+/// Walk the tables from `root` down to the page mapping `virt`
+fn walk(root: u64, levels: usize, virt: u64) -> Option<u64> {
+    let mut table = root;
+    let mut effective = flags::WRITABLE;
+
+    for level in (0..levels).rev() {
+        let entry = unsafe { *entry_at(table, level, virt) };
+        if !entry.is_present() {
+            return None;
+        }
+        effective = combine_flags(effective, entry.raw() & !ADDRESS_MASK);
+        // 1GB and 2MB pages terminate the walk early
+        if is_leaf(&entry, level) {
+            return Some(effective);
+        }
+        table = entry.phys_addr();
+    }
+
+    Some(effective)
+}
+
+/// Identity map the 1GB blocks covering `range` below `root`
+///
+/// Adds the missing tables down to the PDPT, then maps each block not
+/// mapped yet with a 1GB page, or a PD of 2MB pages without `page_1gb`.
+/// Returns false if no table could be allocated.
+fn map_range(
+    root: u64,
+    levels: usize,
+    range: Range<u64>,
+    page_1gb: bool,
+    allocate_table: &mut impl FnMut() -> Option<u64>,
+) -> bool {
+    let page = flags::PRESENT | flags::WRITABLE | flags::HUGE_PAGE;
+    let mut addr = range.start & !(PAGE_SIZE_1G - 1);
+
+    while addr < range.end {
+        let mut table = root;
+        for level in (3..levels).rev() {
+            let entry = unsafe { entry_at(table, level, addr) };
+            if !entry.is_present() {
+                let Some(new_table) = allocate_table() else {
+                    return false;
+                };
+                *entry = PageTableEntry::new(new_table, flags::PRESENT | flags::WRITABLE);
+            }
+            table = entry.phys_addr();
+        }
+
+        let entry = unsafe { entry_at(table, 2, addr) };
+        if !entry.is_present() {
+            if page_1gb {
+                *entry = PageTableEntry::new(addr, page);
+            } else {
+                let Some(pd) = allocate_table() else {
+                    return false;
+                };
+                for i in 0..512 {
+                    let child = PageTableEntry::new(addr + i * PAGE_SIZE_2M, page);
+                    unsafe { *(pd as *mut PageTableEntry).add(i as usize) = child };
+                }
+                *entry = PageTableEntry::new(pd, flags::PRESENT | flags::WRITABLE);
+            }
+        }
+        addr += PAGE_SIZE_1G;
+    }
+
+    true
+}
+
+/// Identity map the memory regions above the mapping set up in assembly
+///
+/// Maps everything from the end of the identity mapping up to the highest
+/// coreboot region, so the mapping stays free of holes, and raises the
+/// allocator's limit to match. Memory past the lower half of the address
+/// space stays unmapped: with 4-level paging that is above 128 TiB.
+///
+/// Needs the EFI allocator, for the page tables.
+pub fn map_memory(memory_map: &[MemoryRegion], mut allocate_table: impl FnMut() -> Option<u64>) {
+    let levels = levels();
+    let limit = address_limit(levels);
+    let top = memory_map
+        .iter()
+        .map(|r| r.start + r.size)
+        .max()
+        .unwrap_or(0);
+    if top > limit {
+        log::warn!(
+            "Memory above {:#x} is out of reach of {}-level paging",
+            limit,
+            levels
+        );
+    }
+
+    let mapped = allocator::max_identity_mapped_address();
+    let end = top.min(limit).next_multiple_of(PAGE_SIZE_1G);
+    if end <= mapped {
+        return;
+    }
+
+    let page_1gb = super::CpuFeatures::detect().page_1gb;
+    let root = super::read_cr3() & ADDRESS_MASK;
+    if map_range(root, levels, mapped..end, page_1gb, &mut allocate_table) {
+        allocator::set_max_identity_mapped_address(end);
+        log::info!(
+            "Identity mapped {:#x}-{:#x} with {} pages",
+            mapped,
+            end,
+            if page_1gb { "1GB" } else { "2MB" }
+        );
+    } else {
+        log::warn!("Out of page tables mapping memory above {:#x}", mapped);
+    }
+    flush_tlb_all();
+}
+
+/// CR0.WP: supervisor writes honour read-only pages
+pub const CR0_WP: u64 = 1 << 16;
+
+/// Run `f` with CR0.WP cleared, so it can write to read-only pages
+///
+/// For the debugger, which patches breakpoints into read-only code.
+pub fn without_write_protect<R>(f: impl FnOnce() -> R) -> R {
+    let cr0 = super::read_cr0();
+    unsafe { super::write_cr0(cr0 & !CR0_WP) };
+    let result = f();
+    unsafe { super::write_cr0(cr0) };
+    result
+}
+
 /// Virtual to physical address translation (identity mapped)
 ///
 /// Since we use identity mapping, this is trivial.
@@ -139,3 +501,109 @@ pub fn virt_to_phys(virt: u64) -> u64 {
 pub fn phys_to_virt(phys: u64) -> u64 {
     phys
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A zeroed page table on the heap, at its identity "physical" address
+    fn table() -> Option<u64> {
+        Some(std::boxed::Box::leak(std::boxed::Box::new(PageTable::empty())) as *mut _ as u64)
+    }
+
+    #[test]
+    fn maps_high_memory() {
+        let page = flags::PRESENT | flags::WRITABLE | flags::HUGE_PAGE;
+        let start = 600 * PAGE_SIZE_1G;
+        let end = start + PAGE_SIZE_1G + 1;
+
+        for levels in [4, 5] {
+            let root = table().unwrap();
+            assert!(map_range(root, levels, start..end, true, &mut table));
+            assert_eq!(walk(root, levels, start), Some(page));
+            assert_eq!(walk(root, levels, end + PAGE_SIZE_1G - 2), Some(page));
+            assert_eq!(walk(root, levels, start - 1), None);
+            assert_eq!(walk(root, levels, end + PAGE_SIZE_1G), None);
+        }
+
+        // 2MB pages without 1GB page support
+        let root = table().unwrap();
+        assert!(map_range(root, 4, start..start + 1, false, &mut table));
+        assert_eq!(walk(root, 4, start + PAGE_SIZE_1G - 1), Some(page));
+        assert_eq!(walk(root, 4, start + PAGE_SIZE_1G), None);
+
+        // Running out of tables
+        assert!(!map_range(root, 4, 0..1, true, &mut || None));
+    }
+
+    #[test]
+    fn updates_ranges() {
+        let start = 4 * PAGE_SIZE_1G;
+        let root = table().unwrap();
+        assert!(map_range(root, 4, start..start + 1, false, &mut table));
+
+        // Only the 2MB page holding the start of the range is split
+        let range = start + PAGE_SIZE_4K..start + 2 * PAGE_SIZE_2M;
+        assert!(update_range(
+            root,
+            3,
+            range,
+            flags::NO_EXECUTE,
+            0,
+            &mut table
+        ));
+        let nx = |addr| walk(root, 4, addr).unwrap() & flags::NO_EXECUTE != 0;
+        assert!(!nx(start));
+        assert!(nx(start + PAGE_SIZE_4K));
+        assert!(nx(start + 2 * PAGE_SIZE_2M - 1));
+        assert!(!nx(start + 2 * PAGE_SIZE_2M));
+
+        let mut pages = std::vec::Vec::new();
+        visit(root, 3, 0, flags::WRITABLE, &mut |range, flags| {
+            pages.push((range, flags & flags::NO_EXECUTE != 0))
+        });
+        assert_eq!(pages.len(), 512 + 511);
+        assert_eq!(pages[0], (start..start + PAGE_SIZE_4K, false));
+        assert_eq!(
+            pages[1],
+            (start + PAGE_SIZE_4K..start + 2 * PAGE_SIZE_4K, true)
+        );
+        assert_eq!(
+            pages[512],
+            (start + PAGE_SIZE_2M..start + 2 * PAGE_SIZE_2M, true)
+        );
+
+        // Pages already having the flags aren't split
+        let range = start + PAGE_SIZE_2M + PAGE_SIZE_4K..start + PAGE_SIZE_2M + 2 * PAGE_SIZE_4K;
+        assert!(update_range(
+            root,
+            3,
+            range,
+            flags::NO_EXECUTE,
+            0,
+            &mut || None
+        ));
+    }
+
+    #[test]
+    fn limits_to_lower_half() {
+        assert_eq!(address_limit(4), 0x8000_0000_0000);
+        assert_eq!(address_limit(5), 0x100_0000_0000_0000);
+    }
+
+    #[test]
+    fn combines_flags_across_levels() {
+        let rwx = flags::PRESENT | flags::WRITABLE;
+        let read_only = flags::PRESENT;
+        let no_execute = flags::PRESENT | flags::WRITABLE | flags::NO_EXECUTE;
+
+        assert_eq!(combine_flags(flags::WRITABLE, rwx), rwx);
+        assert_eq!(combine_flags(rwx, read_only), read_only);
+        assert_eq!(combine_flags(read_only, rwx), read_only);
+        assert_eq!(combine_flags(no_execute, rwx), no_execute);
+        assert_eq!(
+            combine_flags(combine_flags(no_execute, read_only), rwx),
+            flags::PRESENT | flags::NO_EXECUTE
+        );
+    }
+}
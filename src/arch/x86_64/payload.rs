@@ -0,0 +1,82 @@
+//! Transfer to another coreboot payload
+//!
+//! coreboot enters x86 payloads in flat 32-bit protected mode with paging
+//! off, calling the entry point like a C function with one argument: the
+//! address of the coreboot table. Leaving long mode works as for the
+//! [waking vector](super::wake), with the argument and a return address
+//! pushed before the jump.
+
+use core::arch::naked_asm;
+
+use super::wake::{CODE32_SELECTOR, DATA32_SELECTOR, DescriptorPointer};
+
+/// Jump to a payload's entry point
+///
+/// # Safety
+///
+/// Leaves the firmware for good. The payload must be loaded, and the
+/// firmware image and its stack must be identity mapped below 4 GiB.
+pub unsafe fn jump_to_payload(entry: u32, argument: u32) -> ! {
+    let gdt: [u64; 3] = [
+        0,
+        0x00cf9a000000ffff, // 32-bit code
+        0x00cf92000000ffff, // 32-bit data
+    ];
+    let gdt_pointer = DescriptorPointer {
+        limit: (core::mem::size_of_val(&gdt) - 1) as u16,
+        base: gdt.as_ptr() as u64,
+    };
+    // No IDT: the firmware's handlers are 64-bit code
+    let idt_pointer = DescriptorPointer { limit: 0, base: 0 };
+
+    unsafe { payload_trampoline(entry, argument, &gdt_pointer, &idt_pointer) }
+}
+
+/// Leave long mode and call `entry` with `argument` on the stack
+///
+/// Takes the entry point in EDI, the argument in ESI, and pointers to the
+/// GDT and IDT to load in RDX and RCX.
+#[unsafe(naked)]
+unsafe extern "sysv64" fn payload_trampoline(
+    entry: u32,
+    argument: u32,
+    gdt: *const DescriptorPointer,
+    idt: *const DescriptorPointer,
+) -> ! {
+    naked_asm!(
+        "cli",
+        "lgdt [rdx]",
+        "lidt [rcx]",
+        // Far return into the 32-bit code segment (compatibility mode)
+        "push {code32}",
+        "lea rax, [rip + 2f]",
+        "push rax",
+        "retfq",
+        ".code32",
+        "2:",
+        "mov ax, {data32}",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov fs, ax",
+        "mov gs, ax",
+        "mov ss, ax",
+        // Paging off deactivates long mode, then clear EFER.LME and PAE
+        "mov eax, cr0",
+        "and eax, 0x7FFFFFFF",
+        "mov cr0, eax",
+        "mov ecx, 0xC0000080",
+        "rdmsr",
+        "and eax, 0xFFFFFEFF",
+        "wrmsr",
+        "mov eax, cr4",
+        "and eax, 0xFFFFFFDF",
+        "mov cr4, eax",
+        // The C calling convention: the argument above the return address
+        "push esi",
+        "push 0",
+        "jmp edi",
+        ".code64",
+        code32 = const CODE32_SELECTOR,
+        data32 = const DATA32_SELECTOR,
+    );
+}
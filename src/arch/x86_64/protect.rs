@@ -0,0 +1,246 @@
+//! Memory protection hardening
+//!
+//! Boot loaders run in ring 0 with CrabEFI, so a bug in one that lets an
+//! attacker write memory could otherwise patch firmware code or run code
+//! planted in data. After this runs, CrabEFI's own image is mapped W^X:
+//! `.entry32` and `.text` are read-only, `.rodata` is read-only and
+//! non-executable, and everything from there to the top of the stack is
+//! non-executable. CR0.WP makes read-only pages apply to ring 0 too.
+//!
+//! The rest of memory keeps the writable and executable identity mapping
+//! from entry. That is where the PE loader stages images: it writes their
+//! sections and relocations and then runs them from the same pages. Once an
+//! image marked NX-compatible is loaded, its code sections become read-only
+//! and the rest of it non-executable, and freed memory is mapped as it was
+//! again. Loaders can change the mapping of their own memory through the
+//! Memory Attribute protocol.
+//!
+//! With the VPD key `crabefi_strict_nx` set to `1`, the rest of memory is
+//! non-executable as well, except memory allocated as code (`LoaderCode`,
+//! `BootServicesCode` and `RuntimeServicesCode`), and freed memory becomes
+//! non-executable again. This is opt-in: GRUB's handover path and x86 EFI
+//! stubs before Linux 6.2 run code they relocated into `LoaderData`.
+//!
+//! SMEP, SMAP and UMIP are enabled where the CPU has them. CrabEFI maps
+//! nothing as user pages, so they only take effect if a loader builds page
+//! tables with user mappings and then calls into the firmware. The APs
+//! enable CR0.WP and the same CR4 bits in the startup trampoline.
+
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::coreboot::vpd;
+use crate::efi::allocator::{self, MemoryType, PAGE_SIZE};
+use crate::efi::pool_guard;
+use crate::state;
+
+use super::CpuFeatures;
+use super::paging::{self, CR0_WP, flags};
+
+/// CR4.UMIP (bit 11)
+const CR4_UMIP: u64 = 1 << 11;
+/// CR4.SMEP (bit 20)
+const CR4_SMEP: u64 = 1 << 20;
+/// CR4.SMAP (bit 21)
+const CR4_SMAP: u64 = 1 << 21;
+
+/// Whether the firmware image is mapped W^X
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Whether memory outside code allocations is mapped non-executable
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// Code allocations made before [`init`] that are looked at
+const MAX_EARLY_CODE_ALLOCATIONS: usize = 16;
+
+// Linker symbols for the data sections
+unsafe extern "C" {
+    static _rodata_start: u8;
+    static _rodata_end: u8;
+    static __runtime_data_end: u8;
+}
+
+/// Round `range` out to whole pages
+fn page_range(range: Range<u64>) -> Range<u64> {
+    (range.start & !(PAGE_SIZE - 1))..((range.end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1))
+}
+
+/// The firmware image, as (code, read-only data, all data)
+fn image_layout() -> (Range<u64>, Range<u64>, Range<u64>) {
+    let code = allocator::runtime_code_range();
+    let (rodata, data_end) = unsafe {
+        (
+            &_rodata_start as *const u8 as u64..&_rodata_end as *const u8 as u64,
+            &__runtime_data_end as *const u8 as u64,
+        )
+    };
+    let data = code.end..data_end;
+    (code, page_range(rodata), page_range(data))
+}
+
+/// Set and clear page flags over a range, returning the pages changed
+fn protect(range: Range<u64>, set: u64, clear: u64) -> u64 {
+    range
+        .step_by(PAGE_SIZE as usize)
+        .filter(|&page| paging::set_page_flags(page, set, clear, pool_guard::allocate_page_table))
+        .count() as u64
+}
+
+/// CR0 and CR4 bits enabling the protections this CPU has
+///
+/// The APs get them from the trampoline, they may start before [`init`].
+pub fn control_bits() -> (u64, u64) {
+    let features = CpuFeatures::detect();
+    let mut cr4 = 0;
+    if features.smep {
+        cr4 |= CR4_SMEP;
+    }
+    if features.smap {
+        cr4 |= CR4_SMAP;
+    }
+    if features.umip {
+        cr4 |= CR4_UMIP;
+    }
+    (CR0_WP, cr4)
+}
+
+/// Set and clear page flags over a range of whole pages
+fn protect_range(range: Range<u64>, set: u64, clear: u64) -> bool {
+    paging::set_range_flags(range, set, clear, pool_guard::allocate_page_table)
+}
+
+/// Whether `range` overlaps the firmware image
+pub fn overlaps_firmware(range: &Range<u64>) -> bool {
+    let (code, _, data) = image_layout();
+    range.start < data.end && range.end > code.start
+}
+
+/// Enable the CPU protections, map the firmware image W^X and, with
+/// `crabefi_strict_nx`, the rest of memory non-executable
+///
+/// Needs the EFI allocator, for the page tables splitting the 2MB mappings.
+pub fn init() {
+    let features = CpuFeatures::detect();
+    let (cr0, cr4) = control_bits();
+    unsafe {
+        super::write_cr4(super::read_cr4() | cr4);
+        super::write_cr0(super::read_cr0() | cr0);
+    }
+    log::debug!(
+        "SMEP: {}, SMAP: {}, UMIP: {}",
+        features.smep,
+        features.smap,
+        features.umip
+    );
+
+    let (code, rodata, data) = image_layout();
+    let code_pages = protect(code.clone(), 0, flags::WRITABLE);
+    let data_pages = protect(data.clone(), flags::NO_EXECUTE, 0);
+    protect(rodata, 0, flags::WRITABLE);
+    log::debug!(
+        "W^X: {} code pages read-only at {:#x}, {} data pages non-executable at {:#x}",
+        code_pages,
+        code.start,
+        data_pages,
+        data.start
+    );
+
+    ACTIVE.store(true, Ordering::Relaxed);
+    if vpd::find_str(vpd::KEY_STRICT_NX).is_some_and(|value| value.trim() == "1") {
+        protect_data();
+    }
+
+    debug_assert_wx();
+}
+
+/// Map all memory outside the firmware image and code allocations
+/// non-executable
+fn protect_data() {
+    let (code, _, data) = image_layout();
+
+    // The AP trampoline and anything else allocated as code stays executable
+    let early: heapless::Vec<Range<u64>, MAX_EARLY_CODE_ALLOCATIONS> = state::allocator()
+        .entries()
+        .iter()
+        .filter(|entry| MemoryType::from_u32(entry.memory_type).is_some_and(|t| t.is_code()))
+        .map(|entry| entry.physical_start..entry.end())
+        .filter(|range| !overlaps_firmware(range))
+        .take(MAX_EARLY_CODE_ALLOCATIONS)
+        .collect();
+    let mapped_end = allocator::max_identity_mapped_address();
+    let mut done = protect_range(0..code.start, flags::NO_EXECUTE, 0)
+        && protect_range(data.end..mapped_end, flags::NO_EXECUTE, 0);
+    for range in early {
+        done &= protect_range(range, 0, flags::NO_EXECUTE);
+    }
+    if !done {
+        log::warn!("W^X: out of page tables, some memory stays executable");
+    }
+    STRICT.store(true, Ordering::Relaxed);
+    log::info!("W^X: memory up to {:#x} non-executable", mapped_end);
+}
+
+/// Map memory just allocated as `memory_type` for its use
+///
+/// With `crabefi_strict_nx`, code allocations become executable, and stay
+/// writable for the loader to put the code there.
+pub fn allocated(range: Range<u64>, memory_type: MemoryType) {
+    if STRICT.load(Ordering::Relaxed)
+        && memory_type.is_code()
+        && !protect_range(range.clone(), flags::WRITABLE, flags::NO_EXECUTE)
+    {
+        log::warn!("W^X: can't map {:#x} executable", range.start);
+    }
+}
+
+/// Map freed memory writable again, and non-executable with
+/// `crabefi_strict_nx` or executable without
+pub fn freed(range: Range<u64>) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let done = if STRICT.load(Ordering::Relaxed) {
+        protect_range(range.clone(), flags::WRITABLE | flags::NO_EXECUTE, 0)
+    } else {
+        protect_range(range.clone(), flags::WRITABLE, flags::NO_EXECUTE)
+    };
+    if !done {
+        log::warn!("W^X: can't remap freed memory at {:#x}", range.start);
+    }
+}
+
+/// Set and clear page flags over memory of a loaded image or a loader
+///
+/// Returns false for the firmware image or if no page table could be
+/// allocated. Does nothing before [`init`], when all memory is writable
+/// and executable.
+pub fn set_flags(range: Range<u64>, set: u64, clear: u64) -> bool {
+    if overlaps_firmware(&range) {
+        return false;
+    }
+    !ACTIVE.load(Ordering::Relaxed) || protect_range(range, set, clear)
+}
+
+/// Check in debug builds that the firmware image has no W+X pages and, with
+/// `crabefi_strict_nx`, that only code allocations are mapped W+X
+///
+/// Walks the whole page table. Call it after changing the mappings.
+pub fn debug_assert_wx() {
+    if cfg!(debug_assertions) {
+        let strict = STRICT.load(Ordering::Relaxed);
+        paging::for_each_page(|range, f| {
+            if f & flags::WRITABLE != 0 && f & flags::NO_EXECUTE == 0 {
+                debug_assert!(
+                    !overlaps_firmware(&range),
+                    "W+X page in the firmware image at {:#x?}",
+                    range
+                );
+                let code = !strict
+                    || range.clone().step_by(PAGE_SIZE as usize).all(|page| {
+                        allocator::get_memory_type_at(page).is_some_and(|t| t.is_code())
+                    });
+                debug_assert!(code, "W+X page outside code allocations at {:#x?}", range);
+            }
+        });
+    }
+}
@@ -0,0 +1,302 @@
+//! Application processor startup
+//!
+//! Application processors (APs) start in real mode at a 4 KiB aligned page
+//! below 1 MiB when the bootstrap processor sends them INIT and STARTUP IPIs.
+//! The trampoline copied to that page switches to protected mode, enables
+//! paging with the page tables of the BSP and its memory protections,
+//! enters long mode and calls the entry function on its own stack.
+//!
+//! The trampoline is position independent: the 16-bit code derives the page
+//! address from CS, and the 32-bit and 64-bit parts sit at fixed offsets.
+//! Everything that depends on the page or the AP lives in an [`ApStartup`]
+//! block at the end of the page, filled in before each STARTUP IPI.
+
+use core::arch::naked_asm;
+use core::mem::offset_of;
+
+use super::{paging, protect};
+use super::{read_cr3, read_cr4, read_msr, write_msr};
+
+/// IA32_APIC_BASE MSR
+const IA32_APIC_BASE: u32 = 0x1B;
+
+/// IA32_APIC_BASE: x2APIC mode enabled
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+/// IA32_APIC_BASE: base address of the local APIC registers
+const APIC_BASE_MASK: u64 = 0xF_FFFF_F000;
+
+/// Local APIC ID register (xAPIC offset and x2APIC MSR)
+const XAPIC_ID: u64 = 0x20;
+const X2APIC_ID: u32 = 0x802;
+
+/// Interrupt Command Register (xAPIC offsets and x2APIC MSR)
+const XAPIC_ICR_LOW: u64 = 0x300;
+const XAPIC_ICR_HIGH: u64 = 0x310;
+const X2APIC_ICR: u32 = 0x830;
+
+/// ICR: INIT delivery mode, level assert
+const ICR_INIT: u32 = 0x4500;
+
+/// ICR: STARTUP delivery mode, level assert
+const ICR_STARTUP: u32 = 0x4600;
+
+/// ICR: delivery status, set while the IPI is pending
+const ICR_SEND_PENDING: u32 = 1 << 12;
+
+/// Size of the trampoline page
+pub const TRAMPOLINE_SIZE: u64 = 0x1000;
+
+/// Offsets of the 32-bit and 64-bit parts and size of the trampoline code
+const PROTECTED_MODE_OFFSET: usize = 0x80;
+const LONG_MODE_OFFSET: usize = 0x100;
+const TRAMPOLINE_CODE_SIZE: usize = 0x180;
+
+/// Offset of the [`ApStartup`] block in the trampoline page
+const STARTUP_OFFSET: usize = 0xF00;
+
+/// GDT selectors of the trampoline
+const CODE64_SELECTOR: u16 = 0x08;
+const DATA_SELECTOR: u16 = 0x10;
+const CODE32_SELECTOR: u16 = 0x18;
+
+/// Entry function of an AP, called with the argument in the startup block
+pub type ApEntry = extern "C" fn(u64) -> !;
+
+/// Per-AP data read by the trampoline
+#[repr(C)]
+struct ApStartup {
+    cr3: u64,
+    /// CR0 bits to set besides PG and MP (WP)
+    cr0: u64,
+    /// CR4 bits besides PAE and SSE: LA57 for the page tables and the
+    /// protections of the BSP (SMEP, SMAP, UMIP)
+    cr4: u64,
+    stack_top: u64,
+    entry: u64,
+    argument: u64,
+    /// Far pointer (32-bit offset, selector) for the mode switch jumps
+    far_jump: [u32; 2],
+    /// GDT pointer: limit, then the 32-bit base
+    gdtr: [u16; 4],
+    gdt: [u64; 4],
+}
+
+/// Check whether the local APIC runs in x2APIC mode
+fn x2apic_enabled() -> bool {
+    unsafe { read_msr(IA32_APIC_BASE) & APIC_BASE_X2APIC != 0 }
+}
+
+/// Base address of the xAPIC registers
+fn xapic_base() -> u64 {
+    unsafe { read_msr(IA32_APIC_BASE) & APIC_BASE_MASK }
+}
+
+/// APIC ID of the current processor
+pub fn apic_id() -> u32 {
+    if x2apic_enabled() {
+        unsafe { read_msr(X2APIC_ID) as u32 }
+    } else {
+        let id = unsafe { core::ptr::read_volatile((xapic_base() + XAPIC_ID) as *const u32) };
+        id >> 24
+    }
+}
+
+/// Check whether IPIs can be addressed to `apic_id`
+///
+/// xAPIC mode only has 8-bit destinations.
+pub fn can_address(apic_id: u32) -> bool {
+    apic_id <= 0xFF || x2apic_enabled()
+}
+
+/// Send an inter-processor interrupt and wait until it was delivered
+fn send_ipi(apic_id: u32, command: u32) {
+    unsafe {
+        if x2apic_enabled() {
+            write_msr(X2APIC_ICR, ((apic_id as u64) << 32) | command as u64);
+            return;
+        }
+
+        let base = xapic_base();
+        let icr_low = (base + XAPIC_ICR_LOW) as *mut u32;
+        core::ptr::write_volatile((base + XAPIC_ICR_HIGH) as *mut u32, apic_id << 24);
+        core::ptr::write_volatile(icr_low, command);
+        for _ in 0..100_000 {
+            if core::ptr::read_volatile(icr_low) & ICR_SEND_PENDING == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Send INIT to an AP, which puts it into wait-for-SIPI state
+pub fn send_init(apic_id: u32) {
+    send_ipi(apic_id, ICR_INIT);
+}
+
+/// Send STARTUP to an AP, starting it at the trampoline page
+pub fn send_startup(apic_id: u32, trampoline: u64) {
+    send_ipi(apic_id, ICR_STARTUP | (trampoline >> 12) as u32);
+}
+
+/// Copy the trampoline to its page
+///
+/// # Safety
+///
+/// `trampoline` must be a [`TRAMPOLINE_SIZE`] page below 1 MiB owned by the
+/// caller.
+pub unsafe fn install_trampoline(trampoline: u64) {
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            ap_trampoline as *const u8,
+            trampoline as *mut u8,
+            TRAMPOLINE_CODE_SIZE,
+        );
+    }
+}
+
+/// Fill in the startup block for the next AP
+///
+/// The AP enters `entry` with `argument` on the stack ending at `stack_top`.
+///
+/// # Safety
+///
+/// `trampoline` must hold the trampoline, and the previous AP started from
+/// it must be past the trampoline.
+pub unsafe fn prepare_startup(trampoline: u64, entry: ApEntry, argument: u64, stack_top: u64) {
+    let block = trampoline + STARTUP_OFFSET as u64;
+    let gdt = block + offset_of!(ApStartup, gdt) as u64;
+    let (cr0, cr4) = protect::control_bits();
+    let startup = ApStartup {
+        cr3: read_cr3(),
+        cr0,
+        cr4: (read_cr4() & paging::CR4_LA57) | cr4,
+        stack_top,
+        entry: entry as usize as u64,
+        argument,
+        far_jump: [0; 2],
+        gdtr: [
+            (size_of::<[u64; 4]>() - 1) as u16,
+            gdt as u16,
+            (gdt >> 16) as u16,
+            0,
+        ],
+        gdt: [
+            0,
+            0x00af9a000000ffff, // 64-bit code
+            0x00cf92000000ffff, // data
+            0x00cf9a000000ffff, // 32-bit code
+        ],
+    };
+    unsafe { core::ptr::write_volatile(block as *mut ApStartup, startup) };
+}
+
+/// Call `function` with `argument` on the stack ending at `stack_top`
+///
+/// # Safety
+///
+/// `stack_top` must be the 16-byte aligned end of a stack owned by the
+/// caller and large enough for `function`.
+#[unsafe(naked)]
+pub unsafe extern "sysv64" fn call_on_stack(
+    argument: u64,
+    function: extern "C" fn(u64),
+    stack_top: u64,
+) {
+    naked_asm!(
+        "push rbp",
+        "mov rbp, rsp",
+        "mov rsp, rdx",
+        "call rsi",
+        "mov rsp, rbp",
+        "pop rbp",
+        "ret",
+    );
+}
+
+/// Trampoline copied to the startup page
+///
+/// Never called, only copied; it starts in real mode with CS at the page.
+#[unsafe(naked)]
+unsafe extern "C" fn ap_trampoline() {
+    naked_asm!(
+        ".code16",
+        "2:",
+        "cli",
+        "cld",
+        "mov ax, cs",
+        "mov ds, ax",
+        // EBX = linear address of the page
+        "xor ebx, ebx",
+        "mov bx, ax",
+        "shl ebx, 4",
+        "lgdt [{startup} + {gdtr}]",
+        "lea eax, [ebx + {protected}]",
+        "mov dword ptr [{startup} + {far}], eax",
+        "mov word ptr [{startup} + {far} + 4], {code32}",
+        "mov eax, cr0",
+        "or eax, 1",
+        "mov cr0, eax",
+        "jmp fword ptr [{startup} + {far}]",
+        ".fill {protected} - (. - 2b), 1, 0xCC",
+        ".code32",
+        "mov ax, {data}",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov ss, ax",
+        // PAE and SSE
+        "mov eax, cr4",
+        "or eax, 0x620",
+        "or eax, [ebx + {startup} + {cr4}]",
+        "mov cr4, eax",
+        "mov eax, [ebx + {startup} + {cr3}]",
+        "mov cr3, eax",
+        // EFER.LME and EFER.NXE, like the BSP
+        "mov ecx, 0xC0000080",
+        "rdmsr",
+        "or eax, 0x900",
+        "wrmsr",
+        // Paging and MP on, EM off, and WP like the BSP
+        "mov eax, cr0",
+        "and eax, 0xFFFFFFFB",
+        "or eax, 0x80000002",
+        "or eax, [ebx + {startup} + {cr0}]",
+        "mov cr0, eax",
+        "lea eax, [ebx + {long}]",
+        "mov [ebx + {startup} + {far}], eax",
+        "mov word ptr [ebx + {startup} + {far} + 4], {code64}",
+        "jmp fword ptr [ebx + {startup} + {far}]",
+        ".fill {long} - (. - 2b), 1, 0xCC",
+        ".code64",
+        "mov ax, {data}",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov ss, ax",
+        "mov fs, ax",
+        "mov gs, ax",
+        "mov ebx, ebx",
+        "mov rsp, [rbx + {startup} + {stack_top}]",
+        "mov rdi, [rbx + {startup} + {argument}]",
+        "call qword ptr [rbx + {startup} + {entry}]",
+        "3:",
+        "hlt",
+        "jmp 3b",
+        ".fill {size} - (. - 2b), 1, 0xCC",
+        startup = const STARTUP_OFFSET,
+        cr3 = const offset_of!(ApStartup, cr3),
+        cr0 = const offset_of!(ApStartup, cr0),
+        cr4 = const offset_of!(ApStartup, cr4),
+        stack_top = const offset_of!(ApStartup, stack_top),
+        entry = const offset_of!(ApStartup, entry),
+        argument = const offset_of!(ApStartup, argument),
+        far = const offset_of!(ApStartup, far_jump),
+        gdtr = const offset_of!(ApStartup, gdtr),
+        protected = const PROTECTED_MODE_OFFSET,
+        long = const LONG_MODE_OFFSET,
+        size = const TRAMPOLINE_CODE_SIZE,
+        code64 = const CODE64_SELECTOR,
+        code32 = const CODE32_SELECTOR,
+        data = const DATA_SELECTOR,
+    );
+}
@@ -1,7 +1,10 @@
 //! SSE/SIMD support for x86_64
 //!
 //! Rust requires SSE2 support for the x86_64 target. This module
-//! provides utilities for enabling and checking SSE support.
+//! provides utilities for enabling and checking SSE support, and a copy
+//! routine for write-combining memory.
+
+use core::arch::naked_asm;
 
 /// Enable SSE/SSE2 support
 ///
@@ -74,3 +77,54 @@ pub fn cpu_supports_sse2() -> bool {
     // SSE2 is bit 26 of EDX
     (edx & (1 << 26)) != 0
 }
+
+/// Copy `len` bytes to write-combining memory such as a framebuffer
+///
+/// The aligned bulk is copied in 64-byte blocks with non-temporal stores,
+/// which fill whole write-combining buffers and bypass the cache; the
+/// unaligned head and tail are copied normally.
+///
+/// # Safety
+///
+/// `src` and `dst` must be valid for `len` bytes and must not overlap.
+pub unsafe fn copy_to_write_combining(dst: *mut u8, src: *const u8, len: usize) {
+    let head = dst.align_offset(16).min(len);
+    let blocks = (len - head) / 64;
+    let done = head + blocks * 64;
+    unsafe {
+        core::ptr::copy_nonoverlapping(src, dst, head);
+        if blocks > 0 {
+            stream_blocks(dst.add(head), src.add(head), blocks);
+        }
+        core::ptr::copy_nonoverlapping(src.add(done), dst.add(done), len - done);
+    }
+}
+
+/// Copy `blocks` 64-byte blocks from `src` to 16-byte aligned `dst`
+///
+/// In assembly because the target has SSE disabled for code generation.
+///
+/// # Safety
+///
+/// `blocks` must be non-zero, see [`copy_to_write_combining`].
+#[unsafe(naked)]
+unsafe extern "sysv64" fn stream_blocks(dst: *mut u8, src: *const u8, blocks: usize) {
+    naked_asm!(
+        "2:",
+        "movdqu xmm0, [rsi]",
+        "movdqu xmm1, [rsi + 16]",
+        "movdqu xmm2, [rsi + 32]",
+        "movdqu xmm3, [rsi + 48]",
+        "movntdq [rdi], xmm0",
+        "movntdq [rdi + 16], xmm1",
+        "movntdq [rdi + 32], xmm2",
+        "movntdq [rdi + 48], xmm3",
+        "add rsi, 64",
+        "add rdi, 64",
+        "dec rdx",
+        "jnz 2b",
+        // Non-temporal stores are weakly ordered
+        "sfence",
+        "ret",
+    );
+}
@@ -0,0 +1,171 @@
+//! Stack overflow protection
+//!
+//! CrabEFI and every image it starts run on the one boot stack from the
+//! linker script. Deeply recursive boot loader code used to overflow it
+//! into `.bss` without a trace. Now the lowest page of the stack is
+//! unmapped, so an overflow page faults on it. The CPU can't push the page
+//! fault frame onto the exhausted stack either and raises a double fault,
+//! which runs on an interrupt stack of its own (IST) and reports the
+//! overflow instead of triple-faulting. NMIs get their own interrupt stack
+//! too, as they can arrive at any point.
+//!
+//! The interrupt stacks are set in a TSS, which needs a GDT descriptor, so
+//! the BSP switches from the entry GDT to one with the same code and data
+//! selectors plus the TSS. The APs have no TSS and use an IDT without
+//! interrupt stacks.
+
+use core::arch::asm;
+use core::ptr::addr_of;
+
+use crate::efi::allocator::PAGE_SIZE;
+use crate::efi::pool_guard;
+
+use super::paging;
+
+/// IST slot of the double fault handler
+pub const DOUBLE_FAULT_IST: u8 = 1;
+
+/// IST slot of the NMI handler
+pub const NMI_IST: u8 = 2;
+
+/// Size of each interrupt stack
+const INTERRUPT_STACK_SIZE: usize = 16 * 1024;
+
+/// Selector of the TSS descriptor
+const TSS_SELECTOR: u16 = 0x18;
+
+// Linker symbols for the boot stack
+unsafe extern "C" {
+    static _stack_bottom: u8;
+}
+
+/// 64-bit Task State Segment
+#[repr(C, packed)]
+struct TaskStateSegment {
+    reserved0: u32,
+    /// Stacks for privilege level changes, unused
+    rsp: [u64; 3],
+    reserved1: u64,
+    /// Interrupt stacks, IST slot 1 first
+    ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    iomap_base: u16,
+}
+
+/// GDT with the selectors of the entry GDT and a TSS descriptor
+#[repr(C, align(16))]
+struct Gdt {
+    null: u64,
+    code: u64,
+    data: u64,
+    tss: [u64; 2],
+}
+
+/// GDT pointer structure for LGDT
+#[repr(C, packed)]
+struct GdtPointer {
+    limit: u16,
+    base: u64,
+}
+
+/// An interrupt stack
+#[repr(C, align(16))]
+struct InterruptStack([u8; INTERRUPT_STACK_SIZE]);
+
+static mut DOUBLE_FAULT_STACK: InterruptStack = InterruptStack([0; INTERRUPT_STACK_SIZE]);
+static mut NMI_STACK: InterruptStack = InterruptStack([0; INTERRUPT_STACK_SIZE]);
+
+static mut TSS: TaskStateSegment = TaskStateSegment {
+    reserved0: 0,
+    rsp: [0; 3],
+    reserved1: 0,
+    ist: [0; 7],
+    reserved2: 0,
+    reserved3: 0,
+    // No I/O permission bitmap
+    iomap_base: size_of::<TaskStateSegment>() as u16,
+};
+
+/// Written by LTR, which marks the TSS busy
+static mut GDT: Gdt = Gdt {
+    null: 0,
+    code: 0x00af9a000000ffff, // 64-bit code segment
+    data: 0x00cf92000000ffff, // 64-bit data segment
+    tss: [0; 2],
+};
+
+/// Build the descriptor of a 64-bit TSS at `base`
+fn tss_descriptor(base: u64) -> [u64; 2] {
+    let limit = (size_of::<TaskStateSegment>() - 1) as u64;
+    let low = (limit & 0xFFFF)
+        | (base & 0xFF_FFFF) << 16
+        | 0x89 << 40 // Present, 64-bit available TSS
+        | (limit >> 16 & 0xF) << 48
+        | (base >> 24 & 0xFF) << 56;
+    [low, base >> 32]
+}
+
+/// Top of an interrupt stack
+fn stack_top(stack: *const InterruptStack) -> u64 {
+    stack as u64 + INTERRUPT_STACK_SIZE as u64
+}
+
+/// Load the GDT with the TSS holding the interrupt stacks
+///
+/// Must run before the IDT refers to the IST slots.
+pub fn init() {
+    unsafe {
+        let tss = &mut *core::ptr::addr_of_mut!(TSS);
+        tss.ist[DOUBLE_FAULT_IST as usize - 1] = stack_top(addr_of!(DOUBLE_FAULT_STACK));
+        tss.ist[NMI_IST as usize - 1] = stack_top(addr_of!(NMI_STACK));
+
+        let gdt = &mut *core::ptr::addr_of_mut!(GDT);
+        gdt.tss = tss_descriptor(addr_of!(TSS) as u64);
+
+        let pointer = GdtPointer {
+            limit: (size_of::<Gdt>() - 1) as u16,
+            base: addr_of!(GDT) as u64,
+        };
+        // The code and data selectors stay the same, so the segment
+        // registers need no reload
+        asm!("lgdt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
+        asm!("ltr {0:x}", in(reg) TSS_SELECTOR, options(nostack, preserves_flags));
+    }
+    log::debug!("TSS loaded with double fault and NMI stacks");
+}
+
+/// The guard page at the bottom of the boot stack
+pub fn guard_page() -> u64 {
+    unsafe { &_stack_bottom as *const u8 as u64 }
+}
+
+/// Whether `addr` lies in the stack guard page
+pub fn is_guard_page(addr: u64) -> bool {
+    (guard_page()..guard_page() + PAGE_SIZE).contains(&addr)
+}
+
+/// Unmap the guard page
+///
+/// Needs the EFI allocator, for the page table splitting the 2MB mapping.
+pub fn init_guard() {
+    let page = guard_page();
+    if paging::set_page_present(page, false, pool_guard::allocate_page_table) {
+        log::debug!("Stack guard page at {:#x}", page);
+    } else {
+        log::warn!("Can't unmap the stack guard page at {:#x}", page);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_tss_descriptor() {
+        assert_eq!(
+            tss_descriptor(0xFFFF_8000_1234_5678),
+            [0x1200_8934_5678_0067, 0xFFFF_8000]
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Transfer to the OS waking vector
+//!
+//! After an S3 resume the OS expects control at the waking vector from the
+//! FACS, in the mode it asked for: real mode for `Firmware_Waking_Vector`,
+//! flat 32-bit protected mode with paging off for `X_Firmware_Waking_Vector`,
+//! or long mode if OSPM set `64BIT_WAKE_F`.
+//!
+//! Leaving long mode goes through a compatibility mode code segment, then
+//! paging, `EFER.LME` and PAE are turned off. For real mode the switch
+//! continues in a 16-bit protected mode segment based at the stub, with the
+//! stack segment based at the far pointer of the vector. The segment bases
+//! stay cached after `CR0.PE` is cleared, so the final far return works
+//! without copying anything below 1 MiB.
+
+use core::arch::{asm, naked_asm};
+
+/// Flat 32-bit code segment selector
+pub(super) const CODE32_SELECTOR: u16 = 0x08;
+
+/// Flat 32-bit data segment selector
+pub(super) const DATA32_SELECTOR: u16 = 0x10;
+
+/// 16-bit code segment selector, based at [`wake_real_mode`]
+const CODE16_SELECTOR: u16 = 0x18;
+
+/// 16-bit data segment selector, based at the far pointer of the vector
+const DATA16_SELECTOR: u16 = 0x20;
+
+/// Descriptor table pointer for `lgdt` and `lidt`
+#[repr(C, packed)]
+pub(super) struct DescriptorPointer {
+    pub(super) limit: u16,
+    pub(super) base: u64,
+}
+
+/// Mode the waking vector is entered in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeMode {
+    /// Real mode, CS:IP derived from the linear address
+    Real,
+    /// Flat 32-bit protected mode, paging disabled
+    Protected,
+    /// Long mode with the current identity mapping
+    Long,
+}
+
+/// Build a 16-bit segment descriptor with a 64 KiB limit
+fn descriptor16(base: u32, access: u8) -> u64 {
+    let base = base as u64;
+    0xFFFF | ((base & 0xFF_FFFF) << 16) | ((access as u64) << 40) | ((base >> 24) << 56)
+}
+
+/// Jump to the OS waking vector
+///
+/// # Safety
+///
+/// Leaves the firmware for good. `vector` must be the waking vector from the
+/// FACS and lie below 4 GiB (below 1 MiB in real mode), and the firmware
+/// image and its stack must be identity mapped below 4 GiB.
+pub unsafe fn jump_to_waking_vector(vector: u64, mode: WakeMode) -> ! {
+    if mode == WakeMode::Long {
+        unsafe { asm!("cli", "jmp {}", in(reg) vector, options(noreturn)) };
+    }
+
+    // Far pointer popped by the real mode `retf`: IP, then CS
+    let far_pointer: [u16; 2] = [(vector & 0xF) as u16, (vector >> 4) as u16];
+
+    let gdt: [u64; 5] = [
+        0,
+        0x00cf9a000000ffff, // 32-bit code
+        0x00cf92000000ffff, // 32-bit data
+        descriptor16(wake_real_mode as *const () as u32, 0x9A),
+        descriptor16(far_pointer.as_ptr() as u32, 0x92),
+    ];
+    let gdt_pointer = DescriptorPointer {
+        limit: (core::mem::size_of_val(&gdt) - 1) as u16,
+        base: gdt.as_ptr() as u64,
+    };
+    // The real mode interrupt vector table
+    let ivt_pointer = DescriptorPointer {
+        limit: 0x3FF,
+        base: 0,
+    };
+
+    unsafe {
+        wake_trampoline(
+            vector as u32,
+            (mode == WakeMode::Real) as u32,
+            &gdt_pointer,
+            &ivt_pointer,
+        )
+    }
+}
+
+/// Leave long mode and jump to `vector` in protected or real mode
+///
+/// Takes the vector in EDI, a real mode flag in ESI, and pointers to the
+/// GDT and IDT to load in RDX and RCX.
+#[unsafe(naked)]
+unsafe extern "sysv64" fn wake_trampoline(
+    vector: u32,
+    real_mode: u32,
+    gdt: *const DescriptorPointer,
+    ivt: *const DescriptorPointer,
+) -> ! {
+    naked_asm!(
+        "cli",
+        "lgdt [rdx]",
+        "lidt [rcx]",
+        // Far return into the 32-bit code segment (compatibility mode)
+        "push {code32}",
+        "lea rax, [rip + 2f]",
+        "push rax",
+        "retfq",
+        ".code32",
+        "2:",
+        "mov ax, {data32}",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov fs, ax",
+        "mov gs, ax",
+        "mov ss, ax",
+        // Paging off deactivates long mode, then clear EFER.LME and PAE
+        "mov eax, cr0",
+        "and eax, 0x7FFFFFFF",
+        "mov cr0, eax",
+        "mov ecx, 0xC0000080",
+        "rdmsr",
+        "and eax, 0xFFFFFEFF",
+        "wrmsr",
+        "mov eax, cr4",
+        "and eax, 0xFFFFFFDF",
+        "mov cr4, eax",
+        "test esi, esi",
+        "jnz 3f",
+        "jmp edi",
+        // Continue in the 16-bit code segment
+        "3:",
+        "push {code16}",
+        "push 0",
+        "retf",
+        ".code64",
+        code32 = const CODE32_SELECTOR,
+        data32 = const DATA32_SELECTOR,
+        code16 = const CODE16_SELECTOR,
+    );
+}
+
+/// Clear `CR0.PE` and far return to the real mode waking vector
+///
+/// Entered at offset 0 of the 16-bit code segment based at this function.
+#[unsafe(naked)]
+unsafe extern "C" fn wake_real_mode() {
+    naked_asm!(
+        ".code16",
+        "mov ax, {data16}",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov fs, ax",
+        "mov gs, ax",
+        "mov ss, ax",
+        "xor esp, esp",
+        "mov eax, cr0",
+        "and eax, 0xFFFFFFFE",
+        "mov cr0, eax",
+        // Pops IP and CS from the far pointer at SS:0
+        "retf",
+        ".code64",
+        data16 = const DATA16_SELECTOR,
+    );
+}
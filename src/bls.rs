@@ -0,0 +1,603 @@
+//! Boot Loader Specification
+//!
+//! Type #1 boot loader entries are the `.conf` files in `\loader\entries`.
+//! Besides the ESP, the Boot Loader Specification lets them live on the
+//! Extended Boot Loader Partition (XBOOTLDR) of the same disk, which
+//! distributions create when the ESP is too small for their kernels. Like
+//! systemd-boot, CrabEFI reads the entries of both and merges them into one
+//! list ordered by file name.
+//!
+//! Each entry becomes a boot menu entry starting its `linux` kernel with its
+//! `options` and `initrd` lines as the command line, or its `efi` program,
+//! so the menu offers what systemd-boot would. `\loader\loader.conf` on the
+//! ESP sets the menu timeout and the default entry, matched by a glob
+//! against the entry ids.
+//!
+//! Long FAT names aren't read, so entry files are known by their 8.3 names,
+//! such as `ARCH.CON` for `arch.conf`, and so is `LOADER.CON`. Kernel and
+//! initrd paths in entries need to be valid 8.3 names too.
+
+use core::fmt;
+
+use heapless::{String, Vec};
+
+use crate::cmdline::{KernelOptions, MAX_CMDLINE_LEN, MAX_INITRD_LEN};
+use crate::drivers::block::BlockDevice;
+use crate::fs::fat::{FatError, FatFilesystem};
+use crate::fs::gpt::{self, Partition, PartitionType};
+use crate::menu::BootMenu;
+
+/// Directory holding the entry files, on both volumes
+pub const ENTRIES_DIRECTORY: &str = "loader\\entries";
+
+/// Most entry files listed
+pub const MAX_ENTRY_FILES: usize = 32;
+
+/// Extension of entry files, as an 8.3 name shortens `.conf`
+const ENTRY_EXTENSION: &str = "CON";
+
+/// Loader configuration file on the ESP
+pub const LOADER_CONF_PATH: &str = "loader\\LOADER.CON";
+
+/// Largest configuration or entry file read
+const MAX_FILE_SIZE: usize = 4096;
+
+/// Timeout used for `timeout 0`, which shows no menu in systemd-boot
+///
+/// The menu has no hidden mode, so it is shown as briefly as possible.
+const HIDDEN_MENU_TIMEOUT: u32 = 1;
+
+/// A volume holding boot loader entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Volume {
+    /// The EFI System Partition
+    Esp,
+    /// The Extended Boot Loader Partition
+    Xbootldr,
+}
+
+impl fmt::Display for Volume {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Volume::Esp => "ESP",
+            Volume::Xbootldr => "XBOOTLDR",
+        })
+    }
+}
+
+/// The partitions of one disk holding boot loader entries
+#[derive(Debug, Clone)]
+pub struct Volumes {
+    /// The ESP
+    pub esp: Partition,
+    /// The XBOOTLDR partition on the ESP's disk and its number (1-based),
+    /// if there is one
+    pub xbootldr: Option<(u32, Partition)>,
+}
+
+impl Volumes {
+    /// The volumes of the disk the ESP `esp` is on
+    pub fn find(disk: &mut dyn BlockDevice, esp: &Partition) -> Self {
+        let xbootldr = gpt::read_gpt_header(disk)
+            .and_then(|header| gpt::read_partitions(disk, &header))
+            .ok()
+            .and_then(|partitions| {
+                partitions
+                    .into_iter()
+                    .zip(1..)
+                    .find(|(p, _)| p.partition_type() == Some(PartitionType::Xbootldr))
+                    .map(|(p, number)| (number, p))
+            });
+        if let Some((_, xbootldr)) = &xbootldr {
+            log::info!(
+                "Found XBOOTLDR partition {} at LBA {}",
+                xbootldr.partition_uuid(),
+                xbootldr.first_lba
+            );
+        }
+        Volumes {
+            esp: esp.clone(),
+            xbootldr,
+        }
+    }
+
+    /// The partition of `volume`
+    pub fn partition(&self, volume: Volume) -> Option<&Partition> {
+        match volume {
+            Volume::Esp => Some(&self.esp),
+            Volume::Xbootldr => self.xbootldr.as_ref().map(|(_, p)| p),
+        }
+    }
+
+    /// Mount the FAT filesystem of `volume`
+    pub fn mount<'a>(
+        &self,
+        disk: &'a mut dyn BlockDevice,
+        volume: Volume,
+    ) -> Result<FatFilesystem<'a>, FatError> {
+        let partition = self.partition(volume).ok_or(FatError::NotFound)?;
+        FatFilesystem::new(disk, partition.first_lba)
+    }
+}
+
+/// An entry file in `\loader\entries`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryFile {
+    /// Volume the file is on
+    pub volume: Volume,
+    /// 8.3 name of the file
+    pub name: String<12>,
+    /// File size in bytes
+    pub size: u32,
+}
+
+impl EntryFile {
+    /// Id of the entry: the file name without extension, in lowercase
+    pub fn id(&self) -> String<12> {
+        let stem = self
+            .name
+            .rsplit_once('.')
+            .map_or(&*self.name, |(stem, _)| stem);
+        stem.chars().map(|c| c.to_ascii_lowercase()).collect()
+    }
+
+    /// Path of the file on its volume
+    pub fn path(&self) -> String<32> {
+        let mut path = String::new();
+        let _ = path.push_str(ENTRIES_DIRECTORY);
+        let _ = path.push('\\');
+        let _ = path.push_str(&self.name);
+        path
+    }
+}
+
+/// Add the entry files of `volume` to `files`
+///
+/// Returns `false` once `files` is full.
+fn list_volume(
+    disk: &mut dyn BlockDevice,
+    volumes: &Volumes,
+    volume: Volume,
+    files: &mut Vec<EntryFile, MAX_ENTRY_FILES>,
+) -> bool {
+    let Ok(mut fat) = volumes.mount(disk, volume) else {
+        return true;
+    };
+    let Ok(directory) = fat.find_file(ENTRIES_DIRECTORY) else {
+        return true;
+    };
+    if !directory.is_directory() {
+        return true;
+    }
+
+    let cluster = directory.first_cluster();
+    for position in 0.. {
+        let Ok(Some(entry)) = fat.get_directory_entry_at_position(cluster, position) else {
+            break;
+        };
+        let name = entry.short_name();
+        let is_conf = name
+            .rsplit_once('.')
+            .is_some_and(|(_, ext)| ext.eq_ignore_ascii_case(ENTRY_EXTENSION));
+        if !entry.is_file() || !is_conf {
+            continue;
+        }
+        let file = EntryFile {
+            volume,
+            name,
+            size: entry.file_size(),
+        };
+        if files.push(file).is_err() {
+            log::warn!("More than {} boot loader entries", MAX_ENTRY_FILES);
+            return false;
+        }
+    }
+    true
+}
+
+/// The entry files of the ESP and XBOOTLDR partition, ordered by name
+///
+/// A name on both volumes is listed twice, the ESP's file first.
+pub fn entry_files(
+    disk: &mut dyn BlockDevice,
+    volumes: &Volumes,
+) -> Vec<EntryFile, MAX_ENTRY_FILES> {
+    let mut files = Vec::new();
+    if list_volume(disk, volumes, Volume::Esp, &mut files) && volumes.xbootldr.is_some() {
+        list_volume(disk, volumes, Volume::Xbootldr, &mut files);
+    }
+    files.sort_unstable_by(|a, b| a.name.cmp(&b.name).then(a.volume.cmp(&b.volume)));
+    files
+}
+
+/// Settings of `loader.conf`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoaderConf {
+    /// Menu timeout in seconds, 0 to wait for a key
+    pub timeout: Option<u32>,
+    /// Glob matching the id of the default entry
+    pub default: Option<String<64>>,
+}
+
+impl LoaderConf {
+    /// Parse the text of `loader.conf`, ignoring unknown settings
+    pub fn parse(text: &str) -> Self {
+        let mut conf = LoaderConf::default();
+        for (key, value) in settings(text) {
+            match key {
+                "timeout" => {
+                    conf.timeout = match value {
+                        "menu-force" => Some(0),
+                        "0" | "menu-hidden" | "menu-disabled" => Some(HIDDEN_MENU_TIMEOUT),
+                        value => value.parse().ok(),
+                    };
+                }
+                // Saved and one-shot entries aren't supported
+                "default" if !value.starts_with('@') => {
+                    conf.default = value.try_into().ok();
+                }
+                _ => {}
+            }
+        }
+        conf
+    }
+
+    /// Read `loader.conf` from the ESP
+    pub fn load(disk: &mut dyn BlockDevice, volumes: &Volumes) -> Option<Self> {
+        let mut fat = volumes.mount(disk, Volume::Esp).ok()?;
+        let mut buf = [0u8; MAX_FILE_SIZE];
+        let len = fat.read_file_all(LOADER_CONF_PATH, &mut buf, None).ok()?;
+        let conf = Self::parse(core::str::from_utf8(&buf[..len]).ok()?);
+        log::info!("BLS: loader.conf {:?}", conf);
+        Some(conf)
+    }
+
+    /// Select the default entry and set the timeout of the boot menu
+    pub fn apply(&self, menu: &mut BootMenu) {
+        if let Some(pattern) = &self.default {
+            // With or without the `.conf` extension, as systemd-boot matches
+            let index = (0..menu.entry_count()).find(|&index| {
+                menu.get_entry(index).is_some_and(|entry| {
+                    entry.loader_entry.as_ref().is_some_and(|id| {
+                        glob_match(pattern, id) || glob_match(pattern, &entry.loader_id())
+                    })
+                })
+            });
+            match index {
+                Some(index) => menu.set_selected(index),
+                None => log::warn!("BLS: no entry matches default {}", pattern),
+            }
+        }
+        if let Some(timeout) = self.timeout {
+            menu.set_timeout(timeout);
+        }
+    }
+}
+
+/// The `key value` lines of a configuration file, comments skipped
+fn settings(text: &str) -> impl Iterator<Item = (&str, &str)> {
+    text.lines().filter_map(|line| {
+        let line = line.trim();
+        if line.starts_with('#') {
+            return None;
+        }
+        match line.split_once([' ', '\t']) {
+            Some((key, value)) => Some((key, value.trim())),
+            None if !line.is_empty() => Some((line, "")),
+            None => None,
+        }
+    })
+}
+
+/// Match `text` against a glob with `*` and `?`, ignoring case
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` and the text it was matched up to
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(&c) if c == b'?' || c.eq_ignore_ascii_case(&text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Most initrds of one entry
+const MAX_INITRDS: usize = 4;
+
+/// A Type #1 boot loader entry
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entry {
+    /// Title for the menu, the id if the entry has none
+    pub title: String<64>,
+    /// Version shown after the title
+    pub version: String<32>,
+    /// Kernel or EFI program to start, relative to the volume root
+    pub path: String<128>,
+    /// Initrds, relative to the volume root
+    pub initrds: Vec<String<MAX_INITRD_LEN>, MAX_INITRDS>,
+    /// Kernel command line
+    pub options: String<MAX_CMDLINE_LEN>,
+}
+
+impl Entry {
+    /// Parse the text of an entry file
+    ///
+    /// Returns `None` for entries with neither `linux` nor `efi`, or with a
+    /// path or command line too long to keep. Long titles are cut off.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut entry = Entry::default();
+        for (key, value) in settings(text) {
+            match key {
+                "title" => entry.title = truncated(value),
+                "version" => entry.version = truncated(value),
+                "linux" | "efi" => entry.path = volume_path(value)?,
+                "initrd" => {
+                    entry.initrds.push(volume_path(value)?).ok()?;
+                }
+                "options" => {
+                    if !entry.options.is_empty() {
+                        entry.options.push(' ').ok()?;
+                    }
+                    entry.options.push_str(value).ok()?;
+                }
+                _ => {}
+            }
+        }
+        (!entry.path.is_empty()).then_some(entry)
+    }
+
+    /// Menu title: the title and version
+    pub fn display_title(&self) -> String<64> {
+        let mut title = self.title.clone();
+        if !self.version.is_empty() {
+            let _ = title.push(' ');
+            let _ = title.push_str(&self.version);
+        }
+        title
+    }
+
+    /// The command line and initrds as boot menu options
+    ///
+    /// The last initrd takes the initrd field; the others go first, as
+    /// `initrd=` arguments on the command line, so the EFI stub loads them in
+    /// the order of the entry.
+    pub fn kernel_options(&self) -> KernelOptions {
+        let mut options = KernelOptions::default();
+        let (last, others) = match self.initrds.split_last() {
+            Some((last, others)) => (Some(last), others),
+            None => (None, &[][..]),
+        };
+        for initrd in others {
+            let _ = options.cmdline.push_str("initrd=");
+            let _ = options.cmdline.push_str(initrd);
+            let _ = options.cmdline.push(' ');
+        }
+        let _ = options.cmdline.push_str(&self.options);
+        if let Some(last) = last {
+            options.initrd = last.clone();
+        }
+        options
+    }
+}
+
+/// Copy `text` into a string, cut off at its capacity
+fn truncated<const N: usize>(text: &str) -> String<N> {
+    let mut string = String::new();
+    for c in text.chars() {
+        if string.push(c).is_err() {
+            break;
+        }
+    }
+    string
+}
+
+/// Convert a path of an entry to one relative to the volume root
+fn volume_path<const N: usize>(path: &str) -> Option<String<N>> {
+    path.trim_start_matches(['/', '\\'])
+        .chars()
+        .map(|c| if c == '/' { '\\' } else { c })
+        .try_fold(String::new(), |mut converted, c| {
+            converted.push(c).ok()?;
+            Some(converted)
+        })
+}
+
+/// A parsed entry and where it came from
+#[derive(Debug, Clone)]
+pub struct LoadedEntry {
+    /// The entry file
+    pub file: EntryFile,
+    /// Its contents
+    pub entry: Entry,
+}
+
+/// Read and parse the entries of the ESP and XBOOTLDR partition
+///
+/// Entries without a kernel, or whose kernel is missing, are left out.
+pub fn load_entries(
+    disk: &mut dyn BlockDevice,
+    volumes: &Volumes,
+) -> Vec<LoadedEntry, MAX_ENTRY_FILES> {
+    let mut entries = Vec::new();
+    let mut buf = [0u8; MAX_FILE_SIZE];
+    for file in entry_files(disk, volumes) {
+        let Ok(mut fat) = volumes.mount(disk, file.volume) else {
+            continue;
+        };
+        let Ok(len) = fat.read_file_all(&file.path(), &mut buf, None) else {
+            log::warn!("BLS: can't read {} on the {}", file.name, file.volume);
+            continue;
+        };
+        let text = core::str::from_utf8(&buf[..len]).unwrap_or("");
+        let Some(mut entry) = Entry::parse(text) else {
+            log::warn!("BLS: ignoring {} on the {}", file.name, file.volume);
+            continue;
+        };
+        if fat.file_size(&entry.path).is_err() {
+            log::warn!("BLS: {} of {} not found", entry.path, file.name);
+            continue;
+        }
+        if entry.title.is_empty() {
+            let _ = entry.title.push_str(&file.id());
+        }
+        log::info!("BLS: entry {} ({})", entry.display_title(), file.id());
+        // Both are at most MAX_ENTRY_FILES long
+        let _ = entries.push(LoadedEntry { file, entry });
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fat::FatType;
+    use crate::testing::{MemoryDisk, fat_image, gpt_image};
+
+    #[test]
+    fn merges_xbootldr_entries() {
+        let esp = fat_image(
+            FatType::Fat16,
+            &[
+                ("LOADER/ENTRIES/WINDOWS.CON", b"title Windows"),
+                ("LOADER/ENTRIES/README.TXT", b"not an entry"),
+                ("LOADER/LOADER.CON", b"timeout 3"),
+            ],
+        );
+        let xbootldr = fat_image(
+            FatType::Fat16,
+            &[
+                ("LOADER/ENTRIES/ARCH.CON", b"title Arch Linux"),
+                ("LOADER/ENTRIES/ZEN.CON", b"title Arch Linux (zen)"),
+            ],
+        );
+        let image = gpt_image(&[
+            (PartitionType::Esp.guid(), &esp),
+            (PartitionType::Xbootldr.guid(), &xbootldr),
+        ]);
+        let mut disk = MemoryDisk::new(image, 512);
+
+        let esp = gpt::find_esp(&mut disk).unwrap();
+        let volumes = Volumes::find(&mut disk, &esp);
+        let (number, xbootldr) = volumes.xbootldr.as_ref().unwrap();
+        assert_eq!((*number, xbootldr.partition_guid), (2, [2; 16]));
+
+        let files = entry_files(&mut disk, &volumes);
+        let names: std::vec::Vec<(Volume, &str, u32)> = files
+            .iter()
+            .map(|f| (f.volume, f.name.as_str(), f.size))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (Volume::Xbootldr, "ARCH.CON", 16),
+                (Volume::Esp, "WINDOWS.CON", 13),
+                (Volume::Xbootldr, "ZEN.CON", 22),
+            ]
+        );
+        assert_eq!(files[0].path().as_str(), "loader\\entries\\ARCH.CON");
+        assert_eq!(files[0].id().as_str(), "arch");
+
+        let mut fat = volumes.mount(&mut disk, Volume::Xbootldr).unwrap();
+        let mut buf = [0u8; 16];
+        let len = fat.read_file_all(&files[0].path(), &mut buf, None).unwrap();
+        assert_eq!(len, 16);
+        assert_eq!(&buf, b"title Arch Linux");
+    }
+
+    #[test]
+    fn parses_loader_conf() {
+        let conf = LoaderConf::parse("# comment\ntimeout 3\ndefault arch-*\nconsole-mode max\n");
+        assert_eq!(conf.timeout, Some(3));
+        assert_eq!(conf.default.as_deref(), Some("arch-*"));
+
+        let conf = LoaderConf::parse("timeout menu-force\ndefault @saved");
+        assert_eq!(conf.timeout, Some(0));
+        assert_eq!(conf.default, None);
+        assert_eq!(
+            LoaderConf::parse("timeout 0").timeout,
+            Some(HIDDEN_MENU_TIMEOUT)
+        );
+        assert_eq!(LoaderConf::parse("timeout x"), LoaderConf::default());
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("arch*", "arch-lts"));
+        assert!(glob_match("*LTS", "arch-lts"));
+        assert!(glob_match("a?ch", "arch"));
+        assert!(glob_match("*-*-*", "a-b-c"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("arch", "arch-lts"));
+        assert!(!glob_match("*zen", "arch-lts"));
+    }
+
+    #[test]
+    fn parses_entries() {
+        let entry = Entry::parse(
+            "title Arch Linux\nversion 6.9\nlinux /vmlinuz\ninitrd /intel-ucode.img\n\
+             initrd /initramfs.img\noptions root=/dev/sda2\noptions\tquiet\n",
+        )
+        .unwrap();
+        assert_eq!(entry.display_title().as_str(), "Arch Linux 6.9");
+        assert_eq!(entry.path.as_str(), "vmlinuz");
+        let options = entry.kernel_options();
+        assert_eq!(
+            options.load_options().as_str(),
+            "initrd=intel-ucode.img root=/dev/sda2 quiet initrd=initramfs.img"
+        );
+
+        let entry = Entry::parse("title Windows\nefi /EFI/Microsoft/Boot/bootmgfw.efi").unwrap();
+        assert_eq!(entry.path.as_str(), "EFI\\Microsoft\\Boot\\bootmgfw.efi");
+        assert!(entry.kernel_options().is_empty());
+
+        assert_eq!(Entry::parse("title Nothing to boot\n"), None);
+    }
+
+    #[test]
+    fn loads_entries() {
+        let esp = fat_image(
+            FatType::Fat16,
+            &[
+                ("LOADER/LOADER.CON", b"timeout 10\ndefault zen"),
+                (
+                    "LOADER/ENTRIES/ZEN.CON",
+                    b"linux /VMLINUZ.ZEN\noptions quiet",
+                ),
+                ("LOADER/ENTRIES/GONE.CON", b"title Removed\nlinux /OLD"),
+                ("VMLINUZ.ZEN", b"MZ"),
+            ],
+        );
+        let image = gpt_image(&[(PartitionType::Esp.guid(), &esp)]);
+        let mut disk = MemoryDisk::new(image, 512);
+
+        let esp = gpt::find_esp(&mut disk).unwrap();
+        let volumes = Volumes::find(&mut disk, &esp);
+        assert!(volumes.xbootldr.is_none());
+
+        let conf = LoaderConf::load(&mut disk, &volumes).unwrap();
+        assert_eq!(conf.timeout, Some(10));
+
+        let entries = load_entries(&mut disk, &volumes);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].file.id().as_str(), "zen");
+        assert_eq!(entries[0].entry.title.as_str(), "zen");
+        assert_eq!(entries[0].entry.path.as_str(), "VMLINUZ.ZEN");
+    }
+}
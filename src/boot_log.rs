@@ -0,0 +1,305 @@
+//! Boot History
+//!
+//! The last [`MAX_RECORDS`] boots are recorded, to find out why a machine
+//! in the field sometimes doesn't come up. Each record holds the RTC time
+//! the entry was started, which entry it was and how far the boot got:
+//!
+//! - `started`: the loader ran but never called ExitBootServices, because
+//!   it hung, or the machine reset or lost power while it ran
+//! - `booted`: the loader reached ExitBootServices
+//! - `failed`: the loader couldn't be loaded or returned to the firmware
+//!
+//! Entries are identified by their [loader id](BootEntry::loader_id), which
+//! stays the same when disks are added or entries move in the menu. The
+//! setup screen and the recovery console's `bootlog` command list the
+//! records.
+//!
+//! The records are kept, newest first, in the non-volatile variable
+//! `BootLog` under [`CRABEFI_VARIABLE_GUID`], which is saved to the
+//! SMMSTORE region with the other variables. Without an SMMSTORE region the
+//! history only covers the current boot. The variable is a version byte and
+//! a record count, followed by the records:
+//!
+//! ```text
+//! u32 time       year - 2000 (bits 26-31), month, day, hour, minute,
+//!                second (bits 0-5)
+//! u8  outcome    0 started, 1 booted, 2 failed
+//! u8  length     length of the loader id
+//! u8  id[length]
+//! ```
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use heapless::{String, Vec};
+use r_efi::efi;
+
+use crate::efi::runtime_services::{
+    CRABEFI_VARIABLE_GUID, read_rtc_time, read_variable, write_variable,
+};
+use crate::menu::BootEntry;
+
+/// Number of boots remembered, as many as fit in one variable
+pub const MAX_RECORDS: usize = 14;
+
+/// Longest loader id, see [`BootEntry::loader_id`]
+const MAX_ID_LEN: usize = 64;
+
+/// Variable holding the records
+const VARIABLE: &str = "BootLog";
+
+/// Layout version of the variable
+const LOG_VERSION: u8 = 1;
+
+/// Version and record count
+const HEADER_SIZE: usize = 2;
+
+/// Time, outcome and id length
+const RECORD_HEADER_SIZE: usize = 6;
+
+/// Largest size of the variable
+const LOG_SIZE: usize = HEADER_SIZE + MAX_RECORDS * (RECORD_HEADER_SIZE + MAX_ID_LEN);
+
+/// How far a boot got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Started, but ExitBootServices was never reached
+    Started = 0,
+    /// ExitBootServices was reached
+    Booted = 1,
+    /// The loader failed to load or returned
+    Failed = 2,
+}
+
+impl Outcome {
+    fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(Outcome::Started),
+            1 => Some(Outcome::Booted),
+            2 => Some(Outcome::Failed),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Started => "started",
+            Outcome::Booted => "booted",
+            Outcome::Failed => "failed",
+        })
+    }
+}
+
+/// RTC date and time packed into 32 bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp(u32);
+
+impl Timestamp {
+    /// The current RTC time
+    fn now() -> Self {
+        let (year, month, day, hour, minute, second) = read_rtc_time();
+        Self::new(year, month, day, hour, minute, second)
+    }
+
+    fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Self {
+        let year = year.saturating_sub(2000).min(63) as u32;
+        Timestamp(
+            year << 26
+                | (month as u32 & 0xF) << 22
+                | (day as u32 & 0x1F) << 17
+                | (hour as u32 & 0x1F) << 12
+                | (minute as u32 & 0x3F) << 6
+                | second as u32 & 0x3F,
+        )
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let t = self.0;
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            2000 + (t >> 26),
+            (t >> 22) & 0xF,
+            (t >> 17) & 0x1F,
+            (t >> 12) & 0x1F,
+            (t >> 6) & 0x3F,
+            t & 0x3F
+        )
+    }
+}
+
+/// One recorded boot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// When the entry was started
+    pub time: Timestamp,
+    /// Loader id of the entry, see [`BootEntry::loader_id`]
+    pub entry: String<MAX_ID_LEN>,
+    /// How far the boot got
+    pub outcome: Outcome,
+}
+
+impl Record {
+    /// Decode the record at the start of `bytes`, returning its size
+    fn decode(bytes: &[u8]) -> Option<(Self, usize)> {
+        let header = bytes.get(..RECORD_HEADER_SIZE)?;
+        let time = u32::from_le_bytes(header[..4].try_into().ok()?);
+        let outcome = Outcome::from_bits(header[4])?;
+        let size = RECORD_HEADER_SIZE + header[5] as usize;
+        let id = core::str::from_utf8(bytes.get(RECORD_HEADER_SIZE..size)?).ok()?;
+        let record = Record {
+            time: Timestamp(time),
+            entry: String::try_from(id).ok()?,
+            outcome,
+        };
+        Some((record, size))
+    }
+
+    fn encode(&self, out: &mut Vec<u8, LOG_SIZE>) {
+        let _ = out.extend_from_slice(&self.time.0.to_le_bytes());
+        let _ = out.push(self.outcome as u8);
+        let _ = out.push(self.entry.len() as u8);
+        let _ = out.extend_from_slice(self.entry.as_bytes());
+    }
+}
+
+/// The recorded boots, newest first
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct BootLog {
+    records: Vec<Record, MAX_RECORDS>,
+}
+
+impl BootLog {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (&[version, count], mut rest) = bytes.split_first_chunk::<HEADER_SIZE>()?;
+        if version != LOG_VERSION || count as usize > MAX_RECORDS {
+            return None;
+        }
+        let mut log = BootLog::default();
+        for _ in 0..count {
+            let (record, size) = Record::decode(rest)?;
+            let _ = log.records.push(record);
+            rest = &rest[size..];
+        }
+        rest.is_empty().then_some(log)
+    }
+
+    fn encode(&self) -> Vec<u8, LOG_SIZE> {
+        let mut bytes = Vec::new();
+        let _ = bytes.push(LOG_VERSION);
+        let _ = bytes.push(self.records.len() as u8);
+        for record in &self.records {
+            record.encode(&mut bytes);
+        }
+        bytes
+    }
+
+    fn load() -> Self {
+        let mut bytes = [0u8; LOG_SIZE];
+        read_variable(VARIABLE, &CRABEFI_VARIABLE_GUID, &mut bytes)
+            .and_then(|size| BootLog::decode(&bytes[..size]))
+            .unwrap_or_default()
+    }
+
+    fn store(&self) {
+        let attributes = efi::VARIABLE_NON_VOLATILE
+            | efi::VARIABLE_BOOTSERVICE_ACCESS
+            | efi::VARIABLE_RUNTIME_ACCESS;
+        let status = write_variable(VARIABLE, &CRABEFI_VARIABLE_GUID, attributes, &self.encode());
+        if status != efi::Status::SUCCESS {
+            log::warn!("Failed to store the boot log: {:?}", status);
+        }
+    }
+
+    /// Add a record, dropping the oldest one if the log is full
+    fn push(&mut self, record: Record) {
+        if self.records.is_full() {
+            self.records.pop();
+        }
+        let _ = self.records.insert(0, record);
+    }
+}
+
+/// Set once the current boot was recorded, as the newest record
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Record that `entry` is about to be started
+pub fn record_start(entry: &BootEntry) {
+    let mut log = BootLog::load();
+    log.push(Record {
+        time: Timestamp::now(),
+        entry: entry.loader_id(),
+        outcome: Outcome::Started,
+    });
+    log.store();
+    STARTED.store(true, Ordering::Relaxed);
+}
+
+/// Record how far the current boot got
+pub fn record_outcome(outcome: Outcome) {
+    if !STARTED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut log = BootLog::load();
+    let Some(record) = log.records.first_mut() else {
+        return;
+    };
+    record.outcome = outcome;
+    log.store();
+}
+
+/// The recorded boots, newest first
+pub fn records() -> Vec<Record, MAX_RECORDS> {
+    BootLog::load().records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MAX_VARIABLE_DATA_SIZE;
+
+    fn record(second: u8, entry: &str, outcome: Outcome) -> Record {
+        Record {
+            time: Timestamp::new(2026, 10, 16, 12, 0, second),
+            entry: String::try_from(entry).unwrap(),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn log_round_trips() {
+        assert!(LOG_SIZE <= MAX_VARIABLE_DATA_SIZE);
+
+        let mut log = BootLog::default();
+        for i in 0..MAX_RECORDS as u8 + 2 {
+            let outcome = if i % 2 == 0 {
+                Outcome::Booted
+            } else {
+                Outcome::Failed
+            };
+            log.push(record(i, "fedora-6.11.4-300.fc41.x86_64.conf", outcome));
+        }
+        log.push(record(59, &"x".repeat(MAX_ID_LEN), Outcome::Started));
+        assert_eq!(log.records.len(), MAX_RECORDS);
+
+        let decoded = BootLog::decode(&log.encode()).unwrap();
+        assert_eq!(decoded, log);
+        let seconds: std::vec::Vec<u32> = decoded.records.iter().map(|r| r.time.0 & 0x3F).collect();
+        assert_eq!(seconds[..3], [59, 15, 14]);
+        assert_eq!(seconds[MAX_RECORDS - 1], 3);
+        assert_eq!(
+            std::format!("{}", decoded.records[1].time),
+            "2026-10-16 12:00:15"
+        );
+
+        let bytes = log.encode();
+        assert_eq!(BootLog::decode(&bytes[..bytes.len() - 1]), None);
+        let mut corrupt = bytes.clone();
+        corrupt[HEADER_SIZE + 4] = 3;
+        assert_eq!(BootLog::decode(&corrupt), None);
+    }
+}
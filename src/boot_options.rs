@@ -0,0 +1,273 @@
+//! Boot Options
+//!
+//! Distribution installers and shim's `fallback.efi` expect the firmware to
+//! remember the `Boot####` entries they create. On removable media, or after
+//! the boot entries were lost, shim started from `\EFI\BOOT\BOOTX64.EFI` runs
+//! `fallback.efi`, which registers the OS loader found in `\EFI\<vendor>` as
+//! a new `Boot####` entry, puts it first in `BootOrder` and resets the
+//! system.
+//!
+//! Without an SMMSTORE region EFI variables are not persisted across
+//! reboots, so before a reset the first active entry of `BootOrder` is also
+//! saved to CMOS NVRAM: the GPT
+//! partition GUID from its Hard Drive node and the file path after it. When
+//! the boot menu is built, an ESP with that partition GUID gets an entry for
+//! the saved path, placed first so it becomes the default. The record is
+//! kept at 0xA0 in bank 1 unless the
+//! [CMOS option table](crate::coreboot::cmos_options) uses those bytes or
+//! moves it; nothing is saved if it can't be kept.
+//!
+//! Only `BootOrder` is honored. The saved option is not published as a
+//! `Boot####` variable again after the reboot.
+
+use crate::coreboot::cmos_options;
+use crate::drivers::cmos;
+use crate::efi::protocols::device_path;
+use crate::efi::runtime_services::{GLOBAL_VARIABLE_GUID, read_variable};
+use crate::state::MAX_VARIABLE_DATA_SIZE;
+use core::fmt::Write;
+use heapless::String;
+use r_efi::protocols::device_path::{Media, TYPE_END, TYPE_MEDIA};
+
+/// Default CMOS offset of the boot option record (bank 1, below the boot
+/// slot record)
+const CMOS_OFFSET: u8 = 0xA0;
+
+/// Option table name of the boot option record
+const CMOS_NAME: &str = "crabefi_boot_option";
+
+/// Size of the boot option record in CMOS
+const RECORD_SIZE: usize = 80;
+
+/// Magic byte marking a valid boot option record
+const RECORD_MAGIC: u8 = 0xB0;
+
+/// Magic, path length, checksum and partition GUID
+const RECORD_HEADER_SIZE: usize = 19;
+
+/// Maximum length of a saved file path
+pub const MAX_PATH_LEN: usize = RECORD_SIZE - RECORD_HEADER_SIZE;
+
+/// Maximum number of `BootOrder` entries looked at
+const MAX_BOOT_ORDER: usize = 32;
+
+/// `LOAD_OPTION_ACTIVE` attribute of an `EFI_LOAD_OPTION`
+const LOAD_OPTION_ACTIVE: u32 = 0x0000_0001;
+
+/// Signature type of a GPT Hard Drive node
+const SIGNATURE_TYPE_GUID: u8 = 0x02;
+
+/// A boot option that can be saved across reboots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootOption {
+    /// Unique GUID of the GPT partition holding the loader
+    pub partition_guid: [u8; 16],
+    /// Loader path on the partition, without a leading backslash
+    pub path: String<MAX_PATH_LEN>,
+}
+
+impl BootOption {
+    /// Load the saved boot option from CMOS
+    pub fn load() -> Option<Self> {
+        let start = cmos_options::reserve(CMOS_NAME, CMOS_OFFSET, RECORD_SIZE)?;
+        let mut record = [0u8; RECORD_SIZE];
+        for (offset, byte) in (start..).zip(record.iter_mut()) {
+            *byte = cmos::read(offset);
+        }
+
+        let (header, data) = record.split_at(RECORD_HEADER_SIZE);
+        let len = header[1] as usize;
+        if header[0] != RECORD_MAGIC || len == 0 || len > MAX_PATH_LEN {
+            return None;
+        }
+        let path = &data[..len];
+        if header[2] != record_checksum(&header[3..], path) {
+            log::debug!("Boot options: corrupt record in CMOS");
+            return None;
+        }
+
+        let mut option = BootOption {
+            partition_guid: header[3..].try_into().ok()?,
+            path: String::new(),
+        };
+        option
+            .path
+            .push_str(core::str::from_utf8(path).ok()?)
+            .ok()?;
+        Some(option)
+    }
+
+    /// Write the boot option to CMOS
+    fn store(&self) {
+        let Some(start) = cmos_options::reserve(CMOS_NAME, CMOS_OFFSET, RECORD_SIZE) else {
+            return;
+        };
+        let path = self.path.as_bytes();
+        let mut record = [0u8; RECORD_SIZE];
+        record[0] = RECORD_MAGIC;
+        record[1] = path.len() as u8;
+        record[2] = record_checksum(&self.partition_guid, path);
+        record[3..RECORD_HEADER_SIZE].copy_from_slice(&self.partition_guid);
+        record[RECORD_HEADER_SIZE..][..path.len()].copy_from_slice(path);
+
+        for (offset, byte) in (start..).zip(record) {
+            cmos::write(offset, byte);
+        }
+    }
+
+    /// Menu name of the option: the vendor directory of the loader
+    pub fn name(&self) -> &str {
+        let mut components = self.path.rsplit('\\');
+        components.next();
+        components.next().unwrap_or(&self.path)
+    }
+}
+
+/// Checksum over the variable part of the boot option record
+fn record_checksum(guid: &[u8], path: &[u8]) -> u8 {
+    !guid
+        .iter()
+        .chain(path)
+        .fold(RECORD_MAGIC.wrapping_add(path.len() as u8), |sum, &b| {
+            sum.wrapping_add(b)
+        })
+}
+
+/// Parse an `EFI_LOAD_OPTION` into a boot option
+///
+/// Returns `None` for inactive options and options whose device path has no
+/// GPT Hard Drive node followed by File Path nodes.
+pub fn parse_load_option(data: &[u8]) -> Option<BootOption> {
+    let attributes = u32::from_le_bytes(data.get(..4)?.try_into().ok()?);
+    let path_len = u16::from_le_bytes(data.get(4..6)?.try_into().ok()?) as usize;
+    if attributes & LOAD_OPTION_ACTIVE == 0 {
+        return None;
+    }
+
+    // Skip the null-terminated UCS-2 description
+    let mut offset = 6;
+    loop {
+        let unit = data.get(offset..offset + 2)?;
+        offset += 2;
+        if unit == [0, 0] {
+            break;
+        }
+    }
+    let path = data.get(offset..offset + path_len)?;
+
+    // Walk the nodes of the first device path instance within bounds
+    let mut partition = None;
+    let mut node = 0;
+    loop {
+        let header = path.get(node..node + 4)?;
+        let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+        if len < 4 || node + len > path.len() {
+            return None;
+        }
+        if header[0] == TYPE_END {
+            break;
+        }
+        if header[0] == TYPE_MEDIA
+            && header[1] == Media::SUBTYPE_HARDDRIVE
+            && len >= 42
+            && path[node + 41] == SIGNATURE_TYPE_GUID
+        {
+            partition = Some((path[node + 24..node + 40].try_into().ok()?, node + len));
+        }
+        node += len;
+    }
+    let (partition_guid, files): ([u8; 16], usize) = partition?;
+
+    // Safety: the nodes from `files` up to the End node were checked above
+    let name =
+        unsafe { device_path::file_path_to_str::<MAX_PATH_LEN>(path[files..].as_ptr().cast()) }?;
+    let name = name.trim_start_matches('\\');
+    if name.is_empty() || !name.is_ascii() {
+        return None;
+    }
+
+    let mut option = BootOption {
+        partition_guid,
+        path: String::new(),
+    };
+    option.path.push_str(name).ok()?;
+    Some(option)
+}
+
+/// The first active boot option in `BootOrder` that can be saved
+fn default_option() -> Option<BootOption> {
+    let mut order = [0u8; MAX_BOOT_ORDER * 2];
+    let len = read_variable("BootOrder", &GLOBAL_VARIABLE_GUID, &mut order)?;
+
+    order[..len].chunks_exact(2).find_map(|number| {
+        let number = u16::from_le_bytes([number[0], number[1]]);
+        let mut name: String<8> = String::new();
+        write!(name, "Boot{:04X}", number).ok()?;
+
+        let mut data = [0u8; MAX_VARIABLE_DATA_SIZE];
+        let len = read_variable(&name, &GLOBAL_VARIABLE_GUID, &mut data)?;
+        parse_load_option(&data[..len])
+    })
+}
+
+/// Save the default boot option to CMOS
+///
+/// Called before a reset. Leaves the saved option alone if `BootOrder` has
+/// no usable entry, since the variables of the previous boot are gone.
+pub fn persist_default() {
+    let Some(option) = default_option() else {
+        return;
+    };
+    if BootOption::load().as_ref() == Some(&option) {
+        return;
+    }
+
+    log::info!("Boot options: saving {} as the default", option.path);
+    option.store();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build an `EFI_LOAD_OPTION` for a file on a GPT partition
+    fn load_option(attributes: u32, guid: &[u8; 16], file: &str) -> Vec<u8> {
+        let mut path = vec![TYPE_MEDIA, Media::SUBTYPE_HARDDRIVE, 42, 0];
+        path.extend([0u8; 20]);
+        path.extend(guid);
+        path.extend([0x02, SIGNATURE_TYPE_GUID]);
+        let name: Vec<u8> = file
+            .encode_utf16()
+            .chain([0])
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        path.extend([TYPE_MEDIA, Media::SUBTYPE_FILE_PATH]);
+        path.extend(((name.len() + 4) as u16).to_le_bytes());
+        path.extend(name);
+        path.extend([TYPE_END, 0xFF, 4, 0]);
+
+        let mut data = attributes.to_le_bytes().to_vec();
+        data.extend((path.len() as u16).to_le_bytes());
+        data.extend(
+            "Fedora"
+                .encode_utf16()
+                .chain([0])
+                .flat_map(u16::to_le_bytes),
+        );
+        data.extend(path);
+        data
+    }
+
+    #[test]
+    fn parses_load_option() {
+        let guid = [0x5a; 16];
+        let data = load_option(LOAD_OPTION_ACTIVE, &guid, "\\EFI\\fedora\\shimx64.efi");
+        let option = parse_load_option(&data).unwrap();
+        assert_eq!(option.partition_guid, guid);
+        assert_eq!(option.path, "EFI\\fedora\\shimx64.efi");
+        assert_eq!(option.name(), "fedora");
+
+        assert_eq!(parse_load_option(&load_option(0, &guid, "\\a.efi")), None);
+        assert_eq!(parse_load_option(&data[..data.len() - 2]), None);
+    }
+}
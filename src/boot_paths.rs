@@ -0,0 +1,134 @@
+//! Bootloader paths
+//!
+//! Only the removable media path `\EFI\BOOT\BOOTX64.EFI` used to be looked
+//! for, so an ESP whose OS installer didn't write the fallback loader showed
+//! up empty. Each ESP now gets an entry for the first of these paths that
+//! exists:
+//!
+//! 1. Paths configured in the variable `BootLoaderPaths` under
+//!    [`CRABEFI_VARIABLE_GUID`] or else the VPD key `crabefi_boot_paths`, a
+//!    comma-separated list like `\EFI\arch\grubx64.efi,\EFI\nixos\boot.efi`
+//! 2. The removable media path, named after the architecture: `BOOTX64.EFI`,
+//!    `BOOTIA32.EFI` or `BOOTAA64.EFI`
+//! 3. The loaders of common distributions, shim before GRUB, then
+//!    systemd-boot and the Windows boot manager
+//! 4. On x86_64, `BOOTIA32.EFI`, so media only bootable by 32-bit firmware
+//!    get an entry telling so, see [`pe`](crate::pe)
+
+use heapless::{String, Vec};
+
+use crate::coreboot::vpd;
+use crate::efi::runtime_services::{CRABEFI_VARIABLE_GUID, read_variable};
+
+/// Variable holding the configured paths
+const PATHS_VARIABLE: &str = "BootLoaderPaths";
+
+/// Longest path list read
+const MAX_LIST_LEN: usize = 256;
+
+/// Longest path, as in a boot entry
+pub const MAX_PATH_LEN: usize = 128;
+
+/// Most paths searched
+const MAX_PATHS: usize = 16;
+
+/// The removable media path and the distribution loaders for an architecture
+macro_rules! default_paths {
+    ($boot:literal, $arch:literal $(, $extra:literal)*) => {
+        &[
+            concat!("EFI\\BOOT\\BOOT", $boot, ".EFI"),
+            concat!("EFI\\fedora\\shim", $arch, ".efi"),
+            concat!("EFI\\ubuntu\\shim", $arch, ".efi"),
+            concat!("EFI\\debian\\shim", $arch, ".efi"),
+            concat!("EFI\\opensuse\\shim", $arch, ".efi"),
+            concat!("EFI\\debian\\grub", $arch, ".efi"),
+            concat!("EFI\\ubuntu\\grub", $arch, ".efi"),
+            concat!("EFI\\systemd\\systemd-boot", $arch, ".efi"),
+            "EFI\\Microsoft\\Boot\\bootmgfw.efi",
+            $($extra,)*
+        ]
+    };
+}
+
+/// Paths searched after the configured ones
+#[cfg(target_arch = "x86_64")]
+pub const DEFAULT_PATHS: &[&str] = default_paths!("X64", "x64", "EFI\\BOOT\\BOOTIA32.EFI");
+#[cfg(target_arch = "x86")]
+pub const DEFAULT_PATHS: &[&str] = default_paths!("IA32", "ia32");
+#[cfg(target_arch = "aarch64")]
+pub const DEFAULT_PATHS: &[&str] = default_paths!("AA64", "aa64");
+
+/// Removable media path, the one loaded when no file is named
+pub const REMOVABLE_PATH: &str = DEFAULT_PATHS[0];
+
+/// The configured paths followed by [`DEFAULT_PATHS`], without duplicates
+fn parse_paths(list: &str) -> Vec<String<MAX_PATH_LEN>, MAX_PATHS> {
+    let configured = list
+        .split(',')
+        .map(|path| path.trim())
+        .filter(|path| !path.is_empty());
+    let mut paths: Vec<String<MAX_PATH_LEN>, MAX_PATHS> = Vec::new();
+    for path in configured.chain(DEFAULT_PATHS.iter().copied()) {
+        // Separators and case don't matter on FAT
+        let path = path.trim_start_matches(['\\', '/']);
+        let same = |known: &String<MAX_PATH_LEN>| {
+            known.len() == path.len()
+                && known.bytes().zip(path.bytes()).all(|(a, b)| {
+                    a.eq_ignore_ascii_case(&b)
+                        || (matches!(a, b'\\' | b'/') && matches!(b, b'\\' | b'/'))
+                })
+        };
+        if paths.iter().any(same) {
+            continue;
+        }
+        let Ok(path) = String::try_from(path) else {
+            log::warn!("Boot paths: {:?} is too long", path);
+            continue;
+        };
+        if paths.push(path).is_err() {
+            break;
+        }
+    }
+    paths
+}
+
+/// The paths to look for a bootloader at, in order
+pub fn paths() -> Vec<String<MAX_PATH_LEN>, MAX_PATHS> {
+    let mut buf = [0u8; MAX_LIST_LEN];
+    let list = match read_variable(PATHS_VARIABLE, &CRABEFI_VARIABLE_GUID, &mut buf) {
+        Some(len) => core::str::from_utf8(&buf[..len])
+            .ok()
+            .map(|text| text.trim_end_matches('\0')),
+        None => vpd::find_str(vpd::KEY_BOOT_PATHS),
+    };
+    parse_paths(list.unwrap_or(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_paths() {
+        let paths = parse_paths("");
+        assert_eq!(paths.len(), DEFAULT_PATHS.len());
+        assert_eq!(paths[0], "EFI\\BOOT\\BOOTX64.EFI");
+        assert!(paths.iter().any(|path| path == "EFI\\fedora\\shimx64.efi"));
+        assert!(paths.iter().any(|path| path == "EFI\\debian\\grubx64.efi"));
+        assert!(
+            paths
+                .iter()
+                .any(|path| path == "EFI\\systemd\\systemd-bootx64.efi")
+        );
+    }
+
+    #[test]
+    fn configured_paths_first() {
+        let paths = parse_paths(" \\EFI\\arch\\grubx64.efi,,/efi/boot/bootx64.efi ");
+        assert_eq!(paths[0], "EFI\\arch\\grubx64.efi");
+        // The removable path isn't searched twice
+        assert_eq!(paths[1], "efi/boot/bootx64.efi");
+        assert_eq!(paths[2], "EFI\\fedora\\shimx64.efi");
+        assert_eq!(paths.len(), DEFAULT_PATHS.len() + 1);
+    }
+}
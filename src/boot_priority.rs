@@ -0,0 +1,163 @@
+//! Boot device priority
+//!
+//! The boot menu holds a few entries only, and devices used to be scanned
+//! NVMe first, then SATA, USB and SD, so a USB stick could neither be the
+//! default nor find room next to a full internal disk. The device classes
+//! are now scanned in a configurable order, which is also the order of the
+//! menu. It is a comma-separated list like `usb,nvme,sata,sd`, read from the
+//! variable `BootDevicePriority` under [`CRABEFI_VARIABLE_GUID`] or else the
+//! VPD key `crabefi_boot_priority`. Classes left out follow in the default
+//! order. Defaults chosen by `loader.conf`, GRUB or VPD still win.
+//!
+//! For booting once from another device, like `BootNext` for boot options,
+//! the variable `BootOnceDevice` names a device class. It is deleted when
+//! read, and the first entry of that class is booted without showing the
+//! menu. The boot-once-from-USB [hotkey](crate::hotkey) does the same for
+//! USB.
+
+use heapless::Vec;
+
+use crate::coreboot::vpd;
+use crate::efi::runtime_services::{CRABEFI_VARIABLE_GUID, read_variable, write_variable};
+use crate::menu::{BootMenu, DeviceType};
+
+/// Variable holding the priority
+const PRIORITY_VARIABLE: &str = "BootDevicePriority";
+
+/// Variable naming the device class to boot once
+const BOOT_ONCE_VARIABLE: &str = "BootOnceDevice";
+
+/// Longest priority list read
+const MAX_LIST_LEN: usize = 64;
+
+/// A class of boot devices
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceClass {
+    Nvme,
+    Sata,
+    Usb,
+    Sd,
+}
+
+/// The order when nothing is configured
+pub const DEFAULT_PRIORITY: [DeviceClass; 4] = [
+    DeviceClass::Nvme,
+    DeviceClass::Sata,
+    DeviceClass::Usb,
+    DeviceClass::Sd,
+];
+
+impl DeviceClass {
+    /// Class of a boot entry's device, None for RAM disks and CBFS payloads
+    pub fn of(device_type: &DeviceType) -> Option<Self> {
+        match device_type {
+            DeviceType::Nvme { .. } => Some(DeviceClass::Nvme),
+            DeviceType::Ahci { .. } => Some(DeviceClass::Sata),
+            DeviceType::Usb { .. } => Some(DeviceClass::Usb),
+            DeviceType::Sdhci { .. } => Some(DeviceClass::Sd),
+            DeviceType::RamDisk { .. } | DeviceType::Payload => None,
+        }
+    }
+
+    /// Parse a class name, ignoring case
+    pub fn parse(name: &str) -> Option<Self> {
+        DEFAULT_PRIORITY
+            .into_iter()
+            .find(|class| class.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    /// Name used in the priority list
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceClass::Nvme => "nvme",
+            DeviceClass::Sata => "sata",
+            DeviceClass::Usb => "usb",
+            DeviceClass::Sd => "sd",
+        }
+    }
+}
+
+/// Parse a priority list, appending the classes it leaves out
+fn parse_priority(list: &str) -> Vec<DeviceClass, 4> {
+    let mut priority = Vec::new();
+    for name in list.split(',').filter(|name| !name.trim().is_empty()) {
+        match DeviceClass::parse(name) {
+            Some(class) if !priority.contains(&class) => {
+                let _ = priority.push(class);
+            }
+            Some(_) => {}
+            None => log::warn!("Boot priority: unknown device class {:?}", name),
+        }
+    }
+    for class in DEFAULT_PRIORITY {
+        if !priority.contains(&class) {
+            let _ = priority.push(class);
+        }
+    }
+    priority
+}
+
+/// Read a variable holding ASCII text into `buf`
+fn read_text<'a>(name: &str, buf: &'a mut [u8]) -> Option<&'a str> {
+    let len = read_variable(name, &CRABEFI_VARIABLE_GUID, buf)?;
+    core::str::from_utf8(&buf[..len])
+        .ok()
+        .map(|text| text.trim_end_matches('\0'))
+}
+
+/// The order to scan the device classes in
+pub fn priority() -> Vec<DeviceClass, 4> {
+    let mut buf = [0u8; MAX_LIST_LEN];
+    let list =
+        read_text(PRIORITY_VARIABLE, &mut buf).or_else(|| vpd::find_str(vpd::KEY_BOOT_PRIORITY));
+    let priority = parse_priority(list.unwrap_or(""));
+    if let Some(list) = list {
+        log::info!("Boot priority {:?}: {:?}", list, priority.as_slice());
+    }
+    priority
+}
+
+/// Index of the entry to boot once without the menu, if any
+///
+/// `hotkey` is the class requested by a hotkey, which wins over the
+/// `BootOnceDevice` variable. The variable is deleted either way.
+pub fn boot_once(menu: &BootMenu, hotkey: Option<DeviceClass>) -> Option<usize> {
+    let mut buf = [0u8; MAX_LIST_LEN];
+    let variable = read_text(BOOT_ONCE_VARIABLE, &mut buf).map(|name| {
+        let class = DeviceClass::parse(name);
+        if class.is_none() {
+            log::warn!("{}: unknown device class {:?}", BOOT_ONCE_VARIABLE, name);
+        }
+        class
+    });
+    if variable.is_some() {
+        let _ = write_variable(BOOT_ONCE_VARIABLE, &CRABEFI_VARIABLE_GUID, 0, &[]);
+    }
+
+    let class = hotkey.or(variable.flatten())?;
+    let index = (0..menu.entry_count()).find(|&index| {
+        menu.get_entry(index)
+            .is_some_and(|entry| DeviceClass::of(&entry.device_type) == Some(class))
+    });
+    match index {
+        Some(_) => log::info!("Booting once from {}", class.name()),
+        None => log::warn!("No {} boot entry to boot once from", class.name()),
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_priority() {
+        use DeviceClass::*;
+        assert_eq!(parse_priority("").as_slice(), DEFAULT_PRIORITY);
+        assert_eq!(parse_priority("USB, sd").as_slice(), [Usb, Sd, Nvme, Sata]);
+        assert_eq!(
+            parse_priority("sata,floppy,sata,nvme").as_slice(),
+            [Sata, Nvme, Usb, Sd]
+        );
+    }
+}
@@ -0,0 +1,311 @@
+//! A/B Boot Slots
+//!
+//! This module implements automatic fallback between two boot entries. Every
+//! time the entry of the active slot is booted, an attempt counter kept in
+//! CMOS NVRAM is incremented. Reaching `ExitBootServices` counts as a
+//! successful boot and clears the counter. If the counter reaches the
+//! configured limit, the loader never got as far as handing over to the OS
+//! that many times in a row and the other slot becomes active.
+//!
+//! The slot state lives in CMOS rather than in an EFI variable: it is
+//! written on every boot, which would wear the flash behind the
+//! [variable store](crate::efi::variable_store), and it works on boards
+//! without an SMMSTORE region. The record is kept at 0xF0 in bank 1
+//! unless the [CMOS option table](crate::coreboot::cmos_options) uses those
+//! bytes or moves it; the fallback is disabled if it can't be kept.
+//!
+//! # Configuration
+//!
+//! The slots can be configured through EFI variables under
+//! [`CRABEFI_VARIABLE_GUID`]:
+//!
+//! - `BootSlotMaxTries` (u16): failed attempts before switching slots,
+//!   0 disables the fallback logic
+//! - `BootSlotPrimary` (u16): boot menu index of the primary entry
+//!   (default: the first entry)
+//! - `BootSlotFallback` (u16): boot menu index of the fallback entry
+//!   (default: the last entry)
+//!
+//! The variables only last across reboots when they are set non-volatile
+//! and the board has an SMMSTORE region. A variable that isn't set is taken
+//! from the [VPD](crate::coreboot::vpd) key `crabefi_boot_slot_max_tries`,
+//! `crabefi_boot_slot_primary` or `crabefi_boot_slot_fallback` instead,
+//! where the entries are named by their name or Boot Loader Interface ID
+//! since menu indices change when disks come and go.
+//!
+//! The defaults skip the CrabEFI diagnostics entries.
+//!
+//! The current state is published as the volatile variables `BootSlot`
+//! (u8, 0 = primary, 1 = fallback) and `BootSlotAttempts` (u8).
+
+use crate::coreboot::{cmos_options, vpd};
+use crate::drivers::cmos;
+use crate::efi::runtime_services::{CRABEFI_VARIABLE_GUID, read_variable_u16, write_variable};
+use crate::menu::BootMenu;
+use r_efi::efi;
+use spin::Mutex;
+
+/// Default number of failed attempts before switching slots
+const DEFAULT_MAX_TRIES: u16 = 3;
+
+/// Default CMOS offset of the slot record (bank 1)
+const CMOS_OFFSET: u8 = 0xF0;
+
+/// Option table name of the slot record
+const CMOS_NAME: &str = "crabefi_boot_slots";
+
+/// Magic, slot, attempts and checksum
+const RECORD_SIZE: usize = 4;
+
+/// Magic byte marking a valid slot record
+const RECORD_MAGIC: u8 = 0xAB;
+
+/// A boot slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    /// The normal boot entry
+    Primary,
+    /// The recovery entry used after repeated failures of the primary
+    Fallback,
+}
+
+impl Slot {
+    /// The other slot
+    fn other(self) -> Self {
+        match self {
+            Slot::Primary => Slot::Fallback,
+            Slot::Fallback => Slot::Primary,
+        }
+    }
+}
+
+/// Persistent slot state
+#[derive(Debug, Clone, Copy)]
+struct SlotState {
+    /// Currently active slot
+    slot: Slot,
+    /// Boot attempts of the active slot that did not reach ExitBootServices
+    attempts: u8,
+}
+
+impl SlotState {
+    /// Load the state from the CMOS record at `offset`, falling back to the
+    /// primary slot if the record is missing or corrupt
+    fn load(offset: u8) -> Self {
+        let magic = cmos::read(offset);
+        let slot = cmos::read(offset + 1);
+        let attempts = cmos::read(offset + 2);
+        let checksum = cmos::read(offset + 3);
+
+        if magic != RECORD_MAGIC || slot > 1 || checksum != record_checksum(slot, attempts) {
+            log::debug!("Boot slots: no valid record in CMOS, starting on primary");
+            return SlotState {
+                slot: Slot::Primary,
+                attempts: 0,
+            };
+        }
+
+        SlotState {
+            slot: if slot == 0 {
+                Slot::Primary
+            } else {
+                Slot::Fallback
+            },
+            attempts,
+        }
+    }
+
+    /// Write the state to the CMOS record at `offset` and publish it as EFI
+    /// variables
+    fn store(&self, offset: u8) {
+        let slot = self.slot as u8;
+        cmos::write(offset, RECORD_MAGIC);
+        cmos::write(offset + 1, slot);
+        cmos::write(offset + 2, self.attempts);
+        cmos::write(offset + 3, record_checksum(slot, self.attempts));
+
+        self.publish();
+    }
+
+    /// Publish the state as volatile EFI variables for the OS to inspect
+    fn publish(&self) {
+        let attributes = efi::VARIABLE_BOOTSERVICE_ACCESS | efi::VARIABLE_RUNTIME_ACCESS;
+        let guid = &CRABEFI_VARIABLE_GUID;
+        let _ = write_variable("BootSlot", guid, attributes, &[self.slot as u8]);
+        let _ = write_variable("BootSlotAttempts", guid, attributes, &[self.attempts]);
+    }
+}
+
+/// Checksum over the variable part of the slot record
+fn record_checksum(slot: u8, attempts: u8) -> u8 {
+    !RECORD_MAGIC.wrapping_add(slot).wrapping_add(attempts)
+}
+
+/// The slot and boot menu index armed for the current boot
+struct Armed {
+    /// CMOS offset of the slot record
+    offset: u8,
+    /// Slot state as loaded (and possibly switched) this boot
+    state: SlotState,
+    /// Boot menu index of the active slot's entry
+    index: usize,
+    /// Whether an attempt was recorded for this boot
+    attempted: bool,
+}
+
+/// Slot state for the current boot, set up by [`select_default`]
+static ARMED: Mutex<Option<Armed>> = Mutex::new(None);
+
+/// Failed attempts before switching slots, from the variable or else VPD
+fn max_tries() -> u16 {
+    if let Some(tries) = read_variable_u16("BootSlotMaxTries", &CRABEFI_VARIABLE_GUID) {
+        return tries;
+    }
+    let Some(text) = vpd::find_str(vpd::KEY_BOOT_SLOT_MAX_TRIES) else {
+        return DEFAULT_MAX_TRIES;
+    };
+    text.trim().parse().unwrap_or_else(|_| {
+        log::warn!("Boot slots: invalid number of tries {:?}", text);
+        DEFAULT_MAX_TRIES
+    })
+}
+
+/// Boot menu index of the entry a VPD key names
+fn vpd_entry(menu: &BootMenu, key: &str) -> Option<usize> {
+    let target = vpd::find_str(key)?;
+    let index = (0..menu.entry_count()).find(|&index| {
+        menu.get_entry(index)
+            .is_some_and(|entry| entry.name == target || entry.loader_id() == target)
+    });
+    if index.is_none() {
+        log::warn!("Boot slots: no entry {:?} for {}", target, key);
+    }
+    index
+}
+
+/// Resolve the boot menu index of a slot's entry
+fn slot_index(menu: &BootMenu, slot: Slot) -> Option<usize> {
+    let guid = &CRABEFI_VARIABLE_GUID;
+    let count = menu.entry_count();
+
+    // Diagnostics entries are never picked by default
+    let mut os_entries =
+        (0..count).filter(|&i| menu.get_entry(i).is_some_and(|e| !e.is_diagnostics()));
+
+    let index = match slot {
+        Slot::Primary => match read_variable_u16("BootSlotPrimary", guid) {
+            Some(i) => i as usize,
+            None => vpd_entry(menu, vpd::KEY_BOOT_SLOT_PRIMARY).or_else(|| os_entries.next())?,
+        },
+        Slot::Fallback => match read_variable_u16("BootSlotFallback", guid) {
+            Some(i) => i as usize,
+            None => {
+                vpd_entry(menu, vpd::KEY_BOOT_SLOT_FALLBACK).or_else(|| os_entries.next_back())?
+            }
+        },
+    };
+
+    (index < count).then_some(index)
+}
+
+/// Pre-select the boot menu entry of the active slot
+///
+/// Switches to the other slot if the active one has used up its attempts.
+/// Does nothing if fallback is disabled or the menu has no distinct fallback
+/// entry.
+pub fn select_default(menu: &mut BootMenu) {
+    let max_tries = max_tries();
+    if max_tries == 0 {
+        return;
+    }
+
+    let (Some(primary), Some(fallback)) = (
+        slot_index(menu, Slot::Primary),
+        slot_index(menu, Slot::Fallback),
+    ) else {
+        return;
+    };
+    if primary == fallback {
+        log::debug!("Boot slots: no distinct fallback entry, disabled");
+        return;
+    }
+
+    let Some(offset) = cmos_options::reserve(CMOS_NAME, CMOS_OFFSET, RECORD_SIZE) else {
+        log::warn!("Boot slots: no CMOS space for the slot record, disabled");
+        return;
+    };
+
+    let mut state = SlotState::load(offset);
+    if state.attempts as u16 >= max_tries {
+        let next = state.slot.other();
+        log::warn!(
+            "Boot slots: {:?} slot failed {} times, switching to {:?}",
+            state.slot,
+            state.attempts,
+            next
+        );
+        state = SlotState {
+            slot: next,
+            attempts: 0,
+        };
+        state.store(offset);
+    } else {
+        state.publish();
+    }
+
+    let index = match state.slot {
+        Slot::Primary => primary,
+        Slot::Fallback => fallback,
+    };
+    log::info!(
+        "Boot slots: {:?} slot active (entry {}, attempt {} of {})",
+        state.slot,
+        index + 1,
+        state.attempts as u16 + 1,
+        max_tries
+    );
+
+    menu.set_selected(index);
+    *ARMED.lock() = Some(Armed {
+        offset,
+        state,
+        index,
+        attempted: false,
+    });
+}
+
+/// Record a boot attempt for the entry about to be started
+///
+/// Only boots of the active slot's entry count; entries picked manually from
+/// the menu are not tracked.
+pub fn record_attempt(index: usize) {
+    let mut armed = ARMED.lock();
+    let Some(armed) = armed.as_mut() else {
+        return;
+    };
+    if armed.index != index || armed.attempted {
+        return;
+    }
+
+    armed.state.attempts = armed.state.attempts.saturating_add(1);
+    armed.state.store(armed.offset);
+    armed.attempted = true;
+}
+
+/// Mark the current boot as successful
+///
+/// Called from `ExitBootServices`; clears the attempt counter of the active
+/// slot so that it stays active.
+pub fn mark_success() {
+    let mut armed = ARMED.lock();
+    let Some(armed) = armed.as_mut() else {
+        return;
+    };
+    if !armed.attempted {
+        return;
+    }
+
+    armed.state.attempts = 0;
+    armed.state.store(armed.offset);
+    armed.attempted = false;
+}
@@ -0,0 +1,199 @@
+//! Kernel Command Line Editing
+//!
+//! Pressing `e` on a boot menu entry opens an editor for the kernel command
+//! line and initrd path, like GRUB's entry editor. The edit only applies to
+//! the boot started from the editor: the loader gets it as the `LoadOptions`
+//! of its Loaded Image protocol, which the Linux EFI stub takes as the kernel
+//! command line. The initrd path is appended as `initrd=<path>`, which the
+//! stub loads from the volume the kernel came from.
+//!
+//! # Configuration
+//!
+//! Setting the EFI variable `CmdlineRemember` (u16, non-zero enables) under
+//! [`CRABEFI_VARIABLE_GUID`] saves each edit as the variables `LastCmdline`
+//! and `LastInitrd` (ASCII), and the editor starts from them the next time.
+//! They are non-volatile, so they are kept across resets where the
+//! [variable store](crate::efi::variable_store) has an SMMSTORE region, and
+//! the OS can read them to tell how it was started.
+
+use core::ffi::c_void;
+use heapless::String;
+use r_efi::efi;
+use r_efi::protocols::loaded_image;
+use spin::Mutex;
+
+use crate::efi::allocator::{MemoryType, allocate_pool};
+use crate::efi::runtime_services::{
+    CRABEFI_VARIABLE_GUID, read_variable, read_variable_u16, write_variable,
+};
+
+/// Maximum length of an edited command line
+pub const MAX_CMDLINE_LEN: usize = 256;
+
+/// Maximum length of an edited initrd path
+pub const MAX_INITRD_LEN: usize = 128;
+
+/// Maximum length of the load options built from an edit
+const MAX_LOAD_OPTIONS_LEN: usize = MAX_CMDLINE_LEN + MAX_INITRD_LEN + " initrd=".len();
+
+/// Command line and initrd path for one boot
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KernelOptions {
+    /// Kernel command line
+    pub cmdline: String<MAX_CMDLINE_LEN>,
+    /// Initrd path on the loader's volume, empty for none
+    pub initrd: String<MAX_INITRD_LEN>,
+}
+
+impl KernelOptions {
+    /// Whether neither a command line nor an initrd was given
+    pub fn is_empty(&self) -> bool {
+        self.cmdline.trim().is_empty() && self.initrd.trim().is_empty()
+    }
+
+    /// Load options text: the command line followed by `initrd=<path>`
+    ///
+    /// The EFI stub expects the initrd path with backslashes.
+    pub fn load_options(&self) -> String<MAX_LOAD_OPTIONS_LEN> {
+        let mut options = String::new();
+        let _ = options.push_str(self.cmdline.trim());
+        let initrd = self.initrd.trim();
+        if !initrd.is_empty() {
+            if !options.is_empty() {
+                let _ = options.push(' ');
+            }
+            let _ = options.push_str("initrd=");
+            for c in initrd.chars() {
+                let _ = options.push(if c == '/' { '\\' } else { c });
+            }
+        }
+        options
+    }
+
+    /// The last saved edit, if remembering edits is enabled
+    pub fn last() -> Option<Self> {
+        if !remember_enabled() {
+            return None;
+        }
+        let guid = &CRABEFI_VARIABLE_GUID;
+        let mut options = KernelOptions::default();
+        let mut buf = [0u8; MAX_CMDLINE_LEN];
+        if let Some(len) = read_variable("LastCmdline", guid, &mut buf) {
+            let _ = options
+                .cmdline
+                .push_str(core::str::from_utf8(&buf[..len]).unwrap_or(""));
+        }
+        if let Some(len) = read_variable("LastInitrd", guid, &mut buf[..MAX_INITRD_LEN]) {
+            let _ = options
+                .initrd
+                .push_str(core::str::from_utf8(&buf[..len]).unwrap_or(""));
+        }
+        (!options.is_empty()).then_some(options)
+    }
+
+    /// Save the edit for next time, if remembering edits is enabled
+    fn remember(&self) {
+        if !remember_enabled() {
+            return;
+        }
+        let attributes = efi::VARIABLE_NON_VOLATILE
+            | efi::VARIABLE_BOOTSERVICE_ACCESS
+            | efi::VARIABLE_RUNTIME_ACCESS;
+        let guid = &CRABEFI_VARIABLE_GUID;
+        for (name, value) in [
+            ("LastCmdline", &*self.cmdline),
+            ("LastInitrd", &*self.initrd),
+        ] {
+            // Writing empty data deletes the variable
+            let status = write_variable(name, guid, attributes, value.as_bytes());
+            if status != efi::Status::SUCCESS && !value.is_empty() {
+                log::warn!("Failed to save {}: {:?}", name, status);
+            }
+        }
+    }
+}
+
+/// Whether edits are saved to variables
+fn remember_enabled() -> bool {
+    read_variable_u16("CmdlineRemember", &CRABEFI_VARIABLE_GUID).is_some_and(|v| v != 0)
+}
+
+/// Load options for the next loader started from the boot menu
+static PENDING: Mutex<Option<String<MAX_LOAD_OPTIONS_LEN>>> = Mutex::new(None);
+
+/// Pass `options` to the next loader started from the boot menu
+pub fn set_for_next_boot(options: &KernelOptions) {
+    options.remember();
+    let load_options = options.load_options();
+    log::info!("Edited load options: {}", load_options);
+    *PENDING.lock() = Some(load_options);
+}
+
+/// Pass `cmdline` to the next loader unless an edit already did
+///
+/// For command lines built into the image, like that of a Unified Kernel
+/// Image, which an edit in the boot menu overrides.
+pub fn set_default(cmdline: &str) {
+    let mut pending = PENDING.lock();
+    if pending.is_some() || cmdline.is_empty() {
+        return;
+    }
+    let mut load_options = String::new();
+    if load_options.push_str(cmdline).is_err() {
+        log::warn!(
+            "Built-in command line longer than {} bytes",
+            MAX_LOAD_OPTIONS_LEN
+        );
+        return;
+    }
+    log::info!("Built-in load options: {}", load_options);
+    *pending = Some(load_options);
+}
+
+/// Hand the pending load options, if any, to a loader about to be started
+///
+/// The options are stored as a null-terminated UCS-2 string in loader
+/// memory, so they stay valid for as long as the loader runs.
+pub fn apply(protocol: &mut loaded_image::Protocol) {
+    let Some(options) = PENDING.lock().take() else {
+        return;
+    };
+    let units = options.len() + 1;
+    let size = units * size_of::<u16>();
+    let Ok(buffer) = allocate_pool(MemoryType::LoaderData, size) else {
+        log::error!("Failed to allocate load options");
+        return;
+    };
+    let text = unsafe { core::slice::from_raw_parts_mut(buffer as *mut u16, units) };
+    for (unit, byte) in text.iter_mut().zip(options.bytes().chain([0])) {
+        *unit = byte as u16;
+    }
+    protocol.load_options = buffer as *mut c_void;
+    protocol.load_options_size = size as u32;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(cmdline: &str, initrd: &str) -> KernelOptions {
+        let mut options = KernelOptions::default();
+        options.cmdline.push_str(cmdline).unwrap();
+        options.initrd.push_str(initrd).unwrap();
+        options
+    }
+
+    #[test]
+    fn builds_load_options() {
+        assert_eq!(
+            options("root=/dev/sda2 quiet", "/EFI/Linux/initrd.img").load_options(),
+            "root=/dev/sda2 quiet initrd=\\EFI\\Linux\\initrd.img"
+        );
+        assert_eq!(options(" single ", "").load_options(), "single");
+        assert_eq!(
+            options("", "initrd.img").load_options(),
+            "initrd=initrd.img"
+        );
+        assert!(options(" ", "").is_empty());
+    }
+}
@@ -0,0 +1,341 @@
+//! Deflate decoder
+//!
+//! Decodes deflate streams in the zlib wrapper squashfs and PNG use and in
+//! gzip members, which gzipped kernels and initrds come in. Adler-32 and
+//! CRC-32 trailers are skipped.
+//!
+//! Huffman codes are decoded a bit at a time from the code length counts,
+//! like zlib's `puff`, which needs no lookup tables beyond the symbols.
+//!
+//! Reference: RFC 1950, RFC 1951, RFC 1952
+
+use super::{DecompressError, Input, Sink};
+
+/// Magic number of a gzip member, as its first two bytes
+pub(super) const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// Largest match distance
+pub(super) const WINDOW_SIZE: usize = 32768;
+
+/// Compression method of zlib and gzip
+const METHOD_DEFLATE: u8 = 8;
+
+/// zlib FLG bit marking a preset dictionary
+const ZLIB_DICTIONARY: u8 = 0x20;
+
+/// gzip FLG bits
+const GZIP_HEADER_CRC: u8 = 0x02;
+const GZIP_EXTRA: u8 = 0x04;
+const GZIP_NAME: u8 = 0x08;
+const GZIP_COMMENT: u8 = 0x10;
+
+/// Block types
+const BLOCK_STORED: u32 = 0;
+const BLOCK_FIXED: u32 = 1;
+const BLOCK_DYNAMIC: u32 = 2;
+
+/// Longest Huffman code
+const MAX_BITS: usize = 15;
+
+/// Literal/length and distance symbols
+const MAX_LITERAL_LENGTHS: usize = 288;
+const MAX_DISTANCES: usize = 30;
+
+/// End of block symbol
+const END_OF_BLOCK: u16 = 256;
+
+/// Order the code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Length code baselines and extra bits, for symbols 257 to 285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Distance code baselines and extra bits
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Least-significant-bit first reader over the input
+struct Bits<'a, 'b> {
+    input: &'b mut Input<'a>,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a, 'b> Bits<'a, 'b> {
+    fn new(input: &'b mut Input<'a>) -> Self {
+        Bits {
+            input,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    /// Read `n` (at most 16) bits
+    fn bits(&mut self, n: u32) -> Result<u32, DecompressError> {
+        while self.count < n {
+            self.buffer |= (self.input.u8()? as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << n) - 1);
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Drop the bits left in the current byte
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+/// Canonical Huffman code
+struct Huffman<const N: usize> {
+    /// Number of codes of each length
+    counts: [u16; MAX_BITS + 1],
+    /// Symbols ordered by code
+    symbols: [u16; N],
+}
+
+impl<const N: usize> Huffman<N> {
+    /// Build the code from the code length of each symbol, 0 if unused
+    ///
+    /// Incomplete codes are accepted, a single distance code is one.
+    fn new(lengths: &[u8]) -> Result<Self, DecompressError> {
+        let mut code = Huffman {
+            counts: [0; MAX_BITS + 1],
+            symbols: [0; N],
+        };
+        for &length in lengths {
+            code.counts[length as usize] += 1;
+        }
+
+        let mut left = 1i32;
+        for &count in &code.counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(DecompressError::Corrupt);
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + code.counts[length];
+        }
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                code.symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(code)
+    }
+
+    /// Decode a symbol
+    fn decode(&self, bits: &mut Bits) -> Result<u16, DecompressError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(DecompressError::Corrupt)
+    }
+}
+
+/// Decode the symbols of a Huffman coded block
+fn decode_symbols(
+    bits: &mut Bits,
+    lengths: &Huffman<MAX_LITERAL_LENGTHS>,
+    distances: &Huffman<MAX_DISTANCES>,
+    sink: &mut impl Sink,
+) -> Result<(), DecompressError> {
+    loop {
+        let symbol = lengths.decode(bits)?;
+        match symbol {
+            0..END_OF_BLOCK => sink.push(symbol as u8)?,
+            END_OF_BLOCK => return Ok(()),
+            _ => {
+                let index = (symbol - 257) as usize;
+                let (&base, &extra) = LENGTH_BASE
+                    .get(index)
+                    .zip(LENGTH_EXTRA.get(index))
+                    .ok_or(DecompressError::Corrupt)?;
+                let len = base as usize + bits.bits(extra as u32)? as usize;
+
+                let index = distances.decode(bits)? as usize;
+                let (&base, &extra) = DISTANCE_BASE
+                    .get(index)
+                    .zip(DISTANCE_EXTRA.get(index))
+                    .ok_or(DecompressError::Corrupt)?;
+                let distance = base as usize + bits.bits(extra as u32)? as usize;
+                sink.copy_match(distance, len)?;
+            }
+        }
+    }
+}
+
+/// Codes of a fixed Huffman block
+fn fixed_codes() -> Result<(Huffman<MAX_LITERAL_LENGTHS>, Huffman<MAX_DISTANCES>), DecompressError>
+{
+    let mut lengths = [0u8; MAX_LITERAL_LENGTHS];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; MAX_DISTANCES])?))
+}
+
+/// Read the codes of a dynamic Huffman block
+fn dynamic_codes(
+    bits: &mut Bits,
+) -> Result<(Huffman<MAX_LITERAL_LENGTHS>, Huffman<MAX_DISTANCES>), DecompressError> {
+    let literal_lengths = bits.bits(5)? as usize + 257;
+    let distances = bits.bits(5)? as usize + 1;
+    let code_lengths = bits.bits(4)? as usize + 4;
+    if literal_lengths > 286 || distances > MAX_DISTANCES {
+        return Err(DecompressError::Corrupt);
+    }
+
+    let mut lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[symbol] = bits.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::<19>::new(&lengths)?;
+
+    let mut lengths = [0u8; MAX_LITERAL_LENGTHS + MAX_DISTANCES];
+    let total = literal_lengths + distances;
+    let mut index = 0;
+    while index < total {
+        let symbol = code_length_code.decode(bits)?;
+        let (value, repeat) = match symbol {
+            0..16 => (symbol as u8, 1),
+            16 => {
+                let previous = index.checked_sub(1).ok_or(DecompressError::Corrupt)?;
+                (lengths[previous], 3 + bits.bits(2)? as usize)
+            }
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        lengths
+            .get_mut(index..index + repeat)
+            .filter(|_| index + repeat <= total)
+            .ok_or(DecompressError::Corrupt)?
+            .fill(value);
+        index += repeat;
+    }
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(DecompressError::Corrupt);
+    }
+
+    Ok((
+        Huffman::new(&lengths[..literal_lengths])?,
+        Huffman::new(&lengths[literal_lengths..total])?,
+    ))
+}
+
+/// Decode a raw deflate stream, leaving `input` after its last byte
+fn inflate(input: &mut Input, sink: &mut impl Sink) -> Result<(), DecompressError> {
+    let mut bits = Bits::new(input);
+    loop {
+        let last = bits.bits(1)? != 0;
+        match bits.bits(2)? {
+            BLOCK_STORED => {
+                bits.align();
+                let len = bits.input.u16()?;
+                let complement = bits.input.u16()?;
+                if len != !complement {
+                    return Err(DecompressError::Corrupt);
+                }
+                sink.extend(bits.input.bytes(len as usize)?)?;
+            }
+            BLOCK_FIXED => {
+                let (lengths, distances) = fixed_codes()?;
+                decode_symbols(&mut bits, &lengths, &distances, sink)?;
+            }
+            BLOCK_DYNAMIC => {
+                let (lengths, distances) = dynamic_codes(&mut bits)?;
+                decode_symbols(&mut bits, &lengths, &distances, sink)?;
+            }
+            _ => return Err(DecompressError::Corrupt),
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+/// Decode a zlib stream
+pub(super) fn decode_zlib(input: &[u8], sink: &mut impl Sink) -> Result<(), DecompressError> {
+    let mut input = Input::new(input);
+    let method = input.u8()?;
+    let flags = input.u8()?;
+    let check = u16::from_be_bytes([method, flags]);
+    if method & 0x0F != METHOD_DEFLATE || !check.is_multiple_of(31) {
+        return Err(DecompressError::Corrupt);
+    }
+    if flags & ZLIB_DICTIONARY != 0 {
+        return Err(DecompressError::Unsupported);
+    }
+    inflate(&mut input, sink)?;
+    let _adler32 = input.u32()?;
+    Ok(())
+}
+
+/// Skip a zero-terminated gzip header field
+fn skip_string(input: &mut Input) -> Result<(), DecompressError> {
+    while input.u8()? != 0 {}
+    Ok(())
+}
+
+/// Decode all members of a gzip file
+pub(super) fn decode_gzip(input: &[u8], sink: &mut impl Sink) -> Result<(), DecompressError> {
+    let mut input = Input::new(input);
+    loop {
+        if input.bytes(2)? != GZIP_MAGIC || input.u8()? != METHOD_DEFLATE {
+            return Err(DecompressError::Corrupt);
+        }
+        let flags = input.u8()?;
+        let _mtime_xfl_os = input.bytes(6)?;
+        if flags & GZIP_EXTRA != 0 {
+            let len = input.u16()?;
+            input.bytes(len as usize)?;
+        }
+        if flags & GZIP_NAME != 0 {
+            skip_string(&mut input)?;
+        }
+        if flags & GZIP_COMMENT != 0 {
+            skip_string(&mut input)?;
+        }
+        if flags & GZIP_HEADER_CRC != 0 {
+            input.u16()?;
+        }
+
+        inflate(&mut input, sink)?;
+        let _crc32_size = input.bytes(8)?;
+
+        // Members may be followed by zero padding
+        if input.data[input.pos..].iter().all(|&byte| byte == 0) {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! LZ4 frame format decoder
+//!
+//! Decodes concatenated LZ4 frames as written by `lz4` and cbfstool,
+//! skipping skippable frames, and bare blocks as squashfs stores them.
+//! Block and content checksums are not checked.
+//!
+//! Reference: LZ4 Frame Format Description 1.6.x, LZ4 Block Format
+
+use super::{DecompressError, Input, Sink};
+
+/// Magic number of an LZ4 frame
+pub(super) const MAGIC: u32 = 0x184D_2204;
+
+/// Magic numbers of skippable frames, the low nibble is free
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+
+/// Largest match offset
+pub(super) const WINDOW_SIZE: usize = 65536;
+
+/// Frame descriptor FLG bits
+const FLG_VERSION_MASK: u8 = 0xC0;
+const FLG_VERSION_01: u8 = 0x40;
+const FLG_BLOCK_CHECKSUM: u8 = 0x10;
+const FLG_CONTENT_SIZE: u8 = 0x08;
+const FLG_CONTENT_CHECKSUM: u8 = 0x04;
+const FLG_DICT_ID: u8 = 0x01;
+
+/// Block size bit marking an uncompressed block
+const BLOCK_UNCOMPRESSED: u32 = 0x8000_0000;
+
+/// Shortest match
+const MIN_MATCH: usize = 4;
+
+/// Parsed frame descriptor
+struct FrameDescriptor {
+    flags: u8,
+    content_size: Option<u64>,
+}
+
+/// Parse the frame descriptor following the magic number
+fn read_descriptor(input: &mut Input) -> Result<FrameDescriptor, DecompressError> {
+    let flags = input.u8()?;
+    let _block_descriptor = input.u8()?;
+    if flags & FLG_VERSION_MASK != FLG_VERSION_01 {
+        return Err(DecompressError::Unsupported);
+    }
+    let content_size = match flags & FLG_CONTENT_SIZE {
+        0 => None,
+        _ => Some(input.u64()?),
+    };
+    if flags & FLG_DICT_ID != 0 {
+        return Err(DecompressError::Unsupported);
+    }
+    let _header_checksum = input.u8()?;
+    Ok(FrameDescriptor {
+        flags,
+        content_size,
+    })
+}
+
+/// Content size of the first frame, if recorded
+pub(super) fn content_size(input: &[u8]) -> Option<u64> {
+    let mut input = Input::new(input);
+    if input.u32().ok()? != MAGIC {
+        return None;
+    }
+    read_descriptor(&mut input).ok()?.content_size
+}
+
+/// Read a length continued in 255-valued bytes
+fn read_length(input: &mut Input, base: usize) -> Result<usize, DecompressError> {
+    let mut len = base;
+    if base == 15 {
+        loop {
+            let byte = input.u8()?;
+            len += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+/// Decode one compressed block
+pub(super) fn decode_block(block: &[u8], sink: &mut impl Sink) -> Result<(), DecompressError> {
+    let mut input = Input::new(block);
+    while !input.is_empty() {
+        let token = input.u8()?;
+        let literals = read_length(&mut input, (token >> 4) as usize)?;
+        sink.extend(input.bytes(literals)?)?;
+
+        // The last sequence has only literals
+        if input.is_empty() {
+            break;
+        }
+        let offset = input.u16()? as usize;
+        let len = read_length(&mut input, (token & 0x0F) as usize)? + MIN_MATCH;
+        sink.copy_match(offset, len)?;
+    }
+    Ok(())
+}
+
+/// Decode one frame after its magic number
+fn decode_frame(input: &mut Input, sink: &mut impl Sink) -> Result<(), DecompressError> {
+    let descriptor = read_descriptor(input)?;
+    let start = sink.total();
+
+    loop {
+        let size = input.u32()?;
+        if size == 0 {
+            break;
+        }
+        let data = input.bytes((size & !BLOCK_UNCOMPRESSED) as usize)?;
+        if size & BLOCK_UNCOMPRESSED != 0 {
+            sink.extend(data)?;
+        } else {
+            decode_block(data, sink)?;
+        }
+        if descriptor.flags & FLG_BLOCK_CHECKSUM != 0 {
+            input.u32()?;
+        }
+    }
+    if descriptor.flags & FLG_CONTENT_CHECKSUM != 0 {
+        input.u32()?;
+    }
+
+    match descriptor.content_size {
+        Some(size) if size != sink.total() - start => Err(DecompressError::Corrupt),
+        _ => Ok(()),
+    }
+}
+
+/// Decode all frames in `input`
+pub(super) fn decode(input: &[u8], sink: &mut impl Sink) -> Result<(), DecompressError> {
+    let mut input = Input::new(input);
+    if input.u32()? != MAGIC {
+        return Err(DecompressError::Corrupt);
+    }
+    decode_frame(&mut input, sink)?;
+
+    while !input.is_empty() {
+        match input.u32()? {
+            MAGIC => decode_frame(&mut input, sink)?,
+            magic if magic & !0x0F == SKIPPABLE_MAGIC => {
+                let size = input.u32()?;
+                input.bytes(size as usize)?;
+            }
+            _ => return Err(DecompressError::Corrupt),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,369 @@
+//! LZMA decoder
+//!
+//! Decodes the "alone" format coreboot uses for compressed CBFS files and
+//! `xz --format=lzma` writes: a 13-byte header (properties, dictionary size,
+//! decompressed size) followed by the range coded stream.
+//!
+//! Literal coder state is kept on the stack, which limits `lc + lp` to 4
+//! like LZMA2 does. Every common encoder setting is within that.
+//!
+//! Reference: LZMA SDK, `DOC/lzma-specification.txt`
+
+use super::{DecompressError, Input, Sink};
+
+/// Size of the header
+const HEADER_SIZE: usize = 13;
+
+/// Decompressed size meaning "unknown, ends with an end marker"
+const SIZE_UNKNOWN: u64 = u64::MAX;
+
+/// Largest supported `lc + lp`
+const MAX_LC_LP: u32 = 4;
+
+/// Probabilities per literal coder
+const LITERAL_CODER_SIZE: usize = 0x300;
+
+/// Probability model parameters
+const PROB_BITS: u32 = 11;
+const PROB_INIT: u16 = 1 << (PROB_BITS - 1);
+const MOVE_BITS: u32 = 5;
+const TOP_VALUE: u32 = 1 << 24;
+
+const STATES: usize = 12;
+const POS_STATES_MAX: usize = 1 << 4;
+const LEN_TO_POS_STATES: usize = 4;
+const END_POS_MODEL_INDEX: u32 = 14;
+const FULL_DISTANCES: usize = 1 << (END_POS_MODEL_INDEX >> 1);
+const ALIGN_BITS: u32 = 4;
+const MATCH_MIN_LEN: usize = 2;
+
+/// Parsed header
+pub(super) struct Header {
+    lc: u32,
+    lp: u32,
+    pb: u32,
+    pub(super) dict_size: u32,
+    /// Decompressed size, if recorded
+    pub(super) size: Option<u64>,
+}
+
+impl Header {
+    pub(super) fn parse(input: &[u8]) -> Result<Header, DecompressError> {
+        let mut input = Input::new(input);
+        let mut props = input.u8()? as u32;
+        if props >= 9 * 5 * 5 {
+            return Err(DecompressError::Corrupt);
+        }
+        let lc = props % 9;
+        props /= 9;
+        let lp = props % 5;
+        let pb = props / 5;
+        let dict_size = input.u32()?.max(1 << 12);
+        let size = match input.u64()? {
+            SIZE_UNKNOWN => None,
+            size => Some(size),
+        };
+        Ok(Header {
+            lc,
+            lp,
+            pb,
+            dict_size,
+            size,
+        })
+    }
+}
+
+/// Range decoder
+struct RangeDecoder<'a> {
+    input: Input<'a>,
+    range: u32,
+    code: u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, DecompressError> {
+        let mut input = Input::new(data);
+        if input.u8()? != 0 {
+            return Err(DecompressError::Corrupt);
+        }
+        let code = u32::from_be_bytes(input.bytes(4)?.try_into().unwrap());
+        if code == u32::MAX {
+            return Err(DecompressError::Corrupt);
+        }
+        Ok(RangeDecoder {
+            input,
+            range: u32::MAX,
+            code,
+        })
+    }
+
+    fn normalize(&mut self) -> Result<(), DecompressError> {
+        if self.range < TOP_VALUE {
+            self.range <<= 8;
+            self.code = self.code << 8 | self.input.u8()? as u32;
+        }
+        Ok(())
+    }
+
+    fn bit(&mut self, prob: &mut u16) -> Result<u32, DecompressError> {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        let bit = if self.code < bound {
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+            self.range = bound;
+            0
+        } else {
+            *prob -= *prob >> MOVE_BITS;
+            self.code -= bound;
+            self.range -= bound;
+            1
+        };
+        self.normalize()?;
+        Ok(bit)
+    }
+
+    fn direct_bits(&mut self, count: u32) -> Result<u32, DecompressError> {
+        let mut value = 0;
+        for _ in 0..count {
+            self.range >>= 1;
+            let bit = (self.code >= self.range) as u32;
+            if bit != 0 {
+                self.code -= self.range;
+            }
+            value = value << 1 | bit;
+            self.normalize()?;
+        }
+        Ok(value)
+    }
+
+    /// Decode `bits` bits most significant first
+    fn tree(&mut self, probs: &mut [u16], bits: u32) -> Result<u32, DecompressError> {
+        let mut m = 1;
+        for _ in 0..bits {
+            m = m << 1 | self.bit(&mut probs[m as usize])?;
+        }
+        Ok(m - (1 << bits))
+    }
+
+    /// Decode `bits` bits least significant first
+    fn reverse_tree(&mut self, probs: &mut [u16], bits: u32) -> Result<u32, DecompressError> {
+        let (mut m, mut value) = (1, 0);
+        for i in 0..bits {
+            let bit = self.bit(&mut probs[m as usize])?;
+            m = m << 1 | bit;
+            value |= bit << i;
+        }
+        Ok(value)
+    }
+}
+
+/// Match length decoder
+struct LenDecoder {
+    choice: u16,
+    choice2: u16,
+    low: [[u16; 1 << 3]; POS_STATES_MAX],
+    mid: [[u16; 1 << 3]; POS_STATES_MAX],
+    high: [u16; 1 << 8],
+}
+
+impl LenDecoder {
+    const fn new() -> Self {
+        LenDecoder {
+            choice: PROB_INIT,
+            choice2: PROB_INIT,
+            low: [[PROB_INIT; 1 << 3]; POS_STATES_MAX],
+            mid: [[PROB_INIT; 1 << 3]; POS_STATES_MAX],
+            high: [PROB_INIT; 1 << 8],
+        }
+    }
+
+    /// Decode a length, less [`MATCH_MIN_LEN`]
+    fn decode(
+        &mut self,
+        rc: &mut RangeDecoder,
+        pos_state: usize,
+    ) -> Result<usize, DecompressError> {
+        let len = if rc.bit(&mut self.choice)? == 0 {
+            rc.tree(&mut self.low[pos_state], 3)?
+        } else if rc.bit(&mut self.choice2)? == 0 {
+            8 + rc.tree(&mut self.mid[pos_state], 3)?
+        } else {
+            16 + rc.tree(&mut self.high, 8)?
+        };
+        Ok(len as usize)
+    }
+}
+
+/// Decoder probabilities
+struct Model {
+    literal: [u16; LITERAL_CODER_SIZE << MAX_LC_LP],
+    is_match: [u16; STATES * POS_STATES_MAX],
+    is_rep: [u16; STATES],
+    is_rep_g0: [u16; STATES],
+    is_rep_g1: [u16; STATES],
+    is_rep_g2: [u16; STATES],
+    is_rep0_long: [u16; STATES * POS_STATES_MAX],
+    pos_slot: [[u16; 1 << 6]; LEN_TO_POS_STATES],
+    pos: [u16; 1 + FULL_DISTANCES - END_POS_MODEL_INDEX as usize],
+    align: [u16; 1 << ALIGN_BITS],
+    len: LenDecoder,
+    rep_len: LenDecoder,
+}
+
+impl Model {
+    const fn new() -> Self {
+        Model {
+            literal: [PROB_INIT; LITERAL_CODER_SIZE << MAX_LC_LP],
+            is_match: [PROB_INIT; STATES * POS_STATES_MAX],
+            is_rep: [PROB_INIT; STATES],
+            is_rep_g0: [PROB_INIT; STATES],
+            is_rep_g1: [PROB_INIT; STATES],
+            is_rep_g2: [PROB_INIT; STATES],
+            is_rep0_long: [PROB_INIT; STATES * POS_STATES_MAX],
+            pos_slot: [[PROB_INIT; 1 << 6]; LEN_TO_POS_STATES],
+            pos: [PROB_INIT; 1 + FULL_DISTANCES - END_POS_MODEL_INDEX as usize],
+            align: [PROB_INIT; 1 << ALIGN_BITS],
+            len: LenDecoder::new(),
+            rep_len: LenDecoder::new(),
+        }
+    }
+
+    /// Decode a match distance, less one
+    fn distance(&mut self, rc: &mut RangeDecoder, len: usize) -> Result<u32, DecompressError> {
+        let len_state = len.min(LEN_TO_POS_STATES - 1);
+        let slot = rc.tree(&mut self.pos_slot[len_state], 6)?;
+        if slot < 4 {
+            return Ok(slot);
+        }
+
+        let direct_bits = (slot >> 1) - 1;
+        let distance = (2 | (slot & 1)) << direct_bits;
+        if slot < END_POS_MODEL_INDEX {
+            let probs = &mut self.pos[(distance - slot) as usize..];
+            Ok(distance + rc.reverse_tree(probs, direct_bits)?)
+        } else {
+            let high = rc.direct_bits(direct_bits - ALIGN_BITS)? << ALIGN_BITS;
+            Ok(distance + high + rc.reverse_tree(&mut self.align, ALIGN_BITS)?)
+        }
+    }
+}
+
+/// Decode a literal
+fn decode_literal(
+    probs: &mut [u16],
+    rc: &mut RangeDecoder,
+    match_byte: Option<u8>,
+) -> Result<u8, DecompressError> {
+    let mut symbol = 1usize;
+    if let Some(mut match_byte) = match_byte {
+        while symbol < 0x100 {
+            let match_bit = (match_byte >> 7) as usize & 1;
+            match_byte <<= 1;
+            let bit = rc.bit(&mut probs[((1 + match_bit) << 8) + symbol])? as usize;
+            symbol = symbol << 1 | bit;
+            if match_bit != bit {
+                break;
+            }
+        }
+    }
+    while symbol < 0x100 {
+        symbol = symbol << 1 | rc.bit(&mut probs[symbol])? as usize;
+    }
+    Ok(symbol as u8)
+}
+
+/// Decode an LZMA stream with its header
+pub(super) fn decode(input: &[u8], sink: &mut impl Sink) -> Result<(), DecompressError> {
+    let header = Header::parse(input)?;
+    if header.lc + header.lp > MAX_LC_LP {
+        return Err(DecompressError::Unsupported);
+    }
+    let mut rc = RangeDecoder::new(&input[HEADER_SIZE..])?;
+    let mut model = Model::new();
+
+    let pos_mask = (1u64 << header.pb) - 1;
+    let literal_pos_mask = (1u64 << header.lp) - 1;
+    let dict_size = header.dict_size as usize;
+    let start = sink.total();
+    let mut state = 0usize;
+    let mut reps = [0usize; 4];
+
+    loop {
+        let pos = sink.total() - start;
+        if header.size == Some(pos) {
+            return Ok(());
+        }
+        let pos_state = (pos & pos_mask) as usize;
+
+        if rc.bit(&mut model.is_match[(state << 4) + pos_state])? == 0 {
+            let prev = sink.byte_back(1) as usize;
+            let coder =
+                (((pos & literal_pos_mask) as usize) << header.lc) + (prev >> (8 - header.lc));
+            let probs = &mut model.literal[coder * LITERAL_CODER_SIZE..][..LITERAL_CODER_SIZE];
+            let match_byte = (state >= 7).then(|| sink.byte_back(reps[0] + 1));
+            sink.push(decode_literal(probs, &mut rc, match_byte)?)?;
+            state = match state {
+                0..4 => 0,
+                4..10 => state - 3,
+                _ => state - 6,
+            };
+            continue;
+        }
+
+        let len = if rc.bit(&mut model.is_rep[state])? != 0 {
+            if pos == 0 {
+                return Err(DecompressError::Corrupt);
+            }
+            if rc.bit(&mut model.is_rep_g0[state])? == 0 {
+                if rc.bit(&mut model.is_rep0_long[(state << 4) + pos_state])? == 0 {
+                    // Short rep: one byte at the last distance
+                    state = if state < 7 { 9 } else { 11 };
+                    sink.copy_match(reps[0] + 1, 1)?;
+                    continue;
+                }
+            } else {
+                let distance = if rc.bit(&mut model.is_rep_g1[state])? == 0 {
+                    reps[1]
+                } else {
+                    let distance = if rc.bit(&mut model.is_rep_g2[state])? == 0 {
+                        reps[2]
+                    } else {
+                        let distance = reps[3];
+                        reps[3] = reps[2];
+                        distance
+                    };
+                    reps[2] = reps[1];
+                    distance
+                };
+                reps[1] = reps[0];
+                reps[0] = distance;
+            }
+            state = if state < 7 { 8 } else { 11 };
+            model.rep_len.decode(&mut rc, pos_state)?
+        } else {
+            reps.copy_within(0..3, 1);
+            let len = model.len.decode(&mut rc, pos_state)?;
+            state = if state < 7 { 7 } else { 10 };
+            let distance = model.distance(&mut rc, len)?;
+            if distance == u32::MAX {
+                // End marker
+                return match header.size {
+                    None => Ok(()),
+                    Some(_) => Err(DecompressError::Corrupt),
+                };
+            }
+            reps[0] = distance as usize;
+            if reps[0] >= dict_size {
+                return Err(DecompressError::Corrupt);
+            }
+            len
+        };
+
+        let len = len + MATCH_MIN_LEN;
+        if let Some(size) = header.size
+            && pos + len as u64 > size
+        {
+            return Err(DecompressError::Corrupt);
+        }
+        sink.copy_match(reps[0] + 1, len)?;
+    }
+}
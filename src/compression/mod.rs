@@ -0,0 +1,514 @@
+//! Decompression
+//!
+//! cbfstool compresses CBFS files and payloads with LZMA or LZ4, kernels,
+//! initrds and capsules often come zstd or gzip compressed, and squashfs
+//! blocks are zlib, LZMA, LZ4 or zstd compressed. This module decodes all of
+//! them without allocating:
+//!
+//! - [`decompress`] writes the whole output to a buffer, which doubles as
+//!   the history matches are copied from.
+//! - [`decompress_streaming`] keeps only the last [`window_size`] bytes in a
+//!   caller-provided window and hands the output over in chunks as the
+//!   window fills, for outputs too large to hold at once.
+//!
+//! Input is taken as one slice since it is usually memory mapped (flash,
+//! or a file already read into memory). Checksums are skipped.
+
+mod deflate;
+mod lz4;
+mod lzma;
+mod zstd;
+
+/// A compression format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// gzip members
+    Gzip,
+    /// LZ4 frame format
+    Lz4,
+    /// LZ4 block without a frame, as squashfs stores it
+    Lz4Block,
+    /// LZMA "alone" format (13-byte header), as used by coreboot
+    Lzma,
+    /// Zstandard frames
+    Zstd,
+    /// zlib stream, as squashfs stores gzip compressed blocks
+    Zlib,
+}
+
+impl Format {
+    /// Recognise a format by its magic number
+    ///
+    /// LZMA, zlib and bare LZ4 blocks have no magic number and are never
+    /// detected.
+    pub fn detect(input: &[u8]) -> Option<Format> {
+        if input.starts_with(&deflate::GZIP_MAGIC) {
+            return Some(Format::Gzip);
+        }
+        let magic = u32::from_le_bytes(input.get(..4)?.try_into().ok()?);
+        match magic {
+            lz4::MAGIC => Some(Format::Lz4),
+            zstd::MAGIC => Some(Format::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Decompression error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressError {
+    /// Input ends early
+    Truncated,
+    /// Input is not valid for the format
+    Corrupt,
+    /// Output buffer too small
+    OutputTooSmall,
+    /// A match reaches further back than the streaming window
+    WindowTooSmall,
+    /// Valid input using a feature that is not supported
+    Unsupported,
+}
+
+/// Decompressed size recorded in the header, if any
+pub fn decompressed_size(format: Format, input: &[u8]) -> Option<u64> {
+    match format {
+        Format::Lz4 => lz4::content_size(input),
+        Format::Lzma => lzma::Header::parse(input).ok()?.size,
+        Format::Zstd => zstd::content_size(input),
+        Format::Gzip | Format::Lz4Block | Format::Zlib => None,
+    }
+}
+
+/// Window size [`decompress_streaming`] needs for `input`
+///
+/// The furthest back a match can reach, capped at the decompressed size
+/// when the header records it.
+pub fn window_size(format: Format, input: &[u8]) -> Result<usize, DecompressError> {
+    let window = match format {
+        Format::Gzip | Format::Zlib => deflate::WINDOW_SIZE as u64,
+        Format::Lz4 | Format::Lz4Block => lz4::WINDOW_SIZE as u64,
+        Format::Lzma => lzma::Header::parse(input)?.dict_size as u64,
+        Format::Zstd => zstd::window_size(input)?,
+    };
+    let window = match decompressed_size(format, input) {
+        Some(size) => window.min(size),
+        None => window,
+    };
+    usize::try_from(window).map_err(|_| DecompressError::Unsupported)
+}
+
+/// Decompress `input` into `output`, returning the decompressed size
+pub fn decompress(
+    format: Format,
+    input: &[u8],
+    output: &mut [u8],
+) -> Result<usize, DecompressError> {
+    let mut sink = SliceSink {
+        out: output,
+        pos: 0,
+    };
+    decode(format, input, &mut sink)?;
+    Ok(sink.pos)
+}
+
+/// Decompress `input` through a window, returning the decompressed size
+///
+/// `output` receives the decompressed data in order, in chunks of at most
+/// `window.len()` bytes. The window must hold at least [`window_size`]
+/// bytes, or decompression may fail with [`DecompressError::WindowTooSmall`].
+pub fn decompress_streaming(
+    format: Format,
+    input: &[u8],
+    window: &mut [u8],
+    output: impl FnMut(&[u8]),
+) -> Result<u64, DecompressError> {
+    if window.is_empty() {
+        return Err(DecompressError::WindowTooSmall);
+    }
+    let mut sink = WindowSink {
+        window,
+        pos: 0,
+        total: 0,
+        output,
+    };
+    decode(format, input, &mut sink)?;
+    sink.flush();
+    Ok(sink.total)
+}
+
+fn decode(format: Format, input: &[u8], sink: &mut impl Sink) -> Result<(), DecompressError> {
+    match format {
+        Format::Gzip => deflate::decode_gzip(input, sink),
+        Format::Lz4 => lz4::decode(input, sink),
+        Format::Lz4Block => lz4::decode_block(input, sink),
+        Format::Lzma => lzma::decode(input, sink),
+        Format::Zstd => zstd::decode(input, sink),
+        Format::Zlib => deflate::decode_zlib(input, sink),
+    }
+}
+
+/// Destination of decompressed data, which is also the match history
+trait Sink {
+    /// Number of bytes written so far
+    fn total(&self) -> u64;
+
+    /// Append a byte
+    fn push(&mut self, byte: u8) -> Result<(), DecompressError>;
+
+    /// Append bytes
+    fn extend(&mut self, bytes: &[u8]) -> Result<(), DecompressError> {
+        bytes.iter().try_for_each(|&byte| self.push(byte))
+    }
+
+    /// Append `len` bytes copied from `distance` bytes back
+    ///
+    /// The source may overlap the bytes being appended.
+    fn copy_match(&mut self, distance: usize, len: usize) -> Result<(), DecompressError>;
+
+    /// The byte `distance` bytes back, 0 if that is before the start
+    fn byte_back(&self, distance: usize) -> u8;
+}
+
+/// Sink writing the whole output to a buffer
+struct SliceSink<'a> {
+    out: &'a mut [u8],
+    pos: usize,
+}
+
+impl Sink for SliceSink<'_> {
+    fn total(&self) -> u64 {
+        self.pos as u64
+    }
+
+    fn push(&mut self, byte: u8) -> Result<(), DecompressError> {
+        let slot = self
+            .out
+            .get_mut(self.pos)
+            .ok_or(DecompressError::OutputTooSmall)?;
+        *slot = byte;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Result<(), DecompressError> {
+        let end = self.pos + bytes.len();
+        self.out
+            .get_mut(self.pos..end)
+            .ok_or(DecompressError::OutputTooSmall)?
+            .copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn copy_match(&mut self, distance: usize, len: usize) -> Result<(), DecompressError> {
+        if distance == 0 || distance > self.pos {
+            return Err(DecompressError::Corrupt);
+        }
+        let end = self.pos + len;
+        if end > self.out.len() {
+            return Err(DecompressError::OutputTooSmall);
+        }
+        let start = self.pos - distance;
+        if distance >= len {
+            self.out.copy_within(start..start + len, self.pos);
+        } else {
+            // Overlapping: each byte may be one this copy just wrote
+            for i in self.pos..end {
+                self.out[i] = self.out[i - distance];
+            }
+        }
+        self.pos = end;
+        Ok(())
+    }
+
+    fn byte_back(&self, distance: usize) -> u8 {
+        match self.pos.checked_sub(distance) {
+            Some(index) => self.out[index],
+            None => 0,
+        }
+    }
+}
+
+/// Sink keeping the most recent output in a ring buffer
+///
+/// The window is handed to `output` each time it fills up.
+struct WindowSink<'a, F: FnMut(&[u8])> {
+    window: &'a mut [u8],
+    /// Next position to write in the window
+    pos: usize,
+    total: u64,
+    output: F,
+}
+
+impl<F: FnMut(&[u8])> WindowSink<'_, F> {
+    /// Hand the data written since the last flush to `output`
+    fn flush(&mut self) {
+        if self.pos > 0 {
+            (self.output)(&self.window[..self.pos]);
+        }
+    }
+}
+
+impl<F: FnMut(&[u8])> Sink for WindowSink<'_, F> {
+    fn total(&self) -> u64 {
+        self.total
+    }
+
+    fn push(&mut self, byte: u8) -> Result<(), DecompressError> {
+        self.window[self.pos] = byte;
+        self.pos += 1;
+        self.total += 1;
+        if self.pos == self.window.len() {
+            self.flush();
+            self.pos = 0;
+        }
+        Ok(())
+    }
+
+    fn copy_match(&mut self, distance: usize, len: usize) -> Result<(), DecompressError> {
+        if distance == 0 || distance as u64 > self.total {
+            return Err(DecompressError::Corrupt);
+        }
+        if distance > self.window.len() {
+            return Err(DecompressError::WindowTooSmall);
+        }
+        for _ in 0..len {
+            self.push(self.byte_back(distance))?;
+        }
+        Ok(())
+    }
+
+    fn byte_back(&self, distance: usize) -> u8 {
+        if distance as u64 > self.total || distance > self.window.len() {
+            return 0;
+        }
+        let size = self.window.len();
+        self.window[(self.pos + size - distance) % size]
+    }
+}
+
+/// Little-endian reader over the input
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Input<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Input { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecompressError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(DecompressError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecompressError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// Little-endian value of `len` (at most 8) bytes
+    fn uint(&mut self, len: usize) -> Result<u64, DecompressError> {
+        let bytes = self.bytes(len)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | byte as u64))
+    }
+
+    fn u16(&mut self) -> Result<u16, DecompressError> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32, DecompressError> {
+        Ok(self.uint(4)? as u32)
+    }
+
+    fn u64(&mut self) -> Result<u64, DecompressError> {
+        self.uint(8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"CrabEFI loads payloads from CBFS. CrabEFI loads payloads from CBFS. \
+        CrabEFI loads payloads from CBFS. Compressed payloads load faster from SPI flash.\n";
+
+    /// `lz4 --content-size`
+    const LZ4: [u8; 100] = [
+        0x04, 0x22, 0x4d, 0x18, 0x6c, 0x40, 0x96, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x87,
+        0x49, 0x00, 0x00, 0x00, 0xf2, 0x02, 0x43, 0x72, 0x61, 0x62, 0x45, 0x46, 0x49, 0x20, 0x6c,
+        0x6f, 0x61, 0x64, 0x73, 0x20, 0x70, 0x61, 0x79, 0x09, 0x00, 0xbf, 0x66, 0x72, 0x6f, 0x6d,
+        0x20, 0x43, 0x42, 0x46, 0x53, 0x2e, 0x20, 0x22, 0x00, 0x32, 0x96, 0x6f, 0x6d, 0x70, 0x72,
+        0x65, 0x73, 0x73, 0x65, 0x64, 0x63, 0x00, 0x00, 0x69, 0x00, 0x72, 0x20, 0x66, 0x61, 0x73,
+        0x74, 0x65, 0x72, 0x6f, 0x00, 0xb0, 0x53, 0x50, 0x49, 0x20, 0x66, 0x6c, 0x61, 0x73, 0x68,
+        0x2e, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x8c, 0xb3, 0xa1, 0x5c,
+    ];
+
+    /// `xz --format=lzma`, with an end marker and no size
+    const LZMA: [u8; 89] = [
+        0x5d, 0x00, 0x10, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x21,
+        0x9c, 0x88, 0x26, 0x38, 0xc4, 0xfe, 0x28, 0x66, 0xd1, 0x7c, 0x48, 0x72, 0x5c, 0x54, 0xcc,
+        0xf9, 0x15, 0x3d, 0xf6, 0x2d, 0x76, 0x6a, 0x68, 0x96, 0xf6, 0xd9, 0x17, 0x61, 0x0a, 0x6e,
+        0x05, 0xab, 0x4d, 0x54, 0x9c, 0x04, 0xe8, 0xe1, 0xaa, 0x30, 0xcf, 0xdf, 0xdd, 0x7c, 0xa5,
+        0xb3, 0x9c, 0x51, 0x18, 0xb4, 0x0a, 0x20, 0xb7, 0x56, 0xb5, 0x66, 0xa6, 0xba, 0xd9, 0x14,
+        0x7a, 0xe9, 0xe7, 0x77, 0xbb, 0x7d, 0x80, 0xfa, 0x3e, 0xff, 0xa8, 0xa5, 0x80, 0x00,
+    ];
+
+    /// `zstd -19`: Huffman coded literals and FSE coded sequences
+    const ZSTD: [u8; 81] = [
+        0x28, 0xb5, 0x2f, 0xfd, 0x24, 0x96, 0x25, 0x02, 0x00, 0x72, 0x83, 0x0c, 0x12, 0xb0, 0xeb,
+        0x3f, 0x44, 0xb6, 0x64, 0xab, 0xb2, 0xa7, 0x95, 0x84, 0x10, 0xaf, 0xe1, 0x4c, 0x1f, 0x88,
+        0x01, 0x20, 0xac, 0x4e, 0xb6, 0x37, 0x07, 0xb1, 0xc2, 0xce, 0xbd, 0xd2, 0x6e, 0xe5, 0xd7,
+        0xdc, 0x03, 0x2a, 0x62, 0xbc, 0x39, 0xde, 0x98, 0xef, 0xad, 0xd2, 0xb1, 0x9b, 0x42, 0x52,
+        0xf2, 0x08, 0x05, 0x00, 0x4e, 0x8d, 0xa5, 0x04, 0x20, 0xd4, 0x76, 0xa4, 0x28, 0xe4, 0x13,
+        0x2b, 0x4b, 0x96, 0x1c, 0x0e, 0x9e,
+    ];
+
+    /// The block of the LZ4 frame
+    const LZ4_BLOCK: &[u8] = LZ4.split_at(19).1.split_at(73).0;
+
+    /// `gzip -9`: a fixed Huffman block
+    const GZIP: [u8; 85] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x73, 0x2e, 0x4a, 0x4c, 0x72,
+        0x75, 0xf3, 0x54, 0xc8, 0xc9, 0x4f, 0x4c, 0x29, 0x56, 0x28, 0x48, 0xac, 0x84, 0x30, 0xd2,
+        0x8a, 0xf2, 0x73, 0x15, 0x9c, 0x9d, 0xdc, 0x82, 0xf5, 0x14, 0x9c, 0xa9, 0xa1, 0x22, 0x3f,
+        0xb7, 0xa0, 0x28, 0xb5, 0xb8, 0x38, 0x35, 0x05, 0x21, 0x0d, 0x22, 0x15, 0xd2, 0x12, 0x8b,
+        0x4b, 0x52, 0x8b, 0x20, 0x4a, 0x83, 0x03, 0x3c, 0x15, 0xd2, 0x72, 0x12, 0x8b, 0x33, 0xf4,
+        0xb8, 0x00, 0xf0, 0x8b, 0x10, 0x38, 0x96, 0x00, 0x00, 0x00,
+    ];
+
+    /// Python's `zlib.compress(text, 9)`
+    const ZLIB: [u8; 73] = [
+        0x78, 0xda, 0x73, 0x2e, 0x4a, 0x4c, 0x72, 0x75, 0xf3, 0x54, 0xc8, 0xc9, 0x4f, 0x4c, 0x29,
+        0x56, 0x28, 0x48, 0xac, 0x84, 0x30, 0xd2, 0x8a, 0xf2, 0x73, 0x15, 0x9c, 0x9d, 0xdc, 0x82,
+        0xf5, 0x14, 0x9c, 0xa9, 0xa1, 0x22, 0x3f, 0xb7, 0xa0, 0x28, 0xb5, 0xb8, 0x38, 0x35, 0x05,
+        0x21, 0x0d, 0x22, 0x15, 0xd2, 0x12, 0x8b, 0x4b, 0x52, 0x8b, 0x20, 0x4a, 0x83, 0x03, 0x3c,
+        0x15, 0xd2, 0x72, 0x12, 0x8b, 0x33, 0xf4, 0xb8, 0x00, 0x15, 0x67, 0x33, 0x52,
+    ];
+
+    /// zlib stream of `0,1,4,9,...` (squares mod 97): a dynamic Huffman block
+    const ZLIB_DYNAMIC: [u8; 158] = [
+        0x78, 0xda, 0xed, 0x90, 0xc9, 0x8d, 0x05, 0x21, 0x0c, 0x05, 0x13, 0xaa, 0x43, 0xdb, 0x06,
+        0x03, 0xf9, 0x27, 0x36, 0x05, 0x09, 0xfc, 0x04, 0x46, 0x42, 0x88, 0xe5, 0xad, 0xfe, 0x08,
+        0x06, 0x87, 0x68, 0x72, 0x52, 0xcd, 0x38, 0xf4, 0x60, 0x07, 0x45, 0x0e, 0xc6, 0x62, 0x25,
+        0x49, 0x05, 0x9d, 0x1c, 0x11, 0xc5, 0xfa, 0x88, 0x64, 0x16, 0x47, 0xb4, 0x5c, 0x05, 0x3c,
+        0x0f, 0xe6, 0xc7, 0xa6, 0x27, 0xb9, 0xd8, 0x9b, 0x39, 0xc8, 0x64, 0xab, 0xa6, 0xd4, 0x24,
+        0x82, 0xdd, 0xb4, 0x8c, 0x4d, 0x25, 0x21, 0x92, 0x53, 0xec, 0xc9, 0x3a, 0x2c, 0xf7, 0x7a,
+        0xeb, 0x5d, 0x7d, 0xf4, 0xab, 0x2f, 0x48, 0xa8, 0x04, 0x69, 0x92, 0xe3, 0x09, 0x29, 0xa7,
+        0xa8, 0xd2, 0x1a, 0x68, 0xa3, 0x99, 0x96, 0xfb, 0x9a, 0x1b, 0xe1, 0x06, 0x89, 0x17, 0xaa,
+        0x6f, 0x40, 0x63, 0x1a, 0xd6, 0xc8, 0x06, 0xef, 0x57, 0x22, 0x6f, 0x1d, 0x4b, 0x59, 0xad,
+        0x6e, 0x49, 0xab, 0x5a, 0xb8, 0x5e, 0x79, 0x47, 0x70, 0x9c, 0x45, 0xf0, 0xfd, 0xcf, 0xe4,
+        0xc7, 0x4c, 0xfe, 0x00, 0x90, 0x13, 0x6d, 0xad,
+    ];
+
+    fn vectors() -> [(Format, &'static [u8]); 6] {
+        [
+            (Format::Gzip, &GZIP),
+            (Format::Lz4, &LZ4),
+            (Format::Lz4Block, LZ4_BLOCK),
+            (Format::Lzma, &LZMA),
+            (Format::Zstd, &ZSTD),
+            (Format::Zlib, &ZLIB),
+        ]
+    }
+
+    #[test]
+    fn decompresses_each_format() {
+        for (format, input) in vectors() {
+            let mut output = [0u8; 256];
+            assert_eq!(decompress(format, input, &mut output), Ok(TEXT.len()));
+            assert_eq!(&output[..TEXT.len()], TEXT, "{:?}", format);
+
+            let mut short = [0u8; 100];
+            assert_eq!(
+                decompress(format, input, &mut short),
+                Err(DecompressError::OutputTooSmall)
+            );
+        }
+    }
+
+    #[test]
+    fn streams_through_a_window() {
+        for (format, input) in vectors() {
+            let mut window = [0u8; 128];
+            let mut output = std::vec::Vec::new();
+            let mut chunks = 0;
+            let result = decompress_streaming(format, input, &mut window, |chunk| {
+                output.extend_from_slice(chunk);
+                chunks += 1;
+            });
+            assert_eq!(result, Ok(TEXT.len() as u64), "{:?}", format);
+            assert_eq!(output, TEXT);
+            assert_eq!(chunks, TEXT.len().div_ceil(window.len()));
+        }
+    }
+
+    #[test]
+    fn reads_headers() {
+        assert_eq!(Format::detect(&LZ4), Some(Format::Lz4));
+        assert_eq!(Format::detect(&ZSTD), Some(Format::Zstd));
+        assert_eq!(Format::detect(&GZIP), Some(Format::Gzip));
+        assert_eq!(Format::detect(&LZMA), None);
+        assert_eq!(Format::detect(&ZLIB), None);
+        assert_eq!(
+            decompressed_size(Format::Lz4, &LZ4),
+            Some(TEXT.len() as u64)
+        );
+        assert_eq!(decompressed_size(Format::Lzma, &LZMA), None);
+        assert_eq!(window_size(Format::Lzma, &LZMA), Ok(4096));
+        assert_eq!(window_size(Format::Zstd, &ZSTD), Ok(TEXT.len()));
+        assert_eq!(window_size(Format::Zlib, &ZLIB), Ok(32768));
+    }
+
+    #[test]
+    fn inflates_dynamic_and_stored_blocks() {
+        let expected: std::string::String =
+            (0..200).map(|i| std::format!("{},", i * i % 97)).collect();
+        let mut output = [0u8; 1024];
+        let len = decompress(Format::Zlib, &ZLIB_DYNAMIC, &mut output).unwrap();
+        assert_eq!(&output[..len], expected.as_bytes());
+
+        // A stored block, then a second gzip member
+        let mut gzip = std::vec::Vec::from(&GZIP[..10]);
+        gzip.extend_from_slice(&[0x01, 0x05, 0x00, 0xfa, 0xff]);
+        gzip.extend_from_slice(b"Crab!");
+        gzip.extend_from_slice(&[0; 8]);
+        gzip.extend_from_slice(&GZIP);
+        let len = decompress(Format::Gzip, &gzip, &mut output).unwrap();
+        assert_eq!(&output[..5], b"Crab!");
+        assert_eq!(&output[5..len], TEXT);
+    }
+
+    #[test]
+    fn skips_zero_padding() {
+        for (format, input) in [(Format::Zstd, &ZSTD[..]), (Format::Gzip, &GZIP[..])] {
+            let mut padded = std::vec::Vec::from(input);
+            padded.resize(4096, 0);
+            let mut output = [0u8; 256];
+            assert_eq!(decompress(format, &padded, &mut output), Ok(TEXT.len()));
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_input() {
+        let mut input = ZSTD;
+        input[20] ^= 0x10;
+        let mut output = [0u8; 256];
+        assert!(decompress(Format::Zstd, &input, &mut output).is_err());
+        assert_eq!(
+            decompress(Format::Lz4, &LZ4[..50], &mut output),
+            Err(DecompressError::Truncated)
+        );
+    }
+}
@@ -0,0 +1,998 @@
+//! Zstandard decoder
+//!
+//! Decodes concatenated zstd frames, skipping skippable frames. Frames
+//! needing a dictionary are not supported and checksums are not checked.
+//!
+//! Literals are Huffman decoded as the sequences consume them and each
+//! sequence is executed as soon as it is decoded, so beyond the output
+//! window the decoder only needs its tables (about 12 KiB of stack).
+//!
+//! Reference: RFC 8878, Zstandard Compression and the application/zstd Media
+//! Type
+
+use super::{DecompressError, Input, Sink};
+
+/// Magic number of a zstd frame
+pub(super) const MAGIC: u32 = 0xFD2F_B528;
+
+/// Magic numbers of skippable frames, the low nibble is free
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+
+/// Largest block size
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Frame header descriptor bits
+const FHD_SINGLE_SEGMENT: u8 = 0x20;
+const FHD_RESERVED: u8 = 0x08;
+const FHD_CHECKSUM: u8 = 0x04;
+
+/// Block types
+const BLOCK_RAW: u64 = 0;
+const BLOCK_RLE: u64 = 1;
+const BLOCK_COMPRESSED: u64 = 2;
+
+/// Literals block types
+const LITERALS_RAW: u8 = 0;
+const LITERALS_RLE: u8 = 1;
+const LITERALS_COMPRESSED: u8 = 2;
+
+/// Sequence table modes
+const MODE_PREDEFINED: u8 = 0;
+const MODE_RLE: u8 = 1;
+const MODE_COMPRESSED: u8 = 2;
+
+/// Largest Huffman code length
+const HUFFMAN_MAX_BITS: u32 = 11;
+
+/// Largest FSE table, in entries
+const FSE_MAX_SIZE: usize = 1 << 9;
+
+/// Symbols in an FSE distribution, enough for match length codes
+const FSE_MAX_SYMBOLS: usize = 64;
+
+/// Accuracy log limits and largest symbol per FSE table kind
+const LITERAL_LENGTH: (u32, usize) = (9, 35);
+const OFFSET: (u32, usize) = (8, 31);
+const MATCH_LENGTH: (u32, usize) = (9, 52);
+const HUFFMAN_WEIGHTS: (u32, usize) = (6, 12);
+
+/// Predefined distributions (RFC 8878, Section 3.1.1.3.2.2)
+const LITERAL_LENGTH_DEFAULT: (u32, [i16; 36]) = (
+    6,
+    [
+        4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1,
+        1, 1, -1, -1, -1, -1,
+    ],
+);
+const MATCH_LENGTH_DEFAULT: (u32, [i16; 53]) = (
+    6,
+    [
+        1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+    ],
+);
+const OFFSET_DEFAULT: (u32, [i16; 29]) = (
+    5,
+    [
+        1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+    ],
+);
+
+/// Literal length code baselines and extra bits
+const LITERAL_LENGTH_CODES: [(u32, u32); 36] = [
+    (0, 0),
+    (1, 0),
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 0),
+    (12, 0),
+    (13, 0),
+    (14, 0),
+    (15, 0),
+    (16, 1),
+    (18, 1),
+    (20, 1),
+    (22, 1),
+    (24, 2),
+    (28, 2),
+    (32, 3),
+    (40, 3),
+    (48, 4),
+    (64, 6),
+    (128, 7),
+    (256, 8),
+    (512, 9),
+    (1024, 10),
+    (2048, 11),
+    (4096, 12),
+    (8192, 13),
+    (16384, 14),
+    (32768, 15),
+    (65536, 16),
+];
+
+/// Match length code baselines and extra bits
+const MATCH_LENGTH_CODES: [(u32, u32); 53] = [
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 0),
+    (12, 0),
+    (13, 0),
+    (14, 0),
+    (15, 0),
+    (16, 0),
+    (17, 0),
+    (18, 0),
+    (19, 0),
+    (20, 0),
+    (21, 0),
+    (22, 0),
+    (23, 0),
+    (24, 0),
+    (25, 0),
+    (26, 0),
+    (27, 0),
+    (28, 0),
+    (29, 0),
+    (30, 0),
+    (31, 0),
+    (32, 0),
+    (33, 0),
+    (34, 0),
+    (35, 1),
+    (37, 1),
+    (39, 1),
+    (41, 1),
+    (43, 2),
+    (47, 2),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 5),
+    (131, 7),
+    (259, 8),
+    (515, 9),
+    (1027, 10),
+    (2051, 11),
+    (4099, 12),
+    (8195, 13),
+    (16387, 14),
+    (32771, 15),
+    (65539, 16),
+];
+
+/// Parsed frame header
+struct FrameHeader {
+    window_size: u64,
+    content_size: Option<u64>,
+    checksum: bool,
+}
+
+/// Parse the frame header following the magic number
+fn read_frame_header(input: &mut Input) -> Result<FrameHeader, DecompressError> {
+    let descriptor = input.u8()?;
+    if descriptor & FHD_RESERVED != 0 {
+        return Err(DecompressError::Corrupt);
+    }
+    let single_segment = descriptor & FHD_SINGLE_SEGMENT != 0;
+
+    let window_size = if single_segment {
+        0
+    } else {
+        let window = input.u8()?;
+        let base = 1u64 << (10 + (window >> 3));
+        base + (base >> 3) * (window & 7) as u64
+    };
+    let dictionary_id = input.uint([0, 1, 2, 4][(descriptor & 3) as usize])?;
+    if dictionary_id != 0 {
+        return Err(DecompressError::Unsupported);
+    }
+    let content_size = match descriptor >> 6 {
+        0 if single_segment => Some(input.uint(1)?),
+        0 => None,
+        1 => Some(input.uint(2)? + 256),
+        2 => Some(input.uint(4)?),
+        _ => Some(input.uint(8)?),
+    };
+
+    Ok(FrameHeader {
+        window_size: match content_size {
+            Some(size) if single_segment => size,
+            _ => window_size,
+        },
+        content_size,
+        checksum: descriptor & FHD_CHECKSUM != 0,
+    })
+}
+
+/// Parse the header of the first frame
+fn first_frame_header(input: &[u8]) -> Result<FrameHeader, DecompressError> {
+    let mut input = Input::new(input);
+    if input.u32()? != MAGIC {
+        return Err(DecompressError::Corrupt);
+    }
+    read_frame_header(&mut input)
+}
+
+/// Content size of the first frame, if recorded
+pub(super) fn content_size(input: &[u8]) -> Option<u64> {
+    first_frame_header(input).ok()?.content_size
+}
+
+/// Window size of the first frame
+pub(super) fn window_size(input: &[u8]) -> Result<u64, DecompressError> {
+    Ok(first_frame_header(input)?.window_size)
+}
+
+/// Forward bit reader, least significant bit first
+struct ForwardBits<'a> {
+    data: &'a [u8],
+    /// Bits consumed
+    pos: usize,
+}
+
+impl ForwardBits<'_> {
+    /// The next `count` bits, zeros past the end
+    fn peek(&self, count: u32) -> u32 {
+        let byte = self.pos / 8;
+        let value = self
+            .data
+            .iter()
+            .skip(byte)
+            .take(4)
+            .rev()
+            .fold(0u64, |value, &b| value << 8 | b as u64);
+        ((value >> (self.pos % 8)) & ((1 << count) - 1)) as u32
+    }
+
+    fn read(&mut self, count: u32) -> u32 {
+        let value = self.peek(count);
+        self.pos += count as usize;
+        value
+    }
+}
+
+/// Backward bit reader for FSE and Huffman coded streams
+///
+/// Reads from the end of the stream towards its start, most significant
+/// bits first. Reading past the start yields zeros and makes the position
+/// negative, which is how the end of some streams is detected.
+struct BackwardBits<'a> {
+    data: &'a [u8],
+    /// Bits left
+    pos: isize,
+}
+
+impl<'a> BackwardBits<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, DecompressError> {
+        // The last byte is padded up to its highest set bit
+        let last = *data.last().ok_or(DecompressError::Corrupt)?;
+        if last == 0 {
+            return Err(DecompressError::Corrupt);
+        }
+        let pos = data.len() * 8 - last.leading_zeros() as usize - 1;
+        Ok(BackwardBits {
+            data,
+            pos: pos as isize,
+        })
+    }
+
+    /// `count` bits starting at bit `start`, `start + count` within the data
+    fn extract(&self, start: usize, count: u32) -> u64 {
+        let value = self.data[start / 8..]
+            .iter()
+            .take(8)
+            .rev()
+            .fold(0u64, |value, &b| value << 8 | b as u64);
+        (value >> (start % 8)) & ((1 << count) - 1)
+    }
+
+    /// The next `count` bits
+    fn peek(&self, count: u32) -> u64 {
+        let start = self.pos - count as isize;
+        if count == 0 || self.pos <= 0 {
+            0
+        } else if start >= 0 {
+            self.extract(start as usize, count)
+        } else {
+            self.extract(0, self.pos as u32) << -start
+        }
+    }
+
+    fn read(&mut self, count: u32) -> u64 {
+        let value = self.peek(count);
+        self.pos -= count as isize;
+        value
+    }
+
+    fn is_finished(&self) -> bool {
+        self.pos == 0
+    }
+
+    fn overflowed(&self) -> bool {
+        self.pos < 0
+    }
+}
+
+/// FSE decoding table entry
+#[derive(Clone, Copy, Default)]
+struct FseEntry {
+    symbol: u8,
+    bits: u8,
+    base: u16,
+}
+
+/// FSE decoding table
+struct FseTable {
+    entries: [FseEntry; FSE_MAX_SIZE],
+    log: u32,
+    /// Set once built, for the repeat mode
+    ready: bool,
+}
+
+impl FseTable {
+    const fn new() -> Self {
+        FseTable {
+            entries: [FseEntry {
+                symbol: 0,
+                bits: 0,
+                base: 0,
+            }; FSE_MAX_SIZE],
+            log: 0,
+            ready: false,
+        }
+    }
+
+    /// Build the table for a normalized distribution
+    fn build(&mut self, distribution: &[i16], log: u32) -> Result<(), DecompressError> {
+        let size = 1usize << log;
+        let mask = size - 1;
+        let mut high = size - 1;
+        let mut next = [0u16; FSE_MAX_SYMBOLS];
+
+        // "Less than 1" probabilities take the last cells
+        for (symbol, &probability) in distribution.iter().enumerate() {
+            if probability == -1 {
+                self.entries[high].symbol = symbol as u8;
+                high = high.checked_sub(1).ok_or(DecompressError::Corrupt)?;
+                next[symbol] = 1;
+            } else {
+                next[symbol] = probability as u16;
+            }
+        }
+
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut position = 0;
+        for (symbol, &probability) in distribution.iter().enumerate() {
+            for _ in 0..probability.max(0) {
+                self.entries[position].symbol = symbol as u8;
+                position = (position + step) & mask;
+                while position > high {
+                    position = (position + step) & mask;
+                }
+            }
+        }
+        if position != 0 {
+            return Err(DecompressError::Corrupt);
+        }
+
+        for entry in &mut self.entries[..size] {
+            let state = next[entry.symbol as usize];
+            next[entry.symbol as usize] += 1;
+            let bits = log - (15 - state.leading_zeros());
+            entry.bits = bits as u8;
+            entry.base = ((state as usize) << bits).wrapping_sub(size) as u16;
+        }
+        self.log = log;
+        self.ready = true;
+        Ok(())
+    }
+
+    /// Build a table always decoding `symbol` without reading bits
+    fn build_rle(&mut self, symbol: u8) {
+        self.entries[0] = FseEntry {
+            symbol,
+            bits: 0,
+            base: 0,
+        };
+        self.log = 0;
+        self.ready = true;
+    }
+
+    /// Read a distribution and build its table, returning the bytes used
+    fn read(&mut self, data: &[u8], limits: (u32, usize)) -> Result<usize, DecompressError> {
+        let (max_log, max_symbol) = limits;
+        let mut bits = ForwardBits { data, pos: 0 };
+        let log = bits.read(4) + 5;
+        if log > max_log {
+            return Err(DecompressError::Corrupt);
+        }
+
+        let mut distribution = [0i16; FSE_MAX_SYMBOLS];
+        let mut symbols = 0;
+        let mut remaining = (1i32 << log) + 1;
+        let mut threshold = 1i32 << log;
+        let mut count = log + 1;
+        while remaining > 1 {
+            if symbols > max_symbol {
+                return Err(DecompressError::Corrupt);
+            }
+            let max = 2 * threshold - 1 - remaining;
+            let low = bits.peek(count - 1) as i32;
+            let value = if low < max {
+                bits.read(count - 1) as i32
+            } else {
+                let value = bits.read(count) as i32;
+                if value >= threshold {
+                    value - max
+                } else {
+                    value
+                }
+            };
+
+            let probability = value - 1;
+            remaining -= probability.abs();
+            distribution[symbols] = probability as i16;
+            symbols += 1;
+
+            if probability == 0 {
+                // Runs of zero probabilities
+                loop {
+                    let repeat = bits.read(2) as usize;
+                    if symbols + repeat > max_symbol + 1 {
+                        return Err(DecompressError::Corrupt);
+                    }
+                    symbols += repeat;
+                    if repeat != 3 {
+                        break;
+                    }
+                }
+            }
+            if remaining < 1 {
+                return Err(DecompressError::Corrupt);
+            }
+            while remaining < threshold {
+                count -= 1;
+                threshold >>= 1;
+            }
+        }
+        if remaining != 1 {
+            return Err(DecompressError::Corrupt);
+        }
+
+        let used = bits.pos.div_ceil(8);
+        if used > data.len() {
+            return Err(DecompressError::Truncated);
+        }
+        self.build(&distribution[..symbols], log)?;
+        Ok(used)
+    }
+
+    /// Set up the table for a sequence table mode
+    fn read_mode(
+        &mut self,
+        mode: u8,
+        input: &mut Input,
+        limits: (u32, usize),
+        default: (u32, &[i16]),
+    ) -> Result<(), DecompressError> {
+        match mode {
+            MODE_PREDEFINED => self.build(default.1, default.0),
+            MODE_RLE => {
+                let symbol = input.u8()?;
+                if symbol as usize > limits.1 {
+                    return Err(DecompressError::Corrupt);
+                }
+                self.build_rle(symbol);
+                Ok(())
+            }
+            MODE_COMPRESSED => {
+                let used = self.read(&input.data[input.pos..], limits)?;
+                input.bytes(used)?;
+                Ok(())
+            }
+            // Repeat the previous table
+            _ if self.ready => Ok(()),
+            _ => Err(DecompressError::Corrupt),
+        }
+    }
+
+    /// Initial state
+    fn init(&self, bits: &mut BackwardBits) -> usize {
+        bits.read(self.log) as usize
+    }
+
+    fn symbol(&self, state: usize) -> u8 {
+        self.entries[state].symbol
+    }
+
+    /// Move to the next state
+    fn update(&self, state: usize, bits: &mut BackwardBits) -> usize {
+        let entry = self.entries[state];
+        entry.base as usize + bits.read(entry.bits as u32) as usize
+    }
+}
+
+/// Huffman decoding table, indexed by the next [`HuffmanTable::max_bits`]
+/// bits of the stream
+struct HuffmanTable {
+    /// Symbol and code length
+    entries: [(u8, u8); 1 << HUFFMAN_MAX_BITS],
+    max_bits: u32,
+    /// Set once built, for treeless literals
+    ready: bool,
+}
+
+impl HuffmanTable {
+    const fn new() -> Self {
+        HuffmanTable {
+            entries: [(0, 0); 1 << HUFFMAN_MAX_BITS],
+            max_bits: 0,
+            ready: false,
+        }
+    }
+
+    /// Read a Huffman tree description, returning the bytes used
+    fn read(&mut self, data: &[u8], fse: &mut FseTable) -> Result<usize, DecompressError> {
+        let mut input = Input::new(data);
+        let header = input.u8()? as usize;
+        let mut weights = [0u8; 256];
+        let mut count = 0;
+
+        if header < 128 {
+            // FSE compressed, with two interleaved states
+            let data = input.bytes(header)?;
+            let used = fse.read(data, HUFFMAN_WEIGHTS)?;
+            let mut bits = BackwardBits::new(&data[used..])?;
+            let mut states = [fse.init(&mut bits), fse.init(&mut bits)];
+            let mut current = 0;
+            loop {
+                if count + 2 > weights.len() {
+                    return Err(DecompressError::Corrupt);
+                }
+                weights[count] = fse.symbol(states[current]);
+                count += 1;
+                states[current] = fse.update(states[current], &mut bits);
+                current ^= 1;
+                if bits.overflowed() {
+                    weights[count] = fse.symbol(states[current]);
+                    count += 1;
+                    break;
+                }
+            }
+        } else {
+            // Four bits each
+            count = header - 127;
+            let data = input.bytes(count.div_ceil(2))?;
+            for (i, weight) in weights[..count].iter_mut().enumerate() {
+                let byte = data[i / 2];
+                *weight = if i % 2 == 0 { byte >> 4 } else { byte & 0x0F };
+            }
+        }
+
+        self.build(&mut weights, count)?;
+        Ok(input.pos)
+    }
+
+    /// Build the table from the weights of all but the last symbol
+    fn build(&mut self, weights: &mut [u8; 256], count: usize) -> Result<(), DecompressError> {
+        let mut total = 0u32;
+        for &weight in &weights[..count] {
+            if weight as u32 > HUFFMAN_MAX_BITS {
+                return Err(DecompressError::Corrupt);
+            }
+            total += (1 << weight) >> 1;
+        }
+        if total == 0 {
+            return Err(DecompressError::Corrupt);
+        }
+
+        // The last weight completes the total to a power of two
+        let max_bits = 32 - total.leading_zeros();
+        let left = (1 << max_bits) - total;
+        if max_bits > HUFFMAN_MAX_BITS || !left.is_power_of_two() || count >= weights.len() {
+            return Err(DecompressError::Corrupt);
+        }
+        weights[count] = (left.trailing_zeros() + 1) as u8;
+        let symbols = count + 1;
+
+        // Longest codes first, each symbol spanning 2^(weight - 1) entries
+        let mut rank_count = [0usize; HUFFMAN_MAX_BITS as usize + 2];
+        for &weight in &weights[..symbols] {
+            if weight > 0 {
+                rank_count[(max_bits + 1 - weight as u32) as usize] += 1;
+            }
+        }
+        let mut rank_start = [0usize; HUFFMAN_MAX_BITS as usize + 2];
+        let mut start = 0;
+        for bits in (1..=max_bits as usize).rev() {
+            rank_start[bits] = start;
+            start += rank_count[bits] << (max_bits as usize - bits);
+        }
+        for (symbol, &weight) in weights[..symbols].iter().enumerate() {
+            if weight == 0 {
+                continue;
+            }
+            let bits = (max_bits + 1 - weight as u32) as usize;
+            let span = 1 << (max_bits as usize - bits);
+            self.entries[rank_start[bits]..rank_start[bits] + span]
+                .fill((symbol as u8, bits as u8));
+            rank_start[bits] += span;
+        }
+
+        self.max_bits = max_bits;
+        self.ready = true;
+        Ok(())
+    }
+
+    fn decode(&self, bits: &mut BackwardBits) -> u8 {
+        let (symbol, len) = self.entries[bits.peek(self.max_bits) as usize];
+        bits.read(len as u32);
+        symbol
+    }
+}
+
+/// Where the literals of a block come from
+enum LiteralSource<'a> {
+    Raw(&'a [u8]),
+    Rle(u8),
+    /// Huffman coded in one or four streams
+    Huffman {
+        streams: [&'a [u8]; 4],
+        sizes: [usize; 4],
+        /// Stream being decoded
+        stream: usize,
+        bits: Option<BackwardBits<'a>>,
+        /// Literals left in the current stream
+        left: usize,
+    },
+}
+
+/// Literals of a block, decoded as the sequences consume them
+struct Literals<'a> {
+    source: LiteralSource<'a>,
+    remaining: usize,
+}
+
+impl<'a> Literals<'a> {
+    /// Parse a literals section
+    fn read(
+        input: &mut Input<'a>,
+        huffman: &mut HuffmanTable,
+        fse: &mut FseTable,
+    ) -> Result<Self, DecompressError> {
+        let first = input.u8()?;
+        let kind = first & 3;
+        let size_format = (first >> 2) & 3;
+
+        if kind == LITERALS_RAW || kind == LITERALS_RLE {
+            let size = match size_format {
+                0 | 2 => (first >> 3) as usize,
+                1 => (first >> 4) as usize + ((input.u8()? as usize) << 4),
+                _ => (first >> 4) as usize + ((input.u16()? as usize) << 4),
+            };
+            let source = if kind == LITERALS_RAW {
+                LiteralSource::Raw(input.bytes(size)?)
+            } else {
+                LiteralSource::Rle(input.u8()?)
+            };
+            return Ok(Literals {
+                source,
+                remaining: size,
+            });
+        }
+
+        let (header_size, size_bits, stream_count) = match size_format {
+            0 => (3, 10, 1),
+            1 => (3, 10, 4),
+            2 => (4, 14, 4),
+            _ => (5, 18, 4),
+        };
+        let header = first as u64 | input.uint(header_size - 1)? << 8;
+        let mask = (1 << size_bits) - 1;
+        let size = ((header >> 4) & mask) as usize;
+        let compressed_size = ((header >> (4 + size_bits)) & mask) as usize;
+        let mut data = input.bytes(compressed_size)?;
+
+        if kind == LITERALS_COMPRESSED {
+            let used = huffman.read(data, fse)?;
+            data = &data[used..];
+        } else if !huffman.ready {
+            return Err(DecompressError::Corrupt);
+        }
+
+        let mut streams = [&[][..]; 4];
+        let mut sizes = [0; 4];
+        if stream_count == 1 {
+            streams[0] = data;
+            sizes[0] = size;
+        } else {
+            // Jump table with the sizes of the first three streams
+            let mut jump_table = Input::new(data);
+            let mut offset = 6;
+            let stream_size = size.div_ceil(4);
+            for i in 0..3 {
+                let len = jump_table.u16()? as usize;
+                streams[i] = data
+                    .get(offset..offset + len)
+                    .ok_or(DecompressError::Corrupt)?;
+                sizes[i] = stream_size;
+                offset += len;
+            }
+            streams[3] = data.get(offset..).ok_or(DecompressError::Corrupt)?;
+            sizes[3] = size
+                .checked_sub(3 * stream_size)
+                .ok_or(DecompressError::Corrupt)?;
+        }
+
+        Ok(Literals {
+            source: LiteralSource::Huffman {
+                streams,
+                sizes,
+                stream: 0,
+                bits: None,
+                left: 0,
+            },
+            remaining: size,
+        })
+    }
+
+    fn next(&mut self, huffman: &HuffmanTable) -> Result<u8, DecompressError> {
+        if self.remaining == 0 {
+            return Err(DecompressError::Corrupt);
+        }
+        self.remaining -= 1;
+
+        match &mut self.source {
+            LiteralSource::Raw(data) => {
+                let (&byte, rest) = data.split_first().ok_or(DecompressError::Corrupt)?;
+                *data = rest;
+                Ok(byte)
+            }
+            LiteralSource::Rle(byte) => Ok(*byte),
+            LiteralSource::Huffman {
+                streams,
+                sizes,
+                stream,
+                bits,
+                left,
+            } => {
+                while *left == 0 {
+                    if bits.is_some() {
+                        *stream += 1;
+                    }
+                    *left = *sizes.get(*stream).ok_or(DecompressError::Corrupt)?;
+                    *bits = Some(BackwardBits::new(streams[*stream])?);
+                }
+                let stream_bits = bits.as_mut().ok_or(DecompressError::Corrupt)?;
+                let byte = huffman.decode(stream_bits);
+                *left -= 1;
+                if (*left == 0 && !stream_bits.is_finished()) || stream_bits.overflowed() {
+                    return Err(DecompressError::Corrupt);
+                }
+                Ok(byte)
+            }
+        }
+    }
+}
+
+/// Decoder state carried between the blocks of a frame
+struct Decoder {
+    huffman: HuffmanTable,
+    literal_length: FseTable,
+    offset: FseTable,
+    match_length: FseTable,
+    /// Scratch table for Huffman weights
+    weights: FseTable,
+    repeat_offsets: [usize; 3],
+}
+
+impl Decoder {
+    const fn new() -> Self {
+        Decoder {
+            huffman: HuffmanTable::new(),
+            literal_length: FseTable::new(),
+            offset: FseTable::new(),
+            match_length: FseTable::new(),
+            weights: FseTable::new(),
+            repeat_offsets: [1, 4, 8],
+        }
+    }
+
+    /// Resolve an offset value to a match distance, updating the repeat
+    /// offsets
+    fn resolve_offset(
+        &mut self,
+        value: u64,
+        literal_length: u32,
+    ) -> Result<usize, DecompressError> {
+        let reps = &mut self.repeat_offsets;
+        if value > 3 {
+            let offset = (value - 3) as usize;
+            *reps = [offset, reps[0], reps[1]];
+            return Ok(offset);
+        }
+
+        // Without literals, the repeat offsets shift by one
+        let index = value as usize - (literal_length != 0) as usize;
+        let offset = match index {
+            0 => return Ok(reps[0]),
+            1 => reps[1],
+            2 => reps[2],
+            _ => reps[0]
+                .checked_sub(1)
+                .filter(|&offset| offset != 0)
+                .ok_or(DecompressError::Corrupt)?,
+        };
+        if index != 1 {
+            reps[2] = reps[1];
+        }
+        reps[1] = reps[0];
+        reps[0] = offset;
+        Ok(offset)
+    }
+
+    /// Decode a compressed block
+    fn decode_block(&mut self, block: &[u8], sink: &mut impl Sink) -> Result<(), DecompressError> {
+        let mut input = Input::new(block);
+        let mut literals = Literals::read(&mut input, &mut self.huffman, &mut self.weights)?;
+
+        let first = input.u8()? as usize;
+        let sequences = match first {
+            0 => 0,
+            1..128 => first,
+            128..255 => ((first - 128) << 8) + input.u8()? as usize,
+            _ => input.u16()? as usize + 0x7F00,
+        };
+
+        if sequences > 0 {
+            let modes = input.u8()?;
+            if modes & 3 != 0 {
+                return Err(DecompressError::Corrupt);
+            }
+            self.literal_length.read_mode(
+                modes >> 6,
+                &mut input,
+                LITERAL_LENGTH,
+                (LITERAL_LENGTH_DEFAULT.0, &LITERAL_LENGTH_DEFAULT.1),
+            )?;
+            self.offset.read_mode(
+                (modes >> 4) & 3,
+                &mut input,
+                OFFSET,
+                (OFFSET_DEFAULT.0, &OFFSET_DEFAULT.1),
+            )?;
+            self.match_length.read_mode(
+                (modes >> 2) & 3,
+                &mut input,
+                MATCH_LENGTH,
+                (MATCH_LENGTH_DEFAULT.0, &MATCH_LENGTH_DEFAULT.1),
+            )?;
+            self.decode_sequences(&block[input.pos..], sequences, &mut literals, sink)?;
+        }
+
+        while literals.remaining > 0 {
+            sink.push(literals.next(&self.huffman)?)?;
+        }
+        Ok(())
+    }
+
+    /// Decode and execute the sequences of a block
+    fn decode_sequences(
+        &mut self,
+        data: &[u8],
+        count: usize,
+        literals: &mut Literals,
+        sink: &mut impl Sink,
+    ) -> Result<(), DecompressError> {
+        let mut bits = BackwardBits::new(data)?;
+        let mut literal_length_state = self.literal_length.init(&mut bits);
+        let mut offset_state = self.offset.init(&mut bits);
+        let mut match_length_state = self.match_length.init(&mut bits);
+
+        for i in 0..count {
+            let offset_code = self.offset.symbol(offset_state) as u32;
+            let (match_base, match_bits) =
+                MATCH_LENGTH_CODES[self.match_length.symbol(match_length_state) as usize];
+            let (literal_base, literal_bits) =
+                LITERAL_LENGTH_CODES[self.literal_length.symbol(literal_length_state) as usize];
+
+            let offset_value = (1u64 << offset_code) + bits.read(offset_code);
+            let match_length = match_base + bits.read(match_bits) as u32;
+            let literal_length = literal_base + bits.read(literal_bits) as u32;
+
+            if i + 1 < count {
+                literal_length_state = self.literal_length.update(literal_length_state, &mut bits);
+                match_length_state = self.match_length.update(match_length_state, &mut bits);
+                offset_state = self.offset.update(offset_state, &mut bits);
+            }
+            if bits.overflowed() {
+                return Err(DecompressError::Corrupt);
+            }
+
+            for _ in 0..literal_length {
+                sink.push(literals.next(&self.huffman)?)?;
+            }
+            let distance = self.resolve_offset(offset_value, literal_length)?;
+            sink.copy_match(distance, match_length as usize)?;
+        }
+
+        if !bits.is_finished() {
+            return Err(DecompressError::Corrupt);
+        }
+        Ok(())
+    }
+}
+
+/// Decode one frame after its magic number
+fn decode_frame(input: &mut Input, sink: &mut impl Sink) -> Result<(), DecompressError> {
+    let header = read_frame_header(input)?;
+    let start = sink.total();
+    let mut decoder = Decoder::new();
+
+    loop {
+        let block_header = input.uint(3)?;
+        let size = (block_header >> 3) as usize;
+        if size > MAX_BLOCK_SIZE {
+            return Err(DecompressError::Corrupt);
+        }
+        match (block_header >> 1) & 3 {
+            BLOCK_RAW => sink.extend(input.bytes(size)?)?,
+            BLOCK_RLE => {
+                let byte = input.u8()?;
+                for _ in 0..size {
+                    sink.push(byte)?;
+                }
+            }
+            BLOCK_COMPRESSED => decoder.decode_block(input.bytes(size)?, sink)?,
+            _ => return Err(DecompressError::Corrupt),
+        }
+        if block_header & 1 != 0 {
+            break;
+        }
+    }
+    if header.checksum {
+        input.u32()?;
+    }
+
+    match header.content_size {
+        Some(size) if size != sink.total() - start => Err(DecompressError::Corrupt),
+        _ => Ok(()),
+    }
+}
+
+/// Decode all frames in `input`
+pub(super) fn decode(input: &[u8], sink: &mut impl Sink) -> Result<(), DecompressError> {
+    let mut input = Input::new(input);
+    if input.u32()? != MAGIC {
+        return Err(DecompressError::Corrupt);
+    }
+    decode_frame(&mut input, sink)?;
+
+    // Frames may be followed by zero padding, as in btrfs extents
+    while !input.data[input.pos..].iter().all(|&byte| byte == 0) {
+        match input.u32()? {
+            MAGIC => decode_frame(&mut input, sink)?,
+            magic if magic & !0x0F == SKIPPABLE_MAGIC => {
+                let size = input.u32()?;
+                input.bytes(size as usize)?;
+            }
+            _ => return Err(DecompressError::Corrupt),
+        }
+    }
+    Ok(())
+}
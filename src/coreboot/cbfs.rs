@@ -0,0 +1,347 @@
+//! CBFS file lookup
+//!
+//! On x86 the boot flash is mapped just below 4 GiB, so files of the CBFS
+//! coreboot booted from are read straight from memory. Files cbfstool
+//! compressed with LZMA or LZ4 are decompressed into allocated pages the
+//! first time they are looked up and kept there.
+//!
+//! Reference: coreboot/src/commonlib/bsd/include/commonlib/bsd/cbfs_serialized.h
+
+use spin::Mutex;
+
+use super::tables::BootMediaInfo;
+use crate::compression::{self, Format};
+
+/// Magic at the start of every file header
+const FILE_MAGIC: &[u8; 8] = b"LARCHIVE";
+
+/// Size of the fixed part of a file header, in front of the name
+const FILE_HEADER_SIZE: usize = 24;
+
+/// Alignment of file headers
+const ALIGNMENT: usize = 64;
+
+/// File types of free space
+const TYPE_DELETED: u32 = 0x0000_0000;
+const TYPE_NULL: u32 = 0xffff_ffff;
+
+/// Attribute tags
+const ATTRIBUTE_UNUSED: u32 = 0x0000_0000;
+const ATTRIBUTE_UNUSED2: u32 = 0xffff_ffff;
+const ATTRIBUTE_COMPRESSION: u32 = 0x4243_5a4c;
+
+/// Compression algorithms
+const COMPRESS_NONE: u32 = 0;
+const COMPRESS_LZMA: u32 = 1;
+const COMPRESS_LZ4: u32 = 2;
+
+/// Largest boot media mapped below 4 GiB
+const MAX_MAPPED_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Most decompressed files kept
+const MAX_DECOMPRESSED: usize = 16;
+
+/// A CBFS
+#[derive(Clone, Copy)]
+pub struct Cbfs<'a> {
+    data: &'a [u8],
+}
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Compression attribute of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Compression {
+    algorithm: u32,
+    /// Decompressed size
+    size: u32,
+}
+
+/// A file found while walking a CBFS
+struct Entry<'a> {
+    name: &'a [u8],
+    data: &'a [u8],
+    compression: Option<Compression>,
+}
+
+impl<'a> Entry<'a> {
+    /// Contents of the file, decompressed if need be
+    fn contents(&self) -> Option<&'a [u8]> {
+        match self.compression {
+            None => Some(self.data),
+            Some(compression) => decompressed(self, compression),
+        }
+    }
+}
+
+/// Iterator over the files of a CBFS, skipping free space
+struct Entries<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Entry<'a>> {
+        while self.pos + FILE_HEADER_SIZE <= self.data.len() {
+            let header = &self.data[self.pos..];
+            if !header.starts_with(FILE_MAGIC) {
+                self.pos += ALIGNMENT;
+                continue;
+            }
+            let len = be32(header, 8)? as usize;
+            let file_type = be32(header, 12)?;
+            let attributes_offset = be32(header, 16)? as usize;
+            let offset = be32(header, 20)? as usize;
+            let metadata = header.get(FILE_HEADER_SIZE..offset)?;
+            let name_len = metadata.iter().position(|&b| b == 0)?;
+            self.pos = (self.pos + offset + len).next_multiple_of(ALIGNMENT);
+
+            if file_type == TYPE_DELETED || file_type == TYPE_NULL {
+                continue;
+            }
+            let compression = match attributes_offset {
+                0 => None,
+                _ => compression(header.get(attributes_offset..offset)?),
+            };
+            return Some(Entry {
+                name: &metadata[..name_len],
+                data: header.get(offset..offset.checked_add(len)?)?,
+                compression,
+            });
+        }
+        None
+    }
+}
+
+impl<'a> Cbfs<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Cbfs { data }
+    }
+
+    fn entries(&self) -> Entries<'a> {
+        Entries {
+            data: self.data,
+            pos: 0,
+        }
+    }
+
+    /// Contents of file `name`
+    pub fn find(&self, name: &str) -> Option<&'a [u8]> {
+        self.entries()
+            .find(|entry| entry.name == name.as_bytes())?
+            .contents()
+    }
+
+    /// Names and contents of the files whose name starts with `prefix`, in
+    /// CBFS order
+    ///
+    /// Files that can't be decompressed are skipped.
+    pub fn find_prefixed(self, prefix: &str) -> impl Iterator<Item = (&'a str, &'a [u8])> {
+        self.entries()
+            .filter(move |entry| entry.name.starts_with(prefix.as_bytes()))
+            .filter_map(|entry| Some((core::str::from_utf8(entry.name).ok()?, entry.contents()?)))
+    }
+}
+
+/// Compression algorithm file attributes declare, if any
+fn compression(mut attributes: &[u8]) -> Option<Compression> {
+    while let (Some(tag), Some(size)) = (be32(attributes, 0), be32(attributes, 4)) {
+        match tag {
+            ATTRIBUTE_UNUSED | ATTRIBUTE_UNUSED2 => break,
+            ATTRIBUTE_COMPRESSION => {
+                // A truncated attribute still marks the file compressed
+                let algorithm = be32(attributes, 8).unwrap_or(u32::MAX);
+                let size = be32(attributes, 12).unwrap_or(0);
+                return (algorithm != COMPRESS_NONE).then_some(Compression { algorithm, size });
+            }
+            _ => {}
+        }
+        let Some(rest) = attributes.get((size as usize).max(8)..) else {
+            break;
+        };
+        attributes = rest;
+    }
+    None
+}
+
+/// Decompressed files, by the address of their compressed data
+static DECOMPRESSED: Mutex<heapless::Vec<(usize, &'static [u8]), MAX_DECOMPRESSED>> =
+    Mutex::new(heapless::Vec::new());
+
+/// Decompress a file into allocated pages, or get the earlier copy
+fn decompressed(entry: &Entry, compression: Compression) -> Option<&'static [u8]> {
+    let name = core::str::from_utf8(entry.name).unwrap_or("?");
+    let key = entry.data.as_ptr() as usize;
+    if let Some(&(_, data)) = DECOMPRESSED
+        .lock()
+        .iter()
+        .find(|(address, _)| *address == key)
+    {
+        return Some(data);
+    }
+
+    let format = match compression.algorithm {
+        COMPRESS_LZMA => Format::Lzma,
+        COMPRESS_LZ4 => Format::Lz4,
+        other => {
+            log::warn!("CBFS: {} uses unknown compression {}", name, other);
+            return None;
+        }
+    };
+    let Some(buffer) = allocate(compression.size as usize) else {
+        log::warn!("CBFS: no memory to decompress {}", name);
+        return None;
+    };
+    let data = match compression::decompress(format, entry.data, buffer) {
+        Ok(len) => &buffer[..len],
+        Err(error) => {
+            log::warn!("CBFS: can't decompress {}: {:?}", name, error);
+            return None;
+        }
+    };
+    log::debug!("CBFS: decompressed {} to {} bytes", name, data.len());
+    if DECOMPRESSED.lock().push((key, data)).is_err() {
+        log::debug!("CBFS: not keeping the decompressed {}", name);
+    }
+    Some(data)
+}
+
+/// Allocate `size` bytes for a decompressed file
+#[cfg(not(feature = "std"))]
+fn allocate(size: usize) -> Option<&'static mut [u8]> {
+    let pages = size.div_ceil(crate::efi::allocator::PAGE_SIZE_USIZE).max(1);
+    crate::efi::allocate_pages(pages as u64)
+}
+
+/// Allocate `size` bytes for a decompressed file from the host heap
+#[cfg(feature = "std")]
+fn allocate(size: usize) -> Option<&'static mut [u8]> {
+    Some(std::vec![0u8; size].leak())
+}
+
+/// The CBFS coreboot booted from, if it is memory mapped
+static CBFS: Mutex<Option<Cbfs<'static>>> = Mutex::new(None);
+
+/// Use the CBFS on the memory-mapped boot media
+pub fn init(boot_media: &BootMediaInfo) {
+    let end = boot_media.cbfs_offset.saturating_add(boot_media.cbfs_size);
+    if boot_media.boot_media_size > MAX_MAPPED_SIZE || end > boot_media.boot_media_size {
+        log::warn!(
+            "CBFS: boot media of {} bytes is not memory mapped",
+            boot_media.boot_media_size
+        );
+        return;
+    }
+    let address = (1u64 << 32) - boot_media.boot_media_size + boot_media.cbfs_offset;
+    // Safety: the boot flash is mapped just below 4 GiB and stays there
+    let data =
+        unsafe { core::slice::from_raw_parts(address as *const u8, boot_media.cbfs_size as usize) };
+    log::info!(
+        "CBFS: {} KiB at {:#x}",
+        boot_media.cbfs_size / 1024,
+        address
+    );
+    *CBFS.lock() = Some(Cbfs::new(data));
+}
+
+/// Contents of file `name` in the CBFS coreboot booted from
+pub fn find_file(name: &str) -> Option<&'static [u8]> {
+    CBFS.lock().as_ref()?.find(name)
+}
+
+/// Names and contents of the files in the CBFS coreboot booted from whose
+/// name starts with `prefix`
+pub fn find_files(prefix: &str) -> impl Iterator<Item = (&'static str, &'static [u8])> {
+    let cbfs = *CBFS.lock();
+    cbfs.into_iter()
+        .flat_map(move |cbfs| cbfs.find_prefixed(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A CBFS file with big-endian header fields
+    fn file(name: &str, file_type: u32, attributes: &[u8], data: &[u8]) -> std::vec::Vec<u8> {
+        let metadata_len = (FILE_HEADER_SIZE + name.len() + 1).next_multiple_of(4);
+        let offset = metadata_len + attributes.len();
+        let mut file = std::vec![0u8; offset];
+        file[..8].copy_from_slice(FILE_MAGIC);
+        for (field, value) in [
+            data.len() as u32,
+            file_type,
+            if attributes.is_empty() {
+                0
+            } else {
+                metadata_len as u32
+            },
+            offset as u32,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            file[8 + field * 4..12 + field * 4].copy_from_slice(&value.to_be_bytes());
+        }
+        file[FILE_HEADER_SIZE..FILE_HEADER_SIZE + name.len()].copy_from_slice(name.as_bytes());
+        file[metadata_len..].copy_from_slice(attributes);
+        file.extend_from_slice(data);
+        file.resize(file.len().next_multiple_of(ALIGNMENT), 0xff);
+        file
+    }
+
+    #[test]
+    fn finds_files() {
+        let mut compression = std::vec::Vec::new();
+        for word in [ATTRIBUTE_COMPRESSION, 16, 1, 100] {
+            compression.extend_from_slice(&word.to_be_bytes());
+        }
+        let mut data = std::vec![0xffu8; ALIGNMENT];
+        data.extend(file("cbfs master header", 2, &[], &[0; 32]));
+        data.extend(file("old", TYPE_DELETED, &[], b"gone"));
+        data.extend(file("payload", 0x20, &compression, &[1; 100]));
+        data.extend(file("crabefi/hashes", 0x50, &[], b"hashes\n"));
+
+        // An LZ4 frame holding one uncompressed block
+        let mut lz4 = std::vec![0x04, 0x22, 0x4d, 0x18, 0x40, 0x40, 0xc0];
+        lz4.extend_from_slice(&(0x8000_0000u32 | 6).to_le_bytes());
+        lz4.extend_from_slice(b"logo!\n");
+        lz4.extend_from_slice(&[0; 4]);
+        let mut compression = std::vec::Vec::new();
+        for word in [ATTRIBUTE_COMPRESSION, 16, COMPRESS_LZ4, 6] {
+            compression.extend_from_slice(&word.to_be_bytes());
+        }
+        data.extend(file("crabefi/logo", 0x50, &compression, &lz4));
+        let cbfs = Cbfs::new(&data);
+
+        assert_eq!(cbfs.find("crabefi/hashes"), Some(&b"hashes\n"[..]));
+        assert_eq!(cbfs.find("old"), None);
+        assert_eq!(cbfs.find("payload"), None);
+        assert_eq!(cbfs.find("missing"), None);
+        let logo = cbfs.find("crabefi/logo");
+        assert_eq!(logo, Some(&b"logo!\n"[..]));
+        // Decompressed once
+        assert_eq!(
+            cbfs.find("crabefi/logo").map(<[u8]>::as_ptr),
+            logo.map(<[u8]>::as_ptr)
+        );
+        let prefixed: std::vec::Vec<_> = cbfs.find_prefixed("crabefi/").collect();
+        assert_eq!(
+            prefixed,
+            [
+                ("crabefi/hashes", &b"hashes\n"[..]),
+                ("crabefi/logo", &b"logo!\n"[..])
+            ]
+        );
+        assert_eq!(cbfs.find_prefixed("payload").count(), 0);
+        assert_eq!(
+            Cbfs::new(&data[..data.len() - 64]).find("crabefi/logo"),
+            None
+        );
+    }
+}
@@ -88,6 +88,30 @@ pub fn write_bytes(data: &[u8]) {
         return;
     }
 
+    // SAFETY: `init` checked the console
+    unsafe { write_ring(addr, data) }
+}
+
+/// Set up an empty ring in the CBMEM console format, `size` bytes with the
+/// header, at `addr`
+///
+/// # Safety
+///
+/// `addr` must point to `size` writable bytes nothing else uses.
+pub unsafe fn init_ring(addr: u64, size: u64) {
+    let header = CbmemConsoleHeader {
+        size: (size as usize - core::mem::size_of::<CbmemConsoleHeader>()) as u32,
+        cursor: 0,
+    };
+    unsafe { core::ptr::write_volatile(addr as *mut CbmemConsoleHeader, header) };
+}
+
+/// Write bytes to the ring in the CBMEM console format at `addr`
+///
+/// # Safety
+///
+/// `addr` must point to a ring set up by coreboot or [`init_ring`].
+pub unsafe fn write_ring(addr: u64, data: &[u8]) {
     unsafe {
         // For reading size, we can use zerocopy's Unaligned trait
         let header = &*(addr as *const CbmemConsoleHeader);
@@ -0,0 +1,619 @@
+//! CMOS option table
+//!
+//! Boards built with `CONFIG_USE_OPTION_TABLE` describe the settings they
+//! keep in CMOS RAM in `cmos.layout`, and coreboot passes the compiled table
+//! in the coreboot tables. An option is a range of CMOS bits holding a
+//! number or one of a list of named values, and a checksum covers the
+//! options coreboot reads.
+//!
+//! The recovery console shows and sets the options with `cmos`. CrabEFI's
+//! own settings can be enum options named like their [VPD key](super::vpd),
+//! so they also work on boards without VPD or SMMSTORE:
+//!
+//! ```text
+//! entries
+//! 400  8  e  10  crabefi_keyboard_layout
+//!
+//! enumerations
+//! 10  0  us
+//! 10  1  de
+//! ```
+//!
+//! The boot option and boot slot records CrabEFI keeps in bank 1 are
+//! checked against the table before use, see [`reserve`]. A record whose
+//! bytes an option uses is not kept. A reserved option named like the
+//! record moves it elsewhere:
+//!
+//! ```text
+//! entries
+//! 1280  640  r  0  crabefi_boot_option
+//! 1920  32   r  0  crabefi_boot_slots
+//! ```
+//!
+//! Reference: coreboot/src/commonlib/include/commonlib/coreboot_tables.h,
+//! coreboot/src/drivers/pc80/rtc/option.c
+
+use spin::Mutex;
+
+use crate::drivers::cmos;
+
+/// Tags of the records inside the option table
+const TAG_OPTION: u32 = 0xc9;
+const TAG_ENUM: u32 = 0xca;
+const TAG_CHECKSUM: u32 = 0xcc;
+
+/// Checksum type of the PC BIOS checksum, the only one coreboot uses
+const CHECKSUM_PCBIOS: u32 = 1;
+
+/// Size of the option table header: tag, size and header length
+const TABLE_HEADER_SIZE: usize = 12;
+
+/// Largest option table accepted, to bound a corrupt size
+const MAX_TABLE_SIZE: usize = 64 * 1024;
+
+/// Size of the name of an option
+const NAME_SIZE: usize = 32;
+
+/// CMOS bits of both banks
+const CMOS_BITS: u32 = 256 * 8;
+
+/// What an option holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionKind {
+    /// One of a list of named values
+    Enum,
+    /// A number
+    Hex,
+    /// Text
+    String,
+    /// Space used by coreboot itself
+    Reserved,
+}
+
+/// Why an option can't be read or written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmosOptionError {
+    /// coreboot didn't pass an option table
+    NotPresent,
+    /// No option has this name
+    UnknownOption,
+    /// Strings, reserved space and options over 64 bits
+    Unsupported,
+    /// The value doesn't fit or isn't in the option's list
+    InvalidValue,
+    /// CMOS doesn't match its checksum, e.g. after the battery ran out
+    BadChecksum,
+}
+
+/// An option of the table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CmosOption<'a> {
+    pub name: &'a str,
+    /// First CMOS bit
+    pub bit: u32,
+    /// Length in bits
+    pub length: u32,
+    pub kind: OptionKind,
+    /// List of the values of an enum option
+    config_id: u32,
+}
+
+/// Byte access to CMOS RAM
+pub trait Nvram {
+    fn read(&mut self, offset: u8) -> u8;
+    fn write(&mut self, offset: u8, value: u8);
+}
+
+/// The CMOS RAM of the RTC
+pub struct Cmos;
+
+impl Nvram for Cmos {
+    fn read(&mut self, offset: u8) -> u8 {
+        cmos::read(offset)
+    }
+
+    fn write(&mut self, offset: u8, value: u8) {
+        cmos::write(offset, value)
+    }
+}
+
+/// Little-endian u32 at `offset`
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Text up to the first NUL
+fn c_str(bytes: &[u8]) -> &str {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..end]).unwrap_or("")
+}
+
+/// Parse a number in decimal or, with `0x`, in hex
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Value of `length` bits starting at `bit`, least significant bit first
+fn read_bits(nvram: &mut dyn Nvram, bit: u32, length: u32) -> u64 {
+    (0..length).fold(0, |value, i| {
+        let bit = bit + i;
+        let byte = nvram.read((bit / 8) as u8);
+        value | (((byte >> (bit % 8)) & 1) as u64) << i
+    })
+}
+
+/// Store `value` in `length` bits starting at `bit`
+fn write_bits(nvram: &mut dyn Nvram, bit: u32, length: u32, value: u64) {
+    for i in 0..length {
+        let bit = bit + i;
+        let offset = (bit / 8) as u8;
+        let mask = 1 << (bit % 8);
+        let byte = nvram.read(offset);
+        let new = if (value >> i) & 1 != 0 {
+            byte | mask
+        } else {
+            byte & !mask
+        };
+        if new != byte {
+            nvram.write(offset, new);
+        }
+    }
+}
+
+/// The records of a CB_TAG_CMOS_OPTION_TABLE record
+#[derive(Clone, Copy)]
+pub struct OptionTable<'a> {
+    records: &'a [u8],
+}
+
+impl<'a> OptionTable<'a> {
+    /// Parse the option table record
+    pub fn parse(record: &'a [u8]) -> Option<Self> {
+        let size = u32_at(record, 4)? as usize;
+        let header_length = u32_at(record, 8)? as usize;
+        if header_length < TABLE_HEADER_SIZE {
+            return None;
+        }
+        let records = record.get(header_length..size)?;
+        Some(Self { records })
+    }
+
+    /// Tag and bytes of each record in the table
+    fn records(self) -> impl Iterator<Item = (u32, &'a [u8])> {
+        let mut rest = self.records;
+        core::iter::from_fn(move || {
+            let tag = u32_at(rest, 0)?;
+            let size = u32_at(rest, 4)? as usize;
+            if size < 8 || size > rest.len() {
+                return None;
+            }
+            let (record, next) = rest.split_at(size);
+            rest = next;
+            Some((tag, record))
+        })
+    }
+
+    /// The options, skipping any outside CMOS
+    pub fn options(self) -> impl Iterator<Item = CmosOption<'a>> {
+        self.records().filter_map(|(tag, record)| {
+            if tag != TAG_OPTION {
+                return None;
+            }
+            let bit = u32_at(record, 8)?;
+            let length = u32_at(record, 12)?;
+            let kind = match u8::try_from(u32_at(record, 16)?).ok()? {
+                b'e' => OptionKind::Enum,
+                b'h' => OptionKind::Hex,
+                b's' => OptionKind::String,
+                b'r' => OptionKind::Reserved,
+                _ => return None,
+            };
+            let name = record.get(24..)?;
+            if length == 0 || bit.checked_add(length)? > CMOS_BITS {
+                return None;
+            }
+            Some(CmosOption {
+                name: c_str(&name[..name.len().min(NAME_SIZE)]),
+                bit,
+                length,
+                kind,
+                config_id: u32_at(record, 20)?,
+            })
+        })
+    }
+
+    /// The option called `name`
+    pub fn option(self, name: &str) -> Option<CmosOption<'a>> {
+        self.options().find(|option| option.name == name)
+    }
+
+    /// The first option using any of the `size` CMOS bytes from `offset`
+    pub fn overlapping(self, offset: u8, size: usize) -> Option<CmosOption<'a>> {
+        let start = offset as u32 * 8;
+        let end = start + size as u32 * 8;
+        self.options()
+            .find(|option| option.bit < end && start < option.bit + option.length)
+    }
+
+    /// Where to keep `size` bytes of CrabEFI's own record called `name`
+    ///
+    /// A reserved option called `name` that starts on a byte and is long
+    /// enough moves the record there. Otherwise it stays at `default`,
+    /// unless an option uses those bytes, which is returned as the error.
+    pub fn place(self, name: &str, default: u8, size: usize) -> Result<u8, CmosOption<'a>> {
+        if let Some(option) = self.option(name)
+            && option.kind == OptionKind::Reserved
+            && option.bit % 8 == 0
+            && option.length >= size as u32 * 8
+        {
+            return Ok((option.bit / 8) as u8);
+        }
+        match self.overlapping(default, size) {
+            Some(option) => Err(option),
+            None => Ok(default),
+        }
+    }
+
+    /// Values of an enum option and their names
+    pub fn enum_values(self, option: &CmosOption) -> impl Iterator<Item = (u64, &'a str)> {
+        let config_id = option.config_id;
+        self.records().filter_map(move |(tag, record)| {
+            if tag != TAG_ENUM || u32_at(record, 8)? != config_id {
+                return None;
+            }
+            Some((u32_at(record, 12)? as u64, c_str(record.get(16..)?)))
+        })
+    }
+
+    /// Name of `value` of an enum option
+    pub fn enum_text(self, option: &CmosOption, value: u64) -> Option<&'a str> {
+        self.enum_values(option)
+            .find_map(|(v, text)| (v == value).then_some(text))
+    }
+
+    /// Bytes covered by the checksum and the offset it is stored at
+    fn checksum_range(self) -> Option<(u8, u8, u8)> {
+        let (_, record) = self.records().find(|(tag, _)| *tag == TAG_CHECKSUM)?;
+        if u32_at(record, 20)? != CHECKSUM_PCBIOS {
+            return None;
+        }
+        let byte = |offset| u8::try_from(u32_at(record, offset)? / 8).ok();
+        Some((byte(8)?, byte(12)?, byte(16)?))
+    }
+
+    /// Sum of the bytes covered by the checksum and its offset
+    fn checksum(self, nvram: &mut dyn Nvram) -> Option<(u16, u8)> {
+        let (start, end, location) = self.checksum_range()?;
+        let sum = (start..=end).fold(0u16, |sum, offset| {
+            sum.wrapping_add(nvram.read(offset) as u16)
+        });
+        Some((sum, location))
+    }
+
+    /// Whether CMOS matches the checksum, true if the table has none
+    pub fn checksum_valid(self, nvram: &mut dyn Nvram) -> bool {
+        self.checksum(nvram).is_none_or(|(sum, location)| {
+            let stored =
+                u16::from_be_bytes([nvram.read(location), nvram.read(location.wrapping_add(1))]);
+            sum == stored
+        })
+    }
+
+    /// Check that `option` holds a number this module can access
+    fn check(self, nvram: &mut dyn Nvram, option: &CmosOption) -> Result<(), CmosOptionError> {
+        if matches!(option.kind, OptionKind::String | OptionKind::Reserved) || option.length > 64 {
+            return Err(CmosOptionError::Unsupported);
+        }
+        if !self.checksum_valid(nvram) {
+            return Err(CmosOptionError::BadChecksum);
+        }
+        Ok(())
+    }
+
+    /// Value of `option`
+    pub fn read(self, nvram: &mut dyn Nvram, option: &CmosOption) -> Result<u64, CmosOptionError> {
+        self.check(nvram, option)?;
+        Ok(read_bits(nvram, option.bit, option.length))
+    }
+
+    /// Set `option` to `value` and update the checksum
+    pub fn write(
+        self,
+        nvram: &mut dyn Nvram,
+        option: &CmosOption,
+        value: u64,
+    ) -> Result<(), CmosOptionError> {
+        self.check(nvram, option)?;
+        let fits = option.length == 64 || value >> option.length == 0;
+        let named = option.kind != OptionKind::Enum || self.enum_text(option, value).is_some();
+        if !fits || !named {
+            return Err(CmosOptionError::InvalidValue);
+        }
+        write_bits(nvram, option.bit, option.length, value);
+        if let Some((sum, location)) = self.checksum(nvram) {
+            let [high, low] = sum.to_be_bytes();
+            nvram.write(location, high);
+            nvram.write(location.wrapping_add(1), low);
+        }
+        Ok(())
+    }
+
+    /// Value of `option` for `text`: the name of an enum value or a number
+    pub fn parse_value(self, option: &CmosOption, text: &str) -> Result<u64, CmosOptionError> {
+        match option.kind {
+            OptionKind::Enum => self
+                .enum_values(option)
+                .find_map(|(value, name)| (name == text).then_some(value)),
+            _ => parse_number(text),
+        }
+        .ok_or(CmosOptionError::InvalidValue)
+    }
+}
+
+/// Option table of this board, if coreboot provided it
+static TABLE: Mutex<Option<OptionTable<'static>>> = Mutex::new(None);
+
+/// Use the option table record coreboot left at `address`
+pub fn init(address: u64) {
+    let header = unsafe { core::slice::from_raw_parts(address as *const u8, TABLE_HEADER_SIZE) };
+    let size = u32_at(header, 4).map_or(0, |size| (size as usize).min(MAX_TABLE_SIZE));
+    let record = unsafe { core::slice::from_raw_parts(address as *const u8, size) };
+    match OptionTable::parse(record) {
+        Some(table) => {
+            log::info!("CMOS options: {} options", table.options().count());
+            if !table.checksum_valid(&mut Cmos) {
+                log::warn!("CMOS options: checksum mismatch, options ignored");
+            }
+            *TABLE.lock() = Some(table);
+        }
+        None => log::warn!("CMOS option table at {:#x} is invalid", address),
+    }
+}
+
+/// The option table, if coreboot provided one
+pub fn table() -> Option<OptionTable<'static>> {
+    *TABLE.lock()
+}
+
+/// CMOS offset of CrabEFI's own record called `name`, see [`OptionTable::place`]
+///
+/// Without an option table the record is kept at `default`. Returns `None`
+/// if an option uses its bytes.
+pub fn reserve(name: &str, default: u8, size: usize) -> Option<u8> {
+    let Some(table) = table() else {
+        return Some(default);
+    };
+    match table.place(name, default, size) {
+        Ok(offset) => Some(offset),
+        Err(option) => {
+            log::warn!(
+                "CMOS options: {} at {:#x} overlaps option {}, not used",
+                name,
+                default,
+                option.name
+            );
+            None
+        }
+    }
+}
+
+/// Look up the option called `name`
+fn lookup(name: &str) -> Result<(OptionTable<'static>, CmosOption<'static>), CmosOptionError> {
+    let table = table().ok_or(CmosOptionError::NotPresent)?;
+    let option = table.option(name).ok_or(CmosOptionError::UnknownOption)?;
+    Ok((table, option))
+}
+
+/// Value of the option called `name`
+pub fn get(name: &str) -> Result<u64, CmosOptionError> {
+    let (table, option) = lookup(name)?;
+    table.read(&mut Cmos, &option)
+}
+
+/// Set the option called `name` to `value`
+pub fn set(name: &str, value: u64) -> Result<(), CmosOptionError> {
+    let (table, option) = lookup(name)?;
+    table.write(&mut Cmos, &option, value)
+}
+
+/// Set the option called `name` from text, see [`OptionTable::parse_value`]
+pub fn set_str(name: &str, text: &str) -> Result<(), CmosOptionError> {
+    let (table, option) = lookup(name)?;
+    let value = table.parse_value(&option, text)?;
+    table.write(&mut Cmos, &option, value)
+}
+
+/// Name of the value of the enum option called `name`
+pub fn find_str(name: &str) -> Option<&'static str> {
+    let (table, option) = lookup(name).ok()?;
+    if option.kind != OptionKind::Enum {
+        return None;
+    }
+    let value = table.read(&mut Cmos, &option).ok()?;
+    table.enum_text(&option, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Nvram for [u8; 256] {
+        fn read(&mut self, offset: u8) -> u8 {
+            self[offset as usize]
+        }
+
+        fn write(&mut self, offset: u8, value: u8) {
+            self[offset as usize] = value;
+        }
+    }
+
+    fn push_record(table: &mut std::vec::Vec<u8>, tag: u32, fields: &[u32], text: &str) {
+        let start = table.len();
+        table.extend_from_slice(&tag.to_le_bytes());
+        table.extend_from_slice(&[0; 4]);
+        fields
+            .iter()
+            .for_each(|field| table.extend_from_slice(&field.to_le_bytes()));
+        table.extend_from_slice(text.as_bytes());
+        table.push(0);
+        table.resize(table.len().next_multiple_of(4), 0);
+        let size = (table.len() - start) as u32;
+        table[start + 4..start + 8].copy_from_slice(&size.to_le_bytes());
+    }
+
+    /// A table like a cmos.layout with a checksum over bytes 0x32-0x3d
+    fn option_table() -> std::vec::Vec<u8> {
+        let mut table = std::vec![0u8; TABLE_HEADER_SIZE];
+        table[..4].copy_from_slice(&0xc8u32.to_le_bytes());
+        table[8..12].copy_from_slice(&(TABLE_HEADER_SIZE as u32).to_le_bytes());
+        push_record(
+            &mut table,
+            TAG_OPTION,
+            &[384, 1, b'e' as u32, 1],
+            "power_on",
+        );
+        push_record(
+            &mut table,
+            TAG_OPTION,
+            &[388, 4, b'h' as u32, 0],
+            "debug_level",
+        );
+        push_record(
+            &mut table,
+            TAG_OPTION,
+            &[400, 8, b'e' as u32, 2],
+            "crabefi_keyboard_layout",
+        );
+        push_record(&mut table, TAG_OPTION, &[0, 112, b'r' as u32, 0], "rtc");
+        push_record(
+            &mut table,
+            TAG_OPTION,
+            &[1280, 64, b'r' as u32, 0],
+            "crabefi_boot_slots",
+        );
+        push_record(&mut table, TAG_ENUM, &[1, 0], "Disable");
+        push_record(&mut table, TAG_ENUM, &[1, 1], "Enable");
+        push_record(&mut table, TAG_ENUM, &[2, 0], "us");
+        push_record(&mut table, TAG_ENUM, &[2, 1], "de");
+        push_record(
+            &mut table,
+            TAG_CHECKSUM,
+            &[400, 495, 496, CHECKSUM_PCBIOS],
+            "",
+        );
+        let size = table.len() as u32;
+        table[4..8].copy_from_slice(&size.to_le_bytes());
+        table
+    }
+
+    #[test]
+    fn parses_options() {
+        let bytes = option_table();
+        let table = OptionTable::parse(&bytes).unwrap();
+        let names: std::vec::Vec<_> = table.options().map(|option| option.name).collect();
+        assert_eq!(
+            names,
+            [
+                "power_on",
+                "debug_level",
+                "crabefi_keyboard_layout",
+                "rtc",
+                "crabefi_boot_slots"
+            ]
+        );
+
+        let debug = table.option("debug_level").unwrap();
+        assert_eq!(
+            (debug.bit, debug.length, debug.kind),
+            (388, 4, OptionKind::Hex)
+        );
+        let layout = table.option("crabefi_keyboard_layout").unwrap();
+        assert_eq!(table.enum_text(&layout, 1), Some("de"));
+        assert_eq!(table.enum_text(&layout, 2), None);
+        assert_eq!(table.parse_value(&layout, "us"), Ok(0));
+        assert_eq!(table.parse_value(&debug, "0xa"), Ok(10));
+        assert!(table.option("missing").is_none());
+    }
+
+    #[test]
+    fn reads_and_writes_bits() {
+        let bytes = option_table();
+        let table = OptionTable::parse(&bytes).unwrap();
+        let mut nvram = [0u8; 256];
+        let power_on = table.option("power_on").unwrap();
+        let debug = table.option("debug_level").unwrap();
+        let layout = table.option("crabefi_keyboard_layout").unwrap();
+
+        // Options sharing byte 0x30 keep each other's bits
+        nvram[0x30] = 0x50;
+        assert_eq!(table.read(&mut nvram, &debug), Ok(5));
+        assert_eq!(table.write(&mut nvram, &power_on, 1), Ok(()));
+        assert_eq!(table.write(&mut nvram, &debug, 0xc), Ok(()));
+        assert_eq!(nvram[0x30], 0xc1);
+
+        assert_eq!(
+            table.write(&mut nvram, &debug, 0x10),
+            Err(CmosOptionError::InvalidValue)
+        );
+        assert_eq!(
+            table.write(&mut nvram, &layout, 2),
+            Err(CmosOptionError::InvalidValue)
+        );
+        let rtc = table.option("rtc").unwrap();
+        assert_eq!(
+            table.read(&mut nvram, &rtc),
+            Err(CmosOptionError::Unsupported)
+        );
+    }
+
+    #[test]
+    fn places_records() {
+        let bytes = option_table();
+        let table = OptionTable::parse(&bytes).unwrap();
+
+        assert_eq!(
+            table.overlapping(0x32, 1).unwrap().name,
+            "crabefi_keyboard_layout"
+        );
+        assert_eq!(table.overlapping(0x0c, 4).unwrap().name, "rtc");
+        assert!(table.overlapping(0x33, 8).is_none());
+
+        // A reserved option moves the record, otherwise it must be clear
+        assert_eq!(table.place("crabefi_boot_slots", 0xf0, 4), Ok(0xa0));
+        assert_eq!(table.place("crabefi_boot_slots", 0xf0, 9), Ok(0xf0));
+        assert_eq!(table.place("crabefi_boot_option", 0xf0, 4), Ok(0xf0));
+        assert_eq!(
+            table
+                .place("crabefi_boot_option", 0x9c, 8)
+                .unwrap_err()
+                .name,
+            "crabefi_boot_slots"
+        );
+    }
+
+    #[test]
+    fn keeps_checksum() {
+        let bytes = option_table();
+        let table = OptionTable::parse(&bytes).unwrap();
+        let mut nvram = [0u8; 256];
+        let layout = table.option("crabefi_keyboard_layout").unwrap();
+
+        assert!(table.checksum_valid(&mut nvram));
+        assert_eq!(table.write(&mut nvram, &layout, 1), Ok(()));
+        assert_eq!(nvram[0x32], 1);
+        assert_eq!(nvram[0x3e..0x40], [0, 1]);
+        assert_eq!(table.read(&mut nvram, &layout), Ok(1));
+
+        // A lost battery resets CMOS
+        nvram[0x32] = 0xff;
+        assert_eq!(
+            table.read(&mut nvram, &layout),
+            Err(CmosOptionError::BadChecksum)
+        );
+    }
+}
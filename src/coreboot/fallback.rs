@@ -0,0 +1,592 @@
+//! Degraded mode without coreboot tables
+//!
+//! When CrabEFI is started by another loader, or the tables can't be found,
+//! [`tables::parse`](super::tables::parse) leaves the memory map and serial
+//! port empty. [`apply`] fills them in with enough to get to the storage
+//! stack:
+//!
+//! - Memory map: the one the loader passed, see [`LoaderInfo`], else the RAM
+//!   sizes QEMU keeps in CMOS, else a conservative 512 MiB PC layout
+//! - Serial: COM1 at 115200 baud, which is only used if the UART answers
+//! - ACPI: the RSDP the loader passed, or the one in the EBDA or the BIOS
+//!   area
+//!
+//! The entry code has a Multiboot and a Multiboot2 header, so QEMU's
+//! `-kernel` and GRUB's `multiboot` and `multiboot2` commands can start the
+//! payload ELF. `scripts/make-bzimage.py` wraps it into a bzImage for
+//! GRUB's `linux` command, which uses the 32-bit Linux boot protocol.
+//!
+//! References: Multiboot Specification version 0.6.96, Multiboot2
+//! Specification version 2.0, Linux `Documentation/arch/x86/boot.rst`
+
+use zerocopy::{FromBytes, Immutable, KnownLayout, Unaligned};
+
+use super::memory::{MemoryRegion, MemoryType};
+use super::tables::{CorebootInfo, SerialInfo};
+use crate::drivers::cmos;
+
+/// EAX at the Multiboot entry
+pub const MULTIBOOT_MAGIC: u32 = 0x2BAD_B002;
+/// EAX at the Multiboot2 entry
+pub const MULTIBOOT2_MAGIC: u32 = 0x36D7_6289;
+/// Set by the Linux boot protocol entry, the "HdrS" of the setup header
+pub const LINUX_MAGIC: u32 = 0x5372_6448;
+
+/// Multiboot information flag: `mem_lower` and `mem_upper` are valid
+const MB_INFO_MEMORY: u32 = 1 << 0;
+/// Multiboot information flag: `mmap_length` and `mmap_addr` are valid
+const MB_INFO_MMAP: u32 = 1 << 6;
+
+/// Multiboot2 information tags
+const MB2_TAG_END: u32 = 0;
+const MB2_TAG_BASIC_MEMINFO: u32 = 4;
+const MB2_TAG_MMAP: u32 = 6;
+
+/// Linux boot parameters ("zero page") size and fields
+const BOOT_PARAMS_SIZE: usize = 4096;
+const BP_ACPI_RSDP_ADDR: usize = 0x070;
+const BP_ALT_MEM_K: usize = 0x1e0;
+const BP_E820_ENTRIES: usize = 0x1e8;
+const BP_E820_TABLE: usize = 0x2d0;
+/// Size of an E820 entry in the boot parameters
+const E820_ENTRY_SIZE: usize = 20;
+
+/// Start of the conventional memory taken as RAM, the first page is left out
+/// so a null pointer is never handed out
+const LOW_RAM_START: u64 = 0x1000;
+/// End of the conventional memory taken as RAM, below the usual EBDA
+const LOW_RAM_END: u64 = 0x9F000;
+/// Start of the extended memory
+const HIGH_RAM_START: u64 = 0x10_0000;
+/// Memory above 4 GiB
+const FOUR_GIB: u64 = 0x1_0000_0000;
+
+/// Left out of the RAM QEMU reports in CMOS: coreboot keeps CBMEM and its
+/// own stages right below the top of low RAM
+const CBMEM_RESERVE: u64 = 16 * 1024 * 1024;
+
+/// Information structure passed by a loader other than coreboot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoaderInfo {
+    /// Multiboot information
+    Multiboot(u64),
+    /// Multiboot2 boot information
+    Multiboot2(u64),
+    /// Linux boot parameters
+    Linux(u64),
+}
+
+impl LoaderInfo {
+    /// The structure at `address`, from the magic the entry code passes
+    pub fn new(magic: u32, address: u64) -> Option<Self> {
+        if address == 0 {
+            return None;
+        }
+        match magic {
+            MULTIBOOT_MAGIC => Some(LoaderInfo::Multiboot(address)),
+            MULTIBOOT2_MAGIC => Some(LoaderInfo::Multiboot2(address)),
+            LINUX_MAGIC => Some(LoaderInfo::Linux(address)),
+            _ => None,
+        }
+    }
+}
+
+/// Start of the Multiboot information structure, up to the memory map
+#[repr(C)]
+#[derive(FromBytes, Immutable, KnownLayout)]
+struct MultibootInfo {
+    flags: u32,
+    mem_lower: u32,
+    mem_upper: u32,
+    boot_device: u32,
+    cmdline: u32,
+    mods_count: u32,
+    mods_addr: u32,
+    syms: [u32; 4],
+    mmap_length: u32,
+    mmap_addr: u32,
+}
+
+/// Multiboot memory map entry
+///
+/// `size` doesn't count itself, the next entry starts `size + 4` bytes on.
+#[repr(C, packed)]
+#[derive(FromBytes, Immutable, KnownLayout, Unaligned)]
+struct MultibootMmapEntry {
+    size: u32,
+    base_addr: u64,
+    length: u64,
+    entry_type: u32,
+}
+
+/// E820 entry, as in the Linux boot parameters and the Multiboot2 memory map
+#[repr(C, packed)]
+#[derive(FromBytes, Immutable, KnownLayout, Unaligned)]
+struct E820Entry {
+    addr: u64,
+    size: u64,
+    entry_type: u32,
+}
+
+/// Fill in what `info` lacks when there are no usable coreboot tables
+///
+/// `loader` is the information structure of the loader that started
+/// CrabEFI, if it wasn't coreboot.
+pub fn apply(info: &mut CorebootInfo, loader: Option<LoaderInfo>) {
+    if info.memory_map.is_empty() {
+        build_memory_map(info, loader);
+    }
+
+    // With coreboot tables, no serial or RSDP record means there is none
+    if info.table_header.is_some() {
+        return;
+    }
+
+    if info.serial.is_none() {
+        log::info!("No coreboot tables, trying COM1");
+        info.serial = Some(SerialInfo {
+            serial_type: 1, // IO port
+            baseaddr: 0x3f8,
+            baud: 115200,
+            regwidth: 1,
+            input_hertz: 1843200,
+        });
+    }
+
+    if info.acpi_rsdp.is_none() {
+        info.acpi_rsdp = find_rsdp();
+        if let Some(rsdp) = info.acpi_rsdp {
+            log::info!("Found ACPI RSDP at {:#x}", rsdp);
+        }
+    }
+}
+
+/// Build the memory map from the best source there is
+fn build_memory_map(info: &mut CorebootInfo, loader: Option<LoaderInfo>) {
+    // SAFETY: the loader passed the address of its information structure
+    match loader {
+        Some(LoaderInfo::Multiboot(address)) => unsafe { add_multiboot_memory(address, info) },
+        Some(LoaderInfo::Multiboot2(address)) => unsafe {
+            let total_size = core::ptr::read_unaligned(address as usize as *const u32);
+            let bytes =
+                core::slice::from_raw_parts(address as usize as *const u8, total_size as usize);
+            add_multiboot2_info(bytes, info);
+        },
+        Some(LoaderInfo::Linux(address)) => unsafe {
+            let bytes =
+                core::slice::from_raw_parts(address as usize as *const u8, BOOT_PARAMS_SIZE);
+            add_boot_params(bytes, info);
+        },
+        None => {}
+    }
+    if !info.memory_map.is_empty() {
+        log::info!(
+            "Memory map from {:?}: {} regions",
+            loader,
+            info.memory_map.len()
+        );
+        return;
+    }
+
+    if let Some(low_top) = qemu_low_ram_top() {
+        // Above 4 GiB in 64 KiB blocks
+        let high_blocks = (cmos::read(0x5d) as u64) << 16
+            | (cmos::read(0x5c) as u64) << 8
+            | cmos::read(0x5b) as u64;
+        add_ram_layout(low_top - CBMEM_RESERVE, high_blocks * 64 * 1024, info);
+        let _ = info.memory_map.push(MemoryRegion {
+            start: low_top - CBMEM_RESERVE,
+            size: CBMEM_RESERVE,
+            region_type: MemoryType::Reserved,
+        });
+        log::info!("Memory map from QEMU CMOS: RAM up to {:#x}", low_top);
+        return;
+    }
+
+    // Standard PC layout with at least 512 MiB, as QEMU is usually run
+    add_ram_layout(0x2000_0000, 0, info);
+    log::warn!("No memory information, assuming 512 MiB of RAM");
+}
+
+/// Add the memory from the Multiboot information at `address`
+///
+/// # Safety
+///
+/// `address` must point to a Multiboot information structure.
+unsafe fn add_multiboot_memory(address: u64, info: &mut CorebootInfo) {
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            address as usize as *const u8,
+            core::mem::size_of::<MultibootInfo>(),
+        )
+    };
+    let Ok((mb, _)) = MultibootInfo::read_from_prefix(bytes) else {
+        return;
+    };
+
+    if mb.flags & MB_INFO_MMAP != 0 {
+        // SAFETY: the flag says the loader put a memory map there
+        let mmap = unsafe {
+            core::slice::from_raw_parts(mb.mmap_addr as usize as *const u8, mb.mmap_length as usize)
+        };
+        add_multiboot_mmap(mmap, info);
+    }
+    if info.memory_map.is_empty() && mb.flags & MB_INFO_MEMORY != 0 {
+        add_ram_layout(HIGH_RAM_START + mb.mem_upper as u64 * 1024, 0, info);
+    }
+}
+
+/// Add the regions of a Multiboot memory map
+fn add_multiboot_mmap(mmap: &[u8], info: &mut CorebootInfo) {
+    let mut remaining = mmap;
+    while let Ok((entry, _)) = MultibootMmapEntry::read_from_prefix(remaining) {
+        if !add_e820_region(entry.base_addr, entry.length, entry.entry_type, info) {
+            break;
+        }
+        let Some(next) = remaining.get(entry.size as usize + 4..) else {
+            break;
+        };
+        remaining = next;
+    }
+}
+
+/// Add the memory map in a Multiboot2 boot information structure, or else
+/// its memory size
+fn add_multiboot2_info(bytes: &[u8], info: &mut CorebootInfo) {
+    let read_u32 = |offset: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(
+            bytes.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+
+    let mut mem_upper = None;
+    // Tags follow the total size and a reserved field, each 8-byte aligned
+    let mut offset = 8;
+    while let (Some(tag_type), Some(size)) = (read_u32(offset), read_u32(offset + 4)) {
+        let size = size as usize;
+        let Some(tag) = bytes.get(offset..offset + size) else {
+            break;
+        };
+        match tag_type {
+            MB2_TAG_END => break,
+            MB2_TAG_BASIC_MEMINFO => mem_upper = read_u32(offset + 12),
+            MB2_TAG_MMAP => {
+                let entry_size = read_u32(offset + 8).unwrap_or(0) as usize;
+                if entry_size >= core::mem::size_of::<E820Entry>() {
+                    for entry in tag[16..].chunks_exact(entry_size) {
+                        let Ok((entry, _)) = E820Entry::read_from_prefix(entry) else {
+                            break;
+                        };
+                        if !add_e820_region(entry.addr, entry.size, entry.entry_type, info) {
+                            break;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        if size < 8 {
+            break;
+        }
+        offset += size.next_multiple_of(8);
+    }
+
+    if info.memory_map.is_empty()
+        && let Some(mem_upper) = mem_upper
+    {
+        add_ram_layout(HIGH_RAM_START + mem_upper as u64 * 1024, 0, info);
+    }
+}
+
+/// Add the E820 table in Linux boot parameters, or else their memory size,
+/// and take their RSDP address
+fn add_boot_params(bytes: &[u8], info: &mut CorebootInfo) {
+    if let Some(rsdp) = bytes.get(BP_ACPI_RSDP_ADDR..BP_ACPI_RSDP_ADDR + 8)
+        && let Ok(rsdp) = rsdp.try_into()
+        && u64::from_le_bytes(rsdp) != 0
+    {
+        info.acpi_rsdp = Some(u64::from_le_bytes(rsdp));
+    }
+
+    let entries = bytes.get(BP_E820_ENTRIES).copied().unwrap_or(0) as usize;
+    let (table, _) = bytes
+        .get(BP_E820_TABLE..)
+        .unwrap_or(&[])
+        .as_chunks::<E820_ENTRY_SIZE>();
+    for entry in table.iter().take(entries) {
+        let Ok(entry) = E820Entry::read_from_bytes(entry) else {
+            break;
+        };
+        if !add_e820_region(entry.addr, entry.size, entry.entry_type, info) {
+            break;
+        }
+    }
+
+    if info.memory_map.is_empty()
+        && let Some(alt_mem_k) = bytes.get(BP_ALT_MEM_K..BP_ALT_MEM_K + 4)
+        && let Ok(alt_mem_k) = alt_mem_k.try_into()
+    {
+        let alt_mem_k = u32::from_le_bytes(alt_mem_k) as u64;
+        if alt_mem_k != 0 {
+            add_ram_layout(HIGH_RAM_START + alt_mem_k * 1024, 0, info);
+        }
+    }
+}
+
+/// Add an E820 region, returns false once the memory map is full
+fn add_e820_region(mut start: u64, mut size: u64, e820_type: u32, info: &mut CorebootInfo) -> bool {
+    let region_type = match e820_type {
+        1 => MemoryType::Ram,
+        3 => MemoryType::AcpiReclaimable,
+        4 => MemoryType::AcpiNvs,
+        5 => MemoryType::Unusable,
+        7 => MemoryType::Persistent,
+        _ => MemoryType::Reserved,
+    };
+
+    // Keep the first page out of RAM
+    if region_type == MemoryType::Ram && start < LOW_RAM_START {
+        size = size.saturating_sub(LOW_RAM_START - start);
+        start = LOW_RAM_START;
+    }
+    if size != 0
+        && info
+            .memory_map
+            .push(MemoryRegion {
+                start,
+                size,
+                region_type,
+            })
+            .is_err()
+    {
+        log::warn!("Memory map full, ignoring remaining regions");
+        return false;
+    }
+    true
+}
+
+/// Add conventional memory, extended memory up to `low_top` and `high_size`
+/// bytes above 4 GiB as RAM
+fn add_ram_layout(low_top: u64, high_size: u64, info: &mut CorebootInfo) {
+    let _ = info.memory_map.push(MemoryRegion {
+        start: LOW_RAM_START,
+        size: LOW_RAM_END - LOW_RAM_START,
+        region_type: MemoryType::Ram,
+    });
+    if low_top > HIGH_RAM_START {
+        let _ = info.memory_map.push(MemoryRegion {
+            start: HIGH_RAM_START,
+            size: low_top - HIGH_RAM_START,
+            region_type: MemoryType::Ram,
+        });
+    }
+    if high_size != 0 {
+        let _ = info.memory_map.push(MemoryRegion {
+            start: FOUR_GIB,
+            size: high_size,
+            region_type: MemoryType::Ram,
+        });
+    }
+}
+
+/// Top of the RAM below 4 GiB as QEMU reports it in CMOS
+pub(super) fn qemu_low_ram_top() -> Option<u64> {
+    // Above 16 MiB in 64 KiB blocks
+    let blocks = ((cmos::read(0x35) as u64) << 8) | cmos::read(0x34) as u64;
+    let top = 16 * 1024 * 1024 + blocks * 64 * 1024;
+    (blocks != 0 && top <= 0xF000_0000).then_some(top)
+}
+
+/// Find the ACPI RSDP in the first KiB of the EBDA or in the BIOS area
+fn find_rsdp() -> Option<u64> {
+    // SAFETY: the BIOS data area and the legacy ROM area are identity mapped
+    unsafe {
+        let ebda = (core::ptr::read_volatile(0x40E as *const u16) as u64) << 4;
+        let areas = [(ebda, 0x400), (0xE0000, 0x20000)];
+        areas.into_iter().find_map(|(start, size)| {
+            if start == 0 {
+                return None;
+            }
+            let area = core::slice::from_raw_parts(start as usize as *const u8, size);
+            find_rsdp_in(area).map(|offset| start + offset as u64)
+        })
+    }
+}
+
+/// Offset of a valid RSDP in `area`, which is searched on 16-byte boundaries
+fn find_rsdp_in(area: &[u8]) -> Option<usize> {
+    (0..area.len().saturating_sub(19))
+        .step_by(16)
+        .find(|&offset| {
+            let rsdp = &area[offset..offset + 20];
+            rsdp.starts_with(b"RSD PTR ")
+                && rsdp.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mmap_entry(base: u64, length: u64, entry_type: u32) -> std::vec::Vec<u8> {
+        let mut entry = std::vec::Vec::new();
+        entry.extend_from_slice(&20u32.to_le_bytes());
+        entry.extend_from_slice(&base.to_le_bytes());
+        entry.extend_from_slice(&length.to_le_bytes());
+        entry.extend_from_slice(&entry_type.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn multiboot_memory_map() {
+        let mut mmap = std::vec::Vec::new();
+        mmap.extend(mmap_entry(0, 0x9fc00, 1));
+        mmap.extend(mmap_entry(0x9fc00, 0x400, 2));
+        mmap.extend(mmap_entry(0x100000, 0x7ee0000, 1));
+        mmap.extend(mmap_entry(0x7fe0000, 0x20000, 3));
+        mmap.extend(mmap_entry(0xfffc0000, 0x40000, 9));
+        // Cut short: ignored
+        mmap.extend_from_slice(&20u32.to_le_bytes());
+
+        let mut info = CorebootInfo::new();
+        add_multiboot_mmap(&mmap, &mut info);
+
+        assert_eq!(
+            regions(&info),
+            [
+                (0x1000, 0x9ec00, MemoryType::Ram),
+                (0x9fc00, 0x400, MemoryType::Reserved),
+                (0x100000, 0x7ee0000, MemoryType::Ram),
+                (0x7fe0000, 0x20000, MemoryType::AcpiReclaimable),
+                (0xfffc0000, 0x40000, MemoryType::Reserved),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_rsdp() {
+        let mut area = std::vec![0u8; 0x100];
+        // Bad checksum first
+        area[0x20..0x28].copy_from_slice(b"RSD PTR ");
+        area[0x40..0x48].copy_from_slice(b"RSD PTR ");
+        area[0x4f] = 2;
+        let sum = area[0x40..0x54]
+            .iter()
+            .fold(0u8, |sum, &b| sum.wrapping_add(b));
+        area[0x48] = sum.wrapping_neg();
+        assert_eq!(find_rsdp_in(&area), Some(0x40));
+        assert_eq!(find_rsdp_in(&area[..0x50]), None);
+    }
+
+    fn regions(info: &CorebootInfo) -> std::vec::Vec<(u64, u64, MemoryType)> {
+        info.memory_map
+            .iter()
+            .map(|r| (r.start, r.size, r.region_type))
+            .collect()
+    }
+
+    fn e820_entry(addr: u64, size: u64, entry_type: u32) -> std::vec::Vec<u8> {
+        let mut entry = std::vec::Vec::new();
+        entry.extend_from_slice(&addr.to_le_bytes());
+        entry.extend_from_slice(&size.to_le_bytes());
+        entry.extend_from_slice(&entry_type.to_le_bytes());
+        entry
+    }
+
+    fn mb2_tag(tag_type: u32, payload: &[u8]) -> std::vec::Vec<u8> {
+        let mut tag = std::vec::Vec::new();
+        tag.extend_from_slice(&tag_type.to_le_bytes());
+        tag.extend_from_slice(&(8 + payload.len() as u32).to_le_bytes());
+        tag.extend_from_slice(payload);
+        tag.resize(tag.len().next_multiple_of(8), 0);
+        tag
+    }
+
+    fn mb2_info(tags: &[std::vec::Vec<u8>]) -> std::vec::Vec<u8> {
+        let mut info = std::vec![0u8; 8];
+        tags.iter().for_each(|tag| info.extend(tag));
+        info.extend(mb2_tag(MB2_TAG_END, &[]));
+        let total_size = info.len() as u32;
+        info[..4].copy_from_slice(&total_size.to_le_bytes());
+        info
+    }
+
+    #[test]
+    fn multiboot2_memory_map() {
+        // Command line tag first, its odd size is padded
+        let cmdline = mb2_tag(1, b"quiet\0");
+        let meminfo = mb2_tag(MB2_TAG_BASIC_MEMINFO, &[0x7f, 2, 0, 0, 0, 0xfc, 1, 0]);
+        let mut mmap = std::vec::Vec::new();
+        mmap.extend_from_slice(&24u32.to_le_bytes());
+        mmap.extend_from_slice(&0u32.to_le_bytes());
+        for (addr, size, entry_type) in [
+            (0, 0x9fc00, 1),
+            (0x100000, 0x7ee0000, 1),
+            (0x7fe0000, 0x20000, 4),
+        ] {
+            mmap.extend(e820_entry(addr, size, entry_type));
+            mmap.extend_from_slice(&0u32.to_le_bytes());
+        }
+        let mmap = mb2_tag(MB2_TAG_MMAP, &mmap);
+
+        let mut info = CorebootInfo::new();
+        add_multiboot2_info(
+            &mb2_info(&[cmdline.clone(), meminfo.clone(), mmap]),
+            &mut info,
+        );
+        assert_eq!(
+            regions(&info),
+            [
+                (0x1000, 0x9ec00, MemoryType::Ram),
+                (0x100000, 0x7ee0000, MemoryType::Ram),
+                (0x7fe0000, 0x20000, MemoryType::AcpiNvs),
+            ]
+        );
+
+        // Only the memory size
+        let mut info = CorebootInfo::new();
+        add_multiboot2_info(&mb2_info(&[cmdline, meminfo]), &mut info);
+        assert_eq!(regions(&info)[1], (0x100000, 0x7f00000, MemoryType::Ram));
+    }
+
+    #[test]
+    fn linux_boot_params() {
+        let mut params = std::vec![0u8; BOOT_PARAMS_SIZE];
+        params[BP_ALT_MEM_K..BP_ALT_MEM_K + 4].copy_from_slice(&0x1fc00u32.to_le_bytes());
+
+        let mut info = CorebootInfo::new();
+        add_boot_params(&params, &mut info);
+        assert_eq!(regions(&info)[1], (0x100000, 0x7f00000, MemoryType::Ram));
+        assert_eq!(info.acpi_rsdp, None);
+
+        params[BP_ACPI_RSDP_ADDR..BP_ACPI_RSDP_ADDR + 8].copy_from_slice(&0xf5ab0u64.to_le_bytes());
+        params[BP_E820_ENTRIES] = 2;
+        params[BP_E820_TABLE..BP_E820_TABLE + 20]
+            .copy_from_slice(&e820_entry(0x100000, 0x7f00000, 1));
+        params[BP_E820_TABLE + 20..BP_E820_TABLE + 40]
+            .copy_from_slice(&e820_entry(0xfeffc000, 0x4000, 2));
+        // Past the entry count
+        params[BP_E820_TABLE + 40..BP_E820_TABLE + 60].copy_from_slice(&e820_entry(0, 0x1000, 1));
+
+        let mut info = CorebootInfo::new();
+        add_boot_params(&params, &mut info);
+        assert_eq!(
+            regions(&info),
+            [
+                (0x100000, 0x7f00000, MemoryType::Ram),
+                (0xfeffc000, 0x4000, MemoryType::Reserved),
+            ]
+        );
+        assert_eq!(info.acpi_rsdp, Some(0xf5ab0));
+    }
+
+    #[test]
+    fn loader_from_magic() {
+        assert_eq!(
+            LoaderInfo::new(MULTIBOOT2_MAGIC, 0x9000),
+            Some(LoaderInfo::Multiboot2(0x9000))
+        );
+        assert_eq!(LoaderInfo::new(MULTIBOOT_MAGIC, 0), None);
+        assert_eq!(LoaderInfo::new(0, 0x9000), None);
+    }
+}
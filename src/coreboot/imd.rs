@@ -0,0 +1,172 @@
+//! Coreboot IMD (in-memory database) access
+//!
+//! CBMEM is implemented by coreboot as an IMD: a root structure just below
+//! `cbmem_top` that describes a list of entries growing downwards. This module
+//! can look up entries and append new ones, so that the payload can publish
+//! its own CBMEM regions.
+//!
+//! Only the large-entry root is used; small entries live in a nested IMD
+//! that we never touch.
+//!
+//! Reference: coreboot/src/lib/imd.c
+
+use super::memory::{MemoryRegion, MemoryType};
+
+/// Magic of the root pointer at the very top of the IMD
+const IMD_ROOT_PTR_MAGIC: u32 = 0xc038_9481;
+
+/// Magic of every IMD entry
+const IMD_ENTRY_MAGIC: u32 = !IMD_ROOT_PTR_MAGIC;
+
+/// Root flag: no more entries may be added
+const IMD_FLAG_LOCKED: u32 = 1;
+
+/// Root pointer, located at `limit - 8`
+#[repr(C, packed)]
+struct ImdRootPointer {
+    magic: u32,
+    /// Offset of the root relative to the root pointer
+    root_offset: i32,
+}
+
+/// IMD root header, followed by `max_entries` entries
+#[repr(C, packed)]
+struct ImdRoot {
+    max_entries: u32,
+    num_entries: u32,
+    flags: u32,
+    entry_align: u32,
+    /// Lowest allowed entry offset relative to the root (0 = unlimited)
+    max_offset: i32,
+}
+
+/// IMD entry
+#[repr(C, packed)]
+struct ImdEntry {
+    magic: u32,
+    /// Start of the entry relative to the root
+    start_offset: i32,
+    size: u32,
+    id: u32,
+}
+
+/// Handle to the large-entry IMD root
+#[derive(Debug, Clone, Copy)]
+pub struct Imd {
+    root: u64,
+}
+
+impl Imd {
+    /// Locate the IMD root at the top of one of the coreboot table regions
+    ///
+    /// # Safety
+    ///
+    /// The memory map must describe the coreboot tables of this boot.
+    pub unsafe fn find(regions: &[MemoryRegion]) -> Option<Self> {
+        regions
+            .iter()
+            .filter(|r| r.region_type == MemoryType::Table && r.size > 8)
+            .find_map(|r| unsafe { Self::at(r.end(), r.start) })
+    }
+
+    /// Locate the IMD root through the root pointer just below `limit`,
+    /// accepting a root no lower than `lowest`
+    ///
+    /// # Safety
+    ///
+    /// The 8 bytes below `limit` must be readable.
+    pub unsafe fn at(limit: u64, lowest: u64) -> Option<Self> {
+        let rp_addr = limit.checked_sub(core::mem::size_of::<ImdRootPointer>() as u64)?;
+        let rp = unsafe { core::ptr::read_unaligned(rp_addr as *const ImdRootPointer) };
+        if rp.magic != IMD_ROOT_PTR_MAGIC {
+            return None;
+        }
+
+        let root = rp_addr.checked_add_signed(rp.root_offset as i64)?;
+        (root >= lowest && root < rp_addr).then_some(Imd { root })
+    }
+
+    fn header(&self) -> ImdRoot {
+        unsafe { core::ptr::read_unaligned(self.root as *const ImdRoot) }
+    }
+
+    fn entry_ptr(&self, index: u32) -> *mut ImdEntry {
+        let entries = self.root + core::mem::size_of::<ImdRoot>() as u64;
+        (entries + index as u64 * core::mem::size_of::<ImdEntry>() as u64) as *mut ImdEntry
+    }
+
+    fn entry(&self, index: u32) -> ImdEntry {
+        unsafe { core::ptr::read_unaligned(self.entry_ptr(index)) }
+    }
+
+    fn entry_address(&self, entry: &ImdEntry) -> Option<u64> {
+        self.root.checked_add_signed(entry.start_offset as i64)
+    }
+
+    /// Find an entry by CBMEM ID, returning its address and size
+    pub fn find_entry(&self, id: u32) -> Option<(u64, u32)> {
+        let header = self.header();
+        (0..header.num_entries.min(header.max_entries))
+            .map(|i| self.entry(i))
+            .find(|e| e.magic == IMD_ENTRY_MAGIC && e.id == id)
+            .and_then(|e| Some((self.entry_address(&e)?, e.size)))
+    }
+
+    /// Address the next entry of `size` bytes would be placed at
+    ///
+    /// Returns `None` if the IMD is locked, full, or out of space.
+    pub fn next_entry_address(&self, size: u32) -> Option<u64> {
+        self.root
+            .checked_add_signed(self.next_start_offset(size)? as i64)
+    }
+
+    fn next_start_offset(&self, size: u32) -> Option<i32> {
+        let header = self.header();
+        if header.flags & IMD_FLAG_LOCKED != 0
+            || header.num_entries == 0
+            || header.num_entries >= header.max_entries
+            || !header.entry_align.is_power_of_two()
+        {
+            return None;
+        }
+
+        let last = self.entry(header.num_entries - 1);
+        let aligned = size.checked_next_multiple_of(header.entry_align)?;
+        let start_offset = last
+            .start_offset
+            .checked_sub(i32::try_from(aligned).ok()?)?;
+
+        if header.max_offset != 0 && start_offset < header.max_offset {
+            return None;
+        }
+
+        Some(start_offset)
+    }
+
+    /// Append a new entry and return its address
+    ///
+    /// # Safety
+    ///
+    /// The memory below the current lowest entry must not be in use.
+    /// Callers should reserve [`Imd::next_entry_address`] first.
+    pub unsafe fn add_entry(&self, id: u32, size: u32) -> Option<u64> {
+        let start_offset = self.next_start_offset(size)?;
+        let index = self.header().num_entries;
+
+        unsafe {
+            core::ptr::write_unaligned(
+                self.entry_ptr(index),
+                ImdEntry {
+                    magic: IMD_ENTRY_MAGIC,
+                    start_offset,
+                    size,
+                    id,
+                },
+            );
+            let num_entries = core::ptr::addr_of_mut!((*(self.root as *mut ImdRoot)).num_entries);
+            core::ptr::write_unaligned(num_entries, index + 1);
+        }
+
+        self.root.checked_add_signed(start_offset as i64)
+    }
+}
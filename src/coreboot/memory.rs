@@ -17,8 +17,14 @@ pub enum MemoryType {
     AcpiNvs = 4,
     /// Unusable memory
     Unusable = 5,
+    /// Reserved by the silicon vendor's code (FSP, AGESA)
+    VendorReserved = 6,
+    /// Persistent memory (e820 type 7, or legacy type 12)
+    Persistent = 7,
     /// Coreboot tables
     Table = 16,
+    /// Specific purpose memory such as CXL or HBM, left to the OS
+    SoftReserved = 0xefff_ffff,
 }
 
 impl MemoryType {
@@ -41,7 +47,10 @@ impl MemoryType {
             MemoryType::AcpiReclaimable => efi::ACPI_RECLAIM_MEMORY,
             MemoryType::AcpiNvs => efi::ACPI_MEMORY_NVS,
             MemoryType::Unusable => efi::UNUSABLE_MEMORY,
+            MemoryType::VendorReserved => efi::RESERVED_MEMORY_TYPE,
+            MemoryType::Persistent => efi::PERSISTENT_MEMORY,
             MemoryType::Table => efi::BOOT_SERVICES_DATA,
+            MemoryType::SoftReserved => efi::CONVENTIONAL_MEMORY,
         }
     }
 }
@@ -4,16 +4,22 @@
 //! the system hardware, including memory map, serial port, framebuffer,
 //! CBMEM console, and ACPI tables.
 
+pub mod cbfs;
 pub mod cbmem_console;
+pub mod cmos_options;
+pub mod fallback;
 pub mod framebuffer;
+pub mod imd;
 pub mod memory;
+pub mod smmstore;
 pub mod tables;
+pub mod vpd;
 
 use spin::Mutex;
 
 pub use framebuffer::FramebufferInfo;
 pub use memory::{MemoryRegion, MemoryType};
-pub use tables::{CorebootInfo, SerialInfo};
+pub use tables::{CorebootInfo, MainboardInfo, SerialInfo};
 
 /// Global framebuffer info storage
 ///
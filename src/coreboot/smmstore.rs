@@ -0,0 +1,235 @@
+//! SMMSTORE v2 client
+//!
+//! coreboot's SMMSTORE sets aside a flash region for the payload's
+//! variables. With version 2 the region is written by coreboot's SMI
+//! handler, so the flash can stay locked down for everything outside SMM,
+//! the OS included. The payload puts the data in a communication buffer
+//! and raises an SMI through the APM control port, with the command in
+//! `eax` and the address of a parameter block in `ebx`; the buffer and the
+//! APM command come from the coreboot tables.
+//!
+//! Without a working SMI handler blocks are read from the memory-mapped
+//! flash and, before ExitBootServices, written through the chipset's SPI
+//! controller ([`crate::drivers::spi_flash`]). The flash address of the
+//! region is derived from its mapping, assuming the BIOS region ends at the
+//! end of the boot media as on x86 boards. After ExitBootServices the SPI
+//! controller belongs to the OS, so writes fail with
+//! [`SmmstoreError::Unsupported`] and the changes stay volatile.
+//!
+//! Runtime services save variables through the same path. The
+//! communication buffer is reported as runtime services data and the
+//! parameter block is a static in the firmware image, so both stay at their
+//! physical address for the identity-mapped runtime services.
+//!
+//! Reference: coreboot/src/include/smmstore.h
+
+use core::ops::Range;
+
+use spin::Mutex;
+
+use super::tables::{BootMediaInfo, SmmstoreInfo};
+use crate::arch::x86_64::io::outb_smi;
+use crate::drivers::spi_flash::SpiFlash;
+use crate::state;
+
+/// APM control port, writing it raises a software SMI
+const APM_CNT: u16 = 0xb2;
+
+/// Commands of the raw block interface of version 2
+const CMD_RAW_READ: u32 = 5;
+const CMD_RAW_WRITE: u32 = 6;
+const CMD_RAW_CLEAR: u32 = 7;
+
+/// Return values of the SMI handler, 2 is an unsupported command
+const RET_SUCCESS: u32 = 0;
+const RET_FAILURE: u32 = 1;
+
+/// Why an SMMSTORE operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmmstoreError {
+    /// coreboot didn't describe an SMMSTORE v2 region
+    NotPresent,
+    /// The block or size is outside the region or the buffer
+    InvalidBlock,
+    /// The SMI handler reported an error
+    Failed,
+    /// No SMI handler took the command
+    Unsupported,
+}
+
+/// The region described by coreboot, if any
+static STORE: Mutex<Option<SmmstoreInfo>> = Mutex::new(None);
+
+/// Flash address of the region, if it is memory-mapped
+static FLASH_OFFSET: Mutex<Option<u32>> = Mutex::new(None);
+
+/// Parameter block passed to the SMI handler
+///
+/// The raw read and write commands take the size, the offset in the
+/// communication buffer and the block, clear only takes the block. A stack
+/// address would be virtual when the OS calls runtime services.
+static PARAMS: Mutex<[u32; 3]> = Mutex::new([0; 3]);
+
+/// Use the SMMSTORE v2 region described by coreboot
+///
+/// `boot_media` locates the region in the flash for direct writes.
+pub fn init(info: &SmmstoreInfo, boot_media: Option<&BootMediaInfo>) {
+    if info.num_blocks == 0 || info.block_size == 0 || info.com_buffer == 0 {
+        log::warn!("SMMSTORE: ignoring empty region");
+        return;
+    }
+    log::info!(
+        "SMMSTORE: {} blocks of {} KiB, APM command {:#x}",
+        info.num_blocks,
+        info.block_size / 1024,
+        info.apm_cmd
+    );
+    *STORE.lock() = Some(*info);
+
+    // The BIOS region ends at 4 GiB and at the end of the boot media
+    let mapped = (1u64 << 32).checked_sub(info.mmap_addr);
+    let offset = boot_media
+        .zip(mapped.filter(|_| info.mmap_addr != 0))
+        .and_then(|(media, mapped)| media.boot_media_size.checked_sub(mapped))
+        .and_then(|offset| u32::try_from(offset).ok());
+    *FLASH_OFFSET.lock() = offset;
+}
+
+/// Number of blocks and the bytes of a block that can be accessed
+///
+/// Blocks are read and written through the communication buffer, so only
+/// as much of a block as fits in the buffer is used.
+pub fn geometry() -> Option<(u32, usize)> {
+    let info = (*STORE.lock())?;
+    Some((
+        info.num_blocks,
+        info.block_size.min(info.com_buffer_size) as usize,
+    ))
+}
+
+/// The communication buffer, to be kept for runtime services
+pub fn com_buffer_region() -> Option<Range<u64>> {
+    let info = (*STORE.lock())?;
+    let start = info.com_buffer as u64;
+    Some(start..start + info.com_buffer_size as u64)
+}
+
+/// Raise the SMMSTORE SMI with `command` and the parameters in `words`
+fn call(info: &SmmstoreInfo, command: u32, words: &[u32]) -> Result<(), SmmstoreError> {
+    let mut params = PARAMS.lock();
+    params[..words.len()].copy_from_slice(words);
+    // The SMI handler only takes parameters below 4 GiB
+    let address = u32::try_from(params.as_ptr() as usize).map_err(|_| SmmstoreError::Failed)?;
+    let eax = (command << 8) | info.apm_cmd as u32;
+    // Safety: the parameters and the communication buffer are valid
+    match unsafe { outb_smi(APM_CNT, eax, address) } {
+        RET_SUCCESS => Ok(()),
+        RET_FAILURE => Err(SmmstoreError::Failed),
+        // Unsupported, or eax is untouched without an SMI handler
+        _ => Err(SmmstoreError::Unsupported),
+    }
+}
+
+/// Check `block` and `size` against the region, returning the region
+fn check(block: u32, size: usize) -> Result<SmmstoreInfo, SmmstoreError> {
+    let info = (*STORE.lock()).ok_or(SmmstoreError::NotPresent)?;
+    let usable = info.block_size.min(info.com_buffer_size) as usize;
+    if block >= info.num_blocks || size > usable {
+        return Err(SmmstoreError::InvalidBlock);
+    }
+    Ok(info)
+}
+
+/// The communication buffer
+///
+/// # Safety
+///
+/// The buffer must not be in use elsewhere for the lifetime of the slice.
+unsafe fn com_buffer(info: &SmmstoreInfo) -> &'static mut [u8] {
+    unsafe {
+        core::slice::from_raw_parts_mut(
+            info.com_buffer as usize as *mut u8,
+            info.com_buffer_size as usize,
+        )
+    }
+}
+
+/// Read the usable part of `block` and pass it to `f`
+///
+/// Falls back to the memory-mapped flash if the SMI handler can't read.
+pub fn read_block<R>(block: u32, f: impl FnOnce(&[u8]) -> R) -> Result<R, SmmstoreError> {
+    let (_, size) = geometry().ok_or(SmmstoreError::NotPresent)?;
+    let info = check(block, size)?;
+    match call(&info, CMD_RAW_READ, &[size as u32, 0, block]) {
+        // Safety: the SMI handler filled the buffer, which is only used here
+        Ok(()) => Ok(f(unsafe { &com_buffer(&info)[..size] })),
+        Err(error) if info.mmap_addr == 0 => Err(error),
+        Err(error) => {
+            log::debug!("SMMSTORE: read of block {} failed: {:?}", block, error);
+            let address = info.mmap_addr + block as u64 * info.block_size as u64;
+            // Safety: coreboot maps the region there and it stays mapped
+            Ok(f(unsafe {
+                core::slice::from_raw_parts(address as *const u8, size)
+            }))
+        }
+    }
+}
+
+/// Fill the communication buffer for [`write_block`]
+///
+/// `f` gets as much of the buffer as a block can use.
+pub fn fill_buffer<R>(f: impl FnOnce(&mut [u8]) -> R) -> Result<R, SmmstoreError> {
+    let (_, size) = geometry().ok_or(SmmstoreError::NotPresent)?;
+    let info = check(0, size)?;
+    // Safety: the buffer is only used while the caller holds it
+    Ok(f(unsafe { &mut com_buffer(&info)[..size] }))
+}
+
+/// The SPI controller and the flash address of `block`, for writes
+/// without an SMI handler
+fn direct(info: &SmmstoreInfo, block: u32) -> Result<(SpiFlash, u32), SmmstoreError> {
+    if state::efi().allocator.boot_services_exited() {
+        return Err(SmmstoreError::Unsupported);
+    }
+    let offset = (*FLASH_OFFSET.lock()).ok_or(SmmstoreError::Unsupported)?;
+    let flash = SpiFlash::find().ok_or(SmmstoreError::Unsupported)?;
+    Ok((flash, offset + block * info.block_size))
+}
+
+/// Erase `block`
+pub fn clear_block(block: u32) -> Result<(), SmmstoreError> {
+    let info = check(block, 0)?;
+    match call(&info, CMD_RAW_CLEAR, &[block]) {
+        Err(SmmstoreError::Unsupported) => {
+            let (flash, address) = direct(&info, block)?;
+            log::debug!(
+                "SMMSTORE: erasing block {} at {:#x} directly",
+                block,
+                address
+            );
+            flash.erase(address, info.block_size).map_err(|error| {
+                log::warn!("SMMSTORE: erasing the flash failed: {:?}", error);
+                SmmstoreError::Failed
+            })
+        }
+        result => result,
+    }
+}
+
+/// Write the first `size` bytes of the communication buffer to the start
+/// of the erased `block`
+pub fn write_block(block: u32, size: usize) -> Result<(), SmmstoreError> {
+    let info = check(block, size)?;
+    match call(&info, CMD_RAW_WRITE, &[size as u32, 0, block]) {
+        Err(SmmstoreError::Unsupported) => {
+            let (flash, address) = direct(&info, block)?;
+            // Safety: the caller filled the buffer and is done with it
+            let data = unsafe { &com_buffer(&info)[..size] };
+            flash.write(address, data).map_err(|error| {
+                log::warn!("SMMSTORE: writing the flash failed: {:?}", error);
+                SmmstoreError::Failed
+            })
+        }
+        result => result,
+    }
+}
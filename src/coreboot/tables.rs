@@ -4,6 +4,7 @@
 //! Reference: coreboot/src/commonlib/include/commonlib/coreboot_tables.h
 
 use super::framebuffer::FramebufferInfo;
+use super::imd::Imd;
 use super::memory::{MemoryRegion, MemoryType};
 use heapless::Vec;
 use zerocopy::{FromBytes, Immutable, KnownLayout, Unaligned};
@@ -11,6 +12,13 @@ use zerocopy::{FromBytes, Immutable, KnownLayout, Unaligned};
 /// Maximum number of memory regions we can store
 const MAX_MEMORY_REGIONS: usize = 64;
 
+/// Maximum number of forward records followed
+const MAX_FORWARDS: usize = 4;
+
+/// Largest CBMEM searched below `cbmem_top` for the coreboot table
+#[cfg(not(feature = "std"))]
+const MAX_CBMEM_SIZE: u64 = 64 * 1024 * 1024;
+
 /// Coreboot table tags
 #[allow(dead_code)]
 mod tags {
@@ -34,7 +42,10 @@ mod tags {
     pub const CB_TAG_FRAMEBUFFER: u32 = 0x0012;
     pub const CB_TAG_TIMESTAMPS: u32 = 0x0016;
     pub const CB_TAG_CBMEM_CONSOLE: u32 = 0x0017;
+    pub const CB_TAG_BOOT_MEDIA_PARAMS: u32 = 0x0030;
     pub const CB_TAG_CBMEM_ENTRY: u32 = 0x0031;
+    pub const CB_TAG_SMMSTOREV2: u32 = 0x0039;
+    pub const CB_TAG_CMOS_OPTION_TABLE: u32 = 0x00c8;
     pub const CB_TAG_ACPI_RSDP: u32 = 0x0043;
 }
 
@@ -42,6 +53,12 @@ mod tags {
 mod cbmem_ids {
     /// SMBIOS tables CBMEM ID (ASCII "SMBT")
     pub const CBMEM_ID_SMBIOS: u32 = 0x534d4254;
+    /// Coreboot table CBMEM ID (ASCII "CBTB")
+    pub const CBMEM_ID_CBTABLE: u32 = 0x43425442;
+    /// Romstage handoff CBMEM ID (ASCII "GTSR")
+    pub const CBMEM_ID_ROMSTAGE_INFO: u32 = 0x47545352;
+    /// Copy of the RO and RW VPD regions CBMEM ID (ASCII "VPD ")
+    pub const CBMEM_ID_VPD: u32 = 0x56504420;
 }
 
 /// Coreboot header structure
@@ -142,6 +159,34 @@ struct CbCbmemRef {
     cbmem_addr: u64,
 }
 
+/// Boot media layout
+#[repr(C, packed)]
+#[derive(FromBytes, Immutable, KnownLayout, Unaligned)]
+struct CbBootMediaParams {
+    tag: u32,
+    size: u32,
+    fmap_offset: u64,
+    cbfs_offset: u64,
+    cbfs_size: u64,
+    boot_media_size: u64,
+}
+
+/// SMMSTORE v2 region and SMI interface
+#[repr(C, packed)]
+#[derive(FromBytes, Immutable, KnownLayout, Unaligned)]
+struct CbSmmstoreV2 {
+    tag: u32,
+    size: u32,
+    num_blocks: u32,
+    block_size: u32,
+    mmap_addr_deprecated: u32,
+    com_buffer: u32,
+    com_buffer_size: u32,
+    apm_cmd: u8,
+    unused: [u8; 3],
+    mmap_addr: u64,
+}
+
 /// CBMEM entry record (used for SMBIOS, etc.)
 ///
 /// This record provides pointers to CBMEM regions by ID.
@@ -156,6 +201,17 @@ struct CbCbmemEntry {
     id: u32,
 }
 
+/// Reasons a coreboot table is rejected
+#[derive(Debug)]
+enum TableError {
+    /// Table shorter than its header says
+    Truncated,
+    /// No "LBIO" signature
+    BadSignature,
+    /// Header size smaller than the header structure
+    BadHeader,
+}
+
 /// Serial port information
 #[derive(Debug, Clone)]
 pub struct SerialInfo {
@@ -166,6 +222,41 @@ pub struct SerialInfo {
     pub input_hertz: u32,
 }
 
+/// Mainboard identification
+#[derive(Debug, Clone)]
+pub struct MainboardInfo {
+    pub vendor: heapless::String<32>,
+    pub part_number: heapless::String<64>,
+}
+
+/// Location of the CBFS coreboot booted from on the boot media
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootMediaInfo {
+    /// Offset of the CBFS from the start of the boot media
+    pub cbfs_offset: u64,
+    /// Size of the CBFS
+    pub cbfs_size: u64,
+    /// Size of the boot media
+    pub boot_media_size: u64,
+}
+
+/// SMMSTORE v2 flash region, written through SMIs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmmstoreInfo {
+    /// Number of erase blocks in the region
+    pub num_blocks: u32,
+    /// Size of an erase block
+    pub block_size: u32,
+    /// Buffer the SMI handler copies data from and to, below 4 GiB
+    pub com_buffer: u32,
+    /// Size of the communication buffer
+    pub com_buffer_size: u32,
+    /// Value written to the APM control port to raise the SMI
+    pub apm_cmd: u8,
+    /// Address the region is memory-mapped at, 0 if it isn't
+    pub mmap_addr: u64,
+}
+
 /// Information extracted from coreboot tables
 pub struct CorebootInfo {
     /// Memory map
@@ -177,23 +268,53 @@ pub struct CorebootInfo {
     /// ACPI RSDP pointer
     pub acpi_rsdp: Option<u64>,
     /// Coreboot version string
-    pub version: Option<&'static str>,
+    pub version: Option<heapless::String<64>>,
+    /// Coreboot extra version string, the local build suffix
+    pub extra_version: Option<heapless::String<64>>,
+    /// Coreboot build date
+    pub build: Option<heapless::String<64>>,
     /// CBMEM console address
     pub cbmem_console: Option<u64>,
     /// SMBIOS tables address (from CBMEM entry)
     pub smbios: Option<u64>,
+    /// Address of the coreboot table header (after following forward pointers)
+    pub table_header: Option<u64>,
+    /// Mainboard vendor and part number
+    pub mainboard: Option<MainboardInfo>,
+    /// Romstage handoff address (from CBMEM entry), records S3 resume
+    pub romstage_handoff: Option<u64>,
+    /// Timestamp table address
+    pub timestamps: Option<u64>,
+    /// VPD copy address (from CBMEM entry)
+    pub vpd: Option<u64>,
+    /// CBFS location on the boot media
+    pub boot_media: Option<BootMediaInfo>,
+    /// SMMSTORE v2 region for non-volatile variables
+    pub smmstore: Option<SmmstoreInfo>,
+    /// CMOS option table address (the whole record)
+    pub cmos_option_table: Option<u64>,
 }
 
 impl CorebootInfo {
-    fn new() -> Self {
+    pub(super) fn new() -> Self {
         CorebootInfo {
             memory_map: Vec::new(),
             serial: None,
             framebuffer: None,
             acpi_rsdp: None,
             version: None,
+            extra_version: None,
+            build: None,
             cbmem_console: None,
             smbios: None,
+            table_header: None,
+            mainboard: None,
+            romstage_handoff: None,
+            timestamps: None,
+            vpd: None,
+            boot_media: None,
+            smmstore: None,
+            cmos_option_table: None,
         }
     }
 }
@@ -211,131 +332,121 @@ pub unsafe fn parse(ptr: *const u8) -> CorebootInfo {
         log::warn!("Coreboot table pointer is null, scanning memory...");
         unsafe { scan_for_header() }
     } else {
-        unsafe { find_header(ptr) }
+        unsafe { find_header(ptr) }.or_else(|| {
+            log::warn!("No coreboot table at {:p}, scanning memory...", ptr);
+            unsafe { scan_for_header() }
+        })
     };
 
-    let header = match header {
-        Some(h) => h,
-        None => {
-            log::warn!("Could not find coreboot header, using fallback memory map");
-            create_fallback_memory_map(&mut info);
-            return info;
-        }
+    let Some(mut header) = header else {
+        log::warn!("Could not find coreboot header");
+        return info;
     };
 
-    // Safety: We've validated that header points to a valid coreboot table
-    unsafe {
-        // Verify signature "LBIO"
-        if &(*header).signature != b"LBIO" {
-            log::warn!("Invalid coreboot header signature");
-            create_fallback_memory_map(&mut info);
-            return info;
-        }
-
-        let table_bytes = (*header).table_bytes;
-        let header_bytes = (*header).header_bytes;
-
-        log::debug!("Found coreboot header: {} bytes of tables", table_bytes);
-
-        // Parse table entries
-        let table_start = (header as *const u8).add(header_bytes as usize);
-        let mut offset = 0u32;
+    // Follow forward records, but not around in circles
+    for _ in 0..MAX_FORWARDS {
+        // Safety: find_header found an "LBIO" signature here, and coreboot
+        // tables are followed by the number of bytes their header gives
+        let table = unsafe {
+            let header_bytes = (*header).header_bytes as usize;
+            let table_bytes = (*header).table_bytes as usize;
+            core::slice::from_raw_parts(header as *const u8, header_bytes + table_bytes)
+        };
 
-        while offset < table_bytes {
-            let record_ptr = table_start.add(offset as usize);
+        log::debug!("Found coreboot header at {:p}", header);
+        info.table_header = Some(header as u64);
 
-            // Read record header to get size
-            let record_header_bytes = core::slice::from_raw_parts(record_ptr, 8);
-            let Ok((record_header, _)) = CbRecord::read_from_prefix(record_header_bytes) else {
-                log::warn!("Failed to parse record header");
+        let forward = match parse_records(table, &mut info) {
+            Ok(forward) => forward,
+            Err(e) => {
+                log::warn!("Invalid coreboot table: {:?}", e);
                 break;
-            };
-            let record_size = record_header.size;
+            }
+        };
 
-            if record_size < 8 {
-                log::warn!("Invalid record size: {}", record_size);
+        let Some(forward) = forward else {
+            break;
+        };
+        log::debug!("Following forward pointer to {:#x}", forward);
+        // Safety: We trust the forward pointer from coreboot tables
+        header = match unsafe { find_header(forward as *const u8) } {
+            Some(h) => h,
+            None => {
+                log::warn!("Could not find coreboot header at forwarded location");
                 break;
             }
-
-            // Create slice for the full record and call safe parse_record
-            let record_bytes = core::slice::from_raw_parts(record_ptr, record_size as usize);
-            parse_record(record_bytes, &mut info);
-
-            offset += record_size;
-        }
+        };
     }
 
-    // If we still have no memory map, create a fallback
     if info.memory_map.is_empty() {
-        log::warn!("No memory map found in coreboot tables, using fallback");
-        create_fallback_memory_map(&mut info);
+        log::warn!("No memory map found in coreboot tables");
     }
 
     info
 }
 
-/// Create a fallback memory map for when coreboot tables aren't available
-/// This is mainly useful for QEMU testing
-fn create_fallback_memory_map(info: &mut CorebootInfo) {
-    log::info!("Creating fallback memory map for QEMU");
-
-    // Standard QEMU/PC memory layout:
-    // 0x00000000 - 0x0009FFFF: Low memory (640 KB) - usable
-    // 0x000A0000 - 0x000FFFFF: VGA + ROM (384 KB) - reserved
-    // 0x00100000 - 0x07FFFFFF: Extended memory (up to ~128 MB for safety) - usable
-    // We reserve the first 2MB for our code and page tables
-
-    // Low memory (below 640KB), but reserve first 4KB
-    let _ = info.memory_map.push(MemoryRegion {
-        start: 0x1000,
-        size: 0x9F000, // 636 KB
-        region_type: MemoryType::Ram,
-    });
-
-    // Extended memory: start at 2MB to avoid our payload, go up to 128MB
-    // (QEMU typically has at least 128MB, we asked for 512MB)
-    let _ = info.memory_map.push(MemoryRegion {
-        start: 0x200000,   // 2 MB
-        size: 0x1E00_0000, // 480 MB (up to ~512MB total, leaving room for MMIO)
-        region_type: MemoryType::Ram,
-    });
-
-    // Add serial port info for QEMU (COM1)
-    info.serial = Some(SerialInfo {
-        serial_type: 1, // IO port
-        baseaddr: 0x3f8,
-        baud: 115200,
-        regwidth: 1,
-        input_hertz: 1843200,
-    });
-
-    log::info!(
-        "Fallback memory map: {} regions, {} MB total",
-        info.memory_map.len(),
-        (0x9F000 + 0x1E00_0000) / (1024 * 1024)
-    );
-}
-
-/// Find the coreboot header, following forward pointers if needed
+/// Find the coreboot header at `ptr` or in the 4 KiB after it
 unsafe fn find_header(ptr: *const u8) -> Option<*const CbHeader> {
-    let header = ptr as *const CbHeader;
+    unsafe { scan_for_header_at(ptr, 0x1000) }
+}
 
-    // Check if this is a valid header
-    if (*header).signature == *b"LBIO" {
-        return Some(header);
+/// Check the signature and both checksums of a coreboot header
+///
+/// A stale or mis-passed pointer may still point at an "LBIO" signature,
+/// so it takes the checksums to trust a table.
+///
+/// # Safety
+///
+/// The header must be readable, and the table after it if its checksum
+/// matches.
+unsafe fn valid_header(header: *const CbHeader) -> bool {
+    let h = unsafe { core::ptr::read_unaligned(header) };
+    let header_bytes = h.header_bytes as usize;
+    if h.signature != *b"LBIO" || header_bytes != core::mem::size_of::<CbHeader>() {
+        return false;
+    }
+    let header_data = unsafe { core::slice::from_raw_parts(header as *const u8, header_bytes) };
+    if ip_checksum(header_data) != 0 {
+        log::debug!("Coreboot header at {:p} has a bad checksum", header);
+        return false;
+    }
+    let table = unsafe {
+        core::slice::from_raw_parts(
+            (header as *const u8).add(header_bytes),
+            h.table_bytes as usize,
+        )
+    };
+    if ip_checksum(table) as u32 != h.table_checksum {
+        log::warn!("Coreboot table at {:p} has a bad checksum", header);
+        return false;
     }
+    true
+}
+
+/// Find the coreboot table in CBMEM, through the IMD below `cbmem_top`
+#[cfg(not(feature = "std"))]
+unsafe fn find_header_in_cbmem(cbmem_top: u64) -> Option<*const CbHeader> {
+    let imd = unsafe { Imd::at(cbmem_top, cbmem_top.saturating_sub(MAX_CBMEM_SIZE)) }?;
+    let (address, _) = imd.find_entry(cbmem_ids::CBMEM_ID_CBTABLE)?;
+    let header = address as *const CbHeader;
+    unsafe { valid_header(header) }.then_some(header)
+}
 
-    // Try scanning from the given address
-    scan_for_header_at(ptr, 0x1000)
+/// There is no physical memory to scan in host tests
+#[cfg(feature = "std")]
+unsafe fn scan_for_header() -> Option<*const CbHeader> {
+    None
 }
 
-/// Scan memory for coreboot header signature "LBIO"
+/// Scan memory for a valid coreboot header
+#[cfg(not(feature = "std"))]
 unsafe fn scan_for_header() -> Option<*const CbHeader> {
     // Coreboot tables can be found at several locations:
     // 1. Low memory (0x00000 - 0x01000)
     // 2. At the top of low memory / EBDA area
     // 3. In the BIOS area (0xF0000 - 0xFFFFF)
-    // 4. In high memory (where coreboot typically puts them)
+    // 4. In CBMEM, found through its IMD below cbmem_top
+    // 5. In high memory (where coreboot typically puts them)
 
     // First, try low memory
     if let Some(header) = scan_for_header_at(core::ptr::null::<u8>(), 0x1000) {
@@ -355,6 +466,14 @@ unsafe fn scan_for_header() -> Option<*const CbHeader> {
         return Some(header);
     }
 
+    // coreboot's QEMU boards put CBMEM right below the top of low RAM
+    if let Some(cbmem_top) = super::fallback::qemu_low_ram_top()
+        && let Some(header) = unsafe { find_header_in_cbmem(cbmem_top) }
+    {
+        log::debug!("Found coreboot tables in CBMEM below {:#x}", cbmem_top);
+        return Some(header);
+    }
+
     // Try common high memory locations
     for base in &[0x7EE00000u64, 0x7FE00000u64, 0xCFF00000u64] {
         if let Some(header) = scan_for_header_at(*base as *const u8, 0x100000) {
@@ -366,7 +485,7 @@ unsafe fn scan_for_header() -> Option<*const CbHeader> {
     None
 }
 
-/// Scan a memory region for the coreboot header
+/// Scan a memory region for a valid coreboot header
 unsafe fn scan_for_header_at(base: *const u8, size: usize) -> Option<*const CbHeader> {
     // Scan in 16-byte increments (coreboot header is aligned)
     let mut offset = 0;
@@ -378,7 +497,7 @@ unsafe fn scan_for_header_at(base: *const u8, size: usize) -> Option<*const CbHe
         // We need to be careful not to read from invalid memory
         // Use a simple check that won't fault on most systems
         let sig_ptr = ptr as *const [u8; 4];
-        if *sig_ptr == *b"LBIO" {
+        if *sig_ptr == *b"LBIO" && unsafe { valid_header(header) } {
             log::debug!("Found LBIO signature at {:p}", ptr);
             return Some(header);
         }
@@ -396,7 +515,7 @@ unsafe fn scan_for_header_at(base: *const u8, size: usize) -> Option<*const CbHe
 /// * `info` - CorebootInfo to populate
 ///
 /// This function is safe because it uses zerocopy to validate all struct parsing.
-/// The `parse_forward` case still requires unsafe internally to follow the pointer.
+/// Forward records are handled by [`parse_records`].
 fn parse_record(record_bytes: &[u8], info: &mut CorebootInfo) {
     let Ok((header, _)) = CbRecord::read_from_prefix(record_bytes) else {
         return;
@@ -413,36 +532,43 @@ fn parse_record(record_bytes: &[u8], info: &mut CorebootInfo) {
         tags::CB_TAG_FRAMEBUFFER => {
             parse_framebuffer(record_bytes, info);
         }
-        tags::CB_TAG_FORWARD => {
-            // This one still needs unsafe to follow the pointer
-            unsafe { parse_forward(record_bytes, info) };
-        }
         tags::CB_TAG_ACPI_RSDP => {
             parse_acpi_rsdp(record_bytes, info);
         }
         tags::CB_TAG_CBMEM_CONSOLE => {
             parse_cbmem_console(record_bytes, info);
         }
+        tags::CB_TAG_TIMESTAMPS => {
+            parse_timestamps(record_bytes, info);
+        }
         tags::CB_TAG_CBMEM_ENTRY => {
             parse_cbmem_entry(record_bytes, info);
         }
+        tags::CB_TAG_BOOT_MEDIA_PARAMS => {
+            parse_boot_media(record_bytes, info);
+        }
+        tags::CB_TAG_SMMSTOREV2 => {
+            parse_smmstore(record_bytes, info);
+        }
+        tags::CB_TAG_MAINBOARD => {
+            parse_mainboard(record_bytes, info);
+        }
+        tags::CB_TAG_CMOS_OPTION_TABLE => {
+            // The table stays in memory and is parsed when it is used
+            let address = record_bytes.as_ptr() as u64;
+            log::debug!("CMOS option table at {:#x}", address);
+            info.cmos_option_table = Some(address);
+        }
         tags::CB_TAG_VERSION => {
-            // Version string follows the 8-byte record header
-            // Note: We need 'static lifetime since coreboot tables persist
-            // for the entire boot process. This is inherently unsafe as we're
-            // extending the lifetime, but is correct because the tables are in
-            // firmware memory.
-            if record_bytes.len() > 8 {
-                let len = record_bytes.len() - 8;
-                // Safety: The coreboot tables are in firmware memory that persists
-                // for the entire boot, so 'static lifetime is appropriate.
-                let string_bytes: &'static [u8] =
-                    unsafe { core::slice::from_raw_parts(record_bytes.as_ptr().add(8), len) };
-                if let Ok(s) = core::str::from_utf8(string_bytes) {
-                    info.version = Some(s.trim_end_matches('\0'));
-                    log::debug!("Coreboot version: {}", info.version.unwrap());
-                }
-            }
+            let version = string_record(record_bytes);
+            log::debug!("Coreboot version: {}", version);
+            info.version = Some(version);
+        }
+        tags::CB_TAG_EXTRA_VERSION => {
+            info.extra_version = Some(string_record(record_bytes));
+        }
+        tags::CB_TAG_BUILD => {
+            info.build = Some(string_record(record_bytes));
         }
         _ => {
             log::trace!("Ignoring coreboot tag: {:#x}", tag);
@@ -450,6 +576,50 @@ fn parse_record(record_bytes: &[u8], info: &mut CorebootInfo) {
     }
 }
 
+/// Copy the NUL-terminated string of a string record, truncating it to fit
+fn string_record<const N: usize>(record_bytes: &[u8]) -> heapless::String<N> {
+    let bytes = &record_bytes[8.min(record_bytes.len())..];
+    let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+    let mut string = heapless::String::new();
+    for c in core::str::from_utf8(&bytes[..len]).unwrap_or("").chars() {
+        if string.push(c).is_err() {
+            break;
+        }
+    }
+    string
+}
+
+/// Parse the mainboard record
+///
+/// The record holds the indices of the vendor and part number strings
+/// followed by the NUL-terminated strings themselves.
+fn parse_mainboard(record_bytes: &[u8], info: &mut CorebootInfo) {
+    let Some(&[vendor_idx, part_number_idx]) = record_bytes.get(8..10) else {
+        return;
+    };
+    let strings = &record_bytes[10..];
+
+    /// Copy the NUL-terminated string at `index`, truncating it to fit
+    fn string_at<const N: usize>(strings: &[u8], index: u8) -> heapless::String<N> {
+        let bytes = strings.get(index as usize..).unwrap_or(&[]);
+        let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+        let mut string = heapless::String::new();
+        for c in core::str::from_utf8(&bytes[..len]).unwrap_or("").chars() {
+            if string.push(c).is_err() {
+                break;
+            }
+        }
+        string
+    }
+
+    let mainboard = MainboardInfo {
+        vendor: string_at(strings, vendor_idx),
+        part_number: string_at(strings, part_number_idx),
+    };
+    log::debug!("Mainboard: {} {}", mainboard.vendor, mainboard.part_number);
+    info.mainboard = Some(mainboard);
+}
+
 /// Parse memory map from coreboot table
 ///
 /// This function is safe - it uses zerocopy to iterate through memory ranges.
@@ -479,8 +649,18 @@ fn parse_memory(record_bytes: &[u8], info: &mut CorebootInfo) {
             3 => MemoryType::AcpiReclaimable,
             4 => MemoryType::AcpiNvs,
             5 => MemoryType::Unusable,
+            6 => MemoryType::VendorReserved,
+            7 | 12 => MemoryType::Persistent,
             16 => MemoryType::Table,
-            _ => MemoryType::Reserved,
+            0xefff_ffff => MemoryType::SoftReserved,
+            _ => {
+                log::warn!(
+                    "Unknown memory type {:#x} at {:#x}, reserving it",
+                    mem_type,
+                    start
+                );
+                MemoryType::Reserved
+            }
         };
 
         let region = MemoryRegion {
@@ -573,72 +753,152 @@ fn parse_framebuffer(record_bytes: &[u8], info: &mut CorebootInfo) {
     );
 }
 
-/// Parse forward pointer and follow it
+/// Parse a coreboot table held in a byte slice
 ///
-/// # Safety
-/// This function must follow a memory pointer from the coreboot tables,
-/// which requires trusting that the pointer is valid.
-unsafe fn parse_forward(record_bytes: &[u8], info: &mut CorebootInfo) {
-    // Safely parse the forward record using zerocopy
-    let Ok((forward, _)) = CbForward::read_from_prefix(record_bytes) else {
-        log::warn!("Failed to parse forward record");
-        return;
-    };
-    let forward_addr = forward.forward;
-    let new_ptr = forward_addr as *const u8;
-
-    log::debug!("Following forward pointer to {:#x}", forward_addr);
-
-    // Parse the forwarded table directly into info (no recursion)
-    // Safety: We trust the forward pointer from coreboot tables
-    let header = match find_header(new_ptr) {
-        Some(h) => h,
-        None => {
-            log::warn!("Could not find coreboot header at forwarded location");
-            return;
-        }
-    };
-
-    // Verify signature "LBIO"
-    if &(*header).signature != b"LBIO" {
-        log::warn!("Invalid coreboot header signature at forwarded location");
-        return;
+/// `table` starts with the table header and holds all of its records. Forward
+/// records are not followed, since they point at physical memory. No fallback
+/// memory map is created.
+pub fn parse_bytes(table: &[u8]) -> CorebootInfo {
+    let mut info = CorebootInfo::new();
+    if let Err(e) = parse_records(table, &mut info) {
+        log::warn!("Invalid coreboot table: {:?}", e);
     }
+    info
+}
 
-    let table_bytes = (*header).table_bytes;
-    let header_bytes = (*header).header_bytes;
-
-    log::debug!(
-        "Found forwarded coreboot header: {} bytes of tables",
-        table_bytes
-    );
+/// Validate a coreboot table and parse its records into `info`
+///
+/// Returns the target of a forward record, if the table has one; records
+/// after it are still parsed.
+fn parse_records(table: &[u8], info: &mut CorebootInfo) -> Result<Option<u64>, TableError> {
+    let (header, _) = CbHeader::read_from_prefix(table).map_err(|_| TableError::Truncated)?;
+    if header.signature != *b"LBIO" {
+        return Err(TableError::BadSignature);
+    }
 
-    // Parse table entries
-    let table_start = (header as *const u8).add(header_bytes as usize);
-    let mut offset = 0u32;
+    let header_bytes = header.header_bytes as usize;
+    let table_bytes = header.table_bytes as usize;
+    log::debug!("Coreboot table: {} bytes of records", table_bytes);
 
-    while offset < table_bytes {
-        let record_ptr = table_start.add(offset as usize);
+    if header_bytes < core::mem::size_of::<CbHeader>() {
+        return Err(TableError::BadHeader);
+    }
+    let records = table
+        .get(header_bytes..)
+        .and_then(|records| records.get(..table_bytes))
+        .ok_or(TableError::Truncated)?;
 
-        // Read record header to get size
-        let record_header_bytes = core::slice::from_raw_parts(record_ptr, 8);
-        let Ok((record_header, _)) = CbRecord::read_from_prefix(record_header_bytes) else {
+    let mut forward = None;
+    let mut offset = 0;
+    while offset < records.len() {
+        let Ok((record_header, _)) = CbRecord::read_from_prefix(&records[offset..]) else {
             log::warn!("Failed to parse record header");
             break;
         };
-        let record_size = record_header.size;
+        let record_size = record_header.size as usize;
 
-        if record_size < 8 {
+        if record_size < core::mem::size_of::<CbRecord>() || record_size > records.len() - offset {
             log::warn!("Invalid record size: {}", record_size);
             break;
         }
 
-        // Create slice for the full record and call safe parse_record
-        let record_bytes = core::slice::from_raw_parts(record_ptr, record_size as usize);
-        parse_record(record_bytes, info);
+        let record_bytes = &records[offset..offset + record_size];
+        let tag = record_header.tag;
+        if tag == tags::CB_TAG_FORWARD {
+            match CbForward::read_from_prefix(record_bytes) {
+                Ok((record, _)) => forward = Some(record.forward),
+                Err(_) => log::warn!("Failed to parse forward record"),
+            }
+        } else {
+            parse_record(record_bytes, info);
+        }
 
         offset += record_size;
     }
+
+    Ok(forward)
+}
+
+/// Append a CBMEM entry record to the coreboot table
+///
+/// Coreboot writes its table before the payload runs, so CBMEM entries the
+/// payload adds later are invisible to tools like `cbmem` unless they are
+/// also recorded here. The table must live in its own CBMEM entry with room
+/// for one more record; both checksums are updated.
+///
+/// # Safety
+///
+/// `header` must be the coreboot table header found by [`parse`] and `imd`
+/// the CBMEM IMD of this boot.
+pub unsafe fn append_cbmem_entry(
+    header: u64,
+    imd: &Imd,
+    id: u32,
+    address: u64,
+    entry_size: u32,
+) -> bool {
+    let Some((table_addr, capacity)) = imd.find_entry(cbmem_ids::CBMEM_ID_CBTABLE) else {
+        return false;
+    };
+    if table_addr != header {
+        return false;
+    }
+
+    let header_ptr = header as *mut CbHeader;
+    const RECORD_SIZE: usize = core::mem::size_of::<CbCbmemEntry>();
+    let mut record = [0u8; RECORD_SIZE];
+    record[0..4].copy_from_slice(&tags::CB_TAG_CBMEM_ENTRY.to_le_bytes());
+    record[4..8].copy_from_slice(&(RECORD_SIZE as u32).to_le_bytes());
+    record[8..16].copy_from_slice(&address.to_le_bytes());
+    record[16..20].copy_from_slice(&entry_size.to_le_bytes());
+    record[20..24].copy_from_slice(&id.to_le_bytes());
+
+    unsafe {
+        let header_bytes = (*header_ptr).header_bytes;
+        let table_bytes = (*header_ptr).table_bytes;
+        let new_table_bytes = table_bytes + RECORD_SIZE as u32;
+        if header_bytes as u64 + new_table_bytes as u64 > capacity as u64 {
+            return false;
+        }
+
+        let table_start = (header + header_bytes as u64) as *mut u8;
+        core::ptr::copy_nonoverlapping(
+            record.as_ptr(),
+            table_start.add(table_bytes as usize),
+            RECORD_SIZE,
+        );
+
+        (*header_ptr).table_bytes = new_table_bytes;
+        (*header_ptr).table_entries += 1;
+        (*header_ptr).table_checksum = ip_checksum(core::slice::from_raw_parts(
+            table_start,
+            new_table_bytes as usize,
+        )) as u32;
+        (*header_ptr).header_checksum = 0;
+        (*header_ptr).header_checksum = ip_checksum(core::slice::from_raw_parts(
+            header as *const u8,
+            header_bytes as usize,
+        )) as u32;
+    }
+
+    true
+}
+
+/// Internet checksum (RFC 1071) as used by the coreboot table
+fn ip_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for (i, &byte) in data.iter().enumerate() {
+        let value = if i & 1 != 0 {
+            (byte as u32) << 8
+        } else {
+            byte as u32
+        };
+        sum += value;
+        if sum > 0xFFFF {
+            sum = (sum + (sum >> 16)) & 0xFFFF;
+        }
+    }
+    !(sum as u16)
 }
 
 /// Parse ACPI RSDP pointer
@@ -655,6 +915,54 @@ fn parse_acpi_rsdp(record_bytes: &[u8], info: &mut CorebootInfo) {
     log::debug!("ACPI RSDP: {:#x}", rsdp_pointer);
 }
 
+/// Parse boot media parameters
+fn parse_boot_media(record_bytes: &[u8], info: &mut CorebootInfo) {
+    let Ok((params, _)) = CbBootMediaParams::read_from_prefix(record_bytes) else {
+        log::warn!("Failed to parse boot media record");
+        return;
+    };
+    let boot_media = BootMediaInfo {
+        cbfs_offset: params.cbfs_offset,
+        cbfs_size: params.cbfs_size,
+        boot_media_size: params.boot_media_size,
+    };
+    info.boot_media = Some(boot_media);
+
+    log::debug!(
+        "Boot media: CBFS at {:#x}, {} bytes, media {} bytes",
+        boot_media.cbfs_offset,
+        boot_media.cbfs_size,
+        boot_media.boot_media_size
+    );
+}
+
+/// Parse the SMMSTORE v2 record
+fn parse_smmstore(record_bytes: &[u8], info: &mut CorebootInfo) {
+    let Ok((record, _)) = CbSmmstoreV2::read_from_prefix(record_bytes) else {
+        log::warn!("Failed to parse SMMSTORE v2 record");
+        return;
+    };
+    let smmstore = SmmstoreInfo {
+        num_blocks: record.num_blocks,
+        block_size: record.block_size,
+        com_buffer: record.com_buffer,
+        com_buffer_size: record.com_buffer_size,
+        apm_cmd: record.apm_cmd,
+        mmap_addr: match record.mmap_addr {
+            0 => record.mmap_addr_deprecated as u64,
+            addr => addr,
+        },
+    };
+    info.smmstore = Some(smmstore);
+
+    log::debug!(
+        "SMMSTORE v2: {} blocks of {} bytes, buffer {:#x}",
+        smmstore.num_blocks,
+        smmstore.block_size,
+        smmstore.com_buffer
+    );
+}
+
 /// Parse CBMEM console reference
 ///
 /// This function is safe - it uses zerocopy to parse the CBMEM ref struct.
@@ -669,10 +977,23 @@ fn parse_cbmem_console(record_bytes: &[u8], info: &mut CorebootInfo) {
     log::debug!("CBMEM console: {:#x}", cbmem_addr);
 }
 
+/// Parse timestamp table record
+fn parse_timestamps(record_bytes: &[u8], info: &mut CorebootInfo) {
+    let Ok((cbmem_ref, _)) = CbCbmemRef::read_from_prefix(record_bytes) else {
+        log::warn!("Failed to parse timestamps record");
+        return;
+    };
+    let cbmem_addr = cbmem_ref.cbmem_addr;
+    info.timestamps = Some(cbmem_addr);
+
+    log::debug!("Timestamp table: {:#x}", cbmem_addr);
+}
+
 /// Parse CBMEM entry record
 ///
 /// CBMEM entries provide pointers to various firmware data regions by ID.
-/// We specifically look for SMBIOS tables (CBMEM_ID_SMBIOS).
+/// We specifically look for SMBIOS tables (CBMEM_ID_SMBIOS), the romstage
+/// handoff (CBMEM_ID_ROMSTAGE_INFO) and the VPD copy (CBMEM_ID_VPD).
 ///
 /// This function is safe - it uses zerocopy to parse the CBMEM entry struct.
 fn parse_cbmem_entry(record_bytes: &[u8], info: &mut CorebootInfo) {
@@ -694,6 +1015,14 @@ fn parse_cbmem_entry(record_bytes: &[u8], info: &mut CorebootInfo) {
                 entry_size
             );
         }
+        cbmem_ids::CBMEM_ID_ROMSTAGE_INFO => {
+            info.romstage_handoff = Some(address);
+            log::debug!("Romstage handoff at {:#x}", address);
+        }
+        cbmem_ids::CBMEM_ID_VPD => {
+            info.vpd = Some(address);
+            log::debug!("VPD at {:#x} (size {} bytes)", address, entry_size);
+        }
         _ => {
             // Log other CBMEM entries at trace level for debugging
             log::trace!(
@@ -705,3 +1034,299 @@ fn parse_cbmem_entry(record_bytes: &[u8], info: &mut CorebootInfo) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{put_u16, put_u32, put_u64};
+
+    /// A coreboot record with the given tag and payload
+    fn record(tag: u32, payload: &[u8]) -> std::vec::Vec<u8> {
+        let mut record = std::vec![0u8; 8];
+        put_u32(&mut record, 0, tag);
+        record.extend_from_slice(payload);
+        record.resize(record.len().next_multiple_of(4), 0);
+        let size = record.len() as u32;
+        put_u32(&mut record, 4, size);
+        record
+    }
+
+    /// A coreboot table: header followed by the records
+    fn table_bytes(records: &[std::vec::Vec<u8>]) -> std::vec::Vec<u8> {
+        let body: std::vec::Vec<u8> = records.concat();
+        let mut bytes = std::vec![0u8; 24];
+        bytes[0..4].copy_from_slice(b"LBIO");
+        put_u32(&mut bytes, 4, 24);
+        put_u32(&mut bytes, 12, body.len() as u32);
+        put_u32(&mut bytes, 20, records.len() as u32);
+        put_u32(&mut bytes, 16, ip_checksum(&body) as u32);
+        let header_checksum = ip_checksum(&bytes);
+        put_u32(&mut bytes, 8, header_checksum as u32);
+        bytes.extend(body);
+        bytes
+    }
+
+    /// Lay out a coreboot table in 8-byte aligned memory
+    ///
+    /// The memory is leaked, so that forward records can point at it. It is
+    /// padded with the 4 KiB `find_header` scans, so a corrupted table can't
+    /// be mistaken for another test's table next to it on the heap.
+    fn table(records: &[std::vec::Vec<u8>]) -> *const u8 {
+        let bytes = table_bytes(records);
+        let mut words: std::vec::Vec<u64> = bytes
+            .chunks(8)
+            .map(|chunk| {
+                let mut word = [0u8; 8];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u64::from_le_bytes(word)
+            })
+            .collect();
+        words.resize(words.len() + 0x1000 / 8, 0);
+        std::boxed::Box::leak(words.into_boxed_slice()).as_ptr() as *const u8
+    }
+
+    fn memory_record(ranges: &[(u64, u64, u32)]) -> std::vec::Vec<u8> {
+        let mut payload = std::vec![0u8; ranges.len() * 20];
+        for (i, &(start, size, mem_type)) in ranges.iter().enumerate() {
+            put_u64(&mut payload, i * 20, start);
+            put_u64(&mut payload, i * 20 + 8, size);
+            put_u32(&mut payload, i * 20 + 16, mem_type);
+        }
+        record(tags::CB_TAG_MEMORY, &payload)
+    }
+
+    fn serial_record() -> std::vec::Vec<u8> {
+        let mut payload = [0u8; 20];
+        put_u32(&mut payload, 0, 1);
+        put_u32(&mut payload, 4, 0x3f8);
+        put_u32(&mut payload, 8, 115200);
+        put_u32(&mut payload, 12, 1);
+        put_u32(&mut payload, 16, 1_843_200);
+        record(tags::CB_TAG_SERIAL, &payload)
+    }
+
+    fn u64_record(tag: u32, value: u64) -> std::vec::Vec<u8> {
+        record(tag, &value.to_le_bytes())
+    }
+
+    /// Records as coreboot writes them for QEMU q35 with 512 MiB of RAM
+    fn qemu_q35_records() -> std::vec::Vec<std::vec::Vec<u8>> {
+        // Masks, then orientation, flags and padding
+        let mut framebuffer = [0u8; 32];
+        put_u64(&mut framebuffer, 0, 0xfd00_0000);
+        put_u32(&mut framebuffer, 8, 1024);
+        put_u32(&mut framebuffer, 12, 768);
+        put_u32(&mut framebuffer, 16, 4096);
+        framebuffer[20..29].copy_from_slice(&[32, 16, 8, 8, 8, 0, 8, 24, 8]);
+
+        let mut smbios = [0u8; 16];
+        put_u64(&mut smbios, 0, 0x1ffb_6000);
+        put_u32(&mut smbios, 8, 0x800);
+        put_u32(&mut smbios, 12, cbmem_ids::CBMEM_ID_SMBIOS);
+
+        let mut handoff = [0u8; 16];
+        put_u64(&mut handoff, 0, 0x1ffb_f000);
+        put_u32(&mut handoff, 8, 0x1000);
+        put_u32(&mut handoff, 12, cbmem_ids::CBMEM_ID_ROMSTAGE_INFO);
+
+        // FMAP, CBFS offset and size, boot media size
+        let mut boot_media = [0u8; 32];
+        put_u64(&mut boot_media, 8, 0x200);
+        put_u64(&mut boot_media, 16, 0xff_fe00);
+        put_u64(&mut boot_media, 24, 0x100_0000);
+
+        // Blocks, block size, old mmap address, buffer, its size, APM
+        // command and padding, mmap address
+        let mut smmstore = [0u8; 32];
+        put_u32(&mut smmstore, 0, 4);
+        put_u32(&mut smmstore, 4, 0x1_0000);
+        put_u32(&mut smmstore, 12, 0x1ff9_0000);
+        put_u32(&mut smmstore, 16, 0x1_0000);
+        smmstore[20] = 0xed;
+        put_u64(&mut smmstore, 24, 0xffc0_0000);
+
+        std::vec![
+            memory_record(&[
+                (0x0, 0x1000, 16),
+                (0x1000, 0x9f000, 1),
+                (0xc0000, 0x1fee_8000, 1),
+                (0x1ffa_8000, 0x58000, 16),
+                (0xb000_0000, 0x1000_0000, 2),
+            ]),
+            record(tags::CB_TAG_VERSION, b"4.22-1234-gdeadbeef\0"),
+            record(tags::CB_TAG_BUILD, b"Fri Oct 16 12:00:00 UTC 2026\0"),
+            record(
+                tags::CB_TAG_MAINBOARD,
+                b"\x00\x0aEmulation\0QEMU x86 q35/ich9\0"
+            ),
+            serial_record(),
+            record(tags::CB_TAG_FRAMEBUFFER, &framebuffer),
+            u64_record(tags::CB_TAG_CBMEM_CONSOLE, 0x1ffd_d000),
+            u64_record(tags::CB_TAG_ACPI_RSDP, 0xf_6e10),
+            record(tags::CB_TAG_CBMEM_ENTRY, &smbios),
+            record(tags::CB_TAG_CBMEM_ENTRY, &handoff),
+            record(tags::CB_TAG_TIMESTAMPS, &0x1ffd_c000u64.to_le_bytes()),
+            record(tags::CB_TAG_BOOT_MEDIA_PARAMS, &boot_media),
+            record(tags::CB_TAG_SMMSTOREV2, &smmstore),
+            record(tags::CB_TAG_CMOS_OPTION_TABLE, &12u32.to_le_bytes()),
+        ]
+    }
+
+    #[test]
+    fn parses_qemu_table() {
+        let ptr = table(&qemu_q35_records());
+        let info = unsafe { parse(ptr) };
+
+        assert_eq!(info.table_header, Some(ptr as u64));
+        let regions: std::vec::Vec<_> = info
+            .memory_map
+            .iter()
+            .map(|region| (region.start, region.size, region.region_type))
+            .collect();
+        assert_eq!(
+            regions,
+            [
+                (0x0, 0x1000, MemoryType::Table),
+                (0x1000, 0x9f000, MemoryType::Ram),
+                (0xc0000, 0x1fee_8000, MemoryType::Ram),
+                (0x1ffa_8000, 0x58000, MemoryType::Table),
+                (0xb000_0000, 0x1000_0000, MemoryType::Reserved),
+            ]
+        );
+
+        assert_eq!(info.version.as_deref(), Some("4.22-1234-gdeadbeef"));
+        assert_eq!(info.build.as_deref(), Some("Fri Oct 16 12:00:00 UTC 2026"));
+        let mainboard = info.mainboard.unwrap();
+        assert_eq!(mainboard.vendor, "Emulation");
+        assert_eq!(mainboard.part_number, "QEMU x86 q35/ich9");
+        let serial = info.serial.unwrap();
+        assert_eq!((serial.serial_type, serial.baseaddr), (1, 0x3f8));
+        assert_eq!((serial.baud, serial.input_hertz), (115200, 1_843_200));
+
+        let fb = info.framebuffer.unwrap();
+        assert_eq!(fb.physical_address, 0xfd00_0000);
+        assert_eq!((fb.x_resolution, fb.y_resolution), (1024, 768));
+        assert_eq!((fb.bytes_per_line, fb.bits_per_pixel), (4096, 32));
+        assert_eq!(
+            (fb.red_mask_pos, fb.green_mask_pos, fb.blue_mask_pos),
+            (16, 8, 0)
+        );
+
+        assert_eq!(info.cbmem_console, Some(0x1ffd_d000));
+        assert_eq!(info.acpi_rsdp, Some(0xf_6e10));
+        assert_eq!(info.smbios, Some(0x1ffb_6000));
+        assert_eq!(info.romstage_handoff, Some(0x1ffb_f000));
+        assert_eq!(info.timestamps, Some(0x1ffd_c000));
+        assert_eq!(
+            info.boot_media,
+            Some(BootMediaInfo {
+                cbfs_offset: 0x200,
+                cbfs_size: 0xff_fe00,
+                boot_media_size: 0x100_0000,
+            })
+        );
+        assert_eq!(
+            info.smmstore,
+            Some(SmmstoreInfo {
+                num_blocks: 4,
+                block_size: 0x1_0000,
+                com_buffer: 0x1ff9_0000,
+                com_buffer_size: 0x1_0000,
+                apm_cmd: 0xed,
+                mmap_addr: 0xffc0_0000,
+            })
+        );
+        assert!(info.cmos_option_table.is_some());
+    }
+
+    #[test]
+    fn follows_forward_record() {
+        // Low-memory stub table forwarding to the real one in CBMEM
+        let real = table(&qemu_q35_records());
+        let stub = table(&[u64_record(tags::CB_TAG_FORWARD, real as u64)]);
+        let info = unsafe { parse(stub) };
+
+        assert_eq!(info.table_header, Some(real as u64));
+        assert_eq!(info.memory_map.len(), 5);
+        assert_eq!(info.smbios, Some(0x1ffb_6000));
+    }
+
+    #[test]
+    fn stops_at_invalid_record() {
+        let mut bad = record(tags::CB_TAG_SERIAL, &[]);
+        put_u32(&mut bad, 4, 4);
+        let ptr = table(&[memory_record(&[(0x1000, 0x9f000, 1)]), bad, serial_record()]);
+        let info = unsafe { parse(ptr) };
+
+        assert_eq!(info.memory_map.len(), 1);
+        assert!(info.serial.is_none());
+    }
+
+    #[test]
+    fn parses_byte_slice() {
+        let mut bytes = table_bytes(&qemu_q35_records());
+        let info = parse_bytes(&bytes);
+        assert_eq!(info.memory_map.len(), 5);
+        assert_eq!(info.acpi_rsdp, Some(0xf_6e10));
+        assert!(info.table_header.is_none());
+
+        // Forward records are not followed
+        let info = parse_bytes(&table_bytes(&[u64_record(tags::CB_TAG_FORWARD, 0x1000)]));
+        assert!(info.memory_map.is_empty());
+
+        // Records reaching past the table end are dropped
+        let records = [serial_record(), memory_record(&[(0x1000, 0x9f000, 1)])];
+        let mut short = table_bytes(&records);
+        put_u32(&mut short, 12, records[0].len() as u32 + 8);
+        let info = parse_bytes(&short);
+        assert!(info.serial.is_some());
+        assert!(info.memory_map.is_empty());
+
+        // Tables longer than the slice are rejected as a whole
+        bytes.truncate(bytes.len() - 1);
+        assert!(parse_bytes(&bytes).memory_map.is_empty());
+        assert!(parse_bytes(b"LBIO").memory_map.is_empty());
+    }
+
+    #[test]
+    fn empty_without_header() {
+        // No "LBIO" anywhere in the first 4 KiB from the pointer, the gaps
+        // are left to the fallback module
+        let memory = std::vec![0u64; 1024];
+        let info = unsafe { parse(memory.as_ptr() as *const u8) };
+
+        assert!(info.table_header.is_none());
+        assert!(info.memory_map.is_empty());
+        assert!(info.serial.is_none());
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        // A table changed after it was written isn't trusted
+        let ptr = table(&qemu_q35_records());
+        unsafe { *(ptr as *mut u8).add(24 + 8) ^= 1 };
+        let info = unsafe { parse(ptr) };
+        assert!(info.table_header.is_none());
+        assert!(info.memory_map.is_empty());
+
+        let ptr = table(&qemu_q35_records());
+        unsafe { *(ptr as *mut u8).add(20) ^= 1 };
+        assert!(unsafe { parse(ptr) }.table_header.is_none());
+    }
+
+    #[test]
+    fn ip_checksum_rfc1071_example() {
+        // RFC 1071 section 3 sums to 0xddf2 in network byte order; coreboot
+        // sums little-endian words, so the result is byte swapped
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(ip_checksum(&data), !0xf2dd);
+
+        // A header carrying its own checksum sums to zero
+        let mut header = [0u8; 24];
+        header[0..4].copy_from_slice(b"LBIO");
+        put_u16(&mut header, 4, 24);
+        let checksum = ip_checksum(&header);
+        put_u16(&mut header, 8, checksum);
+        assert_eq!(ip_checksum(&header), 0);
+    }
+}
@@ -0,0 +1,343 @@
+//! Vital Product Data (VPD)
+//!
+//! Chromebooks and some servers keep board settings as key-value pairs in
+//! the RO_VPD and RW_VPD flash regions. coreboot copies both regions into
+//! CBMEM, so they are read from memory without a flash driver.
+//!
+//! CrabEFI reads these keys:
+//! - `crabefi_default_boot`: boot entry selected by default, matched against
+//!   the entry's name or its Boot Loader Interface ID
+//! - `crabefi_boot_timeout`: boot menu timeout in seconds, 0 to wait for a key
+//! - `crabefi_boot_priority`: order of the boot device classes, e.g.
+//!   `usb,nvme`; see [`boot_priority`](crate::boot_priority)
+//! - `crabefi_boot_paths`: bootloaders looked for on an ESP before the
+//!   default ones; see [`boot_paths`](crate::boot_paths)
+//! - `crabefi_boot_slot_max_tries`, `crabefi_boot_slot_primary`,
+//!   `crabefi_boot_slot_fallback`: A/B boot fallback; see
+//!   [`boot_slots`](crate::boot_slots)
+//! - `crabefi_usb_quirks`: USB mass storage workarounds per device; see
+//!   [`quirks`](crate::drivers::usb::quirks)
+//! - `crabefi_pci_quirks`: PCI functions enumeration and the drivers leave
+//!   alone; see [`quirks`](crate::drivers::pci::quirks)
+//! - `crabefi_pci_rescan_delay`: milliseconds after which PCI is enumerated
+//!   again, for Thunderbolt devices; see [`pci`](crate::drivers::pci)
+//! - `crabefi_log_level`: log levels, e.g. `info,drivers::nvme=trace`; see
+//!   [`logger`](crate::logger)
+//! - `crabefi_display_rotation`, `crabefi_display_scale`: screen rotation
+//!   and font scale; see [`display`](crate::display)
+//! - `crabefi_keyboard_layout`: keyboard layout, e.g. `de`; see
+//!   [`keyboard_layout`](crate::drivers::keyboard_layout)
+//! - `crabefi_hotkeys`: early boot hotkeys and their scan window, e.g.
+//!   `timeout=2000,setup=f1`; see [`hotkey`](crate::hotkey)
+//! - `crabefi_bmc_console`: BMC interface for IPMI Serial-over-LAN, e.g.
+//!   `uart:0x2f8`; see [`bmc_console`](crate::drivers::bmc_console)
+//! - `crabefi_strict_nx`: `1` maps memory outside code allocations
+//!   non-executable; see [`protect`](crate::arch::x86_64::protect)
+//!
+//! A key in RW_VPD overrides the same key in RO_VPD. Boards without VPD can
+//! keep these keys as enum options in the
+//! [CMOS option table](super::cmos_options).
+//!
+//! Reference: coreboot/src/drivers/vpd/vpd.c, google/vpd lib/vpd_decode.c
+
+use spin::Mutex;
+
+use crate::menu::BootMenu;
+
+/// Magic of the CBMEM VPD copy ("CROS")
+const CBMEM_MAGIC: u32 = 0x43524f53;
+
+/// Size of the CBMEM VPD copy header: magic, version, RO size, RW size
+const CBMEM_HEADER_SIZE: usize = 16;
+
+/// Largest VPD region accepted, to bound a corrupt size
+const MAX_REGION_SIZE: usize = 64 * 1024;
+
+/// Entry types
+const TYPE_TERMINATOR: u8 = 0x00;
+const TYPE_STRING: u8 = 0x01;
+const TYPE_INFO: u8 = 0xFE;
+const TYPE_IMPLICIT_TERMINATOR: u8 = 0xFF;
+
+/// Key of the default boot entry
+pub const KEY_DEFAULT_BOOT: &str = "crabefi_default_boot";
+
+/// Key of the boot menu timeout
+pub const KEY_BOOT_TIMEOUT: &str = "crabefi_boot_timeout";
+
+/// Key of the boot device priority
+pub const KEY_BOOT_PRIORITY: &str = "crabefi_boot_priority";
+
+/// Key of the extra bootloader paths
+pub const KEY_BOOT_PATHS: &str = "crabefi_boot_paths";
+
+/// Key of the failed boots before the boot slots switch
+pub const KEY_BOOT_SLOT_MAX_TRIES: &str = "crabefi_boot_slot_max_tries";
+
+/// Key of the primary boot slot entry
+pub const KEY_BOOT_SLOT_PRIMARY: &str = "crabefi_boot_slot_primary";
+
+/// Key of the fallback boot slot entry
+pub const KEY_BOOT_SLOT_FALLBACK: &str = "crabefi_boot_slot_fallback";
+
+/// Key of the USB mass storage quirks
+pub const KEY_USB_QUIRKS: &str = "crabefi_usb_quirks";
+
+/// Key of the PCI quirks
+pub const KEY_PCI_QUIRKS: &str = "crabefi_pci_quirks";
+
+/// Key of the delay before PCI is enumerated again
+pub const KEY_PCI_RESCAN_DELAY: &str = "crabefi_pci_rescan_delay";
+
+/// Key of the log levels
+pub const KEY_LOG_LEVEL: &str = "crabefi_log_level";
+
+/// Key of the display rotation
+pub const KEY_DISPLAY_ROTATION: &str = "crabefi_display_rotation";
+
+/// Key of the font scale
+pub const KEY_DISPLAY_SCALE: &str = "crabefi_display_scale";
+
+/// Key of the keyboard layout
+pub const KEY_KEYBOARD_LAYOUT: &str = "crabefi_keyboard_layout";
+
+/// Key of the early boot hotkeys
+pub const KEY_HOTKEYS: &str = "crabefi_hotkeys";
+
+/// Key of the BMC console interface
+pub const KEY_BMC_CONSOLE: &str = "crabefi_bmc_console";
+
+/// Key of the non-executable data memory policy
+pub const KEY_STRICT_NX: &str = "crabefi_strict_nx";
+
+/// The RO and RW VPD regions
+#[derive(Clone, Copy)]
+pub struct Vpd<'a> {
+    ro: &'a [u8],
+    rw: &'a [u8],
+}
+
+impl<'a> Vpd<'a> {
+    /// Split the CBMEM VPD copy into its regions
+    pub fn parse(cbmem: &'a [u8]) -> Option<Self> {
+        let word = |index: usize| {
+            let bytes = cbmem.get(index * 4..index * 4 + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().ok()?) as usize)
+        };
+        if word(0)? != CBMEM_MAGIC as usize {
+            return None;
+        }
+        let (ro_size, rw_size) = (word(2)?, word(3)?);
+        if ro_size > MAX_REGION_SIZE || rw_size > MAX_REGION_SIZE {
+            return None;
+        }
+        let data = cbmem.get(CBMEM_HEADER_SIZE..CBMEM_HEADER_SIZE + ro_size + rw_size)?;
+        let (ro, rw) = data.split_at(ro_size);
+        Some(Vpd { ro, rw })
+    }
+
+    /// Value of `key`, from RW_VPD if set there, otherwise from RO_VPD
+    pub fn find(&self, key: &str) -> Option<&'a [u8]> {
+        let lookup = |region| {
+            Entries { data: region }
+                .find(|&(name, _)| name == key.as_bytes())
+                .map(|(_, value)| value)
+        };
+        lookup(self.rw).or_else(|| lookup(self.ro))
+    }
+
+    /// Value of `key` as text
+    pub fn find_str(&self, key: &str) -> Option<&'a str> {
+        core::str::from_utf8(self.find(key)?).ok()
+    }
+}
+
+/// Key-value pairs of a VPD 2.0 region
+struct Entries<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Entries<'a> {
+    /// Read a length-prefixed field; the length is a big-endian base-128
+    /// number with the top bit of each byte set if another byte follows
+    fn field(&mut self) -> Option<&'a [u8]> {
+        let mut len = 0usize;
+        loop {
+            let (&byte, rest) = self.data.split_first()?;
+            self.data = rest;
+            len = len.checked_mul(128)? | (byte & 0x7F) as usize;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let field = self.data.get(..len)?;
+        self.data = &self.data[len..];
+        Some(field)
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (&entry_type, rest) = self.data.split_first()?;
+            self.data = rest;
+            match entry_type {
+                TYPE_STRING | TYPE_INFO => {
+                    let entry = self.field().zip(self.field());
+                    if entry.is_none() {
+                        self.data = &[];
+                    }
+                    if entry_type == TYPE_STRING {
+                        return entry;
+                    }
+                }
+                TYPE_TERMINATOR | TYPE_IMPLICIT_TERMINATOR => {
+                    self.data = &[];
+                    return None;
+                }
+                _ => {
+                    log::debug!("VPD: unknown entry type {:#x}", entry_type);
+                    self.data = &[];
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// VPD of this board, if coreboot provided it
+static VPD: Mutex<Option<Vpd<'static>>> = Mutex::new(None);
+
+/// Use the VPD copy coreboot left in CBMEM at `address`
+pub fn init(address: u64) {
+    let header = unsafe { core::slice::from_raw_parts(address as *const u8, CBMEM_HEADER_SIZE) };
+    let size = header[8..]
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]) as usize)
+        .fold(CBMEM_HEADER_SIZE, |size, region| {
+            size.saturating_add(region.min(MAX_REGION_SIZE))
+        });
+    let data = unsafe { core::slice::from_raw_parts(address as *const u8, size) };
+    match Vpd::parse(data) {
+        Some(vpd) => {
+            log::info!("VPD: {} bytes RO, {} bytes RW", vpd.ro.len(), vpd.rw.len());
+            *VPD.lock() = Some(vpd);
+        }
+        None => log::warn!("VPD at {:#x} is invalid", address),
+    }
+}
+
+/// Value of `key` as text from VPD, or else from the CMOS option of the
+/// same name
+pub fn find_str(key: &str) -> Option<&'static str> {
+    let vpd = *VPD.lock();
+    vpd.and_then(|vpd| vpd.find_str(key))
+        .or_else(|| super::cmos_options::find_str(key))
+}
+
+/// Apply the log levels stored in VPD
+pub fn apply_log_settings() {
+    if let Some(spec) = find_str(KEY_LOG_LEVEL) {
+        match crate::logger::apply_spec(spec) {
+            Ok(()) => log::info!("VPD: log levels {}", spec),
+            Err(e) => log::warn!("VPD: invalid log levels {:?}: {}", spec, e),
+        }
+    }
+}
+
+/// Apply the boot settings stored in VPD to the boot menu
+pub fn apply_boot_settings(menu: &mut BootMenu) {
+    if let Some(target) = find_str(KEY_DEFAULT_BOOT) {
+        let index = (0..menu.entry_count()).find(|&index| {
+            menu.get_entry(index)
+                .is_some_and(|entry| entry.name == target || entry.loader_id() == target)
+        });
+        match index {
+            Some(index) => {
+                log::info!("VPD: default boot entry {}", target);
+                menu.set_selected(index);
+            }
+            None => log::warn!("VPD: default boot entry {} not found", target),
+        }
+    }
+
+    if let Some(timeout) = find_str(KEY_BOOT_TIMEOUT) {
+        match timeout.trim().parse() {
+            Ok(seconds) => {
+                log::info!("VPD: boot timeout {} seconds", seconds);
+                menu.set_timeout(seconds);
+            }
+            Err(_) => log::warn!("VPD: invalid boot timeout {:?}", timeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode string entries as a VPD 2.0 region
+    fn region(entries: &[(&str, &str)]) -> std::vec::Vec<u8> {
+        let mut data = std::vec::Vec::new();
+        for (key, value) in entries {
+            data.push(TYPE_STRING);
+            for field in [key.as_bytes(), value.as_bytes()] {
+                if field.len() >= 128 {
+                    data.push(0x80 | (field.len() >> 7) as u8);
+                }
+                data.push((field.len() & 0x7F) as u8);
+                data.extend_from_slice(field);
+            }
+        }
+        data.push(TYPE_TERMINATOR);
+        data
+    }
+
+    fn cbmem(ro: &[u8], rw: &[u8]) -> std::vec::Vec<u8> {
+        let mut data = std::vec::Vec::new();
+        for word in [CBMEM_MAGIC, 1, ro.len() as u32, rw.len() as u32] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.extend_from_slice(ro);
+        data.extend_from_slice(rw);
+        data
+    }
+
+    #[test]
+    fn lookup() {
+        let long = "x".repeat(200);
+        let mut ro = std::vec![TYPE_INFO, 8];
+        ro.extend_from_slice(b"gVpdInfo");
+        ro.extend_from_slice(&[4, 0, 0, 0, 0]);
+        ro.extend(region(&[
+            ("serial_number", "ABC123"),
+            (KEY_BOOT_TIMEOUT, "5"),
+            ("long", &long),
+        ]));
+        let rw = region(&[(KEY_BOOT_TIMEOUT, "0")]);
+        let data = cbmem(&ro, &rw);
+
+        let vpd = Vpd::parse(&data).unwrap();
+        assert_eq!(vpd.find_str("serial_number"), Some("ABC123"));
+        assert_eq!(vpd.find_str(KEY_BOOT_TIMEOUT), Some("0"));
+        assert_eq!(vpd.find_str("long"), Some(long.as_str()));
+        assert_eq!(vpd.find_str("gVpdInfo"), None);
+        assert_eq!(vpd.find_str("missing"), None);
+    }
+
+    #[test]
+    fn reject_corrupt() {
+        let ro = region(&[("key", "value")]);
+        let mut data = cbmem(&ro, &[]);
+        assert!(Vpd::parse(&data[..data.len() - 1]).is_none());
+
+        // A value running past the region ends the lookup
+        let truncated = cbmem(&ro[..ro.len() - 3], &[]);
+        assert_eq!(Vpd::parse(&truncated).unwrap().find("key"), None);
+
+        data[0] ^= 1;
+        assert!(Vpd::parse(&data).is_none());
+    }
+}
@@ -0,0 +1,427 @@
+//! Crash Reporting
+//!
+//! CPU exceptions and Rust panics are reported with a register dump, the
+//! faulting RIP resolved to a module and section, and a frame-pointer based
+//! stack trace. The report is written to the serial port, the CBMEM console
+//! and a dedicated CBMEM region so that crashes on headless machines can be
+//! examined after the fact, e.g. with `cbmem -r 43525348`.
+//!
+//! The crash region is added to the CBMEM IMD by [`init`] and recorded in the
+//! coreboot table. It starts with a [`CrashRegionHeader`] followed by the
+//! plain text of the last report.
+//!
+//! Reporting must work no matter what state the firmware is in, so it takes
+//! no locks (the serial lock is forcibly released) and guards against faults
+//! raised while a report is being written.
+
+use crate::arch::x86_64::idt::{self, ExceptionFrame};
+use crate::arch::x86_64::{read_cr3, stack};
+use crate::coreboot::cbmem_console;
+use crate::coreboot::imd::Imd;
+use crate::coreboot::tables::{self, CorebootInfo};
+use crate::drivers::serial as serial_driver;
+use crate::efi::allocator::{self, MemoryType, PAGE_SIZE};
+use crate::efi::pool_guard;
+use crate::state;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// CBMEM ID of the crash region (ASCII "CRSH")
+pub const CBMEM_ID_CRASH: u32 = 0x4352_5348;
+
+/// Size of the crash region including its header
+const CRASH_REGION_SIZE: u32 = 16 * 1024;
+
+/// Signature at the start of the crash region
+const CRASH_SIGNATURE: [u8; 4] = *b"CRSH";
+
+/// Maximum number of stack frames in a backtrace
+const MAX_FRAMES: usize = 32;
+
+/// Crash region header
+#[repr(C)]
+pub struct CrashRegionHeader {
+    /// [`CRASH_SIGNATURE`]
+    pub signature: [u8; 4],
+    /// Number of report bytes following the header
+    pub length: u32,
+}
+
+/// Address of the crash region (0 = not available)
+static CRASH_REGION: AtomicU64 = AtomicU64::new(0);
+
+/// Set while a crash report is being written
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+// Linker symbols for section boundaries
+unsafe extern "C" {
+    static _text_start: u8;
+    static _text_end: u8;
+    static _rodata_start: u8;
+    static _rodata_end: u8;
+    static _stack_bottom: u8;
+    static _stack_top: u8;
+}
+
+/// Set up the CBMEM crash region
+///
+/// Must be called after the EFI allocator is initialized, so the region can
+/// be reserved in the memory map.
+pub fn init(cb_info: &CorebootInfo) {
+    let Some(imd) = (unsafe { Imd::find(&cb_info.memory_map) }) else {
+        log::debug!("Crash report: CBMEM IMD not found, using console only");
+        return;
+    };
+
+    let Some(addr) = imd.next_entry_address(CRASH_REGION_SIZE) else {
+        log::warn!("Crash report: no room in CBMEM for crash region");
+        return;
+    };
+
+    let num_pages = (CRASH_REGION_SIZE as u64).div_ceil(PAGE_SIZE);
+    if addr % PAGE_SIZE != 0
+        || allocator::reserve_region(addr, num_pages, MemoryType::ReservedMemoryType).is_err()
+    {
+        log::warn!(
+            "Crash report: could not reserve crash region at {:#x}",
+            addr
+        );
+        return;
+    }
+
+    if unsafe { imd.add_entry(CBMEM_ID_CRASH, CRASH_REGION_SIZE) } != Some(addr) {
+        log::warn!("Crash report: failed to add CBMEM entry");
+        return;
+    }
+
+    if let Some(header) = cb_info.table_header
+        && !unsafe {
+            tables::append_cbmem_entry(header, &imd, CBMEM_ID_CRASH, addr, CRASH_REGION_SIZE)
+        }
+    {
+        log::warn!("Crash report: could not record crash region in coreboot table");
+    }
+
+    unsafe {
+        (addr as *mut CrashRegionHeader).write(CrashRegionHeader {
+            signature: CRASH_SIGNATURE,
+            length: 0,
+        });
+    }
+    CRASH_REGION.store(addr, Ordering::Release);
+
+    log::info!(
+        "Crash report region at {:#x} ({} bytes)",
+        addr,
+        CRASH_REGION_SIZE
+    );
+}
+
+/// Writer that sends crash output to every crash sink
+struct CrashWriter;
+
+impl Write for CrashWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial_driver::write_str(s);
+        cbmem_console::write_bytes(s.as_bytes());
+        append_to_region(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Append bytes to the crash region, truncating when it is full
+fn append_to_region(data: &[u8]) {
+    let addr = CRASH_REGION.load(Ordering::Acquire);
+    if addr == 0 {
+        return;
+    }
+
+    let header = addr as *mut CrashRegionHeader;
+    let capacity = CRASH_REGION_SIZE as usize - core::mem::size_of::<CrashRegionHeader>();
+
+    unsafe {
+        let length = (*header).length as usize;
+        let count = data.len().min(capacity.saturating_sub(length));
+        let body = (addr as *mut u8).add(core::mem::size_of::<CrashRegionHeader>());
+        core::ptr::copy_nonoverlapping(data.as_ptr(), body.add(length), count);
+        (*header).length = (length + count) as u32;
+    }
+}
+
+/// Begin a crash report
+///
+/// Returns `false` if a report is already in progress, i.e. the reporting
+/// code itself faulted.
+fn begin_report() -> bool {
+    if REPORTING.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+
+    // Whoever held the serial lock will never run again
+    unsafe { serial_driver::force_unlock() };
+
+    let addr = CRASH_REGION.load(Ordering::Acquire);
+    if addr != 0 {
+        unsafe { (*(addr as *mut CrashRegionHeader)).length = 0 };
+    }
+
+    true
+}
+
+/// Report a CPU exception
+pub fn report_exception(frame: &ExceptionFrame) {
+    if !begin_report() {
+        unsafe { serial_driver::force_unlock() };
+        let _ = writeln!(
+            CrashWriter,
+            "\n!!! {} at {:#x} while writing crash report",
+            idt::exception_name(frame.vector),
+            frame.rip
+        );
+        return;
+    }
+
+    let _ = write_exception(&mut CrashWriter, frame);
+}
+
+/// Report a Rust panic
+pub fn report_panic(info: &PanicInfo) {
+    if !begin_report() {
+        return;
+    }
+
+    let _ = write_panic(&mut CrashWriter, info);
+}
+
+fn write_exception(w: &mut impl Write, frame: &ExceptionFrame) -> fmt::Result {
+    writeln!(w)?;
+    writeln!(w, "==================== CPU EXCEPTION ====================")?;
+    writeln!(
+        w,
+        "Exception: {} (vector {}), error code {:#x}",
+        idt::exception_name(frame.vector),
+        frame.vector,
+        frame.error_code
+    )?;
+
+    write!(w, "RIP: {:#018x} ", frame.rip)?;
+    write_location(w, frame.rip)?;
+    writeln!(w)?;
+    writeln!(
+        w,
+        "CS: {:#06x}  SS: {:#06x}  RFLAGS: {:#018x}",
+        frame.cs, frame.ss, frame.rflags
+    )?;
+
+    writeln!(
+        w,
+        "RAX: {:#018x} RBX: {:#018x} RCX: {:#018x}",
+        frame.rax, frame.rbx, frame.rcx
+    )?;
+    writeln!(
+        w,
+        "RDX: {:#018x} RSI: {:#018x} RDI: {:#018x}",
+        frame.rdx, frame.rsi, frame.rdi
+    )?;
+    writeln!(
+        w,
+        "RBP: {:#018x} RSP: {:#018x} R8:  {:#018x}",
+        frame.rbp, frame.rsp, frame.r8
+    )?;
+    writeln!(
+        w,
+        "R9:  {:#018x} R10: {:#018x} R11: {:#018x}",
+        frame.r9, frame.r10, frame.r11
+    )?;
+    writeln!(
+        w,
+        "R12: {:#018x} R13: {:#018x} R14: {:#018x}",
+        frame.r12, frame.r13, frame.r14
+    )?;
+    writeln!(w, "R15: {:#018x}", frame.r15)?;
+    writeln!(
+        w,
+        "CR2: {:#018x} CR3: {:#018x}",
+        idt::read_cr2(),
+        read_cr3()
+    )?;
+
+    if frame.vector == 14 {
+        writeln!(
+            w,
+            "Page fault: {} {} {}",
+            if frame.error_code & 1 != 0 {
+                "PRESENT"
+            } else {
+                "NOT_PRESENT"
+            },
+            if frame.error_code & 2 != 0 {
+                "WRITE"
+            } else {
+                "READ"
+            },
+            if frame.error_code & 4 != 0 {
+                "USER"
+            } else {
+                "KERNEL"
+            }
+        )?;
+        if frame.error_code & 1 == 0 && pool_guard::is_guard_page(idt::read_cr2()) {
+            writeln!(w, "Hit a pool guard page: buffer overrun or underrun")?;
+        }
+    }
+
+    // An overflow faults on the guard page, and usually double faults
+    // while pushing the page fault frame
+    if matches!(frame.vector, 8 | 14) && stack::is_guard_page(idt::read_cr2()) {
+        writeln!(
+            w,
+            "STACK OVERFLOW: the boot stack ran into its guard page at {:#x}",
+            stack::guard_page()
+        )?;
+    }
+
+    write_stack(w, frame.rsp)?;
+    write_backtrace(w, frame.rbp)?;
+
+    writeln!(
+        w,
+        "========================================================"
+    )?;
+    writeln!(w, "System halted.")
+}
+
+fn write_panic(w: &mut impl Write, info: &PanicInfo) -> fmt::Result {
+    writeln!(w)?;
+    writeln!(w, "======================== PANIC ========================")?;
+    if let Some(location) = info.location() {
+        writeln!(
+            w,
+            "PANIC at {}:{}: {}",
+            location.file(),
+            location.line(),
+            info.message()
+        )?;
+    } else {
+        writeln!(w, "PANIC: {}", info.message())?;
+    }
+
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack));
+    }
+    write_backtrace(w, rbp)?;
+
+    writeln!(
+        w,
+        "========================================================"
+    )?;
+    writeln!(w, "System halted.")
+}
+
+/// Dump the top of the stack
+fn write_stack(w: &mut impl Write, rsp: u64) -> fmt::Result {
+    writeln!(w, "Stack:")?;
+    for i in 0..8u64 {
+        let addr = rsp + i * 16;
+        if !is_stack_address(addr) || !is_stack_address(addr + 8) {
+            break;
+        }
+        let (a, b) = unsafe { (*(addr as *const u64), *((addr + 8) as *const u64)) };
+        writeln!(w, "  {:#018x}: {:#018x} {:#018x}", addr, a, b)?;
+    }
+    Ok(())
+}
+
+/// Walk the frame pointer chain starting at `rbp`
+fn write_backtrace(w: &mut impl Write, mut rbp: u64) -> fmt::Result {
+    writeln!(w, "Backtrace:")?;
+    for i in 0..MAX_FRAMES {
+        if rbp == 0
+            || !rbp.is_multiple_of(8)
+            || !is_stack_address(rbp)
+            || !is_stack_address(rbp + 8)
+        {
+            break;
+        }
+
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+
+        write!(w, "  #{:<2} {:#018x} ", i, ret)?;
+        write_location(w, ret)?;
+        writeln!(w)?;
+
+        // Frames live at increasing addresses as we unwind
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    Ok(())
+}
+
+/// Check if an address lies within the firmware stack
+///
+/// Loaded images run on the firmware stack too, so this covers every frame
+/// we can sensibly unwind without risking another fault.
+fn is_stack_address(addr: u64) -> bool {
+    let bottom = unsafe { &_stack_bottom as *const u8 as u64 };
+    let top = unsafe { &_stack_top as *const u8 as u64 };
+    addr >= bottom && addr < top && !stack::is_guard_page(addr)
+}
+
+/// Describe the module and section an address belongs to
+fn write_location(w: &mut impl Write, addr: u64) -> fmt::Result {
+    let text = unsafe {
+        (
+            &_text_start as *const u8 as u64,
+            &_text_end as *const u8 as u64,
+        )
+    };
+    let rodata = unsafe {
+        (
+            &_rodata_start as *const u8 as u64,
+            &_rodata_end as *const u8 as u64,
+        )
+    };
+
+    if addr >= text.0 && addr < text.1 {
+        return write!(w, "[crabefi .text+{:#x}]", addr - text.0);
+    }
+    if addr >= rodata.0 && addr < rodata.1 {
+        return write!(w, "[crabefi .rodata+{:#x}]", addr - rodata.0);
+    }
+
+    let Some(fw_state) = state::try_get() else {
+        return write!(w, "[unknown]");
+    };
+
+    for image in fw_state.efi.loaded_images.iter() {
+        if image.image_size == 0
+            || addr < image.image_base
+            || addr - image.image_base >= image.image_size
+        {
+            continue;
+        }
+
+        let rva = addr - image.image_base;
+        let headers_len = image.image_size.min(PAGE_SIZE) as usize;
+        let headers =
+            unsafe { core::slice::from_raw_parts(image.image_base as *const u8, headers_len) };
+
+        write!(w, "[image@{:#x} ", image.image_base)?;
+        if let Some(name) = crate::pe::section_name(headers, rva as u32) {
+            let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            if let Ok(name) = core::str::from_utf8(&name[..len]) {
+                write!(w, "{} ", name)?;
+            }
+        }
+        return write!(w, "rva {:#x}]", rva);
+    }
+
+    write!(w, "[unknown]")
+}
@@ -0,0 +1,348 @@
+//! AES (FIPS 197) and the XTS mode (IEEE 1619)
+//!
+//! dm-crypt's default cipher, aes-xts-plain64, encrypts every sector of a
+//! LUKS volume as one XTS data unit. Decryption uses lookup tables, since
+//! it runs for every sector a bootloader reads from the volume.
+
+/// Multiply in GF(2^8) modulo x^8 + x^4 + x^3 + x + 1
+const fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1B } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+/// The S-box: multiplicative inverse followed by the affine transform
+const fn sbox() -> [u8; 256] {
+    // Powers and logarithms of the generator 3 give the inverses
+    let mut exp = [0u8; 256];
+    let mut log = [0u8; 256];
+    let mut power = 1u8;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = power;
+        log[power as usize] = i as u8;
+        power ^= gf_mul(power, 2);
+        i += 1;
+    }
+
+    let mut sbox = [0u8; 256];
+    let mut x = 0;
+    while x < 256 {
+        let inverse = if x == 0 {
+            0
+        } else {
+            exp[(255 - log[x] as usize) % 255]
+        };
+        sbox[x] = inverse
+            ^ inverse.rotate_left(1)
+            ^ inverse.rotate_left(2)
+            ^ inverse.rotate_left(3)
+            ^ inverse.rotate_left(4)
+            ^ 0x63;
+        x += 1;
+    }
+    sbox
+}
+
+const fn inverse_sbox() -> [u8; 256] {
+    let mut inverse = [0u8; 256];
+    let mut x = 0;
+    while x < 256 {
+        inverse[SBOX[x] as usize] = x as u8;
+        x += 1;
+    }
+    inverse
+}
+
+/// Round table: the S-box output of each byte multiplied by one column of
+/// (Inv)MixColumns, first row in the most significant byte
+const fn round_table(sbox: &[u8; 256], column: [u8; 4]) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut x = 0;
+    while x < 256 {
+        let s = sbox[x];
+        table[x] = u32::from_be_bytes([
+            gf_mul(s, column[0]),
+            gf_mul(s, column[1]),
+            gf_mul(s, column[2]),
+            gf_mul(s, column[3]),
+        ]);
+        x += 1;
+    }
+    table
+}
+
+const SBOX: [u8; 256] = sbox();
+const INVERSE_SBOX: [u8; 256] = inverse_sbox();
+const ENCRYPT_TABLE: [u32; 256] = round_table(&SBOX, [2, 1, 1, 3]);
+const DECRYPT_TABLE: [u32; 256] = round_table(&INVERSE_SBOX, [14, 9, 13, 11]);
+
+/// Largest number of round key words (AES-256)
+const MAX_ROUND_KEY_WORDS: usize = 60;
+
+/// An AES key schedule
+#[derive(Clone)]
+pub struct Aes {
+    encrypt_keys: [u32; MAX_ROUND_KEY_WORDS],
+    /// Round keys of the equivalent inverse cipher, in decryption order
+    decrypt_keys: [u32; MAX_ROUND_KEY_WORDS],
+    rounds: usize,
+}
+
+impl Aes {
+    /// Expand a 128, 192 or 256 bit key
+    pub fn new(key: &[u8]) -> Option<Self> {
+        let key_words = match key.len() {
+            16 | 24 | 32 => key.len() / 4,
+            _ => return None,
+        };
+        let rounds = key_words + 6;
+        let total = 4 * (rounds + 1);
+
+        let mut encrypt_keys = [0u32; MAX_ROUND_KEY_WORDS];
+        for (word, bytes) in encrypt_keys.iter_mut().zip(key.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let mut rcon = 1u8;
+        for i in key_words..total {
+            let mut temp = encrypt_keys[i - 1];
+            if i % key_words == 0 {
+                temp = sub_word(temp.rotate_left(8)) ^ ((rcon as u32) << 24);
+                rcon = gf_mul(rcon, 2);
+            } else if key_words > 6 && i % key_words == 4 {
+                temp = sub_word(temp);
+            }
+            encrypt_keys[i] = encrypt_keys[i - key_words] ^ temp;
+        }
+
+        let mut decrypt_keys = [0u32; MAX_ROUND_KEY_WORDS];
+        for round in 0..=rounds {
+            for column in 0..4 {
+                let word = encrypt_keys[4 * (rounds - round) + column];
+                decrypt_keys[4 * round + column] = if round == 0 || round == rounds {
+                    word
+                } else {
+                    inverse_mix_column(word)
+                };
+            }
+        }
+
+        Some(Aes {
+            encrypt_keys,
+            decrypt_keys,
+            rounds,
+        })
+    }
+
+    /// Encrypt one block in place
+    pub fn encrypt_block(&self, block: &mut [u8; 16]) {
+        let mut s = load(block, &self.encrypt_keys[..4]);
+        for round in 1..self.rounds {
+            let key = &self.encrypt_keys[4 * round..4 * round + 4];
+            s = core::array::from_fn(|c| {
+                ENCRYPT_TABLE[(s[c] >> 24) as usize]
+                    ^ ENCRYPT_TABLE[(s[(c + 1) % 4] >> 16) as usize & 0xFF].rotate_right(8)
+                    ^ ENCRYPT_TABLE[(s[(c + 2) % 4] >> 8) as usize & 0xFF].rotate_right(16)
+                    ^ ENCRYPT_TABLE[s[(c + 3) % 4] as usize & 0xFF].rotate_right(24)
+                    ^ key[c]
+            });
+        }
+        let key = &self.encrypt_keys[4 * self.rounds..4 * self.rounds + 4];
+        store(block, final_round(&SBOX, s, [0, 1, 2, 3]), key);
+    }
+
+    /// Decrypt one block in place
+    pub fn decrypt_block(&self, block: &mut [u8; 16]) {
+        let mut s = load(block, &self.decrypt_keys[..4]);
+        for round in 1..self.rounds {
+            let key = &self.decrypt_keys[4 * round..4 * round + 4];
+            s = core::array::from_fn(|c| {
+                DECRYPT_TABLE[(s[c] >> 24) as usize]
+                    ^ DECRYPT_TABLE[(s[(c + 3) % 4] >> 16) as usize & 0xFF].rotate_right(8)
+                    ^ DECRYPT_TABLE[(s[(c + 2) % 4] >> 8) as usize & 0xFF].rotate_right(16)
+                    ^ DECRYPT_TABLE[s[(c + 1) % 4] as usize & 0xFF].rotate_right(24)
+                    ^ key[c]
+            });
+        }
+        let key = &self.decrypt_keys[4 * self.rounds..4 * self.rounds + 4];
+        store(block, final_round(&INVERSE_SBOX, s, [0, 3, 2, 1]), key);
+    }
+}
+
+impl Drop for Aes {
+    fn drop(&mut self) {
+        self.encrypt_keys.fill(0);
+        self.decrypt_keys.fill(0);
+    }
+}
+
+/// Apply the S-box to each byte of a word
+fn sub_word(word: u32) -> u32 {
+    u32::from_be_bytes(word.to_be_bytes().map(|byte| SBOX[byte as usize]))
+}
+
+/// InvMixColumns of one column
+fn inverse_mix_column(word: u32) -> u32 {
+    // The decryption table applies the inverse S-box first, so undo it
+    let [a, b, c, d] = word.to_be_bytes().map(|byte| SBOX[byte as usize] as usize);
+    DECRYPT_TABLE[a]
+        ^ DECRYPT_TABLE[b].rotate_right(8)
+        ^ DECRYPT_TABLE[c].rotate_right(16)
+        ^ DECRYPT_TABLE[d].rotate_right(24)
+}
+
+/// Load a block as columns and add the first round key
+fn load(block: &[u8; 16], key: &[u32]) -> [u32; 4] {
+    core::array::from_fn(|c| {
+        u32::from_be_bytes([
+            block[4 * c],
+            block[4 * c + 1],
+            block[4 * c + 2],
+            block[4 * c + 3],
+        ]) ^ key[c]
+    })
+}
+
+/// Add the last round key and store the columns as a block
+fn store(block: &mut [u8; 16], s: [u32; 4], key: &[u32]) {
+    for (c, chunk) in block.chunks_exact_mut(4).enumerate() {
+        chunk.copy_from_slice(&(s[c] ^ key[c]).to_be_bytes());
+    }
+}
+
+/// Last round: (inverse) ShiftRows and S-box without MixColumns; `shift`
+/// gives the column offset each row takes its byte from
+fn final_round(sbox: &[u8; 256], s: [u32; 4], shift: [usize; 4]) -> [u32; 4] {
+    core::array::from_fn(|c| {
+        u32::from_be_bytes(core::array::from_fn(|row| {
+            let byte = s[(c + shift[row]) % 4].to_be_bytes()[row];
+            sbox[byte as usize]
+        }))
+    })
+}
+
+/// AES in XTS mode with the key split into data and tweak keys
+#[derive(Clone)]
+pub struct Xts {
+    data: Aes,
+    tweak: Aes,
+}
+
+impl Xts {
+    /// Set up XTS from a 256 or 512 bit key
+    pub fn new(key: &[u8]) -> Option<Self> {
+        if key.len() != 32 && key.len() != 64 {
+            return None;
+        }
+        let (data, tweak) = key.split_at(key.len() / 2);
+        Some(Xts {
+            data: Aes::new(data)?,
+            tweak: Aes::new(tweak)?,
+        })
+    }
+
+    /// Decrypt a data unit whose tweak is `sector` (plain64 IV)
+    ///
+    /// The length must be a multiple of the 16-byte block size.
+    pub fn decrypt_sector(&self, sector: u64, data: &mut [u8]) {
+        self.process(sector, data, Aes::decrypt_block);
+    }
+
+    /// Encrypt a data unit whose tweak is `sector` (plain64 IV)
+    ///
+    /// The length must be a multiple of the 16-byte block size.
+    pub fn encrypt_sector(&self, sector: u64, data: &mut [u8]) {
+        self.process(sector, data, Aes::encrypt_block);
+    }
+
+    fn process(&self, sector: u64, data: &mut [u8], cipher: fn(&Aes, &mut [u8; 16])) {
+        let mut tweak = [0u8; 16];
+        tweak[..8].copy_from_slice(&sector.to_le_bytes());
+        self.tweak.encrypt_block(&mut tweak);
+        let mut tweak = u128::from_le_bytes(tweak);
+
+        for chunk in data.chunks_exact_mut(16) {
+            let block: &mut [u8; 16] = chunk.try_into().unwrap();
+            let mask = tweak.to_le_bytes();
+            block.iter_mut().zip(&mask).for_each(|(b, m)| *b ^= m);
+            cipher(&self.data, block);
+            block.iter_mut().zip(&mask).for_each(|(b, m)| *b ^= m);
+
+            // Multiply the tweak by x in GF(2^128)
+            let carry = tweak >> 127;
+            tweak = (tweak << 1) ^ (carry * 0x87);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aes_known_answers() {
+        // FIPS 197 appendix C.1 and C.3
+        let plaintext = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+            0xee, 0xff,
+        ];
+        let key: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let cases = [
+            (
+                &key[..16],
+                [
+                    0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70,
+                    0xb4, 0xc5, 0x5a,
+                ],
+            ),
+            (
+                &key[..],
+                [
+                    0x8e, 0xa2, 0xb7, 0xca, 0x51, 0x67, 0x45, 0xbf, 0xea, 0xfc, 0x49, 0x90, 0x4b,
+                    0x49, 0x60, 0x89,
+                ],
+            ),
+        ];
+        for (key, ciphertext) in cases {
+            let aes = Aes::new(key).unwrap();
+            let mut block = plaintext;
+            aes.encrypt_block(&mut block);
+            assert_eq!(block, ciphertext);
+            aes.decrypt_block(&mut block);
+            assert_eq!(block, plaintext);
+        }
+        assert!(Aes::new(&key[..20]).is_none());
+    }
+
+    #[test]
+    fn xts_sector() {
+        let key: [u8; 64] = core::array::from_fn(|i| i as u8);
+        let plaintext: [u8; 512] = core::array::from_fn(|i| (i * 7) as u8);
+        let xts = Xts::new(&key).unwrap();
+
+        let mut sector = plaintext;
+        xts.encrypt_sector(5, &mut sector);
+        assert_eq!(
+            sector[..16],
+            [
+                0x35, 0xaf, 0x68, 0x69, 0x01, 0xa5, 0xcb, 0x6d, 0xf9, 0xc1, 0x80, 0xd9, 0x92, 0x26,
+                0xdf, 0xef
+            ]
+        );
+        assert_eq!(
+            sector[496..],
+            [
+                0xad, 0x77, 0xd4, 0xe6, 0x33, 0xd7, 0x77, 0x47, 0xe0, 0x39, 0x06, 0x65, 0xf9, 0xc0,
+                0x52, 0x26
+            ]
+        );
+        xts.decrypt_sector(5, &mut sector);
+        assert_eq!(sector, plaintext);
+    }
+}
@@ -0,0 +1,158 @@
+//! Cryptographic primitives
+//!
+//! Hashes, key derivation and ciphers needed to unlock encrypted drives.
+//! These are straightforward implementations that make no attempt at
+//! resisting side channels.
+
+pub mod aes;
+pub mod sha1;
+pub mod sha256;
+
+/// Largest block size of the supported hashes
+const MAX_BLOCK_SIZE: usize = 64;
+
+/// Largest output size of the supported hashes
+const MAX_OUTPUT_SIZE: usize = 32;
+
+/// A hash function
+pub trait Digest: Clone + Default {
+    /// Size of the blocks the hash processes, in bytes
+    const BLOCK_SIZE: usize;
+    /// Size of the hash value, in bytes
+    const OUTPUT_SIZE: usize;
+
+    /// Hash `data`
+    fn update(&mut self, data: &[u8]);
+
+    /// Write the hash value to the first [`Self::OUTPUT_SIZE`] bytes of `out`
+    fn finalize_into(self, out: &mut [u8]);
+}
+
+/// HMAC (RFC 2104) keyed with a fixed key
+#[derive(Clone)]
+pub struct Hmac<D: Digest> {
+    inner: D,
+    outer: D,
+}
+
+impl<D: Digest> Hmac<D> {
+    /// Start a MAC with `key`
+    pub fn new(key: &[u8]) -> Self {
+        let mut block = [0u8; MAX_BLOCK_SIZE];
+        if key.len() > D::BLOCK_SIZE {
+            let mut digest = D::default();
+            digest.update(key);
+            digest.finalize_into(&mut block);
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner = D::default();
+        let mut outer = D::default();
+        for (digest, pad) in [(&mut inner, 0x36u8), (&mut outer, 0x5C)] {
+            let mut padded = block;
+            padded.iter_mut().for_each(|byte| *byte ^= pad);
+            digest.update(&padded[..D::BLOCK_SIZE]);
+        }
+        block.fill(0);
+        Hmac { inner, outer }
+    }
+
+    /// Authenticate `data`
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    /// Write the MAC to the first `D::OUTPUT_SIZE` bytes of `out`
+    pub fn finalize_into(self, out: &mut [u8]) {
+        let mut inner = [0u8; MAX_OUTPUT_SIZE];
+        self.inner.finalize_into(&mut inner);
+        let mut outer = self.outer;
+        outer.update(&inner[..D::OUTPUT_SIZE]);
+        outer.finalize_into(out);
+    }
+}
+
+/// Derive `out.len()` bytes of key from `password` with PBKDF2 (RFC 8018)
+pub fn pbkdf2<D: Digest>(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    let prf = Hmac::<D>::new(password);
+    for (index, chunk) in out.chunks_mut(D::OUTPUT_SIZE).enumerate() {
+        let mut mac = prf.clone();
+        mac.update(salt);
+        mac.update(&(index as u32 + 1).to_be_bytes());
+        let mut u = [0u8; MAX_OUTPUT_SIZE];
+        mac.finalize_into(&mut u);
+
+        let mut t = u;
+        for _ in 1..iterations {
+            let mut mac = prf.clone();
+            mac.update(&u[..D::OUTPUT_SIZE]);
+            mac.finalize_into(&mut u);
+            t.iter_mut().zip(&u).for_each(|(t, u)| *t ^= u);
+        }
+        chunk.copy_from_slice(&t[..chunk.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sha1::Sha1;
+    use super::*;
+
+    #[test]
+    fn hmac_sha1() {
+        // RFC 2202 test cases 2 and 6
+        let mut out = [0u8; 20];
+        let mut mac = Hmac::<Sha1>::new(b"Jefe");
+        mac.update(b"what do ya want ");
+        mac.update(b"for nothing?");
+        mac.finalize_into(&mut out);
+        assert_eq!(
+            out,
+            [
+                0xef, 0xfc, 0xdf, 0x6a, 0xe5, 0xeb, 0x2f, 0xa2, 0xd2, 0x74, 0x16, 0xd5, 0xf1, 0x84,
+                0xdf, 0x9c, 0x25, 0x9a, 0x7c, 0x79
+            ]
+        );
+
+        let mut mac = Hmac::<Sha1>::new(&[0xAA; 80]);
+        mac.update(b"Test Using Larger Than Block-Size Key - Hash Key First");
+        mac.finalize_into(&mut out);
+        assert_eq!(
+            out,
+            [
+                0xaa, 0x4a, 0xe5, 0xe1, 0x52, 0x72, 0xd0, 0x0e, 0x95, 0x70, 0x56, 0x37, 0xce, 0x8a,
+                0x3b, 0x55, 0xed, 0x40, 0x21, 0x12
+            ]
+        );
+    }
+
+    #[test]
+    fn pbkdf2_sha1() {
+        // RFC 6070 test vectors
+        let mut out = [0u8; 20];
+        pbkdf2::<Sha1>(b"password", b"salt", 2, &mut out);
+        assert_eq!(
+            out,
+            [
+                0xea, 0x6c, 0x01, 0x4d, 0xc7, 0x2d, 0x6f, 0x8c, 0xcd, 0x1e, 0xd9, 0x2a, 0xce, 0x1d,
+                0x41, 0xf0, 0xd8, 0xde, 0x89, 0x57
+            ]
+        );
+
+        let mut out = [0u8; 25];
+        pbkdf2::<Sha1>(
+            b"passwordPASSWORDpassword",
+            b"saltSALTsaltSALTsaltSALTsaltSALTsalt",
+            4096,
+            &mut out,
+        );
+        assert_eq!(
+            out,
+            [
+                0x3d, 0x2e, 0xec, 0x4f, 0xe4, 0x1c, 0x84, 0x9b, 0x80, 0xc8, 0xd8, 0x36, 0x62, 0xc0,
+                0xe4, 0x4a, 0x8b, 0x29, 0x1a, 0x96, 0x4c, 0xf2, 0xf0, 0x70, 0x38
+            ]
+        );
+    }
+}
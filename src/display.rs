@@ -0,0 +1,370 @@
+//! Display rotation and font scaling
+//!
+//! On a panel mounted in portrait the firmware's text runs sideways, and on
+//! high-DPI screens the 8x16 font is tiny. Text drawn by the boot menu, the
+//! framebuffer log and the EFI text console therefore goes through a
+//! [`View`], which rotates the screen and scales the font by a whole
+//! number. GOP Blt rotates too, see
+//! [`graphics_output`](crate::efi::protocols::graphics_output).
+//!
+//! Both are read from variables under [`CRABEFI_VARIABLE_GUID`], or else
+//! from VPD:
+//! - `DisplayRotation` / `crabefi_display_rotation`: clockwise rotation in
+//!   degrees, `0`, `90`, `180` or `270`
+//! - `DisplayScale` / `crabefi_display_scale`: font scale from `1` to `4`,
+//!   or `auto` to pick one from the resolution
+//!
+//! The settings are read once VPD is available and again once the
+//! non-volatile variables are loaded.
+
+use spin::Mutex;
+
+use crate::coreboot::{FramebufferInfo, vpd};
+use crate::efi::runtime_services::{CRABEFI_VARIABLE_GUID, read_variable};
+use crate::framebuffer_console::{CHAR_HEIGHT, CHAR_WIDTH, Color, get_glyph};
+
+/// Variable holding the rotation
+const ROTATION_VARIABLE: &str = "DisplayRotation";
+
+/// Variable holding the font scale
+const SCALE_VARIABLE: &str = "DisplayScale";
+
+/// Largest font scale
+pub const MAX_SCALE: u32 = 4;
+
+/// Screen height per step of the automatic font scale
+const AUTO_SCALE_STEP: u32 = 720;
+
+/// Clockwise rotation of the screen contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Normal,
+    /// 90 degrees, for a panel mounted with its top on the left
+    Right,
+    Inverted,
+    /// 270 degrees, for a panel mounted with its top on the right
+    Left,
+}
+
+impl Rotation {
+    /// Parse a rotation in degrees
+    pub fn parse(degrees: &str) -> Option<Self> {
+        match degrees.trim() {
+            "0" => Some(Rotation::Normal),
+            "90" => Some(Rotation::Right),
+            "180" => Some(Rotation::Inverted),
+            "270" => Some(Rotation::Left),
+            _ => None,
+        }
+    }
+
+    /// Whether width and height trade places
+    pub fn is_sideways(&self) -> bool {
+        matches!(self, Rotation::Right | Rotation::Left)
+    }
+}
+
+/// How text is laid out on the framebuffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct View {
+    pub rotation: Rotation,
+    /// Font scale, at least 1
+    pub scale: u32,
+}
+
+/// The view set up by [`init`]
+static VIEW: Mutex<View> = Mutex::new(View::NORMAL);
+
+/// The current view
+pub fn view() -> View {
+    *VIEW.lock()
+}
+
+impl View {
+    /// Unrotated and unscaled
+    pub const NORMAL: View = View {
+        rotation: Rotation::Normal,
+        scale: 1,
+    };
+
+    /// Size of the rotated screen in pixels
+    pub fn size(&self, fb: &FramebufferInfo) -> (u32, u32) {
+        if self.rotation.is_sideways() {
+            (fb.y_resolution, fb.x_resolution)
+        } else {
+            (fb.x_resolution, fb.y_resolution)
+        }
+    }
+
+    /// Size of a character cell in pixels
+    pub fn cell(&self) -> (u32, u32) {
+        (CHAR_WIDTH * self.scale, CHAR_HEIGHT * self.scale)
+    }
+
+    /// Columns and rows of text that fit on the screen
+    pub fn grid(&self, fb: &FramebufferInfo) -> (u32, u32) {
+        let (width, height) = self.size(fb);
+        let (cell_width, cell_height) = self.cell();
+        (width / cell_width, height / cell_height)
+    }
+
+    /// Framebuffer position of the rotated screen's pixel (x, y)
+    pub fn physical(&self, fb: &FramebufferInfo, x: u32, y: u32) -> (u32, u32) {
+        let (width, height) = (fb.x_resolution, fb.y_resolution);
+        match self.rotation {
+            Rotation::Normal => (x, y),
+            Rotation::Right => (width.wrapping_sub(1).wrapping_sub(y), x),
+            Rotation::Inverted => (
+                width.wrapping_sub(1).wrapping_sub(x),
+                height.wrapping_sub(1).wrapping_sub(y),
+            ),
+            Rotation::Left => (y, height.wrapping_sub(1).wrapping_sub(x)),
+        }
+    }
+
+    /// Framebuffer rectangle (x, y, width, height) covering a rectangle of
+    /// the rotated screen
+    pub fn physical_rect(
+        &self,
+        fb: &FramebufferInfo,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> (u32, u32, u32, u32) {
+        let (fb_width, fb_height) = (fb.x_resolution, fb.y_resolution);
+        match self.rotation {
+            Rotation::Normal => (x, y, width, height),
+            Rotation::Right => (fb_width.saturating_sub(y + height), x, height, width),
+            Rotation::Inverted => (
+                fb_width.saturating_sub(x + width),
+                fb_height.saturating_sub(y + height),
+                width,
+                height,
+            ),
+            Rotation::Left => (y, fb_height.saturating_sub(x + width), height, width),
+        }
+    }
+
+    /// Fill a rectangle of the rotated screen
+    ///
+    /// # Safety
+    ///
+    /// The framebuffer must be accessible.
+    pub unsafe fn fill(
+        &self,
+        fb: &FramebufferInfo,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        color: Color,
+    ) {
+        let (x, y, width, height) = self.physical_rect(fb, x, y, width, height);
+        for y in y..y + height {
+            for x in x..x + width {
+                unsafe { fb.write_pixel(x, y, color.r, color.g, color.b) };
+            }
+        }
+    }
+
+    /// Draw character `c` in the cell at (`col`, `row`)
+    ///
+    /// # Safety
+    ///
+    /// The framebuffer must be accessible.
+    pub unsafe fn draw_char(
+        &self,
+        fb: &FramebufferInfo,
+        c: char,
+        col: u32,
+        row: u32,
+        fg: Color,
+        bg: Color,
+    ) {
+        let (cell_width, cell_height) = self.cell();
+        let (x_base, y_base) = (col * cell_width, row * cell_height);
+        let glyph = get_glyph(c);
+        for glyph_row in 0..CHAR_HEIGHT {
+            let bits = glyph[glyph_row as usize];
+            for glyph_col in 0..CHAR_WIDTH {
+                let color = if (bits >> (7 - glyph_col)) & 1 != 0 {
+                    fg
+                } else {
+                    bg
+                };
+                let x = x_base + glyph_col * self.scale;
+                let y = y_base + glyph_row * self.scale;
+                if self.scale == 1 {
+                    let (x, y) = self.physical(fb, x, y);
+                    unsafe { fb.write_pixel(x, y, color.r, color.g, color.b) };
+                } else {
+                    unsafe { self.fill(fb, x, y, self.scale, self.scale, color) };
+                }
+            }
+        }
+    }
+
+    /// Move the full-width band of `height` pixels at `top` up by
+    /// `distance` pixels of the rotated screen
+    ///
+    /// The bottom `distance` pixels of the band keep their contents.
+    ///
+    /// # Safety
+    ///
+    /// The framebuffer must be accessible and the band on the screen.
+    pub unsafe fn scroll_up(&self, fb: &FramebufferInfo, top: u32, height: u32, distance: u32) {
+        if distance >= height {
+            return;
+        }
+        let (width, _) = self.size(fb);
+        let (x, y, band_width, band_height) = self.physical_rect(fb, 0, top, width, height);
+        let bytes_per_pixel = (fb.bits_per_pixel / 8) as usize;
+        let base = fb.as_ptr();
+        let at = |x: u32, y: u32| unsafe { base.add(fb.pixel_offset(x, y)) };
+        let moved = height - distance;
+
+        match self.rotation {
+            // Scanlines move up or down
+            Rotation::Normal => {
+                for line in 0..moved {
+                    let len = band_width as usize * bytes_per_pixel;
+                    unsafe { core::ptr::copy(at(x, y + line + distance), at(x, y + line), len) };
+                }
+            }
+            Rotation::Inverted => {
+                for line in (0..moved).rev() {
+                    let len = band_width as usize * bytes_per_pixel;
+                    unsafe { core::ptr::copy(at(x, y + line), at(x, y + line + distance), len) };
+                }
+            }
+            // Pixels move right or left within each scanline
+            Rotation::Right => {
+                for line in y..y + band_height {
+                    let len = moved as usize * bytes_per_pixel;
+                    unsafe { core::ptr::copy(at(x, line), at(x + distance, line), len) };
+                }
+            }
+            Rotation::Left => {
+                for line in y..y + band_height {
+                    let len = moved as usize * bytes_per_pixel;
+                    unsafe { core::ptr::copy(at(x + distance, line), at(x, line), len) };
+                }
+            }
+        }
+    }
+}
+
+/// Font scale for a `width` by `height` framebuffer
+///
+/// `auto` scales by one step per [`AUTO_SCALE_STEP`] lines of the shorter
+/// side. Scales that leave less than 80 by 25 characters are reduced.
+fn parse_scale(setting: &str, width: u32, height: u32) -> Option<u32> {
+    let scale = match setting.trim() {
+        "auto" => width.min(height) / AUTO_SCALE_STEP,
+        number => number.parse().ok().filter(|scale| *scale >= 1)?,
+    };
+    let fits = (width / (80 * CHAR_WIDTH)).min(height / (25 * CHAR_HEIGHT));
+    Some(scale.min(fits).clamp(1, MAX_SCALE))
+}
+
+/// Read a setting from its variable, or else from VPD
+fn setting<'a>(variable: &str, key: &str, buf: &'a mut [u8]) -> Option<&'a str> {
+    match read_variable(variable, &CRABEFI_VARIABLE_GUID, buf) {
+        Some(len) => core::str::from_utf8(&buf[..len])
+            .ok()
+            .map(|text| text.trim_end_matches('\0')),
+        None => vpd::find_str(key),
+    }
+}
+
+/// Read the rotation and font scale for `fb`
+pub fn init(fb: &FramebufferInfo) {
+    let mut buf = [0u8; 16];
+    let rotation = setting(ROTATION_VARIABLE, vpd::KEY_DISPLAY_ROTATION, &mut buf).map(|text| {
+        let rotation = Rotation::parse(text);
+        if rotation.is_none() {
+            log::warn!("Display: invalid rotation {:?}", text);
+        }
+        rotation
+    });
+    let rotation = rotation.flatten().unwrap_or(Rotation::Normal);
+
+    // Scale for the rotated screen, which is 80 columns wide
+    let (width, height) = View { rotation, scale: 1 }.size(fb);
+    let mut buf = [0u8; 16];
+    let scale = setting(SCALE_VARIABLE, vpd::KEY_DISPLAY_SCALE, &mut buf).map(|text| {
+        let scale = parse_scale(text, width, height);
+        if scale.is_none() {
+            log::warn!("Display: invalid font scale {:?}", text);
+        }
+        scale
+    });
+    let view = View {
+        rotation,
+        scale: scale.flatten().unwrap_or(1),
+    };
+
+    let mut current = VIEW.lock();
+    if *current != view {
+        log::info!("Display: {:?}, font scale {}", view.rotation, view.scale);
+        *current = view;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framebuffer() -> FramebufferInfo {
+        FramebufferInfo {
+            physical_address: 0,
+            x_resolution: 800,
+            y_resolution: 1280,
+            bytes_per_line: 3200,
+            bits_per_pixel: 32,
+            red_mask_pos: 16,
+            red_mask_size: 8,
+            green_mask_pos: 8,
+            green_mask_size: 8,
+            blue_mask_pos: 0,
+            blue_mask_size: 8,
+        }
+    }
+
+    #[test]
+    fn rotates_coordinates() {
+        let fb = framebuffer();
+        let view = |rotation| View { rotation, scale: 1 };
+        assert_eq!(view(Rotation::Right).size(&fb), (1280, 800));
+        assert_eq!(view(Rotation::Right).physical(&fb, 0, 0), (799, 0));
+        assert_eq!(view(Rotation::Inverted).physical(&fb, 0, 0), (799, 1279));
+        assert_eq!(view(Rotation::Left).physical(&fb, 0, 0), (0, 1279));
+
+        // The rectangle covers the corners' pixels
+        for rotation in [
+            Rotation::Normal,
+            Rotation::Right,
+            Rotation::Inverted,
+            Rotation::Left,
+        ] {
+            let view = view(rotation);
+            let (x, y, width, height) = view.physical_rect(&fb, 10, 20, 8, 16);
+            for (px, py) in [(10, 20), (17, 35)].map(|(lx, ly)| view.physical(&fb, lx, ly)) {
+                assert!((x..x + width).contains(&px) && (y..y + height).contains(&py));
+            }
+        }
+    }
+
+    #[test]
+    fn parses_settings() {
+        assert_eq!(Rotation::parse(" 270"), Some(Rotation::Left));
+        assert_eq!(Rotation::parse("45"), None);
+        assert_eq!(parse_scale("2", 3840, 2160), Some(2));
+        assert_eq!(parse_scale("auto", 3840, 2160), Some(3));
+        assert_eq!(parse_scale("auto", 1920, 1080), Some(1));
+        // 1280 pixels only fit 80 columns at scale 2
+        assert_eq!(parse_scale("4", 1280, 2560), Some(2));
+        assert_eq!(parse_scale("0", 3840, 2160), None);
+    }
+}
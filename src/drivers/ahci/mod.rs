@@ -231,6 +231,12 @@ pub struct AhciPort {
     pub sector_count: u64,
     /// Sector size
     pub sector_size: u32,
+    /// Serial number as reported, padded with spaces (IDENTIFY words 10-19)
+    pub serial_number: [u8; 20],
+    /// ATA security status (IDENTIFY word 128)
+    pub security_status: u16,
+    /// Whether TRUSTED SEND/RECEIVE are supported (IDENTIFY word 48)
+    pub trusted_computing: bool,
 }
 
 impl AhciPort {
@@ -239,6 +245,17 @@ impl AhciPort {
     fn regs(&self) -> &AhciPortRegisters {
         unsafe { &*self.port_regs }
     }
+
+    /// Whether ATA security keeps the drive locked until it gets the password
+    pub fn is_security_locked(&self) -> bool {
+        self.security_status & ATA_SECURITY_ENABLED != 0
+            && self.security_status & ATA_SECURITY_LOCKED != 0
+    }
+
+    /// Whether too many wrong passwords were tried since power-on
+    pub fn is_security_count_expired(&self) -> bool {
+        self.security_status & ATA_SECURITY_COUNT_EXPIRED != 0
+    }
 }
 
 /// Device type detected on port
@@ -556,6 +573,9 @@ impl AhciController {
             device_type,
             sector_count: 0,
             sector_size: 512,
+            serial_number: [b' '; 20],
+            security_status: 0,
+            trusted_computing: false,
         };
 
         // Identify the device
@@ -728,6 +748,15 @@ impl AhciController {
         }
         let model_str = core::str::from_utf8(&model).unwrap_or("Unknown").trim();
 
+        // Serial number (words 10-19), same byte order as the model number
+        for (i, word) in identify[10..20].iter().enumerate() {
+            port.serial_number[i * 2..i * 2 + 2].copy_from_slice(&word.to_be_bytes());
+        }
+
+        // Word 48 is valid if bits 15:14 are 01
+        port.trusted_computing = identify[48] & 0xC001 == 0x4001;
+        port.security_status = identify[128];
+
         log::info!(
             "AHCI Port {}: {} - {} sectors x {} bytes = {} MB",
             port.port_num,
@@ -1127,9 +1156,9 @@ impl AhciController {
         // The ATA TRUSTED RECEIVE DMA command layout:
         // - Command: 0x5C
         // - Features (7:0): Security Protocol
-        // - LBA (15:0): Transfer Length in 512-byte blocks
-        // - LBA (31:24): Security Protocol Specific (high byte)
-        // - Device (7:0): Security Protocol Specific (low byte) | 0x40 (LBA mode)
+        // - Count (7:0): Transfer Length in 512-byte blocks (7:0)
+        // - LBA (7:0): Transfer Length in 512-byte blocks (15:8)
+        // - LBA (23:8): Security Protocol Specific
         let fis = unsafe { &mut *(table.cfis.as_mut_ptr() as *mut FisRegH2D) };
         *fis = FisRegH2D::new();
         fis.set_command(ATA_CMD_TRUSTED_RECEIVE_DMA);
@@ -1137,11 +1166,11 @@ impl AhciController {
         
         // Transfer length in 512-byte blocks
         let transfer_blocks = (buffer.len() as u32 + 511) / 512;
-        fis.lba0 = (transfer_blocks & 0xFF) as u8;
-        fis.lba1 = ((transfer_blocks >> 8) & 0xFF) as u8;
-        fis.lba2 = 0;
-        fis.lba3 = (sp_specific >> 8) as u8;
-        fis.device = ((sp_specific & 0xFF) as u8) | 0x40; // LBA mode
+        fis.count_l = (transfer_blocks & 0xFF) as u8;
+        fis.lba0 = ((transfer_blocks >> 8) & 0xFF) as u8;
+        fis.lba1 = (sp_specific & 0xFF) as u8;
+        fis.lba2 = (sp_specific >> 8) as u8;
+        fis.device = 0x40; // LBA mode
 
         // Setup PRDT
         table.prdt[0].set_address(dma_addr);
@@ -1242,9 +1271,9 @@ impl AhciController {
         // The ATA TRUSTED SEND DMA command layout:
         // - Command: 0x5E
         // - Features (7:0): Security Protocol
-        // - LBA (15:0): Transfer Length in 512-byte blocks
-        // - LBA (31:24): Security Protocol Specific (high byte)
-        // - Device (7:0): Security Protocol Specific (low byte) | 0x40 (LBA mode)
+        // - Count (7:0): Transfer Length in 512-byte blocks (7:0)
+        // - LBA (7:0): Transfer Length in 512-byte blocks (15:8)
+        // - LBA (23:8): Security Protocol Specific
         let fis = unsafe { &mut *(table.cfis.as_mut_ptr() as *mut FisRegH2D) };
         *fis = FisRegH2D::new();
         fis.set_command(ATA_CMD_TRUSTED_SEND_DMA);
@@ -1252,11 +1281,11 @@ impl AhciController {
         
         // Transfer length in 512-byte blocks
         let transfer_blocks = (buffer.len() as u32 + 511) / 512;
-        fis.lba0 = (transfer_blocks & 0xFF) as u8;
-        fis.lba1 = ((transfer_blocks >> 8) & 0xFF) as u8;
-        fis.lba2 = 0;
-        fis.lba3 = (sp_specific >> 8) as u8;
-        fis.device = ((sp_specific & 0xFF) as u8) | 0x40; // LBA mode
+        fis.count_l = (transfer_blocks & 0xFF) as u8;
+        fis.lba0 = ((transfer_blocks >> 8) & 0xFF) as u8;
+        fis.lba1 = (sp_specific & 0xFF) as u8;
+        fis.lba2 = (sp_specific >> 8) as u8;
+        fis.device = 0x40; // LBA mode
 
         // Setup PRDT
         table.prdt[0].set_address(dma_addr);
@@ -1271,6 +1300,67 @@ impl AhciController {
             log::debug!("AHCI Trusted Send: success");
         })
     }
+
+    /// ATA SECURITY UNLOCK (command 0xF2) with the user password
+    ///
+    /// # Arguments
+    /// * `port_index` - Port index
+    /// * `password` - User password, zero-padded to 32 bytes
+    pub fn security_unlock(
+        &mut self,
+        port_index: usize,
+        password: &[u8; 32],
+    ) -> Result<(), AhciError> {
+        if port_index >= self.ports.len() {
+            return Err(AhciError::InvalidParameter);
+        }
+
+        let port_num = self.ports[port_index].port_num;
+        let cmd_list = self.ports[port_index].cmd_list;
+        let cmd_tables = self.ports[port_index].cmd_tables;
+
+        let slot = self
+            .find_free_slot(port_num)
+            .ok_or(AhciError::PortNotReady)?;
+
+        // Word 0 selects the user password, words 1-16 hold it
+        let dma_buffer = efi::allocate_pages(1).ok_or(AhciError::AllocationFailed)?;
+        dma_buffer[..512].fill(0);
+        dma_buffer[2..34].copy_from_slice(password);
+        let dma_addr = dma_buffer.as_ptr() as u64;
+
+        // Setup command header
+        let header = unsafe { &mut *cmd_list.add(slot as usize) };
+        header.dw0 = 0;
+        header.set_cfl(5); // 5 DWORDs for H2D FIS
+        header.set_write(true); // PIO data-out
+        header.set_prdtl(1);
+        header.prdbc = 0;
+
+        // Setup command table
+        let table = unsafe { &mut *cmd_tables[slot as usize] };
+        *table = CommandTable::default();
+
+        let fis = unsafe { &mut *(table.cfis.as_mut_ptr() as *mut FisRegH2D) };
+        *fis = FisRegH2D::new();
+        fis.set_command(ATA_CMD_SECURITY_UNLOCK);
+
+        // Setup PRDT
+        table.prdt[0].set_address(dma_addr);
+        table.prdt[0].set_byte_count(512, true);
+
+        let result = self.issue_command_by_port(port_num, slot);
+
+        // Don't leave the password in freed memory
+        dma_buffer[..512].fill(0);
+        efi::free_pages(dma_buffer, 1);
+
+        if result.is_ok() {
+            // The drive is unlocked now; keep the other status bits
+            self.ports[port_index].security_status &= !ATA_SECURITY_LOCKED;
+        }
+        result
+    }
 }
 
 /// Wrapper for AHCI controller pointer to implement Send
@@ -1296,8 +1386,8 @@ pub fn init() {
         return;
     }
 
-    let mut controllers = AHCI_CONTROLLERS.lock();
-
+    // The list is only locked to add a controller: controller init waits,
+    // and other parallel init tasks run meanwhile, see crate::parallel
     for dev in ahci_devices.iter() {
         match AhciController::new(dev) {
             Ok(controller) => {
@@ -1314,7 +1404,11 @@ pub fn init() {
                     unsafe {
                         ptr::write(controller_box, controller);
                     }
-                    if controllers.push(AhciControllerPtr(controller_box)).is_err() {
+                    if AHCI_CONTROLLERS
+                        .lock()
+                        .push(AhciControllerPtr(controller_box))
+                        .is_err()
+                    {
                         log::warn!(
                             "AHCI: Failed to register controller at {} - controller list full",
                             dev.address
@@ -1336,7 +1430,7 @@ pub fn init() {
 
     log::info!(
         "AHCI initialization complete: {} controllers",
-        controllers.len()
+        AHCI_CONTROLLERS.lock().len()
     );
 }
 
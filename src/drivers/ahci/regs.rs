@@ -491,6 +491,22 @@ pub const ATA_CMD_TRUSTED_RECEIVE_DMA: u8 = 0x5C;
 /// Trusted Send (DMA) - for TCG Opal/IEEE 1667
 pub const ATA_CMD_TRUSTED_SEND_DMA: u8 = 0x5E;
 
+/// Security Unlock (PIO data-out)
+pub const ATA_CMD_SECURITY_UNLOCK: u8 = 0xF2;
+
+// ============================================================================
+// ATA Security Status (IDENTIFY word 128)
+// ============================================================================
+
+/// Security feature set enabled (a user password is set)
+pub const ATA_SECURITY_ENABLED: u16 = 1 << 1;
+
+/// Drive is locked
+pub const ATA_SECURITY_LOCKED: u16 = 1 << 2;
+
+/// Too many failed unlock attempts since power-on
+pub const ATA_SECURITY_COUNT_EXPIRED: u16 = 1 << 4;
+
 // ============================================================================
 // SCSI Commands (used with ATAPI)
 // ============================================================================
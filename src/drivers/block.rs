@@ -12,7 +12,7 @@
 //! The `AnyBlockDevice` enum provides type-safe dispatch without trait objects,
 //! similar to how `UsbControllerHandle` works for USB controllers.
 
-use crate::drivers::{ahci, nvme, sdhci, usb};
+use crate::drivers::{ahci, nvme, ramdisk, sdhci, usb};
 
 /// Standard sector size (512 bytes)
 pub const SECTOR_SIZE: usize = 512;
@@ -87,6 +87,7 @@ impl From<sdhci::SdhciError> for BlockError {
             sdhci::SdhciError::NoCard => BlockError::NoMedia,
             sdhci::SdhciError::InvalidParameter => BlockError::InvalidParameter,
             sdhci::SdhciError::NotInitialized => BlockError::NoMedia,
+            sdhci::SdhciError::MediaChanged => BlockError::MediaChanged,
             _ => BlockError::DeviceError,
         }
     }
@@ -115,6 +116,14 @@ pub trait BlockDevice {
     fn read_block(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
         self.read_blocks(lba, 1, buffer)
     }
+
+    /// Media ID of the media in the device now, or `None` if there is none
+    ///
+    /// Differs from `info().media_id` once the media was replaced. Only SD
+    /// cards are checked; other devices always report their own media.
+    fn current_media(&mut self) -> Option<u32> {
+        Some(self.info().media_id)
+    }
 }
 
 // ============================================================================
@@ -381,10 +390,66 @@ impl BlockDevice for SdhciBlockDevice {
         let controller =
             sdhci::get_controller(self.controller_id).ok_or(BlockError::DeviceError)?;
 
+        // Never read another card with offsets meant for this one
+        match controller.current_media() {
+            Some(media_id) if media_id == self.info.media_id => {}
+            Some(_) => return Err(BlockError::MediaChanged),
+            None => return Err(BlockError::NoMedia),
+        }
+
         controller
             .read_sectors(lba, count, buffer.as_mut_ptr())
             .map_err(BlockError::from)
     }
+
+    fn current_media(&mut self) -> Option<u32> {
+        sdhci::get_controller(self.controller_id)?.current_media()
+    }
+}
+
+// ============================================================================
+// RAM Disk Block Device
+// ============================================================================
+
+/// Block device reading a [RAM disk](ramdisk)
+pub struct RamDiskBlockDevice {
+    /// ID of the RAM disk
+    id: usize,
+    /// Cached device info
+    info: BlockDeviceInfo,
+}
+
+impl RamDiskBlockDevice {
+    /// Create a block device for RAM disk `id`
+    pub fn new(id: usize, disk: &ramdisk::RamDisk) -> Self {
+        Self {
+            id,
+            info: BlockDeviceInfo {
+                num_blocks: disk.num_blocks(),
+                block_size: disk.block_size(),
+                media_id: 0,
+                removable: false,
+                read_only: true,
+            },
+        }
+    }
+
+    /// Get the RAM disk ID
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl BlockDevice for RamDiskBlockDevice {
+    fn info(&self) -> BlockDeviceInfo {
+        self.info
+    }
+
+    fn read_blocks(&mut self, lba: u64, count: u32, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let len = count as usize * self.info.block_size as usize;
+        let buffer = buffer.get_mut(..len).ok_or(BlockError::InvalidParameter)?;
+        ramdisk::read(self.id, lba, buffer)
+    }
 }
 
 // ============================================================================
@@ -536,7 +601,7 @@ impl<'a> BlockDevice for SdhciDisk<'a> {
         BlockDeviceInfo {
             num_blocks: self.controller.num_blocks(),
             block_size: self.controller.block_size(),
-            media_id: 0,
+            media_id: self.controller.media_id(),
             removable: true,
             read_only: false,
         }
@@ -547,6 +612,59 @@ impl<'a> BlockDevice for SdhciDisk<'a> {
             .read_sectors(lba, count, buffer.as_mut_ptr())
             .map_err(BlockError::from)
     }
+
+    fn current_media(&mut self) -> Option<u32> {
+        self.controller.current_media()
+    }
+}
+
+// ============================================================================
+// Offset Block Device
+// ============================================================================
+
+/// Window onto a range of blocks of another block device
+///
+/// Block 0 of the window is block `start` of the device, and reads past the
+/// end of the window fail. Used to mount the FAT image that El Torito embeds
+/// in an ISO as a device of its own.
+pub struct OffsetDisk<'a> {
+    device: &'a mut dyn BlockDevice,
+    /// First block of the window on the device
+    start: u64,
+    /// Size of the window in blocks
+    num_blocks: u64,
+}
+
+impl<'a> OffsetDisk<'a> {
+    /// Create a window of `num_blocks` blocks at `start`, clamped to the device
+    pub fn new(device: &'a mut dyn BlockDevice, start: u64, num_blocks: u64) -> Self {
+        let num_blocks = num_blocks.min(device.info().num_blocks.saturating_sub(start));
+        Self {
+            device,
+            start,
+            num_blocks,
+        }
+    }
+}
+
+impl BlockDevice for OffsetDisk<'_> {
+    fn info(&self) -> BlockDeviceInfo {
+        BlockDeviceInfo {
+            num_blocks: self.num_blocks,
+            ..self.device.info()
+        }
+    }
+
+    fn read_blocks(&mut self, lba: u64, count: u32, buffer: &mut [u8]) -> Result<(), BlockError> {
+        if lba.saturating_add(count as u64) > self.num_blocks {
+            return Err(BlockError::OutOfRange);
+        }
+        self.device.read_blocks(self.start + lba, count, buffer)
+    }
+
+    fn current_media(&mut self) -> Option<u32> {
+        self.device.current_media()
+    }
 }
 
 // ============================================================================
@@ -566,6 +684,8 @@ pub enum AnyBlockDevice {
     Usb(UsbBlockDevice),
     /// SDHCI (SD card) device
     Sdhci(SdhciBlockDevice),
+    /// RAM disk
+    RamDisk(RamDiskBlockDevice),
 }
 
 impl BlockDevice for AnyBlockDevice {
@@ -575,6 +695,7 @@ impl BlockDevice for AnyBlockDevice {
             AnyBlockDevice::Ahci(dev) => dev.info(),
             AnyBlockDevice::Usb(dev) => dev.info(),
             AnyBlockDevice::Sdhci(dev) => dev.info(),
+            AnyBlockDevice::RamDisk(dev) => dev.info(),
         }
     }
 
@@ -584,6 +705,14 @@ impl BlockDevice for AnyBlockDevice {
             AnyBlockDevice::Ahci(dev) => dev.read_blocks(lba, count, buffer),
             AnyBlockDevice::Usb(dev) => dev.read_blocks(lba, count, buffer),
             AnyBlockDevice::Sdhci(dev) => dev.read_blocks(lba, count, buffer),
+            AnyBlockDevice::RamDisk(dev) => dev.read_blocks(lba, count, buffer),
+        }
+    }
+
+    fn current_media(&mut self) -> Option<u32> {
+        match self {
+            AnyBlockDevice::Sdhci(dev) => dev.current_media(),
+            _ => Some(self.info().media_id),
         }
     }
 }
@@ -601,6 +730,7 @@ macro_rules! with_block_device {
             $crate::drivers::block::AnyBlockDevice::Ahci(ref mut $device) => $body,
             $crate::drivers::block::AnyBlockDevice::Usb(ref mut $device) => $body,
             $crate::drivers::block::AnyBlockDevice::Sdhci(ref mut $device) => $body,
+            $crate::drivers::block::AnyBlockDevice::RamDisk(ref mut $device) => $body,
         }
     };
     // Immutable access version
@@ -610,10 +740,66 @@ macro_rules! with_block_device {
             $crate::drivers::block::AnyBlockDevice::Ahci(ref $device) => $body,
             $crate::drivers::block::AnyBlockDevice::Usb(ref $device) => $body,
             $crate::drivers::block::AnyBlockDevice::Sdhci(ref $device) => $body,
+            $crate::drivers::block::AnyBlockDevice::RamDisk(ref $device) => $body,
         }
     };
 }
 
+// ============================================================================
+// Memory Block Device
+// ============================================================================
+
+/// Read-only block device backed by a disk image in memory
+///
+/// Used to run the partition and filesystem parsers on disk images in unit
+/// tests and fuzzing. A partial last block reads as if zero-padded.
+#[cfg(any(test, feature = "fuzz"))]
+pub struct MemoryDisk<T: AsRef<[u8]>> {
+    /// Disk image
+    data: T,
+    /// Block size in bytes
+    block_size: u32,
+}
+
+#[cfg(any(test, feature = "fuzz"))]
+impl<T: AsRef<[u8]>> MemoryDisk<T> {
+    /// Create a memory disk from an image
+    pub fn new(data: T, block_size: u32) -> Self {
+        Self { data, block_size }
+    }
+}
+
+#[cfg(any(test, feature = "fuzz"))]
+impl<T: AsRef<[u8]>> BlockDevice for MemoryDisk<T> {
+    fn info(&self) -> BlockDeviceInfo {
+        BlockDeviceInfo {
+            num_blocks: (self.data.as_ref().len() as u64).div_ceil(self.block_size as u64),
+            block_size: self.block_size,
+            media_id: 0,
+            removable: false,
+            read_only: true,
+        }
+    }
+
+    fn read_blocks(&mut self, lba: u64, count: u32, buffer: &mut [u8]) -> Result<(), BlockError> {
+        // Same contract as the hardware drivers: the buffer holds whole blocks
+        let len = count as usize * self.block_size as usize;
+        if buffer.len() < len {
+            return Err(BlockError::InvalidParameter);
+        }
+        if lba.saturating_add(count as u64) > self.info().num_blocks {
+            return Err(BlockError::OutOfRange);
+        }
+
+        let data = self.data.as_ref();
+        let start = lba as usize * self.block_size as usize;
+        let available = data.len().saturating_sub(start).min(len);
+        buffer[..available].copy_from_slice(&data[start..start + available]);
+        buffer[available..len].fill(0);
+        Ok(())
+    }
+}
+
 // ============================================================================
 // Helper Functions
 // ============================================================================
@@ -654,8 +840,8 @@ pub fn create_ahci_device(
     ))
 }
 
-/// Create an SDHCI block device from a controller
-pub fn create_sdhci_device(controller_id: usize, media_id: u32) -> Option<SdhciBlockDevice> {
+/// Create an SDHCI block device for the card currently in a controller
+pub fn create_sdhci_device(controller_id: usize) -> Option<SdhciBlockDevice> {
     let controller = sdhci::get_controller(controller_id)?;
 
     if !controller.is_ready() {
@@ -666,6 +852,6 @@ pub fn create_sdhci_device(controller_id: usize, media_id: u32) -> Option<SdhciB
         controller_id,
         controller.num_blocks(),
         controller.block_size(),
-        media_id,
+        controller.media_id(),
     ))
 }
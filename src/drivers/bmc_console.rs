@@ -0,0 +1,203 @@
+//! Console to a BMC, for IPMI Serial-over-LAN
+//!
+//! Server boards with a BMC let administrators see the host console through
+//! the BMC's IPMI SOL. Everything written to the serial port is also written
+//! to the BMC through one of:
+//!
+//! - `uart:<port>[,<baud>]`: the BMC's virtual UART, e.g. the ASPEED VUART,
+//!   when coreboot's console is on another UART. Keys typed in the SOL
+//!   session are read from it too.
+//! - `mem:<address>,<size>`: a shared memory buffer the BMC reads, outside
+//!   RAM, e.g. in a BMC PCI BAR. It holds a ring in the CBMEM console format:
+//!   size and cursor, then the text.
+//!
+//! KCS and BT only carry IPMI messages, and IPMI has no command to hand text
+//! to the SOL session, so they aren't used.
+//!
+//! The interface is read from the `BmcConsole` variable under
+//! [`CRABEFI_VARIABLE_GUID`], or else from the VPD key `crabefi_bmc_console`.
+
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::coreboot::{cbmem_console, vpd};
+use crate::drivers::serial::SerialPort;
+use crate::efi::runtime_services::{CRABEFI_VARIABLE_GUID, read_variable};
+
+/// Variable holding the interface
+const CONSOLE_VARIABLE: &str = "BmcConsole";
+
+/// Smallest shared memory buffer, with its 8-byte header
+const MIN_BUFFER_SIZE: u64 = 1024;
+
+/// How the console reaches the BMC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interface {
+    /// A 16550 UART the BMC forwards to SOL
+    Uart { port: u16, baud: u32 },
+    /// A ring buffer in memory the BMC reads
+    Memory { address: u64, size: u64 },
+}
+
+/// The console, once set up
+enum Console {
+    Uart(SerialPort),
+    Memory(u64),
+}
+
+static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+
+/// Parse a decimal or `0x` prefixed hexadecimal number
+fn parse_number(text: &str) -> Option<u64> {
+    match text.trim().strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.trim().parse().ok(),
+    }
+}
+
+impl Interface {
+    /// Parse `uart:<port>[,<baud>]` or `mem:<address>,<size>`
+    pub fn parse(text: &str) -> Option<Self> {
+        let (kind, args) = text.trim().split_once(':')?;
+        let mut args = args.split(',');
+        let first = parse_number(args.next()?)?;
+        let second = args.next().map(parse_number);
+        if args.next().is_some() {
+            return None;
+        }
+        match kind {
+            "uart" => Some(Interface::Uart {
+                port: u16::try_from(first).ok()?,
+                baud: match second {
+                    Some(baud) => u32::try_from(baud?)
+                        .ok()
+                        .filter(|&b| b != 0 && 115200 % b == 0)?,
+                    None => 115200,
+                },
+            }),
+            "mem" => {
+                let size = second??;
+                (size >= MIN_BUFFER_SIZE).then_some(Interface::Memory {
+                    address: first,
+                    size,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Set up the console from its variable, or else from VPD
+///
+/// `serial_port` is the UART already used as console, which isn't written to
+/// twice.
+pub fn init(serial_port: Option<u16>) {
+    let mut buf = [0u8; 64];
+    let text = match read_variable(CONSOLE_VARIABLE, &CRABEFI_VARIABLE_GUID, &mut buf) {
+        Some(len) => core::str::from_utf8(&buf[..len])
+            .ok()
+            .map(|text| text.trim_end_matches('\0')),
+        None => vpd::find_str(vpd::KEY_BMC_CONSOLE),
+    };
+    let Some(text) = text else {
+        return;
+    };
+    let Some(interface) = Interface::parse(text) else {
+        log::warn!("BMC console: bad interface {:?}", text);
+        return;
+    };
+
+    let console = match interface {
+        Interface::Uart { port, .. } if serial_port == Some(port) => {
+            log::info!(
+                "BMC console: UART {:#x} is the serial console already",
+                port
+            );
+            return;
+        }
+        Interface::Uart { port, baud } => {
+            // SAFETY: the board's configuration names a 16550 UART there
+            let mut uart = unsafe { SerialPort::new(port) };
+            if !uart.init(baud) {
+                log::warn!("BMC console: no UART at {:#x}", port);
+                return;
+            }
+            Console::Uart(uart)
+        }
+        Interface::Memory { address, size } => {
+            // SAFETY: the board's configuration names a buffer there
+            unsafe { cbmem_console::init_ring(address, size) };
+            Console::Memory(address)
+        }
+    };
+    log::info!("BMC console: {:x?}", interface);
+    *CONSOLE.lock() = Some(console);
+}
+
+/// Write bytes to the BMC
+pub fn write_bytes(data: &[u8]) {
+    // Output from a crash handler may come while the lock is held
+    let Some(mut console) = CONSOLE.try_lock() else {
+        return;
+    };
+    match console.as_mut() {
+        Some(Console::Uart(uart)) => data.iter().for_each(|&byte| uart.write_byte(byte)),
+        // SAFETY: `init` set up the ring
+        Some(Console::Memory(address)) => unsafe { cbmem_console::write_ring(*address, data) },
+        None => {}
+    }
+}
+
+/// Writer to the BMC for formatted output
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Read a key byte typed in the SOL session, if the BMC sends them
+pub fn try_read() -> Option<u8> {
+    match CONSOLE.lock().as_mut() {
+        Some(Console::Uart(uart)) => uart.try_read_byte(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_interfaces() {
+        assert_eq!(
+            Interface::parse("uart:0x2f8"),
+            Some(Interface::Uart {
+                port: 0x2f8,
+                baud: 115200
+            })
+        );
+        assert_eq!(
+            Interface::parse(" uart:0x3e8, 57600 "),
+            Some(Interface::Uart {
+                port: 0x3e8,
+                baud: 57600
+            })
+        );
+        assert_eq!(
+            Interface::parse("mem:0xfe800000,0x10000"),
+            Some(Interface::Memory {
+                address: 0xfe80_0000,
+                size: 0x10000
+            })
+        );
+        assert_eq!(Interface::parse("uart:0x12345"), None);
+        assert_eq!(Interface::parse("uart:0x2f8,1234"), None);
+        assert_eq!(Interface::parse("mem:0xfe800000"), None);
+        assert_eq!(Interface::parse("mem:0xfe800000,16"), None);
+        assert_eq!(Interface::parse("kcs:0xca2"), None);
+    }
+}
@@ -0,0 +1,60 @@
+//! CMOS NVRAM access
+//!
+//! The RTC on x86 platforms is backed by battery-powered CMOS RAM that
+//! survives reboots and power cycles. The first 14 bytes hold the clock
+//! registers; the rest of bank 0 (0x0E-0x7F) and, on chipsets that provide
+//! it, bank 1 (0x80-0xFF) are general purpose storage. coreboot's
+//! `cmos.layout` may use any of these bytes, so records of CrabEFI's own
+//! are [reserved](crate::coreboot::cmos_options::reserve) against the
+//! option table first.
+//!
+//! Bank 0 is accessed through ports 0x70/0x71 and bank 1 through the
+//! extended index/data ports 0x72/0x73.
+
+use crate::arch::x86_64::io;
+
+/// Bank 0 index port
+const BANK0_INDEX: u16 = 0x70;
+/// Bank 0 data port
+const BANK0_DATA: u16 = 0x71;
+/// Bank 1 index port
+const BANK1_INDEX: u16 = 0x72;
+/// Bank 1 data port
+const BANK1_DATA: u16 = 0x73;
+
+/// First byte past the RTC clock and status registers
+pub const FIRST_NVRAM_BYTE: u8 = 0x0E;
+
+/// Select the index/data port pair for a CMOS offset
+fn ports(offset: u8) -> (u16, u16) {
+    if offset < 0x80 {
+        (BANK0_INDEX, BANK0_DATA)
+    } else {
+        (BANK1_INDEX, BANK1_DATA)
+    }
+}
+
+/// Read a CMOS byte
+pub fn read(offset: u8) -> u8 {
+    let (index, data) = ports(offset);
+    unsafe {
+        io::outb(index, offset & 0x7F);
+        io::inb(data)
+    }
+}
+
+/// Write a CMOS byte
+///
+/// Offsets below [`FIRST_NVRAM_BYTE`] are the RTC registers and are ignored.
+pub fn write(offset: u8, value: u8) {
+    if offset < FIRST_NVRAM_BYTE {
+        log::warn!("CMOS: refusing to write RTC register {:#x}", offset);
+        return;
+    }
+
+    let (index, data) = ports(offset);
+    unsafe {
+        io::outb(index, offset & 0x7F);
+        io::outb(data, value);
+    }
+}
@@ -0,0 +1,247 @@
+//! EDID (Extended Display Identification Data)
+//!
+//! coreboot sets up the framebuffer but doesn't pass on the display's EDID,
+//! so it is read from display devices that expose it without any knowledge
+//! of the GPU: QEMU's standard VGA and bochs-display keep the EDID blob at
+//! the start of their MMIO BAR.
+//!
+//! Reference: VESA Enhanced EDID Standard, Release A Revision 2 (EDID 1.4)
+
+use heapless::Vec;
+
+use crate::drivers::pci::{self, BarType};
+
+/// Size of an EDID block
+pub const BLOCK_SIZE: usize = 128;
+
+/// Largest EDID kept, the size of QEMU's EDID window
+pub const MAX_SIZE: usize = 1024;
+
+/// Most modes listed by [`Edid::modes`]
+pub const MAX_MODES: usize = 16;
+
+/// Fixed header of the base block
+const HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+
+/// Base block offsets
+const VERSION: usize = 18;
+const REVISION: usize = 19;
+const ESTABLISHED_TIMINGS: usize = 35;
+const STANDARD_TIMINGS: usize = 38;
+const DESCRIPTORS: usize = 54;
+const EXTENSION_COUNT: usize = 126;
+
+/// Size of a detailed timing descriptor
+const DESCRIPTOR_SIZE: usize = 18;
+
+/// Established timings: byte offset from [`ESTABLISHED_TIMINGS`], bit, mode
+const ESTABLISHED: [(usize, u8, (u32, u32)); 17] = [
+    (0, 7, (720, 400)),
+    (0, 6, (720, 400)),
+    (0, 5, (640, 480)),
+    (0, 4, (640, 480)),
+    (0, 3, (640, 480)),
+    (0, 2, (640, 480)),
+    (0, 1, (800, 600)),
+    (0, 0, (800, 600)),
+    (1, 7, (800, 600)),
+    (1, 6, (800, 600)),
+    (1, 5, (832, 624)),
+    (1, 4, (1024, 768)),
+    (1, 3, (1024, 768)),
+    (1, 2, (1024, 768)),
+    (1, 1, (1024, 768)),
+    (1, 0, (1280, 1024)),
+    (2, 7, (1152, 870)),
+];
+
+/// QEMU standard VGA and bochs-display PCI IDs
+const QEMU_VGA_VENDOR: u16 = 0x1234;
+const QEMU_VGA_DEVICE: u16 = 0x1111;
+
+/// BAR of the QEMU display MMIO area, which starts with the EDID
+const QEMU_VGA_MMIO_BAR: usize = 2;
+
+/// A validated EDID, the base block and its extensions
+#[derive(Clone)]
+pub struct Edid {
+    data: [u8; MAX_SIZE],
+    len: usize,
+}
+
+impl Edid {
+    /// Validate the EDID at the start of `data`
+    ///
+    /// Checks the header and the base block checksum; extension blocks are
+    /// passed on as they are.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let base = data.get(..BLOCK_SIZE)?;
+        if base[..HEADER.len()] != HEADER
+            || base.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0
+        {
+            return None;
+        }
+        let len = BLOCK_SIZE * (1 + base[EXTENSION_COUNT] as usize);
+        if len > MAX_SIZE {
+            return None;
+        }
+
+        let mut edid = Edid {
+            data: [0; MAX_SIZE],
+            len,
+        };
+        edid.data[..len].copy_from_slice(data.get(..len)?);
+        Some(edid)
+    }
+
+    /// The raw EDID
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Resolutions of the detailed timing descriptors, preferred mode first
+    fn detailed_modes(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.data[DESCRIPTORS..DESCRIPTORS + 4 * DESCRIPTOR_SIZE]
+            .chunks_exact(DESCRIPTOR_SIZE)
+            .filter_map(|descriptor| {
+                // A zero pixel clock marks a display descriptor; skip
+                // interlaced modes
+                let pixel_clock = u16::from_le_bytes([descriptor[0], descriptor[1]]);
+                if pixel_clock == 0 || descriptor[17] & 0x80 != 0 {
+                    return None;
+                }
+                let width = descriptor[2] as u32 | ((descriptor[4] as u32 >> 4) << 8);
+                let height = descriptor[5] as u32 | ((descriptor[7] as u32 >> 4) << 8);
+                Some((width, height))
+            })
+    }
+
+    /// Resolutions of the standard timings
+    fn standard_modes(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        // Aspect ratio code 0 meant 1:1 before EDID 1.3
+        let before_1_3 = self.data[VERSION] == 1 && self.data[REVISION] < 3;
+        self.data[STANDARD_TIMINGS..STANDARD_TIMINGS + 16]
+            .chunks_exact(2)
+            .filter(|timing| timing[0] != 0x01 && timing[0] != 0x00)
+            .map(move |timing| {
+                let width = (timing[0] as u32 + 31) * 8;
+                let (num, den) = match timing[1] >> 6 {
+                    0 if before_1_3 => (1, 1),
+                    0 => (10, 16),
+                    1 => (3, 4),
+                    2 => (4, 5),
+                    _ => (9, 16),
+                };
+                (width, width * num / den)
+            })
+    }
+
+    /// Resolutions of the established timings
+    fn established_modes(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        ESTABLISHED
+            .iter()
+            .filter(|&&(byte, bit, _)| self.data[ESTABLISHED_TIMINGS + byte] & (1 << bit) != 0)
+            .map(|&(_, _, mode)| mode)
+    }
+
+    /// The display's preferred (native) resolution
+    pub fn preferred_mode(&self) -> Option<(u32, u32)> {
+        self.detailed_modes().next()
+    }
+
+    /// Resolutions the display supports, without duplicates
+    ///
+    /// Detailed timings come first, starting with the preferred mode.
+    pub fn modes(&self) -> Vec<(u32, u32), MAX_MODES> {
+        let mut modes = Vec::new();
+        for mode in self
+            .detailed_modes()
+            .chain(self.standard_modes())
+            .chain(self.established_modes())
+        {
+            if !modes.contains(&mode) && modes.push(mode).is_err() {
+                break;
+            }
+        }
+        modes
+    }
+}
+
+/// Read the EDID of the display, if a display device exposes it
+pub fn discover() -> Option<Edid> {
+    let device = pci::get_all_devices()
+        .into_iter()
+        .find(|dev| dev.vendor_id == QEMU_VGA_VENDOR && dev.device_id == QEMU_VGA_DEVICE)?;
+    let bar = device.bars[QEMU_VGA_MMIO_BAR];
+    if !matches!(bar.bar_type, BarType::Memory32 | BarType::Memory64) || bar.address == 0 {
+        return None;
+    }
+
+    let mut data = [0u8; MAX_SIZE];
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = unsafe { ((bar.address as usize + i) as *const u8).read_volatile() };
+    }
+    let edid = Edid::parse(&data);
+    if edid.is_none() {
+        log::debug!("No valid EDID on display {}", device.address);
+    }
+    edid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// EDID 1.4 base block of a 1920x1080 panel
+    fn base_block() -> [u8; BLOCK_SIZE] {
+        let mut block = [0u8; BLOCK_SIZE];
+        block[..8].copy_from_slice(&HEADER);
+        block[VERSION] = 1;
+        block[REVISION] = 4;
+        // 640x480@60 and 1024x768@60
+        block[ESTABLISHED_TIMINGS] = 0x20;
+        block[ESTABLISHED_TIMINGS + 1] = 0x08;
+        // 1280x1024 (5:4), 1920x1080 (16:9), 1440x900 (16:10), rest unused
+        block[STANDARD_TIMINGS..STANDARD_TIMINGS + 16].copy_from_slice(&[
+            0x81, 0x80, 0xD1, 0xC0, 0x95, 0x00, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+            0x01, 0x01,
+        ]);
+        // 1920x1080@60, 148.5 MHz
+        block[DESCRIPTORS..DESCRIPTORS + 8]
+            .copy_from_slice(&[0x02, 0x3A, 0x80, 0x18, 0x71, 0x38, 0x2D, 0x40]);
+        let sum = block.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        block[127] = 0u8.wrapping_sub(sum);
+        block
+    }
+
+    #[test]
+    fn parse_modes() {
+        let edid = Edid::parse(&base_block()).unwrap();
+        assert_eq!(edid.as_bytes().len(), BLOCK_SIZE);
+        assert_eq!(edid.preferred_mode(), Some((1920, 1080)));
+        assert_eq!(
+            edid.modes().as_slice(),
+            &[
+                (1920, 1080),
+                (1280, 1024),
+                (1440, 900),
+                (640, 480),
+                (1024, 768)
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let mut block = base_block();
+        block[127] ^= 1;
+        assert!(Edid::parse(&block).is_none());
+
+        // Extension announced but missing
+        let mut block = base_block();
+        block[EXTENSION_COUNT] = 1;
+        block[127] = block[127].wrapping_sub(1);
+        assert!(Edid::parse(&block).is_none());
+        assert!(Edid::parse(&block[..64]).is_none());
+    }
+}
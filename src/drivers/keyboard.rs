@@ -8,16 +8,30 @@
 //! The keyboard is configured to use scancode set 1 (or set 2 with controller
 //! translation enabled), which is the IBM PC/AT compatible format.
 //!
+//! # Lock Keys
+//!
+//! Caps Lock, Num Lock and Scroll Lock are shared by the PS/2 and USB
+//! keyboards, so typing on either gives the same result and both show it on
+//! their LEDs.
+//!
 //! # References
 //!
 //! - libpayload: `payloads/libpayload/drivers/i8042/keyboard.c`
 //! - OSDev Wiki: https://wiki.osdev.org/PS/2_Keyboard
 
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+
+use r_efi::protocols::simple_text_input_ex::{
+    CAPS_LOCK_ACTIVE, LEFT_ALT_PRESSED, LEFT_CONTROL_PRESSED, LEFT_LOGO_PRESSED,
+    LEFT_SHIFT_PRESSED, MENU_KEY_PRESSED, NUM_LOCK_ACTIVE, RIGHT_ALT_PRESSED,
+    RIGHT_CONTROL_PRESSED, RIGHT_LOGO_PRESSED, RIGHT_SHIFT_PRESSED, SCROLL_LOCK_ACTIVE,
+};
 use spin::Mutex;
 use tock_registers::interfaces::{Readable, Writeable};
 use tock_registers::register_bitfields;
 
 use crate::arch::x86_64::port_regs::{PortAliased8, PortReadWrite8};
+use crate::drivers::keyboard_layout::{self, ISO_KEY};
 
 // ============================================================================
 // Register Definitions using tock-registers
@@ -169,23 +183,16 @@ impl PS2Ports {
 // Keyboard State
 // ============================================================================
 
-/// Modifier key state
-#[derive(Clone, Copy, Default)]
-struct Modifiers {
-    shift: bool,
-    ctrl: bool,
-    alt: bool,
-    caps_lock: bool,
-}
-
 /// Keyboard driver state
 struct KeyboardState {
     /// Whether the keyboard has been initialized
     initialized: bool,
-    /// Current modifier key state
-    modifiers: Modifiers,
+    /// Modifier keys held, as EFI_KEY_SHIFT_STATE bits
+    shift_state: u32,
     /// Whether we're in an extended scancode sequence (0xE0 prefix)
     extended: bool,
+    /// Bytes left of a Pause key sequence (0xE1 prefix)
+    pause_bytes: u8,
     /// PS/2 port registers
     ports: PS2Ports,
 }
@@ -194,13 +201,9 @@ impl KeyboardState {
     const fn new() -> Self {
         KeyboardState {
             initialized: false,
-            modifiers: Modifiers {
-                shift: false,
-                ctrl: false,
-                alt: false,
-                caps_lock: false,
-            },
+            shift_state: 0,
             extended: false,
+            pause_bytes: 0,
             ports: PS2Ports::new(),
         }
     }
@@ -257,6 +260,20 @@ impl KeyboardState {
         self.ports.data.get() == response::ACK
     }
 
+    /// Whether any of the modifier keys in `bits` is held
+    fn held(&self, bits: u32) -> bool {
+        self.shift_state & bits != 0
+    }
+
+    /// Show the lock key states on the LEDs
+    fn set_leds(&self, toggles: u8) {
+        // The LED bits match EFI_KEY_TOGGLE_STATE
+        let leds = toggles & (SCROLL_LOCK_ACTIVE | NUM_LOCK_ACTIVE | CAPS_LOCK_ACTIVE);
+        if !self.send_keyboard_cmd(kb_cmd::SET_LEDS) || !self.send_keyboard_cmd(leds) {
+            log::debug!("PS/2 keyboard: failed to set LEDs");
+        }
+    }
+
     /// Flush any pending data from the controller
     fn flush_output(&self) {
         for _ in 0..100 {
@@ -281,6 +298,16 @@ impl KeyboardState {
 /// Global keyboard state
 static KEYBOARD: Mutex<KeyboardState> = Mutex::new(KeyboardState::new());
 
+/// Lock key states of all keyboards, as EFI_KEY_TOGGLE_STATE bits
+static TOGGLE_STATE: AtomicU8 = AtomicU8::new(NUM_LOCK_ACTIVE);
+
+/// Modifier keys held on the keyboard that typed last, as
+/// EFI_KEY_SHIFT_STATE bits
+static SHIFT_STATE: AtomicU32 = AtomicU32::new(0);
+
+/// Whether modifier and lock key presses are reported as keys
+static PARTIAL_KEYS: AtomicBool = AtomicBool::new(false);
+
 // ============================================================================
 // Public API
 // ============================================================================
@@ -380,6 +407,7 @@ pub fn init() {
         log::warn!("Failed to enable keyboard scanning");
         // Continue anyway - might still work
     }
+    kb.set_leds(toggle_state());
 
     kb.initialized = true;
     log::info!("PS/2 keyboard initialized");
@@ -404,6 +432,55 @@ pub fn has_key() -> bool {
     kb.has_data()
 }
 
+/// Lock key states, as EFI_KEY_TOGGLE_STATE bits
+pub fn toggle_state() -> u8 {
+    TOGGLE_STATE.load(Ordering::Relaxed)
+}
+
+/// Set the lock key states and the LEDs of the PS/2 keyboard
+///
+/// USB keyboards update their LEDs when they are next polled.
+pub fn set_toggle_state(toggles: u8) {
+    TOGGLE_STATE.store(toggles, Ordering::Relaxed);
+    let kb = KEYBOARD.lock();
+    if kb.initialized {
+        kb.set_leds(toggles);
+    }
+}
+
+/// Flip the lock key states in `bits`
+pub fn toggle(bits: u8) {
+    set_toggle_state(toggle_state() ^ bits);
+}
+
+/// Modifier keys held on the keyboard that typed last, as
+/// EFI_KEY_SHIFT_STATE bits
+pub fn shift_state() -> u32 {
+    SHIFT_STATE.load(Ordering::Relaxed)
+}
+
+/// Report presses of modifier and lock keys as keys, (0, 0)
+///
+/// These are the partial keystrokes of Simple Text Input Ex.
+pub fn set_partial_keys(enabled: bool) {
+    PARTIAL_KEYS.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether presses of modifier and lock keys are reported
+pub fn partial_keys() -> bool {
+    PARTIAL_KEYS.load(Ordering::Relaxed)
+}
+
+/// Key reported for a modifier or lock key press
+fn partial_key() -> Option<(u16, u16)> {
+    partial_keys().then_some((0, 0))
+}
+
+/// Record the modifier keys held on a USB keyboard
+pub fn set_shift_state(shift_state: u32) {
+    SHIFT_STATE.store(shift_state, Ordering::Relaxed);
+}
+
 /// Cleanup the keyboard controller before ExitBootServices
 ///
 /// This re-enables keyboard interrupts (IRQ1) so Linux can properly
@@ -452,9 +529,33 @@ pub fn cleanup() {
 ///
 /// Returns Some((scan_code, unicode_char)) if a key is available, None otherwise.
 /// The scan_code and unicode_char follow EFI conventions:
-/// - For printable characters: scan_code = 0, unicode_char = ASCII code
+/// - For printable characters: scan_code = 0, unicode_char = the character
 /// - For special keys: scan_code = EFI scan code, unicode_char = 0
+/// - Ctrl with a letter gives its control character, 0x01 for Ctrl+A
 pub fn try_read_key() -> Option<(u16, u16)> {
+    let (scan_code, unicode_char) = try_read_key_ex()?;
+    let ctrl = shift_state() & (LEFT_CONTROL_PRESSED | RIGHT_CONTROL_PRESSED) != 0;
+    if ctrl {
+        return Some((scan_code, control_char(unicode_char)));
+    }
+    Some((scan_code, unicode_char))
+}
+
+/// Control character typed with Ctrl, for letters
+pub fn control_char(unicode_char: u16) -> u16 {
+    match unicode_char {
+        0x41..=0x5A => unicode_char - 0x40,
+        0x61..=0x7A => unicode_char - 0x60,
+        _ => unicode_char,
+    }
+}
+
+/// Try to read a key as Simple Text Input Ex reports it
+///
+/// Like [`try_read_key`], but Ctrl doesn't change letters, [`shift_state`]
+/// tells whether it was held. With [`set_partial_keys`], pressing a
+/// modifier or lock key gives (0, 0).
+pub fn try_read_key_ex() -> Option<(u16, u16)> {
     // Poll USB keyboard to get latest key state
     crate::drivers::usb::poll_keyboards();
 
@@ -495,6 +596,16 @@ pub fn try_read_key() -> Option<(u16, u16)> {
 
 /// Process a scancode and return the corresponding EFI key
 fn process_scancode(kb: &mut KeyboardState, scancode: u8) -> Option<(u16, u16)> {
+    // Pause sends E1 1D 45 E1 9D C5, which would otherwise toggle Num Lock
+    if kb.pause_bytes > 0 {
+        kb.pause_bytes -= 1;
+        return None;
+    }
+    if scancode == 0xE1 {
+        kb.pause_bytes = 5;
+        return None;
+    }
+
     // Extended scancode prefix
     if scancode == 0xE0 {
         kb.extended = true;
@@ -509,44 +620,33 @@ fn process_scancode(kb: &mut KeyboardState, scancode: u8) -> Option<(u16, u16)>
     kb.extended = false;
 
     // Handle modifier keys
-    if !extended {
-        match code {
-            0x2A | 0x36 => {
-                // Left/Right Shift
-                kb.modifiers.shift = !is_release;
-                return None;
-            }
-            0x1D => {
-                // Left Control
-                kb.modifiers.ctrl = !is_release;
-                return None;
-            }
-            0x38 => {
-                // Left Alt
-                kb.modifiers.alt = !is_release;
-                return None;
-            }
-            0x3A if !is_release => {
-                // Caps Lock toggle
-                kb.modifiers.caps_lock = !kb.modifiers.caps_lock;
-                return None;
-            }
-            _ => {}
-        }
-    } else {
-        match code {
-            0x1D => {
-                // Right Control
-                kb.modifiers.ctrl = !is_release;
-                return None;
-            }
-            0x38 => {
-                // Right Alt
-                kb.modifiers.alt = !is_release;
-                return None;
-            }
-            _ => {}
+    let modifier = match (extended, code) {
+        (false, 0x2A) => LEFT_SHIFT_PRESSED,
+        (false, 0x36) => RIGHT_SHIFT_PRESSED,
+        (false, 0x1D) => LEFT_CONTROL_PRESSED,
+        (true, 0x1D) => RIGHT_CONTROL_PRESSED,
+        (false, 0x38) => LEFT_ALT_PRESSED,
+        (true, 0x38) => RIGHT_ALT_PRESSED,
+        (true, 0x5B) => LEFT_LOGO_PRESSED,
+        (true, 0x5C) => RIGHT_LOGO_PRESSED,
+        (true, 0x5D) => MENU_KEY_PRESSED,
+        _ => 0,
+    };
+    if modifier != 0 {
+        let previous = kb.shift_state;
+        if is_release {
+            kb.shift_state &= !modifier;
+        } else {
+            kb.shift_state |= modifier;
         }
+        SHIFT_STATE.store(kb.shift_state, Ordering::Relaxed);
+        // Typematic repeat of a held modifier isn't a new press
+        let repeat = !is_release && kb.shift_state == previous;
+        return if is_release || repeat {
+            None
+        } else {
+            partial_key()
+        };
     }
 
     // Only process key presses, not releases
@@ -554,6 +654,21 @@ fn process_scancode(kb: &mut KeyboardState, scancode: u8) -> Option<(u16, u16)>
         return None;
     }
 
+    // Lock keys
+    let lock = match (extended, code) {
+        (false, 0x3A) => CAPS_LOCK_ACTIVE,
+        (false, 0x45) => NUM_LOCK_ACTIVE,
+        (false, 0x46) => SCROLL_LOCK_ACTIVE,
+        _ => 0,
+    };
+    if lock != 0 {
+        let toggles = toggle_state() ^ lock;
+        TOGGLE_STATE.store(toggles, Ordering::Relaxed);
+        kb.set_leds(toggles);
+        return partial_key();
+    }
+    SHIFT_STATE.store(kb.shift_state, Ordering::Relaxed);
+
     // Convert scancode to EFI key
     if extended {
         scancode_to_efi_extended(code)
@@ -633,13 +748,13 @@ fn scancode_to_efi_normal(kb: &KeyboardState, code: u8) -> Option<(u16, u16)> {
     }
 
     // Regular character keys
-    let shift = kb.modifiers.shift;
-    let caps = kb.modifiers.caps_lock;
-    let ctrl = kb.modifiers.ctrl;
+    let shift = kb.held(LEFT_SHIFT_PRESSED | RIGHT_SHIFT_PRESSED);
+    let caps = toggle_state() & CAPS_LOCK_ACTIVE != 0;
+    let altgr = kb.held(RIGHT_ALT_PRESSED);
 
-    // US keyboard layout - unshifted characters
+    // Keys by what they type on a US keyboard, see keyboard_layout
     #[rustfmt::skip]
-    static UNSHIFTED: [u8; 89] = [
+    static KEYS: [u8; 89] = [
         0,    0x1B, b'1', b'2', b'3', b'4', b'5', b'6',     // 0x00-0x07
         b'7', b'8', b'9', b'0', b'-', b'=', 0x08, b'\t',    // 0x08-0x0F
         b'q', b'w', b'e', b'r', b't', b'y', b'u', b'i',     // 0x10-0x17
@@ -650,54 +765,27 @@ fn scancode_to_efi_normal(kb: &KeyboardState, code: u8) -> Option<(u16, u16)> {
         0,    b' ', 0,    0,    0,    0,    0,    0,        // 0x38-0x3F
         0,    0,    0,    0,    0,    0,    0,    b'7',     // 0x40-0x47
         b'8', b'9', b'-', b'4', b'5', b'6', b'+', b'1',     // 0x48-0x4F
-        b'2', b'3', b'0', b'.', 0,    0,    0,    0,        // 0x50-0x57
-        0,                                                  // 0x58
-    ];
-
-    // US keyboard layout - shifted characters
-    #[rustfmt::skip]
-    static SHIFTED: [u8; 89] = [
-        0,    0x1B, b'!', b'@', b'#', b'$', b'%', b'^',     // 0x00-0x07
-        b'&', b'*', b'(', b')', b'_', b'+', 0x08, b'\t',    // 0x08-0x0F
-        b'Q', b'W', b'E', b'R', b'T', b'Y', b'U', b'I',     // 0x10-0x17
-        b'O', b'P', b'{', b'}', 0x0D, 0,    b'A', b'S',     // 0x18-0x1F
-        b'D', b'F', b'G', b'H', b'J', b'K', b'L', b':',     // 0x20-0x27
-        b'"', b'~', 0,    b'|', b'Z', b'X', b'C', b'V',     // 0x28-0x2F
-        b'B', b'N', b'M', b'<', b'>', b'?', 0,    b'*',     // 0x30-0x37
-        0,    b' ', 0,    0,    0,    0,    0,    0,        // 0x38-0x3F
-        0,    0,    0,    0,    0,    0,    0,    b'7',     // 0x40-0x47
-        b'8', b'9', b'-', b'4', b'5', b'6', b'+', b'1',     // 0x48-0x4F
-        b'2', b'3', b'0', b'.', 0,    0,    0,    0,        // 0x50-0x57
+        b'2', b'3', b'0', b'.', 0,    0,    ISO_KEY, 0,     // 0x50-0x57
         0,                                                  // 0x58
     ];
 
-    if code as usize >= UNSHIFTED.len() {
+    let key = *KEYS.get(code as usize)?;
+    if key == 0 {
         return None;
     }
 
-    let mut ch = if shift {
-        SHIFTED[code as usize]
+    // The keypad is the same on every layout, and moves the cursor
+    // without Num Lock like the keys of the extended block
+    let keypad = code == 0x37 || (0x47..=0x53).contains(&code);
+    let digit = key.is_ascii_digit() || key == b'.';
+    if keypad && digit && toggle_state() & NUM_LOCK_ACTIVE == 0 {
+        return scancode_to_efi_extended(code);
+    }
+    let ch = if keypad {
+        key as char
     } else {
-        UNSHIFTED[code as usize]
+        keyboard_layout::layout().translate(key, shift, caps, altgr)?
     };
 
-    if ch == 0 {
-        return None;
-    }
-
-    // Apply caps lock to letters
-    if caps && ch.is_ascii_alphabetic() {
-        if shift {
-            ch = ch.to_ascii_lowercase();
-        } else {
-            ch = ch.to_ascii_uppercase();
-        }
-    }
-
-    // Apply control modifier
-    if ctrl && ch.is_ascii_alphabetic() {
-        ch = ch.to_ascii_lowercase() - b'a' + 1;
-    }
-
     Some((0, ch as u16))
 }
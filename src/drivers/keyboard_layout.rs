@@ -0,0 +1,236 @@
+//! Keyboard layouts
+//!
+//! The PS/2 and USB keyboard drivers name a key by the character it types
+//! on a US keyboard without modifiers, e.g. `b'y'` for the key that types
+//! `z` on a German keyboard, and leave the character to the layout. This
+//! keeps passphrases typed at the boot menu or a disk unlock prompt the
+//! same as in the OS on AZERTY and QWERTZ keyboards.
+//!
+//! The layout is read from the `KeyboardLayout` variable under
+//! [`CRABEFI_VARIABLE_GUID`], or else from the VPD key
+//! `crabefi_keyboard_layout`: `us`, `uk`, `de` or `fr`. Dead keys type their
+//! accent on its own.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::coreboot::vpd;
+use crate::efi::runtime_services::{CRABEFI_VARIABLE_GUID, read_variable};
+
+/// Variable holding the layout
+const LAYOUT_VARIABLE: &str = "KeyboardLayout";
+
+/// The extra key left of Z on ISO keyboards, which types `\` on a US one
+pub const ISO_KEY: u8 = 0x80;
+
+/// A keyboard layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    Us,
+    Uk,
+    De,
+    Fr,
+}
+
+/// A key that types something else than on a US keyboard: the US key, then
+/// its character, with Shift and with AltGr, `'\0'` for none
+type Key = (u8, char, char, char);
+
+#[rustfmt::skip]
+static UK: &[Key] = &[
+    (b'`', '`', '¬', '¦'),
+    (b'2', '2', '"', '\0'),
+    (b'3', '3', '£', '\0'),
+    (b'4', '4', '$', '€'),
+    (b'\'', '\'', '@', '\0'),
+    (b'\\', '#', '~', '\0'),
+    (ISO_KEY, '\\', '|', '\0'),
+];
+
+#[rustfmt::skip]
+static DE: &[Key] = &[
+    (b'`', '^', '°', '\0'),
+    (b'2', '2', '"', '²'),
+    (b'3', '3', '§', '³'),
+    (b'6', '6', '&', '\0'),
+    (b'7', '7', '/', '{'),
+    (b'8', '8', '(', '['),
+    (b'9', '9', ')', ']'),
+    (b'0', '0', '=', '}'),
+    (b'-', 'ß', '?', '\\'),
+    (b'=', '´', '`', '\0'),
+    (b'q', 'q', 'Q', '@'),
+    (b'e', 'e', 'E', '€'),
+    (b'y', 'z', 'Z', '\0'),
+    (b'[', 'ü', 'Ü', '\0'),
+    (b']', '+', '*', '~'),
+    (b';', 'ö', 'Ö', '\0'),
+    (b'\'', 'ä', 'Ä', '\0'),
+    (b'\\', '#', '\'', '\0'),
+    (ISO_KEY, '<', '>', '|'),
+    (b'z', 'y', 'Y', '\0'),
+    (b'm', 'm', 'M', 'µ'),
+    (b',', ',', ';', '\0'),
+    (b'.', '.', ':', '\0'),
+    (b'/', '-', '_', '\0'),
+];
+
+#[rustfmt::skip]
+static FR: &[Key] = &[
+    (b'`', '²', '\0', '\0'),
+    (b'1', '&', '1', '\0'),
+    (b'2', 'é', '2', '~'),
+    (b'3', '"', '3', '#'),
+    (b'4', '\'', '4', '{'),
+    (b'5', '(', '5', '['),
+    (b'6', '-', '6', '|'),
+    (b'7', 'è', '7', '`'),
+    (b'8', '_', '8', '\\'),
+    (b'9', 'ç', '9', '^'),
+    (b'0', 'à', '0', '@'),
+    (b'-', ')', '°', ']'),
+    (b'=', '=', '+', '}'),
+    (b'q', 'a', 'A', '\0'),
+    (b'w', 'z', 'Z', '\0'),
+    (b'e', 'e', 'E', '€'),
+    (b'[', '^', '¨', '\0'),
+    (b']', '$', '£', '¤'),
+    (b'a', 'q', 'Q', '\0'),
+    (b';', 'm', 'M', '\0'),
+    (b'\'', 'ù', '%', '\0'),
+    (b'\\', '*', 'µ', '\0'),
+    (ISO_KEY, '<', '>', '\0'),
+    (b'z', 'w', 'W', '\0'),
+    (b'm', ',', '?', '\0'),
+    (b',', ';', '.', '\0'),
+    (b'.', ':', '/', '\0'),
+    (b'/', '!', '§', '\0'),
+];
+
+/// The selected layout
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us as u8);
+
+impl Layout {
+    /// Parse a layout name
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "us" => Some(Layout::Us),
+            "uk" | "gb" => Some(Layout::Uk),
+            "de" => Some(Layout::De),
+            "fr" => Some(Layout::Fr),
+            _ => None,
+        }
+    }
+
+    /// Keys that differ from the US layout
+    fn keys(&self) -> &'static [Key] {
+        match self {
+            Layout::Us => &[],
+            Layout::Uk => UK,
+            Layout::De => DE,
+            Layout::Fr => FR,
+        }
+    }
+
+    /// Character typed by `key`, or `None` if it types nothing
+    ///
+    /// Caps Lock only affects letters. Keys without an AltGr character type
+    /// as if AltGr was released.
+    pub fn translate(&self, key: u8, shift: bool, caps_lock: bool, altgr: bool) -> Option<char> {
+        let (normal, shifted, with_altgr) = match self.keys().iter().find(|k| k.0 == key) {
+            Some(&(_, normal, shifted, with_altgr)) => (normal, shifted, with_altgr),
+            None => (key as char, us_shifted(key) as char, '\0'),
+        };
+        let ch = if altgr && with_altgr != '\0' {
+            with_altgr
+        } else if shift ^ (caps_lock && normal.is_lowercase() && shifted.is_uppercase()) {
+            shifted
+        } else {
+            normal
+        };
+        (ch != '\0').then_some(ch)
+    }
+}
+
+/// Character typed by a key with Shift on a US keyboard
+fn us_shifted(key: u8) -> u8 {
+    const KEYS: &[u8] = b"`1234567890-=[]\\;',./";
+    const SHIFTED: &[u8] = b"~!@#$%^&*()_+{}|:\"<>?";
+    match key {
+        b'a'..=b'z' => key.to_ascii_uppercase(),
+        ISO_KEY => b'|',
+        _ => KEYS
+            .iter()
+            .position(|&k| k == key)
+            .map_or(key, |i| SHIFTED[i]),
+    }
+}
+
+/// The selected layout
+pub fn layout() -> Layout {
+    match LAYOUT.load(Ordering::Relaxed) {
+        1 => Layout::Uk,
+        2 => Layout::De,
+        3 => Layout::Fr,
+        _ => Layout::Us,
+    }
+}
+
+/// Select the layout from its variable, or else from VPD
+pub fn init() {
+    let mut buf = [0u8; 16];
+    let name = match read_variable(LAYOUT_VARIABLE, &CRABEFI_VARIABLE_GUID, &mut buf) {
+        Some(len) => core::str::from_utf8(&buf[..len])
+            .ok()
+            .map(|text| text.trim_end_matches('\0')),
+        None => vpd::find_str(vpd::KEY_KEYBOARD_LAYOUT),
+    };
+    let Some(name) = name else {
+        return;
+    };
+    match Layout::parse(name) {
+        Some(new) if new != layout() => {
+            log::info!("Keyboard: {:?} layout", new);
+            LAYOUT.store(new as u8, Ordering::Relaxed);
+        }
+        Some(_) => {}
+        None => log::warn!("Keyboard: unknown layout {:?}", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_keys() {
+        let us = Layout::Us;
+        assert_eq!(us.translate(b'a', false, false, false), Some('a'));
+        assert_eq!(us.translate(b'a', false, true, false), Some('A'));
+        assert_eq!(us.translate(b'2', true, false, false), Some('@'));
+        assert_eq!(us.translate(b'2', false, true, false), Some('2'));
+        assert_eq!(us.translate(b' ', true, false, true), Some(' '));
+
+        let de = Layout::De;
+        assert_eq!(de.translate(b'y', false, false, false), Some('z'));
+        assert_eq!(de.translate(b'z', true, false, false), Some('Y'));
+        assert_eq!(de.translate(b'q', false, false, true), Some('@'));
+        assert_eq!(de.translate(b';', false, true, false), Some('Ö'));
+        assert_eq!(de.translate(b'-', false, true, false), Some('ß'));
+
+        let fr = Layout::Fr;
+        assert_eq!(fr.translate(b'q', false, false, false), Some('a'));
+        assert_eq!(fr.translate(b'1', false, false, false), Some('&'));
+        assert_eq!(fr.translate(b'1', true, false, false), Some('1'));
+        assert_eq!(fr.translate(b'm', false, false, false), Some(','));
+        assert_eq!(fr.translate(b'`', true, false, false), None);
+        assert_eq!(fr.translate(ISO_KEY, false, false, false), Some('<'));
+    }
+
+    #[test]
+    fn parses_names() {
+        assert_eq!(Layout::parse(" de"), Some(Layout::De));
+        assert_eq!(Layout::parse("gb"), Some(Layout::Uk));
+        assert_eq!(Layout::parse("dvorak"), None);
+    }
+}
@@ -4,11 +4,18 @@
 
 pub mod ahci;
 pub mod block;
+pub mod bmc_console;
+pub mod cmos;
+pub mod edid;
 pub mod keyboard;
+pub mod keyboard_layout;
 pub mod mmio;
 pub mod nvme;
 pub mod pci;
+pub mod ramdisk;
 pub mod sdhci;
 pub mod serial;
+pub mod serial_keys;
+pub mod spi_flash;
 pub mod storage;
 pub mod usb;
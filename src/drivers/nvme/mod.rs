@@ -63,7 +63,11 @@ register_bitfields! [
         /// Arbitration Mechanism Selected
         AMS OFFSET(11) NUMBITS(3) [],
         /// Shutdown Notification
-        SHN OFFSET(14) NUMBITS(2) [],
+        SHN OFFSET(14) NUMBITS(2) [
+            None = 0,
+            Normal = 1,
+            Abrupt = 2
+        ],
         /// I/O Submission Queue Entry Size (2^IOSQES bytes)
         IOSQES OFFSET(16) NUMBITS(4) [],
         /// I/O Completion Queue Entry Size (2^IOCQES bytes)
@@ -76,7 +80,11 @@ register_bitfields! [
         /// Controller Fatal Status
         CFS OFFSET(1) NUMBITS(1) [],
         /// Shutdown Status
-        SHST OFFSET(2) NUMBITS(2) [],
+        SHST OFFSET(2) NUMBITS(2) [
+            Normal = 0,
+            Occurring = 1,
+            Complete = 2
+        ],
         /// NVM Subsystem Reset Occurred
         NSSRO OFFSET(4) NUMBITS(1) [],
         /// Processing Paused
@@ -145,6 +153,9 @@ mod io_cmd {
     pub const READ: u8 = 0x02;
 }
 
+/// Time a controller gets to finish a normal shutdown, as Linux allows
+const SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+
 /// Queue sizes (must be power of 2)
 const ADMIN_QUEUE_SIZE: usize = 16;
 const IO_QUEUE_SIZE: usize = 64;
@@ -451,6 +462,10 @@ pub struct NvmeController {
     namespaces: heapless::Vec<NvmeNamespace, 8>,
     /// Page-aligned DMA buffer for data transfers (avoids corruption from misaligned buffers)
     dma_buffer: *mut u8,
+    /// Serial number as reported, padded with spaces
+    serial_number: [u8; 20],
+    /// Whether Security Send/Receive are supported (OACS bit 0)
+    supports_security: bool,
 }
 
 /// NVMe error type
@@ -530,6 +545,8 @@ impl NvmeController {
             io_cq_phase: true,
             namespaces: heapless::Vec::new(),
             dma_buffer,
+            serial_number: [b' '; 20],
+            supports_security: false,
         };
 
         controller.init()?;
@@ -717,6 +734,9 @@ impl NvmeController {
             firmware
         );
 
+        self.serial_number = ctrl.sn;
+        self.supports_security = ctrl.oacs & 1 != 0;
+
         // Free the identify data page
         efi::free_pages(identify_mem, 1);
 
@@ -850,6 +870,16 @@ impl NvmeController {
         self.pci_address
     }
 
+    /// Serial number as reported, padded with spaces
+    pub fn serial_number(&self) -> &[u8; 20] {
+        &self.serial_number
+    }
+
+    /// Whether the controller supports Security Send/Receive
+    pub fn supports_security(&self) -> bool {
+        self.supports_security
+    }
+
     /// Submit an I/O command
     fn submit_io_command(&mut self, cmd: &SubmissionQueueEntry) -> u16 {
         let tail = self.io_sq_tail as usize;
@@ -1025,15 +1055,15 @@ impl NvmeController {
         );
 
         // Build security receive command
-        // CDW10: Security Protocol ID (bits 31:24), reserved (bits 23:16), SP Specific (bits 15:0)
-        // CDW11: Allocation Length (transfer length in dwords)
+        // CDW10: Security Protocol (bits 31:24), SP Specific (bits 23:8)
+        // CDW11: Allocation Length in bytes
         let mut cmd = SubmissionQueueEntry::new();
         cmd.set_opcode(admin_cmd::SECURITY_RECEIVE);
         cmd.set_cid(self.next_command_id());
         cmd.nsid = nsid;
         cmd.prp1 = self.dma_buffer as u64;
-        cmd.cdw10 = ((protocol_id as u32) << 24) | (sp_specific as u32);
-        cmd.cdw11 = (buffer.len() as u32 + 3) / 4; // Allocation length in dwords
+        cmd.cdw10 = ((protocol_id as u32) << 24) | ((sp_specific as u32) << 8);
+        cmd.cdw11 = buffer.len() as u32;
 
         let cid = self.submit_admin_command(&cmd);
         let completion = self.wait_admin_completion(cid)?;
@@ -1095,15 +1125,15 @@ impl NvmeController {
         }
 
         // Build security send command
-        // CDW10: Security Protocol ID (bits 31:24), reserved (bits 23:16), SP Specific (bits 15:0)
-        // CDW11: Transfer Length (in dwords)
+        // CDW10: Security Protocol (bits 31:24), SP Specific (bits 23:8)
+        // CDW11: Transfer Length in bytes
         let mut cmd = SubmissionQueueEntry::new();
         cmd.set_opcode(admin_cmd::SECURITY_SEND);
         cmd.set_cid(self.next_command_id());
         cmd.nsid = nsid;
         cmd.prp1 = self.dma_buffer as u64;
-        cmd.cdw10 = ((protocol_id as u32) << 24) | (sp_specific as u32);
-        cmd.cdw11 = (buffer.len() as u32 + 3) / 4; // Transfer length in dwords
+        cmd.cdw10 = ((protocol_id as u32) << 24) | ((sp_specific as u32) << 8);
+        cmd.cdw11 = buffer.len() as u32;
 
         let cid = self.submit_admin_command(&cmd);
         self.wait_admin_completion(cid)?;
@@ -1122,6 +1152,72 @@ impl NvmeController {
         let regs = unsafe { &*self.regs };
         regs.vs.get()
     }
+
+    /// Delete the I/O queues created by `create_io_queues`
+    fn delete_io_queues(&mut self) -> Result<(), NvmeError> {
+        if self.io_sq.is_null() {
+            return Ok(());
+        }
+
+        // The submission queue must go before the completion queue it posts to
+        for opcode in [admin_cmd::DELETE_SQ, admin_cmd::DELETE_CQ] {
+            let mut cmd = SubmissionQueueEntry::new();
+            cmd.set_opcode(opcode);
+            cmd.set_cid(self.next_command_id());
+            cmd.cdw10 = 1; // QID
+
+            let cid = self.submit_admin_command(&cmd);
+            self.wait_admin_completion(cid)?;
+        }
+
+        self.io_sq = ptr::null_mut();
+        self.io_cq = ptr::null_mut();
+        self.io_sq_tail = 0;
+        self.io_cq_head = 0;
+        self.io_cq_phase = true;
+        log::debug!("Deleted I/O queues");
+        Ok(())
+    }
+
+    /// Clean up the controller before handing off to the OS
+    ///
+    /// Deletes the I/O queues, sends a normal shutdown notification so the
+    /// drive flushes its caches, and disables the controller, leaving it as
+    /// after a reset for the OS driver.
+    pub fn cleanup(&mut self) {
+        log::debug!(
+            "NVMe cleanup: shutting down controller at {}",
+            self.pci_address
+        );
+
+        let regs = unsafe { &*self.regs };
+        if regs.csts.read(CSTS::RDY) == 0 || regs.csts.read(CSTS::CFS) != 0 {
+            log::warn!(
+                "NVMe controller at {} not ready, only disabling",
+                self.pci_address
+            );
+        } else {
+            if let Err(e) = self.delete_io_queues() {
+                log::warn!("NVMe: failed to delete I/O queues: {:?}", e);
+            }
+
+            regs.cc.modify(CC::SHN::Normal);
+            if !wait_for(SHUTDOWN_TIMEOUT_MS, || {
+                regs.csts.matches_all(CSTS::SHST::Complete)
+            }) {
+                log::warn!("NVMe: shutdown notification timed out");
+            }
+        }
+
+        // CAP.TO bounds how long the controller may take to clear RDY
+        let timeout_ms = regs.cap.read(CAP::TO).max(1) * 500;
+        regs.cc.modify(CC::EN::CLEAR + CC::SHN::None);
+        if !wait_for(timeout_ms, || regs.csts.read(CSTS::RDY) == 0) {
+            log::warn!("NVMe: controller did not disable");
+        }
+
+        log::debug!("NVMe cleanup complete");
+    }
 }
 
 /// Wrapper for NVMe controller pointer to implement Send
@@ -1148,8 +1244,8 @@ pub fn init() {
         return;
     }
 
-    let mut controllers = NVME_CONTROLLERS.lock();
-
+    // The list is only locked to add a controller: controller init waits,
+    // and other parallel init tasks run meanwhile, see crate::parallel
     for dev in nvme_devices.iter() {
         match NvmeController::new(dev) {
             Ok(controller) => {
@@ -1167,7 +1263,11 @@ pub fn init() {
                     unsafe {
                         ptr::write(controller_box, controller);
                     }
-                    if controllers.push(NvmeControllerPtr(controller_box)).is_err() {
+                    if NVME_CONTROLLERS
+                        .lock()
+                        .push(NvmeControllerPtr(controller_box))
+                        .is_err()
+                    {
                         log::warn!(
                             "NVMe: Failed to register controller at {} - controller list full",
                             dev.address
@@ -1189,10 +1289,23 @@ pub fn init() {
 
     log::info!(
         "NVMe initialization complete: {} controllers",
-        controllers.len()
+        NVME_CONTROLLERS.lock().len()
     );
 }
 
+/// Shut down all NVMe controllers before ExitBootServices
+///
+/// Linux's nvme driver expects to find the controller disabled, with no
+/// queues left over from the firmware.
+pub fn cleanup() {
+    let controllers = NVME_CONTROLLERS.lock();
+    for ptr in controllers.iter() {
+        // SAFETY: see NvmeControllerPtr; the lock is held for the whole loop
+        let controller = unsafe { &mut *ptr.0 };
+        controller.cleanup();
+    }
+}
+
 /// Get the first NVMe controller
 pub fn get_controller(index: usize) -> Option<&'static mut NvmeController> {
     let controllers = NVME_CONTROLLERS.lock();
@@ -0,0 +1,156 @@
+//! PCI class, capability and vendor names
+//!
+//! Class codes and capability IDs are decoded into the names `lspci` uses,
+//! so device listings in the log and the recovery console are readable
+//! without looking up numbers. The vendor table only knows vendors common on
+//! coreboot boards and in virtual machines; it is left out of the image
+//! unless the `pci-names` feature is enabled.
+
+/// Name of a class code, as specific as the table knows it
+pub fn class_name(class: u8, subclass: u8, prog_if: u8) -> &'static str {
+    match (class, subclass, prog_if) {
+        (0x01, 0x00, _) => "SCSI storage controller",
+        (0x01, 0x01, _) => "IDE interface",
+        (0x01, 0x04, _) => "RAID bus controller",
+        (0x01, 0x05, _) => "ATA controller",
+        (0x01, 0x06, 0x01) => "SATA controller (AHCI)",
+        (0x01, 0x06, _) => "SATA controller",
+        (0x01, 0x07, _) => "SAS controller",
+        (0x01, 0x08, 0x02) => "NVMe controller",
+        (0x01, 0x08, _) => "Non-volatile memory controller",
+        (0x01, _, _) => "Mass storage controller",
+        (0x02, 0x00, _) => "Ethernet controller",
+        (0x02, _, _) => "Network controller",
+        (0x03, 0x00, _) => "VGA compatible controller",
+        (0x03, 0x02, _) => "3D controller",
+        (0x03, _, _) => "Display controller",
+        (0x04, 0x01, _) => "Multimedia audio controller",
+        (0x04, 0x03, _) => "Audio device",
+        (0x04, _, _) => "Multimedia controller",
+        (0x05, _, _) => "Memory controller",
+        (0x06, 0x00, _) => "Host bridge",
+        (0x06, 0x01, _) => "ISA bridge",
+        (0x06, 0x04, _) => "PCI bridge",
+        (0x06, 0x07, _) => "CardBus bridge",
+        (0x06, _, _) => "Bridge",
+        (0x07, 0x00, _) => "Serial controller",
+        (0x07, _, _) => "Communication controller",
+        (0x08, 0x05, _) => "SD Host controller",
+        (0x08, _, _) => "System peripheral",
+        (0x09, _, _) => "Input device controller",
+        (0x0C, 0x03, 0x00) => "USB controller (UHCI)",
+        (0x0C, 0x03, 0x10) => "USB controller (OHCI)",
+        (0x0C, 0x03, 0x20) => "USB controller (EHCI)",
+        (0x0C, 0x03, 0x30) => "USB controller (xHCI)",
+        (0x0C, 0x03, _) => "USB controller",
+        (0x0C, 0x05, _) => "SMBus",
+        (0x0C, _, _) => "Serial bus controller",
+        (0x0D, _, _) => "Wireless controller",
+        (0x10, _, _) => "Encryption controller",
+        (0x11, _, _) => "Signal processing controller",
+        _ => "Unclassified device",
+    }
+}
+
+/// Name of a capability in the standard capability list
+pub fn capability_name(id: u8) -> &'static str {
+    match id {
+        0x01 => "Power Management",
+        0x02 => "AGP",
+        0x03 => "VPD",
+        0x04 => "Slot ID",
+        0x05 => "MSI",
+        0x06 => "CompactPCI Hot Swap",
+        0x07 => "PCI-X",
+        0x08 => "HyperTransport",
+        0x09 => "Vendor Specific",
+        0x0A => "Debug port",
+        0x0C => "PCI Hot-Plug",
+        0x0D => "Subsystem ID",
+        0x0E => "AGP 8x",
+        0x0F => "Secure Device",
+        0x10 => "PCI Express",
+        0x11 => "MSI-X",
+        0x12 => "SATA",
+        0x13 => "Advanced Features",
+        0x14 => "Enhanced Allocation",
+        _ => "Unknown",
+    }
+}
+
+/// Known vendors, sorted by ID
+#[cfg(feature = "pci-names")]
+const VENDORS: &[(u16, &str)] = &[
+    (0x1002, "AMD/ATI"),
+    (0x1022, "AMD"),
+    (0x104C, "Texas Instruments"),
+    (0x10DE, "NVIDIA"),
+    (0x10EC, "Realtek"),
+    (0x1106, "VIA"),
+    (0x1179, "Toshiba"),
+    (0x1180, "Ricoh"),
+    (0x11AB, "Marvell"),
+    (0x1217, "O2 Micro"),
+    (0x1234, "QEMU"),
+    (0x126F, "Silicon Motion"),
+    (0x1344, "Micron"),
+    (0x144D, "Samsung"),
+    (0x14C3, "MediaTek"),
+    (0x14E4, "Broadcom"),
+    (0x15AD, "VMware"),
+    (0x15B7, "SanDisk"),
+    (0x168C, "Qualcomm Atheros"),
+    (0x17CB, "Qualcomm"),
+    (0x1912, "Renesas"),
+    (0x197B, "JMicron"),
+    (0x1987, "Phison"),
+    (0x1AF4, "Red Hat (virtio)"),
+    (0x1B21, "ASMedia"),
+    (0x1B36, "Red Hat (QEMU)"),
+    (0x1B4B, "Marvell"),
+    (0x1C5C, "SK hynix"),
+    (0x1D6A, "Aquantia"),
+    (0x1E0F, "KIOXIA"),
+    (0x1E4B, "MAXIO"),
+    (0x2646, "Kingston"),
+    (0x8086, "Intel"),
+    (0x80EE, "VirtualBox"),
+];
+
+/// Name of a vendor ID, if the `pci-names` table knows it
+pub fn vendor_name(vendor: u16) -> Option<&'static str> {
+    #[cfg(feature = "pci-names")]
+    {
+        VENDORS
+            .binary_search_by_key(&vendor, |&(id, _)| id)
+            .ok()
+            .map(|i| VENDORS[i].1)
+    }
+    #[cfg(not(feature = "pci-names"))]
+    {
+        let _ = vendor;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn class_names() {
+        assert_eq!(class_name(0x01, 0x08, 0x02), "NVMe controller");
+        assert_eq!(class_name(0x01, 0x06, 0x01), "SATA controller (AHCI)");
+        assert_eq!(class_name(0x01, 0x80, 0x00), "Mass storage controller");
+        assert_eq!(class_name(0x0C, 0x03, 0x30), "USB controller (xHCI)");
+        assert_eq!(class_name(0xFF, 0x00, 0x00), "Unclassified device");
+    }
+
+    #[cfg(feature = "pci-names")]
+    #[test]
+    fn vendor_table_is_sorted() {
+        assert!(VENDORS.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(vendor_name(0x8086), Some("Intel"));
+        assert_eq!(vendor_name(0xFFFF), None);
+    }
+}
@@ -2,10 +2,33 @@
 //!
 //! This module provides PCI device enumeration and configuration space access.
 //! It supports both legacy I/O port-based access (CAM) and memory-mapped access (ECAM).
-
+//!
+//! Enumeration touches devices coreboot may not have configured. Sizing their
+//! BARs or reading absent functions can raise PCIe errors, which some
+//! chipsets escalate to an NMI or a hang when SERR# is enabled. While the
+//! scan runs, parity and system error reporting is therefore switched off on
+//! every function found, memory and I/O decode is off while a BAR is sized,
+//! and afterwards the original settings are restored and the error status
+//! the scan latched is cleared. Functions with the [`quirks::KEEP_CONFIG`]
+//! quirk, like those of the Intel ME, are only read.
+//!
+//! Devices behind Thunderbolt or USB4 show up as ordinary PCIe devices once
+//! coreboot has set up the tunnels, but an enclosure's link may come up only
+//! after the first scan. With the VPD key `crabefi_pci_rescan_delay` set to a
+//! number of milliseconds, enumeration waits that long and scans again for
+//! new functions. Such devices sit below a chain of switch bridges, so
+//! enabling a device also turns on memory decode and bus mastering of every
+//! bridge above it.
+
+pub mod ids;
+pub mod quirks;
+
+use core::fmt;
 use heapless::Vec;
 
+use crate::coreboot::vpd;
 use crate::state;
+use crate::time::{delay_ms, wait_for};
 
 #[cfg(target_arch = "x86_64")]
 use x86_64::instructions::port::{Port, PortWriteOnly};
@@ -41,14 +64,64 @@ pub const SUBCLASS_SDHCI: u8 = 0x05; // SD Host Controller
 /// Invalid vendor ID (no device present)
 const INVALID_VENDOR_ID: u16 = 0xFFFF;
 
+/// Vendor ID read back while a device answers with Configuration Request
+/// Retry Status, i.e. is still initializing
+const CRS_VENDOR_ID: u16 = 0x0001;
+
+/// How long a device may keep answering with retry status (PCIe: 1 second)
+const CRS_TIMEOUT_MS: u64 = 1000;
+
 /// PCI header types
 const HEADER_TYPE_NORMAL: u8 = 0x00;
-#[allow(dead_code)]
 const HEADER_TYPE_BRIDGE: u8 = 0x01;
 #[allow(dead_code)]
 const HEADER_TYPE_CARDBUS: u8 = 0x02;
 const HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
 
+/// Command register and the bits enumeration touches
+const COMMAND: u8 = 0x04;
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_PARITY_ERROR_RESPONSE: u16 = 1 << 6;
+const COMMAND_SERR: u16 = 1 << 8;
+
+/// Error bits of the (secondary) status register, all write-1-to-clear:
+/// master data parity error, signaled/received target abort, received
+/// master abort, signaled system error and detected parity error
+const STATUS_ERRORS: u16 = 0xF900;
+
+/// Bridge header: secondary bus number, secondary status and bridge control
+/// registers
+const SECONDARY_BUS: u8 = 0x19;
+const SECONDARY_STATUS: u8 = 0x1E;
+const BRIDGE_CONTROL: u8 = 0x3E;
+const BRIDGE_CONTROL_PARITY_ERROR_RESPONSE: u16 = 1 << 0;
+const BRIDGE_CONTROL_SERR: u16 = 1 << 1;
+
+/// PCI Express capability: device control and status, relative to the
+/// capability; the low four bits enable and report correctable, non-fatal,
+/// fatal and unsupported request errors
+const PCIE_DEVICE_CONTROL: u8 = 0x08;
+const PCIE_DEVICE_STATUS: u8 = 0x0A;
+const PCIE_ERROR_BITS: u16 = 0x000F;
+
+/// Status register bit: the device has a capability list
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+/// Offset of the capability list pointer in the configuration header
+const CAPABILITIES_POINTER: u8 = 0x34;
+
+/// Maximum number of capabilities recorded per device
+pub const MAX_CAPABILITIES: usize = 16;
+
+/// Capability IDs
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAP_PCI_EXPRESS: u8 = 0x10;
+pub const CAP_MSI_X: u8 = 0x11;
+
 /// PCI device location (Bus:Device.Function)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PciAddress {
@@ -77,8 +150,8 @@ impl PciAddress {
     }
 }
 
-impl core::fmt::Display for PciAddress {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
     }
 }
@@ -102,6 +175,38 @@ pub struct PciBar {
     pub prefetchable: bool,
 }
 
+impl fmt::Display for PciBar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.bar_type {
+            BarType::Unused => return f.write_str("unused"),
+            BarType::Io => "I/O ports",
+            BarType::Memory32 => "Memory (32-bit)",
+            BarType::Memory64 => "Memory (64-bit)",
+        };
+        write!(f, "{} at {:#x} [size={:#x}]", kind, self.address, self.size)?;
+        if self.prefetchable {
+            f.write_str(" prefetchable")?;
+        }
+        Ok(())
+    }
+}
+
+/// An entry of a device's capability list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    /// Capability ID
+    pub id: u8,
+    /// Offset of the capability in configuration space
+    pub offset: u8,
+}
+
+impl Capability {
+    /// Name of the capability
+    pub fn name(&self) -> &'static str {
+        ids::capability_name(self.id)
+    }
+}
+
 /// PCI device information
 #[derive(Debug, Clone)]
 pub struct PciDevice {
@@ -116,6 +221,9 @@ pub struct PciDevice {
     pub bars: [PciBar; 6],
     pub interrupt_line: u8,
     pub interrupt_pin: u8,
+    pub capabilities: Vec<Capability, MAX_CAPABILITIES>,
+    /// [Quirks](quirks) of the function
+    pub quirks: u32,
 }
 
 impl PciDevice {
@@ -133,9 +241,39 @@ impl PciDevice {
             bars: [PciBar::default(); 6],
             interrupt_line: 0,
             interrupt_pin: 0,
+            capabilities: Vec::new(),
+            quirks: 0,
         }
     }
 
+    /// Whether the function has `quirk`
+    pub fn has_quirk(&self, quirk: u32) -> bool {
+        self.quirks & quirk != 0
+    }
+
+    /// Whether a driver may bind to the function
+    pub fn driver_allowed(&self) -> bool {
+        !self.has_quirk(quirks::NO_DRIVER)
+    }
+
+    /// Name of the device's class
+    pub fn class_name(&self) -> &'static str {
+        ids::class_name(self.class_code, self.subclass, self.prog_if)
+    }
+
+    /// Name of the device's vendor, if known
+    pub fn vendor_name(&self) -> Option<&'static str> {
+        ids::vendor_name(self.vendor_id)
+    }
+
+    /// Configuration space offset of the first capability with this ID
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        self.capabilities
+            .iter()
+            .find(|cap| cap.id == id)
+            .map(|cap| cap.offset)
+    }
+
     /// Check if this is an NVMe controller
     pub fn is_nvme(&self) -> bool {
         self.class_code == CLASS_STORAGE && self.subclass == SUBCLASS_NVME
@@ -179,6 +317,28 @@ impl PciDevice {
     }
 }
 
+/// One line in the style of `lspci -nn`
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} [{:02x}{:02x}]: ",
+            self.address,
+            self.class_name(),
+            self.class_code,
+            self.subclass
+        )?;
+        if let Some(vendor) = self.vendor_name() {
+            write!(f, "{} ", vendor)?;
+        }
+        write!(
+            f,
+            "[{:04x}:{:04x}] (rev {:02x})",
+            self.vendor_id, self.device_id, self.revision
+        )
+    }
+}
+
 /// Read a 32-bit value from PCI configuration space using legacy I/O
 #[cfg(target_arch = "x86_64")]
 fn pci_read_config_u32(addr: PciAddress, offset: u8) -> u32 {
@@ -227,6 +387,184 @@ fn get_device_ids(addr: PciAddress) -> (u16, u16) {
     ((data & 0xFFFF) as u16, (data >> 16) as u16)
 }
 
+/// Whether a vendor ID read back names a device rather than an empty slot
+fn is_valid_vendor(vendor_id: u16) -> bool {
+    vendor_id != INVALID_VENDOR_ID && vendor_id != 0x0000
+}
+
+/// Read the IDs of a device, waiting out Configuration Request Retry Status
+///
+/// Returns `None` for absent functions and for devices that never finish
+/// initializing.
+fn probe_device_ids(addr: PciAddress) -> Option<(u16, u16)> {
+    let mut ids = get_device_ids(addr);
+    if ids.0 == CRS_VENDOR_ID {
+        if !wait_for(CRS_TIMEOUT_MS, || get_device_ids(addr).0 != CRS_VENDOR_ID) {
+            log::warn!(
+                "PCI {}: still not ready after {}ms, skipping",
+                addr,
+                CRS_TIMEOUT_MS
+            );
+            return None;
+        }
+        ids = get_device_ids(addr);
+    }
+    let (vendor_id, device_id) = ids;
+    (is_valid_vendor(vendor_id) && device_id != 0xFFFF).then_some(ids)
+}
+
+/// Error reporting settings of a function, saved while enumeration masks them
+#[derive(Clone, Copy)]
+struct MaskedErrors {
+    address: PciAddress,
+    command: u16,
+    /// Bridge control register of bridges
+    bridge_control: Option<u16>,
+    /// PCI Express capability offset and its device control register
+    pcie_device_control: Option<(u8, u16)>,
+}
+
+impl MaskedErrors {
+    /// Turn off parity and system error reporting of `dev`
+    fn mask(dev: &PciDevice) -> Self {
+        let address = dev.address;
+        let command = pci_read_config_u16(address, COMMAND);
+        write_config_u16(
+            address,
+            COMMAND,
+            command & !(COMMAND_PARITY_ERROR_RESPONSE | COMMAND_SERR),
+        );
+
+        let bridge_control = ((dev.header_type & 0x7F) == HEADER_TYPE_BRIDGE).then(|| {
+            let control = pci_read_config_u16(address, BRIDGE_CONTROL);
+            write_config_u16(
+                address,
+                BRIDGE_CONTROL,
+                control & !(BRIDGE_CONTROL_PARITY_ERROR_RESPONSE | BRIDGE_CONTROL_SERR),
+            );
+            control
+        });
+
+        let pcie_device_control = dev.find_capability(CAP_PCI_EXPRESS).map(|cap| {
+            let control = pci_read_config_u16(address, cap + PCIE_DEVICE_CONTROL);
+            write_config_u16(
+                address,
+                cap + PCIE_DEVICE_CONTROL,
+                control & !PCIE_ERROR_BITS,
+            );
+            (cap, control)
+        });
+
+        Self {
+            address,
+            command,
+            bridge_control,
+            pcie_device_control,
+        }
+    }
+
+    /// Clear the errors latched since `mask` and restore the saved settings
+    fn restore(&self) {
+        let address = self.address;
+
+        let status = pci_read_config_u16(address, COMMAND + 2) & STATUS_ERRORS;
+        if status != 0 {
+            log::debug!("PCI {}: clearing status errors {:#06x}", address, status);
+        }
+        // Status shares the dword with command and is write-1-to-clear
+        pci_write_config_u32(
+            address,
+            COMMAND,
+            ((status as u32) << 16) | self.command as u32,
+        );
+
+        if let Some(control) = self.bridge_control {
+            write_config_u16(address, SECONDARY_STATUS, STATUS_ERRORS);
+            write_config_u16(address, BRIDGE_CONTROL, control);
+        }
+
+        if let Some((cap, control)) = self.pcie_device_control {
+            write_config_u16(address, cap + PCIE_DEVICE_STATUS, PCIE_ERROR_BITS);
+            write_config_u16(address, cap + PCIE_DEVICE_CONTROL, control);
+        }
+    }
+}
+
+/// Size the BARs of a normal header with memory and I/O decode disabled
+///
+/// Host bridges keep decoding: turning them off could cut off memory the
+/// firmware itself is running from.
+fn probe_bars(dev: &mut PciDevice) {
+    let addr = dev.address;
+    let is_host_bridge = dev.class_code == CLASS_BRIDGE && dev.subclass == 0x00;
+    let command = pci_read_config_u16(addr, COMMAND);
+    if !is_host_bridge {
+        write_config_u16(
+            addr,
+            COMMAND,
+            command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+    }
+
+    let mut bar_index = 0;
+    while bar_index < 6 {
+        let bar = probe_bar(addr, bar_index);
+        dev.bars[bar_index] = bar;
+
+        // 64-bit BARs consume two slots
+        if bar.bar_type == BarType::Memory64 {
+            bar_index += 2;
+        } else {
+            bar_index += 1;
+        }
+    }
+
+    if !is_host_bridge {
+        write_config_u16(addr, COMMAND, command);
+    }
+}
+
+/// Read the BARs of a normal header without sizing them, their sizes stay 0
+fn read_bars(dev: &mut PciDevice) {
+    let addr = dev.address;
+    let mut bar_index = 0;
+    while bar_index < 6 {
+        let bar_offset = (0x10 + bar_index * 4) as u8;
+        let original = pci_read_config_u32(addr, bar_offset);
+        let bar = if original == 0 {
+            PciBar::default()
+        } else if original & 1 == 1 {
+            PciBar {
+                bar_type: BarType::Io,
+                address: (original & 0xFFFFFFFC) as u64,
+                size: 0,
+                prefetchable: false,
+            }
+        } else if (original >> 1) & 0x3 == 2 {
+            let original_hi = pci_read_config_u32(addr, bar_offset + 4);
+            PciBar {
+                bar_type: BarType::Memory64,
+                address: ((original_hi as u64) << 32) | (original & 0xFFFFFFF0) as u64,
+                size: 0,
+                prefetchable: original & 0x8 != 0,
+            }
+        } else {
+            PciBar {
+                bar_type: BarType::Memory32,
+                address: (original & 0xFFFFFFF0) as u64,
+                size: 0,
+                prefetchable: original & 0x8 != 0,
+            }
+        };
+        dev.bars[bar_index] = bar;
+        bar_index += if bar.bar_type == BarType::Memory64 {
+            2
+        } else {
+            1
+        };
+    }
+}
+
 /// Probe a single BAR and return its type, address, and size
 fn probe_bar(addr: PciAddress, bar_index: usize) -> PciBar {
     let bar_offset = (0x10 + bar_index * 4) as u8;
@@ -306,13 +644,19 @@ fn probe_bar(addr: PciAddress, bar_index: usize) -> PciBar {
 }
 
 /// Scan a single device/function and add to device list if valid
-fn scan_device(bus: u8, device: u8, function: u8) -> Option<PciDevice> {
+///
+/// The function's error reporting is masked before its BARs are sized and
+/// stays masked until `masked` is restored at the end of enumeration.
+/// `me_device` says function 0 of the device is an ME interface.
+fn scan_device(
+    bus: u8,
+    device: u8,
+    function: u8,
+    me_device: bool,
+    masked: &mut Vec<MaskedErrors, { state::MAX_PCI_DEVICES }>,
+) -> Option<PciDevice> {
     let addr = PciAddress::new(bus, device, function);
-    let (vendor_id, device_id) = get_device_ids(addr);
-
-    if vendor_id == INVALID_VENDOR_ID {
-        return None;
-    }
+    let (vendor_id, device_id) = probe_device_ids(addr)?;
 
     let mut dev = PciDevice::new(addr);
     dev.vendor_id = vendor_id;
@@ -320,6 +664,10 @@ fn scan_device(bus: u8, device: u8, function: u8) -> Option<PciDevice> {
 
     // Read class/subclass/prog_if/revision (offset 0x08)
     let class_data = pci_read_config_u32(addr, 0x08);
+    if class_data == 0xFFFF_FFFF {
+        log::warn!("PCI {}: stopped responding while probed, skipping", addr);
+        return None;
+    }
     dev.revision = (class_data & 0xFF) as u8;
     dev.prog_if = ((class_data >> 8) & 0xFF) as u8;
     dev.subclass = ((class_data >> 16) & 0xFF) as u8;
@@ -334,20 +682,48 @@ fn scan_device(bus: u8, device: u8, function: u8) -> Option<PciDevice> {
     dev.interrupt_line = (irq_data & 0xFF) as u8;
     dev.interrupt_pin = ((irq_data >> 8) & 0xFF) as u8;
 
-    // Only scan BARs for normal (type 0) headers
-    if (dev.header_type & 0x7F) == HEADER_TYPE_NORMAL {
-        let mut bar_index = 0;
-        while bar_index < 6 {
-            let bar = probe_bar(addr, bar_index);
-            dev.bars[bar_index] = bar;
-
-            // 64-bit BARs consume two slots
-            if bar.bar_type == BarType::Memory64 {
-                bar_index += 2;
-            } else {
-                bar_index += 1;
+    // Walk the capability list, guarding against loops
+    let status = (pci_read_config_u32(addr, 0x04) >> 16) as u16;
+    if status & STATUS_CAPABILITIES_LIST != 0 {
+        let mut offset = pci_read_config_u8(addr, CAPABILITIES_POINTER) & 0xFC;
+        for _ in 0..48 {
+            if offset < 0x40 {
+                break;
             }
+            let header = pci_read_config_u16(addr, offset);
+            let capability = Capability {
+                id: header as u8,
+                offset,
+            };
+            if dev.capabilities.push(capability).is_err() {
+                break;
+            }
+            offset = (header >> 8) as u8 & 0xFC;
+        }
+    }
+
+    dev.quirks = quirks::for_device(vendor_id, device_id);
+    if me_device || quirks::is_me_interface(&dev) {
+        dev.quirks |= quirks::ME_OWNED;
+    }
+    if dev.has_quirk(quirks::KEEP_CONFIG) {
+        log::debug!("PCI {}: quirks {:#x}, only read", addr, dev.quirks);
+        if (dev.header_type & 0x7F) == HEADER_TYPE_NORMAL {
+            read_bars(&mut dev);
         }
+        return Some(dev);
+    }
+
+    // Keep errors from this function and, for bridges, from behind it quiet
+    let errors = MaskedErrors::mask(&dev);
+    if masked.push(errors).is_err() {
+        errors.restore();
+        return None;
+    }
+
+    // Only scan BARs for normal (type 0) headers
+    if (dev.header_type & 0x7F) == HEADER_TYPE_NORMAL {
+        probe_bars(&mut dev);
     }
 
     Some(dev)
@@ -370,6 +746,33 @@ pub fn enable_device(dev: &PciDevice) {
         cmd,
         new_cmd
     );
+
+    enable_upstream_bridges(dev.address.bus);
+}
+
+/// Enable memory decode and bus mastering of the bridges above `bus`
+///
+/// coreboot may leave them off on hot-plug ports, like the downstream ports
+/// of a Thunderbolt switch, which cuts off the device's BARs and its DMA.
+fn enable_upstream_bridges(mut bus: u8) {
+    let devices = &state::drivers().pci_devices;
+    // A bus number is never below its bridge's, which bounds the walk
+    while bus != 0 {
+        let Some(bridge) = devices.iter().find(|dev| {
+            (dev.header_type & 0x7F) == HEADER_TYPE_BRIDGE
+                && dev.address.bus < bus
+                && pci_read_config_u8(dev.address, SECONDARY_BUS) == bus
+        }) else {
+            break;
+        };
+        let command = pci_read_config_u16(bridge.address, COMMAND);
+        let enable = COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER;
+        if command & enable != enable && !bridge.has_quirk(quirks::KEEP_CONFIG) {
+            write_config_u16(bridge.address, COMMAND, command | enable);
+            log::debug!("Enabled bridge {} above bus {:02x}", bridge.address, bus);
+        }
+        bus = bridge.address.bus;
+    }
 }
 
 /// Initialize PCI subsystem and enumerate all devices
@@ -381,56 +784,86 @@ pub fn init() {
         devices.clear();
         init_inner(devices);
     });
+
+    let Some(delay) = vpd::find_str(vpd::KEY_PCI_RESCAN_DELAY) else {
+        return;
+    };
+    let Ok(delay) = delay.trim().parse::<u64>() else {
+        log::warn!("PCI: invalid rescan delay {:?}", delay);
+        return;
+    };
+    log::info!("PCI: scanning again in {}ms", delay);
+    delay_ms(delay);
+    state::with_drivers_mut(|drivers| init_inner(&mut drivers.pci_devices));
 }
 
 /// Inner initialization that works with a mutable reference to devices
+///
+/// Functions already in `devices` are kept as they are.
 fn init_inner(devices: &mut heapless::Vec<PciDevice, { state::MAX_PCI_DEVICES }>) {
-    // Scan all buses, devices, and functions
+    let mut masked = Vec::new();
+    let known = devices.len();
+    scan_all(devices, &mut masked);
+
+    // Bridges go last, after the devices behind them are quiet again
+    for errors in masked.iter().rev() {
+        errors.restore();
+    }
+
+    if known == 0 {
+        log::info!("PCI enumeration complete: {} devices found", devices.len());
+    } else {
+        log::info!("PCI rescan complete: {} new devices", devices.len() - known);
+    }
+}
+
+/// Scan all buses, devices, and functions not in `devices` yet
+fn scan_all(
+    devices: &mut heapless::Vec<PciDevice, { state::MAX_PCI_DEVICES }>,
+    masked: &mut Vec<MaskedErrors, { state::MAX_PCI_DEVICES }>,
+) {
     for bus in 0..=255u8 {
         for device in 0..32u8 {
             // First check function 0
-            if let Some(dev) = scan_device(bus, device, 0) {
-                let is_multi_function = (dev.header_type & HEADER_TYPE_MULTI_FUNCTION) != 0;
-
-                log::debug!(
-                    "PCI {}: {:04x}:{:04x} class={:02x}:{:02x}",
-                    dev.address,
-                    dev.vendor_id,
-                    dev.device_id,
-                    dev.class_code,
-                    dev.subclass
-                );
-
-                if devices.push(dev).is_err() {
-                    log::warn!("PCI device list full!");
-                    return;
+            let address = PciAddress::new(bus, device, 0);
+            let (header_type, me_device) = match devices.iter().find(|d| d.address == address) {
+                Some(dev) => (dev.header_type, quirks::is_me_interface(dev)),
+                None => {
+                    let Some(dev) = scan_device(bus, device, 0, false, masked) else {
+                        continue;
+                    };
+                    let function0 = (dev.header_type, quirks::is_me_interface(&dev));
+
+                    log::debug!("PCI {}", dev);
+
+                    if devices.push(dev).is_err() {
+                        log::warn!("PCI device list full!");
+                        return;
+                    }
+                    function0
+                }
+            };
+
+            // Check other functions if multi-function
+            if (header_type & HEADER_TYPE_MULTI_FUNCTION) == 0 {
+                continue;
+            }
+            for function in 1..8u8 {
+                let address = PciAddress::new(bus, device, function);
+                if devices.iter().any(|d| d.address == address) {
+                    continue;
                 }
+                if let Some(dev) = scan_device(bus, device, function, me_device, masked) {
+                    log::debug!("PCI {}", dev);
 
-                // Check other functions if multi-function
-                if is_multi_function {
-                    for function in 1..8u8 {
-                        if let Some(dev) = scan_device(bus, device, function) {
-                            log::debug!(
-                                "PCI {}: {:04x}:{:04x} class={:02x}:{:02x}",
-                                dev.address,
-                                dev.vendor_id,
-                                dev.device_id,
-                                dev.class_code,
-                                dev.subclass
-                            );
-
-                            if devices.push(dev).is_err() {
-                                log::warn!("PCI device list full!");
-                                return;
-                            }
-                        }
+                    if devices.push(dev).is_err() {
+                        log::warn!("PCI device list full!");
+                        return;
                     }
                 }
             }
         }
     }
-
-    log::info!("PCI enumeration complete: {} devices found", devices.len());
 }
 
 /// Find all NVMe controllers
@@ -440,7 +873,7 @@ pub fn find_nvme_controllers() -> Vec<PciDevice, 8> {
     let mut nvme_devices = Vec::new();
 
     for dev in devices.iter() {
-        if dev.is_nvme() {
+        if dev.is_nvme() && dev.driver_allowed() {
             log::info!(
                 "Found NVMe controller at {}: {:04x}:{:04x}",
                 dev.address,
@@ -461,7 +894,7 @@ pub fn find_ahci_controllers() -> Vec<PciDevice, 8> {
     let mut ahci_devices = Vec::new();
 
     for dev in devices.iter() {
-        if dev.is_ahci() {
+        if dev.is_ahci() && dev.driver_allowed() {
             log::info!(
                 "Found AHCI controller at {}: {:04x}:{:04x}",
                 dev.address,
@@ -482,7 +915,7 @@ pub fn find_sdhci_controllers() -> Vec<PciDevice, 8> {
     let mut sdhci_devices = Vec::new();
 
     for dev in devices.iter() {
-        if dev.is_sdhci() {
+        if dev.is_sdhci() && dev.driver_allowed() {
             log::info!(
                 "Found SDHCI controller at {}: {:04x}:{:04x}",
                 dev.address,
@@ -508,28 +941,16 @@ pub fn print_devices() {
 
     log::info!("PCI Devices:");
     for dev in devices.iter() {
-        log::info!(
-            "  {}: {:04x}:{:04x} class={:02x}:{:02x} rev={:02x}",
-            dev.address,
-            dev.vendor_id,
-            dev.device_id,
-            dev.class_code,
-            dev.subclass,
-            dev.revision
-        );
+        log::info!("  {}", dev);
 
         for (i, bar) in dev.bars.iter().enumerate() {
             if bar.bar_type != BarType::Unused {
-                log::info!(
-                    "    BAR{}: {:?} addr={:#x} size={:#x} pf={}",
-                    i,
-                    bar.bar_type,
-                    bar.address,
-                    bar.size,
-                    bar.prefetchable
-                );
+                log::info!("    BAR{}: {}", i, bar);
             }
         }
+        for cap in dev.capabilities.iter() {
+            log::info!("    Capability [{:02x}]: {}", cap.offset, cap.name());
+        }
     }
 }
 
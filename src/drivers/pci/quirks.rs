@@ -0,0 +1,100 @@
+//! PCI quirks
+//!
+//! Some functions belong to someone else and must be left alone. On vPro
+//! machines the Intel ME owns the functions of its PCI device: the HECI
+//! (MEI) interfaces, IDE-R and the KT UART that AMT's Serial-over-LAN and
+//! remote KVM run through. Sizing their BARs turns their decode off for a
+//! moment and masking their error reporting rewrites their command
+//! register, which can cut off the remote console. So every function of an
+//! Intel device whose function 0 is a HECI interface is left untouched.
+//!
+//! More functions can be listed in the VPD key `crabefi_pci_quirks`, in the
+//! format of `crabefi_usb_quirks`: comma-separated `VID:DID:flags` with the
+//! IDs in hex, e.g. `8086:a13d:kn`. The flags are:
+//!
+//! - `k`: keep the configuration space as it is, the BARs are read but not
+//!   sized
+//! - `n`: no driver binds to the function
+
+use super::PciDevice;
+use crate::coreboot::vpd;
+
+/// Don't write the configuration space during enumeration
+pub const KEEP_CONFIG: u32 = 1 << 0;
+
+/// Don't bind a driver to the function
+pub const NO_DRIVER: u32 = 1 << 1;
+
+/// Quirks of the functions owned by the ME
+pub const ME_OWNED: u32 = KEEP_CONFIG | NO_DRIVER;
+
+/// Intel's vendor ID
+const VENDOR_INTEL: u16 = 0x8086;
+
+/// Whether `dev` is an ME interface (HECI), a simple communication
+/// controller of the "other" subclass
+pub fn is_me_interface(dev: &PciDevice) -> bool {
+    dev.vendor_id == VENDOR_INTEL && dev.class_code == 0x07 && dev.subclass == 0x80
+}
+
+/// Quirks of a function in a quirk list
+fn lookup(list: &str, vendor_id: u16, device_id: u16) -> u32 {
+    let mut quirks = 0;
+    for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let mut fields = entry.split(':');
+        let ids = (fields.next(), fields.next(), fields.next());
+        let (Some(vid), Some(did), Some(flags)) = ids else {
+            log::warn!("PCI quirks: invalid entry {:?}", entry);
+            continue;
+        };
+        let (Ok(vid), Ok(did)) = (u16::from_str_radix(vid, 16), u16::from_str_radix(did, 16))
+        else {
+            log::warn!("PCI quirks: invalid entry {:?}", entry);
+            continue;
+        };
+        if vid != vendor_id || did != device_id {
+            continue;
+        }
+        for flag in flags.chars() {
+            match flag {
+                'k' => quirks |= KEEP_CONFIG,
+                'n' => quirks |= NO_DRIVER,
+                _ => {}
+            }
+        }
+    }
+    quirks
+}
+
+/// Quirks configured for a function
+pub fn for_device(vendor_id: u16, device_id: u16) -> u32 {
+    vpd::find_str(vpd::KEY_PCI_QUIRKS).map_or(0, |list| lookup(list, vendor_id, device_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::pci::PciAddress;
+
+    #[test]
+    fn looks_up_quirks() {
+        let list = "8086:a13d:k, 8086:A13A:nx,bad,8086:a13d:n";
+        assert_eq!(lookup(list, 0x8086, 0xa13d), KEEP_CONFIG | NO_DRIVER);
+        assert_eq!(lookup(list, 0x8086, 0xa13a), NO_DRIVER);
+        assert_eq!(lookup(list, 0x8086, 0xa13b), 0);
+        assert_eq!(lookup("", 0x8086, 0xa13d), 0);
+    }
+
+    #[test]
+    fn finds_me_interface() {
+        let mut dev = PciDevice::new(PciAddress::new(0, 0x16, 0));
+        dev.vendor_id = VENDOR_INTEL;
+        dev.class_code = 0x07;
+        dev.subclass = 0x80;
+        assert!(is_me_interface(&dev));
+
+        // The KT UART is found through function 0
+        dev.subclass = 0x00;
+        assert!(!is_me_interface(&dev));
+    }
+}
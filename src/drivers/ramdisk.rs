@@ -0,0 +1,421 @@
+//! RAM disks
+//!
+//! A disk image in memory, read like any other disk. Recovery images for
+//! devices without a disk can be kept in CBFS files named
+//! `crabefi/ramdisk/*`: each is copied to memory at EFI initialization and
+//! its ESP gets boot entries. A file ending in `.iso` is a virtual CD with
+//! 2048-byte blocks, anything else a virtual disk with 512-byte blocks.
+//! Boot loaders that fetched an image from the network or an ESP register it
+//! through the [RAM disk protocol](crate::efi::protocols::ram_disk).
+//!
+//! Each RAM disk is described to the OS by a System Physical Address Range
+//! structure in the ACPI NFIT, appended to the structures of the NFIT
+//! coreboot built if there is one, so Linux can keep using the image as a
+//! pmem device after boot.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use heapless::String;
+use r_efi::efi::{self, Guid};
+use spin::Mutex;
+
+use crate::coreboot::cbfs;
+use crate::drivers::block::BlockError;
+use crate::efi::allocator::{self, AllocateType, MemoryType, PAGE_SIZE, attributes};
+
+/// Prefix of the CBFS files holding disk images
+pub const CBFS_PREFIX: &str = "crabefi/ramdisk/";
+
+/// Most RAM disks
+pub const MAX_RAM_DISKS: usize = 4;
+
+/// Longest name kept for a RAM disk
+const MAX_NAME_LEN: usize = 32;
+
+/// Disk types of the RAM disk device path and the NFIT
+pub const VIRTUAL_DISK_GUID: Guid = Guid::from_fields(
+    0x77ab535a,
+    0x45fc,
+    0x624b,
+    0x55,
+    0x60,
+    &[0xf7, 0xb2, 0x81, 0xd1, 0xf9, 0x6e],
+);
+pub const VIRTUAL_CD_GUID: Guid = Guid::from_fields(
+    0x3d5abd30,
+    0x4175,
+    0x87ce,
+    0x6d,
+    0x64,
+    &[0xd2, 0xad, 0xe5, 0x23, 0xc4, 0xbb],
+);
+pub const PERSISTENT_VIRTUAL_DISK_GUID: Guid = Guid::from_fields(
+    0x5cea02c9,
+    0x4d07,
+    0x69d3,
+    0x26,
+    0x9f,
+    &[0x44, 0x96, 0xfb, 0xe0, 0x96, 0xf9],
+);
+pub const PERSISTENT_VIRTUAL_CD_GUID: Guid = Guid::from_fields(
+    0x08018188,
+    0x42cd,
+    0xbb48,
+    0x10,
+    0x0f,
+    &[0x53, 0x87, 0xd5, 0x3d, 0xed, 0x3d],
+);
+
+/// Size of the NFIT header, with its reserved field
+const NFIT_HEADER_SIZE: usize = 40;
+
+/// NFIT System Physical Address Range structure type and size
+const SPA_RANGE: u16 = 0;
+const SPA_RANGE_SIZE: usize = 56;
+
+/// A disk image in memory
+#[derive(Debug, Clone)]
+pub struct RamDisk {
+    /// Address of the image
+    pub address: u64,
+    /// Size of the image, a multiple of the block size
+    pub size: u64,
+    /// One of the virtual disk or CD GUIDs
+    pub disk_type: Guid,
+    /// CBFS file name, empty for images registered by boot loaders
+    pub name: String<MAX_NAME_LEN>,
+    /// Handle its protocols are installed on, 0 until installed
+    pub handle: usize,
+}
+
+impl RamDisk {
+    /// Whether the image is a CD
+    pub fn is_cd(&self) -> bool {
+        is_cd(&self.disk_type)
+    }
+
+    /// Block size of the image
+    pub fn block_size(&self) -> u32 {
+        if self.is_cd() { 2048 } else { 512 }
+    }
+
+    /// Number of blocks of the image
+    pub fn num_blocks(&self) -> u64 {
+        self.size / self.block_size() as u64
+    }
+}
+
+/// The RAM disks, indexed by their ID
+static RAM_DISKS: Mutex<[Option<RamDisk>; MAX_RAM_DISKS]> =
+    Mutex::new([const { None }; MAX_RAM_DISKS]);
+
+/// RSDP the NFIT is added to, 0 without ACPI
+static RSDP: AtomicU64 = AtomicU64::new(0);
+
+/// The NFIT coreboot built, 0 if there is none
+static ORIGINAL_NFIT: AtomicU64 = AtomicU64::new(0);
+
+/// Address and size of the NFIT last added
+static NFIT: Mutex<Option<(u64, usize)>> = Mutex::new(None);
+
+/// Whether `disk_type` names a RAM disk type CrabEFI can serve
+pub fn is_supported_type(disk_type: &Guid) -> bool {
+    [
+        VIRTUAL_DISK_GUID,
+        VIRTUAL_CD_GUID,
+        PERSISTENT_VIRTUAL_DISK_GUID,
+        PERSISTENT_VIRTUAL_CD_GUID,
+    ]
+    .contains(disk_type)
+}
+
+/// Whether `disk_type` is one of the CD types
+fn is_cd(disk_type: &Guid) -> bool {
+    *disk_type == VIRTUAL_CD_GUID || *disk_type == PERSISTENT_VIRTUAL_CD_GUID
+}
+
+/// Build an NFIT from the structures of the original NFIT followed by an SPA
+/// range structure per RAM disk
+///
+/// `table` must be `NFIT_HEADER_SIZE + original.len()` plus
+/// `SPA_RANGE_SIZE` per disk long. The SPA range indices continue after the
+/// highest one in `original`.
+fn write_nfit(table: &mut [u8], original: &[u8], disks: &[&RamDisk]) {
+    let mut last_index = 0u16;
+    let mut offset = 0;
+    while let Some(header) = original.get(offset..offset + 6) {
+        let kind = u16::from_le_bytes([header[0], header[1]]);
+        let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+        if length < 4 {
+            break;
+        }
+        if kind == SPA_RANGE {
+            last_index = last_index.max(u16::from_le_bytes([header[4], header[5]]));
+        }
+        offset += length;
+    }
+
+    let length = table.len() as u32;
+    table.fill(0);
+    table[0..4].copy_from_slice(b"NFIT");
+    table[4..8].copy_from_slice(&length.to_le_bytes());
+    table[8] = 1;
+    table[10..16].copy_from_slice(b"CRABEF");
+    table[16..24].copy_from_slice(b"CRABEFI ");
+    table[24..28].copy_from_slice(&1u32.to_le_bytes());
+    table[28..32].copy_from_slice(b"CRAB");
+    table[32..36].copy_from_slice(&1u32.to_le_bytes());
+    table[NFIT_HEADER_SIZE..NFIT_HEADER_SIZE + original.len()].copy_from_slice(original);
+
+    let structures = table[NFIT_HEADER_SIZE + original.len()..].chunks_mut(SPA_RANGE_SIZE);
+    for (i, (spa, disk)) in structures.zip(disks).enumerate() {
+        spa[0..2].copy_from_slice(&SPA_RANGE.to_le_bytes());
+        spa[2..4].copy_from_slice(&(SPA_RANGE_SIZE as u16).to_le_bytes());
+        let index = last_index.wrapping_add(i as u16 + 1);
+        spa[4..6].copy_from_slice(&index.to_le_bytes());
+        spa[16..32].copy_from_slice(disk.disk_type.as_bytes());
+        spa[32..40].copy_from_slice(&disk.address.to_le_bytes());
+        spa[40..48].copy_from_slice(&disk.size.to_le_bytes());
+        spa[48..56].copy_from_slice(&attributes::EFI_MEMORY_WB.to_le_bytes());
+    }
+    crate::acpi::set_checksum(table, 9);
+}
+
+/// Add an NFIT listing the current RAM disks to the ACPI tables
+fn publish_nfit(disks: &[Option<RamDisk>; MAX_RAM_DISKS]) {
+    let rsdp = RSDP.load(Ordering::Relaxed);
+    if rsdp == 0 {
+        return;
+    }
+    let original = match ORIGINAL_NFIT.load(Ordering::Relaxed) {
+        0 => &[][..],
+        // Safety: coreboot's ACPI tables stay in place
+        nfit => unsafe { crate::acpi::table_bytes(nfit) }
+            .get(NFIT_HEADER_SIZE..)
+            .unwrap_or(&[]),
+    };
+    let disks: heapless::Vec<&RamDisk, MAX_RAM_DISKS> = disks.iter().flatten().collect();
+    let size = NFIT_HEADER_SIZE + original.len() + disks.len() * SPA_RANGE_SIZE;
+
+    let mut address = u32::MAX as u64;
+    let pages = (size as u64).div_ceil(PAGE_SIZE);
+    let status = allocator::allocate_pages(
+        AllocateType::AllocateMaxAddress,
+        MemoryType::AcpiReclaimMemory,
+        pages,
+        &mut address,
+    );
+    if status != efi::Status::SUCCESS {
+        log::warn!("RAM disk: no memory for the NFIT");
+        return;
+    }
+    // Safety: the pages were just allocated
+    let table = unsafe { core::slice::from_raw_parts_mut(address as *mut u8, size) };
+    write_nfit(table, original, &disks);
+
+    // Safety: the table is complete and below 4 GiB
+    let status = unsafe { crate::acpi::add_table(rsdp, address) };
+    if status != efi::Status::SUCCESS {
+        log::warn!("RAM disk: failed to add the NFIT: {:?}", status);
+        let _ = allocator::free_pages(address, pages);
+        return;
+    }
+    // The new table replaced the one added before
+    if let Some((old, old_size)) = NFIT.lock().replace((address, size)) {
+        let _ = allocator::free_pages(old, (old_size as u64).div_ceil(PAGE_SIZE));
+    }
+}
+
+/// Register the image at `address` as a RAM disk, returning its ID
+///
+/// The image must stay in place until it is unregistered, and the size must
+/// be a multiple of the block size of `disk_type`.
+pub fn register(address: u64, size: u64, disk_type: Guid, name: &str) -> Option<usize> {
+    let block_size = if is_cd(&disk_type) { 2048 } else { 512 };
+    if size == 0 || !size.is_multiple_of(block_size) || !is_supported_type(&disk_type) {
+        return None;
+    }
+
+    let mut disks = RAM_DISKS.lock();
+    if disks.iter().flatten().any(|disk| disk.address == address) {
+        return None;
+    }
+    let id = disks.iter().position(Option::is_none)?;
+    let mut short_name = String::new();
+    for c in name.chars() {
+        if short_name.push(c).is_err() {
+            break;
+        }
+    }
+    disks[id] = Some(RamDisk {
+        address,
+        size,
+        disk_type,
+        name: short_name,
+        handle: 0,
+    });
+    log::info!("RAM disk {}: {} bytes at {:#x} {}", id, size, address, name);
+
+    publish_nfit(&disks);
+    Some(id)
+}
+
+/// Remove RAM disk `id`, the memory of its image is left to its owner
+pub fn unregister(id: usize) -> bool {
+    let mut disks = RAM_DISKS.lock();
+    if disks.get_mut(id).and_then(Option::take).is_none() {
+        return false;
+    }
+    log::info!("RAM disk {}: unregistered", id);
+    publish_nfit(&disks);
+    true
+}
+
+/// RAM disk `id`, if it is registered
+pub fn get(id: usize) -> Option<RamDisk> {
+    RAM_DISKS.lock().get(id)?.clone()
+}
+
+/// ID of the RAM disk of type `disk_type` at `address`
+pub fn find(address: u64, disk_type: &Guid) -> Option<usize> {
+    RAM_DISKS.lock().iter().position(|disk| {
+        disk.as_ref()
+            .is_some_and(|disk| disk.address == address && disk.disk_type == *disk_type)
+    })
+}
+
+/// Remember the handle the protocols of RAM disk `id` are installed on
+pub fn set_handle(id: usize, handle: efi::Handle) {
+    if let Some(Some(disk)) = RAM_DISKS.lock().get_mut(id) {
+        disk.handle = handle as usize;
+    }
+}
+
+/// Read whole blocks from RAM disk `id`, starting at block `lba`
+pub fn read(id: usize, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+    let disks = RAM_DISKS.lock();
+    let disk = disks
+        .get(id)
+        .and_then(Option::as_ref)
+        .ok_or(BlockError::NoMedia)?;
+    let block_size = disk.block_size() as u64;
+    if !(buffer.len() as u64).is_multiple_of(block_size) {
+        return Err(BlockError::InvalidParameter);
+    }
+    let end = lba
+        .checked_mul(block_size)
+        .and_then(|start| start.checked_add(buffer.len() as u64))
+        .filter(|&end| end <= disk.size)
+        .ok_or(BlockError::OutOfRange)?;
+    let start = disk.address + end - buffer.len() as u64;
+    // Safety: the image stays in place while it is registered
+    let image = unsafe { core::slice::from_raw_parts(start as *const u8, buffer.len()) };
+    buffer.copy_from_slice(image);
+    Ok(())
+}
+
+/// Copy the disk images in CBFS to memory and register them
+///
+/// The images are in reserved memory, which the OS leaves alone, so the NFIT
+/// still describes them after boot.
+pub fn init(acpi_rsdp: Option<u64>) {
+    if let Some(rsdp) = acpi_rsdp {
+        RSDP.store(rsdp, Ordering::Relaxed);
+        // Safety: the RSDP is the one coreboot built
+        if let Some(nfit) = unsafe { crate::acpi::find_table(rsdp, b"NFIT") } {
+            ORIGINAL_NFIT.store(nfit, Ordering::Relaxed);
+        }
+    }
+
+    for (name, data) in cbfs::find_files(CBFS_PREFIX) {
+        let name = &name[CBFS_PREFIX.len()..];
+        let is_iso = name
+            .rsplit_once('.')
+            .is_some_and(|(_, extension)| extension.eq_ignore_ascii_case("iso"));
+        let disk_type = if is_iso {
+            VIRTUAL_CD_GUID
+        } else {
+            VIRTUAL_DISK_GUID
+        };
+        let block_size = if is_cd(&disk_type) { 2048 } else { 512 };
+        // A partial last block is padded with zeros
+        let size = (data.len() as u64).next_multiple_of(block_size);
+        let pages = size.div_ceil(PAGE_SIZE);
+
+        let mut address = 0;
+        let status = allocator::allocate_pages(
+            AllocateType::AllocateAnyPages,
+            MemoryType::ReservedMemoryType,
+            pages,
+            &mut address,
+        );
+        if status != efi::Status::SUCCESS {
+            log::warn!("RAM disk: no memory for {}", name);
+            continue;
+        }
+        // Safety: the pages were just allocated
+        unsafe {
+            let image = core::slice::from_raw_parts_mut(address as *mut u8, size as usize);
+            image[..data.len()].copy_from_slice(data);
+            image[data.len()..].fill(0);
+        }
+        if register(address, size, disk_type, name).is_none() {
+            log::warn!("RAM disk: too many images, {} left out", name);
+            let _ = allocator::free_pages(address, pages);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disk(address: u64, size: u64, disk_type: Guid) -> RamDisk {
+        RamDisk {
+            address,
+            size,
+            disk_type,
+            name: String::new(),
+            handle: 0,
+        }
+    }
+
+    #[test]
+    fn appends_spa_ranges() {
+        // A persistent memory range with index 3 from coreboot
+        let mut original = [0u8; SPA_RANGE_SIZE];
+        original[2..4].copy_from_slice(&(SPA_RANGE_SIZE as u16).to_le_bytes());
+        original[4..6].copy_from_slice(&3u16.to_le_bytes());
+
+        let disks = [
+            disk(0x1000_0000, 0x20_0000, VIRTUAL_DISK_GUID),
+            disk(0x2000_0000, 0x80_0000, VIRTUAL_CD_GUID),
+        ];
+        let mut table = [0u8; NFIT_HEADER_SIZE + 3 * SPA_RANGE_SIZE];
+        write_nfit(&mut table, &original, &[&disks[0], &disks[1]]);
+
+        assert_eq!(&table[0..4], b"NFIT");
+        assert_eq!(table[4..8], (table.len() as u32).to_le_bytes());
+        assert_eq!(table.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)), 0);
+        assert_eq!(table[NFIT_HEADER_SIZE..][..SPA_RANGE_SIZE], original);
+
+        let spa = &table[NFIT_HEADER_SIZE + SPA_RANGE_SIZE..][..SPA_RANGE_SIZE];
+        assert_eq!(spa[2..4], (SPA_RANGE_SIZE as u16).to_le_bytes());
+        assert_eq!(spa[4..6], 4u16.to_le_bytes());
+        assert_eq!(spa[16..32], *VIRTUAL_DISK_GUID.as_bytes());
+        assert_eq!(spa[32..40], 0x1000_0000u64.to_le_bytes());
+        assert_eq!(spa[40..48], 0x20_0000u64.to_le_bytes());
+        let spa = &table[NFIT_HEADER_SIZE + 2 * SPA_RANGE_SIZE..];
+        assert_eq!(spa[4..6], 5u16.to_le_bytes());
+        assert_eq!(spa[16..32], *VIRTUAL_CD_GUID.as_bytes());
+    }
+
+    #[test]
+    fn block_sizes() {
+        assert_eq!(disk(0, 4096, VIRTUAL_DISK_GUID).num_blocks(), 8);
+        assert_eq!(disk(0, 4096, PERSISTENT_VIRTUAL_CD_GUID).num_blocks(), 2);
+        assert!(!is_supported_type(&Guid::from_fields(
+            0, 0, 0, 0, 0, &[0; 6]
+        )));
+    }
+}
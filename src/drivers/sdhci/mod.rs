@@ -3,15 +3,26 @@
 //! This module provides a driver for SD/MMC cards connected via standard SDHCI
 //! controllers. It supports PCI-based SDHCI controllers and implements the
 //! SD card protocol for reading sectors.
+//!
+//! Boards whose SDHCI controller is not a PCI function list its register
+//! base in the CBFS file `crabefi/sdhci`, one address per line; lines
+//! starting with `#` are ignored:
+//!
+//! ```text
+//! # cbfstool coreboot.rom add -f sdhci -n crabefi/sdhci -t raw
+//! 0xfe330000
+//! ```
 
 pub mod regs;
 
+use crate::coreboot::cbfs;
 use crate::drivers::pci::{self, PciAddress, PciDevice};
 use crate::efi;
 use crate::time::{Timeout, wait_for};
 use core::ptr;
 use core::sync::atomic::{Ordering, fence};
 use spin::Mutex;
+use tock_registers::fields::Field;
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
 use regs::*;
@@ -19,6 +30,9 @@ use regs::*;
 /// Maximum number of SDHCI controllers we can track
 const MAX_SDHCI_CONTROLLERS: usize = 4;
 
+/// CBFS file listing the register bases of non-PCI controllers
+pub const CBFS_NAME: &str = "crabefi/sdhci";
+
 /// Block size for SD cards (always 512 bytes)
 const SD_BLOCK_SIZE: u32 = 512;
 
@@ -34,9 +48,81 @@ const INIT_CLOCK_HZ: u32 = 400_000;
 /// Default speed clock frequency (25 MHz)
 const DEFAULT_CLOCK_HZ: u32 = 25_000_000;
 
-/// High speed clock frequency (50 MHz)
+/// High speed / SDR25 clock frequency (50 MHz)
 const HIGH_SPEED_CLOCK_HZ: u32 = 50_000_000;
 
+/// SDR50 clock frequency (100 MHz)
+const SDR50_CLOCK_HZ: u32 = 100_000_000;
+
+/// SDR104 clock frequency (208 MHz)
+const SDR104_CLOCK_HZ: u32 = 208_000_000;
+
+/// Size of the CMD6 switch function status block
+const SWITCH_STATUS_LEN: usize = 64;
+
+/// Size of the CMD19 tuning block on a 4-bit bus
+const TUNING_BLOCK_LEN: u16 = 64;
+
+/// Maximum number of CMD19 tuning commands (SD Host Controller spec)
+const MAX_TUNING_LOOPS: u32 = 150;
+
+/// Interrupt status bits cleared around commands and transfers
+///
+/// Everything except Card Insertion and Card Removal, which stay latched
+/// until [`SdhciController::poll_card`] looks at them, so a card swapped
+/// between two polls is still noticed.
+const TRANSFER_STATUS: u32 = !((1 << 6) | (1 << 7));
+
+/// Card detect change reported by [`SdhciController::poll_card`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardEvent {
+    /// A card was inserted and initialized, if it could be
+    Inserted,
+    /// The card was removed
+    Removed,
+}
+
+/// Bus speed mode, numbered by its CMD6 access mode function
+///
+/// At 3.3V signaling functions 0 and 1 are default and high speed; at 1.8V
+/// they are SDR12 and SDR25, and SDR50 and SDR104 become available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum BusSpeed {
+    /// Default speed / SDR12
+    Default = 0,
+    /// High speed / SDR25
+    HighSpeed = 1,
+    /// SDR50 (1.8V only)
+    Sdr50 = 2,
+    /// SDR104 (1.8V only)
+    Sdr104 = 3,
+}
+
+impl BusSpeed {
+    /// SD clock frequency for this mode
+    fn clock_hz(self) -> u32 {
+        match self {
+            BusSpeed::Default => DEFAULT_CLOCK_HZ,
+            BusSpeed::HighSpeed => HIGH_SPEED_CLOCK_HZ,
+            BusSpeed::Sdr50 => SDR50_CLOCK_HZ,
+            BusSpeed::Sdr104 => SDR104_CLOCK_HZ,
+        }
+    }
+
+    /// Name of this mode at the given signaling voltage
+    fn name(self, signaling_1v8: bool) -> &'static str {
+        match (self, signaling_1v8) {
+            (BusSpeed::Default, false) => "default speed",
+            (BusSpeed::Default, true) => "SDR12",
+            (BusSpeed::HighSpeed, false) => "high speed",
+            (BusSpeed::HighSpeed, true) => "SDR25",
+            (BusSpeed::Sdr50, _) => "SDR50",
+            (BusSpeed::Sdr104, _) => "SDR104",
+        }
+    }
+}
+
 /// SDHCI error type
 #[derive(Debug, Clone, Copy)]
 pub enum SdhciError {
@@ -70,13 +156,19 @@ pub enum SdhciError {
     AllocationFailed,
     /// Clock configuration failed
     ClockFailed,
+    /// Switching to 1.8V signaling failed
+    VoltageSwitchFailed,
+    /// Sampling clock tuning failed
+    TuningFailed,
+    /// The card was replaced during the operation
+    MediaChanged,
     /// Generic error
     GenericError,
 }
 
 /// SDHCI Controller
 pub struct SdhciController {
-    /// PCI address (bus:device.function)
+    /// PCI address (bus:device.function), 00:00.0 for MMIO controllers
     pci_address: PciAddress,
     /// Pointer to MMIO registers
     regs: *const SdhciRegisters,
@@ -92,10 +184,19 @@ pub struct SdhciController {
     card_present: bool,
     /// Card is initialized
     card_initialized: bool,
+    /// Identifies the card; changes with every card initialized after a
+    /// removal
+    media_id: u32,
     /// Relative Card Address (after initialization)
     rca: u16,
     /// Card is high capacity (SDHC/SDXC)
     high_capacity: bool,
+    /// Bus uses 1.8V signaling (UHS-I)
+    signaling_1v8: bool,
+    /// Current bus speed mode
+    bus_speed: BusSpeed,
+    /// Sampling clock was tuned and must be re-tuned on request
+    tuned: bool,
     /// Total number of blocks on card
     num_blocks: u64,
     /// Block size (always 512 for SD)
@@ -122,17 +223,31 @@ impl SdhciController {
     /// Create a new SDHCI controller from a PCI device
     pub fn new(pci_dev: &PciDevice) -> Result<Self, SdhciError> {
         let mmio_base = pci_dev.mmio_base().ok_or(SdhciError::NotInitialized)?;
-        let regs = mmio_base as *const SdhciRegisters;
 
         // Enable the device (bus master + memory space)
         pci::enable_device(pci_dev);
 
+        Self::with_registers(pci_dev.address, mmio_base)
+    }
+
+    /// Create a new SDHCI controller at a fixed MMIO address
+    ///
+    /// For controllers that are not PCI functions, whose register base comes
+    /// from board configuration instead.
+    pub fn new_mmio(mmio_base: u64) -> Result<Self, SdhciError> {
+        Self::with_registers(PciAddress::new(0, 0, 0), mmio_base)
+    }
+
+    /// Create and initialize a controller with registers at `mmio_base`
+    fn with_registers(pci_address: PciAddress, mmio_base: u64) -> Result<Self, SdhciError> {
+        let regs = mmio_base as *const SdhciRegisters;
+
         // Allocate a page-aligned DMA buffer for data transfers
         let dma_buffer_mem = efi::allocate_pages(1).ok_or(SdhciError::AllocationFailed)?;
         let dma_buffer = dma_buffer_mem.as_mut_ptr();
 
         let mut controller = Self {
-            pci_address: pci_dev.address,
+            pci_address,
             regs,
             version: 0,
             max_clock: 0,
@@ -140,8 +255,12 @@ impl SdhciController {
             capabilities_1: 0,
             card_present: false,
             card_initialized: false,
+            media_id: 0,
             rca: 0,
             high_capacity: false,
+            signaling_1v8: false,
+            bus_speed: BusSpeed::Default,
+            tuned: false,
             num_blocks: 0,
             block_size: SD_BLOCK_SIZE,
             dma_buffer,
@@ -218,6 +337,8 @@ impl SdhciController {
                 + INT_STATUS::DMA_INT::SET
                 + INT_STATUS::BUFFER_WRITE_READY::SET
                 + INT_STATUS::BUFFER_READ_READY::SET
+                + INT_STATUS::CARD_INSERT::SET
+                + INT_STATUS::CARD_REMOVE::SET
                 + INT_STATUS::ERROR::SET
                 + INT_STATUS::CMD_TIMEOUT::SET
                 + INT_STATUS::CMD_CRC::SET
@@ -245,6 +366,7 @@ impl SdhciController {
         } else {
             log::info!("SDHCI: No card detected");
         }
+        self.ack_card_detect();
 
         Ok(())
     }
@@ -420,6 +542,79 @@ impl SdhciController {
             && regs.present_state.is_set(PRESENT_STATE::CARD_STABLE)
     }
 
+    /// Acknowledge the latched card detect interrupt status
+    fn ack_card_detect(&self) {
+        self.regs()
+            .int_status
+            .write(INT_STATUS::CARD_INSERT::SET + INT_STATUS::CARD_REMOVE::SET);
+    }
+
+    /// Check card detect for a card inserted or removed since the last poll
+    ///
+    /// A new card is initialized right away and gets a new media ID. A
+    /// removed card's state is dropped, so reads fail with `NoCard`. If the
+    /// card was swapped between two polls, only `Inserted` is reported.
+    pub fn poll_card(&mut self) -> Option<CardEvent> {
+        let (stable, present, removed) = {
+            let regs = self.regs();
+            (
+                regs.present_state.is_set(PRESENT_STATE::CARD_STABLE),
+                regs.present_state.is_set(PRESENT_STATE::CARD_INSERTED),
+                regs.int_status.is_set(INT_STATUS::CARD_REMOVE),
+            )
+        };
+        // Wait for the card detect pin to settle
+        if !stable {
+            return None;
+        }
+        self.ack_card_detect();
+
+        let mut event = None;
+        if self.card_present && (removed || !present) {
+            log::info!("SDHCI: Card removed");
+            self.card_removed();
+            event = Some(CardEvent::Removed);
+        }
+        if present && !self.card_present {
+            log::info!("SDHCI: Card inserted");
+            self.card_inserted();
+            event = Some(CardEvent::Inserted);
+        }
+        event
+    }
+
+    /// Forget the removed card and turn off the bus
+    fn card_removed(&mut self) {
+        self.card_present = false;
+        self.card_initialized = false;
+        self.signaling_1v8 = false;
+        self.bus_speed = BusSpeed::Default;
+        self.tuned = false;
+        self.num_blocks = 0;
+
+        // A card pulled mid-transfer can leave the command and data lines busy
+        let _ = self.reset_cmd();
+        let _ = self.reset_data();
+
+        let regs = self.regs();
+        regs.clock_control.set(0);
+        regs.power_control.set(0);
+        regs.host_control2.set(0);
+        regs.host_control.modify(HOST_CONTROL::HIGH_SPEED::CLEAR);
+    }
+
+    /// Power up and initialize a newly inserted card
+    fn card_inserted(&mut self) {
+        self.card_present = true;
+        let result = self.set_power_3v3().and_then(|()| self.init_card());
+        match result {
+            Ok(()) => self.media_id = self.media_id.wrapping_add(1),
+            Err(e) => log::error!("SDHCI: Failed to initialize card: {:?}", e),
+        }
+        // Powering the card up may have bounced card detect
+        self.ack_card_detect();
+    }
+
     /// Wait for command/data inhibit to clear
     fn wait_inhibit(&self, data: bool) -> Result<(), SdhciError> {
         let regs = self.regs();
@@ -453,7 +648,7 @@ impl SdhciController {
         self.wait_inhibit(has_data)?;
 
         // Clear all pending interrupts
-        regs.int_status.set(0xFFFFFFFF);
+        regs.int_status.set(TRANSFER_STATUS);
 
         // Set argument
         regs.argument.set(arg);
@@ -504,7 +699,7 @@ impl SdhciController {
             // Check for errors
             if regs.int_status.is_set(INT_STATUS::ERROR) {
                 // Clear status
-                regs.int_status.set(status);
+                regs.int_status.set(status & TRANSFER_STATUS);
 
                 if regs.int_status.is_set(INT_STATUS::CMD_TIMEOUT) {
                     log::debug!("SDHCI: CMD{} timeout", cmd);
@@ -561,6 +756,111 @@ impl SdhciController {
 
     /// Initialize the SD card
     fn init_card(&mut self) -> Result<(), SdhciError> {
+        // Ask for 1.8V signaling if the controller can run UHS-I modes
+        let request_1v8 = self.supports_uhs();
+        match self.identify_card(request_1v8) {
+            Err(SdhciError::VoltageSwitchFailed) => {
+                // The card may be left half-switched, so start over from power-up
+                log::warn!("SDHCI: 1.8V switch failed, retrying at 3.3V");
+                self.regs()
+                    .host_control2
+                    .modify(HOST_CONTROL2::SIGNALING_1V8::CLEAR);
+                self.set_power_3v3()?;
+                self.identify_card(false)?;
+            }
+            result => result?,
+        }
+
+        // CMD2: ALL_SEND_CID (get card identification)
+        log::debug!("SDHCI: Sending CMD2 (ALL_SEND_CID)");
+        let cid = self.send_command(MMC_CMD_ALL_SEND_CID, 0, MMC_RSP_R2)?;
+        log::debug!(
+            "SDHCI: CID: {:08x} {:08x} {:08x} {:08x}",
+            cid[3],
+            cid[2],
+            cid[1],
+            cid[0]
+        );
+
+        // CMD3: SEND_RELATIVE_ADDR (get RCA)
+        log::debug!("SDHCI: Sending CMD3 (SEND_RELATIVE_ADDR)");
+        let resp = self.send_command(SD_CMD_SEND_RELATIVE_ADDR, 0, MMC_RSP_R6)?;
+        self.rca = (resp[0] >> 16) as u16;
+        log::debug!("SDHCI: RCA={:#06x}", self.rca);
+
+        // CMD9: SEND_CSD (get card specific data)
+        log::debug!("SDHCI: Sending CMD9 (SEND_CSD)");
+        let csd = self.send_command(MMC_CMD_SEND_CSD, (self.rca as u32) << 16, MMC_RSP_R2)?;
+        self.parse_csd(&csd);
+
+        // CMD7: SELECT_CARD (select the card)
+        log::debug!("SDHCI: Sending CMD7 (SELECT_CARD)");
+        self.send_command(MMC_CMD_SELECT_CARD, (self.rca as u32) << 16, MMC_RSP_R1B)?;
+
+        // CMD16: SET_BLOCKLEN (set block length to 512 for non-HC cards)
+        if !self.high_capacity {
+            log::debug!("SDHCI: Sending CMD16 (SET_BLOCKLEN)");
+            self.send_command(MMC_CMD_SET_BLOCKLEN, 512, MMC_RSP_R1)?;
+        }
+
+        // Switch to 4-bit mode
+        log::debug!("SDHCI: Switching to 4-bit mode");
+        self.send_command(MMC_CMD_APP_CMD, (self.rca as u32) << 16, MMC_RSP_R1)?;
+        self.send_command(SD_CMD_APP_SET_BUS_WIDTH, 2, MMC_RSP_R1)?; // 2 = 4-bit mode
+        self.set_bus_width(4);
+
+        // Switch to default speed (25 MHz)
+        self.set_clock(DEFAULT_CLOCK_HZ)?;
+
+        // Switch to the fastest bus speed both the card and controller support
+        let speed = self.select_bus_speed();
+        if speed != BusSpeed::Default
+            && let Err(e) = self.switch_bus_speed(speed)
+        {
+            log::warn!(
+                "SDHCI: Failed to switch to {}: {:?}",
+                speed.name(self.signaling_1v8),
+                e
+            );
+            // The card may or may not have switched; default timing works for both
+            self.regs()
+                .host_control
+                .modify(HOST_CONTROL::HIGH_SPEED::CLEAR);
+            self.set_clock(DEFAULT_CLOCK_HZ)?;
+            self.bus_speed = BusSpeed::Default;
+        }
+        log::info!(
+            "SDHCI: Bus speed: {}{}",
+            self.bus_speed.name(self.signaling_1v8),
+            if self.tuned { " (tuned)" } else { "" }
+        );
+
+        self.card_initialized = true;
+        log::info!(
+            "SDHCI: Card initialized: {} blocks x {} bytes = {} MB",
+            self.num_blocks,
+            self.block_size,
+            (self.num_blocks * self.block_size as u64) / (1024 * 1024)
+        );
+
+        Ok(())
+    }
+
+    /// Whether the controller supports UHS-I modes, which need 1.8V signaling
+    fn supports_uhs(&self) -> bool {
+        let caps_1 = &self.regs().capabilities_1;
+        self.version >= SDHCI_SPEC_300
+            && (caps_1.is_set(CAPABILITIES_1::SUPPORT_SDR50)
+                || caps_1.is_set(CAPABILITIES_1::SUPPORT_SDR104))
+    }
+
+    /// Reset the card and wait for it to power up (CMD0, CMD8, ACMD41)
+    ///
+    /// With `request_1v8` the card is asked to switch to 1.8V signaling, and
+    /// the switch is done if the card accepts.
+    fn identify_card(&mut self, request_1v8: bool) -> Result<(), SdhciError> {
+        self.signaling_1v8 = false;
+
         // Set identification clock (400 kHz)
         self.set_clock(INIT_CLOCK_HZ)?;
 
@@ -606,10 +906,10 @@ impl SdhciController {
         // ACMD41: SD_SEND_OP_COND (wait for card ready)
         // Try up to 1 second for card to become ready
         log::debug!("SDHCI: Starting ACMD41 loop");
-        let ocr_arg = if sd_v2 {
-            OCR_HCS | OCR_VDD_RANGE
-        } else {
-            OCR_VDD_RANGE
+        let ocr_arg = match (sd_v2, request_1v8) {
+            (true, true) => OCR_HCS | OCR_S18R | OCR_VDD_RANGE,
+            (true, false) => OCR_HCS | OCR_VDD_RANGE,
+            (false, _) => OCR_VDD_RANGE,
         };
 
         let timeout = Timeout::from_ms(1000);
@@ -655,64 +955,46 @@ impl SdhciController {
             }
         );
 
-        // CMD2: ALL_SEND_CID (get card identification)
-        log::debug!("SDHCI: Sending CMD2 (ALL_SEND_CID)");
-        let cid = self.send_command(MMC_CMD_ALL_SEND_CID, 0, MMC_RSP_R2)?;
-        log::debug!(
-            "SDHCI: CID: {:08x} {:08x} {:08x} {:08x}",
-            cid[3],
-            cid[2],
-            cid[1],
-            cid[0]
-        );
-
-        // CMD3: SEND_RELATIVE_ADDR (get RCA)
-        log::debug!("SDHCI: Sending CMD3 (SEND_RELATIVE_ADDR)");
-        let resp = self.send_command(SD_CMD_SEND_RELATIVE_ADDR, 0, MMC_RSP_R6)?;
-        self.rca = (resp[0] >> 16) as u16;
-        log::debug!("SDHCI: RCA={:#06x}", self.rca);
+        // S18A: the card accepted the 1.8V request (UHS-I card)
+        if ocr_arg & OCR_S18R != 0 && ocr & OCR_S18R != 0 {
+            self.switch_to_1v8()?;
+            self.signaling_1v8 = true;
+            log::info!("SDHCI: Switched to 1.8V signaling");
+        }
 
-        // CMD9: SEND_CSD (get card specific data)
-        log::debug!("SDHCI: Sending CMD9 (SEND_CSD)");
-        let csd = self.send_command(MMC_CMD_SEND_CSD, (self.rca as u32) << 16, MMC_RSP_R2)?;
-        self.parse_csd(&csd);
+        Ok(())
+    }
 
-        // CMD7: SELECT_CARD (select the card)
-        log::debug!("SDHCI: Sending CMD7 (SELECT_CARD)");
-        self.send_command(MMC_CMD_SELECT_CARD, (self.rca as u32) << 16, MMC_RSP_R1B)?;
+    /// Switch card and controller to 1.8V signaling (CMD11)
+    ///
+    /// Follows the signal voltage switch sequence of the SD Host Controller
+    /// specification: the card drives DAT[3:0] low until the clock stops, and
+    /// high again once it restarts at 1.8V.
+    fn switch_to_1v8(&mut self) -> Result<(), SdhciError> {
+        log::debug!("SDHCI: Sending CMD11 (VOLTAGE_SWITCH)");
+        self.send_command(SD_CMD_VOLTAGE_SWITCH, 0, MMC_RSP_R1)
+            .map_err(|_| SdhciError::VoltageSwitchFailed)?;
 
-        // CMD16: SET_BLOCKLEN (set block length to 512 for non-HC cards)
-        if !self.high_capacity {
-            log::debug!("SDHCI: Sending CMD16 (SET_BLOCKLEN)");
-            self.send_command(MMC_CMD_SET_BLOCKLEN, 512, MMC_RSP_R1)?;
+        let regs = self.regs();
+        regs.clock_control.modify(CLOCK_CONTROL::SD_CLK_EN::CLEAR);
+        if regs.present_state.read(PRESENT_STATE::DAT_LEVEL) != 0 {
+            log::debug!("SDHCI: DAT lines not low after CMD11");
+            return Err(SdhciError::VoltageSwitchFailed);
         }
 
-        // Switch to 4-bit mode
-        log::debug!("SDHCI: Switching to 4-bit mode");
-        self.send_command(MMC_CMD_APP_CMD, (self.rca as u32) << 16, MMC_RSP_R1)?;
-        self.send_command(SD_CMD_APP_SET_BUS_WIDTH, 2, MMC_RSP_R1)?; // 2 = 4-bit mode
-        self.set_bus_width(4);
-
-        // Switch to default speed (25 MHz)
-        self.set_clock(DEFAULT_CLOCK_HZ)?;
-
-        // Try to enable high-speed mode if supported
-        if self
-            .regs()
-            .capabilities
-            .is_set(CAPABILITIES::SUPPORT_HIGHSPEED)
-            && self.try_high_speed().is_ok()
-        {
-            log::info!("SDHCI: High-speed mode enabled (50 MHz)");
+        regs.host_control2.modify(HOST_CONTROL2::SIGNALING_1V8::SET);
+        crate::time::delay_ms(5);
+        if !regs.host_control2.is_set(HOST_CONTROL2::SIGNALING_1V8) {
+            log::debug!("SDHCI: Controller regulator did not switch to 1.8V");
+            return Err(SdhciError::VoltageSwitchFailed);
         }
 
-        self.card_initialized = true;
-        log::info!(
-            "SDHCI: Card initialized: {} blocks x {} bytes = {} MB",
-            self.num_blocks,
-            self.block_size,
-            (self.num_blocks * self.block_size as u64) / (1024 * 1024)
-        );
+        regs.clock_control.modify(CLOCK_CONTROL::SD_CLK_EN::SET);
+        crate::time::delay_ms(1);
+        if regs.present_state.read(PRESENT_STATE::DAT_LEVEL) != 0xF {
+            log::debug!("SDHCI: DAT lines not high after 1.8V switch");
+            return Err(SdhciError::VoltageSwitchFailed);
+        }
 
         Ok(())
     }
@@ -762,19 +1044,204 @@ impl SdhciController {
         );
     }
 
-    /// Try to enable high-speed mode
-    fn try_high_speed(&mut self) -> Result<(), SdhciError> {
-        let regs = self.regs();
+    /// Pick the fastest bus speed supported by both the card and the controller
+    fn select_bus_speed(&mut self) -> BusSpeed {
+        // CMD6 in check mode reports the supported functions without switching
+        let mut status = [0u8; SWITCH_STATUS_LEN];
+        if let Err(e) = self.read_data_block(SD_CMD_SWITCH_FUNC, 0x00FF_FFF0, &mut status) {
+            // SD 1.0 cards have no CMD6
+            log::debug!("SDHCI: CMD6 check failed: {:?}", e);
+            return BusSpeed::Default;
+        }
 
-        // Enable high-speed in host control
-        regs.host_control.modify(HOST_CONTROL::HIGH_SPEED::SET);
+        // Bits 415:400 of the big-endian status block: group 1 support
+        let card = u16::from_be_bytes([status[12], status[13]]);
+        log::debug!("SDHCI: Card access modes: {:#06x}", card);
+
+        let caps = &self.regs().capabilities;
+        let caps_1 = &self.regs().capabilities_1;
+        let sdr104 = caps_1.is_set(CAPABILITIES_1::SUPPORT_SDR104);
+        let sdr50 = sdr104 || caps_1.is_set(CAPABILITIES_1::SUPPORT_SDR50);
+        let high_speed = self.signaling_1v8 || caps.is_set(CAPABILITIES::SUPPORT_HIGHSPEED);
+
+        let candidates = [
+            (BusSpeed::Sdr104, self.signaling_1v8 && sdr104),
+            (BusSpeed::Sdr50, self.signaling_1v8 && sdr50),
+            (BusSpeed::HighSpeed, high_speed),
+        ];
+        candidates
+            .into_iter()
+            .find(|&(speed, host)| host && card & (1 << speed as u8) != 0)
+            .map_or(BusSpeed::Default, |(speed, _)| speed)
+    }
+
+    /// Switch card and controller to `speed`, tuning the sampling clock if needed
+    fn switch_bus_speed(&mut self, speed: BusSpeed) -> Result<(), SdhciError> {
+        // CMD6 in switch mode, leaving all other function groups unchanged
+        let mut status = [0u8; SWITCH_STATUS_LEN];
+        self.read_data_block(SD_CMD_SWITCH_FUNC, 0x80FF_FFF0 | speed as u32, &mut status)?;
+
+        // Bits 379:376: function selected in group 1 (0xF on error)
+        let selected = status[16] & 0x0F;
+        if selected != speed as u8 {
+            log::debug!("SDHCI: CMD6 selected function {:#x}", selected);
+            return Err(SdhciError::GenericError);
+        }
 
-        // Set 50 MHz clock
-        self.set_clock(HIGH_SPEED_CLOCK_HZ)?;
+        // Change timing with the SD clock stopped
+        let regs = self.regs();
+        regs.clock_control.modify(CLOCK_CONTROL::SD_CLK_EN::CLEAR);
+        regs.host_control.modify(HOST_CONTROL::HIGH_SPEED::SET);
+        if self.signaling_1v8 {
+            regs.host_control2.modify(match speed {
+                BusSpeed::Sdr104 => HOST_CONTROL2::UHS_MODE::SDR104,
+                BusSpeed::Sdr50 => HOST_CONTROL2::UHS_MODE::SDR50,
+                _ => HOST_CONTROL2::UHS_MODE::SDR25,
+            });
+        }
+        self.set_clock(speed.clock_hz())?;
+        self.bus_speed = speed;
+
+        // SDR104 always needs a tuned sampling point, SDR50 only on some controllers
+        let needs_tuning = speed == BusSpeed::Sdr104
+            || (speed == BusSpeed::Sdr50
+                && self
+                    .regs()
+                    .capabilities_1
+                    .is_set(CAPABILITIES_1::USE_SDR50_TUNING));
+        if needs_tuning && let Err(e) = self.execute_tuning() {
+            // The fixed sampling point is reliable at high-speed clock rates
+            log::warn!("SDHCI: Tuning failed ({:?}), limiting clock to 50 MHz", e);
+            self.set_clock(HIGH_SPEED_CLOCK_HZ)?;
+        }
 
         Ok(())
     }
 
+    /// Tune the sampling clock by reading tuning blocks (CMD19)
+    fn execute_tuning(&mut self) -> Result<(), SdhciError> {
+        self.tuned = false;
+        self.regs()
+            .host_control2
+            .modify(HOST_CONTROL2::EXEC_TUNING::SET + HOST_CONTROL2::SAMPLING_CLK::CLEAR);
+
+        // The controller clears Execute Tuning when done, and sets Sampling
+        // Clock Select if it found a working sampling point
+        let mut result = Err(SdhciError::TuningFailed);
+        for _ in 0..MAX_TUNING_LOOPS {
+            if let Err(e) = self.send_tuning_block() {
+                result = Err(e);
+                break;
+            }
+            let host_control2 = &self.regs().host_control2;
+            if !host_control2.is_set(HOST_CONTROL2::EXEC_TUNING) {
+                if host_control2.is_set(HOST_CONTROL2::SAMPLING_CLK) {
+                    result = Ok(());
+                }
+                break;
+            }
+        }
+
+        if result.is_err() {
+            self.regs()
+                .host_control2
+                .modify(HOST_CONTROL2::EXEC_TUNING::CLEAR + HOST_CONTROL2::SAMPLING_CLK::CLEAR);
+            let _ = self.reset_cmd();
+            let _ = self.reset_data();
+        } else {
+            self.tuned = true;
+        }
+        result
+    }
+
+    /// Send one CMD19 during tuning
+    ///
+    /// The controller compares the tuning pattern itself; the host only waits
+    /// for Buffer Read Ready and never reads the data.
+    fn send_tuning_block(&mut self) -> Result<(), SdhciError> {
+        self.wait_inhibit(true)?;
+
+        let regs = self.regs();
+        regs.int_status.set(TRANSFER_STATUS);
+        regs.block_size
+            .write(BLOCK_SIZE::BLOCK_SIZE.val(TUNING_BLOCK_LEN));
+        regs.block_count.set(1);
+        regs.transfer_mode.write(TRANSFER_MODE::DATA_DIRECTION::SET);
+        regs.argument.set(0);
+        regs.command.write(
+            COMMAND::CMD_INDEX.val(SD_CMD_SEND_TUNING_BLOCK as u16)
+                + COMMAND::RESPONSE_TYPE::Short48
+                + COMMAND::CRC_CHECK::SET
+                + COMMAND::INDEX_CHECK::SET
+                + COMMAND::DATA_PRESENT::SET,
+        );
+
+        let ready = wait_for(50, || regs.int_status.is_set(INT_STATUS::BUFFER_READ_READY));
+        regs.int_status.set(TRANSFER_STATUS);
+        if ready {
+            Ok(())
+        } else {
+            Err(SdhciError::TuningFailed)
+        }
+    }
+
+    /// Read a single small data block through the buffer data port (PIO)
+    ///
+    /// Used for register-like reads such as the CMD6 status block, which are
+    /// not worth setting up DMA for.
+    fn read_data_block(&mut self, cmd: u8, arg: u32, buf: &mut [u8]) -> Result<(), SdhciError> {
+        self.wait_inhibit(true)?;
+        {
+            let regs = self.regs();
+            regs.block_size
+                .write(BLOCK_SIZE::BLOCK_SIZE.val(buf.len() as u16));
+            regs.block_count.set(1);
+            regs.transfer_mode.write(TRANSFER_MODE::DATA_DIRECTION::SET);
+        }
+        self.send_command_internal(cmd, arg, MMC_RSP_R1, true)?;
+
+        // Wait for the block, read it, then wait for the end of the transfer
+        let result = self
+            .wait_data_event(INT_STATUS::BUFFER_READ_READY)
+            .and_then(|()| {
+                let regs = self.regs();
+                for chunk in buf.chunks_mut(4) {
+                    let word = regs.buffer_data.get().to_le_bytes();
+                    chunk.copy_from_slice(&word[..chunk.len()]);
+                }
+                self.wait_data_event(INT_STATUS::TRANSFER_COMPLETE)
+            });
+        if let Err(e) = result {
+            log::debug!("SDHCI: CMD{} data error: {:?}", cmd, e);
+            let _ = self.reset_data();
+        }
+        result
+    }
+
+    /// Wait for the data interrupt `event`, failing on data errors
+    fn wait_data_event(&self, event: Field<u32, INT_STATUS::Register>) -> Result<(), SdhciError> {
+        let regs = self.regs();
+        if !wait_for(DATA_TIMEOUT_MS, || {
+            regs.int_status.is_set(event) || regs.int_status.is_set(INT_STATUS::ERROR)
+        }) {
+            return Err(SdhciError::DataTimeout);
+        }
+
+        let status = regs.int_status.extract();
+        regs.int_status.set(status.get() & TRANSFER_STATUS);
+        if !status.is_set(INT_STATUS::ERROR) {
+            Ok(())
+        } else if status.is_set(INT_STATUS::DATA_TIMEOUT) {
+            Err(SdhciError::DataTimeout)
+        } else if status.is_set(INT_STATUS::DATA_CRC) {
+            Err(SdhciError::DataCrcError)
+        } else if status.is_set(INT_STATUS::DATA_END_BIT) {
+            Err(SdhciError::DataEndBitError)
+        } else {
+            Err(SdhciError::GenericError)
+        }
+    }
+
     /// Read sectors from the card using SDMA
     pub fn read_sectors(
         &mut self,
@@ -782,10 +1249,33 @@ impl SdhciController {
         count: u32,
         buffer: *mut u8,
     ) -> Result<(), SdhciError> {
+        // Notice a removed card before sending it commands
+        self.poll_card();
+        if !self.card_present {
+            return Err(SdhciError::NoCard);
+        }
         if !self.card_initialized {
             return Err(SdhciError::NotInitialized);
         }
 
+        let media_id = self.media_id;
+        self.read_pages(start_lba, count, buffer).map_err(|e| {
+            // A card pulled mid-read fails with some transfer error first
+            match self.poll_card() {
+                Some(_) if !self.card_present => SdhciError::NoCard,
+                Some(_) if self.media_id != media_id => SdhciError::MediaChanged,
+                _ => e,
+            }
+        })
+    }
+
+    /// Read sectors in transfers of at most the DMA buffer size
+    fn read_pages(
+        &mut self,
+        start_lba: u64,
+        count: u32,
+        buffer: *mut u8,
+    ) -> Result<(), SdhciError> {
         if count == 0 {
             return Err(SdhciError::InvalidParameter);
         }
@@ -823,6 +1313,17 @@ impl SdhciController {
     ) -> Result<(), SdhciError> {
         let transfer_size = count as usize * SD_BLOCK_SIZE as usize;
 
+        // The controller asks for re-tuning when its re-tuning timer expires
+        if self.tuned
+            && self
+                .regs()
+                .present_state
+                .is_set(PRESENT_STATE::RETUNE_REQUEST)
+        {
+            log::debug!("SDHCI: Re-tuning sampling clock");
+            self.execute_tuning()?;
+        }
+
         // Wait for data inhibit to clear
         self.wait_inhibit(true)?;
 
@@ -831,7 +1332,7 @@ impl SdhciController {
             let regs = self.regs();
 
             // Clear all pending interrupts
-            regs.int_status.set(0xFFFFFFFF);
+            regs.int_status.set(TRANSFER_STATUS);
 
             // Set DMA address (use our page-aligned buffer)
             let dma_addr = self.dma_buffer as u32;
@@ -893,7 +1394,7 @@ impl SdhciController {
                 let is_complete = regs.int_status.is_set(INT_STATUS::CMD_COMPLETE);
 
                 if has_error {
-                    regs.int_status.set(error_status);
+                    regs.int_status.set(error_status & TRANSFER_STATUS);
                 }
                 if is_complete {
                     regs.int_status.write(INT_STATUS::CMD_COMPLETE::SET);
@@ -944,7 +1445,7 @@ impl SdhciController {
                 let status = regs.int_status.get();
 
                 if regs.int_status.is_set(INT_STATUS::ERROR) {
-                    regs.int_status.set(status);
+                    regs.int_status.set(status & TRANSFER_STATUS);
                     DataResult::Error {
                         status,
                         is_timeout: regs.int_status.is_set(INT_STATUS::DATA_TIMEOUT),
@@ -1039,10 +1540,28 @@ impl SdhciController {
         self.card_present && self.card_initialized
     }
 
+    /// Get the media ID of the current card
+    pub fn media_id(&self) -> u32 {
+        self.media_id
+    }
+
+    /// Media ID of the card in the slot, or `None` without a usable card
+    ///
+    /// Polls card detect first, so a swapped card shows up as a new ID.
+    pub fn current_media(&mut self) -> Option<u32> {
+        self.poll_card();
+        self.is_ready().then_some(self.media_id)
+    }
+
     /// Get the PCI address of this controller
     pub fn pci_address(&self) -> PciAddress {
         self.pci_address
     }
+
+    /// Get the base address of the controller's registers
+    pub fn mmio_base(&self) -> u64 {
+        self.regs as u64
+    }
 }
 
 // ============================================================================
@@ -1067,14 +1586,15 @@ pub fn init() {
     log::info!("Initializing SDHCI controllers...");
 
     let sdhci_devices = pci::find_sdhci_controllers();
+    let mmio_bases = cbfs::find_file(CBFS_NAME)
+        .map(|data| parse_mmio_bases(core::str::from_utf8(data).unwrap_or("")))
+        .unwrap_or_default();
 
-    if sdhci_devices.is_empty() {
+    if sdhci_devices.is_empty() && mmio_bases.is_empty() {
         log::info!("No SDHCI controllers found");
         return;
     }
 
-    let mut controllers = SDHCI_CONTROLLERS.lock();
-
     for dev in sdhci_devices.iter() {
         log::info!(
             "Probing SDHCI controller at {}: {:04x}:{:04x}",
@@ -1085,19 +1605,8 @@ pub fn init() {
 
         match SdhciController::new(dev) {
             Ok(controller) => {
-                // Allocate memory for controller
-                let size = core::mem::size_of::<SdhciController>();
-                let pages = size.div_ceil(4096);
-
-                if let Some(mem) = efi::allocate_pages(pages as u64) {
-                    let controller_ptr = mem.as_mut_ptr() as *mut SdhciController;
-                    unsafe {
-                        ptr::write(controller_ptr, controller);
-                    }
-                    let _ = controllers.push(SdhciControllerPtr(controller_ptr));
+                if add_controller(controller) {
                     log::info!("SDHCI controller at {} initialized", dev.address);
-                } else {
-                    log::error!("Failed to allocate memory for SDHCI controller");
                 }
             }
             Err(e) => {
@@ -1110,12 +1619,78 @@ pub fn init() {
         }
     }
 
+    for &mmio_base in mmio_bases.iter() {
+        log::info!("Probing SDHCI controller at MMIO {:#x}", mmio_base);
+
+        match SdhciController::new_mmio(mmio_base) {
+            Ok(controller) => {
+                if add_controller(controller) {
+                    log::info!("SDHCI controller at MMIO {:#x} initialized", mmio_base);
+                }
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to initialize SDHCI controller at MMIO {:#x}: {:?}",
+                    mmio_base,
+                    e
+                );
+            }
+        }
+    }
+
     log::info!(
         "SDHCI initialization complete: {} controllers",
-        controllers.len()
+        controller_count()
     );
 }
 
+/// Move an initialized controller into the global list
+fn add_controller(controller: SdhciController) -> bool {
+    let mut controllers = SDHCI_CONTROLLERS.lock();
+    if controllers.is_full() {
+        log::error!("Too many SDHCI controllers");
+        return false;
+    }
+
+    // Allocate memory for controller
+    let size = core::mem::size_of::<SdhciController>();
+    let pages = size.div_ceil(4096);
+
+    let Some(mem) = efi::allocate_pages(pages as u64) else {
+        log::error!("Failed to allocate memory for SDHCI controller");
+        return false;
+    };
+    let controller_ptr = mem.as_mut_ptr() as *mut SdhciController;
+    unsafe {
+        ptr::write(controller_ptr, controller);
+    }
+    let _ = controllers.push(SdhciControllerPtr(controller_ptr));
+    true
+}
+
+/// Parse the register bases listed in [`CBFS_NAME`]
+fn parse_mmio_bases(text: &str) -> heapless::Vec<u64, MAX_SDHCI_CONTROLLERS> {
+    let mut bases = heapless::Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let digits = line
+            .strip_prefix("0x")
+            .or_else(|| line.strip_prefix("0X"))
+            .unwrap_or(line);
+        match u64::from_str_radix(digits, 16) {
+            Ok(base) if base != 0 => {
+                if bases.push(base).is_err() {
+                    log::warn!("SDHCI: ignoring {}, too many controllers", line);
+                }
+            }
+            _ => log::warn!("SDHCI: ignoring invalid MMIO base {:?}", line),
+        }
+    }
+    bases
+}
+
 /// Get an SDHCI controller by index
 pub fn get_controller(index: usize) -> Option<&'static mut SdhciController> {
     let controllers = SDHCI_CONTROLLERS.lock();
@@ -1127,6 +1702,19 @@ pub fn controller_count() -> usize {
     SDHCI_CONTROLLERS.lock().len()
 }
 
+/// Poll all controllers for inserted or removed cards
+///
+/// Returns `true` if any card changed.
+pub fn poll_cards() -> bool {
+    let mut changed = false;
+    for index in 0..controller_count() {
+        if let Some(controller) = get_controller(index) {
+            changed |= controller.poll_card().is_some();
+        }
+    }
+    changed
+}
+
 // ============================================================================
 // Global Device for SimpleFileSystem Protocol
 // ============================================================================
@@ -1212,3 +1800,14 @@ pub fn global_read_sector(lba: u64, buffer: &mut [u8]) -> Result<(), ()> {
     }
     result.map_err(|_| ())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mmio_bases() {
+        let bases = parse_mmio_bases("# eMMC and SD\n0xfe330000\n  FE320000 \n\nnot hex\n0\n");
+        assert_eq!(bases.as_slice(), &[0xfe33_0000, 0xfe32_0000]);
+    }
+}
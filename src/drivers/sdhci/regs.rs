@@ -417,9 +417,18 @@ pub const MMC_CMD_APP_CMD: u8 = 55;
 /// SEND_RELATIVE_ADDR (SD) - Ask card to publish new RCA
 pub const SD_CMD_SEND_RELATIVE_ADDR: u8 = 3;
 
+/// SWITCH_FUNC (SD) - Checks or switches a card function, such as bus speed
+pub const SD_CMD_SWITCH_FUNC: u8 = 6;
+
 /// SEND_IF_COND - Sends SD interface condition
 pub const SD_CMD_SEND_IF_COND: u8 = 8;
 
+/// VOLTAGE_SWITCH - Switches signaling to 1.8V
+pub const SD_CMD_VOLTAGE_SWITCH: u8 = 11;
+
+/// SEND_TUNING_BLOCK - Sends a tuning pattern for sampling point tuning
+pub const SD_CMD_SEND_TUNING_BLOCK: u8 = 19;
+
 /// SET_BUS_WIDTH (ACMD6) - Sets bus width
 pub const SD_CMD_APP_SET_BUS_WIDTH: u8 = 6;
 
@@ -436,6 +445,9 @@ pub const OCR_BUSY: u32 = 1 << 31;
 /// Card Capacity Status (HCS) - set for SDHC/SDXC
 pub const OCR_HCS: u32 = 1 << 30;
 
+/// Switching to 1.8V Request (S18R); echoed as Accepted (S18A) in the response
+pub const OCR_S18R: u32 = 1 << 24;
+
 /// Standard voltage range (2.7V - 3.6V)
 pub const OCR_VDD_RANGE: u32 = 0x00FF_8000;
 
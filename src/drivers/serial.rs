@@ -2,6 +2,10 @@
 //!
 //! This module provides a simple driver for the 16550-compatible UART
 //! typically found in PC-compatible systems.
+//!
+//! The port runs at the settings coreboot reports and is put back into them
+//! at ExitBootServices, so a kernel started with
+//! `earlycon=uart,io,<base>,<baud>` keeps writing where the firmware did.
 
 use core::fmt::{self, Write};
 
@@ -9,6 +13,7 @@ use spin::Mutex;
 use tock_registers::interfaces::{Readable, Writeable};
 use tock_registers::register_bitfields;
 
+use super::bmc_console;
 use crate::arch::x86_64::port_regs::{PortReadOnly8, PortReadWrite8, PortWriteOnly8};
 
 // ============================================================================
@@ -189,6 +194,10 @@ impl SerialRegs {
 pub struct SerialPort {
     /// Port registers
     regs: SerialRegs,
+    /// I/O port base address
+    base: u16,
+    /// Baud rate the port was initialized with
+    baud: u32,
     /// Whether this port has been detected as functional
     functional: bool,
 }
@@ -202,6 +211,8 @@ impl SerialPort {
     pub const unsafe fn new(base: u16) -> Self {
         SerialPort {
             regs: SerialRegs::new(base),
+            base,
+            baud: 115200,
             functional: false,
         }
     }
@@ -240,7 +251,15 @@ impl SerialPort {
             return false;
         }
 
-        let divisor = 115200 / baud;
+        self.baud = baud;
+        self.configure();
+        self.functional = true;
+        true
+    }
+
+    /// Program the baud rate, 8N1 framing and FIFOs, with interrupts off
+    fn configure(&self) {
+        let divisor = 115200 / self.baud;
 
         // Disable interrupts
         self.regs.ier.set(0x00);
@@ -269,9 +288,25 @@ impl SerialPort {
         self.regs
             .mcr
             .write(MCR::DTR::SET + MCR::RTS::SET + MCR::OUT2::SET);
+    }
 
-        self.functional = true;
-        true
+    /// Let the transmitter finish and restore the initial line settings
+    ///
+    /// The kernel's early console polls the port without reprogramming it,
+    /// so it has to be left at the rate and framing it is told about.
+    pub fn prepare_handoff(&mut self) {
+        if !self.functional {
+            return;
+        }
+
+        // Bytes still in the FIFO would be lost by the FIFO reset
+        let mut timeout = TX_TIMEOUT_ITERATIONS;
+        while !self.regs.lsr.is_set(LSR::TX_IDLE) && timeout > 0 {
+            timeout -= 1;
+            core::hint::spin_loop();
+        }
+
+        self.configure();
     }
 
     /// Write a byte to the serial port
@@ -367,10 +402,13 @@ pub fn init_from_coreboot(base_addr: u32, baud: u32) {
 }
 
 /// Write a string to the serial port
+///
+/// Output to the serial port also goes to the [BMC console](super::bmc_console).
 pub fn write_str(s: &str) {
     if let Some(ref mut serial) = *SERIAL.lock() {
         let _ = serial.write_str(s);
     }
+    bmc_console::write_bytes(s.as_bytes());
 }
 
 /// Write formatted output to the serial port
@@ -378,6 +416,7 @@ pub fn write_fmt(args: fmt::Arguments) {
     if let Some(ref mut serial) = *SERIAL.lock() {
         let _ = serial.write_fmt(args);
     }
+    let _ = bmc_console::Writer.write_fmt(args);
 }
 
 /// Write a single byte to the serial port
@@ -385,6 +424,38 @@ pub fn write_byte(byte: u8) {
     if let Some(ref mut serial) = *SERIAL.lock() {
         serial.write_byte(byte);
     }
+    bmc_console::write_bytes(&[byte]);
+}
+
+/// Base port of the serial console, if there is one
+pub fn port() -> Option<u16> {
+    SERIAL.lock().as_ref().map(|serial| serial.base)
+}
+
+/// Put the serial port back into its initial settings for the OS
+pub fn prepare_handoff() {
+    // The logger writes to the port, so log before taking the lock
+    let Some((base, baud)) = SERIAL.lock().as_ref().map(|s| (s.base, s.baud)) else {
+        return;
+    };
+    log::info!(
+        "Serial console for the OS: earlycon=uart,io,{:#x},{}",
+        base,
+        baud
+    );
+
+    if let Some(ref mut serial) = *SERIAL.lock() {
+        serial.prepare_handoff();
+    }
+}
+
+/// Check if the serial port can accept another byte
+pub fn can_send() -> bool {
+    if let Some(ref serial) = *SERIAL.lock() {
+        serial.functional && serial.can_send()
+    } else {
+        false
+    }
 }
 
 /// Check if there is input available on the serial port
@@ -405,6 +476,17 @@ pub fn try_read() -> Option<u8> {
     }
 }
 
+/// Release the serial port lock, even if it is held
+///
+/// # Safety
+///
+/// Only for crash reporting: whoever holds the lock must never run again.
+pub unsafe fn force_unlock() {
+    if SERIAL.is_locked() {
+        unsafe { SERIAL.force_unlock() };
+    }
+}
+
 /// Macro for printing to serial
 #[macro_export]
 macro_rules! serial_print {
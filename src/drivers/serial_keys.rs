@@ -0,0 +1,264 @@
+//! Keys typed on a serial terminal
+//!
+//! Terminals send special keys as ANSI/VT escape sequences, e.g. `ESC [ A`
+//! for Up or `ESC O P` and `ESC [ 1 1 ~` for F1. [`KeyDecoder`] turns them
+//! into EFI scan codes. A lone Escape looks like the start of a sequence, so
+//! the caller calls [`KeyDecoder::flush`] once no byte followed it for
+//! [`ESCAPE_TIMEOUT_MS`].
+
+use heapless::{Deque, Vec};
+
+use crate::efi::protocols::console::scan_codes::*;
+
+/// How long to wait for the rest of an escape sequence
+pub const ESCAPE_TIMEOUT_MS: u64 = 50;
+
+/// Escape
+const ESC: u8 = 0x1B;
+
+/// Longest escape sequence kept, without the ESC
+const MAX_SEQUENCE: usize = 16;
+
+/// Keys decoded but not read yet
+const MAX_KEYS: usize = 16;
+
+/// How the bytes after ESC decode
+#[derive(Debug, PartialEq, Eq)]
+enum Decoded {
+    /// A special key
+    Key(u16),
+    /// A complete sequence without an EFI key
+    Ignored,
+    /// More bytes are needed
+    Incomplete,
+    /// Not an escape sequence
+    Invalid,
+}
+
+/// Decoder of serial input into EFI keys (scan code, unicode char)
+pub struct KeyDecoder {
+    /// Bytes received after ESC
+    sequence: Vec<u8, MAX_SEQUENCE>,
+    /// Whether an ESC was received
+    in_escape: bool,
+    /// Decoded keys
+    keys: Deque<(u16, u16), MAX_KEYS>,
+}
+
+impl Default for KeyDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyDecoder {
+    pub const fn new() -> Self {
+        Self {
+            sequence: Vec::new(),
+            in_escape: false,
+            keys: Deque::new(),
+        }
+    }
+
+    /// Feed a byte received from the terminal
+    pub fn push(&mut self, byte: u8) {
+        if !self.in_escape {
+            if byte == ESC {
+                self.in_escape = true;
+            } else {
+                self.queue(plain_key(byte));
+            }
+            return;
+        }
+
+        // ESC ESC is a lone Escape followed by the start of a sequence
+        if byte == ESC && self.sequence.is_empty() {
+            self.queue((SCAN_ESC, 0));
+            return;
+        }
+        if self.sequence.push(byte).is_err() {
+            self.flush();
+            return;
+        }
+
+        match decode(&self.sequence) {
+            Decoded::Key(scan_code) => {
+                self.queue((scan_code, 0));
+                self.reset();
+            }
+            Decoded::Ignored => self.reset(),
+            Decoded::Incomplete => {}
+            Decoded::Invalid => self.flush(),
+        }
+    }
+
+    /// Whether the decoder waits for the rest of an escape sequence
+    pub fn in_sequence(&self) -> bool {
+        self.in_escape
+    }
+
+    /// Give up on the escape sequence being received
+    ///
+    /// The ESC becomes an Escape key and the bytes after it plain keys.
+    pub fn flush(&mut self) {
+        if !self.in_escape {
+            return;
+        }
+        self.queue((SCAN_ESC, 0));
+        let sequence = core::mem::take(&mut self.sequence);
+        self.in_escape = false;
+        for byte in sequence {
+            self.push(byte);
+        }
+    }
+
+    /// Whether a decoded key is waiting
+    pub fn has_key(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Next decoded key
+    pub fn pop(&mut self) -> Option<(u16, u16)> {
+        self.keys.pop_front()
+    }
+
+    fn queue(&mut self, key: (u16, u16)) {
+        // Drop keys nobody reads rather than block
+        let _ = self.keys.push_back(key);
+    }
+
+    fn reset(&mut self) {
+        self.in_escape = false;
+        self.sequence.clear();
+    }
+}
+
+/// Key for a byte outside an escape sequence
+fn plain_key(byte: u8) -> (u16, u16) {
+    match byte {
+        // Enter
+        b'\r' | b'\n' => (0, 0x000D),
+        // Backspace, terminals send DEL for it
+        0x7F | 0x08 => (0, 0x0008),
+        _ => (0, byte as u16),
+    }
+}
+
+/// Decode the bytes after ESC
+fn decode(sequence: &[u8]) -> Decoded {
+    match sequence {
+        [] | [b'['] | [b'O'] | [b'[', b'['] => Decoded::Incomplete,
+
+        // SS3: F1-F4, and cursor keys in application mode
+        [b'O', final_byte] => cursor_key(*final_byte).map_or(Decoded::Ignored, Decoded::Key),
+
+        // Linux console F1-F5
+        [b'[', b'[', key @ b'A'..=b'E'] => Decoded::Key(SCAN_F1 + (key - b'A') as u16),
+        [b'[', b'[', _] => Decoded::Ignored,
+
+        // CSI: parameters, then a final byte
+        [b'[', rest @ ..] => {
+            let (&last, params) = rest.split_last().unwrap_or((&0, &[]));
+            match last {
+                0x40..=0x7E => csi_key(params, last).map_or(Decoded::Ignored, Decoded::Key),
+                0x20..=0x3F => Decoded::Incomplete,
+                _ => Decoded::Invalid,
+            }
+        }
+
+        _ => Decoded::Invalid,
+    }
+}
+
+/// Key for the final byte of a cursor key or F1-F4 sequence
+fn cursor_key(final_byte: u8) -> Option<u16> {
+    Some(match final_byte {
+        b'A' => SCAN_UP,
+        b'B' => SCAN_DOWN,
+        b'C' => SCAN_RIGHT,
+        b'D' => SCAN_LEFT,
+        b'H' => SCAN_HOME,
+        b'F' => SCAN_END,
+        b'P' => SCAN_F1,
+        b'Q' => SCAN_F2,
+        b'R' => SCAN_F3,
+        b'S' => SCAN_F4,
+        _ => return None,
+    })
+}
+
+/// Key for a CSI sequence
+///
+/// Modifiers, the second parameter as in `ESC [ 1 ; 5 A`, are ignored.
+fn csi_key(params: &[u8], final_byte: u8) -> Option<u16> {
+    if final_byte != b'~' {
+        return cursor_key(final_byte);
+    }
+    let first = params.split(|&b| b == b';').next()?;
+    let number = core::str::from_utf8(first).ok()?.parse::<u8>().ok()?;
+    Some(match number {
+        1 | 7 => SCAN_HOME,
+        2 => SCAN_INSERT,
+        3 => SCAN_DELETE,
+        4 | 8 => SCAN_END,
+        5 => SCAN_PAGE_UP,
+        6 => SCAN_PAGE_DOWN,
+        11..=15 => SCAN_F1 + (number - 11) as u16,
+        17..=21 => SCAN_F6 + (number - 17) as u16,
+        23 => SCAN_F11,
+        24 => SCAN_F12,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(input: &[u8], flush: bool) -> std::vec::Vec<(u16, u16)> {
+        let mut decoder = KeyDecoder::new();
+        input.iter().for_each(|&byte| decoder.push(byte));
+        if flush {
+            decoder.flush();
+        }
+        core::iter::from_fn(|| decoder.pop()).collect()
+    }
+
+    #[test]
+    fn decodes_sequences() {
+        assert_eq!(
+            decode_all(b"\x1b[A\x1bOP", false),
+            [(SCAN_UP, 0), (SCAN_F1, 0)]
+        );
+        assert_eq!(
+            decode_all(b"\x1b[15~\x1b[1;5D", false),
+            [(SCAN_F5, 0), (SCAN_LEFT, 0)]
+        );
+        assert_eq!(
+            decode_all(b"\x1b[[B\x1b[24~", false),
+            [(SCAN_F2, 0), (SCAN_F12, 0)]
+        );
+        // Unknown sequences are dropped whole
+        assert_eq!(decode_all(b"\x1b[200~a", false), [(0, b'a' as u16)]);
+        assert_eq!(decode_all(b"\x7f\n", false), [(0, 0x08), (0, 0x0D)]);
+    }
+
+    #[test]
+    fn splits_lone_escape() {
+        // Still waiting for the rest of the sequence
+        assert_eq!(decode_all(b"\x1b[", false), []);
+        assert_eq!(decode_all(b"\x1b", true), [(SCAN_ESC, 0)]);
+        assert_eq!(
+            decode_all(b"\x1b[", true),
+            [(SCAN_ESC, 0), (0, b'[' as u16)]
+        );
+        assert_eq!(
+            decode_all(b"\x1bx", false),
+            [(SCAN_ESC, 0), (0, b'x' as u16)]
+        );
+        assert_eq!(
+            decode_all(b"\x1b\x1b[C", false),
+            [(SCAN_ESC, 0), (SCAN_RIGHT, 0)]
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Intel SPI flash controller
+//!
+//! Erases and writes the boot flash through the hardware sequencing
+//! registers of the chipset's SPI controller, for boards whose SMI handler
+//! doesn't take SMMSTORE commands. The controller does the SPI opcodes
+//! itself, the driver only gives it a cycle type, a flash address and up to
+//! 64 bytes of data.
+//!
+//! Two generations are supported: the fast SPI device 00:1f.5 of 100 series
+//! and later PCHs, which has the registers behind BAR0 and erases 4 KiB at
+//! a time, and the older ICH and PCH chipsets, which have them at offset
+//! 0x3800 of the root complex base of the LPC bridge and report the erase
+//! size in HSFS. Both need a valid flash descriptor.
+//!
+//! Writing needs BIOSWE in BIOS_CNTL. With BIOS lock enabled the chipset
+//! takes an SMI and coreboot clears it again, and the protected range
+//! registers make cycles fail, so a locked-down flash is reported as
+//! [`SpiError::Locked`] or [`SpiError::Cycle`] instead of being written.
+//!
+//! Reference: Intel PCH datasheets, "SPI Interface"; flashrom's ichspi.c
+
+use crate::drivers::mmio::MmioRegion;
+use crate::drivers::pci::{self, PciAddress};
+use crate::time;
+
+const VENDOR_INTEL: u16 = 0x8086;
+
+/// Fast SPI device of 100 series and later PCHs
+const FAST_SPI: PciAddress = PciAddress::new(0, 0x1f, 5);
+/// LPC bridge of older chipsets
+const LPC: PciAddress = PciAddress::new(0, 0x1f, 0);
+
+/// Root complex base address in the LPC bridge's config space
+const LPC_RCBA: u8 = 0xf0;
+/// SPI registers in the root complex register block
+const RCBA_SPIBAR: u64 = 0x3800;
+/// BIOS_CNTL in the config space of the LPC bridge or fast SPI device
+const BIOS_CNTL: u8 = 0xdc;
+const BIOS_CNTL_BIOSWE: u8 = 1 << 0;
+
+/// Hardware sequencing flash status
+const HSFS: u64 = 0x04;
+const HSFS_FDONE: u16 = 1 << 0;
+const HSFS_FCERR: u16 = 1 << 1;
+const HSFS_AEL: u16 = 1 << 2;
+const HSFS_BERASE_SHIFT: u16 = 3;
+const HSFS_SCIP: u16 = 1 << 5;
+const HSFS_FDV: u16 = 1 << 14;
+/// Hardware sequencing flash control
+const HSFC: u64 = 0x06;
+const HSFC_FGO: u16 = 1 << 0;
+const HSFC_FCYCLE_SHIFT: u16 = 1;
+const HSFC_FDBC_SHIFT: u16 = 8;
+/// Flash address
+const FADDR: u64 = 0x08;
+/// Flash data, 16 dwords
+const FDATA: u64 = 0x10;
+
+const CYCLE_WRITE: u16 = 2;
+const CYCLE_ERASE: u16 = 3;
+
+/// Bytes moved by one cycle
+const MAX_TRANSFER: usize = 64;
+/// Writes must not cross a flash page
+const PAGE_SIZE: u32 = 256;
+/// Erasing a block can take a while on old parts
+const CYCLE_TIMEOUT_MS: u64 = 5000;
+
+/// Why a flash operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpiError {
+    /// BIOSWE can't be set
+    Locked,
+    /// The address or size isn't aligned to the erase size
+    Unaligned,
+    /// The controller rejected the cycle, e.g. for a protected range
+    Cycle,
+    /// The cycle didn't finish
+    Timeout,
+}
+
+/// The SPI controller of the chipset
+pub struct SpiFlash {
+    regs: MmioRegion,
+    /// Device with BIOS_CNTL
+    bios_cntl: PciAddress,
+    /// Bytes erased by one erase cycle
+    erase_size: u32,
+}
+
+impl SpiFlash {
+    /// Find the SPI controller
+    ///
+    /// Returns `None` on non-Intel chipsets or without a flash descriptor.
+    pub fn find() -> Option<Self> {
+        let flash = if pci::read_config_u16(FAST_SPI, 0x00) == VENDOR_INTEL {
+            let bar = pci::read_config_u32(FAST_SPI, 0x10) & !0xfff;
+            Self {
+                regs: MmioRegion::new(bar as u64, 0x1000),
+                bios_cntl: FAST_SPI,
+                erase_size: 4096,
+            }
+        } else if pci::read_config_u16(LPC, 0x00) == VENDOR_INTEL {
+            let rcba = pci::read_config_u32(LPC, LPC_RCBA);
+            if rcba & 1 == 0 {
+                return None;
+            }
+            let regs = MmioRegion::new((rcba & !0x3fff) as u64 + RCBA_SPIBAR, 0x200);
+            let erase_size = match (regs.read16(HSFS) >> HSFS_BERASE_SHIFT) & 3 {
+                0 => 256,
+                1 => 4096,
+                2 => 8192,
+                _ => 65536,
+            };
+            Self {
+                regs,
+                bios_cntl: LPC,
+                erase_size,
+            }
+        } else {
+            return None;
+        };
+        if flash.regs.base() == 0 || flash.regs.read16(HSFS) & HSFS_FDV == 0 {
+            log::debug!("SPI flash: no hardware sequencing");
+            return None;
+        }
+        Some(flash)
+    }
+
+    /// Erase `size` bytes at flash address `offset`
+    ///
+    /// Both must be multiples of the erase size of the controller.
+    pub fn erase(&self, offset: u32, size: u32) -> Result<(), SpiError> {
+        if !offset.is_multiple_of(self.erase_size) || !size.is_multiple_of(self.erase_size) {
+            return Err(SpiError::Unaligned);
+        }
+        self.write_enabled(|| {
+            (offset..offset + size)
+                .step_by(self.erase_size as usize)
+                .try_for_each(|address| self.cycle(CYCLE_ERASE, address, 1))
+        })
+    }
+
+    /// Write `data` to the erased flash at address `offset`
+    pub fn write(&self, offset: u32, data: &[u8]) -> Result<(), SpiError> {
+        self.write_enabled(|| {
+            let mut done = 0;
+            while done < data.len() {
+                let address = offset + done as u32;
+                let len = (data.len() - done)
+                    .min(MAX_TRANSFER)
+                    .min((PAGE_SIZE - address % PAGE_SIZE) as usize);
+                for (i, chunk) in data[done..done + len].chunks(4).enumerate() {
+                    let mut word = [0xff; 4];
+                    word[..chunk.len()].copy_from_slice(chunk);
+                    self.regs
+                        .write32(FDATA + 4 * i as u64, u32::from_le_bytes(word));
+                }
+                self.cycle(CYCLE_WRITE, address, len)?;
+                done += len;
+            }
+            Ok(())
+        })
+    }
+
+    /// Run `f` with BIOSWE set, clearing it again afterwards
+    fn write_enabled(&self, f: impl FnOnce() -> Result<(), SpiError>) -> Result<(), SpiError> {
+        let bios_cntl = pci::read_config_u8(self.bios_cntl, BIOS_CNTL);
+        pci::write_config_u8(self.bios_cntl, BIOS_CNTL, bios_cntl | BIOS_CNTL_BIOSWE);
+        let result = if pci::read_config_u8(self.bios_cntl, BIOS_CNTL) & BIOS_CNTL_BIOSWE == 0 {
+            Err(SpiError::Locked)
+        } else {
+            f()
+        };
+        pci::write_config_u8(self.bios_cntl, BIOS_CNTL, bios_cntl & !BIOS_CNTL_BIOSWE);
+        result
+    }
+
+    /// Run one hardware sequencing cycle of `len` bytes at `address`
+    fn cycle(&self, cycle: u16, address: u32, len: usize) -> Result<(), SpiError> {
+        if !time::wait_for(CYCLE_TIMEOUT_MS, || self.regs.read16(HSFS) & HSFS_SCIP == 0) {
+            return Err(SpiError::Timeout);
+        }
+        // The status bits are cleared by writing ones
+        self.regs.write16(HSFS, HSFS_FDONE | HSFS_FCERR | HSFS_AEL);
+        self.regs.write32(FADDR, address);
+        self.regs.write16(
+            HSFC,
+            HSFC_FGO | (cycle << HSFC_FCYCLE_SHIFT) | (((len - 1) as u16) << HSFC_FDBC_SHIFT),
+        );
+        if !time::wait_for(CYCLE_TIMEOUT_MS, || {
+            self.regs.read16(HSFS) & (HSFS_FDONE | HSFS_FCERR | HSFS_AEL) != 0
+        }) {
+            return Err(SpiError::Timeout);
+        }
+        let status = self.regs.read16(HSFS);
+        self.regs.write16(HSFS, HSFS_FDONE | HSFS_FCERR | HSFS_AEL);
+        if status & (HSFS_FCERR | HSFS_AEL) != 0 {
+            log::debug!(
+                "SPI flash: cycle {} at {:#x} failed: {:#x}",
+                cycle,
+                address,
+                status
+            );
+            return Err(SpiError::Cycle);
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 //! Unified Storage Device Abstraction
 //!
-//! This module provides a common interface for all storage devices (USB, NVMe, AHCI)
+//! This module provides a common interface for all storage devices (USB, NVMe, AHCI, RAM disks)
 //! that can be used by the BlockIO protocol and filesystem code.
 
 use spin::Mutex;
@@ -12,13 +12,15 @@ const MAX_STORAGE_DEVICES: usize = 8;
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum StorageType {
     /// USB Mass Storage
-    Usb { slot_id: u8 },
+    Usb { slot_id: u8, lun: u8 },
     /// NVMe
     Nvme { controller_id: usize, nsid: u32 },
     /// AHCI/SATA
     Ahci { controller_id: usize, port: usize },
     /// SDHCI (SD Card)
     Sdhci { controller_id: usize },
+    /// Disk image in memory
+    RamDisk { id: usize },
 }
 
 /// Storage device information
@@ -92,6 +94,38 @@ pub fn get_device(device_id: u32) -> Option<StorageDevice> {
     None
 }
 
+/// Media in a storage device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Media {
+    /// Changes whenever the media is replaced
+    pub media_id: u32,
+    /// Total number of blocks
+    pub num_blocks: u64,
+}
+
+/// Look at the media in a storage device, or `None` if it has none
+///
+/// SD cards are polled for removal and insertion. Other devices cannot be
+/// swapped while we run and always report their registered size.
+pub fn media(device_id: u32) -> Option<Media> {
+    let device = get_device(device_id)?;
+
+    match device.device_type {
+        StorageType::Sdhci { controller_id } => {
+            let controller = crate::drivers::sdhci::get_controller(controller_id)?;
+            let media_id = controller.current_media()?;
+            Some(Media {
+                media_id,
+                num_blocks: controller.num_blocks(),
+            })
+        }
+        _ => Some(Media {
+            media_id: 0,
+            num_blocks: device.num_blocks,
+        }),
+    }
+}
+
 /// Read sectors from a storage device
 ///
 /// This is the unified read function used by BlockIO protocol.
@@ -99,7 +133,7 @@ pub fn read_sectors(device_id: u32, lba: u64, buffer: &mut [u8]) -> Result<(), (
     let device = get_device(device_id).ok_or(())?;
 
     match device.device_type {
-        StorageType::Usb { slot_id: _ } => {
+        StorageType::Usb { .. } => {
             // Use the global USB read function
             crate::drivers::usb::mass_storage::global_read_sector(lba, buffer)
         }
@@ -127,7 +161,10 @@ pub fn read_sectors(device_id: u32, lba: u64, buffer: &mut [u8]) -> Result<(), (
             // Use global_read_sector for SDHCI
             crate::drivers::sdhci::global_read_sector(lba, buffer)
         }
+        StorageType::RamDisk { id } => {
+            crate::drivers::ramdisk::read(id, lba, buffer).map_err(|e| {
+                log::error!("RAM disk read failed at LBA {}: {:?}", lba, e);
+            })
+        }
     }
 }
-
-
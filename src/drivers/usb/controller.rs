@@ -10,6 +10,7 @@
 //! - Device Class Drivers (MSC, HID) use the trait to communicate with devices
 //! - The core layer handles enumeration and device management
 
+use super::quirks;
 use crate::efi;
 use core::ptr;
 use zerocopy::{FromBytes, Immutable, KnownLayout, Unaligned};
@@ -697,6 +698,15 @@ pub trait UsbController {
 
     /// Get interrupt endpoint info for a device
     fn get_interrupt_endpoint(&self, device: u8) -> Option<EndpointInfo>;
+
+    /// Check the root hub ports for devices plugged in or removed since
+    /// the last scan
+    ///
+    /// New devices are enumerated and removed ones forgotten.
+    ///
+    /// # Returns
+    /// `true` if any device was attached or removed
+    fn poll_ports(&mut self) -> bool;
 }
 
 /// Device information
@@ -817,6 +827,27 @@ impl UsbDevice {
     }
 }
 
+/// Forget the device on a root hub port and the devices behind it, if it
+/// is a hub
+///
+/// Returns `true` if a device was forgotten.
+pub fn detach_port(devices: &mut [Option<UsbDevice>], port: u8) -> bool {
+    let Some(index) = devices.iter().position(|d| {
+        d.as_ref()
+            .is_some_and(|d| d.hub_addr == 0 && d.port == port)
+    }) else {
+        return false;
+    };
+    let address = devices[index].as_ref().map_or(0, |d| d.address);
+    devices[index] = None;
+    for device in devices.iter_mut() {
+        if device.as_ref().is_some_and(|d| d.hub_addr == address) {
+            *device = None;
+        }
+    }
+    true
+}
+
 // ============================================================================
 // Interrupt Queue Management
 // ============================================================================
@@ -1028,6 +1059,34 @@ pub struct ConfigurationInfo {
     pub num_interfaces: usize,
 }
 
+impl ConfigurationInfo {
+    /// The Bulk-Only mass storage interface
+    ///
+    /// UAS enclosures usually offer BOT as another alternate setting of
+    /// the same interface, and only BOT is supported. Interfaces without
+    /// both bulk endpoints are skipped.
+    pub fn bot_interface(&self) -> Option<&InterfaceInfo> {
+        let interfaces = &self.interfaces[..self.num_interfaces];
+        let bot = interfaces.iter().find(|iface| {
+            iface.is_mass_storage()
+                && iface.find_bulk_in().is_some()
+                && iface.find_bulk_out().is_some()
+        });
+        if bot.is_none() && interfaces.iter().any(InterfaceInfo::is_uas) {
+            log::warn!("    UAS-only mass storage interface, not supported");
+        }
+        bot
+    }
+
+    /// Whether an interface has alternate settings, needing SET_INTERFACE
+    /// to pick one
+    pub fn has_alternates(&self, interface_number: u8) -> bool {
+        self.interfaces[..self.num_interfaces]
+            .iter()
+            .any(|iface| iface.interface_number == interface_number && iface.alternate_setting != 0)
+    }
+}
+
 /// Parsed interface information
 #[derive(Clone, Copy)]
 pub struct InterfaceInfo {
@@ -1060,6 +1119,11 @@ impl InterfaceInfo {
         self.interface_class == class::MASS_STORAGE && self.interface_protocol == 0x50
     }
 
+    /// Check if this is a USB Attached SCSI interface
+    pub fn is_uas(&self) -> bool {
+        self.interface_class == class::MASS_STORAGE && self.interface_protocol == 0x62
+    }
+
     /// Check if this is a HID keyboard interface
     pub fn is_hid_keyboard(&self) -> bool {
         self.interface_class == class::HID
@@ -1203,12 +1267,25 @@ where
     }
 
     // Find interfaces and their endpoints
+    let quirks = quirks::for_device(vid, pid);
+    let storage = device
+        .config_info
+        .bot_interface()
+        .filter(|_| quirks & quirks::IGNORE_DEVICE == 0)
+        .copied();
+    if let Some(iface) = &storage {
+        device.is_mass_storage = true;
+        device.bulk_in = iface.find_bulk_in().cloned();
+        device.bulk_out = iface.find_bulk_out().cloned();
+        log::info!(
+            "    Mass Storage interface {} alt {}",
+            iface.interface_number,
+            iface.alternate_setting
+        );
+    }
     for iface in &device.config_info.interfaces[..device.config_info.num_interfaces] {
-        if iface.is_mass_storage() {
-            device.is_mass_storage = true;
-            device.bulk_in = iface.find_bulk_in().cloned();
-            device.bulk_out = iface.find_bulk_out().cloned();
-            log::info!("    Mass Storage interface");
+        if iface.interface_class == class::MASS_STORAGE {
+            // Handled above
         } else if iface.is_hid_keyboard() {
             device.is_hid_keyboard = true;
             device.interrupt_in = iface.find_interrupt_in().cloned();
@@ -1231,5 +1308,19 @@ where
         )?;
     }
 
+    // Select the BOT alternate setting of UAS devices
+    if let Some(iface) = storage
+        && device.config_info.has_alternates(iface.interface_number)
+    {
+        do_control(
+            &device,
+            req_type::DIR_OUT | req_type::TYPE_STANDARD | req_type::RCPT_INTERFACE,
+            request::SET_INTERFACE,
+            iface.alternate_setting as u16,
+            iface.interface_number as u16,
+            None,
+        )?;
+    }
+
     Ok(device)
 }
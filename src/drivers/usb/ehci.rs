@@ -28,8 +28,8 @@ use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
 use super::controller::{
     DeviceInfo, EndpointInfo, HUB_DESCRIPTOR_TYPE, HubDescriptor, SetupPacket, UsbController,
-    UsbDevice, UsbError, UsbSpeed, enumerate_device, hub_feature, hub_port_status, req_type,
-    request,
+    UsbDevice, UsbError, UsbSpeed, detach_port, enumerate_device, hub_feature, hub_port_status,
+    req_type, request,
 };
 
 // Import register definitions from ehci_regs module
@@ -638,78 +638,90 @@ impl EhciController {
         log::trace!("EHCI: Enumerating {} ports", self.num_ports);
 
         for port in 0..self.num_ports {
-            let port_reg = self.port(port);
+            self.enumerate_port(port);
+        }
 
-            // Clear status change bits (write 1 to clear CSC, PEC, OCC)
-            port_reg
-                .portsc
-                .modify(PORTSC::CSC::SET + PORTSC::PEC::SET + PORTSC::OCC::SET);
+        Ok(())
+    }
 
-            if !port_reg.portsc.is_set(PORTSC::CCS) {
-                continue;
-            }
+    /// Attach the device on a port, if any
+    ///
+    /// Returns `true` if a device was attached. Low- and full-speed devices
+    /// are handed to the companion controller instead.
+    fn enumerate_port(&mut self, port: u8) -> bool {
+        let port_reg = self.port(port);
+
+        // Clear status change bits (write 1 to clear CSC, PEC, OCC)
+        port_reg
+            .portsc
+            .modify(PORTSC::CSC::SET + PORTSC::PEC::SET + PORTSC::OCC::SET);
+
+        if !port_reg.portsc.is_set(PORTSC::CCS) {
+            return false;
+        }
 
-            // Check line status - if it's K-state (Low Speed), release to companion
-            if port_reg.portsc.read(PORTSC::LS) == PORTSC::LS::KState.into() {
-                log::debug!(
-                    "EHCI: Port {} has low-speed device, releasing to companion",
-                    port
-                );
-                port_reg.portsc.modify(PORTSC::PO::SET);
-                continue;
-            }
+        // Check line status - if it's K-state (Low Speed), release to companion
+        if port_reg.portsc.read(PORTSC::LS) == PORTSC::LS::KState.into() {
+            log::debug!(
+                "EHCI: Port {} has low-speed device, releasing to companion",
+                port
+            );
+            port_reg.portsc.modify(PORTSC::PO::SET);
+            return false;
+        }
 
-            log::info!("EHCI: Device detected on port {}", port);
+        log::info!("EHCI: Device detected on port {}", port);
 
-            // Reset the port (set PR, clear PE)
-            port_reg.portsc.modify(PORTSC::PR::SET + PORTSC::PE::CLEAR);
+        // Reset the port (set PR, clear PE)
+        port_reg.portsc.modify(PORTSC::PR::SET + PORTSC::PE::CLEAR);
 
-            crate::time::delay_ms(50); // USB spec: 10-20ms reset, we use 50ms
+        crate::time::delay_ms(50); // USB spec: 10-20ms reset, we use 50ms
 
-            // Clear reset
-            port_reg.portsc.modify(PORTSC::PR::CLEAR);
+        // Clear reset
+        port_reg.portsc.modify(PORTSC::PR::CLEAR);
 
-            crate::time::delay_ms(10);
+        crate::time::delay_ms(10);
 
-            // Wait for enable
-            let timeout = Timeout::from_ms(100);
-            let mut enabled = false;
-            while !timeout.is_expired() {
-                if port_reg.portsc.is_set(PORTSC::PE) {
-                    enabled = true;
-                    break;
-                }
-                if !port_reg.portsc.is_set(PORTSC::CCS) {
-                    // Device disconnected during reset
-                    break;
-                }
-                crate::time::delay_ms(1);
+        // Wait for enable
+        let timeout = Timeout::from_ms(100);
+        let mut enabled = false;
+        while !timeout.is_expired() {
+            if port_reg.portsc.is_set(PORTSC::PE) {
+                enabled = true;
+                break;
+            }
+            if !port_reg.portsc.is_set(PORTSC::CCS) {
+                // Device disconnected during reset
+                break;
             }
+            crate::time::delay_ms(1);
+        }
 
-            if !enabled {
-                // Check if it's a full-speed device (should go to companion)
-                if port_reg.portsc.is_set(PORTSC::CCS) && !port_reg.portsc.is_set(PORTSC::PE) {
-                    log::debug!(
-                        "EHCI: Port {} has full-speed device, releasing to companion",
-                        port
-                    );
-                    port_reg.portsc.modify(PORTSC::PO::SET);
-                }
-                continue;
+        if !enabled {
+            // Check if it's a full-speed device (should go to companion)
+            if port_reg.portsc.is_set(PORTSC::CCS) && !port_reg.portsc.is_set(PORTSC::PE) {
+                log::debug!(
+                    "EHCI: Port {} has full-speed device, releasing to companion",
+                    port
+                );
+                port_reg.portsc.modify(PORTSC::PO::SET);
             }
+            return false;
+        }
 
-            // Clear status change bits
-            port_reg
-                .portsc
-                .modify(PORTSC::CSC::SET + PORTSC::PEC::SET + PORTSC::OCC::SET);
+        // Clear status change bits
+        port_reg
+            .portsc
+            .modify(PORTSC::CSC::SET + PORTSC::PEC::SET + PORTSC::OCC::SET);
 
-            // Device is high-speed if enabled on EHCI
-            if let Err(e) = self.attach_device(port, UsbSpeed::High) {
+        // Device is high-speed if enabled on EHCI
+        match self.attach_device(port, UsbSpeed::High) {
+            Ok(()) => true,
+            Err(e) => {
                 log::error!("Failed to attach device on port {}: {:?}", port, e);
+                false
             }
         }
-
-        Ok(())
     }
 
     /// Attach a device on a port
@@ -1674,4 +1686,20 @@ impl UsbController for EhciController {
     fn get_interrupt_endpoint(&self, device: u8) -> Option<EndpointInfo> {
         self.get_device(device).and_then(|d| d.interrupt_in)
     }
+
+    fn poll_ports(&mut self) -> bool {
+        let mut changed = false;
+        for port in 0..self.num_ports {
+            if !self.port(port).portsc.is_set(PORTSC::CSC) {
+                continue;
+            }
+            if detach_port(&mut self.devices, port) {
+                log::info!("EHCI: Device removed from port {}", port);
+                changed = true;
+            }
+            // Clears the change bits and skips empty ports
+            changed |= self.enumerate_port(port);
+        }
+        changed
+    }
 }
@@ -9,7 +9,14 @@
 //! - libpayload usbhid.c
 
 use super::controller::{hid_request, req_type, UsbController, UsbError};
+use crate::drivers::keyboard;
+use crate::drivers::keyboard_layout::{self, ISO_KEY};
 use crate::time::Timeout;
+use r_efi::protocols::simple_text_input_ex::{
+    CAPS_LOCK_ACTIVE, LEFT_ALT_PRESSED, LEFT_CONTROL_PRESSED, LEFT_LOGO_PRESSED,
+    LEFT_SHIFT_PRESSED, NUM_LOCK_ACTIVE, RIGHT_ALT_PRESSED, RIGHT_CONTROL_PRESSED,
+    RIGHT_LOGO_PRESSED, RIGHT_SHIFT_PRESSED, SCROLL_LOCK_ACTIVE,
+};
 use spin::Mutex;
 
 // ============================================================================
@@ -61,6 +68,23 @@ impl KeyboardReport {
         (self.modifiers & (Self::MOD_LEFT_ALT | Self::MOD_RIGHT_ALT)) != 0
     }
 
+    /// Modifier keys held, as EFI_KEY_SHIFT_STATE bits
+    pub fn shift_state(&self) -> u32 {
+        const BITS: [u32; 8] = [
+            LEFT_CONTROL_PRESSED,
+            LEFT_SHIFT_PRESSED,
+            LEFT_ALT_PRESSED,
+            LEFT_LOGO_PRESSED,
+            RIGHT_CONTROL_PRESSED,
+            RIGHT_SHIFT_PRESSED,
+            RIGHT_ALT_PRESSED,
+            RIGHT_LOGO_PRESSED,
+        ];
+        (0..8)
+            .filter(|bit| self.modifiers & (1 << bit) != 0)
+            .fold(0, |state, bit| state | BITS[bit])
+    }
+
     /// Check if a key is pressed (in this report)
     pub fn is_key_pressed(&self, keycode: u8) -> bool {
         self.keys.iter().any(|&k| k == keycode && k != 0)
@@ -101,18 +125,16 @@ pub struct UsbHidKeyboard {
     interval: u8,
     /// Previous report (for detecting changes)
     prev_report: KeyboardReport,
-    /// Caps Lock state
-    caps_lock: bool,
-    /// Num Lock state
-    num_lock: bool,
+    /// Lock key states last shown on the LEDs
+    leds: Option<u8>,
     /// Key buffer
-    key_buffer: [u16; 16],
+    key_buffer: [u32; 16],
     /// Buffer read index
     read_idx: usize,
     /// Buffer write index
     write_idx: usize,
     /// Last key for repeat
-    last_key: u16,
+    last_key: u32,
     /// Timeout for initial repeat delay (None = no key held)
     repeat_delay_timeout: Option<Timeout>,
     /// Timeout for repeat interval
@@ -135,8 +157,7 @@ impl UsbHidKeyboard {
             max_packet,
             interval,
             prev_report: KeyboardReport::default(),
-            caps_lock: false,
-            num_lock: false,
+            leds: None,
             key_buffer: [0; 16],
             read_idx: 0,
             write_idx: 0,
@@ -182,16 +203,20 @@ impl UsbHidKeyboard {
         Ok(())
     }
 
-    /// Set LED state
+    /// Show the lock key states on the LEDs
     pub fn set_leds<C: UsbController>(&mut self, controller: &mut C) -> Result<(), UsbError> {
+        let toggles = keyboard::toggle_state();
         let mut led_byte = 0u8;
-        if self.num_lock {
+        if toggles & NUM_LOCK_ACTIVE != 0 {
             led_byte |= 1;
         }
-        if self.caps_lock {
+        if toggles & CAPS_LOCK_ACTIVE != 0 {
             led_byte |= 2;
         }
-        // Scroll Lock would be bit 2
+        if toggles & SCROLL_LOCK_ACTIVE != 0 {
+            led_byte |= 4;
+        }
+        self.leds = Some(toggles);
 
         let mut data = [led_byte];
         controller.control_transfer(
@@ -210,24 +235,33 @@ impl UsbHidKeyboard {
         // Collect new keycodes first to avoid borrow conflict
         let new_keycodes: heapless::Vec<u8, 6> = report.new_keys(&self.prev_report).collect();
 
+        keyboard::set_shift_state(report.shift_state());
+
+        // Partial keystrokes for newly pressed modifiers
+        if report.modifiers & !self.prev_report.modifiers != 0 && keyboard::partial_keys() {
+            self.enqueue_key(0);
+        }
+
         // Process each new keycode
         for keycode in new_keycodes {
+            // Handle lock keys
+            let lock = match keycode {
+                0x39 => CAPS_LOCK_ACTIVE,
+                0x53 => NUM_LOCK_ACTIVE,
+                0x47 => SCROLL_LOCK_ACTIVE,
+                _ => 0,
+            };
+            if lock != 0 {
+                keyboard::toggle(lock);
+                if keyboard::partial_keys() {
+                    self.enqueue_key(0);
+                }
+                continue;
+            }
+
             if let Some(efi_key) = self.translate_keycode(keycode, report) {
                 self.enqueue_key(efi_key);
 
-                // Handle lock keys
-                match keycode {
-                    0x39 => {
-                        // Caps Lock
-                        self.caps_lock = !self.caps_lock;
-                    }
-                    0x53 => {
-                        // Num Lock
-                        self.num_lock = !self.num_lock;
-                    }
-                    _ => {}
-                }
-
                 // Set last key for repeat - start the initial delay timer
                 self.last_key = efi_key;
                 self.repeat_delay_timeout = Some(Timeout::from_ms(REPEAT_INITIAL_DELAY_MS));
@@ -275,7 +309,7 @@ impl UsbHidKeyboard {
     }
 
     /// Enqueue a key
-    fn enqueue_key(&mut self, key: u16) {
+    fn enqueue_key(&mut self, key: u32) {
         let next_write = (self.write_idx + 1) % self.key_buffer.len();
         if next_write != self.read_idx {
             self.key_buffer[self.write_idx] = key;
@@ -289,7 +323,7 @@ impl UsbHidKeyboard {
     }
 
     /// Get a key from the buffer
-    pub fn get_key(&mut self) -> Option<u16> {
+    pub fn get_key(&mut self) -> Option<u32> {
         if self.read_idx == self.write_idx {
             return None;
         }
@@ -300,313 +334,181 @@ impl UsbHidKeyboard {
 
     /// Translate HID keycode to EFI key
     ///
-    /// Returns packed (scan_code << 8) | unicode_char
-    fn translate_keycode(&self, keycode: u8, report: &KeyboardReport) -> Option<u16> {
-        let shift = report.shift_pressed() ^ self.caps_lock;
-        let ctrl = report.ctrl_pressed();
+    /// Returns packed (scan_code << 16) | unicode_char
+    fn translate_keycode(&self, keycode: u8, report: &KeyboardReport) -> Option<u32> {
+        let shift = report.shift_pressed();
+        let altgr = report.modifiers & KeyboardReport::MOD_RIGHT_ALT != 0;
+        let toggles = keyboard::toggle_state();
+        let caps_lock = toggles & CAPS_LOCK_ACTIVE != 0;
+        let num_lock = toggles & NUM_LOCK_ACTIVE != 0;
 
         // EFI scan codes
-        const SCAN_UP: u16 = 0x01;
-        const SCAN_DOWN: u16 = 0x02;
-        const SCAN_RIGHT: u16 = 0x03;
-        const SCAN_LEFT: u16 = 0x04;
-        const SCAN_HOME: u16 = 0x05;
-        const SCAN_END: u16 = 0x06;
-        const SCAN_INSERT: u16 = 0x07;
-        const SCAN_DELETE: u16 = 0x08;
-        const SCAN_PAGE_UP: u16 = 0x09;
-        const SCAN_PAGE_DOWN: u16 = 0x0A;
-        const SCAN_F1: u16 = 0x0B;
-        const SCAN_F2: u16 = 0x0C;
-        const SCAN_F3: u16 = 0x0D;
-        const SCAN_F4: u16 = 0x0E;
-        const SCAN_F5: u16 = 0x0F;
-        const SCAN_F6: u16 = 0x10;
-        const SCAN_F7: u16 = 0x11;
-        const SCAN_F8: u16 = 0x12;
-        const SCAN_F9: u16 = 0x13;
-        const SCAN_F10: u16 = 0x14;
-        const SCAN_F11: u16 = 0x15;
-        const SCAN_F12: u16 = 0x16;
-        const SCAN_ESC: u16 = 0x17;
-
-        // Special keys (return scan_code << 8)
+        const SCAN_UP: u32 = 0x01;
+        const SCAN_DOWN: u32 = 0x02;
+        const SCAN_RIGHT: u32 = 0x03;
+        const SCAN_LEFT: u32 = 0x04;
+        const SCAN_HOME: u32 = 0x05;
+        const SCAN_END: u32 = 0x06;
+        const SCAN_INSERT: u32 = 0x07;
+        const SCAN_DELETE: u32 = 0x08;
+        const SCAN_PAGE_UP: u32 = 0x09;
+        const SCAN_PAGE_DOWN: u32 = 0x0A;
+        const SCAN_F1: u32 = 0x0B;
+        const SCAN_F2: u32 = 0x0C;
+        const SCAN_F3: u32 = 0x0D;
+        const SCAN_F4: u32 = 0x0E;
+        const SCAN_F5: u32 = 0x0F;
+        const SCAN_F6: u32 = 0x10;
+        const SCAN_F7: u32 = 0x11;
+        const SCAN_F8: u32 = 0x12;
+        const SCAN_F9: u32 = 0x13;
+        const SCAN_F10: u32 = 0x14;
+        const SCAN_F11: u32 = 0x15;
+        const SCAN_F12: u32 = 0x16;
+        const SCAN_ESC: u32 = 0x17;
+
+        // Special keys (return scan_code << 16)
         let scan_code = match keycode {
-            0x29 => return Some(SCAN_ESC << 8), // Escape
-            0x3A => return Some(SCAN_F1 << 8),
-            0x3B => return Some(SCAN_F2 << 8),
-            0x3C => return Some(SCAN_F3 << 8),
-            0x3D => return Some(SCAN_F4 << 8),
-            0x3E => return Some(SCAN_F5 << 8),
-            0x3F => return Some(SCAN_F6 << 8),
-            0x40 => return Some(SCAN_F7 << 8),
-            0x41 => return Some(SCAN_F8 << 8),
-            0x42 => return Some(SCAN_F9 << 8),
-            0x43 => return Some(SCAN_F10 << 8),
-            0x44 => return Some(SCAN_F11 << 8),
-            0x45 => return Some(SCAN_F12 << 8),
-            0x49 => return Some(SCAN_INSERT << 8),
-            0x4A => return Some(SCAN_HOME << 8),
-            0x4B => return Some(SCAN_PAGE_UP << 8),
-            0x4C => return Some(SCAN_DELETE << 8),
-            0x4D => return Some(SCAN_END << 8),
-            0x4E => return Some(SCAN_PAGE_DOWN << 8),
-            0x4F => return Some(SCAN_RIGHT << 8),
-            0x50 => return Some(SCAN_LEFT << 8),
-            0x51 => return Some(SCAN_DOWN << 8),
-            0x52 => return Some(SCAN_UP << 8),
+            0x29 => return Some(SCAN_ESC << 16), // Escape
+            0x3A => return Some(SCAN_F1 << 16),
+            0x3B => return Some(SCAN_F2 << 16),
+            0x3C => return Some(SCAN_F3 << 16),
+            0x3D => return Some(SCAN_F4 << 16),
+            0x3E => return Some(SCAN_F5 << 16),
+            0x3F => return Some(SCAN_F6 << 16),
+            0x40 => return Some(SCAN_F7 << 16),
+            0x41 => return Some(SCAN_F8 << 16),
+            0x42 => return Some(SCAN_F9 << 16),
+            0x43 => return Some(SCAN_F10 << 16),
+            0x44 => return Some(SCAN_F11 << 16),
+            0x45 => return Some(SCAN_F12 << 16),
+            0x49 => return Some(SCAN_INSERT << 16),
+            0x4A => return Some(SCAN_HOME << 16),
+            0x4B => return Some(SCAN_PAGE_UP << 16),
+            0x4C => return Some(SCAN_DELETE << 16),
+            0x4D => return Some(SCAN_END << 16),
+            0x4E => return Some(SCAN_PAGE_DOWN << 16),
+            0x4F => return Some(SCAN_RIGHT << 16),
+            0x50 => return Some(SCAN_LEFT << 16),
+            0x51 => return Some(SCAN_DOWN << 16),
+            0x52 => return Some(SCAN_UP << 16),
             _ => 0,
         };
 
         if scan_code != 0 {
-            return Some(scan_code << 8);
+            return Some(scan_code << 16);
         }
 
-        // Character keys
-        let ch = match keycode {
+        // Keys by what they type on a US keyboard, see keyboard_layout
+        let key = match keycode {
             // Letters (a-z: 0x04-0x1D)
-            0x04..=0x1D => {
-                let base = b'a' + (keycode - 0x04);
-                if ctrl {
-                    base - b'a' + 1 // Ctrl+A = 1, Ctrl+Z = 26
-                } else if shift {
-                    base.to_ascii_uppercase()
-                } else {
-                    base
-                }
-            }
-
-            // Numbers (1-9, 0: 0x1E-0x27)
-            0x1E => {
-                if shift {
-                    b'!'
-                } else {
-                    b'1'
-                }
-            }
-            0x1F => {
-                if shift {
-                    b'@'
-                } else {
-                    b'2'
-                }
-            }
-            0x20 => {
-                if shift {
-                    b'#'
-                } else {
-                    b'3'
-                }
-            }
-            0x21 => {
-                if shift {
-                    b'$'
-                } else {
-                    b'4'
-                }
-            }
-            0x22 => {
-                if shift {
-                    b'%'
-                } else {
-                    b'5'
-                }
-            }
-            0x23 => {
-                if shift {
-                    b'^'
-                } else {
-                    b'6'
-                }
-            }
-            0x24 => {
-                if shift {
-                    b'&'
-                } else {
-                    b'7'
-                }
-            }
-            0x25 => {
-                if shift {
-                    b'*'
-                } else {
-                    b'8'
-                }
-            }
-            0x26 => {
-                if shift {
-                    b'('
-                } else {
-                    b'9'
-                }
-            }
-            0x27 => {
-                if shift {
-                    b')'
-                } else {
-                    b'0'
-                }
-            }
-
-            // Special characters
+            0x04..=0x1D => b'a' + (keycode - 0x04),
+            // Numbers (1-9: 0x1E-0x26)
+            0x1E..=0x26 => b'1' + (keycode - 0x1E),
+            0x27 => b'0',
             0x28 => 0x0D, // Enter
             0x2A => 0x08, // Backspace
             0x2B => 0x09, // Tab
-            0x2C => b' ', // Space
-            0x2D => {
-                if shift {
-                    b'_'
-                } else {
-                    b'-'
-                }
-            }
-            0x2E => {
-                if shift {
-                    b'+'
-                } else {
-                    b'='
-                }
-            }
-            0x2F => {
-                if shift {
-                    b'{'
-                } else {
-                    b'['
-                }
-            }
-            0x30 => {
-                if shift {
-                    b'}'
-                } else {
-                    b']'
-                }
-            }
-            0x31 => {
-                if shift {
-                    b'|'
-                } else {
-                    b'\\'
-                }
-            }
-            0x33 => {
-                if shift {
-                    b':'
-                } else {
-                    b';'
-                }
-            }
-            0x34 => {
-                if shift {
-                    b'"'
-                } else {
-                    b'\''
-                }
-            }
-            0x35 => {
-                if shift {
-                    b'~'
-                } else {
-                    b'`'
-                }
-            }
-            0x36 => {
-                if shift {
-                    b'<'
-                } else {
-                    b','
-                }
-            }
-            0x37 => {
-                if shift {
-                    b'>'
-                } else {
-                    b'.'
-                }
-            }
-            0x38 => {
-                if shift {
-                    b'?'
-                } else {
-                    b'/'
-                }
-            }
+            0x2C => b' ',
+            0x2D => b'-',
+            0x2E => b'=',
+            0x2F => b'[',
+            0x30 => b']',
+            // Backslash, and the key next to Enter on ISO keyboards
+            0x31 | 0x32 => b'\\',
+            0x33 => b';',
+            0x34 => b'\'',
+            0x35 => b'`',
+            0x36 => b',',
+            0x37 => b'.',
+            0x38 => b'/',
+            0x64 => ISO_KEY,
+            _ => 0,
+        };
 
+        if key != 0 {
+            let ch = keyboard_layout::layout().translate(key, shift, caps_lock, altgr)?;
+            return Some(ch as u32);
+        }
+
+        // Keypad keys
+        let ch = match keycode {
             // Keypad numbers (if NumLock is on)
             0x59 => {
-                if self.num_lock {
+                if num_lock {
                     b'1'
                 } else {
-                    return Some(SCAN_END << 8);
+                    return Some(SCAN_END << 16);
                 }
             }
             0x5A => {
-                if self.num_lock {
+                if num_lock {
                     b'2'
                 } else {
-                    return Some(SCAN_DOWN << 8);
+                    return Some(SCAN_DOWN << 16);
                 }
             }
             0x5B => {
-                if self.num_lock {
+                if num_lock {
                     b'3'
                 } else {
-                    return Some(SCAN_PAGE_DOWN << 8);
+                    return Some(SCAN_PAGE_DOWN << 16);
                 }
             }
             0x5C => {
-                if self.num_lock {
+                if num_lock {
                     b'4'
                 } else {
-                    return Some(SCAN_LEFT << 8);
+                    return Some(SCAN_LEFT << 16);
                 }
             }
             0x5D => {
-                if self.num_lock {
+                if num_lock {
                     b'5'
                 } else {
                     return None;
                 }
             }
             0x5E => {
-                if self.num_lock {
+                if num_lock {
                     b'6'
                 } else {
-                    return Some(SCAN_RIGHT << 8);
+                    return Some(SCAN_RIGHT << 16);
                 }
             }
             0x5F => {
-                if self.num_lock {
+                if num_lock {
                     b'7'
                 } else {
-                    return Some(SCAN_HOME << 8);
+                    return Some(SCAN_HOME << 16);
                 }
             }
             0x60 => {
-                if self.num_lock {
+                if num_lock {
                     b'8'
                 } else {
-                    return Some(SCAN_UP << 8);
+                    return Some(SCAN_UP << 16);
                 }
             }
             0x61 => {
-                if self.num_lock {
+                if num_lock {
                     b'9'
                 } else {
-                    return Some(SCAN_PAGE_UP << 8);
+                    return Some(SCAN_PAGE_UP << 16);
                 }
             }
             0x62 => {
-                if self.num_lock {
+                if num_lock {
                     b'0'
                 } else {
-                    return Some(SCAN_INSERT << 8);
+                    return Some(SCAN_INSERT << 16);
                 }
             }
             0x63 => {
-                if self.num_lock {
+                if num_lock {
                     b'.'
                 } else {
-                    return Some(SCAN_DELETE << 8);
+                    return Some(SCAN_DELETE << 16);
                 }
             }
 
@@ -620,7 +522,7 @@ impl UsbHidKeyboard {
             _ => return None,
         };
 
-        Some(ch as u16)
+        Some(ch as u32)
     }
 
     /// Get device address
@@ -712,8 +614,8 @@ pub fn get_key() -> Option<(u16, u16)> {
     let mut keyboard = USB_KEYBOARD.lock();
     let key = keyboard.as_mut()?.get_key()?;
 
-    let scan_code = (key >> 8) & 0xFF;
-    let unicode = key & 0xFF;
+    let scan_code = (key >> 16) as u16;
+    let unicode = key as u16;
     Some((scan_code, unicode))
 }
 
@@ -780,6 +682,13 @@ pub fn poll<C: UsbController>(controller: &mut C) {
         }
     }
 
+    // Lock keys may have been toggled here or on another keyboard
+    if keyboard.leds != Some(crate::drivers::keyboard::toggle_state())
+        && let Err(e) = keyboard.set_leds(controller)
+    {
+        log::debug!("Failed to set keyboard LEDs: {:?}", e);
+    }
+
     keyboard.handle_repeat();
 }
 
@@ -5,8 +5,16 @@
 //!
 //! This driver works with any USB host controller that implements the
 //! `UsbController` trait (xHCI, EHCI, OHCI, UHCI).
+//!
+//! Card readers expose each slot as a logical unit (LUN). [`max_lun`] asks
+//! the device how many it has, and each LUN with media is opened as a
+//! separate [`UsbMassStorage`].
+//!
+//! Optical drives are handled the same way with their 2048-byte blocks, so
+//! installer discs in an external DVD drive boot through El Torito.
 
-use super::controller::{UsbController, UsbError};
+use super::controller::{UsbController, UsbError, req_type};
+use super::quirks;
 use crate::time;
 use zerocopy::{FromBytes, Immutable, KnownLayout, Unaligned};
 
@@ -22,6 +30,26 @@ mod scsi_cmd {
     pub const READ_CAPACITY_16: u8 = 0x9E;
 }
 
+/// Bulk-Only Transport class requests
+mod class_request {
+    pub const GET_MAX_LUN: u8 = 0xFE;
+}
+
+/// Most LUNs a device can have, the CBW holds 4 bits
+pub const MAX_LUNS: usize = 16;
+
+/// SCSI peripheral device type of CD/DVD drives
+const PERIPHERAL_OPTICAL: u8 = 0x05;
+
+/// Block size of optical media
+const OPTICAL_BLOCK_SIZE: u32 = 2048;
+
+/// Additional sense code for "medium not present"
+const ASC_MEDIUM_NOT_PRESENT: u8 = 0x3A;
+
+/// Test Unit Ready attempts while an optical drive spins up, 100ms apart
+const OPTICAL_READY_ATTEMPTS: u32 = 100;
+
 /// Command Block Wrapper (CBW) - 31 bytes
 #[repr(C, packed)]
 #[derive(FromBytes, Immutable, KnownLayout, Unaligned, Clone, Copy, Default)]
@@ -119,6 +147,8 @@ pub struct UsbMassStorage {
     max_packet: u16,
     /// LUN
     lun: u8,
+    /// SCSI peripheral device type from INQUIRY
+    peripheral_type: u8,
     /// Command tag counter
     tag: u32,
     /// Number of blocks
@@ -164,6 +194,21 @@ impl UsbMassStorage {
         controller: &mut dyn UsbController,
         device_addr: u8,
     ) -> Result<Self, MassStorageError> {
+        Self::new_lun(controller, device_addr, 0)
+    }
+
+    /// Open a logical unit of a USB mass storage device
+    ///
+    /// Fails if the LUN has no media, like an empty card reader slot.
+    pub fn new_lun(
+        controller: &mut dyn UsbController,
+        device_addr: u8,
+        lun: u8,
+    ) -> Result<Self, MassStorageError> {
+        if lun as usize >= MAX_LUNS {
+            return Err(MassStorageError::InvalidParameter);
+        }
+
         // Get device info to verify it's a mass storage device
         let device_info = controller
             .get_device_info(device_addr)
@@ -183,7 +228,8 @@ impl UsbMassStorage {
             bulk_in: bulk_in_ep.number,
             bulk_out: bulk_out_ep.number,
             max_packet: bulk_in_ep.max_packet_size,
-            lun: 0,
+            lun,
+            peripheral_type: 0,
             tag: 1,
             num_blocks: 0,
             block_size: 512,
@@ -200,10 +246,15 @@ impl UsbMassStorage {
     /// Initialize the device
     fn init(&mut self, controller: &mut dyn UsbController) -> Result<(), MassStorageError> {
         // Test Unit Ready (may need multiple attempts as device spins up)
+        let mut ready = false;
         for _ in 0..5 {
             if self.test_unit_ready(controller).is_ok() {
+                ready = true;
                 break;
             }
+            // Clear the pending sense data, like a unit attention after
+            // a card was inserted
+            let _ = self.request_sense(controller);
             // Delay 100ms between retries
             time::delay_ms(100);
         }
@@ -211,11 +262,28 @@ impl UsbMassStorage {
         // Inquiry
         self.inquiry(controller)?;
 
+        // Optical drives take seconds to spin up a disc
+        if !ready && self.is_optical() {
+            self.wait_for_disc(controller)?;
+        }
+
         // Read Capacity
         self.read_capacity(controller)?;
 
+        // Some drives report the raw sector size of CDs, like 2352 bytes,
+        // but READ(10) transfers the 2048-byte user data
+        if self.is_optical() && self.block_size != OPTICAL_BLOCK_SIZE {
+            log::debug!(
+                "USB optical drive reports {}-byte blocks, using {}",
+                self.block_size,
+                OPTICAL_BLOCK_SIZE
+            );
+            self.block_size = OPTICAL_BLOCK_SIZE;
+        }
+
         log::info!(
-            "USB Mass Storage: {} {} - {} blocks x {} bytes = {} MB",
+            "USB Mass Storage LUN {}: {} {} - {} blocks x {} bytes = {} MB",
+            self.lun,
             core::str::from_utf8(&self.vendor).unwrap_or("?").trim(),
             core::str::from_utf8(&self.product).unwrap_or("?").trim(),
             self.num_blocks,
@@ -308,6 +376,42 @@ impl UsbMassStorage {
         Ok(())
     }
 
+    /// Request Sense command, returns the additional sense code
+    fn request_sense(
+        &mut self,
+        controller: &mut dyn UsbController,
+    ) -> Result<u8, MassStorageError> {
+        let cdb = [scsi_cmd::REQUEST_SENSE, 0, 0, 0, 18, 0];
+        let mut response = [0u8; 18];
+        self.scsi_command(controller, &cdb, Some(&mut response), true)?;
+        log::debug!(
+            "USB Mass Storage LUN {}: sense key {:#x}, ASC {:#x}",
+            self.lun,
+            response[2] & 0x0F,
+            response[12]
+        );
+        Ok(response[12])
+    }
+
+    /// Wait until an optical drive has spun up its disc
+    fn wait_for_disc(
+        &mut self,
+        controller: &mut dyn UsbController,
+    ) -> Result<(), MassStorageError> {
+        log::info!("USB optical drive LUN {}: waiting for the disc", self.lun);
+        for _ in 0..OPTICAL_READY_ATTEMPTS {
+            if self.test_unit_ready(controller).is_ok() {
+                return Ok(());
+            }
+            if matches!(self.request_sense(controller), Ok(ASC_MEDIUM_NOT_PRESENT)) {
+                log::info!("USB optical drive LUN {}: no disc", self.lun);
+                return Err(MassStorageError::NotReady);
+            }
+            time::delay_ms(100);
+        }
+        Err(MassStorageError::NotReady)
+    }
+
     /// Inquiry command
     fn inquiry(&mut self, controller: &mut dyn UsbController) -> Result<(), MassStorageError> {
         let cdb = [scsi_cmd::INQUIRY, 0, 0, 0, 36, 0]; // Request 36 bytes
@@ -317,6 +421,7 @@ impl UsbMassStorage {
 
         // Parse inquiry response using zerocopy
         if let Ok((inquiry, _)) = InquiryResponse::read_from_prefix(&response) {
+            self.peripheral_type = inquiry.peripheral & 0x1F;
             self.vendor = inquiry.vendor;
             self.product = inquiry.product;
         }
@@ -484,6 +589,16 @@ impl UsbMassStorage {
         self.device_addr
     }
 
+    /// Get the logical unit number
+    pub fn lun(&self) -> u8 {
+        self.lun
+    }
+
+    /// Whether this is a CD/DVD drive
+    pub fn is_optical(&self) -> bool {
+        self.peripheral_type == PERIPHERAL_OPTICAL
+    }
+
     // ============================================================================
     // TCG Security Protocol Commands (for Opal SED support)
     // ============================================================================
@@ -619,6 +734,37 @@ impl UsbMassStorage {
     }
 }
 
+/// Highest LUN of a mass storage device, from GET MAX LUN
+///
+/// Devices with a single LUN may stall the request, which means LUN 0.
+/// It isn't sent to devices with the single LUN quirk, as some hang on it.
+pub fn max_lun(controller: &mut dyn UsbController, device_addr: u8) -> u8 {
+    let single_lun = controller.get_device_info(device_addr).is_some_and(|info| {
+        quirks::for_device(info.vendor_id, info.product_id) & quirks::SINGLE_LUN != 0
+    });
+    if single_lun {
+        return 0;
+    }
+    let mut max_lun = [0u8; 1];
+    // Mass storage is the first interface of the devices we configure
+    let result = controller.control_transfer(
+        device_addr,
+        req_type::DIR_IN | req_type::TYPE_CLASS | req_type::RCPT_INTERFACE,
+        class_request::GET_MAX_LUN,
+        0,
+        0,
+        Some(&mut max_lun),
+    );
+    match result {
+        Ok(1) => max_lun[0].min(MAX_LUNS as u8 - 1),
+        Ok(_) => 0,
+        Err(e) => {
+            log::debug!("GET MAX LUN failed ({:?}), assuming one LUN", e);
+            0
+        }
+    }
+}
+
 // ============================================================================
 // Global USB Mass Storage Device
 // ============================================================================
@@ -708,6 +854,24 @@ pub unsafe fn store_global_device_with_controller_ptr(
     }
 }
 
+/// Open a LUN of a device and make it the global device
+///
+/// Discovery leaves the last LUN it scanned as the global device, so the
+/// LUN of the entry to boot is opened again.
+pub fn store_global_lun(controller_index: usize, device_addr: u8, lun: u8) -> bool {
+    let device = super::with_controller(controller_index, |controller| {
+        UsbMassStorage::new_lun(controller, device_addr, lun)
+    });
+    match device {
+        Some(Ok(device)) => store_global_device(device, controller_index),
+        Some(Err(e)) => {
+            log::error!("Failed to open USB LUN {}: {:?}", lun, e);
+            false
+        }
+        None => false,
+    }
+}
+
 /// Get a reference to the global USB mass storage device
 pub fn get_global_device() -> Option<&'static mut UsbMassStorage> {
     GLOBAL_USB_STATE
@@ -9,7 +9,8 @@
 //! - UHCI (USB 1.1) - Intel's USB 1.x controller
 //!
 //! # Device Classes
-//! - Mass Storage (Bulk-Only Transport with SCSI)
+//! - Mass Storage (Bulk-Only Transport with SCSI, the BOT alternate setting
+//!   of UAS devices)
 //! - HID Keyboard (Boot Protocol)
 //!
 //! # Architecture
@@ -23,6 +24,7 @@ pub mod ehci_regs;
 pub mod hid_keyboard;
 pub mod mass_storage;
 pub mod ohci;
+pub mod quirks;
 pub mod uhci;
 pub mod xhci;
 pub mod xhci_regs;
@@ -43,6 +45,7 @@ use core::ptr;
 // ============================================================================
 
 /// Unified USB controller handle
+#[derive(Clone, Copy)]
 pub enum UsbControllerHandle {
     Xhci(*mut XhciController),
     Ehci(*mut ehci::EhciController),
@@ -127,7 +130,12 @@ pub fn init() {
     log::info!("Initializing USB controllers...");
 
     let devices = pci::get_all_devices();
-    let mut controllers = ALL_CONTROLLERS.lock();
+
+    // The list is only locked to add a controller: controller init waits,
+    // and other parallel init tasks run meanwhile, see crate::parallel
+    let add = |handle| {
+        let _ = ALL_CONTROLLERS.lock().push(handle);
+    };
 
     let mut xhci_count = 0;
     let mut ehci_count = 0;
@@ -136,7 +144,7 @@ pub fn init() {
 
     for dev in devices.iter() {
         // Check for USB host controller (class 0x0C, subclass 0x03)
-        if dev.class_code != 0x0C || dev.subclass != 0x03 {
+        if dev.class_code != 0x0C || dev.subclass != 0x03 || !dev.driver_allowed() {
             continue;
         }
 
@@ -157,7 +165,7 @@ pub fn init() {
                         if let Some(mem) = efi::allocate_pages(pages as u64) {
                             let controller_ptr = mem.as_mut_ptr() as *mut XhciController;
                             unsafe { ptr::write(controller_ptr, controller) };
-                            let _ = controllers.push(UsbControllerHandle::Xhci(controller_ptr));
+                            add(UsbControllerHandle::Xhci(controller_ptr));
                             xhci_count += 1;
                             log::info!("  xHCI controller initialized");
                         }
@@ -182,7 +190,7 @@ pub fn init() {
                         if let Some(mem) = efi::allocate_pages(pages as u64) {
                             let controller_ptr = mem.as_mut_ptr() as *mut ehci::EhciController;
                             unsafe { ptr::write(controller_ptr, controller) };
-                            let _ = controllers.push(UsbControllerHandle::Ehci(controller_ptr));
+                            add(UsbControllerHandle::Ehci(controller_ptr));
                             ehci_count += 1;
                             log::info!("  EHCI controller initialized");
                         }
@@ -207,7 +215,7 @@ pub fn init() {
                         if let Some(mem) = efi::allocate_pages(pages as u64) {
                             let controller_ptr = mem.as_mut_ptr() as *mut ohci::OhciController;
                             unsafe { ptr::write(controller_ptr, controller) };
-                            let _ = controllers.push(UsbControllerHandle::Ohci(controller_ptr));
+                            add(UsbControllerHandle::Ohci(controller_ptr));
                             ohci_count += 1;
                             log::info!("  OHCI controller initialized");
                         }
@@ -232,7 +240,7 @@ pub fn init() {
                         if let Some(mem) = efi::allocate_pages(pages as u64) {
                             let controller_ptr = mem.as_mut_ptr() as *mut uhci::UhciController;
                             unsafe { ptr::write(controller_ptr, controller) };
-                            let _ = controllers.push(UsbControllerHandle::Uhci(controller_ptr));
+                            add(UsbControllerHandle::Uhci(controller_ptr));
                             uhci_count += 1;
                             log::info!("  UHCI controller initialized");
                         }
@@ -271,7 +279,8 @@ pub fn init_all() {
 
 /// Initialize USB keyboards from all controllers
 fn init_keyboards() {
-    let controllers = ALL_CONTROLLERS.lock();
+    // Keyboard init waits for the devices, so the list isn't kept locked
+    let controllers = ALL_CONTROLLERS.lock().clone();
 
     for (idx, handle) in controllers.iter().enumerate() {
         with_usb_controller!(handle, mut |controller| {
@@ -317,8 +326,11 @@ pub fn controller_count() -> usize {
 pub fn find_mass_storage() -> Option<(usize, u8)> {
     let controllers = ALL_CONTROLLERS.lock();
 
-    log::debug!("find_mass_storage: checking {} controllers", controllers.len());
-    
+    log::debug!(
+        "find_mass_storage: checking {} controllers",
+        controllers.len()
+    );
+
     controllers.iter().enumerate().find_map(|(idx, handle)| {
         let device = with_usb_controller!(handle, |controller| {
             let result = controller.find_mass_storage();
@@ -347,6 +359,18 @@ pub fn poll_keyboards() {
     }
 }
 
+/// Poll all controllers for devices plugged in or removed
+///
+/// Returns `true` if any device changed.
+pub fn poll_ports() -> bool {
+    let controllers = ALL_CONTROLLERS.lock();
+    let mut changed = false;
+    for handle in controllers.iter() {
+        changed |= with_usb_controller!(handle, mut |controller| controller.poll_ports());
+    }
+    changed
+}
+
 /// Check if USB keyboard has input
 pub fn keyboard_has_key() -> bool {
     hid_keyboard::has_key()
@@ -537,4 +561,8 @@ impl UsbController for XhciController {
             toggle: false,
         })
     }
+
+    fn poll_ports(&mut self) -> bool {
+        xhci::XhciController::poll_ports(self)
+    }
 }
@@ -16,7 +16,7 @@ use core::sync::atomic::{Ordering, fence};
 
 use super::controller::{
     DeviceInfo, Direction, EndpointInfo, SetupPacket, UsbController, UsbDevice, UsbError, UsbSpeed,
-    enumerate_device,
+    detach_port, enumerate_device,
 };
 
 // ============================================================================
@@ -575,58 +575,69 @@ impl OhciController {
     /// Enumerate ports and attach devices
     fn enumerate_ports(&mut self) -> Result<(), UsbError> {
         for port in 0..self.num_ports {
-            let portsc = self.read_port_reg(port);
+            self.enumerate_port(port);
+        }
 
-            // Clear status change bits
-            self.write_port_reg(port, rhportstatus::CLEAR_MASK);
+        Ok(())
+    }
 
-            // Check if device connected
-            if (portsc & rhportstatus::CCS) == 0 {
-                continue;
-            }
+    /// Attach the device on a port, if any
+    ///
+    /// Returns `true` if a device was attached.
+    fn enumerate_port(&mut self, port: u8) -> bool {
+        let portsc = self.read_port_reg(port);
 
-            let is_low_speed = (portsc & rhportstatus::LSDA) != 0;
-            log::info!(
-                "OHCI: Device on port {} ({})",
-                port,
-                if is_low_speed {
-                    "low-speed"
-                } else {
-                    "full-speed"
-                }
-            );
+        // Clear status change bits
+        self.write_port_reg(port, rhportstatus::CLEAR_MASK);
 
-            // Reset the port
-            self.write_port_reg(port, rhportstatus::PRS);
+        // Check if device connected
+        if (portsc & rhportstatus::CCS) == 0 {
+            return false;
+        }
 
-            // Wait for reset complete
-            wait_for(100, || (self.read_port_reg(port) & rhportstatus::PRS) == 0);
+        let is_low_speed = (portsc & rhportstatus::LSDA) != 0;
+        log::info!(
+            "OHCI: Device on port {} ({})",
+            port,
+            if is_low_speed {
+                "low-speed"
+            } else {
+                "full-speed"
+            }
+        );
 
-            crate::time::delay_ms(10); // Recovery time
+        // Reset the port
+        self.write_port_reg(port, rhportstatus::PRS);
 
-            // Clear status change
-            self.write_port_reg(port, rhportstatus::PRSC);
+        // Wait for reset complete
+        wait_for(100, || (self.read_port_reg(port) & rhportstatus::PRS) == 0);
 
-            // Check if enabled
-            let portsc = self.read_port_reg(port);
-            if (portsc & rhportstatus::PES) == 0 {
-                log::warn!("OHCI: Port {} not enabled after reset", port);
-                continue;
-            }
+        crate::time::delay_ms(10); // Recovery time
 
-            // Enumerate the device
-            let speed = if is_low_speed {
-                UsbSpeed::Low
-            } else {
-                UsbSpeed::Full
-            };
+        // Clear status change
+        self.write_port_reg(port, rhportstatus::PRSC);
 
-            if let Err(e) = self.attach_device(port, speed) {
+        // Check if enabled
+        let portsc = self.read_port_reg(port);
+        if (portsc & rhportstatus::PES) == 0 {
+            log::warn!("OHCI: Port {} not enabled after reset", port);
+            return false;
+        }
+
+        // Enumerate the device
+        let speed = if is_low_speed {
+            UsbSpeed::Low
+        } else {
+            UsbSpeed::Full
+        };
+
+        match self.attach_device(port, speed) {
+            Ok(()) => true,
+            Err(e) => {
                 log::error!("Failed to attach device on port {}: {:?}", port, e);
+                false
             }
         }
-
-        Ok(())
     }
 
     /// Attach a device on a port
@@ -1015,6 +1026,22 @@ impl UsbController for OhciController {
     fn get_interrupt_endpoint(&self, device: u8) -> Option<EndpointInfo> {
         self.get_device(device).and_then(|d| d.interrupt_in)
     }
+
+    fn poll_ports(&mut self) -> bool {
+        let mut changed = false;
+        for port in 0..self.num_ports {
+            if self.read_port_reg(port) & rhportstatus::CSC == 0 {
+                continue;
+            }
+            if detach_port(&mut self.devices, port) {
+                log::info!("OHCI: Device removed from port {}", port);
+                changed = true;
+            }
+            // Clears the change bits and skips empty ports
+            changed |= self.enumerate_port(port);
+        }
+        changed
+    }
 }
 
 impl OhciController {
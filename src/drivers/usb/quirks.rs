@@ -0,0 +1,78 @@
+//! USB mass storage quirks
+//!
+//! Some devices need workarounds to work over Bulk-Only Transport. They are
+//! set per VID/PID in the VPD key `crabefi_usb_quirks`, in the format of
+//! Linux's `usb-storage.quirks` parameter so known entries can be copied:
+//! comma-separated `VID:PID:flags` with the IDs in hex, e.g.
+//! `abcd:0001:s,abcd:0002:i`. The flags understood are:
+//!
+//! - `i`: ignore the device
+//! - `s`: the device has a single LUN, don't send GET MAX LUN
+//!
+//! Other flags are ignored.
+
+use crate::coreboot::vpd;
+
+/// Don't use the device
+pub const IGNORE_DEVICE: u32 = 1 << 0;
+
+/// Don't send GET MAX LUN, some devices hang on it
+pub const SINGLE_LUN: u32 = 1 << 1;
+
+/// Quirks of a device in a quirk list
+fn lookup(list: &str, vendor_id: u16, product_id: u16) -> u32 {
+    let mut quirks = 0;
+    for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let mut fields = entry.split(':');
+        let ids = (fields.next(), fields.next(), fields.next());
+        let (Some(vid), Some(pid), Some(flags)) = ids else {
+            log::warn!("USB quirks: invalid entry {:?}", entry);
+            continue;
+        };
+        let (Ok(vid), Ok(pid)) = (u16::from_str_radix(vid, 16), u16::from_str_radix(pid, 16))
+        else {
+            log::warn!("USB quirks: invalid entry {:?}", entry);
+            continue;
+        };
+        if vid != vendor_id || pid != product_id {
+            continue;
+        }
+        for flag in flags.chars() {
+            match flag {
+                'i' => quirks |= IGNORE_DEVICE,
+                's' => quirks |= SINGLE_LUN,
+                _ => {}
+            }
+        }
+    }
+    quirks
+}
+
+/// Quirks configured for a device
+pub fn for_device(vendor_id: u16, product_id: u16) -> u32 {
+    let quirks =
+        vpd::find_str(vpd::KEY_USB_QUIRKS).map_or(0, |list| lookup(list, vendor_id, product_id));
+    if quirks != 0 {
+        log::info!(
+            "USB quirks for {:04x}:{:04x}: {:#x}",
+            vendor_id,
+            product_id,
+            quirks
+        );
+    }
+    quirks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_quirks() {
+        let list = "abcd:0001:s, ABCD:0002:ir,bad, abcd:0001:i";
+        assert_eq!(lookup(list, 0xabcd, 0x0001), SINGLE_LUN | IGNORE_DEVICE);
+        assert_eq!(lookup(list, 0xabcd, 0x0002), IGNORE_DEVICE);
+        assert_eq!(lookup(list, 0xabcd, 0x0003), 0);
+        assert_eq!(lookup("", 0xabcd, 0x0001), 0);
+    }
+}
@@ -16,7 +16,7 @@ use core::sync::atomic::{Ordering, fence};
 
 use super::controller::{
     DeviceInfo, EndpointInfo, SetupPacket, UsbController, UsbDevice, UsbError, UsbSpeed,
-    enumerate_device,
+    detach_port, enumerate_device,
 };
 
 // ============================================================================
@@ -111,6 +111,15 @@ mod portsc {
     pub const WC_BITS: u16 = CSC | PEC;
 }
 
+/// Status/control register of a root hub port
+fn port_reg(port: u8) -> u16 {
+    if port == 0 {
+        regs::PORTSC1
+    } else {
+        regs::PORTSC2
+    }
+}
+
 // ============================================================================
 // UHCI Data Structures
 // ============================================================================
@@ -523,72 +532,79 @@ impl UhciController {
     /// Enumerate ports
     fn enumerate_ports(&mut self) -> Result<(), UsbError> {
         for port in 0..self.num_ports {
-            let reg = if port == 0 {
-                regs::PORTSC1
-            } else {
-                regs::PORTSC2
-            };
+            self.enumerate_port(port);
+        }
 
-            let portsc = self.inw(reg);
+        Ok(())
+    }
 
-            // Clear status change bits
-            self.outw(reg, portsc | portsc::WC_BITS);
+    /// Attach the device on a port, if any
+    ///
+    /// Returns `true` if a device was attached.
+    fn enumerate_port(&mut self, port: u8) -> bool {
+        let reg = port_reg(port);
 
-            if (portsc & portsc::CCS) == 0 {
-                continue;
-            }
+        let portsc = self.inw(reg);
 
-            let is_low_speed = (portsc & portsc::LSDA) != 0;
-            log::info!(
-                "UHCI: Device on port {} ({})",
-                port,
-                if is_low_speed {
-                    "low-speed"
-                } else {
-                    "full-speed"
-                }
-            );
+        // Clear status change bits
+        self.outw(reg, portsc | portsc::WC_BITS);
 
-            // Reset port
-            self.outw(reg, portsc::PR);
-            crate::time::delay_ms(50);
-            self.outw(reg, 0);
-            crate::time::delay_ms(10);
+        if (portsc & portsc::CCS) == 0 {
+            return false;
+        }
 
-            // Enable port
-            for _ in 0..10 {
-                let portsc = self.inw(reg);
-                if (portsc & portsc::CCS) == 0 {
-                    break;
-                }
-                if (portsc & portsc::PE) != 0 {
-                    break;
-                }
-                self.outw(reg, portsc | portsc::PE);
-                crate::time::delay_ms(10);
+        let is_low_speed = (portsc & portsc::LSDA) != 0;
+        log::info!(
+            "UHCI: Device on port {} ({})",
+            port,
+            if is_low_speed {
+                "low-speed"
+            } else {
+                "full-speed"
             }
+        );
 
+        // Reset port
+        self.outw(reg, portsc::PR);
+        crate::time::delay_ms(50);
+        self.outw(reg, 0);
+        crate::time::delay_ms(10);
+
+        // Enable port
+        for _ in 0..10 {
             let portsc = self.inw(reg);
-            if (portsc & portsc::PE) == 0 {
-                log::warn!("UHCI: Port {} not enabled", port);
-                continue;
+            if (portsc & portsc::CCS) == 0 {
+                break;
             }
+            if (portsc & portsc::PE) != 0 {
+                break;
+            }
+            self.outw(reg, portsc | portsc::PE);
+            crate::time::delay_ms(10);
+        }
 
-            // Clear status changes again
-            self.outw(reg, portsc | portsc::WC_BITS);
+        let portsc = self.inw(reg);
+        if (portsc & portsc::PE) == 0 {
+            log::warn!("UHCI: Port {} not enabled", port);
+            return false;
+        }
 
-            let speed = if is_low_speed {
-                UsbSpeed::Low
-            } else {
-                UsbSpeed::Full
-            };
+        // Clear status changes again
+        self.outw(reg, portsc | portsc::WC_BITS);
+
+        let speed = if is_low_speed {
+            UsbSpeed::Low
+        } else {
+            UsbSpeed::Full
+        };
 
-            if let Err(e) = self.attach_device(port, speed) {
+        match self.attach_device(port, speed) {
+            Ok(()) => true,
+            Err(e) => {
                 log::error!("Failed to attach device on port {}: {:?}", port, e);
+                false
             }
         }
-
-        Ok(())
     }
 
     /// Attach a device
@@ -947,6 +963,22 @@ impl UsbController for UhciController {
     fn get_interrupt_endpoint(&self, device: u8) -> Option<EndpointInfo> {
         self.get_device(device).and_then(|d| d.interrupt_in)
     }
+
+    fn poll_ports(&mut self) -> bool {
+        let mut changed = false;
+        for port in 0..self.num_ports {
+            if self.inw(port_reg(port)) & portsc::CSC == 0 {
+                continue;
+            }
+            if detach_port(&mut self.devices, port) {
+                log::info!("UHCI: Device removed from port {}", port);
+                changed = true;
+            }
+            // Clears the change bits and skips empty ports
+            changed |= self.enumerate_port(port);
+        }
+        changed
+    }
 }
 
 impl UhciController {
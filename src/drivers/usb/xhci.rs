@@ -11,6 +11,7 @@ use core::sync::atomic::{Ordering, fence};
 use zerocopy::FromBytes;
 
 use super::controller::{DeviceDescriptor, desc_type, parse_configuration, req_type, request};
+use super::quirks;
 
 // Import all constants from xhci_regs
 use super::xhci_regs::{
@@ -32,6 +33,7 @@ use super::xhci_regs::{
     PORTSC_CCS,
     // PORTSC register bits
     PORTSC_CHANGE_MASK,
+    PORTSC_CSC,
     PORTSC_PED,
     PORTSC_PR,
     PORTSC_PRC,
@@ -45,6 +47,7 @@ use super::xhci_regs::{
     TRB_TYPE_COMMAND_COMPLETION,
     TRB_TYPE_CONFIGURE_ENDPOINT,
     TRB_TYPE_DATA,
+    TRB_TYPE_DISABLE_SLOT,
     TRB_TYPE_ENABLE_SLOT,
     TRB_TYPE_LINK,
     TRB_TYPE_NORMAL,
@@ -971,98 +974,165 @@ impl XhciController {
     /// Enumerate ports and attach devices
     fn enumerate_ports(&mut self) -> Result<(), XhciError> {
         for port in 0..self.num_ports {
-            let portsc = self.read_port_reg(port, PORT_PORTSC);
+            self.enumerate_port(port);
+        }
 
-            // Check if device is connected
-            if portsc & PORTSC_CCS == 0 {
-                continue;
-            }
+        Ok(())
+    }
 
-            // Get speed
-            let speed = ((portsc & PORTSC_SPEED_MASK) >> 10) as u8;
-            let speed_name = match speed {
-                1 => "Full",
-                2 => "Low",
-                3 => "High",
-                4 => "Super",
-                _ => "Unknown",
-            };
+    /// Attach the device on a port, if any
+    ///
+    /// Returns `true` if a device was attached.
+    fn enumerate_port(&mut self, port: u8) -> bool {
+        let portsc = self.read_port_reg(port, PORT_PORTSC);
 
-            log::info!("USB device on port {}: {} speed", port, speed_name);
+        // Check if device is connected
+        if portsc & PORTSC_CCS == 0 {
+            return false;
+        }
+
+        // Get speed
+        let speed = ((portsc & PORTSC_SPEED_MASK) >> 10) as u8;
+        let speed_name = match speed {
+            1 => "Full",
+            2 => "Low",
+            3 => "High",
+            4 => "Super",
+            _ => "Unknown",
+        };
 
-            // Clear status change bits
-            self.write_port_reg(port, PORT_PORTSC, portsc | PORTSC_CHANGE_MASK);
+        log::info!("USB device on port {}: {} speed", port, speed_name);
 
-            // Reset the port if needed
-            if portsc & PORTSC_PED == 0 {
+        // Clear status change bits
+        self.write_port_reg(port, PORT_PORTSC, portsc | PORTSC_CHANGE_MASK);
+
+        // Reset the port if needed
+        if portsc & PORTSC_PED == 0 {
+            let portsc = self.read_port_reg(port, PORT_PORTSC);
+            self.write_port_reg(port, PORT_PORTSC, portsc | PORTSC_PR);
+
+            // Wait for reset to complete (up to 100ms per USB spec)
+            let timeout = Timeout::from_ms(100);
+            while !timeout.is_expired() {
                 let portsc = self.read_port_reg(port, PORT_PORTSC);
-                self.write_port_reg(port, PORT_PORTSC, portsc | PORTSC_PR);
-
-                // Wait for reset to complete (up to 100ms per USB spec)
-                let timeout = Timeout::from_ms(100);
-                while !timeout.is_expired() {
-                    let portsc = self.read_port_reg(port, PORT_PORTSC);
-                    if portsc & PORTSC_PRC != 0 {
-                        self.write_port_reg(port, PORT_PORTSC, portsc | PORTSC_PRC);
-                        break;
-                    }
-                    core::hint::spin_loop();
+                if portsc & PORTSC_PRC != 0 {
+                    self.write_port_reg(port, PORT_PORTSC, portsc | PORTSC_PRC);
+                    break;
                 }
+                core::hint::spin_loop();
             }
+        }
 
-            // Enable slot and address device
-            match self.enable_slot() {
-                Ok(slot_id) => {
-                    log::debug!("Enabled slot {}", slot_id);
+        // Enable slot and address device
+        match self.enable_slot() {
+            Ok(slot_id) => {
+                log::debug!("Enabled slot {}", slot_id);
 
-                    if let Err(e) = self.address_device(slot_id, port, speed) {
-                        log::error!("Failed to address device on port {}: {:?}", port, e);
-                        continue;
-                    }
+                if let Err(e) = self.address_device(slot_id, port, speed) {
+                    log::error!("Failed to address device on port {}: {:?}", port, e);
+                    return false;
+                }
+
+                // Get device descriptor
+                match self.get_device_descriptor(slot_id) {
+                    Ok(desc) => {
+                        // Copy fields to avoid alignment issues
+                        let vid = desc.vendor_id;
+                        let pid = desc.product_id;
+                        let class = desc.device_class;
+                        let num_configs = desc.num_configurations;
+
+                        log::info!("  VID={:04x} PID={:04x} Class={:02x}", vid, pid, class);
 
-                    // Get device descriptor
-                    match self.get_device_descriptor(slot_id) {
-                        Ok(desc) => {
-                            // Copy fields to avoid alignment issues
-                            let vid = desc.vendor_id;
-                            let pid = desc.product_id;
-                            let class = desc.device_class;
-                            let num_configs = desc.num_configurations;
-
-                            log::info!("  VID={:04x} PID={:04x} Class={:02x}", vid, pid, class);
-
-                            if let Some(slot) = &mut self.slots[slot_id as usize] {
-                                slot.device_desc = desc;
-                            }
-
-                            // Try to configure as mass storage (class 0x08)
-                            if (class == 0x08 || (class == 0x00 && num_configs > 0))
-                                && let Err(e) = self.configure_mass_storage(slot_id)
-                            {
-                                log::debug!("Not a mass storage device: {:?}", e);
-                            }
-
-                            // Try to configure as HID keyboard (class 0x03 or class 0x00)
-                            if (class == 0x03 || (class == 0x00 && num_configs > 0))
-                                && let Err(e) = self.configure_hid_keyboard(slot_id)
-                            {
-                                log::debug!("Not a HID keyboard: {:?}", e);
-                            }
+                        if let Some(slot) = &mut self.slots[slot_id as usize] {
+                            slot.device_desc = desc;
                         }
-                        Err(e) => {
-                            log::error!("Failed to get device descriptor: {:?}", e);
+
+                        // Try to configure as mass storage (class 0x08)
+                        if (class == 0x08 || (class == 0x00 && num_configs > 0))
+                            && quirks::for_device(vid, pid) & quirks::IGNORE_DEVICE == 0
+                            && let Err(e) = self.configure_mass_storage(slot_id)
+                        {
+                            log::debug!("Not a mass storage device: {:?}", e);
+                        }
+
+                        // Try to configure as HID keyboard (class 0x03 or class 0x00)
+                        if (class == 0x03 || (class == 0x00 && num_configs > 0))
+                            && let Err(e) = self.configure_hid_keyboard(slot_id)
+                        {
+                            log::debug!("Not a HID keyboard: {:?}", e);
                         }
+                        true
+                    }
+                    Err(e) => {
+                        log::error!("Failed to get device descriptor: {:?}", e);
+                        false
                     }
-                }
-                Err(e) => {
-                    log::error!("Failed to enable slot for port {}: {:?}", port, e);
                 }
             }
+            Err(e) => {
+                log::error!("Failed to enable slot for port {}: {:?}", port, e);
+                false
+            }
         }
+    }
+
+    /// Disable a slot after its device was removed
+    fn disable_slot(&mut self, slot_id: u8) -> Result<(), XhciError> {
+        let mut trb = Trb::default();
+        trb.set_type(TRB_TYPE_DISABLE_SLOT);
+        trb.control |= (slot_id as u32) << 24;
+
+        self.cmd_ring.enqueue(&trb);
+        self.ring_doorbell(0, 0);
+        self.wait_command_completion()?;
 
+        // The contexts aren't freed, devices aren't plugged in that often
+        let dcbaa_entry = unsafe { &mut *((self.dcbaa + (slot_id as u64 * 8)) as *mut u64) };
+        *dcbaa_entry = 0;
         Ok(())
     }
 
+    /// Attach and forget devices on the ports whose connection changed
+    ///
+    /// Returns `true` if any device was attached or removed.
+    pub fn poll_ports(&mut self) -> bool {
+        let mut changed = false;
+        for port in 0..self.num_ports {
+            let portsc = self.read_port_reg(port, PORT_PORTSC);
+            if portsc & PORTSC_CSC == 0 {
+                continue;
+            }
+            // Writing PED would disable the port
+            self.write_port_reg(
+                port,
+                PORT_PORTSC,
+                (portsc & !(PORTSC_PED | PORTSC_CHANGE_MASK)) | PORTSC_CSC,
+            );
+
+            for slot_id in 0..self.slots.len() as u8 {
+                if self.slots[slot_id as usize]
+                    .as_ref()
+                    .is_some_and(|slot| slot.port == port)
+                {
+                    log::info!(
+                        "xHCI: Device in slot {} removed from port {}",
+                        slot_id,
+                        port
+                    );
+                    self.slots[slot_id as usize] = None;
+                    if let Err(e) = self.disable_slot(slot_id) {
+                        log::warn!("Failed to disable slot {}: {:?}", slot_id, e);
+                    }
+                    changed = true;
+                }
+            }
+
+            changed |= self.enumerate_port(port);
+        }
+        changed
+    }
+
     /// Configure a mass storage device
     ///
     /// Uses the shared parse_configuration() infrastructure from controller.rs
@@ -1097,44 +1167,27 @@ impl XhciController {
         // Parse configuration using shared infrastructure
         let config_info = parse_configuration(&config_buf[..total_len]);
 
-        // Find mass storage interface
-        let mut bulk_in = 0u8;
-        let mut bulk_out = 0u8;
-        let mut bulk_max_packet = 0u16;
-        let mut found = false;
-
-        for iface in &config_info.interfaces[..config_info.num_interfaces] {
-            if iface.is_mass_storage() {
-                log::info!(
-                    "  Found USB Mass Storage interface {}",
-                    iface.interface_number
-                );
-
-                if let Some(ep) = iface.find_bulk_in() {
-                    bulk_in = ep.number;
-                    bulk_max_packet = ep.max_packet_size;
-                    log::debug!(
-                        "    Bulk IN EP: {} max_packet: {}",
-                        bulk_in,
-                        bulk_max_packet
-                    );
-                }
-                if let Some(ep) = iface.find_bulk_out() {
-                    bulk_out = ep.number;
-                    log::debug!(
-                        "    Bulk OUT EP: {} max_packet: {}",
-                        bulk_out,
-                        ep.max_packet_size
-                    );
-                }
-                found = true;
-                break;
-            }
-        }
-
-        if !found || bulk_in == 0 || bulk_out == 0 {
+        // Find the Bulk-Only mass storage interface, also of UAS devices
+        let Some(iface) = config_info.bot_interface() else {
             return Err(XhciError::DeviceNotFound);
-        }
+        };
+        let (Some(ep_in), Some(ep_out)) = (iface.find_bulk_in(), iface.find_bulk_out()) else {
+            return Err(XhciError::DeviceNotFound);
+        };
+        let bulk_in = ep_in.number;
+        let bulk_out = ep_out.number;
+        let bulk_max_packet = ep_in.max_packet_size;
+        log::info!(
+            "  Found USB Mass Storage interface {} alt {}",
+            iface.interface_number,
+            iface.alternate_setting
+        );
+        log::debug!(
+            "    Bulk IN EP: {} OUT EP: {} max_packet: {}",
+            bulk_in,
+            bulk_out,
+            bulk_max_packet
+        );
 
         // Set configuration
         self.set_configuration(slot_id, config_info.configuration_value)?;
@@ -1142,6 +1195,18 @@ impl XhciController {
         // Configure endpoints
         self.configure_bulk_endpoints(slot_id, bulk_in, bulk_out, bulk_max_packet)?;
 
+        // Select the BOT alternate setting of UAS devices
+        if config_info.has_alternates(iface.interface_number) {
+            self.control_transfer(
+                slot_id,
+                req_type::DIR_OUT | req_type::TYPE_STANDARD | req_type::RCPT_INTERFACE,
+                request::SET_INTERFACE,
+                iface.alternate_setting as u16,
+                iface.interface_number as u16,
+                None,
+            )?;
+        }
+
         // Update slot info
         if let Some(slot) = &mut self.slots[slot_id as usize] {
             slot.is_mass_storage = true;
@@ -515,5 +515,7 @@ pub const PORTSC_PR: u32 = 1 << 4;
 pub const PORTSC_PP: u32 = 1 << 9;
 /// Port Speed Mask (bits 10-13)
 pub const PORTSC_SPEED_MASK: u32 = 0xF << 10;
+/// Connect Status Change
+pub const PORTSC_CSC: u32 = 1 << 17;
 /// Port Reset Change
 pub const PORTSC_PRC: u32 = 1 << 21;
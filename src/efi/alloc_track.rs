@@ -0,0 +1,242 @@
+//! Allocation tracking (`alloc-track` feature)
+//!
+//! Records every live pool and page allocation with its owner: the image
+//! running when it was made, or the firmware. Memory the firmware allocates
+//! while serving an image's call, such as a file handle, belongs to the
+//! image, since the image is the one to release it.
+//!
+//! When an image returns from StartImage, whatever it still owns has
+//! leaked and is logged. At ExitBootServices a report gives the totals per
+//! memory type and owner, and lists the LoaderData allocations of images
+//! that have returned. That keeps CrabEFI's own drivers honest and shows
+//! which loader eats the memory of a low-RAM device.
+//!
+//! Pages backing pool allocations are counted as the pool allocation.
+//! Allocations past [`MAX_TRACKED`] are counted but not recorded.
+
+use heapless::{String, Vec};
+use r_efi::efi::Handle;
+use r_efi::protocols::loaded_image;
+use spin::Mutex;
+
+use super::allocator::MemoryType;
+use super::handles;
+use super::protocols::device_path;
+
+/// Most live allocations recorded
+pub const MAX_TRACKED: usize = 4096;
+
+/// Most images told apart, later ones are counted under the last
+const MAX_IMAGES: usize = 32;
+
+/// Deepest StartImage nesting tracked
+const MAX_DEPTH: usize = 8;
+
+/// Longest image name kept
+const MAX_NAME_LEN: usize = 64;
+
+/// Most memory types reported, the ones the spec defines
+const MEMORY_TYPES: usize = 16;
+
+/// Owner tag of the firmware
+const FIRMWARE: u8 = 0;
+
+/// How the memory was allocated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Pool,
+    Pages,
+}
+
+/// A live allocation
+struct Allocation {
+    address: u64,
+    size: u64,
+    memory_type: u32,
+    kind: Kind,
+    owner: u8,
+}
+
+/// An image that has been started
+struct Image {
+    name: String<MAX_NAME_LEN>,
+    /// Whether it has returned from StartImage
+    returned: bool,
+}
+
+struct Tracker {
+    live: Vec<Allocation, MAX_TRACKED>,
+    /// Allocations that didn't fit in `live`
+    untracked: usize,
+    /// Images by owner tag minus one
+    images: Vec<Image, MAX_IMAGES>,
+    /// Owner tags of the images in StartImage, innermost last
+    running: Vec<u8, MAX_DEPTH>,
+}
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    live: Vec::new(),
+    untracked: 0,
+    images: Vec::new(),
+    running: Vec::new(),
+});
+
+impl Tracker {
+    fn owner(&self) -> u8 {
+        self.running.last().copied().unwrap_or(FIRMWARE)
+    }
+
+    fn name(&self, owner: u8) -> &str {
+        match owner {
+            FIRMWARE => "firmware",
+            tag => self.images[tag as usize - 1].name.as_str(),
+        }
+    }
+
+    fn returned(&self, owner: u8) -> bool {
+        owner != FIRMWARE && self.images[owner as usize - 1].returned
+    }
+
+    /// Count and size of the live allocations of `owner`
+    fn owned(&self, owner: u8) -> (usize, u64) {
+        self.live
+            .iter()
+            .filter(|a| a.owner == owner)
+            .fold((0, 0), |(count, size), a| (count + 1, size + a.size))
+    }
+}
+
+/// Record an allocation
+pub fn allocated(address: u64, size: u64, memory_type: MemoryType, kind: Kind) {
+    let mut tracker = TRACKER.lock();
+    let allocation = Allocation {
+        address,
+        size,
+        memory_type: memory_type as u32,
+        kind,
+        owner: tracker.owner(),
+    };
+    // AllocateAddress succeeds again on memory the loader already has
+    if let Some(existing) = tracker.live.iter_mut().find(|a| a.address == address) {
+        *existing = allocation;
+    } else if tracker.live.push(allocation).is_err() {
+        tracker.untracked += 1;
+    }
+}
+
+/// Forget the allocation at `address`
+pub fn freed(address: u64) {
+    let mut tracker = TRACKER.lock();
+    if let Some(index) = tracker.live.iter().position(|a| a.address == address) {
+        tracker.live.swap_remove(index);
+    }
+}
+
+/// Attribute the following allocations to the image being started
+pub fn enter(image_handle: Handle, image_base: u64) {
+    let loaded_image = handles::with(|db| db.find(image_handle, &loaded_image::PROTOCOL_GUID));
+    let path = loaded_image.and_then(|protocol| {
+        let file_path = unsafe { (*(protocol as *const loaded_image::Protocol)).file_path };
+        if file_path.is_null() {
+            return None;
+        }
+        unsafe { device_path::file_path_to_str::<MAX_NAME_LEN>(file_path) }
+    });
+    let name = path.unwrap_or_else(|| {
+        let mut name = String::new();
+        let _ = core::fmt::write(&mut name, format_args!("image at {:#x}", image_base));
+        name
+    });
+
+    let mut tracker = TRACKER.lock();
+    let image = Image {
+        name,
+        returned: false,
+    };
+    let owner = match tracker.images.push(image) {
+        Ok(()) => tracker.images.len() as u8,
+        Err(_) => MAX_IMAGES as u8,
+    };
+    if tracker.running.push(owner).is_err() {
+        log::warn!("Alloc-track: images nested too deep, not tracked");
+    }
+}
+
+/// Report what the image returning from StartImage leaked
+pub fn leave() {
+    let mut tracker = TRACKER.lock();
+    let Some(owner) = tracker.running.pop() else {
+        return;
+    };
+    tracker.images[owner as usize - 1].returned = true;
+
+    let (count, size) = tracker.owned(owner);
+    if count > 0 {
+        log::warn!(
+            "Alloc-track: {} returned with {} allocations ({} bytes) not freed",
+            tracker.name(owner),
+            count,
+            size
+        );
+    }
+}
+
+/// Log the totals per memory type and owner and the leaked LoaderData
+pub fn report() {
+    let tracker = TRACKER.lock();
+    log::info!(
+        "Alloc-track: {} live allocations at ExitBootServices",
+        tracker.live.len()
+    );
+    if tracker.untracked > 0 {
+        log::warn!(
+            "Alloc-track: {} allocations past the first {} weren't tracked",
+            tracker.untracked,
+            MAX_TRACKED
+        );
+    }
+
+    for memory_type in 0..MEMORY_TYPES as u32 {
+        let (mut pool, mut pages, mut count) = (0u64, 0u64, 0);
+        for a in tracker.live.iter().filter(|a| a.memory_type == memory_type) {
+            match a.kind {
+                Kind::Pool => pool += a.size,
+                Kind::Pages => pages += a.size,
+            }
+            count += 1;
+        }
+        if count > 0 {
+            log::info!(
+                "  {:?}: {} allocations, {} bytes pool, {} KiB pages",
+                MemoryType::from_u32(memory_type),
+                count,
+                pool,
+                pages / 1024
+            );
+        }
+    }
+
+    for owner in 0..=tracker.images.len() as u8 {
+        let (count, size) = tracker.owned(owner);
+        if count > 0 {
+            log::info!(
+                "  {}: {} allocations, {} bytes",
+                tracker.name(owner),
+                count,
+                size
+            );
+        }
+    }
+
+    for a in tracker.live.iter() {
+        if a.memory_type == MemoryType::LoaderData as u32 && tracker.returned(a.owner) {
+            log::warn!(
+                "  Leaked by {}: {:?} of {} bytes at {:#x}",
+                tracker.name(a.owner),
+                a.kind,
+                a.size,
+                a.address
+            );
+        }
+    }
+}
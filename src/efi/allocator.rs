@@ -9,7 +9,14 @@
 //! The allocator state is stored in the centralized `FirmwareState` structure.
 //! Access it via `crate::state::allocator()` or `crate::state::allocator_mut()`.
 
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::arch::x86_64::protect;
 use crate::coreboot::memory::{MemoryRegion, MemoryType as CbMemoryType};
+#[cfg(feature = "alloc-track")]
+use crate::efi::alloc_track;
+use crate::efi::{pool, pool_guard};
 use crate::state;
 use heapless::Vec;
 use r_efi::efi;
@@ -24,10 +31,23 @@ pub const PAGE_SIZE: u64 = 4096;
 /// Page size as usize for convenience
 pub const PAGE_SIZE_USIZE: usize = 4096;
 
+/// End of the identity mapping the assembly code sets up
+/// The first 64GB (64 PDPTs * 512 PDs * 2MB each)
+const ENTRY_IDENTITY_MAPPED_ADDRESS: u64 = 0x10_0000_0000; // 64GB
+
+/// End of the identity mapping, raised when paging maps higher memory
+static MAX_IDENTITY_MAPPED: AtomicU64 = AtomicU64::new(ENTRY_IDENTITY_MAPPED_ADDRESS);
+
 /// Maximum address that is identity-mapped in page tables
-/// Our assembly code sets up identity mapping for the first 64GB (64 PDPTs * 512 PDs * 2MB each)
 /// Allocations above this address will cause page faults!
-const MAX_IDENTITY_MAPPED_ADDRESS: u64 = 0x10_0000_0000; // 64GB
+pub fn max_identity_mapped_address() -> u64 {
+    MAX_IDENTITY_MAPPED.load(Ordering::Relaxed)
+}
+
+/// Record that the identity mapping now reaches `end`
+pub fn set_max_identity_mapped_address(end: u64) {
+    MAX_IDENTITY_MAPPED.store(end, Ordering::Relaxed);
+}
 
 /// EFI memory allocation types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -68,6 +88,14 @@ impl MemoryType {
         matches!(self, MemoryType::ConventionalMemory)
     }
 
+    /// Check if memory of this type holds code
+    pub fn is_code(&self) -> bool {
+        matches!(
+            self,
+            MemoryType::LoaderCode | MemoryType::BootServicesCode | MemoryType::RuntimeServicesCode
+        )
+    }
+
     /// Check if this memory type should be freed after ExitBootServices
     pub fn is_boot_services(&self) -> bool {
         matches!(
@@ -110,7 +138,12 @@ fn cb_to_efi_memory_type(cb_type: CbMemoryType) -> MemoryType {
         CbMemoryType::AcpiReclaimable => MemoryType::AcpiReclaimMemory,
         CbMemoryType::AcpiNvs => MemoryType::AcpiMemoryNvs,
         CbMemoryType::Unusable => MemoryType::UnusableMemory,
+        CbMemoryType::VendorReserved => MemoryType::ReservedMemoryType,
+        CbMemoryType::Persistent => MemoryType::PersistentMemory,
         CbMemoryType::Table => MemoryType::BootServicesData,
+        // Conventional memory with EFI_MEMORY_SP, which the OS hands to
+        // drivers like dax instead of its page allocator
+        CbMemoryType::SoftReserved => MemoryType::ConventionalMemory,
     }
 }
 
@@ -129,6 +162,7 @@ pub mod attributes {
     pub const EFI_MEMORY_RO: u64 = 0x0000000000020000; // Read-Only
     pub const EFI_MEMORY_SP: u64 = 0x0000000000040000; // Specific Purpose
     pub const EFI_MEMORY_CPU_CRYPTO: u64 = 0x0000000000080000;
+    pub const EFI_MEMORY_HOT_PLUGGABLE: u64 = 0x0000000000100000; // Hot-pluggable
     pub const EFI_MEMORY_RUNTIME: u64 = 0x8000000000000000; // Runtime accessible
 }
 
@@ -168,6 +202,16 @@ impl MemoryDescriptor {
         }
     }
 
+    /// Whether the firmware may allocate from this region
+    ///
+    /// Specific purpose memory is conventional memory reserved for the OS.
+    /// Hot-pluggable memory is left free so the OS can unplug it.
+    pub fn is_free(&self) -> bool {
+        self.get_memory_type() == Some(MemoryType::ConventionalMemory)
+            && self.attribute & (attributes::EFI_MEMORY_SP | attributes::EFI_MEMORY_HOT_PLUGGABLE)
+                == 0
+    }
+
     /// Get the end address (exclusive)
     ///
     /// Returns u64::MAX if the calculation would overflow, which ensures
@@ -252,8 +296,15 @@ impl MemoryAllocator {
                 MemoryType::MemoryMappedIo | MemoryType::MemoryMappedIoPortSpace => {
                     attributes::EFI_MEMORY_UC
                 }
+                MemoryType::PersistentMemory => {
+                    attributes::EFI_MEMORY_WB | attributes::EFI_MEMORY_NV
+                }
                 _ => attributes::EFI_MEMORY_WB,
             };
+            let attribute = match region.region_type {
+                CbMemoryType::SoftReserved => attribute | attributes::EFI_MEMORY_SP,
+                _ => attribute,
+            };
 
             let desc = MemoryDescriptor::new(memory_type, region.start, num_pages, attribute);
 
@@ -262,10 +313,8 @@ impl MemoryAllocator {
             }
         }
 
-        // Sort by physical address
-        self.sort_entries();
-        // Merge adjacent regions of the same type
-        self.merge_entries();
+        // Sort by physical address and merge adjacent regions of the same type
+        self.normalize();
 
         log::info!(
             "Memory allocator initialized with {} entries",
@@ -312,7 +361,7 @@ impl MemoryAllocator {
         }
 
         self.map_key += 1;
-        self.sort_entries();
+        self.normalize();
 
         Ok(())
     }
@@ -360,7 +409,7 @@ impl MemoryAllocator {
                     return Err(efi::Status::OUT_OF_RESOURCES);
                 }
                 self.map_key += 1;
-                self.sort_entries();
+                self.normalize();
                 return Ok(());
             }
         };
@@ -380,40 +429,82 @@ impl MemoryAllocator {
             return Ok(());
         }
 
-        let attribute = entry.attribute;
-
-        // Remove the old entry
-        self.entries.remove(idx);
+        self.split_entry(
+            idx,
+            addr,
+            num_pages,
+            MemoryType::AcpiReclaimMemory,
+            entry.attribute,
+        )
+    }
 
-        // Add up to 3 new entries: before, acpi, after
-        // Region before the ACPI portion (keep original type)
-        if entry.physical_start < addr {
-            let before_pages = (addr - entry.physical_start) / PAGE_SIZE;
-            let before =
-                MemoryDescriptor::new(original_type, entry.physical_start, before_pages, attribute);
-            if self.entries.push(before).is_err() {
-                return Err(efi::Status::OUT_OF_RESOURCES);
+    /// Mark a memory region as runtime services data
+    ///
+    /// For buffers coreboot set up that runtime services keep using. Like
+    /// [`Self::mark_as_acpi_reclaim`] this works on any memory type.
+    pub fn mark_as_runtime_data(&mut self, addr: u64, num_pages: u64) -> Result<(), efi::Status> {
+        let end = num_pages
+            .checked_mul(PAGE_SIZE)
+            .and_then(|size| addr.checked_add(size))
+            .ok_or(efi::Status::INVALID_PARAMETER)?;
+        let found_idx = self
+            .entries
+            .iter()
+            .position(|entry| entry.physical_start <= addr && entry.end() >= end);
+        match found_idx {
+            Some(idx) => {
+                let attribute = self.entries[idx].attribute
+                    | attributes::EFI_MEMORY_RUNTIME
+                    | attributes::EFI_MEMORY_XP;
+                self.split_entry(
+                    idx,
+                    addr,
+                    num_pages,
+                    MemoryType::RuntimeServicesData,
+                    attribute,
+                )
             }
-        }
-
-        // The ACPI reclaim region
-        let acpi = MemoryDescriptor::new(MemoryType::AcpiReclaimMemory, addr, num_pages, attribute);
-        if self.entries.push(acpi).is_err() {
-            return Err(efi::Status::OUT_OF_RESOURCES);
-        }
-
-        // Region after the ACPI portion (keep original type)
-        if entry.end() > end {
-            let after_pages = (entry.end() - end) / PAGE_SIZE;
-            let after = MemoryDescriptor::new(original_type, end, after_pages, attribute);
-            if self.entries.push(after).is_err() {
-                return Err(efi::Status::OUT_OF_RESOURCES);
+            // Spanning several entries, not worth splitting each
+            None if self
+                .entries
+                .iter()
+                .any(|entry| addr < entry.end() && end > entry.physical_start) =>
+            {
+                Err(efi::Status::INVALID_PARAMETER)
             }
+            None => self.force_add_region(addr, num_pages, MemoryType::RuntimeServicesData),
         }
+    }
 
-        self.map_key += 1;
-        self.sort_entries();
-
+    /// Mark the memory in `range` hot-pluggable
+    ///
+    /// Every entry overlapping the range gets EFI_MEMORY_HOT_PLUGGABLE on
+    /// the overlapping pages, which keeps allocations out of them.
+    pub fn mark_hot_pluggable(&mut self, range: Range<u64>) -> Result<(), efi::Status> {
+        let start = range.start & !(PAGE_SIZE - 1);
+        let end = range
+            .end
+            .checked_next_multiple_of(PAGE_SIZE)
+            .unwrap_or(u64::MAX - (PAGE_SIZE - 1));
+
+        while let Some(idx) = self.entries.iter().position(|entry| {
+            entry.physical_start < end
+                && entry.end() > start
+                && entry.attribute & attributes::EFI_MEMORY_HOT_PLUGGABLE == 0
+        }) {
+            let entry = self.entries[idx];
+            let from = entry.physical_start.max(start);
+            let to = entry.end().min(end);
+            self.split_entry(
+                idx,
+                from,
+                (to - from) / PAGE_SIZE,
+                entry
+                    .get_memory_type()
+                    .unwrap_or(MemoryType::ReservedMemoryType),
+                entry.attribute | attributes::EFI_MEMORY_HOT_PLUGGABLE,
+            )?;
+        }
         Ok(())
     }
 
@@ -510,23 +601,41 @@ impl MemoryAllocator {
             return efi::Status::UNSUPPORTED;
         }
 
-        // Find the entry containing this allocation
+        let Some(end) = num_pages
+            .checked_mul(PAGE_SIZE)
+            .and_then(|size| memory.checked_add(size))
+        else {
+            return efi::Status::INVALID_PARAMETER;
+        };
+
+        // Adjacent allocations of the same type share an entry, so the
+        // allocation may be any part of the entry containing it
         let found_idx = self.entries.iter().position(|entry| {
-            entry.physical_start == memory
-                && entry.number_of_pages == num_pages
+            entry.physical_start <= memory
+                && entry.end() >= end
                 && entry
                     .get_memory_type()
                     .is_some_and(|mt| mt.is_boot_services() || mt == MemoryType::ConventionalMemory)
         });
 
-        if let Some(idx) = found_idx {
-            // Change the type back to conventional memory
-            self.entries[idx].memory_type = MemoryType::ConventionalMemory as u32;
-            self.map_key += 1;
-            self.merge_entries();
-            efi::Status::SUCCESS
-        } else {
-            efi::Status::NOT_FOUND
+        let Some(idx) = found_idx else {
+            return efi::Status::NOT_FOUND;
+        };
+        let entry = self.entries[idx];
+        if entry.get_memory_type() == Some(MemoryType::ConventionalMemory) {
+            return efi::Status::SUCCESS;
+        }
+
+        // Change the type back to conventional memory
+        match self.split_entry(
+            idx,
+            memory,
+            num_pages,
+            MemoryType::ConventionalMemory,
+            entry.attribute,
+        ) {
+            Ok(()) => efi::Status::SUCCESS,
+            Err(status) => status,
         }
     }
 
@@ -573,6 +682,16 @@ impl MemoryAllocator {
         self.entries.len()
     }
 
+    /// Get the memory map entries, sorted by physical address
+    pub fn entries(&self) -> &[MemoryDescriptor] {
+        &self.entries
+    }
+
+    /// Check whether ExitBootServices succeeded
+    pub fn boot_services_exited(&self) -> bool {
+        self.boot_services_exited
+    }
+
     /// Mark boot services as exited
     pub fn exit_boot_services(&mut self, provided_map_key: usize) -> efi::Status {
         log::debug!(
@@ -639,13 +758,13 @@ impl MemoryAllocator {
         let size = num_pages.checked_mul(PAGE_SIZE)?;
 
         // Limit max_addr to the identity-mapped region
-        // Our page tables only cover the first 4GB, so allocating above that
-        // would cause page faults when the memory is accessed
-        let max_addr = max_addr.min(MAX_IDENTITY_MAPPED_ADDRESS);
+        // Allocating above that would cause page faults when the memory is
+        // accessed
+        let max_addr = max_addr.min(max_identity_mapped_address());
 
         // Search from high to low addresses (prefer high memory within mapped region)
         for entry in self.entries.iter().rev() {
-            if entry.get_memory_type() != Some(MemoryType::ConventionalMemory) {
+            if !entry.is_free() {
                 continue;
             }
 
@@ -684,17 +803,17 @@ impl MemoryAllocator {
             None => return false,
         };
 
-        let found = self.entries.iter().any(|entry| {
-            entry.get_memory_type() == Some(MemoryType::ConventionalMemory)
-                && entry.physical_start <= start
-                && entry.end() >= end
-        });
+        let found = self
+            .entries
+            .iter()
+            .any(|entry| entry.is_free() && entry.physical_start <= start && entry.end() >= end);
 
         if !found {
             // Debug: find what's at this address
-            let containing = self.entries.iter().find(|entry| {
-                entry.physical_start <= start && entry.end() > start
-            });
+            let containing = self
+                .entries
+                .iter()
+                .find(|entry| entry.physical_start <= start && entry.end() > start);
             if let Some(entry) = containing {
                 log::debug!(
                     "is_region_free({:#x}, {:#x}): found {:?} at {:#x}-{:#x} (need end >= {:#x})",
@@ -789,49 +908,8 @@ impl MemoryAllocator {
             memory_type
         );
 
-        // Calculate how many new entries we need
-        let need_before = entry.physical_start < addr;
-        let need_after = entry.end() > end;
-        let new_entries_needed = 1 + need_before as usize + need_after as usize;
-
-        // Check if we have space BEFORE modifying
-        let entries_after_op = self.entries.len() + new_entries_needed - 1;
-        if entries_after_op > MAX_MEMORY_ENTRIES {
-            self.merge_entries();
-            if self.entries.len() + new_entries_needed - 1 > MAX_MEMORY_ENTRIES {
-                log::warn!("Memory map full, cannot split loader region");
-                return false;
-            }
-        }
-
-        let attribute = entry.attribute;
-
-        // Remove the old entry
-        self.entries.remove(idx);
-
-        // Add region before the new allocation (keep original type)
-        if need_before {
-            let before_pages = (addr - entry.physical_start) / PAGE_SIZE;
-            let before =
-                MemoryDescriptor::new(original_type, entry.physical_start, before_pages, attribute);
-            let _ = self.entries.push(before);
-        }
-
-        // Add the newly allocated region
-        let new_region = MemoryDescriptor::new(memory_type, addr, num_pages, attribute);
-        let _ = self.entries.push(new_region);
-
-        // Add region after the new allocation (keep original type)
-        if need_after {
-            let after_pages = (entry.end() - end) / PAGE_SIZE;
-            let after = MemoryDescriptor::new(original_type, end, after_pages, attribute);
-            let _ = self.entries.push(after);
-        }
-
-        self.map_key += 1;
-        self.sort_entries();
-        self.merge_entries(); // Consolidate to reduce fragmentation
-        true
+        self.split_entry(idx, addr, num_pages, memory_type, entry.attribute)
+            .is_ok()
     }
 
     /// Carve out a region from conventional memory and mark it as a new type
@@ -851,40 +929,11 @@ impl MemoryAllocator {
 
         // Find the entry containing this region
         let found_idx = self.entries.iter().position(|entry| {
-            entry.get_memory_type() == Some(MemoryType::ConventionalMemory)
-                && entry.physical_start <= addr
-                && entry.end() >= end
+            entry.is_free() && entry.physical_start <= addr && entry.end() >= end
         });
 
         let idx = found_idx.ok_or(efi::Status::NOT_FOUND)?;
-        let entry = self.entries[idx];
-
-        // Calculate how many new entries we need (1-3: before?, carved, after?)
-        let need_before = entry.physical_start < addr;
-        let need_after = entry.end() > end;
-        let new_entries_needed = 1 + need_before as usize + need_after as usize;
-
-        // Check if we have space BEFORE modifying anything
-        // We remove 1 entry and add new_entries_needed, so net change is new_entries_needed - 1
-        let entries_after_op = self.entries.len() + new_entries_needed - 1;
-        if entries_after_op > MAX_MEMORY_ENTRIES {
-            // Try to make room by merging first
-            self.merge_entries();
-            let entries_after_merge = self.entries.len() + new_entries_needed - 1;
-            if entries_after_merge > MAX_MEMORY_ENTRIES {
-                log::warn!(
-                    "Memory map full ({} entries), cannot carve out region",
-                    self.entries.len()
-                );
-                return Err(efi::Status::OUT_OF_RESOURCES);
-            }
-        }
-
-        // Now safe to remove the old entry
-        self.entries.remove(idx);
-
-        // Add up to 3 new entries: before, carved, after
-        let mut attribute = entry.attribute;
+        let mut attribute = self.entries[idx].attribute;
 
         // RuntimeServicesCode/Data must have EFI_MEMORY_RUNTIME attribute
         // so the OS knows to keep them mapped after ExitBootServices
@@ -898,46 +947,74 @@ impl MemoryAllocator {
             attribute |= attributes::EFI_MEMORY_XP;
         }
 
-        // Region before the carved out portion
-        if need_before {
-            let before_pages = (addr - entry.physical_start) / PAGE_SIZE;
-            let before = MemoryDescriptor::new(
-                MemoryType::ConventionalMemory,
-                entry.physical_start,
-                before_pages,
-                entry.attribute, // Use original attribute for ConventionalMemory
+        self.split_entry(idx, addr, num_pages, memory_type, attribute)
+    }
+
+    /// Give part of entry `idx` a new type and attribute
+    ///
+    /// The parts of the entry before and after the range keep theirs. The
+    /// pieces replace the entry in place, so the map stays sorted, and are
+    /// merged with their neighbours.
+    fn split_entry(
+        &mut self,
+        idx: usize,
+        addr: u64,
+        num_pages: u64,
+        memory_type: MemoryType,
+        attribute: u64,
+    ) -> Result<(), efi::Status> {
+        let entry = self.entries[idx];
+        let end = addr + num_pages * PAGE_SIZE;
+        let before_pages = (addr - entry.physical_start) / PAGE_SIZE;
+        let after_pages = (entry.end() - end) / PAGE_SIZE;
+
+        // Check if we have space BEFORE modifying anything
+        let new_entries = (before_pages > 0) as usize + (after_pages > 0) as usize;
+        if self.entries.len() + new_entries > MAX_MEMORY_ENTRIES {
+            log::warn!(
+                "Memory map full ({} entries), cannot split region at {:#x}",
+                self.entries.len(),
+                entry.physical_start
             );
-            let _ = self.entries.push(before); // We pre-checked space
+            return Err(efi::Status::OUT_OF_RESOURCES);
         }
 
-        // The carved out region
-        let carved = MemoryDescriptor::new(memory_type, addr, num_pages, attribute);
-        let _ = self.entries.push(carved); // We pre-checked space
+        let mut pieces: Vec<MemoryDescriptor, 3> = Vec::new();
+        if before_pages > 0 {
+            let mut before = entry;
+            before.number_of_pages = before_pages;
+            let _ = pieces.push(before);
+        }
+        let _ = pieces.push(MemoryDescriptor::new(
+            memory_type,
+            addr,
+            num_pages,
+            attribute,
+        ));
+        if after_pages > 0 {
+            let mut after = entry;
+            after.physical_start = end;
+            after.number_of_pages = after_pages;
+            let _ = pieces.push(after);
+        }
 
-        // Region after the carved out portion
-        if need_after {
-            let after_pages = (entry.end() - end) / PAGE_SIZE;
-            let after = MemoryDescriptor::new(
-                MemoryType::ConventionalMemory,
-                end,
-                after_pages,
-                entry.attribute, // Use original attribute for ConventionalMemory
-            );
-            let _ = self.entries.push(after); // We pre-checked space
+        self.entries[idx] = pieces[0];
+        for (i, piece) in pieces.iter().enumerate().skip(1) {
+            let _ = self.entries.insert(idx + i, *piece); // We pre-checked space
         }
 
         self.map_key += 1;
-        self.sort_entries();
-        self.merge_entries(); // Always merge after carving to reduce fragmentation
+        self.merge_entries();
 
         Ok(())
     }
 
-    /// Sort entries by physical address (ascending)
-    fn sort_entries(&mut self) {
+    /// Sort entries by physical address (ascending) and merge them
+    fn normalize(&mut self) {
         self.entries
             .as_mut_slice()
             .sort_unstable_by_key(|entry| entry.physical_start);
+        self.merge_entries();
     }
 
     /// Debug: dump memory map to log
@@ -973,29 +1050,24 @@ impl MemoryAllocator {
     }
 
     /// Merge adjacent entries of the same type and attributes
+    ///
+    /// Compacts the sorted entries in place in one pass.
     fn merge_entries(&mut self) {
-        if self.entries.len() < 2 {
-            return;
-        }
-
-        let mut i = 0;
-        while i < self.entries.len() - 1 {
-            let current_end = self.entries[i].end();
-            let next_start = self.entries[i + 1].physical_start;
-
-            // Check if entries are adjacent and have same type/attributes
-            if current_end == next_start
-                && self.entries[i].memory_type == self.entries[i + 1].memory_type
-                && self.entries[i].attribute == self.entries[i + 1].attribute
+        let mut merged = 0;
+        for i in 1..self.entries.len() {
+            let next = self.entries[i];
+            let current = &mut self.entries[merged];
+            if current.end() == next.physical_start
+                && current.memory_type == next.memory_type
+                && current.attribute == next.attribute
             {
-                // Merge: extend current entry and remove next
-                self.entries[i].number_of_pages += self.entries[i + 1].number_of_pages;
-                self.entries.remove(i + 1);
-                // Don't increment i, check if we can merge more
+                current.number_of_pages += next.number_of_pages;
             } else {
-                i += 1;
+                merged += 1;
+                self.entries[merged] = next;
             }
         }
+        self.entries.truncate((merged + 1).min(self.entries.len()));
     }
 }
 
@@ -1006,6 +1078,23 @@ pub fn init(regions: &[MemoryRegion]) {
     });
 }
 
+/// Mark the memory in `ranges` hot-pluggable
+pub fn mark_hot_pluggable(ranges: &[Range<u64>]) {
+    state::with_allocator_mut(|alloc| {
+        for range in ranges {
+            match alloc.mark_hot_pluggable(range.clone()) {
+                Ok(()) => log::info!("Hot-pluggable memory: {:#x}-{:#x}", range.start, range.end),
+                Err(status) => log::warn!(
+                    "Can't mark {:#x}-{:#x} hot-pluggable: {:?}",
+                    range.start,
+                    range.end,
+                    status
+                ),
+            }
+        }
+    });
+}
+
 /// Reserve a region of memory
 pub fn reserve_region(
     physical_start: u64,
@@ -1035,6 +1124,11 @@ pub fn mark_as_acpi_reclaim(addr: u64, num_pages: u64) -> Result<(), efi::Status
     state::with_allocator_mut(|alloc| alloc.mark_as_acpi_reclaim(addr, num_pages))
 }
 
+/// Mark a memory region as runtime services data
+pub fn mark_as_runtime_data(addr: u64, num_pages: u64) -> Result<(), efi::Status> {
+    state::with_allocator_mut(|alloc| alloc.mark_as_runtime_data(addr, num_pages))
+}
+
 /// Allocate pages of memory
 pub fn allocate_pages(
     alloc_type: AllocateType,
@@ -1042,14 +1136,67 @@ pub fn allocate_pages(
     num_pages: u64,
     memory: &mut u64,
 ) -> efi::Status {
-    state::with_allocator_mut(|alloc| {
+    let status = state::with_allocator_mut(|alloc| {
         alloc.allocate_pages(alloc_type, memory_type, num_pages, memory)
-    })
+    });
+    if status == efi::Status::SUCCESS {
+        protect::allocated(*memory..*memory + num_pages * PAGE_SIZE, memory_type);
+    }
+    #[cfg(feature = "alloc-track")]
+    if status == efi::Status::SUCCESS {
+        alloc_track::allocated(
+            *memory,
+            num_pages * PAGE_SIZE,
+            memory_type,
+            alloc_track::Kind::Pages,
+        );
+    }
+    status
 }
 
 /// Free previously allocated pages
 pub fn free_pages(memory: u64, num_pages: u64) -> efi::Status {
-    state::with_allocator_mut(|alloc| alloc.free_pages(memory, num_pages))
+    let status = state::with_allocator_mut(|alloc| alloc.free_pages(memory, num_pages));
+    if status == efi::Status::SUCCESS {
+        protect::freed(memory..memory + num_pages * PAGE_SIZE);
+    }
+    #[cfg(feature = "alloc-track")]
+    if status == efi::Status::SUCCESS {
+        alloc_track::freed(memory);
+    }
+    status
+}
+
+/// Allocate the pages backing a pool allocation
+///
+/// Unlike [`allocate_pages`], these are left out of allocation tracking,
+/// which counts the pool allocation instead.
+pub fn allocate_pool_pages(memory_type: MemoryType, num_pages: u64) -> Result<u64, efi::Status> {
+    let mut addr = 0;
+    let status = state::with_allocator_mut(|alloc| {
+        alloc.allocate_pages(
+            AllocateType::AllocateAnyPages,
+            memory_type,
+            num_pages,
+            &mut addr,
+        )
+    });
+    match status {
+        efi::Status::SUCCESS => {
+            protect::allocated(addr..addr + num_pages * PAGE_SIZE, memory_type);
+            Ok(addr)
+        }
+        status => Err(status),
+    }
+}
+
+/// Free the pages backing a pool allocation
+pub fn free_pool_pages(memory: u64, num_pages: u64) -> efi::Status {
+    let status = state::with_allocator_mut(|alloc| alloc.free_pages(memory, num_pages));
+    if status == efi::Status::SUCCESS {
+        protect::freed(memory..memory + num_pages * PAGE_SIZE);
+    }
+    status
 }
 
 /// Get the memory map size
@@ -1113,9 +1260,27 @@ const POOL_MAGIC: u64 = 0x504F4F4C_48445200; // "POOLHDR\0"
 
 /// Allocate pool memory (arbitrary size)
 pub fn allocate_pool(memory_type: MemoryType, size: usize) -> Result<*mut u8, efi::Status> {
+    let buffer = allocate_pool_untracked(memory_type, size)?;
+    #[cfg(feature = "alloc-track")]
+    alloc_track::allocated(
+        buffer as u64,
+        size as u64,
+        memory_type,
+        alloc_track::Kind::Pool,
+    );
+    Ok(buffer)
+}
+
+fn allocate_pool_untracked(memory_type: MemoryType, size: usize) -> Result<*mut u8, efi::Status> {
     if size == 0 {
         return Err(efi::Status::INVALID_PARAMETER);
     }
+    if cfg!(feature = "alloc-guard") {
+        return pool_guard::allocate(memory_type, size);
+    }
+    if let Some(class) = pool::size_class(memory_type, size) {
+        return pool::allocate(memory_type, class);
+    }
 
     // Calculate total size including header, with overflow check
     let header_size = core::mem::size_of::<PoolHeader>();
@@ -1130,17 +1295,7 @@ pub fn allocate_pool(memory_type: MemoryType, size: usize) -> Result<*mut u8, ef
         .ok_or(efi::Status::OUT_OF_RESOURCES)?
         / PAGE_SIZE;
 
-    let mut addr = 0u64;
-    let status = allocate_pages(
-        AllocateType::AllocateAnyPages,
-        memory_type,
-        num_pages,
-        &mut addr,
-    );
-
-    if status != efi::Status::SUCCESS {
-        return Err(status);
-    }
+    let addr = allocate_pool_pages(memory_type, num_pages)?;
 
     // Write the header
     let header = addr as *mut PoolHeader;
@@ -1156,23 +1311,38 @@ pub fn allocate_pool(memory_type: MemoryType, size: usize) -> Result<*mut u8, ef
 
 /// Free pool memory
 pub fn free_pool(buffer: *mut u8) -> efi::Status {
+    let status = free_pool_untracked(buffer);
+    #[cfg(feature = "alloc-track")]
+    if status == efi::Status::SUCCESS {
+        alloc_track::freed(buffer as u64);
+    }
+    status
+}
+
+fn free_pool_untracked(buffer: *mut u8) -> efi::Status {
     if buffer.is_null() {
         return efi::Status::INVALID_PARAMETER;
     }
+    if cfg!(feature = "alloc-guard") {
+        return pool_guard::free(buffer);
+    }
 
     // Get the header
     let header = unsafe { (buffer as *mut PoolHeader).sub(1) };
 
-    // Validate magic
+    // Validate magic, small allocations come from slabs
     let magic = unsafe { (*header).magic };
     if magic != POOL_MAGIC {
+        if pool::is_slab_block(buffer) {
+            return pool::free(buffer);
+        }
         return efi::Status::INVALID_PARAMETER;
     }
 
     let num_pages = unsafe { (*header).num_pages };
     let addr = header as u64;
 
-    free_pages(addr, num_pages)
+    free_pool_pages(addr, num_pages)
 }
 
 // Linker symbols for section boundaries
@@ -1183,6 +1353,15 @@ unsafe extern "C" {
     static __runtime_data_end: u8;
 }
 
+/// Page-aligned bounds of CrabEFI's code, from the linker script
+pub fn runtime_code_range() -> Range<u64> {
+    let code_start = unsafe { &__runtime_code_start as *const u8 as u64 };
+    let code_end = unsafe { &__runtime_code_end as *const u8 as u64 };
+
+    // Round start down, round end up
+    (code_start & !(PAGE_SIZE - 1))..((code_end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1))
+}
+
 /// Reserve the CrabEFI runtime regions using linker-provided section boundaries
 ///
 /// This marks the memory containing our code and data sections so that the OS
@@ -1190,15 +1369,11 @@ unsafe extern "C" {
 /// linker script symbols.
 pub fn reserve_runtime_region() {
     // Get section boundaries from linker symbols
-    let code_start = unsafe { &__runtime_code_start as *const u8 as u64 };
-    let code_end = unsafe { &__runtime_code_end as *const u8 as u64 };
     let data_start = unsafe { &__runtime_data_start as *const u8 as u64 };
     let data_end = unsafe { &__runtime_data_end as *const u8 as u64 };
 
-    // Align to page boundaries
-    // Code region: round start down, round end up
-    let code_start_aligned = code_start & !(PAGE_SIZE - 1);
-    let code_end_aligned = (code_end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let code = runtime_code_range();
+    let (code_start_aligned, code_end_aligned) = (code.start, code.end);
 
     // Data region: start where code ends (to avoid overlap), round end up
     // The linker places data_start immediately after code_end, but when we
@@ -1316,5 +1491,206 @@ pub fn reserve_runtime_region() {
 mod tests {
     use super::*;
 
-    // Tests would go here
+    fn allocator() -> MemoryAllocator {
+        let ram = |start, size| MemoryRegion {
+            start,
+            size,
+            region_type: CbMemoryType::Ram,
+        };
+        let mut allocator = MemoryAllocator::new();
+        // Out of order and split, as some coreboot tables are
+        allocator.init_from_coreboot(&[
+            ram(0x20_0000, 0x10_0000),
+            ram(0x10_0000, 0x10_0000),
+            MemoryRegion {
+                start: 0x30_0000,
+                size: 0x1000,
+                region_type: CbMemoryType::Reserved,
+            },
+        ]);
+        allocator
+    }
+
+    fn types(allocator: &MemoryAllocator) -> std::vec::Vec<(u64, u64, u32)> {
+        allocator
+            .entries()
+            .iter()
+            .map(|e| (e.physical_start, e.number_of_pages, e.memory_type))
+            .collect()
+    }
+
+    #[test]
+    fn coalesces_adjacent_allocations() {
+        let mut allocator = allocator();
+        let conventional = MemoryType::ConventionalMemory as u32;
+        let data = MemoryType::BootServicesData as u32;
+        let reserved = MemoryType::ReservedMemoryType as u32;
+        assert_eq!(
+            types(&allocator),
+            [(0x10_0000, 0x200, conventional), (0x30_0000, 1, reserved)]
+        );
+
+        let mut addresses = [0; 3];
+        for address in addresses.iter_mut() {
+            let status = allocator.allocate_pages(
+                AllocateType::AllocateAnyPages,
+                MemoryType::BootServicesData,
+                2,
+                address,
+            );
+            assert_eq!(status, efi::Status::SUCCESS);
+        }
+        assert_eq!(
+            types(&allocator),
+            [
+                (0x10_0000, 0x1FA, conventional),
+                (0x2F_A000, 6, data),
+                (0x30_0000, 1, reserved)
+            ]
+        );
+
+        // Any allocation inside a merged entry can be freed
+        assert_eq!(allocator.free_pages(addresses[1], 2), efi::Status::SUCCESS);
+        assert_eq!(
+            types(&allocator),
+            [
+                (0x10_0000, 0x1FA, conventional),
+                (0x2F_A000, 2, data),
+                (0x2F_C000, 2, conventional),
+                (0x2F_E000, 2, data),
+                (0x30_0000, 1, reserved)
+            ]
+        );
+        assert_eq!(allocator.free_pages(addresses[0], 2), efi::Status::SUCCESS);
+        assert_eq!(allocator.free_pages(addresses[2], 2), efi::Status::SUCCESS);
+        assert_eq!(
+            types(&allocator),
+            [(0x10_0000, 0x200, conventional), (0x30_0000, 1, reserved)]
+        );
+        assert_eq!(allocator.free_pages(0x30_0000, 1), efi::Status::NOT_FOUND);
+    }
+
+    #[test]
+    fn leaves_specific_purpose_memory_alone() {
+        let region = |start, region_type| MemoryRegion {
+            start,
+            size: 0x10_0000,
+            region_type,
+        };
+        let mut allocator = MemoryAllocator::new();
+        allocator.init_from_coreboot(&[
+            region(0x10_0000, CbMemoryType::Ram),
+            region(0x20_0000, CbMemoryType::SoftReserved),
+            region(0x30_0000, CbMemoryType::Persistent),
+            region(0x40_0000, CbMemoryType::VendorReserved),
+        ]);
+        let described: std::vec::Vec<_> = allocator
+            .entries()
+            .iter()
+            .map(|e| (e.memory_type, e.attribute))
+            .collect();
+        let wb = attributes::EFI_MEMORY_WB;
+        assert_eq!(
+            described,
+            [
+                (MemoryType::ConventionalMemory as u32, wb),
+                (
+                    MemoryType::ConventionalMemory as u32,
+                    wb | attributes::EFI_MEMORY_SP
+                ),
+                (
+                    MemoryType::PersistentMemory as u32,
+                    wb | attributes::EFI_MEMORY_NV
+                ),
+                (MemoryType::ReservedMemoryType as u32, wb),
+            ]
+        );
+
+        // Allocations only come from the plain RAM
+        let mut address = 0;
+        let status = allocator.allocate_pages(
+            AllocateType::AllocateAnyPages,
+            MemoryType::LoaderData,
+            0x100,
+            &mut address,
+        );
+        assert_eq!((status, address), (efi::Status::SUCCESS, 0x10_0000));
+        address = 0x20_0000;
+        let status = allocator.allocate_pages(
+            AllocateType::AllocateAddress,
+            MemoryType::LoaderData,
+            1,
+            &mut address,
+        );
+        assert_eq!(status, efi::Status::NOT_FOUND);
+    }
+
+    #[test]
+    fn avoids_hot_pluggable_memory() {
+        let mut allocator = allocator();
+        let hot = attributes::EFI_MEMORY_HOT_PLUGGABLE;
+        allocator.mark_hot_pluggable(0x18_0000..0x30_0800).unwrap();
+        let described: std::vec::Vec<_> = allocator
+            .entries()
+            .iter()
+            .map(|e| (e.physical_start, e.number_of_pages, e.attribute & hot))
+            .collect();
+        assert_eq!(
+            described,
+            [
+                (0x10_0000, 0x80, 0),
+                (0x18_0000, 0x180, hot),
+                (0x30_0000, 1, hot)
+            ]
+        );
+
+        // Allocations skip it, freeing keeps the attribute
+        let mut address = 0;
+        let status = allocator.allocate_pages(
+            AllocateType::AllocateAnyPages,
+            MemoryType::LoaderData,
+            0x81,
+            &mut address,
+        );
+        assert_eq!(status, efi::Status::OUT_OF_RESOURCES);
+        address = 0x18_0000;
+        let status = allocator.allocate_pages(
+            AllocateType::AllocateAddress,
+            MemoryType::LoaderData,
+            1,
+            &mut address,
+        );
+        assert_eq!(status, efi::Status::NOT_FOUND);
+        let status = allocator.allocate_pages(
+            AllocateType::AllocateAnyPages,
+            MemoryType::LoaderData,
+            0x80,
+            &mut address,
+        );
+        assert_eq!((status, address), (efi::Status::SUCCESS, 0x10_0000));
+        assert_eq!(allocator.free_pages(address, 0x80), efi::Status::SUCCESS);
+        assert_eq!(allocator.entries()[1].attribute & hot, hot);
+    }
+
+    #[test]
+    fn keeps_map_sorted() {
+        let mut allocator = allocator();
+        allocator
+            .force_add_region(0x8_0000, 0x10, MemoryType::RuntimeServicesData)
+            .unwrap();
+        allocator.mark_as_acpi_reclaim(0x18_0000, 0x10).unwrap();
+        allocator.mark_as_runtime_data(0x30_0000, 1).unwrap();
+        let entries = allocator.entries();
+        assert!(
+            entries
+                .windows(2)
+                .all(|pair| pair[0].end() <= pair[1].physical_start)
+        );
+        assert_eq!(entries.len(), 5);
+        let runtime = attributes::EFI_MEMORY_RUNTIME;
+        assert_eq!(
+            (entries[4].memory_type, entries[4].attribute & runtime),
+            (MemoryType::RuntimeServicesData as u32, runtime)
+        );
+    }
 }
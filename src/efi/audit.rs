@@ -0,0 +1,482 @@
+//! Service audit (`audit` feature)
+//!
+//! Interposes on the boot and runtime services tables so every call a
+//! loaded image makes is logged together with how complete CrabEFI's
+//! implementation of the service is, as the
+//! [capability table](super::capabilities) describes it: calls to fully
+//! implemented services at trace level, to partial ones at debug level with
+//! what's missing, and to missing ones as warnings. Calls are counted per image, and when an image
+//! returns from StartImage or calls ExitBootServices its compatibility
+//! report lists the services it used. Comparing the reports of boot loaders
+//! shows which gaps matter most.
+//!
+//! The firmware calls the services directly, so only calls through the
+//! tables, made by images, are seen. RaiseTPL, RestoreTPL, CopyMem, SetMem,
+//! ResetSystem and the variadic (Un)InstallMultipleProtocolInterfaces don't
+//! return a status and aren't interposed. Nothing is recorded after
+//! ExitBootServices.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use heapless::String;
+use r_efi::efi::{self, Handle, Status};
+use r_efi::protocols::loaded_image;
+use spin::Mutex;
+
+use super::capabilities::Support::{Full, Missing, Partial};
+use super::capabilities::{self, CAPABILITIES, Capability};
+use super::protocols::device_path;
+use super::{boot_services, handles, runtime_services};
+
+/// The interposed services, in table order
+const SERVICES: &[&str] = &[
+    "BS.AllocatePages",
+    "BS.FreePages",
+    "BS.GetMemoryMap",
+    "BS.AllocatePool",
+    "BS.FreePool",
+    "BS.CreateEvent",
+    "BS.SetTimer",
+    "BS.WaitForEvent",
+    "BS.SignalEvent",
+    "BS.CloseEvent",
+    "BS.CheckEvent",
+    "BS.InstallProtocolInterface",
+    "BS.ReinstallProtocolInterface",
+    "BS.UninstallProtocolInterface",
+    "BS.HandleProtocol",
+    "BS.RegisterProtocolNotify",
+    "BS.LocateHandle",
+    "BS.LocateDevicePath",
+    "BS.InstallConfigurationTable",
+    "BS.LoadImage",
+    "BS.StartImage",
+    "BS.Exit",
+    "BS.UnloadImage",
+    "BS.ExitBootServices",
+    "BS.GetNextMonotonicCount",
+    "BS.Stall",
+    "BS.SetWatchdogTimer",
+    "BS.ConnectController",
+    "BS.DisconnectController",
+    "BS.OpenProtocol",
+    "BS.CloseProtocol",
+    "BS.OpenProtocolInformation",
+    "BS.ProtocolsPerHandle",
+    "BS.LocateHandleBuffer",
+    "BS.LocateProtocol",
+    "BS.CalculateCrc32",
+    "BS.CreateEventEx",
+    "RT.GetTime",
+    "RT.SetTime",
+    "RT.GetWakeupTime",
+    "RT.SetWakeupTime",
+    "RT.SetVirtualAddressMap",
+    "RT.ConvertPointer",
+    "RT.GetVariable",
+    "RT.GetNextVariableName",
+    "RT.SetVariable",
+    "RT.GetNextHighMonotonicCount",
+    "RT.UpdateCapsule",
+    "RT.QueryCapsuleCapabilities",
+    "RT.QueryVariableInfo",
+];
+
+/// The capability covering each interposed service, looked up at compile time
+const COVERING: [&Capability; SERVICES.len()] = {
+    let mut covering = [&CAPABILITIES[0]; SERVICES.len()];
+    let mut i = 0;
+    while i < SERVICES.len() {
+        covering[i] = &CAPABILITIES[capabilities::covering(SERVICES[i])];
+        i += 1;
+    }
+    covering
+};
+
+/// Index of the service called `name`, at compile time
+const fn service_index(name: &str) -> usize {
+    let name = name.as_bytes();
+    let mut i = 0;
+    while i < SERVICES.len() {
+        let candidate = SERVICES[i].as_bytes();
+        if candidate.len() == name.len() {
+            let mut j = 0;
+            while j < name.len() && candidate[j] == name[j] {
+                j += 1;
+            }
+            if j == name.len() {
+                return i;
+            }
+        }
+        i += 1;
+    }
+    panic!("unknown service");
+}
+
+/// Deepest StartImage nesting tracked, deeper images count as their parent
+const MAX_DEPTH: usize = 4;
+
+/// Longest image name kept
+const MAX_NAME_LEN: usize = 64;
+
+/// Calls made by one running image
+struct Frame {
+    name: String<MAX_NAME_LEN>,
+    calls: [u32; SERVICES.len()],
+    failures: [u32; SERVICES.len()],
+}
+
+impl Frame {
+    const fn new() -> Self {
+        Frame {
+            name: String::new(),
+            calls: [0; SERVICES.len()],
+            failures: [0; SERVICES.len()],
+        }
+    }
+
+    /// Log the compatibility report of the image
+    fn report(&self) {
+        let used = || (0..SERVICES.len()).filter(|&i| self.calls[i] > 0);
+        let incomplete = used().filter(|&i| COVERING[i].support != Full).count();
+        log::info!(
+            "Audit of {}: {} calls to {} services, {} of them incomplete",
+            self.name,
+            self.calls.iter().sum::<u32>(),
+            used().count(),
+            incomplete
+        );
+        for i in used() {
+            let capability = COVERING[i];
+            log::info!(
+                "  {:<32} {:>6} calls {:>4} failed  {:?} {}",
+                SERVICES[i],
+                self.calls[i],
+                self.failures[i],
+                capability.support,
+                capability.note
+            );
+        }
+    }
+}
+
+/// The images running, innermost last
+struct Audit {
+    frames: [Frame; MAX_DEPTH],
+    /// Images entered, also counting those beyond `MAX_DEPTH`
+    depth: usize,
+}
+
+impl Audit {
+    fn current(&mut self) -> &mut Frame {
+        &mut self.frames[self.depth.clamp(1, MAX_DEPTH) - 1]
+    }
+}
+
+static AUDIT: Mutex<Audit> = Mutex::new(Audit {
+    frames: [const { Frame::new() }; MAX_DEPTH],
+    depth: 0,
+});
+
+/// The original function of each interposed service
+static ORIGINALS: [AtomicUsize; SERVICES.len()] = [const { AtomicUsize::new(0) }; SERVICES.len()];
+
+/// Set once boot services are gone
+static EXITED: AtomicBool = AtomicBool::new(false);
+
+/// Count and log a call
+fn record(index: usize, status: Status) {
+    if EXITED.load(Ordering::Relaxed) {
+        return;
+    }
+    let (name, capability) = (SERVICES[index], COVERING[index]);
+    {
+        let mut audit = AUDIT.lock();
+        let frame = audit.current();
+        frame.calls[index] += 1;
+        if status.is_error() {
+            frame.failures[index] += 1;
+        }
+    }
+    match capability.support {
+        Full => log::trace!("audit: {} -> {:?}", name, status),
+        Partial => log::debug!(
+            "audit: {} -> {:?} (partial: {})",
+            name,
+            status,
+            capability.note
+        ),
+        Missing => log::warn!(
+            "audit: {} -> {:?} (missing: {})",
+            name,
+            status,
+            capability.note
+        ),
+    }
+
+    if index == service_index("BS.ExitBootServices") && status == Status::SUCCESS {
+        EXITED.store(true, Ordering::Relaxed);
+        let audit = AUDIT.lock();
+        for frame in audit.frames[..audit.depth.min(MAX_DEPTH)].iter().rev() {
+            frame.report();
+        }
+    }
+}
+
+/// Define a wrapper calling the original of service `I` and recording it
+macro_rules! wrapper {
+    ($name:ident, $($arg:ident: $ty:ident),*) => {
+        extern "efiapi" fn $name<const I: usize, $($ty),*>($($arg: $ty),*) -> Status {
+            let original = ORIGINALS[I].load(Ordering::Relaxed);
+            let original: extern "efiapi" fn($($ty),*) -> Status =
+                unsafe { core::mem::transmute_copy(&original) };
+            let status = original($($arg),*);
+            record(I, status);
+            status
+        }
+    };
+}
+
+wrapper!(call1, a: A);
+wrapper!(call2, a: A, b: B);
+wrapper!(call3, a: A, b: B, c: C);
+wrapper!(call4, a: A, b: B, c: C, d: D);
+wrapper!(call5, a: A, b: B, c: C, d: D, e: E);
+wrapper!(call6, a: A, b: B, c: C, d: D, e: E, f: F);
+
+/// Replace `table.field` with the wrapper for the service called `name`
+macro_rules! interpose {
+    ($table:ident.$field:ident, $name:literal, $call:ident, $($ty:tt),*) => {{
+        const INDEX: usize = service_index($name);
+        ORIGINALS[INDEX].store($table.$field as usize, Ordering::Relaxed);
+        $table.$field = $call::<INDEX, $($ty),*>;
+    }};
+}
+
+/// Interpose on the services tables
+pub fn install() {
+    boot_services::with_table(|bs: &mut efi::BootServices| {
+        interpose!(bs.allocate_pages, "BS.AllocatePages", call4, _, _, _, _);
+        interpose!(bs.free_pages, "BS.FreePages", call2, _, _);
+        interpose!(bs.get_memory_map, "BS.GetMemoryMap", call5, _, _, _, _, _);
+        interpose!(bs.allocate_pool, "BS.AllocatePool", call3, _, _, _);
+        interpose!(bs.free_pool, "BS.FreePool", call1, _);
+        interpose!(bs.create_event, "BS.CreateEvent", call5, _, _, _, _, _);
+        interpose!(bs.set_timer, "BS.SetTimer", call3, _, _, _);
+        interpose!(bs.wait_for_event, "BS.WaitForEvent", call3, _, _, _);
+        interpose!(bs.signal_event, "BS.SignalEvent", call1, _);
+        interpose!(bs.close_event, "BS.CloseEvent", call1, _);
+        interpose!(bs.check_event, "BS.CheckEvent", call1, _);
+        interpose!(
+            bs.install_protocol_interface,
+            "BS.InstallProtocolInterface",
+            call4,
+            _,
+            _,
+            _,
+            _
+        );
+        interpose!(
+            bs.reinstall_protocol_interface,
+            "BS.ReinstallProtocolInterface",
+            call4,
+            _,
+            _,
+            _,
+            _
+        );
+        interpose!(
+            bs.uninstall_protocol_interface,
+            "BS.UninstallProtocolInterface",
+            call3,
+            _,
+            _,
+            _
+        );
+        interpose!(bs.handle_protocol, "BS.HandleProtocol", call3, _, _, _);
+        interpose!(
+            bs.register_protocol_notify,
+            "BS.RegisterProtocolNotify",
+            call3,
+            _,
+            _,
+            _
+        );
+        interpose!(bs.locate_handle, "BS.LocateHandle", call5, _, _, _, _, _);
+        interpose!(bs.locate_device_path, "BS.LocateDevicePath", call3, _, _, _);
+        interpose!(
+            bs.install_configuration_table,
+            "BS.InstallConfigurationTable",
+            call2,
+            _,
+            _
+        );
+        interpose!(bs.load_image, "BS.LoadImage", call6, _, _, _, _, _, _);
+        interpose!(bs.start_image, "BS.StartImage", call3, _, _, _);
+        interpose!(bs.exit, "BS.Exit", call4, _, _, _, _);
+        interpose!(bs.unload_image, "BS.UnloadImage", call1, _);
+        interpose!(bs.exit_boot_services, "BS.ExitBootServices", call2, _, _);
+        interpose!(
+            bs.get_next_monotonic_count,
+            "BS.GetNextMonotonicCount",
+            call1,
+            _
+        );
+        interpose!(bs.stall, "BS.Stall", call1, _);
+        interpose!(
+            bs.set_watchdog_timer,
+            "BS.SetWatchdogTimer",
+            call4,
+            _,
+            _,
+            _,
+            _
+        );
+        interpose!(
+            bs.connect_controller,
+            "BS.ConnectController",
+            call4,
+            _,
+            _,
+            _,
+            _
+        );
+        interpose!(
+            bs.disconnect_controller,
+            "BS.DisconnectController",
+            call3,
+            _,
+            _,
+            _
+        );
+        interpose!(bs.open_protocol, "BS.OpenProtocol", call6, _, _, _, _, _, _);
+        interpose!(bs.close_protocol, "BS.CloseProtocol", call4, _, _, _, _);
+        interpose!(
+            bs.open_protocol_information,
+            "BS.OpenProtocolInformation",
+            call4,
+            _,
+            _,
+            _,
+            _
+        );
+        interpose!(
+            bs.protocols_per_handle,
+            "BS.ProtocolsPerHandle",
+            call3,
+            _,
+            _,
+            _
+        );
+        interpose!(
+            bs.locate_handle_buffer,
+            "BS.LocateHandleBuffer",
+            call5,
+            _,
+            _,
+            _,
+            _,
+            _
+        );
+        interpose!(bs.locate_protocol, "BS.LocateProtocol", call3, _, _, _);
+        interpose!(bs.calculate_crc32, "BS.CalculateCrc32", call3, _, _, _);
+        interpose!(
+            bs.create_event_ex,
+            "BS.CreateEventEx",
+            call6,
+            _,
+            _,
+            _,
+            _,
+            _,
+            _
+        );
+    });
+    runtime_services::with_table(|rt: &mut efi::RuntimeServices| {
+        interpose!(rt.get_time, "RT.GetTime", call2, _, _);
+        interpose!(rt.set_time, "RT.SetTime", call1, _);
+        interpose!(rt.get_wakeup_time, "RT.GetWakeupTime", call3, _, _, _);
+        interpose!(rt.set_wakeup_time, "RT.SetWakeupTime", call2, _, _);
+        interpose!(
+            rt.set_virtual_address_map,
+            "RT.SetVirtualAddressMap",
+            call4,
+            _,
+            _,
+            _,
+            _
+        );
+        interpose!(rt.convert_pointer, "RT.ConvertPointer", call2, _, _);
+        interpose!(rt.get_variable, "RT.GetVariable", call5, _, _, _, _, _);
+        interpose!(
+            rt.get_next_variable_name,
+            "RT.GetNextVariableName",
+            call3,
+            _,
+            _,
+            _
+        );
+        interpose!(rt.set_variable, "RT.SetVariable", call5, _, _, _, _, _);
+        interpose!(
+            rt.get_next_high_mono_count,
+            "RT.GetNextHighMonotonicCount",
+            call1,
+            _
+        );
+        interpose!(rt.update_capsule, "RT.UpdateCapsule", call3, _, _, _);
+        interpose!(
+            rt.query_capsule_capabilities,
+            "RT.QueryCapsuleCapabilities",
+            call4,
+            _,
+            _,
+            _,
+            _
+        );
+        interpose!(
+            rt.query_variable_info,
+            "RT.QueryVariableInfo",
+            call4,
+            _,
+            _,
+            _,
+            _
+        );
+    });
+    log::info!("Audit: interposed on {} services", SERVICES.len());
+}
+
+/// Attribute the following calls to the image being started
+pub fn enter(image_handle: Handle, image_base: u64) {
+    let loaded_image = handles::with(|db| db.find(image_handle, &loaded_image::PROTOCOL_GUID));
+    let path = loaded_image.and_then(|protocol| {
+        let file_path = unsafe { (*(protocol as *const loaded_image::Protocol)).file_path };
+        if file_path.is_null() {
+            return None;
+        }
+        unsafe { device_path::file_path_to_str::<MAX_NAME_LEN>(file_path) }
+    });
+
+    let mut audit = AUDIT.lock();
+    audit.depth += 1;
+    if audit.depth > MAX_DEPTH {
+        return;
+    }
+    let frame = audit.current();
+    *frame = Frame::new();
+    match path {
+        Some(path) => frame.name = path,
+        None => {
+            let _ = core::fmt::write(&mut frame.name, format_args!("image at {:#x}", image_base));
+        }
+    }
+}
+
+/// Report on the image that returned from StartImage
+pub fn leave() {
+    let mut audit = AUDIT.lock();
+    if audit.depth <= MAX_DEPTH {
+        audit.current().report();
+    }
+    audit.depth = audit.depth.saturating_sub(1);
+}
@@ -5,20 +5,31 @@
 //!
 //! # State Management
 //!
-//! Boot Services state (handles, events, loaded images) is stored in the
-//! centralized `FirmwareState` structure. Access it via `crate::state::efi_mut()`.
+//! Boot Services state (events, loaded images) is stored in the centralized
+//! `FirmwareState` structure. Access it via `crate::state::efi_mut()`. The
+//! handle database lives in [`super::handles`].
 
 use super::allocator::{self, AllocateType, MemoryDescriptor, MemoryType};
+use super::cell::EfiCell;
+use super::handles;
+use super::protocols::device_path;
 use super::protocols::loaded_image::{LOADED_IMAGE_PROTOCOL_GUID, create_loaded_image_protocol};
+use super::protocols::security;
+use super::runtime_services;
 use super::system_table;
+use crate::image_policy;
 use crate::pe;
-use crate::state::{
-    self, EventEntry, LoadedImageEntry, MAX_EVENTS, MAX_HANDLES, MAX_PROTOCOLS_PER_HANDLE,
-    ProtocolEntry,
-};
+use crate::state::{self, EventEntry, LoadedImageEntry, MAX_EVENTS};
+use crate::time::Timeout;
+use crate::timing::{self, Stage};
 use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use r_efi::efi::{self, Boolean, Guid, Handle, Status, SystemTable, TableHeader, Tpl};
 use r_efi::protocols::device_path::Protocol as DevicePathProtocol;
+use r_efi::protocols::file::Protocol as FileProtocol;
+use r_efi::protocols::load_file::{PROTOCOL_GUID as LOAD_FILE_GUID, Protocol as LoadFileProtocol};
+use r_efi::protocols::load_file2::PROTOCOL_GUID as LOAD_FILE2_GUID;
+use r_efi::protocols::simple_file_system::{PROTOCOL_GUID as SFS_GUID, Protocol as SfsProtocol};
 
 /// Boot Services signature "BOOTSERV"
 const EFI_BOOT_SERVICES_SIGNATURE: u64 = 0x56524553544F4F42;
@@ -26,6 +37,9 @@ const EFI_BOOT_SERVICES_SIGNATURE: u64 = 0x56524553544F4F42;
 /// Boot Services revision (matches system table)
 const EFI_BOOT_SERVICES_REVISION: u32 = (2 << 16) | 100;
 
+/// Maximum image file path length for LoadImage from a device path
+const MAX_IMAGE_PATH_LEN: usize = 256;
+
 /// Event types
 pub const EVT_TIMER: u32 = 0x80000000;
 pub const EVT_RUNTIME: u32 = 0x40000000;
@@ -37,8 +51,37 @@ pub const EVT_SIGNAL_VIRTUAL_ADDRESS_CHANGE: u32 = 0x60000202;
 /// Special event ID for keyboard input
 pub const KEYBOARD_EVENT_ID: usize = 1;
 
+/// Counter behind GetNextMonotonicCount
+static MONOTONIC_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Vendor GUID of the `MTC` variable holding the high 32 bits of the count
+const MTC_VENDOR_GUID: Guid = Guid::from_fields(
+    0xeb704011,
+    0x1402,
+    0x11d3,
+    0x8e,
+    0x77,
+    &[0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+);
+
+/// Start the monotonic count above the counts of earlier boots
+///
+/// The high 32 bits are bumped every boot and kept in the non-volatile
+/// `MTC` variable, like EDK2 does. Must run after the variable store is
+/// loaded.
+pub fn init_monotonic_count() {
+    let high = runtime_services::read_variable_u32("MTC", &MTC_VENDOR_GUID)
+        .map_or(0, |high| high.wrapping_add(1));
+    let attributes = efi::VARIABLE_NON_VOLATILE
+        | efi::VARIABLE_BOOTSERVICE_ACCESS
+        | efi::VARIABLE_RUNTIME_ACCESS;
+    let _ =
+        runtime_services::write_variable("MTC", &MTC_VENDOR_GUID, attributes, &high.to_le_bytes());
+    MONOTONIC_COUNT.store((high as u64) << 32, Ordering::Relaxed);
+}
+
 /// Static boot services table
-static mut BOOT_SERVICES: efi::BootServices = efi::BootServices {
+static BOOT_SERVICES: EfiCell<efi::BootServices> = EfiCell::new(efi::BootServices {
     hdr: TableHeader {
         signature: EFI_BOOT_SERVICES_SIGNATURE,
         revision: EFI_BOOT_SERVICES_REVISION,
@@ -122,11 +165,17 @@ static mut BOOT_SERVICES: efi::BootServices = efi::BootServices {
     copy_mem,
     set_mem,
     create_event_ex,
-};
+});
 
 /// Get a pointer to the boot services table
 pub fn get_boot_services() -> *mut efi::BootServices {
-    &raw mut BOOT_SERVICES
+    BOOT_SERVICES.as_ptr()
+}
+
+/// Modify the boot services table, to interpose on services
+#[cfg(feature = "audit")]
+pub(super) fn with_table<R>(f: impl FnOnce(&mut efi::BootServices) -> R) -> R {
+    BOOT_SERVICES.with(f)
 }
 
 // ============================================================================
@@ -141,7 +190,12 @@ extern "efiapi" fn raise_tpl(new_tpl: Tpl) -> Tpl {
 
 extern "efiapi" fn restore_tpl(old_tpl: Tpl) {
     log::debug!("BS.RestoreTpl({:?})", old_tpl);
-    // No-op
+
+    // Dropping back to the application level is where pending timer
+    // notifications would run on interrupt-driven firmware
+    if old_tpl == efi::TPL_APPLICATION {
+        dispatch_timers();
+    }
 }
 
 // ============================================================================
@@ -293,14 +347,30 @@ extern "efiapi" fn free_pool(buffer: *mut c_void) -> Status {
 }
 
 // ============================================================================
-// Event Functions (mostly unsupported)
+// Event Functions
 // ============================================================================
+//
+// There is no timer interrupt: expired timers are signaled, and their
+// notification functions run, whenever the application calls CheckEvent,
+// WaitForEvent, Stall or RestoreTPL.
+
+/// First event ID handed out by CreateEvent
+const FIRST_EVENT_ID: usize = KEYBOARD_EVENT_ID + 1;
+
+/// Notification functions collected while the firmware state is borrowed
+type NotifyQueue = heapless::Vec<(efi::EventNotify, efi::Event, *mut c_void), MAX_EVENTS>;
+
+/// Longest time Stall goes without dispatching timers
+const STALL_DISPATCH_US: u64 = 10_000;
+
+/// Set while timer notifications run, so they don't dispatch again
+static DISPATCHING: AtomicBool = AtomicBool::new(false);
 
 extern "efiapi" fn create_event(
     event_type: u32,
     notify_tpl: Tpl,
-    _notify_function: Option<efi::EventNotify>,
-    _notify_context: *mut c_void,
+    notify_function: Option<efi::EventNotify>,
+    notify_context: *mut c_void,
     event: *mut efi::Event,
 ) -> Status {
     log::debug!(
@@ -309,27 +379,54 @@ extern "efiapi" fn create_event(
         notify_tpl
     );
 
+    new_event(
+        event_type,
+        notify_tpl,
+        notify_function,
+        notify_context,
+        None,
+        event,
+    )
+}
+
+/// Create an event, optionally as a member of an event group
+fn new_event(
+    event_type: u32,
+    notify_tpl: Tpl,
+    notify_function: Option<efi::EventNotify>,
+    notify_context: *mut c_void,
+    group: Option<Guid>,
+    event: *mut efi::Event,
+) -> Status {
     if event.is_null() {
         return Status::INVALID_PARAMETER;
     }
+    if event_type & (EVT_NOTIFY_SIGNAL | EVT_NOTIFY_WAIT) != 0 && notify_function.is_none() {
+        return Status::INVALID_PARAMETER;
+    }
 
-    // Allocate an event ID from centralized state
-    state::with_efi_mut(|efi_state| {
-        let event_id = efi_state.next_event_id;
+    // The legacy signal types are members of the matching event group
+    let group = match event_type {
+        EVT_SIGNAL_EXIT_BOOT_SERVICES => Some(efi::EVENT_GROUP_EXIT_BOOT_SERVICES),
+        EVT_SIGNAL_VIRTUAL_ADDRESS_CHANGE => Some(efi::EVENT_GROUP_VIRTUAL_ADDRESS_CHANGE),
+        _ => group,
+    };
 
-        if event_id >= MAX_EVENTS {
+    state::with_efi_mut(|efi_state| {
+        let Some(event_id) = (FIRST_EVENT_ID..MAX_EVENTS).find(|&id| !efi_state.events[id].in_use)
+        else {
             log::error!("  -> OUT_OF_RESOURCES (no more event slots)");
             return Status::OUT_OF_RESOURCES;
-        }
-
-        efi_state.next_event_id += 1;
+        };
 
-        // Store event info
         efi_state.events[event_id] = EventEntry {
             event_type,
             notify_tpl,
-            signaled: false,
-            is_keyboard_event: false,
+            in_use: true,
+            notify_function,
+            notify_context,
+            group,
+            ..EventEntry::empty()
         };
 
         // Return the event ID as the event handle
@@ -342,6 +439,120 @@ extern "efiapi" fn create_event(
     })
 }
 
+/// Look up a created event by its handle
+fn event_entry(
+    events: &mut [EventEntry; MAX_EVENTS],
+    event: efi::Event,
+) -> Option<&mut EventEntry> {
+    let event_id = event as usize;
+    if event_id < FIRST_EVENT_ID {
+        return None;
+    }
+    events.get_mut(event_id).filter(|entry| entry.in_use)
+}
+
+/// Signal a single event
+///
+/// `EVT_NOTIFY_SIGNAL` events have their notification function queued
+/// instead of becoming signaled.
+fn signal_one(entry: &mut EventEntry, event_id: usize, queue: &mut NotifyQueue) {
+    match entry.notify_function {
+        Some(notify) if entry.event_type & EVT_NOTIFY_SIGNAL != 0 => {
+            let _ = queue.push((notify, event_id as efi::Event, entry.notify_context));
+        }
+        _ => entry.signaled = true,
+    }
+}
+
+/// Signal every event of a group
+fn signal_group_in(events: &mut [EventEntry; MAX_EVENTS], group: &Guid, queue: &mut NotifyQueue) {
+    for (event_id, entry) in events.iter_mut().enumerate() {
+        if entry.in_use && entry.group.as_ref() == Some(group) {
+            signal_one(entry, event_id, queue);
+        }
+    }
+}
+
+/// Signal an event group and run the notification functions
+fn signal_group(group: &Guid) {
+    let mut queue = NotifyQueue::new();
+    state::with_efi_mut(|efi_state| signal_group_in(&mut efi_state.events, group, &mut queue));
+    run_notifies(&queue);
+}
+
+/// Run queued notification functions, after the state borrow ended
+fn run_notifies(queue: &NotifyQueue) {
+    for &(notify, event, context) in queue {
+        notify(event, context);
+    }
+}
+
+/// Signal expired timers and run their notification functions
+///
+/// Keyboard and serial input are polled here too, like a periodic timer.
+fn dispatch_timers() {
+    if DISPATCHING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    super::protocols::console::poll_input();
+
+    let mut queue = NotifyQueue::new();
+    state::with_efi_mut(|efi_state| {
+        for (event_id, entry) in efi_state.events.iter_mut().enumerate() {
+            if !entry.in_use || !entry.deadline.is_some_and(|d| d.is_expired()) {
+                continue;
+            }
+            entry.deadline = entry.period_us.map(Timeout::from_us);
+            signal_one(entry, event_id, &mut queue);
+        }
+    });
+    run_notifies(&queue);
+
+    // Non-blocking MP Services requests complete here as well
+    super::protocols::mp_services::check_requests();
+
+    DISPATCHING.store(false, Ordering::Release);
+}
+
+/// Check whether an event is signaled, clearing it if so
+///
+/// Runs the notification function of an unsignaled `EVT_NOTIFY_WAIT` event
+/// first, which may signal it.
+fn poll_event(event: efi::Event) -> Status {
+    if event as usize == KEYBOARD_EVENT_ID {
+        // Signaled while the input queue holds a key
+        if super::protocols::console::has_key() {
+            return Status::SUCCESS;
+        }
+        return Status::NOT_READY;
+    }
+
+    let Some(entry) =
+        state::with_efi_mut(|efi_state| event_entry(&mut efi_state.events, event).copied())
+    else {
+        return Status::INVALID_PARAMETER;
+    };
+    if entry.event_type & EVT_NOTIFY_SIGNAL != 0 {
+        return Status::INVALID_PARAMETER;
+    }
+    if !entry.signaled
+        && entry.event_type & EVT_NOTIFY_WAIT != 0
+        && let Some(notify) = entry.notify_function
+    {
+        notify(event, entry.notify_context);
+    }
+
+    state::with_efi_mut(
+        |efi_state| match event_entry(&mut efi_state.events, event) {
+            Some(entry) if entry.signaled => {
+                entry.signaled = false;
+                Status::SUCCESS
+            }
+            _ => Status::NOT_READY,
+        },
+    )
+}
+
 extern "efiapi" fn set_timer(
     event: efi::Event,
     timer_type: efi::TimerDelay,
@@ -353,10 +564,33 @@ extern "efiapi" fn set_timer(
         timer_type,
         trigger_time
     );
-    // Timer events are not fully implemented, but we accept the call
-    // to allow bootloaders to proceed
-    log::debug!("  -> SUCCESS (stubbed)");
-    Status::SUCCESS
+
+    state::with_efi_mut(|efi_state| {
+        let Some(entry) = event_entry(&mut efi_state.events, event)
+            .filter(|entry| entry.event_type & EVT_TIMER != 0)
+        else {
+            return Status::INVALID_PARAMETER;
+        };
+
+        // Trigger times are in 100ns units
+        let trigger_us = trigger_time / 10;
+        match timer_type {
+            efi::TIMER_CANCEL => {
+                entry.deadline = None;
+                entry.period_us = None;
+            }
+            efi::TIMER_RELATIVE => {
+                entry.deadline = Some(Timeout::from_us(trigger_us));
+                entry.period_us = None;
+            }
+            efi::TIMER_PERIODIC => {
+                entry.deadline = Some(Timeout::from_us(trigger_us));
+                entry.period_us = Some(trigger_us);
+            }
+            _ => return Status::INVALID_PARAMETER,
+        }
+        Status::SUCCESS
+    })
 }
 
 extern "efiapi" fn wait_for_event(
@@ -373,38 +607,16 @@ extern "efiapi" fn wait_for_event(
     // Get the list of events to wait on
     let events_to_wait = unsafe { core::slice::from_raw_parts(event, number_of_events) };
 
-    // Check if any of the events is a keyboard event
-    let has_keyboard_event = events_to_wait.iter().any(|e| {
-        let event_id = *e as usize;
-        event_id == KEYBOARD_EVENT_ID
-    });
-
-    // Poll for keyboard input
-    // In a real implementation, we'd use proper async I/O
-    // Here we poll the serial port for input
+    // Poll for keyboard input and timers
     loop {
-        // Check each event
-        for (i, &evt) in events_to_wait.iter().enumerate() {
-            let event_id = evt as usize;
-
-            // Check if it's the keyboard event and there's input
-            if event_id == KEYBOARD_EVENT_ID || has_keyboard_event {
-                // Check if serial port or PS/2 keyboard has data
-                if crate::drivers::serial::has_input() || crate::drivers::keyboard::has_key() {
-                    unsafe { *index = i };
-                    log::debug!("  -> SUCCESS (keyboard input ready, index={})", i);
-                    return Status::SUCCESS;
-                }
-            }
+        dispatch_timers();
 
-            // Check if a regular event is signaled
-            if event_id > 0 && event_id < MAX_EVENTS {
-                let efi_state = state::efi();
-                if efi_state.events[event_id].signaled {
-                    unsafe { *index = i };
-                    log::debug!("  -> SUCCESS (event signaled, index={})", i);
-                    return Status::SUCCESS;
-                }
+        for (i, &evt) in events_to_wait.iter().enumerate() {
+            let status = poll_event(evt);
+            if status != Status::NOT_READY {
+                unsafe { *index = i };
+                log::debug!("  -> {:?} (index={})", status, i);
+                return status;
             }
         }
 
@@ -416,62 +628,53 @@ extern "efiapi" fn wait_for_event(
 }
 
 extern "efiapi" fn signal_event(event: efi::Event) -> Status {
-    let event_id = event as usize;
-    log::debug!("BS.SignalEvent(event={})", event_id);
+    log::debug!("BS.SignalEvent(event={})", event as usize);
 
-    if event_id > 0 && event_id < MAX_EVENTS {
-        state::with_efi_mut(|efi_state| {
-            efi_state.events[event_id].signaled = true;
-        });
-    }
+    let mut queue = NotifyQueue::new();
+    let status = state::with_efi_mut(|efi_state| {
+        let Some(entry) = event_entry(&mut efi_state.events, event) else {
+            return Status::INVALID_PARAMETER;
+        };
 
-    Status::SUCCESS
+        // Signaling a group member signals the whole group
+        match entry.group {
+            Some(group) => signal_group_in(&mut efi_state.events, &group, &mut queue),
+            None => signal_one(entry, event as usize, &mut queue),
+        }
+        Status::SUCCESS
+    });
+    run_notifies(&queue);
+
+    status
 }
 
 extern "efiapi" fn close_event(event: efi::Event) -> Status {
-    let event_id = event as usize;
-    log::debug!("BS.CloseEvent(event={})", event_id);
-
-    if event_id > 0 && event_id < MAX_EVENTS {
-        state::with_efi_mut(|efi_state| {
-            efi_state.events[event_id] = EventEntry::empty();
-        });
-    }
+    log::debug!("BS.CloseEvent(event={})", event as usize);
 
-    Status::SUCCESS
+    state::with_efi_mut(
+        |efi_state| match event_entry(&mut efi_state.events, event) {
+            Some(entry) => {
+                *entry = EventEntry::empty();
+                Status::SUCCESS
+            }
+            None => Status::INVALID_PARAMETER,
+        },
+    )
 }
 
 extern "efiapi" fn check_event(event: efi::Event) -> Status {
-    let event_id = event as usize;
-    log::debug!("BS.CheckEvent(event={})", event_id);
-
-    // Special case for keyboard event
-    if event_id == KEYBOARD_EVENT_ID {
-        // Check serial port or PS/2 keyboard for input
-        if crate::drivers::serial::has_input() || crate::drivers::keyboard::has_key() {
-            return Status::SUCCESS;
-        } else {
-            return Status::NOT_READY;
-        }
-    }
-
-    // Check regular events
-    if event_id > 0 && event_id < MAX_EVENTS {
-        let efi_state = state::efi();
-        if efi_state.events[event_id].signaled {
-            return Status::SUCCESS;
-        }
-    }
+    log::debug!("BS.CheckEvent(event={})", event as usize);
 
-    Status::NOT_READY
+    dispatch_timers();
+    poll_event(event)
 }
 
 extern "efiapi" fn create_event_ex(
     event_type: u32,
     notify_tpl: Tpl,
-    _notify_function: Option<efi::EventNotify>,
-    _notify_context: *const c_void,
-    _event_group: *const Guid,
+    notify_function: Option<efi::EventNotify>,
+    notify_context: *const c_void,
+    event_group: *const Guid,
     event: *mut efi::Event,
 ) -> Status {
     log::debug!(
@@ -480,8 +683,23 @@ extern "efiapi" fn create_event_ex(
         notify_tpl
     );
 
-    // Forward to create_event (ignoring event_group for now)
-    create_event(event_type, notify_tpl, None, core::ptr::null_mut(), event)
+    // The legacy signal types can't be combined with an explicit group
+    if matches!(
+        event_type,
+        EVT_SIGNAL_EXIT_BOOT_SERVICES | EVT_SIGNAL_VIRTUAL_ADDRESS_CHANGE
+    ) {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let group = (!event_group.is_null()).then(|| unsafe { *event_group });
+    new_event(
+        event_type,
+        notify_tpl,
+        notify_function,
+        notify_context as *mut c_void,
+        group,
+        event,
+    )
 }
 
 // ============================================================================
@@ -506,50 +724,22 @@ extern "efiapi" fn install_protocol_interface(
     let guid = unsafe { *protocol };
     let handle_ptr = unsafe { *handle };
 
-    state::with_efi_mut(|efi_state| {
+    handles::with(|db| {
         // If handle is null, create a new handle
         if handle_ptr.is_null() {
-            if efi_state.handle_count >= MAX_HANDLES {
-                return Status::OUT_OF_RESOURCES;
-            }
-
-            let new_handle = efi_state.next_handle as *mut c_void;
-            efi_state.next_handle += 1;
-
-            let idx = efi_state.handle_count;
-            efi_state.handles[idx].handle = new_handle;
-            efi_state.handles[idx].protocols[0] = ProtocolEntry { guid, interface };
-            efi_state.handles[idx].protocol_count = 1;
-            efi_state.handle_count += 1;
-
-            unsafe { *handle = new_handle };
-            return Status::SUCCESS;
-        }
-
-        // Find existing handle
-        if let Some(entry) = efi_state.handles[..efi_state.handle_count]
-            .iter_mut()
-            .find(|e| e.handle == handle_ptr)
-        {
-            // Check if protocol already installed
-            if entry.protocols[..entry.protocol_count]
-                .iter()
-                .any(|p| p.guid == guid)
-            {
-                return Status::INVALID_PARAMETER; // Protocol already installed
-            }
+            let new_handle = match db.create() {
+                Ok(h) => h,
+                Err(status) => return status,
+            };
 
-            // Add new protocol
-            if entry.protocol_count >= MAX_PROTOCOLS_PER_HANDLE {
-                return Status::OUT_OF_RESOURCES;
+            let status = db.install(new_handle, &guid, interface);
+            if status == Status::SUCCESS {
+                unsafe { *handle = new_handle };
             }
-
-            entry.protocols[entry.protocol_count] = ProtocolEntry { guid, interface };
-            entry.protocol_count += 1;
-            return Status::SUCCESS;
+            return status;
         }
 
-        Status::INVALID_PARAMETER
+        db.install(handle_ptr, &guid, interface)
     })
 }
 
@@ -650,39 +840,34 @@ extern "efiapi" fn locate_handle(
     }
 
     let guid = unsafe { *protocol };
-    let efi_state = state::efi();
 
-    // Collect matching handles
-    let matching: heapless::Vec<Handle, MAX_HANDLES> = efi_state.handles[..efi_state.handle_count]
-        .iter()
-        .filter(|entry| {
-            entry.protocols[..entry.protocol_count]
-                .iter()
-                .any(|p| p.guid == guid)
-        })
-        .map(|entry| entry.handle)
-        .collect();
+    handles::with(|db| {
+        let matching = || db.handles_with(&guid);
 
-    let required_size = matching.len() * core::mem::size_of::<Handle>();
+        let count = matching().count();
+        let required_size = count * core::mem::size_of::<Handle>();
 
-    if buffer.is_null() || unsafe { *buffer_size } < required_size {
-        unsafe { *buffer_size = required_size };
-        return Status::BUFFER_TOO_SMALL;
-    }
+        if buffer.is_null() || unsafe { *buffer_size } < required_size {
+            unsafe { *buffer_size = required_size };
+            return Status::BUFFER_TOO_SMALL;
+        }
 
-    // Copy handles to buffer using slice copy
-    let dest = unsafe { core::slice::from_raw_parts_mut(buffer, matching.len()) };
-    dest.copy_from_slice(&matching[..]);
-    unsafe { *buffer_size = required_size };
+        // Copy handles to buffer
+        let dest = unsafe { core::slice::from_raw_parts_mut(buffer, count) };
+        for (slot, h) in dest.iter_mut().zip(matching()) {
+            *slot = h;
+        }
+        unsafe { *buffer_size = required_size };
 
-    if matching.is_empty() {
-        log::info!("  -> NOT_FOUND");
-        Status::NOT_FOUND
-    } else {
-        log::info!("  -> found {} handles: {:?}", matching.len(), &matching[..]);
-        log::info!("  -> returning from LocateHandle");
-        Status::SUCCESS
-    }
+        if count == 0 {
+            log::info!("  -> NOT_FOUND");
+            Status::NOT_FOUND
+        } else {
+            log::info!("  -> found {} handles: {:?}", count, dest);
+            log::info!("  -> returning from LocateHandle");
+            Status::SUCCESS
+        }
+    })
 }
 
 extern "efiapi" fn locate_device_path(
@@ -704,26 +889,14 @@ extern "efiapi" fn locate_device_path(
     }
 
     // Find a handle with both the specified protocol and a DEVICE_PATH protocol
-    let efi_state = state::efi();
-
-    let found = efi_state.handles[..efi_state.handle_count]
-        .iter()
-        .filter_map(|entry| {
-            let protocols = &entry.protocols[..entry.protocol_count];
-
-            let has_protocol = protocols.iter().any(|p| p.guid == guid);
-
-            let handle_dp = protocols
-                .iter()
-                .find(|p| p.guid == r_efi::protocols::device_path::PROTOCOL_GUID)
-                .map(|p| p.interface as *mut DevicePathProtocol);
-
-            match (has_protocol, handle_dp) {
-                (true, Some(dp)) if !dp.is_null() => Some((entry.handle, dp)),
-                _ => None,
-            }
+    let found = handles::with(|db| {
+        db.handles_with(&guid).find_map(|h| {
+            db.find(h, &r_efi::protocols::device_path::PROTOCOL_GUID)
+                .map(|iface| iface as *mut DevicePathProtocol)
+                .filter(|dp| !dp.is_null())
+                .map(|dp| (h, dp))
         })
-        .next();
+    });
 
     if let Some((handle, handle_dp)) = found {
         // For the initrd case, GRUB installs a handle with LOAD_FILE2 and a vendor media
@@ -799,17 +972,70 @@ extern "efiapi" fn load_image(
         return Status::INVALID_PARAMETER;
     }
 
-    // We only support loading from a memory buffer currently
-    if source_buffer.is_null() || source_size == 0 {
-        log::error!("BS.LoadImage: source_buffer is NULL or size is 0");
+    // Without a source buffer, the image is read from the device path
+    let image_file = if source_buffer.is_null() {
+        if device_path.is_null() {
+            log::error!("BS.LoadImage: source_buffer and device_path are NULL");
+            return Status::NOT_FOUND;
+        }
+        match load_image_file(boot_policy, device_path) {
+            Ok(file) => Some(file),
+            Err(status) => {
+                log::error!("BS.LoadImage: Failed to read image file: {:?}", status);
+                return status;
+            }
+        }
+    } else if source_size == 0 {
+        log::error!("BS.LoadImage: source_size is 0");
         return Status::INVALID_PARAMETER;
-    }
+    } else {
+        None
+    };
 
     // Create a slice from the source buffer
-    let data = unsafe { core::slice::from_raw_parts(source_buffer as *const u8, source_size) };
+    let data = match &image_file {
+        Some(file) => unsafe { core::slice::from_raw_parts(file.buffer, file.size) },
+        None => unsafe { core::slice::from_raw_parts(source_buffer as *const u8, source_size) },
+    };
 
-    // Load the PE image using our PE loader
-    let loaded_image = match pe::load_image(data) {
+    // Known-bad images never load, the rest is up to the Security protocols
+    let status = match image_policy::check_denied(data) {
+        Ok(()) => security::authenticate(device_path, data, boot_policy),
+        Err(_) => Status::ACCESS_DENIED,
+    };
+    if status != Status::SUCCESS {
+        log::error!("BS.LoadImage: Image refused by policy: {:?}", status);
+        if let Some(file) = &image_file {
+            let _ = allocator::free_pool(file.buffer);
+        }
+        return status;
+    }
+
+    // The device path the image was loaded from, if any
+    let file_path = image_file
+        .as_ref()
+        .map_or(device_path, |file| file.file_path);
+
+    // Load the PE image using our PE loader, which copies the sections out
+    let result = timing::measure(Stage::PeLoad, || pe::load_image(data));
+
+    // Drivers written for PI firmware need a few services we don't have;
+    // which ones is looked up while the image file is still around
+    let quirks = result
+        .as_ref()
+        .ok()
+        .filter(|img| {
+            matches!(
+                img.subsystem,
+                pe::SUBSYSTEM_EFI_BOOT_SERVICE_DRIVER | pe::SUBSYSTEM_EFI_RUNTIME_DRIVER
+            )
+        })
+        .map(|_| unsafe { super::driver_quirks::quirks_for(file_path, data) });
+
+    if let Some(file) = &image_file {
+        let _ = allocator::free_pool(file.buffer);
+    }
+    let loaded_image = match result {
         Ok(img) => img,
         Err(status) => {
             log::error!("BS.LoadImage: Failed to load PE image: {:?}", status);
@@ -835,8 +1061,11 @@ extern "efiapi" fn load_image(
     };
 
     // Create LoadedImageProtocol for this image
-    // Get the parent's device handle if available
-    let device_handle = get_device_handle_from_parent(parent_image_handle);
+    // The device handle is the one the file came from, or the parent's
+    let device_handle = match &image_file {
+        Some(file) => file.device_handle,
+        None => get_device_handle_from_parent(parent_image_handle),
+    };
 
     let system_table = super::get_system_table();
     let loaded_image_protocol = create_loaded_image_protocol(
@@ -854,9 +1083,9 @@ extern "efiapi" fn load_image(
     }
 
     // Set the device path on the loaded image if provided
-    if !device_path.is_null() {
+    if !file_path.is_null() {
         unsafe {
-            super::protocols::loaded_image::set_file_path(loaded_image_protocol, device_path);
+            super::protocols::loaded_image::set_file_path(loaded_image_protocol, file_path);
         }
     }
 
@@ -876,6 +1105,10 @@ extern "efiapi" fn load_image(
         return status;
     }
 
+    if let Some(quirks) = quirks {
+        super::driver_quirks::apply(device_handle, quirks);
+    }
+
     // Store the loaded image info so StartImage can find it
     let store_result = state::with_efi_mut(|efi_state| {
         let slot = efi_state
@@ -918,6 +1151,230 @@ extern "efiapi" fn load_image(
     Status::SUCCESS
 }
 
+/// Image file read from a device by LoadImage
+struct ImageFile {
+    /// Pool buffer holding the file
+    buffer: *mut u8,
+    size: usize,
+    /// Handle of the device the file was read from
+    device_handle: Handle,
+    /// File path relative to the device
+    file_path: *mut DevicePathProtocol,
+}
+
+/// Read the image file for LoadImage from a device path
+///
+/// Tries the protocols in the order the UEFI specification gives: Simple
+/// File System, then Load File, then Load File 2 unless `boot_policy` is set.
+/// Each is looked up on the handle whose device path is the longest prefix
+/// of `device_path`.
+fn load_image_file(
+    boot_policy: Boolean,
+    device_path: *mut DevicePathProtocol,
+) -> Result<ImageFile, Status> {
+    let mut status = Status::NOT_FOUND;
+
+    if let Some((handle, iface, file_path)) = find_device_handle(&SFS_GUID, device_path) {
+        match read_sfs_file(iface as *mut SfsProtocol, file_path, boot_policy) {
+            Ok((buffer, size)) => {
+                return Ok(ImageFile {
+                    buffer,
+                    size,
+                    device_handle: handle,
+                    file_path,
+                });
+            }
+            Err(e) => status = e,
+        }
+    }
+
+    let mut load_file_guids = [
+        (LOAD_FILE_GUID, boot_policy),
+        (LOAD_FILE2_GUID, false.into()),
+    ];
+    let count = if boot_policy.into() { 1 } else { 2 };
+    for (guid, policy) in &mut load_file_guids[..count] {
+        if let Some((handle, iface, file_path)) = find_device_handle(guid, device_path) {
+            match read_load_file(iface as *mut LoadFileProtocol, file_path, *policy) {
+                Ok((buffer, size)) => {
+                    return Ok(ImageFile {
+                        buffer,
+                        size,
+                        device_handle: handle,
+                        file_path,
+                    });
+                }
+                Err(e) => status = e,
+            }
+        }
+    }
+
+    Err(status)
+}
+
+/// Find the handle with a protocol whose device path is the longest prefix of `path`
+///
+/// Returns the handle, the protocol interface and the rest of `path`.
+fn find_device_handle(
+    guid: &Guid,
+    path: *mut DevicePathProtocol,
+) -> Option<(Handle, *mut c_void, *mut DevicePathProtocol)> {
+    handles::with(|db| {
+        let mut best: Option<(Handle, *mut c_void, *mut DevicePathProtocol)> = None;
+        for h in db.handles_with(guid) {
+            let Some(handle_dp) = db.find(h, &r_efi::protocols::device_path::PROTOCOL_GUID) else {
+                continue;
+            };
+            if handle_dp.is_null() {
+                continue;
+            }
+
+            let rest = unsafe { device_path::strip_prefix(handle_dp as *const _, path) };
+            if let Some(rest) = rest
+                && best.is_none_or(|(_, _, best_rest)| rest > best_rest)
+                && let Some(iface) = db.find(h, guid)
+            {
+                best = Some((h, iface, rest));
+            }
+        }
+        best
+    })
+}
+
+/// Read a file through a Simple File System protocol into a pool buffer
+fn read_sfs_file(
+    sfs: *mut SfsProtocol,
+    file_path: *mut DevicePathProtocol,
+    boot_policy: Boolean,
+) -> Result<(*mut u8, usize), Status> {
+    let name = unsafe { device_path::file_path_to_str::<MAX_IMAGE_PATH_LEN>(file_path) }
+        .ok_or(Status::NOT_FOUND)?;
+    let name = if !name.is_empty() {
+        name.as_str()
+    } else if boot_policy.into() {
+        crate::menu::DEFAULT_BOOT_PATH
+    } else {
+        return Err(Status::NOT_FOUND);
+    };
+
+    let mut name16 = [0u16; MAX_IMAGE_PATH_LEN + 1];
+    for (slot, unit) in name16[..MAX_IMAGE_PATH_LEN]
+        .iter_mut()
+        .zip(name.encode_utf16())
+    {
+        *slot = unit;
+    }
+
+    unsafe {
+        let mut root: *mut FileProtocol = core::ptr::null_mut();
+        let status = ((*sfs).open_volume)(sfs, &mut root);
+        if status.is_error() {
+            return Err(status);
+        }
+
+        let mut file: *mut FileProtocol = core::ptr::null_mut();
+        let status = ((*root).open)(
+            root,
+            &mut file,
+            name16.as_mut_ptr(),
+            r_efi::protocols::file::MODE_READ,
+            0,
+        );
+        ((*root).close)(root);
+        if status.is_error() {
+            return Err(status);
+        }
+
+        let result = read_open_file(file);
+        ((*file).close)(file);
+        result
+    }
+}
+
+/// Read a whole open file into a pool buffer
+///
+/// # Safety
+/// `file` must be a valid open file protocol instance.
+unsafe fn read_open_file(file: *mut FileProtocol) -> Result<(*mut u8, usize), Status> {
+    use r_efi::protocols::file::{DIRECTORY, INFO_ID, Info};
+
+    let mut info: Info<MAX_IMAGE_PATH_LEN> = unsafe { core::mem::zeroed() };
+    let mut info_size = core::mem::size_of_val(&info);
+    let mut info_guid = INFO_ID;
+    let status = unsafe {
+        ((*file).get_info)(
+            file,
+            &mut info_guid,
+            &mut info_size,
+            &mut info as *mut _ as *mut c_void,
+        )
+    };
+    if status.is_error() {
+        return Err(status);
+    }
+    if info.attribute & DIRECTORY != 0 || info.file_size == 0 {
+        return Err(Status::NOT_FOUND);
+    }
+
+    let size = info.file_size as usize;
+    let buffer = allocator::allocate_pool(MemoryType::BootServicesData, size)?;
+    let mut read = size;
+    let status = unsafe { ((*file).read)(file, &mut read, buffer as *mut c_void) };
+    if status.is_error() || read != size {
+        let _ = allocator::free_pool(buffer);
+        return Err(if status.is_error() {
+            status
+        } else {
+            Status::END_OF_FILE
+        });
+    }
+
+    Ok((buffer, size))
+}
+
+/// Read a file through a Load File protocol into a pool buffer
+fn read_load_file(
+    load_file: *mut LoadFileProtocol,
+    file_path: *mut DevicePathProtocol,
+    boot_policy: Boolean,
+) -> Result<(*mut u8, usize), Status> {
+    // Ask for the size first
+    let mut size = 0;
+    let status = unsafe {
+        ((*load_file).load_file)(
+            load_file,
+            file_path,
+            boot_policy,
+            &mut size,
+            core::ptr::null_mut(),
+        )
+    };
+    if status != Status::BUFFER_TOO_SMALL {
+        return Err(if status.is_error() {
+            status
+        } else {
+            Status::NOT_FOUND
+        });
+    }
+
+    let buffer = allocator::allocate_pool(MemoryType::BootServicesData, size)?;
+    let status = unsafe {
+        ((*load_file).load_file)(
+            load_file,
+            file_path,
+            boot_policy,
+            &mut size,
+            buffer as *mut c_void,
+        )
+    };
+    if status.is_error() {
+        let _ = allocator::free_pool(buffer);
+        return Err(status);
+    }
+
+    Ok((buffer, size))
+}
+
 /// Get the device handle from a parent image's LoadedImageProtocol
 fn get_device_handle_from_parent(parent_handle: Handle) -> Handle {
     if parent_handle.is_null() {
@@ -925,23 +1382,12 @@ fn get_device_handle_from_parent(parent_handle: Handle) -> Handle {
     }
 
     // Try to get the LoadedImageProtocol from the parent
-    let efi_state = state::efi();
-    efi_state
-        .handles
-        .iter()
-        .find(|entry| entry.handle == parent_handle)
-        .and_then(|entry| {
-            entry.protocols[..entry.protocol_count]
-                .iter()
-                .find(|proto| {
-                    proto.guid == LOADED_IMAGE_PROTOCOL_GUID && !proto.interface.is_null()
-                })
-                .map(|proto| {
-                    let loaded_image = unsafe {
-                        &*(proto.interface as *const r_efi::protocols::loaded_image::Protocol)
-                    };
-                    loaded_image.device_handle
-                })
+    handles::with(|db| db.find(parent_handle, &LOADED_IMAGE_PROTOCOL_GUID))
+        .filter(|iface| !iface.is_null())
+        .map(|iface| {
+            let loaded_image =
+                unsafe { &*(iface as *const r_efi::protocols::loaded_image::Protocol) };
+            loaded_image.device_handle
         })
         .unwrap_or(core::ptr::null_mut())
 }
@@ -990,9 +1436,23 @@ extern "efiapi" fn start_image(
     // Define the entry point function type
     type EfiEntryPoint = extern "efiapi" fn(Handle, *mut SystemTable) -> Status;
 
+    // Shell-style arguments for command-line utilities
+    let shell_parameters = super::protocols::shell_parameters::install(image_handle);
+
     // Call the entry point
+    #[cfg(feature = "audit")]
+    super::audit::enter(image_handle, image_base);
+    #[cfg(feature = "alloc-track")]
+    super::alloc_track::enter(image_handle, image_base);
     let entry: EfiEntryPoint = unsafe { core::mem::transmute(entry_point) };
     let status = entry(image_handle, system_table);
+    #[cfg(feature = "alloc-track")]
+    super::alloc_track::leave();
+    #[cfg(feature = "audit")]
+    super::audit::leave();
+    if shell_parameters {
+        super::protocols::shell_parameters::uninstall(image_handle);
+    }
 
     log::info!("BS.StartImage: Image returned with status: {:?}", status);
 
@@ -1088,17 +1548,29 @@ extern "efiapi" fn exit_boot_services(image_handle: Handle, map_key: usize) -> S
         map_key
     );
 
+    signal_group(&efi::EVENT_GROUP_BEFORE_EXIT_BOOT_SERVICES);
+
     let status = allocator::exit_boot_services(map_key);
 
     if status == Status::SUCCESS {
+        // The shadow framebuffer's pages belong to the OS now
+        crate::fb_shadow::disable();
+
+        // Describe the runtime regions of the map the OS got
+        super::memory_attributes_table::update();
+
         log::info!("ExitBootServices SUCCESS - transitioning to OS");
+        timing::report();
+        #[cfg(feature = "alloc-track")]
+        super::alloc_track::report();
+
+        signal_group(&efi::EVENT_GROUP_EXIT_BOOT_SERVICES);
 
-        // Clean up hardware state for OS handoff
-        // Re-enable keyboard interrupts so Linux's i8042 driver works
-        crate::drivers::keyboard::cleanup();
+        // The loader made it to the OS, reset the boot slot attempt counter
+        crate::boot_slots::mark_success();
+        crate::boot_log::record_outcome(crate::boot_log::Outcome::Booted);
 
-        // Stop and reset USB controllers so Linux can reinitialize them
-        crate::drivers::usb::cleanup();
+        prepare_handoff();
 
         // CRITICAL: Set boot_services pointer to NULL in SystemTable
         // This is REQUIRED by UEFI spec and Linux checks for this!
@@ -1112,33 +1584,68 @@ extern "efiapi" fn exit_boot_services(image_handle: Handle, map_key: usize) -> S
     status
 }
 
+/// Clean up hardware state for the OS, or for another coreboot payload
+pub fn prepare_handoff() {
+    // Re-enable keyboard interrupts so Linux's i8042 driver works
+    crate::drivers::keyboard::cleanup();
+
+    // Stop and reset USB controllers so Linux can reinitialize them
+    crate::drivers::usb::cleanup();
+
+    // Shut down NVMe controllers so Linux's nvme driver starts clean
+    crate::drivers::nvme::cleanup();
+
+    // Leave the UART as coreboot set it up, for the kernel's earlycon
+    crate::drivers::serial::prepare_handoff();
+
+    // Leave the APs waiting for the OS to start them
+    super::protocols::mp_services::park();
+}
+
 // ============================================================================
 // Miscellaneous Functions
 // ============================================================================
 
-extern "efiapi" fn get_next_monotonic_count(_count: *mut u64) -> Status {
-    Status::DEVICE_ERROR
+extern "efiapi" fn get_next_monotonic_count(count: *mut u64) -> Status {
+    if count.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    // The high 32 bits come from init_monotonic_count
+    unsafe { *count = MONOTONIC_COUNT.fetch_add(1, Ordering::Relaxed) };
+    Status::SUCCESS
 }
 
 extern "efiapi" fn stall(microseconds: usize) -> Status {
     log::debug!("BS.Stall({}us)", microseconds);
-    // Busy-wait using CPU cycles
-    // This is a rough approximation - real implementation would use TSC or HPET
-    for _ in 0..microseconds {
-        for _ in 0..1000 {
-            core::hint::spin_loop();
+    // Dispatch during long stalls too, the UART only holds a few keys
+    let mut remaining = microseconds as u64;
+    loop {
+        let step = remaining.min(STALL_DISPATCH_US);
+        crate::time::delay_us(step);
+        remaining -= step;
+        dispatch_timers();
+        if remaining == 0 {
+            break;
         }
     }
     Status::SUCCESS
 }
 
 extern "efiapi" fn set_watchdog_timer(
-    _timeout: usize,
+    timeout: usize,
     _watchdog_code: u64,
     _data_size: usize,
     _watchdog_data: *mut u16,
 ) -> Status {
-    Status::UNSUPPORTED
+    log::debug!("BS.SetWatchdogTimer({}s)", timeout);
+
+    // There is no watchdog, so disabling it always succeeds
+    if timeout == 0 {
+        Status::SUCCESS
+    } else {
+        Status::UNSUPPORTED
+    }
 }
 
 extern "efiapi" fn connect_controller(
@@ -1179,29 +1686,19 @@ extern "efiapi" fn open_protocol(
         attributes
     );
 
-    let efi_state = state::efi();
-
-    // Find the handle entry
-    let handle_entry = efi_state.handles[..efi_state.handle_count]
-        .iter()
-        .find(|entry| entry.handle == handle);
+    // Find the protocol on the handle
+    let lookup = handles::with(|db| db.contains(handle).then(|| db.find(handle, &guid)));
 
-    let Some(entry) = handle_entry else {
+    let Some(proto) = lookup else {
         log::warn!("  -> INVALID_PARAMETER (handle not found)");
         return Status::INVALID_PARAMETER;
     };
 
-    // Find the protocol on this handle
-    let proto = entry.protocols[..entry.protocol_count]
-        .iter()
-        .find(|p| p.guid == guid);
-
-    let Some(proto) = proto else {
+    let Some(iface) = proto else {
         log::warn!("  -> UNSUPPORTED (protocol not on handle)");
         return Status::UNSUPPORTED;
     };
 
-    let iface = proto.interface;
     if !interface.is_null() {
         unsafe { *interface = iface };
     }
@@ -1356,17 +1853,12 @@ extern "efiapi" fn locate_protocol(
     let guid = unsafe { *protocol };
     log::trace!("BS.LocateProtocol(protocol={})", GuidFmt(guid));
 
-    let efi_state = state::efi();
-
     // Find first handle with this protocol
-    let found = efi_state.handles[..efi_state.handle_count]
-        .iter()
-        .flat_map(|entry| entry.protocols[..entry.protocol_count].iter())
-        .find(|proto| proto.guid == guid);
+    let found = handles::with(|db| db.locate(&guid));
 
-    if let Some(proto) = found {
-        unsafe { *interface = proto.interface };
-        log::trace!("  -> SUCCESS (interface={:p})", proto.interface);
+    if let Some(iface) = found {
+        unsafe { *interface = iface };
+        log::trace!("  -> SUCCESS (interface={:p})", iface);
         return Status::SUCCESS;
     }
 
@@ -1487,20 +1979,8 @@ extern "efiapi" fn uninstall_multiple_protocol_interfaces(
         let guid = unsafe { *(*guid_ptr as *const Guid) };
         log::debug!("  Uninstalling protocol: {}", GuidFmt(guid));
 
-        // Find and remove the protocol from the handle
-        state::with_efi_mut(|efi_state| {
-            if let Some(entry) = efi_state.handles[..efi_state.handle_count]
-                .iter_mut()
-                .find(|e| e.handle == handle)
-                && let Some(j) = entry.protocols[..entry.protocol_count]
-                    .iter()
-                    .position(|p| p.guid == guid)
-            {
-                // Remove by shifting remaining protocols down
-                entry.protocols.copy_within(j + 1..entry.protocol_count, j);
-                entry.protocol_count -= 1;
-            }
-        });
+        // Remove the protocol from the handle
+        let _ = handles::with(|db| db.uninstall(handle, &guid));
     }
 
     log::trace!("  -> SUCCESS");
@@ -1841,6 +2321,28 @@ fn format_guid(guid: &Guid) -> &'static str {
             ),
             "MEMORY_ATTRIBUTE",
         ),
+        (
+            Guid::from_fields(
+                0x3fdda605,
+                0xa76e,
+                0x4f46,
+                0xad,
+                0x29,
+                &[0x12, 0xf4, 0x53, 0x1b, 0x3d, 0x08],
+            ),
+            "MP_SERVICES",
+        ),
+        (
+            Guid::from_fields(
+                0x752f3136,
+                0x4e16,
+                0x4fdc,
+                0xa2,
+                0x2a,
+                &[0xe5, 0xf4, 0x68, 0x12, 0xf4, 0xca],
+            ),
+            "SHELL_PARAMETERS",
+        ),
         (
             Guid::from_fields(
                 0xf541796d,
@@ -1896,50 +2398,15 @@ fn format_guid(guid: &Guid) -> &'static str {
 
 /// Create a new handle and register it
 pub fn create_handle() -> Option<Handle> {
-    state::with_efi_mut(|efi_state| {
-        if efi_state.handle_count >= MAX_HANDLES {
-            return None;
-        }
-
-        let handle = efi_state.next_handle as *mut c_void;
-        efi_state.next_handle += 1;
-
-        let idx = efi_state.handle_count;
-        efi_state.handles[idx].handle = handle;
-        efi_state.handles[idx].protocol_count = 0;
-        efi_state.handle_count += 1;
+    handles::with(|db| db.create()).ok()
+}
 
-        Some(handle)
-    })
+/// Signal an event, running its notification function
+pub fn signal(event: efi::Event) -> Status {
+    signal_event(event)
 }
 
 /// Install a protocol on an existing handle
 pub fn install_protocol(handle: Handle, guid: &Guid, interface: *mut c_void) -> Status {
-    state::with_efi_mut(|efi_state| {
-        if let Some(entry) = efi_state.handles[..efi_state.handle_count]
-            .iter_mut()
-            .find(|e| e.handle == handle)
-        {
-            // Check if protocol already installed
-            if entry.protocols[..entry.protocol_count]
-                .iter()
-                .any(|p| p.guid == *guid)
-            {
-                return Status::INVALID_PARAMETER;
-            }
-
-            if entry.protocol_count >= MAX_PROTOCOLS_PER_HANDLE {
-                return Status::OUT_OF_RESOURCES;
-            }
-
-            entry.protocols[entry.protocol_count] = ProtocolEntry {
-                guid: *guid,
-                interface,
-            };
-            entry.protocol_count += 1;
-            return Status::SUCCESS;
-        }
-
-        Status::INVALID_PARAMETER
-    })
+    handles::with(|db| db.install(handle, guid, interface))
 }
@@ -0,0 +1,370 @@
+//! Firmware capability report
+//!
+//! Lists the UEFI services that boot loaders depend on, how complete their
+//! implementation is, and the milestone each remaining gap blocks. The
+//! report is logged at startup so that the boot log of a failing loader
+//! shows which services it may have tripped over.
+//!
+//! The `windows-boot` milestone collects what Windows Boot Manager
+//! (`\EFI\Microsoft\Boot\bootmgfw.efi`) needs beyond what Linux loaders use.
+//! Update the table together with the service it describes.
+
+/// How complete the implementation of a service is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Support {
+    /// Implemented as the specification describes
+    Full,
+    /// Implemented with limitations, see the note
+    Partial,
+    /// Not implemented, calls fail with `UNSUPPORTED` or the data is absent
+    Missing,
+}
+
+/// A goal that open gaps are tracked under
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Milestone {
+    /// Booting Windows through Windows Boot Manager
+    WindowsBoot,
+}
+
+impl Milestone {
+    /// Name of the milestone in logs
+    pub fn name(self) -> &'static str {
+        match self {
+            Milestone::WindowsBoot => "windows-boot",
+        }
+    }
+}
+
+/// A service and the state of its implementation
+pub struct Capability {
+    /// Service or protocol name
+    pub service: &'static str,
+    /// Implementation status
+    pub support: Support,
+    /// Milestone blocked by the gap, if any
+    pub milestone: Option<Milestone>,
+    /// What works and what doesn't
+    pub note: &'static str,
+    /// Boot (`BS.`) and runtime (`RT.`) services table entries it covers
+    pub functions: &'static [&'static str],
+}
+
+/// Shorthand for the table below
+const fn capability(
+    service: &'static str,
+    support: Support,
+    milestone: Option<Milestone>,
+    note: &'static str,
+    functions: &'static [&'static str],
+) -> Capability {
+    Capability {
+        service,
+        support,
+        milestone,
+        note,
+        functions,
+    }
+}
+
+const WINDOWS: Option<Milestone> = Some(Milestone::WindowsBoot);
+
+/// The services boot loaders use, in boot/runtime services table order
+///
+/// Every services table entry the [audit](super::audit) interposes on is
+/// listed under one capability, whose status the audit reports.
+pub const CAPABILITIES: &[Capability] = &[
+    capability(
+        "Memory services",
+        Support::Full,
+        None,
+        "runtime regions carry EFI_MEMORY_RUNTIME, ACPI regions are reclaim/NVS",
+        &[
+            "BS.AllocatePages",
+            "BS.FreePages",
+            "BS.GetMemoryMap",
+            "BS.AllocatePool",
+            "BS.FreePool",
+        ],
+    ),
+    capability(
+        "Events and timers",
+        Support::Partial,
+        None,
+        "no timer interrupt: timers fire and notifications run from CheckEvent, \
+         WaitForEvent, Stall and RestoreTPL",
+        &[
+            "BS.CreateEvent",
+            "BS.SetTimer",
+            "BS.WaitForEvent",
+            "BS.SignalEvent",
+            "BS.CloseEvent",
+            "BS.CheckEvent",
+            "BS.Stall",
+        ],
+    ),
+    capability(
+        "Event groups",
+        Support::Partial,
+        WINDOWS,
+        "ExitBootServices groups are signaled, VirtualAddressChange never is",
+        &["BS.CreateEventEx"],
+    ),
+    capability(
+        "Protocol handlers",
+        Support::Partial,
+        None,
+        "LocateHandle only searches ByProtocol, OpenProtocol doesn't track \
+         agents or exclusive opens",
+        &[
+            "BS.InstallProtocolInterface",
+            "BS.ReinstallProtocolInterface",
+            "BS.UninstallProtocolInterface",
+            "BS.HandleProtocol",
+            "BS.LocateHandle",
+            "BS.LocateDevicePath",
+            "BS.OpenProtocol",
+            "BS.LocateHandleBuffer",
+            "BS.LocateProtocol",
+        ],
+    ),
+    capability(
+        "Protocol notifications",
+        Support::Missing,
+        None,
+        "RegisterProtocolNotify returns UNSUPPORTED",
+        &["BS.RegisterProtocolNotify"],
+    ),
+    capability(
+        "Configuration tables",
+        Support::Full,
+        None,
+        "tables are added, replaced and removed by GUID",
+        &["BS.InstallConfigurationTable"],
+    ),
+    capability(
+        "Image services",
+        Support::Partial,
+        None,
+        "no exit data, Exit returns instead of unwinding to StartImage",
+        &["BS.LoadImage", "BS.StartImage", "BS.Exit", "BS.UnloadImage"],
+    ),
+    capability(
+        "ExitBootServices",
+        Support::Full,
+        None,
+        "the map key is checked and ExitBootServices events are signaled",
+        &["BS.ExitBootServices"],
+    ),
+    capability(
+        "Monotonic count",
+        Support::Partial,
+        None,
+        "high 32 bits are kept in the MTC variable, GetNextHighMonotonicCount \
+         returns UNSUPPORTED",
+        &["BS.GetNextMonotonicCount", "RT.GetNextHighMonotonicCount"],
+    ),
+    capability(
+        "Watchdog timer",
+        Support::Partial,
+        None,
+        "no watchdog, only disabling it succeeds",
+        &["BS.SetWatchdogTimer"],
+    ),
+    capability(
+        "Driver model",
+        Support::Missing,
+        None,
+        "no driver binding, (Dis)ConnectController return UNSUPPORTED",
+        &["BS.ConnectController", "BS.DisconnectController"],
+    ),
+    capability(
+        "Protocol information",
+        Support::Missing,
+        WINDOWS,
+        "CloseProtocol, OpenProtocolInformation and ProtocolsPerHandle return UNSUPPORTED",
+        &[
+            "BS.CloseProtocol",
+            "BS.OpenProtocolInformation",
+            "BS.ProtocolsPerHandle",
+        ],
+    ),
+    capability(
+        "CalculateCrc32",
+        Support::Missing,
+        None,
+        "returns UNSUPPORTED",
+        &["BS.CalculateCrc32"],
+    ),
+    capability(
+        "GetTime",
+        Support::Full,
+        None,
+        "CMOS RTC, 1 s resolution",
+        &["RT.GetTime"],
+    ),
+    capability(
+        "SetTime",
+        Support::Missing,
+        WINDOWS,
+        "the OS can't set the RTC through runtime services",
+        &["RT.SetTime"],
+    ),
+    capability(
+        "Wakeup time",
+        Support::Missing,
+        None,
+        "no RTC alarm",
+        &["RT.GetWakeupTime", "RT.SetWakeupTime"],
+    ),
+    capability(
+        "SetVirtualAddressMap",
+        Support::Partial,
+        WINDOWS,
+        "runtime services only work identity-mapped, so neither it nor the \
+         UNSUPPORTED ConvertPointer is in the RT properties table",
+        &["RT.SetVirtualAddressMap", "RT.ConvertPointer"],
+    ),
+    capability(
+        "Variables",
+        Support::Partial,
+        WINDOWS,
+        "non-volatile variables are saved in SMMSTORE v2, at runtime too; \
+         without an SMMSTORE region they are lost on reset, except the \
+         BootOrder default",
+        &[
+            "RT.GetVariable",
+            "RT.GetNextVariableName",
+            "RT.SetVariable",
+            "RT.QueryVariableInfo",
+        ],
+    ),
+    capability(
+        "Secure Boot",
+        Support::Missing,
+        None,
+        "reported as off (SecureBoot=0, SetupMode=1)",
+        &[],
+    ),
+    capability(
+        "ResetSystem",
+        Support::Partial,
+        None,
+        "cold and warm reset per board quirks, shutdown halts",
+        &["RT.ResetSystem"],
+    ),
+    capability(
+        "Capsule updates",
+        Support::Missing,
+        None,
+        "not needed for boot",
+        &["RT.UpdateCapsule", "RT.QueryCapsuleCapabilities"],
+    ),
+    capability(
+        "Graphics Output",
+        Support::Partial,
+        None,
+        "coreboot framebuffer, smaller EDID resolutions as centered windows, \
+         no modesetting",
+        &[],
+    ),
+    capability(
+        "Simple Text Input Ex",
+        Support::Full,
+        None,
+        "shift and toggle state, key notifications run when input is polled",
+        &[],
+    ),
+    capability(
+        "MP Services",
+        Support::Partial,
+        None,
+        "APs from the MADT, SwitchBSP is UNSUPPORTED",
+        &[],
+    ),
+    capability(
+        "Shell Parameters",
+        Support::Partial,
+        None,
+        "argv from LoadOptions, no StdIn/StdOut/StdErr handles",
+        &[],
+    ),
+    capability(
+        "Firmware Volume 2",
+        Support::Partial,
+        None,
+        "empty volume on the device handle of loaded drivers, no files",
+        &[],
+    ),
+    capability(
+        "HII",
+        Support::Partial,
+        None,
+        "package lists and string lookup only, no forms, fonts or keyboard layouts",
+        &[],
+    ),
+    capability(
+        "ACPI tables",
+        Support::Full,
+        None,
+        "coreboot tables installed as configuration tables",
+        &[],
+    ),
+    capability(
+        "SMBIOS tables",
+        Support::Full,
+        None,
+        "coreboot tables installed as configuration tables",
+        &[],
+    ),
+];
+
+/// Index of the capability covering services table entry `function`, at
+/// compile time
+pub const fn covering(function: &str) -> usize {
+    let function = function.as_bytes();
+    let mut i = 0;
+    while i < CAPABILITIES.len() {
+        let functions = CAPABILITIES[i].functions;
+        let mut j = 0;
+        while j < functions.len() {
+            let candidate = functions[j].as_bytes();
+            if candidate.len() == function.len() {
+                let mut k = 0;
+                while k < function.len() && candidate[k] == function[k] {
+                    k += 1;
+                }
+                if k == function.len() {
+                    return i;
+                }
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    panic!("service not in the capability table");
+}
+
+/// Open gaps of a milestone
+pub fn gaps(milestone: Milestone) -> impl Iterator<Item = &'static Capability> {
+    CAPABILITIES
+        .iter()
+        .filter(move |c| c.support != Support::Full && c.milestone == Some(milestone))
+}
+
+/// Log the capability report
+pub fn log_report() {
+    for c in CAPABILITIES {
+        log::debug!("  {:<22} {:?}: {}", c.service, c.support, c.note);
+    }
+
+    let milestone = Milestone::WindowsBoot;
+    log::info!(
+        "Capabilities: {} gaps open for {}",
+        gaps(milestone).count(),
+        milestone.name()
+    );
+    for c in gaps(milestone) {
+        log::debug!("  {}: {}", c.service, c.note);
+    }
+}
@@ -0,0 +1,199 @@
+//! Interior-mutable statics shared with EFI clients
+//!
+//! The system table, the service tables and the protocol interfaces live in
+//! statics whose addresses are handed out to EFI applications. `EfiCell`
+//! replaces `static mut` for them: the firmware accesses the value through
+//! [`EfiCell::with`], and only [`EfiCell::as_ptr`] exposes it to clients.
+//!
+//! # Invariants
+//!
+//! Firmware code runs on one processor at a time. That is the BSP, except
+//! during [parallel device init](crate::parallel), where init tasks on the
+//! APs take turns holding the boot lock and hand it over only while waiting
+//! in [`crate::time::delay_us`]. Handing it over with a `with` closure
+//! active would let another task reach the same cell, so
+//! [`crate::parallel::wait`] asserts that none is, see [`any_borrowed`].
+//! Interrupt handlers never touch EFI state. EFI clients run between firmware
+//! accesses (they call in through `extern "efiapi"` functions or get control
+//! back from them), never during one. So the only way to alias the `&mut`
+//! handed to a `with` closure is for the closure itself to reach the same
+//! cell again, and that reentrancy is caught at runtime.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Number of `with` closures active, on any cell
+static BORROWS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a `with` closure is active on any cell
+pub fn any_borrowed() -> bool {
+    BORROWS.load(Ordering::Relaxed) != 0
+}
+
+/// Interior-mutable storage for a static whose address is given to EFI clients
+pub struct EfiCell<T> {
+    value: UnsafeCell<T>,
+    /// Set while a `with` closure holds the value
+    borrowed: AtomicBool,
+}
+
+// Safety: only the holder of the boot lock runs firmware code, see the
+// module documentation
+unsafe impl<T> Sync for EfiCell<T> {}
+
+impl<T> EfiCell<T> {
+    /// Create a new cell
+    pub const fn new(value: T) -> Self {
+        Self {
+            value: UnsafeCell::new(value),
+            borrowed: AtomicBool::new(false),
+        }
+    }
+
+    /// Get the pointer handed out to EFI clients
+    ///
+    /// The pointer is valid for the firmware lifetime. The firmware itself
+    /// must go through [`EfiCell::with`] instead of dereferencing it.
+    pub const fn as_ptr(&self) -> *mut T {
+        self.value.get()
+    }
+
+    /// Access the value mutably through a closure
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from within another `with` closure on the same cell.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let already_borrowed = self.borrowed.swap(true, Ordering::Acquire);
+        assert!(!already_borrowed, "EfiCell accessed reentrantly");
+        BORROWS.fetch_add(1, Ordering::Relaxed);
+
+        // Safety: the borrow flag makes this the only reference created by
+        // the firmware, and EFI clients don't run while it is alive
+        let result = f(unsafe { &mut *self.value.get() });
+
+        BORROWS.fetch_sub(1, Ordering::Relaxed);
+        self.borrowed.store(false, Ordering::Release);
+        result
+    }
+}
+
+impl<T: Copy> EfiCell<T> {
+    /// Get a copy of the value
+    pub fn get(&self) -> T {
+        self.with(|value| *value)
+    }
+
+    /// Replace the value
+    pub fn set(&self, value: T) {
+        self.with(|current| *current = value);
+    }
+}
+
+/// Per-instance contexts of a protocol, keyed by the interface pointer
+///
+/// Protocols installed once per device (Block I/O, the pass-thru protocols)
+/// get their interface pointer back as `this` and look up the device behind
+/// it here.
+pub struct ContextTable<P, C, const N: usize> {
+    slots: EfiCell<[Option<(*mut P, C)>; N]>,
+}
+
+impl<P, C: Copy, const N: usize> ContextTable<P, C, N> {
+    /// Create an empty table
+    pub const fn new() -> Self {
+        Self {
+            slots: EfiCell::new([const { None }; N]),
+        }
+    }
+
+    /// Check whether all slots are taken
+    pub fn is_full(&self) -> bool {
+        self.slots.with(|slots| slots.iter().all(Option::is_some))
+    }
+
+    /// Store the context of a new protocol instance
+    ///
+    /// Returns the slot index, or `None` if the table is full.
+    pub fn insert(&self, protocol: *mut P, context: C) -> Option<usize> {
+        self.slots.with(|slots| {
+            let index = slots.iter().position(Option::is_none)?;
+            slots[index] = Some((protocol, context));
+            Some(index)
+        })
+    }
+
+    /// Find the slot index of a protocol instance
+    pub fn index_of(&self, protocol: *mut P) -> Option<usize> {
+        self.slots.with(|slots| {
+            slots
+                .iter()
+                .position(|slot| matches!(slot, Some((p, _)) if *p == protocol))
+        })
+    }
+
+    /// Get the context of a protocol instance
+    pub fn get(&self, protocol: *mut P) -> Option<C> {
+        self.slots.with(|slots| {
+            slots.iter().find_map(|slot| match slot {
+                Some((p, context)) if *p == protocol => Some(*context),
+                _ => None,
+            })
+        })
+    }
+
+    /// Change the context of a protocol instance
+    pub fn update(&self, protocol: *mut P, f: impl FnOnce(&mut C)) {
+        self.slots.with(|slots| {
+            if let Some((_, context)) = slots.iter_mut().flatten().find(|(p, _)| *p == protocol) {
+                f(context);
+            }
+        })
+    }
+}
+
+impl<P, C: Copy, const N: usize> Default for ContextTable<P, C, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cell_access() {
+        let cell = EfiCell::new(1u32);
+        cell.with(|value| *value += 1);
+        assert_eq!(cell.get(), 2);
+        cell.set(5);
+        assert_eq!(unsafe { *cell.as_ptr() }, 5);
+        cell.with(|_| assert!(any_borrowed()));
+    }
+
+    #[test]
+    #[should_panic(expected = "reentrantly")]
+    fn cell_rejects_reentrancy() {
+        let cell = EfiCell::new(0u32);
+        cell.with(|_| cell.set(1));
+    }
+
+    #[test]
+    fn context_table() {
+        let table: ContextTable<u8, u32, 2> = ContextTable::new();
+        let (mut a, mut b, mut c) = (0u8, 0u8, 0u8);
+
+        assert_eq!(table.insert(&mut a, 10), Some(0));
+        assert_eq!(table.insert(&mut b, 20), Some(1));
+        assert!(table.is_full());
+        assert_eq!(table.insert(&mut c, 30), None);
+
+        assert_eq!(table.get(&mut b), Some(20));
+        assert_eq!(table.index_of(&mut b), Some(1));
+        assert_eq!(table.get(&mut c), None);
+
+        table.update(&mut b, |context| *context += 1);
+        assert_eq!(table.get(&mut b), Some(21));
+    }
+}
@@ -0,0 +1,236 @@
+//! Compatibility shims for EFI drivers
+//!
+//! Vendor drivers, such as a GOP driver taken from the vendor's firmware
+//! image, are built for PI firmware and use services CrabEFI doesn't have.
+//! When LoadImage loads a boot or runtime services driver, the shims it
+//! needs are set up before it can run:
+//!
+//! - `fv2`: an [empty firmware volume](super::protocols::firmware_volume) on
+//!   the driver's device handle, for drivers that read sections of the
+//!   volume they came from
+//!
+//! Drivers get every shim by default. The CBFS file `crabefi/driver-quirks`
+//! chooses them per driver instead: one driver per line, followed by its
+//! shims, or `none`. Lines starting with `#` are ignored. A driver is
+//! identified by the GUID of the firmware file it was loaded from or, since
+//! drivers loaded from a buffer, an option ROM or a disk have no firmware
+//! file, by the SHA-256 hash of the image as `sha256sum` prints it:
+//!
+//! ```text
+//! # cbfstool coreboot.rom add -f driver-quirks -n crabefi/driver-quirks -t raw
+//! 01234567-89ab-cdef-0123-456789abcdef none
+//! 0d1e2f...  fv2
+//! ```
+
+use r_efi::efi::{Guid, Handle, Status};
+use r_efi::protocols::device_path::Protocol as DevicePathProtocol;
+
+use super::handles;
+use super::protocols::device_path;
+use super::protocols::firmware_volume::{self, FIRMWARE_VOLUME2_PROTOCOL_GUID};
+use crate::coreboot::cbfs;
+use crate::image_policy::{self, HASH_SIZE};
+
+/// CBFS file listing the shims of individual drivers
+pub const CBFS_NAME: &str = "crabefi/driver-quirks";
+
+/// Shims set up for a driver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// Empty firmware volume on the driver's device handle
+    pub firmware_volume: bool,
+}
+
+impl Quirks {
+    /// Shims for drivers that aren't listed
+    pub const DEFAULT: Self = Self {
+        firmware_volume: true,
+    };
+
+    /// No shims at all
+    pub const NONE: Self = Self {
+        firmware_volume: false,
+    };
+
+    /// Parse a list of shim names
+    fn parse<'a>(names: impl Iterator<Item = &'a str>) -> Option<Self> {
+        let mut quirks = Self::NONE;
+        for name in names {
+            match name {
+                "fv2" => quirks.firmware_volume = true,
+                "none" => {}
+                _ => return None,
+            }
+        }
+        Some(quirks)
+    }
+}
+
+/// Parse a GUID in registry format, `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`
+fn parse_guid(text: &str) -> Option<Guid> {
+    let mut parts = text.split('-');
+    let mut field = |len: usize| {
+        let part = parts.next()?;
+        if part.len() != len {
+            return None;
+        }
+        u64::from_str_radix(part, 16).ok()
+    };
+    let (d1, d2, d3, d4, d5) = (field(8)?, field(4)?, field(4)?, field(4)?, field(12)?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let node = d5.to_be_bytes();
+    Some(Guid::from_fields(
+        d1 as u32,
+        d2 as u16,
+        d3 as u16,
+        (d4 >> 8) as u8,
+        d4 as u8,
+        node[2..].try_into().ok()?,
+    ))
+}
+
+/// How a line of the quirk list identifies a driver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Driver {
+    /// GUID of the firmware file the driver was loaded from
+    File(Guid),
+    /// SHA-256 hash of the image
+    Image([u8; HASH_SIZE]),
+}
+
+impl Driver {
+    fn parse(text: &str) -> Option<Self> {
+        parse_guid(text)
+            .map(Self::File)
+            .or_else(|| image_policy::parse_hash(text).map(Self::Image))
+    }
+}
+
+/// Shims the quirk list `text` gives the driver with the firmware file
+/// `guid` and the image hash `hash`, if it lists it
+fn lookup(text: &str, guid: Option<&Guid>, hash: &[u8; HASH_SIZE]) -> Option<Quirks> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .find_map(|line| {
+            let mut words = line.split_whitespace();
+            let listed = words.next().and_then(Driver::parse);
+            let quirks = listed.and_then(|_| Quirks::parse(words));
+            if quirks.is_none() {
+                log::warn!("Driver quirks: ignoring invalid line {:?}", line);
+            }
+            let matches = match listed {
+                Some(Driver::File(listed)) => guid == Some(&listed),
+                Some(Driver::Image(listed)) => listed == *hash,
+                None => false,
+            };
+            matches.then_some(quirks).flatten()
+        })
+}
+
+/// Shims for a driver
+///
+/// `file_path` is the device path the driver was loaded from, if any, and
+/// `image` the contents of its image file.
+///
+/// # Safety
+/// `file_path` must be null or point to a valid device path terminated by
+/// an End node.
+pub unsafe fn quirks_for(file_path: *const DevicePathProtocol, image: &[u8]) -> Quirks {
+    let Some(text) = cbfs::find_file(CBFS_NAME).and_then(|data| core::str::from_utf8(data).ok())
+    else {
+        return Quirks::DEFAULT;
+    };
+    let guid = if file_path.is_null() {
+        None
+    } else {
+        unsafe { device_path::firmware_file_guid(file_path) }
+    };
+    lookup(text, guid.as_ref(), &image_policy::hash(image)).unwrap_or(Quirks::DEFAULT)
+}
+
+/// Set up the shims for a driver that was just loaded
+///
+/// `device_handle` is that of the driver's Loaded Image protocol.
+pub fn apply(device_handle: Handle, quirks: Quirks) {
+    log::debug!("Driver quirks: {:?}", quirks);
+
+    if quirks.firmware_volume && !device_handle.is_null() {
+        let present = handles::with(|db| {
+            db.find(device_handle, &FIRMWARE_VOLUME2_PROTOCOL_GUID)
+                .is_some()
+        });
+        if present {
+            return;
+        }
+        let volume = firmware_volume::empty_volume();
+        if volume.is_null() {
+            return;
+        }
+        let status = handles::with(|db| {
+            db.install(
+                device_handle,
+                &FIRMWARE_VOLUME2_PROTOCOL_GUID,
+                volume as *mut core::ffi::c_void,
+            )
+        });
+        if status != Status::SUCCESS {
+            log::warn!(
+                "Driver quirks: failed to install firmware volume: {:?}",
+                status
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_listed_drivers() {
+        let guid = parse_guid("01234567-89ab-cdef-0123-456789abcdef").unwrap();
+        assert_eq!(
+            guid,
+            Guid::from_fields(
+                0x0123_4567,
+                0x89ab,
+                0xcdef,
+                0x01,
+                0x23,
+                &[0x45, 0x67, 0x89, 0xab, 0xcd, 0xef],
+            )
+        );
+        assert!(parse_guid("01234567-89ab-cdef-0123").is_none());
+
+        let hash = image_policy::hash(b"driver");
+        let unlisted_hash = image_policy::hash(b"other driver");
+        let list = "# vendor GOP\n\
+                    not-a-guid fv2\n\
+                    01234567-89AB-CDEF-0123-456789ABCDEF none\n\
+                    11111111-2222-3333-4444-555555555555 fv2\n";
+        assert_eq!(
+            lookup(list, Some(&guid), &unlisted_hash),
+            Some(Quirks::NONE)
+        );
+        let other = parse_guid("11111111-2222-3333-4444-555555555555").unwrap();
+        assert_eq!(
+            lookup(list, Some(&other), &unlisted_hash),
+            Some(Quirks::DEFAULT)
+        );
+        let unlisted = parse_guid("ffffffff-2222-3333-4444-555555555555").unwrap();
+        assert_eq!(lookup(list, Some(&unlisted), &unlisted_hash), None);
+
+        // Drivers without a firmware file are listed by their image hash
+        let mut list = heapless::String::<128>::new();
+        let _ = list.push_str("# option ROM driver\n");
+        for byte in hash {
+            let _ = core::fmt::write(&mut list, format_args!("{:02x}", byte));
+        }
+        let _ = list.push_str("  none\n");
+        assert_eq!(lookup(&list, None, &hash), Some(Quirks::NONE));
+        assert_eq!(lookup(&list, Some(&guid), &unlisted_hash), None);
+    }
+}
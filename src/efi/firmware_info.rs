@@ -0,0 +1,160 @@
+//! Firmware identification for the OS
+//!
+//! The system table names both CrabEFI and the coreboot build it runs on.
+//! The firmware vendor reads like `CrabEFI 0.1.0 (coreboot 4.22-1234-gdeadbeef)`
+//! and the firmware revision holds [`CRABEFI_REVISION`] in its upper 16 bits
+//! and the coreboot major and minor version in the two lower bytes, so 4.22
+//! becomes `0x0416`.
+//!
+//! Inventory tooling finds the details in the configuration table
+//! [`FIRMWARE_INFO_TABLE_GUID`]. Its strings are NUL-terminated UTF-8,
+//! padded with NULs and empty when coreboot didn't provide them:
+//!
+//! ```text
+//! u32  signature               "CBFI"
+//! u32  size                    size of the table
+//! char crabefi_version[32]
+//! char coreboot_version[64]
+//! char coreboot_extra_version[64]
+//! char coreboot_build[64]      build date
+//! char mainboard_vendor[32]
+//! char mainboard_part_number[64]
+//! ```
+
+use core::fmt::Write;
+
+use r_efi::efi::{self, Guid};
+
+use super::cell::EfiCell;
+use super::system_table::{self, CRABEFI_REVISION};
+use crate::coreboot::tables::CorebootInfo;
+
+/// Configuration table describing the firmware build
+/// {758733A6-675F-4FE1-B2E6-955E2D9C1A3D}
+pub const FIRMWARE_INFO_TABLE_GUID: Guid = Guid::from_fields(
+    0x758733a6,
+    0x675f,
+    0x4fe1,
+    0xb2,
+    0xe6,
+    &[0x95, 0x5e, 0x2d, 0x9c, 0x1a, 0x3d],
+);
+
+/// "CBFI"
+const SIGNATURE: u32 = u32::from_le_bytes(*b"CBFI");
+
+/// Length of the firmware vendor string in UCS-2 characters, with the NUL
+const VENDOR_LEN: usize = 128;
+
+/// Firmware information table
+#[repr(C)]
+struct FirmwareInfo {
+    signature: u32,
+    size: u32,
+    crabefi_version: [u8; 32],
+    coreboot_version: [u8; 64],
+    coreboot_extra_version: [u8; 64],
+    coreboot_build: [u8; 64],
+    mainboard_vendor: [u8; 32],
+    mainboard_part_number: [u8; 64],
+}
+
+/// The table handed to the OS
+static FIRMWARE_INFO: EfiCell<FirmwareInfo> = EfiCell::new(FirmwareInfo {
+    signature: SIGNATURE,
+    size: core::mem::size_of::<FirmwareInfo>() as u32,
+    crabefi_version: [0; 32],
+    coreboot_version: [0; 64],
+    coreboot_extra_version: [0; 64],
+    coreboot_build: [0; 64],
+    mainboard_vendor: [0; 32],
+    mainboard_part_number: [0; 64],
+});
+
+/// Firmware vendor string in UCS-2
+static FIRMWARE_VENDOR: EfiCell<[u16; VENDOR_LEN]> = EfiCell::new([0; VENDOR_LEN]);
+
+/// Copy `text` into a NUL-padded field, truncating it to leave room for the NUL
+fn copy_field(field: &mut [u8], text: &str) {
+    let mut len = text.len().min(field.len() - 1);
+    while !text.is_char_boundary(len) {
+        len -= 1;
+    }
+    field.fill(0);
+    field[..len].copy_from_slice(&text.as_bytes()[..len]);
+}
+
+/// Encode a coreboot version such as `4.22-1234-gdeadbeef` as `0x0416`
+fn coreboot_revision(version: &str) -> Option<u16> {
+    let (major, rest) = version.split_once('.')?;
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let major: u8 = major.parse().ok()?;
+    let minor: u8 = rest[..digits].parse().ok()?;
+    Some(u16::from(major) << 8 | u16::from(minor))
+}
+
+/// Name the firmware in the system table and install the information table
+pub fn publish(cb_info: &CorebootInfo) {
+    let version = cb_info.version.as_deref();
+
+    let mut vendor: heapless::String<VENDOR_LEN> = heapless::String::new();
+    let _ = write!(vendor, "CrabEFI {}", env!("CARGO_PKG_VERSION"));
+    if let Some(version) = version {
+        let _ = write!(vendor, " (coreboot {})", version);
+    }
+    let revision = CRABEFI_REVISION | u32::from(version.and_then(coreboot_revision).unwrap_or(0));
+
+    FIRMWARE_VENDOR.with(|buf| {
+        // Leave the last character as the NUL terminator
+        for (dst, c) in buf[..VENDOR_LEN - 1].iter_mut().zip(vendor.encode_utf16()) {
+            *dst = c;
+        }
+    });
+    unsafe {
+        system_table::set_firmware_vendor(FIRMWARE_VENDOR.as_ptr() as *const u16, revision);
+    }
+    log::info!("Firmware: {} (revision {:#010x})", vendor, revision);
+
+    FIRMWARE_INFO.with(|info| {
+        copy_field(&mut info.crabefi_version, env!("CARGO_PKG_VERSION"));
+        copy_field(&mut info.coreboot_version, version.unwrap_or(""));
+        copy_field(
+            &mut info.coreboot_extra_version,
+            cb_info.extra_version.as_deref().unwrap_or(""),
+        );
+        copy_field(
+            &mut info.coreboot_build,
+            cb_info.build.as_deref().unwrap_or(""),
+        );
+        if let Some(mainboard) = &cb_info.mainboard {
+            copy_field(&mut info.mainboard_vendor, &mainboard.vendor);
+            copy_field(&mut info.mainboard_part_number, &mainboard.part_number);
+        }
+    });
+
+    let status = system_table::install_configuration_table(
+        &FIRMWARE_INFO_TABLE_GUID,
+        FIRMWARE_INFO.as_ptr() as *mut core::ffi::c_void,
+    );
+    if status != efi::Status::SUCCESS {
+        log::warn!("Failed to install firmware information table: {:?}", status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_coreboot_revision() {
+        assert_eq!(coreboot_revision("4.22-1234-gdeadbeef"), Some(0x0416));
+        assert_eq!(coreboot_revision("25.03"), Some(0x1903));
+        assert_eq!(coreboot_revision("unknown"), None);
+
+        let mut field = [0xffu8; 8];
+        copy_field(&mut field, "coreboot");
+        assert_eq!(&field, b"coreboo\0");
+    }
+}
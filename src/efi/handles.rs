@@ -0,0 +1,392 @@
+//! EFI handle database
+//!
+//! Every handle carries a list of installed protocols. Handles and protocol
+//! entries live in pool-allocated arrays that grow with the number of
+//! devices, so installing Block I/O, Disk I/O, Partition Info and a device
+//! path on every partition of a multi-disk system doesn't hit a fixed limit.
+//!
+//! Handle values are 1-based indices into the handle array, which makes
+//! looking a handle up O(1). Handles are never freed, so a value always
+//! refers to the same handle. The protocols of a handle form a linked list
+//! threaded through the shared protocol array; uninstalled entries go on a
+//! free list and are reused by the next install.
+//!
+//! `LocateProtocol` and `LocateHandle` search by GUID across all handles, and
+//! boot loaders call them hundreds of times. Every GUID ever installed gets an
+//! entry in an open-addressing hash table, and all protocol entries with that
+//! GUID form a second linked list, so these lookups don't scan the database.
+//!
+//! The database is kept outside `FirmwareState` because growing it allocates
+//! from the pool, and the allocator is part of `EfiState`.
+
+use super::cell::EfiCell;
+use super::utils::PoolVec;
+use core::ffi::c_void;
+use r_efi::efi::{Guid, Handle, Status};
+
+/// End of a protocol list, or empty hash bucket
+const NONE: usize = usize::MAX;
+
+/// Number of hash buckets allocated for the first GUID
+const INITIAL_BUCKETS: usize = 64;
+
+/// Protocol interface installed on a handle
+#[derive(Clone, Copy)]
+pub struct ProtocolEntry {
+    pub guid: Guid,
+    pub interface: *mut c_void,
+    /// Index of the handle the protocol is installed on
+    handle: usize,
+    /// Next protocol on the same handle, or next free entry
+    next: usize,
+    /// Next protocol with the same GUID
+    next_by_guid: usize,
+}
+
+/// Handle entry in the handle database
+#[derive(Clone, Copy)]
+struct HandleEntry {
+    /// First protocol installed on the handle
+    first_protocol: usize,
+}
+
+/// GUID entry in the protocol index
+#[derive(Clone, Copy)]
+struct GuidEntry {
+    key: u128,
+    /// First and last protocol entry with this GUID, in install order
+    first_protocol: usize,
+    last_protocol: usize,
+}
+
+/// The handle database
+pub struct HandleDatabase {
+    handles: PoolVec<HandleEntry>,
+    protocols: PoolVec<ProtocolEntry>,
+    /// Head of the list of uninstalled protocol entries
+    free_protocol: usize,
+    /// Every GUID installed so far
+    guids: PoolVec<GuidEntry>,
+    /// Hash table of indices into `guids`, a power of two in size
+    buckets: PoolVec<usize>,
+}
+
+static HANDLES: EfiCell<HandleDatabase> = EfiCell::new(HandleDatabase::new());
+
+/// Access the handle database through a closure
+pub fn with<R>(f: impl FnOnce(&mut HandleDatabase) -> R) -> R {
+    HANDLES.with(f)
+}
+
+impl HandleDatabase {
+    /// Create an empty database
+    pub const fn new() -> Self {
+        Self {
+            handles: PoolVec::new(),
+            protocols: PoolVec::new(),
+            free_protocol: NONE,
+            guids: PoolVec::new(),
+            buckets: PoolVec::new(),
+        }
+    }
+
+    /// Number of handles
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// Check whether no handle was created yet
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    /// Create a new handle without protocols
+    pub fn create(&mut self) -> Result<Handle, Status> {
+        self.handles.push(HandleEntry {
+            first_protocol: NONE,
+        })?;
+        Ok(self.handles.len() as *mut c_void)
+    }
+
+    /// Check whether `handle` was created by this database
+    pub fn contains(&self, handle: Handle) -> bool {
+        self.index(handle).is_some()
+    }
+
+    /// Iterate over all handles, in creation order
+    pub fn handles(&self) -> impl Iterator<Item = Handle> + use<> {
+        (1..=self.handles.len()).map(|value| value as *mut c_void)
+    }
+
+    /// Iterate over the protocols installed on `handle`, in install order
+    ///
+    /// Unknown handles have no protocols.
+    pub fn protocols(&self, handle: Handle) -> Protocols<'_> {
+        let next = self
+            .index(handle)
+            .map_or(NONE, |index| self.handles.as_slice()[index].first_protocol);
+        Protocols {
+            entries: self.protocols.as_slice(),
+            next,
+        }
+    }
+
+    /// Get the interface of a protocol installed on `handle`
+    pub fn find(&self, handle: Handle, guid: &Guid) -> Option<*mut c_void> {
+        self.protocols(handle)
+            .find(|entry| entry.guid == *guid)
+            .map(|entry| entry.interface)
+    }
+
+    /// Iterate over the handles carrying a protocol, in install order
+    pub fn handles_with(&self, guid: &Guid) -> impl Iterator<Item = Handle> + '_ {
+        let mut next = self
+            .guid_index(guid_key(guid))
+            .map_or(NONE, |index| self.guids.as_slice()[index].first_protocol);
+        core::iter::from_fn(move || {
+            let entry = self.protocols.as_slice().get(next)?;
+            next = entry.next_by_guid;
+            Some((entry.handle + 1) as *mut c_void)
+        })
+    }
+
+    /// Get the interface of the first protocol installed with `guid`
+    pub fn locate(&self, guid: &Guid) -> Option<*mut c_void> {
+        let index = self.guid_index(guid_key(guid))?;
+        let first = self.guids.as_slice()[index].first_protocol;
+        self.protocols
+            .as_slice()
+            .get(first)
+            .map(|entry| entry.interface)
+    }
+
+    /// Install a protocol on an existing handle
+    ///
+    /// Returns `INVALID_PARAMETER` if the handle is unknown or already
+    /// carries the protocol.
+    pub fn install(&mut self, handle: Handle, guid: &Guid, interface: *mut c_void) -> Status {
+        let Some(index) = self.index(handle) else {
+            return Status::INVALID_PARAMETER;
+        };
+
+        // Walk to the end of the list, rejecting duplicates on the way
+        let mut last = NONE;
+        let mut current = self.handles.as_slice()[index].first_protocol;
+        while current != NONE {
+            let entry = &self.protocols.as_slice()[current];
+            if entry.guid == *guid {
+                return Status::INVALID_PARAMETER;
+            }
+            last = current;
+            current = entry.next;
+        }
+
+        let guid_index = match self.insert_guid(guid_key(guid)) {
+            Ok(index) => index,
+            Err(status) => return status,
+        };
+
+        let entry = ProtocolEntry {
+            guid: *guid,
+            interface,
+            handle: index,
+            next: NONE,
+            next_by_guid: NONE,
+        };
+        let slot = if self.free_protocol != NONE {
+            let slot = self.free_protocol;
+            self.free_protocol = self.protocols.as_slice()[slot].next;
+            self.protocols.as_mut_slice()[slot] = entry;
+            slot
+        } else {
+            if let Err(status) = self.protocols.push(entry) {
+                return status;
+            }
+            self.protocols.len() - 1
+        };
+
+        if last == NONE {
+            self.handles.as_mut_slice()[index].first_protocol = slot;
+        } else {
+            self.protocols.as_mut_slice()[last].next = slot;
+        }
+
+        let by_guid = &mut self.guids.as_mut_slice()[guid_index];
+        let last_by_guid = by_guid.last_protocol;
+        by_guid.last_protocol = slot;
+        if last_by_guid == NONE {
+            by_guid.first_protocol = slot;
+        } else {
+            self.protocols.as_mut_slice()[last_by_guid].next_by_guid = slot;
+        }
+        Status::SUCCESS
+    }
+
+    /// Remove a protocol from a handle
+    ///
+    /// Returns `NOT_FOUND` if the handle doesn't carry the protocol.
+    pub fn uninstall(&mut self, handle: Handle, guid: &Guid) -> Status {
+        let Some(index) = self.index(handle) else {
+            return Status::NOT_FOUND;
+        };
+
+        let mut previous = NONE;
+        let mut current = self.handles.as_slice()[index].first_protocol;
+        while current != NONE {
+            let entry = self.protocols.as_slice()[current];
+            if entry.guid == *guid {
+                if previous == NONE {
+                    self.handles.as_mut_slice()[index].first_protocol = entry.next;
+                } else {
+                    self.protocols.as_mut_slice()[previous].next = entry.next;
+                }
+                self.unlink_by_guid(current);
+
+                let freed = &mut self.protocols.as_mut_slice()[current];
+                freed.interface = core::ptr::null_mut();
+                freed.next = self.free_protocol;
+                self.free_protocol = current;
+                return Status::SUCCESS;
+            }
+            previous = current;
+            current = entry.next;
+        }
+
+        Status::NOT_FOUND
+    }
+
+    /// Remove a protocol entry from the list of its GUID
+    fn unlink_by_guid(&mut self, slot: usize) {
+        let entry = self.protocols.as_slice()[slot];
+        let Some(guid_index) = self.guid_index(guid_key(&entry.guid)) else {
+            return;
+        };
+
+        let mut previous = NONE;
+        let mut current = self.guids.as_slice()[guid_index].first_protocol;
+        while current != NONE && current != slot {
+            previous = current;
+            current = self.protocols.as_slice()[current].next_by_guid;
+        }
+        if current == NONE {
+            return;
+        }
+
+        if previous == NONE {
+            self.guids.as_mut_slice()[guid_index].first_protocol = entry.next_by_guid;
+        } else {
+            self.protocols.as_mut_slice()[previous].next_by_guid = entry.next_by_guid;
+        }
+        if entry.next_by_guid == NONE {
+            self.guids.as_mut_slice()[guid_index].last_protocol = previous;
+        }
+    }
+
+    /// Find the index of a GUID in `guids`
+    fn guid_index(&self, key: u128) -> Option<usize> {
+        let buckets = self.buckets.as_slice();
+        if buckets.is_empty() {
+            return None;
+        }
+
+        let mask = buckets.len() - 1;
+        let mut position = hash(key) & mask;
+        loop {
+            let index = buckets[position];
+            if index == NONE {
+                return None;
+            }
+            if self.guids.as_slice()[index].key == key {
+                return Some(index);
+            }
+            position = (position + 1) & mask;
+        }
+    }
+
+    /// Find or add the index of a GUID in `guids`
+    fn insert_guid(&mut self, key: u128) -> Result<usize, Status> {
+        if let Some(index) = self.guid_index(key) {
+            return Ok(index);
+        }
+
+        // Keep the table at most half full so probe sequences stay short
+        if (self.guids.len() + 1) * 2 > self.buckets.len() {
+            self.rehash()?;
+        }
+
+        self.guids.push(GuidEntry {
+            key,
+            first_protocol: NONE,
+            last_protocol: NONE,
+        })?;
+        let index = self.guids.len() - 1;
+        self.place(index);
+        Ok(index)
+    }
+
+    /// Double the number of buckets and re-insert all GUIDs
+    fn rehash(&mut self) -> Result<(), Status> {
+        let size = (self.buckets.len() * 2).max(INITIAL_BUCKETS);
+        let mut buckets = PoolVec::new();
+        for _ in 0..size {
+            buckets.push(NONE)?;
+        }
+
+        self.buckets = buckets;
+        for index in 0..self.guids.len() {
+            self.place(index);
+        }
+        Ok(())
+    }
+
+    /// Put a GUID index in the first free bucket of its probe sequence
+    fn place(&mut self, index: usize) {
+        let key = self.guids.as_slice()[index].key;
+        let buckets = self.buckets.as_mut_slice();
+        let mask = buckets.len() - 1;
+        let mut position = hash(key) & mask;
+        while buckets[position] != NONE {
+            position = (position + 1) & mask;
+        }
+        buckets[position] = index;
+    }
+
+    /// Index of `handle` in the handle array
+    fn index(&self, handle: Handle) -> Option<usize> {
+        let index = (handle as usize).checked_sub(1)?;
+        (index < self.handles.len()).then_some(index)
+    }
+}
+
+impl Default for HandleDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// GUID as a single integer, for hashing and fast comparison
+fn guid_key(guid: &Guid) -> u128 {
+    u128::from_le_bytes(*guid.as_bytes())
+}
+
+/// Hash a GUID key into a bucket position (before masking)
+fn hash(key: u128) -> usize {
+    let folded = (key as u64) ^ ((key >> 64) as u64);
+    (folded.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as usize
+}
+
+/// Iterator over the protocols of a handle
+pub struct Protocols<'a> {
+    entries: &'a [ProtocolEntry],
+    next: usize,
+}
+
+impl<'a> Iterator for Protocols<'a> {
+    type Item = &'a ProtocolEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.entries.get(self.next)?;
+        self.next = entry.next;
+        Some(entry)
+    }
+}
@@ -0,0 +1,112 @@
+//! systemd Boot Loader Interface variables
+//!
+//! `systemd-analyze` splits the boot into firmware, loader, kernel and
+//! userspace time using the `LoaderTimeInitUSec` and `LoaderTimeExecUSec`
+//! variables, and shows the `LoaderInfo` and `LoaderFirmware*` strings.
+//! `bootctl status` lists the menu entries from `LoaderEntries` and
+//! `LoaderEntrySelected`, and systemd finds the ESP through
+//! `LoaderDevicePartUUID`. CrabEFI sets them for its own boot menu, counting
+//! itself as the loader. A systemd-boot started afterwards replaces them
+//! with its own.
+//!
+//! Times are microseconds since reset, from the TSC like systemd-boot's.
+//!
+//! Reference: https://systemd.io/BOOT_LOADER_INTERFACE/
+
+use core::fmt::Write;
+use r_efi::efi::{self, Guid};
+
+use super::runtime_services::write_variable;
+use super::system_table::{CRABEFI_REVISION, EFI_SYSTEM_TABLE_REVISION};
+use crate::menu::{BootEntry, BootMenu};
+use crate::time::{self, rdtsc};
+
+/// Vendor GUID of the Boot Loader Interface variables
+pub const LOADER_VARIABLE_GUID: Guid = Guid::from_fields(
+    0x4a67_b082,
+    0x0a4c,
+    0x41cf,
+    0xb6,
+    0xc7,
+    &[0x44, 0x0b, 0x29, 0xbb, 0x8c, 0x4f],
+);
+
+/// Append a null-terminated UCS-2 string, returning false if it doesn't fit
+fn push_string<const N: usize>(data: &mut heapless::Vec<u8, N>, value: &str) -> bool {
+    value
+        .encode_utf16()
+        .chain([0])
+        .all(|unit| data.extend_from_slice(&unit.to_le_bytes()).is_ok())
+}
+
+/// Set a volatile variable
+fn write_loader_variable(name: &str, data: &[u8]) {
+    let attributes = efi::VARIABLE_BOOTSERVICE_ACCESS | efi::VARIABLE_RUNTIME_ACCESS;
+    let _ = write_variable(name, &LOADER_VARIABLE_GUID, attributes, data);
+}
+
+/// Set a volatile string variable, encoded as null-terminated UCS-2
+fn write_string_variable(name: &str, value: &str) {
+    let mut data: heapless::Vec<u8, 256> = heapless::Vec::new();
+    if push_string(&mut data, value) {
+        write_loader_variable(name, &data);
+    }
+}
+
+/// Set a variable to a decimal number of microseconds
+fn write_time_variable(name: &str, tsc: u64) {
+    let cycles_per_us = (time::tsc_frequency() / 1_000_000).max(1);
+    let mut value: heapless::String<24> = heapless::String::new();
+    let _ = write!(value, "{}", tsc / cycles_per_us);
+    write_string_variable(name, &value);
+}
+
+/// Publish the entries of the boot menu
+pub fn publish_entries(menu: &BootMenu) {
+    let mut data: heapless::Vec<u8, 2048> = heapless::Vec::new();
+    for entry in (0..menu.entry_count()).filter_map(|index| menu.get_entry(index)) {
+        if !push_string(&mut data, &entry.loader_id()) {
+            log::warn!("LoaderEntries: too many entries");
+            break;
+        }
+    }
+    write_loader_variable("LoaderEntries", &data);
+}
+
+/// Publish the entry chosen from the boot menu and the ESP it is on
+pub fn publish_selected(entry: &BootEntry) {
+    write_string_variable("LoaderEntrySelected", &entry.loader_id());
+    write_string_variable("LoaderDevicePartUUID", &entry.partition.partition_uuid());
+
+    let mut path: heapless::String<130> = heapless::String::new();
+    let _ = write!(path, "\\{}", entry.path);
+    write_string_variable("LoaderImageIdentifier", &path);
+}
+
+/// Publish the loader variables, right before starting a boot loader
+pub fn publish() {
+    let mut value: heapless::String<64> = heapless::String::new();
+    let _ = write!(value, "CrabEFI {}", env!("CARGO_PKG_VERSION"));
+    write_string_variable("LoaderInfo", &value);
+
+    value.clear();
+    let _ = write!(
+        value,
+        "CrabEFI {}.{:02}",
+        CRABEFI_REVISION >> 16,
+        CRABEFI_REVISION & 0xFFFF
+    );
+    write_string_variable("LoaderFirmwareInfo", &value);
+
+    value.clear();
+    let _ = write!(
+        value,
+        "UEFI {}.{:02}",
+        EFI_SYSTEM_TABLE_REVISION >> 16,
+        EFI_SYSTEM_TABLE_REVISION & 0xFFFF
+    );
+    write_string_variable("LoaderFirmwareType", &value);
+
+    write_time_variable("LoaderTimeInitUSec", crate::logger::boot_tsc());
+    write_time_variable("LoaderTimeExecUSec", rdtsc());
+}
@@ -0,0 +1,197 @@
+//! EFI Memory Attributes Table
+//!
+//! The memory map only says which regions the OS must keep mapped for
+//! runtime services. The Memory Attributes Table (UEFI 2.10 section 4.6.4)
+//! adds how to map them: Linux maps runtime code read-only and runtime data
+//! non-executable when it finds the table, instead of mapping everything
+//! RWX.
+//!
+//! CrabEFI's own code, `.entry32` and `.text`, is never written after boot
+//! and is described as read-only. Other runtime code has no such guarantee
+//! and keeps plain attributes. All runtime data is execute-protected, which
+//! matches the NX bit `carve_out` sets in its memory map entries.
+//!
+//! The table is installed with the other configuration tables and rebuilt
+//! at ExitBootServices from the final memory map, as the table must cover
+//! every runtime region of the map the OS gets.
+
+use core::ops::Range;
+
+use r_efi::efi::{self, Guid};
+
+use super::allocator::{self, MemoryDescriptor, MemoryType, PAGE_SIZE, attributes};
+use super::cell::EfiCell;
+use super::system_table;
+use crate::state;
+
+/// EFI_MEMORY_ATTRIBUTES_TABLE_GUID
+pub const MEMORY_ATTRIBUTES_TABLE_GUID: Guid = Guid::from_fields(
+    0xdcfa911d,
+    0x26eb,
+    0x469f,
+    0xa2,
+    0x20,
+    &[0x38, 0xb7, 0xdc, 0x46, 0x12, 0x20],
+);
+
+/// Table version, 1 has no flags
+const VERSION: u32 = 1;
+
+/// Most entries the table holds
+const MAX_ENTRIES: usize = 64;
+
+/// EFI_MEMORY_ATTRIBUTES_TABLE followed by its entries
+#[repr(C)]
+struct MemoryAttributesTable {
+    version: u32,
+    number_of_entries: u32,
+    descriptor_size: u32,
+    flags: u32,
+    entries: [MemoryDescriptor; MAX_ENTRIES],
+}
+
+static TABLE: EfiCell<MemoryAttributesTable> = EfiCell::new(MemoryAttributesTable {
+    version: VERSION,
+    number_of_entries: 0,
+    descriptor_size: size_of::<MemoryDescriptor>() as u32,
+    flags: 0,
+    entries: [MemoryDescriptor {
+        memory_type: 0,
+        padding: 0,
+        physical_start: 0,
+        virtual_start: 0,
+        number_of_pages: 0,
+        attribute: 0,
+    }; MAX_ENTRIES],
+});
+
+/// Describe the runtime regions of `map` in `entries`, returning the count
+///
+/// `read_only` is the code that may be mapped read-only. Runtime regions
+/// that don't fit in `entries` are left out.
+pub fn build(
+    map: &[MemoryDescriptor],
+    read_only: Range<u64>,
+    entries: &mut [MemoryDescriptor],
+) -> usize {
+    let mut count = 0;
+    let mut push = |memory_type, start: u64, end: u64, attribute| {
+        if start >= end {
+            return;
+        }
+        match entries.get_mut(count) {
+            Some(entry) => {
+                *entry = MemoryDescriptor::new(
+                    memory_type,
+                    start,
+                    (end - start) / PAGE_SIZE,
+                    attributes::EFI_MEMORY_RUNTIME | attribute,
+                );
+                count += 1;
+            }
+            None => log::warn!("Memory attributes table full, skipping {:#x}", start),
+        }
+    };
+
+    for entry in map {
+        let (start, end) = (entry.physical_start, entry.end());
+        match entry.get_memory_type() {
+            Some(MemoryType::RuntimeServicesCode) => {
+                let ro_start = read_only.start.clamp(start, end);
+                let ro_end = read_only.end.clamp(ro_start, end);
+                let code = MemoryType::RuntimeServicesCode;
+                push(code, start, ro_start, 0);
+                push(code, ro_start, ro_end, attributes::EFI_MEMORY_RO);
+                push(code, ro_end, end, 0);
+            }
+            Some(MemoryType::RuntimeServicesData) => push(
+                MemoryType::RuntimeServicesData,
+                start,
+                end,
+                attributes::EFI_MEMORY_XP,
+            ),
+            _ => {}
+        }
+    }
+    count
+}
+
+/// Rebuild the table from the current memory map
+pub fn update() {
+    let map = state::allocator().entries();
+    let code = allocator::runtime_code_range();
+    TABLE.with(|table| {
+        let count = build(map, code, &mut table.entries);
+        table.number_of_entries = count as u32;
+    });
+}
+
+/// Build the table and install it
+pub fn publish() {
+    update();
+    let status = system_table::install_configuration_table(
+        &MEMORY_ATTRIBUTES_TABLE_GUID,
+        TABLE.as_ptr() as *mut core::ffi::c_void,
+    );
+    if status != efi::Status::SUCCESS {
+        log::warn!("Failed to install memory attributes table: {:?}", status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_runtime_regions() {
+        let map = [
+            MemoryDescriptor::new(MemoryType::ConventionalMemory, 0, 0x100, 0),
+            // CrabEFI's code merged with a runtime driver after it
+            MemoryDescriptor::new(MemoryType::RuntimeServicesCode, 0x10_0000, 0x30, 0),
+            MemoryDescriptor::new(MemoryType::RuntimeServicesData, 0x13_0000, 0x10, 0),
+            MemoryDescriptor::new(MemoryType::BootServicesData, 0x14_0000, 0x10, 0),
+        ];
+        let mut entries = [MemoryDescriptor::new(MemoryType::ReservedMemoryType, 0, 0, 0); 4];
+        let count = build(&map, 0x10_0000..0x12_0000, &mut entries);
+        assert_eq!(count, 3);
+
+        let runtime = attributes::EFI_MEMORY_RUNTIME;
+        let described: std::vec::Vec<_> = entries[..count]
+            .iter()
+            .map(|e| {
+                (
+                    e.memory_type,
+                    e.physical_start,
+                    e.number_of_pages,
+                    e.attribute,
+                )
+            })
+            .collect();
+        assert_eq!(
+            described,
+            [
+                (
+                    MemoryType::RuntimeServicesCode as u32,
+                    0x10_0000,
+                    0x20,
+                    runtime | attributes::EFI_MEMORY_RO
+                ),
+                (
+                    MemoryType::RuntimeServicesCode as u32,
+                    0x12_0000,
+                    0x10,
+                    runtime
+                ),
+                (
+                    MemoryType::RuntimeServicesData as u32,
+                    0x13_0000,
+                    0x10,
+                    runtime | attributes::EFI_MEMORY_XP
+                ),
+            ]
+        );
+
+        // Regions past the end of the table are dropped
+        assert_eq!(build(&map, 0..0, &mut entries[..1]), 1);
+    }
+}
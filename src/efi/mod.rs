@@ -3,12 +3,27 @@
 //! This module provides the UEFI system table, boot services, and runtime services
 //! implementations.
 
+#[cfg(feature = "alloc-track")]
+pub mod alloc_track;
 pub mod allocator;
+#[cfg(feature = "audit")]
+pub mod audit;
 pub mod boot_services;
+pub mod capabilities;
+pub mod cell;
+pub mod driver_quirks;
+pub mod firmware_info;
+pub mod handles;
+pub mod loader_interface;
+pub mod memory_attributes_table;
+pub mod pool;
+pub mod pool_guard;
 pub mod protocols;
 pub mod runtime_services;
 pub mod system_table;
 pub mod utils;
+pub mod variable_policy;
+pub mod variable_store;
 
 use crate::coreboot::tables::CorebootInfo;
 use r_efi::efi::{self, Status};
@@ -23,12 +38,32 @@ pub fn init(cb_info: &CorebootInfo) {
     // Initialize the memory allocator from coreboot memory map
     allocator::init(&cb_info.memory_map);
 
+    // Keep the firmware's and loaders' allocations out of memory the SRAT
+    // says can be unplugged, so the OS can still offline it
+    if let Some(srat) = cb_info
+        .acpi_rsdp
+        .and_then(|rsdp| unsafe { crate::acpi::find_table(rsdp, b"SRAT") })
+    {
+        let ranges = crate::acpi::srat_hot_pluggable(unsafe { crate::acpi::table_bytes(srat) });
+        allocator::mark_hot_pluggable(&ranges);
+    }
+
     // Reserve the runtime services memory regions using linker-provided boundaries.
     // This marks CrabEFI's code and data sections as EfiRuntimeServicesCode/Data
     // with EFI_MEMORY_RUNTIME attribute, which tells the OS to keep these regions
     // mapped after ExitBootServices.
     allocator::reserve_runtime_region();
 
+    // Non-volatile variables saved on previous boots
+    variable_store::load();
+    boot_services::init_monotonic_count();
+
+    // They may change the screen rotation, font scale and keyboard layout
+    if let Some(ref fb) = cb_info.framebuffer {
+        crate::display::init(fb);
+    }
+    crate::drivers::keyboard_layout::init();
+
     // Initialize system table with boot and runtime services
     unsafe {
         system_table::init(
@@ -37,6 +72,13 @@ pub fn init(cb_info: &CorebootInfo) {
         );
     }
 
+    // Log the services loaded images call
+    #[cfg(feature = "audit")]
+    audit::install();
+
+    // Name CrabEFI and the coreboot build in the system table
+    firmware_info::publish(cb_info);
+
     // Install ACPI tables if available
     if let Some(rsdp) = cb_info.acpi_rsdp {
         system_table::install_acpi_tables(rsdp);
@@ -44,6 +86,12 @@ pub fn init(cb_info: &CorebootInfo) {
         log::warn!("No ACPI RSDP from coreboot - Linux may not have ACPI support!");
     }
 
+    // Extra SSDTs from CBFS
+    crate::ssdt::init(cb_info.acpi_rsdp);
+
+    // Disk images in CBFS, published in the NFIT
+    crate::drivers::ramdisk::init(cb_info.acpi_rsdp);
+
     // Install SMBIOS tables if available
     if let Some(smbios) = cb_info.smbios {
         system_table::install_smbios_tables(smbios);
@@ -51,6 +99,9 @@ pub fn init(cb_info: &CorebootInfo) {
         log::debug!("No SMBIOS tables from coreboot");
     }
 
+    // Boot logo and its BGRT
+    crate::splash::init(cb_info.framebuffer.as_ref(), cb_info.acpi_rsdp);
+
     // Create console handle - this will also have GOP installed on it
     let console_handle = init_console();
 
@@ -67,18 +118,45 @@ pub fn init(cb_info: &CorebootInfo) {
     // Install Unicode Collation protocol
     init_unicode_collation();
 
+    // Install HII Database and HII String protocols
+    init_hii();
+
     // Install Memory Attribute protocol
     init_memory_attribute();
 
     // Install Serial IO protocol
     init_serial_io();
 
+    // Install RAM Disk protocol
+    init_ram_disk();
+
+    // Start the APs and install MP Services protocol
+    protocols::mp_services::init(cb_info.acpi_rsdp);
+    init_mp_services();
+
     // Install Console Control protocol (legacy, but some bootloaders need it)
     init_console_control();
 
+    // Install the Security protocols LoadImage consults
+    init_security();
+
+    // Variables boot loaders expect the platform to provide
+    runtime_services::publish_global_variables();
+
+    // Tell the OS which runtime services work
+    runtime_services::publish_properties_table();
+
+    // Let the OS collect the firmware log
+    crate::log_buffer::publish();
+
+    // Page permissions for the runtime regions
+    memory_attributes_table::publish();
+
     // Dump configuration tables for debugging
     system_table::dump_configuration_tables();
 
+    capabilities::log_report();
+
     log::info!("EFI environment initialized");
 }
 
@@ -86,7 +164,8 @@ pub fn init(cb_info: &CorebootInfo) {
 /// Returns the console handle so GOP can be installed on it
 fn init_console() -> Option<efi::Handle> {
     use protocols::console::{
-        SIMPLE_TEXT_INPUT_PROTOCOL_GUID, SIMPLE_TEXT_OUTPUT_PROTOCOL_GUID, get_text_input_protocol,
+        SIMPLE_TEXT_INPUT_EX_PROTOCOL_GUID, SIMPLE_TEXT_INPUT_PROTOCOL_GUID,
+        SIMPLE_TEXT_OUTPUT_PROTOCOL_GUID, get_text_input_ex_protocol, get_text_input_protocol,
         get_text_output_protocol,
     };
     use protocols::device_path::{DEVICE_PATH_PROTOCOL_GUID, create_video_device_path};
@@ -124,6 +203,19 @@ fn init_console() -> Option<efi::Handle> {
         log::error!("Failed to install text input protocol: {:?}", status);
     }
 
+    // Install extended text input protocol
+    let status = boot_services::install_protocol(
+        console_handle,
+        &SIMPLE_TEXT_INPUT_EX_PROTOCOL_GUID,
+        get_text_input_ex_protocol() as *mut core::ffi::c_void,
+    );
+    if status != Status::SUCCESS {
+        log::error!(
+            "Failed to install extended text input protocol: {:?}",
+            status
+        );
+    }
+
     // Install text output protocol
     let output_protocol = get_text_output_protocol();
     let status = boot_services::install_protocol(
@@ -185,6 +277,39 @@ fn init_unicode_collation() {
     log::debug!("Unicode Collation protocols installed");
 }
 
+/// Initialize HII Database and HII String protocols
+fn init_hii() {
+    use protocols::hii::{
+        HII_DATABASE_PROTOCOL_GUID, HII_STRING_PROTOCOL_GUID, get_database_protocol,
+        get_string_protocol,
+    };
+
+    let handle = match boot_services::create_handle() {
+        Some(h) => h,
+        None => {
+            log::error!("Failed to create HII handle");
+            return;
+        }
+    };
+
+    let status = boot_services::install_protocol(
+        handle,
+        &HII_DATABASE_PROTOCOL_GUID,
+        get_database_protocol(),
+    );
+    if status != Status::SUCCESS {
+        log::error!("Failed to install HII Database protocol: {:?}", status);
+    }
+
+    let status =
+        boot_services::install_protocol(handle, &HII_STRING_PROTOCOL_GUID, get_string_protocol());
+    if status != Status::SUCCESS {
+        log::error!("Failed to install HII String protocol: {:?}", status);
+    }
+
+    log::debug!("HII protocols installed on handle {:?}", handle);
+}
+
 /// Initialize Memory Attribute protocol
 fn init_memory_attribute() {
     use protocols::memory_attribute::{MEMORY_ATTRIBUTE_PROTOCOL_GUID, create_protocol};
@@ -218,6 +343,39 @@ fn init_memory_attribute() {
     log::debug!("Memory Attribute protocol installed on handle {:?}", handle);
 }
 
+/// Initialize MP Services protocol
+fn init_mp_services() {
+    use protocols::mp_services::{MP_SERVICES_PROTOCOL_GUID, create_protocol};
+
+    // Create a handle for MP Services protocol
+    let handle = match boot_services::create_handle() {
+        Some(h) => h,
+        None => {
+            log::error!("Failed to create MP Services handle");
+            return;
+        }
+    };
+
+    // Create and install the protocol
+    let protocol = create_protocol();
+    if protocol.is_null() {
+        log::error!("Failed to create MP Services protocol");
+        return;
+    }
+
+    let status = boot_services::install_protocol(
+        handle,
+        &MP_SERVICES_PROTOCOL_GUID,
+        protocol as *mut core::ffi::c_void,
+    );
+    if status != Status::SUCCESS {
+        log::error!("Failed to install MP Services protocol: {:?}", status);
+        return;
+    }
+
+    log::debug!("MP Services protocol installed on handle {:?}", handle);
+}
+
 /// Initialize Serial IO protocol
 fn init_serial_io() {
     use protocols::serial_io::{SERIAL_IO_PROTOCOL_GUID, create_protocol};
@@ -249,6 +407,42 @@ fn init_serial_io() {
     }
 
     log::debug!("Serial IO protocol installed on handle {:?}", handle);
+
+    // The Debug Port shares the UART with Serial IO
+    let debug_port = protocols::debug_port::create_protocol();
+    if debug_port.is_null() {
+        return;
+    }
+    let status = boot_services::install_protocol(
+        handle,
+        &protocols::debug_port::DEBUG_PORT_PROTOCOL_GUID,
+        debug_port as *mut core::ffi::c_void,
+    );
+    if status != Status::SUCCESS {
+        log::error!("Failed to install Debug Port protocol: {:?}", status);
+    }
+}
+
+/// Initialize RAM Disk protocol
+fn init_ram_disk() {
+    use protocols::ram_disk::{RAM_DISK_PROTOCOL_GUID, get_protocol};
+
+    let Some(handle) = boot_services::create_handle() else {
+        log::error!("Failed to create RAM Disk handle");
+        return;
+    };
+
+    let status = boot_services::install_protocol(
+        handle,
+        &RAM_DISK_PROTOCOL_GUID,
+        get_protocol() as *mut core::ffi::c_void,
+    );
+    if status != Status::SUCCESS {
+        log::error!("Failed to install RAM Disk protocol: {:?}", status);
+        return;
+    }
+
+    log::debug!("RAM Disk protocol installed on handle {:?}", handle);
 }
 
 /// Initialize Console Control protocol (legacy Intel EFI protocol)
@@ -280,6 +474,42 @@ fn init_console_control() {
     log::debug!("Console Control protocol installed on handle {:?}", handle);
 }
 
+/// Initialize the Security and Security2 Architectural protocols
+fn init_security() {
+    use protocols::security::{
+        SECURITY_ARCH_PROTOCOL_GUID, SECURITY2_ARCH_PROTOCOL_GUID, get_security_protocol,
+        get_security2_protocol,
+    };
+
+    let handle = match boot_services::create_handle() {
+        Some(h) => h,
+        None => {
+            log::error!("Failed to create Security handle");
+            return;
+        }
+    };
+
+    let status = boot_services::install_protocol(
+        handle,
+        &SECURITY_ARCH_PROTOCOL_GUID,
+        get_security_protocol(),
+    );
+    if status != Status::SUCCESS {
+        log::error!("Failed to install Security protocol: {:?}", status);
+    }
+
+    let status = boot_services::install_protocol(
+        handle,
+        &SECURITY2_ARCH_PROTOCOL_GUID,
+        get_security2_protocol(),
+    );
+    if status != Status::SUCCESS {
+        log::error!("Failed to install Security2 protocol: {:?}", status);
+    }
+
+    log::debug!("Security protocols installed on handle {:?}", handle);
+}
+
 /// Initialize Graphics Output Protocol (GOP) on a specific handle
 /// Installing GOP on the same handle as ConOut is important for GRUB compatibility
 fn init_graphics_output_on_handle(
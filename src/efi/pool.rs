@@ -0,0 +1,281 @@
+//! Slab allocator for small pool allocations
+//!
+//! AllocatePool used to give every allocation pages of its own, so the
+//! handles, device paths and protocol structures boot loaders and drivers
+//! allocate by the thousand wasted most of a 4KB page each and fragmented
+//! the memory map over a long GRUB session. Allocations of up to
+//! [`MAX_BLOCK_SIZE`] bytes now come from slab pages: each page holds blocks
+//! of one size class for one memory type, so the memory map still shows
+//! pool memory with the type it was allocated as.
+//!
+//! A slab page starts with a [`SlabHeader`], and a free list threads through
+//! its free blocks. Pages with free blocks are kept on a list per memory
+//! type and size class, and a page goes back to the page allocator once its
+//! last block is freed. Larger allocations still get pages of their own.
+
+use r_efi::efi;
+use spin::Mutex;
+
+use super::allocator::{self, MemoryType, PAGE_SIZE};
+
+/// Block sizes of the size classes
+const BLOCK_SIZES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+
+/// Largest allocation served from slabs
+pub const MAX_BLOCK_SIZE: usize = BLOCK_SIZES[BLOCK_SIZES.len() - 1];
+
+/// Memory types with slabs: the types defined by the spec
+const MEMORY_TYPES: usize = 16;
+
+/// Magic number of a slab page
+const SLAB_MAGIC: u64 = 0x534C_4142_5041_4745; // "SLABPAGE"
+
+/// Header at the start of each slab page
+#[repr(C)]
+struct SlabHeader {
+    magic: u64,
+    /// Next page of the same type and class with free blocks, 0 if none
+    next: u64,
+    /// First free block, 0 if the page is full
+    free: u64,
+    memory_type: u32,
+    class: u16,
+    /// Blocks in use
+    used: u16,
+}
+
+/// Offset of the first block, keeping blocks 16-byte aligned
+const FIRST_BLOCK: usize = size_of::<SlabHeader>().next_multiple_of(16);
+
+/// Pages with free blocks, per memory type and size class
+struct Slabs {
+    partial: [[u64; BLOCK_SIZES.len()]; MEMORY_TYPES],
+}
+
+static SLABS: Mutex<Slabs> = Mutex::new(Slabs::new());
+
+/// Size class of a `size` byte allocation of `memory_type`
+///
+/// None if the allocation is too large for slabs or of an OEM or OS type.
+pub fn size_class(memory_type: MemoryType, size: usize) -> Option<usize> {
+    if memory_type as usize >= MEMORY_TYPES {
+        return None;
+    }
+    BLOCK_SIZES.iter().position(|&block| size <= block)
+}
+
+impl Slabs {
+    const fn new() -> Self {
+        Slabs {
+            partial: [[0; BLOCK_SIZES.len()]; MEMORY_TYPES],
+        }
+    }
+
+    /// Take a block of `class` for `memory_type`
+    ///
+    /// Adds a page from `allocate_page` when no page has a free block.
+    fn allocate(
+        &mut self,
+        memory_type: MemoryType,
+        class: usize,
+        allocate_page: impl FnOnce(MemoryType) -> Option<u64>,
+    ) -> Option<*mut u8> {
+        let head = &mut self.partial[memory_type as usize][class];
+        if *head == 0 {
+            *head = new_slab(allocate_page(memory_type)?, memory_type, class);
+        }
+
+        let header = unsafe { &mut *(*head as *mut SlabHeader) };
+        let block = header.free;
+        header.free = unsafe { *(block as *const u64) };
+        header.used += 1;
+        if header.free == 0 {
+            // Full pages leave the list until a block is freed
+            *head = header.next;
+            header.next = 0;
+        }
+        Some(block as *mut u8)
+    }
+
+    /// Return a block to its page
+    ///
+    /// Passes the page to `free_page` once none of its blocks is in use.
+    fn free(&mut self, buffer: *mut u8, free_page: impl FnOnce(u64)) -> efi::Status {
+        let Some(page) = slab_page(buffer) else {
+            return efi::Status::INVALID_PARAMETER;
+        };
+        let header = unsafe { &mut *(page as *mut SlabHeader) };
+        let offset = buffer as usize - page as usize;
+        let block_size = BLOCK_SIZES[header.class as usize];
+        if offset < FIRST_BLOCK
+            || !(offset - FIRST_BLOCK).is_multiple_of(block_size)
+            || header.used == 0
+        {
+            return efi::Status::INVALID_PARAMETER;
+        }
+
+        let head = &mut self.partial[header.memory_type as usize][header.class as usize];
+        let was_full = header.free == 0;
+        unsafe { *(buffer as *mut u64) = header.free };
+        header.free = buffer as u64;
+        header.used -= 1;
+        if was_full {
+            header.next = *head;
+            *head = page;
+        }
+
+        if header.used == 0 {
+            unlink(head, page);
+            header.magic = 0;
+            free_page(page);
+        }
+        efi::Status::SUCCESS
+    }
+}
+
+/// Set up `page` as a slab of `class` blocks, all free
+fn new_slab(page: u64, memory_type: MemoryType, class: usize) -> u64 {
+    let block_size = BLOCK_SIZES[class];
+    let blocks = (PAGE_SIZE as usize - FIRST_BLOCK) / block_size;
+
+    // Thread the free list through the blocks in address order
+    let mut free = 0;
+    for i in (0..blocks).rev() {
+        let block = page + (FIRST_BLOCK + i * block_size) as u64;
+        unsafe { *(block as *mut u64) = free };
+        free = block;
+    }
+
+    let header = SlabHeader {
+        magic: SLAB_MAGIC,
+        next: 0,
+        free,
+        memory_type: memory_type as u32,
+        class: class as u16,
+        used: 0,
+    };
+    unsafe { core::ptr::write(page as *mut SlabHeader, header) };
+    page
+}
+
+/// Remove `page` from the list starting at `head`
+fn unlink(head: &mut u64, page: u64) {
+    let mut link = head;
+    while *link != 0 {
+        let header = unsafe { &mut *(*link as *mut SlabHeader) };
+        if *link == page {
+            *link = header.next;
+            return;
+        }
+        link = &mut header.next;
+    }
+}
+
+/// The slab page holding `buffer`, if it is one
+fn slab_page(buffer: *mut u8) -> Option<u64> {
+    let page = buffer as u64 & !(PAGE_SIZE - 1);
+    let header = unsafe { &*(page as *const SlabHeader) };
+    (header.magic == SLAB_MAGIC
+        && (header.memory_type as usize) < MEMORY_TYPES
+        && (header.class as usize) < BLOCK_SIZES.len())
+    .then_some(page)
+}
+
+/// Whether `buffer` lies in a slab page
+pub fn is_slab_block(buffer: *mut u8) -> bool {
+    slab_page(buffer).is_some()
+}
+
+/// Allocate a block of size class `class`
+pub fn allocate(memory_type: MemoryType, class: usize) -> Result<*mut u8, efi::Status> {
+    SLABS
+        .lock()
+        .allocate(memory_type, class, |memory_type| {
+            allocator::allocate_pool_pages(memory_type, 1).ok()
+        })
+        .ok_or(efi::Status::OUT_OF_RESOURCES)
+}
+
+/// Free a block allocated from a slab
+pub fn free(buffer: *mut u8) -> efi::Status {
+    SLABS.lock().free(buffer, |page| {
+        let status = allocator::free_pool_pages(page, 1);
+        if status != efi::Status::SUCCESS {
+            log::warn!("Failed to free slab page {:#x}: {:?}", page, status);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C, align(4096))]
+    struct Page([u8; PAGE_SIZE as usize]);
+
+    fn page(_: MemoryType) -> Option<u64> {
+        Some(
+            std::boxed::Box::leak(std::boxed::Box::new(Page([0; PAGE_SIZE as usize]))) as *mut _
+                as u64,
+        )
+    }
+
+    #[test]
+    fn picks_size_classes() {
+        assert_eq!(size_class(MemoryType::BootServicesData, 1), Some(0));
+        assert_eq!(size_class(MemoryType::LoaderData, 17), Some(1));
+        assert_eq!(size_class(MemoryType::LoaderData, MAX_BLOCK_SIZE), Some(6));
+        assert_eq!(size_class(MemoryType::LoaderData, MAX_BLOCK_SIZE + 1), None);
+    }
+
+    #[test]
+    fn fills_and_releases_pages() {
+        let mut slabs = Slabs::new();
+        let class = 6;
+        let per_page = (PAGE_SIZE as usize - FIRST_BLOCK) / BLOCK_SIZES[class];
+
+        let mut blocks = std::vec::Vec::new();
+        for _ in 0..per_page + 1 {
+            let block = slabs.allocate(MemoryType::LoaderData, class, page).unwrap();
+            assert_eq!(block as usize % 16, 0);
+            assert!(is_slab_block(block));
+            blocks.push(block);
+        }
+        let first_page = blocks[0] as u64 & !(PAGE_SIZE - 1);
+        assert!(
+            blocks[..per_page]
+                .iter()
+                .all(|&b| b as u64 & !(PAGE_SIZE - 1) == first_page)
+        );
+        assert_ne!(blocks[per_page] as u64 & !(PAGE_SIZE - 1), first_page);
+
+        // Other memory types never share a page
+        let other = slabs
+            .allocate(MemoryType::BootServicesData, class, page)
+            .unwrap();
+        assert!(blocks.iter().all(|&b| b as u64 >> 12 != other as u64 >> 12));
+
+        // A freed block is handed out again
+        assert_eq!(slabs.free(blocks[1], |_| panic!()), efi::Status::SUCCESS);
+        let again = slabs.allocate(MemoryType::LoaderData, class, |_| None);
+        assert_eq!(again, Some(blocks[1]));
+
+        // Misaligned pointers are rejected
+        let inside = unsafe { blocks[0].add(8) };
+        assert_eq!(
+            slabs.free(inside, |_| panic!()),
+            efi::Status::INVALID_PARAMETER
+        );
+
+        // The page goes back once empty
+        let mut released = std::vec::Vec::new();
+        for &block in &blocks[..per_page] {
+            assert_eq!(
+                slabs.free(block, |p| released.push(p)),
+                efi::Status::SUCCESS
+            );
+        }
+        assert_eq!(released, [first_page]);
+        assert!(!is_slab_block(blocks[0]));
+    }
+}
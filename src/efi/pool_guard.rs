@@ -0,0 +1,211 @@
+//! Guarded pool allocations (`alloc-guard` feature)
+//!
+//! Memory corruption in a boot loader or driver usually shows up much later
+//! as a hang that is hard to trace back. With the `alloc-guard` feature,
+//! AllocatePool and FreePool go through this module instead, which makes
+//! such bugs fail where they happen:
+//!
+//! - Each allocation gets pages of its own between two unmapped guard
+//!   pages, with the buffer placed against the trailing guard page, so an
+//!   overrun page faults on the first byte past the buffer (rounded up to
+//!   8 bytes for alignment; those padding bytes are checked on free).
+//! - FreePool validates the header in front of the buffer, reporting double
+//!   frees, frees of pointers AllocatePool didn't return and underruns
+//!   that overwrote the header.
+//! - Freed buffers are filled with [`FREED_POISON`], so a use after free
+//!   reads an obviously bogus value instead of stale data.
+//!
+//! A guard page hit shows up as a not-present page fault in the crash dump.
+//! This costs two extra pages per allocation and splits the identity
+//! mapping into 4KB pages, so it is for debugging only.
+
+use r_efi::efi;
+
+use crate::arch::x86_64::paging;
+use crate::efi::allocator::{self, AllocateType, MemoryType, PAGE_SIZE};
+
+/// Magic number of a live allocation
+const MAGIC: u64 = 0x4755_4152_4448_4452; // "GUARDHDR"
+
+/// Magic number left in the header of a freed allocation
+const FREED_MAGIC: u64 = 0x4652_4545_4448_4452; // "FREEDHDR"
+
+/// Byte pattern filling freed buffers
+///
+/// Same value as EDK2's DEBUG_CLEAR_MEMORY_VALUE, so it is recognisable to
+/// anyone who has debugged EDK2.
+pub const FREED_POISON: u8 = 0xAF;
+
+/// Byte pattern filling the padding between a buffer and its guard page
+const PADDING_CANARY: u8 = 0x5A;
+
+/// Alignment of returned buffers, as the UEFI specification requires
+const POOL_ALIGNMENT: usize = 8;
+
+/// Header in front of a guarded buffer
+///
+/// The magic number comes last, right in front of the buffer, so an
+/// underrun corrupts it first.
+#[repr(C)]
+struct GuardHeader {
+    /// First page of the allocation, a guard page
+    base: u64,
+    /// Pages allocated, including both guard pages
+    num_pages: u64,
+    /// Size requested
+    size: u64,
+    magic: u64,
+}
+
+/// Where a buffer of `size` bytes goes in an allocation at `base`
+///
+/// Returns the number of pages including guard pages and the buffer address.
+fn layout(base: u64, size: usize) -> Option<(u64, u64)> {
+    let total = size.checked_add(size_of::<GuardHeader>() + POOL_ALIGNMENT)? as u64;
+    let num_pages = total.div_ceil(PAGE_SIZE).checked_add(2)?;
+    let trailing_guard = base + (num_pages - 1) * PAGE_SIZE;
+    let buffer = (trailing_guard - size as u64) & !(POOL_ALIGNMENT as u64 - 1);
+    Some((num_pages, buffer))
+}
+
+/// Allocate a zeroed page table page for splitting the identity mapping
+pub fn allocate_page_table() -> Option<u64> {
+    let mut addr = 0;
+    let status = allocator::allocate_pages(
+        AllocateType::AllocateAnyPages,
+        MemoryType::BootServicesData,
+        1,
+        &mut addr,
+    );
+    if status != efi::Status::SUCCESS {
+        return None;
+    }
+    unsafe { core::ptr::write_bytes(addr as *mut u8, 0, PAGE_SIZE as usize) };
+    Some(addr)
+}
+
+/// Map or unmap the guard pages of an allocation
+fn set_guards_present(base: u64, num_pages: u64, present: bool) {
+    for page in [base, base + (num_pages - 1) * PAGE_SIZE] {
+        if !paging::set_page_present(page, present, allocate_page_table) {
+            log::warn!(
+                "alloc-guard: can't change mapping of guard page {:#x}",
+                page
+            );
+        }
+    }
+}
+
+/// Whether a page fault at `address` hit a guard page
+///
+/// Guard pages are the only holes this firmware makes in its identity
+/// mapping.
+pub fn is_guard_page(address: u64) -> bool {
+    cfg!(feature = "alloc-guard")
+        && address < allocator::max_identity_mapped_address()
+        && !paging::is_mapped(address)
+}
+
+/// AllocatePool with guard pages
+pub fn allocate(memory_type: MemoryType, size: usize) -> Result<*mut u8, efi::Status> {
+    let (num_pages, _) = layout(0, size).ok_or(efi::Status::OUT_OF_RESOURCES)?;
+    let base = allocator::allocate_pool_pages(memory_type, num_pages)?;
+    let (_, buffer) = layout(base, size).ok_or(efi::Status::OUT_OF_RESOURCES)?;
+
+    let header = (buffer as *mut GuardHeader).wrapping_sub(1);
+    let padding_start = buffer + size as u64;
+    let padding_len = (base + (num_pages - 1) * PAGE_SIZE - padding_start) as usize;
+    unsafe {
+        header.write(GuardHeader {
+            base,
+            num_pages,
+            size: size as u64,
+            magic: MAGIC,
+        });
+        core::ptr::write_bytes(padding_start as *mut u8, PADDING_CANARY, padding_len);
+    }
+
+    set_guards_present(base, num_pages, false);
+    Ok(buffer as *mut u8)
+}
+
+/// FreePool checking the header and padding, poisoning the buffer
+pub fn free(buffer: *mut u8) -> efi::Status {
+    let address = buffer as u64;
+    if !address.is_multiple_of(POOL_ALIGNMENT as u64) {
+        log::error!("alloc-guard: FreePool of misaligned pointer {:#x}", address);
+        return efi::Status::INVALID_PARAMETER;
+    }
+
+    let header_ptr = (address as *mut GuardHeader).wrapping_sub(1);
+    let header = unsafe { header_ptr.read() };
+    match header.magic {
+        MAGIC => {}
+        FREED_MAGIC => {
+            log::error!("alloc-guard: double free of pool buffer {:#x}", address);
+            return efi::Status::INVALID_PARAMETER;
+        }
+        magic => {
+            log::error!(
+                "alloc-guard: FreePool of {:#x}: bad header magic {:#x} (underrun or not from AllocatePool)",
+                address,
+                magic
+            );
+            return efi::Status::INVALID_PARAMETER;
+        }
+    }
+    let size = header.size as usize;
+    if layout(header.base, size) != Some((header.num_pages, address)) {
+        log::error!(
+            "alloc-guard: FreePool of {:#x}: corrupted header (base {:#x}, {} pages, size {})",
+            address,
+            header.base,
+            header.num_pages,
+            size
+        );
+        return efi::Status::INVALID_PARAMETER;
+    }
+
+    let trailing_guard = header.base + (header.num_pages - 1) * PAGE_SIZE;
+    let padding = unsafe {
+        core::slice::from_raw_parts(
+            (address + size as u64) as *const u8,
+            (trailing_guard - address) as usize - size,
+        )
+    };
+    if let Some(offset) = padding.iter().position(|&byte| byte != PADDING_CANARY) {
+        log::error!(
+            "alloc-guard: pool buffer {:#x} ({} bytes) overrun at byte {}",
+            address,
+            size,
+            size + offset
+        );
+    }
+
+    unsafe {
+        core::ptr::write_bytes(address as *mut u8, FREED_POISON, size);
+        (*header_ptr).magic = FREED_MAGIC;
+    }
+    set_guards_present(header.base, header.num_pages, true);
+    allocator::free_pool_pages(header.base, header.num_pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_ends_at_trailing_guard() {
+        let base = 0x10_0000;
+        for size in [1, 7, 8, 4000, 4096, 10000] {
+            let (num_pages, buffer) = layout(base, size).unwrap();
+            let trailing_guard = base + (num_pages - 1) * PAGE_SIZE;
+            assert_eq!(buffer % POOL_ALIGNMENT as u64, 0);
+            assert!(trailing_guard - (buffer + size as u64) < POOL_ALIGNMENT as u64);
+            // Header and buffer clear of the leading guard page
+            assert!(buffer - size_of::<GuardHeader>() as u64 >= base + PAGE_SIZE);
+        }
+        assert_eq!(layout(base, 4096).unwrap().0, 4);
+        assert!(layout(base, usize::MAX).is_none());
+    }
+}
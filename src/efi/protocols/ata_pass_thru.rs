@@ -13,6 +13,7 @@ use r_efi::efi::{Event, Guid, Status};
 use r_efi::protocols::device_path::Protocol as DevicePathProtocol;
 
 use crate::drivers::ahci;
+use crate::efi::cell::ContextTable;
 use crate::efi::protocols::device_path::{self, SataDevicePathNode};
 use crate::efi::utils::allocate_protocol_with_log;
 
@@ -231,6 +232,7 @@ pub struct AtaPassThruProtocol {
 }
 
 /// Internal context for ATA Pass Thru protocol instance
+#[derive(Clone, Copy)]
 struct AtaPassThruContext {
     /// Controller index in the global controller list
     controller_index: usize,
@@ -243,36 +245,9 @@ struct AtaPassThruContext {
 /// Maximum number of ATA Pass Thru protocol instances
 const MAX_INSTANCES: usize = 8;
 
-/// Global storage for contexts
-static mut CONTEXTS: [Option<AtaPassThruContext>; MAX_INSTANCES] = [const { None }; MAX_INSTANCES];
-
-/// Protocol instance to context mapping
-static mut PROTOCOL_TO_CONTEXT: [Option<*mut AtaPassThruProtocol>; MAX_INSTANCES] =
-    [const { None }; MAX_INSTANCES];
-
-/// Find context index for a protocol instance
-fn find_context_index(protocol: *mut AtaPassThruProtocol) -> Option<usize> {
-    unsafe {
-        let proto_map = core::ptr::addr_of!(PROTOCOL_TO_CONTEXT);
-        for (i, p) in (*proto_map).iter().enumerate() {
-            if let Some(ptr) = p
-                && *ptr == protocol
-            {
-                return Some(i);
-            }
-        }
-    }
-    None
-}
-
-/// Get context for a protocol instance
-fn get_context(protocol: *mut AtaPassThruProtocol) -> Option<&'static AtaPassThruContext> {
-    let idx = find_context_index(protocol)?;
-    unsafe {
-        let contexts = core::ptr::addr_of!(CONTEXTS);
-        (*contexts)[idx].as_ref()
-    }
-}
+/// Contexts of the protocol instances
+static CONTEXTS: ContextTable<AtaPassThruProtocol, AtaPassThruContext, MAX_INSTANCES> =
+    ContextTable::new();
 
 // ============================================================================
 // Protocol Functions
@@ -290,7 +265,7 @@ extern "efiapi" fn ata_pass_thru(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx = match get_context(this) {
+    let ctx = match CONTEXTS.get(this) {
         Some(c) => c,
         None => {
             log::error!("AtaPassThru.PassThru: unknown protocol instance");
@@ -432,7 +407,7 @@ extern "efiapi" fn ata_get_next_port(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx = match get_context(this) {
+    let ctx = match CONTEXTS.get(this) {
         Some(c) => c,
         None => {
             log::error!("AtaPassThru.GetNextPort: unknown protocol instance");
@@ -493,7 +468,7 @@ extern "efiapi" fn ata_get_next_device(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx = match get_context(this) {
+    let ctx = match CONTEXTS.get(this) {
         Some(c) => c,
         None => {
             log::error!("AtaPassThru.GetNextDevice: unknown protocol instance");
@@ -547,7 +522,7 @@ extern "efiapi" fn ata_build_device_path(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx = match get_context(this) {
+    let ctx = match CONTEXTS.get(this) {
         Some(c) => c,
         None => {
             log::error!("AtaPassThru.BuildDevicePath: unknown protocol instance");
@@ -632,7 +607,7 @@ extern "efiapi" fn ata_reset_port(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx = match get_context(this) {
+    let ctx = match CONTEXTS.get(this) {
         Some(c) => c,
         None => {
             log::error!("AtaPassThru.ResetPort: unknown protocol instance");
@@ -692,24 +667,11 @@ pub fn create_ata_pass_thru_protocol(
     pci_device: u8,
     pci_function: u8,
 ) -> *mut AtaPassThruProtocol {
-    // Find a free context slot
-    let ctx_idx = unsafe {
-        let mut found = None;
-        let contexts = core::ptr::addr_of!(CONTEXTS);
-        for (i, slot) in (*contexts).iter().enumerate() {
-            if slot.is_none() {
-                found = Some(i);
-                break;
-            }
-        }
-        match found {
-            Some(i) => i,
-            None => {
-                log::error!("AtaPassThru: no free context slots");
-                return core::ptr::null_mut();
-            }
-        }
-    };
+    // Make sure there is a free context slot
+    if CONTEXTS.is_full() {
+        log::error!("AtaPassThru: no free context slots");
+        return core::ptr::null_mut();
+    }
 
     // Verify controller exists
     if ahci::get_controller(controller_index).is_none() {
@@ -749,17 +711,15 @@ pub fn create_ata_pass_thru_protocol(
         return core::ptr::null_mut();
     }
 
-    // Store context
-    unsafe {
-        let contexts = core::ptr::addr_of_mut!(CONTEXTS);
-        (*contexts)[ctx_idx] = Some(AtaPassThruContext {
+    // Store context (checked for a free slot above)
+    CONTEXTS.insert(
+        protocol_ptr,
+        AtaPassThruContext {
             controller_index,
             pci_device,
             pci_function,
-        });
-        let proto_map = core::ptr::addr_of_mut!(PROTOCOL_TO_CONTEXT);
-        (*proto_map)[ctx_idx] = Some(protocol_ptr);
-    }
+        },
+    );
 
     log::info!(
         "AtaPassThru: created protocol for controller {} (PCI {:02x}:{:x})",
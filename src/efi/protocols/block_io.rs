@@ -6,6 +6,7 @@
 use core::ffi::c_void;
 use r_efi::efi::{Guid, Status};
 
+use crate::efi::cell::ContextTable;
 use crate::efi::utils::allocate_protocol_with_log;
 
 /// Block I/O Protocol GUID
@@ -76,6 +77,7 @@ pub struct BlockIoProtocol {
 }
 
 /// Internal context for BlockIO protocol instance
+#[derive(Clone, Copy)]
 struct BlockIoContext {
     /// Media ID (matches BlockIoMedia.media_id)
     media_id: u32,
@@ -87,33 +89,18 @@ struct BlockIoContext {
     num_blocks: u64,
     /// Block size
     block_size: u32,
+    /// Unlocked LUKS volume whose decrypted sectors are served instead
+    volume: Option<usize>,
+    /// Media ID of the storage device's media this instance reads
+    device_media: u32,
 }
 
 /// Maximum number of BlockIO instances
 const MAX_BLOCK_IO_INSTANCES: usize = 16;
 
-/// Global storage for BlockIO contexts
-static mut BLOCK_IO_CONTEXTS: [Option<BlockIoContext>; MAX_BLOCK_IO_INSTANCES] =
-    [const { None }; MAX_BLOCK_IO_INSTANCES];
-
-/// Protocol instance to context mapping
-static mut PROTOCOL_TO_CONTEXT: [Option<*mut BlockIoProtocol>; MAX_BLOCK_IO_INSTANCES] =
-    [const { None }; MAX_BLOCK_IO_INSTANCES];
-
-/// Find context index for a protocol instance
-fn find_context_index(protocol: *mut BlockIoProtocol) -> Option<usize> {
-    unsafe {
-        let contexts = core::ptr::addr_of!(PROTOCOL_TO_CONTEXT);
-        for (i, p) in (*contexts).iter().enumerate() {
-            if let Some(ptr) = p
-                && *ptr == protocol
-            {
-                return Some(i);
-            }
-        }
-    }
-    None
-}
+/// Contexts of the protocol instances
+static CONTEXTS: ContextTable<BlockIoProtocol, BlockIoContext, MAX_BLOCK_IO_INSTANCES> =
+    ContextTable::new();
 
 /// Reset the block device
 extern "efiapi" fn block_io_reset(
@@ -133,27 +120,20 @@ extern "efiapi" fn block_io_read_blocks(
     buffer: *mut c_void,
 ) -> Status {
     use crate::drivers::storage;
+    use crate::fs::luks;
 
     if this.is_null() || buffer.is_null() {
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx_idx = match find_context_index(this) {
-        Some(idx) => idx,
+    let ctx = match CONTEXTS.get(this) {
+        Some(c) => c,
         None => {
             log::error!("BlockIO.ReadBlocks: unknown protocol instance");
             return Status::INVALID_PARAMETER;
         }
     };
 
-    let ctx = unsafe {
-        let contexts = core::ptr::addr_of!(BLOCK_IO_CONTEXTS);
-        match &(*contexts)[ctx_idx] {
-            Some(c) => c,
-            None => return Status::INVALID_PARAMETER,
-        }
-    };
-
     // Verify media ID
     if media_id != ctx.media_id {
         log::debug!(
@@ -164,6 +144,12 @@ extern "efiapi" fn block_io_read_blocks(
         return Status::MEDIA_CHANGED;
     }
 
+    // SD cards may have been pulled or swapped since
+    let media = storage::media(ctx.storage_device_id);
+    if media.map(|m| m.media_id) != Some(ctx.device_media) {
+        return media_changed(this, &ctx, media);
+    }
+
     // Calculate number of blocks to read
     let block_size = ctx.block_size as usize;
     if !buffer_size.is_multiple_of(block_size) {
@@ -204,7 +190,11 @@ extern "efiapi" fn block_io_read_blocks(
         let offset = i * block_size;
         let block_buf = &mut buffer_slice[offset..offset + block_size];
 
-        if storage::read_sectors(ctx.storage_device_id, absolute_lba, block_buf).is_err() {
+        let result = match ctx.volume {
+            Some(volume) => luks::read_sector(volume, lba + i as u64, block_buf),
+            None => storage::read_sectors(ctx.storage_device_id, absolute_lba, block_buf),
+        };
+        if result.is_err() {
             log::error!("BlockIO.ReadBlocks: read failed at LBA {}", absolute_lba);
             return Status::DEVICE_ERROR;
         }
@@ -213,6 +203,50 @@ extern "efiapi" fn block_io_read_blocks(
     Status::SUCCESS
 }
 
+/// Update a protocol instance whose media was removed or replaced
+///
+/// A whole-disk instance moves on to the new media under a new media ID, so
+/// callers see `MEDIA_CHANGED` once and can then read the new media. The
+/// layout behind a partition instance may not exist on the new media, so it
+/// stays without media.
+fn media_changed(
+    this: *mut BlockIoProtocol,
+    ctx: &BlockIoContext,
+    media: Option<crate::drivers::storage::Media>,
+) -> Status {
+    let block_io_media = unsafe { &mut *(*this).media };
+
+    match media {
+        Some(media) if !block_io_media.logical_partition => {
+            let media_id = ctx.media_id.wrapping_add(1);
+            block_io_media.media_id = media_id;
+            block_io_media.media_present = true;
+            block_io_media.last_block = media.num_blocks.saturating_sub(1);
+            CONTEXTS.update(this, |ctx| {
+                ctx.media_id = media_id;
+                ctx.device_media = media.media_id;
+                ctx.num_blocks = media.num_blocks;
+            });
+            log::info!(
+                "BlockIO: media changed on storage {} (media={})",
+                ctx.storage_device_id,
+                media_id
+            );
+            Status::MEDIA_CHANGED
+        }
+        _ => {
+            if block_io_media.media_present {
+                log::info!(
+                    "BlockIO: media removed from storage {}",
+                    ctx.storage_device_id
+                );
+            }
+            block_io_media.media_present = false;
+            Status::NO_MEDIA
+        }
+    }
+}
+
 /// Write blocks to the device (not supported - read only for boot)
 extern "efiapi" fn block_io_write_blocks(
     _this: *mut BlockIoProtocol,
@@ -245,7 +279,7 @@ pub fn create_disk_block_io(
     num_blocks: u64,
     block_size: u32,
 ) -> *mut BlockIoProtocol {
-    create_block_io_internal(storage_device_id, 0, 0, num_blocks, block_size, false)
+    create_block_io_internal(storage_device_id, 0, 0, num_blocks, block_size, false, None)
 }
 
 /// Create a BlockIO protocol for a partition
@@ -273,6 +307,32 @@ pub fn create_partition_block_io(
         num_blocks,
         block_size,
         true,
+        None,
+    )
+}
+
+/// Create a BlockIO protocol serving an unlocked LUKS partition decrypted
+///
+/// # Arguments
+/// * `storage_device_id` - Device ID from the storage registry
+/// * `partition_num` - Partition number (1-based)
+/// * `unlocked` - The unlocked volume on the partition
+///
+/// # Returns
+/// Pointer to BlockIoProtocol, or null on failure
+pub fn create_decrypted_block_io(
+    storage_device_id: u32,
+    partition_num: u32,
+    unlocked: &crate::fs::luks::Unlocked,
+) -> *mut BlockIoProtocol {
+    create_block_io_internal(
+        storage_device_id,
+        partition_num,
+        0,
+        unlocked.num_sectors,
+        unlocked.sector_size,
+        true,
+        Some(unlocked.volume),
     )
 }
 
@@ -284,25 +344,13 @@ fn create_block_io_internal(
     num_blocks: u64,
     block_size: u32,
     is_partition: bool,
+    volume: Option<usize>,
 ) -> *mut BlockIoProtocol {
-    // Find a free context slot
-    let ctx_idx = unsafe {
-        let mut found = None;
-        let contexts = core::ptr::addr_of!(BLOCK_IO_CONTEXTS);
-        for (i, slot) in (*contexts).iter().enumerate() {
-            if slot.is_none() {
-                found = Some(i);
-                break;
-            }
-        }
-        match found {
-            Some(i) => i,
-            None => {
-                log::error!("BlockIO: no free context slots");
-                return core::ptr::null_mut();
-            }
-        }
-    };
+    // Make sure there is a free context slot
+    if CONTEXTS.is_full() {
+        log::error!("BlockIO: no free context slots");
+        return core::ptr::null_mut();
+    }
 
     // Allocate media structure
     let media_ptr = allocate_protocol_with_log::<BlockIoMedia>("BlockIoMedia", |m| {
@@ -329,26 +377,33 @@ fn create_block_io_internal(
         p.read_blocks = block_io_read_blocks;
         p.write_blocks = block_io_write_blocks;
         p.flush_blocks = block_io_flush_blocks;
+        #[cfg(feature = "protocol-trace")]
+        super::trace::block_io(p);
     });
     if protocol_ptr.is_null() {
         return core::ptr::null_mut();
     }
 
-    // Store context
-    unsafe {
-        let contexts = core::ptr::addr_of_mut!(BLOCK_IO_CONTEXTS);
-        (*contexts)[ctx_idx] = Some(BlockIoContext {
+    // Store context (checked for a free slot above)
+    CONTEXTS.insert(
+        protocol_ptr,
+        BlockIoContext {
             media_id,
             storage_device_id,
             start_lba,
             num_blocks,
             block_size,
-        });
-        let proto_map = core::ptr::addr_of_mut!(PROTOCOL_TO_CONTEXT);
-        (*proto_map)[ctx_idx] = Some(protocol_ptr);
-    }
+            volume,
+            device_media: crate::drivers::storage::media(storage_device_id)
+                .map_or(0, |media| media.media_id),
+        },
+    );
 
-    let kind = if is_partition { "partition" } else { "disk" };
+    let kind = match (is_partition, volume) {
+        (_, Some(_)) => "decrypted partition",
+        (true, None) => "partition",
+        (false, None) => "disk",
+    };
     log::info!(
         "BlockIO: created {} protocol (media={}, storage={}, start={}, blocks={}, bs={})",
         kind,
@@ -1,26 +1,44 @@
 //! EFI Console Protocols
 //!
-//! This module implements the Simple Text Input and Simple Text Output protocols
-//! for console I/O.
+//! This module implements the Simple Text Input, Simple Text Input Ex and
+//! Simple Text Output protocols for console I/O.
 //!
 //! # Keyboard Input
 //!
 //! Input is gathered from two sources:
 //! - Serial console: ANSI escape sequences are parsed for arrow keys, function keys, etc.
 //! - PS/2 keyboard: Scancodes are translated to EFI keys via the i8042 keyboard controller.
+//!
+//! Both are polled into one input queue whenever timers are dispatched, so
+//! keys typed while the application is busy wait there to be read.
+//!
+//! Simple Text Input Ex also reports the modifier keys held and the lock key
+//! states, which are shared by all keyboards, and sets the lock keys.
 
 use crate::coreboot::FramebufferInfo;
+use crate::display;
+use crate::drivers::bmc_console;
 use crate::drivers::keyboard;
 use crate::drivers::serial;
+use crate::drivers::serial_keys::ESCAPE_TIMEOUT_MS;
 use crate::efi::boot_services::KEYBOARD_EVENT_ID;
-use crate::framebuffer_console::{CHAR_HEIGHT, CHAR_WIDTH, VGA_FONT_8X16};
-use crate::state::{self, InputState};
+use crate::efi::cell::EfiCell;
+use crate::efi::protocols::console_control;
+use crate::framebuffer_console::Color;
+use crate::state;
+use crate::time::Timeout;
 use core::ffi::c_void;
 use r_efi::efi::{Boolean, Event, Guid, Status};
 use r_efi::protocols::simple_text_input::{InputKey, Protocol as SimpleTextInputProtocol};
+use r_efi::protocols::simple_text_input_ex::{
+    KEY_STATE_EXPOSED, KeyData, KeyNotifyFunction, KeyState, KeyToggleState, LEFT_CONTROL_PRESSED,
+    Protocol as SimpleTextInputExProtocol, RIGHT_CONTROL_PRESSED, SHIFT_STATE_VALID,
+    TOGGLE_STATE_VALID,
+};
 use r_efi::protocols::simple_text_output::{
     Mode as SimpleTextOutputMode, Protocol as SimpleTextOutputProtocol,
 };
+use spin::Mutex;
 
 // ============================================================================
 // EFI Framebuffer Console State (stored in state::ConsoleState)
@@ -28,8 +46,7 @@ use r_efi::protocols::simple_text_output::{
 
 /// Initialize the EFI console with framebuffer support
 pub fn init_framebuffer(fb: FramebufferInfo) {
-    let cols = fb.x_resolution / CHAR_WIDTH;
-    let rows = fb.y_resolution / CHAR_HEIGHT;
+    let (cols, rows) = display::view().grid(&fb);
 
     // Reserve top portion for debug log, use bottom portion for EFI console
     // Use bottom half of screen for EFI console output
@@ -52,13 +69,18 @@ pub fn init_framebuffer(fb: FramebufferInfo) {
 
 /// Write a character to the EFI framebuffer console
 fn fb_put_char(c: char) {
+    // A graphics mode client owns the framebuffer
+    if !console_control::is_text_mode() {
+        return;
+    }
+
     state::with_console_mut(|console| {
         let Some(ref fb) = console.efi_framebuffer else {
             return;
         };
 
         let (cols, _rows) = console.dimensions;
-        let total_rows = fb.y_resolution / CHAR_HEIGHT;
+        let (_, total_rows) = display::view().grid(fb);
         let start_row = console.start_row;
 
         let (mut col, mut row) = console.cursor_pos;
@@ -96,64 +118,28 @@ fn fb_put_char(c: char) {
 
 /// Draw a character at a specific position
 fn fb_draw_char(fb: &FramebufferInfo, c: char, col: u32, row: u32) {
-    let x_base = col * CHAR_WIDTH;
-    let y_base = row * CHAR_HEIGHT;
-
-    let glyph = if (c as usize) < 256 {
-        &VGA_FONT_8X16[c as usize]
-    } else {
-        &VGA_FONT_8X16[b'?' as usize]
-    };
-
     // White on black for EFI console
-    let (fg_r, fg_g, fg_b) = (255u8, 255u8, 255u8);
-    let (bg_r, bg_g, bg_b) = (0u8, 0u8, 0u8);
-
-    for glyph_row in 0..CHAR_HEIGHT {
-        let bits = glyph[glyph_row as usize];
-        for glyph_col in 0..CHAR_WIDTH {
-            let pixel_set = (bits >> (7 - glyph_col)) & 1 != 0;
-            let (r, g, b) = if pixel_set {
-                (fg_r, fg_g, fg_b)
-            } else {
-                (bg_r, bg_g, bg_b)
-            };
-            unsafe {
-                fb.write_pixel(x_base + glyph_col, y_base + glyph_row, r, g, b);
-            }
-        }
-    }
+    unsafe { display::view().draw_char(fb, c, col, row, Color::white(), Color::black()) };
 }
 
 /// Scroll the EFI console area up by one line
 fn fb_scroll_up(fb: &FramebufferInfo, start_row: u32, total_rows: u32) {
-    let row_stride = fb.bytes_per_line as usize;
-
-    // Copy each row up
-    for row in start_row..(total_rows - 1) {
-        let src_y = (row + 1) * CHAR_HEIGHT;
-        let dst_y = row * CHAR_HEIGHT;
-
-        for line in 0..CHAR_HEIGHT {
-            let src_offset = ((src_y + line) as usize) * row_stride;
-            let dst_offset = ((dst_y + line) as usize) * row_stride;
-
-            unsafe {
-                let src = (fb.physical_address as *const u8).add(src_offset);
-                let dst = (fb.physical_address as *mut u8).add(dst_offset);
-                core::ptr::copy(src, dst, row_stride);
-            }
-        }
-    }
-
-    // Clear the last row
-    let last_row_y = (total_rows - 1) * CHAR_HEIGHT;
-    for line in 0..CHAR_HEIGHT {
-        let offset = ((last_row_y + line) as usize) * row_stride;
-        unsafe {
-            let dst = (fb.physical_address as *mut u8).add(offset);
-            core::slice::from_raw_parts_mut(dst, row_stride).fill(0);
-        }
+    let view = display::view();
+    let (width, _) = view.size(fb);
+    let (_, cell_height) = view.cell();
+    let top = start_row * cell_height;
+    let height = (total_rows - start_row) * cell_height;
+    unsafe {
+        view.scroll_up(fb, top, height, cell_height);
+        // Clear the last row
+        view.fill(
+            fb,
+            0,
+            top + height - cell_height,
+            width,
+            cell_height,
+            Color::black(),
+        );
     }
 }
 
@@ -167,6 +153,10 @@ pub const SIMPLE_TEXT_INPUT_PROTOCOL_GUID: Guid = Guid::from_fields(
     &[0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
 );
 
+/// Simple Text Input Ex Protocol GUID
+pub const SIMPLE_TEXT_INPUT_EX_PROTOCOL_GUID: Guid =
+    r_efi::protocols::simple_text_input_ex::PROTOCOL_GUID;
+
 /// Simple Text Output Protocol GUID
 pub const SIMPLE_TEXT_OUTPUT_PROTOCOL_GUID: Guid = Guid::from_fields(
     0x387477c2,
@@ -183,7 +173,7 @@ pub const SIMPLE_TEXT_OUTPUT_PROTOCOL_GUID: Guid = Guid::from_fields(
 
 /// EFI Scan codes for special keys
 #[allow(dead_code)]
-mod scan_codes {
+pub(crate) mod scan_codes {
     pub const SCAN_NULL: u16 = 0x0000;
     pub const SCAN_UP: u16 = 0x0001;
     pub const SCAN_DOWN: u16 = 0x0002;
@@ -215,49 +205,74 @@ mod scan_codes {
 // ============================================================================
 
 /// Console output mode
-static mut CONSOLE_MODE: SimpleTextOutputMode = SimpleTextOutputMode {
+static CONSOLE_MODE: EfiCell<SimpleTextOutputMode> = EfiCell::new(SimpleTextOutputMode {
     max_mode: 1,
     mode: 0,
     attribute: 0x07, // Light gray on black
     cursor_column: 0,
     cursor_row: 0,
     cursor_visible: Boolean::TRUE,
-};
+});
 
 /// Static text input protocol
 /// Note: wait_for_key is set to KEYBOARD_EVENT_ID which is the special event
 /// used for keyboard input polling
-static mut TEXT_INPUT_PROTOCOL: SimpleTextInputProtocol = SimpleTextInputProtocol {
-    reset: text_input_reset,
-    read_key_stroke: text_input_read_key_stroke,
-    wait_for_key: KEYBOARD_EVENT_ID as *mut c_void as Event,
-};
+static TEXT_INPUT_PROTOCOL: EfiCell<SimpleTextInputProtocol> =
+    EfiCell::new(SimpleTextInputProtocol {
+        reset: text_input_reset,
+        read_key_stroke: text_input_read_key_stroke,
+        wait_for_key: KEYBOARD_EVENT_ID as *mut c_void as Event,
+    });
+
+/// Static extended text input protocol, sharing the keyboard event
+static TEXT_INPUT_EX_PROTOCOL: EfiCell<SimpleTextInputExProtocol> =
+    EfiCell::new(SimpleTextInputExProtocol {
+        reset: text_input_ex_reset,
+        read_key_stroke_ex: text_input_read_key_stroke_ex,
+        wait_for_key_ex: KEYBOARD_EVENT_ID as *mut c_void as Event,
+        set_state: text_input_set_state,
+        register_key_notify: text_input_register_key_notify,
+        unregister_key_notify: text_input_unregister_key_notify,
+    });
+
+/// Maximum number of registered key notifications
+const MAX_KEY_NOTIFIES: usize = 8;
+
+/// Key notifications registered with RegisterKeyNotify
+///
+/// They are called when a matching key is queued.
+static KEY_NOTIFIES: Mutex<[Option<(KeyData, KeyNotifyFunction)>; MAX_KEY_NOTIFIES]> =
+    Mutex::new([None; MAX_KEY_NOTIFIES]);
 
 /// Static text output protocol
-static mut TEXT_OUTPUT_PROTOCOL: SimpleTextOutputProtocol = SimpleTextOutputProtocol {
-    reset: text_output_reset,
-    output_string: text_output_string,
-    test_string: text_output_test_string,
-    query_mode: text_output_query_mode,
-    set_mode: text_output_set_mode,
-    set_attribute: text_output_set_attribute,
-    clear_screen: text_output_clear_screen,
-    set_cursor_position: text_output_set_cursor_position,
-    enable_cursor: text_output_enable_cursor,
-    mode: core::ptr::null_mut(),
-};
+static TEXT_OUTPUT_PROTOCOL: EfiCell<SimpleTextOutputProtocol> =
+    EfiCell::new(SimpleTextOutputProtocol {
+        reset: text_output_reset,
+        output_string: text_output_string,
+        test_string: text_output_test_string,
+        query_mode: text_output_query_mode,
+        set_mode: text_output_set_mode,
+        set_attribute: text_output_set_attribute,
+        clear_screen: text_output_clear_screen,
+        set_cursor_position: text_output_set_cursor_position,
+        enable_cursor: text_output_enable_cursor,
+        mode: core::ptr::null_mut(),
+    });
 
 /// Get the text input protocol
 pub fn get_text_input_protocol() -> *mut SimpleTextInputProtocol {
-    &raw mut TEXT_INPUT_PROTOCOL
+    TEXT_INPUT_PROTOCOL.as_ptr()
+}
+
+/// Get the extended text input protocol
+pub fn get_text_input_ex_protocol() -> *mut SimpleTextInputExProtocol {
+    TEXT_INPUT_EX_PROTOCOL.as_ptr()
 }
 
 /// Get the text output protocol
 pub fn get_text_output_protocol() -> *mut SimpleTextOutputProtocol {
-    unsafe {
-        TEXT_OUTPUT_PROTOCOL.mode = &raw mut CONSOLE_MODE;
-        &raw mut TEXT_OUTPUT_PROTOCOL
-    }
+    TEXT_OUTPUT_PROTOCOL.with(|protocol| protocol.mode = CONSOLE_MODE.as_ptr());
+    TEXT_OUTPUT_PROTOCOL.as_ptr()
 }
 
 // ============================================================================
@@ -280,254 +295,257 @@ extern "efiapi" fn text_input_read_key_stroke(
         return Status::INVALID_PARAMETER;
     }
 
-    state::with_console_mut(|console| {
-        let input_state = &mut console.input;
-
-        // First check if we have a queued key from previous escape sequence parsing
-        if let Some((scan_code, unicode_char)) = input_state.queued_key.take() {
-            unsafe {
-                (*key).scan_code = scan_code;
-                (*key).unicode_char = unicode_char;
-            }
-            log::trace!(
-                "ConIn.ReadKeyStroke: queued key -> scan={:#x}, unicode={:#x}",
-                scan_code,
-                unicode_char
-            );
-            return Status::SUCCESS;
+    loop {
+        let mut data = match read_key() {
+            Ok(data) => data,
+            Err(status) => return status,
+        };
+        // Partial keystrokes are only reported by ReadKeyStrokeEx
+        if data.key.scan_code == 0 && data.key.unicode_char == 0 {
+            continue;
         }
-
-        // Try to get a key from PS/2 keyboard first
-        if let Some((scan_code, unicode_char)) = keyboard::try_read_key() {
-            unsafe {
-                (*key).scan_code = scan_code;
-                (*key).unicode_char = unicode_char;
-            }
-            log::trace!(
-                "ConIn.ReadKeyStroke: PS/2 -> scan={:#x}, unicode={:#x}",
-                scan_code,
-                unicode_char
-            );
-            return Status::SUCCESS;
+        // Without the shift state, Ctrl+A is reported as 0x01
+        if data.key_state.key_shift_state & (LEFT_CONTROL_PRESSED | RIGHT_CONTROL_PRESSED) != 0 {
+            data.key.unicode_char = keyboard::control_char(data.key.unicode_char);
         }
+        // Safety: the caller passes a valid key
+        unsafe { *key = data.key };
+        return Status::SUCCESS;
+    }
+}
 
-        // Try to read from serial port
-        match serial::try_read() {
-            Some(byte) => {
-                // Handle escape sequence parsing
-                let (scan_code, unicode_char) = process_serial_byte(input_state, byte);
-
-                if scan_code == 0 && unicode_char == 0 {
-                    // Still collecting escape sequence, no key ready yet
-                    return Status::NOT_READY;
-                }
-
-                unsafe {
-                    (*key).scan_code = scan_code;
-                    (*key).unicode_char = unicode_char;
-                }
+/// Read the next key from the input queue
+fn read_key() -> Result<KeyData, Status> {
+    poll_input();
+    let data = state::with_console_mut(|console| console.input.keys.pop_front());
+    let data = data.ok_or(Status::NOT_READY)?;
+    log::trace!(
+        "ConIn.ReadKeyStroke: scan={:#x}, unicode={:#x}",
+        data.key.scan_code,
+        data.key.unicode_char
+    );
+    Ok(data)
+}
 
-                log::trace!(
-                    "ConIn.ReadKeyStroke: serial byte={:#x} -> scan={:#x}, unicode={:#x}",
-                    byte,
+/// Move the keys typed on the keyboards and the serial console to the input
+/// queue
+///
+/// Called whenever timers are dispatched, as there is no timer interrupt, so
+/// keys typed while the application is busy aren't lost. Runs the key
+/// notifications registered for the keys.
+pub fn poll_input() {
+    let mut new_keys = heapless::Vec::<KeyData, { state::INPUT_QUEUE_SIZE }>::new();
+
+    // PS/2 and USB keyboards, with the modifiers held for them
+    for _ in 0..state::INPUT_QUEUE_SIZE {
+        if !keyboard::has_key() {
+            break;
+        }
+        if let Some((scan_code, unicode_char)) = keyboard::try_read_key_ex() {
+            let _ = new_keys.push(KeyData {
+                key: InputKey {
                     scan_code,
-                    unicode_char
-                );
+                    unicode_char,
+                },
+                key_state: KeyState {
+                    key_shift_state: SHIFT_STATE_VALID | keyboard::shift_state(),
+                    key_toggle_state: toggle_state(),
+                },
+            });
+        }
+    }
 
-                Status::SUCCESS
+    state::with_console_mut(|console| {
+        let input = &mut console.input;
+
+        // Decode what the serial port and the BMC received
+        let mut received = false;
+        while let Some(byte) = serial::try_read().or_else(bmc_console::try_read) {
+            input.decoder.push(byte);
+            received = true;
+            while let Some((scan_code, unicode_char)) = input.decoder.pop() {
+                let _ = new_keys.push(serial_key(scan_code, unicode_char));
             }
-            None => {
-                // Check if we're in the middle of an escape sequence that timed out
-                if input_state.in_escape && input_state.escape_len > 0 {
-                    // Timeout: return what we have as individual characters
-                    // This happens when user presses just ESC
-                    let result = finalize_escape_sequence(input_state);
-                    if let Some((scan_code, unicode_char)) = result {
-                        unsafe {
-                            (*key).scan_code = scan_code;
-                            (*key).unicode_char = unicode_char;
-                        }
-                        return Status::SUCCESS;
-                    }
-                }
+        }
+
+        if !input.decoder.in_sequence() {
+            input.escape_timeout = None;
+        } else if received {
+            input.escape_timeout = Some(Timeout::from_ms(ESCAPE_TIMEOUT_MS));
+        } else if input
+            .escape_timeout
+            .as_ref()
+            .is_some_and(Timeout::is_expired)
+        {
+            // Nothing followed the ESC, it was the Escape key
+            input.decoder.flush();
+            input.escape_timeout = None;
+            while let Some((scan_code, unicode_char)) = input.decoder.pop() {
+                let _ = new_keys.push(serial_key(scan_code, unicode_char));
+            }
+        }
 
-                // No key available
-                Status::NOT_READY
+        // Keys beyond the queue size are dropped, as on a full keyboard buffer
+        for data in &new_keys {
+            if input.keys.push_back(*data).is_err() {
+                log::debug!("ConIn: input queue full, dropping key");
+                break;
             }
         }
-    })
+    });
+
+    for data in &new_keys {
+        notify_key(data);
+    }
 }
 
-/// Process a serial byte, handling escape sequences
+/// A key typed on the serial console
 ///
-/// Returns (scan_code, unicode_char) if a key is ready, or (0, 0) if still collecting
-/// an escape sequence.
-fn process_serial_byte(input_state: &mut InputState, byte: u8) -> (u16, u16) {
-    if input_state.in_escape {
-        // We're collecting an escape sequence
-        if input_state.escape_len < state::ESCAPE_BUF_SIZE {
-            input_state.escape_buf[input_state.escape_len] = byte;
-            input_state.escape_len += 1;
-        }
+/// Control characters other than Backspace, Tab and Enter become Ctrl with
+/// their letter, as a keyboard reports them. Other modifiers aren't known.
+fn serial_key(scan_code: u16, unicode_char: u16) -> KeyData {
+    let (unicode_char, key_shift_state) = match unicode_char {
+        0x08 | 0x09 | 0x0A | 0x0D => (unicode_char, 0),
+        0x01..=0x1A => (
+            unicode_char + 0x60,
+            SHIFT_STATE_VALID | LEFT_CONTROL_PRESSED,
+        ),
+        _ => (unicode_char, 0),
+    };
+    KeyData {
+        key: InputKey {
+            scan_code,
+            unicode_char,
+        },
+        key_state: KeyState {
+            key_shift_state,
+            key_toggle_state: toggle_state(),
+        },
+    }
+}
 
-        // Try to match the escape sequence
-        if let Some(key) = match_escape_sequence(&input_state.escape_buf[..input_state.escape_len])
-        {
-            // Found a match
-            input_state.in_escape = false;
-            input_state.escape_len = 0;
-            return key;
-        }
+/// Lock key states as reported in key data
+fn toggle_state() -> KeyToggleState {
+    let exposed = if keyboard::partial_keys() {
+        KEY_STATE_EXPOSED
+    } else {
+        0
+    };
+    TOGGLE_STATE_VALID | exposed | keyboard::toggle_state()
+}
 
-        // Check if the sequence is definitely not going to match
-        if input_state.escape_len >= 5
-            || !could_be_escape_sequence(&input_state.escape_buf[..input_state.escape_len])
-        {
-            // Give up on this escape sequence, return ESC and queue the rest
-            let result = finalize_escape_sequence(input_state);
-            return result.unwrap_or((0, 0));
-        }
+/// Whether the input queue holds a key, for WaitForKey
+pub fn has_key() -> bool {
+    poll_input();
+    !state::console().input.keys.is_empty()
+}
 
-        // Still collecting, no key ready yet
-        return (0, 0);
-    }
-
-    // Not in an escape sequence - check if this starts one
-    if byte == 0x1B {
-        // Start of escape sequence
-        input_state.in_escape = true;
-        input_state.escape_len = 0;
-        // Return (0, 0) to indicate we need more input
-        // But first check if there's more data available immediately
-        if !serial::has_input() {
-            // No more data immediately available, this might be a standalone ESC
-            // We'll let the next call to read_key_stroke handle the timeout
-            return (0, 0);
+/// Run the key notifications registered for `data`
+fn notify_key(data: &KeyData) {
+    let matches = |registered: &KeyData| {
+        let state = &registered.key_state;
+        registered.key.scan_code == data.key.scan_code
+            && registered.key.unicode_char == data.key.unicode_char
+            && (state.key_shift_state & SHIFT_STATE_VALID == 0
+                || state.key_shift_state == data.key_state.key_shift_state)
+            && (state.key_toggle_state & TOGGLE_STATE_VALID == 0
+                || state.key_toggle_state == data.key_state.key_toggle_state)
+    };
+    // Copy the matches so a notification can register or unregister others
+    let notifies = *KEY_NOTIFIES.lock();
+    for (registered, function) in notifies.iter().flatten() {
+        if matches(registered) {
+            let mut data = *data;
+            function(&mut data);
         }
-        return (0, 0);
     }
-
-    // Regular character - convert directly
-    convert_byte_to_efi_key(byte)
 }
 
-/// Convert a single byte to EFI key (non-escape sequence)
-fn convert_byte_to_efi_key(byte: u8) -> (u16, u16) {
-    match byte {
-        // Enter key
-        b'\r' | b'\n' => (0, 0x000D), // CHAR_CARRIAGE_RETURN
-
-        // Backspace
-        0x7F | 0x08 => (0, 0x0008), // CHAR_BACKSPACE
+// ============================================================================
+// Simple Text Input Ex Protocol Implementation
+// ============================================================================
 
-        // Tab
-        b'\t' => (0, 0x0009), // CHAR_TAB
+extern "efiapi" fn text_input_ex_reset(
+    _this: *mut SimpleTextInputExProtocol,
+    _extended_verification: Boolean,
+) -> Status {
+    Status::SUCCESS
+}
 
-        // Regular printable ASCII
-        0x20..=0x7E => (0, byte as u16),
+extern "efiapi" fn text_input_read_key_stroke_ex(
+    _this: *mut SimpleTextInputExProtocol,
+    key_data: *mut KeyData,
+) -> Status {
+    if key_data.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
 
-        // Other control characters
-        _ => (0, byte as u16),
+    match read_key() {
+        Ok(data) => {
+            // Safety: the caller passes valid key data
+            unsafe { *key_data = data };
+            Status::SUCCESS
+        }
+        Err(status) => status,
     }
 }
 
-/// Try to match an escape sequence buffer to a known sequence
-///
-/// Returns Some((scan_code, unicode_char)) if matched, None if not yet matched
-fn match_escape_sequence(buf: &[u8]) -> Option<(u16, u16)> {
-    use scan_codes::*;
-
-    // ANSI escape sequences (CSI sequences starting with ESC [)
-    // Arrow keys: ESC [ A/B/C/D
-    // Home/End: ESC [ H/F or ESC [ 1 ~/ESC [ 4 ~
-    // Page Up/Down: ESC [ 5 ~/ESC [ 6 ~
-    // Insert/Delete: ESC [ 2 ~/ESC [ 3 ~
-    // Function keys: ESC O P/Q/R/S (F1-F4) or ESC [ 15 ~ etc.
-
-    match buf {
-        // Arrow keys
-        [b'[', b'A'] => Some((SCAN_UP, 0)),
-        [b'[', b'B'] => Some((SCAN_DOWN, 0)),
-        [b'[', b'C'] => Some((SCAN_RIGHT, 0)),
-        [b'[', b'D'] => Some((SCAN_LEFT, 0)),
-
-        // Home/End (VT style)
-        [b'[', b'H'] => Some((SCAN_HOME, 0)),
-        [b'[', b'F'] => Some((SCAN_END, 0)),
-
-        // Home/End (alternate style)
-        [b'[', b'1', b'~'] => Some((SCAN_HOME, 0)),
-        [b'[', b'4', b'~'] => Some((SCAN_END, 0)),
-
-        // Insert/Delete
-        [b'[', b'2', b'~'] => Some((SCAN_INSERT, 0)),
-        [b'[', b'3', b'~'] => Some((SCAN_DELETE, 0)),
-
-        // Page Up/Down
-        [b'[', b'5', b'~'] => Some((SCAN_PAGE_UP, 0)),
-        [b'[', b'6', b'~'] => Some((SCAN_PAGE_DOWN, 0)),
-
-        // Function keys F1-F4 (VT style)
-        [b'O', b'P'] => Some((SCAN_F1, 0)),
-        [b'O', b'Q'] => Some((SCAN_F2, 0)),
-        [b'O', b'R'] => Some((SCAN_F3, 0)),
-        [b'O', b'S'] => Some((SCAN_F4, 0)),
-
-        // Function keys F1-F4 (alternate xterm style)
-        [b'[', b'[', b'A'] => Some((SCAN_F1, 0)),
-        [b'[', b'[', b'B'] => Some((SCAN_F2, 0)),
-        [b'[', b'[', b'C'] => Some((SCAN_F3, 0)),
-        [b'[', b'[', b'D'] => Some((SCAN_F4, 0)),
-        [b'[', b'[', b'E'] => Some((SCAN_F5, 0)),
-
-        // Function keys F5-F12 (VT style)
-        [b'[', b'1', b'5', b'~'] => Some((SCAN_F5, 0)),
-        [b'[', b'1', b'7', b'~'] => Some((SCAN_F6, 0)),
-        [b'[', b'1', b'8', b'~'] => Some((SCAN_F7, 0)),
-        [b'[', b'1', b'9', b'~'] => Some((SCAN_F8, 0)),
-        [b'[', b'2', b'0', b'~'] => Some((SCAN_F9, 0)),
-        [b'[', b'2', b'1', b'~'] => Some((SCAN_F10, 0)),
-        [b'[', b'2', b'3', b'~'] => Some((SCAN_F11, 0)),
-        [b'[', b'2', b'4', b'~'] => Some((SCAN_F12, 0)),
-
-        _ => None,
-    }
-}
-
-/// Check if the buffer could potentially be a valid escape sequence prefix
-fn could_be_escape_sequence(buf: &[u8]) -> bool {
-    // All valid escape sequences start with '[' or 'O'
-    if buf.is_empty() {
-        return true;
-    }
-
-    matches!(buf[0], b'[' | b'O')
-}
-
-/// Finalize an incomplete escape sequence
-///
-/// Returns the ESC key and queues remaining bytes to be returned as separate keys
-fn finalize_escape_sequence(input_state: &mut InputState) -> Option<(u16, u16)> {
-    use scan_codes::*;
+extern "efiapi" fn text_input_set_state(
+    _this: *mut SimpleTextInputExProtocol,
+    toggle_state: *mut KeyToggleState,
+) -> Status {
+    if toggle_state.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    // Safety: the caller passes a valid toggle state
+    let toggle_state = unsafe { *toggle_state };
+    if toggle_state & TOGGLE_STATE_VALID == 0 {
+        return Status::UNSUPPORTED;
+    }
 
-    input_state.in_escape = false;
+    keyboard::set_partial_keys(toggle_state & KEY_STATE_EXPOSED != 0);
+    keyboard::set_toggle_state(toggle_state & !(TOGGLE_STATE_VALID | KEY_STATE_EXPOSED));
+    log::debug!("ConIn.SetState: {:#x}", toggle_state);
+    Status::SUCCESS
+}
 
-    if input_state.escape_len == 0 {
-        // Just ESC with no following characters
-        input_state.escape_len = 0;
-        return Some((SCAN_ESC, 0));
+extern "efiapi" fn text_input_register_key_notify(
+    _this: *mut SimpleTextInputExProtocol,
+    key_data: *mut KeyData,
+    function: KeyNotifyFunction,
+    handle: *mut *mut c_void,
+) -> Status {
+    if key_data.is_null() || handle.is_null() {
+        return Status::INVALID_PARAMETER;
     }
+    // Safety: the caller passes valid key data
+    let key_data = unsafe { *key_data };
 
-    // We have bytes that didn't form a valid sequence
-    // Return ESC and queue the first remaining byte
-    // (Ideally we'd queue all of them, but for simplicity just queue one)
-    if input_state.escape_len > 0 {
-        let first_byte = input_state.escape_buf[0];
-        input_state.queued_key = Some(convert_byte_to_efi_key(first_byte));
-    }
+    let mut notifies = KEY_NOTIFIES.lock();
+    let Some(index) = notifies.iter().position(Option::is_none) else {
+        return Status::OUT_OF_RESOURCES;
+    };
+    notifies[index] = Some((key_data, function));
+    // Handles are the slot number plus one, so none is null
+    unsafe { *handle = (index + 1) as *mut c_void };
+    Status::SUCCESS
+}
 
-    input_state.escape_len = 0;
-    Some((SCAN_ESC, 0))
+extern "efiapi" fn text_input_unregister_key_notify(
+    _this: *mut SimpleTextInputExProtocol,
+    handle: *mut c_void,
+) -> Status {
+    let mut notifies = KEY_NOTIFIES.lock();
+    match (handle as usize)
+        .checked_sub(1)
+        .and_then(|index| notifies.get_mut(index))
+    {
+        Some(slot @ Some(_)) => {
+            *slot = None;
+            Status::SUCCESS
+        }
+        _ => Status::INVALID_PARAMETER,
+    }
 }
 
 // ============================================================================
@@ -539,11 +557,11 @@ extern "efiapi" fn text_output_reset(
     _extended_verification: Boolean,
 ) -> Status {
     // Reset console state
-    unsafe {
-        CONSOLE_MODE.cursor_column = 0;
-        CONSOLE_MODE.cursor_row = 0;
-        CONSOLE_MODE.attribute = 0x07;
-    }
+    CONSOLE_MODE.with(|mode| {
+        mode.cursor_column = 0;
+        mode.cursor_row = 0;
+        mode.attribute = 0x07;
+    });
 
     // Send reset sequence to serial
     serial::write_str("\x1b[2J\x1b[H"); // Clear screen, home cursor
@@ -575,25 +593,27 @@ extern "efiapi" fn text_output_string(
                         serial::write_byte(b'\r');
                         serial::write_byte(b'\n');
                         fb_put_char('\n');
-                        CONSOLE_MODE.cursor_column = 0;
-                        CONSOLE_MODE.cursor_row += 1;
+                        CONSOLE_MODE.with(|mode| {
+                            mode.cursor_column = 0;
+                            mode.cursor_row += 1;
+                        });
                     }
                     b'\r' => {
                         serial::write_byte(b'\r');
                         fb_put_char('\r');
-                        CONSOLE_MODE.cursor_column = 0;
+                        CONSOLE_MODE.with(|mode| mode.cursor_column = 0);
                     }
                     _ => {
                         serial::write_byte(byte);
                         fb_put_char(c);
-                        CONSOLE_MODE.cursor_column += 1;
+                        CONSOLE_MODE.with(|mode| mode.cursor_column += 1);
                     }
                 }
             } else {
                 // Non-ASCII: output '?'
                 serial::write_byte(b'?');
                 fb_put_char('?');
-                CONSOLE_MODE.cursor_column += 1;
+                CONSOLE_MODE.with(|mode| mode.cursor_column += 1);
             }
 
             ptr = ptr.add(1);
@@ -658,9 +678,7 @@ extern "efiapi" fn text_output_set_mode(
         return Status::UNSUPPORTED;
     }
 
-    unsafe {
-        CONSOLE_MODE.mode = mode_number as i32;
-    }
+    CONSOLE_MODE.with(|mode| mode.mode = mode_number as i32);
 
     Status::SUCCESS
 }
@@ -669,9 +687,7 @@ extern "efiapi" fn text_output_set_attribute(
     _this: *mut SimpleTextOutputProtocol,
     attribute: usize,
 ) -> Status {
-    unsafe {
-        CONSOLE_MODE.attribute = attribute as i32;
-    }
+    CONSOLE_MODE.with(|mode| mode.attribute = attribute as i32);
 
     // Convert EFI attribute to ANSI escape sequence
     let fg = attribute & 0x0F;
@@ -723,39 +739,34 @@ extern "efiapi" fn text_output_set_attribute(
 extern "efiapi" fn text_output_clear_screen(_this: *mut SimpleTextOutputProtocol) -> Status {
     serial::write_str("\x1b[2J\x1b[H");
 
-    unsafe {
-        CONSOLE_MODE.cursor_column = 0;
-        CONSOLE_MODE.cursor_row = 0;
+    CONSOLE_MODE.with(|mode| {
+        mode.cursor_column = 0;
+        mode.cursor_row = 0;
+    });
+
+    if console_control::is_text_mode() {
+        clear_framebuffer();
     }
 
+    Status::SUCCESS
+}
+
+/// Clear the whole framebuffer and give the EFI console the full screen
+pub fn clear_framebuffer() {
     // Clear the ENTIRE framebuffer (bootloader expects full screen)
     state::with_console_mut(|console| {
         let Some(ref fb) = console.efi_framebuffer else {
             return;
         };
 
-        let total_rows = fb.y_resolution / CHAR_HEIGHT;
-        let row_stride = fb.bytes_per_line as usize;
-
-        // Clear the entire screen (from row 0 to bottom)
-        for row in 0..total_rows {
-            let y_base = row * CHAR_HEIGHT;
-            for line in 0..CHAR_HEIGHT {
-                let offset = ((y_base + line) as usize) * row_stride;
-                unsafe {
-                    let dst = (fb.physical_address as *mut u8).add(offset);
-                    core::slice::from_raw_parts_mut(dst, row_stride).fill(0);
-                }
-            }
-        }
+        // Clear the entire screen, black is all zero in any pixel format
+        unsafe { core::ptr::write_bytes(fb.as_ptr(), 0, fb.size() as usize) };
 
         // Reset console to use full screen (bootloader wants the whole display)
         console.start_row = 0;
-        console.dimensions = (fb.x_resolution / CHAR_WIDTH, total_rows);
+        console.dimensions = display::view().grid(fb);
         console.cursor_pos = (0, 0);
     });
-
-    Status::SUCCESS
 }
 
 extern "efiapi" fn text_output_set_cursor_position(
@@ -771,10 +782,10 @@ extern "efiapi" fn text_output_set_cursor_position(
         serial::write_byte(byte);
     }
 
-    unsafe {
-        CONSOLE_MODE.cursor_column = column as i32;
-        CONSOLE_MODE.cursor_row = row as i32;
-    }
+    CONSOLE_MODE.with(|mode| {
+        mode.cursor_column = column as i32;
+        mode.cursor_row = row as i32;
+    });
 
     // Update framebuffer cursor position
     // Row is relative to the EFI console area, so add start_row to get absolute row
@@ -791,9 +802,7 @@ extern "efiapi" fn text_output_enable_cursor(
     visible: Boolean,
 ) -> Status {
     let is_visible: bool = visible.into();
-    unsafe {
-        CONSOLE_MODE.cursor_visible = visible;
-    }
+    CONSOLE_MODE.with(|mode| mode.cursor_visible = visible);
 
     if is_visible {
         serial::write_str("\x1b[?25h"); // Show cursor
@@ -3,10 +3,17 @@
 //! This protocol is used by bootloaders to switch between text and graphics
 //! console modes. It was part of the Intel EFI specification but deprecated
 //! in UEFI 2.0. Some bootloaders still use it for compatibility.
+//!
+//! In text mode, Simple Text Output draws on the framebuffer. Switching to
+//! graphics mode hands the framebuffer over to the GOP client: text output
+//! (and the framebuffer log) only go to the serial port until the loader
+//! switches back, which clears the screen for a fresh text console.
 
 use core::ffi::c_void;
 use r_efi::efi::{Boolean, Guid, Status};
 
+use super::console;
+use crate::efi::cell::EfiCell;
 use crate::efi::utils::allocate_protocol_with_log;
 
 /// Console Control Protocol GUID
@@ -41,13 +48,18 @@ pub struct ConsoleControlProtocol {
         gop_uga_exists: *mut Boolean,
         std_in_locked: *mut Boolean,
     ) -> Status,
-    pub set_mode: extern "efiapi" fn(this: *mut ConsoleControlProtocol, mode: ScreenMode) -> Status,
+    pub set_mode: extern "efiapi" fn(this: *mut ConsoleControlProtocol, mode: u32) -> Status,
     pub lock_std_in:
         extern "efiapi" fn(this: *mut ConsoleControlProtocol, password: *mut u16) -> Status,
 }
 
-/// Current screen mode (we start in graphics mode since we have a framebuffer)
-static mut CURRENT_MODE: ScreenMode = ScreenMode::Graphics;
+/// Current screen mode
+static CURRENT_MODE: EfiCell<ScreenMode> = EfiCell::new(ScreenMode::Text);
+
+/// Check whether text output may draw on the framebuffer
+pub fn is_text_mode() -> bool {
+    CURRENT_MODE.get() == ScreenMode::Text
+}
 
 /// Get the current console mode
 extern "efiapi" fn console_get_mode(
@@ -58,7 +70,7 @@ extern "efiapi" fn console_get_mode(
 ) -> Status {
     if !mode.is_null() {
         unsafe {
-            *mode = CURRENT_MODE;
+            *mode = CURRENT_MODE.get();
         }
     }
 
@@ -80,21 +92,22 @@ extern "efiapi" fn console_get_mode(
 }
 
 /// Set the console mode
-extern "efiapi" fn console_set_mode(
-    _this: *mut ConsoleControlProtocol,
-    mode: ScreenMode,
-) -> Status {
-    if mode as u32 >= ScreenMode::MaxValue as u32 {
-        return Status::INVALID_PARAMETER;
+extern "efiapi" fn console_set_mode(_this: *mut ConsoleControlProtocol, mode: u32) -> Status {
+    let mode = match mode {
+        0 => ScreenMode::Text,
+        1 => ScreenMode::Graphics,
+        _ => return Status::INVALID_PARAMETER,
+    };
+    log::debug!("ConsoleControl.SetMode({:?})", mode);
+
+    let previous = CURRENT_MODE.get();
+    CURRENT_MODE.set(mode);
+
+    // The GOP client may have drawn anything, start text over on a clean screen
+    if previous == ScreenMode::Graphics && mode == ScreenMode::Text {
+        console::clear_framebuffer();
     }
 
-    unsafe {
-        CURRENT_MODE = mode;
-    }
-
-    // In a full implementation, we would switch between text and graphics
-    // rendering here. For now, we just track the mode.
-
     Status::SUCCESS
 }
 
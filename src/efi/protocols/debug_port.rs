@@ -0,0 +1,135 @@
+//! EFI Debug Port Protocol
+//!
+//! A byte pipe over the serial port for debug agents and loaders that want
+//! to talk to a host without taking over the UART. It shares the port with
+//! Serial IO and the log, so it runs at the settings coreboot chose.
+//!
+//! Reference: UEFI Specification 2.10, Section 18.3
+
+use core::ffi::c_void;
+
+use r_efi::efi::{Guid, Status};
+
+use crate::drivers::serial;
+use crate::efi::utils::allocate_protocol_with_log;
+use crate::time::Timeout;
+
+/// Debug Port Protocol GUID
+/// {EBA4E8D2-3858-41EC-A281-2647BA9660D0}
+pub const DEBUG_PORT_PROTOCOL_GUID: Guid = Guid::from_fields(
+    0xEBA4E8D2,
+    0x3858,
+    0x41EC,
+    0xA2,
+    0x81,
+    &[0x26, 0x47, 0xBA, 0x96, 0x60, 0xD0],
+);
+
+/// EFI Debug Port Protocol structure
+#[repr(C)]
+pub struct Protocol {
+    pub reset: extern "efiapi" fn(this: *mut Protocol) -> Status,
+    pub write: extern "efiapi" fn(
+        this: *mut Protocol,
+        timeout: u32,
+        buffer_size: *mut usize,
+        buffer: *const c_void,
+    ) -> Status,
+    pub read: extern "efiapi" fn(
+        this: *mut Protocol,
+        timeout: u32,
+        buffer_size: *mut usize,
+        buffer: *mut c_void,
+    ) -> Status,
+    pub poll: extern "efiapi" fn(this: *mut Protocol) -> Status,
+}
+
+/// Reset the debug port, dropping unread input
+extern "efiapi" fn debug_port_reset(_this: *mut Protocol) -> Status {
+    while serial::try_read().is_some() {}
+    Status::SUCCESS
+}
+
+/// Write bytes, giving each up to `timeout` microseconds to be accepted
+extern "efiapi" fn debug_port_write(
+    _this: *mut Protocol,
+    timeout: u32,
+    buffer_size: *mut usize,
+    buffer: *const c_void,
+) -> Status {
+    if buffer_size.is_null() || buffer.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let size = unsafe { *buffer_size };
+    let data = unsafe { core::slice::from_raw_parts(buffer as *const u8, size) };
+
+    for (written, &byte) in data.iter().enumerate() {
+        let deadline = Timeout::from_us(timeout as u64);
+        while !serial::can_send() {
+            if deadline.is_expired() {
+                unsafe { *buffer_size = written };
+                return Status::TIMEOUT;
+            }
+            core::hint::spin_loop();
+        }
+        serial::write_byte(byte);
+    }
+
+    Status::SUCCESS
+}
+
+/// Read up to `*buffer_size` bytes, waiting at most `timeout` microseconds
+/// for each
+extern "efiapi" fn debug_port_read(
+    _this: *mut Protocol,
+    timeout: u32,
+    buffer_size: *mut usize,
+    buffer: *mut c_void,
+) -> Status {
+    if buffer_size.is_null() || buffer.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let size = unsafe { *buffer_size };
+    let data = unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, size) };
+
+    for (read, slot) in data.iter_mut().enumerate() {
+        let deadline = Timeout::from_us(timeout as u64);
+        loop {
+            if let Some(byte) = serial::try_read() {
+                *slot = byte;
+                break;
+            }
+            if deadline.is_expired() {
+                unsafe { *buffer_size = read };
+                return Status::TIMEOUT;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    Status::SUCCESS
+}
+
+/// Check whether input is waiting
+extern "efiapi" fn debug_port_poll(_this: *mut Protocol) -> Status {
+    if serial::has_input() {
+        Status::SUCCESS
+    } else {
+        Status::NOT_READY
+    }
+}
+
+/// Create the Debug Port Protocol
+///
+/// # Returns
+/// A pointer to the protocol instance, or null on allocation failure
+pub fn create_protocol() -> *mut Protocol {
+    allocate_protocol_with_log::<Protocol>("DebugPortProtocol", |p| {
+        p.reset = debug_port_reset;
+        p.write = debug_port_write;
+        p.read = debug_port_read;
+        p.poll = debug_port_poll;
+    })
+}
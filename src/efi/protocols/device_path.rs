@@ -11,7 +11,7 @@ use r_efi::protocols::device_path::{
     self, End, HardDriveMedia, Media, Protocol, TYPE_END, TYPE_MEDIA,
 };
 
-use crate::efi::allocator::{allocate_pool, MemoryType};
+use crate::efi::allocator::{MemoryType, allocate_pool};
 
 /// Re-export the GUID for external use
 pub const DEVICE_PATH_PROTOCOL_GUID: Guid = device_path::PROTOCOL_GUID;
@@ -199,7 +199,7 @@ impl UsbDevicePathNode {
 
 /// Create an End device path node (safe)
 #[inline]
-const fn create_end_node() -> End {
+pub const fn create_end_node() -> End {
     End {
         header: Protocol {
             r#type: TYPE_END,
@@ -357,6 +357,73 @@ pub fn create_usb_partition_device_path(
     dest as *mut Protocol
 }
 
+/// Full USB CD-ROM device path: ACPI + PCI + USB + CDROM + End
+#[repr(C, packed)]
+pub struct FullUsbCdromDevicePath {
+    pub acpi: AcpiDevicePathNode,
+    pub pci: PciDevicePathNode,
+    pub usb: UsbDevicePathNode,
+    pub cdrom: CdromDevicePathNode,
+    pub end: End,
+}
+
+/// Create a device path for a CD-ROM El Torito boot image on USB
+///
+/// Creates a device path: ACPI(PNP0A03,0)/PCI(dev,func)/USB(port,0)/CDROM(entry,start,size)/End
+///
+/// # Arguments
+/// * `pci_device` - PCI device number of the USB controller
+/// * `pci_function` - PCI function number
+/// * `usb_port` - USB port number
+/// * `boot_entry` - El Torito boot catalog entry number
+/// * `partition_start` - Start LBA of the boot image
+/// * `partition_size` - Size of the boot image in blocks
+///
+/// # Returns
+/// A pointer to the device path protocol, or null on failure
+pub fn create_usb_cdrom_device_path(
+    pci_device: u8,
+    pci_function: u8,
+    usb_port: u8,
+    boot_entry: u32,
+    partition_start: u64,
+    partition_size: u64,
+) -> *mut Protocol {
+    let size = core::mem::size_of::<FullUsbCdromDevicePath>();
+
+    let dest = match allocate_pool(MemoryType::BootServicesData, size) {
+        Ok(p) => p as *mut FullUsbCdromDevicePath,
+        Err(_) => {
+            log::error!("Failed to allocate USB CDROM device path");
+            return core::ptr::null_mut();
+        }
+    };
+
+    // Build the device path on the stack (safe), then write to allocated memory
+    let device_path = FullUsbCdromDevicePath {
+        acpi: AcpiDevicePathNode::new(0),
+        pci: PciDevicePathNode::new(pci_device, pci_function),
+        usb: UsbDevicePathNode::new(usb_port, 0),
+        cdrom: CdromDevicePathNode::new(boot_entry, partition_start, partition_size),
+        end: create_end_node(),
+    };
+
+    // Safety: dest points to valid, properly aligned memory of sufficient size
+    unsafe { ptr::write(dest, device_path) };
+
+    log::debug!(
+        "Created USB CDROM device path: ACPI/PCI({:02x},{:x})/USB({},0)/CDROM({},{},{})",
+        pci_device,
+        pci_function,
+        usb_port,
+        boot_entry,
+        partition_start,
+        partition_size
+    );
+
+    dest as *mut Protocol
+}
+
 /// Create a minimal "end-only" device path
 ///
 /// This is the simplest possible device path, just an end node.
@@ -820,6 +887,133 @@ pub fn create_sata_cdrom_device_path(
     dest as *mut Protocol
 }
 
+// ============================================================================
+// RAM Disk Device Paths
+// ============================================================================
+
+/// RAM Disk Device Path Node (UEFI Spec 10.3.5.9)
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct RamDiskDevicePathNode {
+    pub r#type: u8,
+    pub sub_type: u8,
+    pub length: [u8; 2],
+    /// First byte of the RAM disk
+    pub starting_address: u64,
+    /// Last byte of the RAM disk
+    pub ending_address: u64,
+    /// Virtual disk or CD GUID
+    pub disk_type: [u8; 16],
+    /// Instance number of the RAM disk
+    pub instance: u16,
+}
+
+impl RamDiskDevicePathNode {
+    /// Create a RAM disk device path node for `size` bytes at `address`
+    #[inline]
+    pub const fn new(address: u64, size: u64, disk_type: Guid, instance: u16) -> Self {
+        Self {
+            r#type: TYPE_MEDIA,
+            sub_type: Media::SUBTYPE_RAM_DISK,
+            length: (core::mem::size_of::<Self>() as u16).to_le_bytes(),
+            starting_address: address,
+            ending_address: address + size - 1,
+            disk_type: *disk_type.as_bytes(),
+            instance,
+        }
+    }
+
+    /// The disk type GUID
+    pub fn disk_type(&self) -> Guid {
+        Guid::from_bytes(&self.disk_type)
+    }
+}
+
+/// Create a device path for a RAM disk or a partition on it
+///
+/// Creates a device path: <parent>/RamDisk(start,end,type,instance)/End, or
+/// with an HD node for a partition or a CDROM node for an El Torito boot
+/// image before the End node.
+///
+/// # Arguments
+/// * `parent` - Device path the RAM disk was loaded from, or null
+/// * `node` - The RAM disk node
+/// * `partition` - Partition number (1-based, 0 for El Torito) and partition
+///
+/// # Returns
+/// A pointer to the device path protocol, or null on failure
+///
+/// # Safety
+/// `parent` must be null or point to a valid device path terminated by an
+/// End node.
+pub unsafe fn create_ram_disk_device_path(
+    parent: *const Protocol,
+    node: RamDiskDevicePathNode,
+    partition: Option<(u32, &crate::fs::gpt::Partition)>,
+) -> *mut Protocol {
+    let parent_size = if parent.is_null() {
+        0
+    } else {
+        match unsafe { path_size(parent) } {
+            Some(size) => size,
+            None => {
+                log::error!("RAM disk parent device path is invalid");
+                return core::ptr::null_mut();
+            }
+        }
+    };
+    let media_size = match partition {
+        Some((0, _)) => core::mem::size_of::<CdromDevicePathNode>(),
+        Some(_) => core::mem::size_of::<HardDriveMedia>(),
+        None => 0,
+    };
+    let node_size = core::mem::size_of::<RamDiskDevicePathNode>();
+    let size = parent_size + node_size + media_size + core::mem::size_of::<End>();
+
+    let dest = match allocate_pool(MemoryType::BootServicesData, size) {
+        Ok(p) => p,
+        Err(_) => {
+            log::error!("Failed to allocate RAM disk device path");
+            return core::ptr::null_mut();
+        }
+    };
+
+    // Safety: dest has room for all nodes, which are written unaligned
+    unsafe {
+        ptr::copy_nonoverlapping(parent as *const u8, dest, parent_size);
+        let mut next = dest.add(parent_size);
+        ptr::write_unaligned(next as *mut RamDiskDevicePathNode, node);
+        next = next.add(node_size);
+        match partition {
+            Some((0, partition)) => ptr::write_unaligned(
+                next as *mut CdromDevicePathNode,
+                CdromDevicePathNode::new(0, partition.first_lba, partition.size_sectors()),
+            ),
+            Some((number, partition)) => ptr::write_unaligned(
+                next as *mut HardDriveMedia,
+                create_hard_drive_node(
+                    number,
+                    partition.first_lba,
+                    partition.size_sectors(),
+                    &partition.partition_guid,
+                ),
+            ),
+            None => {}
+        }
+        ptr::write_unaligned(next.add(media_size) as *mut End, create_end_node());
+    }
+
+    let (start, instance) = (node.starting_address, node.instance);
+    log::debug!(
+        "Created RAM disk device path: RamDisk({:#x},{})/{:?}",
+        start,
+        instance,
+        partition.map(|(number, _)| number)
+    );
+
+    dest as *mut Protocol
+}
+
 // ============================================================================
 // File Path Device Paths
 // ============================================================================
@@ -920,3 +1114,295 @@ pub fn create_video_device_path() -> *mut Protocol {
 
     dest as *mut Protocol
 }
+
+// ============================================================================
+// Device Path Parsing
+// ============================================================================
+
+/// Maximum number of nodes walked in a device path
+///
+/// Bounds walks over device paths handed in by EFI applications.
+const MAX_NODES: usize = 64;
+
+/// Get the length of a device path node
+///
+/// # Safety
+/// `node` must point to a readable device path node header.
+unsafe fn node_length(node: *const Protocol) -> usize {
+    u16::from_le_bytes(unsafe { (*node).length }) as usize
+}
+
+/// Get the size of a device path without its End node
+///
+/// # Safety
+/// `path` must point to a valid device path terminated by an End node.
+unsafe fn path_size(path: *const Protocol) -> Option<usize> {
+    let mut size = 0;
+
+    for _ in 0..MAX_NODES {
+        let node = unsafe { (path as *const u8).add(size) } as *const Protocol;
+        if unsafe { (*node).r#type } == TYPE_END {
+            return Some(size);
+        }
+
+        let len = unsafe { node_length(node) };
+        if len < 4 {
+            return None;
+        }
+        size += len;
+    }
+
+    None
+}
+
+/// Get the RAM disk node of a device path
+///
+/// # Safety
+/// `path` must point to a valid device path terminated by an End node.
+pub unsafe fn ram_disk_node(path: *const Protocol) -> Option<RamDiskDevicePathNode> {
+    let mut node = path as *const u8;
+
+    for _ in 0..MAX_NODES {
+        let header = node as *const Protocol;
+        let (node_type, sub_type) = unsafe { ((*header).r#type, (*header).sub_type) };
+        if node_type == TYPE_END {
+            return None;
+        }
+
+        let len = unsafe { node_length(header) };
+        if len < 4 {
+            return None;
+        }
+        if node_type == TYPE_MEDIA
+            && sub_type == Media::SUBTYPE_RAM_DISK
+            && len >= size_of::<RamDiskDevicePathNode>()
+        {
+            return Some(unsafe { (node as *const RamDiskDevicePathNode).read_unaligned() });
+        }
+
+        node = unsafe { node.add(len) };
+    }
+
+    None
+}
+
+/// Match the start of a device path against another device path
+///
+/// Returns the rest of `path` after the nodes of `prefix`, or `None` if
+/// `path` doesn't start with them.
+///
+/// # Safety
+/// Both pointers must point to valid device paths terminated by an End node.
+pub unsafe fn strip_prefix(
+    prefix: *const Protocol,
+    path: *const Protocol,
+) -> Option<*mut Protocol> {
+    let mut prefix = prefix as *const u8;
+    let mut path = path as *const u8;
+
+    for _ in 0..MAX_NODES {
+        let node = prefix as *const Protocol;
+        if unsafe { (*node).r#type } == TYPE_END {
+            return Some(path as *mut Protocol);
+        }
+
+        let len = unsafe { node_length(node) };
+        if len < 4 || len != unsafe { node_length(path as *const Protocol) } {
+            return None;
+        }
+        let (a, b) = unsafe {
+            (
+                core::slice::from_raw_parts(prefix, len),
+                core::slice::from_raw_parts(path, len),
+            )
+        };
+        if a != b {
+            return None;
+        }
+
+        prefix = unsafe { prefix.add(len) };
+        path = unsafe { path.add(len) };
+    }
+
+    None
+}
+
+/// Get the GUID of the firmware file a device path points to
+///
+/// PI firmware loads drivers from paths ending in a PIWG Firmware File node
+/// holding the file's GUID. Returns `None` for paths without one.
+///
+/// # Safety
+/// `path` must point to a valid device path terminated by an End node.
+pub unsafe fn firmware_file_guid(path: *const Protocol) -> Option<Guid> {
+    let mut node = path as *const u8;
+
+    for _ in 0..MAX_NODES {
+        let header = node as *const Protocol;
+        let (node_type, sub_type) = unsafe { ((*header).r#type, (*header).sub_type) };
+        if node_type == TYPE_END {
+            return None;
+        }
+
+        let len = unsafe { node_length(header) };
+        if len < 4 {
+            return None;
+        }
+        if node_type == TYPE_MEDIA
+            && sub_type == Media::SUBTYPE_PIWG_FIRMWARE_FILE
+            && len >= 4 + size_of::<Guid>()
+        {
+            return Some(unsafe { (node.add(4) as *const Guid).read_unaligned() });
+        }
+
+        node = unsafe { node.add(len) };
+    }
+
+    None
+}
+
+/// Get the file name described by a sequence of File Path nodes
+///
+/// Joins the path names of the Media File Path nodes up to the End node with
+/// backslashes. Returns an empty string for a path that is just an End node,
+/// and `None` if there are other node types or the name doesn't fit.
+///
+/// # Safety
+/// `path` must point to a valid device path terminated by an End node.
+pub unsafe fn file_path_to_str<const N: usize>(
+    path: *const Protocol,
+) -> Option<heapless::String<N>> {
+    let mut name = heapless::String::new();
+    let mut node = path as *const u8;
+
+    for _ in 0..MAX_NODES {
+        let header = node as *const Protocol;
+        let (node_type, sub_type) = unsafe { ((*header).r#type, (*header).sub_type) };
+        if node_type == TYPE_END {
+            return Some(name);
+        }
+        if node_type != TYPE_MEDIA || sub_type != Media::SUBTYPE_FILE_PATH {
+            return None;
+        }
+
+        let len = unsafe { node_length(header) };
+        if len < 4 {
+            return None;
+        }
+
+        // The path name is UCS-2 and may be unaligned
+        let chars = (len - 4) / 2;
+        let mut separator = !name.is_empty() && !name.ends_with('\\');
+        for i in 0..chars {
+            let unit = unsafe { (node.add(4 + i * 2) as *const u16).read_unaligned() };
+            if unit == 0 {
+                break;
+            }
+            let c = char::from_u32(unit as u32)?;
+            let c = if c == '/' { '\\' } else { c };
+            if separator {
+                if c != '\\' {
+                    name.push('\\').ok()?;
+                }
+                separator = false;
+            }
+            name.push(c).ok()?;
+        }
+
+        node = unsafe { node.add(len) };
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Append a device path node
+    fn node(path: &mut Vec<u8>, node_type: u8, sub_type: u8, data: &[u8]) {
+        path.extend([node_type, sub_type]);
+        path.extend(((data.len() + 4) as u16).to_le_bytes());
+        path.extend(data);
+    }
+
+    /// Append a File Path node
+    fn file_node(path: &mut Vec<u8>, name: &str) {
+        let data: Vec<u8> = name
+            .encode_utf16()
+            .chain([0])
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        node(path, TYPE_MEDIA, Media::SUBTYPE_FILE_PATH, &data);
+    }
+
+    fn end(path: &mut Vec<u8>) {
+        node(path, TYPE_END, End::SUBTYPE_ENTIRE, &[]);
+    }
+
+    #[test]
+    fn strips_device_prefix() {
+        let mut device = Vec::new();
+        node(&mut device, TYPE_HARDWARE, SUBTYPE_PCI, &[0, 0x1f]);
+        let mut path = device.clone();
+        let mut other = device.clone();
+        end(&mut device);
+        file_node(&mut path, "\\EFI\\BOOT\\BOOTX64.EFI");
+        end(&mut path);
+        node(&mut other, TYPE_HARDWARE, SUBTYPE_PCI, &[0, 0x1e]);
+        end(&mut other);
+
+        let rest = unsafe { strip_prefix(device.as_ptr().cast(), path.as_ptr().cast()) };
+        assert_eq!(rest, Some(path[6..].as_ptr() as *mut Protocol));
+        let rest = unsafe { strip_prefix(other.as_ptr().cast(), path.as_ptr().cast()) };
+        assert_eq!(rest, None);
+    }
+
+    #[test]
+    fn finds_ram_disk_node() {
+        let ram_disk =
+            RamDiskDevicePathNode::new(0x1000_0000, 0x10_0000, Guid::from_bytes(&[7; 16]), 1);
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                &ram_disk as *const RamDiskDevicePathNode as *const u8,
+                size_of::<RamDiskDevicePathNode>(),
+            )
+        };
+        assert_eq!(bytes.len(), 38);
+
+        let mut path = Vec::new();
+        node(&mut path, TYPE_HARDWARE, SUBTYPE_PCI, &[0, 0x1f]);
+        path.extend(bytes);
+        end(&mut path);
+        assert_eq!(unsafe { path_size(path.as_ptr().cast()) }, Some(6 + 38));
+        let found = unsafe { ram_disk_node(path.as_ptr().cast()) }.unwrap();
+        let (start, end_address) = (found.starting_address, found.ending_address);
+        assert_eq!((start, end_address), (0x1000_0000, 0x100f_ffff));
+        assert_eq!(found.disk_type(), Guid::from_bytes(&[7; 16]));
+
+        let mut other = Vec::new();
+        file_node(&mut other, "\\EFI");
+        end(&mut other);
+        assert!(unsafe { ram_disk_node(other.as_ptr().cast()) }.is_none());
+    }
+
+    #[test]
+    fn joins_file_path_nodes() {
+        let mut path = Vec::new();
+        file_node(&mut path, "\\EFI\\BOOT");
+        file_node(&mut path, "grubx64.efi");
+        end(&mut path);
+        let name = unsafe { file_path_to_str::<64>(path.as_ptr().cast()) };
+        assert_eq!(name.as_deref(), Some("\\EFI\\BOOT\\grubx64.efi"));
+
+        let mut empty = Vec::new();
+        end(&mut empty);
+        let name = unsafe { file_path_to_str::<64>(empty.as_ptr().cast()) };
+        assert_eq!(name.as_deref(), Some(""));
+
+        let mut device = Vec::new();
+        node(&mut device, TYPE_HARDWARE, SUBTYPE_PCI, &[0, 0x1f]);
+        end(&mut device);
+        assert!(unsafe { file_path_to_str::<64>(device.as_ptr().cast()) }.is_none());
+    }
+}
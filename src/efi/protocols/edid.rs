@@ -0,0 +1,108 @@
+//! EFI EDID Discovered and EDID Active Protocols
+//!
+//! Installed on the GOP handle when the display's EDID is known, so loaders
+//! can look up the panel's native resolution. There is no EDID override, so
+//! both protocols carry the EDID the display reports.
+//!
+//! Reference: UEFI Specification 2.10, Section 12.9.2
+
+use r_efi::efi::{Guid, Status};
+
+use super::graphics_output::{self, GRAPHICS_OUTPUT_GUID};
+use crate::drivers::edid::{self, Edid};
+use crate::efi::allocator::{MemoryType, allocate_pool};
+use crate::efi::boot_services;
+use crate::efi::handles;
+use crate::efi::utils::allocate_protocol_with_log;
+
+/// EFI_EDID_DISCOVERED_PROTOCOL GUID
+/// {1C0C34F6-D380-41FA-A049-8AD06C1A66AA}
+pub const EDID_DISCOVERED_GUID: Guid = Guid::from_fields(
+    0x1c0c34f6,
+    0xd380,
+    0x41fa,
+    0xa0,
+    0x49,
+    &[0x8a, 0xd0, 0x6c, 0x1a, 0x66, 0xaa],
+);
+
+/// EFI_EDID_ACTIVE_PROTOCOL GUID
+/// {BD8C1056-9F36-44EC-92A8-A6337F817986}
+pub const EDID_ACTIVE_GUID: Guid = Guid::from_fields(
+    0xbd8c1056,
+    0x9f36,
+    0x44ec,
+    0x92,
+    0xa8,
+    &[0xa6, 0x33, 0x7f, 0x81, 0x79, 0x86],
+);
+
+/// EDID Discovered and EDID Active Protocol structure
+#[repr(C)]
+pub struct Protocol {
+    pub size_of_edid: u32,
+    pub edid: *mut u8,
+}
+
+/// Find the display's EDID and install the EDID protocols
+///
+/// Needs the PCI devices enumerated. The resolutions the EDID lists are
+/// added to the GOP modes.
+pub fn init() {
+    let Some(edid) = edid::discover() else {
+        log::debug!("No display EDID found");
+        return;
+    };
+    let Some(handle) = handles::with(|db| db.handles_with(&GRAPHICS_OUTPUT_GUID).next()) else {
+        return;
+    };
+
+    let bytes = edid.as_bytes();
+    let buffer = match allocate_pool(MemoryType::BootServicesData, bytes.len()) {
+        Ok(buffer) => buffer,
+        Err(status) => {
+            log::error!("Failed to allocate EDID buffer: {:?}", status);
+            return;
+        }
+    };
+    unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), buffer, bytes.len()) };
+
+    for (guid, name) in [
+        (&EDID_DISCOVERED_GUID, "EDID Discovered"),
+        (&EDID_ACTIVE_GUID, "EDID Active"),
+    ] {
+        let protocol = allocate_protocol_with_log::<Protocol>(name, |p| {
+            p.size_of_edid = bytes.len() as u32;
+            p.edid = buffer;
+        });
+        if protocol.is_null() {
+            return;
+        }
+        let status = boot_services::install_protocol(handle, guid, protocol as *mut _);
+        if status != Status::SUCCESS {
+            log::error!("Failed to install {} protocol: {:?}", name, status);
+            return;
+        }
+    }
+
+    log_preferred_mode(&edid);
+    graphics_output::add_modes(&edid.modes());
+}
+
+/// Log the display's native resolution, warning if coreboot's framebuffer
+/// can't show it
+fn log_preferred_mode(edid: &Edid) {
+    let Some((width, height)) = edid.preferred_mode() else {
+        return;
+    };
+    log::info!("Display EDID: native resolution {}x{}", width, height);
+    if let Some(fb) = crate::coreboot::get_framebuffer()
+        && (fb.x_resolution, fb.y_resolution) != (width, height)
+    {
+        log::warn!(
+            "Framebuffer is {}x{}, not the display's native resolution",
+            fb.x_resolution,
+            fb.y_resolution
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! EFI Firmware Volume 2 Protocol stub
+//!
+//! Drivers built for PI firmware, vendor GOP drivers in particular, look for
+//! the firmware volume they were loaded from to read optional policy or VBT
+//! sections, and some assert when there is none. CrabEFI has no firmware
+//! volumes, so this is an empty, read-only volume: every lookup reports
+//! `NOT_FOUND`, which these drivers handle by using their built-in defaults.
+//!
+//! Reference: UEFI PI Specification 1.8, Volume 3, Section 3.4.1
+
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use r_efi::efi::{Guid, Handle, Status};
+
+use crate::efi::boot_services::GuidFmt;
+use crate::efi::utils::allocate_protocol_with_log;
+
+/// Firmware Volume 2 Protocol GUID
+/// {220E73B6-6BDB-4413-8405-B974B108619A}
+pub const FIRMWARE_VOLUME2_PROTOCOL_GUID: Guid = Guid::from_fields(
+    0x220E73B6,
+    0x6BDB,
+    0x4413,
+    0x84,
+    0x05,
+    &[0xB9, 0x74, 0xB1, 0x08, 0x61, 0x9A],
+);
+
+/// Volume attributes: reads are possible and enabled
+const FV2_READ_ENABLE_CAP: u64 = 1 << 1;
+const FV2_READ_STATUS: u64 = 1 << 2;
+
+/// EFI Firmware Volume 2 Protocol structure
+#[repr(C)]
+pub struct Protocol {
+    pub get_volume_attributes:
+        extern "efiapi" fn(this: *const Protocol, attributes: *mut u64) -> Status,
+    pub set_volume_attributes:
+        extern "efiapi" fn(this: *const Protocol, attributes: *mut u64) -> Status,
+    pub read_file: extern "efiapi" fn(
+        this: *const Protocol,
+        name: *const Guid,
+        buffer: *mut *mut c_void,
+        buffer_size: *mut usize,
+        found_type: *mut u8,
+        file_attributes: *mut u32,
+        authentication_status: *mut u32,
+    ) -> Status,
+    pub read_section: extern "efiapi" fn(
+        this: *const Protocol,
+        name: *const Guid,
+        section_type: u8,
+        section_instance: usize,
+        buffer: *mut *mut c_void,
+        buffer_size: *mut usize,
+        authentication_status: *mut u32,
+    ) -> Status,
+    pub write_file: extern "efiapi" fn(
+        this: *const Protocol,
+        number_of_files: u32,
+        write_policy: u32,
+        file_data: *mut c_void,
+    ) -> Status,
+    pub get_next_file: extern "efiapi" fn(
+        this: *const Protocol,
+        key: *mut c_void,
+        file_type: *mut u8,
+        name: *mut Guid,
+        attributes: *mut u32,
+        size: *mut usize,
+    ) -> Status,
+    pub key_size: u32,
+    pub parent_handle: Handle,
+    pub get_info: extern "efiapi" fn(
+        this: *const Protocol,
+        information_type: *const Guid,
+        buffer_size: *mut usize,
+        buffer: *mut c_void,
+    ) -> Status,
+    pub set_info: extern "efiapi" fn(
+        this: *const Protocol,
+        information_type: *const Guid,
+        buffer_size: usize,
+        buffer: *const c_void,
+    ) -> Status,
+}
+
+extern "efiapi" fn fv_get_volume_attributes(
+    _this: *const Protocol,
+    attributes: *mut u64,
+) -> Status {
+    if attributes.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    unsafe { *attributes = FV2_READ_ENABLE_CAP | FV2_READ_STATUS };
+    Status::SUCCESS
+}
+
+extern "efiapi" fn fv_set_volume_attributes(
+    _this: *const Protocol,
+    _attributes: *mut u64,
+) -> Status {
+    Status::UNSUPPORTED
+}
+
+extern "efiapi" fn fv_read_file(
+    _this: *const Protocol,
+    name: *const Guid,
+    _buffer: *mut *mut c_void,
+    _buffer_size: *mut usize,
+    _found_type: *mut u8,
+    _file_attributes: *mut u32,
+    _authentication_status: *mut u32,
+) -> Status {
+    if !name.is_null() {
+        log::debug!("FV2.ReadFile({}) -> NOT_FOUND", GuidFmt(unsafe { *name }));
+    }
+    Status::NOT_FOUND
+}
+
+extern "efiapi" fn fv_read_section(
+    _this: *const Protocol,
+    name: *const Guid,
+    section_type: u8,
+    _section_instance: usize,
+    _buffer: *mut *mut c_void,
+    _buffer_size: *mut usize,
+    _authentication_status: *mut u32,
+) -> Status {
+    if !name.is_null() {
+        log::debug!(
+            "FV2.ReadSection({}, type={:#x}) -> NOT_FOUND",
+            GuidFmt(unsafe { *name }),
+            section_type
+        );
+    }
+    Status::NOT_FOUND
+}
+
+extern "efiapi" fn fv_write_file(
+    _this: *const Protocol,
+    _number_of_files: u32,
+    _write_policy: u32,
+    _file_data: *mut c_void,
+) -> Status {
+    Status::WRITE_PROTECTED
+}
+
+/// The volume is empty, so there is never a next file
+extern "efiapi" fn fv_get_next_file(
+    _this: *const Protocol,
+    _key: *mut c_void,
+    _file_type: *mut u8,
+    _name: *mut Guid,
+    _attributes: *mut u32,
+    _size: *mut usize,
+) -> Status {
+    Status::NOT_FOUND
+}
+
+extern "efiapi" fn fv_get_info(
+    _this: *const Protocol,
+    _information_type: *const Guid,
+    _buffer_size: *mut usize,
+    _buffer: *mut c_void,
+) -> Status {
+    Status::UNSUPPORTED
+}
+
+extern "efiapi" fn fv_set_info(
+    _this: *const Protocol,
+    _information_type: *const Guid,
+    _buffer_size: usize,
+    _buffer: *const c_void,
+) -> Status {
+    Status::WRITE_PROTECTED
+}
+
+/// The empty volume, created on first use and shared by all handles
+static EMPTY_VOLUME: AtomicPtr<Protocol> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Get the empty firmware volume
+///
+/// # Returns
+/// A pointer to the protocol instance, or null on allocation failure
+pub fn empty_volume() -> *mut Protocol {
+    let existing = EMPTY_VOLUME.load(Ordering::Acquire);
+    if !existing.is_null() {
+        return existing;
+    }
+
+    let ptr = allocate_protocol_with_log::<Protocol>("FirmwareVolume2Protocol", |p| {
+        p.get_volume_attributes = fv_get_volume_attributes;
+        p.set_volume_attributes = fv_set_volume_attributes;
+        p.read_file = fv_read_file;
+        p.read_section = fv_read_section;
+        p.write_file = fv_write_file;
+        p.get_next_file = fv_get_next_file;
+        p.key_size = 8;
+        p.parent_handle = core::ptr::null_mut();
+        p.get_info = fv_get_info;
+        p.set_info = fv_set_info;
+    });
+    EMPTY_VOLUME.store(ptr, Ordering::Release);
+    ptr
+}
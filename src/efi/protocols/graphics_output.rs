@@ -3,11 +3,26 @@
 //! This module implements the UEFI Graphics Output Protocol, which provides
 //! framebuffer access to the OS. We expose the framebuffer information from
 //! coreboot tables.
+//!
+//! coreboot sets up a single mode and there is no modesetting, so mode 0 is
+//! coreboot's framebuffer. Once the display's EDID is known, the smaller
+//! resolutions it lists are offered as further modes: windows centered in
+//! the framebuffer, with the rest of the screen left black.
+//!
+//! With the [display](crate::display) rotated, the only mode is the rotated
+//! screen. A linear framebuffer can't be rotated, so the mode is Blt-only
+//! and Blt maps each pixel to its place on the framebuffer. Loaders and
+//! OSes that need a framebuffer to draw on themselves then have to bring
+//! their own display driver.
 
+use heapless::Vec;
 use r_efi::efi::{Guid, Status};
 
+use crate::arch::x86_64::sse;
 use crate::coreboot::FramebufferInfo;
+use crate::display::{self, Rotation, View};
 use crate::efi::allocator::{MemoryType, allocate_pool};
+use crate::efi::cell::EfiCell;
 use crate::efi::utils::allocate_protocol_with_log;
 use crate::state;
 
@@ -129,6 +144,112 @@ pub struct GraphicsOutputProtocol {
     pub mode: *mut GopMode,
 }
 
+/// Most modes offered, including the native one
+const MAX_MODES: usize = 16;
+
+/// Modes offered by the protocol
+struct ModeList {
+    /// The framebuffer as coreboot set it up
+    native: Option<FramebufferInfo>,
+    /// Resolution of each mode, mode 0 first
+    resolutions: Vec<(u32, u32), MAX_MODES>,
+    /// Mode structure of the protocol
+    mode: *mut GopMode,
+}
+
+static MODES: EfiCell<ModeList> = EfiCell::new(ModeList {
+    native: None,
+    resolutions: Vec::new(),
+    mode: core::ptr::null_mut(),
+});
+
+/// Framebuffer of a window of `native` centered on the screen
+fn window(native: &FramebufferInfo, (width, height): (u32, u32)) -> FramebufferInfo {
+    let bytes_per_pixel = native.bits_per_pixel.div_ceil(8) as u64;
+    let x = (native.x_resolution - width) / 2;
+    let y = (native.y_resolution - height) / 2;
+    FramebufferInfo {
+        physical_address: native.physical_address
+            + y as u64 * native.bytes_per_line as u64
+            + x as u64 * bytes_per_pixel,
+        x_resolution: width,
+        y_resolution: height,
+        ..native.clone()
+    }
+}
+
+/// Framebuffer of mode `mode_number`, and the bytes from its base to the
+/// end of the framebuffer
+fn mode_framebuffer(mode_number: u32) -> Option<(FramebufferInfo, u64)> {
+    MODES.with(|modes| {
+        let native = modes.native.as_ref()?;
+        let fb = window(native, *modes.resolutions.get(mode_number as usize)?);
+        let size = native.physical_address + native.size() - fb.physical_address;
+        Some((fb, size))
+    })
+}
+
+/// Mode information describing `fb`
+fn mode_info(fb: &FramebufferInfo) -> GopModeInfo {
+    let view = display::view();
+    if view.rotation != Rotation::Normal {
+        let (width, height) = view.size(fb);
+        return GopModeInfo {
+            version: 0,
+            horizontal_resolution: width,
+            vertical_resolution: height,
+            pixel_format: PixelFormat::BltOnly,
+            pixel_information: PixelBitmask::default(),
+            pixels_per_scan_line: width,
+        };
+    }
+    let (pixel_format, pixel_information) = mode_pixel_format(fb);
+    GopModeInfo {
+        version: 0,
+        horizontal_resolution: fb.x_resolution,
+        vertical_resolution: fb.y_resolution,
+        pixel_format,
+        pixel_information,
+        pixels_per_scan_line: fb.bytes_per_line / fb.bits_per_pixel.div_ceil(8) as u32,
+    }
+}
+
+/// Offer the resolutions that fit in the framebuffer as further modes
+pub fn add_modes(resolutions: &[(u32, u32)]) {
+    MODES.with(|modes| {
+        let Some(native) = modes.native.as_ref() else {
+            return;
+        };
+        // Windows would need rotating too
+        if display::view().rotation != Rotation::Normal {
+            return;
+        }
+        let (max_width, max_height) = (native.x_resolution, native.y_resolution);
+        for &(width, height) in resolutions {
+            if width == 0
+                || height == 0
+                || width > max_width
+                || height > max_height
+                || modes.resolutions.contains(&(width, height))
+            {
+                continue;
+            }
+            if modes.resolutions.push((width, height)).is_err() {
+                break;
+            }
+        }
+
+        // Largest first after the native mode
+        modes.resolutions[1..].sort_unstable_by_key(|&(width, height)| {
+            core::cmp::Reverse(width as u64 * height as u64)
+        });
+        if !modes.mode.is_null() {
+            unsafe { (*modes.mode).max_mode = modes.resolutions.len() as u32 };
+        }
+        log::info!("GOP offers {} modes", modes.resolutions.len());
+    });
+}
+
 /// Query available video mode information
 extern "efiapi" fn gop_query_mode(
     this: *mut GraphicsOutputProtocol,
@@ -147,17 +268,9 @@ extern "efiapi" fn gop_query_mode(
         return Status::INVALID_PARAMETER;
     }
 
-    // We only support mode 0
-    if mode_number != 0 {
+    let Some((fb, _)) = mode_framebuffer(mode_number) else {
         return Status::INVALID_PARAMETER;
-    }
-
-    let protocol = unsafe { &*this };
-    if protocol.mode.is_null() {
-        return Status::DEVICE_ERROR;
-    }
-
-    let mode = unsafe { &*protocol.mode };
+    };
 
     // Allocate memory for the mode info copy
     let info_size = core::mem::size_of::<GopModeInfo>();
@@ -166,9 +279,8 @@ extern "efiapi" fn gop_query_mode(
         Err(_) => return Status::OUT_OF_RESOURCES,
     };
 
-    // Copy mode info
     unsafe {
-        core::ptr::copy_nonoverlapping(mode.info, info_ptr, 1);
+        info_ptr.write(mode_info(&fb));
         *size_of_info = info_size;
         *info = info_ptr;
     }
@@ -185,17 +297,159 @@ extern "efiapi" fn gop_set_mode(this: *mut GraphicsOutputProtocol, mode_number:
         return Status::INVALID_PARAMETER;
     }
 
-    // We only support mode 0 (the current mode from coreboot)
-    if mode_number != 0 {
+    let Some((fb, frame_buffer_size)) = mode_framebuffer(mode_number) else {
         return Status::UNSUPPORTED;
+    };
+    let protocol = unsafe { &*this };
+    if protocol.mode.is_null() {
+        return Status::DEVICE_ERROR;
     }
 
-    // Mode 0 is already set
-    log::debug!("  -> SUCCESS (mode already set)");
+    // The new mode starts on a black screen, all zero in any pixel format
+    if let Some(native) = MODES.with(|modes| modes.native.clone()) {
+        unsafe { core::ptr::write_bytes(native.as_ptr(), 0, native.size() as usize) };
+    }
+
+    let mode = unsafe { &mut *protocol.mode };
+    unsafe { mode.info.write(mode_info(&fb)) };
+    mode.mode = mode_number;
+    mode.frame_buffer_base = fb.physical_address;
+    mode.frame_buffer_size = frame_buffer_size as usize;
+
+    log::debug!(
+        "  -> SUCCESS ({}x{} @ {:#x})",
+        fb.x_resolution,
+        fb.y_resolution,
+        fb.physical_address
+    );
+    state::with_console_mut(|console| {
+        console.gop_framebuffer = Some(fb);
+    });
     Status::SUCCESS
 }
 
+/// Pixels converted at a time when the framebuffer isn't BGRx
+const CHUNK_PIXELS: usize = 256;
+
+/// One color channel of a framebuffer pixel
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Channel {
+    pos: u8,
+    size: u8,
+}
+
+impl Channel {
+    /// Bits of the channel within a pixel
+    fn mask(&self) -> u32 {
+        match self.size {
+            0 => 0,
+            size => (u32::MAX >> (32 - size.min(32)))
+                .checked_shl(self.pos as u32)
+                .unwrap_or(0),
+        }
+    }
+
+    /// Scale an 8-bit value to the channel and shift it into place
+    fn encode(&self, value: u8) -> u32 {
+        match self.size {
+            0 => 0,
+            size => (((value as u32) << 24) >> (32 - size.min(32))) << self.pos,
+        }
+    }
+
+    /// Extract the channel from a pixel, scaled to 8 bits
+    fn decode(&self, pixel: u32) -> u8 {
+        match self.size {
+            0 => 0,
+            size => (((pixel & self.mask()) >> self.pos) << (32 - size.min(32)) >> 24) as u8,
+        }
+    }
+}
+
+/// Layout of a framebuffer pixel, from coreboot's mask positions and sizes
+#[derive(Debug, Clone, Copy)]
+struct PixelLayout {
+    bytes_per_pixel: usize,
+    red: Channel,
+    green: Channel,
+    blue: Channel,
+}
+
+impl PixelLayout {
+    /// Layout of `fb`, if it has a pixel size Blt supports
+    fn new(fb: &FramebufferInfo) -> Option<Self> {
+        let bytes_per_pixel = fb.bits_per_pixel.div_ceil(8) as usize;
+        if !(2..=4).contains(&bytes_per_pixel) {
+            return None;
+        }
+        let channels = [
+            (fb.red_mask_pos, fb.red_mask_size),
+            (fb.green_mask_pos, fb.green_mask_size),
+            (fb.blue_mask_pos, fb.blue_mask_size),
+        ];
+        if channels
+            .iter()
+            .any(|&(pos, size)| pos as u32 + size as u32 > fb.bits_per_pixel as u32)
+        {
+            return None;
+        }
+        Some(PixelLayout {
+            bytes_per_pixel,
+            red: Channel {
+                pos: fb.red_mask_pos,
+                size: fb.red_mask_size,
+            },
+            green: Channel {
+                pos: fb.green_mask_pos,
+                size: fb.green_mask_size,
+            },
+            blue: Channel {
+                pos: fb.blue_mask_pos,
+                size: fb.blue_mask_size,
+            },
+        })
+    }
+
+    /// Whether framebuffer pixels are laid out like [`BltPixel`]
+    fn is_bgrx(&self) -> bool {
+        self.bytes_per_pixel == 4
+            && self.red == (Channel { pos: 16, size: 8 })
+            && self.green == (Channel { pos: 8, size: 8 })
+            && self.blue == (Channel { pos: 0, size: 8 })
+    }
+
+    /// Write `pixel` in framebuffer format to `out`
+    fn encode(&self, pixel: &BltPixel, out: &mut [u8]) {
+        let value = self.red.encode(pixel.red)
+            | self.green.encode(pixel.green)
+            | self.blue.encode(pixel.blue);
+        out.copy_from_slice(&value.to_le_bytes()[..self.bytes_per_pixel]);
+    }
+
+    /// Read a pixel in framebuffer format from `bytes`
+    fn decode(&self, bytes: &[u8]) -> BltPixel {
+        let mut value = [0u8; 4];
+        value[..self.bytes_per_pixel].copy_from_slice(bytes);
+        let value = u32::from_le_bytes(value);
+        BltPixel {
+            blue: self.blue.decode(value),
+            green: self.green.decode(value),
+            red: self.red.decode(value),
+            reserved: 0,
+        }
+    }
+}
+
+/// Whether `width` pixels starting at `start` stay below `limit`
+fn fits(start: usize, width: usize, limit: usize) -> bool {
+    start.checked_add(width).is_some_and(|end| end <= limit)
+}
+
 /// Block transfer (Blt) operation
+///
+/// Works a row at a time: rows of a BGRx framebuffer are copied as they
+/// are, other formats are converted in chunks of [`CHUNK_PIXELS`]. Writes
+/// to VRAM use non-temporal stores, since VRAM is usually write-combining.
 extern "efiapi" fn gop_blt(
     this: *mut GraphicsOutputProtocol,
     blt_buffer: *mut BltPixel,
@@ -229,9 +483,17 @@ extern "efiapi" fn gop_blt(
         Some(fb) => fb,
         None => return Status::DEVICE_ERROR,
     };
+    let Some(layout) = PixelLayout::new(fb) else {
+        return Status::DEVICE_ERROR;
+    };
     let fb_width = fb.x_resolution as usize;
     let fb_height = fb.y_resolution as usize;
-    let fb_ptr = fb.physical_address as *mut u8;
+    let bytes_per_pixel = layout.bytes_per_pixel;
+    let row_bytes = width * bytes_per_pixel;
+    let fb_pixel = |x: usize, y: usize| {
+        (fb.physical_address as usize + y * fb.bytes_per_line as usize + x * bytes_per_pixel)
+            as *mut u8
+    };
 
     // Calculate buffer line length
     let buffer_line_length = if delta != 0 {
@@ -240,6 +502,26 @@ extern "efiapi" fn gop_blt(
         width
     };
 
+    let view = display::view();
+    if view.rotation != Rotation::Normal {
+        let source = (source_x, source_y);
+        let destination = (destination_x, destination_y);
+        return blt_rotated(
+            fb,
+            &layout,
+            &view,
+            blt_buffer,
+            blt_operation,
+            source,
+            destination,
+            (width, height),
+            buffer_line_length,
+        );
+    }
+
+    // Framebuffer pixels converted from or to Blt pixels
+    let mut chunk = [0u8; CHUNK_PIXELS * 4];
+
     match blt_operation {
         BltOperation::VideoFill => {
             // Fill a rectangle with a single color
@@ -247,18 +529,24 @@ extern "efiapi" fn gop_blt(
                 return Status::INVALID_PARAMETER;
             }
 
-            if destination_x + width > fb_width || destination_y + height > fb_height {
+            if !fits(destination_x, width, fb_width) || !fits(destination_y, height, fb_height) {
                 return Status::INVALID_PARAMETER;
             }
 
             let pixel = unsafe { *blt_buffer };
+            for out in chunk.chunks_exact_mut(bytes_per_pixel) {
+                layout.encode(&pixel, out);
+            }
 
-            for y in 0..height {
-                for x in 0..width {
-                    let fb_x = destination_x + x;
-                    let fb_y = destination_y + y;
+            for y in destination_y..destination_y + height {
+                for x in (0..width).step_by(CHUNK_PIXELS) {
+                    let count = (width - x).min(CHUNK_PIXELS);
                     unsafe {
-                        write_pixel_to_fb(fb, fb_ptr, fb_x, fb_y, &pixel);
+                        sse::copy_to_write_combining(
+                            fb_pixel(destination_x + x, y),
+                            chunk.as_ptr(),
+                            count * bytes_per_pixel,
+                        );
                     }
                 }
             }
@@ -270,19 +558,41 @@ extern "efiapi" fn gop_blt(
                 return Status::INVALID_PARAMETER;
             }
 
-            if source_x + width > fb_width || source_y + height > fb_height {
+            if !fits(source_x, width, fb_width) || !fits(source_y, height, fb_height) {
                 return Status::INVALID_PARAMETER;
             }
 
             for y in 0..height {
-                for x in 0..width {
-                    let fb_x = source_x + x;
-                    let fb_y = source_y + y;
-                    let buf_idx = (destination_y + y) * buffer_line_length + (destination_x + x);
-
+                let buffer_row = unsafe {
+                    core::slice::from_raw_parts_mut(
+                        blt_buffer.add((destination_y + y) * buffer_line_length + destination_x),
+                        width,
+                    )
+                };
+                let video_row = fb_pixel(source_x, source_y + y);
+
+                if layout.is_bgrx() {
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            video_row,
+                            buffer_row.as_mut_ptr() as *mut u8,
+                            row_bytes,
+                        );
+                    }
+                    continue;
+                }
+                for (i, pixels) in buffer_row.chunks_mut(CHUNK_PIXELS).enumerate() {
+                    let bytes = &mut chunk[..pixels.len() * bytes_per_pixel];
                     unsafe {
-                        let pixel = read_pixel_from_fb(fb, fb_ptr, fb_x, fb_y);
-                        *blt_buffer.add(buf_idx) = pixel;
+                        core::ptr::copy_nonoverlapping(
+                            video_row.add(i * CHUNK_PIXELS * bytes_per_pixel),
+                            bytes.as_mut_ptr(),
+                            bytes.len(),
+                        );
+                    }
+                    for (pixel, bytes) in pixels.iter_mut().zip(bytes.chunks_exact(bytes_per_pixel))
+                    {
+                        *pixel = layout.decode(bytes);
                     }
                 }
             }
@@ -294,19 +604,40 @@ extern "efiapi" fn gop_blt(
                 return Status::INVALID_PARAMETER;
             }
 
-            if destination_x + width > fb_width || destination_y + height > fb_height {
+            if !fits(destination_x, width, fb_width) || !fits(destination_y, height, fb_height) {
                 return Status::INVALID_PARAMETER;
             }
 
             for y in 0..height {
-                for x in 0..width {
-                    let buf_idx = (source_y + y) * buffer_line_length + (source_x + x);
-                    let fb_x = destination_x + x;
-                    let fb_y = destination_y + y;
-
+                let buffer_row = unsafe {
+                    core::slice::from_raw_parts(
+                        blt_buffer.add((source_y + y) * buffer_line_length + source_x),
+                        width,
+                    )
+                };
+                let video_row = fb_pixel(destination_x, destination_y + y);
+
+                if layout.is_bgrx() {
+                    unsafe {
+                        sse::copy_to_write_combining(
+                            video_row,
+                            buffer_row.as_ptr() as *const u8,
+                            row_bytes,
+                        );
+                    }
+                    continue;
+                }
+                for (i, pixels) in buffer_row.chunks(CHUNK_PIXELS).enumerate() {
+                    let bytes = &mut chunk[..pixels.len() * bytes_per_pixel];
+                    for (pixel, out) in pixels.iter().zip(bytes.chunks_exact_mut(bytes_per_pixel)) {
+                        layout.encode(pixel, out);
+                    }
                     unsafe {
-                        let pixel = *blt_buffer.add(buf_idx);
-                        write_pixel_to_fb(fb, fb_ptr, fb_x, fb_y, &pixel);
+                        sse::copy_to_write_combining(
+                            video_row.add(i * CHUNK_PIXELS * bytes_per_pixel),
+                            bytes.as_ptr(),
+                            bytes.len(),
+                        );
                     }
                 }
             }
@@ -314,47 +645,26 @@ extern "efiapi" fn gop_blt(
 
         BltOperation::VideoToVideo => {
             // Copy within video memory
-            if source_x + width > fb_width || source_y + height > fb_height {
+            if !fits(source_x, width, fb_width) || !fits(source_y, height, fb_height) {
                 return Status::INVALID_PARAMETER;
             }
-            if destination_x + width > fb_width || destination_y + height > fb_height {
+            if !fits(destination_x, width, fb_width) || !fits(destination_y, height, fb_height) {
                 return Status::INVALID_PARAMETER;
             }
 
-            // Handle overlapping regions by choosing copy direction
-            let copy_forward = destination_y < source_y
-                || (destination_y == source_y && destination_x <= source_x);
-
-            if copy_forward {
-                for y in 0..height {
-                    for x in 0..width {
-                        unsafe {
-                            let pixel = read_pixel_from_fb(fb, fb_ptr, source_x + x, source_y + y);
-                            write_pixel_to_fb(
-                                fb,
-                                fb_ptr,
-                                destination_x + x,
-                                destination_y + y,
-                                &pixel,
-                            );
-                        }
-                    }
-                }
+            // Handle overlapping regions by choosing the row order; within
+            // a row, copy() handles the overlap
+            let copy_row = |y: usize| unsafe {
+                core::ptr::copy(
+                    fb_pixel(source_x, source_y + y),
+                    fb_pixel(destination_x, destination_y + y),
+                    row_bytes,
+                );
+            };
+            if destination_y <= source_y {
+                (0..height).for_each(copy_row);
             } else {
-                for y in (0..height).rev() {
-                    for x in (0..width).rev() {
-                        unsafe {
-                            let pixel = read_pixel_from_fb(fb, fb_ptr, source_x + x, source_y + y);
-                            write_pixel_to_fb(
-                                fb,
-                                fb_ptr,
-                                destination_x + x,
-                                destination_y + y,
-                                &pixel,
-                            );
-                        }
-                    }
-                }
+                (0..height).rev().for_each(copy_row);
             }
         }
     }
@@ -362,167 +672,175 @@ extern "efiapi" fn gop_blt(
     Status::SUCCESS
 }
 
-/// Write a BltPixel to framebuffer at (x, y)
-unsafe fn write_pixel_to_fb(
+/// Blt on the rotated screen of `view`, a pixel at a time
+///
+/// Coordinates are on the rotated screen.
+fn blt_rotated(
     fb: &FramebufferInfo,
-    fb_ptr: *mut u8,
-    x: usize,
-    y: usize,
-    pixel: &BltPixel,
-) {
-    let bytes_per_pixel = (fb.bits_per_pixel / 8) as usize;
-    let offset = y * fb.bytes_per_line as usize + x * bytes_per_pixel;
-    let ptr = fb_ptr.add(offset);
-
-    match fb.bits_per_pixel {
-        32 => {
-            // Encode based on mask positions
-            let value = ((pixel.red as u32) << fb.red_mask_pos)
-                | ((pixel.green as u32) << fb.green_mask_pos)
-                | ((pixel.blue as u32) << fb.blue_mask_pos);
-            (ptr as *mut u32).write_volatile(value);
-        }
-        24 => {
-            if fb.blue_mask_pos < fb.red_mask_pos {
-                // BGR
-                ptr.write_volatile(pixel.blue);
-                ptr.add(1).write_volatile(pixel.green);
-                ptr.add(2).write_volatile(pixel.red);
-            } else {
-                // RGB
-                ptr.write_volatile(pixel.red);
-                ptr.add(1).write_volatile(pixel.green);
-                ptr.add(2).write_volatile(pixel.blue);
-            }
-        }
-        16 => {
-            // RGB565 typically
-            let r = (pixel.red >> 3) as u16;
-            let g = (pixel.green >> 2) as u16;
-            let b = (pixel.blue >> 3) as u16;
-            let value = (r << 11) | (g << 5) | b;
-            (ptr as *mut u16).write_volatile(value);
-        }
-        _ => {}
-    }
-}
+    layout: &PixelLayout,
+    view: &View,
+    blt_buffer: *mut BltPixel,
+    blt_operation: BltOperation,
+    (source_x, source_y): (usize, usize),
+    (destination_x, destination_y): (usize, usize),
+    (width, height): (usize, usize),
+    buffer_line_length: usize,
+) -> Status {
+    let (screen_width, screen_height) = view.size(fb);
+    let on_screen = |x: usize, y: usize| {
+        fits(x, width, screen_width as usize) && fits(y, height, screen_height as usize)
+    };
+    let bytes_per_pixel = layout.bytes_per_pixel;
+    let fb_pixel = |x: usize, y: usize| {
+        let (x, y) = view.physical(fb, x as u32, y as u32);
+        (fb.physical_address as usize + fb.pixel_offset(x, y)) as *mut u8
+    };
+    let mut bytes = [0u8; 4];
+    let bytes = &mut bytes[..bytes_per_pixel];
 
-/// Read a BltPixel from framebuffer at (x, y)
-unsafe fn read_pixel_from_fb(
-    fb: &FramebufferInfo,
-    fb_ptr: *mut u8,
-    x: usize,
-    y: usize,
-) -> BltPixel {
-    let bytes_per_pixel = (fb.bits_per_pixel / 8) as usize;
-    let offset = y * fb.bytes_per_line as usize + x * bytes_per_pixel;
-    let ptr = fb_ptr.add(offset);
-
-    match fb.bits_per_pixel {
-        32 => {
-            let value = (ptr as *const u32).read_volatile();
-            BltPixel {
-                red: ((value >> fb.red_mask_pos) & 0xFF) as u8,
-                green: ((value >> fb.green_mask_pos) & 0xFF) as u8,
-                blue: ((value >> fb.blue_mask_pos) & 0xFF) as u8,
-                reserved: 0,
+    match blt_operation {
+        BltOperation::VideoFill => {
+            if blt_buffer.is_null() || !on_screen(destination_x, destination_y) {
+                return Status::INVALID_PARAMETER;
+            }
+            layout.encode(unsafe { &*blt_buffer }, bytes);
+            for y in destination_y..destination_y + height {
+                for x in destination_x..destination_x + width {
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(bytes.as_ptr(), fb_pixel(x, y), bytes.len())
+                    };
+                }
             }
         }
-        24 => {
-            if fb.blue_mask_pos < fb.red_mask_pos {
-                // BGR
-                BltPixel {
-                    blue: ptr.read_volatile(),
-                    green: ptr.add(1).read_volatile(),
-                    red: ptr.add(2).read_volatile(),
-                    reserved: 0,
+        BltOperation::VideoToBltBuffer => {
+            if blt_buffer.is_null() || !on_screen(source_x, source_y) {
+                return Status::INVALID_PARAMETER;
+            }
+            for y in 0..height {
+                for x in 0..width {
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            fb_pixel(source_x + x, source_y + y),
+                            bytes.as_mut_ptr(),
+                            bytes.len(),
+                        );
+                        blt_buffer
+                            .add((destination_y + y) * buffer_line_length + destination_x + x)
+                            .write(layout.decode(bytes));
+                    }
                 }
-            } else {
-                // RGB
-                BltPixel {
-                    red: ptr.read_volatile(),
-                    green: ptr.add(1).read_volatile(),
-                    blue: ptr.add(2).read_volatile(),
-                    reserved: 0,
+            }
+        }
+        BltOperation::BufferToVideo => {
+            if blt_buffer.is_null() || !on_screen(destination_x, destination_y) {
+                return Status::INVALID_PARAMETER;
+            }
+            for y in 0..height {
+                for x in 0..width {
+                    let pixel = unsafe {
+                        &*blt_buffer.add((source_y + y) * buffer_line_length + source_x + x)
+                    };
+                    layout.encode(pixel, bytes);
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            bytes.as_ptr(),
+                            fb_pixel(destination_x + x, destination_y + y),
+                            bytes.len(),
+                        );
+                    }
                 }
             }
         }
-        16 => {
-            let value = (ptr as *const u16).read_volatile();
-            BltPixel {
-                red: ((value >> 11) << 3) as u8,
-                green: (((value >> 5) & 0x3F) << 2) as u8,
-                blue: ((value & 0x1F) << 3) as u8,
-                reserved: 0,
+        BltOperation::VideoToVideo => {
+            if !on_screen(source_x, source_y) || !on_screen(destination_x, destination_y) {
+                return Status::INVALID_PARAMETER;
+            }
+            // Like memmove: go against the direction of the move, so no
+            // pixel is overwritten before it was copied
+            let copy = |x: usize, y: usize| unsafe {
+                core::ptr::copy_nonoverlapping(
+                    fb_pixel(source_x + x, source_y + y),
+                    fb_pixel(destination_x + x, destination_y + y),
+                    bytes_per_pixel,
+                );
+            };
+            let copy_row = |y: usize| {
+                if destination_x <= source_x {
+                    (0..width).for_each(|x| copy(x, y));
+                } else {
+                    (0..width).rev().for_each(|x| copy(x, y));
+                }
+            };
+            if destination_y <= source_y {
+                (0..height).for_each(copy_row);
+            } else {
+                (0..height).rev().for_each(copy_row);
             }
         }
-        _ => BltPixel::default(),
     }
+
+    Status::SUCCESS
 }
 
-/// Create the Graphics Output Protocol from coreboot framebuffer info
+/// Pixel format and bitmask to report in the mode information
 ///
-/// # Returns
-/// A pointer to the GraphicsOutputProtocol, or null on failure
-pub fn create_gop(framebuffer: &FramebufferInfo) -> *mut GraphicsOutputProtocol {
-    // Determine pixel format based on mask positions
-    let (pixel_format, pixel_bitmask) = if framebuffer.bits_per_pixel == 32 {
-        if framebuffer.red_mask_pos == 16
-            && framebuffer.green_mask_pos == 8
-            && framebuffer.blue_mask_pos == 0
-        {
-            // BGRA (most common)
-            (
-                PixelFormat::BlueGreenRedReserved8BitPerColor,
-                PixelBitmask::default(),
-            )
-        } else if framebuffer.red_mask_pos == 0
-            && framebuffer.green_mask_pos == 8
-            && framebuffer.blue_mask_pos == 16
-        {
-            // RGBA
-            (
-                PixelFormat::RedGreenBlueReserved8BitPerColor,
-                PixelBitmask::default(),
-            )
-        } else {
-            // Custom bitmask
+/// The reserved mask covers the unused bits of the pixel, since loaders
+/// like GRUB derive the pixel size from the combined masks.
+fn mode_pixel_format(fb: &FramebufferInfo) -> (PixelFormat, PixelBitmask) {
+    let red = Channel {
+        pos: fb.red_mask_pos,
+        size: fb.red_mask_size,
+    }
+    .mask();
+    let green = Channel {
+        pos: fb.green_mask_pos,
+        size: fb.green_mask_size,
+    }
+    .mask();
+    let blue = Channel {
+        pos: fb.blue_mask_pos,
+        size: fb.blue_mask_size,
+    }
+    .mask();
+
+    match (fb.bits_per_pixel, red, green, blue) {
+        (32, 0x00FF_0000, 0x0000_FF00, 0x0000_00FF) => (
+            PixelFormat::BlueGreenRedReserved8BitPerColor,
+            PixelBitmask::default(),
+        ),
+        (32, 0x0000_00FF, 0x0000_FF00, 0x00FF_0000) => (
+            PixelFormat::RedGreenBlueReserved8BitPerColor,
+            PixelBitmask::default(),
+        ),
+        (bits_per_pixel, ..) => {
+            let pixel_mask = u32::MAX >> (32 - bits_per_pixel.clamp(1, 32));
             let bitmask = PixelBitmask {
-                red_mask: 0xFF << framebuffer.red_mask_pos,
-                green_mask: 0xFF << framebuffer.green_mask_pos,
-                blue_mask: 0xFF << framebuffer.blue_mask_pos,
-                reserved_mask: 0,
+                red_mask: red,
+                green_mask: green,
+                blue_mask: blue,
+                reserved_mask: pixel_mask & !(red | green | blue),
             };
             (PixelFormat::BitMask, bitmask)
         }
-    } else {
-        // For non-32bpp, use bitmask
-        let bitmask = PixelBitmask {
-            red_mask: ((1u32 << framebuffer.red_mask_size) - 1) << framebuffer.red_mask_pos,
-            green_mask: ((1u32 << framebuffer.green_mask_size) - 1) << framebuffer.green_mask_pos,
-            blue_mask: ((1u32 << framebuffer.blue_mask_size) - 1) << framebuffer.blue_mask_pos,
-            reserved_mask: 0,
-        };
-        (PixelFormat::BitMask, bitmask)
-    };
+    }
+}
 
+/// Create the Graphics Output Protocol from coreboot framebuffer info
+///
+/// # Returns
+/// A pointer to the GraphicsOutputProtocol, or null on failure
+pub fn create_gop(framebuffer: &FramebufferInfo) -> *mut GraphicsOutputProtocol {
     // Allocate mode info
-    let mode_info_ptr = allocate_protocol_with_log::<GopModeInfo>("GopModeInfo", |m| {
-        m.version = 0;
-        m.horizontal_resolution = framebuffer.x_resolution;
-        m.vertical_resolution = framebuffer.y_resolution;
-        m.pixel_format = pixel_format;
-        m.pixel_information = pixel_bitmask;
-        m.pixels_per_scan_line =
-            framebuffer.bytes_per_line / (framebuffer.bits_per_pixel as u32 / 8);
-    });
+    let info = mode_info(framebuffer);
+    let pixel_format = info.pixel_format;
+    let mode_info_ptr = allocate_protocol_with_log::<GopModeInfo>("GopModeInfo", |m| *m = info);
     if mode_info_ptr.is_null() {
         return core::ptr::null_mut();
     }
 
     // Allocate GOP mode structure
     let mode_ptr = allocate_protocol_with_log::<GopMode>("GopMode", |m| {
-        m.max_mode = 1; // We support 1 mode (mode 0)
+        m.max_mode = 1; // Until the EDID adds modes
         m.mode = 0;
         m.info = mode_info_ptr;
         m.size_of_info = core::mem::size_of::<GopModeInfo>();
@@ -540,6 +858,8 @@ pub fn create_gop(framebuffer: &FramebufferInfo) -> *mut GraphicsOutputProtocol
             p.set_mode = gop_set_mode;
             p.blt = gop_blt;
             p.mode = mode_ptr;
+            #[cfg(feature = "protocol-trace")]
+            super::trace::graphics_output(p);
         });
     if protocol_ptr.is_null() {
         return core::ptr::null_mut();
@@ -549,6 +869,14 @@ pub fn create_gop(framebuffer: &FramebufferInfo) -> *mut GraphicsOutputProtocol
     state::with_console_mut(|console| {
         console.gop_framebuffer = Some(framebuffer.clone());
     });
+    MODES.with(|modes| {
+        modes.native = Some(framebuffer.clone());
+        modes.resolutions.clear();
+        let _ = modes
+            .resolutions
+            .push((framebuffer.x_resolution, framebuffer.y_resolution));
+        modes.mode = mode_ptr;
+    });
 
     log::info!(
         "GraphicsOutputProtocol created: {}x{} @ {:#x}, {:?}",
@@ -560,3 +888,84 @@ pub fn create_gop(framebuffer: &FramebufferInfo) -> *mut GraphicsOutputProtocol
 
     protocol_ptr
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framebuffer(bits_per_pixel: u8, channels: [(u8, u8); 3]) -> FramebufferInfo {
+        let [
+            (red_pos, red_size),
+            (green_pos, green_size),
+            (blue_pos, blue_size),
+        ] = channels;
+        FramebufferInfo {
+            physical_address: 0,
+            x_resolution: 640,
+            y_resolution: 480,
+            bytes_per_line: 640 * bits_per_pixel.div_ceil(8) as u32,
+            bits_per_pixel,
+            red_mask_pos: red_pos,
+            red_mask_size: red_size,
+            green_mask_pos: green_pos,
+            green_mask_size: green_size,
+            blue_mask_pos: blue_pos,
+            blue_mask_size: blue_size,
+        }
+    }
+
+    #[test]
+    fn pixel_format_from_masks() {
+        let bgrx = framebuffer(32, [(16, 8), (8, 8), (0, 8)]);
+        assert_eq!(
+            mode_pixel_format(&bgrx).0,
+            PixelFormat::BlueGreenRedReserved8BitPerColor
+        );
+        let rgbx = framebuffer(32, [(0, 8), (8, 8), (16, 8)]);
+        assert_eq!(
+            mode_pixel_format(&rgbx).0,
+            PixelFormat::RedGreenBlueReserved8BitPerColor
+        );
+
+        // 10 bits per color: the reserved mask keeps the pixel 32 bits wide
+        let (format, bitmask) = mode_pixel_format(&framebuffer(32, [(20, 10), (10, 10), (0, 10)]));
+        assert_eq!(format, PixelFormat::BitMask);
+        assert_eq!(bitmask.red_mask, 0x3FF0_0000);
+        assert_eq!(bitmask.reserved_mask, 0xC000_0000);
+
+        let (_, bitmask) = mode_pixel_format(&framebuffer(16, [(10, 5), (5, 5), (0, 5)]));
+        assert_eq!(bitmask.green_mask, 0x03E0);
+        assert_eq!(bitmask.reserved_mask, 0x8000);
+    }
+
+    #[test]
+    fn pixel_conversion() {
+        let pixel = BltPixel {
+            blue: 0x12,
+            green: 0x84,
+            red: 0xF8,
+            reserved: 0,
+        };
+        let mut out = [0u8; 4];
+
+        let bgrx = PixelLayout::new(&framebuffer(32, [(16, 8), (8, 8), (0, 8)])).unwrap();
+        assert!(bgrx.is_bgrx());
+        bgrx.encode(&pixel, &mut out);
+        assert_eq!(out, [0x12, 0x84, 0xF8, 0]);
+
+        let rgbx = PixelLayout::new(&framebuffer(32, [(0, 8), (8, 8), (16, 8)])).unwrap();
+        assert!(!rgbx.is_bgrx());
+        rgbx.encode(&pixel, &mut out);
+        assert_eq!(out, [0xF8, 0x84, 0x12, 0]);
+        assert_eq!(rgbx.decode(&out).blue, 0x12);
+
+        let rgb565 = PixelLayout::new(&framebuffer(16, [(11, 5), (5, 6), (0, 5)])).unwrap();
+        rgb565.encode(&pixel, &mut out[..2]);
+        assert_eq!(u16::from_le_bytes([out[0], out[1]]), 0xFC22);
+        let decoded = rgb565.decode(&out[..2]);
+        assert_eq!(
+            (decoded.red, decoded.green, decoded.blue),
+            (0xF8, 0x84, 0x10)
+        );
+    }
+}
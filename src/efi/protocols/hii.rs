@@ -0,0 +1,696 @@
+//! EFI HII Database and HII String Protocols
+//!
+//! Boot loaders such as shim's fallback and GRUB look up the HII database to
+//! register their own strings or to read localized ones, and print errors
+//! or give up when it is missing. CrabEFI has no setup forms, fonts or
+//! keyboard layouts, so this is a minimal database:
+//!
+//! - package lists are copied and registered, and can be listed, exported,
+//!   updated and removed
+//! - strings are looked up in the string packages of a list, for any
+//!   language the list has strings for (usually just `en-US`)
+//! - adding or changing strings, keyboard layouts and package notifications
+//!   are not supported
+//!
+//! Reference: UEFI Specification 2.10, Sections 33.3.6 and 34.8
+
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use r_efi::efi::{Guid, Handle, Status};
+use r_efi::hii;
+use r_efi::protocols::{hii_database, hii_font, hii_string};
+use spin::Mutex;
+
+use crate::efi::allocator::{self, MemoryType};
+use crate::efi::cell::EfiCell;
+
+/// Re-export the GUIDs for external use
+pub const HII_DATABASE_PROTOCOL_GUID: Guid = hii_database::PROTOCOL_GUID;
+pub const HII_STRING_PROTOCOL_GUID: Guid = hii_string::PROTOCOL_GUID;
+
+/// Most package lists registered at once
+const MAX_PACKAGE_LISTS: usize = 32;
+
+/// Size of the package list header: GUID and length
+const LIST_HEADER_SIZE: usize = 20;
+
+/// Size of a package header: 24 bit length and type
+const PACKAGE_HEADER_SIZE: usize = 4;
+
+/// Offset of the language in a string package
+const STRING_LANGUAGE_OFFSET: usize = 46;
+
+/// String information block types
+const SIBT_END: u8 = 0x00;
+const SIBT_STRING_SCSU: u8 = 0x10;
+const SIBT_STRING_SCSU_FONT: u8 = 0x11;
+const SIBT_STRINGS_SCSU: u8 = 0x12;
+const SIBT_STRINGS_SCSU_FONT: u8 = 0x13;
+const SIBT_STRING_UCS2: u8 = 0x14;
+const SIBT_STRING_UCS2_FONT: u8 = 0x15;
+const SIBT_STRINGS_UCS2: u8 = 0x16;
+const SIBT_STRINGS_UCS2_FONT: u8 = 0x17;
+const SIBT_DUPLICATE: u8 = 0x20;
+const SIBT_SKIP2: u8 = 0x21;
+const SIBT_SKIP1: u8 = 0x22;
+const SIBT_EXT1: u8 = 0x30;
+const SIBT_EXT2: u8 = 0x31;
+const SIBT_EXT4: u8 = 0x32;
+
+/// A registered package list
+#[derive(Clone, Copy)]
+struct PackageList {
+    /// HII handle handed out for the list, never 0
+    handle: usize,
+    /// Driver handle the list was registered for
+    driver: usize,
+    /// Pool copy of the list, header included
+    data: usize,
+    len: usize,
+}
+
+impl PackageList {
+    fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.data as *const u8, self.len) }
+    }
+}
+
+/// The registered package lists
+static DATABASE: Mutex<heapless::Vec<PackageList, MAX_PACKAGE_LISTS>> =
+    Mutex::new(heapless::Vec::new());
+
+/// HII handle of the next registered list
+static NEXT_HANDLE: AtomicUsize = AtomicUsize::new(1);
+
+static HII_DATABASE: EfiCell<hii_database::Protocol> = EfiCell::new(hii_database::Protocol {
+    new_package_list: db_new_package_list,
+    remove_package_list: db_remove_package_list,
+    update_package_list: db_update_package_list,
+    list_package_lists: db_list_package_lists,
+    export_package_lists: db_export_package_lists,
+    register_package_notify: db_register_package_notify,
+    unregister_package_notify: db_unregister_package_notify,
+    find_keyboard_layouts: db_find_keyboard_layouts,
+    get_keyboard_layout: db_get_keyboard_layout,
+    set_keyboard_layout: db_set_keyboard_layout,
+    get_package_list_handle: db_get_package_list_handle,
+});
+
+static HII_STRING: EfiCell<hii_string::Protocol> = EfiCell::new(hii_string::Protocol {
+    new_string: string_new_string,
+    get_string: string_get_string,
+    set_string: string_set_string,
+    get_languages: string_get_languages,
+    get_secondary_languages: string_get_secondary_languages,
+});
+
+/// Get the HII Database Protocol
+pub fn get_database_protocol() -> *mut c_void {
+    HII_DATABASE.as_ptr() as *mut c_void
+}
+
+/// Get the HII String Protocol
+pub fn get_string_protocol() -> *mut c_void {
+    HII_STRING.as_ptr() as *mut c_void
+}
+
+/// The packages of a package list, as (type, package) with the header
+fn packages(list: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut rest = list.get(LIST_HEADER_SIZE..).unwrap_or(&[]);
+    core::iter::from_fn(move || {
+        let header = rest.get(..PACKAGE_HEADER_SIZE)?;
+        let len = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+        let package_type = header[3];
+        if package_type == hii::PACKAGE_END || len < PACKAGE_HEADER_SIZE {
+            return None;
+        }
+        let package = rest.get(..len)?;
+        rest = &rest[len..];
+        Some((package_type, package))
+    })
+}
+
+/// The string packages of a package list, as (language, package)
+fn string_packages(list: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    packages(list)
+        .filter(|&(package_type, _)| package_type == hii::PACKAGE_STRINGS)
+        .filter_map(|(_, package)| {
+            let language = package.get(STRING_LANGUAGE_OFFSET..)?;
+            let end = language.iter().position(|&b| b == 0)?;
+            Some((&language[..end], package))
+        })
+}
+
+/// Check a caller's package list, returning its length
+///
+/// # Safety
+///
+/// `list` must point to a package list.
+unsafe fn list_len(list: *const hii::PackageListHeader) -> Option<usize> {
+    let len = unsafe { (*list).package_length } as usize;
+    (len >= LIST_HEADER_SIZE).then_some(len)
+}
+
+/// A string read from a string package
+enum StringData<'a> {
+    /// NUL-terminated SCSU bytes, of which only ASCII is decoded
+    Scsu(&'a [u8]),
+    /// NUL-terminated UCS-2
+    Ucs2(&'a [u8]),
+}
+
+impl StringData<'_> {
+    /// The characters, without the NUL
+    fn chars(&self) -> impl Iterator<Item = u16> + '_ {
+        let (bytes, width) = match self {
+            StringData::Scsu(bytes) => (*bytes, 1),
+            StringData::Ucs2(bytes) => (*bytes, 2),
+        };
+        bytes
+            .chunks_exact(width)
+            .map(|c| match c {
+                [b] => *b as u16,
+                [lo, hi] => u16::from_le_bytes([*lo, *hi]),
+                _ => 0,
+            })
+            .take_while(|&c| c != 0)
+    }
+}
+
+/// Length of the NUL-terminated string at the start of `bytes`, with the
+/// NUL, for characters of `width` bytes
+fn terminated_len(bytes: &[u8], width: usize) -> Option<usize> {
+    let chars = bytes
+        .chunks_exact(width)
+        .position(|c| c.iter().all(|&b| b == 0))?;
+    Some((chars + 1) * width)
+}
+
+/// Find string `id` in a string package
+///
+/// String IDs count from 1 through the string information blocks.
+/// Duplicates are followed to the string they refer to.
+fn find_string(package: &[u8], id: hii::StringId) -> Option<StringData<'_>> {
+    let info_offset = u32::from_le_bytes(package.get(8..12)?.try_into().ok()?) as usize;
+    let mut blocks = package.get(info_offset..)?;
+    let mut current: hii::StringId = 1;
+
+    loop {
+        let (&block_type, data) = blocks.split_first()?;
+        // Font identifier and string count, before the strings
+        let (skip, count, width) = match block_type {
+            SIBT_END => return None,
+            SIBT_STRING_SCSU => (0, 1, 1),
+            SIBT_STRING_SCSU_FONT => (1, 1, 1),
+            SIBT_STRINGS_SCSU => (2, u16::from_le_bytes([*data.first()?, *data.get(1)?]), 1),
+            SIBT_STRINGS_SCSU_FONT => (3, u16::from_le_bytes([*data.get(1)?, *data.get(2)?]), 1),
+            SIBT_STRING_UCS2 => (0, 1, 2),
+            SIBT_STRING_UCS2_FONT => (1, 1, 2),
+            SIBT_STRINGS_UCS2 => (2, u16::from_le_bytes([*data.first()?, *data.get(1)?]), 2),
+            SIBT_STRINGS_UCS2_FONT => (3, u16::from_le_bytes([*data.get(1)?, *data.get(2)?]), 2),
+            SIBT_DUPLICATE => {
+                if current == id {
+                    let original = u16::from_le_bytes([*data.first()?, *data.get(1)?]);
+                    return (original < id).then(|| find_string(package, original))?;
+                }
+                current = current.checked_add(1)?;
+                blocks = data.get(2..)?;
+                continue;
+            }
+            SIBT_SKIP2 => {
+                current =
+                    current.checked_add(u16::from_le_bytes([*data.first()?, *data.get(1)?]))?;
+                blocks = data.get(2..)?;
+                continue;
+            }
+            SIBT_SKIP1 => {
+                current = current.checked_add(*data.first()? as u16)?;
+                blocks = data.get(1..)?;
+                continue;
+            }
+            SIBT_EXT1 | SIBT_EXT2 | SIBT_EXT4 => {
+                // The length covers the whole block
+                let len = match block_type {
+                    SIBT_EXT1 => *data.get(1)? as usize,
+                    SIBT_EXT2 => u16::from_le_bytes([*data.get(1)?, *data.get(2)?]) as usize,
+                    _ => u32::from_le_bytes(data.get(1..5)?.try_into().ok()?) as usize,
+                };
+                blocks = blocks.get(len.max(1)..)?;
+                continue;
+            }
+            _ => return None,
+        };
+
+        let mut strings = data.get(skip..)?;
+        for _ in 0..count {
+            let len = terminated_len(strings, width)?;
+            if current == id {
+                let bytes = &strings[..len];
+                return Some(if width == 1 {
+                    StringData::Scsu(bytes)
+                } else {
+                    StringData::Ucs2(bytes)
+                });
+            }
+            current = current.checked_add(1)?;
+            strings = &strings[len..];
+        }
+        blocks = strings;
+    }
+}
+
+/// Copy a caller's package list to pool memory
+///
+/// # Safety
+///
+/// `list` must point to a package list of `len` bytes.
+unsafe fn copy_list(list: *const hii::PackageListHeader, len: usize) -> Result<usize, Status> {
+    let copy = allocator::allocate_pool(MemoryType::BootServicesData, len)?;
+    unsafe { core::ptr::copy_nonoverlapping(list as *const u8, copy, len) };
+    Ok(copy as usize)
+}
+
+extern "efiapi" fn db_new_package_list(
+    _this: *const hii_database::Protocol,
+    package_list: *const hii::PackageListHeader,
+    driver_handle: Handle,
+    handle: *mut hii::Handle,
+) -> Status {
+    if package_list.is_null() || handle.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let Some(len) = (unsafe { list_len(package_list) }) else {
+        return Status::INVALID_PARAMETER;
+    };
+    let data = match unsafe { copy_list(package_list, len) } {
+        Ok(data) => data,
+        Err(status) => return status,
+    };
+
+    let list = PackageList {
+        handle: NEXT_HANDLE.fetch_add(1, Ordering::Relaxed),
+        driver: driver_handle as usize,
+        data,
+        len,
+    };
+    if DATABASE.lock().push(list).is_err() {
+        let _ = allocator::free_pool(data as *mut u8);
+        log::warn!("HII: more than {} package lists", MAX_PACKAGE_LISTS);
+        return Status::OUT_OF_RESOURCES;
+    }
+
+    let languages = string_packages(list.bytes()).count();
+    log::debug!(
+        "HII.NewPackageList({} bytes, {} string packages) -> handle {}",
+        len,
+        languages,
+        list.handle
+    );
+    unsafe { *handle = list.handle as hii::Handle };
+    Status::SUCCESS
+}
+
+extern "efiapi" fn db_remove_package_list(
+    _this: *const hii_database::Protocol,
+    handle: hii::Handle,
+) -> Status {
+    let mut database = DATABASE.lock();
+    let Some(index) = database.iter().position(|l| l.handle == handle as usize) else {
+        return Status::NOT_FOUND;
+    };
+    let list = database.swap_remove(index);
+    let _ = allocator::free_pool(list.data as *mut u8);
+    Status::SUCCESS
+}
+
+extern "efiapi" fn db_update_package_list(
+    _this: *const hii_database::Protocol,
+    handle: hii::Handle,
+    package_list: *const hii::PackageListHeader,
+) -> Status {
+    if package_list.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let Some(len) = (unsafe { list_len(package_list) }) else {
+        return Status::INVALID_PARAMETER;
+    };
+    let mut database = DATABASE.lock();
+    let Some(list) = database.iter_mut().find(|l| l.handle == handle as usize) else {
+        return Status::NOT_FOUND;
+    };
+    let data = match unsafe { copy_list(package_list, len) } {
+        Ok(data) => data,
+        Err(status) => return status,
+    };
+    let _ = allocator::free_pool(list.data as *mut u8);
+    list.data = data;
+    list.len = len;
+    Status::SUCCESS
+}
+
+/// Whether a package list holds packages of `package_type`, or GUID packages
+/// of `guid`
+fn has_packages(list: &PackageList, package_type: u8, guid: Option<&Guid>) -> bool {
+    if package_type == hii::PACKAGE_TYPE_ALL {
+        return true;
+    }
+    packages(list.bytes()).any(|(t, package)| {
+        t == package_type
+            && (package_type != hii::PACKAGE_TYPE_GUID
+                || package
+                    .get(PACKAGE_HEADER_SIZE..PACKAGE_HEADER_SIZE + 16)
+                    .zip(guid)
+                    .is_some_and(|(bytes, guid)| bytes == guid.as_bytes()))
+    })
+}
+
+extern "efiapi" fn db_list_package_lists(
+    _this: *const hii_database::Protocol,
+    package_type: u8,
+    package_guid: *const Guid,
+    handle_buffer_length: *mut usize,
+    handle: *mut hii::Handle,
+) -> Status {
+    if handle_buffer_length.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let guid = unsafe { package_guid.as_ref() };
+    if (package_type == hii::PACKAGE_TYPE_GUID) != guid.is_some() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let database = DATABASE.lock();
+    let matching = || {
+        database
+            .iter()
+            .filter(|l| has_packages(l, package_type, guid))
+    };
+    let needed = matching().count() * core::mem::size_of::<hii::Handle>();
+    if needed == 0 {
+        return Status::NOT_FOUND;
+    }
+    let available = unsafe { *handle_buffer_length };
+    unsafe { *handle_buffer_length = needed };
+    if available < needed {
+        return Status::BUFFER_TOO_SMALL;
+    }
+    if handle.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    for (i, list) in matching().enumerate() {
+        unsafe { *handle.add(i) = list.handle as hii::Handle };
+    }
+    Status::SUCCESS
+}
+
+extern "efiapi" fn db_export_package_lists(
+    _this: *const hii_database::Protocol,
+    handle: hii::Handle,
+    buffer_size: *mut usize,
+    buffer: *mut hii::PackageListHeader,
+) -> Status {
+    if buffer_size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let database = DATABASE.lock();
+    let exported = || {
+        database
+            .iter()
+            .filter(|l| handle.is_null() || l.handle == handle as usize)
+    };
+    if !handle.is_null() && exported().next().is_none() {
+        return Status::NOT_FOUND;
+    }
+    let needed: usize = exported().map(|l| l.len).sum();
+    let available = unsafe { *buffer_size };
+    unsafe { *buffer_size = needed };
+    if available < needed {
+        return Status::BUFFER_TOO_SMALL;
+    }
+    if buffer.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let mut out = buffer as *mut u8;
+    for list in exported() {
+        unsafe {
+            core::ptr::copy_nonoverlapping(list.data as *const u8, out, list.len);
+            out = out.add(list.len);
+        }
+    }
+    Status::SUCCESS
+}
+
+extern "efiapi" fn db_register_package_notify(
+    _this: *const hii_database::Protocol,
+    _package_type: u8,
+    _package_guid: *const Guid,
+    _notify: hii_database::Notify,
+    _notify_type: hii_database::NotifyType,
+    _notify_handle: *mut Handle,
+) -> Status {
+    Status::UNSUPPORTED
+}
+
+extern "efiapi" fn db_unregister_package_notify(
+    _this: *const hii_database::Protocol,
+    _notification_handle: Handle,
+) -> Status {
+    Status::NOT_FOUND
+}
+
+/// There are no keyboard layouts
+extern "efiapi" fn db_find_keyboard_layouts(
+    _this: *const hii_database::Protocol,
+    key_guid_buffer_length: *mut u16,
+    _key_guid_buffer: *mut Guid,
+) -> Status {
+    if key_guid_buffer_length.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    unsafe { *key_guid_buffer_length = 0 };
+    Status::NOT_FOUND
+}
+
+extern "efiapi" fn db_get_keyboard_layout(
+    _this: *const hii_database::Protocol,
+    _key_guid: *const Guid,
+    _keyboard_layout_length: *mut u16,
+    _keyboard_layout: *mut hii_database::KeyboardLayout,
+) -> Status {
+    Status::NOT_FOUND
+}
+
+extern "efiapi" fn db_set_keyboard_layout(
+    _this: *const hii_database::Protocol,
+    _key_guid: *mut Guid,
+) -> Status {
+    Status::NOT_FOUND
+}
+
+extern "efiapi" fn db_get_package_list_handle(
+    _this: *const hii_database::Protocol,
+    package_list_handle: hii::Handle,
+    driver_handle: *mut Handle,
+) -> Status {
+    if driver_handle.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let database = DATABASE.lock();
+    let Some(list) = database
+        .iter()
+        .find(|l| l.handle == package_list_handle as usize)
+    else {
+        return Status::INVALID_PARAMETER;
+    };
+    unsafe { *driver_handle = list.driver as Handle };
+    Status::SUCCESS
+}
+
+extern "efiapi" fn string_new_string(
+    _this: *const hii_string::Protocol,
+    _package_list: hii::Handle,
+    _string_id: *mut hii::StringId,
+    _language: *const u8,
+    _language_name: *const u16,
+    _string: hii_font::String,
+    _string_font_info: *const hii_string::Info,
+) -> Status {
+    Status::UNSUPPORTED
+}
+
+/// Length of a NUL-terminated ASCII string
+///
+/// # Safety
+///
+/// `s` must point to a NUL-terminated string.
+unsafe fn ascii_str<'a>(s: *const u8) -> &'a [u8] {
+    let mut len = 0;
+    while unsafe { *s.add(len) } != 0 {
+        len += 1;
+    }
+    unsafe { core::slice::from_raw_parts(s, len) }
+}
+
+extern "efiapi" fn string_get_string(
+    _this: *const hii_string::Protocol,
+    language: *const u8,
+    package_list: hii::Handle,
+    string_id: hii::StringId,
+    string: hii_font::String,
+    string_size: *mut usize,
+    string_font_info: *mut *mut hii_string::Info,
+) -> Status {
+    if language.is_null() || string_id == 0 || string_size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let language = unsafe { ascii_str(language) };
+    let database = DATABASE.lock();
+    let Some(list) = database.iter().find(|l| l.handle == package_list as usize) else {
+        return Status::NOT_FOUND;
+    };
+    let Some((_, package)) =
+        string_packages(list.bytes()).find(|(l, _)| l.eq_ignore_ascii_case(language))
+    else {
+        log::debug!(
+            "HII.GetString({}, {}) -> INVALID_LANGUAGE",
+            core::str::from_utf8(language).unwrap_or("?"),
+            string_id
+        );
+        return Status::INVALID_LANGUAGE;
+    };
+    let Some(found) = find_string(package, string_id) else {
+        return Status::NOT_FOUND;
+    };
+
+    let needed = (found.chars().count() + 1) * 2;
+    let available = unsafe { *string_size };
+    unsafe { *string_size = needed };
+    if available < needed {
+        return Status::BUFFER_TOO_SMALL;
+    }
+    if string.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    for (i, c) in found.chars().chain([0]).enumerate() {
+        unsafe { *string.add(i) = c };
+    }
+    if !string_font_info.is_null() {
+        unsafe { *string_font_info = core::ptr::null_mut() };
+    }
+    Status::SUCCESS
+}
+
+extern "efiapi" fn string_set_string(
+    _this: *const hii_string::Protocol,
+    _package_list: hii::Handle,
+    _string_id: hii::StringId,
+    _language: *const u8,
+    _string: hii_font::String,
+    _string_font_info: *const hii_string::Info,
+) -> Status {
+    Status::UNSUPPORTED
+}
+
+/// The languages of a list's string packages, as `en-US;fr-FR`
+extern "efiapi" fn string_get_languages(
+    _this: *const hii_string::Protocol,
+    package_list: hii::Handle,
+    languages: *mut u8,
+    languages_size: *mut usize,
+) -> Status {
+    if languages_size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let database = DATABASE.lock();
+    let Some(list) = database.iter().find(|l| l.handle == package_list as usize) else {
+        return Status::NOT_FOUND;
+    };
+    let separated = || {
+        string_packages(list.bytes())
+            .enumerate()
+            .flat_map(|(i, (language, _))| {
+                (i > 0)
+                    .then_some(b';')
+                    .into_iter()
+                    .chain(language.iter().copied())
+            })
+            .chain([0])
+    };
+
+    let needed = separated().count();
+    let available = unsafe { *languages_size };
+    unsafe { *languages_size = needed };
+    if available < needed {
+        return Status::BUFFER_TOO_SMALL;
+    }
+    if languages.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    for (i, b) in separated().enumerate() {
+        unsafe { *languages.add(i) = b };
+    }
+    Status::SUCCESS
+}
+
+extern "efiapi" fn string_get_secondary_languages(
+    _this: *const hii_string::Protocol,
+    _package_list: hii::Handle,
+    _primary_language: *const u8,
+    _secondary_languages: *mut u8,
+    _secondary_languages_size: *mut usize,
+) -> Status {
+    Status::NOT_FOUND
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A package list with one en-US string package
+    fn package_list(blocks: &[u8]) -> std::vec::Vec<u8> {
+        let mut package = std::vec![0u8; STRING_LANGUAGE_OFFSET];
+        package.extend(b"en-US\0");
+        let info_offset = package.len() as u32;
+        package[4..8].copy_from_slice(&info_offset.to_le_bytes());
+        package[8..12].copy_from_slice(&info_offset.to_le_bytes());
+        package.extend(blocks);
+        let len = package.len() as u32;
+        package[..3].copy_from_slice(&len.to_le_bytes()[..3]);
+        package[3] = hii::PACKAGE_STRINGS;
+
+        let mut list = std::vec![0u8; LIST_HEADER_SIZE];
+        list.extend(package);
+        list.extend([4, 0, 0, hii::PACKAGE_END]);
+        let len = list.len() as u32;
+        list[16..20].copy_from_slice(&len.to_le_bytes());
+        list
+    }
+
+    fn text(data: Option<StringData<'_>>) -> std::string::String {
+        char::decode_utf16(data.unwrap().chars())
+            .map(|c| c.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn finds_strings_in_package() {
+        let mut blocks = std::vec![SIBT_STRING_SCSU];
+        blocks.extend(b"Boot\0");
+        blocks.extend([SIBT_SKIP1, 2, SIBT_STRINGS_UCS2, 2, 0]);
+        for c in "Hi\0Yo\0".encode_utf16() {
+            blocks.extend(c.to_le_bytes());
+        }
+        blocks.extend([SIBT_EXT1, 0x40, 4, 0, SIBT_DUPLICATE, 1, 0, SIBT_END]);
+        let list = package_list(&blocks);
+
+        let (language, package) = string_packages(&list).next().unwrap();
+        assert_eq!(language, b"en-US");
+        assert_eq!(text(find_string(package, 1)), "Boot");
+        assert!(find_string(package, 2).is_none());
+        assert_eq!(text(find_string(package, 4)), "Hi");
+        assert_eq!(text(find_string(package, 5)), "Yo");
+        assert_eq!(text(find_string(package, 6)), "Boot");
+        assert!(find_string(package, 7).is_none());
+    }
+}
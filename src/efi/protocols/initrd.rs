@@ -0,0 +1,153 @@
+//! Linux initrd Load File 2 Protocol
+//!
+//! The Linux EFI stub loads its initrd through EFI_LOAD_FILE2_PROTOCOL on a
+//! handle whose device path is a single vendor media node with
+//! `LINUX_EFI_INITRD_MEDIA_GUID`. This is how systemd-stub hands the kernel
+//! the initrd of a Unified Kernel Image, and CrabEFI does the same when it
+//! starts a UKI's kernel itself.
+//!
+//! Reference: Linux `drivers/firmware/efi/libstub/efi-stub-helper.c`
+
+use core::ffi::c_void;
+use r_efi::efi::{Boolean, Guid, Handle, Status};
+use r_efi::protocols::device_path::{End, Media, Protocol as DevicePathProtocol, TYPE_MEDIA};
+use r_efi::protocols::load_file2;
+use spin::Mutex;
+
+use super::device_path::{DEVICE_PATH_PROTOCOL_GUID, create_end_node};
+use crate::efi::cell::EfiCell;
+use crate::efi::handles;
+
+/// Re-export the GUID for external use
+pub const LOAD_FILE2_PROTOCOL_GUID: Guid = load_file2::PROTOCOL_GUID;
+
+/// Vendor GUID of the initrd media device path
+pub const LINUX_EFI_INITRD_MEDIA_GUID: Guid = Guid::from_fields(
+    0x5568e427,
+    0x68fc,
+    0x4f3d,
+    0xac,
+    0x74,
+    &[0xca, 0x55, 0x52, 0x31, 0xcc, 0x68],
+);
+
+/// Vendor media device path node
+#[repr(C, packed)]
+struct VendorMediaNode {
+    header: DevicePathProtocol,
+    guid: [u8; 16],
+}
+
+/// The initrd media device path: the vendor node and an End node
+#[repr(C, packed)]
+struct InitrdDevicePath {
+    vendor: VendorMediaNode,
+    end: End,
+}
+
+static DEVICE_PATH: EfiCell<InitrdDevicePath> = EfiCell::new(InitrdDevicePath {
+    vendor: VendorMediaNode {
+        header: DevicePathProtocol {
+            r#type: TYPE_MEDIA,
+            sub_type: Media::SUBTYPE_VENDOR,
+            length: (size_of::<VendorMediaNode>() as u16).to_le_bytes(),
+        },
+        guid: *LINUX_EFI_INITRD_MEDIA_GUID.as_bytes(),
+    },
+    end: create_end_node(),
+});
+
+static LOAD_FILE2: EfiCell<load_file2::Protocol> = EfiCell::new(load_file2::Protocol {
+    load_file: load_initrd,
+});
+
+/// The installed initrd: its handle, address and size
+static INITRD: Mutex<Option<(usize, usize, usize)>> = Mutex::new(None);
+
+extern "efiapi" fn load_initrd(
+    _this: *mut load_file2::Protocol,
+    file_path: *mut DevicePathProtocol,
+    boot_policy: Boolean,
+    buffer_size: *mut usize,
+    buffer: *mut c_void,
+) -> Status {
+    if file_path.is_null() || buffer_size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    // Load File 2 never loads boot options
+    if boot_policy.into() {
+        return Status::UNSUPPORTED;
+    }
+    let Some((_, address, size)) = *INITRD.lock() else {
+        return Status::NOT_FOUND;
+    };
+
+    if buffer.is_null() || unsafe { *buffer_size } < size {
+        unsafe { *buffer_size = size };
+        return Status::BUFFER_TOO_SMALL;
+    }
+    unsafe {
+        core::ptr::copy_nonoverlapping(address as *const u8, buffer as *mut u8, size);
+        *buffer_size = size;
+    }
+    log::debug!("LoadFile2: initrd ({} bytes)", size);
+    Status::SUCCESS
+}
+
+/// Offer `initrd` to the next kernel on the initrd media device path
+///
+/// `initrd` must stay in memory until [`uninstall`]. Returns whether the
+/// protocol was installed.
+pub fn install(initrd: &[u8]) -> bool {
+    if INITRD.lock().is_some() {
+        uninstall();
+    }
+    let status = handles::with(|db| {
+        let handle = db.create()?;
+        let status = db.install(
+            handle,
+            &DEVICE_PATH_PROTOCOL_GUID,
+            DEVICE_PATH.as_ptr() as *mut c_void,
+        );
+        if status != Status::SUCCESS {
+            return Err(status);
+        }
+        match db.install(
+            handle,
+            &LOAD_FILE2_PROTOCOL_GUID,
+            LOAD_FILE2.as_ptr() as *mut c_void,
+        ) {
+            Status::SUCCESS => Ok(handle),
+            status => {
+                let _ = db.uninstall(handle, &DEVICE_PATH_PROTOCOL_GUID);
+                Err(status)
+            }
+        }
+    });
+    match status {
+        Ok(handle) => {
+            *INITRD.lock() = Some((handle as usize, initrd.as_ptr() as usize, initrd.len()));
+            log::info!("Initrd of {} bytes offered through LoadFile2", initrd.len());
+            true
+        }
+        Err(status) => {
+            log::error!(
+                "Failed to install the initrd LoadFile2 protocol: {:?}",
+                status
+            );
+            false
+        }
+    }
+}
+
+/// Withdraw the initrd offered by [`install`]
+pub fn uninstall() {
+    let Some((handle, _, _)) = INITRD.lock().take() else {
+        return;
+    };
+    let handle = handle as Handle;
+    handles::with(|db| {
+        let _ = db.uninstall(handle, &LOAD_FILE2_PROTOCOL_GUID);
+        let _ = db.uninstall(handle, &DEVICE_PATH_PROTOCOL_GUID);
+    });
+}
@@ -0,0 +1,108 @@
+//! EFI Load File Protocol
+//!
+//! This module provides EFI_LOAD_FILE_PROTOCOL for the boot volume. LoadImage
+//! with `BootPolicy` set falls back to Load File on devices without a usable
+//! Simple File System, and network boot will install its own instance on
+//! network handles the same way.
+//!
+//! The volume instance reads from the filesystem behind the Simple File
+//! System protocol. An empty file path with `BootPolicy` set loads the
+//! default removable media boot loader.
+
+use core::ffi::c_void;
+use r_efi::efi::{Boolean, Guid, Status};
+use r_efi::protocols::device_path::Protocol as DevicePathProtocol;
+use r_efi::protocols::load_file;
+
+use super::device_path;
+use crate::efi::cell::EfiCell;
+use crate::fs::fat::FatFilesystem;
+use crate::menu::DEFAULT_BOOT_PATH;
+use crate::state;
+
+/// Re-export the GUID for external use
+pub const LOAD_FILE_PROTOCOL_GUID: Guid = load_file::PROTOCOL_GUID;
+
+/// Maximum file path length supported
+const MAX_PATH_LEN: usize = 256;
+
+/// Load File Protocol instance for the boot volume
+static VOLUME_LOAD_FILE: EfiCell<load_file::Protocol> = EfiCell::new(load_file::Protocol {
+    load_file: volume_load_file,
+});
+
+/// Get the Load File Protocol for the boot volume
+///
+/// Install it on the handle carrying the volume's Simple File System
+/// protocol, after `simple_file_system::init`.
+pub fn get_volume_protocol() -> *mut load_file::Protocol {
+    VOLUME_LOAD_FILE.as_ptr()
+}
+
+extern "efiapi" fn volume_load_file(
+    _this: *mut load_file::Protocol,
+    file_path: *mut DevicePathProtocol,
+    boot_policy: Boolean,
+    buffer_size: *mut usize,
+    buffer: *mut c_void,
+) -> Status {
+    if file_path.is_null() || buffer_size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let Some(path) = (unsafe { device_path::file_path_to_str::<MAX_PATH_LEN>(file_path) }) else {
+        log::debug!("LoadFile: not a file path");
+        return Status::NOT_FOUND;
+    };
+    let path = if !path.is_empty() {
+        path.as_str()
+    } else if boot_policy.into() {
+        DEFAULT_BOOT_PATH
+    } else {
+        return Status::INVALID_PARAMETER;
+    };
+
+    log::debug!(
+        "LoadFile(path={}, boot_policy={:?}, buffer_size={})",
+        path,
+        boot_policy,
+        unsafe { *buffer_size }
+    );
+
+    let partition_start = match state::efi().filesystem {
+        Some(s) => s.partition_start,
+        None => return Status::NOT_READY,
+    };
+
+    let result = state::with_block_device_mut(|device| {
+        let mut fat =
+            FatFilesystem::new(device, partition_start).map_err(|_| Status::DEVICE_ERROR)?;
+        let entry = fat.find_file(path).map_err(|_| Status::NOT_FOUND)?;
+        if entry.is_directory() {
+            return Err(Status::NOT_FOUND);
+        }
+
+        let file_size = entry.file_size() as usize;
+        if buffer.is_null() || unsafe { *buffer_size } < file_size {
+            unsafe { *buffer_size = file_size };
+            return Err(Status::BUFFER_TOO_SMALL);
+        }
+
+        let dest = unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, file_size) };
+        fat.read_file(&entry, 0, dest)
+            .map_err(|_| Status::DEVICE_ERROR)
+    });
+
+    match result {
+        Some(Ok(bytes_read)) => {
+            unsafe { *buffer_size = bytes_read };
+            log::debug!("  -> SUCCESS ({} bytes)", bytes_read);
+            Status::SUCCESS
+        }
+        Some(Err(status)) => {
+            log::debug!("  -> {:?}", status);
+            status
+        }
+        None => Status::NOT_READY,
+    }
+}
@@ -2,11 +2,17 @@
 //!
 //! This protocol provides retrieval and update services for memory attributes.
 //! It allows querying and modifying memory protection attributes (read/write/execute).
+//! Loaders use it to make the code they load executable and read-only, see
+//! [`crate::arch::x86_64::protect`]. Read protection is not supported.
 //!
 //! Reference: UEFI Specification 2.10, Section 7.2
 
+use core::ops::Range;
+
 use r_efi::efi::{Guid, PhysicalAddress, Status};
 
+use crate::arch::x86_64::{paging, protect};
+use crate::efi::allocator::PAGE_SIZE;
 use crate::efi::utils::allocate_protocol_with_log;
 
 /// Memory Attribute Protocol GUID
@@ -54,10 +60,64 @@ pub struct Protocol {
     ) -> Status,
 }
 
-/// Get memory attributes for a region
+/// Check that a region is whole pages, returning it as a range
+fn page_range(base_address: PhysicalAddress, length: u64) -> Option<Range<u64>> {
+    let end = base_address.checked_add(length)?;
+    (length != 0 && base_address.is_multiple_of(PAGE_SIZE) && length.is_multiple_of(PAGE_SIZE))
+        .then_some(base_address..end)
+}
+
+/// The memory attributes of a mapped page
+fn page_attributes(page: u64) -> Option<u64> {
+    let flags = paging::page_flags(page)?;
+    let mut attributes = 0;
+    if flags & paging::flags::WRITABLE == 0 {
+        attributes |= EFI_MEMORY_RO;
+    }
+    if flags & paging::flags::NO_EXECUTE != 0 {
+        attributes |= EFI_MEMORY_XP;
+    }
+    Some(attributes)
+}
+
+/// Set and clear page flags for the attributes in `attributes`
+fn update(base_address: PhysicalAddress, length: u64, attributes: u64, set: bool) -> Status {
+    let Some(range) = page_range(base_address, length) else {
+        return Status::INVALID_PARAMETER;
+    };
+    if attributes == 0 || attributes & !EFI_MEMORY_ACCESS_MASK != 0 {
+        return Status::INVALID_PARAMETER;
+    }
+    // Read protection would mean unmapping the pages
+    if attributes & EFI_MEMORY_RP != 0 {
+        return Status::UNSUPPORTED;
+    }
+    if protect::overlaps_firmware(&range) {
+        return Status::ACCESS_DENIED;
+    }
+
+    // RO clears the writable flag, XP sets the no-execute flag
+    let mut enable = 0;
+    let mut disable = 0;
+    if attributes & EFI_MEMORY_RO != 0 {
+        disable |= paging::flags::WRITABLE;
+    }
+    if attributes & EFI_MEMORY_XP != 0 {
+        enable |= paging::flags::NO_EXECUTE;
+    }
+    if !set {
+        core::mem::swap(&mut enable, &mut disable);
+    }
+    match protect::set_flags(range, enable, disable) {
+        true => Status::SUCCESS,
+        false => Status::OUT_OF_RESOURCES,
+    }
+}
+
+/// Get the memory attributes of a region
 ///
-/// This stub implementation returns 0 (read-write-execute) for all memory,
-/// since we don't track per-page attributes.
+/// Reports RO and XP from the page tables. Regions with pages mapped
+/// differently or not at all have no single set of attributes.
 extern "efiapi" fn get_memory_attributes(
     _this: *mut Protocol,
     base_address: PhysicalAddress,
@@ -70,30 +130,25 @@ extern "efiapi" fn get_memory_attributes(
         length
     );
 
-    if length == 0 {
-        log::debug!("  -> INVALID_PARAMETER (length is 0)");
+    let Some(range) = page_range(base_address, length) else {
         return Status::INVALID_PARAMETER;
-    }
-
+    };
     if attributes.is_null() {
-        log::debug!("  -> INVALID_PARAMETER (attributes is null)");
         return Status::INVALID_PARAMETER;
     }
 
-    // Return 0 = no protection attributes (read-write-execute allowed)
-    // This is the default for conventional memory
-    unsafe {
-        *attributes = 0;
+    let mut pages = range.step_by(PAGE_SIZE as usize).map(page_attributes);
+    let Some(Some(first)) = pages.next() else {
+        return Status::NO_MAPPING;
+    };
+    if !pages.all(|page| page == Some(first)) {
+        return Status::NO_MAPPING;
     }
-
-    log::trace!("  -> SUCCESS (attributes=0x0)");
+    unsafe { *attributes = first };
     Status::SUCCESS
 }
 
 /// Set memory attributes for a region
-///
-/// This stub implementation logs the request but doesn't modify page tables.
-/// Returns SUCCESS to allow the bootloader to proceed.
 extern "efiapi" fn set_memory_attributes(
     _this: *mut Protocol,
     base_address: PhysicalAddress,
@@ -106,47 +161,10 @@ extern "efiapi" fn set_memory_attributes(
         length,
         attributes
     );
-
-    if length == 0 {
-        log::trace!("  -> INVALID_PARAMETER (length is 0)");
-        return Status::INVALID_PARAMETER;
-    }
-
-    if attributes == 0 {
-        log::trace!("  -> INVALID_PARAMETER (attributes is 0)");
-        return Status::INVALID_PARAMETER;
-    }
-
-    // Validate that only valid attribute bits are set
-    if (attributes & !EFI_MEMORY_ACCESS_MASK) != 0 {
-        log::trace!("  -> INVALID_PARAMETER (invalid attribute bits)");
-        return Status::INVALID_PARAMETER;
-    }
-
-    // Log what attributes are being set
-    let mut attr_str = heapless::String::<64>::new();
-    if attributes & EFI_MEMORY_RP != 0 {
-        let _ = attr_str.push_str("RP ");
-    }
-    if attributes & EFI_MEMORY_XP != 0 {
-        let _ = attr_str.push_str("XP ");
-    }
-    if attributes & EFI_MEMORY_RO != 0 {
-        let _ = attr_str.push_str("RO ");
-    }
-    log::trace!("  -> SUCCESS (stubbed, requested: {})", attr_str.as_str());
-
-    // In a full implementation, we would modify page table entries here
-    // to set the appropriate protection bits (NX, read-only, etc.)
-    // For now, we just return success to allow the bootloader to proceed.
-
-    Status::SUCCESS
+    update(base_address, length, attributes, true)
 }
 
 /// Clear memory attributes for a region
-///
-/// This stub implementation logs the request but doesn't modify page tables.
-/// Returns SUCCESS to allow the bootloader to proceed.
 extern "efiapi" fn clear_memory_attributes(
     _this: *mut Protocol,
     base_address: PhysicalAddress,
@@ -159,32 +177,7 @@ extern "efiapi" fn clear_memory_attributes(
         length,
         attributes
     );
-
-    if length == 0 {
-        log::trace!("  -> INVALID_PARAMETER (length is 0)");
-        return Status::INVALID_PARAMETER;
-    }
-
-    // Validate that only valid attribute bits are set
-    if (attributes & !EFI_MEMORY_ACCESS_MASK) != 0 {
-        log::trace!("  -> INVALID_PARAMETER (invalid attribute bits)");
-        return Status::INVALID_PARAMETER;
-    }
-
-    // Log what attributes are being cleared
-    let mut attr_str = heapless::String::<64>::new();
-    if attributes & EFI_MEMORY_RP != 0 {
-        let _ = attr_str.push_str("RP ");
-    }
-    if attributes & EFI_MEMORY_XP != 0 {
-        let _ = attr_str.push_str("XP ");
-    }
-    if attributes & EFI_MEMORY_RO != 0 {
-        let _ = attr_str.push_str("RO ");
-    }
-    log::trace!("  -> SUCCESS (stubbed, clearing: {})", attr_str.as_str());
-
-    Status::SUCCESS
+    update(base_address, length, attributes, false)
 }
 
 /// Create and initialize the Memory Attribute Protocol
@@ -6,14 +6,26 @@ pub mod ata_pass_thru;
 pub mod block_io;
 pub mod console;
 pub mod console_control;
+pub mod debug_port;
 pub mod device_path;
+pub mod edid;
+pub mod firmware_volume;
 pub mod graphics_output;
+pub mod hii;
+pub mod initrd;
+pub mod load_file;
 pub mod loaded_image;
 pub mod memory_attribute;
+pub mod mp_services;
 pub mod nvme_pass_thru;
 pub mod pass_thru_init;
+pub mod ram_disk;
 pub mod scsi_pass_thru;
+pub mod security;
 pub mod serial_io;
+pub mod shell_parameters;
 pub mod simple_file_system;
 pub mod storage_security;
+#[cfg(feature = "protocol-trace")]
+pub mod trace;
 pub mod unicode_collation;
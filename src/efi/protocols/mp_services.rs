@@ -0,0 +1,730 @@
+//! EFI MP Services Protocol
+//!
+//! This module provides EFI_MP_SERVICES_PROTOCOL so that applications such
+//! as memory testers and benchmarks can run code on all cores.
+//!
+//! The processors are the enabled entries of the ACPI MADT, the BSP being
+//! processor 0. At startup every AP is started with INIT-SIPI-SIPI and spins
+//! in [`ap_main`] until StartupAllAPs or StartupThisAP hands it a procedure
+//! through its slot in the processor table. There is no timer interrupt, so
+//! non-blocking requests complete while the application polls events.
+//!
+//! At ExitBootServices every AP gets an INIT IPI, which leaves it in the
+//! wait-for-SIPI state the OS's own SMP startup expects.
+//!
+//! Reference: UEFI PI Specification 1.8, Volume 2, Section 13.4
+
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use heapless::Vec;
+use r_efi::efi::{Boolean, Event, Guid, Status};
+use r_efi::protocols::mp_services::{
+    self, ApProcedure, CpuPhysicalLocation, CpuPhysicalLocation2, ExtendedProcessorInformation,
+    ProcessorInformation,
+};
+use spin::Mutex;
+
+use crate::arch::x86_64::{idt, smp};
+use crate::efi::allocator::{self, AllocateType, MemoryType};
+use crate::efi::boot_services;
+use crate::efi::utils::allocate_protocol_with_log;
+use crate::state::MAX_PROCESSORS;
+use crate::time::{self, Timeout};
+
+/// Re-export the GUID for external use
+pub const MP_SERVICES_PROTOCOL_GUID: Guid = mp_services::PROTOCOL_GUID;
+
+/// Processor number flag asking GetProcessorInfo for the extended location
+const CPU_V2_EXTENDED_TOPOLOGY: usize = 1 << 24;
+
+/// Stack size of each AP, in pages
+const AP_STACK_PAGES: u64 = 4;
+
+/// Time an AP gets to reach [`ap_main`] after the STARTUP IPIs
+const AP_START_TIMEOUT_MS: u64 = 100;
+
+/// AP states
+const OFFLINE: u8 = 0;
+const IDLE: u8 = 1;
+const BUSY: u8 = 2;
+
+/// EFI MP Services Protocol structure
+///
+/// Like the r-efi definition, but with the procedures optional so a null
+/// procedure from the caller can be rejected.
+#[repr(C)]
+pub struct Protocol {
+    pub get_number_of_processors:
+        extern "efiapi" fn(this: *mut Protocol, number: *mut usize, enabled: *mut usize) -> Status,
+    pub get_processor_info: extern "efiapi" fn(
+        this: *mut Protocol,
+        processor_number: usize,
+        info: *mut ProcessorInformation,
+    ) -> Status,
+    pub startup_all_aps: extern "efiapi" fn(
+        this: *mut Protocol,
+        procedure: Option<ApProcedure>,
+        single_thread: Boolean,
+        wait_event: Event,
+        timeout_us: usize,
+        argument: *mut c_void,
+        failed_cpu_list: *mut *mut usize,
+    ) -> Status,
+    pub startup_this_ap: extern "efiapi" fn(
+        this: *mut Protocol,
+        procedure: Option<ApProcedure>,
+        processor_number: usize,
+        wait_event: Event,
+        timeout_us: usize,
+        argument: *mut c_void,
+        finished: *mut Boolean,
+    ) -> Status,
+    pub switch_bsp: extern "efiapi" fn(
+        this: *mut Protocol,
+        processor_number: usize,
+        enable_old_bsp: Boolean,
+    ) -> Status,
+    pub enable_disable_ap: extern "efiapi" fn(
+        this: *mut Protocol,
+        processor_number: usize,
+        enable: Boolean,
+        health_flag: *mut u32,
+    ) -> Status,
+    pub who_am_i: extern "efiapi" fn(this: *mut Protocol, processor_number: *mut usize) -> Status,
+}
+
+/// Slot of a processor in the processor table
+struct Processor {
+    apic_id: AtomicU32,
+    /// `OFFLINE`, `IDLE` or `BUSY`
+    state: AtomicU8,
+    enabled: AtomicBool,
+    healthy: AtomicBool,
+    /// Procedure and argument of the current job, valid while `BUSY`
+    procedure: AtomicUsize,
+    argument: AtomicUsize,
+    stack_top: AtomicU64,
+}
+
+impl Processor {
+    const fn new() -> Self {
+        Self {
+            apic_id: AtomicU32::new(0),
+            state: AtomicU8::new(OFFLINE),
+            enabled: AtomicBool::new(false),
+            healthy: AtomicBool::new(false),
+            procedure: AtomicUsize::new(0),
+            argument: AtomicUsize::new(0),
+            stack_top: AtomicU64::new(0),
+        }
+    }
+}
+
+/// The processor table, BSP first
+static PROCESSORS: [Processor; MAX_PROCESSORS] = [const { Processor::new() }; MAX_PROCESSORS];
+
+/// Number of processors in the table
+static PROCESSOR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Startup page of the APs, below 1 MiB
+static TRAMPOLINE: AtomicU64 = AtomicU64::new(0);
+
+/// A StartupAllAPs or StartupThisAP request
+struct Request {
+    procedure: ApProcedure,
+    argument: *mut c_void,
+    single_thread: bool,
+    /// Processors that haven't started the procedure yet
+    pending: u64,
+    /// Processors running the procedure
+    running: u64,
+    deadline: Option<Timeout>,
+    /// Completion event of a non-blocking request
+    event: Event,
+    failed_cpu_list: *mut *mut usize,
+    finished: *mut Boolean,
+}
+
+// Safety: requests are only touched by the BSP, under the REQUESTS lock
+unsafe impl Send for Request {}
+
+/// Non-blocking requests in progress
+static REQUESTS: Mutex<Vec<Request, MAX_PROCESSORS>> = Mutex::new(Vec::new());
+
+/// Iterate over the processor numbers set in a mask
+fn processors_in(mask: u64) -> impl Iterator<Item = usize> {
+    (0..MAX_PROCESSORS).filter(move |&index| mask & (1 << index) != 0)
+}
+
+/// Number of processors in the table
+fn processor_count() -> usize {
+    PROCESSOR_COUNT.load(Ordering::Acquire)
+}
+
+/// Processor number of the calling processor
+fn current_processor() -> Option<usize> {
+    let apic_id = smp::apic_id();
+    (0..processor_count())
+        .find(|&index| PROCESSORS[index].apic_id.load(Ordering::Relaxed) == apic_id)
+}
+
+/// Check whether the BSP is calling
+fn called_from_bsp() -> bool {
+    current_processor() == Some(0)
+}
+
+/// Processors that are taken by a running or queued request
+fn reserved_processors(requests: &[Request]) -> u64 {
+    let busy = (1..processor_count())
+        .filter(|&index| PROCESSORS[index].state.load(Ordering::Acquire) == BUSY)
+        .fold(0, |mask, index| mask | 1 << index);
+    requests
+        .iter()
+        .fold(busy, |mask, request| mask | request.pending)
+}
+
+/// Entry point of the APs, called by the trampoline
+extern "C" fn ap_main(index: u64) -> ! {
+    idt::load_ap();
+
+    let processor = &PROCESSORS[index as usize];
+    processor.state.store(IDLE, Ordering::Release);
+    loop {
+        if processor.state.load(Ordering::Acquire) == BUSY {
+            let procedure = processor.procedure.load(Ordering::Relaxed);
+            let argument = processor.argument.load(Ordering::Relaxed);
+            // Safety: the BSP stores a valid ApProcedure before setting BUSY
+            let procedure: ApProcedure = unsafe { core::mem::transmute(procedure) };
+            procedure(argument as *mut c_void);
+            processor.state.store(IDLE, Ordering::Release);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Start an AP and wait until it reaches [`ap_main`]
+///
+/// Also used to recover an AP that timed out, since INIT stops it.
+fn start_ap(index: usize) -> bool {
+    let processor = &PROCESSORS[index];
+    let apic_id = processor.apic_id.load(Ordering::Relaxed);
+    let trampoline = TRAMPOLINE.load(Ordering::Relaxed);
+
+    processor.state.store(OFFLINE, Ordering::Release);
+    unsafe {
+        smp::prepare_startup(
+            trampoline,
+            ap_main,
+            index as u64,
+            processor.stack_top.load(Ordering::Relaxed),
+        );
+    }
+
+    smp::send_init(apic_id);
+    time::delay_ms(10);
+    for _ in 0..2 {
+        smp::send_startup(apic_id, trampoline);
+        time::delay_us(200);
+    }
+
+    let started = time::wait_for(AP_START_TIMEOUT_MS, || {
+        processor.state.load(Ordering::Acquire) == IDLE
+    });
+    if !started {
+        // Keep it from coming up later on the startup block of another AP
+        smp::send_init(apic_id);
+        log::warn!("MP: AP with APIC ID {} didn't start", apic_id);
+    }
+    processor.enabled.store(started, Ordering::Relaxed);
+    processor.healthy.store(started, Ordering::Relaxed);
+    started
+}
+
+/// Start the APs listed in the MADT
+pub fn init(acpi_rsdp: Option<u64>) {
+    let bsp_id = smp::apic_id();
+    let mut apic_ids: Vec<u32, MAX_PROCESSORS> = Vec::new();
+    let _ = apic_ids.push(bsp_id);
+    if let Some(madt) = acpi_rsdp.and_then(|rsdp| unsafe { crate::acpi::find_table(rsdp, b"APIC") })
+    {
+        let madt = unsafe { crate::acpi::table_bytes(madt) };
+        for id in crate::acpi::madt_apic_ids(madt) {
+            if id == bsp_id {
+                continue;
+            }
+            if !smp::can_address(id) {
+                log::warn!("MP: APIC ID {} not reachable in xAPIC mode", id);
+                continue;
+            }
+            let _ = apic_ids.push(id);
+        }
+    } else {
+        log::warn!("MP: no MADT, only the BSP is available");
+    }
+
+    let bsp = &PROCESSORS[0];
+    bsp.apic_id.store(bsp_id, Ordering::Relaxed);
+    bsp.enabled.store(true, Ordering::Relaxed);
+    bsp.healthy.store(true, Ordering::Relaxed);
+    bsp.state.store(IDLE, Ordering::Relaxed);
+    if apic_ids.len() == 1 {
+        PROCESSOR_COUNT.store(1, Ordering::Release);
+        return;
+    }
+
+    // STARTUP IPIs take the number of a page below 1 MiB, allocated as code
+    // to stay executable for APs recovered later
+    let mut trampoline = 0xF_FFFF;
+    let status = allocator::allocate_pages(
+        AllocateType::AllocateMaxAddress,
+        MemoryType::BootServicesCode,
+        1,
+        &mut trampoline,
+    );
+    if status != Status::SUCCESS {
+        log::error!("MP: no memory below 1 MiB for the AP trampoline");
+        PROCESSOR_COUNT.store(1, Ordering::Release);
+        return;
+    }
+    unsafe { smp::install_trampoline(trampoline) };
+    TRAMPOLINE.store(trampoline, Ordering::Relaxed);
+
+    let mut count = 1;
+    for &apic_id in &apic_ids[1..] {
+        let mut stack = 0;
+        let status = allocator::allocate_pages(
+            AllocateType::AllocateAnyPages,
+            MemoryType::BootServicesData,
+            AP_STACK_PAGES,
+            &mut stack,
+        );
+        if status != Status::SUCCESS {
+            log::error!("MP: no memory for AP stacks");
+            break;
+        }
+
+        let processor = &PROCESSORS[count];
+        processor.apic_id.store(apic_id, Ordering::Relaxed);
+        processor.stack_top.store(
+            stack + AP_STACK_PAGES * allocator::PAGE_SIZE,
+            Ordering::Relaxed,
+        );
+        start_ap(count);
+        count += 1;
+    }
+    PROCESSOR_COUNT.store(count, Ordering::Release);
+
+    let enabled = (0..count)
+        .filter(|&index| PROCESSORS[index].enabled.load(Ordering::Relaxed))
+        .count();
+    log::info!("MP: {} of {} processors started", enabled, count);
+}
+
+/// Put the APs into wait-for-SIPI state for the OS
+///
+/// Called at ExitBootServices.
+pub fn park() {
+    REQUESTS.lock().clear();
+    for processor in PROCESSORS.iter().take(processor_count()).skip(1) {
+        if processor.state.swap(OFFLINE, Ordering::AcqRel) != OFFLINE {
+            smp::send_init(processor.apic_id.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Enabled APs that are idle and not reserved by a request
+pub fn idle_aps() -> impl Iterator<Item = usize> {
+    let reserved = reserved_processors(&REQUESTS.lock());
+    (1..processor_count()).filter(move |&index| {
+        let processor = &PROCESSORS[index];
+        reserved & (1 << index) == 0
+            && processor.enabled.load(Ordering::Relaxed)
+            && processor.state.load(Ordering::Acquire) == IDLE
+    })
+}
+
+/// Hand a procedure to an idle AP
+///
+/// The firmware also uses this directly for APs from [`idle_aps`], outside
+/// of any request.
+pub fn assign(index: usize, procedure: ApProcedure, argument: *mut c_void) {
+    let processor = &PROCESSORS[index];
+    processor
+        .procedure
+        .store(procedure as usize, Ordering::Relaxed);
+    processor
+        .argument
+        .store(argument as usize, Ordering::Relaxed);
+    processor.state.store(BUSY, Ordering::Release);
+}
+
+/// Move a request forward, returning true once all its processors are done
+fn advance(request: &mut Request) -> bool {
+    for index in processors_in(request.running) {
+        if PROCESSORS[index].state.load(Ordering::Acquire) != BUSY {
+            request.running &= !(1 << index);
+        }
+    }
+
+    if !request.single_thread || request.running == 0 {
+        let next = if request.single_thread {
+            request.pending & request.pending.wrapping_neg()
+        } else {
+            request.pending
+        };
+        for index in processors_in(next) {
+            assign(index, request.procedure, request.argument);
+        }
+        request.pending &= !next;
+        request.running |= next;
+    }
+
+    request.pending == 0 && request.running == 0
+}
+
+/// Finish a request, recovering the APs that didn't finish in time
+fn complete(request: &Request, timed_out: bool) -> Status {
+    let failed = if timed_out {
+        request.pending | request.running
+    } else {
+        0
+    };
+    for index in processors_in(request.running) {
+        log::warn!("MP: processor {} timed out, restarting it", index);
+        start_ap(index);
+    }
+
+    if !request.failed_cpu_list.is_null() {
+        unsafe { *request.failed_cpu_list = failed_cpu_list(failed) };
+    }
+    if !request.finished.is_null() {
+        unsafe { *request.finished = (!timed_out).into() };
+    }
+
+    if timed_out {
+        Status::TIMEOUT
+    } else {
+        Status::SUCCESS
+    }
+}
+
+/// Allocate the `END_OF_CPU_LIST` terminated list of failed processors
+fn failed_cpu_list(failed: u64) -> *mut usize {
+    if failed == 0 {
+        return core::ptr::null_mut();
+    }
+
+    let count = failed.count_ones() as usize + 1;
+    let Ok(buffer) =
+        allocator::allocate_pool(MemoryType::BootServicesData, count * size_of::<usize>())
+    else {
+        return core::ptr::null_mut();
+    };
+    let list = unsafe { core::slice::from_raw_parts_mut(buffer as *mut usize, count) };
+    for (slot, index) in list
+        .iter_mut()
+        .zip(processors_in(failed).chain([mp_services::END_OF_CPU_LIST]))
+    {
+        *slot = index;
+    }
+    buffer as *mut usize
+}
+
+/// Run a request, waiting for it unless it has a completion event
+fn run(request: Request) -> Status {
+    let mut request = request;
+    if request.event.is_null() {
+        loop {
+            if advance(&mut request) {
+                return complete(&request, false);
+            }
+            if request.deadline.is_some_and(|d| d.is_expired()) {
+                return complete(&request, true);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    advance(&mut request);
+    match REQUESTS.lock().push(request) {
+        Ok(()) => Status::SUCCESS,
+        Err(_) => Status::OUT_OF_RESOURCES,
+    }
+}
+
+/// Complete finished non-blocking requests and signal their events
+///
+/// Called whenever the application polls events.
+pub fn check_requests() {
+    let mut completed: Vec<Event, MAX_PROCESSORS> = Vec::new();
+    REQUESTS.lock().retain_mut(|request| {
+        let done = advance(request);
+        let timed_out = !done && request.deadline.is_some_and(|d| d.is_expired());
+        if !done && !timed_out {
+            return true;
+        }
+        complete(request, timed_out);
+        let _ = completed.push(request.event);
+        false
+    });
+
+    for event in completed {
+        boot_services::signal(event);
+    }
+}
+
+/// Timeout of a request, 0 meaning no timeout
+fn deadline(timeout_us: usize) -> Option<Timeout> {
+    (timeout_us != 0).then(|| Timeout::from_us(timeout_us as u64))
+}
+
+/// Bit widths of the thread and thread-plus-core parts of an APIC ID
+fn topology_widths() -> (u32, u32) {
+    if __cpuid(0).eax < 0xB {
+        return (0, 0);
+    }
+    let smt = __cpuid_count(0xB, 0).eax & 0x1F;
+    let core = __cpuid_count(0xB, 1).eax & 0x1F;
+    (smt, core.max(smt))
+}
+
+extern "efiapi" fn get_number_of_processors(
+    _this: *mut Protocol,
+    number: *mut usize,
+    enabled: *mut usize,
+) -> Status {
+    if !called_from_bsp() {
+        return Status::DEVICE_ERROR;
+    }
+    if number.is_null() || enabled.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let count = processor_count();
+    let enabled_count = (0..count)
+        .filter(|&index| PROCESSORS[index].enabled.load(Ordering::Relaxed))
+        .count();
+    unsafe {
+        *number = count;
+        *enabled = enabled_count;
+    }
+    log::debug!("MP.GetNumberOfProcessors() -> {}/{}", enabled_count, count);
+    Status::SUCCESS
+}
+
+extern "efiapi" fn get_processor_info(
+    _this: *mut Protocol,
+    processor_number: usize,
+    info: *mut ProcessorInformation,
+) -> Status {
+    if !called_from_bsp() {
+        return Status::DEVICE_ERROR;
+    }
+    if info.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let index = processor_number & !CPU_V2_EXTENDED_TOPOLOGY;
+    if index >= processor_count() {
+        return Status::NOT_FOUND;
+    }
+
+    let processor = &PROCESSORS[index];
+    let apic_id = processor.apic_id.load(Ordering::Relaxed);
+    let mut status_flag = 0;
+    if index == 0 {
+        status_flag |= mp_services::PROCESSOR_AS_BSP_BIT;
+    }
+    if processor.enabled.load(Ordering::Relaxed) {
+        status_flag |= mp_services::PROCESSOR_ENABLED_BIT;
+    }
+    if processor.healthy.load(Ordering::Relaxed) {
+        status_flag |= mp_services::PROCESSOR_HEALTH_STATUS_BIT;
+    }
+
+    let (smt_width, core_width) = topology_widths();
+    let thread = apic_id & ((1 << smt_width) - 1);
+    let core = (apic_id >> smt_width) & ((1 << (core_width - smt_width)) - 1);
+    let package = apic_id >> core_width;
+
+    let info = unsafe { &mut *info };
+    info.processor_id = apic_id as u64;
+    info.status_flag = status_flag;
+    info.location = CpuPhysicalLocation {
+        package,
+        core,
+        thread,
+    };
+    if processor_number & CPU_V2_EXTENDED_TOPOLOGY != 0 {
+        info.extended_information = ExtendedProcessorInformation {
+            location2: CpuPhysicalLocation2 {
+                package,
+                module: 0,
+                tile: 0,
+                die: 0,
+                core,
+                thread,
+            },
+        };
+    }
+    Status::SUCCESS
+}
+
+extern "efiapi" fn startup_all_aps(
+    _this: *mut Protocol,
+    procedure: Option<ApProcedure>,
+    single_thread: Boolean,
+    wait_event: Event,
+    timeout_us: usize,
+    argument: *mut c_void,
+    failed_cpu_list: *mut *mut usize,
+) -> Status {
+    log::debug!(
+        "MP.StartupAllAPs(single_thread={:?}, event={:?}, timeout={})",
+        single_thread,
+        wait_event,
+        timeout_us
+    );
+
+    if !called_from_bsp() {
+        return Status::DEVICE_ERROR;
+    }
+    let Some(procedure) = procedure else {
+        return Status::INVALID_PARAMETER;
+    };
+
+    let aps = (1..processor_count())
+        .filter(|&index| PROCESSORS[index].enabled.load(Ordering::Relaxed))
+        .fold(0u64, |mask, index| mask | 1 << index);
+    if aps == 0 {
+        return Status::NOT_STARTED;
+    }
+    if reserved_processors(&REQUESTS.lock()) & aps != 0 {
+        return Status::NOT_READY;
+    }
+
+    run(Request {
+        procedure,
+        argument,
+        single_thread: single_thread.into(),
+        pending: aps,
+        running: 0,
+        deadline: deadline(timeout_us),
+        event: wait_event,
+        failed_cpu_list,
+        finished: core::ptr::null_mut(),
+    })
+}
+
+extern "efiapi" fn startup_this_ap(
+    _this: *mut Protocol,
+    procedure: Option<ApProcedure>,
+    processor_number: usize,
+    wait_event: Event,
+    timeout_us: usize,
+    argument: *mut c_void,
+    finished: *mut Boolean,
+) -> Status {
+    log::debug!(
+        "MP.StartupThisAP(processor={}, event={:?}, timeout={})",
+        processor_number,
+        wait_event,
+        timeout_us
+    );
+
+    if !called_from_bsp() {
+        return Status::DEVICE_ERROR;
+    }
+    let Some(procedure) = procedure else {
+        return Status::INVALID_PARAMETER;
+    };
+    if processor_number >= processor_count() {
+        return Status::NOT_FOUND;
+    }
+    if processor_number == 0 || !PROCESSORS[processor_number].enabled.load(Ordering::Relaxed) {
+        return Status::INVALID_PARAMETER;
+    }
+    if reserved_processors(&REQUESTS.lock()) & (1 << processor_number) != 0 {
+        return Status::NOT_READY;
+    }
+
+    run(Request {
+        procedure,
+        argument,
+        single_thread: false,
+        pending: 1 << processor_number,
+        running: 0,
+        deadline: deadline(timeout_us),
+        event: wait_event,
+        failed_cpu_list: core::ptr::null_mut(),
+        finished,
+    })
+}
+
+extern "efiapi" fn switch_bsp(
+    _this: *mut Protocol,
+    processor_number: usize,
+    _enable_old_bsp: Boolean,
+) -> Status {
+    log::debug!("MP.SwitchBSP({}) -> UNSUPPORTED", processor_number);
+    Status::UNSUPPORTED
+}
+
+extern "efiapi" fn enable_disable_ap(
+    _this: *mut Protocol,
+    processor_number: usize,
+    enable: Boolean,
+    health_flag: *mut u32,
+) -> Status {
+    if !called_from_bsp() {
+        return Status::DEVICE_ERROR;
+    }
+    if processor_number >= processor_count() {
+        return Status::NOT_FOUND;
+    }
+    if processor_number == 0 {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let processor = &PROCESSORS[processor_number];
+    let enable: bool = enable.into();
+    if enable && processor.state.load(Ordering::Acquire) == OFFLINE {
+        // The AP never started
+        return Status::UNSUPPORTED;
+    }
+    processor.enabled.store(enable, Ordering::Relaxed);
+    if !health_flag.is_null() {
+        let healthy = unsafe { *health_flag } & mp_services::PROCESSOR_HEALTH_STATUS_BIT != 0;
+        processor.healthy.store(healthy, Ordering::Relaxed);
+    }
+    Status::SUCCESS
+}
+
+extern "efiapi" fn who_am_i(_this: *mut Protocol, processor_number: *mut usize) -> Status {
+    if processor_number.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    match current_processor() {
+        Some(index) => {
+            unsafe { *processor_number = index };
+            Status::SUCCESS
+        }
+        None => Status::DEVICE_ERROR,
+    }
+}
+
+/// Create the MP Services Protocol
+///
+/// # Returns
+/// A pointer to the protocol instance, or null on allocation failure
+pub fn create_protocol() -> *mut Protocol {
+    allocate_protocol_with_log::<Protocol>("MpServicesProtocol", |p| {
+        p.get_number_of_processors = get_number_of_processors;
+        p.get_processor_info = get_processor_info;
+        p.startup_all_aps = startup_all_aps;
+        p.startup_this_ap = startup_this_ap;
+        p.switch_bsp = switch_bsp;
+        p.enable_disable_ap = enable_disable_ap;
+        p.who_am_i = who_am_i;
+    })
+}
@@ -13,6 +13,7 @@ use r_efi::efi::{Event, Guid, Status};
 use r_efi::protocols::device_path::Protocol as DevicePathProtocol;
 
 use crate::drivers::nvme;
+use crate::efi::cell::ContextTable;
 use crate::efi::protocols::device_path::{self, NvmeDevicePathNode};
 use crate::efi::utils::allocate_protocol_with_log;
 
@@ -165,6 +166,7 @@ pub struct NvmExpressPassThruProtocol {
 }
 
 /// Internal context for NVMe Pass Thru protocol instance
+#[derive(Clone, Copy)]
 struct NvmePassThruContext {
     /// Controller index in the global controller list
     controller_index: usize,
@@ -177,36 +179,9 @@ struct NvmePassThruContext {
 /// Maximum number of NVMe Pass Thru protocol instances
 const MAX_INSTANCES: usize = 8;
 
-/// Global storage for contexts
-static mut CONTEXTS: [Option<NvmePassThruContext>; MAX_INSTANCES] = [const { None }; MAX_INSTANCES];
-
-/// Protocol instance to context mapping
-static mut PROTOCOL_TO_CONTEXT: [Option<*mut NvmExpressPassThruProtocol>; MAX_INSTANCES] =
-    [const { None }; MAX_INSTANCES];
-
-/// Find context index for a protocol instance
-fn find_context_index(protocol: *mut NvmExpressPassThruProtocol) -> Option<usize> {
-    unsafe {
-        let proto_map = core::ptr::addr_of!(PROTOCOL_TO_CONTEXT);
-        for (i, p) in (*proto_map).iter().enumerate() {
-            if let Some(ptr) = p
-                && *ptr == protocol
-            {
-                return Some(i);
-            }
-        }
-    }
-    None
-}
-
-/// Get context for a protocol instance
-fn get_context(protocol: *mut NvmExpressPassThruProtocol) -> Option<&'static NvmePassThruContext> {
-    let idx = find_context_index(protocol)?;
-    unsafe {
-        let contexts = core::ptr::addr_of!(CONTEXTS);
-        (*contexts)[idx].as_ref()
-    }
-}
+/// Contexts of the protocol instances
+static CONTEXTS: ContextTable<NvmExpressPassThruProtocol, NvmePassThruContext, MAX_INSTANCES> =
+    ContextTable::new();
 
 // ============================================================================
 // Protocol Functions
@@ -223,7 +198,7 @@ extern "efiapi" fn nvme_pass_thru(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx = match get_context(this) {
+    let ctx = match CONTEXTS.get(this) {
         Some(c) => c,
         None => {
             log::error!("NvmePassThru.PassThru: unknown protocol instance");
@@ -341,7 +316,7 @@ extern "efiapi" fn nvme_get_next_namespace(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx = match get_context(this) {
+    let ctx = match CONTEXTS.get(this) {
         Some(c) => c,
         None => {
             log::error!("NvmePassThru.GetNextNamespace: unknown protocol instance");
@@ -398,7 +373,7 @@ extern "efiapi" fn nvme_build_device_path(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx = match get_context(this) {
+    let ctx = match CONTEXTS.get(this) {
         Some(c) => c,
         None => {
             log::error!("NvmePassThru.BuildDevicePath: unknown protocol instance");
@@ -487,24 +462,11 @@ pub fn create_nvme_pass_thru_protocol(
     pci_device: u8,
     pci_function: u8,
 ) -> *mut NvmExpressPassThruProtocol {
-    // Find a free context slot
-    let ctx_idx = unsafe {
-        let mut found = None;
-        let contexts = core::ptr::addr_of!(CONTEXTS);
-        for (i, slot) in (*contexts).iter().enumerate() {
-            if slot.is_none() {
-                found = Some(i);
-                break;
-            }
-        }
-        match found {
-            Some(i) => i,
-            None => {
-                log::error!("NvmePassThru: no free context slots");
-                return core::ptr::null_mut();
-            }
-        }
-    };
+    // Make sure there is a free context slot
+    if CONTEXTS.is_full() {
+        log::error!("NvmePassThru: no free context slots");
+        return core::ptr::null_mut();
+    }
 
     // Get controller to read version
     let nvme_version = match nvme::get_controller(controller_index) {
@@ -545,17 +507,15 @@ pub fn create_nvme_pass_thru_protocol(
         return core::ptr::null_mut();
     }
 
-    // Store context
-    unsafe {
-        let contexts = core::ptr::addr_of_mut!(CONTEXTS);
-        (*contexts)[ctx_idx] = Some(NvmePassThruContext {
+    // Store context (checked for a free slot above)
+    CONTEXTS.insert(
+        protocol_ptr,
+        NvmePassThruContext {
             controller_index,
             pci_device,
             pci_function,
-        });
-        let proto_map = core::ptr::addr_of_mut!(PROTOCOL_TO_CONTEXT);
-        (*proto_map)[ctx_idx] = Some(protocol_ptr);
-    }
+        },
+    );
 
     log::info!(
         "NvmePassThru: created protocol for controller {} (PCI {:02x}:{:x}, NVMe version {:#x})",
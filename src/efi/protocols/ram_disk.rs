@@ -0,0 +1,231 @@
+//! EFI RAM Disk Protocol
+//!
+//! This module provides EFI_RAM_DISK_PROTOCOL, through which boot loaders
+//! register a disk image they loaded into memory, e.g. from the network or
+//! an ESP. A registered [RAM disk](crate::drivers::ramdisk) gets a handle
+//! with Block I/O and a RAM disk device path, and each GPT partition on it
+//! one of its own, the way the partition driver of other firmware finds
+//! them. Filesystems on it are read through the boot loader's own drivers,
+//! the Simple File System protocol serves the boot volume only.
+
+use core::ffi::c_void;
+use r_efi::efi::{Guid, Handle, Status};
+use r_efi::protocols::device_path::Protocol as DevicePathProtocol;
+
+use super::block_io::{self, BLOCK_IO_PROTOCOL_GUID, BlockIoProtocol};
+use super::device_path::{self, DEVICE_PATH_PROTOCOL_GUID, RamDiskDevicePathNode};
+use crate::drivers::block::RamDiskBlockDevice;
+use crate::drivers::ramdisk::{self, RamDisk};
+use crate::drivers::storage::{self, StorageType};
+use crate::efi::boot_services;
+use crate::efi::cell::EfiCell;
+use crate::efi::handles;
+use crate::fs::gpt;
+
+/// RAM Disk Protocol GUID
+pub const RAM_DISK_PROTOCOL_GUID: Guid = Guid::from_fields(
+    0xab38a0df,
+    0x6873,
+    0x44a9,
+    0x87,
+    0xe6,
+    &[0xd4, 0xeb, 0x56, 0x14, 0x84, 0x49],
+);
+
+/// Most handles of a RAM disk: the disk and its partitions
+const MAX_HANDLES: usize = 32;
+
+/// RAM Disk Protocol structure
+#[repr(C)]
+pub struct RamDiskProtocol {
+    pub register: extern "efiapi" fn(
+        ram_disk_base: u64,
+        ram_disk_size: u64,
+        ram_disk_type: *mut Guid,
+        parent_device_path: *mut DevicePathProtocol,
+        device_path: *mut *mut DevicePathProtocol,
+    ) -> Status,
+    pub unregister: extern "efiapi" fn(device_path: *mut DevicePathProtocol) -> Status,
+}
+
+/// RAM Disk Protocol instance
+static RAM_DISK_PROTOCOL: EfiCell<RamDiskProtocol> = EfiCell::new(RamDiskProtocol {
+    register: ram_disk_register,
+    unregister: ram_disk_unregister,
+});
+
+/// Get the RAM Disk Protocol
+pub fn get_protocol() -> *mut RamDiskProtocol {
+    RAM_DISK_PROTOCOL.as_ptr()
+}
+
+/// Device path node of RAM disk `id`
+fn node(id: usize, disk: &RamDisk) -> RamDiskDevicePathNode {
+    RamDiskDevicePathNode::new(disk.address, disk.size, disk.disk_type, id as u16)
+}
+
+/// Create a handle with `block_io` and `device_path` installed
+fn install_block_device(
+    block_io: *mut BlockIoProtocol,
+    device_path: *mut DevicePathProtocol,
+) -> Option<Handle> {
+    if block_io.is_null() || device_path.is_null() {
+        return None;
+    }
+    let handle = boot_services::create_handle()?;
+    let status =
+        boot_services::install_protocol(handle, &BLOCK_IO_PROTOCOL_GUID, block_io as *mut c_void);
+    if status != Status::SUCCESS {
+        log::warn!("RAM disk: failed to install BlockIO: {:?}", status);
+        return None;
+    }
+    let status = boot_services::install_protocol(
+        handle,
+        &DEVICE_PATH_PROTOCOL_GUID,
+        device_path as *mut c_void,
+    );
+    if status != Status::SUCCESS {
+        log::warn!("RAM disk: failed to install DevicePath: {:?}", status);
+    }
+    Some(handle)
+}
+
+/// Install Block I/O and device paths for RAM disk `id` and its partitions
+///
+/// `parent` is the device path the image was loaded from, or null. Returns
+/// the handle of the whole disk; a RAM disk is installed once.
+///
+/// # Safety
+/// `parent` must be null or point to a valid device path terminated by an
+/// End node.
+pub unsafe fn install(id: usize, parent: *const DevicePathProtocol) -> Option<Handle> {
+    let disk = ramdisk::get(id)?;
+    if disk.handle != 0 {
+        return Some(disk.handle as Handle);
+    }
+    let (num_blocks, block_size) = (disk.num_blocks(), disk.block_size());
+    let storage_id = storage::register_device(StorageType::RamDisk { id }, num_blocks, block_size)?;
+
+    let handle = install_block_device(
+        block_io::create_disk_block_io(storage_id, num_blocks, block_size),
+        unsafe { device_path::create_ram_disk_device_path(parent, node(id, &disk), None) },
+    )?;
+    ramdisk::set_handle(id, handle);
+    log::info!("RAM disk {}: installed on handle {:?}", id, handle);
+
+    let mut device = RamDiskBlockDevice::new(id, &disk);
+    let partitions = gpt::read_gpt_header(&mut device)
+        .and_then(|header| gpt::read_partitions(&mut device, &header))
+        .unwrap_or_default();
+    for (i, partition) in partitions.iter().enumerate() {
+        let number = (i + 1) as u32;
+        let _ = install_block_device(
+            block_io::create_partition_block_io(
+                storage_id,
+                number,
+                partition.first_lba,
+                partition.size_sectors(),
+                block_size,
+            ),
+            unsafe {
+                device_path::create_ram_disk_device_path(
+                    parent,
+                    node(id, &disk),
+                    Some((number, partition)),
+                )
+            },
+        );
+    }
+
+    Some(handle)
+}
+
+/// Remove Block I/O and the device paths of a RAM disk and its partitions
+fn uninstall(handle: Handle) {
+    handles::with(|db| {
+        let Some(disk_path) = db.find(handle, &DEVICE_PATH_PROTOCOL_GUID) else {
+            return;
+        };
+        let disk_path = disk_path as *const DevicePathProtocol;
+        let on_disk: heapless::Vec<Handle, MAX_HANDLES> = db
+            .handles_with(&DEVICE_PATH_PROTOCOL_GUID)
+            .filter(|&h| {
+                db.find(h, &DEVICE_PATH_PROTOCOL_GUID).is_some_and(|path| {
+                    // Safety: both are device paths installed on handles
+                    unsafe { device_path::strip_prefix(disk_path, path as *const _) }.is_some()
+                })
+            })
+            .take(MAX_HANDLES)
+            .collect();
+        for h in on_disk {
+            let _ = db.uninstall(h, &BLOCK_IO_PROTOCOL_GUID);
+            let _ = db.uninstall(h, &DEVICE_PATH_PROTOCOL_GUID);
+        }
+    });
+}
+
+extern "efiapi" fn ram_disk_register(
+    ram_disk_base: u64,
+    ram_disk_size: u64,
+    ram_disk_type: *mut Guid,
+    parent_device_path: *mut DevicePathProtocol,
+    device_path: *mut *mut DevicePathProtocol,
+) -> Status {
+    if ram_disk_size == 0 || ram_disk_type.is_null() || device_path.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let disk_type = unsafe { *ram_disk_type };
+    log::debug!(
+        "RamDisk.Register(base={:#x}, size={:#x})",
+        ram_disk_base,
+        ram_disk_size
+    );
+
+    if !ramdisk::is_supported_type(&disk_type) {
+        return Status::UNSUPPORTED;
+    }
+    if ramdisk::find(ram_disk_base, &disk_type).is_some() {
+        return Status::ALREADY_STARTED;
+    }
+    let Some(id) = ramdisk::register(ram_disk_base, ram_disk_size, disk_type, "") else {
+        log::warn!("RamDisk.Register: image rejected");
+        return Status::INVALID_PARAMETER;
+    };
+    let disk = ramdisk::get(id);
+    let Some(disk) = disk.filter(|_| unsafe { install(id, parent_device_path) }.is_some()) else {
+        ramdisk::unregister(id);
+        return Status::OUT_OF_RESOURCES;
+    };
+
+    // The caller frees its copy of the device path
+    let path = unsafe {
+        device_path::create_ram_disk_device_path(parent_device_path, node(id, &disk), None)
+    };
+    if path.is_null() {
+        return Status::OUT_OF_RESOURCES;
+    }
+    unsafe { *device_path = path };
+    Status::SUCCESS
+}
+
+extern "efiapi" fn ram_disk_unregister(device_path: *mut DevicePathProtocol) -> Status {
+    if device_path.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let Some(node) = (unsafe { device_path::ram_disk_node(device_path) }) else {
+        return Status::UNSUPPORTED;
+    };
+    let (address, disk_type) = (node.starting_address, node.disk_type());
+    log::debug!("RamDisk.Unregister(base={:#x})", address);
+
+    let Some(id) = ramdisk::find(address, &disk_type) else {
+        return Status::NOT_FOUND;
+    };
+    if let Some(disk) = ramdisk::get(id)
+        && disk.handle != 0
+    {
+        uninstall(disk.handle as Handle);
+    }
+    ramdisk::unregister(id);
+    Status::SUCCESS
+}
@@ -13,6 +13,7 @@ use r_efi::efi::{Event, Guid, Status};
 use r_efi::protocols::device_path::Protocol as DevicePathProtocol;
 
 use crate::drivers::usb;
+use crate::efi::cell::{ContextTable, EfiCell};
 use crate::efi::protocols::device_path::{self, UsbDevicePathNode};
 use crate::efi::utils::allocate_protocol_with_log;
 
@@ -182,6 +183,7 @@ pub struct ExtScsiPassThruProtocol {
 }
 
 /// Internal context for SCSI Pass Thru protocol instance
+#[derive(Clone, Copy)]
 struct ScsiPassThruContext {
     /// USB controller index
     controller_index: usize,
@@ -198,40 +200,13 @@ struct ScsiPassThruContext {
 /// Maximum number of SCSI Pass Thru protocol instances
 const MAX_INSTANCES: usize = 8;
 
-/// Global storage for contexts
-static mut CONTEXTS: [Option<ScsiPassThruContext>; MAX_INSTANCES] = [const { None }; MAX_INSTANCES];
-
-/// Protocol instance to context mapping
-static mut PROTOCOL_TO_CONTEXT: [Option<*mut ExtScsiPassThruProtocol>; MAX_INSTANCES] =
-    [const { None }; MAX_INSTANCES];
+/// Contexts of the protocol instances
+static CONTEXTS: ContextTable<ExtScsiPassThruProtocol, ScsiPassThruContext, MAX_INSTANCES> =
+    ContextTable::new();
 
 /// Target ID storage for each instance
-static mut TARGET_IDS: [[u8; TARGET_MAX_BYTES]; MAX_INSTANCES] =
-    [[0; TARGET_MAX_BYTES]; MAX_INSTANCES];
-
-/// Find context index for a protocol instance
-fn find_context_index(protocol: *mut ExtScsiPassThruProtocol) -> Option<usize> {
-    unsafe {
-        let proto_map = core::ptr::addr_of!(PROTOCOL_TO_CONTEXT);
-        for (i, p) in (*proto_map).iter().enumerate() {
-            if let Some(ptr) = p
-                && *ptr == protocol
-            {
-                return Some(i);
-            }
-        }
-    }
-    None
-}
-
-/// Get context for a protocol instance
-fn get_context(protocol: *mut ExtScsiPassThruProtocol) -> Option<&'static ScsiPassThruContext> {
-    let idx = find_context_index(protocol)?;
-    unsafe {
-        let contexts = core::ptr::addr_of!(CONTEXTS);
-        (*contexts)[idx].as_ref()
-    }
-}
+static TARGET_IDS: EfiCell<[[u8; TARGET_MAX_BYTES]; MAX_INSTANCES]> =
+    EfiCell::new([[0; TARGET_MAX_BYTES]; MAX_INSTANCES]);
 
 // ============================================================================
 // Protocol Functions
@@ -249,7 +224,7 @@ extern "efiapi" fn scsi_pass_thru(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx = match get_context(this) {
+    let ctx = match CONTEXTS.get(this) {
         Some(c) => c,
         None => {
             log::error!("ScsiPassThru.PassThru: unknown protocol instance");
@@ -429,7 +404,7 @@ extern "efiapi" fn scsi_get_next_target_lun(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx_idx = match find_context_index(this) {
+    let ctx_idx = match CONTEXTS.index_of(this) {
         Some(i) => i,
         None => {
             log::error!("ScsiPassThru.GetNextTargetLun: unknown protocol instance");
@@ -455,10 +430,12 @@ extern "efiapi" fn scsi_get_next_target_lun(
 
     if is_initial {
         // Return the first (and only) target: target ID 0, LUN 0
+        let target_id = TARGET_IDS.with(|ids| {
+            ids[ctx_idx].fill(0);
+            ids[ctx_idx].as_mut_ptr()
+        });
         unsafe {
-            let target_storage = core::ptr::addr_of_mut!(TARGET_IDS);
-            (*target_storage)[ctx_idx].fill(0);
-            *target = (*target_storage)[ctx_idx].as_mut_ptr();
+            *target = target_id;
             *lun = 0;
         }
         return Status::SUCCESS;
@@ -484,7 +461,7 @@ extern "efiapi" fn scsi_build_device_path(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx = match get_context(this) {
+    let ctx = match CONTEXTS.get(this) {
         Some(c) => c,
         None => {
             log::error!("ScsiPassThru.BuildDevicePath: unknown protocol instance");
@@ -523,7 +500,7 @@ extern "efiapi" fn scsi_get_target_lun(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx_idx = match find_context_index(this) {
+    let ctx_idx = match CONTEXTS.index_of(this) {
         Some(i) => i,
         None => {
             log::error!("ScsiPassThru.GetTargetLun: unknown protocol instance");
@@ -549,10 +526,12 @@ extern "efiapi" fn scsi_get_target_lun(
             log::debug!("ScsiPassThru.GetTargetLun: found USB port={}", port);
             
             // Return target ID 0 and LUN 0
+            let target_id = TARGET_IDS.with(|ids| {
+                ids[ctx_idx].fill(0);
+                ids[ctx_idx].as_mut_ptr()
+            });
             unsafe {
-                let target_storage = core::ptr::addr_of_mut!(TARGET_IDS);
-                (*target_storage)[ctx_idx].fill(0);
-                *target = (*target_storage)[ctx_idx].as_mut_ptr();
+                *target = target_id;
                 *lun = 0;
             }
             return Status::SUCCESS;
@@ -613,7 +592,7 @@ extern "efiapi" fn scsi_get_next_target(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx_idx = match find_context_index(this) {
+    let ctx_idx = match CONTEXTS.index_of(this) {
         Some(i) => i,
         None => {
             log::error!("ScsiPassThru.GetNextTarget: unknown protocol instance");
@@ -639,10 +618,12 @@ extern "efiapi" fn scsi_get_next_target(
 
     if is_initial {
         // Return the first (and only) target: target ID 0
+        let target_id = TARGET_IDS.with(|ids| {
+            ids[ctx_idx].fill(0);
+            ids[ctx_idx].as_mut_ptr()
+        });
         unsafe {
-            let target_storage = core::ptr::addr_of_mut!(TARGET_IDS);
-            (*target_storage)[ctx_idx].fill(0);
-            *target = (*target_storage)[ctx_idx].as_mut_ptr();
+            *target = target_id;
         }
         return Status::SUCCESS;
     }
@@ -673,24 +654,11 @@ pub fn create_scsi_pass_thru_protocol(
     pci_function: u8,
     usb_port: u8,
 ) -> *mut ExtScsiPassThruProtocol {
-    // Find a free context slot
-    let ctx_idx = unsafe {
-        let mut found = None;
-        let contexts = core::ptr::addr_of!(CONTEXTS);
-        for (i, slot) in (*contexts).iter().enumerate() {
-            if slot.is_none() {
-                found = Some(i);
-                break;
-            }
-        }
-        match found {
-            Some(i) => i,
-            None => {
-                log::error!("ScsiPassThru: no free context slots");
-                return core::ptr::null_mut();
-            }
-        }
-    };
+    // Make sure there is a free context slot
+    if CONTEXTS.is_full() {
+        log::error!("ScsiPassThru: no free context slots");
+        return core::ptr::null_mut();
+    }
 
     // Allocate mode structure
     let mode_ptr = allocate_protocol_with_log::<ExtScsiPassThruMode>(
@@ -725,19 +693,17 @@ pub fn create_scsi_pass_thru_protocol(
         return core::ptr::null_mut();
     }
 
-    // Store context
-    unsafe {
-        let contexts = core::ptr::addr_of_mut!(CONTEXTS);
-        (*contexts)[ctx_idx] = Some(ScsiPassThruContext {
+    // Store context (checked for a free slot above)
+    CONTEXTS.insert(
+        protocol_ptr,
+        ScsiPassThruContext {
             controller_index,
             device_addr,
             pci_device,
             pci_function,
             usb_port,
-        });
-        let proto_map = core::ptr::addr_of_mut!(PROTOCOL_TO_CONTEXT);
-        (*proto_map)[ctx_idx] = Some(protocol_ptr);
-    }
+        },
+    );
 
     log::info!(
         "ScsiPassThru: created protocol for USB device {} on controller {} (PCI {:02x}:{:x}, port {})",
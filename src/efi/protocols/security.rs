@@ -0,0 +1,190 @@
+//! Security and Security2 Architectural Protocols
+//!
+//! In PI firmware, LoadImage asks these protocols whether an image may be
+//! loaded. CrabEFI installs them with the image execution policy as the
+//! default: Security2 applies the allow list for removable media, Security
+//! accepts every image, as there are no firmware volumes to authenticate.
+//!
+//! Boot loaders such as shim and systemd-boot replace the functions in the
+//! installed protocols to verify the images they start themselves, and pass
+//! on the others. LoadImage calls through the installed protocols, so those
+//! overrides take effect. The deny list is checked before and can't be
+//! overridden.
+//!
+//! Reference: PI Specification 1.7, Volume 2, Sections 12.9 and 12.10
+
+use core::ffi::c_void;
+use r_efi::efi::{Boolean, Guid, Handle, Status};
+use r_efi::protocols::device_path::Protocol as DevicePathProtocol;
+
+use super::block_io::{BLOCK_IO_PROTOCOL_GUID, BlockIoProtocol};
+use super::device_path;
+use crate::efi::cell::EfiCell;
+use crate::efi::handles;
+use crate::image_policy;
+
+/// Security Architectural Protocol GUID
+/// {A46423E3-4617-49F1-B9FF-D1BFA9115839}
+pub const SECURITY_ARCH_PROTOCOL_GUID: Guid = Guid::from_fields(
+    0xa46423e3,
+    0x4617,
+    0x49f1,
+    0xb9,
+    0xff,
+    &[0xd1, 0xbf, 0xa9, 0x11, 0x58, 0x39],
+);
+
+/// Security2 Architectural Protocol GUID
+/// {94AB2F58-1438-4EF1-9152-18941A3A0E68}
+pub const SECURITY2_ARCH_PROTOCOL_GUID: Guid = Guid::from_fields(
+    0x94ab2f58,
+    0x1438,
+    0x4ef1,
+    0x91,
+    0x52,
+    &[0x18, 0x94, 0x1a, 0x3a, 0x0e, 0x68],
+);
+
+/// Security Architectural Protocol
+#[repr(C)]
+pub struct SecurityProtocol {
+    pub file_authentication_state: extern "efiapi" fn(
+        this: *const SecurityProtocol,
+        authentication_status: u32,
+        file: *const DevicePathProtocol,
+    ) -> Status,
+}
+
+/// Security2 Architectural Protocol
+#[repr(C)]
+pub struct Security2Protocol {
+    pub file_authentication: extern "efiapi" fn(
+        this: *const Security2Protocol,
+        device_path: *const DevicePathProtocol,
+        file_buffer: *mut c_void,
+        file_size: usize,
+        boot_policy: Boolean,
+    ) -> Status,
+}
+
+static SECURITY: EfiCell<SecurityProtocol> = EfiCell::new(SecurityProtocol {
+    file_authentication_state,
+});
+
+static SECURITY2: EfiCell<Security2Protocol> = EfiCell::new(Security2Protocol {
+    file_authentication,
+});
+
+/// Get the Security protocol
+pub fn get_security_protocol() -> *mut c_void {
+    SECURITY.as_ptr() as *mut c_void
+}
+
+/// Get the Security2 protocol
+pub fn get_security2_protocol() -> *mut c_void {
+    SECURITY2.as_ptr() as *mut c_void
+}
+
+extern "efiapi" fn file_authentication_state(
+    _this: *const SecurityProtocol,
+    authentication_status: u32,
+    _file: *const DevicePathProtocol,
+) -> Status {
+    log::trace!(
+        "Security.FileAuthenticationState(status={:#x})",
+        authentication_status
+    );
+    Status::SUCCESS
+}
+
+extern "efiapi" fn file_authentication(
+    _this: *const Security2Protocol,
+    device_path: *const DevicePathProtocol,
+    file_buffer: *mut c_void,
+    file_size: usize,
+    boot_policy: Boolean,
+) -> Status {
+    log::trace!(
+        "Security2.FileAuthentication(size={}, boot_policy={:?})",
+        file_size,
+        boot_policy
+    );
+    if file_buffer.is_null() {
+        return Status::SUCCESS;
+    }
+    let data = unsafe { core::slice::from_raw_parts(file_buffer as *const u8, file_size) };
+    match image_policy::check_allowed(data, is_removable_path(device_path)) {
+        Ok(()) => Status::SUCCESS,
+        Err(_) => Status::ACCESS_DENIED,
+    }
+}
+
+/// Removable media flag of the Block I/O protocol on `handle`, if any
+fn removable_media(db: &handles::HandleDatabase, handle: Handle) -> Option<bool> {
+    let block_io = db.find(handle, &BLOCK_IO_PROTOCOL_GUID)? as *const BlockIoProtocol;
+    let media = unsafe { block_io.as_ref()?.media };
+    Some(unsafe { media.as_ref()?.removable_media })
+}
+
+/// Whether `handle` is a block device with removable media
+pub fn is_removable(handle: Handle) -> bool {
+    !handle.is_null() && handles::with(|db| removable_media(db, handle)).unwrap_or(false)
+}
+
+/// Whether `path` is on a block device with removable media
+fn is_removable_path(path: *const DevicePathProtocol) -> bool {
+    if path.is_null() {
+        return false;
+    }
+    handles::with(|db| {
+        db.handles_with(&BLOCK_IO_PROTOCOL_GUID).any(|handle| {
+            let on_device = db
+                .find(handle, &device_path::DEVICE_PATH_PROTOCOL_GUID)
+                .filter(|dp| !dp.is_null())
+                .is_some_and(|dp| unsafe {
+                    device_path::strip_prefix(dp as *const _, path).is_some()
+                });
+            on_device && removable_media(db, handle) == Some(true)
+        })
+    })
+}
+
+/// The interface of the first installed instance of a protocol
+fn installed(guid: &Guid) -> Option<*mut c_void> {
+    handles::with(|db| {
+        let handle = db.handles_with(guid).next()?;
+        db.find(handle, guid)
+    })
+    .filter(|iface| !iface.is_null())
+}
+
+/// Ask the installed Security protocols whether an image may be loaded
+///
+/// `device_path` is where the image came from and may be null for images
+/// loaded from a buffer.
+pub fn authenticate(
+    device_path: *const DevicePathProtocol,
+    data: &[u8],
+    boot_policy: Boolean,
+) -> Status {
+    if let Some(security) = installed(&SECURITY_ARCH_PROTOCOL_GUID) {
+        let security = security as *const SecurityProtocol;
+        let status = unsafe { ((*security).file_authentication_state)(security, 0, device_path) };
+        if status != Status::SUCCESS {
+            return status;
+        }
+    }
+    if let Some(security2) = installed(&SECURITY2_ARCH_PROTOCOL_GUID) {
+        let security2 = security2 as *const Security2Protocol;
+        return unsafe {
+            ((*security2).file_authentication)(
+                security2,
+                device_path,
+                data.as_ptr() as *mut c_void,
+                data.len(),
+                boot_policy,
+            )
+        };
+    }
+    Status::SUCCESS
+}
@@ -10,6 +10,7 @@ use core::ffi::c_void;
 use r_efi::efi::{Guid, Status};
 
 use crate::drivers::serial::{self, COM1};
+use crate::efi::cell::EfiCell;
 use crate::efi::utils::allocate_protocol_with_log;
 
 /// Serial IO Protocol GUID
@@ -272,7 +273,7 @@ extern "efiapi" fn serial_read(
 }
 
 /// Static mode structure for the serial port
-static mut SERIAL_MODE: SerialIoMode = SerialIoMode {
+static SERIAL_MODE: EfiCell<SerialIoMode> = EfiCell::new(SerialIoMode {
     control_mask: EFI_SERIAL_CLEAR_TO_SEND
         | EFI_SERIAL_DATA_SET_READY
         | EFI_SERIAL_RING_INDICATE
@@ -287,7 +288,7 @@ static mut SERIAL_MODE: SerialIoMode = SerialIoMode {
     data_bits: 8,
     parity: 1,    // NoParity
     stop_bits: 1, // OneStopBit
-};
+});
 
 /// Create and initialize the Serial IO Protocol
 ///
@@ -303,7 +304,7 @@ pub fn create_protocol() -> *mut Protocol {
         p.get_control = serial_get_control;
         p.write = serial_write;
         p.read = serial_read;
-        p.mode = &raw mut SERIAL_MODE;
+        p.mode = SERIAL_MODE.as_ptr();
         p.device_type_guid = core::ptr::null();
     });
     if ptr.is_null() {
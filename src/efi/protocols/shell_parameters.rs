@@ -0,0 +1,226 @@
+//! EFI Shell Parameters Protocol
+//!
+//! Command-line utilities built with the UEFI Shell libraries take their
+//! arguments from EFI_SHELL_PARAMETERS_PROTOCOL on their image handle
+//! instead of parsing LoadedImage.LoadOptions themselves. Before an image
+//! starts, its load options are split into argv the way the shell does and
+//! the protocol is installed, so such utilities run without a shell.
+//!
+//! Like the shell, the load options are taken as the whole command line,
+//! program name included. Without load options, argv holds just the image
+//! file name. There is no shell to provide file handles, so StdIn, StdOut
+//! and StdErr are null.
+//!
+//! Reference: UEFI Shell Specification 2.2, Section 2.3
+
+use core::ffi::c_void;
+use r_efi::efi::{Guid, Handle, Status};
+use r_efi::protocols::loaded_image;
+use r_efi::protocols::shell_parameters::{self, Protocol};
+
+use crate::efi::allocator::{self, MemoryType};
+use crate::efi::handles;
+use crate::efi::protocols::device_path;
+use crate::efi::utils::allocate_protocol_with_log;
+
+/// Re-export the GUID for external use
+pub const SHELL_PARAMETERS_PROTOCOL_GUID: Guid = shell_parameters::PROTOCOL_GUID;
+
+const SPACE: u16 = b' ' as u16;
+const TAB: u16 = b'\t' as u16;
+const QUOTE: u16 = b'"' as u16;
+const CARET: u16 = b'^' as u16;
+
+/// Longest image file name used as argv[0]
+const MAX_NAME_LEN: usize = 256;
+
+/// Split a command line into arguments like the UEFI Shell
+///
+/// Spaces and tabs separate arguments, double quotes group and are removed,
+/// and `^` takes the next character literally. The command line ends at the
+/// first null. Writes the null-terminated arguments one after the other to
+/// `out`, which needs room for one unit more than the command line, and
+/// returns their number.
+fn split_arguments(command_line: impl IntoIterator<Item = u16>, out: &mut [u16]) -> usize {
+    let mut units = command_line.into_iter().take_while(|&unit| unit != 0);
+    let (mut argc, mut len) = (0, 0);
+    let (mut in_argument, mut quoted) = (false, false);
+
+    while let Some(unit) = units.next() {
+        let unit = match unit {
+            SPACE | TAB if !quoted => {
+                if in_argument {
+                    out[len] = 0;
+                    len += 1;
+                    argc += 1;
+                    in_argument = false;
+                }
+                continue;
+            }
+            QUOTE => {
+                quoted = !quoted;
+                in_argument = true;
+                continue;
+            }
+            CARET => match units.next() {
+                Some(next) => next,
+                None => break,
+            },
+            unit => unit,
+        };
+        out[len] = unit;
+        len += 1;
+        in_argument = true;
+    }
+
+    if in_argument {
+        out[len] = 0;
+        argc += 1;
+    }
+    argc
+}
+
+/// Install the protocol on an image handle before the image starts
+///
+/// Does nothing if the image has no LoadedImage protocol or a shell already
+/// installed the protocol. Returns whether it was installed, in which case
+/// [`uninstall`] removes it once the image returns.
+pub fn install(image_handle: Handle) -> bool {
+    let (loaded_image, installed) = handles::with(|db| {
+        (
+            db.find(image_handle, &loaded_image::PROTOCOL_GUID),
+            db.find(image_handle, &SHELL_PARAMETERS_PROTOCOL_GUID)
+                .is_some(),
+        )
+    });
+    let Some(loaded_image) = loaded_image.filter(|_| !installed) else {
+        return false;
+    };
+    let loaded_image = unsafe { &*(loaded_image as *const loaded_image::Protocol) };
+
+    // The load options, which may be unaligned, or the quoted file name
+    let options: &[u8] = if loaded_image.load_options.is_null() {
+        &[]
+    } else {
+        unsafe {
+            core::slice::from_raw_parts(
+                loaded_image.load_options as *const u8,
+                loaded_image.load_options_size as usize,
+            )
+        }
+    };
+    let file_name = if options.len() >= 2 || loaded_image.file_path.is_null() {
+        None
+    } else {
+        unsafe { device_path::file_path_to_str::<MAX_NAME_LEN>(loaded_image.file_path) }
+    };
+    let options = options
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+    let length = match &file_name {
+        Some(name) => name.encode_utf16().count() + 2,
+        None => options.len(),
+    };
+
+    // The argv array and the arguments it points to, in one pool buffer
+    let units = length + 1;
+    let max_arguments = units / 2 + 1;
+    let size = (max_arguments + 1) * size_of::<*mut u16>() + units * size_of::<u16>();
+    let Ok(buffer) = allocator::allocate_pool(MemoryType::BootServicesData, size) else {
+        return false;
+    };
+    let argv = buffer as *mut *mut u16;
+    let strings = unsafe { argv.add(max_arguments + 1) as *mut u16 };
+    let out = unsafe { core::slice::from_raw_parts_mut(strings, units) };
+    let argc = match &file_name {
+        // Quoted, since the file name may contain spaces
+        Some(name) => {
+            let quoted = [QUOTE]
+                .into_iter()
+                .chain(name.encode_utf16())
+                .chain([QUOTE]);
+            split_arguments(quoted, out)
+        }
+        None => split_arguments(options, out),
+    };
+
+    let mut offset = 0;
+    for index in 0..argc {
+        unsafe { *argv.add(index) = strings.add(offset) };
+        offset += out[offset..]
+            .iter()
+            .position(|&unit| unit == 0)
+            .unwrap_or(0)
+            + 1;
+    }
+    unsafe { *argv.add(argc) = core::ptr::null_mut() };
+
+    let protocol = allocate_protocol_with_log::<Protocol>("ShellParametersProtocol", |p| {
+        p.argv = argv;
+        p.argc = argc;
+        p.std_in = core::ptr::null_mut();
+        p.std_out = core::ptr::null_mut();
+        p.std_err = core::ptr::null_mut();
+    });
+    if protocol.is_null() {
+        let _ = allocator::free_pool(buffer);
+        return false;
+    }
+
+    let status = handles::with(|db| {
+        db.install(
+            image_handle,
+            &SHELL_PARAMETERS_PROTOCOL_GUID,
+            protocol as *mut c_void,
+        )
+    });
+    if status != Status::SUCCESS {
+        free(protocol);
+        return false;
+    }
+    log::debug!("ShellParameters installed with {} arguments", argc);
+    true
+}
+
+/// Free a protocol built by [`install`] and its arguments
+fn free(protocol: *mut Protocol) {
+    let _ = allocator::free_pool(unsafe { (*protocol).argv } as *mut u8);
+    let _ = allocator::free_pool(protocol as *mut u8);
+}
+
+/// Remove the protocol [`install`] put on an image handle and free it
+pub fn uninstall(image_handle: Handle) {
+    let protocol = handles::with(|db| {
+        let protocol = db.find(image_handle, &SHELL_PARAMETERS_PROTOCOL_GUID)?;
+        let _ = db.uninstall(image_handle, &SHELL_PARAMETERS_PROTOCOL_GUID);
+        Some(protocol)
+    });
+    if let Some(protocol) = protocol {
+        free(protocol as *mut Protocol);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(command_line: &str) -> std::vec::Vec<std::string::String> {
+        let mut out = std::vec![0xFFFF; command_line.len() + 1];
+        let argc = split_arguments(command_line.encode_utf16(), &mut out);
+        let arguments: std::vec::Vec<_> = out
+            .split(|&unit| unit == 0)
+            .take(argc)
+            .map(std::string::String::from_utf16_lossy)
+            .collect();
+        assert_eq!(arguments.len(), argc);
+        arguments
+    }
+
+    #[test]
+    fn splits_like_the_shell() {
+        assert_eq!(split("memtest.efi -v  1"), ["memtest.efi", "-v", "1"]);
+        assert_eq!(split(" \"a b\"\tc\"d\" "), ["a b", "cd"]);
+        assert_eq!(split("x \"\" ^\"y^^"), ["x", "", "\"y^"]);
+        assert!(split("   ").is_empty());
+    }
+}
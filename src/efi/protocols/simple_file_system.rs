@@ -14,8 +14,10 @@ use spin::Mutex;
 use zerocopy::FromBytes;
 
 use crate::drivers::block::{AnyBlockDevice, BlockDevice};
-use crate::fs::fat::{DirectoryEntry, FatFilesystem, FatType};
-use crate::state;
+use crate::efi::allocator::PAGE_SIZE_USIZE;
+use crate::efi::cell::EfiCell;
+use crate::fs::fat::{DirectoryEntry, Extent, FatFilesystem, FatType};
+use crate::{efi, state, verity};
 
 // Re-export FilesystemState for backward compatibility with lib.rs
 pub use crate::state::FilesystemState;
@@ -31,6 +33,9 @@ const MAX_PATH_LEN: usize = 256;
 /// Maximum number of open file handles
 const MAX_FILE_HANDLES: usize = 32;
 
+/// Files at least this large get progress messages while being read (8MB)
+const PROGRESS_MIN_FILE_SIZE: u64 = 8 * 1024 * 1024;
+
 /// File open modes
 pub const FILE_MODE_READ: u64 = efi_file::MODE_READ;
 pub const FILE_MODE_WRITE: u64 = efi_file::MODE_WRITE;
@@ -55,6 +60,11 @@ struct FileHandle {
     first_cluster: u32,
     /// Is this a directory?
     is_directory: bool,
+    /// Location of the file if it is stored in consecutive clusters
+    extent: Option<Extent>,
+    /// Contents checked against the verity hash list, served instead of
+    /// reading the disk again
+    verified: Option<&'static mut [u8]>,
     /// The File Protocol struct for this handle
     protocol: efi_file::Protocol,
 }
@@ -69,6 +79,8 @@ impl FileHandle {
             file_size: 0,
             first_cluster: 0,
             is_directory: false,
+            extent: None,
+            verified: None,
             protocol: efi_file::Protocol {
                 revision: efi_file::REVISION,
                 open: file_open,
@@ -97,10 +109,10 @@ static FILE_HANDLES: Mutex<[FileHandle; MAX_FILE_HANDLES]> =
     Mutex::new([const { FileHandle::empty() }; MAX_FILE_HANDLES]);
 
 /// Simple File System Protocol instance
-static mut SFS_PROTOCOL: efi_sfs::Protocol = efi_sfs::Protocol {
+static SFS_PROTOCOL: EfiCell<efi_sfs::Protocol> = EfiCell::new(efi_sfs::Protocol {
     revision: efi_sfs::REVISION,
     open_volume: sfs_open_volume,
-};
+});
 
 /// Initialize the simple file system protocol with a block device
 ///
@@ -154,7 +166,15 @@ pub fn init(block_device: AnyBlockDevice, partition_start: u64) -> *mut efi_sfs:
         partition_start
     );
 
-    &raw mut SFS_PROTOCOL
+    #[cfg(feature = "protocol-trace")]
+    {
+        SFS_PROTOCOL.with(super::trace::simple_file_system);
+        for handle in FILE_HANDLES.lock().iter_mut() {
+            super::trace::file(&mut handle.protocol);
+        }
+    }
+
+    SFS_PROTOCOL.as_ptr()
 }
 
 /// Get the Simple File System Protocol GUID
@@ -204,6 +224,7 @@ extern "efiapi" fn sfs_open_volume(
     handles[handle_idx].file_size = 0;
     handles[handle_idx].first_cluster = fs_state.root_cluster;
     handles[handle_idx].is_directory = true;
+    handles[handle_idx].extent = None;
 
     // Return pointer to the protocol in this handle
     unsafe {
@@ -266,9 +287,9 @@ extern "efiapi" fn file_open(
     log::info!("File.Open: full path = {:?}", full_path_str);
 
     // Get partition start
-    let partition_start = match state::efi().filesystem {
-        Some(s) => s.partition_start,
-        None => return Status::NOT_READY,
+    let partition_start = match mounted_partition_start() {
+        Ok(start) => start,
+        Err(status) => return status,
     };
 
     // Find the file using FatFilesystem
@@ -279,22 +300,45 @@ extern "efiapi" fn file_open(
         };
 
         match fat.find_file(full_path_str) {
-            Ok(entry) => Ok((
-                entry.first_cluster(),
-                entry.file_size(),
-                entry.is_directory(),
-            )),
+            Ok(entry) => {
+                // Kernels and initrds are usually contiguous: look the extent
+                // up once so File.Read can read them without walking the FAT
+                let extent = if entry.is_directory() {
+                    None
+                } else {
+                    fat.file_extent(&entry).ok().flatten()
+                };
+                Ok((
+                    entry.first_cluster(),
+                    entry.file_size(),
+                    entry.is_directory(),
+                    extent,
+                ))
+            }
             Err(_) => Err(()),
         }
     });
 
     match result {
-        Some(Ok((cluster, size, is_dir))) => {
+        Some(Ok((cluster, size, is_dir, extent))) => {
+            // With a verity hash list, files are checked in full up front
+            let verified = if verity::is_enforcing() && !is_dir {
+                match read_verified(full_path_str, partition_start, cluster, size, extent) {
+                    Ok(data) => Some(data),
+                    Err(status) => return status,
+                }
+            } else {
+                None
+            };
+
             // Allocate a new file handle
             let mut handles = FILE_HANDLES.lock();
             let handle_idx = match handles.iter().position(|h| !h.in_use) {
                 Some(idx) => idx,
-                None => return Status::OUT_OF_RESOURCES,
+                None => {
+                    free_verified(verified);
+                    return Status::OUT_OF_RESOURCES;
+                }
             };
 
             handles[handle_idx].in_use = true;
@@ -304,6 +348,8 @@ extern "efiapi" fn file_open(
             handles[handle_idx].file_size = size as u64;
             handles[handle_idx].first_cluster = cluster;
             handles[handle_idx].is_directory = is_dir;
+            handles[handle_idx].extent = extent;
+            handles[handle_idx].verified = verified;
 
             unsafe {
                 *new_handle = &raw mut handles[handle_idx].protocol;
@@ -336,6 +382,8 @@ extern "efiapi" fn file_close(this: *mut efi_file::Protocol) -> Status {
         handles[idx].in_use = false;
         handles[idx].path_len = 0;
         handles[idx].position = 0;
+        handles[idx].extent = None;
+        free_verified(handles[idx].verified.take());
         Status::SUCCESS
     } else {
         Status::INVALID_PARAMETER
@@ -359,7 +407,7 @@ extern "efiapi" fn file_read(
     let requested_size = unsafe { *buffer_size };
 
     // Get handle info
-    let (is_dir, file_size, position, first_cluster, handle_idx) = {
+    let (is_dir, file_size, position, first_cluster, extent, handle_idx) = {
         let handles = FILE_HANDLES.lock();
         let idx = match find_handle_index_unlocked(&handles, this) {
             Some(i) => i,
@@ -370,6 +418,7 @@ extern "efiapi" fn file_read(
             handles[idx].file_size,
             handles[idx].position,
             handles[idx].first_cluster,
+            handles[idx].extent,
             idx,
         )
     };
@@ -396,13 +445,25 @@ extern "efiapi" fn file_read(
         return Status::SUCCESS;
     }
 
-    let partition_start = match state::efi().filesystem {
-        Some(s) => s.partition_start,
-        None => return Status::NOT_READY,
-    };
-
     let buf_slice = unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, bytes_to_read) };
 
+    // Verified files are served from the copy that was checked
+    {
+        let mut handles = FILE_HANDLES.lock();
+        if let Some(data) = handles[handle_idx].verified.as_deref() {
+            let start = position as usize;
+            buf_slice.copy_from_slice(&data[start..start + bytes_to_read]);
+            handles[handle_idx].position += bytes_to_read as u64;
+            unsafe { *buffer_size = bytes_to_read };
+            return Status::SUCCESS;
+        }
+    }
+
+    let partition_start = match mounted_partition_start() {
+        Ok(start) => start,
+        Err(status) => return status,
+    };
+
     // Create a fake DirectoryEntry for read_file
     // We need to read using the stored cluster and position
     let result = state::with_block_device_mut(|device| {
@@ -411,6 +472,12 @@ extern "efiapi" fn file_read(
             Err(_) => return Err(()),
         };
 
+        if let Some(extent) = extent {
+            return fat
+                .read_extent(&extent, position as u32, buf_slice)
+                .map_err(|_| ());
+        }
+
         // Create a minimal entry for reading
         let entry = create_file_entry(first_cluster, file_size as u32);
         fat.read_file(&entry, position as u32, buf_slice)
@@ -427,6 +494,19 @@ extern "efiapi" fn file_read(
 
             unsafe { *buffer_size = bytes_read };
             log::trace!("File.Read: read {} bytes", bytes_read);
+
+            // Loaders read kernels and initrds in chunks; report every 10%
+            if file_size >= PROGRESS_MIN_FILE_SIZE {
+                let before = position * 10 / file_size;
+                let after = (position + bytes_read as u64) * 10 / file_size;
+                if after > before {
+                    log::info!(
+                        "File.Read: {}% of {} KiB loaded",
+                        after * 10,
+                        file_size / 1024
+                    );
+                }
+            }
             Status::SUCCESS
         }
         Some(Err(_)) => {
@@ -664,6 +744,90 @@ extern "efiapi" fn file_flush_ex(
 // Helper Functions
 // ============================================================================
 
+/// Partition start of the mounted filesystem, after checking its media
+///
+/// If the SD card it was mounted from was removed or swapped, the
+/// filesystem is unmounted: open handles would otherwise go on reading
+/// cached clusters from whatever card is in the slot now.
+fn mounted_partition_start() -> Result<u64, Status> {
+    let partition_start = state::efi()
+        .filesystem
+        .ok_or(Status::NOT_READY)?
+        .partition_start;
+    let media =
+        state::with_block_device_mut(|device| (device.current_media(), device.info().media_id));
+
+    match media {
+        Some((Some(current), mounted)) if current == mounted => Ok(partition_start),
+        Some((current, _)) => {
+            log::warn!(
+                "SimpleFileSystem: media {}, unmounting",
+                if current.is_some() {
+                    "changed"
+                } else {
+                    "removed"
+                }
+            );
+            state::with_efi_mut(|efi| {
+                efi.filesystem = None;
+                efi.block_device = None;
+            });
+            Err(if current.is_some() {
+                Status::MEDIA_CHANGED
+            } else {
+                Status::NO_MEDIA
+            })
+        }
+        None => Err(Status::NOT_READY),
+    }
+}
+
+/// Read a whole file and check it against the verity hash list
+///
+/// Returns the checked contents, in pages to free with [`free_verified`].
+fn read_verified(
+    path: &str,
+    partition_start: u64,
+    first_cluster: u32,
+    file_size: u32,
+    extent: Option<Extent>,
+) -> Result<&'static mut [u8], Status> {
+    let size = file_size as usize;
+    let data = if size == 0 {
+        Default::default()
+    } else {
+        let pages = size.div_ceil(PAGE_SIZE_USIZE) as u64;
+        &mut efi::allocate_pages(pages).ok_or(Status::OUT_OF_RESOURCES)?[..size]
+    };
+
+    let result = state::with_block_device_mut(|device| {
+        let mut fat = FatFilesystem::new(device, partition_start).map_err(|_| ())?;
+        match extent {
+            Some(extent) => fat.read_extent(&extent, 0, data),
+            None => fat.read_file(&create_file_entry(first_cluster, file_size), 0, data),
+        }
+        .map_err(|_| ())
+    });
+    let status = match result {
+        Some(Ok(len)) if len == size => match verity::check(path, data) {
+            Ok(()) => return Ok(data),
+            Err(_) => Status::SECURITY_VIOLATION,
+        },
+        Some(_) => Status::DEVICE_ERROR,
+        None => Status::NOT_READY,
+    };
+    free_verified(Some(data));
+    Err(status)
+}
+
+/// Free the contents read by [`read_verified`]
+fn free_verified(data: Option<&'static mut [u8]>) {
+    if let Some(data) = data.filter(|data| !data.is_empty()) {
+        let pages = data.len().div_ceil(PAGE_SIZE_USIZE) as u64;
+        efi::free_pages(data, pages);
+    }
+}
+
 /// Find handle index without holding the lock (for use when we already have it)
 fn find_handle_index_unlocked(
     handles: &[FileHandle; MAX_FILE_HANDLES],
@@ -809,9 +973,9 @@ fn create_file_entry(first_cluster: u32, file_size: u32) -> DirectoryEntry {
 
 /// Read directory entries
 fn read_directory(buffer_size: *mut usize, buffer: *mut c_void, handle_idx: usize) -> Status {
-    let partition_start = match state::efi().filesystem {
-        Some(s) => s.partition_start,
-        None => return Status::NOT_READY,
+    let partition_start = match mounted_partition_start() {
+        Ok(start) => start,
+        Err(status) => return status,
     };
 
     let (cluster, position) = {
@@ -14,6 +14,7 @@ use core::ffi::c_void;
 use r_efi::efi::{Guid, Status};
 
 use crate::drivers::{ahci, nvme, usb};
+use crate::efi::cell::ContextTable;
 use crate::efi::utils::allocate_protocol_with_log;
 
 /// Storage Security Command Protocol GUID
@@ -77,6 +78,7 @@ pub struct StorageSecurityCommandProtocol {
 }
 
 /// Internal context for Storage Security protocol instance
+#[derive(Clone, Copy)]
 struct StorageSecurityContext {
     /// Media ID (for validation)
     media_id: u32,
@@ -87,36 +89,12 @@ struct StorageSecurityContext {
 /// Maximum number of Storage Security protocol instances
 const MAX_INSTANCES: usize = 16;
 
-/// Global storage for contexts
-static mut CONTEXTS: [Option<StorageSecurityContext>; MAX_INSTANCES] = [const { None }; MAX_INSTANCES];
-
-/// Protocol instance to context mapping
-static mut PROTOCOL_TO_CONTEXT: [Option<*mut StorageSecurityCommandProtocol>; MAX_INSTANCES] =
-    [const { None }; MAX_INSTANCES];
-
-/// Find context index for a protocol instance
-fn find_context_index(protocol: *mut StorageSecurityCommandProtocol) -> Option<usize> {
-    unsafe {
-        let proto_map = core::ptr::addr_of!(PROTOCOL_TO_CONTEXT);
-        for (i, p) in (*proto_map).iter().enumerate() {
-            if let Some(ptr) = p
-                && *ptr == protocol
-            {
-                return Some(i);
-            }
-        }
-    }
-    None
-}
-
-/// Get context for a protocol instance
-fn get_context(protocol: *mut StorageSecurityCommandProtocol) -> Option<&'static StorageSecurityContext> {
-    let idx = find_context_index(protocol)?;
-    unsafe {
-        let contexts = core::ptr::addr_of!(CONTEXTS);
-        (*contexts)[idx].as_ref()
-    }
-}
+/// Contexts of the protocol instances
+static CONTEXTS: ContextTable<
+    StorageSecurityCommandProtocol,
+    StorageSecurityContext,
+    MAX_INSTANCES,
+> = ContextTable::new();
 
 /// Receive data from security subsystem
 ///
@@ -150,7 +128,7 @@ extern "efiapi" fn storage_security_receive_data(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx = match get_context(this) {
+    let ctx = match CONTEXTS.get(this) {
         Some(c) => c,
         None => {
             log::error!("StorageSecurity.ReceiveData: unknown protocol instance");
@@ -229,7 +207,7 @@ extern "efiapi" fn storage_security_send_data(
         return Status::INVALID_PARAMETER;
     }
 
-    let ctx = match get_context(this) {
+    let ctx = match CONTEXTS.get(this) {
         Some(c) => c,
         None => {
             log::error!("StorageSecurity.SendData: unknown protocol instance");
@@ -417,24 +395,11 @@ pub fn create_storage_security_protocol(
     media_id: u32,
     storage_type: StorageType,
 ) -> *mut StorageSecurityCommandProtocol {
-    // Find a free context slot
-    let ctx_idx = unsafe {
-        let mut found = None;
-        let contexts = core::ptr::addr_of!(CONTEXTS);
-        for (i, slot) in (*contexts).iter().enumerate() {
-            if slot.is_none() {
-                found = Some(i);
-                break;
-            }
-        }
-        match found {
-            Some(i) => i,
-            None => {
-                log::error!("StorageSecurity: no free context slots");
-                return core::ptr::null_mut();
-            }
-        }
-    };
+    // Make sure there is a free context slot
+    if CONTEXTS.is_full() {
+        log::error!("StorageSecurity: no free context slots");
+        return core::ptr::null_mut();
+    }
 
     // Allocate protocol structure
     let protocol_ptr = allocate_protocol_with_log::<StorageSecurityCommandProtocol>(
@@ -449,16 +414,14 @@ pub fn create_storage_security_protocol(
         return core::ptr::null_mut();
     }
 
-    // Store context
-    unsafe {
-        let contexts = core::ptr::addr_of_mut!(CONTEXTS);
-        (*contexts)[ctx_idx] = Some(StorageSecurityContext {
+    // Store context (checked for a free slot above)
+    CONTEXTS.insert(
+        protocol_ptr,
+        StorageSecurityContext {
             media_id,
             storage_type,
-        });
-        let proto_map = core::ptr::addr_of_mut!(PROTOCOL_TO_CONTEXT);
-        (*proto_map)[ctx_idx] = Some(protocol_ptr);
-    }
+        },
+    );
 
     log::info!(
         "StorageSecurity: created protocol (media={}, type={:?})",
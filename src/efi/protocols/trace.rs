@@ -0,0 +1,151 @@
+//! Protocol call tracing (`protocol-trace` feature)
+//!
+//! Wraps the members of the Block I/O, Simple File System, File and
+//! Graphics Output interfaces CrabEFI installs, so each call a boot loader
+//! makes is logged at trace level with its arguments, and again with the
+//! status it returns. A loader that hangs or misreads a disk on real
+//! hardware can then be followed through the serial log without a
+//! debugger.
+//!
+//! Every instance of a protocol shares the same member functions, so the
+//! wrappers keep one original per member. Interfaces installed by images
+//! are left alone.
+
+use core::fmt::{Debug, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use heapless::String;
+use r_efi::efi::Status;
+use r_efi::protocols::file as efi_file;
+use r_efi::protocols::simple_file_system as efi_sfs;
+
+use super::block_io::BlockIoProtocol;
+use super::graphics_output::GraphicsOutputProtocol;
+
+/// The wrapped members, with the name they are logged under
+const MEMBERS: &[&str] = &[
+    "BlockIo.Reset",
+    "BlockIo.ReadBlocks",
+    "BlockIo.WriteBlocks",
+    "BlockIo.FlushBlocks",
+    "SimpleFileSystem.OpenVolume",
+    "File.Open",
+    "File.Close",
+    "File.Delete",
+    "File.Read",
+    "File.Write",
+    "File.GetPosition",
+    "File.SetPosition",
+    "File.GetInfo",
+    "File.SetInfo",
+    "File.Flush",
+    "File.OpenEx",
+    "File.ReadEx",
+    "File.WriteEx",
+    "File.FlushEx",
+    "Gop.QueryMode",
+    "Gop.SetMode",
+    "Gop.Blt",
+];
+
+/// Index of the member called `name`, at compile time
+const fn member_index(name: &str) -> usize {
+    let name = name.as_bytes();
+    let mut i = 0;
+    while i < MEMBERS.len() {
+        let candidate = MEMBERS[i].as_bytes();
+        if candidate.len() == name.len() {
+            let mut j = 0;
+            while j < name.len() && candidate[j] == name[j] {
+                j += 1;
+            }
+            if j == name.len() {
+                return i;
+            }
+        }
+        i += 1;
+    }
+    panic!("unknown member");
+}
+
+/// The original function of each member
+static ORIGINALS: [AtomicUsize; MEMBERS.len()] = [const { AtomicUsize::new(0) }; MEMBERS.len()];
+
+/// Define a wrapper logging the call of member `I` around the original
+macro_rules! wrapper {
+    ($name:ident, $($arg:ident: $ty:ident),*) => {
+        extern "efiapi" fn $name<const I: usize, $($ty: Debug + Copy),*>($($arg: $ty),*) -> Status {
+            if log::log_enabled!(log::Level::Trace) {
+                let mut args: String<256> = String::new();
+                $(let _ = write!(args, "{:?}, ", $arg);)*
+                log::trace!("{}({})", MEMBERS[I], args.trim_end_matches(", "));
+            }
+            let original = ORIGINALS[I].load(Ordering::Relaxed);
+            let original: extern "efiapi" fn($($ty),*) -> Status =
+                unsafe { core::mem::transmute_copy(&original) };
+            let status = original($($arg),*);
+            log::trace!("{} -> {:?}", MEMBERS[I], status);
+            status
+        }
+    };
+}
+
+wrapper!(call1, a: A);
+wrapper!(call2, a: A, b: B);
+wrapper!(call3, a: A, b: B, c: C);
+wrapper!(call4, a: A, b: B, c: C, d: D);
+wrapper!(call5, a: A, b: B, c: C, d: D, e: E);
+wrapper!(call6, a: A, b: B, c: C, d: D, e: E, f: F);
+wrapper!(call10, a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H, i: I2, j: J);
+
+/// Replace `protocol.field` with the wrapper of the member called `name`
+///
+/// Interfaces already wrapped are left as they are.
+macro_rules! interpose {
+    ($protocol:ident.$field:ident, $name:literal, $call:ident, $($ty:tt),*) => {{
+        const INDEX: usize = member_index($name);
+        let original = $protocol.$field as usize;
+        $protocol.$field = $call::<INDEX, $($ty),*>;
+        if $protocol.$field as usize != original {
+            ORIGINALS[INDEX].store(original, Ordering::Relaxed);
+        }
+    }};
+}
+
+/// Trace the calls to a Block I/O interface
+pub fn block_io(p: &mut BlockIoProtocol) {
+    interpose!(p.reset, "BlockIo.Reset", call2, _, _);
+    interpose!(p.read_blocks, "BlockIo.ReadBlocks", call5, _, _, _, _, _);
+    interpose!(p.write_blocks, "BlockIo.WriteBlocks", call5, _, _, _, _, _);
+    interpose!(p.flush_blocks, "BlockIo.FlushBlocks", call1, _);
+}
+
+/// Trace the calls to a Simple File System interface
+pub fn simple_file_system(p: &mut efi_sfs::Protocol) {
+    interpose!(p.open_volume, "SimpleFileSystem.OpenVolume", call2, _, _);
+}
+
+/// Trace the calls to a File interface
+pub fn file(p: &mut efi_file::Protocol) {
+    interpose!(p.open, "File.Open", call5, _, _, _, _, _);
+    interpose!(p.close, "File.Close", call1, _);
+    interpose!(p.delete, "File.Delete", call1, _);
+    interpose!(p.read, "File.Read", call3, _, _, _);
+    interpose!(p.write, "File.Write", call3, _, _, _);
+    interpose!(p.get_position, "File.GetPosition", call2, _, _);
+    interpose!(p.set_position, "File.SetPosition", call2, _, _);
+    interpose!(p.get_info, "File.GetInfo", call4, _, _, _, _);
+    interpose!(p.set_info, "File.SetInfo", call4, _, _, _, _);
+    interpose!(p.flush, "File.Flush", call1, _);
+    interpose!(p.open_ex, "File.OpenEx", call6, _, _, _, _, _, _);
+    interpose!(p.read_ex, "File.ReadEx", call2, _, _);
+    interpose!(p.write_ex, "File.WriteEx", call2, _, _);
+    interpose!(p.flush_ex, "File.FlushEx", call2, _, _);
+}
+
+/// Trace the calls to a Graphics Output interface
+pub fn graphics_output(p: &mut GraphicsOutputProtocol) {
+    interpose!(p.query_mode, "Gop.QueryMode", call4, _, _, _, _);
+    interpose!(p.set_mode, "Gop.SetMode", call2, _, _);
+    interpose!(p.blt, "Gop.Blt", call10, _, _, _, _, _, _, _, _, _, _);
+}
@@ -4,6 +4,10 @@
 //! time, variable, and system reset services that persist after ExitBootServices.
 
 use crate::arch::x86_64::io;
+use crate::efi::cell::EfiCell;
+use crate::efi::variable_policy::{self, Action, Request, Stored};
+use crate::efi::variable_store;
+use crate::platform::ResetKind;
 use crate::state::{self, MAX_VARIABLE_DATA_SIZE, MAX_VARIABLE_NAME_LEN, MAX_VARIABLES};
 use core::ffi::c_void;
 use r_efi::efi::{
@@ -17,7 +21,7 @@ const EFI_RUNTIME_SERVICES_SIGNATURE: u64 = 0x56524553544E5552;
 const EFI_RUNTIME_SERVICES_REVISION: u32 = (2 << 16) | 100;
 
 /// Static runtime services table
-static mut RUNTIME_SERVICES: efi::RuntimeServices = efi::RuntimeServices {
+static RUNTIME_SERVICES: EfiCell<efi::RuntimeServices> = EfiCell::new(efi::RuntimeServices {
     hdr: TableHeader {
         signature: EFI_RUNTIME_SERVICES_SIGNATURE,
         revision: EFI_RUNTIME_SERVICES_REVISION,
@@ -39,11 +43,37 @@ static mut RUNTIME_SERVICES: efi::RuntimeServices = efi::RuntimeServices {
     update_capsule,
     query_capsule_capabilities,
     query_variable_info,
-};
+});
+
+/// Runtime services that work after ExitBootServices
+///
+/// The others return `UNSUPPORTED`. SetVirtualAddressMap succeeds but
+/// converts nothing, so the services only work identity-mapped and it isn't
+/// advertised: an OS honoring the mask keeps the identity mapping instead.
+/// Update together with the services and the capability report.
+const RUNTIME_SERVICES_SUPPORTED: u32 = efi::RT_SUPPORTED_GET_TIME
+    | efi::RT_SUPPORTED_GET_VARIABLE
+    | efi::RT_SUPPORTED_GET_NEXT_VARIABLE_NAME
+    | efi::RT_SUPPORTED_SET_VARIABLE
+    | efi::RT_SUPPORTED_RESET_SYSTEM
+    | efi::RT_SUPPORTED_QUERY_VARIABLE_INFO;
+
+/// EFI_RT_PROPERTIES_TABLE, telling the OS which runtime services to use
+static RT_PROPERTIES: EfiCell<efi::RtPropertiesTable> = EfiCell::new(efi::RtPropertiesTable {
+    version: efi::RT_PROPERTIES_TABLE_VERSION,
+    length: core::mem::size_of::<efi::RtPropertiesTable>() as u16,
+    runtime_services_supported: RUNTIME_SERVICES_SUPPORTED,
+});
 
 /// Get a pointer to the runtime services table
 pub fn get_runtime_services() -> *mut efi::RuntimeServices {
-    &raw mut RUNTIME_SERVICES
+    RUNTIME_SERVICES.as_ptr()
+}
+
+/// Modify the runtime services table, to interpose on services
+#[cfg(feature = "audit")]
+pub(super) fn with_table<R>(f: impl FnOnce(&mut efi::RuntimeServices) -> R) -> R {
+    RUNTIME_SERVICES.with(f)
 }
 
 /// Get the address of runtime services code (for memory map reservation)
@@ -140,6 +170,28 @@ extern "efiapi" fn get_variable(
     attributes: *mut u32,
     data_size: *mut usize,
     data: *mut c_void,
+) -> Status {
+    load_variable(
+        variable_name,
+        vendor_guid,
+        attributes,
+        data_size,
+        data,
+        false,
+    )
+}
+
+/// Carry out a GetVariable call
+///
+/// `firmware` marks reads by the firmware itself, which may read private
+/// variables.
+fn load_variable(
+    variable_name: *mut u16,
+    vendor_guid: *mut Guid,
+    attributes: *mut u32,
+    data_size: *mut usize,
+    data: *mut c_void,
+    firmware: bool,
 ) -> Status {
     if variable_name.is_null() || vendor_guid.is_null() || data_size.is_null() {
         return Status::INVALID_PARAMETER;
@@ -149,11 +201,17 @@ extern "efiapi" fn get_variable(
     let guid = unsafe { *vendor_guid };
     let efi = state::efi();
     let variables = &efi.variables;
+    let at_runtime = efi.allocator.boot_services_exited();
 
     // Find the variable using iterator
-    let found = variables
-        .iter()
-        .find(|var| var.in_use && var.vendor_guid == guid && name_eq(&var.name, name));
+    let found = variables.iter().find(|var| {
+        var.in_use
+            && variable_policy::is_visible(var.attributes, at_runtime)
+            && var.vendor_guid == guid
+            && name_eq(&var.name, name)
+            && (firmware
+                || !variable_policy::is_private(&var.name[..ucs2_strlen(&var.name)], &guid))
+    });
 
     match found {
         Some(var) => {
@@ -196,8 +254,13 @@ extern "efiapi" fn get_next_variable_name(
     // If name is empty, return first variable
     let is_first = unsafe { *current_name == 0 };
 
-    // Create iterator over in-use variables
-    let mut var_iter = variables.iter().filter(|var| var.in_use);
+    // Create iterator over the in-use variables the caller can see
+    let at_runtime = efi.allocator.boot_services_exited();
+    let mut var_iter = variables.iter().filter(|var| {
+        var.in_use
+            && variable_policy::is_visible(var.attributes, at_runtime)
+            && !variable_policy::is_private(&var.name[..ucs2_strlen(&var.name)], &var.vendor_guid)
+    });
 
     // If not first call, skip to current variable and advance past it
     let next_var = if is_first {
@@ -244,75 +307,102 @@ extern "efiapi" fn set_variable(
     if variable_name.is_null() || vendor_guid.is_null() {
         return Status::INVALID_PARAMETER;
     }
-
-    let name = variable_name;
-    let guid = unsafe { *vendor_guid };
-
-    // Check name length
-    let name_len = ucs2_strlen_ptr(name);
-    if name_len == 0 || name_len >= MAX_VARIABLE_NAME_LEN {
+    if data_size > 0 && data.is_null() {
         return Status::INVALID_PARAMETER;
     }
 
-    // Check data size
-    if data_size > MAX_VARIABLE_DATA_SIZE {
-        return Status::OUT_OF_RESOURCES;
-    }
+    let guid = unsafe { *vendor_guid };
+    let name_len = ucs2_strlen_ptr(variable_name);
+    let name = unsafe { core::slice::from_raw_parts(variable_name, name_len) };
+    let data = if data_size == 0 {
+        &[]
+    } else {
+        unsafe { core::slice::from_raw_parts(data as *const u8, data_size) }
+    };
+    store_variable(name, guid, attributes, data, false)
+}
 
-    if data_size > 0 && data.is_null() {
+/// Check a SetVariable call against the variable policy and carry it out
+///
+/// `name` has no terminating null. `firmware` marks writes by the firmware
+/// itself, which may set read-only variables.
+fn store_variable(
+    name: &[u16],
+    guid: Guid,
+    attributes: u32,
+    data: &[u8],
+    firmware: bool,
+) -> Status {
+    if name.is_empty() || name.len() >= MAX_VARIABLE_NAME_LEN {
         return Status::INVALID_PARAMETER;
     }
 
-    state::with_efi_mut(|efi| {
+    let status = state::with_efi_mut(|efi| {
+        let at_runtime = efi.allocator.boot_services_exited();
         let variables = &mut efi.variables;
 
-        // Find existing variable using position()
-        let existing_idx = variables
-            .iter()
-            .position(|var| var.in_use && var.vendor_guid == guid && name_eq(&var.name, name));
-
-        // Find first free slot using position()
-        let free_idx = variables.iter().position(|var| !var.in_use);
-
-        // Delete variable if data_size is 0
-        if data_size == 0 {
-            if let Some(idx) = existing_idx {
-                variables[idx].in_use = false;
+        let existing_idx = variables.iter().position(|var| {
+            var.in_use && var.vendor_guid == guid && var.name[..ucs2_strlen(&var.name)] == *name
+        });
+        let request = Request {
+            name,
+            guid,
+            attributes,
+            data,
+            at_runtime,
+            firmware,
+        };
+        let existing = existing_idx.map(|idx| Stored {
+            attributes: variables[idx].attributes,
+            timestamp: variables[idx].timestamp,
+        });
+
+        let (data, attributes, append, timestamp) = match variable_policy::check(&request, existing)
+        {
+            Ok(Action::Write {
+                data,
+                attributes,
+                append,
+                timestamp,
+            }) => (data, attributes, append, timestamp),
+            Ok(Action::Keep) => return Status::SUCCESS,
+            Ok(Action::Delete) => {
+                if let Some(idx) = existing_idx {
+                    variables[idx].in_use = false;
+                }
                 return Status::SUCCESS;
             }
-            return Status::NOT_FOUND;
-        }
+            Err(status) => return status,
+        };
 
         // Update or create variable
-        let idx = match existing_idx {
-            Some(i) => i,
-            None => match free_idx {
-                Some(i) => i,
-                None => return Status::OUT_OF_RESOURCES,
-            },
+        let Some(idx) = existing_idx.or_else(|| variables.iter().position(|var| !var.in_use))
+        else {
+            return Status::OUT_OF_RESOURCES;
         };
-
-        // Copy name using slice operations
-        let src = unsafe { core::slice::from_raw_parts(name, name_len + 1) };
-        variables[idx].name[..name_len + 1].copy_from_slice(src);
-        variables[idx].name[name_len + 1..].fill(0);
-
-        // Copy data
-        unsafe {
-            core::ptr::copy_nonoverlapping(
-                data as *const u8,
-                variables[idx].data.as_mut_ptr(),
-                data_size,
-            );
+        let var = &mut variables[idx];
+        let offset = if append { var.data_size } else { 0 };
+        if offset + data.len() > MAX_VARIABLE_DATA_SIZE {
+            return Status::OUT_OF_RESOURCES;
         }
 
-        variables[idx].vendor_guid = guid;
-        variables[idx].attributes = attributes;
-        variables[idx].data_size = data_size;
-        variables[idx].in_use = true;
+        var.name[..name.len()].copy_from_slice(name);
+        var.name[name.len()..].fill(0);
+        var.data[offset..offset + data.len()].copy_from_slice(data);
+        var.vendor_guid = guid;
+        var.attributes = attributes;
+        var.data_size = offset + data.len();
+        var.timestamp = timestamp;
+        var.in_use = true;
 
         Status::SUCCESS
-    })
+    });
+
+    // Saving skips unchanged snapshots, volatile changes don't cost a write
+    if status == Status::SUCCESS {
+        variable_store::save();
+    }
+    status
 }
 
 extern "efiapi" fn query_variable_info(
@@ -350,6 +440,123 @@ extern "efiapi" fn query_variable_info(
     Status::SUCCESS
 }
 
+// ============================================================================
+// Firmware-internal Variable Access
+// ============================================================================
+
+/// Vendor GUID of the architectural variables (`BootOrder`, `Boot####`, ...)
+pub const GLOBAL_VARIABLE_GUID: Guid = Guid::from_fields(
+    0x8be4_df61,
+    0x93ca,
+    0x11d2,
+    0xaa,
+    0x0d,
+    &[0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c],
+);
+
+/// Vendor GUID for CrabEFI's own configuration variables
+pub const CRABEFI_VARIABLE_GUID: Guid = Guid::from_fields(
+    0x6a3c_8f2e,
+    0x5d41,
+    0x4b7a,
+    0x9c,
+    0x1e,
+    &[0x43, 0x72, 0x61, 0x62, 0x45, 0x46],
+);
+
+/// Encode an ASCII variable name as a null-terminated UCS-2 buffer
+fn encode_name(name: &str) -> Option<[u16; MAX_VARIABLE_NAME_LEN]> {
+    if name.is_empty() || name.len() >= MAX_VARIABLE_NAME_LEN || !name.is_ascii() {
+        return None;
+    }
+    let mut buf = [0u16; MAX_VARIABLE_NAME_LEN];
+    for (dst, b) in buf.iter_mut().zip(name.bytes()) {
+        *dst = b as u16;
+    }
+    Some(buf)
+}
+
+/// Read a variable into `buf` on behalf of the firmware itself
+///
+/// Returns the variable's data size, or `None` if the variable does not
+/// exist or does not fit in `buf`.
+pub fn read_variable(name: &str, guid: &Guid, buf: &mut [u8]) -> Option<usize> {
+    let mut name = encode_name(name)?;
+    let mut guid = *guid;
+    let mut size = buf.len();
+    let status = load_variable(
+        name.as_mut_ptr(),
+        &mut guid,
+        core::ptr::null_mut(),
+        &mut size,
+        buf.as_mut_ptr() as *mut c_void,
+        true,
+    );
+    (status == Status::SUCCESS).then_some(size)
+}
+
+/// Create, replace or (with empty `data`) delete a variable on behalf of
+/// the firmware itself
+pub fn write_variable(name: &str, guid: &Guid, attributes: u32, data: &[u8]) -> Status {
+    let Some(encoded) = encode_name(name) else {
+        return Status::INVALID_PARAMETER;
+    };
+    store_variable(&encoded[..name.len()], *guid, attributes, data, true)
+}
+
+/// Publish the architectural variables boot loaders look at on startup
+///
+/// Secure Boot is not implemented, so the platform reports setup mode with
+/// Secure Boot off.
+pub fn publish_global_variables() {
+    let attributes = efi::VARIABLE_BOOTSERVICE_ACCESS | efi::VARIABLE_RUNTIME_ACCESS;
+    let guid = &GLOBAL_VARIABLE_GUID;
+    let _ = write_variable("SecureBoot", guid, attributes, &[0]);
+    let _ = write_variable("SetupMode", guid, attributes, &[1]);
+    let _ = write_variable(
+        "OsIndicationsSupported",
+        guid,
+        attributes,
+        &0u64.to_le_bytes(),
+    );
+    let _ = write_variable("PlatformLangCodes", guid, attributes, b"en-US\0");
+    let _ = write_variable("PlatformLang", guid, attributes, b"en-US\0");
+}
+
+/// Install the RT properties table advertising the working runtime services
+pub fn publish_properties_table() {
+    let status = crate::efi::system_table::install_configuration_table(
+        &efi::RT_PROPERTIES_TABLE_GUID,
+        RT_PROPERTIES.as_ptr() as *mut c_void,
+    );
+    if status != Status::SUCCESS {
+        log::warn!("Failed to install RT properties table: {:?}", status);
+        return;
+    }
+    log::debug!(
+        "Runtime services supported: {:#06x}",
+        RUNTIME_SERVICES_SUPPORTED
+    );
+}
+
+/// Read a little-endian `u16` variable
+pub fn read_variable_u16(name: &str, guid: &Guid) -> Option<u16> {
+    let mut buf = [0u8; 2];
+    match read_variable(name, guid, &mut buf) {
+        Some(2) => Some(u16::from_le_bytes(buf)),
+        _ => None,
+    }
+}
+
+/// Read a little-endian `u32` variable
+pub fn read_variable_u32(name: &str, guid: &Guid) -> Option<u32> {
+    let mut buf = [0u8; 4];
+    match read_variable(name, guid, &mut buf) {
+        Some(4) => Some(u32::from_le_bytes(buf)),
+        _ => None,
+    }
+}
+
 // ============================================================================
 // Miscellaneous Services
 // ============================================================================
@@ -366,34 +573,13 @@ extern "efiapi" fn reset_system(
 ) {
     log::info!("ResetSystem called with type {:?}", reset_type);
 
-    // Try different reset methods
-    match reset_type {
-        efi::RESET_COLD | efi::RESET_WARM => {
-            // Try keyboard controller reset
-            unsafe {
-                // Wait for keyboard controller to be ready
-                for _ in 0..1000 {
-                    let status = x86_in8(0x64);
-                    if status & 0x02 == 0 {
-                        break;
-                    }
-                }
-                // Send reset command
-                x86_out8(0x64, 0xFE);
-            }
+    // Boot options created this boot (e.g. by shim's fallback.efi) also go
+    // to CMOS, for boards without an SMMSTORE region
+    crate::boot_options::persist_default();
 
-            // If that didn't work, try triple fault
-            unsafe {
-                // Load a null IDT and trigger an interrupt
-                let null_idt: [u8; 6] = [0; 6];
-                core::arch::asm!(
-                    "lidt [{}]",
-                    "int3",
-                    in(reg) null_idt.as_ptr(),
-                    options(noreturn)
-                );
-            }
-        }
+    match reset_type {
+        efi::RESET_COLD => crate::platform::reset(ResetKind::Cold),
+        efi::RESET_WARM => crate::platform::reset(ResetKind::Warm),
         efi::RESET_SHUTDOWN => {
             // Try ACPI shutdown (S5)
             // This requires parsing ACPI tables which we don't do yet
@@ -430,7 +616,9 @@ extern "efiapi" fn query_capsule_capabilities(
 // ============================================================================
 
 /// Read time from CMOS RTC
-fn read_rtc_time() -> (u16, u8, u8, u8, u8, u8) {
+///
+/// Returns year, month, day, hour, minute and second.
+pub fn read_rtc_time() -> (u16, u8, u8, u8, u8, u8) {
     // Wait for RTC update to complete
     unsafe {
         loop {
@@ -477,10 +665,7 @@ fn read_rtc_time() -> (u16, u8, u8, u8, u8, u8) {
 
 /// Read a CMOS register
 fn read_cmos(reg: u8) -> u8 {
-    unsafe {
-        x86_out8(0x70, reg);
-        x86_in8(0x71)
-    }
+    crate::drivers::cmos::read(reg)
 }
 
 /// Port I/O functions - wrapper for arch module
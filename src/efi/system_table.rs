@@ -9,13 +9,14 @@ use r_efi::protocols::simple_text_input::Protocol as SimpleTextInputProtocol;
 use r_efi::protocols::simple_text_output::Protocol as SimpleTextOutputProtocol;
 use zerocopy::{FromBytes, Immutable, KnownLayout, Unaligned};
 
+use crate::efi::cell::EfiCell;
 use crate::state::{self, ConfigurationTable, MAX_CONFIG_TABLES};
 
 /// EFI System Table signature "IBI SYST"
 const EFI_SYSTEM_TABLE_SIGNATURE: u64 = 0x5453595320494249;
 
 /// EFI System Table revision (2.100 = UEFI 2.10)
-const EFI_SYSTEM_TABLE_REVISION: u32 = (2 << 16) | 100;
+pub const EFI_SYSTEM_TABLE_REVISION: u32 = (2 << 16) | 100;
 
 /// ACPI 2.0 RSDP GUID
 pub const ACPI_20_TABLE_GUID: Guid = Guid::from_fields(
@@ -156,7 +157,7 @@ pub struct SystemTable {
 }
 
 /// Static storage for the system table
-static mut SYSTEM_TABLE: SystemTable = SystemTable {
+static SYSTEM_TABLE: EfiCell<SystemTable> = EfiCell::new(SystemTable {
     hdr: TableHeader {
         signature: EFI_SYSTEM_TABLE_SIGNATURE,
         revision: EFI_SYSTEM_TABLE_REVISION,
@@ -176,7 +177,7 @@ static mut SYSTEM_TABLE: SystemTable = SystemTable {
     boot_services: core::ptr::null_mut(),
     number_of_table_entries: 0,
     configuration_table: core::ptr::null_mut(),
-};
+});
 
 /// Firmware vendor string "CrabEFI" in UCS-2
 static FIRMWARE_VENDOR: [u16; 8] = [
@@ -184,7 +185,7 @@ static FIRMWARE_VENDOR: [u16; 8] = [
 ];
 
 /// CrabEFI firmware revision (0.1.0 = 0x00010000)
-const CRABEFI_REVISION: u32 = 0x00010000;
+pub const CRABEFI_REVISION: u32 = 0x00010000;
 
 /// Initialize the system table
 ///
@@ -195,21 +196,24 @@ pub unsafe fn init(
     boot_services: *mut efi::BootServices,
     runtime_services: *mut efi::RuntimeServices,
 ) {
-    SYSTEM_TABLE.firmware_vendor = FIRMWARE_VENDOR.as_ptr();
-    SYSTEM_TABLE.firmware_revision = CRABEFI_REVISION;
-    SYSTEM_TABLE.boot_services = boot_services;
-    SYSTEM_TABLE.runtime_services = runtime_services;
-
     // Set up configuration table pointer
     let efi = state::efi();
-    SYSTEM_TABLE.configuration_table = efi.config_tables.as_ptr() as *mut ConfigurationTable;
+    let config_tables = efi.config_tables.as_ptr() as *mut ConfigurationTable;
+
+    SYSTEM_TABLE.with(|st| {
+        st.firmware_vendor = FIRMWARE_VENDOR.as_ptr();
+        st.firmware_revision = CRABEFI_REVISION;
+        st.boot_services = boot_services;
+        st.runtime_services = runtime_services;
+        st.configuration_table = config_tables;
+    });
 
     log::debug!("EFI System Table initialized");
 }
 
 /// Get a pointer to the system table
 pub fn get_system_table() -> *mut SystemTable {
-    &raw mut SYSTEM_TABLE
+    SYSTEM_TABLE.as_ptr()
 }
 
 /// Get a pointer to the system table as EFI type
@@ -218,14 +222,29 @@ pub fn get_system_table_efi() -> *mut efi::SystemTable {
     get_system_table() as *mut efi::SystemTable
 }
 
+/// Set the firmware vendor string and revision
+///
+/// # Safety
+///
+/// `vendor` must point to a NUL-terminated UCS-2 string that remains valid
+/// for the firmware lifetime.
+pub unsafe fn set_firmware_vendor(vendor: *const u16, revision: u32) {
+    SYSTEM_TABLE.with(|st| {
+        st.firmware_vendor = vendor;
+        st.firmware_revision = revision;
+    });
+}
+
 /// Set the console input protocol
 ///
 /// # Safety
 ///
 /// The protocol pointer must remain valid for the lifetime of boot services.
 pub unsafe fn set_console_in(handle: Handle, protocol: *mut SimpleTextInputProtocol) {
-    SYSTEM_TABLE.console_in_handle = handle;
-    SYSTEM_TABLE.con_in = protocol;
+    SYSTEM_TABLE.with(|st| {
+        st.console_in_handle = handle;
+        st.con_in = protocol;
+    });
 }
 
 /// Set the console output protocol
@@ -234,8 +253,10 @@ pub unsafe fn set_console_in(handle: Handle, protocol: *mut SimpleTextInputProto
 ///
 /// The protocol pointer must remain valid for the lifetime of boot services.
 pub unsafe fn set_console_out(handle: Handle, protocol: *mut SimpleTextOutputProtocol) {
-    SYSTEM_TABLE.console_out_handle = handle;
-    SYSTEM_TABLE.con_out = protocol;
+    SYSTEM_TABLE.with(|st| {
+        st.console_out_handle = handle;
+        st.con_out = protocol;
+    });
 }
 
 /// Set the standard error protocol
@@ -244,8 +265,10 @@ pub unsafe fn set_console_out(handle: Handle, protocol: *mut SimpleTextOutputPro
 ///
 /// The protocol pointer must remain valid for the lifetime of boot services.
 pub unsafe fn set_std_err(handle: Handle, protocol: *mut SimpleTextOutputProtocol) {
-    SYSTEM_TABLE.standard_error_handle = handle;
-    SYSTEM_TABLE.std_err = protocol;
+    SYSTEM_TABLE.with(|st| {
+        st.standard_error_handle = handle;
+        st.std_err = protocol;
+    });
 }
 
 /// Install a configuration table
@@ -294,9 +317,7 @@ pub fn install_configuration_table(guid: &Guid, table: *mut c_void) -> efi::Stat
 
 /// Update the table count in the system table
 fn update_table_count(count: usize) {
-    unsafe {
-        SYSTEM_TABLE.number_of_table_entries = count;
-    }
+    SYSTEM_TABLE.with(|st| st.number_of_table_entries = count);
 }
 
 /// ACPI RSDP structure (Root System Description Pointer)
@@ -641,7 +662,7 @@ pub fn install_acpi_tables(rsdp: u64) {
     log::info!(
         "Configuration table has {} entries, SystemTable.number_of_table_entries = {}",
         count,
-        unsafe { SYSTEM_TABLE.number_of_table_entries }
+        SYSTEM_TABLE.with(|st| st.number_of_table_entries)
     );
 }
 
@@ -811,9 +832,7 @@ pub fn install_smbios_tables(smbios_addr: u64) {
 pub fn update_crc32() {
     // For now, we leave CRC32 as 0
     // A proper implementation would calculate CRC32 of the table
-    unsafe {
-        SYSTEM_TABLE.hdr.crc32 = 0;
-    }
+    SYSTEM_TABLE.with(|st| st.hdr.crc32 = 0);
 }
 
 /// Dump configuration table entries for debugging
@@ -853,6 +872,6 @@ pub fn dump_configuration_tables() {
 ///
 /// This must only be called after ExitBootServices succeeds.
 pub unsafe fn clear_boot_services() {
-    SYSTEM_TABLE.boot_services = core::ptr::null_mut();
+    SYSTEM_TABLE.with(|st| st.boot_services = core::ptr::null_mut());
     log::debug!("SystemTable.boot_services set to NULL");
 }
@@ -2,7 +2,8 @@
 //!
 //! Common utility functions used across EFI modules.
 
-use crate::efi::allocator::{MemoryType, allocate_pool};
+use crate::efi::allocator::{MemoryType, allocate_pool, free_pool};
+use r_efi::efi;
 
 /// Allocate and initialize a protocol structure
 ///
@@ -57,3 +58,116 @@ pub fn allocate_protocol_with_log<T>(name: &str, init: impl FnOnce(&mut T)) -> *
     }
     ptr
 }
+
+/// Growable array backed by pool allocations
+///
+/// The firmware has no global allocator, so databases that must grow with
+/// the number of devices (handles, protocol entries) keep their entries in a
+/// `PoolVec`. The backing buffer doubles in size when full; growth fails with
+/// `OUT_OF_RESOURCES` instead of panicking.
+pub struct PoolVec<T: Copy> {
+    ptr: *mut T,
+    len: usize,
+    capacity: usize,
+}
+
+impl<T: Copy> PoolVec<T> {
+    /// Capacity of the first allocation
+    const INITIAL_CAPACITY: usize = 16;
+
+    /// Create an empty vector without allocating
+    pub const fn new() -> Self {
+        Self {
+            ptr: core::ptr::null_mut(),
+            len: 0,
+            capacity: 0,
+        }
+    }
+
+    /// Number of elements
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the vector is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append an element, growing the buffer if needed
+    pub fn push(&mut self, value: T) -> Result<(), efi::Status> {
+        if self.len == self.capacity {
+            self.grow()?;
+        }
+
+        // SAFETY: len < capacity after growing, so the slot is in the buffer
+        unsafe { self.ptr.add(self.len).write(value) };
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove the element at `index`, shifting the following ones down
+    pub fn remove(&mut self, index: usize) -> T {
+        let value = self.as_slice()[index];
+        self.as_mut_slice().copy_within(index + 1.., index);
+        self.len -= 1;
+        value
+    }
+
+    /// View the elements as a slice
+    pub fn as_slice(&self) -> &[T] {
+        if self.ptr.is_null() {
+            return &[];
+        }
+        // SAFETY: the first len elements of the buffer are initialized
+        unsafe { core::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    /// View the elements as a mutable slice
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        if self.ptr.is_null() {
+            return &mut [];
+        }
+        // SAFETY: the first len elements of the buffer are initialized
+        unsafe { core::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
+    /// Move the elements to a buffer twice the current capacity
+    fn grow(&mut self) -> Result<(), efi::Status> {
+        let capacity = if self.capacity == 0 {
+            Self::INITIAL_CAPACITY
+        } else {
+            self.capacity
+                .checked_mul(2)
+                .ok_or(efi::Status::OUT_OF_RESOURCES)?
+        };
+        let size = capacity
+            .checked_mul(core::mem::size_of::<T>())
+            .ok_or(efi::Status::OUT_OF_RESOURCES)?;
+
+        let ptr = allocate_pool(MemoryType::BootServicesData, size)? as *mut T;
+        if !self.ptr.is_null() {
+            // SAFETY: both buffers hold at least len elements and don't overlap
+            unsafe { core::ptr::copy_nonoverlapping(self.ptr, ptr, self.len) };
+            let _ = free_pool(self.ptr as *mut u8);
+        }
+
+        self.ptr = ptr;
+        self.capacity = capacity;
+        Ok(())
+    }
+}
+
+impl<T: Copy> Default for PoolVec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy> Drop for PoolVec<T> {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            let _ = free_pool(self.ptr as *mut u8);
+        }
+    }
+}
@@ -0,0 +1,592 @@
+//! SetVariable attribute checks and authenticated writes
+//!
+//! SetVariable refuses the updates the UEFI specification (section 8.2)
+//! forbids with the status codes it defines, so tools like efivar, mokutil
+//! and sbctl see the same errors as on other firmware:
+//!
+//! - unknown attributes, runtime access without boot services access and
+//!   malformed hardware error records are invalid
+//! - the deprecated count-based and the enhanced authenticated writes are
+//!   unsupported
+//! - a variable keeps its attributes; rewriting it with others, like making
+//!   a volatile variable non-volatile, is invalid
+//! - after ExitBootServices only non-volatile runtime variables are written
+//! - variables the firmware reports, like `SecureBoot`, are read-only
+//! - variables private to the firmware, like `SetupPassword`, can't be
+//!   written or deleted, and GetVariable and GetNextVariableName hide them
+//!
+//! Time-based authenticated writes carry an `EFI_VARIABLE_AUTHENTICATION_2`
+//! descriptor: a timestamp and a PKCS#7 signature. There is no Secure Boot,
+//! so the platform stays in setup mode, where the key databases (`KEK`,
+//! `db`, `dbx`, `dbt`, `dbr`) are written without checking the signature.
+//! The descriptor must still be well-formed and the timestamp later than
+//! the stored one. Signatures can't be verified, so `PK` and authenticated
+//! private variables fail with `SECURITY_VIOLATION`, as do updates of an
+//! authenticated variable without a descriptor.
+//!
+//! After ExitBootServices, variables without runtime access can't be read,
+//! listed or written anymore. shim relies on this for the Machine Owner
+//! Keys: it keeps `MokList` and its state (`MokSBState`, `MokDBState`, ...)
+//! in boot services variables the OS can't tamper with, and mirrors them
+//! to volatile runtime variables like `MokListRT` for the OS to read.
+//! mokutil queues changes in non-volatile runtime variables (`MokNew`,
+//! `MokAuth`, ...) that shim processes on the next boot.
+
+use r_efi::efi::{self, Guid, Status};
+
+use super::runtime_services::{CRABEFI_VARIABLE_GUID, GLOBAL_VARIABLE_GUID};
+use crate::setup_password;
+
+/// Vendor GUID of `db`, `dbx`, `dbt` and `dbr`
+pub const IMAGE_SECURITY_DATABASE_GUID: Guid = Guid::from_fields(
+    0xd719_b2cb,
+    0x3d3a,
+    0x4596,
+    0xa3,
+    0xbc,
+    &[0xda, 0xd0, 0x0e, 0x67, 0x65, 0x6f],
+);
+
+/// Certificate type of the signature in an authentication descriptor
+const CERT_TYPE_PKCS7_GUID: Guid = Guid::from_fields(
+    0x4aaf_d29d,
+    0x68df,
+    0x49ee,
+    0x8a,
+    0xa9,
+    &[0x34, 0x7d, 0x37, 0x56, 0x65, 0xa7],
+);
+
+/// `WIN_CERTIFICATE` revision 2.0
+const WIN_CERT_REVISION: u16 = 0x0200;
+
+/// `WIN_CERT_TYPE_EFI_GUID`
+const WIN_CERT_TYPE_EFI_GUID: u16 = 0x0EF1;
+
+/// Size of `EFI_TIME`
+const TIME_SIZE: usize = 16;
+
+/// Size of `WIN_CERTIFICATE_UEFI_GUID` without the certificate data
+const CERT_HEADER_SIZE: usize = 24;
+
+/// Attributes SetVariable knows
+const KNOWN_ATTRIBUTES: u32 = efi::VARIABLE_NON_VOLATILE
+    | efi::VARIABLE_BOOTSERVICE_ACCESS
+    | efi::VARIABLE_RUNTIME_ACCESS
+    | efi::VARIABLE_HARDWARE_ERROR_RECORD
+    | efi::VARIABLE_AUTHENTICATED_WRITE_ACCESS
+    | efi::VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS
+    | efi::VARIABLE_APPEND_WRITE
+    | efi::VARIABLE_ENHANCED_AUTHENTICATED_ACCESS;
+
+/// Attributes hardware error records must have
+const HARDWARE_ERROR_ATTRIBUTES: u32 = efi::VARIABLE_NON_VOLATILE
+    | efi::VARIABLE_BOOTSERVICE_ACCESS
+    | efi::VARIABLE_RUNTIME_ACCESS
+    | efi::VARIABLE_HARDWARE_ERROR_RECORD;
+
+/// Global variables only the firmware sets
+const READ_ONLY_GLOBAL_VARIABLES: &[&str] = &[
+    "AuditMode",
+    "BootCurrent",
+    "BootOptionSupport",
+    "DeployedMode",
+    "LangCodes",
+    "OsIndicationsSupported",
+    "PlatformLangCodes",
+    "SecureBoot",
+    "SetupMode",
+    "SignatureSupport",
+    "VendorKeys",
+    "PKDefault",
+    "KEKDefault",
+    "dbDefault",
+    "dbxDefault",
+    "dbtDefault",
+    "dbrDefault",
+];
+
+/// CrabEFI variables only the firmware reads and writes
+const PRIVATE_VARIABLES: &[&str] = &[setup_password::VARIABLE];
+
+/// Key databases writable in setup mode without a signature check
+const KEY_DATABASES: &[(&str, &Guid)] = &[
+    ("KEK", &GLOBAL_VARIABLE_GUID),
+    ("db", &IMAGE_SECURITY_DATABASE_GUID),
+    ("dbx", &IMAGE_SECURITY_DATABASE_GUID),
+    ("dbt", &IMAGE_SECURITY_DATABASE_GUID),
+    ("dbr", &IMAGE_SECURITY_DATABASE_GUID),
+];
+
+/// A SetVariable call
+pub struct Request<'a> {
+    /// Name without the terminating null
+    pub name: &'a [u16],
+    pub guid: Guid,
+    pub attributes: u32,
+    pub data: &'a [u8],
+    /// Called after ExitBootServices
+    pub at_runtime: bool,
+    /// Set by the firmware itself, which may write read-only variables
+    pub firmware: bool,
+}
+
+/// The variable a request updates, if it exists
+#[derive(Clone, Copy)]
+pub struct Stored {
+    pub attributes: u32,
+    /// Timestamp of the last authenticated write
+    pub timestamp: [u8; TIME_SIZE],
+}
+
+/// What an accepted SetVariable call does
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action<'a> {
+    /// Delete the variable
+    Delete,
+    /// Leave the variable as it is, when appending nothing
+    Keep,
+    /// Store `data` with `attributes`, appended to the existing data with
+    /// `append`
+    Write {
+        data: &'a [u8],
+        attributes: u32,
+        append: bool,
+        timestamp: [u8; TIME_SIZE],
+    },
+}
+
+/// Whether a UCS-2 name equals an ASCII one
+fn name_is(name: &[u16], ascii: &str) -> bool {
+    name.len() == ascii.len() && name.iter().zip(ascii.bytes()).all(|(&c, b)| c == b as u16)
+}
+
+/// Whether a variable is a valid hardware error record, `HwErrRec####`
+fn is_hardware_error_record(request: &Request) -> bool {
+    let name = request.name;
+    request.guid == efi::HARDWARE_ERROR_VARIABLE_GUID
+        && request.attributes & HARDWARE_ERROR_ATTRIBUTES == HARDWARE_ERROR_ATTRIBUTES
+        && name.len() == 12
+        && name_is(&name[..8], "HwErrRec")
+        && name[8..]
+            .iter()
+            .all(|&c| char::from_u32(c as u32).is_some_and(|c| c.is_ascii_hexdigit()))
+}
+
+fn is_read_only(request: &Request) -> bool {
+    request.guid == GLOBAL_VARIABLE_GUID
+        && READ_ONLY_GLOBAL_VARIABLES
+            .iter()
+            .any(|name| name_is(request.name, name))
+}
+
+/// Whether a variable is private to the firmware
+///
+/// `name` has no terminating null.
+pub fn is_private(name: &[u16], guid: &Guid) -> bool {
+    *guid == CRABEFI_VARIABLE_GUID
+        && PRIVATE_VARIABLES
+            .iter()
+            .any(|private| name_is(name, private))
+}
+
+fn is_key_database(request: &Request) -> bool {
+    KEY_DATABASES
+        .iter()
+        .any(|(name, guid)| request.guid == **guid && name_is(request.name, name))
+}
+
+/// Split an `EFI_VARIABLE_AUTHENTICATION_2` descriptor off the data
+///
+/// Returns the timestamp and the payload, or `None` if the descriptor is
+/// malformed.
+fn parse_authentication(data: &[u8]) -> Option<([u8; TIME_SIZE], &[u8])> {
+    let (time, rest) = data.split_first_chunk::<TIME_SIZE>()?;
+    let (header, _) = rest.split_first_chunk::<CERT_HEADER_SIZE>()?;
+    let length = u32::from_le_bytes(header[0..4].try_into().ok()?) as usize;
+    let revision = u16::from_le_bytes([header[4], header[5]]);
+    let cert_type = u16::from_le_bytes([header[6], header[7]]);
+    let cert_guid = Guid::from_bytes(header[8..24].try_into().ok()?);
+    if length < CERT_HEADER_SIZE
+        || length > rest.len()
+        || revision != WIN_CERT_REVISION
+        || cert_type != WIN_CERT_TYPE_EFI_GUID
+        || cert_guid != CERT_TYPE_PKCS7_GUID
+    {
+        return None;
+    }
+    // Pad1, Nanosecond, TimeZone, Daylight and Pad2 must be zero
+    if time[7..].iter().any(|&b| b != 0) {
+        return None;
+    }
+    Some((*time, &rest[length..]))
+}
+
+/// Sort key of an `EFI_TIME`: year, month, day, hour, minute, second
+fn time_key(time: &[u8; TIME_SIZE]) -> (u16, [u8; 5]) {
+    (
+        u16::from_le_bytes([time[0], time[1]]),
+        [time[2], time[3], time[4], time[5], time[6]],
+    )
+}
+
+/// Whether a variable with `attributes` can be accessed, after
+/// ExitBootServices with `at_runtime`
+pub fn is_visible(attributes: u32, at_runtime: bool) -> bool {
+    !at_runtime || attributes & efi::VARIABLE_RUNTIME_ACCESS != 0
+}
+
+/// Check a SetVariable call against the variable it updates
+pub fn check<'a>(request: &Request<'a>, existing: Option<Stored>) -> Result<Action<'a>, Status> {
+    let attributes = request.attributes;
+    let authenticated = attributes & efi::VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS != 0;
+    if attributes & !KNOWN_ATTRIBUTES != 0
+        || (authenticated && attributes & efi::VARIABLE_AUTHENTICATED_WRITE_ACCESS != 0)
+    {
+        return Err(Status::INVALID_PARAMETER);
+    }
+    if attributes
+        & (efi::VARIABLE_AUTHENTICATED_WRITE_ACCESS | efi::VARIABLE_ENHANCED_AUTHENTICATED_ACCESS)
+        != 0
+    {
+        return Err(Status::UNSUPPORTED);
+    }
+    if (attributes & efi::VARIABLE_RUNTIME_ACCESS != 0
+        && attributes & efi::VARIABLE_BOOTSERVICE_ACCESS == 0)
+        || (attributes & efi::VARIABLE_HARDWARE_ERROR_RECORD != 0
+            && !is_hardware_error_record(request))
+    {
+        return Err(Status::INVALID_PARAMETER);
+    }
+    if !request.firmware && (is_read_only(request) || is_private(request.name, &request.guid)) {
+        return Err(Status::WRITE_PROTECTED);
+    }
+
+    // After ExitBootServices, boot services variables are gone and only
+    // non-volatile runtime variables can be written
+    let existing = existing.filter(|var| is_visible(var.attributes, request.at_runtime));
+    let runtime_attributes = efi::VARIABLE_NON_VOLATILE | efi::VARIABLE_RUNTIME_ACCESS;
+    if request.at_runtime
+        && attributes != 0
+        && attributes & runtime_attributes != runtime_attributes
+    {
+        return Err(Status::INVALID_PARAMETER);
+    }
+
+    let append = attributes & efi::VARIABLE_APPEND_WRITE != 0;
+    let attributes = attributes & !efi::VARIABLE_APPEND_WRITE;
+    if let Some(var) = existing
+        && attributes != 0
+        && attributes != var.attributes
+    {
+        return Err(Status::INVALID_PARAMETER);
+    }
+
+    if authenticated {
+        return check_authenticated(request, existing, attributes, append);
+    }
+    if existing.is_some_and(|var| {
+        var.attributes & efi::VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS != 0
+    }) {
+        return Err(Status::SECURITY_VIOLATION);
+    }
+
+    if attributes == 0 || (request.data.is_empty() && !append) {
+        return match existing {
+            Some(_) => Ok(Action::Delete),
+            None => Err(Status::NOT_FOUND),
+        };
+    }
+    if request.data.is_empty() {
+        return Ok(Action::Keep);
+    }
+    Ok(Action::Write {
+        data: request.data,
+        attributes,
+        append: append && existing.is_some(),
+        timestamp: [0; TIME_SIZE],
+    })
+}
+
+/// Check a time-based authenticated write
+fn check_authenticated<'a>(
+    request: &Request<'a>,
+    existing: Option<Stored>,
+    attributes: u32,
+    append: bool,
+) -> Result<Action<'a>, Status> {
+    let Some((timestamp, payload)) = parse_authentication(request.data) else {
+        log::debug!("SetVariable: malformed authentication descriptor");
+        return Err(Status::SECURITY_VIOLATION);
+    };
+    if !is_key_database(request) {
+        // The signature would have to be verified
+        return Err(Status::SECURITY_VIOLATION);
+    }
+
+    let timestamp = match existing {
+        // Appending keeps the later timestamp
+        Some(var) if append => core::cmp::max_by_key(var.timestamp, timestamp, time_key),
+        Some(var) if time_key(&timestamp) <= time_key(&var.timestamp) => {
+            log::debug!("SetVariable: authenticated write is not newer than the variable");
+            return Err(Status::SECURITY_VIOLATION);
+        }
+        _ => timestamp,
+    };
+
+    if payload.is_empty() {
+        return match existing {
+            Some(_) if append => Ok(Action::Keep),
+            Some(_) => Ok(Action::Delete),
+            None => Err(Status::NOT_FOUND),
+        };
+    }
+    Ok(Action::Write {
+        data: payload,
+        attributes,
+        append: append && existing.is_some(),
+        timestamp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NV_BS_RT: u32 = efi::VARIABLE_NON_VOLATILE
+        | efi::VARIABLE_BOOTSERVICE_ACCESS
+        | efi::VARIABLE_RUNTIME_ACCESS;
+    const AUTH: u32 = NV_BS_RT | efi::VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS;
+
+    fn ucs2(name: &str) -> Vec<u16> {
+        name.encode_utf16().collect()
+    }
+
+    fn request<'a>(name: &'a [u16], guid: Guid, attributes: u32, data: &'a [u8]) -> Request<'a> {
+        Request {
+            name,
+            guid,
+            attributes,
+            data,
+            at_runtime: false,
+            firmware: false,
+        }
+    }
+
+    /// A stored variable last written at the start of `year`
+    fn stored(attributes: u32, year: u16) -> Option<Stored> {
+        let mut timestamp = [0; TIME_SIZE];
+        timestamp[..2].copy_from_slice(&year.to_le_bytes());
+        timestamp[2] = 1;
+        timestamp[3] = 1;
+        Some(Stored {
+            attributes,
+            timestamp,
+        })
+    }
+
+    /// Data of an authenticated write at the start of `year`
+    fn authenticated(year: u16, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; TIME_SIZE + CERT_HEADER_SIZE + 4];
+        data[..2].copy_from_slice(&year.to_le_bytes());
+        data[2] = 1;
+        data[3] = 1;
+        let cert = &mut data[TIME_SIZE..];
+        cert[..4].copy_from_slice(&(CERT_HEADER_SIZE as u32 + 4).to_le_bytes());
+        cert[4..6].copy_from_slice(&WIN_CERT_REVISION.to_le_bytes());
+        cert[6..8].copy_from_slice(&WIN_CERT_TYPE_EFI_GUID.to_le_bytes());
+        cert[8..24].copy_from_slice(CERT_TYPE_PKCS7_GUID.as_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn validates_attributes() {
+        let name = ucs2("Test");
+        let guid = Guid::from_fields(1, 2, 3, 4, 5, &[6; 6]);
+        let check_new = |attributes| check(&request(&name, guid, attributes, b"x"), None);
+
+        assert!(matches!(check_new(NV_BS_RT), Ok(Action::Write { .. })));
+        assert_eq!(check_new(0x100), Err(Status::INVALID_PARAMETER));
+        assert_eq!(
+            check_new(efi::VARIABLE_RUNTIME_ACCESS),
+            Err(Status::INVALID_PARAMETER)
+        );
+        assert_eq!(
+            check_new(NV_BS_RT | efi::VARIABLE_AUTHENTICATED_WRITE_ACCESS),
+            Err(Status::UNSUPPORTED)
+        );
+        assert_eq!(
+            check_new(NV_BS_RT | efi::VARIABLE_HARDWARE_ERROR_RECORD),
+            Err(Status::INVALID_PARAMETER)
+        );
+
+        // Volatile to non-volatile, and deleting with other attributes
+        let volatile = stored(efi::VARIABLE_BOOTSERVICE_ACCESS, 0);
+        let write = request(&name, guid, NV_BS_RT, b"x");
+        assert_eq!(check(&write, volatile), Err(Status::INVALID_PARAMETER));
+        let delete = request(&name, guid, efi::VARIABLE_BOOTSERVICE_ACCESS, b"");
+        assert_eq!(check(&delete, volatile), Ok(Action::Delete));
+        assert_eq!(check(&delete, None), Err(Status::NOT_FOUND));
+
+        // Appending
+        let append = NV_BS_RT | efi::VARIABLE_APPEND_WRITE;
+        let existing = stored(NV_BS_RT, 0);
+        assert_eq!(
+            check(&request(&name, guid, append, b"x"), existing),
+            Ok(Action::Write {
+                data: b"x",
+                attributes: NV_BS_RT,
+                append: true,
+                timestamp: [0; TIME_SIZE],
+            })
+        );
+        assert_eq!(
+            check(&request(&name, guid, append, b""), existing),
+            Ok(Action::Keep)
+        );
+
+        // Read-only and hardware error records
+        let secure_boot = ucs2("SecureBoot");
+        let mut write = request(&secure_boot, GLOBAL_VARIABLE_GUID, NV_BS_RT, b"\x01");
+        assert_eq!(check(&write, None), Err(Status::WRITE_PROTECTED));
+        write.firmware = true;
+        assert!(check(&write, None).is_ok());
+        let password = ucs2(setup_password::VARIABLE);
+        let mut delete = request(&password, CRABEFI_VARIABLE_GUID, 0, b"");
+        let boot_only = efi::VARIABLE_NON_VOLATILE | efi::VARIABLE_BOOTSERVICE_ACCESS;
+        assert_eq!(
+            check(&delete, stored(boot_only, 0)),
+            Err(Status::WRITE_PROTECTED)
+        );
+        delete.firmware = true;
+        assert_eq!(check(&delete, stored(boot_only, 0)), Ok(Action::Delete));
+        let record = ucs2("HwErrRec00aF");
+        let attributes = NV_BS_RT | efi::VARIABLE_HARDWARE_ERROR_RECORD;
+        let write = request(&record, efi::HARDWARE_ERROR_VARIABLE_GUID, attributes, b"x");
+        assert!(check(&write, None).is_ok());
+    }
+
+    #[test]
+    fn restricts_runtime_writes() {
+        let name = ucs2("Test");
+        let guid = Guid::from_fields(1, 2, 3, 4, 5, &[6; 6]);
+        let mut write = request(&name, guid, NV_BS_RT, b"x");
+        write.at_runtime = true;
+        assert!(check(&write, None).is_ok());
+
+        write.attributes = efi::VARIABLE_BOOTSERVICE_ACCESS | efi::VARIABLE_RUNTIME_ACCESS;
+        assert_eq!(check(&write, None), Err(Status::INVALID_PARAMETER));
+
+        // Boot services variables are gone
+        let mut delete = request(&name, guid, 0, b"");
+        delete.at_runtime = true;
+        let boot_only = efi::VARIABLE_NON_VOLATILE | efi::VARIABLE_BOOTSERVICE_ACCESS;
+        assert_eq!(check(&delete, stored(boot_only, 0)), Err(Status::NOT_FOUND));
+    }
+
+    #[test]
+    fn supports_shim_mok_variables() {
+        let shim_lock = Guid::from_fields(
+            0x605d_ab50,
+            0xe046,
+            0x4300,
+            0xab,
+            0xb6,
+            &[0x3d, 0xd8, 0x10, 0xdd, 0x8b, 0x23],
+        );
+        let boot_only = efi::VARIABLE_NON_VOLATILE | efi::VARIABLE_BOOTSERVICE_ACCESS;
+        let mirror = efi::VARIABLE_BOOTSERVICE_ACCESS | efi::VARIABLE_RUNTIME_ACCESS;
+        assert!(is_visible(boot_only, false));
+        assert!(!is_visible(boot_only, true));
+        assert!(is_visible(mirror, true));
+
+        // shim mirrors MokList to the volatile MokListRT, after deleting a
+        // non-volatile one left by old versions
+        let list_rt = ucs2("MokListRT");
+        let write = request(&list_rt, shim_lock, mirror, b"esl");
+        assert_eq!(
+            check(&write, stored(NV_BS_RT, 0)),
+            Err(Status::INVALID_PARAMETER)
+        );
+        let delete = request(&list_rt, shim_lock, 0, b"");
+        assert_eq!(check(&delete, stored(NV_BS_RT, 0)), Ok(Action::Delete));
+        assert!(check(&write, None).is_ok());
+
+        // mokutil queues a request from the OS
+        let new = ucs2("MokNew");
+        let mut write = request(&new, shim_lock, NV_BS_RT, b"esl");
+        write.at_runtime = true;
+        assert!(check(&write, None).is_ok());
+
+        // The OS can't replace the boot services MokList
+        let list = ucs2("MokList");
+        let mut write = request(&list, shim_lock, boot_only, b"esl");
+        write.at_runtime = true;
+        assert_eq!(
+            check(&write, stored(boot_only, 0)),
+            Err(Status::INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn checks_authenticated_writes() {
+        let db = ucs2("db");
+        let guid = IMAGE_SECURITY_DATABASE_GUID;
+        let data = authenticated(2024, b"list");
+
+        assert_eq!(
+            check(&request(&db, guid, AUTH, &data), stored(AUTH, 2023)),
+            Ok(Action::Write {
+                data: b"list",
+                attributes: AUTH,
+                append: false,
+                timestamp: parse_authentication(&data).unwrap().0,
+            })
+        );
+        // Replayed or older updates
+        assert_eq!(
+            check(&request(&db, guid, AUTH, &data), stored(AUTH, 2024)),
+            Err(Status::SECURITY_VIOLATION)
+        );
+        // Appending keeps the later timestamp
+        let append = AUTH | efi::VARIABLE_APPEND_WRITE;
+        let Ok(Action::Write { timestamp, .. }) =
+            check(&request(&db, guid, append, &data), stored(AUTH, 2025))
+        else {
+            panic!("append refused");
+        };
+        assert_eq!(u16::from_le_bytes([timestamp[0], timestamp[1]]), 2025);
+
+        // Malformed descriptors, unauthenticated updates and signed variables
+        let mut bad = data.clone();
+        bad[8] = 1; // nanoseconds
+        assert_eq!(
+            check(&request(&db, guid, AUTH, &bad), None),
+            Err(Status::SECURITY_VIOLATION)
+        );
+        assert_eq!(
+            check(&request(&db, guid, AUTH, &data[..30]), None),
+            Err(Status::SECURITY_VIOLATION)
+        );
+        assert_eq!(
+            check(&request(&db, guid, NV_BS_RT, b"list"), stored(AUTH, 2023)),
+            Err(Status::INVALID_PARAMETER)
+        );
+        assert_eq!(
+            check(&request(&db, guid, 0, b""), stored(AUTH, 2023)),
+            Err(Status::SECURITY_VIOLATION)
+        );
+        let pk = ucs2("PK");
+        assert_eq!(
+            check(&request(&pk, GLOBAL_VARIABLE_GUID, AUTH, &data), None),
+            Err(Status::SECURITY_VIOLATION)
+        );
+        // Deleting with an empty payload
+        let empty = authenticated(2024, b"");
+        assert_eq!(
+            check(&request(&db, guid, AUTH, &empty), stored(AUTH, 2023)),
+            Ok(Action::Delete)
+        );
+    }
+}
@@ -0,0 +1,382 @@
+//! Non-volatile variable storage
+//!
+//! Variables with the non-volatile attribute are kept in coreboot's
+//! SMMSTORE region, written through SMM (see [`crate::coreboot::smmstore`]).
+//! A snapshot of all of them is stored in one block: a header with a
+//! sequence number and the SHA-256 hash of the records, then one record per
+//! variable. The first two blocks take turns, each save erases the block
+//! not holding the newest snapshot, so losing power while saving leaves the
+//! previous snapshot. On boot the valid snapshot with the highest sequence
+//! number is loaded.
+//!
+//! The blocks are only used if they are erased or hold a snapshot, a region
+//! already holding another payload's variable store is left alone.
+//!
+//! Variables are saved after each change, at runtime too: the communication
+//! buffer is marked as runtime services data, so the OS keeps it mapped and
+//! leaves it alone, and variables like MokNew or BootNext set from the OS
+//! are there on the next boot.
+
+use r_efi::efi::{self, Guid};
+use spin::Mutex;
+
+use crate::coreboot::smmstore;
+use crate::crypto::Digest;
+use crate::crypto::sha256::Sha256;
+use crate::efi::allocator::{self, PAGE_SIZE};
+use crate::state::{self, MAX_VARIABLE_DATA_SIZE, MAX_VARIABLE_NAME_LEN, VariableEntry};
+
+/// Magic at the start of a snapshot
+const MAGIC: &[u8; 8] = b"CRABVARS";
+
+/// Snapshot format version
+const VERSION: u32 = 1;
+
+/// Magic, version, sequence, length of the records, reserved and hash
+const HEADER_SIZE: usize = 56;
+
+/// GUID, attributes, name and data length and timestamp, before the name
+const RECORD_HEADER_SIZE: usize = 40;
+
+/// Blocks holding snapshots
+const BLOCKS: u32 = 2;
+
+type Hash = [u8; 32];
+
+/// The newest snapshot in the store
+#[derive(Clone, Copy)]
+struct Newest {
+    block: u32,
+    sequence: u32,
+    hash: Hash,
+}
+
+/// Whether snapshots can be saved, and where the newest one is
+struct Store {
+    usable: bool,
+    newest: Option<Newest>,
+}
+
+static STORE: Mutex<Store> = Mutex::new(Store {
+    usable: false,
+    newest: None,
+});
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn hash(records: &[u8]) -> Hash {
+    let mut digest = Sha256::default();
+    digest.update(records);
+    let mut hash = [0u8; 32];
+    digest.finalize_into(&mut hash);
+    hash
+}
+
+/// A variable read from a snapshot
+struct Record<'a> {
+    guid: Guid,
+    attributes: u32,
+    timestamp: [u8; 16],
+    /// UCS-2 name in little endian, without the terminating null
+    name: &'a [u8],
+    data: &'a [u8],
+}
+
+/// A valid snapshot
+struct Snapshot<'a> {
+    sequence: u32,
+    hash: Hash,
+    records: &'a [u8],
+}
+
+impl<'a> Snapshot<'a> {
+    /// Parse the snapshot at the start of a block
+    fn parse(block: &'a [u8]) -> Option<Self> {
+        if block.len() < HEADER_SIZE || !block.starts_with(MAGIC) || le32(block, 8) != VERSION {
+            return None;
+        }
+        let length = le32(block, 16) as usize;
+        let records = block.get(HEADER_SIZE..HEADER_SIZE.checked_add(length)?)?;
+        let stored: Hash = block[24..HEADER_SIZE].try_into().ok()?;
+        (hash(records) == stored).then_some(Snapshot {
+            sequence: le32(block, 12),
+            hash: stored,
+            records,
+        })
+    }
+
+    /// The variables, stopping at the first malformed record
+    fn records(&self) -> impl Iterator<Item = Record<'a>> {
+        let mut rest = self.records;
+        core::iter::from_fn(move || {
+            let header = rest.get(..RECORD_HEADER_SIZE)?;
+            let name_len = le16(header, 20) as usize * 2;
+            let data_len = le16(header, 22) as usize;
+            let body = rest.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + name_len + data_len)?;
+            rest = &rest[RECORD_HEADER_SIZE + name_len + data_len..];
+            Some(Record {
+                guid: Guid::from_bytes(header[..16].try_into().ok()?),
+                attributes: le32(header, 16),
+                timestamp: header[24..40].try_into().ok()?,
+                name: &body[..name_len],
+                data: &body[name_len..],
+            })
+        })
+    }
+}
+
+/// Whether a block is erased, as far as the header goes
+fn is_erased(block: &[u8]) -> bool {
+    block.iter().take(HEADER_SIZE).all(|&b| b == 0xff)
+}
+
+/// Write a snapshot of the non-volatile variables into `out`
+///
+/// Returns the size of the snapshot and the hash of its records, or None
+/// if it doesn't fit.
+fn encode(variables: &[VariableEntry], sequence: u32, out: &mut [u8]) -> Option<(usize, Hash)> {
+    if out.len() < HEADER_SIZE {
+        return None;
+    }
+    let mut pos = HEADER_SIZE;
+    for var in variables
+        .iter()
+        .filter(|var| var.in_use && var.attributes & efi::VARIABLE_NON_VOLATILE != 0)
+    {
+        let name_len = var.name.iter().position(|&c| c == 0).unwrap_or(0);
+        let size = RECORD_HEADER_SIZE + name_len * 2 + var.data_size;
+        let record = out.get_mut(pos..pos + size)?;
+        record[..16].copy_from_slice(var.vendor_guid.as_bytes());
+        record[16..20].copy_from_slice(&var.attributes.to_le_bytes());
+        record[20..22].copy_from_slice(&(name_len as u16).to_le_bytes());
+        record[22..24].copy_from_slice(&(var.data_size as u16).to_le_bytes());
+        record[24..40].copy_from_slice(&var.timestamp);
+        for (bytes, c) in record[RECORD_HEADER_SIZE..]
+            .as_chunks_mut::<2>()
+            .0
+            .iter_mut()
+            .zip(&var.name[..name_len])
+        {
+            *bytes = c.to_le_bytes();
+        }
+        record[RECORD_HEADER_SIZE + name_len * 2..].copy_from_slice(&var.data[..var.data_size]);
+        pos += size;
+    }
+
+    let hash = hash(&out[HEADER_SIZE..pos]);
+    let header = &mut out[..HEADER_SIZE];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&sequence.to_le_bytes());
+    header[16..20].copy_from_slice(&((pos - HEADER_SIZE) as u32).to_le_bytes());
+    header[20..24].fill(0);
+    header[24..].copy_from_slice(&hash);
+    Some((pos, hash))
+}
+
+/// Add the variables of a snapshot to empty entries
+///
+/// Returns the number of variables added.
+fn restore(snapshot: &Snapshot, variables: &mut [VariableEntry]) -> usize {
+    let mut free = variables.iter_mut().filter(|var| !var.in_use);
+    let mut count = 0;
+    for record in snapshot.records() {
+        let name_len = record.name.len() / 2;
+        if name_len == 0
+            || name_len >= MAX_VARIABLE_NAME_LEN
+            || record.data.len() > MAX_VARIABLE_DATA_SIZE
+            || record.attributes & efi::VARIABLE_NON_VOLATILE == 0
+        {
+            log::warn!("Variable store: skipping malformed variable");
+            continue;
+        }
+        let Some(var) = free.next() else {
+            log::warn!("Variable store: no room for all variables");
+            break;
+        };
+        for (c, bytes) in var.name.iter_mut().zip(record.name.as_chunks::<2>().0) {
+            *c = u16::from_le_bytes(*bytes);
+        }
+        var.name[name_len..].fill(0);
+        var.vendor_guid = record.guid;
+        var.attributes = record.attributes;
+        var.data[..record.data.len()].copy_from_slice(record.data);
+        var.data_size = record.data.len();
+        var.timestamp = record.timestamp;
+        var.in_use = true;
+        count += 1;
+    }
+    count
+}
+
+/// Load the newest snapshot from SMMSTORE
+///
+/// Must run before the firmware publishes its own variables.
+pub fn load() {
+    let Some((num_blocks, _)) = smmstore::geometry() else {
+        return;
+    };
+    if num_blocks < BLOCKS {
+        log::warn!("Variable store: SMMSTORE needs {} blocks", BLOCKS);
+        return;
+    }
+
+    let mut store = STORE.lock();
+    store.usable = true;
+    for block in 0..BLOCKS {
+        let result = smmstore::read_block(block, |data| match Snapshot::parse(data) {
+            Some(snapshot) => {
+                if store
+                    .newest
+                    .is_none_or(|newest| snapshot.sequence > newest.sequence)
+                {
+                    store.newest = Some(Newest {
+                        block,
+                        sequence: snapshot.sequence,
+                        hash: snapshot.hash,
+                    });
+                }
+            }
+            None if is_erased(data) => {}
+            None => {
+                log::warn!("Variable store: block {} holds unknown data", block);
+                store.usable = false;
+            }
+        });
+        if let Err(error) = result {
+            log::warn!("Variable store: can't read block {}: {:?}", block, error);
+            store.usable = false;
+        }
+    }
+    if !store.usable {
+        log::warn!("Variable store: not using SMMSTORE, variables are volatile");
+        store.newest = None;
+        return;
+    }
+    if let Some(buffer) = smmstore::com_buffer_region() {
+        let start = buffer.start & !(PAGE_SIZE - 1);
+        let pages = (buffer.end - start).div_ceil(PAGE_SIZE);
+        if let Err(status) = allocator::mark_as_runtime_data(start, pages) {
+            log::warn!("Variable store: runtime saves won't work: {:?}", status);
+        }
+    }
+
+    let Some(newest) = store.newest else {
+        log::info!("Variable store: SMMSTORE is empty");
+        return;
+    };
+    let count = smmstore::read_block(newest.block, |data| {
+        let snapshot = Snapshot::parse(data)?;
+        Some(state::with_efi_mut(|efi| {
+            restore(&snapshot, &mut efi.variables)
+        }))
+    });
+    match count {
+        Ok(Some(count)) => log::info!(
+            "Variable store: {} variables from snapshot {}",
+            count,
+            newest.sequence
+        ),
+        _ => log::warn!("Variable store: snapshot {} went away", newest.sequence),
+    }
+}
+
+/// Save the non-volatile variables if they changed
+pub fn save() {
+    let mut store = STORE.lock();
+    if !store.usable {
+        return;
+    }
+    let (block, sequence) = match store.newest {
+        Some(newest) => ((newest.block + 1) % BLOCKS, newest.sequence.wrapping_add(1)),
+        None => (0, 1),
+    };
+
+    let encoded = smmstore::fill_buffer(|buffer| encode(&state::efi().variables, sequence, buffer));
+    let (size, hash) = match encoded {
+        Ok(Some(encoded)) => encoded,
+        Ok(None) => {
+            log::error!("Variable store: variables don't fit in a block");
+            return;
+        }
+        Err(error) => {
+            log::error!("Variable store: {:?}", error);
+            return;
+        }
+    };
+    if store.newest.is_some_and(|newest| newest.hash == hash) {
+        return;
+    }
+
+    let result = smmstore::clear_block(block).and_then(|()| smmstore::write_block(block, size));
+    match result {
+        Ok(()) => {
+            store.newest = Some(Newest {
+                block,
+                sequence,
+                hash,
+            });
+            log::debug!("Variable store: saved snapshot {}", sequence);
+        }
+        Err(error) => log::error!("Variable store: saving failed: {:?}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+
+    fn variable(name: &str, attributes: u32, data: &[u8]) -> VariableEntry {
+        let mut var = VariableEntry::empty();
+        for (c, b) in var.name.iter_mut().zip(name.bytes()) {
+            *c = b as u16;
+        }
+        var.vendor_guid = Guid::from_fields(1, 2, 3, 4, 5, &[6, 7, 8, 9, 10, 11]);
+        var.attributes = attributes;
+        var.data[..data.len()].copy_from_slice(data);
+        var.data_size = data.len();
+        var.timestamp[0] = 0x7e;
+        var.in_use = true;
+        var
+    }
+
+    #[test]
+    fn round_trips_snapshots() {
+        let nv = efi::VARIABLE_NON_VOLATILE | efi::VARIABLE_BOOTSERVICE_ACCESS;
+        let variables = Box::new([
+            variable("BootOrder", nv, &[1, 0]),
+            variable("Volatile", efi::VARIABLE_BOOTSERVICE_ACCESS, b"gone"),
+            variable("Empty", nv, &[]),
+        ]);
+
+        let mut block = std::vec![0xffu8; 4096];
+        assert!(is_erased(&block));
+        let (size, hash) = encode(&variables[..], 7, &mut block).unwrap();
+        assert!(!is_erased(&block));
+        let snapshot = Snapshot::parse(&block).unwrap();
+        assert_eq!((snapshot.sequence, snapshot.hash), (7, hash));
+        assert_eq!(snapshot.records().count(), 2);
+
+        let mut restored = Box::new([const { VariableEntry::empty() }; 4]);
+        assert_eq!(restore(&snapshot, &mut restored[..]), 2);
+        assert_eq!(restored[0].name, variables[0].name);
+        assert_eq!(restored[0].data[..2], [1, 0]);
+        assert_eq!(restored[0].attributes, nv);
+        assert_eq!(restored[0].vendor_guid, variables[0].vendor_guid);
+        assert_eq!(restored[0].timestamp, variables[0].timestamp);
+        assert_eq!(restored[1].data_size, 0);
+        assert!(!restored[2].in_use);
+
+        // A torn write fails the hash, a too small block doesn't fit
+        block[size - 1] ^= 1;
+        assert!(Snapshot::parse(&block).is_none());
+        assert!(encode(&variables[..], 8, &mut [0; 64]).is_none());
+    }
+}
@@ -2,13 +2,18 @@
 //!
 //! This module provides logging output to the framebuffer. It is disabled by
 //! default as it is very slow. Enable with the `fb-log` feature flag.
+//!
+//! Once the [shadow framebuffer](crate::fb_shadow) is set up, each message
+//! is drawn there and the lines it touched are copied to the screen.
 
 use core::fmt::Write;
 use log::Level;
 use spin::Mutex;
 
 use crate::coreboot::FramebufferInfo;
-use crate::framebuffer_console::{CHAR_HEIGHT, CHAR_WIDTH, Color, VGA_FONT_8X16};
+use crate::display::{self, View};
+use crate::fb_shadow::{self, DirtyRect};
+use crate::framebuffer_console::Color;
 
 /// Global framebuffer info for logging
 static FB_INFO: Mutex<Option<FramebufferInfo>> = Mutex::new(None);
@@ -32,9 +37,16 @@ pub fn set_framebuffer(fb: FramebufferInfo) {
 
 /// Log a message to the framebuffer
 pub fn log_to_framebuffer(level: Level, ts: u64, args: &core::fmt::Arguments) {
-    let Some(ref fb_info) = *FB_INFO.lock() else {
+    // A graphics mode client owns the framebuffer
+    if !crate::efi::protocols::console_control::is_text_mode() {
+        return;
+    }
+
+    let Some(ref vram) = *FB_INFO.lock() else {
         return;
     };
+    let fb_info = &fb_shadow::framebuffer(vram);
+    let mut dirty = DirtyRect::new();
 
     // Level strings for framebuffer (no ANSI)
     let (level_str_fb, level_color) = match level {
@@ -47,8 +59,8 @@ pub fn log_to_framebuffer(level: Level, ts: u64, args: &core::fmt::Arguments) {
 
     // Get cursor position
     let (mut row, mut col) = *FB_CURSOR.lock();
-    let cols = fb_info.x_resolution / CHAR_WIDTH;
-    let rows = fb_info.y_resolution / CHAR_HEIGHT;
+    let view = display::view();
+    let (cols, rows) = view.grid(fb_info);
 
     // Format the message with timestamp
     let mut buf = FormattingBuffer::new();
@@ -59,13 +71,13 @@ pub fn log_to_framebuffer(level: Level, ts: u64, args: &core::fmt::Arguments) {
     let bg = Color::new(0, 0, 0); // Black background
 
     // Clear the current line first (remove stale content)
-    clear_line(fb_info, row, cols, bg);
+    clear_line(&view, fb_info, row, cols, bg, &mut dirty);
 
     // Draw timestamp (first 9 chars: "XXXXXXXX ")
     let timestamp_color = Color::new(128, 128, 128); // Gray for timestamp
     for (i, c) in buf.as_str().chars().take(9).enumerate() {
         if col < cols {
-            draw_char_at(fb_info, c, col + i as u32, row, timestamp_color, bg);
+            unsafe { view.draw_char(fb_info, c, col + i as u32, row, timestamp_color, bg) };
         }
     }
     col += 9;
@@ -73,7 +85,7 @@ pub fn log_to_framebuffer(level: Level, ts: u64, args: &core::fmt::Arguments) {
     // Draw level with color (skip the space after timestamp)
     for (i, c) in level_str_fb.chars().enumerate() {
         if col < cols {
-            draw_char_at(fb_info, c, col + 1 + i as u32, row, level_color, bg);
+            unsafe { view.draw_char(fb_info, c, col + 1 + i as u32, row, level_color, bg) };
         }
     }
     col += 7; // "[LEVEL]"
@@ -85,19 +97,23 @@ pub fn log_to_framebuffer(level: Level, ts: u64, args: &core::fmt::Arguments) {
             col = 0;
             row += 1;
             if row >= rows {
-                // Wrap around to top
+                // Wrap around to top, flushing the bottom first so the
+                // dirty area doesn't grow to the whole screen
                 row = 0;
+                fb_shadow::flush(fb_info, core::mem::take(&mut dirty));
             }
             // Clear the new line before writing
-            clear_line(fb_info, row, cols, bg);
+            clear_line(&view, fb_info, row, cols, bg, &mut dirty);
             if c == '\n' {
                 continue;
             }
         }
-        draw_char_at(fb_info, c, col, row, fg, bg);
+        unsafe { view.draw_char(fb_info, c, col, row, fg, bg) };
         col += 1;
     }
 
+    fb_shadow::flush(fb_info, dirty);
+
     // Move to next line
     col = 0;
     row += 1;
@@ -109,50 +125,20 @@ pub fn log_to_framebuffer(level: Level, ts: u64, args: &core::fmt::Arguments) {
     *FB_CURSOR.lock() = (row, col);
 }
 
-/// Clear a line on the framebuffer
-fn clear_line(fb: &FramebufferInfo, row: u32, cols: u32, bg: Color) {
-    let y_start = row * CHAR_HEIGHT;
-    for y in y_start..(y_start + CHAR_HEIGHT) {
-        for x in 0..(cols * CHAR_WIDTH) {
-            unsafe {
-                fb.write_pixel(x, y, bg.r, bg.g, bg.b);
-            }
-        }
-    }
-}
-
-/// Draw a character at a specific position on the framebuffer
-fn draw_char_at(fb: &FramebufferInfo, c: char, col: u32, row: u32, fg: Color, bg: Color) {
-    let x_base = col * CHAR_WIDTH;
-    let y_base = row * CHAR_HEIGHT;
-
-    let glyph = get_glyph(c);
-
-    for glyph_row in 0..CHAR_HEIGHT {
-        let bits = glyph[glyph_row as usize];
-        for glyph_col in 0..CHAR_WIDTH {
-            let pixel_set = (bits >> (7 - glyph_col)) & 1 != 0;
-            let (r, g, b) = if pixel_set {
-                (fg.r, fg.g, fg.b)
-            } else {
-                (bg.r, bg.g, bg.b)
-            };
-
-            unsafe {
-                fb.write_pixel(x_base + glyph_col, y_base + glyph_row, r, g, b);
-            }
-        }
-    }
-}
-
-/// Get glyph data for a character (simplified - just use '?' for non-ASCII)
-fn get_glyph(c: char) -> &'static [u8; 16] {
-    let index = c as usize;
-    if index < 256 {
-        &VGA_FONT_8X16[index]
-    } else {
-        &VGA_FONT_8X16[b'?' as usize]
-    }
+/// Clear a line on the framebuffer and add it to `dirty`
+fn clear_line(
+    view: &View,
+    fb: &FramebufferInfo,
+    row: u32,
+    cols: u32,
+    bg: Color,
+    dirty: &mut DirtyRect,
+) {
+    let (cell_width, cell_height) = view.cell();
+    let (x, y, width, height) =
+        view.physical_rect(fb, 0, row * cell_height, cols * cell_width, cell_height);
+    dirty.add(x, y, width, height);
+    unsafe { view.fill(fb, 0, row * cell_height, cols * cell_width, cell_height, bg) };
 }
 
 /// Small formatting buffer for log messages
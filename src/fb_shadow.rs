@@ -0,0 +1,205 @@
+//! Shadow framebuffer
+//!
+//! The framebuffer is write-combining or even uncached VRAM, where pixel
+//! by pixel writes are slow and reads, as in scrolling, are slower still.
+//! The boot menu and framebuffer logging therefore draw on a copy of the
+//! framebuffer in RAM and track the rectangle they changed in a
+//! [`DirtyRect`]. [`flush`] copies just that rectangle to VRAM, a scanline
+//! at a time with SSE non-temporal stores.
+//!
+//! The EFI text console and GOP still write to VRAM directly: OS loaders
+//! draw on the framebuffer behind the firmware's back, so the shadow can't
+//! be the only copy of the screen. At ExitBootServices the shadow is
+//! dropped, as its pages now belong to the OS.
+
+use r_efi::efi;
+use spin::Mutex;
+
+use crate::arch::x86_64::sse;
+use crate::coreboot::FramebufferInfo;
+use crate::efi::allocator::{self, AllocateType, MemoryType, PAGE_SIZE};
+
+/// The shadow of the coreboot framebuffer
+struct Shadow {
+    /// Address of the framebuffer in VRAM
+    vram: u64,
+    /// Address of the copy in RAM, same layout as VRAM
+    buffer: u64,
+}
+
+/// Shadow framebuffer, if set up
+static SHADOW: Mutex<Option<Shadow>> = Mutex::new(None);
+
+/// Pixel area changed since the last flush
+///
+/// Coordinates are in pixels, `x1` and `y1` exclusive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DirtyRect {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+}
+
+impl DirtyRect {
+    /// An empty rectangle
+    pub const fn new() -> Self {
+        DirtyRect {
+            x0: 0,
+            y0: 0,
+            x1: 0,
+            y1: 0,
+        }
+    }
+
+    /// Whether nothing changed
+    pub fn is_empty(&self) -> bool {
+        self.x0 >= self.x1 || self.y0 >= self.y1
+    }
+
+    /// Grow to include a `width` by `height` area at (x, y)
+    pub fn add(&mut self, x: u32, y: u32, width: u32, height: u32) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        let (x1, y1) = (x.saturating_add(width), y.saturating_add(height));
+        if self.is_empty() {
+            *self = DirtyRect {
+                x0: x,
+                y0: y,
+                x1,
+                y1,
+            };
+        } else {
+            self.x0 = self.x0.min(x);
+            self.y0 = self.y0.min(y);
+            self.x1 = self.x1.max(x1);
+            self.y1 = self.y1.max(y1);
+        }
+    }
+}
+
+/// Set up the shadow of `fb`, starting with what is on screen
+///
+/// Needs the EFI allocator. Without memory for the shadow, drawing keeps
+/// going to VRAM directly.
+pub fn init(fb: &FramebufferInfo) {
+    let num_pages = fb.size().div_ceil(PAGE_SIZE);
+    let mut buffer = 0;
+    let status = allocator::allocate_pages(
+        AllocateType::AllocateAnyPages,
+        MemoryType::BootServicesData,
+        num_pages,
+        &mut buffer,
+    );
+    if status != efi::Status::SUCCESS {
+        log::warn!("No memory for a shadow framebuffer: {:?}", status);
+        return;
+    }
+    unsafe {
+        core::ptr::copy_nonoverlapping(fb.as_ptr(), buffer as *mut u8, fb.size() as usize);
+    }
+    *SHADOW.lock() = Some(Shadow {
+        vram: fb.physical_address,
+        buffer,
+    });
+    log::info!(
+        "Shadow framebuffer: {} KB at {:#x}",
+        fb.size() / 1024,
+        buffer
+    );
+}
+
+/// Stop using the shadow, at ExitBootServices
+pub fn disable() {
+    *SHADOW.lock() = None;
+}
+
+/// Framebuffer to draw on instead of `fb`
+///
+/// This is the shadow of `fb` if there is one, otherwise `fb` itself. Draw
+/// on the result, then [`flush`] the changed area.
+pub fn framebuffer(fb: &FramebufferInfo) -> FramebufferInfo {
+    let mut target = fb.clone();
+    // Don't wait on a lock held by code that crashed while flushing
+    if let Some(shadow) = SHADOW.try_lock()
+        && let Some(shadow) = shadow.as_ref()
+        && shadow.vram == fb.physical_address
+    {
+        target.physical_address = shadow.buffer;
+    }
+    target
+}
+
+/// Copy the `dirty` area of `fb` to VRAM
+///
+/// `fb` is what [`framebuffer`] returned; if that is VRAM itself, there is
+/// nothing to do.
+pub fn flush(fb: &FramebufferInfo, dirty: DirtyRect) {
+    if dirty.is_empty() {
+        return;
+    }
+    let guard = SHADOW.lock();
+    let Some(shadow) = guard.as_ref() else {
+        return;
+    };
+    if shadow.buffer != fb.physical_address {
+        return;
+    }
+
+    let x1 = dirty.x1.min(fb.x_resolution);
+    let y1 = dirty.y1.min(fb.y_resolution);
+    if dirty.x0 >= x1 || dirty.y0 >= y1 {
+        return;
+    }
+    let bytes_per_pixel = (fb.bits_per_pixel / 8) as usize;
+    let len = (x1 - dirty.x0) as usize * bytes_per_pixel;
+    for y in dirty.y0..y1 {
+        let offset = fb.pixel_offset(dirty.x0, y) as u64;
+        unsafe {
+            sse::copy_to_write_combining(
+                (shadow.vram + offset) as *mut u8,
+                (shadow.buffer + offset) as *const u8,
+                len,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dirty_rect_union() {
+        let mut dirty = DirtyRect::new();
+        assert!(dirty.is_empty());
+        dirty.add(10, 10, 0, 16);
+        assert!(dirty.is_empty());
+
+        dirty.add(16, 32, 8, 16);
+        dirty.add(0, 0, 8, 16);
+        assert_eq!(
+            dirty,
+            DirtyRect {
+                x0: 0,
+                y0: 0,
+                x1: 24,
+                y1: 48
+            }
+        );
+    }
+
+    #[test]
+    fn write_combining_copy() {
+        let src: [u8; 300] = core::array::from_fn(|i| i as u8);
+        for (offset, len) in [(0, 0), (1, 15), (3, 64), (5, 200), (16, 256), (7, 290)] {
+            let mut dst = [0u8; 300];
+            unsafe {
+                sse::copy_to_write_combining(dst.as_mut_ptr().add(offset), src.as_ptr(), len);
+            }
+            assert_eq!(&dst[offset..offset + len], &src[..len]);
+            assert!(dst[offset + len..].iter().all(|&byte| byte == 0));
+        }
+    }
+}
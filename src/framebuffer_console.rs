@@ -3,8 +3,15 @@
 //! This module provides text rendering on the coreboot framebuffer using an
 //! embedded 8x16 VGA bitmap font. It supports basic text output with colors,
 //! cursor positioning, and scrolling.
+//!
+//! Drawing on the [shadow framebuffer](crate::fb_shadow) shows up on screen
+//! once the console is flushed. The text is rotated and scaled as the
+//! [display settings](crate::display) say.
 
 use crate::coreboot::framebuffer::FramebufferInfo;
+use crate::display::{self, View};
+use crate::fb_shadow::{self, DirtyRect};
+use core::cell::Cell;
 use core::fmt::{self, Write};
 
 /// Character width in pixels
@@ -57,6 +64,8 @@ impl Color {
 pub struct FramebufferConsole<'a> {
     /// Reference to the framebuffer info
     fb: &'a FramebufferInfo,
+    /// Rotation and font scale
+    view: View,
     /// Current cursor column (0-based)
     cursor_col: u32,
     /// Current cursor row (0-based)
@@ -69,6 +78,8 @@ pub struct FramebufferConsole<'a> {
     fg_color: Color,
     /// Current background color
     bg_color: Color,
+    /// Area drawn on since the last flush
+    dirty: Cell<DirtyRect>,
 }
 
 impl<'a> FramebufferConsole<'a> {
@@ -78,17 +89,19 @@ impl<'a> FramebufferConsole<'a> {
     ///
     /// * `fb` - Reference to the framebuffer info from coreboot
     pub fn new(fb: &'a FramebufferInfo) -> Self {
-        let cols = fb.x_resolution / CHAR_WIDTH;
-        let rows = fb.y_resolution / CHAR_HEIGHT;
+        let view = display::view();
+        let (cols, rows) = view.grid(fb);
 
         FramebufferConsole {
             fb,
+            view,
             cursor_col: 0,
             cursor_row: 0,
             cols,
             rows,
             fg_color: DEFAULT_FG,
             bg_color: DEFAULT_BG,
+            dirty: Cell::new(DirtyRect::new()),
         }
     }
 
@@ -135,12 +148,27 @@ impl<'a> FramebufferConsole<'a> {
         self.bg_color = DEFAULT_BG;
     }
 
+    /// Mark a pixel area of the rotated screen as drawn on
+    fn mark_dirty(&self, x: u32, y: u32, width: u32, height: u32) {
+        let (x, y, width, height) = self.view.physical_rect(self.fb, x, y, width, height);
+        let mut dirty = self.dirty.get();
+        dirty.add(x, y, width, height);
+        self.dirty.set(dirty);
+    }
+
+    /// Copy what was drawn since the last flush to the screen
+    pub fn flush(&self) {
+        fb_shadow::flush(self.fb, self.dirty.take());
+    }
+
     /// Clear the entire screen with the current background color
     pub fn clear(&mut self) {
         unsafe {
             self.fb
                 .clear(self.bg_color.r, self.bg_color.g, self.bg_color.b);
         }
+        let (width, height) = self.view.size(self.fb);
+        self.mark_dirty(0, 0, width, height);
         self.cursor_col = 0;
         self.cursor_row = 0;
     }
@@ -151,14 +179,18 @@ impl<'a> FramebufferConsole<'a> {
             return;
         }
 
-        let y_start = row * CHAR_HEIGHT;
-        for y in y_start..(y_start + CHAR_HEIGHT) {
-            for x in 0..self.fb.x_resolution {
-                unsafe {
-                    self.fb
-                        .write_pixel(x, y, self.bg_color.r, self.bg_color.g, self.bg_color.b);
-                }
-            }
+        let (width, _) = self.view.size(self.fb);
+        let (_, cell_height) = self.view.cell();
+        self.mark_dirty(0, row * cell_height, width, cell_height);
+        unsafe {
+            self.view.fill(
+                self.fb,
+                0,
+                row * cell_height,
+                width,
+                cell_height,
+                self.bg_color,
+            );
         }
     }
 
@@ -215,27 +247,13 @@ impl<'a> FramebufferConsole<'a> {
 
     /// Draw a character at the current cursor position (without advancing)
     fn draw_char(&self, c: char) {
-        let x_base = self.cursor_col * CHAR_WIDTH;
-        let y_base = self.cursor_row * CHAR_HEIGHT;
-
-        // Get glyph data for this character
-        let glyph = get_glyph(c);
-
-        for row in 0..CHAR_HEIGHT {
-            let bits = glyph[row as usize];
-            for col in 0..CHAR_WIDTH {
-                let pixel_set = (bits >> (7 - col)) & 1 != 0;
-                let (r, g, b) = if pixel_set {
-                    (self.fg_color.r, self.fg_color.g, self.fg_color.b)
-                } else {
-                    (self.bg_color.r, self.bg_color.g, self.bg_color.b)
-                };
-
-                unsafe {
-                    self.fb.write_pixel(x_base + col, y_base + row, r, g, b);
-                }
-            }
-        }
+        self.draw_char_at(
+            c,
+            self.cursor_col,
+            self.cursor_row,
+            self.fg_color,
+            self.bg_color,
+        );
     }
 
     /// Draw a character at a specific position (without affecting cursor)
@@ -244,50 +262,18 @@ impl<'a> FramebufferConsole<'a> {
             return;
         }
 
-        let x_base = col * CHAR_WIDTH;
-        let y_base = row * CHAR_HEIGHT;
-
-        let glyph = get_glyph(c);
-
-        for glyph_row in 0..CHAR_HEIGHT {
-            let bits = glyph[glyph_row as usize];
-            for glyph_col in 0..CHAR_WIDTH {
-                let pixel_set = (bits >> (7 - glyph_col)) & 1 != 0;
-                let (r, g, b) = if pixel_set {
-                    (fg.r, fg.g, fg.b)
-                } else {
-                    (bg.r, bg.g, bg.b)
-                };
-
-                unsafe {
-                    self.fb
-                        .write_pixel(x_base + glyph_col, y_base + glyph_row, r, g, b);
-                }
-            }
-        }
+        let (cell_width, cell_height) = self.view.cell();
+        self.mark_dirty(col * cell_width, row * cell_height, cell_width, cell_height);
+        unsafe { self.view.draw_char(self.fb, c, col, row, fg, bg) };
     }
 
     /// Scroll the screen up by one line
     pub fn scroll_up(&mut self) {
-        // This is a simple but slow implementation - copy pixel by pixel
-        // A faster version would use memcpy on scanlines
-        let bytes_per_pixel = (self.fb.bits_per_pixel / 8) as usize;
-        let line_height = CHAR_HEIGHT as usize;
-        let scanline_bytes = self.fb.bytes_per_line as usize;
-        let copy_height = ((self.rows - 1) * CHAR_HEIGHT) as usize;
-
-        unsafe {
-            let fb_ptr = self.fb.as_ptr();
-
-            // Copy each scanline up by one character height
-            for y in 0..copy_height {
-                let src_offset = (y + line_height) * scanline_bytes;
-                let dst_offset = y * scanline_bytes;
-                let line_bytes = self.fb.x_resolution as usize * bytes_per_pixel;
-
-                core::ptr::copy(fb_ptr.add(src_offset), fb_ptr.add(dst_offset), line_bytes);
-            }
-        }
+        let (width, _) = self.view.size(self.fb);
+        let (_, cell_height) = self.view.cell();
+        let height = self.rows * cell_height;
+        self.mark_dirty(0, 0, width, height - cell_height);
+        unsafe { self.view.scroll_up(self.fb, 0, height, cell_height) };
 
         // Clear the last line
         self.clear_line(self.rows - 1);
@@ -341,7 +327,7 @@ impl<'a> Write for FramebufferConsole<'a> {
 /// Get the glyph data for a character
 ///
 /// Returns a reference to 16 bytes representing the 8x16 bitmap for the character.
-fn get_glyph(c: char) -> &'static [u8; 16] {
+pub(crate) fn get_glyph(c: char) -> &'static [u8; 16] {
     let index = c as usize;
     if index < 256 {
         &VGA_FONT_8X16[index]
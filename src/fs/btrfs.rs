@@ -0,0 +1,1298 @@
+//! btrfs filesystem driver
+//!
+//! Read-only support for btrfs, so kernels and loader entries can be read
+//! from a root filesystem without a separate `/boot` partition, as openSUSE
+//! and Fedora lay out their systems. Paths are looked up in the default
+//! subvolume, the one the system mounts as `/`, and cross into the
+//! subvolumes below it.
+//!
+//! Chunks are mapped on demand from the chunk tree, keeping the system
+//! chunks and a few recently used ones. Only the stripes on this device are
+//! read, so single, DUP and RAID1 profiles work while striped profiles
+//! (RAID0, RAID10, RAID5/6) are not supported. Inline and regular extents
+//! compressed with zlib or zstd are read; LZO is not supported. Checksums
+//! are not checked and the log tree is not replayed.
+//!
+//! Reference: btrfs on-disk format, Linux `fs/btrfs`
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use heapless::{String, Vec};
+use spin::Mutex;
+
+use crate::compression::{self, DecompressError, Format};
+use crate::drivers::block::BlockDevice;
+use crate::fs::normalize_path;
+
+/// Offset of the primary superblock
+const SUPERBLOCK_OFFSET: u64 = 0x10000;
+
+/// Size of the superblock
+const SUPERBLOCK_SIZE: usize = 4096;
+
+/// Magic number in the superblock
+const MAGIC: &[u8; 8] = b"_BHRfS_M";
+
+/// Largest tree node
+const MAX_NODE_SIZE: usize = 65536;
+
+/// Largest device block read through the bounce buffer
+const MAX_DEVICE_BLOCK_SIZE: usize = 4096;
+
+/// Largest system chunk array
+const MAX_SYS_CHUNK_ARRAY_SIZE: usize = 2048;
+
+/// Chunks kept mapped, the system chunks included
+const MAX_CHUNKS: usize = 16;
+
+/// Deepest tree
+const MAX_LEVEL: u8 = 8;
+
+/// Largest compressed extent, before and after decompression
+const MAX_COMPRESSED_SIZE: usize = 128 * 1024;
+
+/// Tree node layout
+const NODE_HEADER_SIZE: usize = 101;
+const KEY_SIZE: usize = 17;
+const KEY_PTR_SIZE: usize = 33;
+const ITEM_SIZE: usize = 25;
+
+/// Object IDs
+const FS_TREE_OBJECTID: u64 = 5;
+const ROOT_TREE_DIR_OBJECTID: u64 = 6;
+const FIRST_CHUNK_TREE_OBJECTID: u64 = 256;
+
+/// Item types
+const INODE_ITEM: u8 = 1;
+const DIR_ITEM: u8 = 84;
+const DIR_INDEX: u8 = 96;
+const EXTENT_DATA: u8 = 108;
+const ROOT_ITEM: u8 = 132;
+const CHUNK_ITEM: u8 = 228;
+
+/// Block group profiles that stripe data across devices
+const STRIPED_PROFILES: u64 = 0x8 | 0x40 | 0x80 | 0x100;
+
+/// Incompatible features that change what is read
+const INCOMPAT_EXTENT_TREE_V2: u64 = 1 << 13;
+const INCOMPAT_RAID_STRIPE_TREE: u64 = 1 << 14;
+
+/// Size of the header of a directory item
+const DIR_ITEM_HEADER_SIZE: usize = 30;
+
+/// Size of an inode item
+const INODE_ITEM_SIZE: usize = 160;
+
+/// Offsets in a root item
+const ROOT_ITEM_DIRID: usize = 168;
+const ROOT_ITEM_BYTENR: usize = 176;
+const ROOT_ITEM_LEVEL: usize = 238;
+
+/// Extent types
+const EXTENT_INLINE: u8 = 0;
+const EXTENT_PREALLOC: u8 = 2;
+
+/// Size of the header of an extent item, the data of inline extents
+/// follows it
+const EXTENT_HEADER_SIZE: usize = 21;
+
+/// Size of a regular extent item
+const EXTENT_REGULAR_SIZE: usize = 53;
+
+/// Compression types
+const COMPRESS_NONE: u8 = 0;
+const COMPRESS_ZLIB: u8 = 1;
+const COMPRESS_ZSTD: u8 = 3;
+
+/// Directory entry file types
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+/// Inode mode file types
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Longest file name
+const MAX_NAME_LEN: usize = 255;
+
+/// Longest path, including the targets of symbolic links
+const MAX_PATH_LEN: usize = 512;
+
+/// Most symbolic links followed while looking up a path
+const MAX_SYMLINKS: usize = 8;
+
+/// btrfs error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BtrfsError {
+    /// No btrfs superblock
+    NotBtrfs,
+    /// Feature, profile or compression not supported
+    Unsupported,
+    /// Read error
+    ReadError,
+    /// Inconsistent trees or data that fails to decompress
+    Corrupt,
+    /// File not found
+    NotFound,
+    /// Not a file
+    NotAFile,
+    /// Not a directory
+    NotADirectory,
+    /// Too many symbolic links or a path too long
+    TooManyLinks,
+}
+
+impl From<DecompressError> for BtrfsError {
+    fn from(e: DecompressError) -> Self {
+        match e {
+            DecompressError::Unsupported => BtrfsError::Unsupported,
+            _ => BtrfsError::Corrupt,
+        }
+    }
+}
+
+/// Read a little-endian u16 at `offset`
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Read a little-endian u32 at `offset`
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap_or_default())
+}
+
+/// Read a little-endian u64 at `offset`
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap_or_default())
+}
+
+/// CRC-32C without the final inversion, as btrfs hashes names
+fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82F6_3B78 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+/// Hash of a name, the offset of its directory item key
+pub fn name_hash(name: &[u8]) -> u64 {
+    crc32c(!1, name) as u64
+}
+
+/// Key of a tree item, ordered by its fields in turn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    objectid: u64,
+    item_type: u8,
+    offset: u64,
+}
+
+impl Key {
+    fn new(objectid: u64, item_type: u8, offset: u64) -> Self {
+        Key {
+            objectid,
+            item_type,
+            offset,
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Self {
+        Key::new(u64_at(bytes, 0), bytes[8], u64_at(bytes, 9))
+    }
+}
+
+/// Root node of a tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Tree {
+    bytenr: u64,
+    level: u8,
+}
+
+/// A chunk, mapping logical addresses to this device
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    logical: u64,
+    length: u64,
+    physical: u64,
+}
+
+impl Chunk {
+    /// Parse a chunk item, picking the stripe on device `devid`
+    fn parse(logical: u64, item: &[u8], devid: u64) -> Result<Self, BtrfsError> {
+        if item.len() < 48 {
+            return Err(BtrfsError::Corrupt);
+        }
+        if u64_at(item, 24) & STRIPED_PROFILES != 0 {
+            log::warn!("btrfs: striped chunk at {:#x} is not supported", logical);
+            return Err(BtrfsError::Unsupported);
+        }
+        let stripes = u16_at(item, 44) as usize;
+        let physical = (0..stripes)
+            .filter_map(|i| item.get(48 + 32 * i..48 + 32 * i + 16))
+            .find(|stripe| u64_at(stripe, 0) == devid)
+            .map(|stripe| u64_at(stripe, 8))
+            .ok_or(BtrfsError::Unsupported)?;
+        Ok(Chunk {
+            logical,
+            length: u64_at(item, 0),
+            physical,
+        })
+    }
+
+    fn contains(&self, logical: u64) -> bool {
+        logical >= self.logical && logical - self.logical < self.length
+    }
+}
+
+/// An item in the leaf in the node buffer
+#[derive(Debug, Clone, Copy)]
+struct Item {
+    key: Key,
+    /// Range of its data in the node buffer
+    start: usize,
+    len: usize,
+}
+
+/// Kind of an inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeKind {
+    Directory,
+    File,
+    Symlink,
+    /// Device, FIFO or socket
+    Other,
+}
+
+/// An inode
+#[derive(Debug, Clone, Copy)]
+pub struct Inode {
+    /// Kind of the inode
+    pub kind: InodeKind,
+    /// Size of a file or of the target of a symbolic link
+    pub size: u64,
+    /// Subvolume tree holding the inode
+    tree: Tree,
+    /// Inode number
+    ino: u64,
+}
+
+impl Inode {
+    /// Whether this is a directory
+    pub fn is_dir(&self) -> bool {
+        self.kind == InodeKind::Directory
+    }
+
+    /// Whether this is a regular file
+    pub fn is_file(&self) -> bool {
+        self.kind == InodeKind::File
+    }
+}
+
+/// A directory entry
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    name: Vec<u8, MAX_NAME_LEN>,
+    /// Kind of the inode the entry names
+    pub kind: InodeKind,
+    /// Tree of the directory
+    tree: Tree,
+    /// Key of the inode, or of the root of a subvolume
+    location: Key,
+}
+
+impl DirEntry {
+    /// The name, or `?` if it isn't UTF-8
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name).unwrap_or("?")
+    }
+
+    fn parse(tree: Tree, item: &[u8]) -> Result<Self, BtrfsError> {
+        let header = item
+            .get(..DIR_ITEM_HEADER_SIZE)
+            .ok_or(BtrfsError::Corrupt)?;
+        let name_len = u16_at(header, 27) as usize;
+        let name = item
+            .get(DIR_ITEM_HEADER_SIZE..DIR_ITEM_HEADER_SIZE + name_len)
+            .and_then(|name| Vec::from_slice(name).ok())
+            .ok_or(BtrfsError::Corrupt)?;
+        Ok(DirEntry {
+            name,
+            kind: match header[29] {
+                FT_REG_FILE => InodeKind::File,
+                FT_DIR => InodeKind::Directory,
+                FT_SYMLINK => InodeKind::Symlink,
+                _ => InodeKind::Other,
+            },
+            tree,
+            location: Key::parse(header),
+        })
+    }
+}
+
+/// A file extent item
+#[derive(Debug, Clone, Copy)]
+struct Extent {
+    /// Offset in the file
+    file_offset: u64,
+    /// Bytes of the file it covers
+    len: u64,
+    /// Size once decompressed
+    ram_bytes: u64,
+    compression: u8,
+    kind: ExtentKind,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ExtentKind {
+    /// Data in the leaf, at this range of the node buffer
+    Inline { start: usize, len: usize },
+    /// Data on disk; `offset` is where the file's part starts once
+    /// decompressed
+    Regular {
+        disk_bytenr: u64,
+        disk_len: u64,
+        offset: u64,
+    },
+    /// Unwritten or a hole
+    Zero,
+}
+
+impl Extent {
+    fn parse(item: &Item, node: &[u8]) -> Result<Self, BtrfsError> {
+        let data = &node[item.start..item.start + item.len];
+        let header = data.get(..EXTENT_HEADER_SIZE).ok_or(BtrfsError::Corrupt)?;
+        if header[17] != 0 || u16_at(header, 18) != 0 {
+            // Encryption or another encoding
+            return Err(BtrfsError::Unsupported);
+        }
+        let ram_bytes = u64_at(header, 8);
+        let (len, kind) = if header[20] == EXTENT_INLINE {
+            let start = item.start + EXTENT_HEADER_SIZE;
+            let len = item.len - EXTENT_HEADER_SIZE;
+            (ram_bytes, ExtentKind::Inline { start, len })
+        } else {
+            if data.len() < EXTENT_REGULAR_SIZE {
+                return Err(BtrfsError::Corrupt);
+            }
+            let disk_bytenr = u64_at(data, 21);
+            let kind = if disk_bytenr == 0 || header[20] == EXTENT_PREALLOC {
+                ExtentKind::Zero
+            } else {
+                ExtentKind::Regular {
+                    disk_bytenr,
+                    disk_len: u64_at(data, 29),
+                    offset: u64_at(data, 37),
+                }
+            };
+            (u64_at(data, 45), kind)
+        };
+        Ok(Extent {
+            file_offset: item.key.offset,
+            len,
+            ram_bytes,
+            compression: header[16],
+            kind,
+        })
+    }
+}
+
+/// The last decompressed extent, shared by all filesystems
+struct ExtentCache {
+    /// Filesystem the extent belongs to, 0 for none
+    owner: u32,
+    /// Logical address of the extent
+    location: u64,
+    /// Decompressed size
+    len: usize,
+    data: [u8; MAX_COMPRESSED_SIZE],
+    /// Compressed extent as read from the device
+    input: [u8; MAX_COMPRESSED_SIZE],
+}
+
+static CACHE: Mutex<ExtentCache> = Mutex::new(ExtentCache {
+    owner: 0,
+    location: 0,
+    len: 0,
+    data: [0; MAX_COMPRESSED_SIZE],
+    input: [0; MAX_COMPRESSED_SIZE],
+});
+
+/// ID of the next filesystem mounted, for the extent cache
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Decoder of a btrfs compression type
+fn compression_format(compression: u8) -> Result<Format, BtrfsError> {
+    match compression {
+        COMPRESS_ZLIB => Ok(Format::Zlib),
+        COMPRESS_ZSTD => Ok(Format::Zstd),
+        other => {
+            log::warn!("btrfs: compression {} is not supported", other);
+            Err(BtrfsError::Unsupported)
+        }
+    }
+}
+
+/// Index of the first of `count` entries for which `below` is false
+fn partition_point(count: usize, below: impl Fn(usize) -> bool) -> usize {
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if below(mid) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+/// Read bytes at byte `position` of `device`
+fn read_device(
+    device: &mut dyn BlockDevice,
+    mut position: u64,
+    buffer: &mut [u8],
+) -> Result<(), BtrfsError> {
+    let block_size = device.info().block_size as usize;
+    let mut bounce = [0u8; MAX_DEVICE_BLOCK_SIZE];
+    let mut done = 0;
+
+    while done < buffer.len() {
+        let lba = position / block_size as u64;
+        let within = (position % block_size as u64) as usize;
+        let remaining = buffer.len() - done;
+        let len = if within == 0 && remaining >= block_size {
+            // Whole blocks go straight to the buffer
+            let count = remaining / block_size;
+            let len = count * block_size;
+            device
+                .read_blocks(lba, count as u32, &mut buffer[done..done + len])
+                .map_err(|_| BtrfsError::ReadError)?;
+            len
+        } else {
+            device
+                .read_blocks(lba, 1, &mut bounce[..block_size])
+                .map_err(|_| BtrfsError::ReadError)?;
+            let len = (block_size - within).min(remaining);
+            buffer[done..done + len].copy_from_slice(&bounce[within..within + len]);
+            len
+        };
+        done += len;
+        position += len as u64;
+    }
+    Ok(())
+}
+
+/// btrfs filesystem instance
+pub struct Btrfs<'a> {
+    /// Block device
+    device: &'a mut dyn BlockDevice,
+    /// Byte offset of the filesystem on the device
+    start: u64,
+    /// ID of this device in the filesystem
+    devid: u64,
+    /// Tree node size
+    node_size: usize,
+    /// Mapped chunks, the system chunks first
+    chunks: Vec<Chunk, MAX_CHUNKS>,
+    system_chunks: usize,
+    chunk_tree: Tree,
+    root_tree: Tree,
+    /// Default subvolume and its root directory
+    fs_tree: Tree,
+    root_dir: u64,
+    /// ID for the extent cache
+    id: u32,
+    /// Node in the buffer, and the tree it belongs to if it is a leaf
+    node_bytenr: Option<u64>,
+    leaf_tree: Option<Tree>,
+    node: [u8; MAX_NODE_SIZE],
+}
+
+impl<'a> Btrfs<'a> {
+    /// Mount the btrfs starting at block `partition_start` of `device`
+    pub fn new(device: &'a mut dyn BlockDevice, partition_start: u64) -> Result<Self, BtrfsError> {
+        let device_block_size = device.info().block_size as u64;
+        if device_block_size == 0 || device_block_size > MAX_DEVICE_BLOCK_SIZE as u64 {
+            return Err(BtrfsError::Unsupported);
+        }
+        let mut fs = Btrfs {
+            device,
+            start: partition_start * device_block_size,
+            devid: 0,
+            node_size: 0,
+            chunks: Vec::new(),
+            system_chunks: 0,
+            chunk_tree: Tree {
+                bytenr: 0,
+                level: 0,
+            },
+            root_tree: Tree {
+                bytenr: 0,
+                level: 0,
+            },
+            fs_tree: Tree {
+                bytenr: 0,
+                level: 0,
+            },
+            root_dir: 0,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            node_bytenr: None,
+            leaf_tree: None,
+            node: [0; MAX_NODE_SIZE],
+        };
+
+        let mut superblock = [0u8; SUPERBLOCK_SIZE];
+        fs.read_physical(SUPERBLOCK_OFFSET, &mut superblock)?;
+        if &superblock[0x40..0x48] != MAGIC {
+            return Err(BtrfsError::NotBtrfs);
+        }
+        let incompat = u64_at(&superblock, 0xBC);
+        if incompat & (INCOMPAT_EXTENT_TREE_V2 | INCOMPAT_RAID_STRIPE_TREE) != 0 {
+            log::warn!("btrfs: incompatible features {:#x}", incompat);
+            return Err(BtrfsError::Unsupported);
+        }
+        fs.node_size = u32_at(&superblock, 0x94) as usize;
+        if !fs.node_size.is_power_of_two() || !(4096..=MAX_NODE_SIZE).contains(&fs.node_size) {
+            return Err(BtrfsError::Unsupported);
+        }
+        fs.devid = u64_at(&superblock, 0xC9);
+        fs.root_tree = Tree {
+            bytenr: u64_at(&superblock, 0x50),
+            level: superblock[0xC6],
+        };
+        fs.chunk_tree = Tree {
+            bytenr: u64_at(&superblock, 0x58),
+            level: superblock[0xC7],
+        };
+
+        // System chunks, which hold the chunk tree
+        let array_size = u32_at(&superblock, 0xA0) as usize;
+        let array = superblock[0x32B..]
+            .get(..array_size)
+            .filter(|_| array_size <= MAX_SYS_CHUNK_ARRAY_SIZE)
+            .ok_or(BtrfsError::Corrupt)?;
+        let mut offset = 0;
+        while offset < array.len() {
+            let entry = &array[offset..];
+            let key = Key::parse(entry.get(..KEY_SIZE).ok_or(BtrfsError::Corrupt)?);
+            let item = &entry[KEY_SIZE..];
+            if key.item_type != CHUNK_ITEM || item.len() < 48 {
+                return Err(BtrfsError::Corrupt);
+            }
+            let len = 48 + 32 * u16_at(item, 44) as usize;
+            let item = item.get(..len).ok_or(BtrfsError::Corrupt)?;
+            let chunk = Chunk::parse(key.offset, item, fs.devid)?;
+            fs.chunks.push(chunk).map_err(|_| BtrfsError::Unsupported)?;
+            offset += KEY_SIZE + len;
+        }
+        fs.system_chunks = fs.chunks.len();
+        if fs.chunks.is_full() {
+            return Err(BtrfsError::Unsupported);
+        }
+
+        // The default subvolume is named by the `default` entry of the root
+        // tree directory
+        let key = Key::new(ROOT_TREE_DIR_OBJECTID, DIR_ITEM, name_hash(b"default"));
+        let subvolume = match fs.find_dir_item(fs.root_tree, key, b"default")? {
+            Some(entry) => entry.location.objectid,
+            None => FS_TREE_OBJECTID,
+        };
+        (fs.fs_tree, fs.root_dir) = fs.subvolume(subvolume)?;
+
+        log::debug!(
+            "btrfs: {} byte nodes, default subvolume {}",
+            fs.node_size,
+            subvolume
+        );
+        Ok(fs)
+    }
+
+    /// Read bytes at `offset` from the start of the filesystem
+    fn read_physical(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), BtrfsError> {
+        read_device(self.device, self.start + offset, buffer)
+    }
+
+    /// The chunk holding `logical`, looking it up in the chunk tree unless
+    /// `system` restricts it to the system chunks
+    fn chunk(&mut self, logical: u64, system: bool) -> Result<Chunk, BtrfsError> {
+        if let Some(chunk) = self.chunks.iter().find(|chunk| chunk.contains(logical)) {
+            return Ok(*chunk);
+        }
+        if system {
+            return Err(BtrfsError::Corrupt);
+        }
+
+        let key = Key::new(FIRST_CHUNK_TREE_OBJECTID, CHUNK_ITEM, logical);
+        let item = self
+            .prev(self.chunk_tree, key, true)?
+            .filter(|item| item.key.item_type == CHUNK_ITEM)
+            .ok_or(BtrfsError::Corrupt)?;
+        let data = &self.node[item.start..item.start + item.len];
+        let chunk = Chunk::parse(item.key.offset, data, self.devid)?;
+        if !chunk.contains(logical) {
+            return Err(BtrfsError::Corrupt);
+        }
+        if self.chunks.is_full() {
+            self.chunks.remove(self.system_chunks);
+        }
+        let _ = self.chunks.push(chunk);
+        Ok(chunk)
+    }
+
+    /// Read bytes at logical address `logical`
+    fn read_logical(
+        &mut self,
+        mut logical: u64,
+        buffer: &mut [u8],
+        system: bool,
+    ) -> Result<(), BtrfsError> {
+        let mut done = 0;
+        while done < buffer.len() {
+            let chunk = self.chunk(logical, system)?;
+            let within = logical - chunk.logical;
+            let len = (chunk.length - within).min((buffer.len() - done) as u64) as usize;
+            self.read_physical(chunk.physical + within, &mut buffer[done..done + len])?;
+            done += len;
+            logical += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Read the node at `bytenr` into the node buffer
+    fn read_node(&mut self, bytenr: u64, level: u8, system: bool) -> Result<(), BtrfsError> {
+        if self.node_bytenr == Some(bytenr) {
+            return Ok(());
+        }
+        self.node_bytenr = None;
+        self.leaf_tree = None;
+
+        // Nodes never cross chunks
+        let chunk = self.chunk(bytenr, system)?;
+        let within = bytenr - chunk.logical;
+        if chunk.length - within < self.node_size as u64 {
+            return Err(BtrfsError::Corrupt);
+        }
+        let position = self.start + chunk.physical + within;
+        read_device(self.device, position, &mut self.node[..self.node_size])?;
+        let entry_size = if level == 0 { ITEM_SIZE } else { KEY_PTR_SIZE };
+        if u64_at(&self.node, 0x30) != bytenr
+            || self.node[0x64] != level
+            || NODE_HEADER_SIZE + self.nritems() * entry_size > self.node_size
+        {
+            return Err(BtrfsError::Corrupt);
+        }
+        self.node_bytenr = Some(bytenr);
+        Ok(())
+    }
+
+    /// Number of items in the node buffer
+    fn nritems(&self) -> usize {
+        u32_at(&self.node, 0x60) as usize
+    }
+
+    /// Key of item `slot` of the leaf in the node buffer
+    fn leaf_key(&self, slot: usize) -> Key {
+        Key::parse(&self.node[NODE_HEADER_SIZE + slot * ITEM_SIZE..])
+    }
+
+    /// Item `slot` of the leaf in the node buffer
+    fn leaf_item(&self, slot: usize) -> Result<Item, BtrfsError> {
+        let entry = &self.node[NODE_HEADER_SIZE + slot * ITEM_SIZE..];
+        let start = NODE_HEADER_SIZE + u32_at(entry, KEY_SIZE) as usize;
+        let len = u32_at(entry, KEY_SIZE + 4) as usize;
+        if start + len > self.node_size {
+            return Err(BtrfsError::Corrupt);
+        }
+        Ok(Item {
+            key: self.leaf_key(slot),
+            start,
+            len,
+        })
+    }
+
+    /// Key of pointer `slot` of the internal node in the node buffer
+    fn pointer_key(&self, slot: usize) -> Key {
+        Key::parse(&self.node[NODE_HEADER_SIZE + slot * KEY_PTR_SIZE..])
+    }
+
+    /// Find the leaf that would hold `key`
+    ///
+    /// Leaves the leaf in the node buffer and returns the slot of its last
+    /// item not after `key`, and the first key of the next leaf if known.
+    fn search(
+        &mut self,
+        tree: Tree,
+        key: Key,
+        system: bool,
+    ) -> Result<(Option<usize>, Option<Key>), BtrfsError> {
+        let count = self.nritems();
+        let cached = self.leaf_tree == Some(tree)
+            && count > 0
+            && self.leaf_key(0) <= key
+            && key <= self.leaf_key(count - 1);
+
+        let mut next = None;
+        if !cached {
+            if tree.level > MAX_LEVEL {
+                return Err(BtrfsError::Corrupt);
+            }
+            let (mut bytenr, mut level) = (tree.bytenr, tree.level);
+            loop {
+                self.read_node(bytenr, level, system)?;
+                if level == 0 {
+                    break;
+                }
+                let count = self.nritems();
+                let slot =
+                    partition_point(count, |slot| self.pointer_key(slot) <= key).saturating_sub(1);
+                if slot + 1 < count {
+                    next = Some(self.pointer_key(slot + 1));
+                }
+                bytenr = u64_at(
+                    &self.node,
+                    NODE_HEADER_SIZE + slot * KEY_PTR_SIZE + KEY_SIZE,
+                );
+                level -= 1;
+            }
+            self.leaf_tree = Some(tree);
+        }
+
+        let below = partition_point(self.nritems(), |slot| self.leaf_key(slot) <= key);
+        Ok((below.checked_sub(1), next))
+    }
+
+    /// The last item not after `key`
+    fn prev(&mut self, tree: Tree, key: Key, system: bool) -> Result<Option<Item>, BtrfsError> {
+        match self.search(tree, key, system)? {
+            (Some(slot), _) => self.leaf_item(slot).map(Some),
+            (None, _) => Ok(None),
+        }
+    }
+
+    /// The first item not before `key`
+    fn next(&mut self, tree: Tree, key: Key) -> Result<Option<Item>, BtrfsError> {
+        let (slot, next) = self.search(tree, key, false)?;
+        let slot = match slot {
+            Some(slot) if self.leaf_key(slot) == key => slot,
+            Some(slot) => slot + 1,
+            None => 0,
+        };
+        if slot < self.nritems() {
+            return self.leaf_item(slot).map(Some);
+        }
+        match next {
+            Some(next) => self.prev(tree, next, false),
+            None => Ok(None),
+        }
+    }
+
+    /// The item with exactly `key`
+    fn exact(&mut self, tree: Tree, key: Key) -> Result<Option<Item>, BtrfsError> {
+        Ok(self.prev(tree, key, false)?.filter(|item| item.key == key))
+    }
+
+    /// Find `name` among the entries of the directory item with `key`
+    fn find_dir_item(
+        &mut self,
+        tree: Tree,
+        key: Key,
+        name: &[u8],
+    ) -> Result<Option<DirEntry>, BtrfsError> {
+        let Some(item) = self.exact(tree, key)? else {
+            return Ok(None);
+        };
+        // Names with the same hash share the item
+        let mut data = &self.node[item.start..item.start + item.len];
+        while !data.is_empty() {
+            let entry = DirEntry::parse(tree, data)?;
+            if entry.name.as_slice() == name {
+                return Ok(Some(entry));
+            }
+            let len = DIR_ITEM_HEADER_SIZE + u16_at(data, 25) as usize + entry.name.len();
+            data = data.get(len..).ok_or(BtrfsError::Corrupt)?;
+        }
+        Ok(None)
+    }
+
+    /// Tree and root directory of subvolume `id`
+    fn subvolume(&mut self, id: u64) -> Result<(Tree, u64), BtrfsError> {
+        let item = self
+            .prev(self.root_tree, Key::new(id, ROOT_ITEM, u64::MAX), false)?
+            .filter(|item| item.key.objectid == id && item.key.item_type == ROOT_ITEM)
+            .ok_or(BtrfsError::NotFound)?;
+        let data = &self.node[item.start..item.start + item.len];
+        if data.len() <= ROOT_ITEM_LEVEL {
+            return Err(BtrfsError::Corrupt);
+        }
+        let tree = Tree {
+            bytenr: u64_at(data, ROOT_ITEM_BYTENR),
+            level: data[ROOT_ITEM_LEVEL],
+        };
+        Ok((tree, u64_at(data, ROOT_ITEM_DIRID)))
+    }
+
+    /// Read inode `ino` of `tree`
+    fn read_inode(&mut self, tree: Tree, ino: u64) -> Result<Inode, BtrfsError> {
+        let item = self
+            .exact(tree, Key::new(ino, INODE_ITEM, 0))?
+            .ok_or(BtrfsError::NotFound)?;
+        let data = &self.node[item.start..item.start + item.len];
+        if data.len() < INODE_ITEM_SIZE {
+            return Err(BtrfsError::Corrupt);
+        }
+        let kind = match u32_at(data, 52) & S_IFMT {
+            S_IFDIR => InodeKind::Directory,
+            S_IFREG => InodeKind::File,
+            S_IFLNK => InodeKind::Symlink,
+            _ => InodeKind::Other,
+        };
+        Ok(Inode {
+            kind,
+            size: u64_at(data, 16),
+            tree,
+            ino,
+        })
+    }
+
+    /// The root directory of the default subvolume
+    pub fn root(&mut self) -> Result<Inode, BtrfsError> {
+        self.read_inode(self.fs_tree, self.root_dir)
+    }
+
+    /// The inode a directory entry names, the root directory for a
+    /// subvolume
+    pub fn inode(&mut self, entry: &DirEntry) -> Result<Inode, BtrfsError> {
+        match entry.location.item_type {
+            ROOT_ITEM => {
+                let (tree, dir) = self.subvolume(entry.location.objectid)?;
+                self.read_inode(tree, dir)
+            }
+            _ => self.read_inode(entry.tree, entry.location.objectid),
+        }
+    }
+
+    /// The entry of `dir` after `position`, which starts at 0 and is moved
+    /// past the entry returned
+    pub fn read_dir(
+        &mut self,
+        dir: &Inode,
+        position: &mut u64,
+    ) -> Result<Option<DirEntry>, BtrfsError> {
+        if !dir.is_dir() {
+            return Err(BtrfsError::NotADirectory);
+        }
+        let item = self
+            .next(dir.tree, Key::new(dir.ino, DIR_INDEX, *position))?
+            .filter(|item| item.key.objectid == dir.ino && item.key.item_type == DIR_INDEX);
+        let Some(item) = item else {
+            return Ok(None);
+        };
+        *position = item.key.offset.saturating_add(1);
+        DirEntry::parse(dir.tree, &self.node[item.start..item.start + item.len]).map(Some)
+    }
+
+    /// Entry `name` of `dir`
+    fn lookup(&mut self, dir: &Inode, name: &str) -> Result<DirEntry, BtrfsError> {
+        if !dir.is_dir() {
+            return Err(BtrfsError::NotADirectory);
+        }
+        let key = Key::new(dir.ino, DIR_ITEM, name_hash(name.as_bytes()));
+        self.find_dir_item(dir.tree, key, name.as_bytes())?
+            .ok_or(BtrfsError::NotFound)
+    }
+
+    /// Look up a path in the default subvolume, following symbolic links
+    ///
+    /// Both `/` and `\` separate components; the path is taken from the root
+    /// whether or not it starts with one.
+    pub fn find(&mut self, path: &str) -> Result<Inode, BtrfsError> {
+        let mut resolved: String<MAX_PATH_LEN> = String::new();
+        normalize_path(path, &mut resolved).ok_or(BtrfsError::TooManyLinks)?;
+
+        for _ in 0..=MAX_SYMLINKS {
+            let current = resolved.clone();
+            let mut inode = self.root()?;
+            let mut parent: String<MAX_PATH_LEN> = String::new();
+            let mut components = current.split('/').filter(|c| !c.is_empty());
+            let mut link = None;
+
+            for name in components.by_ref() {
+                let entry = self.lookup(&inode, name)?;
+                let next = self.inode(&entry)?;
+                if next.kind == InodeKind::Symlink {
+                    link = Some(next);
+                    break;
+                }
+                let _ = parent.push('/');
+                let _ = parent.push_str(name);
+                inode = next;
+            }
+            let Some(link) = link else {
+                return Ok(inode);
+            };
+
+            // Replace the link in the path with its target
+            let mut target = [0u8; MAX_PATH_LEN];
+            let len = usize::try_from(link.size)
+                .ok()
+                .filter(|&len| len <= target.len())
+                .ok_or(BtrfsError::TooManyLinks)?;
+            self.read_data(&link, 0, &mut target[..len])?;
+            let target = core::str::from_utf8(&target[..len]).map_err(|_| BtrfsError::NotFound)?;
+            let mut rewritten: String<MAX_PATH_LEN> = String::new();
+            if !target.starts_with('/') {
+                rewritten
+                    .push_str(&parent)
+                    .map_err(|_| BtrfsError::TooManyLinks)?;
+            }
+            rewritten.push('/').map_err(|_| BtrfsError::TooManyLinks)?;
+            rewritten
+                .push_str(target)
+                .map_err(|_| BtrfsError::TooManyLinks)?;
+            for name in components {
+                rewritten.push('/').map_err(|_| BtrfsError::TooManyLinks)?;
+                rewritten
+                    .push_str(name)
+                    .map_err(|_| BtrfsError::TooManyLinks)?;
+            }
+            normalize_path(&rewritten, &mut resolved).ok_or(BtrfsError::TooManyLinks)?;
+        }
+        Err(BtrfsError::TooManyLinks)
+    }
+
+    /// Decompress an extent into the cache and call `f` with it
+    ///
+    /// Regular extents are cached by `location`, inline ones are given as
+    /// `input` and not cached.
+    fn with_decompressed<R>(
+        &mut self,
+        extent: &Extent,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Result<R, BtrfsError> {
+        let format = compression_format(extent.compression)?;
+        let len = usize::try_from(extent.ram_bytes)
+            .ok()
+            .filter(|&len| len <= MAX_COMPRESSED_SIZE)
+            .ok_or(BtrfsError::Corrupt)?;
+        let mut cache = CACHE.lock();
+        let cache = &mut *cache;
+
+        match extent.kind {
+            ExtentKind::Inline { start, len: input } => {
+                cache.owner = 0;
+                let input = &self.node[start..start + input];
+                cache.len = compression::decompress(format, input, &mut cache.data[..len])?;
+            }
+            ExtentKind::Regular {
+                disk_bytenr,
+                disk_len,
+                ..
+            } => {
+                if cache.owner != self.id || cache.location != disk_bytenr {
+                    cache.owner = 0;
+                    let disk_len = usize::try_from(disk_len)
+                        .ok()
+                        .filter(|&len| len <= MAX_COMPRESSED_SIZE)
+                        .ok_or(BtrfsError::Corrupt)?;
+                    self.read_logical(disk_bytenr, &mut cache.input[..disk_len], false)?;
+                    let input = &cache.input[..disk_len];
+                    cache.len = compression::decompress(format, input, &mut cache.data[..len])?;
+                    cache.owner = self.id;
+                    cache.location = disk_bytenr;
+                }
+            }
+            ExtentKind::Zero => return Err(BtrfsError::Corrupt),
+        }
+        Ok(f(&cache.data[..cache.len]))
+    }
+
+    /// Read from `inode` at `position` up to the end of the extent or hole
+    /// holding it, returning the number of bytes read
+    fn read_extent(
+        &mut self,
+        inode: &Inode,
+        position: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, BtrfsError> {
+        let item = self
+            .prev(
+                inode.tree,
+                Key::new(inode.ino, EXTENT_DATA, position),
+                false,
+            )?
+            .filter(|item| item.key.objectid == inode.ino && item.key.item_type == EXTENT_DATA);
+        let extent = item
+            .map(|item| Extent::parse(&item, &self.node))
+            .transpose()?
+            .filter(|extent| position - extent.file_offset < extent.len);
+
+        let Some(extent) = extent else {
+            // A hole without an extent, up to the next extent
+            let next = self
+                .next(inode.tree, Key::new(inode.ino, EXTENT_DATA, position + 1))?
+                .filter(|item| item.key.objectid == inode.ino && item.key.item_type == EXTENT_DATA)
+                .map_or(u64::MAX, |item| item.key.offset);
+            let len = (next - position).min(buffer.len() as u64) as usize;
+            buffer[..len].fill(0);
+            return Ok(len);
+        };
+
+        let within = position - extent.file_offset;
+        let len = (extent.len - within).min(buffer.len() as u64) as usize;
+        let out = &mut buffer[..len];
+        let copied = match extent.kind {
+            ExtentKind::Zero => {
+                out.fill(0);
+                true
+            }
+            _ if extent.compression != COMPRESS_NONE => {
+                let offset = match extent.kind {
+                    ExtentKind::Regular { offset, .. } => offset,
+                    _ => 0,
+                };
+                let start = (offset + within) as usize;
+                self.with_decompressed(&extent, |data| {
+                    data.get(start..start + len)
+                        .map(|data| out.copy_from_slice(data))
+                        .is_some()
+                })?
+            }
+            ExtentKind::Inline { start, len: size } => {
+                let within = within as usize;
+                if within + len > size {
+                    false
+                } else {
+                    out.copy_from_slice(&self.node[start + within..start + within + len]);
+                    true
+                }
+            }
+            ExtentKind::Regular {
+                disk_bytenr,
+                offset,
+                ..
+            } => {
+                self.read_logical(disk_bytenr + offset + within, out, false)?;
+                true
+            }
+        };
+        if !copied {
+            return Err(BtrfsError::Corrupt);
+        }
+        Ok(len)
+    }
+
+    /// Read the data of `inode` at `offset`, up to its size
+    fn read_data(
+        &mut self,
+        inode: &Inode,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, BtrfsError> {
+        let len = inode.size.saturating_sub(offset).min(buffer.len() as u64) as usize;
+        let mut done = 0;
+        while done < len {
+            done += self.read_extent(inode, offset + done as u64, &mut buffer[done..len])?;
+        }
+        Ok(len)
+    }
+
+    /// Read from a file at `offset`, returning the number of bytes read
+    ///
+    /// Reads stop at the end of the file.
+    pub fn read_file(
+        &mut self,
+        file: &Inode,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, BtrfsError> {
+        if !file.is_file() {
+            return Err(BtrfsError::NotAFile);
+        }
+        self.read_data(file, offset, buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MemoryDisk, btrfs_image, pattern};
+    use std::format;
+    use std::string::String as StdString;
+    use std::vec::Vec as StdVec;
+
+    /// Read all of the file at `path`
+    fn read_all(fs: &mut Btrfs, path: &str) -> Result<StdVec<u8>, BtrfsError> {
+        let inode = fs.find(path)?;
+        let mut data = std::vec![0u8; inode.size as usize];
+        assert_eq!(fs.read_file(&inode, 0, &mut data)?, data.len());
+        Ok(data)
+    }
+
+    #[test]
+    fn hashes_names() {
+        assert_eq!(!crc32c(!0, b"123456789"), 0xE306_9283);
+        assert_eq!(
+            name_hash(b"default"),
+            crc32c(0xFFFF_FFFE, b"default") as u64
+        );
+    }
+
+    #[test]
+    fn reads_extents() {
+        // Plain, compressed and partial extents, inline data and a hole
+        let kernel = pattern(20000, 1);
+        let entry = b"title openSUSE\nlinux /boot/vmlinuz\n";
+        let mut sparse = pattern(30000, 2);
+        sparse[8192..16384].fill(0);
+        let image = btrfs_image(
+            &[
+                ("boot/vmlinuz-6.4", &kernel),
+                ("boot/loader/entries/opensuse.conf", entry),
+                ("sparse", &sparse),
+            ],
+            &[],
+            true,
+        );
+        for block_size in [512, 4096] {
+            let mut disk = MemoryDisk::new(&image, block_size);
+            let mut fs = Btrfs::new(&mut disk, 0).unwrap();
+            assert_eq!(read_all(&mut fs, "/boot/vmlinuz-6.4").unwrap(), kernel);
+            assert_eq!(
+                read_all(&mut fs, "boot\\loader\\entries\\opensuse.conf").unwrap(),
+                entry
+            );
+            assert_eq!(read_all(&mut fs, "/sparse").unwrap(), sparse);
+
+            // Reads across extents, and past the end
+            let inode = fs.find("/boot/vmlinuz-6.4").unwrap();
+            let mut buffer = [0u8; 10000];
+            assert_eq!(fs.read_file(&inode, 5000, &mut buffer).unwrap(), 10000);
+            assert_eq!(&buffer[..], &kernel[5000..15000]);
+            assert_eq!(fs.read_file(&inode, 19000, &mut buffer).unwrap(), 1000);
+            assert_eq!(&buffer[..1000], &kernel[19000..]);
+            assert_eq!(fs.read_file(&inode, 20000, &mut buffer).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn uses_default_subvolume() {
+        let files: &[(&str, &[u8])] = &[("boot/vmlinuz", b"kernel")];
+        let image = btrfs_image(files, &[], true);
+        let mut disk = MemoryDisk::new(&image, 512);
+        let mut fs = Btrfs::new(&mut disk, 0).unwrap();
+        assert_eq!(read_all(&mut fs, "/boot/vmlinuz").unwrap(), b"kernel");
+
+        // The top level, with the subvolume below it
+        let image = btrfs_image(files, &[], false);
+        let mut disk = MemoryDisk::new(&image, 512);
+        let mut fs = Btrfs::new(&mut disk, 0).unwrap();
+        assert_eq!(fs.find("/boot").err(), Some(BtrfsError::NotFound));
+        assert_eq!(read_all(&mut fs, "/@/boot/vmlinuz").unwrap(), b"kernel");
+        let root = fs.root().unwrap();
+        let entry = fs.read_dir(&root, &mut 0).unwrap().unwrap();
+        assert_eq!((entry.name(), entry.kind), ("@", InodeKind::Directory));
+        assert!(fs.inode(&entry).unwrap().is_dir());
+    }
+
+    #[test]
+    fn lists_directories_across_leaves() {
+        let names: StdVec<_> = (0..200)
+            .map(|i| format!("module-with-a-long-name-{:03}.ko", i))
+            .collect();
+        let contents: StdVec<_> = (0..200).map(|i| pattern(20, i as u8)).collect();
+        let files: StdVec<_> = names
+            .iter()
+            .zip(&contents)
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+        let image = btrfs_image(&files, &[], true);
+        let mut disk = MemoryDisk::new(&image, 512);
+        let mut fs = Btrfs::new(&mut disk, 0).unwrap();
+        assert!(fs.fs_tree.level > 0);
+
+        let root = fs.root().unwrap();
+        let mut position = 0;
+        let mut listed = StdVec::new();
+        while let Some(entry) = fs.read_dir(&root, &mut position).unwrap() {
+            assert_eq!(entry.kind, InodeKind::File);
+            listed.push(StdString::from(entry.name()));
+        }
+        assert_eq!(listed, names);
+        for i in [0, 99, 199] {
+            assert_eq!(read_all(&mut fs, &names[i]).unwrap(), contents[i]);
+        }
+    }
+
+    #[test]
+    fn follows_symlinks() {
+        let kernel = pattern(5000, 4);
+        let image = btrfs_image(
+            &[("boot/vmlinuz-6.4.0-1-default", &kernel)],
+            &[
+                ("boot/vmlinuz", "vmlinuz-6.4.0-1-default"),
+                ("boot/current", "../boot/vmlinuz"),
+                ("boot/absolute", "/boot/vmlinuz-6.4.0-1-default"),
+                ("kernels", "boot"),
+                ("loop", "loop"),
+            ],
+            true,
+        );
+        let mut disk = MemoryDisk::new(&image, 512);
+        let mut fs = Btrfs::new(&mut disk, 0).unwrap();
+        assert_eq!(read_all(&mut fs, "/boot/vmlinuz").unwrap(), kernel);
+        assert_eq!(read_all(&mut fs, "/boot/current").unwrap(), kernel);
+        assert_eq!(read_all(&mut fs, "/boot/absolute").unwrap(), kernel);
+        assert_eq!(read_all(&mut fs, "/kernels/vmlinuz").unwrap(), kernel);
+        assert_eq!(fs.find("/loop").err(), Some(BtrfsError::TooManyLinks));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let image = btrfs_image(&[("boot/vmlinuz", b"kernel")], &[], true);
+        let mut disk = MemoryDisk::new(&image, 512);
+        let mut fs = Btrfs::new(&mut disk, 0).unwrap();
+        assert_eq!(fs.find("/missing").err(), Some(BtrfsError::NotFound));
+        assert_eq!(
+            fs.find("/boot/vmlinuz/x").err(),
+            Some(BtrfsError::NotADirectory)
+        );
+        let boot = fs.find("/boot").unwrap();
+        let mut buffer = [0u8; 4];
+        assert_eq!(
+            fs.read_file(&boot, 0, &mut buffer).err(),
+            Some(BtrfsError::NotAFile)
+        );
+
+        let mut zeros = MemoryDisk::new(std::vec![0u8; 0x20000], 512);
+        assert_eq!(Btrfs::new(&mut zeros, 0).err(), Some(BtrfsError::NotBtrfs));
+        let mut v2 = image.clone();
+        v2[0x10000 + 0xBD] |= 0x20;
+        let mut disk = MemoryDisk::new(v2, 512);
+        assert_eq!(
+            Btrfs::new(&mut disk, 0).err(),
+            Some(BtrfsError::Unsupported)
+        );
+    }
+
+    #[test]
+    fn mounts_at_partition_offset() {
+        let mut image = std::vec![0u8; 2048 * 512];
+        image.extend(btrfs_image(
+            &[("etc/os-release", b"ID=opensuse\n")],
+            &[],
+            true,
+        ));
+        let mut disk = MemoryDisk::new(image, 512);
+        let mut fs = Btrfs::new(&mut disk, 2048).unwrap();
+        assert_eq!(
+            read_all(&mut fs, "/etc/os-release").unwrap(),
+            b"ID=opensuse\n"
+        );
+    }
+}
@@ -12,6 +12,20 @@ pub const SECTOR_SIZE: usize = 512;
 /// Maximum block size we support (4KB - handles CD-ROMs with 2048-byte blocks)
 const MAX_BLOCK_SIZE: usize = 4096;
 
+/// Maximum cluster size (64KB, the largest the FAT specification allows)
+const MAX_CLUSTER_SIZE: usize = 65536;
+
+/// Maximum number of entries in a directory (FAT specification limit)
+///
+/// Bounds cluster chain walks over directories, so a cyclic chain on a
+/// corrupted filesystem ends in an error instead of a hang.
+const MAX_DIRECTORY_ENTRIES: usize = 65536;
+
+/// Largest read issued to the device for a file extent (1MB)
+///
+/// Keeps a single command within what every controller driver can transfer.
+const MAX_EXTENT_READ: usize = 1024 * 1024;
+
 /// FAT Boot Parameter Block (BPB) - common fields
 #[repr(C, packed)]
 #[derive(FromBytes, Immutable, KnownLayout, Unaligned, Clone, Copy, Debug)]
@@ -229,6 +243,18 @@ pub enum FatError {
     BufferTooSmall,
 }
 
+/// Location of a file stored in consecutive clusters
+///
+/// Such a file occupies one run of device blocks, so it can be read straight
+/// into its destination with a few large reads instead of cluster by cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// Device block holding the first byte of the file
+    pub device_block: u64,
+    /// File size in bytes
+    pub size: u32,
+}
+
 /// FAT filesystem instance
 pub struct FatFilesystem<'a> {
     /// Block device
@@ -259,6 +285,8 @@ pub struct FatFilesystem<'a> {
     /// Total data clusters (kept for filesystem completeness)
     #[allow(dead_code)]
     data_clusters: u32,
+    /// Size of the volume in sectors
+    total_sectors: u32,
 }
 
 impl<'a> FatFilesystem<'a> {
@@ -308,6 +336,17 @@ impl<'a> FatFilesystem<'a> {
             return Err(FatError::InvalidBpb);
         }
 
+        // Clusters are read into fixed-size buffers
+        if bpb_sectors_per_cluster as usize * bpb_bytes_per_sector as usize > MAX_CLUSTER_SIZE {
+            log::debug!(
+                "Invalid cluster size: {} sectors of {} bytes (max {} bytes)",
+                bpb_sectors_per_cluster,
+                bpb_bytes_per_sector,
+                MAX_CLUSTER_SIZE
+            );
+            return Err(FatError::InvalidBpb);
+        }
+
         // num_fats must be 1 or 2
         if bpb_num_fats == 0 || bpb_num_fats > 2 {
             log::debug!("Invalid num_fats: {} (expected 1 or 2)", bpb_num_fats);
@@ -350,11 +389,18 @@ impl<'a> FatFilesystem<'a> {
 
         // Calculate first data sector
         let fat_start = reserved_sectors;
-        let root_dir_start = fat_start + (num_fats * sectors_per_fat);
-        let data_start = root_dir_start + root_dir_sectors;
+        let root_dir_start = num_fats
+            .checked_mul(sectors_per_fat)
+            .and_then(|fat_sectors| fat_start.checked_add(fat_sectors))
+            .ok_or(FatError::InvalidBpb)?;
+        let data_start = root_dir_start
+            .checked_add(root_dir_sectors)
+            .ok_or(FatError::InvalidBpb)?;
 
         // Calculate total data clusters
-        let data_sectors = total_sectors - data_start;
+        let data_sectors = total_sectors
+            .checked_sub(data_start)
+            .ok_or(FatError::InvalidBpb)?;
         let data_clusters = data_sectors / sectors_per_cluster as u32;
 
         // Determine FAT type
@@ -411,6 +457,7 @@ impl<'a> FatFilesystem<'a> {
             root_dir_start,
             root_dir_sectors,
             data_clusters,
+            total_sectors,
         })
     }
 
@@ -686,8 +733,7 @@ impl<'a> FatFilesystem<'a> {
 
     /// Find an entry in a directory
     fn find_in_directory(&mut self, cluster: u32, name: &str) -> Result<DirectoryEntry, FatError> {
-        // FAT cluster sizes can be up to 128 sectors * 512 bytes = 65536 bytes
-        let mut buffer = [0u8; 65536]; // Max cluster size (128 sectors * 512 bytes)
+        let mut buffer = [0u8; MAX_CLUSTER_SIZE];
 
         if cluster == 0 && self.fat_type != FatType::Fat32 {
             // FAT12/16 root directory (fixed location)
@@ -753,7 +799,7 @@ impl<'a> FatFilesystem<'a> {
             let cluster_size = self.sectors_per_cluster as usize * self.bytes_per_sector as usize;
             let entries_per_cluster = cluster_size / 32;
 
-            loop {
+            for _ in 0..MAX_DIRECTORY_ENTRIES / entries_per_cluster {
                 self.read_cluster(current_cluster, &mut buffer[..cluster_size])?;
 
                 for i in 0..entries_per_cluster {
@@ -797,6 +843,9 @@ impl<'a> FatFilesystem<'a> {
                     }
                 }
             }
+
+            log::debug!("FAT: directory at cluster {} is too long", cluster);
+            return Err(FatError::InvalidCluster);
         }
 
         Err(FatError::NotFound)
@@ -808,6 +857,17 @@ impl<'a> FatFilesystem<'a> {
         entry: &DirectoryEntry,
         offset: u32,
         buffer: &mut [u8],
+    ) -> Result<usize, FatError> {
+        self.read_file_with_progress(entry, offset, buffer, &mut |_, _| {})
+    }
+
+    /// Read a file into a buffer, reporting progress after every cluster
+    fn read_file_with_progress(
+        &mut self,
+        entry: &DirectoryEntry,
+        offset: u32,
+        buffer: &mut [u8],
+        progress: &mut dyn FnMut(usize, usize),
     ) -> Result<usize, FatError> {
         if entry.is_directory() {
             return Err(FatError::NotAFile);
@@ -833,7 +893,7 @@ impl<'a> FatFilesystem<'a> {
             }
         }
 
-        let mut cluster_buffer = [0u8; 65536]; // Max cluster size (128 sectors * 512 bytes)
+        let mut cluster_buffer = [0u8; MAX_CLUSTER_SIZE];
         let mut bytes_read = 0;
 
         // Read first (potentially partial) cluster
@@ -858,6 +918,7 @@ impl<'a> FatFilesystem<'a> {
                 &mut buffer[bytes_read..bytes_read + cluster_size as usize],
             )?;
             bytes_read += cluster_size as usize;
+            progress(bytes_read, bytes_to_read);
 
             match self.next_cluster(cluster)? {
                 Some(next) => cluster = next,
@@ -878,14 +939,142 @@ impl<'a> FatFilesystem<'a> {
     }
 
     /// Read entire file into a buffer (convenience method)
-    pub fn read_file_all(&mut self, path: &str, buffer: &mut [u8]) -> Result<usize, FatError> {
+    ///
+    /// Contiguous files are read as an extent. `progress`, if given, is
+    /// called with the number of bytes read so far and the file size.
+    pub fn read_file_all(
+        &mut self,
+        path: &str,
+        buffer: &mut [u8],
+        progress: Option<&mut dyn FnMut(usize, usize)>,
+    ) -> Result<usize, FatError> {
         let entry = self.find_file(path)?;
 
         if entry.file_size as usize > buffer.len() {
             return Err(FatError::BufferTooSmall);
         }
 
-        self.read_file(&entry, 0, buffer)
+        let mut no_progress = |_, _| {};
+        let progress = progress.unwrap_or(&mut no_progress);
+        let bytes_read = match self.file_extent(&entry)? {
+            Some(extent) => self.read_extent_with_progress(&extent, 0, buffer, &mut *progress)?,
+            None => self.read_file_with_progress(&entry, 0, buffer, &mut *progress)?,
+        };
+        progress(bytes_read, bytes_read);
+        Ok(bytes_read)
+    }
+
+    /// Get the extent of a file stored in consecutive clusters
+    ///
+    /// Returns `None` if the file is empty, fragmented, or doesn't start on a
+    /// device block boundary; use [`read_file`](Self::read_file) for those.
+    pub fn file_extent(&mut self, entry: &DirectoryEntry) -> Result<Option<Extent>, FatError> {
+        if entry.is_directory() {
+            return Err(FatError::NotAFile);
+        }
+        if entry.file_size == 0 {
+            return Ok(None);
+        }
+
+        let first_cluster = entry.first_cluster();
+        let (device_block, offset) = self
+            .cluster_to_device_block(first_cluster)
+            .ok_or(FatError::InvalidCluster)?;
+        if offset != 0 {
+            return Ok(None);
+        }
+
+        let cluster_size = self.sectors_per_cluster as u32 * self.bytes_per_sector as u32;
+        let mut cluster = first_cluster;
+        for _ in 1..entry.file_size.div_ceil(cluster_size) {
+            match self.next_cluster(cluster)? {
+                Some(next) if next == cluster + 1 => cluster = next,
+                _ => return Ok(None),
+            }
+        }
+
+        // The last cluster must still be inside the data region
+        self.cluster_to_device_block(cluster)
+            .ok_or(FatError::InvalidCluster)?;
+
+        Ok(Some(Extent {
+            device_block,
+            size: entry.file_size,
+        }))
+    }
+
+    /// Read from a file extent into a buffer
+    ///
+    /// Whole device blocks are read directly into `buffer`; only a partial
+    /// first and last block go through a bounce buffer.
+    pub fn read_extent(
+        &mut self,
+        extent: &Extent,
+        offset: u32,
+        buffer: &mut [u8],
+    ) -> Result<usize, FatError> {
+        self.read_extent_with_progress(extent, offset, buffer, &mut |_, _| {})
+    }
+
+    /// Read from a file extent, reporting progress after every device read
+    fn read_extent_with_progress(
+        &mut self,
+        extent: &Extent,
+        offset: u32,
+        buffer: &mut [u8],
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<usize, FatError> {
+        if offset >= extent.size {
+            return Ok(0);
+        }
+
+        let bytes_to_read = core::cmp::min(buffer.len() as u32, extent.size - offset) as usize;
+        let block_size = self.device_block_size as usize;
+        let mut block = extent.device_block + (offset as usize / block_size) as u64;
+        let block_offset = offset as usize % block_size;
+        let mut block_buffer = [0u8; MAX_BLOCK_SIZE];
+        let mut bytes_read = 0;
+
+        // Partial first block
+        if block_offset > 0 {
+            self.device
+                .read_block(block, &mut block_buffer[..block_size])
+                .map_err(|_| FatError::ReadError)?;
+            let copy_len = core::cmp::min(bytes_to_read, block_size - block_offset);
+            buffer[..copy_len]
+                .copy_from_slice(&block_buffer[block_offset..block_offset + copy_len]);
+            bytes_read += copy_len;
+            block += 1;
+        }
+
+        // Whole blocks, straight into the destination
+        while bytes_to_read - bytes_read >= block_size {
+            let blocks =
+                ((bytes_to_read - bytes_read) / block_size).min(MAX_EXTENT_READ / block_size);
+            let len = blocks * block_size;
+            self.device
+                .read_blocks(
+                    block,
+                    blocks as u32,
+                    &mut buffer[bytes_read..bytes_read + len],
+                )
+                .map_err(|_| FatError::ReadError)?;
+            bytes_read += len;
+            block += blocks as u64;
+            progress(bytes_read, bytes_to_read);
+        }
+
+        // Partial last block
+        if bytes_read < bytes_to_read {
+            self.device
+                .read_block(block, &mut block_buffer[..block_size])
+                .map_err(|_| FatError::ReadError)?;
+            let remaining = bytes_to_read - bytes_read;
+            buffer[bytes_read..bytes_to_read].copy_from_slice(&block_buffer[..remaining]);
+            bytes_read += remaining;
+        }
+
+        Ok(bytes_read)
     }
 
     /// Get file size
@@ -904,6 +1093,11 @@ impl<'a> FatFilesystem<'a> {
         self.fat_type
     }
 
+    /// Size of the volume in bytes, from the BPB
+    pub fn volume_size(&self) -> u64 {
+        self.total_sectors as u64 * self.bytes_per_sector as u64
+    }
+
     /// Get a directory entry at a specific position (for directory enumeration)
     ///
     /// # Arguments
@@ -919,7 +1113,7 @@ impl<'a> FatFilesystem<'a> {
         cluster: u32,
         position: usize,
     ) -> Result<Option<DirectoryEntry>, FatError> {
-        let mut buffer = [0u8; 65536]; // Max cluster size
+        let mut buffer = [0u8; MAX_CLUSTER_SIZE];
         let cluster_size = self.sectors_per_cluster as usize * self.bytes_per_sector as usize;
         let entries_per_cluster = cluster_size / 32;
         let mut current_position = 0usize;
@@ -981,7 +1175,7 @@ impl<'a> FatFilesystem<'a> {
         // Cluster chain directory
         let mut current_cluster = cluster;
 
-        loop {
+        for _ in 0..MAX_DIRECTORY_ENTRIES / entries_per_cluster {
             self.read_cluster(current_cluster, &mut buffer[..cluster_size])?;
 
             // Search entries
@@ -1012,5 +1206,252 @@ impl<'a> FatFilesystem<'a> {
                 None => return Ok(None),
             };
         }
+
+        Err(FatError::InvalidCluster)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MemoryDisk, fat_image, pattern, put_u16};
+
+    /// Files of a typical ESP
+    fn esp_files(loader: &[u8]) -> [(&'static str, &[u8]); 3] {
+        [
+            ("EFI/BOOT/BOOTX64.EFI", loader),
+            ("EFI/BOOT/grub.cfg", b"set timeout=5\n"),
+            ("STARTUP.NSH", b"fs0:\\EFI\\BOOT\\BOOTX64.EFI\r\n"),
+        ]
+    }
+
+    #[test]
+    fn detects_fat_type() {
+        for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let mut disk = MemoryDisk::new(fat_image(fat_type, &[]), 512);
+            let fs = FatFilesystem::new(&mut disk, 0).unwrap();
+            assert_eq!(fs.fat_type(), fat_type);
+        }
+    }
+
+    #[test]
+    fn reads_nested_file() {
+        let loader = pattern(10_000, 1);
+        for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let mut disk = MemoryDisk::new(fat_image(fat_type, &esp_files(&loader)), 512);
+            let mut fs = FatFilesystem::new(&mut disk, 0).unwrap();
+
+            let mut buffer = vec![0u8; 16384];
+            let len = fs
+                .read_file_all("\\EFI\\BOOT\\BOOTX64.EFI", &mut buffer, None)
+                .unwrap();
+            assert_eq!(&buffer[..len], &loader[..], "{:?}", fat_type);
+        }
+    }
+
+    #[test]
+    fn path_lookup_ignores_case_and_separators() {
+        let mut disk = MemoryDisk::new(fat_image(FatType::Fat16, &esp_files(b"MZ")), 512);
+        let mut fs = FatFilesystem::new(&mut disk, 0).unwrap();
+
+        for path in [
+            "/efi/boot/bootx64.efi",
+            "EFI\\Boot\\BootX64.efi",
+            "EFI//BOOT/BOOTX64.EFI",
+        ] {
+            assert_eq!(fs.file_size(path).unwrap(), 2, "{}", path);
+        }
+        // The long name entry in front of the short one is skipped
+        assert_eq!(fs.file_size("EFI/BOOT/GRUB.CFG").unwrap(), 14);
+    }
+
+    #[test]
+    fn lookup_errors() {
+        let mut disk = MemoryDisk::new(fat_image(FatType::Fat32, &esp_files(b"MZ")), 512);
+        let mut fs = FatFilesystem::new(&mut disk, 0).unwrap();
+
+        assert!(matches!(
+            fs.find_file("EFI/BOOT/MISSING.EFI"),
+            Err(FatError::NotFound)
+        ));
+        assert!(matches!(
+            fs.find_file("STARTUP.NSH/FOO"),
+            Err(FatError::NotADirectory)
+        ));
+
+        let dir = fs.find_file("EFI/BOOT").unwrap();
+        assert!(dir.is_directory());
+        assert!(matches!(
+            fs.read_file(&dir, 0, &mut [0u8; 16]),
+            Err(FatError::NotAFile)
+        ));
+        assert!(matches!(
+            fs.read_file_all("EFI/BOOT/BOOTX64.EFI", &mut [0u8; 1], None),
+            Err(FatError::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn reads_at_offset_across_clusters() {
+        let data = pattern(20_000, 7);
+        for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let mut disk = MemoryDisk::new(fat_image(fat_type, &[("DATA.BIN", &data)]), 512);
+            let mut fs = FatFilesystem::new(&mut disk, 0).unwrap();
+            let entry = fs.find_file("DATA.BIN").unwrap();
+
+            for (offset, len) in [(0, 100), (500, 3000), (4095, 4097), (19_990, 100)] {
+                let mut buffer = vec![0u8; len];
+                let read = fs.read_file(&entry, offset as u32, &mut buffer).unwrap();
+                let expected = &data[offset..(offset + len).min(data.len())];
+                assert_eq!(&buffer[..read], expected, "{:?} at {}", fat_type, offset);
+            }
+            assert_eq!(fs.read_file(&entry, 20_000, &mut [0u8; 16]).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn reads_contiguous_extent() {
+        let data = pattern(20_000, 5);
+        for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let mut disk = MemoryDisk::new(fat_image(fat_type, &[("DATA.BIN", &data)]), 512);
+            let mut fs = FatFilesystem::new(&mut disk, 0).unwrap();
+            let entry = fs.find_file("DATA.BIN").unwrap();
+            let extent = fs.file_extent(&entry).unwrap().unwrap();
+            assert_eq!(extent.size, 20_000);
+
+            for (offset, len) in [(0, 20_000), (500, 3000), (4095, 4097), (19_990, 100)] {
+                let mut buffer = vec![0u8; len];
+                let read = fs.read_extent(&extent, offset as u32, &mut buffer).unwrap();
+                let expected = &data[offset..(offset + len).min(data.len())];
+                assert_eq!(&buffer[..read], expected, "{:?} at {}", fat_type, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_read_progress() {
+        let data = pattern(20_000, 9);
+        for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let mut disk = MemoryDisk::new(fat_image(fat_type, &[("DATA.BIN", &data)]), 512);
+            let mut fs = FatFilesystem::new(&mut disk, 0).unwrap();
+
+            let mut reports = Vec::new();
+            let mut buffer = vec![0u8; 20_000];
+            let len = fs
+                .read_file_all(
+                    "DATA.BIN",
+                    &mut buffer,
+                    Some(&mut |done, total| reports.push((done, total))),
+                )
+                .unwrap();
+            assert_eq!(&buffer[..len], &data[..]);
+            assert_eq!(reports.last(), Some(&(20_000, 20_000)), "{:?}", fat_type);
+            assert!(reports.windows(2).all(|w| w[0].0 <= w[1].0));
+        }
+    }
+
+    #[test]
+    fn fragmented_file_has_no_extent() {
+        // DATA.BIN takes clusters 2-11 of 2 KiB; reroute it as 2, 12, 4..=11
+        let mut image = fat_image(FatType::Fat16, &[("DATA.BIN", &pattern(20_000, 5))]);
+        let fat_start = 4 * 512;
+        put_u16(&mut image, fat_start + 3 * 2, 12);
+        put_u16(&mut image, fat_start + 12 * 2, 4);
+
+        let mut disk = MemoryDisk::new(image, 512);
+        let mut fs = FatFilesystem::new(&mut disk, 0).unwrap();
+        let entry = fs.find_file("DATA.BIN").unwrap();
+        assert_eq!(entry.first_cluster(), 2);
+        assert_eq!(fs.file_extent(&entry).unwrap(), None);
+    }
+
+    #[test]
+    fn directory_spanning_clusters() {
+        // 40 entries do not fit in one 512-byte cluster
+        let names: Vec<String> = (0..40).map(|i| format!("FILE{:02}.TXT", i)).collect();
+        let contents: Vec<Vec<u8>> = (0..40).map(|i| pattern(100 + i, i as u8)).collect();
+        let files: Vec<(&str, &[u8])> = names
+            .iter()
+            .zip(&contents)
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+
+        let mut disk = MemoryDisk::new(fat_image(FatType::Fat32, &files), 512);
+        let mut fs = FatFilesystem::new(&mut disk, 0).unwrap();
+        assert_eq!(fs.file_size("FILE39.TXT").unwrap(), 139);
+
+        let root = fs.root_cluster();
+        let names_found: Vec<String> = (0..)
+            .map_while(|i| fs.get_directory_entry_at_position(root, i).unwrap())
+            .map(|entry| entry.short_name().as_str().into())
+            .collect();
+        assert_eq!(names_found, names);
+    }
+
+    #[test]
+    fn enumerates_root_directory() {
+        let mut disk = MemoryDisk::new(fat_image(FatType::Fat12, &esp_files(b"MZ")), 512);
+        let mut fs = FatFilesystem::new(&mut disk, 0).unwrap();
+
+        // The volume label is not listed
+        let first = fs.get_directory_entry_at_position(0, 0).unwrap().unwrap();
+        assert!(first.is_directory());
+        assert!(first.matches_name("EFI"));
+        let second = fs.get_directory_entry_at_position(0, 1).unwrap().unwrap();
+        assert!(second.is_file());
+        assert_eq!(second.short_name().as_str(), "STARTUP.NSH");
+        assert!(fs.get_directory_entry_at_position(0, 2).unwrap().is_none());
+    }
+
+    #[test]
+    fn large_device_blocks() {
+        // FAT with 512-byte sectors on a 2048-byte block device, as on an El Torito image
+        let loader = pattern(9000, 3);
+        for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let mut image = vec![0u8; 4 * 2048];
+            image.extend(fat_image(fat_type, &esp_files(&loader)));
+            let mut disk = MemoryDisk::new(image, 2048);
+            let mut fs = FatFilesystem::new(&mut disk, 4).unwrap();
+
+            let mut buffer = vec![0u8; 9000];
+            let len = fs
+                .read_file_all("EFI/BOOT/BOOTX64.EFI", &mut buffer, None)
+                .unwrap();
+            assert_eq!(&buffer[..len], &loader[..], "{:?}", fat_type);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_bpb() {
+        let mut image = fat_image(FatType::Fat16, &[]);
+        image[13] = 3; // sectors per cluster must be a power of two
+        let mut disk = MemoryDisk::new(image, 512);
+        assert!(matches!(
+            FatFilesystem::new(&mut disk, 0),
+            Err(FatError::InvalidBpb)
+        ));
+
+        let mut image = fat_image(FatType::Fat16, &[]);
+        image[11..13].copy_from_slice(&4096u16.to_le_bytes());
+        image[13] = 128; // 512K clusters don't fit the cluster buffers
+        let mut disk = MemoryDisk::new(image, 512);
+        assert!(matches!(
+            FatFilesystem::new(&mut disk, 0),
+            Err(FatError::InvalidBpb)
+        ));
+
+        let mut image = fat_image(FatType::Fat16, &[]);
+        image[19..21].copy_from_slice(&1u16.to_le_bytes()); // ends before the data region
+        let mut disk = MemoryDisk::new(image, 512);
+        assert!(matches!(
+            FatFilesystem::new(&mut disk, 0),
+            Err(FatError::InvalidBpb)
+        ));
+
+        let mut disk = MemoryDisk::new(vec![0u8; 4096], 512);
+        assert!(matches!(
+            FatFilesystem::new(&mut disk, 0),
+            Err(FatError::InvalidBpb)
+        ));
     }
 }
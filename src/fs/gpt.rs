@@ -1,7 +1,10 @@
 //! GPT (GUID Partition Table) parser
 //!
 //! This module provides parsing of GPT partitioned disks to find the EFI
-//! System Partition (ESP).
+//! System Partition (ESP). Partition names are decoded and the well-known
+//! partition types recognised, including those of the Discoverable
+//! Partitions Specification, so a Linux root partition can be found without
+//! a `root=` argument.
 
 use crate::drivers::block::{BlockDevice, BlockError};
 use zerocopy::{FromBytes, Immutable, KnownLayout, Unaligned};
@@ -25,6 +28,121 @@ const ESP_TYPE_GUID: [u8; 16] = [
     0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b, // BE: 00A0C93EC93B
 ];
 
+/// Convert a GUID written as one number to the mixed-endian on-disk format
+const fn type_guid(guid: u128) -> [u8; 16] {
+    let mut bytes = guid.to_be_bytes();
+    // The first three fields are little-endian
+    bytes.swap(0, 3);
+    bytes.swap(1, 2);
+    bytes.swap(4, 5);
+    bytes.swap(6, 7);
+    bytes
+}
+
+/// Discoverable Partitions attribute: don't mount the partition automatically
+pub const ATTRIBUTE_NO_AUTO: u64 = 1 << 63;
+
+/// Well-known partition types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    /// EFI System Partition
+    Esp,
+    /// Extended Boot Loader Partition, `/boot` of the Boot Loader Specification
+    Xbootldr,
+    /// Linux root filesystem for x86-64
+    LinuxRootX86_64,
+    /// Linux `/usr` filesystem for x86-64
+    LinuxUsrX86_64,
+    /// Linux `/home`
+    LinuxHome,
+    /// Linux swap
+    LinuxSwap,
+    /// Linux filesystem without a specific purpose
+    LinuxData,
+    /// Windows and other FAT/NTFS data partitions
+    MicrosoftBasicData,
+    /// Microsoft Reserved Partition
+    MicrosoftReserved,
+    /// Windows Recovery Environment
+    WindowsRecovery,
+}
+
+impl PartitionType {
+    /// Type GUIDs of the well-known types
+    const GUIDS: [(PartitionType, [u8; 16]); 10] = [
+        (PartitionType::Esp, ESP_TYPE_GUID),
+        (
+            PartitionType::Xbootldr,
+            type_guid(0xBC13C2FF_59E6_4262_A352_B275FD6F7172),
+        ),
+        (
+            PartitionType::LinuxRootX86_64,
+            type_guid(0x4F68BCE3_E8CD_4DB1_96E7_FBCAF984B709),
+        ),
+        (
+            PartitionType::LinuxUsrX86_64,
+            type_guid(0x8484680C_9521_48C6_9C11_B0720656F69E),
+        ),
+        (
+            PartitionType::LinuxHome,
+            type_guid(0x933AC7E1_2EB4_4F13_B844_0E14E2AEF915),
+        ),
+        (
+            PartitionType::LinuxSwap,
+            type_guid(0x0657FD6D_A4AB_43C4_84E5_0933C84B4F4F),
+        ),
+        (
+            PartitionType::LinuxData,
+            type_guid(0x0FC63DAF_8483_4772_8E79_3D69D8477DE4),
+        ),
+        (
+            PartitionType::MicrosoftBasicData,
+            type_guid(0xEBD0A0A2_B9E5_4433_87C0_68B6B72699C7),
+        ),
+        (
+            PartitionType::MicrosoftReserved,
+            type_guid(0xE3C9E316_0B5C_4DB8_817D_F92DF00215AE),
+        ),
+        (
+            PartitionType::WindowsRecovery,
+            type_guid(0xDE94BBA4_06D1_4D40_A16A_BFD50179D6AC),
+        ),
+    ];
+
+    /// Recognise a type GUID
+    pub fn from_guid(guid: &[u8; 16]) -> Option<Self> {
+        Self::GUIDS
+            .iter()
+            .find(|(_, known)| known == guid)
+            .map(|&(partition_type, _)| partition_type)
+    }
+
+    /// The type GUID, in the on-disk format
+    pub fn guid(self) -> [u8; 16] {
+        Self::GUIDS
+            .iter()
+            .find(|&&(known, _)| known == self)
+            .map(|&(_, guid)| guid)
+            .unwrap_or_default()
+    }
+
+    /// Short name for display
+    pub fn name(self) -> &'static str {
+        match self {
+            PartitionType::Esp => "ESP",
+            PartitionType::Xbootldr => "XBOOTLDR",
+            PartitionType::LinuxRootX86_64 => "Linux root (x86-64)",
+            PartitionType::LinuxUsrX86_64 => "Linux /usr (x86-64)",
+            PartitionType::LinuxHome => "Linux home",
+            PartitionType::LinuxSwap => "Linux swap",
+            PartitionType::LinuxData => "Linux data",
+            PartitionType::MicrosoftBasicData => "Microsoft basic data",
+            PartitionType::MicrosoftReserved => "Microsoft reserved",
+            PartitionType::WindowsRecovery => "Windows recovery",
+        }
+    }
+}
+
 /// GPT Header structure
 #[repr(C, packed)]
 #[derive(FromBytes, Immutable, KnownLayout, Unaligned, Clone, Copy, Debug)]
@@ -111,7 +229,7 @@ impl GptPartitionEntry {
     /// Get partition size in sectors
     pub fn size_sectors(&self) -> u64 {
         if self.last_lba >= self.first_lba {
-            self.last_lba - self.first_lba + 1
+            (self.last_lba - self.first_lba).saturating_add(1)
         } else {
             0
         }
@@ -123,7 +241,20 @@ impl GptPartitionEntry {
     /// this will underestimate the actual size. The LBA values are relative to the
     /// device's native block size.
     pub fn size_bytes(&self) -> u64 {
-        self.size_sectors() * MIN_BLOCK_SIZE as u64
+        self.size_sectors().saturating_mul(MIN_BLOCK_SIZE as u64)
+    }
+
+    /// Get the partition name, with invalid UTF-16 replaced
+    pub fn name(&self) -> PartitionName {
+        let mut s = PartitionName::new();
+        // Copy name array to avoid reference to packed struct field
+        let name = self.name;
+        let units = name.iter().copied().take_while(|&c| c != 0);
+        for c in char::decode_utf16(units) {
+            // 36 UTF-16 units take at most 108 bytes of UTF-8
+            let _ = s.push(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+        }
+        s
     }
 
     /// Get partition name as ASCII (for display)
@@ -142,6 +273,9 @@ impl GptPartitionEntry {
     }
 }
 
+/// A partition name decoded to UTF-8
+pub type PartitionName = heapless::String<108>;
+
 /// Parsed partition information
 #[derive(Debug, Clone)]
 pub struct Partition {
@@ -159,13 +293,39 @@ pub struct Partition {
     pub is_esp: bool,
     /// Block size of the device (for size calculations)
     pub block_size: u32,
+    /// Partition name, empty if unnamed
+    pub name: PartitionName,
 }
 
 impl Partition {
+    /// The partition type, if it is a well-known one
+    pub fn partition_type(&self) -> Option<PartitionType> {
+        PartitionType::from_guid(&self.type_guid)
+    }
+
+    /// Whether this is a Linux root partition to use without a `root=`
+    /// argument
+    ///
+    /// Per the Discoverable Partitions Specification, partitions with the
+    /// no-auto attribute are left alone.
+    pub fn is_auto_root(&self) -> bool {
+        self.partition_type() == Some(PartitionType::LinuxRootX86_64)
+            && self.attributes & ATTRIBUTE_NO_AUTO == 0
+    }
+
+    /// Label for display: the partition name, or else the type name
+    pub fn label(&self) -> &str {
+        match self.partition_type() {
+            _ if !self.name.is_empty() => &self.name,
+            Some(partition_type) => partition_type.name(),
+            None => "",
+        }
+    }
+
     /// Get partition size in blocks
     pub fn size_sectors(&self) -> u64 {
         if self.last_lba >= self.first_lba {
-            self.last_lba - self.first_lba + 1
+            (self.last_lba - self.first_lba).saturating_add(1)
         } else {
             0
         }
@@ -173,7 +333,31 @@ impl Partition {
 
     /// Get partition size in bytes
     pub fn size_bytes(&self) -> u64 {
-        self.size_sectors() * self.block_size as u64
+        self.size_sectors().saturating_mul(self.block_size as u64)
+    }
+
+    /// Format the unique partition GUID the usual way, in lowercase
+    ///
+    /// The first three fields are stored little-endian on disk.
+    pub fn partition_uuid(&self) -> heapless::String<36> {
+        use core::fmt::Write;
+
+        let g = &self.partition_guid;
+        let mut uuid = heapless::String::new();
+        let _ = write!(
+            uuid,
+            "{:08x}-{:04x}-{:04x}-",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]])
+        );
+        for (i, byte) in g[8..].iter().enumerate() {
+            if i == 2 {
+                let _ = uuid.push('-');
+            }
+            let _ = write!(uuid, "{:02x}", byte);
+        }
+        uuid
     }
 }
 
@@ -188,6 +372,8 @@ pub enum GptError {
     NoPartitions,
     /// No EFI System Partition found
     NoEsp,
+    /// No Linux root partition found
+    NoRoot,
     /// Buffer too small
     BufferTooSmall,
 }
@@ -249,6 +435,14 @@ pub fn read_gpt_header(device: &mut dyn BlockDevice) -> Result<GptHeader, GptErr
         return Err(GptError::InvalidHeader);
     }
 
+    // Entries are at least as large as our view of them and must not straddle blocks
+    if (partition_entry_size as usize) < core::mem::size_of::<GptPartitionEntry>()
+        || partition_entry_size as usize > block_size
+    {
+        log::error!("Invalid GPT partition entry size: {}", partition_entry_size);
+        return Err(GptError::InvalidHeader);
+    }
+
     log::debug!(
         "GPT Header: revision={:#x}, entries={}, entry_size={}",
         revision,
@@ -282,10 +476,11 @@ pub fn read_partitions(
     // Calculate where partition entries start in byte terms
     // For hybrid ISOs, partition_entry_lba is in 512-byte terms
     let entries_byte_offset = if is_hybrid {
-        header.partition_entry_lba as usize * MIN_BLOCK_SIZE
+        (header.partition_entry_lba as usize).checked_mul(MIN_BLOCK_SIZE)
     } else {
-        header.partition_entry_lba as usize * block_size
-    };
+        (header.partition_entry_lba as usize).checked_mul(block_size)
+    }
+    .ok_or(GptError::InvalidHeader)?;
 
     let entry_size = header.partition_entry_size as usize;
     let total_entries = header.num_partition_entries as usize;
@@ -297,7 +492,9 @@ pub fn read_partitions(
 
     'outer: while bytes_read < total_bytes_needed {
         // Calculate which device block to read
-        let current_byte_offset = entries_byte_offset + bytes_read;
+        let Some(current_byte_offset) = entries_byte_offset.checked_add(bytes_read) else {
+            break;
+        };
         let lba = (current_byte_offset / block_size) as u64;
         let offset_in_block = current_byte_offset % block_size;
 
@@ -329,10 +526,11 @@ pub fn read_partitions(
 
                 // For hybrid ISOs, translate GPT LBAs (512-byte terms) to device LBAs
                 let (first_lba, last_lba) = if is_hybrid {
-                    // GPT LBA * 512 / block_size = device LBA
+                    // GPT LBA / (block_size / 512) = device LBA
                     // This works because hybrid ISO partitions are aligned to 2048 bytes
-                    let first = entry.first_lba * MIN_BLOCK_SIZE as u64 / block_size as u64;
-                    let last = entry.last_lba * MIN_BLOCK_SIZE as u64 / block_size as u64;
+                    let sectors_per_block = (block_size / MIN_BLOCK_SIZE) as u64;
+                    let first = entry.first_lba / sectors_per_block;
+                    let last = entry.last_lba / sectors_per_block;
                     (first, last)
                 } else {
                     (entry.first_lba, entry.last_lba)
@@ -346,15 +544,19 @@ pub fn read_partitions(
                     attributes: entry.attributes,
                     is_esp: entry.is_esp(),
                     block_size: block_size as u32,
+                    name: entry.name(),
                 };
 
                 log::debug!(
-                    "Partition {}: LBA {}-{} ({} MB) ESP={}{}",
+                    "Partition {}: LBA {}-{} ({} MB) {} \"{}\"{}",
                     entry_index,
                     partition.first_lba,
                     partition.last_lba,
                     partition.size_bytes() / (1024 * 1024),
-                    partition.is_esp,
+                    partition
+                        .partition_type()
+                        .map_or("unknown", PartitionType::name),
+                    partition.name,
                     if is_hybrid { " [hybrid]" } else { "" }
                 );
 
@@ -411,3 +613,163 @@ pub fn find_esp(device: &mut dyn BlockDevice) -> Result<Partition, GptError> {
         })
         .ok_or(GptError::NoEsp)
 }
+
+/// Find the Linux root partition, as systemd's gpt-auto-generator would
+///
+/// The first x86-64 root partition without the no-auto attribute.
+pub fn find_root(device: &mut dyn BlockDevice) -> Result<Partition, GptError> {
+    let header = read_gpt_header(device)?;
+    let partitions = read_partitions(device, &header)?;
+
+    partitions
+        .into_iter()
+        .find(Partition::is_auto_root)
+        .inspect(|partition| {
+            log::info!(
+                "Found Linux root partition {}: LBA {}-{}",
+                partition.partition_uuid(),
+                partition.first_lba,
+                partition.last_lba
+            );
+        })
+        .ok_or(GptError::NoRoot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MemoryDisk, gpt_image};
+
+    /// Linux filesystem data type GUID (0FC63DAF-8483-4772-8E79-3D69D8477DE4)
+    const LINUX_TYPE_GUID: [u8; 16] = [
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d,
+        0xe4,
+    ];
+
+    #[test]
+    fn finds_esp() {
+        let image = gpt_image(&[
+            (LINUX_TYPE_GUID, &[0u8; 4096]),
+            (ESP_TYPE_GUID, &[0u8; 3 << 20]),
+        ]);
+        let mut disk = MemoryDisk::new(image, 512);
+
+        let header = read_gpt_header(&mut disk).unwrap();
+        let partitions = read_partitions(&mut disk, &header).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert!(!partitions[0].is_esp);
+        assert_eq!(partitions[0].first_lba, 2048);
+        assert_eq!(partitions[0].size_bytes(), 1 << 20);
+
+        let esp = find_esp(&mut disk).unwrap();
+        assert_eq!((esp.first_lba, esp.last_lba), (4096, 4096 + 6144 - 1));
+        assert_eq!(esp.partition_guid, [2; 16]);
+        assert_eq!(esp.size_bytes(), 3 << 20);
+
+        let linux = Partition {
+            partition_guid: LINUX_TYPE_GUID,
+            ..partitions[0].clone()
+        };
+        assert_eq!(
+            linux.partition_uuid().as_str(),
+            "0fc63daf-8483-4772-8e79-3d69d8477de4"
+        );
+    }
+
+    #[test]
+    fn no_esp() {
+        let image = gpt_image(&[(LINUX_TYPE_GUID, &[])]);
+        let mut disk = MemoryDisk::new(image, 512);
+        assert!(matches!(find_esp(&mut disk), Err(GptError::NoEsp)));
+    }
+
+    #[test]
+    fn invalid_header() {
+        let mut disk = MemoryDisk::new(vec![0u8; 64 * 512], 512);
+        assert!(matches!(
+            read_gpt_header(&mut disk),
+            Err(GptError::InvalidHeader)
+        ));
+
+        // Entry size smaller than a partition entry
+        let mut image = gpt_image(&[(ESP_TYPE_GUID, &[])]);
+        image[512 + 84..512 + 88].copy_from_slice(&0u32.to_le_bytes());
+        let mut disk = MemoryDisk::new(image, 512);
+        assert!(matches!(
+            read_gpt_header(&mut disk),
+            Err(GptError::InvalidHeader)
+        ));
+    }
+
+    #[test]
+    fn empty_table() {
+        let image = gpt_image(&[]);
+        let mut disk = MemoryDisk::new(image, 512);
+        let header = read_gpt_header(&mut disk).unwrap();
+        assert!(matches!(
+            read_partitions(&mut disk, &header),
+            Err(GptError::NoPartitions)
+        ));
+    }
+
+    #[test]
+    fn hybrid_iso_block_size() {
+        // The same table read from a 2048-byte block device, as for a hybrid ISO
+        // on a CD-ROM: LBAs stay in 512-byte units and must be translated
+        let image = gpt_image(&[
+            (LINUX_TYPE_GUID, &[0u8; 4096]),
+            (ESP_TYPE_GUID, &[0u8; 4096]),
+        ]);
+        let mut disk = MemoryDisk::new(image, 2048);
+
+        let esp = find_esp(&mut disk).unwrap();
+        assert_eq!((esp.first_lba, esp.last_lba), (1024, 1535));
+        assert_eq!(esp.block_size, 2048);
+        assert_eq!(esp.size_bytes(), 1 << 20);
+    }
+
+    #[test]
+    fn partition_entry_name() {
+        let image = gpt_image(&[(ESP_TYPE_GUID, &[])]);
+        let entry = GptPartitionEntry::read_from_prefix(&image[2 * 512..])
+            .unwrap()
+            .0;
+        assert!(entry.is_esp());
+        assert_eq!(entry.name_ascii().as_str(), "Partition 1");
+        assert_eq!(entry.name().as_str(), "Partition 1");
+        assert!(GptPartitionEntry::default().is_empty());
+    }
+
+    #[test]
+    fn partition_types() {
+        assert_eq!(
+            PartitionType::from_guid(&ESP_TYPE_GUID),
+            Some(PartitionType::Esp)
+        );
+        assert_eq!(
+            PartitionType::from_guid(&LINUX_TYPE_GUID),
+            Some(PartitionType::LinuxData)
+        );
+        assert_eq!(PartitionType::from_guid(&[0; 16]), None);
+
+        let root_guid = PartitionType::GUIDS[2].1;
+        let mut image = gpt_image(&[(LINUX_TYPE_GUID, &[]), (root_guid, &[]), (root_guid, &[])]);
+        let mut disk = MemoryDisk::new(image.clone(), 512);
+        assert!(matches!(find_esp(&mut disk), Err(GptError::NoEsp)));
+        let root = find_root(&mut disk).unwrap();
+        assert_eq!(root.partition_guid, [2; 16]);
+        assert_eq!(root.label(), "Partition 2");
+
+        // The no-auto attribute skips the first root partition
+        image[2 * 512 + 128 + 55] = 0x80;
+        let mut disk = MemoryDisk::new(image, 512);
+        let root = find_root(&mut disk).unwrap();
+        assert_eq!(root.partition_guid, [3; 16]);
+
+        let unnamed = Partition {
+            name: PartitionName::new(),
+            ..root
+        };
+        assert_eq!(unnamed.label(), "Linux root (x86-64)");
+    }
+}
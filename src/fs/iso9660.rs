@@ -9,8 +9,15 @@
 //! - Boot Record Volume Descriptor at sector 17 (byte offset 34816)
 //! - Boot Catalog at a sector specified in the BRVD
 //! - EFI boot image referenced in the boot catalog (platform ID 0xEF)
+//!
+//! The EFI boot image is a FAT filesystem. [`efi_boot_partition`] describes
+//! it as a partition, so it is mounted through an [`OffsetDisk`] and gets
+//! SimpleFileSystem and a CD-ROM device path like an ESP.
 
-use crate::drivers::block::{BlockDevice, BlockError};
+use crate::drivers::block::{BlockDevice, BlockError, OffsetDisk};
+use crate::fs::fat::FatFilesystem;
+use crate::fs::gpt;
+use zerocopy::{FromBytes, Immutable, KnownLayout, Unaligned};
 
 /// ISO9660 sector size (always 2048 bytes)
 pub const ISO_SECTOR_SIZE: usize = 2048;
@@ -27,9 +34,12 @@ const CD001_SIGNATURE: &[u8] = b"CD001";
 /// EFI platform ID in El Torito
 const PLATFORM_EFI: u8 = 0xEF;
 
+/// Unit of the sector counts in the boot catalog
+const VIRTUAL_SECTOR_SIZE: u64 = 512;
+
 /// El Torito boot catalog entry - Validation Entry
 #[repr(C, packed)]
-#[derive(Clone, Copy, Debug)]
+#[derive(FromBytes, Immutable, KnownLayout, Unaligned, Clone, Copy, Debug)]
 struct ValidationEntry {
     header_id: u8,   // Must be 0x01
     platform_id: u8, // 0 = x86, 1 = PowerPC, 2 = Mac, 0xEF = EFI
@@ -42,7 +52,7 @@ struct ValidationEntry {
 
 /// El Torito boot catalog entry - Initial/Default or Section Entry
 #[repr(C, packed)]
-#[derive(Clone, Copy, Debug)]
+#[derive(FromBytes, Immutable, KnownLayout, Unaligned, Clone, Copy, Debug)]
 struct BootEntry {
     boot_indicator: u8,  // 0x88 = bootable, 0x00 = not bootable
     boot_media_type: u8, // 0 = no emulation, 1 = 1.2M floppy, etc.
@@ -56,7 +66,7 @@ struct BootEntry {
 
 /// El Torito section header
 #[repr(C, packed)]
-#[derive(Clone, Copy, Debug)]
+#[derive(FromBytes, Immutable, KnownLayout, Unaligned, Clone, Copy, Debug)]
 struct SectionHeader {
     header_indicator: u8, // 0x90 = more sections, 0x91 = final section
     platform_id: u8,
@@ -103,6 +113,12 @@ pub fn find_efi_boot_image(device: &mut dyn BlockDevice) -> Result<EfiBootImage,
     let info = device.info();
     let block_size = info.block_size as usize;
 
+    // ISO sectors are made up of whole device blocks
+    if block_size == 0 || block_size > ISO_SECTOR_SIZE {
+        log::debug!("ISO9660: Unsupported block size {}", block_size);
+        return Err(IsoError::NotIso9660);
+    }
+
     // For non-2048 byte devices, we need to calculate the right sector
     let sectors_per_iso_sector = ISO_SECTOR_SIZE / block_size;
 
@@ -161,8 +177,10 @@ pub fn find_efi_boot_image(device: &mut dyn BlockDevice) -> Result<EfiBootImage,
         device.read_block(catalog_device_sector, &mut buffer[..block_size])?;
     }
 
-    // Parse validation entry (first 32 bytes)
-    let validation = unsafe { &*(buffer.as_ptr() as *const ValidationEntry) };
+    // Parse validation entry (first 32 bytes) using zerocopy
+    let validation = ValidationEntry::read_from_prefix(&buffer)
+        .map_err(|_| IsoError::InvalidCatalog)?
+        .0;
 
     if validation.header_id != 0x01 || validation.key55 != 0x55 || validation.keyaa != 0xAA {
         log::debug!("El Torito: Invalid validation entry");
@@ -175,7 +193,9 @@ pub fn find_efi_boot_image(device: &mut dyn BlockDevice) -> Result<EfiBootImage,
     );
 
     // Check if the initial/default entry is EFI
-    let default_entry = unsafe { &*(buffer.as_ptr().add(32) as *const BootEntry) };
+    let default_entry = BootEntry::read_from_prefix(&buffer[32..])
+        .map_err(|_| IsoError::InvalidCatalog)?
+        .0;
 
     if validation.platform_id == PLATFORM_EFI && default_entry.boot_indicator == 0x88 {
         let load_rba = default_entry.load_rba;
@@ -187,18 +207,16 @@ pub fn find_efi_boot_image(device: &mut dyn BlockDevice) -> Result<EfiBootImage,
             sector_count
         );
 
-        return Ok(EfiBootImage {
-            start_sector: load_rba as u64 * sectors_per_iso_sector as u64,
-            sector_count: sector_count * sectors_per_iso_sector as u32,
-            size_bytes: sector_count as u64 * ISO_SECTOR_SIZE as u64,
-        });
+        return Ok(EfiBootImage::new(load_rba, sector_count, block_size));
     }
 
     // Scan section entries for EFI platform
     let mut offset = 64usize; // Start after validation + default entry
 
     while offset + 32 <= ISO_SECTOR_SIZE {
-        let header = unsafe { &*(buffer.as_ptr().add(offset) as *const SectionHeader) };
+        let Ok((header, _)) = SectionHeader::read_from_prefix(&buffer[offset..]) else {
+            break;
+        };
 
         // Check if this is a section header
         let indicator = header.header_indicator;
@@ -225,7 +243,9 @@ pub fn find_efi_boot_image(device: &mut dyn BlockDevice) -> Result<EfiBootImage,
                 break;
             }
 
-            let entry = unsafe { &*(buffer.as_ptr().add(offset) as *const BootEntry) };
+            let Ok((entry, _)) = BootEntry::read_from_prefix(&buffer[offset..]) else {
+                break;
+            };
 
             if platform == PLATFORM_EFI && entry.boot_indicator == 0x88 {
                 let load_rba = entry.load_rba;
@@ -237,21 +257,9 @@ pub fn find_efi_boot_image(device: &mut dyn BlockDevice) -> Result<EfiBootImage,
                     sector_count
                 );
 
-                // For EFI images, sector_count might be 1 or 0, meaning "rest of image"
-                // We'll need to determine the actual size from the FAT BPB
-                return Ok(EfiBootImage {
-                    start_sector: load_rba as u64 * sectors_per_iso_sector as u64,
-                    sector_count: if sector_count > 0 {
-                        sector_count * sectors_per_iso_sector as u32
-                    } else {
-                        0
-                    },
-                    size_bytes: if sector_count > 0 {
-                        sector_count as u64 * ISO_SECTOR_SIZE as u64
-                    } else {
-                        0
-                    },
-                });
+                // For EFI images, sector_count might be 1 or 0, meaning "rest of image";
+                // efi_boot_partition() determines the actual size from the FAT BPB
+                return Ok(EfiBootImage::new(load_rba, sector_count, block_size));
             }
 
             offset += 32;
@@ -267,22 +275,187 @@ pub fn find_efi_boot_image(device: &mut dyn BlockDevice) -> Result<EfiBootImage,
     Err(IsoError::NoEfiEntry)
 }
 
+impl EfiBootImage {
+    /// Image at ISO sector `load_rba` with `sector_count` 512-byte sectors
+    fn new(load_rba: u32, sector_count: u32, block_size: usize) -> Self {
+        let size_bytes = sector_count as u64 * VIRTUAL_SECTOR_SIZE;
+        EfiBootImage {
+            start_sector: load_rba as u64 * (ISO_SECTOR_SIZE / block_size) as u64,
+            sector_count: size_bytes.div_ceil(block_size as u64) as u32,
+            size_bytes,
+        }
+    }
+}
+
+/// The El Torito EFI boot image as a partition holding a FAT filesystem
+///
+/// The boot catalog can't describe images of 32 MiB or more, and mkisofs
+/// and xorriso often write a count of 0 or 1, so the size is taken from the
+/// FAT boot sector of the image. The catalog count is only used if the
+/// image has no valid FAT.
+pub fn efi_boot_partition(device: &mut dyn BlockDevice) -> Result<gpt::Partition, IsoError> {
+    let image = find_efi_boot_image(device)?;
+    let block_size = device.info().block_size;
+
+    let fat_blocks = {
+        let mut disk = OffsetDisk::new(device, image.start_sector, u64::MAX);
+        FatFilesystem::new(&mut disk, 0)
+            .ok()
+            .map(|fat| fat.volume_size().div_ceil(block_size as u64))
+    };
+    let num_blocks = fat_blocks.unwrap_or(image.sector_count as u64).max(1);
+    log::debug!(
+        "El Torito: EFI boot image is {} blocks (catalog: {})",
+        num_blocks,
+        image.sector_count
+    );
+
+    Ok(gpt::Partition {
+        type_guid: [0u8; 16], // Not a real GUID
+        partition_guid: [0u8; 16],
+        first_lba: image.start_sector,
+        last_lba: image.start_sector + num_blocks - 1,
+        attributes: 0,
+        is_esp: true, // Treat it as ESP
+        block_size,
+        name: gpt::PartitionName::new(),
+    })
+}
+
 /// Check if a device looks like an ISO9660 image
 pub fn is_iso9660(device: &mut dyn BlockDevice) -> bool {
     let info = device.info();
     let block_size = info.block_size as usize;
+    if block_size == 0 || block_size > ISO_SECTOR_SIZE {
+        return false;
+    }
     let sectors_per_iso_sector = ISO_SECTOR_SIZE / block_size;
 
     // Check for Primary Volume Descriptor at sector 16
     let pvd_sector = 16 * sectors_per_iso_sector as u64;
 
-    let mut buffer = [0u8; 8];
-
-    // Just read enough to check the signature
-    if device.read_block(pvd_sector, &mut buffer).is_err() {
+    // Block reads need a whole block, even though only the signature is checked
+    let mut buffer = [0u8; ISO_SECTOR_SIZE];
+    if device
+        .read_block(pvd_sector, &mut buffer[..block_size])
+        .is_err()
+    {
         return false;
     }
 
     // Check for CD001 signature at offset 1
     &buffer[1..6] == CD001_SIGNATURE
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fat::FatType;
+    use crate::testing::{ISO_BOOT_IMAGE_SECTOR, MemoryDisk, fat_image, iso_image, pattern};
+
+    #[test]
+    fn finds_default_efi_entry() {
+        let boot_image = pattern(5000, 9);
+        for block_size in [2048, 512] {
+            let mut disk = MemoryDisk::new(iso_image(&boot_image, false), block_size);
+            assert!(is_iso9660(&mut disk));
+
+            let image = find_efi_boot_image(&mut disk).unwrap();
+            let blocks_per_sector = ISO_SECTOR_SIZE as u64 / block_size as u64;
+            assert_eq!(
+                image.start_sector,
+                ISO_BOOT_IMAGE_SECTOR as u64 * blocks_per_sector
+            );
+            // 5000 bytes are 10 virtual sectors of 512 bytes
+            assert_eq!(image.size_bytes, 5120);
+            assert_eq!(
+                image.sector_count as u64,
+                5120u64.div_ceil(block_size as u64)
+            );
+        }
+    }
+
+    #[test]
+    fn sizes_boot_partition_from_fat() {
+        let fat = fat_image(FatType::Fat12, &[("EFI/BOOT/BOOTX64.EFI", b"MZ")]);
+        for block_size in [2048, 512] {
+            let mut iso = iso_image(&fat, false);
+            // A catalog count of 4 virtual sectors, as xorriso writes
+            iso[19 * ISO_SECTOR_SIZE + 32 + 6] = 4;
+            iso[19 * ISO_SECTOR_SIZE + 32 + 7] = 0;
+            let mut disk = MemoryDisk::new(iso, block_size);
+
+            let partition = efi_boot_partition(&mut disk).unwrap();
+            assert_eq!(
+                partition.size_sectors() * block_size as u64,
+                fat.len() as u64
+            );
+
+            let mut esp = OffsetDisk::new(&mut disk, partition.first_lba, partition.size_sectors());
+            let mut fs = FatFilesystem::new(&mut esp, 0).unwrap();
+            assert_eq!(fs.file_size("EFI/BOOT/BOOTX64.EFI").unwrap(), 2);
+        }
+
+        // Without a FAT the catalog count is all there is
+        let mut disk = MemoryDisk::new(iso_image(&pattern(5000, 9), false), 2048);
+        let partition = efi_boot_partition(&mut disk).unwrap();
+        assert_eq!(partition.size_sectors(), 3);
+    }
+
+    #[test]
+    fn finds_efi_section_entry() {
+        // BIOS default entry with the EFI image in a section, as on hybrid installers
+        let mut disk = MemoryDisk::new(iso_image(&pattern(5000, 9), true), 2048);
+        let image = find_efi_boot_image(&mut disk).unwrap();
+        assert_eq!(image.start_sector, ISO_BOOT_IMAGE_SECTOR as u64);
+    }
+
+    #[test]
+    fn no_efi_entry() {
+        let mut image = iso_image(&[0u8; 512], true);
+        // Turn the EFI section into a second x86 one
+        image[19 * ISO_SECTOR_SIZE + 65] = 0;
+        let mut disk = MemoryDisk::new(image, 2048);
+        assert!(matches!(
+            find_efi_boot_image(&mut disk),
+            Err(IsoError::NoEfiEntry)
+        ));
+    }
+
+    #[test]
+    fn invalid_catalog() {
+        let mut image = iso_image(&[0u8; 512], false);
+        image[19 * ISO_SECTOR_SIZE + 30] = 0; // break the 0x55 0xAA key
+        let mut disk = MemoryDisk::new(image, 2048);
+        assert!(matches!(
+            find_efi_boot_image(&mut disk),
+            Err(IsoError::InvalidCatalog)
+        ));
+    }
+
+    #[test]
+    fn no_el_torito() {
+        // Plain data ISO: the terminator follows the primary volume descriptor
+        let mut image = iso_image(&[0u8; 512], false);
+        image.copy_within(
+            18 * ISO_SECTOR_SIZE..19 * ISO_SECTOR_SIZE,
+            17 * ISO_SECTOR_SIZE,
+        );
+        let mut disk = MemoryDisk::new(image, 2048);
+        assert!(is_iso9660(&mut disk));
+        assert!(matches!(
+            find_efi_boot_image(&mut disk),
+            Err(IsoError::NoElTorito)
+        ));
+    }
+
+    #[test]
+    fn not_iso9660() {
+        let mut disk = MemoryDisk::new(vec![0u8; 64 * 1024], 512);
+        assert!(!is_iso9660(&mut disk));
+        assert!(matches!(
+            find_efi_boot_image(&mut disk),
+            Err(IsoError::NotIso9660)
+        ));
+    }
+}
@@ -0,0 +1,1064 @@
+//! LUKS2 encrypted partitions
+//!
+//! With /boot on a LUKS2 partition, the bootloader can only read its
+//! configuration if it brings its own cryptodisk support. Instead, CrabEFI
+//! unlocks such partitions while installing the partitions' BlockIO and
+//! serves the decrypted contents through the partition's BlockIO.
+//!
+//! Only what firmware can afford is supported:
+//! - keyslots derived with PBKDF2; argon2 keyslots need far more memory
+//!   and time than firmware has, so a volume needs an extra PBKDF2 keyslot
+//!   (`cryptsetup luksAddKey --pbkdf pbkdf2`)
+//! - the aes-xts-plain64 cipher, the cryptsetup default
+//!
+//! The key is taken from a key file on the disk's ESP, named after the
+//! first eight hex digits of the volume UUID, e.g.
+//! `EFI\CRABEFI\KEYS\0A1B2C3D.KEY`. Without one, the user is asked for
+//! the passphrase.
+//!
+//! Reference: LUKS2 On-Disk Format Specification, cryptsetup lib/luks2
+
+use core::fmt::Write;
+use heapless::{String, Vec};
+use spin::Mutex;
+
+use crate::crypto::aes::Xts;
+use crate::crypto::sha1::Sha1;
+use crate::crypto::sha256::Sha256;
+use crate::crypto::{Digest, pbkdf2};
+use crate::drivers::block::BlockDevice;
+use crate::efi;
+use crate::fs::fat::FatFilesystem;
+use crate::fs::gpt::Partition;
+use crate::menu::{self, MAX_PASSPHRASE_LEN};
+
+/// Magic at the start of a LUKS header
+const MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+
+/// Size of the binary header in front of the JSON metadata
+const BINARY_HEADER_SIZE: usize = 4096;
+
+/// Largest header (binary header and JSON area) read; cryptsetup defaults
+/// to 16 KiB
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// Offsets of binary header fields
+const VERSION_OFFSET: usize = 6;
+const HEADER_SIZE_OFFSET: usize = 8;
+const LABEL_OFFSET: usize = 24;
+const CHECKSUM_ALGORITHM_OFFSET: usize = 72;
+const UUID_OFFSET: usize = 168;
+const CHECKSUM_OFFSET: usize = 448;
+
+/// Unit of keyslot area encryption and of the plain64 IV
+const SECTOR_SIZE: usize = 512;
+
+/// The only supported cipher
+const CIPHER: &str = "aes-xts-plain64";
+
+/// Largest volume or keyslot key (AES-256-XTS)
+const MAX_KEY_SIZE: usize = 64;
+
+/// Largest PBKDF2 salt or digest
+const MAX_SALT_SIZE: usize = 64;
+
+/// Largest key file read from the ESP
+const MAX_KEY_FILE_SIZE: usize = 4096;
+
+/// Directory of key files on the ESP
+const KEY_FILE_DIRECTORY: &str = "EFI\\CRABEFI\\KEYS";
+
+/// Passphrases tried per volume before giving up on it
+const MAX_ATTEMPTS: usize = 3;
+
+/// Maximum number of unlocked volumes
+const MAX_VOLUMES: usize = 4;
+
+/// Reads bytes at a byte offset from the start of the header
+type Reader<'a> = dyn FnMut(u64, &mut [u8]) -> Result<(), LuksError> + 'a;
+
+/// Error type for LUKS operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LuksError {
+    /// No LUKS2 header
+    NotLuks,
+    /// Header or metadata is damaged
+    Corrupt,
+    /// Volume uses features that aren't supported
+    Unsupported,
+    /// Read error from storage device
+    ReadError,
+    /// No keyslot opens with the key
+    WrongKey,
+}
+
+// ============================================================================
+// JSON metadata
+// ============================================================================
+
+/// A JSON value of the metadata, kept as unparsed text
+#[derive(Clone, Copy)]
+struct Json<'a>(&'a [u8]);
+
+impl<'a> Json<'a> {
+    /// Member `key` of an object
+    fn get(self, key: &str) -> Option<Json<'a>> {
+        self.members()
+            .find(|&(name, _)| name == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Members of an object, or elements of an array with empty names
+    fn members(self) -> Members<'a> {
+        let object = self.0.first() == Some(&b'{');
+        let data = if object || self.0.first() == Some(&b'[') {
+            self.0
+        } else {
+            &[]
+        };
+        Members {
+            data,
+            pos: 1,
+            object,
+        }
+    }
+
+    /// A string without escapes
+    fn as_str(self) -> Option<&'a str> {
+        let text = self.0.strip_prefix(b"\"")?.strip_suffix(b"\"")?;
+        if text.contains(&b'\\') {
+            return None;
+        }
+        core::str::from_utf8(text).ok()
+    }
+
+    /// A number, or a string holding one as LUKS2 stores 64-bit values
+    fn as_u64(self) -> Option<u64> {
+        match self.as_str() {
+            Some(text) => text.parse().ok(),
+            None => core::str::from_utf8(self.0).ok()?.parse().ok(),
+        }
+    }
+}
+
+/// Iterator over the members of a JSON object or array
+struct Members<'a> {
+    data: &'a [u8],
+    pos: usize,
+    object: bool,
+}
+
+impl<'a> Iterator for Members<'a> {
+    type Item = (&'a str, Json<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.data;
+        let mut pos = skip_whitespace(data, self.pos);
+        if *data.get(pos)? == b',' {
+            pos = skip_whitespace(data, pos + 1);
+        }
+
+        let mut name = "";
+        if self.object {
+            let end = value_end(data, pos)?;
+            name = Json(&data[pos..end]).as_str()?;
+            pos = skip_whitespace(data, end);
+            if data.get(pos) != Some(&b':') {
+                return None;
+            }
+            pos = skip_whitespace(data, pos + 1);
+        }
+        let end = value_end(data, pos)?;
+        self.pos = end;
+        Some((name, Json(&data[pos..end])))
+    }
+}
+
+fn skip_whitespace(data: &[u8], mut pos: usize) -> usize {
+    while data.get(pos).is_some_and(u8::is_ascii_whitespace) {
+        pos += 1;
+    }
+    pos
+}
+
+/// End of the string starting at `start`
+fn string_end(data: &[u8], start: usize) -> Option<usize> {
+    let mut pos = start + 1;
+    loop {
+        match *data.get(pos)? {
+            b'\\' => pos += 2,
+            b'"' => return Some(pos + 1),
+            _ => pos += 1,
+        }
+    }
+}
+
+/// End of the value starting at `start`; `None` at the end of a container
+fn value_end(data: &[u8], start: usize) -> Option<usize> {
+    match *data.get(start)? {
+        b'"' => string_end(data, start),
+        b'{' | b'[' => {
+            let mut depth = 0usize;
+            let mut pos = start;
+            loop {
+                match *data.get(pos)? {
+                    b'"' => {
+                        pos = string_end(data, pos)?;
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(pos + 1);
+                        }
+                    }
+                    _ => {}
+                }
+                pos += 1;
+            }
+        }
+        b'}' | b']' | b',' | b':' => None,
+        _ => {
+            let len = data[start..]
+                .iter()
+                .position(|&b| matches!(b, b',' | b'}' | b']') || b.is_ascii_whitespace())
+                .unwrap_or(data.len() - start);
+            Some(start + len)
+        }
+    }
+}
+
+/// Decode base64 `text` into `out`, returning the decoded length
+fn base64_decode(text: &str, out: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    let mut bits = 0u32;
+    let mut count = 0;
+    for byte in text.bytes().take_while(|&b| b != b'=') {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            *out.get_mut(len)? = (bits >> count) as u8;
+            len += 1;
+        }
+    }
+    Some(len)
+}
+
+// ============================================================================
+// Header and keyslots
+// ============================================================================
+
+/// Hash used by PBKDF2 and the anti-forensic splitter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Hash {
+    Sha1,
+    Sha256,
+}
+
+impl Hash {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha1" => Some(Hash::Sha1),
+            "sha256" => Some(Hash::Sha256),
+            _ => None,
+        }
+    }
+
+    fn pbkdf2(self, password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+        match self {
+            Hash::Sha1 => pbkdf2::<Sha1>(password, salt, iterations, out),
+            Hash::Sha256 => pbkdf2::<Sha256>(password, salt, iterations, out),
+        }
+    }
+
+    /// The anti-forensic diffusion: each digest-sized chunk of `data` is
+    /// replaced by the hash of its index and itself
+    fn diffuse(self, data: &mut [u8]) {
+        match self {
+            Hash::Sha1 => diffuse::<Sha1>(data),
+            Hash::Sha256 => diffuse::<Sha256>(data),
+        }
+    }
+}
+
+fn diffuse<D: Digest>(data: &mut [u8]) {
+    for (index, chunk) in data.chunks_mut(D::OUTPUT_SIZE).enumerate() {
+        let mut digest = D::default();
+        digest.update(&(index as u32).to_be_bytes());
+        digest.update(chunk);
+        let mut out = [0u8; 32];
+        digest.finalize_into(&mut out);
+        chunk.copy_from_slice(&out[..chunk.len()]);
+    }
+}
+
+/// PBKDF2 parameters with a salt
+struct Kdf {
+    hash: Hash,
+    iterations: u32,
+    salt: [u8; MAX_SALT_SIZE],
+    salt_len: usize,
+}
+
+impl Kdf {
+    /// Parse a `pbkdf2` kdf or digest object
+    fn parse(json: Json) -> Option<Self> {
+        if json.get("type")?.as_str()? != "pbkdf2" {
+            return None;
+        }
+        let mut salt = [0u8; MAX_SALT_SIZE];
+        let salt_len = base64_decode(json.get("salt")?.as_str()?, &mut salt)?;
+        Some(Kdf {
+            hash: Hash::from_name(json.get("hash")?.as_str()?)?,
+            iterations: u32::try_from(json.get("iterations")?.as_u64()?).ok()?,
+            salt,
+            salt_len,
+        })
+    }
+
+    fn derive(&self, password: &[u8], out: &mut [u8]) {
+        self.hash
+            .pbkdf2(password, &self.salt[..self.salt_len], self.iterations, out);
+    }
+}
+
+/// A keyslot that can be opened here
+struct Keyslot<'a> {
+    id: &'a str,
+    /// Size of the volume key it holds
+    key_size: usize,
+    kdf: Kdf,
+    af_hash: Hash,
+    stripes: usize,
+    /// Byte offset of the encrypted key material from the header
+    area_offset: u64,
+    area_key_size: usize,
+}
+
+impl<'a> Keyslot<'a> {
+    fn parse(id: &'a str, json: Json<'a>) -> Result<Self, LuksError> {
+        let kdf = json.get("kdf").ok_or(LuksError::Corrupt)?;
+        let kdf_type = kdf.get("type").and_then(Json::as_str);
+        if kdf_type != Some("pbkdf2") {
+            log::info!(
+                "LUKS: keyslot {} uses {}, only pbkdf2 is supported",
+                id,
+                kdf_type.unwrap_or("an unknown kdf")
+            );
+            return Err(LuksError::Unsupported);
+        }
+        Self::parse_pbkdf2(id, json).ok_or_else(|| {
+            log::info!("LUKS: keyslot {} is not supported", id);
+            LuksError::Unsupported
+        })
+    }
+
+    fn parse_pbkdf2(id: &'a str, json: Json<'a>) -> Option<Self> {
+        let af = json.get("af")?;
+        let area = json.get("area")?;
+        if json.get("type")?.as_str()? != "luks2"
+            || af.get("type")?.as_str()? != "luks1"
+            || area.get("type")?.as_str()? != "raw"
+            || area.get("encryption")?.as_str()? != CIPHER
+        {
+            return None;
+        }
+        let key_size = json.get("key_size")?.as_u64()? as usize;
+        let area_key_size = area.get("key_size")?.as_u64()? as usize;
+        if key_size > MAX_KEY_SIZE || area_key_size > MAX_KEY_SIZE {
+            return None;
+        }
+        let stripes = af.get("stripes")?.as_u64()? as usize;
+        if stripes == 0 || key_size * stripes > area.get("size")?.as_u64()? as usize {
+            return None;
+        }
+        Some(Keyslot {
+            id,
+            key_size,
+            kdf: Kdf::parse(json.get("kdf")?)?,
+            af_hash: Hash::from_name(af.get("hash")?.as_str()?)?,
+            stripes,
+            area_offset: area.get("offset")?.as_u64()?,
+            area_key_size,
+        })
+    }
+}
+
+/// The encrypted data segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Segment {
+    /// Byte offset of the data from the header
+    offset: u64,
+    /// Size in bytes, `None` if it extends to the end of the partition
+    size: Option<u64>,
+    /// Added to the sector number to form the IV
+    iv_tweak: u64,
+    sector_size: u32,
+}
+
+impl Segment {
+    fn parse(json: Json) -> Option<Self> {
+        if json.get("type")?.as_str()? != "crypt" || json.get("encryption")?.as_str()? != CIPHER {
+            return None;
+        }
+        let size = json.get("size")?;
+        let sector_size = json.get("sector_size")?.as_u64()? as u32;
+        if !sector_size.is_power_of_two() || !(512..=4096).contains(&sector_size) {
+            return None;
+        }
+        Some(Segment {
+            offset: json.get("offset")?.as_u64()?,
+            size: if size.as_str() == Some("dynamic") {
+                None
+            } else {
+                Some(size.as_u64()?)
+            },
+            iv_tweak: json.get("iv_tweak")?.as_u64()?,
+            sector_size,
+        })
+    }
+}
+
+/// The parsed header of a LUKS2 volume
+struct Header<'a> {
+    uuid: &'a str,
+    label: &'a str,
+    metadata: Json<'a>,
+}
+
+/// NUL-terminated text of a binary header field
+fn header_text(field: &[u8]) -> &str {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).unwrap_or("")
+}
+
+/// Size of the header (binary header and JSON area) starting with `data`
+fn header_size(data: &[u8]) -> Result<usize, LuksError> {
+    if data.get(..MAGIC.len()) != Some(MAGIC) {
+        return Err(LuksError::NotLuks);
+    }
+    let version = data
+        .get(VERSION_OFFSET..VERSION_OFFSET + 2)
+        .ok_or(LuksError::NotLuks)?;
+    if version != [0, 2] {
+        log::info!("LUKS: version {:?} is not supported", version);
+        return Err(LuksError::Unsupported);
+    }
+    let size = data
+        .get(HEADER_SIZE_OFFSET..HEADER_SIZE_OFFSET + 8)
+        .ok_or(LuksError::Corrupt)?;
+    let size = u64::from_be_bytes(size.try_into().unwrap());
+    if size < BINARY_HEADER_SIZE as u64 + 2 || size > MAX_HEADER_SIZE as u64 {
+        log::info!("LUKS: header size {} is not supported", size);
+        return Err(LuksError::Unsupported);
+    }
+    Ok(size as usize)
+}
+
+impl<'a> Header<'a> {
+    /// Check and parse a complete header
+    fn parse(data: &'a [u8]) -> Result<Self, LuksError> {
+        let size = header_size(data)?;
+        let data = data.get(..size).ok_or(LuksError::Corrupt)?;
+        if header_text(&data[CHECKSUM_ALGORITHM_OFFSET..UUID_OFFSET]) != "sha256" {
+            log::info!("LUKS: header checksum algorithm is not supported");
+            return Err(LuksError::Unsupported);
+        }
+
+        // The checksum covers the whole header with the checksum field zeroed
+        let mut digest = Sha256::default();
+        digest.update(&data[..CHECKSUM_OFFSET]);
+        digest.update(&[0u8; 64]);
+        digest.update(&data[CHECKSUM_OFFSET + 64..]);
+        let mut checksum = [0u8; 32];
+        digest.finalize_into(&mut checksum);
+        if checksum[..] != data[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 32] {
+            return Err(LuksError::Corrupt);
+        }
+
+        let json = &data[BINARY_HEADER_SIZE..];
+        let json_len = json.iter().position(|&b| b == 0).unwrap_or(json.len());
+        let start = skip_whitespace(json, 0);
+        let metadata = Json(&json[start..json_len]);
+        if metadata.0.first() != Some(&b'{') {
+            return Err(LuksError::Corrupt);
+        }
+        Ok(Header {
+            uuid: header_text(&data[UUID_OFFSET..UUID_OFFSET + 40]),
+            label: header_text(&data[LABEL_OFFSET..LABEL_OFFSET + 48]),
+            metadata,
+        })
+    }
+
+    /// The data segment
+    fn segment(&self) -> Result<Segment, LuksError> {
+        let mut segments = self
+            .metadata
+            .get("segments")
+            .ok_or(LuksError::Corrupt)?
+            .members();
+        let (_, segment) = segments.next().ok_or(LuksError::Corrupt)?;
+        if segments.next().is_some() {
+            log::info!("LUKS: volumes with several segments are not supported");
+            return Err(LuksError::Unsupported);
+        }
+        Segment::parse(segment).ok_or_else(|| {
+            log::info!("LUKS: segment cipher or format is not supported");
+            LuksError::Unsupported
+        })
+    }
+
+    /// Keyslots that can be opened here
+    fn keyslots(&self) -> impl Iterator<Item = Keyslot<'a>> + 'a {
+        let keyslots = self.metadata.get("keyslots");
+        keyslots
+            .into_iter()
+            .flat_map(Json::members)
+            .filter_map(|(id, json)| Keyslot::parse(id, json).ok())
+    }
+
+    /// Check a volume key against the digests of keyslot `id`
+    fn verify_key(&self, id: &str, key: &[u8]) -> bool {
+        let Some(digests) = self.metadata.get("digests") else {
+            return false;
+        };
+        digests.members().any(|(_, digest)| {
+            let covers_keyslot = digest
+                .get("keyslots")
+                .is_some_and(|slots| slots.members().any(|(_, slot)| slot.as_str() == Some(id)));
+            let (Some(kdf), Some(expected)) = (
+                Kdf::parse(digest),
+                digest.get("digest").and_then(Json::as_str),
+            ) else {
+                return false;
+            };
+            let mut expected_bytes = [0u8; MAX_SALT_SIZE];
+            let Some(len) = base64_decode(expected, &mut expected_bytes) else {
+                return false;
+            };
+            let mut derived = [0u8; MAX_SALT_SIZE];
+            covers_keyslot && {
+                kdf.derive(key, &mut derived[..len]);
+                derived[..len] == expected_bytes[..len]
+            }
+        })
+    }
+
+    /// Recover the volume key with `passphrase`
+    fn recover_key(
+        &self,
+        passphrase: &[u8],
+        read: &mut Reader<'_>,
+        key: &mut [u8; MAX_KEY_SIZE],
+    ) -> Result<usize, LuksError> {
+        let mut tried = false;
+        for keyslot in self.keyslots() {
+            tried = true;
+            log::info!(
+                "LUKS: trying keyslot {} ({} PBKDF2 iterations)",
+                keyslot.id,
+                keyslot.kdf.iterations
+            );
+            let mut area_key = [0u8; MAX_KEY_SIZE];
+            keyslot
+                .kdf
+                .derive(passphrase, &mut area_key[..keyslot.area_key_size]);
+            let xts = Xts::new(&area_key[..keyslot.area_key_size]);
+            area_key.fill(0);
+            let Some(xts) = xts else {
+                continue;
+            };
+
+            // Anti-forensic merge: every stripe but the last is XORed into
+            // the accumulator, which is then diffused; XORing the last
+            // stripe yields the key
+            let key_size = keyslot.key_size;
+            let material = key_size * keyslot.stripes;
+            let (mut stripe, mut pos) = (0, 0);
+            key.fill(0);
+            let mut sector = [0u8; SECTOR_SIZE];
+            for index in 0..material.div_ceil(SECTOR_SIZE) {
+                read(
+                    keyslot.area_offset + (index * SECTOR_SIZE) as u64,
+                    &mut sector,
+                )?;
+                xts.decrypt_sector(index as u64, &mut sector);
+                let len = (material - index * SECTOR_SIZE).min(SECTOR_SIZE);
+                for &byte in &sector[..len] {
+                    key[pos] ^= byte;
+                    pos += 1;
+                    if pos == key_size {
+                        pos = 0;
+                        stripe += 1;
+                        if stripe < keyslot.stripes {
+                            keyslot.af_hash.diffuse(&mut key[..key_size]);
+                        }
+                    }
+                }
+            }
+            sector.fill(0);
+
+            if self.verify_key(keyslot.id, &key[..key_size]) {
+                log::info!("LUKS: keyslot {} opened", keyslot.id);
+                return Ok(key_size);
+            }
+        }
+        key.fill(0);
+        if tried {
+            Err(LuksError::WrongKey)
+        } else {
+            Err(LuksError::Unsupported)
+        }
+    }
+}
+
+// ============================================================================
+// Unlocked volumes
+// ============================================================================
+
+/// An unlocked volume
+struct Volume {
+    storage_device_id: u32,
+    /// First device block of the partition
+    partition_lba: u64,
+    /// First device block of the encrypted data
+    data_lba: u64,
+    device_block_size: u32,
+    segment: Segment,
+    xts: Xts,
+}
+
+impl Volume {
+    /// Read and decrypt data sector `sector`
+    fn read_sector(&self, sector: u64, buffer: &mut [u8]) -> Result<(), ()> {
+        let sector_size = self.segment.sector_size as usize;
+        let buffer = buffer.get_mut(..sector_size).ok_or(())?;
+        let blocks_per_sector = (sector_size / self.device_block_size as usize) as u64;
+        for (index, block) in buffer
+            .chunks_exact_mut(self.device_block_size as usize)
+            .enumerate()
+        {
+            let lba = self.data_lba + sector * blocks_per_sector + index as u64;
+            crate::drivers::storage::read_sectors(self.storage_device_id, lba, block)?;
+        }
+        // The IV counts 512-byte sectors whatever the encryption sector size
+        let iv = self.segment.iv_tweak + sector * (sector_size / SECTOR_SIZE) as u64;
+        self.xts.decrypt_sector(iv, buffer);
+        Ok(())
+    }
+}
+
+/// Unlocked volumes, indexed by the BlockIO instances serving them
+static VOLUMES: Mutex<Vec<Volume, MAX_VOLUMES>> = Mutex::new(Vec::new());
+
+/// An unlocked partition, ready for a decrypted BlockIO
+#[derive(Debug, Clone, Copy)]
+pub struct Unlocked {
+    /// Index to pass to [`read_sector`]
+    pub volume: usize,
+    /// Number of decrypted sectors
+    pub num_sectors: u64,
+    /// Size of a decrypted sector in bytes
+    pub sector_size: u32,
+}
+
+/// Read decrypted sector `sector` of unlocked volume `volume`
+pub fn read_sector(volume: usize, sector: u64, buffer: &mut [u8]) -> Result<(), ()> {
+    VOLUMES
+        .lock()
+        .get(volume)
+        .ok_or(())?
+        .read_sector(sector, buffer)
+}
+
+/// Read bytes at a byte offset of a partition
+fn read_partition(
+    disk: &mut dyn BlockDevice,
+    partition: &Partition,
+    offset: u64,
+    buffer: &mut [u8],
+) -> Result<(), LuksError> {
+    let block_size = disk.info().block_size as usize;
+    let mut block = [0u8; 4096];
+    let block = block.get_mut(..block_size).ok_or(LuksError::Unsupported)?;
+    let mut done = 0;
+    while done < buffer.len() {
+        let position = offset + done as u64;
+        let lba = partition.first_lba + position / block_size as u64;
+        if lba > partition.last_lba {
+            return Err(LuksError::ReadError);
+        }
+        disk.read_block(lba, block)
+            .map_err(|_| LuksError::ReadError)?;
+        let within = (position % block_size as u64) as usize;
+        let len = (block_size - within).min(buffer.len() - done);
+        buffer[done..done + len].copy_from_slice(&block[within..within + len]);
+        done += len;
+    }
+    Ok(())
+}
+
+/// Unlock `partition` if it is a LUKS2 volume
+///
+/// Returns `None` for partitions that aren't LUKS2, can't be opened here
+/// or that the user leaves locked; their BlockIO serves the raw contents.
+pub fn unlock_partition(
+    disk: &mut dyn BlockDevice,
+    partitions: &[Partition],
+    partition: &Partition,
+    storage_device_id: u32,
+) -> Option<Unlocked> {
+    if partition.is_esp {
+        return None;
+    }
+    let mut start = [0u8; 16];
+    let size = read_partition(disk, partition, 0, &mut start)
+        .and_then(|_| header_size(&start))
+        .ok()?;
+
+    // Booting again after returning to the menu finds the volume open
+    let volumes = VOLUMES.lock();
+    if let Some(index) = volumes.iter().position(|v| {
+        v.storage_device_id == storage_device_id && v.partition_lba == partition.first_lba
+    }) {
+        return Some(unlocked(index, &volumes[index], partition));
+    }
+    drop(volumes);
+
+    let pages = size.div_ceil(efi::allocator::PAGE_SIZE_USIZE);
+    let buffer = efi::allocate_pages(pages as u64)?;
+    let result = unlock_with_header(disk, partitions, partition, storage_device_id, buffer, size);
+    efi::free_pages(buffer, pages as u64);
+
+    match result {
+        Ok(unlocked) => Some(unlocked),
+        Err(e) => {
+            log::info!(
+                "LUKS: partition at LBA {} stays locked: {:?}",
+                partition.first_lba,
+                e
+            );
+            None
+        }
+    }
+}
+
+fn unlock_with_header(
+    disk: &mut dyn BlockDevice,
+    partitions: &[Partition],
+    partition: &Partition,
+    storage_device_id: u32,
+    buffer: &mut [u8],
+    size: usize,
+) -> Result<Unlocked, LuksError> {
+    read_partition(disk, partition, 0, &mut buffer[..size])?;
+    let header = Header::parse(&buffer[..size])?;
+    let segment = header.segment()?;
+    let device_block_size = partition.block_size;
+    if segment.sector_size < device_block_size || segment.offset % device_block_size as u64 != 0 {
+        log::info!(
+            "LUKS: sector size {} on {}-byte blocks is not supported",
+            segment.sector_size,
+            device_block_size
+        );
+        return Err(LuksError::Unsupported);
+    }
+    if header.keyslots().next().is_none() {
+        log::info!("LUKS: volume {} has no PBKDF2 keyslot", header.uuid);
+        return Err(LuksError::Unsupported);
+    }
+    log::info!(
+        "LUKS: found volume {} at LBA {}",
+        header.uuid,
+        partition.first_lba
+    );
+
+    let mut key_file = [0u8; MAX_KEY_FILE_SIZE];
+    let key_file_len = read_key_file(disk, partitions, header.uuid, &mut key_file);
+    let mut read = |offset: u64, buffer: &mut [u8]| read_partition(disk, partition, offset, buffer);
+    let mut key = [0u8; MAX_KEY_SIZE];
+    let result = open_volume(
+        &header,
+        key_file_len.map(|len| &key_file[..len]),
+        &mut read,
+        &mut key,
+    );
+    key_file.fill(0);
+    let key_size = result?;
+    let xts = Xts::new(&key[..key_size]);
+    key.fill(0);
+
+    let volume = Volume {
+        storage_device_id,
+        partition_lba: partition.first_lba,
+        data_lba: partition.first_lba + segment.offset / device_block_size as u64,
+        device_block_size,
+        segment,
+        xts: xts.ok_or(LuksError::Unsupported)?,
+    };
+    let mut volumes = VOLUMES.lock();
+    let index = volumes.len();
+    let unlocked = unlocked(index, &volume, partition);
+    if volumes.push(volume).is_err() {
+        log::warn!("LUKS: too many unlocked volumes");
+        return Err(LuksError::Unsupported);
+    }
+    Ok(unlocked)
+}
+
+/// Geometry of the decrypted view of `volume`
+fn unlocked(index: usize, volume: &Volume, partition: &Partition) -> Unlocked {
+    let segment = &volume.segment;
+    let size = segment
+        .size
+        .unwrap_or_else(|| partition.size_bytes().saturating_sub(segment.offset));
+    Unlocked {
+        volume: index,
+        num_sectors: size / segment.sector_size as u64,
+        sector_size: segment.sector_size,
+    }
+}
+
+/// Recover the volume key with the key file, or else a passphrase
+fn open_volume(
+    header: &Header,
+    key_file: Option<&[u8]>,
+    read: &mut Reader<'_>,
+    key: &mut [u8; MAX_KEY_SIZE],
+) -> Result<usize, LuksError> {
+    if let Some(key_file) = key_file {
+        match header.recover_key(key_file, read, key) {
+            Ok(key_size) => return Ok(key_size),
+            Err(e) => log::warn!("LUKS: key file does not open {}: {:?}", header.uuid, e),
+        }
+    }
+
+    let mut prompt: String<96> = String::new();
+    let name = if header.label.is_empty() {
+        header.uuid
+    } else {
+        header.label
+    };
+    let _ = write!(prompt, "Passphrase for encrypted volume {}:", name);
+    let mut status = None;
+    let mut passphrase = [0u8; MAX_PASSPHRASE_LEN];
+    for _ in 0..MAX_ATTEMPTS {
+        let len =
+            menu::read_passphrase(&prompt, status, &mut passphrase).ok_or(LuksError::WrongKey)?;
+        let result = header.recover_key(&passphrase[..len], read, key);
+        passphrase.fill(0);
+        match result {
+            Err(LuksError::WrongKey) => status = Some("Wrong passphrase"),
+            result => return result,
+        }
+    }
+    Err(LuksError::WrongKey)
+}
+
+/// Read the key file of volume `uuid` from an ESP of the disk
+fn read_key_file(
+    disk: &mut dyn BlockDevice,
+    partitions: &[Partition],
+    uuid: &str,
+    buffer: &mut [u8; MAX_KEY_FILE_SIZE],
+) -> Option<usize> {
+    let name: String<8> = uuid
+        .chars()
+        .filter(char::is_ascii_hexdigit)
+        .take(8)
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if name.len() != 8 {
+        return None;
+    }
+    let mut path: String<40> = String::new();
+    write!(path, "{}\\{}.KEY", KEY_FILE_DIRECTORY, name).ok()?;
+
+    for esp in partitions.iter().filter(|p| p.is_esp) {
+        let Ok(mut fat) = FatFilesystem::new(&mut *disk, esp.first_lba) else {
+            continue;
+        };
+        match fat.read_file_all(&path, buffer, None) {
+            Ok(len) => {
+                log::info!("LUKS: using key file {}", path);
+                return Some(len);
+            }
+            Err(e) => log::debug!("LUKS: no key file {}: {:?}", path, e),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSPHRASE: &[u8] = b"correct horse";
+    const HEADER_SIZE: usize = 16384;
+    const AREA_OFFSET: usize = 32768;
+    const STRIPES: usize = 4;
+    const KEY_SIZE: usize = 64;
+    const UUID: &str = "0a1b2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d";
+
+    fn base64(data: &[u8]) -> std::string::String {
+        const ALPHABET: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut text = std::string::String::new();
+        for chunk in data.chunks(3) {
+            let bits = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    text.push(ALPHABET[(bits >> (18 - 6 * i)) as usize & 63] as char);
+                } else {
+                    text.push('=');
+                }
+            }
+        }
+        text
+    }
+
+    /// A LUKS2 header and keyslot area holding `volume_key` in keyslot 0
+    fn image(volume_key: &[u8; KEY_SIZE]) -> std::vec::Vec<u8> {
+        let (kdf_salt, digest_salt) = ([0x11u8; 32], [0x22u8; 32]);
+
+        // Split the key: random stripes, the last one chosen so the
+        // merge yields the key
+        let mut area = std::vec![0u8; (KEY_SIZE * STRIPES).div_ceil(SECTOR_SIZE) * SECTOR_SIZE];
+        let mut accumulator = [0u8; KEY_SIZE];
+        for stripe in 0..STRIPES {
+            let material = &mut area[stripe * KEY_SIZE..(stripe + 1) * KEY_SIZE];
+            for (i, byte) in material.iter_mut().enumerate() {
+                *byte = if stripe < STRIPES - 1 {
+                    (stripe * 37 + i * 11) as u8
+                } else {
+                    volume_key[i] ^ accumulator[i]
+                };
+                accumulator[i] ^= *byte;
+            }
+            Hash::Sha256.diffuse(&mut accumulator);
+        }
+        let mut area_key = [0u8; KEY_SIZE];
+        pbkdf2::<Sha256>(PASSPHRASE, &kdf_salt, 10, &mut area_key);
+        let xts = Xts::new(&area_key).unwrap();
+        for (index, sector) in area.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            xts.encrypt_sector(index as u64, sector);
+        }
+
+        let mut digest = [0u8; 32];
+        pbkdf2::<Sha256>(volume_key, &digest_salt, 10, &mut digest);
+        let json = std::format!(
+            r#"{{"keyslots":{{"0":{{"type":"luks2","key_size":64,
+            "af":{{"type":"luks1","stripes":{},"hash":"sha256"}},
+            "area":{{"type":"raw","offset":"{}","size":"{}","encryption":"aes-xts-plain64","key_size":64}},
+            "kdf":{{"type":"pbkdf2","hash":"sha256","iterations":10,"salt":"{}"}}}},
+            "1":{{"type":"luks2","key_size":64,"kdf":{{"type":"argon2id","salt":"a\"b"}}}}}},
+            "segments":{{"0":{{"type":"crypt","offset":"16777216","size":"dynamic","iv_tweak":"0",
+            "encryption":"aes-xts-plain64","sector_size":4096}}}},
+            "digests":{{"0":{{"type":"pbkdf2","keyslots":["0"],"segments":["0"],"hash":"sha256",
+            "iterations":10,"salt":"{}","digest":"{}"}}}},
+            "config":{{"json_size":"12288","keyslots_size":"16744448"}}}}"#,
+            STRIPES,
+            AREA_OFFSET,
+            area.len(),
+            base64(&kdf_salt),
+            base64(&digest_salt),
+            base64(&digest)
+        );
+
+        let mut data = std::vec![0u8; AREA_OFFSET];
+        data[..6].copy_from_slice(MAGIC);
+        data[VERSION_OFFSET + 1] = 2;
+        data[HEADER_SIZE_OFFSET..HEADER_SIZE_OFFSET + 8]
+            .copy_from_slice(&(HEADER_SIZE as u64).to_be_bytes());
+        data[LABEL_OFFSET..LABEL_OFFSET + 4].copy_from_slice(b"boot");
+        data[CHECKSUM_ALGORITHM_OFFSET..CHECKSUM_ALGORITHM_OFFSET + 6].copy_from_slice(b"sha256");
+        data[UUID_OFFSET..UUID_OFFSET + UUID.len()].copy_from_slice(UUID.as_bytes());
+        data[BINARY_HEADER_SIZE..BINARY_HEADER_SIZE + json.len()].copy_from_slice(json.as_bytes());
+        let mut checksum = Sha256::default();
+        checksum.update(&data[..HEADER_SIZE]);
+        checksum.finalize_into(&mut data[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 32]);
+        data.extend_from_slice(&area);
+        data
+    }
+
+    #[test]
+    fn json_values() {
+        let json = Json(br#"{ "a" : [1, "x", {"b": "}"}], "c":"\"", "d": "42", "e" :7 }"#);
+        let names: std::vec::Vec<&str> = json.members().map(|(name, _)| name).collect();
+        assert_eq!(names, ["a", "c", "d", "e"]);
+        let array: std::vec::Vec<_> = json.get("a").unwrap().members().collect();
+        assert_eq!(array.len(), 3);
+        assert_eq!(array[1].1.as_str(), Some("x"));
+        assert_eq!(array[2].1.get("b").unwrap().as_str(), Some("}"));
+        assert_eq!(json.get("c").unwrap().as_str(), None);
+        assert_eq!(json.get("d").unwrap().as_u64(), Some(42));
+        assert_eq!(json.get("e").unwrap().as_u64(), Some(7));
+        assert!(json.get("f").is_none());
+
+        let mut out = [0u8; 8];
+        assert_eq!(base64_decode("aGVsbG8=", &mut out), Some(5));
+        assert_eq!(&out[..5], b"hello");
+        assert_eq!(base64_decode("a!", &mut out), None);
+    }
+
+    #[test]
+    fn recover_volume_key() {
+        let volume_key: [u8; KEY_SIZE] = core::array::from_fn(|i| (i * 3 + 1) as u8);
+        let data = image(&volume_key);
+        let header = Header::parse(&data).unwrap();
+        assert_eq!(header.uuid, UUID);
+        assert_eq!(header.label, "boot");
+        assert_eq!(
+            header.segment(),
+            Ok(Segment {
+                offset: 16 * 1024 * 1024,
+                size: None,
+                iv_tweak: 0,
+                sector_size: 4096,
+            })
+        );
+        // The argon2 keyslot is skipped
+        assert_eq!(header.keyslots().count(), 1);
+
+        let mut read = |offset: u64, buffer: &mut [u8]| {
+            let offset = offset as usize;
+            buffer.copy_from_slice(
+                data.get(offset..offset + buffer.len())
+                    .ok_or(LuksError::ReadError)?,
+            );
+            Ok(())
+        };
+        let mut key = [0u8; MAX_KEY_SIZE];
+        assert_eq!(header.recover_key(PASSPHRASE, &mut read, &mut key), Ok(64));
+        assert_eq!(key, volume_key);
+        assert_eq!(
+            header.recover_key(b"wrong", &mut read, &mut key),
+            Err(LuksError::WrongKey)
+        );
+        assert_eq!(key, [0; MAX_KEY_SIZE]);
+    }
+
+    #[test]
+    fn reject_bad_headers() {
+        let mut data = image(&[7; KEY_SIZE]);
+        data[BINARY_HEADER_SIZE + 10] ^= 1;
+        assert_eq!(Header::parse(&data).err(), Some(LuksError::Corrupt));
+        data[VERSION_OFFSET + 1] = 1;
+        assert_eq!(header_size(&data), Err(LuksError::Unsupported));
+        data[0] = 0;
+        assert_eq!(header_size(&data), Err(LuksError::NotLuks));
+    }
+}
@@ -1,8 +1,58 @@
 //! Filesystem support
 //!
 //! This module provides FAT, GPT, and ISO9660/El Torito support for reading
-//! the EFI System Partition and booting from installation media.
+//! the EFI System Partition and booting from installation media, and LUKS2
+//! support for serving encrypted partitions decrypted. squashfs support reads
+//! recovery images without unpacking them, and btrfs support kernels kept in
+//! a btrfs root filesystem.
 
+pub mod btrfs;
 pub mod fat;
 pub mod gpt;
 pub mod iso9660;
+pub mod luks;
+pub mod squashfs;
+
+use heapless::String;
+
+/// Resolve `.` and `..` in `path` into `out`, as `/a/b`
+///
+/// Both `/` and `\` separate components. `..` at the root stays at the root,
+/// and the root itself is empty. Returns `None` if `out` is too short.
+pub fn normalize_path<const N: usize>(path: &str, out: &mut String<N>) -> Option<()> {
+    out.clear();
+    for name in path.split(['/', '\\']) {
+        match name {
+            "" | "." => {}
+            ".." => {
+                let parent = out.rfind('/').unwrap_or(0);
+                out.truncate(parent);
+            }
+            _ => {
+                out.push('/').ok()?;
+                out.push_str(name).ok()?;
+            }
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_paths() {
+        let mut out: String<64> = String::new();
+        for (path, expected) in [
+            ("/a/./b//c", "/a/b/c"),
+            ("a\\b\\..\\c", "/a/c"),
+            ("/../..", ""),
+            ("/a/b/../../c", "/c"),
+        ] {
+            normalize_path(path, &mut out).unwrap();
+            assert_eq!(out.as_str(), expected);
+        }
+        assert_eq!(normalize_path("/a/b/c", &mut String::<4>::new()), None);
+    }
+}
@@ -0,0 +1,940 @@
+//! squashfs filesystem driver
+//!
+//! Read-only support for squashfs 4.0, which recovery images and live
+//! systems ship their root filesystem in. Files such as a kernel can be read
+//! straight out of the image and its directories listed, without unpacking
+//! it to a second filesystem first.
+//!
+//! Blocks compressed with gzip, LZMA, LZ4 or zstd are decoded by
+//! [`crate::compression`]; xz and LZO images are not supported. Extended
+//! attributes, the export table and directory indexes are not used.
+//!
+//! Data blocks are decompressed into a buffer shared by all mounted
+//! filesystems, which keeps the last block so reading a file in small pieces
+//! decompresses each block once. Blocks of up to 128 KiB, the mksquashfs
+//! default, are supported.
+//!
+//! Reference: squashfs 4.0 on-disk format, Linux `fs/squashfs`
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use heapless::{String, Vec};
+use spin::Mutex;
+
+use crate::compression::{self, DecompressError, Format};
+use crate::drivers::block::BlockDevice;
+use crate::fs::normalize_path;
+
+/// Magic number at the start of the superblock ("hsqs")
+const MAGIC: u32 = 0x7371_7368;
+
+/// Size of the superblock
+const SUPERBLOCK_SIZE: usize = 96;
+
+/// Largest supported data block
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Largest device block read through the bounce buffer
+const MAX_DEVICE_BLOCK_SIZE: usize = 4096;
+
+/// Size of a metadata block once decompressed
+const METADATA_SIZE: usize = 8192;
+
+/// Metadata block header bit marking an uncompressed block
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+
+/// Data block size bit marking an uncompressed block
+const DATA_UNCOMPRESSED: u32 = 1 << 24;
+
+/// Fragment index of a file without a fragment
+const NO_FRAGMENT: u32 = 0xFFFF_FFFF;
+
+/// Size of a fragment table entry
+const FRAGMENT_ENTRY_SIZE: usize = 16;
+
+/// Most entries under one directory header
+const MAX_HEADER_ENTRIES: u32 = 256;
+
+/// Longest file name
+const MAX_NAME_LEN: usize = 256;
+
+/// Longest path, including the targets of symbolic links
+const MAX_PATH_LEN: usize = 512;
+
+/// Most symbolic links followed while looking up a path
+const MAX_SYMLINKS: usize = 8;
+
+/// Compressor IDs
+const COMPRESSOR_GZIP: u16 = 1;
+const COMPRESSOR_LZMA: u16 = 2;
+const COMPRESSOR_LZ4: u16 = 5;
+const COMPRESSOR_ZSTD: u16 = 6;
+
+/// Inode types
+const INODE_DIRECTORY: u16 = 1;
+const INODE_FILE: u16 = 2;
+const INODE_SYMLINK: u16 = 3;
+const INODE_EXTENDED_DIRECTORY: u16 = 8;
+const INODE_EXTENDED_FILE: u16 = 9;
+const INODE_EXTENDED_SYMLINK: u16 = 10;
+
+/// Size of the header common to all inodes
+const INODE_HEADER_SIZE: usize = 16;
+
+/// squashfs error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SquashfsError {
+    /// No squashfs superblock
+    NotSquashfs,
+    /// Compressor, version or block size not supported
+    Unsupported,
+    /// Read error
+    ReadError,
+    /// Inconsistent metadata or data that fails to decompress
+    Corrupt,
+    /// File not found
+    NotFound,
+    /// Not a file
+    NotAFile,
+    /// Not a directory
+    NotADirectory,
+    /// Too many symbolic links or a path too long
+    TooManyLinks,
+}
+
+impl From<DecompressError> for SquashfsError {
+    fn from(e: DecompressError) -> Self {
+        match e {
+            DecompressError::Unsupported => SquashfsError::Unsupported,
+            _ => SquashfsError::Corrupt,
+        }
+    }
+}
+
+/// Kind of an inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeKind {
+    Directory,
+    File,
+    Symlink,
+    /// Device, FIFO or socket
+    Other,
+}
+
+impl InodeKind {
+    fn from_type(inode_type: u16) -> Self {
+        match inode_type {
+            INODE_DIRECTORY | INODE_EXTENDED_DIRECTORY => InodeKind::Directory,
+            INODE_FILE | INODE_EXTENDED_FILE => InodeKind::File,
+            INODE_SYMLINK | INODE_EXTENDED_SYMLINK => InodeKind::Symlink,
+            _ => InodeKind::Other,
+        }
+    }
+}
+
+/// Position in a table of metadata blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MetadataPosition {
+    /// Location of the metadata block
+    block: u64,
+    /// Offset in the decompressed block
+    offset: usize,
+}
+
+/// An inode
+#[derive(Debug, Clone, Copy)]
+pub struct Inode {
+    /// Kind of the inode
+    pub kind: InodeKind,
+    /// Size of a file or of the target of a symbolic link
+    pub size: u64,
+    /// First data block of a file, listing of a directory relative to the
+    /// directory table
+    start: u64,
+    /// Offset of the listing in its metadata block
+    listing_offset: usize,
+    /// Fragment holding the end of a file and where in it
+    fragment: u32,
+    fragment_offset: u32,
+    /// Block sizes of a file or target of a symbolic link
+    data: MetadataPosition,
+}
+
+impl Inode {
+    /// Whether this is a directory
+    pub fn is_dir(&self) -> bool {
+        self.kind == InodeKind::Directory
+    }
+
+    /// Whether this is a regular file
+    pub fn is_file(&self) -> bool {
+        self.kind == InodeKind::File
+    }
+}
+
+/// A directory entry
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    name: Vec<u8, MAX_NAME_LEN>,
+    /// Kind of the inode the entry names
+    pub kind: InodeKind,
+    /// Reference to the inode: metadata block and offset
+    inode: u64,
+}
+
+impl DirEntry {
+    /// The name, or `?` if it isn't UTF-8
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name).unwrap_or("?")
+    }
+}
+
+/// The last decompressed data block, shared by all filesystems
+struct BlockCache {
+    /// Filesystem the block belongs to, 0 for none
+    owner: u32,
+    /// Location of the block
+    location: u64,
+    /// Decompressed size
+    len: usize,
+    data: [u8; MAX_BLOCK_SIZE],
+    /// Compressed block as read from the device
+    input: [u8; MAX_BLOCK_SIZE],
+}
+
+static CACHE: Mutex<BlockCache> = Mutex::new(BlockCache {
+    owner: 0,
+    location: 0,
+    len: 0,
+    data: [0; MAX_BLOCK_SIZE],
+    input: [0; MAX_BLOCK_SIZE],
+});
+
+/// ID of the next filesystem mounted, for the block cache
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// squashfs filesystem instance
+pub struct Squashfs<'a> {
+    /// Block device
+    device: &'a mut dyn BlockDevice,
+    /// Device block size
+    device_block_size: u64,
+    /// Byte offset of the filesystem on the device
+    start: u64,
+    /// Decoder of compressed blocks
+    format: Format,
+    /// Data block size
+    block_size: u32,
+    /// Reference to the root directory inode
+    root_inode: u64,
+    /// Locations of the tables
+    inode_table: u64,
+    directory_table: u64,
+    fragment_table: u64,
+    fragment_count: u32,
+    /// ID for the block cache
+    id: u32,
+    /// The last metadata block read: location, size on disk and contents
+    metadata_location: Option<u64>,
+    metadata_disk_size: u64,
+    metadata_len: usize,
+    metadata: [u8; METADATA_SIZE],
+}
+
+impl<'a> Squashfs<'a> {
+    /// Mount the squashfs starting at block `partition_start` of `device`
+    pub fn new(
+        device: &'a mut dyn BlockDevice,
+        partition_start: u64,
+    ) -> Result<Self, SquashfsError> {
+        let device_block_size = device.info().block_size as u64;
+        if device_block_size == 0 || device_block_size > MAX_DEVICE_BLOCK_SIZE as u64 {
+            return Err(SquashfsError::Unsupported);
+        }
+        let mut fs = Squashfs {
+            device,
+            device_block_size,
+            start: partition_start * device_block_size,
+            format: Format::Zlib,
+            block_size: 0,
+            root_inode: 0,
+            inode_table: 0,
+            directory_table: 0,
+            fragment_table: 0,
+            fragment_count: 0,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            metadata_location: None,
+            metadata_disk_size: 0,
+            metadata_len: 0,
+            metadata: [0; METADATA_SIZE],
+        };
+
+        let mut superblock = [0u8; SUPERBLOCK_SIZE];
+        fs.read_bytes(0, &mut superblock)?;
+        let u16_at =
+            |offset: usize| u16::from_le_bytes([superblock[offset], superblock[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes(
+                superblock[offset..offset + 4]
+                    .try_into()
+                    .unwrap_or_default(),
+            )
+        };
+        let u64_at = |offset: usize| {
+            u64::from_le_bytes(
+                superblock[offset..offset + 8]
+                    .try_into()
+                    .unwrap_or_default(),
+            )
+        };
+        if u32_at(0) != MAGIC {
+            return Err(SquashfsError::NotSquashfs);
+        }
+        if (u16_at(28), u16_at(30)) != (4, 0) {
+            return Err(SquashfsError::Unsupported);
+        }
+
+        fs.block_size = u32_at(12);
+        let block_log = u16_at(22);
+        if !fs.block_size.is_power_of_two()
+            || 1u32.checked_shl(block_log as u32) != Some(fs.block_size)
+        {
+            return Err(SquashfsError::Corrupt);
+        }
+        if fs.block_size as usize > MAX_BLOCK_SIZE {
+            log::warn!("squashfs: {} byte blocks are not supported", fs.block_size);
+            return Err(SquashfsError::Unsupported);
+        }
+        fs.format = match u16_at(20) {
+            COMPRESSOR_GZIP => Format::Zlib,
+            COMPRESSOR_LZMA => Format::Lzma,
+            COMPRESSOR_LZ4 => Format::Lz4Block,
+            COMPRESSOR_ZSTD => Format::Zstd,
+            other => {
+                log::warn!("squashfs: compressor {} is not supported", other);
+                return Err(SquashfsError::Unsupported);
+            }
+        };
+        fs.fragment_count = u32_at(16);
+        fs.root_inode = u64_at(32);
+        fs.inode_table = u64_at(64);
+        fs.directory_table = u64_at(72);
+        fs.fragment_table = u64_at(80);
+
+        log::debug!(
+            "squashfs: {:?}, {} byte blocks, {} inodes",
+            fs.format,
+            fs.block_size,
+            u32_at(4)
+        );
+        Ok(fs)
+    }
+
+    /// Read bytes at `offset` from the start of the filesystem
+    fn read_bytes(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), SquashfsError> {
+        let block_size = self.device_block_size as usize;
+        let mut bounce = [0u8; MAX_DEVICE_BLOCK_SIZE];
+        let mut position = self.start + offset;
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let lba = position / self.device_block_size;
+            let within = (position % self.device_block_size) as usize;
+            let remaining = buffer.len() - done;
+            let len = if within == 0 && remaining >= block_size {
+                // Whole blocks go straight to the buffer
+                let count = remaining / block_size;
+                let len = count * block_size;
+                self.device
+                    .read_blocks(lba, count as u32, &mut buffer[done..done + len])
+                    .map_err(|_| SquashfsError::ReadError)?;
+                len
+            } else {
+                self.device
+                    .read_blocks(lba, 1, &mut bounce[..block_size])
+                    .map_err(|_| SquashfsError::ReadError)?;
+                let len = (block_size - within).min(remaining);
+                buffer[done..done + len].copy_from_slice(&bounce[within..within + len]);
+                len
+            };
+            done += len;
+            position += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Load the metadata block at `location`
+    fn load_metadata(&mut self, location: u64) -> Result<(), SquashfsError> {
+        if self.metadata_location == Some(location) {
+            return Ok(());
+        }
+        self.metadata_location = None;
+
+        let mut header = [0u8; 2];
+        self.read_bytes(location, &mut header)?;
+        let header = u16::from_le_bytes(header);
+        let size = (header & !METADATA_UNCOMPRESSED) as usize;
+        if size == 0 || size > METADATA_SIZE {
+            return Err(SquashfsError::Corrupt);
+        }
+
+        if header & METADATA_UNCOMPRESSED != 0 {
+            let mut metadata = [0u8; METADATA_SIZE];
+            self.read_bytes(location + 2, &mut metadata[..size])?;
+            self.metadata = metadata;
+            self.metadata_len = size;
+        } else {
+            let mut input = [0u8; METADATA_SIZE];
+            self.read_bytes(location + 2, &mut input[..size])?;
+            self.metadata_len =
+                compression::decompress(self.format, &input[..size], &mut self.metadata)?;
+        }
+        self.metadata_location = Some(location);
+        self.metadata_disk_size = 2 + size as u64;
+        Ok(())
+    }
+
+    /// Read metadata at `position`, moving it past what was read
+    fn read_metadata(
+        &mut self,
+        position: &mut MetadataPosition,
+        buffer: &mut [u8],
+    ) -> Result<(), SquashfsError> {
+        let mut done = 0;
+        while done < buffer.len() {
+            self.load_metadata(position.block)?;
+            if position.offset >= self.metadata_len {
+                // Continue in the next block
+                position.offset -= self.metadata_len;
+                position.block += self.metadata_disk_size;
+                continue;
+            }
+            let len = (self.metadata_len - position.offset).min(buffer.len() - done);
+            buffer[done..done + len]
+                .copy_from_slice(&self.metadata[position.offset..position.offset + len]);
+            position.offset += len;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Read an array of `N` bytes of metadata
+    fn read_array<const N: usize>(
+        &mut self,
+        position: &mut MetadataPosition,
+    ) -> Result<[u8; N], SquashfsError> {
+        let mut bytes = [0u8; N];
+        self.read_metadata(position, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Read the inode `reference` points to
+    fn read_inode(&mut self, reference: u64) -> Result<Inode, SquashfsError> {
+        let mut position = MetadataPosition {
+            block: self.inode_table + (reference >> 16),
+            offset: (reference & 0xFFFF) as usize,
+        };
+        let header: [u8; INODE_HEADER_SIZE] = self.read_array(&mut position)?;
+        let inode_type = u16::from_le_bytes([header[0], header[1]]);
+
+        let mut inode = Inode {
+            kind: InodeKind::from_type(inode_type),
+            size: 0,
+            start: 0,
+            listing_offset: 0,
+            fragment: NO_FRAGMENT,
+            fragment_offset: 0,
+            data: position,
+        };
+        let u16_at =
+            |bytes: &[u8], offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let u32_at = |bytes: &[u8], offset: usize| {
+            u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap_or_default())
+        };
+        let u64_at = |bytes: &[u8], offset: usize| {
+            u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap_or_default())
+        };
+
+        match inode_type {
+            INODE_DIRECTORY => {
+                let body: [u8; 16] = self.read_array(&mut position)?;
+                inode.start = u32_at(&body, 0) as u64;
+                // The listing size is stored 3 bytes too large
+                inode.size = (u16_at(&body, 8) as u64).saturating_sub(3);
+                inode.listing_offset = u16_at(&body, 10) as usize;
+            }
+            INODE_EXTENDED_DIRECTORY => {
+                let body: [u8; 24] = self.read_array(&mut position)?;
+                inode.size = (u32_at(&body, 4) as u64).saturating_sub(3);
+                inode.start = u32_at(&body, 8) as u64;
+                inode.listing_offset = u16_at(&body, 18) as usize;
+            }
+            INODE_FILE => {
+                let body: [u8; 16] = self.read_array(&mut position)?;
+                inode.start = u32_at(&body, 0) as u64;
+                inode.fragment = u32_at(&body, 4);
+                inode.fragment_offset = u32_at(&body, 8);
+                inode.size = u32_at(&body, 12) as u64;
+            }
+            INODE_EXTENDED_FILE => {
+                let body: [u8; 40] = self.read_array(&mut position)?;
+                inode.start = u64_at(&body, 0);
+                inode.size = u64_at(&body, 8);
+                inode.fragment = u32_at(&body, 28);
+                inode.fragment_offset = u32_at(&body, 32);
+            }
+            INODE_SYMLINK | INODE_EXTENDED_SYMLINK => {
+                let body: [u8; 8] = self.read_array(&mut position)?;
+                inode.size = u32_at(&body, 4) as u64;
+            }
+            _ => {}
+        }
+        if inode.listing_offset >= METADATA_SIZE {
+            return Err(SquashfsError::Corrupt);
+        }
+        inode.data = position;
+        Ok(inode)
+    }
+
+    /// The root directory
+    pub fn root(&mut self) -> Result<Inode, SquashfsError> {
+        self.read_inode(self.root_inode)
+    }
+
+    /// The inode a directory entry names
+    pub fn inode(&mut self, entry: &DirEntry) -> Result<Inode, SquashfsError> {
+        self.read_inode(entry.inode)
+    }
+
+    /// Call `f` with each entry of `dir` until it returns `false`
+    fn walk_directory(
+        &mut self,
+        dir: &Inode,
+        mut f: impl FnMut(DirEntry) -> bool,
+    ) -> Result<(), SquashfsError> {
+        if !dir.is_dir() {
+            return Err(SquashfsError::NotADirectory);
+        }
+        let mut position = MetadataPosition {
+            block: self.directory_table + dir.start,
+            offset: dir.listing_offset,
+        };
+        let mut remaining = dir.size;
+
+        while remaining > 0 {
+            remaining = remaining.checked_sub(12).ok_or(SquashfsError::Corrupt)?;
+            let header: [u8; 12] = self.read_array(&mut position)?;
+            let count = u32::from_le_bytes(header[0..4].try_into().unwrap_or_default()) + 1;
+            let start = u32::from_le_bytes(header[4..8].try_into().unwrap_or_default());
+            if count > MAX_HEADER_ENTRIES {
+                return Err(SquashfsError::Corrupt);
+            }
+
+            for _ in 0..count {
+                let entry: [u8; 8] = self.read_array(&mut position)?;
+                let offset = u16::from_le_bytes([entry[0], entry[1]]);
+                let inode_type = u16::from_le_bytes([entry[4], entry[5]]);
+                let name_len = u16::from_le_bytes([entry[6], entry[7]]) as usize + 1;
+                if name_len > MAX_NAME_LEN {
+                    return Err(SquashfsError::Corrupt);
+                }
+                remaining = remaining
+                    .checked_sub(8 + name_len as u64)
+                    .ok_or(SquashfsError::Corrupt)?;
+
+                let mut name = [0u8; MAX_NAME_LEN];
+                self.read_metadata(&mut position, &mut name[..name_len])?;
+                let entry = DirEntry {
+                    name: Vec::from_slice(&name[..name_len]).unwrap_or_default(),
+                    kind: InodeKind::from_type(inode_type),
+                    inode: (start as u64) << 16 | offset as u64,
+                };
+                if !f(entry) {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Entry `index` of `dir`, `None` past the last one
+    pub fn entry_at(
+        &mut self,
+        dir: &Inode,
+        index: usize,
+    ) -> Result<Option<DirEntry>, SquashfsError> {
+        let mut found = None;
+        let mut position = 0;
+        self.walk_directory(dir, |entry| {
+            if position == index {
+                found = Some(entry);
+                return false;
+            }
+            position += 1;
+            true
+        })?;
+        Ok(found)
+    }
+
+    /// Entry `name` of `dir`
+    fn lookup(&mut self, dir: &Inode, name: &str) -> Result<DirEntry, SquashfsError> {
+        let mut found = None;
+        self.walk_directory(dir, |entry| {
+            if entry.name.as_slice() == name.as_bytes() {
+                found = Some(entry);
+                return false;
+            }
+            true
+        })?;
+        found.ok_or(SquashfsError::NotFound)
+    }
+
+    /// Read the target of a symbolic link, returning its length
+    fn read_link(&mut self, link: &Inode, buffer: &mut [u8]) -> Result<usize, SquashfsError> {
+        let len = usize::try_from(link.size).map_err(|_| SquashfsError::TooManyLinks)?;
+        let target = buffer.get_mut(..len).ok_or(SquashfsError::TooManyLinks)?;
+        let mut position = link.data;
+        self.read_metadata(&mut position, target)?;
+        Ok(len)
+    }
+
+    /// Look up a path, following symbolic links
+    ///
+    /// Both `/` and `\` separate components; the path is taken from the root
+    /// whether or not it starts with one.
+    pub fn find(&mut self, path: &str) -> Result<Inode, SquashfsError> {
+        let mut resolved: String<MAX_PATH_LEN> = String::new();
+        normalize_path(path, &mut resolved).ok_or(SquashfsError::TooManyLinks)?;
+
+        for _ in 0..=MAX_SYMLINKS {
+            let current = resolved.clone();
+            let mut inode = self.root()?;
+            let mut parent: String<MAX_PATH_LEN> = String::new();
+            let mut components = current.split('/').filter(|c| !c.is_empty());
+            let mut link = None;
+
+            for name in components.by_ref() {
+                let entry = self.lookup(&inode, name)?;
+                let next = self.read_inode(entry.inode)?;
+                if next.kind == InodeKind::Symlink {
+                    link = Some(next);
+                    break;
+                }
+                let _ = parent.push('/');
+                let _ = parent.push_str(name);
+                inode = next;
+            }
+            let Some(link) = link else {
+                return Ok(inode);
+            };
+
+            // Replace the link in the path with its target
+            let mut target = [0u8; MAX_PATH_LEN];
+            let len = self.read_link(&link, &mut target)?;
+            let target =
+                core::str::from_utf8(&target[..len]).map_err(|_| SquashfsError::NotFound)?;
+            let mut rewritten: String<MAX_PATH_LEN> = String::new();
+            if !target.starts_with('/') {
+                rewritten
+                    .push_str(&parent)
+                    .map_err(|_| SquashfsError::TooManyLinks)?;
+            }
+            rewritten
+                .push('/')
+                .map_err(|_| SquashfsError::TooManyLinks)?;
+            rewritten
+                .push_str(target)
+                .map_err(|_| SquashfsError::TooManyLinks)?;
+            for name in components {
+                rewritten
+                    .push('/')
+                    .map_err(|_| SquashfsError::TooManyLinks)?;
+                rewritten
+                    .push_str(name)
+                    .map_err(|_| SquashfsError::TooManyLinks)?;
+            }
+            normalize_path(&rewritten, &mut resolved).ok_or(SquashfsError::TooManyLinks)?;
+        }
+        Err(SquashfsError::TooManyLinks)
+    }
+
+    /// Decompress the data block at `location` into the cache and call `f`
+    /// with it
+    fn with_block<R>(
+        &mut self,
+        location: u64,
+        size: u32,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Result<R, SquashfsError> {
+        let mut cache = CACHE.lock();
+        if cache.owner != self.id || cache.location != location {
+            cache.owner = 0;
+            let disk_size = (size & !DATA_UNCOMPRESSED) as usize;
+            if disk_size > MAX_BLOCK_SIZE {
+                return Err(SquashfsError::Corrupt);
+            }
+            let cache = &mut *cache;
+            cache.len = if size & DATA_UNCOMPRESSED != 0 {
+                self.read_bytes(location, &mut cache.data[..disk_size])?;
+                disk_size
+            } else {
+                self.read_bytes(location, &mut cache.input[..disk_size])?;
+                let output = &mut cache.data[..self.block_size as usize];
+                compression::decompress(self.format, &cache.input[..disk_size], output)?
+            };
+            cache.owner = self.id;
+            cache.location = location;
+        }
+        Ok(f(&cache.data[..cache.len]))
+    }
+
+    /// Location and size of the fragment block `index`
+    fn fragment(&mut self, index: u32) -> Result<(u64, u32), SquashfsError> {
+        if index >= self.fragment_count {
+            return Err(SquashfsError::Corrupt);
+        }
+        let per_block = (METADATA_SIZE / FRAGMENT_ENTRY_SIZE) as u32;
+        let mut location = [0u8; 8];
+        self.read_bytes(
+            self.fragment_table + (index / per_block) as u64 * 8,
+            &mut location,
+        )?;
+        let mut position = MetadataPosition {
+            block: u64::from_le_bytes(location),
+            offset: (index % per_block) as usize * FRAGMENT_ENTRY_SIZE,
+        };
+        let entry: [u8; FRAGMENT_ENTRY_SIZE] = self.read_array(&mut position)?;
+        Ok((
+            u64::from_le_bytes(entry[0..8].try_into().unwrap_or_default()),
+            u32::from_le_bytes(entry[8..12].try_into().unwrap_or_default()),
+        ))
+    }
+
+    /// Read from a file at `offset`, returning the number of bytes read
+    ///
+    /// Reads stop at the end of the file.
+    pub fn read_file(
+        &mut self,
+        file: &Inode,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, SquashfsError> {
+        if !file.is_file() {
+            return Err(SquashfsError::NotAFile);
+        }
+        let len = file.size.saturating_sub(offset).min(buffer.len() as u64) as usize;
+        let block_size = self.block_size as u64;
+        let full_blocks = if file.fragment == NO_FRAGMENT {
+            file.size.div_ceil(block_size)
+        } else {
+            file.size / block_size
+        };
+
+        // Walk the block sizes up to the first block read
+        let first = offset / block_size;
+        let mut sizes = file.data;
+        let mut location = file.start;
+        for _ in 0..first.min(full_blocks) {
+            let size = u32::from_le_bytes(self.read_array(&mut sizes)?);
+            location += (size & !DATA_UNCOMPRESSED) as u64;
+        }
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let block = position / block_size;
+            let within = (position % block_size) as usize;
+            let chunk = (len - done).min(block_size as usize - within);
+            let out = &mut buffer[done..done + chunk];
+
+            let copied = if block < full_blocks {
+                let size = u32::from_le_bytes(self.read_array(&mut sizes)?);
+                let block_location = location;
+                location += (size & !DATA_UNCOMPRESSED) as u64;
+                if size & !DATA_UNCOMPRESSED == 0 {
+                    // Sparse block
+                    out.fill(0);
+                    true
+                } else {
+                    self.with_block(block_location, size, |data| {
+                        data.get(within..within + chunk)
+                            .map(|data| out.copy_from_slice(data))
+                            .is_some()
+                    })?
+                }
+            } else {
+                let (fragment_location, size) = self.fragment(file.fragment)?;
+                let start = file.fragment_offset as usize + within;
+                self.with_block(fragment_location, size, |data| {
+                    data.get(start..start + chunk)
+                        .map(|data| out.copy_from_slice(data))
+                        .is_some()
+                })?
+            };
+            if !copied {
+                return Err(SquashfsError::Corrupt);
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MemoryDisk, SQUASHFS_BLOCK_SIZE, pattern, squashfs_image};
+    use std::format;
+    use std::string::String as StdString;
+    use std::vec::Vec as StdVec;
+
+    /// Read all of the file at `path`
+    fn read_all(fs: &mut Squashfs, path: &str) -> Result<StdVec<u8>, SquashfsError> {
+        let inode = fs.find(path)?;
+        let mut data = std::vec![0u8; inode.size as usize];
+        assert_eq!(fs.read_file(&inode, 0, &mut data)?, data.len());
+        Ok(data)
+    }
+
+    #[test]
+    fn reads_blocks_and_fragments() {
+        let kernel = pattern(10000, 1);
+        let block = pattern(SQUASHFS_BLOCK_SIZE, 2);
+        let small = pattern(100, 3);
+        let image = squashfs_image(
+            &[
+                ("boot/vmlinuz", &kernel),
+                ("boot/block", &block),
+                ("etc/hostname", &small),
+            ],
+            &[],
+        );
+        for block_size in [512, 4096] {
+            let mut disk = MemoryDisk::new(&image, block_size);
+            let mut fs = Squashfs::new(&mut disk, 0).unwrap();
+            assert_eq!(read_all(&mut fs, "/boot/vmlinuz").unwrap(), kernel);
+            assert_eq!(read_all(&mut fs, "boot\\block").unwrap(), block);
+            assert_eq!(read_all(&mut fs, "/etc/hostname").unwrap(), small);
+
+            // Reads across blocks and into the fragment, and past the end
+            let inode = fs.find("/boot/vmlinuz").unwrap();
+            let mut buffer = [0u8; 5000];
+            assert_eq!(fs.read_file(&inode, 3000, &mut buffer).unwrap(), 5000);
+            assert_eq!(&buffer[..], &kernel[3000..8000]);
+            assert_eq!(fs.read_file(&inode, 9000, &mut buffer).unwrap(), 1000);
+            assert_eq!(&buffer[..1000], &kernel[9000..]);
+            assert_eq!(fs.read_file(&inode, 10000, &mut buffer).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn lists_directories() {
+        let image = squashfs_image(&[("a/x", b"1"), ("b", b"2")], &[("c", "a/x")]);
+        let mut disk = MemoryDisk::new(&image, 512);
+        let mut fs = Squashfs::new(&mut disk, 0).unwrap();
+        let root = fs.root().unwrap();
+        assert!(root.is_dir());
+
+        let mut names = StdVec::new();
+        while let Some(entry) = fs.entry_at(&root, names.len()).unwrap() {
+            names.push((StdString::from(entry.name()), entry.kind));
+        }
+        assert_eq!(
+            names,
+            [
+                (StdString::from("a"), InodeKind::Directory),
+                (StdString::from("b"), InodeKind::File),
+                (StdString::from("c"), InodeKind::Symlink),
+            ]
+        );
+        let entry = fs.entry_at(&root, 1).unwrap().unwrap();
+        assert_eq!(fs.inode(&entry).unwrap().size, 1);
+    }
+
+    #[test]
+    fn lists_directories_spanning_metadata_blocks() {
+        let names: StdVec<_> = (0..300)
+            .map(|i| format!("module-with-a-long-name-{:03}.ko", i))
+            .collect();
+        let contents: StdVec<_> = (0..300).map(|i| pattern(20, i as u8)).collect();
+        let files: StdVec<_> = names
+            .iter()
+            .zip(&contents)
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+        let image = squashfs_image(&files, &[]);
+        let mut disk = MemoryDisk::new(&image, 512);
+        let mut fs = Squashfs::new(&mut disk, 0).unwrap();
+
+        let root = fs.root().unwrap();
+        assert!(root.size > METADATA_SIZE as u64);
+        for (i, name) in names.iter().enumerate() {
+            let entry = fs.entry_at(&root, i).unwrap().unwrap();
+            assert_eq!(entry.name(), name);
+        }
+        assert!(fs.entry_at(&root, 300).unwrap().is_none());
+        assert_eq!(read_all(&mut fs, &names[299]).unwrap(), contents[299]);
+    }
+
+    #[test]
+    fn follows_symlinks() {
+        let kernel = pattern(5000, 4);
+        let image = squashfs_image(
+            &[("boot/vmlinuz-6.1", &kernel)],
+            &[
+                ("vmlinuz", "boot/vmlinuz-6.1"),
+                ("boot/current", "../vmlinuz"),
+                ("boot/absolute", "/boot/vmlinuz-6.1"),
+                ("kernels", "boot"),
+                ("loop", "loop"),
+            ],
+        );
+        let mut disk = MemoryDisk::new(&image, 512);
+        let mut fs = Squashfs::new(&mut disk, 0).unwrap();
+        assert_eq!(read_all(&mut fs, "vmlinuz").unwrap(), kernel);
+        assert_eq!(read_all(&mut fs, "/boot/current").unwrap(), kernel);
+        assert_eq!(read_all(&mut fs, "/boot/absolute").unwrap(), kernel);
+        assert_eq!(read_all(&mut fs, "/kernels/current").unwrap(), kernel);
+        assert_eq!(fs.find("/loop").err(), Some(SquashfsError::TooManyLinks));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let image = squashfs_image(&[("boot/vmlinuz", b"kernel")], &[]);
+        let mut disk = MemoryDisk::new(&image, 512);
+        let mut fs = Squashfs::new(&mut disk, 0).unwrap();
+        assert_eq!(fs.find("/missing").err(), Some(SquashfsError::NotFound));
+        assert_eq!(
+            fs.find("/boot/vmlinuz/x").err(),
+            Some(SquashfsError::NotADirectory)
+        );
+        let boot = fs.find("/boot").unwrap();
+        let mut buffer = [0u8; 4];
+        assert_eq!(
+            fs.read_file(&boot, 0, &mut buffer).err(),
+            Some(SquashfsError::NotAFile)
+        );
+
+        let mut zeros = MemoryDisk::new(std::vec![0u8; 4096], 512);
+        assert_eq!(
+            Squashfs::new(&mut zeros, 0).err(),
+            Some(SquashfsError::NotSquashfs)
+        );
+        let mut xz = image.clone();
+        xz[20] = 4;
+        let mut disk = MemoryDisk::new(xz, 512);
+        assert_eq!(
+            Squashfs::new(&mut disk, 0).err(),
+            Some(SquashfsError::Unsupported)
+        );
+    }
+
+    #[test]
+    fn mounts_at_partition_offset() {
+        let mut image = std::vec![0u8; 2048 * 512];
+        image.extend(squashfs_image(&[("init", b"#!/bin/sh\n")], &[]));
+        let mut disk = MemoryDisk::new(image, 512);
+        let mut fs = Squashfs::new(&mut disk, 2048).unwrap();
+        assert_eq!(read_all(&mut fs, "/init").unwrap(), b"#!/bin/sh\n");
+    }
+}
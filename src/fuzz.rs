@@ -0,0 +1,150 @@
+//! Byte-slice entry points for fuzzing the parsers
+//!
+//! Each function feeds untrusted bytes to a parser the way the firmware does
+//! with data read from disk or handed over by coreboot, and ignores the
+//! result: only panics, hangs and memory errors are bugs. The cargo-fuzz
+//! targets in `fuzz/` are thin wrappers around these.
+
+use crate::compression::{self, Format};
+use crate::coreboot::tables;
+use crate::drivers::block::{BlockDevice, MemoryDisk};
+use crate::fs::{btrfs::Btrfs, fat::FatFilesystem, gpt, iso9660};
+use crate::pe;
+
+/// Device block sizes the disk entry points run with
+const BLOCK_SIZES: [u32; 3] = [512, 2048, 4096];
+
+/// Decompression output and streaming window size
+const DECOMPRESS_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Maximum number of directory entries listed per directory
+const MAX_DIRECTORY_ENTRIES: usize = 64;
+
+/// Split a disk image input into a memory disk and the rest
+///
+/// The first byte picks the device block size, so one corpus covers hard
+/// disks, CD-ROMs and 4K-native drives.
+fn memory_disk(data: &[u8]) -> Option<MemoryDisk<&[u8]>> {
+    let (&selector, image) = data.split_first()?;
+    let block_size = BLOCK_SIZES[selector as usize % BLOCK_SIZES.len()];
+    Some(MemoryDisk::new(image, block_size))
+}
+
+/// Coreboot table: header followed by its records
+pub fn coreboot_table(data: &[u8]) {
+    let _ = tables::parse_bytes(data);
+}
+
+/// GPT disk: header and partition entries
+pub fn gpt(data: &[u8]) {
+    let Some(mut disk) = memory_disk(data) else {
+        return;
+    };
+    if let Ok(header) = gpt::read_gpt_header(&mut disk) {
+        let _ = gpt::read_partitions(&mut disk, &header);
+    }
+}
+
+/// FAT filesystem at the start of the disk
+pub fn fat(data: &[u8]) {
+    let Some(mut disk) = memory_disk(data) else {
+        return;
+    };
+    fat_filesystem(&mut disk, 0);
+}
+
+/// btrfs filesystem: list the root directory and read each file in it
+pub fn btrfs(data: &[u8]) {
+    let Some(mut disk) = memory_disk(data) else {
+        return;
+    };
+    let Ok(mut fs) = Btrfs::new(&mut disk, 0) else {
+        return;
+    };
+    let Ok(root) = fs.root() else {
+        return;
+    };
+
+    let mut buffer = [0u8; 4096];
+    let mut position = 0;
+    for _ in 0..MAX_DIRECTORY_ENTRIES {
+        let Ok(Some(entry)) = fs.read_dir(&root, &mut position) else {
+            break;
+        };
+        if let Ok(inode) = fs.inode(&entry) {
+            let _ = fs.read_file(&inode, 0, &mut buffer);
+        }
+    }
+    if let Ok(inode) = fs.find("/boot/vmlinuz") {
+        let _ = fs.read_file(&inode, inode.size / 2, &mut buffer);
+    }
+}
+
+/// ISO9660 image: El Torito boot catalog and the FAT image it points at
+pub fn iso9660(data: &[u8]) {
+    let Some(mut disk) = memory_disk(data) else {
+        return;
+    };
+    let _ = iso9660::is_iso9660(&mut disk);
+    if let Ok(partition) = iso9660::efi_boot_partition(&mut disk) {
+        fat_filesystem(&mut disk, partition.first_lba);
+    }
+}
+
+/// PE32+ image: headers, sections and relocations
+pub fn pe(data: &[u8]) {
+    let Ok(image) = pe::load_image(data) else {
+        return;
+    };
+
+    // Safety: load_image copied the headers to the start of the image
+    let headers = unsafe {
+        core::slice::from_raw_parts(image.image_base as *const u8, image.image_size as usize)
+    };
+    let _ = pe::section_name(headers, (image.entry_point - image.image_base) as u32);
+    pe::unload_image(&image);
+}
+
+/// Compressed data: the first byte picks the format
+///
+/// Each input is decoded both into a buffer and through a streaming window.
+pub fn decompress(data: &[u8]) {
+    const FORMATS: [Format; 6] = [
+        Format::Gzip,
+        Format::Lz4,
+        Format::Lz4Block,
+        Format::Lzma,
+        Format::Zstd,
+        Format::Zlib,
+    ];
+    let Some((&selector, input)) = data.split_first() else {
+        return;
+    };
+    let format = FORMATS[selector as usize % FORMATS.len()];
+
+    let mut buffer = [0u8; DECOMPRESS_BUFFER_SIZE];
+    let _ = compression::decompress(format, input, &mut buffer);
+    let _ = compression::decompress_streaming(format, input, &mut buffer, |_| {});
+}
+
+/// Mount a FAT filesystem, list the root directory and read the boot loader
+fn fat_filesystem(disk: &mut dyn BlockDevice, partition_start: u64) {
+    let Ok(mut fs) = FatFilesystem::new(disk, partition_start) else {
+        return;
+    };
+
+    let mut buffer = [0u8; 4096];
+    let root = fs.root_cluster();
+    for position in 0..MAX_DIRECTORY_ENTRIES {
+        let Ok(Some(entry)) = fs.get_directory_entry_at_position(root, position) else {
+            break;
+        };
+        if entry.is_file() {
+            let _ = fs.read_file(&entry, 0, &mut buffer);
+        }
+    }
+
+    if let Ok(entry) = fs.find_file(crate::menu::DEFAULT_BOOT_PATH) {
+        let _ = fs.read_file(&entry, entry.file_size() / 2, &mut buffer);
+    }
+}
@@ -0,0 +1,474 @@
+//! GDB Remote Serial Protocol stub
+//!
+//! With the `gdbstub` feature enabled, breakpoints (`int3`), single-step
+//! traps, fatal CPU exceptions, panics and the debugger hotkey all enter a
+//! GDB remote protocol loop on the serial port. This allows source-level
+//! debugging of CrabEFI and of loaded bootloaders on real hardware:
+//!
+//! ```text
+//! $ gdb target/x86_64-unknown-none/debug/crabefi
+//! (gdb) set serial baud 115200
+//! (gdb) target remote /dev/ttyUSB0
+//! ```
+//!
+//! Supported packets: `?`, `g`/`G` (registers), `m`/`M` (memory), `c`/`s`
+//! (continue/step), `Z0`/`z0` (software breakpoints), `D`, `k` and the
+//! `qSupported`/`qAttached` queries. Everything else gets an empty reply.
+//!
+//! The stub shares the serial port with the console; disconnect any terminal
+//! before attaching GDB.
+
+use crate::arch::x86_64::idt::ExceptionFrame;
+use crate::arch::x86_64::paging;
+use crate::drivers::serial as serial_driver;
+use heapless::Vec;
+use spin::Mutex;
+
+/// Maximum packet size (payload) we accept and send
+const PACKET_SIZE: usize = 2048;
+
+/// Maximum number of software breakpoints
+const MAX_BREAKPOINTS: usize = 32;
+
+/// The `int3` opcode
+const INT3: u8 = 0xCC;
+
+/// RFLAGS trap flag (single-step)
+const RFLAGS_TF: u64 = 1 << 8;
+
+/// An inserted software breakpoint
+struct Breakpoint {
+    addr: u64,
+    original: u8,
+}
+
+/// Software breakpoints currently inserted
+static BREAKPOINTS: Mutex<Vec<Breakpoint, MAX_BREAKPOINTS>> = Mutex::new(Vec::new());
+
+/// Reply buffer
+type Reply = Vec<u8, PACKET_SIZE>;
+
+/// Stop in the debugger at the current location
+#[inline(always)]
+pub fn breakpoint() {
+    unsafe {
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+}
+
+/// Enter the GDB protocol loop for an exception
+///
+/// Returns when GDB resumes execution; the (possibly modified) frame is then
+/// restored by the exception entry stub.
+pub fn handle_exception(frame: &mut ExceptionFrame) {
+    // Nobody else gets to run while we own the serial port
+    unsafe { serial_driver::force_unlock() };
+
+    // Report the stop at the breakpoint instruction, not after it
+    if frame.vector == 3 && is_breakpoint(frame.rip.wrapping_sub(1)) {
+        frame.rip -= 1;
+    }
+    frame.rflags &= !RFLAGS_TF;
+
+    let signal = signal_for(frame.vector);
+    let mut packet = [0u8; PACKET_SIZE];
+    let mut reply = Reply::new();
+
+    stop_reply(&mut reply, signal);
+    send_packet(&reply);
+
+    loop {
+        let len = receive_packet(&mut packet);
+        let cmd = &packet[..len];
+        let args = cmd.get(1..).unwrap_or(&[]);
+        reply.clear();
+
+        match cmd.first() {
+            Some(b'?') => stop_reply(&mut reply, signal),
+            Some(b'g') => read_registers(frame, &mut reply),
+            Some(b'G') => {
+                write_registers(frame, args);
+                push_str(&mut reply, "OK");
+            }
+            Some(b'm') => read_memory(args, &mut reply),
+            Some(b'M') => write_memory(args, &mut reply),
+            Some(b'c') => {
+                resume(frame, args, false);
+                return;
+            }
+            Some(b's') => {
+                resume(frame, args, true);
+                return;
+            }
+            Some(b'Z') => set_breakpoint(args, true, &mut reply),
+            Some(b'z') => set_breakpoint(args, false, &mut reply),
+            Some(b'D') => {
+                remove_all_breakpoints();
+                send_packet(b"OK");
+                return;
+            }
+            Some(b'k') => {
+                remove_all_breakpoints();
+                return;
+            }
+            Some(b'H') => push_str(&mut reply, "OK"),
+            Some(b'q') => query(args, &mut reply),
+            _ => {}
+        }
+
+        send_packet(&reply);
+    }
+}
+
+/// Map an exception vector to a POSIX signal number for GDB
+fn signal_for(vector: u64) -> u8 {
+    match vector {
+        1 | 3 => 5,       // SIGTRAP
+        0 | 16 | 19 => 8, // SIGFPE
+        6 => 4,           // SIGILL
+        17 => 7,          // SIGBUS
+        11..=14 => 11,    // SIGSEGV
+        _ => 5,
+    }
+}
+
+fn stop_reply(reply: &mut Reply, signal: u8) {
+    let _ = reply.push(b'S');
+    push_hex_byte(reply, signal);
+}
+
+/// Resume execution, optionally at a new address and/or single-stepping
+fn resume(frame: &mut ExceptionFrame, args: &[u8], step: bool) {
+    if let Some(addr) = parse_hex(args) {
+        frame.rip = addr;
+    }
+    if step {
+        frame.rflags |= RFLAGS_TF;
+    }
+}
+
+// ============================================================================
+// Registers
+// ============================================================================
+
+/// 64-bit registers in GDB's amd64 order (rax ... rip)
+fn gpr_mut(frame: &mut ExceptionFrame, index: usize) -> Option<&mut u64> {
+    Some(match index {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        _ => return None,
+    })
+}
+
+/// Number of 64-bit registers in the `g` packet
+const NUM_GPRS: usize = 17;
+
+fn read_registers(frame: &mut ExceptionFrame, reply: &mut Reply) {
+    for i in 0..NUM_GPRS {
+        let value = gpr_mut(frame, i).map_or(0, |r| *r);
+        push_hex_le(reply, value, 8);
+    }
+
+    // eflags, cs, ss, ds, es, fs, gs (32-bit each)
+    let segments = [frame.rflags, frame.cs, frame.ss, 0, 0, 0, 0];
+    for value in segments {
+        push_hex_le(reply, value, 4);
+    }
+}
+
+fn write_registers(frame: &mut ExceptionFrame, data: &[u8]) {
+    let mut chunks = data.chunks_exact(16);
+    for i in 0..NUM_GPRS {
+        let Some(value) = chunks.next().and_then(parse_hex_le) else {
+            return;
+        };
+        if let Some(reg) = gpr_mut(frame, i) {
+            *reg = value;
+        }
+    }
+
+    if let Some(value) = data
+        .get(NUM_GPRS * 16..NUM_GPRS * 16 + 8)
+        .and_then(parse_hex_le)
+    {
+        frame.rflags = value;
+    }
+}
+
+// ============================================================================
+// Memory
+// ============================================================================
+
+/// Parse `addr,len` (and return the remainder after an optional `:`)
+fn parse_addr_len(args: &[u8]) -> Option<(u64, usize, &[u8])> {
+    let comma = args.iter().position(|&b| b == b',')?;
+    let addr = parse_hex(&args[..comma])?;
+    let rest = &args[comma + 1..];
+    let (len, data) = match rest.iter().position(|&b| b == b':') {
+        Some(colon) => (&rest[..colon], &rest[colon + 1..]),
+        None => (rest, &[][..]),
+    };
+    Some((addr, parse_hex(len)? as usize, data))
+}
+
+/// Check that every page of `[addr, addr + len)` is mapped
+fn is_range_mapped(addr: u64, len: usize) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let Some(last) = addr.checked_add(len as u64 - 1) else {
+        return false;
+    };
+    let mut page = addr & !0xFFF;
+    while page <= last {
+        if !paging::is_mapped(page) {
+            return false;
+        }
+        page += 0x1000;
+    }
+    true
+}
+
+fn read_memory(args: &[u8], reply: &mut Reply) {
+    let Some((addr, len, _)) = parse_addr_len(args) else {
+        push_str(reply, "E01");
+        return;
+    };
+    let len = len.min(PACKET_SIZE / 2);
+    if !is_range_mapped(addr, len) {
+        push_str(reply, "E14");
+        return;
+    }
+
+    for i in 0..len as u64 {
+        let byte = unsafe { core::ptr::read_volatile((addr + i) as *const u8) };
+        push_hex_byte(reply, byte);
+    }
+}
+
+fn write_memory(args: &[u8], reply: &mut Reply) {
+    let Some((addr, len, data)) = parse_addr_len(args) else {
+        push_str(reply, "E01");
+        return;
+    };
+    if data.len() < len * 2 || !is_range_mapped(addr, len) {
+        push_str(reply, "E14");
+        return;
+    }
+
+    for (i, pair) in data.chunks_exact(2).take(len).enumerate() {
+        let Some(byte) = parse_hex(pair) else {
+            push_str(reply, "E01");
+            return;
+        };
+        poke(addr + i as u64, byte as u8);
+    }
+    push_str(reply, "OK");
+}
+
+// ============================================================================
+// Breakpoints
+// ============================================================================
+
+/// Write a byte, even to read-only firmware code
+fn poke(addr: u64, byte: u8) {
+    paging::without_write_protect(|| unsafe { core::ptr::write_volatile(addr as *mut u8, byte) });
+}
+
+fn is_breakpoint(addr: u64) -> bool {
+    BREAKPOINTS.lock().iter().any(|bp| bp.addr == addr)
+}
+
+/// Handle `Z0,addr,kind` / `z0,addr,kind`
+fn set_breakpoint(args: &[u8], insert: bool, reply: &mut Reply) {
+    // Only software breakpoints are supported
+    let Some(rest) = args.strip_prefix(b"0,") else {
+        return;
+    };
+    let Some((addr, _kind, _)) = parse_addr_len(rest) else {
+        push_str(reply, "E01");
+        return;
+    };
+    if !is_range_mapped(addr, 1) {
+        push_str(reply, "E14");
+        return;
+    }
+
+    let mut breakpoints = BREAKPOINTS.lock();
+    let existing = breakpoints.iter().position(|bp| bp.addr == addr);
+
+    match (insert, existing) {
+        (true, None) => {
+            let original = unsafe { core::ptr::read_volatile(addr as *const u8) };
+            if breakpoints.push(Breakpoint { addr, original }).is_err() {
+                push_str(reply, "E12");
+                return;
+            }
+            poke(addr, INT3);
+        }
+        (false, Some(index)) => {
+            let bp = breakpoints.swap_remove(index);
+            poke(bp.addr, bp.original);
+        }
+        _ => {}
+    }
+    push_str(reply, "OK");
+}
+
+fn remove_all_breakpoints() {
+    let mut breakpoints = BREAKPOINTS.lock();
+    for bp in breakpoints.iter() {
+        poke(bp.addr, bp.original);
+    }
+    breakpoints.clear();
+}
+
+// ============================================================================
+// Queries
+// ============================================================================
+
+fn query(args: &[u8], reply: &mut Reply) {
+    if args.starts_with(b"Supported") {
+        push_str(reply, "PacketSize=");
+        push_hex(reply, PACKET_SIZE as u64);
+    } else if args.starts_with(b"Attached") {
+        push_str(reply, "1");
+    }
+}
+
+// ============================================================================
+// Packet I/O
+// ============================================================================
+
+fn read_byte() -> u8 {
+    loop {
+        if let Some(byte) = serial_driver::try_read() {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Receive a `$<data>#<checksum>` packet into `buf`, returning its length
+fn receive_packet(buf: &mut [u8]) -> usize {
+    loop {
+        // Wait for the start of a packet (ignoring acks and interrupts)
+        while read_byte() != b'$' {}
+
+        let mut len = 0;
+        let mut checksum: u8 = 0;
+        let mut overflow = false;
+        loop {
+            let byte = read_byte();
+            if byte == b'#' {
+                break;
+            }
+            checksum = checksum.wrapping_add(byte);
+            if len < buf.len() {
+                buf[len] = byte;
+                len += 1;
+            } else {
+                overflow = true;
+            }
+        }
+
+        let expected = [read_byte(), read_byte()];
+        if !overflow && parse_hex(&expected) == Some(checksum as u64) {
+            serial_driver::write_byte(b'+');
+            return len;
+        }
+        serial_driver::write_byte(b'-');
+    }
+}
+
+/// Send a packet and wait for GDB to acknowledge it
+fn send_packet(data: &[u8]) {
+    let checksum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    loop {
+        serial_driver::write_byte(b'$');
+        for &byte in data {
+            serial_driver::write_byte(byte);
+        }
+        serial_driver::write_byte(b'#');
+        serial_driver::write_byte(HEX_DIGITS[(checksum >> 4) as usize]);
+        serial_driver::write_byte(HEX_DIGITS[(checksum & 0xF) as usize]);
+
+        match read_byte() {
+            b'-' => continue,
+            _ => return,
+        }
+    }
+}
+
+// ============================================================================
+// Hex encoding
+// ============================================================================
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn push_str(reply: &mut Reply, s: &str) {
+    let _ = reply.extend_from_slice(s.as_bytes());
+}
+
+fn push_hex_byte(reply: &mut Reply, byte: u8) {
+    let _ = reply.push(HEX_DIGITS[(byte >> 4) as usize]);
+    let _ = reply.push(HEX_DIGITS[(byte & 0xF) as usize]);
+}
+
+/// Push a value as big-endian hex without leading zeros
+fn push_hex(reply: &mut Reply, value: u64) {
+    let digits = (64 - value.leading_zeros()).div_ceil(4).max(1);
+    for i in (0..digits).rev() {
+        let _ = reply.push(HEX_DIGITS[((value >> (i * 4)) & 0xF) as usize]);
+    }
+}
+
+/// Push the low `bytes` bytes of a value in target (little-endian) order
+fn push_hex_le(reply: &mut Reply, value: u64, bytes: usize) {
+    for byte in &value.to_le_bytes()[..bytes] {
+        push_hex_byte(reply, *byte);
+    }
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parse big-endian hex (addresses, lengths, checksums)
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits
+        .iter()
+        .try_fold(0u64, |acc, &d| Some((acc << 4) | hex_value(d)? as u64))
+}
+
+/// Parse little-endian hex as sent in register packets
+fn parse_hex_le(digits: &[u8]) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    for (byte, pair) in bytes.iter_mut().zip(digits.chunks_exact(2)) {
+        *byte = (hex_value(pair[0])? << 4) | hex_value(pair[1])?;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
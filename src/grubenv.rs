@@ -0,0 +1,195 @@
+//! GRUB environment block
+//!
+//! `grub-set-default` and `grub-reboot` store the default and the one-time
+//! entry as `saved_entry` and `next_entry` in `grubenv`, a 1 KiB block of
+//! `name=value` lines padded with `#`. Distributions keep it next to
+//! `grub.cfg` in `\EFI\<distro>`, so CrabEFI looks for it in every directory
+//! under `\EFI` on the ESP and preselects the matching menu entry. That way
+//! the default chosen under GRUB carries over when moving to the built-in
+//! boot manager.
+//!
+//! `next_entry` takes precedence over `saved_entry`. The block is never
+//! written, so `next_entry` isn't cleared as GRUB would. Values match an
+//! entry by its title or Boot Loader Specification id, which is what GRUB's
+//! `blscfg` module saves; numeric indices can't be mapped to CrabEFI's
+//! menu and are ignored. A `default` in `loader.conf` wins over both.
+
+use heapless::String;
+
+use crate::fs::fat::FatFilesystem;
+use crate::menu::BootMenu;
+
+/// Size of the environment block
+pub const BLOCK_SIZE: usize = 1024;
+
+/// First line of a valid block
+const SIGNATURE: &str = "# GRUB Environment Block\n";
+
+/// File name of the block in each distribution directory
+const FILE_NAME: &str = "GRUBENV";
+
+/// Longest entry name kept
+const MAX_ENTRY_LEN: usize = 128;
+
+/// The settings of a GRUB environment block CrabEFI uses
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GrubEnv {
+    /// Default entry, set by `grub-set-default`
+    pub saved_entry: Option<String<MAX_ENTRY_LEN>>,
+    /// Entry for the next boot only, set by `grub-reboot`
+    pub next_entry: Option<String<MAX_ENTRY_LEN>>,
+}
+
+impl GrubEnv {
+    /// Parse an environment block, `None` without the signature
+    pub fn parse(block: &[u8]) -> Option<Self> {
+        let text = block.strip_prefix(SIGNATURE.as_bytes())?;
+        let mut env = GrubEnv::default();
+        for line in text.split(|&b| b == b'\n') {
+            // The padding, and comments
+            if line.first().is_none_or(|&b| b == b'#') {
+                continue;
+            }
+            let Some(equals) = line.iter().position(|&b| b == b'=') else {
+                continue;
+            };
+            let (name, value) = (&line[..equals], &line[equals + 1..]);
+            let slot = match name {
+                b"saved_entry" => &mut env.saved_entry,
+                b"next_entry" => &mut env.next_entry,
+                _ => continue,
+            };
+            *slot = unescape(value).filter(|value| !value.is_empty());
+        }
+        Some(env)
+    }
+
+    /// Find and read the environment block of the ESP
+    pub fn load(fat: &mut FatFilesystem<'_>) -> Option<Self> {
+        let efi = fat.find_file("EFI").ok().filter(|e| e.is_directory())?;
+        let cluster = efi.first_cluster();
+        for position in 0.. {
+            let Ok(Some(entry)) = fat.get_directory_entry_at_position(cluster, position) else {
+                break;
+            };
+            let name = entry.short_name();
+            if !entry.is_directory() || name.starts_with('.') {
+                continue;
+            }
+            let mut path: String<32> = String::new();
+            let _ = path.push_str("EFI\\");
+            let _ = path.push_str(&name);
+            let _ = path.push('\\');
+            let _ = path.push_str(FILE_NAME);
+
+            let mut block = [0u8; BLOCK_SIZE];
+            let Ok(len) = fat.read_file_all(&path, &mut block, None) else {
+                continue;
+            };
+            match Self::parse(&block[..len]) {
+                Some(env) => {
+                    log::info!("GRUB environment block {}: {:?}", path, env);
+                    return Some(env);
+                }
+                None => log::warn!("{} is not a GRUB environment block", path),
+            }
+        }
+        None
+    }
+
+    /// The entry GRUB would boot next
+    pub fn entry(&self) -> Option<&str> {
+        self.next_entry.as_deref().or(self.saved_entry.as_deref())
+    }
+
+    /// Select the menu entry GRUB would boot next
+    pub fn apply(&self, menu: &mut BootMenu) {
+        let Some(target) = self.entry() else {
+            return;
+        };
+        if target.bytes().all(|b| b.is_ascii_digit()) {
+            log::info!("GRUB: ignoring numeric default entry {}", target);
+            return;
+        }
+        // Entries in submenus are saved as `submenu>entry`
+        let target = target.rsplit('>').next().unwrap_or(target);
+        let index = (0..menu.entry_count()).find(|&index| {
+            menu.get_entry(index).is_some_and(|entry| {
+                entry.name == target
+                    || entry.loader_entry.as_ref().is_some_and(|id| {
+                        let target = target.strip_suffix(".conf").unwrap_or(target);
+                        id.eq_ignore_ascii_case(target)
+                    })
+            })
+        });
+        match index {
+            Some(index) => {
+                log::info!("GRUB: default entry {}", target);
+                menu.set_selected(index);
+            }
+            None => log::warn!("GRUB: default entry {} not found", target),
+        }
+    }
+}
+
+/// Undo GRUB's escaping of `\`, newlines and `#` with a backslash
+fn unescape(value: &[u8]) -> Option<String<MAX_ENTRY_LEN>> {
+    let mut unescaped = heapless::Vec::new();
+    let mut bytes = value.iter();
+    while let Some(&b) = bytes.next() {
+        let b = match b {
+            b'\\' => *bytes.next()?,
+            b => b,
+        };
+        unescaped.push(b).ok()?;
+    }
+    String::from_utf8(unescaped).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fat::FatType;
+    use crate::testing::{MemoryDisk, fat_image};
+
+    /// Pad environment lines to a block like grub-editenv does
+    fn block(lines: &str) -> std::vec::Vec<u8> {
+        let mut block = std::format!("{}{}", SIGNATURE, lines).into_bytes();
+        block.resize(BLOCK_SIZE, b'#');
+        block
+    }
+
+    #[test]
+    fn parses_block() {
+        let env = GrubEnv::parse(&block(
+            "saved_entry=Fedora Linux (6.8.5)\\\\x\nboot_success=1\nnext_entry=\n",
+        ))
+        .unwrap();
+        assert_eq!(env.saved_entry.as_deref(), Some("Fedora Linux (6.8.5)\\x"));
+        assert_eq!(env.next_entry, None);
+        assert_eq!(env.entry(), Some("Fedora Linux (6.8.5)\\x"));
+
+        let env = GrubEnv::parse(&block("saved_entry=a\nnext_entry=b\n")).unwrap();
+        assert_eq!(env.entry(), Some("b"));
+
+        assert_eq!(GrubEnv::parse(&[b'#'; BLOCK_SIZE]), None);
+    }
+
+    #[test]
+    fn finds_block() {
+        let env = block("saved_entry=Advanced options>Debian GNU/Linux\n");
+        let esp = fat_image(
+            FatType::Fat16,
+            &[
+                ("EFI/BOOT/BOOTX64.EFI", b"MZ"),
+                ("EFI/DEBIAN/GRUB.CFG", b"set default=0"),
+                ("EFI/DEBIAN/GRUBENV", &env),
+            ],
+        );
+        let mut disk = MemoryDisk::new(esp, 512);
+        let mut fat = FatFilesystem::new(&mut disk, 0).unwrap();
+
+        let env = GrubEnv::load(&mut fat).unwrap();
+        assert_eq!(env.entry(), Some("Advanced options>Debian GNU/Linux"));
+    }
+}
@@ -0,0 +1,319 @@
+//! Early Boot Hotkeys
+//!
+//! This module implements a short keystroke scan window that runs after the
+//! input devices are initialized but before the boot menu starts its auto-boot
+//! countdown. It polls the serial console, the PS/2 keyboard and any USB
+//! keyboards for a small set of well-known hotkeys:
+//!
+//! | Key | Action                                   |
+//! |-----|------------------------------------------|
+//! | Esc | Show the boot menu and wait for the user |
+//! | F12 | One-time boot device picker              |
+//! | F11 | Boot once from USB                       |
+//! | F2  | Setup screen, then the boot menu         |
+//! | F10 | Break into the GDB stub (`gdbstub` only) |
+//!
+//! # Configuration
+//!
+//! The scan window and key bindings can be overridden through EFI variables
+//! under [`CRABEFI_VARIABLE_GUID`]:
+//!
+//! - `HotkeyTimeout` (u16, milliseconds): length of the scan window, 0 disables it
+//! - `HotkeyBootMenu`, `HotkeyBootPicker`, `HotkeyBootUsb`, `HotkeySetup`,
+//!   `HotkeyDebugger` (u16): EFI scan code bound to each action, 0 disables
+//!   the binding
+//!
+//! The variables only last across reboots when they are set non-volatile
+//! and the board has an SMMSTORE region. Settings without a variable are
+//! taken from the [VPD](crate::coreboot::vpd) key `crabefi_hotkeys`, a list
+//! like `timeout=2000,menu=esc,setup=f1,usb=none` whose names are
+//! `timeout`, `menu`, `picker`, `usb`, `setup` and `debugger` and whose keys
+//! are `esc`, `f1` to `f12`, `none` or an EFI scan code.
+//!
+//! The prompt shown during the scan window names the keys bound.
+
+use core::fmt::{self, Write};
+
+use heapless::String;
+
+use crate::coreboot::vpd;
+use crate::drivers::keyboard;
+use crate::drivers::serial as serial_driver;
+use crate::drivers::serial_keys::{ESCAPE_TIMEOUT_MS, KeyDecoder};
+use crate::efi::protocols::console::scan_codes;
+use crate::efi::runtime_services::{CRABEFI_VARIABLE_GUID, read_variable_u16};
+use crate::time::{Timeout, delay_ms};
+
+/// Default length of the hotkey scan window in milliseconds
+const DEFAULT_WINDOW_MS: u16 = 1000;
+
+/// Action requested by an early boot hotkey
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotkeyAction {
+    /// Show the boot menu without an auto-boot countdown
+    BootMenu,
+    /// Show the one-time boot device picker
+    BootPicker,
+    /// Boot the first USB entry once, see [`crate::boot_priority`]
+    BootUsb,
+    /// Show the setup screen
+    Setup,
+    /// Break into the GDB stub
+    #[cfg(feature = "gdbstub")]
+    Debugger,
+}
+
+/// Hotkey configuration (scan window and key bindings)
+#[derive(Debug, Clone, Copy)]
+pub struct HotkeyConfig {
+    /// Scan window in milliseconds (0 = disabled)
+    pub window_ms: u16,
+    /// EFI scan code that opens the boot menu
+    pub boot_menu: u16,
+    /// EFI scan code that opens the boot device picker
+    pub boot_picker: u16,
+    /// EFI scan code that boots once from USB
+    pub boot_usb: u16,
+    /// EFI scan code that opens the setup screen
+    pub setup: u16,
+    /// EFI scan code that breaks into the GDB stub
+    #[cfg(feature = "gdbstub")]
+    pub debugger: u16,
+}
+
+impl Default for HotkeyConfig {
+    fn default() -> Self {
+        Self {
+            window_ms: DEFAULT_WINDOW_MS,
+            boot_menu: scan_codes::SCAN_ESC,
+            boot_picker: scan_codes::SCAN_F12,
+            boot_usb: scan_codes::SCAN_F11,
+            setup: scan_codes::SCAN_F2,
+            #[cfg(feature = "gdbstub")]
+            debugger: scan_codes::SCAN_F10,
+        }
+    }
+}
+
+impl HotkeyConfig {
+    /// Load the configuration, applying any overrides from VPD and EFI
+    /// variables
+    pub fn load() -> Self {
+        let mut config = Self::default();
+        let guid = &CRABEFI_VARIABLE_GUID;
+
+        if let Some(list) = vpd::find_str(vpd::KEY_HOTKEYS) {
+            config.apply_list(list);
+        }
+
+        if let Some(ms) = read_variable_u16("HotkeyTimeout", guid) {
+            config.window_ms = ms;
+        }
+        if let Some(code) = read_variable_u16("HotkeyBootMenu", guid) {
+            config.boot_menu = code;
+        }
+        if let Some(code) = read_variable_u16("HotkeyBootPicker", guid) {
+            config.boot_picker = code;
+        }
+        if let Some(code) = read_variable_u16("HotkeyBootUsb", guid) {
+            config.boot_usb = code;
+        }
+        if let Some(code) = read_variable_u16("HotkeySetup", guid) {
+            config.setup = code;
+        }
+        #[cfg(feature = "gdbstub")]
+        if let Some(code) = read_variable_u16("HotkeyDebugger", guid) {
+            config.debugger = code;
+        }
+
+        config
+    }
+
+    /// Apply a `crabefi_hotkeys` list, skipping invalid settings
+    fn apply_list(&mut self, list: &str) {
+        for setting in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let Some((name, value)) = setting.split_once('=') else {
+                log::warn!("Hotkeys: invalid setting {:?}", setting);
+                continue;
+            };
+            let value = value.trim();
+            let field = match name.trim() {
+                "timeout" => &mut self.window_ms,
+                "menu" => &mut self.boot_menu,
+                "picker" => &mut self.boot_picker,
+                "usb" => &mut self.boot_usb,
+                "setup" => &mut self.setup,
+                #[cfg(feature = "gdbstub")]
+                "debugger" => &mut self.debugger,
+                _ => {
+                    log::warn!("Hotkeys: unknown setting {:?}", setting);
+                    continue;
+                }
+            };
+            let parsed = if name.trim() == "timeout" {
+                value.parse().ok()
+            } else {
+                parse_key(value)
+            };
+            match parsed {
+                Some(parsed) => *field = parsed,
+                None => log::warn!("Hotkeys: invalid setting {:?}", setting),
+            }
+        }
+    }
+
+    /// The bound keys and what they do
+    fn bindings(&self) -> impl Iterator<Item = (u16, &'static str)> {
+        [
+            (self.boot_menu, "boot menu"),
+            (self.boot_picker, "boot device"),
+            (self.boot_usb, "USB"),
+            (self.setup, "setup"),
+            #[cfg(feature = "gdbstub")]
+            (self.debugger, "debugger"),
+        ]
+        .into_iter()
+        .filter(|&(scan_code, _)| scan_code != 0)
+    }
+
+    /// Prompt naming the bound keys, e.g. `Press Esc for boot menu, F2 for setup`
+    fn prompt(&self) -> String<160> {
+        let mut prompt = String::new();
+        for (scan_code, action) in self.bindings() {
+            let _ = prompt.push_str(if prompt.is_empty() { "Press " } else { ", " });
+            let _ = write_key_name(&mut prompt, scan_code);
+            let _ = write!(prompt, " for {}", action);
+        }
+        prompt
+    }
+
+    /// Map an EFI scan code to the action bound to it
+    pub fn action_for(&self, scan_code: u16) -> Option<HotkeyAction> {
+        if scan_code == 0 {
+            None
+        } else if scan_code == self.boot_menu {
+            Some(HotkeyAction::BootMenu)
+        } else if scan_code == self.boot_picker {
+            Some(HotkeyAction::BootPicker)
+        } else if scan_code == self.boot_usb {
+            Some(HotkeyAction::BootUsb)
+        } else if scan_code == self.setup {
+            Some(HotkeyAction::Setup)
+        } else {
+            #[cfg(feature = "gdbstub")]
+            if scan_code == self.debugger {
+                return Some(HotkeyAction::Debugger);
+            }
+            None
+        }
+    }
+}
+
+/// Scan for early boot hotkeys using the configuration from EFI variables
+///
+/// Blocks for at most the configured scan window and returns as soon as a
+/// bound hotkey is seen. Other keys pressed during the window are discarded.
+pub fn scan() -> Option<HotkeyAction> {
+    let config = HotkeyConfig::load();
+    if config.window_ms == 0 || config.bindings().next().is_none() {
+        return None;
+    }
+
+    log::info!("{} ({} ms)", config.prompt(), config.window_ms);
+
+    let timeout = Timeout::from_ms(config.window_ms as u64);
+    while !timeout.is_expired() {
+        if let Some(scan_code) = read_scan_code()
+            && let Some(action) = config.action_for(scan_code)
+        {
+            log::info!("Hotkey pressed: {:?}", action);
+            return Some(action);
+        }
+
+        delay_ms(10);
+    }
+
+    None
+}
+
+/// Write the name of the key with EFI scan code `scan_code`
+fn write_key_name(out: &mut impl Write, scan_code: u16) -> fmt::Result {
+    match scan_code {
+        scan_codes::SCAN_ESC => out.write_str("Esc"),
+        scan_codes::SCAN_F1..=scan_codes::SCAN_F12 => {
+            write!(out, "F{}", scan_code - scan_codes::SCAN_F1 + 1)
+        }
+        _ => write!(out, "key {:#x}", scan_code),
+    }
+}
+
+/// EFI scan code of a key name in a `crabefi_hotkeys` list, 0 for `none`
+fn parse_key(name: &str) -> Option<u16> {
+    if name.eq_ignore_ascii_case("none") {
+        return Some(0);
+    }
+    if name.eq_ignore_ascii_case("esc") {
+        return Some(scan_codes::SCAN_ESC);
+    }
+    if let Some(number) = name.strip_prefix(['f', 'F']) {
+        return match number.parse::<u16>() {
+            Ok(n @ 1..=12) => Some(scan_codes::SCAN_F1 + n - 1),
+            _ => None,
+        };
+    }
+    match name.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => name.parse().ok(),
+    }
+}
+
+/// Read the EFI scan code of the next pending special key, if any
+///
+/// PS/2 and USB keyboards are handled by the keyboard driver; serial input is
+/// decoded from ANSI/VT escape sequences.
+fn read_scan_code() -> Option<u16> {
+    if let Some((scan_code, _)) = keyboard::try_read_key() {
+        return Some(scan_code);
+    }
+
+    let mut decoder = KeyDecoder::new();
+    decoder.push(serial_driver::try_read()?);
+    if !decoder.in_sequence() {
+        return None;
+    }
+
+    // Collect the rest of the escape sequence (if any)
+    delay_ms(ESCAPE_TIMEOUT_MS);
+    while decoder.in_sequence()
+        && let Some(byte) = serial_driver::try_read()
+    {
+        decoder.push(byte);
+    }
+    decoder.flush();
+    decoder.pop().map(|(scan_code, _)| scan_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_vpd_list() {
+        let mut config = HotkeyConfig::default();
+        assert_eq!(
+            config.prompt(),
+            "Press Esc for boot menu, F12 for boot device, F11 for USB, F2 for setup"
+        );
+
+        config.apply_list("timeout=2500, setup=F1,usb=none,picker=0x9,menu=f13,bogus");
+        assert_eq!(config.window_ms, 2500);
+        assert_eq!(config.setup, scan_codes::SCAN_F1);
+        assert_eq!(config.boot_picker, scan_codes::SCAN_PAGE_UP);
+        assert_eq!(config.boot_menu, scan_codes::SCAN_ESC);
+        assert_eq!(config.action_for(scan_codes::SCAN_F11), None);
+        assert_eq!(
+            config.prompt(),
+            "Press Esc for boot menu, key 0x9 for boot device, F1 for setup"
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Image execution policy
+//!
+//! Without Secure Boot, operators can still keep known-bad EFI binaries,
+//! like a vulnerable boot loader on a USB stick, from running. Images are
+//! identified by the SHA-256 hash of the whole file, as `sha256sum` prints
+//! it.
+//!
+//! An image on the deny list is never loaded. The deny list is the CBFS
+//! file `crabefi/deny-hashes` together with the non-volatile variable
+//! `ImageDenyList` under [`CRABEFI_VARIABLE_GUID`], which holds up to
+//! [`MAX_VARIABLE_HASHES`] raw 32-byte hashes and can be extended from the
+//! OS. With the CBFS file `crabefi/allow-hashes`, images from removable
+//! media must be on the allow list; a variable could be written by any OS,
+//! so the allow list only comes from the write-protected flash.
+//!
+//! The CBFS lists use the `sha256sum` format, the file names are comments:
+//!
+//! ```text
+//! # cbfstool coreboot.rom add -f deny -n crabefi/deny-hashes -t raw
+//! 0d1e2f...  grubx64.efi (vulnerable)
+//! ```
+//!
+//! The deny list applies to every image. The allow list is the default
+//! file authentication of the Security2 architectural protocol, so a boot
+//! loader that verifies the images it starts itself can take over that
+//! check, see [`crate::efi::protocols::security`].
+
+use spin::Mutex;
+
+use crate::coreboot::cbfs;
+use crate::crypto::Digest;
+use crate::crypto::sha256::Sha256;
+use crate::efi::runtime_services::{CRABEFI_VARIABLE_GUID, read_variable};
+
+/// CBFS file holding the deny list
+pub const DENY_CBFS_NAME: &str = "crabefi/deny-hashes";
+
+/// CBFS file holding the allow list for removable media
+pub const ALLOW_CBFS_NAME: &str = "crabefi/allow-hashes";
+
+/// Variable extending the deny list
+const DENY_VARIABLE: &str = "ImageDenyList";
+
+/// Most hashes read from the deny list variable
+pub const MAX_VARIABLE_HASHES: usize = 64;
+
+/// Size of a SHA-256 hash
+pub const HASH_SIZE: usize = 32;
+
+/// Why an image may not run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyError {
+    /// The image is on the deny list
+    Denied,
+    /// The image is from removable media and not on the allow list
+    NotAllowed,
+}
+
+/// A list of hashes in `sha256sum` format
+#[derive(Clone, Copy)]
+struct HashList<'a> {
+    text: &'a str,
+}
+
+impl<'a> HashList<'a> {
+    /// Hash of each valid line
+    fn hashes(&self) -> impl Iterator<Item = [u8; HASH_SIZE]> + 'a {
+        self.text.lines().filter_map(|line| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let hash = line.split_whitespace().next().and_then(parse_hash);
+            if hash.is_none() {
+                log::warn!("Image policy: ignoring invalid line {:?}", line);
+            }
+            hash
+        })
+    }
+
+    fn contains(&self, hash: &[u8; HASH_SIZE]) -> bool {
+        self.hashes().any(|listed| listed == *hash)
+    }
+}
+
+/// Parse a hash written in hex
+pub fn parse_hash(hex: &str) -> Option<[u8; HASH_SIZE]> {
+    if hex.len() != HASH_SIZE * 2 {
+        return None;
+    }
+    let mut hash = [0u8; HASH_SIZE];
+    for (byte, pair) in hash.iter_mut().zip(hex.as_bytes().as_chunks::<2>().0) {
+        *byte = u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// The lists an image is checked against
+struct Policy<'a> {
+    deny: Option<HashList<'a>>,
+    /// Raw hashes from the deny list variable
+    deny_variable: &'a [u8],
+    allow: Option<HashList<'a>>,
+}
+
+impl Policy<'_> {
+    fn is_denied(&self, hash: &[u8; HASH_SIZE]) -> bool {
+        self.deny.is_some_and(|list| list.contains(hash))
+            || self.deny_variable.as_chunks::<HASH_SIZE>().0.contains(hash)
+    }
+
+    fn is_allowed(&self, hash: &[u8; HASH_SIZE], removable: bool) -> bool {
+        !removable || self.allow.is_none_or(|list| list.contains(hash))
+    }
+}
+
+/// The lists from CBFS
+static LISTS: Mutex<(Option<HashList<'static>>, Option<HashList<'static>>)> =
+    Mutex::new((None, None));
+
+/// Read a list from CBFS
+fn cbfs_list(name: &str) -> Option<HashList<'static>> {
+    let data = cbfs::find_file(name)?;
+    // An unreadable list is still used: an empty allow list refuses all
+    let text = core::str::from_utf8(data).unwrap_or_else(|_| {
+        log::error!("Image policy: {} is not text", name);
+        ""
+    });
+    let list = HashList { text };
+    log::info!("Image policy: {} hashes in {}", list.hashes().count(), name);
+    Some(list)
+}
+
+/// Load the deny and allow lists from CBFS
+pub fn init() {
+    *LISTS.lock() = (cbfs_list(DENY_CBFS_NAME), cbfs_list(ALLOW_CBFS_NAME));
+}
+
+/// SHA-256 hash of an image file
+pub fn hash(data: &[u8]) -> [u8; HASH_SIZE] {
+    let mut digest = Sha256::default();
+    digest.update(data);
+    let mut hash = [0u8; HASH_SIZE];
+    digest.finalize_into(&mut hash);
+    hash
+}
+
+/// Run `f` with the current policy
+///
+/// The variable is read each time, so hashes added during boot apply.
+fn with_policy<R>(f: impl FnOnce(&Policy) -> R) -> R {
+    let mut variable = [0u8; MAX_VARIABLE_HASHES * HASH_SIZE];
+    let size = read_variable(DENY_VARIABLE, &CRABEFI_VARIABLE_GUID, &mut variable).unwrap_or(0);
+    let (deny, allow) = *LISTS.lock();
+    f(&Policy {
+        deny,
+        deny_variable: &variable[..size],
+        allow,
+    })
+}
+
+/// Log a refused image
+fn refuse(hash: &[u8; HASH_SIZE], error: PolicyError) -> Result<(), PolicyError> {
+    let mut hex = heapless::String::<{ HASH_SIZE * 2 }>::new();
+    for byte in hash {
+        let _ = core::fmt::write(&mut hex, format_args!("{:02x}", byte));
+    }
+    log::error!("Image policy: refusing image {}: {:?}", hex, error);
+    Err(error)
+}
+
+/// Check an image against the deny list
+pub fn check_denied(data: &[u8]) -> Result<(), PolicyError> {
+    let hash = hash(data);
+    if with_policy(|policy| policy.is_denied(&hash)) {
+        return refuse(&hash, PolicyError::Denied);
+    }
+    Ok(())
+}
+
+/// Check an image against the allow list, if it is from removable media
+pub fn check_allowed(data: &[u8], removable: bool) -> Result<(), PolicyError> {
+    let hash = hash(data);
+    if !with_policy(|policy| policy.is_allowed(&hash, removable)) {
+        return refuse(&hash, PolicyError::NotAllowed);
+    }
+    Ok(())
+}
+
+/// Check an image against both lists
+pub fn check(data: &[u8], removable: bool) -> Result<(), PolicyError> {
+    check_denied(data)?;
+    check_allowed(data, removable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_lists() {
+        // sha256("abc") and sha256("")
+        let abc = hash(b"abc");
+        let empty = hash(b"");
+        let deny = HashList {
+            text: "# known bad\n\
+                   ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  bad.efi\n\
+                   not a hash\n",
+        };
+        let allow = HashList {
+            text: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\n",
+        };
+
+        let policy = Policy {
+            deny: Some(deny),
+            deny_variable: &[],
+            allow: Some(allow),
+        };
+        assert!(policy.is_denied(&abc));
+        assert!(!policy.is_denied(&empty));
+        assert!(policy.is_allowed(&empty, true));
+        assert!(!policy.is_allowed(&hash(b"abd"), true));
+        assert!(policy.is_allowed(&hash(b"abd"), false));
+
+        let policy = Policy {
+            deny: None,
+            deny_variable: &[[0; HASH_SIZE], empty].concat(),
+            allow: None,
+        };
+        assert!(policy.is_denied(&empty));
+        assert!(!policy.is_denied(&abc));
+        assert!(policy.is_allowed(&abc, true));
+    }
+}
@@ -3,7 +3,7 @@
 //! This library provides the core functionality for a minimal UEFI environment
 //! that can boot Linux via shim+GRUB2 or systemd-boot on real laptop hardware.
 
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 #![feature(abi_x86_interrupt)]
 #![allow(unsafe_op_in_unsafe_fn)]
 // Allow common firmware code patterns
@@ -14,37 +14,71 @@
 // Note: We don't use alloc for now as we don't have a heap allocator yet
 // extern crate alloc;
 
+pub mod acpi;
 pub mod arch;
+pub mod bls;
+pub mod boot_log;
+pub mod boot_options;
+pub mod boot_paths;
+pub mod boot_priority;
+pub mod boot_slots;
+pub mod cmdline;
+pub mod compression;
 pub mod coreboot;
+pub mod crash;
+pub mod crypto;
+pub mod display;
 pub mod drivers;
 pub mod efi;
 #[cfg(feature = "fb-log")]
 pub mod fb_log;
+pub mod fb_shadow;
 pub mod framebuffer_console;
 pub mod fs;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+#[cfg(feature = "gdbstub")]
+pub mod gdbstub;
+pub mod grubenv;
+pub mod hotkey;
+pub mod image_policy;
+pub mod log_buffer;
 pub mod logger;
+pub mod memtest;
 pub mod menu;
+pub mod parallel;
+pub mod payload;
 pub mod pe;
+pub mod platform;
+pub mod recovery;
+pub mod resume;
+pub mod sed;
+pub mod setup_password;
+pub mod splash;
+pub mod ssdt;
 pub mod state;
+#[cfg(test)]
+mod testing;
 pub mod time;
+pub mod timing;
+pub mod uki;
+pub mod verity;
 
 use crate::drivers::block::{AhciDisk, BlockDevice, NvmeDisk, SdhciDisk, UsbDisk};
-use core::panic::PanicInfo;
+use crate::timing::Stage;
 
 /// Global panic handler
+///
+/// Host builds (`std` feature) use the standard library's handler instead.
+#[cfg(not(feature = "std"))]
 #[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    // Try to print the panic message to serial
-    if let Some(location) = info.location() {
-        log::error!(
-            "PANIC at {}:{}: {}",
-            location.file(),
-            location.line(),
-            info.message()
-        );
-    } else {
-        log::error!("PANIC: {}", info.message());
-    }
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    // Report to serial, the CBMEM console and the CBMEM crash region
+    crash::report_panic(info);
+
+    // Give an attached debugger a chance to inspect the panic
+    #[cfg(feature = "gdbstub")]
+    gdbstub::breakpoint();
 
     // Halt the CPU
     loop {
@@ -71,7 +105,8 @@ fn sort_partitions_by_size(partitions: &mut heapless::Vec<(u32, fs::gpt::Partiti
 /// # Arguments
 ///
 /// * `coreboot_table_ptr` - Pointer to the coreboot tables
-pub fn init(coreboot_table_ptr: u64) {
+/// * `loader` - Information from the loader that started CrabEFI, if not coreboot
+pub fn init(coreboot_table_ptr: u64, loader: Option<coreboot::fallback::LoaderInfo>) {
     // Allocate firmware state on the stack
     // This is THE primary state for the entire firmware
     let mut firmware_state = state::FirmwareState::new();
@@ -85,7 +120,10 @@ pub fn init(coreboot_table_ptr: u64) {
 
     // Parse coreboot tables first (before any I/O) to get hardware info
     // SAFETY: coreboot_table_ptr is passed from coreboot and points to valid tables
-    let cb_info = unsafe { coreboot::tables::parse(coreboot_table_ptr as *const u8) };
+    let mut cb_info = unsafe { coreboot::tables::parse(coreboot_table_ptr as *const u8) };
+
+    // Without coreboot tables, get far enough to boot from disk anyway
+    coreboot::fallback::apply(&mut cb_info, loader);
 
     // Initialize CBMEM console early (before logging) so all output goes there
     if let Some(cbmem_addr) = cb_info.cbmem_console {
@@ -146,9 +184,48 @@ pub fn init(coreboot_table_ptr: u64) {
     }
     log::info!("  Memory regions: {}", cb_info.memory_map.len());
 
+    // Board settings from the RO and RW VPD regions or the CMOS options
+    if let Some(table) = cb_info.cmos_option_table {
+        coreboot::cmos_options::init(table);
+    }
+    if let Some(vpd) = cb_info.vpd {
+        coreboot::vpd::init(vpd);
+    }
+    coreboot::vpd::apply_log_settings();
+
+    // Screen rotation, font scale and keyboard layout from VPD
+    if let Some(ref fb) = cb_info.framebuffer {
+        display::init(fb);
+    }
+    drivers::keyboard_layout::init();
+
+    // Console for the BMC's Serial-over-LAN
+    drivers::bmc_console::init(drivers::serial::port());
+
+    // Image hashes built into the firmware image enable verified boot
+    if let Some(ref boot_media) = cb_info.boot_media {
+        coreboot::cbfs::init(boot_media);
+    }
+    verity::init();
+    image_policy::init();
+
+    // Flash region for non-volatile variables, written through SMM or SPI
+    if let Some(ref smmstore) = cb_info.smmstore {
+        coreboot::smmstore::init(smmstore, cb_info.boot_media.as_ref());
+    }
+
+    // Select reset strategy and other board quirks
+    platform::init(cb_info.mainboard.as_ref());
+
+    // After suspend-to-RAM, go straight back to the OS
+    resume::handle_s3_resume(&cb_info);
+
     // Initialize timing subsystem (calibrate TSC using ACPI PM timer)
     time::init(cb_info.acpi_rsdp);
 
+    // Boot-time telemetry goes into coreboot's timestamp table too
+    timing::init(cb_info.timestamps);
+
     // Print memory map summary
     let total_ram: u64 = cb_info
         .memory_map
@@ -169,6 +246,29 @@ pub fn init(coreboot_table_ptr: u64) {
     // Initialize EFI environment
     efi::init(&cb_info);
 
+    // Map memory above the first 64GB (needs the EFI allocator)
+    #[cfg(target_arch = "x86_64")]
+    arch::x86_64::paging::map_memory(&cb_info.memory_map, efi::pool_guard::allocate_page_table);
+
+    // Draw the menu and log on a copy of the framebuffer in RAM
+    if let Some(ref fb) = cb_info.framebuffer {
+        fb_shadow::init(fb);
+    }
+
+    // Set up the CBMEM crash region (needs the EFI allocator)
+    crash::init(&cb_info);
+
+    // Catch boot stack overflows (needs the EFI allocator too)
+    #[cfg(target_arch = "x86_64")]
+    arch::x86_64::stack::init_guard();
+
+    // Map the firmware W^X and enable SMEP/SMAP/UMIP
+    #[cfg(target_arch = "x86_64")]
+    arch::x86_64::protect::init();
+
+    // Other coreboot payloads get the coreboot table
+    payload::init(cb_info.table_header);
+
     log::info!("CrabEFI initialized successfully!");
     log::info!("EFI System Table at: {:p}", efi::get_system_table());
 
@@ -191,34 +291,87 @@ fn init_storage() {
     log::info!("Initializing storage subsystem...");
 
     // Enumerate PCI devices
-    drivers::pci::init();
+    timing::measure(Stage::PciScan, drivers::pci::init);
     drivers::pci::print_devices();
 
-    // Initialize all storage controllers
-    drivers::nvme::init();
-    drivers::ahci::init();
-    drivers::usb::init_all();
-    drivers::sdhci::init();
+    // The display device is known now, look for its EDID
+    efi::protocols::edid::init();
+
+    // Initialize all storage controllers, overlapping their delays on the APs
+    parallel::run(&[
+        || timing::measure(Stage::Nvme, drivers::nvme::init),
+        || timing::measure(Stage::Ahci, drivers::ahci::init),
+        || timing::measure(Stage::Usb, drivers::usb::init_all),
+        || timing::measure(Stage::Sdhci, drivers::sdhci::init),
+    ]);
+
+    // Ask for the passphrases of locked drives before looking for ESPs
+    sed::unlock_drives();
 
     // Initialize pass-through protocols for TCG Opal support
     efi::protocols::pass_thru_init::init();
 
+    // All keyboards are up now, give the user a chance to press a hotkey
+    let hotkey = hotkey::scan();
+
     // Discover boot entries and show menu
     let mut boot_menu = menu::discover_boot_entries();
 
+    // The menu waits for a USB drive or SD card to be plugged in
     if boot_menu.entry_count() == 0 {
         log::warn!("No bootable media found!");
-        log::info!("Storage initialization complete");
-        return;
     }
 
+    // Pre-select the entry of the active A/B slot
+    boot_slots::select_default(&mut boot_menu);
+
+    // Default entry and timeout configured in VPD
+    coreboot::vpd::apply_boot_settings(&mut boot_menu);
+
+    // Let `bootctl status` see the menu
+    efi::loader_interface::publish_entries(&boot_menu);
+
+    match hotkey {
+        Some(hotkey::HotkeyAction::BootMenu) => boot_menu.set_timeout(0),
+        Some(hotkey::HotkeyAction::BootPicker) => {
+            boot_menu.set_timeout(0);
+            boot_menu.set_title(menu::PICKER_TITLE);
+        }
+        // Shows the menu if there is no USB entry
+        Some(hotkey::HotkeyAction::BootUsb) => boot_menu.set_timeout(0),
+        Some(hotkey::HotkeyAction::Setup) if setup_password::authorize("setup") => {
+            menu::show_setup(&boot_menu);
+            boot_menu.set_timeout(0);
+        }
+        #[cfg(feature = "gdbstub")]
+        Some(hotkey::HotkeyAction::Debugger) => {
+            log::info!("Waiting for GDB on the serial port...");
+            gdbstub::breakpoint();
+        }
+        Some(hotkey::HotkeyAction::Setup) | None => {}
+    }
+
+    // A one-time boot from another device skips the menu
+    let usb_hotkey = matches!(hotkey, Some(hotkey::HotkeyAction::BootUsb));
+    let boot_once = boot_priority::boot_once(
+        &boot_menu,
+        usb_hotkey.then_some(boot_priority::DeviceClass::Usb),
+    );
+
     // If only one entry and no interactive mode requested, boot directly
     // For now, always show the menu for testing
-    if let Some(selected_index) = menu::show_menu(&mut boot_menu)
+    if let Some(selected_index) = boot_once.or_else(|| menu::show_menu(&mut boot_menu))
         && let Some(entry) = boot_menu.get_entry(selected_index)
     {
         log::info!("Booting: {} from {}", entry.name, entry.path);
+        if let Some(options) = &entry.kernel_options {
+            cmdline::set_for_next_boot(options);
+        }
+        boot_slots::record_attempt(selected_index);
+        boot_log::record_start(entry);
+        efi::loader_interface::publish_selected(entry);
         boot_selected_entry(entry);
+        boot_log::record_outcome(boot_log::Outcome::Failed);
     }
 
     log::info!("Boot menu returned, storage initialization complete");
@@ -292,6 +445,7 @@ fn boot_selected_entry(entry: &menu::BootEntry) {
                         entry.pci_device,
                         entry.pci_function,
                         nsid,
+                        &entry.path,
                     ) {
                         return;
                     }
@@ -364,6 +518,7 @@ fn boot_selected_entry(entry: &menu::BootEntry) {
                         entry.pci_device,
                         entry.pci_function,
                         port as u16,
+                        &entry.path,
                     ) {
                         return;
                     }
@@ -373,10 +528,16 @@ fn boot_selected_entry(entry: &menu::BootEntry) {
         }
         menu::DeviceType::Usb {
             controller_id,
-            device_addr: _,
+            device_addr,
+            lun,
         } => {
             use drivers::storage::{self, StorageType};
 
+            if !drivers::usb::mass_storage::store_global_lun(controller_id, device_addr, lun) {
+                log::error!("Failed to store USB device globally");
+                return;
+            }
+
             // Get the controller pointer directly (no lock needed for the boot phase
             // since global_read_sector stores the pointer)
             let controller_ptr = match drivers::usb::get_controller_ptr(controller_id) {
@@ -395,7 +556,10 @@ fn boot_selected_entry(entry: &menu::BootEntry) {
 
                 // Register with storage abstraction (needed for BlockIO)
                 let storage_id = match storage::register_device(
-                    StorageType::Usb { slot_id: 0 },
+                    StorageType::Usb {
+                        slot_id: device_addr,
+                        lun,
+                    },
                     num_blocks,
                     block_size,
                 ) {
@@ -437,6 +601,7 @@ fn boot_selected_entry(entry: &menu::BootEntry) {
                         entry.pci_device,
                         entry.pci_function,
                         0, // USB port (default)
+                        &entry.path,
                     ) {
                         return;
                     }
@@ -496,6 +661,7 @@ fn boot_selected_entry(entry: &menu::BootEntry) {
                         entry.partition_num,
                         entry.pci_device,
                         entry.pci_function,
+                        &entry.path,
                     ) {
                         return;
                     }
@@ -503,6 +669,42 @@ fn boot_selected_entry(entry: &menu::BootEntry) {
             }
             log::error!("Failed to boot SDHCI entry");
         }
+        menu::DeviceType::RamDisk { id } => {
+            if try_boot_from_ram_disk(id, entry) {
+                return;
+            }
+            log::error!("Failed to boot RAM disk entry");
+        }
+        menu::DeviceType::Payload => {
+            let e = payload::boot(&entry.path);
+            log::error!("Failed to start payload {}: {:?}", entry.path, e);
+        }
+    }
+}
+
+/// Create BlockIO for partition `partition_num` of a disk
+///
+/// A LUKS2 partition is unlocked with a passphrase from the user and served
+/// decrypted; if it stays locked, it is served as is like other partitions.
+fn create_partition_block_io<R: BlockDevice>(
+    disk: &mut R,
+    partitions: &[fs::gpt::Partition],
+    partition: &fs::gpt::Partition,
+    partition_num: u32,
+    storage_id: u32,
+    block_size: u32,
+) -> *mut efi::protocols::block_io::BlockIoProtocol {
+    use efi::protocols::block_io;
+
+    match fs::luks::unlock_partition(disk, partitions, partition, storage_id) {
+        Some(unlocked) => block_io::create_decrypted_block_io(storage_id, partition_num, &unlocked),
+        None => block_io::create_partition_block_io(
+            storage_id,
+            partition_num,
+            partition.first_lba,
+            partition.size_sectors(),
+            block_size,
+        ),
     }
 }
 
@@ -594,12 +796,12 @@ fn install_block_io_for_disk<R: BlockDevice>(
     for (i, partition) in partitions.iter().enumerate() {
         let partition_num = (i + 1) as u32;
         let partition_blocks = partition.size_sectors();
-
-        let partition_block_io = block_io::create_partition_block_io(
-            storage_id,
+        let partition_block_io = create_partition_block_io(
+            disk,
+            &partitions,
+            partition,
             partition_num,
-            partition.first_lba,
-            partition_blocks,
+            storage_id,
             block_size,
         );
 
@@ -774,12 +976,12 @@ fn install_block_io_for_nvme_disk<R: BlockDevice>(
     for (i, partition) in partitions.iter().enumerate() {
         let partition_num = (i + 1) as u32;
         let partition_blocks = partition.size_sectors();
-
-        let partition_block_io = block_io::create_partition_block_io(
-            storage_id,
+        let partition_block_io = create_partition_block_io(
+            disk,
+            &partitions,
+            partition,
             partition_num,
-            partition.first_lba,
-            partition_blocks,
+            storage_id,
             block_size,
         );
 
@@ -952,12 +1154,12 @@ fn install_block_io_for_ahci_disk<R: BlockDevice>(
     for (i, partition) in partitions.iter().enumerate() {
         let partition_num = (i + 1) as u32;
         let partition_blocks = partition.size_sectors();
-
-        let partition_block_io = block_io::create_partition_block_io(
-            storage_id,
+        let partition_block_io = create_partition_block_io(
+            disk,
+            &partitions,
+            partition,
             partition_num,
-            partition.first_lba,
-            partition_blocks,
+            storage_id,
             block_size,
         );
 
@@ -1043,180 +1245,209 @@ fn install_block_io_for_ahci_disk<R: BlockDevice>(
     None
 }
 
-/// Try to boot from an ESP on USB (with SimpleFileSystem support)
+/// Boot `boot_path` from the FAT ESP of a disk
 ///
-/// # Arguments
-/// * `disk` - USB disk to read from (any SectorRead implementer)
-/// * `esp` - ESP partition info
-/// * `partition_num` - 1-based partition number of the ESP
-/// * `pci_device` - PCI device number of USB controller
-/// * `pci_function` - PCI function number
-/// * `usb_port` - USB port number
-fn try_boot_from_esp_usb<D: BlockDevice>(
+/// The ESP is mounted on `block_device` for the loader's Simple File System
+/// and gets a device handle with the device path `device_path` returns,
+/// Block I/O of the partition if the disk is registered as `storage` (type,
+/// number of blocks and block size), Simple File System and LoadFile.
+fn boot_from_esp<D: BlockDevice>(
     disk: &mut D,
+    block_device: drivers::block::AnyBlockDevice,
     esp: &fs::gpt::Partition,
     partition_num: u32,
-    pci_device: u8,
-    pci_function: u8,
-    usb_port: u8,
+    storage: Option<(drivers::storage::StorageType, u64, u32)>,
+    device_path: impl FnOnce() -> *mut r_efi::protocols::device_path::Protocol,
+    boot_path: &str,
 ) -> bool {
-    use drivers::block::{AnyBlockDevice, UsbBlockDevice};
-    use drivers::storage::{self, StorageType};
     use efi::boot_services;
     use efi::protocols::block_io::{self, BLOCK_IO_PROTOCOL_GUID};
-    use efi::protocols::device_path::{self, DEVICE_PATH_PROTOCOL_GUID};
+    use efi::protocols::device_path::DEVICE_PATH_PROTOCOL_GUID;
+    use efi::protocols::load_file::{self, LOAD_FILE_PROTOCOL_GUID};
     use efi::protocols::simple_file_system::{self, SIMPLE_FILE_SYSTEM_GUID};
     use r_efi::efi::Status;
 
-    // Get USB device info for creating block device
-    let (controller_id, device_addr, num_blocks, block_size) =
-        match drivers::usb::mass_storage::get_global_device() {
-            Some(usb_device) => (
-                0usize, // Controller ID (we only support one controller)
-                usb_device.slot_id(),
-                usb_device.num_blocks,
-                usb_device.block_size,
-            ),
-            None => {
-                log::error!("USB device not available");
-                return false;
-            }
-        };
-
-    // Create a UsbBlockDevice for the SimpleFileSystem protocol
-    let usb_block_device =
-        UsbBlockDevice::new(controller_id, device_addr, num_blocks, block_size, 0);
-    let block_device = AnyBlockDevice::Usb(usb_block_device);
-
     // Initialize SimpleFileSystem protocol with the block device
-    let sfs_protocol = simple_file_system::init(block_device, esp.first_lba);
+    let sfs_protocol = timing::measure(Stage::FsMount, || {
+        simple_file_system::init(block_device, esp.first_lba)
+    });
     if sfs_protocol.is_null() {
         log::error!("Failed to initialize SimpleFileSystem protocol");
         return false;
     }
 
-    // Verify filesystem is accessible by creating a temporary FatFilesystem
-    match fs::fat::FatFilesystem::new(disk, esp.first_lba) {
-        Ok(mut fat) => {
-            log::info!("FAT filesystem mounted on ESP");
+    let mut fat = match fs::fat::FatFilesystem::new(disk, esp.first_lba) {
+        Ok(fat) => fat,
+        Err(e) => {
+            log::error!("Failed to mount FAT filesystem: {:?}", e);
+            return false;
+        }
+    };
+    log::info!("FAT filesystem mounted on ESP");
 
-            // Create a device handle with SimpleFileSystem and DevicePath protocols
-            let device_handle = match boot_services::create_handle() {
-                Some(h) => h,
-                None => {
-                    log::error!("Failed to create device handle");
-                    return false;
-                }
-            };
+    // Create a device handle with SimpleFileSystem and DevicePath protocols
+    let Some(device_handle) = boot_services::create_handle() else {
+        log::error!("Failed to create device handle");
+        return false;
+    };
 
-            // Install DevicePath protocol on the device handle
-            // Use full USB partition path for proper hierarchy matching
-            let partition_size = esp.size_sectors();
-            let device_path = device_path::create_usb_partition_device_path(
-                pci_device,
-                pci_function,
-                usb_port,
-                partition_num,
-                esp.first_lba,
-                partition_size,
-                &esp.partition_guid,
+    let device_path = device_path();
+    if !device_path.is_null() {
+        let status = boot_services::install_protocol(
+            device_handle,
+            &DEVICE_PATH_PROTOCOL_GUID,
+            device_path as *mut core::ffi::c_void,
+        );
+        if status == Status::SUCCESS {
+            log::info!(
+                "DevicePath protocol installed on device handle {:?}",
+                device_handle
             );
+        } else {
+            log::warn!("Failed to install DevicePath protocol: {:?}", status);
+        }
+    }
 
-            if !device_path.is_null() {
-                let status = boot_services::install_protocol(
-                    device_handle,
-                    &DEVICE_PATH_PROTOCOL_GUID,
-                    device_path as *mut core::ffi::c_void,
+    // Install BlockIO protocol on the device handle
+    // The bootloader needs this to access the disk
+    if let Some((storage_type, num_blocks, block_size)) = storage
+        && let Some(storage_id) =
+            drivers::storage::register_device(storage_type, num_blocks, block_size)
+    {
+        let block_io = block_io::create_partition_block_io(
+            storage_id,
+            partition_num,
+            esp.first_lba,
+            esp.size_sectors(),
+            block_size,
+        );
+        if !block_io.is_null() {
+            let status = boot_services::install_protocol(
+                device_handle,
+                &BLOCK_IO_PROTOCOL_GUID,
+                block_io as *mut core::ffi::c_void,
+            );
+            if status == Status::SUCCESS {
+                log::info!(
+                    "BlockIO protocol installed on device handle {:?}",
+                    device_handle
                 );
-                if status == Status::SUCCESS {
-                    log::info!(
-                        "DevicePath protocol installed on device handle {:?}",
-                        device_handle
-                    );
-                } else {
-                    log::warn!("Failed to install DevicePath protocol: {:?}", status);
-                }
+            } else {
+                log::warn!("Failed to install BlockIO protocol: {:?}", status);
             }
+        }
+    }
 
-            // Install BlockIO protocol on the device handle
-            // The bootloader needs this to access the disk
-            if let Some(usb_device) = drivers::usb::mass_storage::get_global_device() {
-                let block_size = usb_device.block_size;
-                let num_blocks = usb_device.num_blocks;
-                // Get slot_id from the device
-                let slot_id = usb_device.slot_id();
+    // Install SimpleFileSystem protocol on the device handle
+    let status = boot_services::install_protocol(
+        device_handle,
+        &SIMPLE_FILE_SYSTEM_GUID,
+        sfs_protocol as *mut core::ffi::c_void,
+    );
+    if status != Status::SUCCESS {
+        log::error!("Failed to install SimpleFileSystem protocol: {:?}", status);
+        return false;
+    }
+    log::info!(
+        "SimpleFileSystem protocol installed on device handle {:?}",
+        device_handle
+    );
 
-                let storage_id =
-                    storage::register_device(StorageType::Usb { slot_id }, num_blocks, block_size);
+    // Install LoadFile protocol for LoadImage with BootPolicy
+    let status = boot_services::install_protocol(
+        device_handle,
+        &LOAD_FILE_PROTOCOL_GUID,
+        load_file::get_volume_protocol() as *mut core::ffi::c_void,
+    );
+    if status != Status::SUCCESS {
+        log::warn!("Failed to install LoadFile protocol: {:?}", status);
+    }
 
-                if let Some(storage_id) = storage_id {
-                    let block_io = block_io::create_partition_block_io(
-                        storage_id,
-                        partition_num,
-                        esp.first_lba,
-                        partition_size,
-                        block_size,
-                    );
+    // Look for EFI bootloader
+    match fat.file_size(boot_path) {
+        Ok(size) => {
+            log::info!("Found bootloader: {} ({} bytes)", boot_path, size);
 
-                    if !block_io.is_null() {
-                        let status = boot_services::install_protocol(
-                            device_handle,
-                            &BLOCK_IO_PROTOCOL_GUID,
-                            block_io as *mut core::ffi::c_void,
-                        );
-                        if status == Status::SUCCESS {
-                            log::info!(
-                                "BlockIO protocol installed on device handle {:?}",
-                                device_handle
-                            );
-                        } else {
-                            log::warn!("Failed to install BlockIO protocol: {:?}", status);
-                        }
-                    }
-                }
+            // Load and execute the bootloader with device handle
+            match load_and_execute_bootloader(&mut fat, boot_path, size, device_handle) {
+                Ok(()) => return true,
+                Err(e) => log::error!("Failed to execute bootloader: {:?}", e),
             }
+        }
+        Err(e) => log::warn!("Bootloader not found: {:?}", e),
+    }
+    false
+}
 
-            // Install SimpleFileSystem protocol on the device handle
-            let status = boot_services::install_protocol(
-                device_handle,
-                &SIMPLE_FILE_SYSTEM_GUID,
-                sfs_protocol as *mut core::ffi::c_void,
-            );
-
-            if status != Status::SUCCESS {
-                log::error!("Failed to install SimpleFileSystem protocol: {:?}", status);
-                return false;
-            }
+/// Try to boot from an ESP on USB (with SimpleFileSystem support)
+///
+/// # Arguments
+/// * `disk` - USB disk to read from (any SectorRead implementer)
+/// * `esp` - ESP partition info
+/// * `partition_num` - 1-based partition number of the ESP
+/// * `pci_device` - PCI device number of USB controller
+/// * `pci_function` - PCI function number
+/// * `usb_port` - USB port number
+/// * `boot_path` - Path of the EFI application on the ESP
+fn try_boot_from_esp_usb<D: BlockDevice>(
+    disk: &mut D,
+    esp: &fs::gpt::Partition,
+    partition_num: u32,
+    pci_device: u8,
+    pci_function: u8,
+    usb_port: u8,
+    boot_path: &str,
+) -> bool {
+    use drivers::block::{AnyBlockDevice, UsbBlockDevice};
+    use drivers::storage::StorageType;
+    use efi::protocols::device_path;
 
-            log::info!(
-                "SimpleFileSystem protocol installed on device handle {:?}",
-                device_handle
-            );
+    // Get USB device info for creating block device
+    let Some(usb_device) = drivers::usb::mass_storage::get_global_device() else {
+        log::error!("USB device not available");
+        return false;
+    };
+    let (slot_id, lun) = (usb_device.slot_id(), usb_device.lun());
+    let (num_blocks, block_size) = (usb_device.num_blocks, usb_device.block_size);
 
-            // Look for EFI bootloader
-            let boot_path = "EFI\\BOOT\\BOOTX64.EFI";
-            match fat.file_size(boot_path) {
-                Ok(size) => {
-                    log::info!("Found bootloader: {} ({} bytes)", boot_path, size);
-
-                    // Load and execute the bootloader with device handle
-                    match load_and_execute_bootloader(&mut fat, boot_path, size, device_handle) {
-                        Ok(()) => return true,
-                        Err(e) => {
-                            log::error!("Failed to execute bootloader: {:?}", e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    log::warn!("Bootloader not found: {:?}", e);
-                }
-            }
-        }
-        Err(e) => {
-            log::error!("Failed to mount FAT filesystem: {:?}", e);
+    // Create a UsbBlockDevice for the SimpleFileSystem protocol
+    // (controller 0, we only support one controller)
+    let usb_block_device = UsbBlockDevice::new(0, slot_id, num_blocks, block_size, 0);
+
+    // Use CDROM device path for El Torito (partition_num = 0) or
+    // full USB partition path for proper hierarchy matching
+    let device_path = || {
+        if partition_num == 0 {
+            device_path::create_usb_cdrom_device_path(
+                pci_device,
+                pci_function,
+                usb_port,
+                0, // boot_entry (El Torito catalog entry)
+                esp.first_lba,
+                esp.size_sectors(),
+            )
+        } else {
+            device_path::create_usb_partition_device_path(
+                pci_device,
+                pci_function,
+                usb_port,
+                partition_num,
+                esp.first_lba,
+                esp.size_sectors(),
+                &esp.partition_guid,
+            )
         }
-    }
-    false
+    };
+
+    boot_from_esp(
+        disk,
+        AnyBlockDevice::Usb(usb_block_device),
+        esp,
+        partition_num,
+        Some((StorageType::Usb { slot_id, lun }, num_blocks, block_size)),
+        device_path,
+        boot_path,
+    )
 }
 
 /// Debug helper: check if system table is intact
@@ -1251,6 +1482,7 @@ fn check_system_table_integrity(label: &str) {
 /// * `pci_device` - PCI device number of NVMe controller
 /// * `pci_function` - PCI function number
 /// * `namespace_id` - NVMe namespace ID
+/// * `boot_path` - Path of the EFI application on the ESP
 fn try_boot_from_esp_nvme(
     disk: &mut NvmeDisk,
     esp: &fs::gpt::Partition,
@@ -1258,14 +1490,11 @@ fn try_boot_from_esp_nvme(
     pci_device: u8,
     pci_function: u8,
     namespace_id: u32,
+    boot_path: &str,
 ) -> bool {
     use drivers::block::{AnyBlockDevice, NvmeBlockDevice};
-    use drivers::storage::{self, StorageType};
-    use efi::boot_services;
-    use efi::protocols::block_io::{self, BLOCK_IO_PROTOCOL_GUID};
-    use efi::protocols::device_path::{self, DEVICE_PATH_PROTOCOL_GUID};
-    use efi::protocols::simple_file_system::{self, SIMPLE_FILE_SYSTEM_GUID};
-    use r_efi::efi::Status;
+    use drivers::storage::StorageType;
+    use efi::protocols::device_path;
 
     check_system_table_integrity("NVMe: start");
 
@@ -1277,142 +1506,40 @@ fn try_boot_from_esp_nvme(
 
     // Create an NvmeBlockDevice for the SimpleFileSystem protocol
     let nvme_block_device = NvmeBlockDevice::new(0, namespace_id, num_blocks, block_size, 0);
-    let block_device = AnyBlockDevice::Nvme(nvme_block_device);
-
-    // Initialize SimpleFileSystem protocol with the block device
-    let sfs_protocol = simple_file_system::init(block_device, esp.first_lba);
-    if sfs_protocol.is_null() {
-        log::error!("Failed to initialize SimpleFileSystem protocol");
-        return false;
-    }
-    check_system_table_integrity("NVMe: after SFS init");
-
-    match fs::fat::FatFilesystem::new(disk, esp.first_lba) {
-        Ok(mut fat) => {
-            log::info!("FAT filesystem mounted on ESP");
-            check_system_table_integrity("NVMe: after FAT mount");
 
-            // Create a device handle with SimpleFileSystem and DevicePath protocols
-            let device_handle = match boot_services::create_handle() {
-                Some(h) => h,
-                None => {
-                    log::error!("Failed to create device handle");
-                    return false;
-                }
+    // Register the namespace for the BlockIO protocol
+    let storage = drivers::nvme::get_controller(0)
+        .and_then(|controller| controller.default_namespace())
+        .map(|ns| {
+            let storage_type = StorageType::Nvme {
+                controller_id: 0,
+                nsid: namespace_id,
             };
+            (storage_type, ns.num_blocks, ns.block_size)
+        });
 
-            // Install DevicePath protocol on the device handle
-            // Use full NVMe partition path for proper hierarchy matching
-            let partition_size = esp.size_sectors();
-            let device_path = device_path::create_nvme_partition_device_path(
-                pci_device,
-                pci_function,
-                namespace_id,
-                partition_num,
-                esp.first_lba,
-                partition_size,
-                &esp.partition_guid,
-            );
-
-            if !device_path.is_null() {
-                let status = boot_services::install_protocol(
-                    device_handle,
-                    &DEVICE_PATH_PROTOCOL_GUID,
-                    device_path as *mut core::ffi::c_void,
-                );
-                if status == Status::SUCCESS {
-                    log::info!(
-                        "DevicePath protocol installed on device handle {:?}",
-                        device_handle
-                    );
-                } else {
-                    log::warn!("Failed to install DevicePath protocol: {:?}", status);
-                }
-            }
-
-            // Install BlockIO protocol on the device handle
-            // The bootloader needs this to access the disk
-            if let Some(controller) = drivers::nvme::get_controller(0)
-                && let Some(ns) = controller.default_namespace()
-            {
-                let block_size = ns.block_size;
-                let storage_id = storage::register_device(
-                    StorageType::Nvme {
-                        controller_id: 0,
-                        nsid: namespace_id,
-                    },
-                    ns.num_blocks,
-                    block_size,
-                );
-
-                if let Some(storage_id) = storage_id {
-                    let block_io = block_io::create_partition_block_io(
-                        storage_id,
-                        partition_num,
-                        esp.first_lba,
-                        partition_size,
-                        block_size,
-                    );
-
-                    if !block_io.is_null() {
-                        let status = boot_services::install_protocol(
-                            device_handle,
-                            &BLOCK_IO_PROTOCOL_GUID,
-                            block_io as *mut core::ffi::c_void,
-                        );
-                        if status == Status::SUCCESS {
-                            log::info!(
-                                "BlockIO protocol installed on device handle {:?}",
-                                device_handle
-                            );
-                        } else {
-                            log::warn!("Failed to install BlockIO protocol: {:?}", status);
-                        }
-                    }
-                }
-            }
-
-            // Install SimpleFileSystem protocol on the device handle
-            let status = boot_services::install_protocol(
-                device_handle,
-                &SIMPLE_FILE_SYSTEM_GUID,
-                sfs_protocol as *mut core::ffi::c_void,
-            );
-
-            if status != Status::SUCCESS {
-                log::error!("Failed to install SimpleFileSystem protocol: {:?}", status);
-                return false;
-            }
-
-            log::info!(
-                "SimpleFileSystem protocol installed on device handle {:?}",
-                device_handle
-            );
+    // Use full NVMe partition path for proper hierarchy matching
+    let device_path = || {
+        device_path::create_nvme_partition_device_path(
+            pci_device,
+            pci_function,
+            namespace_id,
+            partition_num,
+            esp.first_lba,
+            esp.size_sectors(),
+            &esp.partition_guid,
+        )
+    };
 
-            // Look for EFI bootloader
-            let boot_path = "EFI\\BOOT\\BOOTX64.EFI";
-            match fat.file_size(boot_path) {
-                Ok(size) => {
-                    log::info!("Found bootloader: {} ({} bytes)", boot_path, size);
-
-                    // Load and execute the bootloader with device handle
-                    match load_and_execute_bootloader(&mut fat, boot_path, size, device_handle) {
-                        Ok(()) => return true,
-                        Err(e) => {
-                            log::error!("Failed to execute bootloader: {:?}", e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    log::warn!("Bootloader not found: {:?}", e);
-                }
-            }
-        }
-        Err(e) => {
-            log::error!("Failed to mount FAT filesystem: {:?}", e);
-        }
-    }
-    false
+    boot_from_esp(
+        disk,
+        AnyBlockDevice::Nvme(nvme_block_device),
+        esp,
+        partition_num,
+        storage,
+        device_path,
+        boot_path,
+    )
 }
 
 /// Try to boot from an ESP on AHCI (with SimpleFileSystem support)
@@ -1424,6 +1551,7 @@ fn try_boot_from_esp_nvme(
 /// * `pci_device` - PCI device number of AHCI controller
 /// * `pci_function` - PCI function number
 /// * `port` - AHCI port number
+/// * `boot_path` - Path of the EFI application on the ESP
 fn try_boot_from_esp_ahci(
     disk: &mut AhciDisk,
     esp: &fs::gpt::Partition,
@@ -1431,14 +1559,11 @@ fn try_boot_from_esp_ahci(
     pci_device: u8,
     pci_function: u8,
     port: u16,
+    boot_path: &str,
 ) -> bool {
     use drivers::block::{AhciBlockDevice, AnyBlockDevice};
-    use drivers::storage::{self, StorageType};
-    use efi::boot_services;
-    use efi::protocols::block_io::{self, BLOCK_IO_PROTOCOL_GUID};
-    use efi::protocols::device_path::{self, DEVICE_PATH_PROTOCOL_GUID};
-    use efi::protocols::simple_file_system::{self, SIMPLE_FILE_SYSTEM_GUID};
-    use r_efi::efi::Status;
+    use drivers::storage::StorageType;
+    use efi::protocols::device_path;
 
     // Get AHCI device info for creating block device
     let (num_blocks, block_size) = {
@@ -1448,155 +1573,52 @@ fn try_boot_from_esp_ahci(
 
     // Create an AhciBlockDevice for the SimpleFileSystem protocol
     let ahci_block_device = AhciBlockDevice::new(0, port as usize, num_blocks, block_size, 0);
-    let block_device = AnyBlockDevice::Ahci(ahci_block_device);
-
-    // Initialize SimpleFileSystem protocol with the block device
-    let sfs_protocol = simple_file_system::init(block_device, esp.first_lba);
-    if sfs_protocol.is_null() {
-        log::error!("Failed to initialize SimpleFileSystem protocol");
-        return false;
-    }
-
-    match fs::fat::FatFilesystem::new(disk, esp.first_lba) {
-        Ok(mut fat) => {
-            log::info!("FAT filesystem mounted on ESP");
 
-            // Create a device handle with SimpleFileSystem and DevicePath protocols
-            let device_handle = match boot_services::create_handle() {
-                Some(h) => h,
-                None => {
-                    log::error!("Failed to create device handle");
-                    return false;
-                }
+    // Register the port for the BlockIO protocol
+    let storage = drivers::ahci::get_controller(0)
+        .and_then(|controller| controller.get_port(port as usize))
+        .map(|port_info| {
+            let storage_type = StorageType::Ahci {
+                controller_id: 0,
+                port: port as usize,
             };
+            (storage_type, port_info.sector_count, port_info.sector_size)
+        });
 
-            // Install DevicePath protocol on the device handle
-            // Use CDROM device path for El Torito (partition_num = 0) or
-            // HardDrive device path for GPT partitions
-            let partition_size = esp.size_sectors();
-            let device_path = if partition_num == 0 {
-                // El Torito boot - use CD-ROM device path
-                device_path::create_sata_cdrom_device_path(
-                    pci_device,
-                    pci_function,
-                    port,
-                    0, // boot_entry (El Torito catalog entry)
-                    esp.first_lba,
-                    partition_size,
-                )
-            } else {
-                // GPT partition - use HardDrive device path
-                device_path::create_sata_partition_device_path(
-                    pci_device,
-                    pci_function,
-                    port,
-                    partition_num,
-                    esp.first_lba,
-                    partition_size,
-                    &esp.partition_guid,
-                )
-            };
-
-            if !device_path.is_null() {
-                let status = boot_services::install_protocol(
-                    device_handle,
-                    &DEVICE_PATH_PROTOCOL_GUID,
-                    device_path as *mut core::ffi::c_void,
-                );
-                if status == Status::SUCCESS {
-                    log::info!(
-                        "DevicePath protocol installed on device handle {:?}",
-                        device_handle
-                    );
-                } else {
-                    log::warn!("Failed to install DevicePath protocol: {:?}", status);
-                }
-            }
-
-            // Install BlockIO protocol on the device handle
-            // The bootloader needs this to access the disk
-            // First register the storage device
-            if let Some(controller) = drivers::ahci::get_controller(0)
-                && let Some(port_info) = controller.get_port(port as usize)
-            {
-                let block_size = port_info.sector_size;
-                let storage_id = storage::register_device(
-                    StorageType::Ahci {
-                        controller_id: 0,
-                        port: port as usize,
-                    },
-                    port_info.sector_count,
-                    block_size,
-                );
-
-                if let Some(storage_id) = storage_id {
-                    let block_io = block_io::create_partition_block_io(
-                        storage_id,
-                        partition_num,
-                        esp.first_lba,
-                        partition_size,
-                        block_size,
-                    );
-
-                    if !block_io.is_null() {
-                        let status = boot_services::install_protocol(
-                            device_handle,
-                            &BLOCK_IO_PROTOCOL_GUID,
-                            block_io as *mut core::ffi::c_void,
-                        );
-                        if status == Status::SUCCESS {
-                            log::info!(
-                                "BlockIO protocol installed on device handle {:?}",
-                                device_handle
-                            );
-                        } else {
-                            log::warn!("Failed to install BlockIO protocol: {:?}", status);
-                        }
-                    }
-                }
-            }
-
-            // Install SimpleFileSystem protocol on the device handle
-            let status = boot_services::install_protocol(
-                device_handle,
-                &SIMPLE_FILE_SYSTEM_GUID,
-                sfs_protocol as *mut core::ffi::c_void,
-            );
-
-            if status != Status::SUCCESS {
-                log::error!("Failed to install SimpleFileSystem protocol: {:?}", status);
-                return false;
-            }
-
-            log::info!(
-                "SimpleFileSystem protocol installed on device handle {:?}",
-                device_handle
-            );
-
-            // Look for EFI bootloader
-            let boot_path = "EFI\\BOOT\\BOOTX64.EFI";
-            match fat.file_size(boot_path) {
-                Ok(size) => {
-                    log::info!("Found bootloader: {} ({} bytes)", boot_path, size);
-
-                    // Load and execute the bootloader with device handle
-                    match load_and_execute_bootloader(&mut fat, boot_path, size, device_handle) {
-                        Ok(()) => return true,
-                        Err(e) => {
-                            log::error!("Failed to execute bootloader: {:?}", e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    log::warn!("Bootloader not found: {:?}", e);
-                }
-            }
-        }
-        Err(e) => {
-            log::error!("Failed to mount FAT filesystem: {:?}", e);
+    // Use CDROM device path for El Torito (partition_num = 0) or
+    // HardDrive device path for GPT partitions
+    let device_path = || {
+        if partition_num == 0 {
+            device_path::create_sata_cdrom_device_path(
+                pci_device,
+                pci_function,
+                port,
+                0, // boot_entry (El Torito catalog entry)
+                esp.first_lba,
+                esp.size_sectors(),
+            )
+        } else {
+            device_path::create_sata_partition_device_path(
+                pci_device,
+                pci_function,
+                port,
+                partition_num,
+                esp.first_lba,
+                esp.size_sectors(),
+                &esp.partition_guid,
+            )
         }
-    }
-    false
+    };
+
+    boot_from_esp(
+        disk,
+        AnyBlockDevice::Ahci(ahci_block_device),
+        esp,
+        partition_num,
+        storage,
+        device_path,
+        boot_path,
+    )
 }
 
 /// Load and execute an EFI bootloader from the filesystem
@@ -1620,7 +1642,10 @@ fn load_and_execute_bootloader(
     // Read the file into the buffer
     let buffer = unsafe { core::slice::from_raw_parts_mut(buffer_ptr, file_size as usize) };
 
-    let bytes_read = fat.read_file_all(path, buffer).map_err(|e| {
+    let bytes_read = menu::show_load_progress(path, |progress| {
+        fat.read_file_all(path, buffer, Some(progress))
+    })
+    .map_err(|e| {
         log::error!("Failed to read bootloader file: {:?}", e);
         let _ = free_pool(buffer_ptr);
         Status::DEVICE_ERROR
@@ -1628,14 +1653,42 @@ fn load_and_execute_bootloader(
 
     log::info!("Read {} bytes from {}", bytes_read, path);
 
-    // Load the PE image
-    let loaded_image = pe::load_image(&buffer[..bytes_read]).inspect_err(|&status| {
-        log::error!("Failed to load PE image: {:?}", status);
+    // With a hash list in CBFS, only listed, unmodified loaders are started
+    if verity::check(path, &buffer[..bytes_read]).is_err() {
         let _ = free_pool(buffer_ptr);
-    })?;
+        return Err(Status::SECURITY_VIOLATION);
+    }
+
+    // Loaders on the deny list, or missing from the allow list for
+    // removable media, are refused
+    let removable = efi::protocols::security::is_removable(device_handle);
+    if image_policy::check(&buffer[..bytes_read], removable).is_err() {
+        let _ = free_pool(buffer_ptr);
+        return Err(Status::ACCESS_DENIED);
+    }
+
+    // A Unified Kernel Image has its kernel started directly; the initrd and
+    // splash stay in the file buffer until it returns
+    let uki = uki::Uki::parse(&buffer[..bytes_read]);
+    let image = match &uki {
+        Some(uki) => {
+            log::info!("Unified Kernel Image: {}", uki.title());
+            uki.linux
+        }
+        None => &buffer[..bytes_read],
+    };
+
+    // Load the PE image
+    let loaded_image =
+        timing::measure(Stage::PeLoad, || pe::load_image(image)).inspect_err(|&status| {
+            log::error!("Failed to load PE image: {:?}", status);
+            let _ = free_pool(buffer_ptr);
+        })?;
 
     // Free the raw file buffer (we no longer need it - PE loader copied sections)
-    let _ = free_pool(buffer_ptr);
+    if uki.is_none() {
+        let _ = free_pool(buffer_ptr);
+    }
 
     log::info!(
         "PE image loaded at {:#x}, entry point {:#x}, size {:#x}",
@@ -1677,6 +1730,12 @@ fn load_and_execute_bootloader(
         log::debug!("Set LoadedImage.FilePath to: {}", path);
     }
 
+    // Command line edited in the boot menu, or else the UKI's
+    if let Some(uki) = &uki {
+        cmdline::set_default(uki.cmdline);
+    }
+    cmdline::apply(unsafe { &mut *loaded_image_protocol });
+
     let status = boot_services::install_protocol(
         image_handle,
         &LOADED_IMAGE_PROTOCOL_GUID,
@@ -1718,14 +1777,42 @@ fn load_and_execute_bootloader(
         }
     }
 
+    // SSDTs the user put on the ESP, before the OS reads the ACPI tables
+    ssdt::load_from_esp(fat);
+
+    // Replace the menu with the boot logo, if there is one
+    splash::show();
+    if let Some(uki) = &uki {
+        splash::show_image(uki.splash);
+    }
+
+    // The UKI's initrd, where the kernel's EFI stub looks for it
+    let initrd = uki
+        .is_some_and(|uki| !uki.initrd.is_empty() && efi::protocols::initrd::install(uki.initrd));
+
+    // Boot timing and loader identity for systemd-analyze
+    efi::loader_interface::publish();
+
+    // Shell-style arguments for command-line utilities such as the self-test
+    let shell_parameters = efi::protocols::shell_parameters::install(image_handle);
+
     // Execute the bootloader
     let exec_status = pe::execute_image(&loaded_image, image_handle, system_table);
+    if shell_parameters {
+        efi::protocols::shell_parameters::uninstall(image_handle);
+    }
+    if initrd {
+        efi::protocols::initrd::uninstall();
+    }
 
     // If the bootloader returns, log it
     log::info!("Bootloader returned with status: {:?}", exec_status);
 
     // Clean up (normally the bootloader would call ExitBootServices and never return)
     pe::unload_image(&loaded_image);
+    if uki.is_some() {
+        let _ = free_pool(buffer_ptr);
+    }
 
     if exec_status == Status::SUCCESS {
         Ok(())
@@ -1820,12 +1907,12 @@ fn install_block_io_for_sdhci_disk<R: BlockDevice>(
     for (i, partition) in partitions.iter().enumerate() {
         let partition_num = (i + 1) as u32;
         let partition_blocks = partition.size_sectors();
-
-        let partition_block_io = block_io::create_partition_block_io(
-            storage_id,
+        let partition_block_io = create_partition_block_io(
+            disk,
+            &partitions,
+            partition,
             partition_num,
-            partition.first_lba,
-            partition_blocks,
+            storage_id,
             block_size,
         );
 
@@ -1927,25 +2014,23 @@ fn install_block_io_for_sdhci_disk<R: BlockDevice>(
 /// * `partition_num` - 1-based partition number of the ESP
 /// * `pci_device` - PCI device number of SDHCI controller
 /// * `pci_function` - PCI function number
+/// * `boot_path` - Path of the EFI application on the ESP
 fn try_boot_from_esp_sdhci(
     disk: &mut SdhciDisk,
     esp: &fs::gpt::Partition,
     partition_num: u32,
     pci_device: u8,
     pci_function: u8,
+    boot_path: &str,
 ) -> bool {
     use drivers::block::{AnyBlockDevice, SdhciBlockDevice};
-    use drivers::storage::{self, StorageType};
-    use efi::boot_services;
-    use efi::protocols::block_io::{self, BLOCK_IO_PROTOCOL_GUID};
-    use efi::protocols::device_path::{self, DEVICE_PATH_PROTOCOL_GUID};
-    use efi::protocols::simple_file_system::{self, SIMPLE_FILE_SYSTEM_GUID};
-    use r_efi::efi::Status;
+    use drivers::storage::StorageType;
+    use efi::protocols::device_path;
 
     // Get SDHCI device info for creating block device
-    let (num_blocks, block_size) = {
+    let (num_blocks, block_size, media_id) = {
         let info = disk.info();
-        (info.num_blocks, info.block_size)
+        (info.num_blocks, info.block_size, info.media_id)
     };
 
     log::debug!(
@@ -1955,132 +2040,82 @@ fn try_boot_from_esp_sdhci(
     );
 
     // Create an SdhciBlockDevice for the SimpleFileSystem protocol
-    let sdhci_block_device = SdhciBlockDevice::new(0, num_blocks, block_size, 0);
-    let block_device = AnyBlockDevice::Sdhci(sdhci_block_device);
+    let sdhci_block_device = SdhciBlockDevice::new(0, num_blocks, block_size, media_id);
+
+    // Register the card for the BlockIO protocol
+    let storage = drivers::sdhci::get_controller(0).map(|controller| {
+        let storage_type = StorageType::Sdhci { controller_id: 0 };
+        (
+            storage_type,
+            controller.num_blocks(),
+            controller.block_size(),
+        )
+    });
+
+    let device_path = || {
+        device_path::create_usb_partition_device_path(
+            pci_device,
+            pci_function,
+            0,
+            partition_num,
+            esp.first_lba,
+            esp.size_sectors(),
+            &esp.partition_guid,
+        )
+    };
 
-    // Initialize SimpleFileSystem protocol with the block device
-    let sfs_protocol = simple_file_system::init(block_device, esp.first_lba);
-    if sfs_protocol.is_null() {
-        log::error!("Failed to initialize SimpleFileSystem protocol");
+    boot_from_esp(
+        disk,
+        AnyBlockDevice::Sdhci(sdhci_block_device),
+        esp,
+        partition_num,
+        storage,
+        device_path,
+        boot_path,
+    )
+}
+
+/// Try to boot from the ESP of a RAM disk
+///
+/// The disk and its partitions get Block I/O first, as if registered
+/// through the RAM disk protocol.
+fn try_boot_from_ram_disk(id: usize, entry: &menu::BootEntry) -> bool {
+    use drivers::block::{AnyBlockDevice, RamDiskBlockDevice};
+    use drivers::ramdisk;
+    use efi::protocols::device_path::{self, RamDiskDevicePathNode};
+    use efi::protocols::ram_disk;
+
+    let Some(disk) = ramdisk::get(id) else {
+        return false;
+    };
+    // Safety: a null parent is allowed
+    if unsafe { ram_disk::install(id, core::ptr::null()) }.is_none() {
+        log::error!("Failed to install RAM disk {}", id);
         return false;
     }
 
-    match fs::fat::FatFilesystem::new(disk, esp.first_lba) {
-        Ok(mut fat) => {
-            log::info!("FAT filesystem mounted on ESP");
-
-            // Create a device handle with SimpleFileSystem and DevicePath protocols
-            let device_handle = match boot_services::create_handle() {
-                Some(h) => h,
-                None => {
-                    log::error!("Failed to create device handle");
-                    return false;
-                }
-            };
-
-            // Install DevicePath protocol on the device handle
-            let partition_size = esp.size_sectors();
-            let device_path = device_path::create_usb_partition_device_path(
-                pci_device,
-                pci_function,
-                0,
-                partition_num,
-                esp.first_lba,
-                partition_size,
-                &esp.partition_guid,
-            );
-
-            if !device_path.is_null() {
-                let status = boot_services::install_protocol(
-                    device_handle,
-                    &DEVICE_PATH_PROTOCOL_GUID,
-                    device_path as *mut core::ffi::c_void,
-                );
-                if status == Status::SUCCESS {
-                    log::info!(
-                        "DevicePath protocol installed on device handle {:?}",
-                        device_handle
-                    );
-                } else {
-                    log::warn!("Failed to install DevicePath protocol: {:?}", status);
-                }
-            }
-
-            // Install BlockIO protocol on the device handle
-            if let Some(controller) = drivers::sdhci::get_controller(0) {
-                let block_size = controller.block_size();
-                let storage_id = storage::register_device(
-                    StorageType::Sdhci { controller_id: 0 },
-                    controller.num_blocks(),
-                    block_size,
-                );
-
-                if let Some(storage_id) = storage_id {
-                    let block_io = block_io::create_partition_block_io(
-                        storage_id,
-                        partition_num,
-                        esp.first_lba,
-                        partition_size,
-                        block_size,
-                    );
-
-                    if !block_io.is_null() {
-                        let status = boot_services::install_protocol(
-                            device_handle,
-                            &BLOCK_IO_PROTOCOL_GUID,
-                            block_io as *mut core::ffi::c_void,
-                        );
-                        if status == Status::SUCCESS {
-                            log::info!(
-                                "BlockIO protocol installed on device handle {:?}",
-                                device_handle
-                            );
-                        } else {
-                            log::warn!("Failed to install BlockIO protocol: {:?}", status);
-                        }
-                    }
-                }
-            }
-
-            // Install SimpleFileSystem protocol on the device handle
-            let status = boot_services::install_protocol(
-                device_handle,
-                &SIMPLE_FILE_SYSTEM_GUID,
-                sfs_protocol as *mut core::ffi::c_void,
-            );
-
-            if status != Status::SUCCESS {
-                log::error!("Failed to install SimpleFileSystem protocol: {:?}", status);
-                return false;
-            }
-
-            log::info!(
-                "SimpleFileSystem protocol installed on device handle {:?}",
-                device_handle
-            );
-
-            // Look for EFI bootloader
-            let boot_path = "EFI\\BOOT\\BOOTX64.EFI";
-            match fat.file_size(boot_path) {
-                Ok(size) => {
-                    log::info!("Found bootloader: {} ({} bytes)", boot_path, size);
-
-                    // Load and execute the bootloader with device handle
-                    match load_and_execute_bootloader(&mut fat, boot_path, size, device_handle) {
-                        Ok(()) => return true,
-                        Err(e) => {
-                            log::error!("Failed to execute bootloader: {:?}", e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    log::warn!("Bootloader not found: {:?}", e);
-                }
-            }
-        }
-        Err(e) => {
-            log::error!("Failed to mount FAT filesystem: {:?}", e);
+    let esp = &entry.partition;
+    let block_device = AnyBlockDevice::RamDisk(RamDiskBlockDevice::new(id, &disk));
+    let device_path = || {
+        let node = RamDiskDevicePathNode::new(disk.address, disk.size, disk.disk_type, id as u16);
+        // Safety: a null parent is allowed
+        unsafe {
+            device_path::create_ram_disk_device_path(
+                core::ptr::null(),
+                node,
+                Some((entry.partition_num, esp)),
+            )
         }
-    }
-    false
+    };
+
+    // The RAM disk protocol installed Block I/O already
+    boot_from_esp(
+        &mut RamDiskBlockDevice::new(id, &disk),
+        block_device,
+        esp,
+        entry.partition_num,
+        None,
+        device_path,
+        &entry.path,
+    )
 }
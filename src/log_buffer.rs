@@ -0,0 +1,147 @@
+//! Firmware log for the OS
+//!
+//! Every log line also goes into a ring buffer in CrabEFI's data section,
+//! which the memory map reports as runtime services data, so it survives
+//! ExitBootServices. The OS finds it through the configuration table
+//! [`LOG_BUFFER_TABLE_GUID`] and the volatile variable `LogBuffer` under
+//! [`CRABEFI_VARIABLE_GUID`] (physical address as u64, then size as u32,
+//! little endian); this works on boards without a CBMEM console.
+//!
+//! The buffer uses the CBMEM console layout behind a signature:
+//!
+//! ```text
+//! u32 signature   "CLOG"
+//! u32 size        size of body
+//! u32 cursor      next write offset; bit 31 set once the body wrapped
+//! u8  body[size]  log text, oldest at cursor when wrapped
+//! ```
+//!
+//! Lines logged after ExitBootServices keep landing in the buffer.
+
+use core::fmt;
+
+use r_efi::efi::{self, Guid};
+use spin::Mutex;
+
+use crate::efi::runtime_services::{CRABEFI_VARIABLE_GUID, write_variable};
+use crate::efi::system_table;
+
+/// Configuration table pointing at the log buffer
+/// {876910E1-F9A3-4C7D-92F1-AF85346F8F24}
+pub const LOG_BUFFER_TABLE_GUID: Guid = Guid::from_fields(
+    0x876910e1,
+    0xf9a3,
+    0x4c7d,
+    0x92,
+    0xf1,
+    &[0xaf, 0x85, 0x34, 0x6f, 0x8f, 0x24],
+);
+
+/// "CLOG"
+const SIGNATURE: u32 = u32::from_le_bytes(*b"CLOG");
+
+/// Cursor bit set once the body has wrapped around
+const OVERFLOW: u32 = 1 << 31;
+
+/// Size of the log body
+const LOG_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Log ring buffer in the CBMEM console layout
+#[repr(C)]
+struct Ring<const N: usize> {
+    signature: u32,
+    size: u32,
+    cursor: u32,
+    body: [u8; N],
+}
+
+impl<const N: usize> Ring<N> {
+    const fn new() -> Self {
+        Self {
+            signature: SIGNATURE,
+            size: N as u32,
+            cursor: 0,
+            body: [0; N],
+        }
+    }
+
+    /// Append bytes, overwriting the oldest once full
+    fn write(&mut self, bytes: &[u8]) {
+        let mut offset = (self.cursor & !OVERFLOW) as usize;
+        let mut overflow = self.cursor & OVERFLOW;
+        for &byte in bytes {
+            self.body[offset] = byte;
+            offset += 1;
+            if offset == N {
+                offset = 0;
+                overflow = OVERFLOW;
+            }
+        }
+        self.cursor = offset as u32 | overflow;
+    }
+}
+
+impl<const N: usize> fmt::Write for Ring<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// The log buffer handed to the OS
+static LOG_BUFFER: Mutex<Ring<LOG_BUFFER_SIZE>> = Mutex::new(Ring::new());
+
+/// Append formatted text to the log buffer
+///
+/// Skips the text rather than wait if the buffer is busy, so logging from a
+/// crash handler cannot deadlock on it.
+pub fn write_fmt(args: fmt::Arguments) {
+    if let Some(mut ring) = LOG_BUFFER.try_lock() {
+        let _ = fmt::Write::write_fmt(&mut *ring, args);
+    }
+}
+
+/// Tell the OS where the log buffer is
+pub fn publish() {
+    let ring = &*LOG_BUFFER.lock() as *const Ring<LOG_BUFFER_SIZE>;
+    let status = system_table::install_configuration_table(
+        &LOG_BUFFER_TABLE_GUID,
+        ring as *mut core::ffi::c_void,
+    );
+    if status != efi::Status::SUCCESS {
+        log::warn!("Failed to install log buffer table: {:?}", status);
+        return;
+    }
+
+    let mut location = [0u8; 12];
+    location[..8].copy_from_slice(&(ring as u64).to_le_bytes());
+    location[8..].copy_from_slice(&(LOG_BUFFER_SIZE as u32).to_le_bytes());
+    let attributes = efi::VARIABLE_BOOTSERVICE_ACCESS | efi::VARIABLE_RUNTIME_ACCESS;
+    let status = write_variable("LogBuffer", &CRABEFI_VARIABLE_GUID, attributes, &location);
+    if status != efi::Status::SUCCESS {
+        log::warn!("Failed to publish LogBuffer variable: {:?}", status);
+    }
+
+    log::info!(
+        "Log buffer at {:#x} ({} bytes)",
+        ring as u64,
+        LOG_BUFFER_SIZE
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_wraps_and_flags_overflow() {
+        let mut ring = Ring::<8>::new();
+        ring.write(b"abcde");
+        assert_eq!(ring.cursor, 5);
+        assert_eq!(&ring.body[..5], b"abcde");
+
+        ring.write(b"fghij");
+        assert_eq!(ring.cursor, 2 | OVERFLOW);
+        assert_eq!(&ring.body, b"ijcdefgh");
+    }
+}
@@ -1,16 +1,24 @@
 //! Logging infrastructure for CrabEFI
 //!
 //! This module provides logging via the `log` crate, outputting to the
-//! serial port, the coreboot CBMEM console, and optionally the framebuffer.
+//! serial port, the coreboot CBMEM console, the [log buffer](crate::log_buffer)
+//! kept for the OS, and optionally the framebuffer.
 //!
 //! Framebuffer logging is disabled by default as it is very slow.
 //! Enable with the `fb-log` feature flag.
+//!
+//! Levels can be set per log target, i.e. per module path without the
+//! `crabefi::` prefix. A setting applies to the module and everything below
+//! it, and the longest matching target wins, so `drivers::nvme=trace` traces
+//! one driver while the rest stays at the default level.
 
 use crate::arch::x86_64::rdtsc;
 use crate::coreboot::cbmem_console;
 use core::fmt::Write;
 use core::sync::atomic::{AtomicU64, Ordering};
+use heapless::{String, Vec};
 use log::{Level, LevelFilter, Metadata, Record};
+use spin::Mutex;
 
 /// Initial TSC value at boot (set during init)
 static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
@@ -23,12 +31,116 @@ pub fn get_timestamp_k() -> u64 {
     current.saturating_sub(boot) / 1000
 }
 
+/// TSC value when CrabEFI started
+pub fn boot_tsc() -> u64 {
+    BOOT_TSC.load(Ordering::Relaxed)
+}
+
+/// Maximum number of targets with their own level
+pub const MAX_TARGET_LEVELS: usize = 8;
+
+/// Longest target name that can be given a level
+pub const MAX_TARGET_LEN: usize = 32;
+
+/// A target and the level set for it
+#[derive(Debug, Clone)]
+pub struct TargetLevel {
+    pub target: String<MAX_TARGET_LEN>,
+    pub level: LevelFilter,
+}
+
+/// Default level and per-target levels
+#[derive(Debug, Clone)]
+pub struct Levels {
+    pub default: LevelFilter,
+    pub targets: Vec<TargetLevel, MAX_TARGET_LEVELS>,
+}
+
+impl Levels {
+    const fn new(default: LevelFilter) -> Self {
+        Self {
+            default,
+            targets: Vec::new(),
+        }
+    }
+
+    /// Level of the longest target matching the module path `target`
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let target = target.strip_prefix("crabefi::").unwrap_or(target);
+        self.targets
+            .iter()
+            .filter(|t| {
+                target
+                    .strip_prefix(t.target.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|t| t.target.len())
+            .map_or(self.default, |t| t.level)
+    }
+
+    /// Set the level of `target`, or drop its own level with `None`
+    fn set(&mut self, target: &str, level: Option<LevelFilter>) -> Result<(), &'static str> {
+        let index = self.targets.iter().position(|t| t.target == target);
+        match (index, level) {
+            (Some(index), Some(level)) => self.targets[index].level = level,
+            (Some(index), None) => {
+                self.targets.remove(index);
+            }
+            (None, Some(level)) => {
+                let target = String::try_from(target).map_err(|_| "Target name too long")?;
+                self.targets
+                    .push(TargetLevel { target, level })
+                    .map_err(|_| "Too many log targets")?;
+            }
+            (None, None) => {}
+        }
+        Ok(())
+    }
+
+    /// Apply a spec like `info,drivers::nvme=trace,drivers::usb=warn`
+    fn apply_spec(&mut self, spec: &str) -> Result<(), &'static str> {
+        for item in spec.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            match item.split_once('=') {
+                Some((target, level)) => {
+                    let level = level.trim().parse().map_err(|_| "Bad log level")?;
+                    self.set(target.trim(), Some(level))?;
+                }
+                None => self.default = item.parse().map_err(|_| "Bad log level")?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Most verbose level of any target, for the `log` crate's fast check
+    fn max(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|t| t.level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+/// Log levels in effect
+static LEVELS: Mutex<Levels> = Mutex::new(Levels::new(LevelFilter::Debug));
+
+/// Change the log levels and update the global maximum
+fn update_levels<R>(f: impl FnOnce(&mut Levels) -> R) -> R {
+    let mut levels = LEVELS.lock();
+    let result = f(&mut levels);
+    log::set_max_level(levels.max());
+    result
+}
+
 /// Combined serial + framebuffer logger
 struct CombinedLogger;
 
 impl log::Log for CombinedLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Trace
+        // Never wait on the levels: a message logged while they are being
+        // changed, e.g. from a crash, goes through
+        LEVELS
+            .try_lock()
+            .is_none_or(|levels| metadata.level() <= levels.level_for(metadata.target()))
     }
 
     fn log(&self, record: &Record) {
@@ -69,6 +181,13 @@ impl log::Log for CombinedLogger {
                 );
             }
 
+            crate::log_buffer::write_fmt(format_args!(
+                "[{:>10}] [{}] {}\n",
+                ts,
+                level_str_plain,
+                record.args()
+            ));
+
             // Output to framebuffer (if feature enabled)
             #[cfg(feature = "fb-log")]
             crate::fb_log::log_to_framebuffer(record.level(), ts, record.args());
@@ -86,7 +205,7 @@ pub fn init() {
     BOOT_TSC.store(rdtsc(), Ordering::Relaxed);
 
     log::set_logger(&LOGGER)
-        .map(|()| log::set_max_level(LevelFilter::Debug))
+        .map(|()| log::set_max_level(LEVELS.lock().max()))
         .expect("Failed to set logger");
 }
 
@@ -107,7 +226,59 @@ pub fn set_framebuffer(_fb: crate::coreboot::FramebufferInfo) {
     // Framebuffer logging disabled at compile time
 }
 
-/// Set the maximum log level
+/// Set the default log level
 pub fn set_level(level: LevelFilter) {
-    log::set_max_level(level);
+    update_levels(|levels| levels.default = level);
+}
+
+/// Set the level of one target, or make it follow the default with `None`
+pub fn set_target_level(target: &str, level: Option<LevelFilter>) -> Result<(), &'static str> {
+    update_levels(|levels| levels.set(target, level))
+}
+
+/// Apply a level spec like `info,drivers::nvme=trace`
+///
+/// A bare level sets the default, `target=level` the level of a target.
+pub fn apply_spec(spec: &str) -> Result<(), &'static str> {
+    update_levels(|levels| levels.apply_spec(spec))
+}
+
+/// The log levels in effect
+pub fn levels() -> Levels {
+    LEVELS.lock().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_target_wins() {
+        let mut levels = Levels::new(LevelFilter::Info);
+        levels
+            .apply_spec("warn, drivers=debug, drivers::nvme=trace")
+            .unwrap();
+
+        assert_eq!(levels.level_for("crabefi::menu"), LevelFilter::Warn);
+        assert_eq!(
+            levels.level_for("crabefi::drivers::usb::xhci"),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            levels.level_for("crabefi::drivers::nvme"),
+            LevelFilter::Trace
+        );
+        assert_eq!(
+            levels.level_for("crabefi::drivers::nvmex"),
+            LevelFilter::Debug
+        );
+        assert_eq!(levels.max(), LevelFilter::Trace);
+
+        levels.set("drivers::nvme", None).unwrap();
+        assert_eq!(
+            levels.level_for("crabefi::drivers::nvme"),
+            LevelFilter::Debug
+        );
+        assert!(levels.apply_spec("drivers=loud").is_err());
+    }
 }
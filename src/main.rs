@@ -10,9 +10,15 @@
 /// # Arguments
 ///
 /// * `coreboot_table_ptr` - Pointer to the coreboot tables (passed in RDI)
+/// * `loader_info` - Information structure of the Multiboot, Multiboot2 or
+///   Linux boot protocol loader that started CrabEFI, 0 otherwise (passed in RSI)
+/// * `loader_magic` - Which of them it is (passed in RDX)
 #[unsafe(no_mangle)]
-pub extern "C" fn rust_main(coreboot_table_ptr: u64) -> ! {
-    crabefi::init(coreboot_table_ptr);
+pub extern "C" fn rust_main(coreboot_table_ptr: u64, loader_info: u64, loader_magic: u32) -> ! {
+    crabefi::init(
+        coreboot_table_ptr,
+        crabefi::coreboot::fallback::LoaderInfo::new(loader_magic, loader_info),
+    );
 
     // Should never reach here
     loop {
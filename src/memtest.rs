@@ -0,0 +1,232 @@
+//! Memory test
+//!
+//! The recovery console's `memtest` checks the RAM coreboot trained, which
+//! helps when bringing up RAM init on a new board. Only memory free in the
+//! UEFI memory map is tested: each free range is allocated for the test and
+//! freed again, so CrabEFI's image, its allocations and the tables coreboot
+//! handed over are left alone.
+//!
+//! Every pass fills each range with a pattern and reads it back, for these
+//! patterns:
+//!
+//! - each word's own address, to find address lines that are stuck or
+//!   shorted
+//! - alternating bits `0x5555...` and `0xaaaa...`, to find data lines and
+//!   cells that influence their neighbours
+//! - the inverted address
+//!
+//! Ranges are tested in 64 MiB chunks, larger than any cache, so the reads
+//! come from DRAM. Escape stops the test between chunks.
+
+use core::fmt::Write;
+
+use heapless::Vec;
+use r_efi::efi;
+
+use crate::efi::allocator::{self, AllocateType, MemoryType, PAGE_SIZE};
+use crate::menu::{self, KeyPress};
+use crate::state;
+
+/// Bytes filled before reading back
+const CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Most free ranges tested
+const MAX_RANGES: usize = 128;
+
+/// Most failures printed; the rest are only counted
+const MAX_REPORTED: u64 = 32;
+
+/// A word that didn't read back what was written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Failure {
+    pub address: u64,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+/// A test pattern: the value written to the word at an address
+type Pattern = fn(u64) -> u64;
+
+/// The patterns of one pass, with their names
+const PATTERNS: [(&str, Pattern); 4] = [
+    ("address", |address| address),
+    ("0x5555...", |_| 0x5555_5555_5555_5555),
+    ("0xaaaa...", |_| 0xaaaa_aaaa_aaaa_aaaa),
+    ("inverted address", |address| !address),
+];
+
+/// Write `pattern` to the words at `base`
+///
+/// # Safety
+///
+/// `base` must point to `words` words of memory nothing else uses.
+unsafe fn fill(base: u64, words: usize, pattern: Pattern) {
+    let ptr = base as *mut u64;
+    for i in 0..words {
+        let address = base + i as u64 * 8;
+        unsafe { ptr.add(i).write_volatile(pattern(address)) };
+    }
+}
+
+/// Read back the words [`fill`] wrote, calling `on_failure` for mismatches
+///
+/// Returns the number of mismatches.
+///
+/// # Safety
+///
+/// `base` must point to `words` words of memory nothing else uses.
+unsafe fn verify(
+    base: u64,
+    words: usize,
+    pattern: Pattern,
+    on_failure: &mut dyn FnMut(Failure),
+) -> u64 {
+    let ptr = base as *const u64;
+    let mut failures = 0;
+    for i in 0..words {
+        let address = base + i as u64 * 8;
+        let expected = pattern(address);
+        let actual = unsafe { ptr.add(i).read_volatile() };
+        if actual != expected {
+            failures += 1;
+            on_failure(Failure {
+                address,
+                expected,
+                actual,
+            });
+        }
+    }
+    failures
+}
+
+/// Free memory below the identity mapping limit, as (start, pages)
+fn free_ranges() -> Vec<(u64, u64), MAX_RANGES> {
+    let mut ranges = Vec::new();
+    let mapped = allocator::max_identity_mapped_address();
+    for entry in state::allocator().entries() {
+        if !entry.is_free() || entry.physical_start >= mapped {
+            continue;
+        }
+        let end = entry.end().min(mapped);
+        let pages = (end - entry.physical_start) / PAGE_SIZE;
+        if ranges.push((entry.physical_start, pages)).is_err() {
+            log::warn!("Memtest: more than {} free ranges", MAX_RANGES);
+            break;
+        }
+    }
+    ranges
+}
+
+/// Log a failure, and print it unless `reported` failures were printed already
+fn report(out: &mut dyn Write, reported: u64, failure: Failure) {
+    if reported < MAX_REPORTED {
+        let _ = writeln!(
+            out,
+            "  FAIL {:#014x}: wrote {:016x} read {:016x}",
+            failure.address, failure.expected, failure.actual
+        );
+    }
+    log::error!(
+        "Memtest: {:#x} wrote {:#018x} read {:#018x}",
+        failure.address,
+        failure.expected,
+        failure.actual
+    );
+}
+
+/// Whether Escape was pressed
+fn abort_requested() -> bool {
+    matches!(menu::read_key(), Some(KeyPress::Escape))
+}
+
+/// Test the free memory `passes` times, printing progress to `out`
+///
+/// Returns the number of failed words.
+pub fn run(out: &mut dyn Write, passes: u32) -> u64 {
+    let ranges = free_ranges();
+    let total: u64 = ranges.iter().map(|&(_, pages)| pages * PAGE_SIZE).sum();
+    let _ = writeln!(
+        out,
+        "Testing {} MiB in {} ranges, Escape stops",
+        total / (1024 * 1024),
+        ranges.len()
+    );
+
+    let mut failures = 0;
+    'passes: for pass in 1..=passes {
+        for &(name, pattern) in &PATTERNS {
+            let _ = writeln!(out, "Pass {}/{}: {}", pass, passes, name);
+            for &(start, pages) in &ranges {
+                let mut address = start;
+                let status = allocator::allocate_pages(
+                    AllocateType::AllocateAddress,
+                    MemoryType::BootServicesData,
+                    pages,
+                    &mut address,
+                );
+                if status != efi::Status::SUCCESS {
+                    log::warn!("Memtest: {:#x} is no longer free: {:?}", start, status);
+                    continue;
+                }
+
+                let end = start + pages * PAGE_SIZE;
+                let mut chunk = start;
+                let mut aborted = false;
+                while chunk < end && !aborted {
+                    let words = ((end - chunk).min(CHUNK_SIZE) / 8) as usize;
+                    unsafe {
+                        fill(chunk, words, pattern);
+                        verify(chunk, words, pattern, &mut |failure| {
+                            report(out, failures, failure);
+                            failures += 1;
+                        });
+                    }
+                    chunk += words as u64 * 8;
+                    aborted = abort_requested();
+                }
+                let _ = allocator::free_pages(start, pages);
+                if aborted {
+                    break 'passes;
+                }
+            }
+        }
+    }
+
+    if failures > MAX_REPORTED {
+        let _ = writeln!(out, "  ... {} more", failures - MAX_REPORTED);
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_corrupted_words() {
+        let mut memory = std::vec![0u64; 64];
+        let base = memory.as_mut_ptr() as u64;
+        for &(_, pattern) in &PATTERNS {
+            let mut found = std::vec::Vec::new();
+            unsafe {
+                fill(base, memory.len(), pattern);
+                assert_eq!(
+                    verify(base, memory.len(), pattern, &mut |f| found.push(f)),
+                    0
+                );
+            }
+
+            memory[5] ^= 1 << 17;
+            let failures = unsafe { verify(base, memory.len(), pattern, &mut |f| found.push(f)) };
+            assert_eq!(failures, 1);
+            assert_eq!(
+                found,
+                [Failure {
+                    address: base + 40,
+                    expected: pattern(base + 40),
+                    actual: pattern(base + 40) ^ 1 << 17,
+                }]
+            );
+        }
+    }
+}
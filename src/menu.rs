@@ -6,20 +6,39 @@
 //! # Features
 //!
 //! - Discovers boot entries from NVMe, AHCI, and USB storage devices
+//! - Lists [Boot Loader Specification](crate::bls) entries and honours `loader.conf`
+//! - Lists [Unified Kernel Images](crate::uki) by their os-release name
+//! - Preselects the entry GRUB saved in its [environment block](crate::grubenv)
 //! - Displays menu on serial (with ANSI escape codes) and framebuffer
 //! - Arrow key navigation and Enter to select
+//! - `e` to edit the kernel command line of an entry for one boot
+//! - `c` to open the [recovery console](crate::recovery)
+//! - `s` to open the setup screen, which lists the [boot history](crate::boot_log)
+//! - Editing, the console and setup ask for the [setup password](crate::setup_password)
 //! - Configurable auto-boot timeout with countdown
 //! - Future: file browser, EFI variable support
 
+use crate::bls;
+use crate::boot_log;
+use crate::boot_options::BootOption;
+use crate::boot_paths;
+use crate::boot_priority::{self, DeviceClass};
+use crate::cmdline::{KernelOptions, MAX_CMDLINE_LEN, MAX_INITRD_LEN};
 use crate::coreboot;
 use crate::drivers::block::{AhciDisk, BlockDevice, NvmeDisk, SdhciDisk, UsbDisk};
 use crate::drivers::keyboard;
 use crate::drivers::serial as serial_driver;
+use crate::fb_shadow;
 use crate::framebuffer_console::{
     Color, DEFAULT_BG, DEFAULT_FG, FramebufferConsole, HIGHLIGHT_BG, HIGHLIGHT_FG, TITLE_COLOR,
 };
 use crate::fs::{fat::FatFilesystem, gpt, iso9660};
+use crate::grubenv::GrubEnv;
+use crate::pe;
+use crate::recovery;
+use crate::setup_password;
 use crate::time::{Timeout, delay_ms};
+use crate::uki;
 use core::fmt::Write;
 use heapless::{String, Vec};
 
@@ -29,11 +48,54 @@ const MAX_BOOT_ENTRIES: usize = 8;
 /// Default timeout in seconds for auto-boot
 const DEFAULT_TIMEOUT_SECONDS: u32 = 5;
 
+/// Most characters of a partition name shown in an entry's description
+const MAX_LABEL_CHARS: usize = 36;
+
 /// Menu title
 const MENU_TITLE: &str = "CrabEFI Boot Menu";
 
+/// Title used for the one-time boot device picker
+pub const PICKER_TITLE: &str = "Select Boot Device";
+
+/// Removable media boot path, loaded when a boot option names no file
+pub const DEFAULT_BOOT_PATH: &str = boot_paths::REMOVABLE_PATH;
+
+/// Path of the CrabEFI self-test application (built from `test/selftest`)
+pub const DIAGNOSTICS_PATH: &str = "EFI\\CRABEFI\\SELFTEST.EFI";
+
+/// Name of the boot entry launching the self-test application
+const DIAGNOSTICS_NAME: &str = "CrabEFI Diagnostics";
+
 /// Help text
-const HELP_TEXT: &str = "Use arrow keys to select, Enter to boot";
+const HELP_TEXT: &str =
+    "Arrow keys to select, Enter to boot, e to edit, c for console, s for setup";
+
+/// Status line of an entry with a 32-bit bootloader
+const IA32_TEXT: &str = "32-bit (IA-32) EFI bootloaders can't be started by this firmware";
+
+/// Status line of an empty menu
+const NO_MEDIA_TEXT: &str = "No bootable media found, insert a USB drive or SD card";
+
+/// Title of the passphrase prompt
+const PASSPHRASE_TITLE: &str = "Unlock Drive";
+
+/// Help text of the passphrase prompt
+const PASSPHRASE_HELP: &str = "Enter to unlock, Esc to skip this drive";
+
+/// Title of the kernel command line editor
+const EDITOR_TITLE: &str = "Edit Boot Entry";
+
+/// Help text of the kernel command line editor
+const EDITOR_HELP: &str = "Up/Down to switch fields, Enter to boot, Esc to cancel";
+
+/// Title of the setup screen
+const SETUP_TITLE: &str = "CrabEFI Setup";
+
+/// Help text of the setup screen
+const SETUP_HELP: &str = "Esc to return";
+
+/// Longest passphrase [`read_passphrase`] accepts
+pub const MAX_PASSPHRASE_LEN: usize = 64;
 
 /// Storage device type
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -46,9 +108,14 @@ pub enum DeviceType {
     Usb {
         controller_id: usize,
         device_addr: u8,
+        lun: u8,
     },
     /// SDHCI (SD card)
     Sdhci { controller_id: usize },
+    /// A disk image in memory, see [`crate::drivers::ramdisk`]
+    RamDisk { id: usize },
+    /// Another coreboot payload in CBFS, see [`crate::payload`]
+    Payload,
 }
 
 impl DeviceType {
@@ -59,6 +126,8 @@ impl DeviceType {
             DeviceType::Ahci { .. } => "SATA",
             DeviceType::Usb { .. } => "USB",
             DeviceType::Sdhci { .. } => "SD",
+            DeviceType::RamDisk { .. } => "RAM",
+            DeviceType::Payload => "CBFS",
         }
     }
 }
@@ -80,6 +149,12 @@ pub struct BootEntry {
     pub pci_device: u8,
     /// PCI function number
     pub pci_function: u8,
+    /// Command line and initrd edited for this boot
+    pub kernel_options: Option<KernelOptions>,
+    /// Id of the Boot Loader Specification entry this was built from
+    pub loader_entry: Option<String<12>>,
+    /// Whether the bootloader is a 32-bit (IA-32) EFI application
+    pub ia32: bool,
 }
 
 impl BootEntry {
@@ -101,22 +176,105 @@ impl BootEntry {
             partition,
             pci_device,
             pci_function,
+            kernel_options: None,
+            loader_entry: None,
+            ia32: false,
         };
         let _ = entry.name.push_str(name);
         let _ = entry.path.push_str(path);
         entry
     }
 
+    /// Create an entry starting the coreboot payload in CBFS file `cbfs_name`
+    pub fn payload(name: &str, cbfs_name: &str) -> Self {
+        let partition = gpt::Partition {
+            type_guid: [0; 16],
+            partition_guid: [0; 16],
+            first_lba: 0,
+            last_lba: 0,
+            attributes: 0,
+            is_esp: false,
+            block_size: 0,
+            name: gpt::PartitionName::new(),
+        };
+        Self::new(name, cbfs_name, DeviceType::Payload, 0, partition, 0, 0)
+    }
+
+    /// Whether this entry starts another coreboot payload
+    pub fn is_payload(&self) -> bool {
+        self.device_type == DeviceType::Payload
+    }
+
+    /// Whether this entry launches the self-test application
+    pub fn is_diagnostics(&self) -> bool {
+        self.path == DIAGNOSTICS_PATH
+    }
+
+    /// Whether this entry starts a Unified Kernel Image
+    pub fn is_uki(&self) -> bool {
+        !self.is_payload() && uki::is_uki_path(&self.path)
+    }
+
+    /// Identifier of the entry in the systemd Boot Loader Interface
+    ///
+    /// Stable across boots: the kind of loader and the ESP it is on. Boot
+    /// Loader Specification entries are known by their id and UKIs by their
+    /// file name, as in systemd-boot.
+    pub fn loader_id(&self) -> String<64> {
+        let mut id = String::new();
+        if let Some(loader_entry) = &self.loader_entry {
+            let _ = write!(id, "{}.conf", loader_entry);
+            return id;
+        }
+        if self.is_payload() {
+            let _ = write!(id, "crabefi-payload-{}", self.name);
+            return id;
+        }
+        if self.is_uki() {
+            let file_name = self.path.rsplit('\\').next().unwrap_or("");
+            for c in file_name.chars() {
+                let _ = id.push(c.to_ascii_lowercase());
+            }
+            return id;
+        }
+        let kind = if self.is_diagnostics() {
+            "crabefi-diagnostics"
+        } else {
+            "auto-efi-default"
+        };
+        let _ = write!(id, "{}-{}", kind, self.partition.partition_uuid());
+        id
+    }
+
     /// Format a description for display
     pub fn format_description(&self, buf: &mut String<128>) {
         buf.clear();
+        if self.is_payload() {
+            let _ = write!(buf, "{} (coreboot payload)", self.name);
+            return;
+        }
         let _ = write!(
             buf,
-            "{} ({}, partition {})",
+            "{} ({}, partition {}",
             self.name,
             self.device_type.description(),
             self.partition_num
         );
+        // Keep long partition names from pushing out the closing parenthesis
+        let label = self.partition.label();
+        if !label.is_empty() {
+            let _ = buf.push_str(": ");
+            for c in label.chars().take(MAX_LABEL_CHARS) {
+                if buf.len() + c.len_utf8() >= buf.capacity() {
+                    break;
+                }
+                let _ = buf.push(c);
+            }
+        }
+        let _ = buf.push(')');
+        if self.ia32 {
+            let _ = buf.push_str(" [32-bit]");
+        }
     }
 }
 
@@ -128,6 +286,12 @@ pub struct BootMenu {
     selected: usize,
     /// Timeout in seconds (0 = no timeout)
     timeout_seconds: u32,
+    /// Title shown in the menu header
+    title: &'static str,
+    /// Settings of the first `loader.conf` found, applied after discovery
+    loader_conf: Option<bls::LoaderConf>,
+    /// The first GRUB environment block found, applied after discovery
+    grub_env: Option<GrubEnv>,
 }
 
 impl Default for BootMenu {
@@ -143,6 +307,9 @@ impl BootMenu {
             entries: Vec::new(),
             selected: 0,
             timeout_seconds: DEFAULT_TIMEOUT_SECONDS,
+            title: MENU_TITLE,
+            loader_conf: None,
+            grub_env: None,
         }
     }
 
@@ -151,6 +318,11 @@ impl BootMenu {
         self.entries.push(entry).is_ok()
     }
 
+    /// Insert a boot entry at `index`, moving the following entries down
+    pub fn insert_entry(&mut self, index: usize, entry: BootEntry) -> bool {
+        self.entries.insert(index, entry).is_ok()
+    }
+
     /// Get the number of entries
     pub fn entry_count(&self) -> usize {
         self.entries.len()
@@ -161,6 +333,11 @@ impl BootMenu {
         self.entries.get(index)
     }
 
+    /// Find the entry with the given [loader id](BootEntry::loader_id)
+    pub fn find_loader_id(&self, id: &str) -> Option<&BootEntry> {
+        self.entries.iter().find(|entry| entry.loader_id() == id)
+    }
+
     /// Get the selected entry
     pub fn selected_entry(&self) -> Option<&BootEntry> {
         self.entries.get(self.selected)
@@ -180,15 +357,33 @@ impl BootMenu {
         }
     }
 
+    /// Select an entry by index (ignored if out of range)
+    pub fn set_selected(&mut self, index: usize) {
+        if index < self.entries.len() {
+            self.selected = index;
+        }
+    }
+
     /// Set the timeout
     pub fn set_timeout(&mut self, seconds: u32) {
         self.timeout_seconds = seconds;
     }
+
+    /// Set the title shown in the menu header
+    pub fn set_title(&mut self, title: &'static str) {
+        self.title = title;
+    }
 }
 
 /// Discover boot entries from all storage devices
 ///
-/// Scans NVMe, AHCI, and USB devices for ESPs containing `EFI\BOOT\BOOTX64.EFI`.
+/// Scans NVMe, AHCI, USB and SD devices for ESPs containing one of the
+/// [bootloader paths](boot_paths), in the order of the
+/// [boot priority](boot_priority).
+/// ESPs with the self-test application at [`DIAGNOSTICS_PATH`] also get a
+/// diagnostics entry and each Unified Kernel Image in `\EFI\Linux` an entry
+/// of its own. The RAM disks loaded from CBFS follow, and other coreboot
+/// payloads in CBFS are listed last.
 ///
 /// # Returns
 ///
@@ -198,17 +393,36 @@ pub fn discover_boot_entries() -> BootMenu {
 
     log::info!("Discovering boot entries...");
 
-    // Scan NVMe devices
-    discover_nvme_entries(&mut menu);
+    // Devices scanned first get their entries listed first, and a place in
+    // the menu before it fills up
+    for class in boot_priority::priority() {
+        match class {
+            DeviceClass::Nvme => discover_nvme_entries(&mut menu),
+            DeviceClass::Sata => discover_ahci_entries(&mut menu),
+            DeviceClass::Usb => discover_usb_entries(&mut menu),
+            DeviceClass::Sd => discover_sdhci_entries(&mut menu),
+        }
+    }
 
-    // Scan AHCI devices
-    discover_ahci_entries(&mut menu);
+    // Disk images in CBFS
+    discover_ram_disk_entries(&mut menu);
 
-    // Scan USB devices
-    discover_usb_entries(&mut menu);
+    // Other coreboot payloads, like SeaBIOS
+    crate::payload::add_entries(&mut menu);
 
-    // Scan SDHCI devices (SD cards)
-    discover_sdhci_entries(&mut menu);
+    // The default of loader.conf wins over the one GRUB saved
+    let loader_conf = menu.loader_conf.take();
+    let grub_env = menu.grub_env.take();
+    if let Some(grub_env) = grub_env
+        && loader_conf
+            .as_ref()
+            .is_none_or(|conf| conf.default.is_none())
+    {
+        grub_env.apply(&mut menu);
+    }
+    if let Some(loader_conf) = loader_conf {
+        loader_conf.apply(&mut menu);
+    }
 
     log::info!("Found {} boot entries", menu.entry_count());
 
@@ -245,26 +459,24 @@ fn discover_nvme_entries(menu: &mut BootMenu) {
                     // Try to find bootloader on this partition
                     if let Some(controller) = nvme::get_controller(0) {
                         let mut disk = NvmeDisk::new(controller, nsid);
-                        if check_bootloader_exists(&mut disk, partition.first_lba) {
-                            let mut name: String<64> = String::new();
-                            let _ = write!(name, "Boot Entry (NVMe ns{})", nsid);
-
-                            let entry = BootEntry::new(
-                                &name,
-                                "EFI\\BOOT\\BOOTX64.EFI",
-                                DeviceType::Nvme {
-                                    controller_id: 0,
-                                    nsid,
-                                },
-                                partition_num,
-                                partition.clone(),
-                                pci_addr.device,
-                                pci_addr.function,
-                            );
-
-                            if !menu.add_entry(entry) {
-                                return; // Menu full
-                            }
+                        let mut name: String<64> = String::new();
+                        let _ = write!(name, "Boot Entry (NVMe ns{})", nsid);
+
+                        let entry = BootEntry::new(
+                            &name,
+                            DEFAULT_BOOT_PATH,
+                            DeviceType::Nvme {
+                                controller_id: 0,
+                                nsid,
+                            },
+                            partition_num,
+                            partition.clone(),
+                            pci_addr.device,
+                            pci_addr.function,
+                        );
+
+                        if !add_partition_entries(menu, &mut disk, entry) {
+                            return; // Menu full
                         }
                     }
                 }
@@ -302,26 +514,24 @@ fn discover_ahci_entries(menu: &mut BootMenu) {
                             // Try to find bootloader on this partition
                             if let Some(controller) = ahci::get_controller(0) {
                                 let mut disk = AhciDisk::new(controller, port_index);
-                                if check_bootloader_exists(&mut disk, partition.first_lba) {
-                                    let mut name: String<64> = String::new();
-                                    let _ = write!(name, "Boot Entry (SATA port {})", port_index);
-
-                                    let entry = BootEntry::new(
-                                        &name,
-                                        "EFI\\BOOT\\BOOTX64.EFI",
-                                        DeviceType::Ahci {
-                                            controller_id: 0,
-                                            port: port_index,
-                                        },
-                                        partition_num,
-                                        partition.clone(),
-                                        pci_addr.device,
-                                        pci_addr.function,
-                                    );
-
-                                    if !menu.add_entry(entry) {
-                                        return; // Menu full
-                                    }
+                                let mut name: String<64> = String::new();
+                                let _ = write!(name, "Boot Entry (SATA port {})", port_index);
+
+                                let entry = BootEntry::new(
+                                    &name,
+                                    DEFAULT_BOOT_PATH,
+                                    DeviceType::Ahci {
+                                        controller_id: 0,
+                                        port: port_index,
+                                    },
+                                    partition_num,
+                                    partition.clone(),
+                                    pci_addr.device,
+                                    pci_addr.function,
+                                );
+
+                                if !add_partition_entries(menu, &mut disk, entry) {
+                                    return; // Menu full
                                 }
                             }
                         }
@@ -330,44 +540,29 @@ fn discover_ahci_entries(menu: &mut BootMenu) {
                     // GPT failed - try El Torito (ISO9660) as fallback
                     if let Some(controller) = ahci::get_controller(0) {
                         let mut disk = AhciDisk::new(controller, port_index);
-                        if let Ok(efi_image) = iso9660::find_efi_boot_image(&mut disk) {
-                            // Create a synthetic partition for the El Torito boot image
-                            let block_size = disk.info().block_size;
-                            let partition = gpt::Partition {
-                                type_guid: [0u8; 16], // Not a real GUID
-                                partition_guid: [0u8; 16],
-                                first_lba: efi_image.start_sector,
-                                last_lba: efi_image.start_sector
-                                    + efi_image.sector_count as u64
-                                    - 1,
-                                attributes: 0,
-                                is_esp: true, // Treat it as ESP
-                                block_size,
-                            };
-
+                        // Synthetic partition for the El Torito boot image
+                        if let Ok(partition) = iso9660::efi_boot_partition(&mut disk) {
                             // Check if the boot image contains BOOTX64.EFI
                             if let Some(controller) = ahci::get_controller(0) {
                                 let mut disk = AhciDisk::new(controller, port_index);
-                                if check_bootloader_exists(&mut disk, efi_image.start_sector) {
-                                    let mut name: String<64> = String::new();
-                                    let _ = write!(name, "ISO Boot (SATA port {})", port_index);
-
-                                    let entry = BootEntry::new(
-                                        &name,
-                                        "EFI\\BOOT\\BOOTX64.EFI",
-                                        DeviceType::Ahci {
-                                            controller_id: 0,
-                                            port: port_index,
-                                        },
-                                        0, // No partition number for El Torito
-                                        partition,
-                                        pci_addr.device,
-                                        pci_addr.function,
-                                    );
-
-                                    if !menu.add_entry(entry) {
-                                        return; // Menu full
-                                    }
+                                let mut name: String<64> = String::new();
+                                let _ = write!(name, "ISO Boot (SATA port {})", port_index);
+
+                                let entry = BootEntry::new(
+                                    &name,
+                                    DEFAULT_BOOT_PATH,
+                                    DeviceType::Ahci {
+                                        controller_id: 0,
+                                        port: port_index,
+                                    },
+                                    0, // No partition number for El Torito
+                                    partition,
+                                    pci_addr.device,
+                                    pci_addr.function,
+                                );
+
+                                if !add_partition_entries(menu, &mut disk, entry) {
+                                    return; // Menu full
                                 }
                             }
                         }
@@ -379,8 +574,11 @@ fn discover_ahci_entries(menu: &mut BootMenu) {
 }
 
 /// Discover boot entries from USB devices (all controller types)
+///
+/// Each LUN with media is scanned, so the cards in a USB card reader each
+/// get their entries.
 fn discover_usb_entries(menu: &mut BootMenu) {
-    use crate::drivers::usb::{self, UsbMassStorage, mass_storage};
+    use crate::drivers::usb::{self, mass_storage};
 
     // Check if we have any mass storage on any controller
     if let Some((controller_id, device_addr)) = usb::find_mass_storage() {
@@ -390,87 +588,143 @@ fn discover_usb_entries(menu: &mut BootMenu) {
             device_addr
         );
 
-        // Get the controller pointer for storing globally
-        let controller_ptr = match usb::get_controller_ptr(controller_id) {
-            Some(ptr) => ptr,
-            None => {
-                log::error!("Failed to get controller {} pointer", controller_id);
-                return;
+        let max_lun = usb::with_controller(controller_id, |controller| {
+            mass_storage::max_lun(controller, device_addr)
+        })
+        .unwrap_or(0);
+        if max_lun > 0 {
+            log::info!("USB mass storage has {} LUNs", max_lun + 1);
+        }
+
+        for lun in 0..=max_lun {
+            if !discover_usb_lun_entries(menu, controller_id, device_addr, lun) {
+                return; // Menu full
             }
-        };
+        }
+    }
+}
 
-        // Use with_controller to create the mass storage device
-        let device_created = usb::with_controller(controller_id, |controller| {
-            match UsbMassStorage::new(controller, device_addr) {
-                Ok(usb_device) => {
-                    // Store device globally WITH controller pointer so global_read_sector can use it directly
-                    // This avoids lock contention since we store the pointer, not just the ID
-                    // SAFETY: controller_ptr is obtained from get_controller_ptr and is valid
-                    unsafe {
-                        mass_storage::store_global_device_with_controller_ptr(
-                            usb_device,
-                            controller_ptr,
-                        )
-                    }
-                }
-                Err(e) => {
-                    log::debug!("Failed to create USB mass storage: {:?}", e);
-                    false
+/// Discover boot entries on a LUN of a USB mass storage device
+///
+/// Returns `false` if the menu is full.
+fn discover_usb_lun_entries(
+    menu: &mut BootMenu,
+    controller_id: usize,
+    device_addr: u8,
+    lun: u8,
+) -> bool {
+    use crate::drivers::usb::{self, UsbMassStorage, mass_storage};
+
+    // Get the controller pointer for storing globally
+    let controller_ptr = match usb::get_controller_ptr(controller_id) {
+        Some(ptr) => ptr,
+        None => {
+            log::error!("Failed to get controller {} pointer", controller_id);
+            return true;
+        }
+    };
+
+    // Use with_controller to create the mass storage device
+    let device_created = usb::with_controller(controller_id, |controller| {
+        match UsbMassStorage::new_lun(controller, device_addr, lun) {
+            Ok(usb_device) => {
+                // Store device globally WITH controller pointer so global_read_sector can use it directly
+                // This avoids lock contention since we store the pointer, not just the ID
+                // SAFETY: controller_ptr is obtained from get_controller_ptr and is valid
+                unsafe {
+                    mass_storage::store_global_device_with_controller_ptr(
+                        usb_device,
+                        controller_ptr,
+                    )
                 }
             }
-        });
-
-        if device_created != Some(true) {
-            return;
+            Err(e) => {
+                log::debug!("Failed to open USB mass storage LUN {}: {:?}", lun, e);
+                false
+            }
         }
+    });
 
-        // Now read partitions using the stored device
-        usb::with_controller(controller_id, |controller| {
-            if let Some(usb_device) = mass_storage::get_global_device() {
-                let mut disk = UsbDisk::new(usb_device, controller);
+    if device_created != Some(true) {
+        return true;
+    }
 
-                // Read GPT and find partitions
-                if let Ok(header) = gpt::read_gpt_header(&mut disk)
-                    && let Ok(partitions) = gpt::read_partitions(&mut disk, &header)
-                {
-                    for (i, partition) in partitions.iter().enumerate() {
-                        let partition_num = (i + 1) as u32;
+    // Now read partitions using the stored device
+    usb::with_controller(controller_id, |controller| {
+        let Some(usb_device) = mass_storage::get_global_device() else {
+            return true;
+        };
+        let controller_type = controller.controller_type();
+        let mut name: String<64> = String::new();
+        let _ = write!(name, "{} USB", controller_type);
+        if lun > 0 {
+            let _ = write!(name, " LUN {}", lun);
+        }
+        let device_type = DeviceType::Usb {
+            controller_id,
+            device_addr,
+            lun,
+        };
+        let mut disk = UsbDisk::new(usb_device, controller);
 
-                        // Check if this is an ESP or potential boot partition
-                        if partition.is_esp || is_potential_esp(partition) {
-                            // We need to create a new disk reference for checking bootloader
-                            // This is a bit awkward due to borrowing rules
-                            if let Some(usb_device2) = mass_storage::get_global_device() {
-                                let mut disk2 = UsbDisk::new(usb_device2, controller);
-                                if check_bootloader_exists(&mut disk2, partition.first_lba) {
-                                    let mut name: String<64> = String::new();
-                                    let controller_type = controller.controller_type();
-                                    let _ = write!(name, "Boot Entry ({} USB)", controller_type);
-
-                                    // Get PCI address - we need to handle this differently
-                                    // For now use placeholder values
-                                    let entry = BootEntry::new(
-                                        &name,
-                                        "EFI\\BOOT\\BOOTX64.EFI",
-                                        DeviceType::Usb {
-                                            controller_id,
-                                            device_addr,
-                                        },
-                                        partition_num,
-                                        partition.clone(),
-                                        0, // PCI device - TODO: get from controller
-                                        0, // PCI function - TODO: get from controller
-                                    );
-
-                                    menu.add_entry(entry);
-                                }
-                            }
-                        }
+        // Read GPT and find partitions
+        let partitions = gpt::read_gpt_header(&mut disk)
+            .and_then(|header| gpt::read_partitions(&mut disk, &header));
+        let Ok(partitions) = partitions else {
+            // GPT failed - try El Torito (ISO9660), e.g. a DVD drive
+            let Ok(partition) = iso9660::efi_boot_partition(&mut disk) else {
+                return true;
+            };
+            let mut iso_name: String<64> = String::new();
+            let _ = write!(iso_name, "ISO Boot ({})", name);
+            let entry = BootEntry::new(
+                &iso_name,
+                DEFAULT_BOOT_PATH,
+                device_type,
+                0, // No partition number for El Torito
+                partition,
+                0, // PCI device - TODO: get from controller
+                0, // PCI function - TODO: get from controller
+            );
+            let Some(usb_device) = mass_storage::get_global_device() else {
+                return true;
+            };
+            let mut disk = UsbDisk::new(usb_device, controller);
+            return add_partition_entries(menu, &mut disk, entry);
+        };
+        for (i, partition) in partitions.iter().enumerate() {
+            let partition_num = (i + 1) as u32;
+
+            // Check if this is an ESP or potential boot partition
+            if partition.is_esp || is_potential_esp(partition) {
+                // We need to create a new disk reference for checking bootloader
+                // This is a bit awkward due to borrowing rules
+                if let Some(usb_device2) = mass_storage::get_global_device() {
+                    let mut entry_name: String<64> = String::new();
+                    let _ = write!(entry_name, "Boot Entry ({})", name);
+
+                    // Get PCI address - we need to handle this differently
+                    // For now use placeholder values
+                    let entry = BootEntry::new(
+                        &entry_name,
+                        DEFAULT_BOOT_PATH,
+                        device_type,
+                        partition_num,
+                        partition.clone(),
+                        0, // PCI device - TODO: get from controller
+                        0, // PCI function - TODO: get from controller
+                    );
+
+                    let mut disk2 = UsbDisk::new(usb_device2, controller);
+                    if !add_partition_entries(menu, &mut disk2, entry) {
+                        return false; // Menu full
                     }
                 }
             }
-        });
-    }
+        }
+        true
+    })
+    .unwrap_or(true)
 }
 
 /// Discover boot entries from SDHCI devices (SD cards)
@@ -506,23 +760,21 @@ fn discover_sdhci_entries(menu: &mut BootMenu) {
                             // Try to find bootloader on this partition
                             if let Some(controller) = sdhci::get_controller(controller_id) {
                                 let mut disk = SdhciDisk::new(controller);
-                                if check_bootloader_exists(&mut disk, partition.first_lba) {
-                                    let mut name: String<64> = String::new();
-                                    let _ = write!(name, "Boot Entry (SD card)");
-
-                                    let entry = BootEntry::new(
-                                        &name,
-                                        "EFI\\BOOT\\BOOTX64.EFI",
-                                        DeviceType::Sdhci { controller_id },
-                                        partition_num,
-                                        partition.clone(),
-                                        pci_addr.device,
-                                        pci_addr.function,
-                                    );
-
-                                    if !menu.add_entry(entry) {
-                                        return; // Menu full
-                                    }
+                                let mut name: String<64> = String::new();
+                                let _ = write!(name, "Boot Entry (SD card)");
+
+                                let entry = BootEntry::new(
+                                    &name,
+                                    DEFAULT_BOOT_PATH,
+                                    DeviceType::Sdhci { controller_id },
+                                    partition_num,
+                                    partition.clone(),
+                                    pci_addr.device,
+                                    pci_addr.function,
+                                );
+
+                                if !add_partition_entries(menu, &mut disk, entry) {
+                                    return; // Menu full
                                 }
                             }
                         }
@@ -533,6 +785,80 @@ fn discover_sdhci_entries(menu: &mut BootMenu) {
     }
 }
 
+/// Discover boot entries from RAM disks
+fn discover_ram_disk_entries(menu: &mut BootMenu) {
+    use crate::drivers::block::RamDiskBlockDevice;
+    use crate::drivers::ramdisk;
+
+    for id in 0..ramdisk::MAX_RAM_DISKS {
+        let Some(ram_disk) = ramdisk::get(id) else {
+            continue;
+        };
+        let mut disk = RamDiskBlockDevice::new(id, &ram_disk);
+        let device_type = DeviceType::RamDisk { id };
+
+        let partitions = gpt::read_gpt_header(&mut disk)
+            .and_then(|header| gpt::read_partitions(&mut disk, &header));
+        let Ok(partitions) = partitions else {
+            // GPT failed - try El Torito (ISO9660)
+            let Ok(partition) = iso9660::efi_boot_partition(&mut disk) else {
+                continue;
+            };
+            let mut name: String<64> = String::new();
+            let _ = write!(name, "ISO Boot ({})", ram_disk.name);
+            let entry = BootEntry::new(
+                &name,
+                DEFAULT_BOOT_PATH,
+                device_type,
+                0, // No partition number for El Torito
+                partition,
+                0,
+                0,
+            );
+            if !add_partition_entries(menu, &mut disk, entry) {
+                return; // Menu full
+            }
+            continue;
+        };
+        for (i, partition) in partitions.iter().enumerate() {
+            if partition.is_esp || is_potential_esp(partition) {
+                let mut name: String<64> = String::new();
+                let _ = write!(name, "Boot Entry ({})", ram_disk.name);
+                let entry = BootEntry::new(
+                    &name,
+                    DEFAULT_BOOT_PATH,
+                    device_type,
+                    (i + 1) as u32,
+                    partition.clone(),
+                    0,
+                    0,
+                );
+                if !add_partition_entries(menu, &mut disk, entry) {
+                    return; // Menu full
+                }
+            }
+        }
+    }
+}
+
+/// Replace the SD card entries after a card was inserted or removed
+fn rescan_sdhci_entries(menu: &mut BootMenu) {
+    menu.entries
+        .retain(|entry| !matches!(entry.device_type, DeviceType::Sdhci { .. }));
+    discover_sdhci_entries(menu);
+    menu.selected = menu.selected.min(menu.entries.len().saturating_sub(1));
+    log::info!("SD cards changed, {} boot entries", menu.entry_count());
+}
+
+/// Replace the USB entries after a device was plugged in or removed
+fn rescan_usb_entries(menu: &mut BootMenu) {
+    menu.entries
+        .retain(|entry| !matches!(entry.device_type, DeviceType::Usb { .. }));
+    discover_usb_entries(menu);
+    menu.selected = menu.selected.min(menu.entries.len().saturating_sub(1));
+    log::info!("USB devices changed, {} boot entries", menu.entry_count());
+}
+
 /// Check if a partition might be an ESP (fallback heuristic)
 fn is_potential_esp(partition: &gpt::Partition) -> bool {
     // Small partitions (< 512 MB) are more likely to be boot partitions
@@ -540,10 +866,126 @@ fn is_potential_esp(partition: &gpt::Partition) -> bool {
     size_mb > 0 && size_mb < 512 && partition.first_lba > 0
 }
 
-/// Check if a bootloader exists on the given partition
-fn check_bootloader_exists<D: BlockDevice>(disk: &mut D, partition_start: u64) -> bool {
+/// Add the boot entries found on a partition
+///
+/// `entry` describes the partition and is added for the first of the
+/// [bootloader paths](boot_paths) that exists on it. If the boot option saved by [`crate::boot_options`] lives on
+/// this partition, it is put first in the menu. The Boot Loader
+/// Specification entries of an ESP and its XBOOTLDR partition follow the
+/// default bootloader, then the Unified Kernel Images in `\EFI\Linux`. If
+/// the CrabEFI self-test application is installed on the same partition, a
+/// diagnostics entry is added as well.
+///
+/// Returns `false` if the menu is full.
+fn add_partition_entries<D: BlockDevice>(
+    menu: &mut BootMenu,
+    disk: &mut D,
+    mut entry: BootEntry,
+) -> bool {
+    let partition_start = entry.partition.first_lba;
+
+    let bootloader = boot_paths::paths()
+        .into_iter()
+        .find(|path| file_exists(disk, partition_start, path));
+    if let Some(path) = &bootloader {
+        entry.path.clone_from(path);
+        entry.ia32 = is_ia32(disk, partition_start, path);
+    }
+
+    // Built before `entry` is moved into the menu
+    let boot_option = BootOption::load()
+        .filter(|option| option.partition_guid == entry.partition.partition_guid)
+        .filter(|option| !option.path.eq_ignore_ascii_case(&entry.path))
+        .filter(|option| file_exists(disk, partition_start, &option.path))
+        .map(|option| {
+            let mut boot_option = entry.clone();
+            boot_option.name.clear();
+            boot_option.path.clear();
+            let _ = boot_option.name.push_str(option.name());
+            let _ = boot_option.path.push_str(&option.path);
+            boot_option
+        });
+    let diagnostics = file_exists(disk, partition_start, DIAGNOSTICS_PATH).then(|| {
+        let mut diagnostics = entry.clone();
+        diagnostics.name.clear();
+        diagnostics.path.clear();
+        let _ = diagnostics.name.push_str(DIAGNOSTICS_NAME);
+        let _ = diagnostics.path.push_str(DIAGNOSTICS_PATH);
+        diagnostics
+    });
+    let loader_entries: Vec<BootEntry, { bls::MAX_ENTRY_FILES }> = if entry.partition.is_esp {
+        let volumes = bls::Volumes::find(disk, &entry.partition);
+        if menu.loader_conf.is_none() {
+            menu.loader_conf = bls::LoaderConf::load(disk, &volumes);
+        }
+        if menu.grub_env.is_none() {
+            menu.grub_env = FatFilesystem::new(disk, partition_start)
+                .ok()
+                .and_then(|mut fat| GrubEnv::load(&mut fat));
+        }
+        bls::load_entries(disk, &volumes)
+            .into_iter()
+            .map(|loaded| {
+                let mut boot_entry = entry.clone();
+                if let (bls::Volume::Xbootldr, Some((number, xbootldr))) =
+                    (loaded.file.volume, &volumes.xbootldr)
+                {
+                    boot_entry.partition = xbootldr.clone();
+                    boot_entry.partition_num = *number;
+                }
+                boot_entry.name = loaded.entry.display_title();
+                boot_entry.path.clear();
+                let _ = boot_entry.path.push_str(&loaded.entry.path);
+                let options = loaded.entry.kernel_options();
+                boot_entry.kernel_options = (!options.is_empty()).then_some(options);
+                boot_entry.loader_entry = Some(loaded.file.id());
+                boot_entry
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let ukis: Vec<BootEntry, { uki::MAX_IMAGES }> = FatFilesystem::new(disk, partition_start)
+        .map(|mut fat| uki::find_images(&mut fat))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|image| {
+            let mut boot_entry = entry.clone();
+            boot_entry.name.clear();
+            boot_entry.path.clear();
+            let _ = boot_entry.name.push_str(&image.title);
+            let _ = boot_entry.path.push_str(&image.path);
+            boot_entry
+        })
+        .collect();
+
+    if let Some(boot_option) = boot_option {
+        log::info!("Found saved boot option {}", boot_option.path);
+        if !menu.insert_entry(0, boot_option) {
+            return false;
+        }
+    }
+
+    if bootloader.is_some() && !menu.add_entry(entry) {
+        return false;
+    }
+
+    for boot_entry in loader_entries.into_iter().chain(ukis) {
+        if !menu.add_entry(boot_entry) {
+            return false;
+        }
+    }
+
+    match diagnostics {
+        Some(diagnostics) => menu.add_entry(diagnostics),
+        None => true,
+    }
+}
+
+/// Check if a non-empty file exists on the given partition
+fn file_exists<D: BlockDevice>(disk: &mut D, partition_start: u64, path: &str) -> bool {
     match FatFilesystem::new(disk, partition_start) {
-        Ok(mut fat) => match fat.file_size("EFI\\BOOT\\BOOTX64.EFI") {
+        Ok(mut fat) => match fat.file_size(path) {
             Ok(size) => size > 0,
             Err(_) => false,
         },
@@ -551,8 +993,33 @@ fn check_bootloader_exists<D: BlockDevice>(disk: &mut D, partition_start: u64) -
     }
 }
 
+/// Check if a file on the given partition is a 32-bit EFI application
+fn is_ia32<D: BlockDevice>(disk: &mut D, partition_start: u64, path: &str) -> bool {
+    let mut header = [0u8; 1024];
+    let Ok(mut fat) = FatFilesystem::new(disk, partition_start) else {
+        return false;
+    };
+    let len = fat
+        .find_file(path)
+        .and_then(|file| fat.read_file(&file, 0, &mut header))
+        .unwrap_or(0);
+    pe::machine(&header[..len]) == Some(pe::IMAGE_FILE_MACHINE_I386)
+}
+
+/// Whether the selected entry can be booted, showing why not otherwise
+fn selected_bootable(menu: &BootMenu, fb_console: &mut Option<FramebufferConsole>) -> bool {
+    if menu.selected_entry().is_some_and(|entry| entry.ia32) {
+        draw_status(IA32_TEXT, fb_console);
+        return false;
+    }
+    true
+}
+
 /// Show the boot menu and wait for user selection
 ///
+/// USB drives and SD cards plugged in while the menu is shown are scanned
+/// and their entries added. An empty menu waits for one.
+///
 /// # Arguments
 ///
 /// * `menu` - The boot menu with discovered entries
@@ -561,13 +1028,8 @@ fn check_bootloader_exists<D: BlockDevice>(disk: &mut D, partition_start: u64) -
 ///
 /// The index of the selected boot entry, or `None` if no selection was made.
 pub fn show_menu(menu: &mut BootMenu) -> Option<usize> {
-    if menu.entry_count() == 0 {
-        log::error!("No boot entries to display");
-        return None;
-    }
-
-    // Get framebuffer for rendering
-    let fb_info = coreboot::get_framebuffer();
+    // Get framebuffer for rendering, drawing on the shadow if there is one
+    let fb_info = coreboot::get_framebuffer().map(|fb| fb_shadow::framebuffer(&fb));
 
     // Create framebuffer console if available
     let mut fb_console = fb_info.as_ref().map(FramebufferConsole::new);
@@ -583,16 +1045,16 @@ pub fn show_menu(menu: &mut BootMenu) -> Option<usize> {
     let mut last_second_check = Timeout::from_ms(0); // Immediately update
 
     loop {
-        // Check for timeout
-        if remaining_seconds > 0 && last_second_check.is_expired() {
+        // Check for timeout, there is nothing to boot in an empty menu
+        if remaining_seconds > 0 && menu.entry_count() > 0 && last_second_check.is_expired() {
             remaining_seconds -= 1;
             last_second_check = Timeout::from_ms(1000);
 
             // Update countdown display
             draw_countdown(remaining_seconds, &mut fb_console);
 
-            if remaining_seconds == 0 {
-                // Timeout - boot selected entry
+            // Timeout - boot selected entry
+            if remaining_seconds == 0 && selected_bootable(menu, &mut fb_console) {
                 return Some(menu.selected);
             }
         }
@@ -611,13 +1073,49 @@ pub fn show_menu(menu: &mut BootMenu) -> Option<usize> {
                     menu.select_next();
                     draw_menu(menu, &mut fb_console);
                 }
-                KeyPress::Enter => {
+                KeyPress::Enter
+                    if menu.entry_count() > 0 && selected_bootable(menu, &mut fb_console) =>
+                {
                     return Some(menu.selected);
                 }
                 KeyPress::Escape => {
                     // Future: file browser
                     draw_status("File browser not yet implemented", &mut fb_console);
                 }
+                KeyPress::Char('e')
+                    if menu
+                        .selected_entry()
+                        .is_some_and(|entry| !entry.is_payload()) =>
+                {
+                    if !setup_password::authorize("editing") {
+                        clear_screen(&mut fb_console);
+                        draw_menu(menu, &mut fb_console);
+                        continue;
+                    }
+                    let entry = &mut menu.entries[menu.selected];
+                    if let Some(options) = edit_kernel_options(entry, &mut fb_console) {
+                        entry.kernel_options = (!options.is_empty()).then_some(options);
+                        return Some(menu.selected);
+                    }
+                    clear_screen(&mut fb_console);
+                    draw_menu(menu, &mut fb_console);
+                }
+                KeyPress::Char('c') => {
+                    if setup_password::authorize("the console")
+                        && let Some(index) = recovery::run(menu, &mut fb_console)
+                    {
+                        return Some(index);
+                    }
+                    clear_screen(&mut fb_console);
+                    draw_menu(menu, &mut fb_console);
+                }
+                KeyPress::Char('s') => {
+                    if setup_password::authorize("setup") {
+                        show_setup(menu);
+                    }
+                    clear_screen(&mut fb_console);
+                    draw_menu(menu, &mut fb_console);
+                }
                 KeyPress::Char(c) if c.is_ascii_digit() => {
                     // Direct selection by number
                     let num = (c as u8 - b'0') as usize;
@@ -630,28 +1128,412 @@ pub fn show_menu(menu: &mut BootMenu) -> Option<usize> {
             }
         }
 
+        // Pick up SD cards inserted or removed while the menu is shown
+        if crate::drivers::sdhci::poll_cards() {
+            remaining_seconds = menu.timeout_seconds;
+            rescan_sdhci_entries(menu);
+            clear_screen(&mut fb_console);
+            draw_menu(menu, &mut fb_console);
+        }
+
+        // Same for USB drives
+        if crate::drivers::usb::poll_ports() {
+            remaining_seconds = menu.timeout_seconds;
+            rescan_usb_entries(menu);
+            clear_screen(&mut fb_console);
+            draw_menu(menu, &mut fb_console);
+        }
+
         // Small delay to avoid busy-waiting
         delay_ms(10);
     }
 }
 
+/// Show the setup screen until Escape is pressed
+///
+/// Lists the [boot history](boot_log), with the name of each entry that is
+/// still in `menu`.
+pub fn show_setup(menu: &BootMenu) {
+    let fb_info = coreboot::get_framebuffer().map(|fb| fb_shadow::framebuffer(&fb));
+    let mut fb_console = fb_info.as_ref().map(FramebufferConsole::new);
+    let cols = fb_console.as_ref().map(|c| c.cols()).unwrap_or(80) as usize;
+
+    clear_screen(&mut fb_console);
+    draw_header(SETUP_TITLE, &mut fb_console, cols);
+    draw_boot_history(menu, &mut fb_console);
+
+    loop {
+        if let Some(KeyPress::Escape) = read_key() {
+            return;
+        }
+        delay_ms(10);
+    }
+}
+
+/// Draw the boot history on the setup screen
+fn draw_boot_history(menu: &BootMenu, fb_console: &mut Option<FramebufferConsole>) {
+    let title_row = 4;
+    let first_row = 6;
+    let records = boot_log::records();
+
+    let mut lines: Vec<String<128>, { boot_log::MAX_RECORDS }> = Vec::new();
+    for record in &records {
+        let mut line = String::new();
+        let _ = write!(line, "{}  {:<8} ", record.time, record.outcome);
+        let _ = match menu.find_loader_id(&record.entry) {
+            Some(entry) => write!(line, "{}", entry.name),
+            None => write!(line, "{} (no longer in the menu)", record.entry),
+        };
+        let _ = lines.push(line);
+    }
+    if lines.is_empty() {
+        let _ = lines.push(String::try_from("No boots recorded").unwrap_or_default());
+    }
+    let help_row = first_row + lines.len() + 1;
+
+    // Serial output, ANSI rows are 1-based
+    let _ = write!(
+        SerialWriter,
+        "\x1b[{};3H\x1b[1mBoot history\x1b[0m\x1b[K",
+        title_row + 1
+    );
+    for (i, line) in lines.iter().enumerate() {
+        let _ = write!(SerialWriter, "\x1b[{};3H{}\x1b[K", first_row + i + 1, line);
+    }
+    let _ = write!(
+        SerialWriter,
+        "\x1b[{};3H\x1b[36m{}\x1b[0m\x1b[K",
+        help_row + 1,
+        SETUP_HELP
+    );
+
+    // Framebuffer output
+    if let Some(console) = fb_console {
+        console.set_fg_color(TITLE_COLOR);
+        console.set_position(2, title_row as u32);
+        let _ = console.write_str("Boot history");
+        console.reset_colors();
+        for (i, line) in lines.iter().enumerate() {
+            console.set_position(2, (first_row + i) as u32);
+            let _ = console.write_str(line);
+        }
+        console.set_fg_color(Color::new(0, 192, 192)); // Cyan
+        console.write_centered(help_row as u32, SETUP_HELP);
+        console.reset_colors();
+        console.flush();
+    }
+}
+
+/// Ask for a passphrase on both outputs
+///
+/// Typed characters are shown as `*`. `status` is shown in red below the
+/// prompt, e.g. after a wrong passphrase. Returns the length of the
+/// passphrase written to `passphrase`, or `None` if Escape was pressed.
+pub fn read_passphrase(
+    prompt: &str,
+    status: Option<&str>,
+    passphrase: &mut [u8; MAX_PASSPHRASE_LEN],
+) -> Option<usize> {
+    read_secret(
+        PASSPHRASE_TITLE,
+        PASSPHRASE_HELP,
+        prompt,
+        status,
+        passphrase,
+    )
+}
+
+/// Ask for a secret on both outputs, under `title` and with `help`
+///
+/// Works like [`read_passphrase`].
+pub fn read_secret(
+    title: &str,
+    help: &str,
+    prompt: &str,
+    status: Option<&str>,
+    secret: &mut [u8; MAX_PASSPHRASE_LEN],
+) -> Option<usize> {
+    let fb_info = coreboot::get_framebuffer().map(|fb| fb_shadow::framebuffer(&fb));
+    let mut fb_console = fb_info.as_ref().map(FramebufferConsole::new);
+    let cols = fb_console.as_ref().map(|c| c.cols()).unwrap_or(80) as usize;
+
+    clear_screen(&mut fb_console);
+    draw_header(title, &mut fb_console, cols);
+    if let Some(status) = status {
+        draw_status(status, &mut fb_console);
+    }
+    draw_passphrase(prompt, help, 0, &mut fb_console);
+
+    let mut len = 0;
+    loop {
+        if let Some(key) = read_key() {
+            match key {
+                KeyPress::Enter => return Some(len),
+                KeyPress::Escape => {
+                    secret.fill(0);
+                    return None;
+                }
+                // Backspace, or DEL from serial terminals
+                KeyPress::Char('\x08' | '\x7f') => len = len.saturating_sub(1),
+                KeyPress::Char(c) if (' '..='~').contains(&c) && len < MAX_PASSPHRASE_LEN => {
+                    secret[len] = c as u8;
+                    len += 1;
+                }
+                _ => continue,
+            }
+            draw_passphrase(prompt, help, len, &mut fb_console);
+        }
+
+        delay_ms(10);
+    }
+}
+
+/// Draw the passphrase prompt with `len` characters typed
+fn draw_passphrase(
+    prompt: &str,
+    help: &str,
+    len: usize,
+    fb_console: &mut Option<FramebufferConsole>,
+) {
+    let mut stars: String<MAX_PASSPHRASE_LEN> = String::new();
+    for _ in 0..len {
+        let _ = stars.push('*');
+    }
+
+    // Serial output
+    let _ = write!(
+        SerialWriter,
+        "\x1b[6;1H{}\x1b[K\r\n\r\n\x1b[36m{}\x1b[0m\x1b[K\x1b[6;{}H{}",
+        prompt,
+        help,
+        prompt.len() + 2,
+        stars
+    );
+
+    // Framebuffer output
+    if let Some(console) = fb_console {
+        console.write_centered(5, prompt);
+        console.clear_line(7);
+        console.write_centered(7, &stars);
+        console.set_fg_color(Color::new(0, 192, 192)); // Cyan
+        console.write_centered(9, help);
+        console.reset_colors();
+        console.flush();
+    }
+}
+
+/// A single-line text field of the kernel command line editor
+struct Field {
+    label: &'static str,
+    text: Vec<u8, MAX_CMDLINE_LEN>,
+    /// Maximum length of the text
+    limit: usize,
+    /// Insertion point
+    cursor: usize,
+}
+
+impl Field {
+    fn new(label: &'static str, text: &str, limit: usize) -> Self {
+        let mut field = Field {
+            label,
+            text: Vec::new(),
+            limit,
+            cursor: 0,
+        };
+        let _ = field
+            .text
+            .extend_from_slice(&text.as_bytes()[..text.len().min(limit)]);
+        field.cursor = field.text.len();
+        field
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.text).unwrap_or("")
+    }
+
+    /// First character shown when `width` characters fit on screen
+    fn scroll(&self, width: usize) -> usize {
+        (self.cursor + 1).saturating_sub(width)
+    }
+
+    /// The part of the text shown when `width` characters fit on screen
+    fn visible(&self, width: usize) -> &str {
+        let start = self.scroll(width);
+        &self.as_str()[start..(start + width).min(self.text.len())]
+    }
+
+    /// Apply an editing key, returning whether the field changed
+    fn edit(&mut self, key: KeyPress) -> bool {
+        match key {
+            KeyPress::Left if self.cursor > 0 => self.cursor -= 1,
+            KeyPress::Right if self.cursor < self.text.len() => self.cursor += 1,
+            // Backspace, or DEL from serial terminals
+            KeyPress::Char('\x08' | '\x7f') if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.text.remove(self.cursor);
+            }
+            KeyPress::Char(c) if (' '..='~').contains(&c) && self.text.len() < self.limit => {
+                let _ = self.text.insert(self.cursor, c as u8);
+                self.cursor += 1;
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Edit the command line and initrd of `entry`
+///
+/// Starts from the entry's previous edit or the last remembered one.
+/// Returns the options to boot with on Enter, or `None` if Escape was
+/// pressed.
+fn edit_kernel_options(
+    entry: &BootEntry,
+    fb_console: &mut Option<FramebufferConsole>,
+) -> Option<KernelOptions> {
+    let initial = entry
+        .kernel_options
+        .clone()
+        .or_else(KernelOptions::last)
+        .unwrap_or_default();
+    let mut fields = [
+        Field::new("Command line:", &initial.cmdline, MAX_CMDLINE_LEN),
+        Field::new("Initrd:", &initial.initrd, MAX_INITRD_LEN),
+    ];
+    let mut active = 0;
+    let cols = fb_console.as_ref().map(|c| c.cols()).unwrap_or(80) as usize;
+
+    clear_screen(fb_console);
+    draw_header(EDITOR_TITLE, fb_console, cols);
+    draw_editor(&entry.name, &fields, active, fb_console, cols);
+
+    loop {
+        if let Some(key) = read_key() {
+            match key {
+                KeyPress::Enter => {
+                    let mut options = KernelOptions::default();
+                    let _ = options.cmdline.push_str(fields[0].as_str());
+                    let _ = options.initrd.push_str(fields[1].as_str());
+                    return Some(options);
+                }
+                KeyPress::Escape => return None,
+                KeyPress::Up => active = active.saturating_sub(1),
+                KeyPress::Down => active = (active + 1).min(fields.len() - 1),
+                KeyPress::Char('\t') => active = (active + 1) % fields.len(),
+                key => {
+                    if !fields[active].edit(key) {
+                        continue;
+                    }
+                }
+            }
+            draw_editor(&entry.name, &fields, active, fb_console, cols);
+        }
+
+        delay_ms(10);
+    }
+}
+
+/// Draw the editor fields, with the cursor in the `active` one
+fn draw_editor(
+    name: &str,
+    fields: &[Field],
+    active: usize,
+    fb_console: &mut Option<FramebufferConsole>,
+    cols: usize,
+) {
+    // Fields are indented by two columns on both sides
+    let width = cols.saturating_sub(4).max(1);
+    let name_row = 4;
+    let field_row = |i: usize| 6 + i * 3;
+    let help_row = field_row(fields.len());
+
+    // Serial output, ANSI rows are 1-based
+    let _ = write!(SerialWriter, "\x1b[{};3H{}\x1b[K", name_row + 1, name);
+    for (i, field) in fields.iter().enumerate() {
+        let row = field_row(i) + 1;
+        let highlight = if i == active { "\x1b[7m" } else { "" };
+        let _ = write!(
+            SerialWriter,
+            "\x1b[{};3H{}{}\x1b[0m\x1b[K\x1b[{};3H{}\x1b[K",
+            row,
+            highlight,
+            field.label,
+            row + 1,
+            field.visible(width)
+        );
+    }
+    let _ = write!(
+        SerialWriter,
+        "\x1b[{};3H\x1b[36m{}\x1b[0m\x1b[K",
+        help_row + 1,
+        EDITOR_HELP
+    );
+    // Leave the terminal cursor at the insertion point
+    let field = &fields[active];
+    let _ = write!(
+        SerialWriter,
+        "\x1b[{};{}H",
+        field_row(active) + 2,
+        3 + field.cursor - field.scroll(width)
+    );
+
+    // Framebuffer output
+    if let Some(console) = fb_console {
+        console.clear_line(name_row as u32);
+        console.set_position(2, name_row as u32);
+        let _ = console.write_str(name);
+        for (i, field) in fields.iter().enumerate() {
+            let row = field_row(i) as u32;
+            console.clear_line(row);
+            console.clear_line(row + 1);
+            if i == active {
+                console.set_colors(HIGHLIGHT_FG, HIGHLIGHT_BG);
+            }
+            console.set_position(2, row);
+            let _ = console.write_str(field.label);
+            console.reset_colors();
+            console.set_position(2, row + 1);
+            let _ = console.write_str(field.visible(width));
+        }
+
+        // Draw the cursor over the character at the insertion point
+        let cursor = *field.text.get(field.cursor).unwrap_or(&b' ') as char;
+        console.draw_char_at(
+            cursor,
+            (2 + field.cursor - field.scroll(width)) as u32,
+            field_row(active) as u32 + 1,
+            HIGHLIGHT_FG,
+            HIGHLIGHT_BG,
+        );
+
+        console.set_fg_color(Color::new(0, 192, 192)); // Cyan
+        console.write_centered(help_row as u32, EDITOR_HELP);
+        console.reset_colors();
+        console.flush();
+    }
+}
+
 /// Key press types for menu navigation
 #[derive(Debug, Clone, Copy)]
-enum KeyPress {
+pub enum KeyPress {
     Up,
     Down,
+    Left,
+    Right,
     Enter,
     Escape,
     Char(char),
 }
 
 /// Read a key from keyboard or serial
-fn read_key() -> Option<KeyPress> {
+pub fn read_key() -> Option<KeyPress> {
     // Try PS/2 keyboard first
     if let Some((scan_code, unicode_char)) = keyboard::try_read_key() {
         return match scan_code {
             0x01 => Some(KeyPress::Up),                         // SCAN_UP
             0x02 => Some(KeyPress::Down),                       // SCAN_DOWN
+            0x03 => Some(KeyPress::Right),                      // SCAN_RIGHT
+            0x04 => Some(KeyPress::Left),                       // SCAN_LEFT
             0x17 => Some(KeyPress::Escape),                     // SCAN_ESC
             0 if unicode_char == 0x0D => Some(KeyPress::Enter), // Carriage return
             0 if unicode_char > 0 => Some(KeyPress::Char(unicode_char as u8 as char)),
@@ -669,6 +1551,8 @@ fn read_key() -> Option<KeyPress> {
                     match serial_driver::try_read() {
                         Some(b'A') => Some(KeyPress::Up),
                         Some(b'B') => Some(KeyPress::Down),
+                        Some(b'C') => Some(KeyPress::Right),
+                        Some(b'D') => Some(KeyPress::Left),
                         _ => Some(KeyPress::Escape),
                     }
                 } else {
@@ -691,6 +1575,7 @@ fn clear_screen(fb_console: &mut Option<FramebufferConsole>) {
     // Clear framebuffer
     if let Some(console) = fb_console {
         console.clear();
+        console.flush();
     }
 }
 
@@ -699,7 +1584,7 @@ fn draw_menu(menu: &BootMenu, fb_console: &mut Option<FramebufferConsole>) {
     let cols = fb_console.as_ref().map(|c| c.cols()).unwrap_or(80) as usize;
 
     // Draw header
-    draw_header(fb_console, cols);
+    draw_header(menu.title, fb_console, cols);
 
     // Draw entries
     let start_row = 4;
@@ -711,10 +1596,18 @@ fn draw_menu(menu: &BootMenu, fb_console: &mut Option<FramebufferConsole>) {
     // Draw help text
     let help_row = start_row + menu.entry_count() + 2;
     draw_help(help_row, fb_console, cols);
+
+    if menu.entries.is_empty() {
+        draw_status(NO_MEDIA_TEXT, fb_console);
+    }
+
+    if let Some(console) = fb_console {
+        console.flush();
+    }
 }
 
 /// Draw the menu header
-fn draw_header(fb_console: &mut Option<FramebufferConsole>, cols: usize) {
+fn draw_header(title: &str, fb_console: &mut Option<FramebufferConsole>, cols: usize) {
     // Build horizontal line
     let mut line = [0u8; 128];
     let line_len = cols.min(line.len());
@@ -728,11 +1621,11 @@ fn draw_header(fb_console: &mut Option<FramebufferConsole>, cols: usize) {
     serial_driver::write_str("\r\n");
 
     // Center title
-    let title_pad = (cols.saturating_sub(MENU_TITLE.len())) / 2;
+    let title_pad = (cols.saturating_sub(title.len())) / 2;
     for _ in 0..title_pad {
         serial_driver::write_str(" ");
     }
-    serial_driver::write_str(MENU_TITLE);
+    serial_driver::write_str(title);
     serial_driver::write_str("\r\n");
 
     serial_driver::write_str(line_str);
@@ -744,7 +1637,7 @@ fn draw_header(fb_console: &mut Option<FramebufferConsole>, cols: usize) {
         console.set_fg_color(TITLE_COLOR);
         let _ = console.write_str(line_str);
         console.set_position(0, 1);
-        console.write_centered(1, MENU_TITLE);
+        console.write_centered(1, title);
         console.set_position(0, 2);
         let _ = console.write_str(line_str);
         console.reset_colors();
@@ -852,6 +1745,7 @@ fn draw_countdown(seconds: u32, fb_console: &mut Option<FramebufferConsole>) {
         console.set_fg_color(Color::new(255, 255, 0)); // Yellow
         console.write_centered(row, &msg);
         console.reset_colors();
+        console.flush();
     }
 }
 
@@ -870,7 +1764,66 @@ fn draw_status(message: &str, fb_console: &mut Option<FramebufferConsole>) {
         console.set_fg_color(Color::new(255, 0, 0)); // Red
         console.write_centered(row, message);
         console.reset_colors();
+        console.flush();
+    }
+}
+
+/// Width of the load progress bar in characters
+const PROGRESS_BAR_WIDTH: usize = 40;
+
+/// Show progress while a file is loaded
+///
+/// `load` gets the callback to hand to the file reader. The framebuffer shows
+/// a progress bar where the countdown was, and a log line goes to serial
+/// every 10%, so loading a large image doesn't look like a hang.
+pub fn show_load_progress<R>(
+    path: &str,
+    load: impl FnOnce(&mut dyn FnMut(usize, usize)) -> R,
+) -> R {
+    let fb_info = coreboot::get_framebuffer().map(|fb| fb_shadow::framebuffer(&fb));
+    let mut fb_console = fb_info.as_ref().map(FramebufferConsole::new);
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    let mut last_percent: Option<usize> = None;
+
+    load(&mut |done, total| {
+        let percent = (done * 100).checked_div(total).unwrap_or(100);
+        if last_percent == Some(percent) {
+            return;
+        }
+        if last_percent.is_none_or(|last| percent / 10 > last / 10) {
+            log::info!("Loading {}: {}%", name, percent);
+        }
+        last_percent = Some(percent);
+        draw_progress(name, percent, &mut fb_console);
+    })
+}
+
+/// Draw the load progress bar
+fn draw_progress(name: &str, percent: usize, fb_console: &mut Option<FramebufferConsole>) {
+    let Some(console) = fb_console else {
+        return;
+    };
+
+    let filled = percent * PROGRESS_BAR_WIDTH / 100;
+    let mut bar: String<64> = String::new();
+    let _ = bar.push('[');
+    for i in 0..PROGRESS_BAR_WIDTH {
+        let _ = bar.push(if i < filled { '#' } else { '-' });
     }
+    let _ = write!(bar, "] {:3}%", percent);
+
+    let mut title: String<64> = String::new();
+    let _ = write!(title, "Loading {}", name);
+
+    let row = console.rows().saturating_sub(3);
+    let title_row = row.saturating_sub(1);
+    console.clear_line(title_row);
+    console.clear_line(row);
+    console.set_fg_color(TITLE_COLOR);
+    console.write_centered(title_row, &title);
+    console.reset_colors();
+    console.write_centered(row, &bar);
+    console.flush();
 }
 
 /// Helper for serial formatted output
@@ -0,0 +1,151 @@
+//! Parallel device initialization
+//!
+//! Controller init spends most of its time waiting: USB port reset and
+//! settle delays, the SD card power-up loop, controller ready delays.
+//! [`run`] hands independent init functions to the APs started for MP
+//! Services so those waits overlap instead of adding up.
+//!
+//! The rest of the firmware assumes a single thread (firmware state, the
+//! allocator, PCI config access, the console), so the tasks don't really run
+//! at the same time: they take turns holding the boot lock and only give it
+//! up while they wait in [`crate::time::delay_us`]. Results are joined
+//! through the usual driver globals, which the storage code reads once
+//! [`run`] returns.
+//!
+//! A task must not wait while it holds something another task may take: a
+//! `spin::Mutex` guard would make that task spin forever with the boot lock,
+//! and an [`EfiCell`](crate::efi::cell::EfiCell) borrow would be reentered.
+//! The drivers lock their controller lists only to add a controller, and
+//! [`wait`] asserts that no `EfiCell` borrow is active.
+
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use heapless::Vec;
+
+use crate::arch::x86_64::smp;
+use crate::efi;
+use crate::efi::protocols::mp_services;
+use crate::state::MAX_PROCESSORS;
+
+/// Stack size of a task on an AP, in pages
+///
+/// Driver init builds large controller structures on the stack.
+const TASK_STACK_PAGES: u64 = 64;
+
+/// Boot lock owner value when nobody holds it
+const NO_OWNER: u32 = u32::MAX;
+
+/// Set while [`run`] has tasks on the APs
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// APIC ID of the processor holding the boot lock
+static OWNER: AtomicU32 = AtomicU32::new(NO_OWNER);
+
+/// A task handed to an AP
+struct Task {
+    function: fn(),
+    processor: usize,
+    stack: u64,
+    done: AtomicBool,
+}
+
+/// Take the boot lock
+fn acquire() {
+    let id = smp::apic_id();
+    while OWNER
+        .compare_exchange_weak(NO_OWNER, id, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+}
+
+/// Give up the boot lock
+fn release() {
+    OWNER.store(NO_OWNER, Ordering::Release);
+}
+
+/// Wait in `wait`, letting the other tasks run meanwhile
+///
+/// Outside of [`run`], or if the caller doesn't hold the boot lock, this
+/// just calls `wait`.
+///
+/// # Panics
+///
+/// Panics if an `EfiCell` borrow is active while other tasks would run.
+pub fn wait(wait: impl FnOnce()) {
+    if !ACTIVE.load(Ordering::Acquire) || OWNER.load(Ordering::Relaxed) != smp::apic_id() {
+        wait();
+        return;
+    }
+
+    assert!(
+        !efi::cell::any_borrowed(),
+        "Parallel init: waiting inside an EfiCell borrow"
+    );
+    release();
+    wait();
+    acquire();
+}
+
+/// Body of a task on the task stack
+extern "C" fn task_main(argument: u64) {
+    let task = unsafe { &*(argument as *const Task) };
+    acquire();
+    (task.function)();
+    release();
+    task.done.store(true, Ordering::Release);
+}
+
+/// MP procedure of a task, switching to the task stack
+extern "efiapi" fn ap_task(argument: *mut c_void) {
+    let task = unsafe { &*(argument as *const Task) };
+    let stack_top = task.stack + TASK_STACK_PAGES * efi::allocator::PAGE_SIZE;
+    unsafe { smp::call_on_stack(argument as u64, task_main, stack_top) };
+}
+
+/// Run independent init functions, on the APs when there are any
+///
+/// The first function and those without an AP run on the BSP. Returns once
+/// all of them have finished.
+pub fn run(functions: &[fn()]) {
+    let mut tasks: Vec<Task, MAX_PROCESSORS> = Vec::new();
+    for (&function, processor) in functions.iter().skip(1).zip(mp_services::idle_aps()) {
+        let Some(stack) = efi::allocate_pages(TASK_STACK_PAGES) else {
+            log::warn!("Parallel init: no memory for task stacks");
+            break;
+        };
+        let _ = tasks.push(Task {
+            function,
+            processor,
+            stack: stack.as_ptr() as u64,
+            done: AtomicBool::new(false),
+        });
+    }
+    if tasks.is_empty() {
+        functions.iter().for_each(|function| function());
+        return;
+    }
+    log::debug!("Parallel init: {} tasks on APs", tasks.len());
+
+    ACTIVE.store(true, Ordering::Release);
+    acquire();
+    for task in &tasks {
+        mp_services::assign(task.processor, ap_task, task as *const Task as *mut c_void);
+    }
+
+    functions[0]();
+    functions[1 + tasks.len()..]
+        .iter()
+        .for_each(|function| function());
+
+    release();
+    while !tasks.iter().all(|task| task.done.load(Ordering::Acquire)) {
+        core::hint::spin_loop();
+    }
+    ACTIVE.store(false, Ordering::Release);
+
+    for task in &tasks {
+        let _ = efi::allocator::free_pages(task.stack, TASK_STACK_PAGES);
+    }
+}
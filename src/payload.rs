@@ -0,0 +1,376 @@
+//! Chainloading coreboot payloads
+//!
+//! Other payloads in CBFS, like SeaBIOS for operating systems that need a
+//! legacy BIOS, are listed in the boot menu after the EFI boot loaders.
+//! Following coreboot's convention for secondary payloads, they are the
+//! files named `img/*`, which SeaBIOS and GRUB offer as well:
+//!
+//! ```text
+//! cbfstool coreboot.rom add-payload -f seabios.elf -n img/seabios -c lzma
+//! ```
+//!
+//! `add-payload` and `add-flat-binary` store payloads as SELF: a table of
+//! segments to load, each uncompressed, LZMA or LZ4 compressed, ending with
+//! the entry point. A payload is started the way coreboot starts it, in flat
+//! 32-bit protected mode with the coreboot table as argument.
+//!
+//! Segments are only loaded into free memory, and below 1 MiB, where legacy
+//! payloads like SeaBIOS live and coreboot lets them overwrite reserved
+//! ranges too. Once loaded, the devices are shut down as for
+//! ExitBootServices, and the RSDP is pointed back at coreboot's ACPI tables:
+//! the tables CrabEFI added lie in memory the next payload considers free.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use heapless::Vec;
+use r_efi::efi;
+
+use crate::compression::{self, DecompressError, Format};
+use crate::coreboot::cbfs;
+use crate::efi::allocator::{self, AllocateType, MemoryType, PAGE_SIZE};
+use crate::menu::{BootEntry, BootMenu};
+use crate::state;
+
+/// Prefix of the CBFS files holding payloads
+pub const CBFS_PREFIX: &str = "img/";
+
+/// Size of a SELF segment header
+const SEGMENT_SIZE: usize = 28;
+
+/// SELF segment types
+const SEGMENT_CODE: u32 = u32::from_be_bytes(*b"CODE");
+const SEGMENT_DATA: u32 = u32::from_be_bytes(*b"DATA");
+const SEGMENT_BSS: u32 = u32::from_be_bytes(*b"BSS ");
+const SEGMENT_PARAMS: u32 = u32::from_be_bytes(*b"PARA");
+const SEGMENT_ENTRY: u32 = u32::from_be_bytes(*b"ENTR");
+
+/// SELF segment compression
+const COMPRESSION_NONE: u32 = 0;
+const COMPRESSION_LZMA: u32 = 1;
+const COMPRESSION_LZ4: u32 = 2;
+
+/// Most segments of a payload
+const MAX_SEGMENTS: usize = 16;
+
+/// End of the legacy area payloads may be loaded to regardless of the
+/// memory map
+const LEGACY_END: u64 = 0x10_0000;
+
+/// Payloads run in 32-bit mode without paging
+const ADDRESS_LIMIT: u64 = 1 << 32;
+
+/// Address of the coreboot table handed to payloads
+static COREBOOT_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// Why a payload can't be started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadError {
+    /// Not in CBFS
+    NotFound,
+    /// Not a SELF, or a segment lies outside the file
+    Malformed,
+    /// A segment uses an unknown compression
+    Compression(u32),
+    /// A segment lies above 4 GiB
+    TooHigh(u64),
+    /// A segment would overwrite memory in use
+    InUse(u64),
+    /// A segment failed to decompress
+    Decompress(DecompressError),
+}
+
+/// A segment to load
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Segment<'a> {
+    /// Contents, empty for BSS
+    data: &'a [u8],
+    compression: Option<Format>,
+    load_address: u64,
+    /// Size in memory, the part past the contents is zeroed
+    memory_size: u64,
+}
+
+/// A parsed SELF
+#[derive(Debug, PartialEq, Eq)]
+struct Payload<'a> {
+    segments: Vec<Segment<'a>, MAX_SEGMENTS>,
+    entry: u64,
+}
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+impl<'a> Payload<'a> {
+    /// Parse the segment table of a SELF
+    fn parse(file: &'a [u8]) -> Result<Self, PayloadError> {
+        let mut segments = Vec::new();
+        for header in file.chunks_exact(SEGMENT_SIZE) {
+            let field = |offset| be32(header, offset).ok_or(PayloadError::Malformed);
+            let segment_type = field(0)?;
+            let load_address = u64::from(field(12)?) << 32 | u64::from(field(16)?);
+            let (offset, len) = (field(8)? as usize, field(20)? as usize);
+            let memory_size = u64::from(field(24)?);
+
+            let (data, compression) = match segment_type {
+                SEGMENT_ENTRY => {
+                    return Ok(Payload {
+                        segments,
+                        entry: load_address,
+                    });
+                }
+                SEGMENT_PARAMS => continue,
+                SEGMENT_BSS => (&[][..], None),
+                SEGMENT_CODE | SEGMENT_DATA => {
+                    let data = offset
+                        .checked_add(len)
+                        .and_then(|end| file.get(offset..end))
+                        .ok_or(PayloadError::Malformed)?;
+                    let compression = match field(4)? {
+                        COMPRESSION_NONE if len as u64 <= memory_size => None,
+                        COMPRESSION_NONE => return Err(PayloadError::Malformed),
+                        COMPRESSION_LZMA => Some(Format::Lzma),
+                        COMPRESSION_LZ4 => Some(Format::Lz4),
+                        other => return Err(PayloadError::Compression(other)),
+                    };
+                    (data, compression)
+                }
+                _ => return Err(PayloadError::Malformed),
+            };
+            match load_address.checked_add(memory_size) {
+                Some(end) if end <= ADDRESS_LIMIT => {}
+                _ => return Err(PayloadError::TooHigh(load_address)),
+            }
+            segments
+                .push(Segment {
+                    data,
+                    compression,
+                    load_address,
+                    memory_size,
+                })
+                .map_err(|_| PayloadError::Malformed)?;
+        }
+        Err(PayloadError::Malformed)
+    }
+
+    /// Pages the segments cover, merged where they share pages
+    fn page_ranges(&self) -> Vec<(u64, u64), MAX_SEGMENTS> {
+        let mut ranges: Vec<(u64, u64), MAX_SEGMENTS> = self
+            .segments
+            .iter()
+            .filter(|segment| segment.memory_size > 0)
+            .map(|segment| {
+                let start = segment.load_address & !(PAGE_SIZE - 1);
+                let end = (segment.load_address + segment.memory_size).next_multiple_of(PAGE_SIZE);
+                (start, end)
+            })
+            .collect();
+        ranges.sort_unstable();
+        let mut merged: Vec<(u64, u64), MAX_SEGMENTS> = Vec::new();
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start < last.1 => last.1 = last.1.max(end),
+                // Can't overflow: there are no more ranges than segments
+                _ => {
+                    let _ = merged.push((start, end));
+                }
+            }
+        }
+        merged
+    }
+}
+
+/// Reserve the pages from `start` to `end` for the payload
+///
+/// Free memory is allocated. Below 1 MiB, memory reserved in the memory map
+/// may be used as well, but nothing CrabEFI allocated. Returns whether the
+/// pages were allocated.
+fn claim(start: u64, end: u64) -> Result<bool, PayloadError> {
+    let mut address = start;
+    let status = allocator::allocate_pages(
+        AllocateType::AllocateAddress,
+        MemoryType::LoaderCode,
+        (end - start) / PAGE_SIZE,
+        &mut address,
+    );
+    if status == efi::Status::SUCCESS {
+        return Ok(true);
+    }
+    let legacy = end <= LEGACY_END
+        && state::allocator()
+            .entries()
+            .iter()
+            .filter(|entry| entry.physical_start < end && entry.end() > start)
+            .all(|entry| {
+                matches!(
+                    MemoryType::from_u32(entry.memory_type),
+                    Some(MemoryType::ConventionalMemory | MemoryType::ReservedMemoryType)
+                )
+            });
+    if legacy {
+        Ok(false)
+    } else {
+        Err(PayloadError::InUse(start))
+    }
+}
+
+/// Copy or decompress a segment to its load address
+///
+/// # Safety
+///
+/// The segment's memory must be claimed for the payload.
+unsafe fn load(segment: &Segment<'_>) -> Result<(), PayloadError> {
+    let memory = unsafe {
+        core::slice::from_raw_parts_mut(
+            segment.load_address as *mut u8,
+            segment.memory_size as usize,
+        )
+    };
+    let written = match segment.compression {
+        Some(format) => compression::decompress(format, segment.data, memory)
+            .map_err(PayloadError::Decompress)?,
+        None => {
+            memory[..segment.data.len()].copy_from_slice(segment.data);
+            segment.data.len()
+        }
+    };
+    memory[written..].fill(0);
+    Ok(())
+}
+
+/// Remember the coreboot table to hand to payloads
+pub fn init(table_header: Option<u64>) {
+    COREBOOT_TABLE.store(table_header.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Add a boot menu entry for each payload in CBFS
+pub fn add_entries(menu: &mut BootMenu) {
+    for (name, data) in cbfs::find_files(CBFS_PREFIX) {
+        if let Err(e) = Payload::parse(data) {
+            log::debug!("Payload: ignoring {}: {:?}", name, e);
+            continue;
+        }
+        log::info!("Payload: found {}", name);
+        let entry = BootEntry::payload(&name[CBFS_PREFIX.len()..], name);
+        if !menu.add_entry(entry) {
+            log::warn!("Payload: boot menu is full");
+            return;
+        }
+    }
+}
+
+/// Claim the memory of a payload and load its segments
+///
+/// On failure, the memory allocated for it is freed again.
+fn load_payload(payload: &Payload<'_>) -> Result<(), PayloadError> {
+    let mut allocated: Vec<(u64, u64), MAX_SEGMENTS> = Vec::new();
+    let result = payload
+        .page_ranges()
+        .into_iter()
+        .try_for_each(|(start, end)| {
+            if claim(start, end)? {
+                let _ = allocated.push((start, end));
+            }
+            Ok(())
+        });
+    let result = result.and_then(|()| {
+        payload.segments.iter().try_for_each(|segment| {
+            log::debug!(
+                "Payload: {:#x}-{:#x} from {} bytes",
+                segment.load_address,
+                segment.load_address + segment.memory_size,
+                segment.data.len()
+            );
+            unsafe { load(segment) }
+        })
+    });
+    if result.is_err() {
+        for (start, end) in allocated {
+            let _ = allocator::free_pages(start, (end - start) / PAGE_SIZE);
+        }
+    }
+    result
+}
+
+/// Load the payload in CBFS file `name` and start it
+///
+/// Only returns if the payload can't be loaded, with the firmware still
+/// usable.
+pub fn boot(name: &str) -> PayloadError {
+    let Some(file) = cbfs::find_file(name) else {
+        return PayloadError::NotFound;
+    };
+    let payload = match Payload::parse(file) {
+        Ok(payload) => payload,
+        Err(e) => return e,
+    };
+    if let Err(e) = load_payload(&payload) {
+        return e;
+    }
+
+    log::info!("Payload: starting {} at {:#x}", name, payload.entry);
+    crate::boot_log::record_outcome(crate::boot_log::Outcome::Booted);
+    crate::fb_shadow::disable();
+    crate::efi::boot_services::prepare_handoff();
+    unsafe {
+        crate::acpi::restore_rsdp();
+        crate::arch::x86_64::payload::jump_to_payload(
+            payload.entry as u32,
+            COREBOOT_TABLE.load(Ordering::Relaxed) as u32,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(
+        kind: &[u8; 4],
+        compression: u32,
+        offset: u32,
+        address: u32,
+        len: u32,
+        size: u32,
+    ) -> [u8; SEGMENT_SIZE] {
+        let mut header = [0u8; SEGMENT_SIZE];
+        header[0..4].copy_from_slice(kind);
+        header[4..8].copy_from_slice(&compression.to_be_bytes());
+        header[8..12].copy_from_slice(&offset.to_be_bytes());
+        header[16..20].copy_from_slice(&address.to_be_bytes());
+        header[20..24].copy_from_slice(&len.to_be_bytes());
+        header[24..28].copy_from_slice(&size.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn parses_self() {
+        let mut file = std::vec::Vec::new();
+        file.extend(segment(b"CODE", 0, 84, 0xe_0000, 4, 0x1000));
+        file.extend(segment(b"BSS ", 0, 0, 0xe_0800, 0, 0x1000));
+        file.extend(segment(b"ENTR", 0, 0, 0xe_0010, 0, 0));
+        file.extend([0x90; 4]);
+
+        let payload = Payload::parse(&file).unwrap();
+        assert_eq!(payload.entry, 0xe_0010);
+        assert_eq!(payload.segments.len(), 2);
+        assert_eq!(payload.segments[0].data, [0x90; 4]);
+        assert_eq!(payload.segments[1].data, []);
+        // Both segments use the page at 0xe0000
+        assert_eq!(payload.page_ranges(), [(0xe_0000, 0xe_2000)]);
+        drop(payload);
+
+        // No entry point
+        assert_eq!(Payload::parse(&file[..56]), Err(PayloadError::Malformed));
+        file[4..8].copy_from_slice(&3u32.to_be_bytes());
+        assert_eq!(Payload::parse(&file), Err(PayloadError::Compression(3)));
+        file[4..8].copy_from_slice(&0u32.to_be_bytes());
+        file[12..16].copy_from_slice(&1u32.to_be_bytes());
+        assert_eq!(
+            Payload::parse(&file),
+            Err(PayloadError::TooHigh(0x1_000e_0000))
+        );
+    }
+}
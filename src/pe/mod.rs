@@ -3,6 +3,10 @@
 //! This module provides a loader for PE32+ executables (EFI applications).
 //! It supports loading, relocating, and executing UEFI applications.
 //!
+//! 32-bit (IA-32) images are recognized but not run: mixed mode would need
+//! 32-bit copies of the system table and the services, and thunks into
+//! compatibility mode. The boot menu marks entries with such a bootloader.
+//!
 //! # Security
 //!
 //! This loader validates all bounds before accessing untrusted PE data to prevent:
@@ -10,7 +14,11 @@
 //! - Arbitrary memory writes via crafted relocations
 //! - Integer overflows in size calculations
 
-use crate::efi::allocator::{self, AllocateType, MemoryType, PAGE_SIZE};
+use core::ops::Range;
+
+use crate::efi::allocator::PAGE_SIZE;
+#[cfg(not(feature = "std"))]
+use crate::efi::allocator::{self, AllocateType, MemoryType};
 use r_efi::efi::{Handle, Status, SystemTable};
 use zerocopy::{FromBytes, Immutable, KnownLayout, Unaligned};
 
@@ -26,6 +34,9 @@ const PE32_PLUS_MAGIC: u16 = 0x020B;
 /// Machine type: AMD64
 const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
 
+/// Machine type: IA-32
+pub const IMAGE_FILE_MACHINE_I386: u16 = 0x014C;
+
 /// Relocation types
 const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
 const IMAGE_REL_BASED_DIR64: u16 = 10;
@@ -33,6 +44,16 @@ const IMAGE_REL_BASED_DIR64: u16 = 10;
 /// Data directory index for base relocations
 const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
 
+/// DLL characteristics: the image runs with W^X section mappings
+#[cfg(not(feature = "std"))]
+const IMAGE_DLLCHARACTERISTICS_NX_COMPAT: u16 = 0x0100;
+
+/// Section characteristics: executable and writable
+#[cfg(not(feature = "std"))]
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+#[cfg(not(feature = "std"))]
+const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
 /// Size of base relocation block header
 const BASE_RELOCATION_HEADER_SIZE: usize = 8;
 
@@ -165,8 +186,15 @@ pub struct LoadedImage {
     pub entry_point: u64,
     /// Number of pages allocated
     pub num_pages: u64,
+    /// Subsystem from the optional header, e.g. [`SUBSYSTEM_EFI_APPLICATION`]
+    pub subsystem: u16,
 }
 
+/// Image subsystems of EFI images
+pub const SUBSYSTEM_EFI_APPLICATION: u16 = 10;
+pub const SUBSYSTEM_EFI_BOOT_SERVICE_DRIVER: u16 = 11;
+pub const SUBSYSTEM_EFI_RUNTIME_DRIVER: u16 = 12;
+
 /// Load a PE32+ image from memory
 ///
 /// # Arguments
@@ -209,9 +237,13 @@ pub fn load_image(data: &[u8]) -> Result<LoadedImage, Status> {
         return Err(Status::INVALID_PARAMETER);
     }
 
-    // Check PE signature
-    // Safety: We verified pe_offset + 4 <= data.len()
-    let pe_sig = unsafe { *(data.as_ptr().add(pe_offset) as *const u32) };
+    // Check PE signature (e_lfanew need not be aligned)
+    let pe_sig = u32::from_le_bytes([
+        data[pe_offset],
+        data[pe_offset + 1],
+        data[pe_offset + 2],
+        data[pe_offset + 3],
+    ]);
     if pe_sig != PE_SIGNATURE {
         log::error!("PE: Invalid PE signature: {:#x}", pe_sig);
         return Err(Status::INVALID_PARAMETER);
@@ -235,6 +267,10 @@ pub fn load_image(data: &[u8]) -> Result<LoadedImage, Status> {
     let num_sections = coff_header.number_of_sections;
     let opt_header_size = coff_header.size_of_optional_header;
 
+    if machine == IMAGE_FILE_MACHINE_I386 {
+        log::error!("PE: 32-bit (IA-32) image, mixed mode is not supported");
+        return Err(Status::UNSUPPORTED);
+    }
     if machine != IMAGE_FILE_MACHINE_AMD64 {
         log::error!("PE: Unsupported machine type: {:#x}", machine);
         return Err(Status::UNSUPPORTED);
@@ -272,6 +308,7 @@ pub fn load_image(data: &[u8]) -> Result<LoadedImage, Status> {
     let entry_point_rva = opt_header.address_of_entry_point;
     let size_of_headers = opt_header.size_of_headers;
     let num_data_dirs = opt_header.number_of_rva_and_sizes;
+    let subsystem = opt_header.subsystem;
 
     if magic != PE32_PLUS_MAGIC {
         log::error!("PE: Not a PE32+ image: {:#x}", magic);
@@ -333,12 +370,7 @@ pub fn load_image(data: &[u8]) -> Result<LoadedImage, Status> {
     let num_pages = (image_size as u64).div_ceil(PAGE_SIZE);
     let mut load_addr = 0u64;
 
-    let status = allocator::allocate_pages(
-        AllocateType::AllocateAnyPages,
-        MemoryType::LoaderCode,
-        num_pages,
-        &mut load_addr,
-    );
+    let status = allocate_image_pages(num_pages, &mut load_addr);
 
     if status != Status::SUCCESS {
         log::error!("PE: Failed to allocate memory: {:?}", status);
@@ -415,7 +447,7 @@ pub fn load_image(data: &[u8]) -> Result<LoadedImage, Status> {
                 image_size
             );
             // Free allocated memory and return error
-            let _ = allocator::free_pages(load_addr, num_pages);
+            let _ = free_image_pages(load_addr, num_pages);
             return Err(Status::INVALID_PARAMETER);
         }
 
@@ -428,7 +460,7 @@ pub fn load_image(data: &[u8]) -> Result<LoadedImage, Status> {
     }
 
     // Apply relocations if we loaded at a different address
-    let delta = load_addr as i64 - image_base_preferred as i64;
+    let delta = (load_addr as i64).wrapping_sub(image_base_preferred as i64);
     if delta != 0 {
         // Validate data directories fit within optional header
         let data_dirs_offset = opt_offset
@@ -443,7 +475,7 @@ pub fn load_image(data: &[u8]) -> Result<LoadedImage, Status> {
 
         if data_dirs_end > data.len() {
             log::error!("PE: Data directories extend beyond file");
-            let _ = allocator::free_pages(load_addr, num_pages);
+            let _ = free_image_pages(load_addr, num_pages);
             return Err(Status::INVALID_PARAMETER);
         }
 
@@ -458,7 +490,7 @@ pub fn load_image(data: &[u8]) -> Result<LoadedImage, Status> {
                 match DataDirectory::ref_from_prefix(&data_dirs_data[reloc_dir_offset..]) {
                     Ok((d, _)) => d,
                     Err(_) => {
-                        let _ = allocator::free_pages(load_addr, num_pages);
+                        let _ = free_image_pages(load_addr, num_pages);
                         return Err(Status::INVALID_PARAMETER);
                     }
                 };
@@ -471,12 +503,17 @@ pub fn load_image(data: &[u8]) -> Result<LoadedImage, Status> {
                     apply_relocations(load_addr, image_size, reloc_rva, reloc_size, delta)
             {
                 log::error!("PE: Failed to apply relocations");
-                let _ = allocator::free_pages(load_addr, num_pages);
+                let _ = free_image_pages(load_addr, num_pages);
                 return Err(e);
             }
         }
     }
 
+    #[cfg(not(feature = "std"))]
+    if opt_header.dll_characteristics & IMAGE_DLLCHARACTERISTICS_NX_COMPAT != 0 {
+        protect_sections(load_addr, image_size, opt_header.section_alignment, data);
+    }
+
     let entry_point = load_addr + entry_point_rva as u64;
 
     log::info!(
@@ -490,9 +527,46 @@ pub fn load_image(data: &[u8]) -> Result<LoadedImage, Status> {
         image_size: image_size as u64,
         entry_point,
         num_pages,
+        subsystem,
     })
 }
 
+/// Map the sections of a loaded NX-compatible image W^X
+///
+/// Code sections become read-only and executable, the headers and the
+/// other sections non-executable. Images with sections smaller than a page
+/// keep the writable and executable mapping of their allocation.
+#[cfg(not(feature = "std"))]
+fn protect_sections(load_addr: u64, image_size: u32, section_alignment: u32, headers: &[u8]) {
+    use crate::arch::x86_64::paging::flags;
+    use crate::arch::x86_64::protect;
+
+    let Some(sections) = section_headers(headers) else {
+        return;
+    };
+    if (section_alignment as u64) < PAGE_SIZE {
+        log::debug!(
+            "PE: sections aligned to {:#x}, not protected",
+            section_alignment
+        );
+        return;
+    }
+    let image = load_addr..load_addr + (image_size as u64).next_multiple_of(PAGE_SIZE);
+    let mut done = protect::set_flags(image.clone(), flags::NO_EXECUTE, 0);
+    for section in sections.filter(|section| section.characteristics & IMAGE_SCN_MEM_EXECUTE != 0) {
+        let start = load_addr + section.virtual_address as u64;
+        let end = (start + section.virtual_size as u64).next_multiple_of(PAGE_SIZE);
+        let clear = match section.characteristics & IMAGE_SCN_MEM_WRITE {
+            0 => flags::NO_EXECUTE | flags::WRITABLE,
+            _ => flags::NO_EXECUTE,
+        };
+        done &= protect::set_flags(start..end.min(image.end), 0, clear);
+    }
+    if !done {
+        log::warn!("PE: couldn't protect the image at {:#x}", load_addr);
+    }
+}
+
 /// Apply base relocations with full bounds validation
 ///
 /// # Arguments
@@ -570,18 +644,15 @@ fn apply_relocations(
             return Err(Status::INVALID_PARAMETER);
         }
 
-        let num_entries = (block_size as usize - BASE_RELOCATION_HEADER_SIZE) / 2;
-
-        // Safety: We verified the block fits within the relocation directory
+        // We verified the block fits within the relocation directory; entries
+        // are read bytewise since a malformed block size can misalign them
         let entries_start = offset as usize + BASE_RELOCATION_HEADER_SIZE;
-        let entries = unsafe {
-            core::slice::from_raw_parts(
-                reloc_slice[entries_start..].as_ptr() as *const u16,
-                num_entries,
-            )
-        };
+        let entries_end = offset as usize + block_size as usize;
+        let entries = reloc_slice[entries_start..entries_end]
+            .chunks_exact(2)
+            .map(|entry| u16::from_le_bytes([entry[0], entry[1]]));
 
-        for &entry in entries {
+        for entry in entries {
             let reloc_type = entry >> 12;
             let reloc_offset = (entry & 0x0FFF) as u32;
 
@@ -616,7 +687,7 @@ fn apply_relocations(
                     unsafe {
                         let ptr = addr as *mut u64;
                         let value = ptr.read_unaligned();
-                        ptr.write_unaligned((value as i64 + delta) as u64);
+                        ptr.write_unaligned((value as i64).wrapping_add(delta) as u64);
                     }
                 }
                 _ => {
@@ -631,6 +702,86 @@ fn apply_relocations(
     Ok(())
 }
 
+/// The section headers of a PE image
+///
+/// `headers` are the PE headers, at the start of either the file or the
+/// loaded image.
+fn section_headers(headers: &[u8]) -> Option<impl Iterator<Item = &SectionHeader>> {
+    let (dos_header, _) = DosHeader::ref_from_prefix(headers).ok()?;
+    if dos_header.e_magic != DOS_MAGIC {
+        return None;
+    }
+
+    let pe_offset = dos_header.e_lfanew as usize;
+    let pe_sig = headers.get(pe_offset..pe_offset.checked_add(4)?)?;
+    if u32::from_le_bytes(pe_sig.try_into().ok()?) != PE_SIGNATURE {
+        return None;
+    }
+
+    let coff_offset = pe_offset + 4;
+    let (coff_header, _) = CoffHeader::ref_from_prefix(headers.get(coff_offset..)?).ok()?;
+    let offset = coff_offset
+        + core::mem::size_of::<CoffHeader>()
+        + coff_header.size_of_optional_header as usize;
+    let count = coff_header.number_of_sections.min(MAX_SECTIONS) as usize;
+
+    Some((0..count).map_while(move |i| {
+        let at = offset + i * core::mem::size_of::<SectionHeader>();
+        let (section, _) = SectionHeader::ref_from_prefix(headers.get(at..)?).ok()?;
+        Some(section)
+    }))
+}
+
+/// Machine type of a PE image, from the start of the file
+pub fn machine(data: &[u8]) -> Option<u16> {
+    let (dos_header, _) = DosHeader::ref_from_prefix(data).ok()?;
+    if dos_header.e_magic != DOS_MAGIC {
+        return None;
+    }
+    let pe_offset = dos_header.e_lfanew as usize;
+    let header = data.get(pe_offset..pe_offset.checked_add(6)?)?;
+    if header[..4] != PE_SIGNATURE.to_le_bytes() {
+        return None;
+    }
+    Some(u16::from_le_bytes([header[4], header[5]]))
+}
+
+/// Find the section of a loaded image that contains an RVA
+///
+/// `headers` are the PE headers as copied to the start of the loaded image.
+/// Returns the raw (null-padded) 8-byte section name.
+pub fn section_name(headers: &[u8], rva: u32) -> Option<[u8; 8]> {
+    section_headers(headers)?
+        .find(|section| {
+            let start = section.virtual_address;
+            let size = section.virtual_size.max(section.size_of_raw_data);
+            rva >= start && rva - start < size
+        })
+        .map(|section| section.name)
+}
+
+/// Find where the data of a named section is in a PE file
+///
+/// `headers` are the PE headers at the start of the file. The range covers
+/// the section's virtual size, without the padding to the file alignment,
+/// but never more than its raw data.
+pub fn file_section_range(headers: &[u8], name: &str) -> Option<Range<usize>> {
+    let section = section_headers(headers)?
+        .find(|section| section.name.split(|&b| b == 0).next() == Some(name.as_bytes()))?;
+    let raw_size = section.size_of_raw_data;
+    let size = match section.virtual_size {
+        0 => raw_size,
+        virtual_size => virtual_size.min(raw_size),
+    };
+    let start = section.pointer_to_raw_data as usize;
+    Some(start..start + size as usize)
+}
+
+/// Get the data of a named section of a PE file
+pub fn file_section<'a>(data: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    data.get(file_section_range(data, name)?)
+}
+
 /// Execute a loaded PE image
 ///
 /// # Arguments
@@ -660,10 +811,310 @@ pub fn execute_image(
 
 /// Unload a PE image and free its memory
 pub fn unload_image(image: &LoadedImage) -> Status {
-    allocator::free_pages(image.image_base, image.num_pages)
+    free_image_pages(image.image_base, image.num_pages)
+}
+
+/// Allocate pages for an image from the firmware memory map
+#[cfg(not(feature = "std"))]
+fn allocate_image_pages(num_pages: u64, load_addr: &mut u64) -> Status {
+    allocator::allocate_pages(
+        AllocateType::AllocateAnyPages,
+        MemoryType::LoaderCode,
+        num_pages,
+        load_addr,
+    )
+}
+
+/// Free pages allocated by [`allocate_image_pages`]
+#[cfg(not(feature = "std"))]
+fn free_image_pages(load_addr: u64, num_pages: u64) -> Status {
+    allocator::free_pages(load_addr, num_pages)
+}
+
+/// Layout of an image allocation on the host heap
+#[cfg(feature = "std")]
+fn image_layout(num_pages: u64) -> Option<std::alloc::Layout> {
+    let size = usize::try_from(num_pages.checked_mul(PAGE_SIZE)?).ok()?;
+    std::alloc::Layout::from_size_align(size, PAGE_SIZE as usize).ok()
+}
+
+/// Allocate pages for an image from the host heap
+///
+/// Host builds have no firmware memory map; images are loaded into page-aligned
+/// heap memory instead, which also exercises the relocation path.
+#[cfg(feature = "std")]
+fn allocate_image_pages(num_pages: u64, load_addr: &mut u64) -> Status {
+    let Some(layout) = image_layout(num_pages).filter(|layout| layout.size() != 0) else {
+        return Status::INVALID_PARAMETER;
+    };
+
+    // Safety: the layout has a non-zero size
+    let ptr = unsafe { std::alloc::alloc(layout) };
+    if ptr.is_null() {
+        return Status::OUT_OF_RESOURCES;
+    }
+
+    *load_addr = ptr as u64;
+    Status::SUCCESS
+}
+
+/// Free pages allocated by [`allocate_image_pages`]
+#[cfg(feature = "std")]
+fn free_image_pages(load_addr: u64, num_pages: u64) -> Status {
+    let Some(layout) = image_layout(num_pages) else {
+        return Status::INVALID_PARAMETER;
+    };
+
+    // Safety: the pages were allocated by allocate_image_pages with this layout
+    unsafe { std::alloc::dealloc(load_addr as *mut u8, layout) };
+    Status::SUCCESS
 }
 
 #[cfg(test)]
 mod tests {
-    // Tests would go here
+    use super::*;
+    use crate::testing::{put_u16, put_u32, put_u64};
+
+    /// Preferred load address of the test image
+    const IMAGE_BASE: u64 = 0x1_4000_0000;
+
+    /// Offset of the PE signature
+    const PE_OFFSET: usize = 0x40;
+
+    /// Offset of the optional header
+    const OPT_OFFSET: usize = PE_OFFSET + 4 + 20;
+
+    /// Offset of the section table (optional header with 16 data directories)
+    const SECTIONS_OFFSET: usize = OPT_OFFSET + 112 + 16 * 8;
+
+    /// RVA of the pointer that gets relocated
+    const POINTER_RVA: u32 = 0x1010;
+
+    /// Minimal PE32+ EFI application, laid out like a linker would
+    ///
+    /// - headers in the first 0x200 bytes
+    /// - `.text` at RVA 0x1000 (one 0x200 byte raw block plus 0x100 bytes of bss),
+    ///   holding a pointer to RVA 0x1008 at [`POINTER_RVA`]
+    /// - `.reloc` at RVA 0x2000 with a DIR64 fixup for that pointer
+    fn pe_image() -> std::vec::Vec<u8> {
+        let mut image = std::vec![0u8; 0x600];
+
+        // DOS header and PE signature
+        image[0..2].copy_from_slice(b"MZ");
+        put_u32(&mut image, 0x3C, PE_OFFSET as u32);
+        image[PE_OFFSET..PE_OFFSET + 4].copy_from_slice(b"PE\0\0");
+
+        // COFF header
+        let coff = PE_OFFSET + 4;
+        put_u16(&mut image, coff, IMAGE_FILE_MACHINE_AMD64);
+        put_u16(&mut image, coff + 2, 2);
+        put_u16(&mut image, coff + 16, (SECTIONS_OFFSET - OPT_OFFSET) as u16);
+        put_u16(&mut image, coff + 18, 0x22);
+
+        // Optional header
+        put_u16(&mut image, OPT_OFFSET, PE32_PLUS_MAGIC);
+        put_u32(&mut image, OPT_OFFSET + 16, 0x1000); // entry point
+        put_u32(&mut image, OPT_OFFSET + 20, 0x1000); // base of code
+        put_u64(&mut image, OPT_OFFSET + 24, IMAGE_BASE);
+        put_u32(&mut image, OPT_OFFSET + 32, 0x1000); // section alignment
+        put_u32(&mut image, OPT_OFFSET + 36, 0x200); // file alignment
+        put_u32(&mut image, OPT_OFFSET + 56, 0x3000); // size of image
+        put_u32(&mut image, OPT_OFFSET + 60, 0x200); // size of headers
+        put_u16(&mut image, OPT_OFFSET + 68, 10); // EFI application
+        put_u32(&mut image, OPT_OFFSET + 108, 16);
+        let reloc_dir = OPT_OFFSET + 112 + IMAGE_DIRECTORY_ENTRY_BASERELOC * 8;
+        put_u32(&mut image, reloc_dir, 0x2000);
+        put_u32(&mut image, reloc_dir + 4, 12);
+
+        // Section table: name, virtual size, RVA, raw size, raw offset, characteristics
+        let sections = [
+            (*b".text\0\0\0", 0x300, 0x1000, 0x200, 0x200, 0x6000_0020u32),
+            (*b".reloc\0\0", 12, 0x2000, 0x200, 0x400, 0x4200_0040),
+        ];
+        for (i, (name, virtual_size, rva, raw_size, raw_offset, flags)) in
+            sections.into_iter().enumerate()
+        {
+            let header = SECTIONS_OFFSET + i * 40;
+            image[header..header + 8].copy_from_slice(&name);
+            put_u32(&mut image, header + 8, virtual_size);
+            put_u32(&mut image, header + 12, rva);
+            put_u32(&mut image, header + 16, raw_size);
+            put_u32(&mut image, header + 20, raw_offset);
+            put_u32(&mut image, header + 36, flags);
+        }
+
+        // .text: "ret" at the entry point and the pointer to relocate
+        image[0x200] = 0xC3;
+        put_u64(&mut image, 0x210, IMAGE_BASE + 0x1008);
+
+        // .reloc: one block for page 0x1000 with a DIR64 entry and padding
+        put_u32(&mut image, 0x400, 0x1000);
+        put_u32(&mut image, 0x404, 12);
+        put_u16(&mut image, 0x408, (IMAGE_REL_BASED_DIR64 << 12) | 0x010);
+        put_u16(&mut image, 0x40A, IMAGE_REL_BASED_ABSOLUTE << 12);
+
+        image
+    }
+
+    /// Change made to a valid image
+    type Corruption = fn(&mut std::vec::Vec<u8>);
+
+    /// The loaded image as a byte slice
+    fn loaded_bytes(image: &LoadedImage) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(image.image_base as *const u8, image.image_size as usize)
+        }
+    }
+
+    #[test]
+    fn loads_and_relocates() {
+        let image = load_image(&pe_image()).unwrap();
+        let bytes = loaded_bytes(&image);
+
+        assert_eq!(image.image_base % PAGE_SIZE, 0);
+        assert_eq!(image.image_size, 0x3000);
+        assert_eq!(image.num_pages, 3);
+        assert_eq!(image.entry_point, image.image_base + 0x1000);
+
+        // Headers and sections are copied, the rest of .text is zeroed
+        assert_eq!(&bytes[..2], b"MZ");
+        assert_eq!(bytes[0x1000], 0xC3);
+        assert!(bytes[0x1200..0x1300].iter().all(|&b| b == 0));
+
+        // The heap never hands out the preferred base, so the pointer was fixed up
+        let at = POINTER_RVA as usize;
+        let pointer = u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        assert_eq!(pointer, image.image_base + 0x1008);
+
+        assert_eq!(unload_image(&image), Status::SUCCESS);
+    }
+
+    #[test]
+    fn section_lookup() {
+        let image = load_image(&pe_image()).unwrap();
+        let headers = &loaded_bytes(&image)[..0x200];
+
+        assert_eq!(section_name(headers, 0x1000), Some(*b".text\0\0\0"));
+        // The virtual size counts, including the bss past the raw data
+        assert_eq!(section_name(headers, 0x12FF), Some(*b".text\0\0\0"));
+        assert_eq!(section_name(headers, 0x2004), Some(*b".reloc\0\0"));
+        assert_eq!(section_name(headers, 0x500), None);
+        assert_eq!(section_name(&headers[..0x40], 0x1000), None);
+
+        unload_image(&image);
+    }
+
+    #[test]
+    fn file_section_lookup() {
+        let mut image = pe_image();
+
+        // The virtual size, not the raw data padded to the file alignment
+        assert_eq!(file_section_range(&image, ".reloc"), Some(0x400..0x40C));
+        assert_eq!(
+            &file_section(&image, ".reloc").unwrap()[..4],
+            &0x1000u32.to_le_bytes()
+        );
+        // ... but the bss past the raw data isn't in the file
+        assert_eq!(file_section_range(&image, ".text"), Some(0x200..0x400));
+        assert_eq!(file_section(&image, ".tex"), None);
+        assert_eq!(file_section(&image, ".linux"), None);
+
+        // Raw data past the end of the file
+        image.truncate(0x408);
+        assert_eq!(file_section(&image, ".reloc"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        let cases: [(&str, Corruption, Status); 8] = [
+            (
+                "DOS magic",
+                |image| image[0] = b'X',
+                Status::INVALID_PARAMETER,
+            ),
+            (
+                "PE offset",
+                |image| put_u32(image, 0x3C, 0x10_0000),
+                Status::INVALID_PARAMETER,
+            ),
+            (
+                "PE signature",
+                |image| image[PE_OFFSET] = b'X',
+                Status::INVALID_PARAMETER,
+            ),
+            (
+                "machine",
+                |image| put_u16(image, PE_OFFSET + 4, 0x14C),
+                Status::UNSUPPORTED,
+            ),
+            (
+                "PE32",
+                |image| put_u16(image, OPT_OFFSET, 0x10B),
+                Status::UNSUPPORTED,
+            ),
+            (
+                "entry point",
+                |image| put_u32(image, OPT_OFFSET + 16, 0x3000),
+                Status::INVALID_PARAMETER,
+            ),
+            (
+                "image size",
+                |image| put_u32(image, OPT_OFFSET + 56, 0),
+                Status::INVALID_PARAMETER,
+            ),
+            (
+                "section count",
+                |image| put_u16(image, PE_OFFSET + 6, 200),
+                Status::INVALID_PARAMETER,
+            ),
+        ];
+
+        for (what, corrupt, status) in cases {
+            let mut image = pe_image();
+            corrupt(&mut image);
+            assert_eq!(load_image(&image).err(), Some(status), "{}", what);
+        }
+
+        assert_eq!(
+            load_image(&pe_image()[..0x100]).err(),
+            Some(Status::INVALID_PARAMETER)
+        );
+        assert_eq!(load_image(&[]).err(), Some(Status::INVALID_PARAMETER));
+    }
+
+    #[test]
+    fn reads_machine() {
+        let mut image = pe_image();
+        assert_eq!(machine(&image), Some(IMAGE_FILE_MACHINE_AMD64));
+        put_u16(&mut image, PE_OFFSET + 4, IMAGE_FILE_MACHINE_I386);
+        assert_eq!(
+            machine(&image[..PE_OFFSET + 6]),
+            Some(IMAGE_FILE_MACHINE_I386)
+        );
+        assert_eq!(machine(&image[..PE_OFFSET + 5]), None);
+        image[0] = b'X';
+        assert_eq!(machine(&image), None);
+    }
+
+    #[test]
+    fn rejects_section_outside_image() {
+        let mut image = pe_image();
+        // Move .reloc so that its raw data ends past SizeOfImage
+        put_u32(&mut image, SECTIONS_OFFSET + 40 + 12, 0x2FF8);
+        assert_eq!(load_image(&image).err(), Some(Status::INVALID_PARAMETER));
+    }
+
+    #[test]
+    fn rejects_relocation_outside_image() {
+        let mut image = pe_image();
+        // Point the fixup block at the last page, so the target runs past the end
+        put_u32(&mut image, 0x400, 0x2000);
+        put_u16(&mut image, 0x408, (IMAGE_REL_BASED_DIR64 << 12) | 0xFFC);
+        assert_eq!(load_image(&image).err(), Some(Status::INVALID_PARAMETER));
+
+        let mut image = pe_image();
+        // Block larger than the relocation directory
+        put_u32(&mut image, 0x404, 0x100);
+        assert_eq!(load_image(&image).err(), Some(Status::INVALID_PARAMETER));
+    }
 }
@@ -0,0 +1,221 @@
+//! Platform quirks and system reset
+//!
+//! Some boards need a different reset sequence than the default: on older
+//! chipsets a write to the Reset Control register (0xCF9) can hang or only
+//! reset the CPU, while the keyboard controller pulse works, or the other
+//! way round. The quirk table below selects the reset strategy and the
+//! pre-reset quiesce behavior by the mainboard vendor and part number from
+//! the coreboot tables.
+//!
+//! Before resetting with boot services still active, DMA-capable devices are
+//! quiesced: USB controllers are stopped and bus mastering is turned off, so
+//! a warm reset doesn't leave DMA writing into memory the next boot uses.
+//! After ExitBootServices the OS owns the devices and they are left alone.
+//! The CMOS shutdown status byte is cleared as well, so the warm reset takes
+//! the normal POST path instead of a legacy resume vector.
+
+use crate::arch::x86_64::io;
+use crate::coreboot::MainboardInfo;
+use crate::drivers::{cmos, pci};
+use crate::state;
+use spin::Mutex;
+
+/// Reset Control register of the chipset
+const RESET_CONTROL_PORT: u16 = 0xCF9;
+
+/// Reset Control: system reset (as opposed to a CPU-only INIT)
+const RST_SYS: u8 = 0x02;
+
+/// Reset Control: start the reset
+const RST_CPU: u8 = 0x04;
+
+/// Reset Control: full reset, power cycling the platform
+const RST_FULL: u8 = 0x08;
+
+/// Keyboard controller status and command port
+const KBC_COMMAND_PORT: u16 = 0x64;
+
+/// Keyboard controller status: input buffer full
+const KBC_INPUT_FULL: u8 = 0x02;
+
+/// Keyboard controller command pulsing the reset line
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+/// CMOS shutdown status byte
+const CMOS_SHUTDOWN_STATUS: u8 = 0x0F;
+
+/// Time to wait for a reset method to take effect before trying the next
+const RESET_SETTLE_MS: u64 = 500;
+
+/// Kind of reset requested
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetKind {
+    /// Full platform reset
+    Cold,
+    /// CPU and chipset reset without power cycling
+    Warm,
+}
+
+/// Ways to reset the system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetMethod {
+    /// Write the chipset's Reset Control register
+    ResetControl,
+    /// Pulse the reset line through the keyboard controller
+    KeyboardController,
+    /// Load an empty IDT and raise an exception
+    TripleFault,
+}
+
+/// Platform-specific behavior
+#[derive(Debug)]
+pub struct Quirks {
+    /// Reset methods, tried in order
+    pub reset_methods: &'static [ResetMethod],
+    /// Stop DMA-capable devices before resetting
+    pub quiesce_before_reset: bool,
+}
+
+/// Behavior of boards without a quirk entry
+const DEFAULT_QUIRKS: Quirks = Quirks {
+    reset_methods: &[
+        ResetMethod::ResetControl,
+        ResetMethod::KeyboardController,
+        ResetMethod::TripleFault,
+    ],
+    quiesce_before_reset: true,
+};
+
+/// A quirk entry, matched against the coreboot mainboard record
+struct QuirkEntry {
+    vendor: &'static str,
+    part_number: &'static str,
+    quirks: Quirks,
+}
+
+/// Boards that need non-default behavior
+const QUIRK_TABLE: &[QuirkEntry] = &[QuirkEntry {
+    // QEMU's i440fx machine: keep the keyboard controller reset CrabEFI has
+    // always used there
+    vendor: "Emulation",
+    part_number: "QEMU x86 i440fx/piix4",
+    quirks: Quirks {
+        reset_methods: &[ResetMethod::KeyboardController, ResetMethod::TripleFault],
+        quiesce_before_reset: true,
+    },
+}];
+
+/// Quirks of the running board
+static QUIRKS: Mutex<&'static Quirks> = Mutex::new(&DEFAULT_QUIRKS);
+
+/// Look up the quirks of a mainboard
+fn lookup(mainboard: Option<&MainboardInfo>) -> &'static Quirks {
+    mainboard
+        .and_then(|board| {
+            QUIRK_TABLE.iter().find(|entry| {
+                entry.vendor == board.vendor.as_str()
+                    && entry.part_number == board.part_number.as_str()
+            })
+        })
+        .map_or(&DEFAULT_QUIRKS, |entry| &entry.quirks)
+}
+
+/// Select the quirks of the running board
+pub fn init(mainboard: Option<&MainboardInfo>) {
+    let quirks = lookup(mainboard);
+    if let Some(board) = mainboard {
+        log::info!("  Mainboard: {} {}", board.vendor, board.part_number);
+    }
+    log::debug!("Platform quirks: {:?}", quirks);
+    *QUIRKS.lock() = quirks;
+}
+
+/// Get the quirks of the running board
+pub fn quirks() -> &'static Quirks {
+    *QUIRKS.lock()
+}
+
+/// Reset the system
+///
+/// Tries the board's reset methods in order and halts if none works.
+pub fn reset(kind: ResetKind) -> ! {
+    let quirks = quirks();
+
+    let boot_services_active = !state::efi().allocator.boot_services_exited();
+    if quirks.quiesce_before_reset && boot_services_active {
+        quiesce_devices();
+    }
+
+    // A non-zero shutdown status makes legacy POST code jump to a resume
+    // vector instead of booting normally
+    cmos::write(CMOS_SHUTDOWN_STATUS, 0);
+
+    for &method in quirks.reset_methods {
+        log::debug!("Reset: trying {:?}", method);
+        match method {
+            ResetMethod::ResetControl => reset_control(kind),
+            ResetMethod::KeyboardController => keyboard_controller_reset(),
+            ResetMethod::TripleFault => triple_fault(),
+        }
+        crate::time::delay_ms(RESET_SETTLE_MS);
+    }
+
+    log::error!("Reset: no method worked, halting");
+    loop {
+        unsafe { core::arch::asm!("hlt") };
+    }
+}
+
+/// Stop devices that could keep doing DMA across a warm reset
+fn quiesce_devices() {
+    crate::drivers::usb::cleanup();
+
+    for device in pci::get_all_devices() {
+        if device.class_code == pci::CLASS_BRIDGE {
+            continue;
+        }
+        let command = pci::read_config_u16(device.address, 0x04);
+        // Clear bit 2 (bus master)
+        pci::write_config_u16(device.address, 0x04, command & !0x04);
+    }
+}
+
+/// Reset through the chipset's Reset Control register
+fn reset_control(kind: ResetKind) {
+    let reset = match kind {
+        ResetKind::Cold => RST_FULL | RST_SYS,
+        ResetKind::Warm => RST_SYS,
+    };
+    unsafe {
+        // The reset starts on the 0 -> 1 transition of RST_CPU
+        io::outb(RESET_CONTROL_PORT, reset);
+        io::outb(RESET_CONTROL_PORT, reset | RST_CPU);
+    }
+}
+
+/// Reset by pulsing the reset line through the keyboard controller
+fn keyboard_controller_reset() {
+    unsafe {
+        // Wait for keyboard controller to be ready
+        for _ in 0..1000 {
+            if io::inb(KBC_COMMAND_PORT) & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        io::outb(KBC_COMMAND_PORT, KBC_PULSE_RESET);
+    }
+}
+
+/// Reset by triple faulting the CPU
+fn triple_fault() {
+    unsafe {
+        // Load a null IDT and trigger an interrupt
+        let null_idt: [u8; 6] = [0; 6];
+        core::arch::asm!(
+            "lidt [{}]",
+            "int3",
+            in(reg) null_idt.as_ptr(),
+            options(noreturn)
+        );
+    }
+}
@@ -0,0 +1,917 @@
+//! Recovery Console
+//!
+//! Pressing `c` in the boot menu opens a small command prompt for finding
+//! out why nothing boots, e.g. during board bring-up. It runs on the serial
+//! console and the framebuffer at the same time.
+//!
+//! | Command                        | Action                               |
+//! |--------------------------------|--------------------------------------|
+//! | `help`                         | List the commands                    |
+//! | `lspci`                        | PCI devices and their BARs           |
+//! | `lsblk`                        | Disks and their GPT partitions       |
+//! | `ls <disk>p<n> [dir]`          | List a directory on a partition      |
+//! | `cat <disk>p<n> <file>`        | Print a file on a partition          |
+//! | `hexdump <disk> <lba> [count]` | Dump sectors of a disk               |
+//! | `memmap`                       | The UEFI memory map                  |
+//! | `memtest [passes]`             | [Test](crate::memtest) the free RAM  |
+//! | `log [<target>] [<level>]`     | Show or set log levels               |
+//! | `bootlog`                      | How the last boots went              |
+//! | `cmos [<option> [<value>]]`    | Show or set CMOS options             |
+//! | `boot <disk>p<n> <file>`       | Start an EFI application             |
+//! | `password [clear]`             | Set or clear the setup password      |
+//! | `exit`                         | Back to the boot menu                |
+//!
+//! Disks are named `disk0`, `disk1`, ... in the order `lsblk` lists them and
+//! partitions `disk0p1`, ... after their GPT entry. `ls` and `cat` read
+//! FAT, [squashfs](crate::fs::squashfs) and [btrfs](crate::fs::btrfs)
+//! partitions, for looking into recovery images and root filesystems.
+//!
+//! `log <level>` sets the default log level, `log <target> <level>` the
+//! level of one module such as `drivers::nvme`, and `log <target> default`
+//! makes the module follow the default again.
+
+use core::fmt::Write;
+use heapless::{String, Vec};
+use log::LevelFilter;
+use r_efi::efi::Status;
+
+use crate::boot_log;
+use crate::coreboot::cmos_options::{self, Cmos, CmosOption, CmosOptionError, OptionKind};
+use crate::drivers::block::{
+    AhciDisk, BlockDevice, NvmeDisk, RamDiskBlockDevice, SdhciDisk, UsbDisk,
+};
+use crate::drivers::pci::{self, BarType};
+use crate::drivers::serial as serial_driver;
+use crate::drivers::{ahci, nvme, ramdisk, sdhci, usb};
+use crate::efi::allocator::MemoryType;
+use crate::framebuffer_console::FramebufferConsole;
+use crate::fs::btrfs::{self, Btrfs, BtrfsError};
+use crate::fs::fat::{FatFilesystem, FatType};
+use crate::fs::gpt::{self, Partition, PartitionType};
+use crate::fs::squashfs::{InodeKind, Squashfs, SquashfsError};
+use crate::logger;
+use crate::memtest;
+use crate::menu::{self, BootEntry, BootMenu, DeviceType, KeyPress};
+use crate::setup_password;
+use crate::state;
+use crate::time::delay_ms;
+
+/// Prompt shown before each command
+const PROMPT: &str = "crabefi> ";
+
+/// Longest command line accepted
+const MAX_LINE_LEN: usize = 128;
+
+/// Maximum number of disks listed
+const MAX_DISKS: usize = 16;
+
+/// Largest block size of a disk that can be dumped
+const MAX_BLOCK_SIZE: usize = 4096;
+
+/// Maximum number of sectors dumped by one `hexdump`
+const MAX_HEXDUMP_SECTORS: u64 = 16;
+
+/// Maximum number of bytes printed by `cat`
+const MAX_CAT_SIZE: u32 = 64 * 1024;
+
+/// Command summary printed by `help`
+const HELP: &str = "\
+help                          this list
+lspci                         PCI devices
+lsblk                         disks and partitions
+ls <disk>p<n> [dir]           list a directory
+cat <disk>p<n> <file>         print a file
+hexdump <disk> <lba> [count]  dump sectors
+memmap                        memory map
+memtest [passes]              test the free RAM
+log [<target>] [<level>]      show or set log levels
+bootlog                       outcome of the last boots
+cmos [<option> [<value>]]     show or set CMOS options
+boot <disk>p<n> <file>        start an EFI application
+password [clear]              set or clear the setup password
+exit                          back to the boot menu
+";
+
+/// Result of a command, with an error message on failure
+type CommandResult = Result<(), &'static str>;
+
+/// A disk the console can read
+#[derive(Debug, Clone, Copy)]
+struct Disk {
+    device_type: DeviceType,
+    pci_device: u8,
+    pci_function: u8,
+}
+
+/// Find all disks of the storage controllers
+fn disks() -> Vec<Disk, MAX_DISKS> {
+    let mut disks = Vec::new();
+
+    for controller_id in 0.. {
+        let Some(controller) = nvme::get_controller(controller_id) else {
+            break;
+        };
+        let pci_addr = controller.pci_address();
+        for ns in controller.namespaces() {
+            let _ = disks.push(Disk {
+                device_type: DeviceType::Nvme {
+                    controller_id,
+                    nsid: ns.nsid,
+                },
+                pci_device: pci_addr.device,
+                pci_function: pci_addr.function,
+            });
+        }
+    }
+
+    for controller_id in 0.. {
+        let Some(controller) = ahci::get_controller(controller_id) else {
+            break;
+        };
+        let pci_addr = controller.pci_address();
+        for port in 0..controller.num_active_ports() {
+            if controller
+                .get_port(port)
+                .is_some_and(|p| p.device_type != ahci::DeviceType::None)
+            {
+                let _ = disks.push(Disk {
+                    device_type: DeviceType::Ahci {
+                        controller_id,
+                        port,
+                    },
+                    pci_device: pci_addr.device,
+                    pci_function: pci_addr.function,
+                });
+            }
+        }
+    }
+
+    // Only the mass storage device set up by boot entry discovery is usable
+    if let Some((controller_id, device_addr)) = usb::find_mass_storage()
+        && let Some(device) = usb::mass_storage::get_global_device()
+    {
+        let _ = disks.push(Disk {
+            device_type: DeviceType::Usb {
+                controller_id,
+                device_addr,
+                lun: device.lun(),
+            },
+            pci_device: 0,
+            pci_function: 0,
+        });
+    }
+
+    for controller_id in 0..sdhci::controller_count() {
+        if let Some(controller) = sdhci::get_controller(controller_id)
+            && controller.is_ready()
+        {
+            let pci_addr = controller.pci_address();
+            let _ = disks.push(Disk {
+                device_type: DeviceType::Sdhci { controller_id },
+                pci_device: pci_addr.device,
+                pci_function: pci_addr.function,
+            });
+        }
+    }
+
+    disks
+}
+
+/// Run `f` on a disk
+fn with_disk<R>(device_type: DeviceType, f: impl FnOnce(&mut dyn BlockDevice) -> R) -> Option<R> {
+    match device_type {
+        DeviceType::Nvme {
+            controller_id,
+            nsid,
+        } => {
+            let controller = nvme::get_controller(controller_id)?;
+            Some(f(&mut NvmeDisk::new(controller, nsid)))
+        }
+        DeviceType::Ahci {
+            controller_id,
+            port,
+        } => {
+            let controller = ahci::get_controller(controller_id)?;
+            Some(f(&mut AhciDisk::new(controller, port)))
+        }
+        DeviceType::Usb { controller_id, .. } => {
+            usb::with_controller(controller_id, |controller| {
+                let device = usb::mass_storage::get_global_device()?;
+                Some(f(&mut UsbDisk::new(device, controller)))
+            })
+            .flatten()
+        }
+        DeviceType::Sdhci { controller_id } => {
+            let controller = sdhci::get_controller(controller_id)?;
+            Some(f(&mut SdhciDisk::new(controller)))
+        }
+        DeviceType::RamDisk { id } => {
+            let disk = ramdisk::get(id)?;
+            Some(f(&mut RamDiskBlockDevice::new(id, &disk)))
+        }
+        DeviceType::Payload => None,
+    }
+}
+
+/// GPT partitions of a disk
+fn partitions(disk: &Disk) -> Result<Vec<Partition, 16>, &'static str> {
+    with_disk(disk.device_type, |device| {
+        let header = gpt::read_gpt_header(device).map_err(|_| "No GPT on this disk")?;
+        gpt::read_partitions(device, &header).map_err(|_| "Failed to read the partitions")
+    })
+    .unwrap_or(Err("Disk disappeared"))
+}
+
+/// Parse `disk<n>` or `disk<n>p<m>` into a disk and partition number
+fn parse_disk(spec: &str) -> Result<(Disk, Option<u32>), &'static str> {
+    let spec = spec
+        .strip_prefix("disk")
+        .ok_or("Disks are named disk0, disk1, ...")?;
+    let (disk, partition) = match spec.split_once('p') {
+        Some((disk, partition)) => (disk, Some(partition)),
+        None => (spec, None),
+    };
+    let disk: usize = disk.parse().map_err(|_| "Bad disk number")?;
+    let disk = *disks().get(disk).ok_or("No such disk, see lsblk")?;
+    let partition = match partition {
+        Some(partition) => Some(partition.parse().map_err(|_| "Bad partition number")?),
+        None => None,
+    };
+    Ok((disk, partition))
+}
+
+/// Parse `disk<n>p<m>` into a disk, its partition number and the partition
+fn parse_partition(spec: Option<&str>) -> Result<(Disk, u32, Partition), &'static str> {
+    let (disk, number) = parse_disk(spec.ok_or("Missing partition, e.g. disk0p1")?)?;
+    let number = number.ok_or("Missing partition number, e.g. disk0p1")?;
+    let partition = partitions(&disk)?
+        .get((number as usize).wrapping_sub(1))
+        .cloned()
+        .ok_or("No such partition, see lsblk")?;
+    Ok((disk, number, partition))
+}
+
+/// Message for a squashfs error
+fn squashfs_error(e: SquashfsError) -> &'static str {
+    match e {
+        SquashfsError::NotSquashfs => "No squashfs filesystem",
+        SquashfsError::Unsupported => "Unsupported squashfs",
+        SquashfsError::ReadError => "Read error",
+        SquashfsError::Corrupt => "Corrupt squashfs",
+        SquashfsError::NotFound => "Not found",
+        SquashfsError::NotAFile => "Not a file",
+        SquashfsError::NotADirectory => "Not a directory",
+        SquashfsError::TooManyLinks => "Too many symbolic links",
+    }
+}
+
+/// Message for a btrfs error
+fn btrfs_error(e: BtrfsError) -> &'static str {
+    match e {
+        BtrfsError::NotBtrfs => "No FAT, squashfs or btrfs filesystem",
+        BtrfsError::Unsupported => "Unsupported btrfs",
+        BtrfsError::ReadError => "Read error",
+        BtrfsError::Corrupt => "Corrupt btrfs",
+        BtrfsError::NotFound => "Not found",
+        BtrfsError::NotAFile => "Not a file",
+        BtrfsError::NotADirectory => "Not a directory",
+        BtrfsError::TooManyLinks => "Too many symbolic links",
+    }
+}
+
+/// Parse a decimal or `0x` prefixed hexadecimal number
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// What to do after a command
+enum Action {
+    Continue,
+    Exit,
+    /// Boot the menu entry with this index
+    Boot(usize),
+}
+
+/// Output to both consoles and input from the keyboards
+struct Console<'a, 'b> {
+    fb_console: &'a mut Option<FramebufferConsole<'b>>,
+}
+
+impl Write for Console<'_, '_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for line in s.split_inclusive('\n') {
+            match line.strip_suffix('\n') {
+                Some(line) => {
+                    serial_driver::write_str(line);
+                    serial_driver::write_str("\r\n");
+                }
+                None => serial_driver::write_str(line),
+            }
+        }
+        if let Some(console) = self.fb_console {
+            s.chars().for_each(|c| console.put_char(c));
+        }
+        Ok(())
+    }
+}
+
+impl Console<'_, '_> {
+    fn flush(&self) {
+        if let Some(console) = self.fb_console.as_ref() {
+            console.flush();
+        }
+    }
+
+    /// Longest command that fits on a line after the prompt
+    fn max_line_len(&self) -> usize {
+        let cols = self.fb_console.as_ref().map(|c| c.cols()).unwrap_or(80) as usize;
+        MAX_LINE_LEN.min(cols.saturating_sub(PROMPT.len() + 1))
+    }
+
+    /// Read a line after `prompt`, echoing it or, if `hidden`, a `*` per
+    /// character
+    fn read_line(&mut self, prompt: &str, hidden: bool, line: &mut String<MAX_LINE_LEN>) {
+        line.clear();
+        let _ = self.write_str(prompt);
+        self.flush();
+        let max_len = self.max_line_len();
+
+        loop {
+            if let Some(key) = menu::read_key() {
+                match key {
+                    KeyPress::Enter => {
+                        let _ = self.write_str("\n");
+                        self.flush();
+                        return;
+                    }
+                    // Backspace, or DEL from serial terminals
+                    KeyPress::Char('\x08' | '\x7f') => {
+                        if line.pop().is_some() {
+                            let _ = self.write_str("\x08 \x08");
+                        }
+                    }
+                    KeyPress::Char(c) if (' '..='~').contains(&c) && line.len() < max_len => {
+                        let _ = line.push(c);
+                        let _ = self.write_char(if hidden { '*' } else { c });
+                    }
+                    _ => continue,
+                }
+                self.flush();
+            }
+
+            delay_ms(10);
+        }
+    }
+
+    /// Run one command line
+    fn execute(&mut self, line: &str, menu: &mut BootMenu) -> Action {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Action::Continue;
+        };
+        let result = match command {
+            "help" => self.write_str(HELP).map_err(|_| "Output failed"),
+            "lspci" => self.lspci(),
+            "lsblk" => self.lsblk(),
+            "ls" => self.ls(words.next(), words.next().unwrap_or("")),
+            "cat" => self.cat(words.next(), words.next()),
+            "hexdump" => self.hexdump(words.next(), words.next(), words.next()),
+            "memmap" => self.memmap(),
+            "memtest" => self.memtest(words.next()),
+            "log" => self.log_levels(words.next(), words.next()),
+            "bootlog" => self.boot_log(menu),
+            "cmos" => self.cmos(words.next(), words.next()),
+            "boot" => match boot(words.next(), words.next(), menu) {
+                Ok(index) => return Action::Boot(index),
+                Err(message) => Err(message),
+            },
+            "password" => self.password(words.next()),
+            "exit" => return Action::Exit,
+            _ => Err("Unknown command, try help"),
+        };
+        if let Err(message) = result {
+            let _ = writeln!(self, "error: {}", message);
+        }
+        Action::Continue
+    }
+
+    fn lspci(&mut self) -> CommandResult {
+        for dev in pci::get_all_devices().iter() {
+            let _ = writeln!(self, "{}", dev);
+            for (i, bar) in dev.bars.iter().enumerate() {
+                if bar.bar_type != BarType::Unused {
+                    let _ = writeln!(self, "  BAR{}: {}", i, bar);
+                }
+            }
+            if !dev.capabilities.is_empty() {
+                let _ = self.write_str("  Capabilities:");
+                for cap in dev.capabilities.iter() {
+                    let _ = write!(self, " [{:02x}] {}", cap.offset, cap.name());
+                }
+                let _ = self.write_str("\n");
+            }
+        }
+        Ok(())
+    }
+
+    fn lsblk(&mut self) -> CommandResult {
+        for (i, disk) in disks().iter().enumerate() {
+            let info = with_disk(disk.device_type, |device| device.info());
+            let (num_blocks, block_size) = info.map_or((0, 0), |i| (i.num_blocks, i.block_size));
+            let _ = writeln!(
+                self,
+                "disk{}  {:?}  {} x {} bytes",
+                i, disk.device_type, num_blocks, block_size
+            );
+            let Ok(partitions) = partitions(disk) else {
+                continue;
+            };
+            for (n, partition) in partitions.iter().enumerate() {
+                let _ = write!(
+                    self,
+                    "  disk{}p{}  LBA {}-{}  {} MiB  {}  {}",
+                    i,
+                    n + 1,
+                    partition.first_lba,
+                    partition.last_lba,
+                    partition.size_bytes() / (1024 * 1024),
+                    partition.partition_uuid(),
+                    partition
+                        .partition_type()
+                        .map_or("unknown", PartitionType::name)
+                );
+                if !partition.name.is_empty() {
+                    let _ = write!(self, "  \"{}\"", partition.name);
+                }
+                if partition.is_auto_root() {
+                    let _ = self.write_str("  (root)");
+                }
+                let _ = self.write_str("\n");
+            }
+        }
+        Ok(())
+    }
+
+    fn ls(&mut self, spec: Option<&str>, path: &str) -> CommandResult {
+        let (disk, _, partition) = parse_partition(spec)?;
+        with_disk(disk.device_type, |device| {
+            if FatFilesystem::new(&mut *device, partition.first_lba).is_err() {
+                if Squashfs::new(&mut *device, partition.first_lba).err()
+                    == Some(SquashfsError::NotSquashfs)
+                {
+                    return self.ls_btrfs(device, partition.first_lba, path);
+                }
+                return self.ls_squashfs(device, partition.first_lba, path);
+            }
+            let mut fat =
+                FatFilesystem::new(device, partition.first_lba).map_err(|_| "No FAT filesystem")?;
+            let root = match fat.fat_type() {
+                FatType::Fat32 => fat.root_cluster(),
+                _ => 0,
+            };
+            let cluster = if path.split(['/', '\\']).all(str::is_empty) {
+                root
+            } else {
+                let entry = fat.find_file(path).map_err(|_| "Not found")?;
+                if !entry.is_directory() {
+                    return Err("Not a directory");
+                }
+                // `..` entries pointing at the root directory use cluster 0
+                match entry.first_cluster() {
+                    0 => root,
+                    cluster => cluster,
+                }
+            };
+
+            for position in 0.. {
+                match fat.get_directory_entry_at_position(cluster, position) {
+                    Ok(Some(entry)) if entry.is_directory() => {
+                        let _ = writeln!(self, "{:>10}  {}\\", "<DIR>", entry.short_name());
+                    }
+                    Ok(Some(entry)) => {
+                        let _ = writeln!(self, "{:>10}  {}", entry.file_size(), entry.short_name());
+                    }
+                    Ok(None) => break,
+                    Err(_) => return Err("Read error"),
+                }
+            }
+            Ok(())
+        })
+        .unwrap_or(Err("Disk disappeared"))
+    }
+
+    fn cat(&mut self, spec: Option<&str>, path: Option<&str>) -> CommandResult {
+        let (disk, _, partition) = parse_partition(spec)?;
+        let path = path.ok_or("Missing file name")?;
+        with_disk(disk.device_type, |device| {
+            if FatFilesystem::new(&mut *device, partition.first_lba).is_err() {
+                if Squashfs::new(&mut *device, partition.first_lba).err()
+                    == Some(SquashfsError::NotSquashfs)
+                {
+                    return self.cat_btrfs(device, partition.first_lba, path);
+                }
+                return self.cat_squashfs(device, partition.first_lba, path);
+            }
+            let mut fat =
+                FatFilesystem::new(device, partition.first_lba).map_err(|_| "No FAT filesystem")?;
+            let entry = fat.find_file(path).map_err(|_| "Not found")?;
+            if entry.is_directory() {
+                return Err("Is a directory");
+            }
+
+            let size = entry.file_size().min(MAX_CAT_SIZE);
+            let mut buffer = [0u8; 512];
+            let mut offset = 0;
+            while offset < size {
+                let len = (size - offset).min(buffer.len() as u32) as usize;
+                let read = fat
+                    .read_file(&entry, offset, &mut buffer[..len])
+                    .map_err(|_| "Read error")?;
+                if read == 0 {
+                    break;
+                }
+                self.write_printable(&buffer[..read]);
+                offset += read as u32;
+            }
+            if entry.file_size() > MAX_CAT_SIZE {
+                let _ = writeln!(self, "\n[truncated at {} bytes]", MAX_CAT_SIZE);
+            }
+            self.flush();
+            Ok(())
+        })
+        .unwrap_or(Err("Disk disappeared"))
+    }
+
+    /// Print file contents, keeping binary files from garbling the terminal
+    fn write_printable(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let c = match byte {
+                b'\n' | b'\t' | b' '..=b'~' => byte as char,
+                b'\r' => continue,
+                _ => '.',
+            };
+            let _ = self.write_char(c);
+        }
+    }
+
+    fn ls_squashfs(
+        &mut self,
+        device: &mut dyn BlockDevice,
+        start: u64,
+        path: &str,
+    ) -> CommandResult {
+        let mut fs = Squashfs::new(device, start).map_err(squashfs_error)?;
+        let dir = fs.find(path).map_err(squashfs_error)?;
+        for index in 0.. {
+            let Some(entry) = fs.entry_at(&dir, index).map_err(squashfs_error)? else {
+                break;
+            };
+            match entry.kind {
+                InodeKind::Directory => {
+                    let _ = writeln!(self, "{:>10}  {}/", "<DIR>", entry.name());
+                }
+                InodeKind::Symlink => {
+                    let _ = writeln!(self, "{:>10}  {}@", "<LINK>", entry.name());
+                }
+                _ => {
+                    let size = fs.inode(&entry).map_err(squashfs_error)?.size;
+                    let _ = writeln!(self, "{:>10}  {}", size, entry.name());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn cat_squashfs(
+        &mut self,
+        device: &mut dyn BlockDevice,
+        start: u64,
+        path: &str,
+    ) -> CommandResult {
+        let mut fs = Squashfs::new(device, start).map_err(squashfs_error)?;
+        let file = fs.find(path).map_err(squashfs_error)?;
+        if file.is_dir() {
+            return Err("Is a directory");
+        }
+        self.print_file(file.size, |offset, buffer| {
+            fs.read_file(&file, offset, buffer).map_err(squashfs_error)
+        })
+    }
+
+    fn ls_btrfs(&mut self, device: &mut dyn BlockDevice, start: u64, path: &str) -> CommandResult {
+        let mut fs = Btrfs::new(device, start).map_err(btrfs_error)?;
+        let dir = fs.find(path).map_err(btrfs_error)?;
+        let mut position = 0;
+        while let Some(entry) = fs.read_dir(&dir, &mut position).map_err(btrfs_error)? {
+            match entry.kind {
+                btrfs::InodeKind::Directory => {
+                    let _ = writeln!(self, "{:>10}  {}/", "<DIR>", entry.name());
+                }
+                btrfs::InodeKind::Symlink => {
+                    let _ = writeln!(self, "{:>10}  {}@", "<LINK>", entry.name());
+                }
+                _ => {
+                    let size = fs.inode(&entry).map_err(btrfs_error)?.size;
+                    let _ = writeln!(self, "{:>10}  {}", size, entry.name());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn cat_btrfs(&mut self, device: &mut dyn BlockDevice, start: u64, path: &str) -> CommandResult {
+        let mut fs = Btrfs::new(device, start).map_err(btrfs_error)?;
+        let file = fs.find(path).map_err(btrfs_error)?;
+        if file.is_dir() {
+            return Err("Is a directory");
+        }
+        self.print_file(file.size, |offset, buffer| {
+            fs.read_file(&file, offset, buffer).map_err(btrfs_error)
+        })
+    }
+
+    /// Print up to [`MAX_CAT_SIZE`] bytes of a file of `size` bytes read by
+    /// `read`
+    fn print_file(
+        &mut self,
+        size: u64,
+        mut read: impl FnMut(u64, &mut [u8]) -> Result<usize, &'static str>,
+    ) -> CommandResult {
+        let mut buffer = [0u8; 512];
+        let limit = size.min(MAX_CAT_SIZE as u64);
+        let mut offset = 0;
+        while offset < limit {
+            let len = (limit - offset).min(buffer.len() as u64) as usize;
+            let len = read(offset, &mut buffer[..len])?;
+            if len == 0 {
+                break;
+            }
+            self.write_printable(&buffer[..len]);
+            offset += len as u64;
+        }
+        if size > MAX_CAT_SIZE as u64 {
+            let _ = writeln!(self, "\n[truncated at {} bytes]", MAX_CAT_SIZE);
+        }
+        self.flush();
+        Ok(())
+    }
+
+    fn hexdump(
+        &mut self,
+        spec: Option<&str>,
+        lba: Option<&str>,
+        count: Option<&str>,
+    ) -> CommandResult {
+        let (disk, _) = parse_disk(spec.ok_or("Missing disk, e.g. disk0")?)?;
+        let lba = lba.and_then(parse_number).ok_or("Missing or bad LBA")?;
+        let count = match count {
+            Some(count) => parse_number(count).ok_or("Bad sector count")?,
+            None => 1,
+        };
+
+        with_disk(disk.device_type, |device| {
+            let block_size = device.info().block_size as usize;
+            if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+                return Err("Unsupported block size");
+            }
+            let mut buffer = [0u8; MAX_BLOCK_SIZE];
+            for sector in lba..lba.saturating_add(count.min(MAX_HEXDUMP_SECTORS)) {
+                device
+                    .read_block(sector, &mut buffer[..block_size])
+                    .map_err(|_| "Read error")?;
+                let base = sector * block_size as u64;
+                for (i, row) in buffer[..block_size].chunks(16).enumerate() {
+                    let _ = write!(self, "{:010x} ", base + (i * 16) as u64);
+                    for byte in row {
+                        let _ = write!(self, " {:02x}", byte);
+                    }
+                    let _ = self.write_str("  |");
+                    for &byte in row {
+                        let c = if byte.is_ascii_graphic() || byte == b' ' {
+                            byte as char
+                        } else {
+                            '.'
+                        };
+                        let _ = self.write_char(c);
+                    }
+                    let _ = self.write_str("|\n");
+                }
+                self.flush();
+            }
+            Ok(())
+        })
+        .unwrap_or(Err("Disk disappeared"))
+    }
+
+    fn log_levels(&mut self, first: Option<&str>, second: Option<&str>) -> CommandResult {
+        let parse = |level: &str| level.parse::<LevelFilter>().map_err(|_| "Bad log level");
+        match (first, second) {
+            (None, _) => {
+                let levels = logger::levels();
+                let _ = writeln!(self, "default  {}", levels.default);
+                for target in levels.targets.iter() {
+                    let _ = writeln!(self, "{}  {}", target.target, target.level);
+                }
+                Ok(())
+            }
+            (Some(level), None) => {
+                logger::set_level(parse(level)?);
+                Ok(())
+            }
+            (Some(target), Some("default")) => logger::set_target_level(target, None),
+            (Some(target), Some(level)) => logger::set_target_level(target, Some(parse(level)?)),
+        }
+    }
+
+    fn boot_log(&mut self, menu: &BootMenu) -> CommandResult {
+        let records = boot_log::records();
+        if records.is_empty() {
+            let _ = writeln!(self, "No boots recorded");
+        }
+        for record in records {
+            let _ = write!(self, "{}  {:<8} ", record.time, record.outcome);
+            let _ = match menu.find_loader_id(&record.entry) {
+                Some(entry) => writeln!(self, "{}", entry.name),
+                None => writeln!(self, "{} (no longer in the menu)", record.entry),
+            };
+        }
+        Ok(())
+    }
+
+    fn cmos(&mut self, name: Option<&str>, value: Option<&str>) -> CommandResult {
+        let table = cmos_options::table().ok_or("No CMOS option table")?;
+        let show = |console: &mut Self, option: &CmosOption| {
+            let value = match table.read(&mut Cmos, option) {
+                Ok(value) => value,
+                Err(CmosOptionError::BadChecksum) => return Err("Bad CMOS checksum"),
+                Err(_) => return Ok(()),
+            };
+            match table.enum_text(option, value) {
+                Some(text) => writeln!(console, "{}  {}", option.name, text),
+                None => writeln!(console, "{}  {:#x}", option.name, value),
+            }
+            .map_err(|_| "Output failed")
+        };
+        match (name, value) {
+            (None, _) => table.options().try_for_each(|option| show(self, &option)),
+            (Some(name), None) => {
+                let option = table.option(name).ok_or("Unknown option")?;
+                show(self, &option)?;
+                if option.kind == OptionKind::Enum {
+                    for (_, text) in table.enum_values(&option) {
+                        let _ = writeln!(self, "  {}", text);
+                    }
+                }
+                Ok(())
+            }
+            (Some(name), Some(value)) => {
+                cmos_options::set_str(name, value).map_err(|error| match error {
+                    CmosOptionError::NotPresent | CmosOptionError::UnknownOption => {
+                        "Unknown option"
+                    }
+                    CmosOptionError::Unsupported => "Option can't be set",
+                    CmosOptionError::InvalidValue => "Bad value",
+                    CmosOptionError::BadChecksum => "Bad CMOS checksum",
+                })
+            }
+        }
+    }
+
+    fn memmap(&mut self) -> CommandResult {
+        for entry in state::allocator().entries() {
+            let _ = write!(
+                self,
+                "{:016x}-{:016x} {:>8} pages  ",
+                entry.physical_start,
+                entry.end(),
+                entry.number_of_pages
+            );
+            let _ = match MemoryType::from_u32(entry.memory_type) {
+                Some(memory_type) => writeln!(self, "{:?}", memory_type),
+                None => writeln!(self, "type {:#x}", entry.memory_type),
+            };
+        }
+        Ok(())
+    }
+
+    fn memtest(&mut self, passes: Option<&str>) -> CommandResult {
+        let passes = match passes {
+            Some(passes) => parse_number(passes)
+                .and_then(|passes| u32::try_from(passes).ok())
+                .filter(|&passes| passes > 0)
+                .ok_or("Bad number of passes")?,
+            None => 1,
+        };
+        // The test takes a while, show its progress as it goes
+        struct Flushing<'c, 'a, 'b>(&'c mut Console<'a, 'b>);
+        impl Write for Flushing<'_, '_, '_> {
+            fn write_str(&mut self, s: &str) -> core::fmt::Result {
+                self.0.write_str(s)?;
+                self.0.flush();
+                Ok(())
+            }
+        }
+
+        match memtest::run(&mut Flushing(self), passes) {
+            0 => {
+                let _ = writeln!(self, "No errors found");
+                Ok(())
+            }
+            _ => Err("Memory errors found"),
+        }
+    }
+
+    fn password(&mut self, argument: Option<&str>) -> CommandResult {
+        let status = match argument {
+            Some("clear") => setup_password::set(&[]),
+            Some(_) => return Err("Usage: password [clear]"),
+            None => {
+                let mut password = String::new();
+                let mut again = String::new();
+                self.read_line("New password: ", true, &mut password);
+                self.read_line("Repeat: ", true, &mut again);
+                if password != again {
+                    return Err("The passwords differ");
+                }
+                if password.is_empty() {
+                    return Err("Empty password, use password clear");
+                }
+                setup_password::set(password.as_bytes())
+            }
+        };
+        if status != Status::SUCCESS {
+            return Err("Can't write the variable");
+        }
+        // Whoever set it doesn't need to enter it again
+        setup_password::unlock();
+        Ok(())
+    }
+}
+
+/// Add a boot menu entry for an EFI application and return its index
+fn boot(
+    spec: Option<&str>,
+    path: Option<&str>,
+    menu: &mut BootMenu,
+) -> Result<usize, &'static str> {
+    let (disk, number, partition) = parse_partition(spec)?;
+    let path = path.ok_or("Missing file name")?;
+
+    let mut loader: String<128> = String::new();
+    for c in path.trim_start_matches(['/', '\\']).chars() {
+        loader
+            .push(if c == '/' { '\\' } else { c })
+            .map_err(|_| "Path too long")?;
+    }
+    let found = with_disk(disk.device_type, |device| {
+        FatFilesystem::new(device, partition.first_lba)
+            .and_then(|mut fat| fat.file_size(&loader))
+            .is_ok_and(|size| size > 0)
+    });
+    if found != Some(true) {
+        return Err("File not found");
+    }
+
+    let mut name: String<64> = String::new();
+    let _ = write!(name, "Console: {}", loader);
+    let entry = BootEntry::new(
+        &name,
+        &loader,
+        disk.device_type,
+        number,
+        partition,
+        disk.pci_device,
+        disk.pci_function,
+    );
+    if !menu.add_entry(entry) {
+        return Err("The boot menu is full");
+    }
+    Ok(menu.entry_count() - 1)
+}
+
+/// Run the console until the user exits or boots something
+///
+/// Returns the index of the boot menu entry added by `boot`, or `None`
+/// after `exit`.
+pub fn run(menu: &mut BootMenu, fb_console: &mut Option<FramebufferConsole>) -> Option<usize> {
+    serial_driver::write_str("\x1b[2J\x1b[H");
+    if let Some(console) = fb_console {
+        console.clear();
+    }
+
+    let mut console = Console { fb_console };
+    let _ = writeln!(console, "CrabEFI recovery console, type help for commands");
+    let mut line = String::new();
+
+    loop {
+        console.read_line(PROMPT, false, &mut line);
+        match console.execute(&line, menu) {
+            Action::Continue => console.flush(),
+            Action::Exit => return None,
+            Action::Boot(index) => return Some(index),
+        }
+    }
+}
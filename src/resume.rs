@@ -0,0 +1,122 @@
+//! S3 resume
+//!
+//! On most boards coreboot resumes from suspend-to-RAM without running the
+//! payload, but when it does hand off after an S3 resume, memory still holds
+//! the suspended OS. Initializing storage or starting a boot loader would
+//! overwrite it, so the only thing left to do is to enter the OS waking
+//! vector from the ACPI FACS.
+//!
+//! coreboot records the resume in the romstage handoff in CBMEM. This only
+//! works if the payload runs from memory the OS doesn't use, which is up to
+//! the coreboot configuration.
+
+use crate::acpi;
+use crate::arch::x86_64::wake::{self, WakeMode};
+use crate::coreboot::CorebootInfo;
+use crate::platform::{self, ResetKind};
+
+/// Offset of `s3_resume` in coreboot's `struct romstage_handoff`
+const HANDOFF_S3_RESUME: u64 = 0;
+
+/// FACS field offsets
+const FACS_LENGTH: u64 = 4;
+const FACS_FIRMWARE_WAKING_VECTOR: u64 = 12;
+const FACS_X_FIRMWARE_WAKING_VECTOR: u64 = 24;
+const FACS_VERSION: u64 = 32;
+const FACS_OSPM_FLAGS: u64 = 36;
+
+/// OSPM flag: enter the extended waking vector in long mode
+const OSPM_64BIT_WAKE: u32 = 1 << 0;
+
+/// FADT field offsets
+const FADT_FIRMWARE_CTRL: u64 = 36;
+const FADT_X_FIRMWARE_CTRL: u64 = 132;
+
+/// Read a possibly unaligned value from physical memory
+///
+/// # Safety
+///
+/// `address` must be mapped and readable for `size_of::<T>()` bytes.
+unsafe fn read<T: Copy>(address: u64) -> T {
+    unsafe { core::ptr::read_unaligned(address as *const T) }
+}
+
+/// Check whether coreboot reports an S3 resume
+pub fn is_s3_resume(cb_info: &CorebootInfo) -> bool {
+    cb_info
+        .romstage_handoff
+        .is_some_and(|handoff| unsafe { read::<u8>(handoff + HANDOFF_S3_RESUME) } != 0)
+}
+
+/// Find the waking vector and the mode to enter it in
+///
+/// # Safety
+///
+/// `rsdp` must point to the ACPI RSDP of this boot.
+unsafe fn waking_vector(rsdp: u64) -> Option<(u64, WakeMode)> {
+    unsafe {
+        let fadt = acpi::find_table(rsdp, b"FACP")?;
+        let fadt_length = read::<u32>(fadt + 4) as u64;
+        let x_facs = if fadt_length >= FADT_X_FIRMWARE_CTRL + 8 {
+            read::<u64>(fadt + FADT_X_FIRMWARE_CTRL)
+        } else {
+            0
+        };
+        let facs = if x_facs != 0 {
+            x_facs
+        } else {
+            read::<u32>(fadt + FADT_FIRMWARE_CTRL) as u64
+        };
+        if facs == 0 || read::<[u8; 4]>(facs) != *b"FACS" {
+            log::warn!("S3 resume: no FACS");
+            return None;
+        }
+
+        let facs_length = read::<u32>(facs + FACS_LENGTH) as u64;
+        let x_vector = if facs_length >= FACS_X_FIRMWARE_WAKING_VECTOR + 8 {
+            read::<u64>(facs + FACS_X_FIRMWARE_WAKING_VECTOR)
+        } else {
+            0
+        };
+        if x_vector != 0 {
+            let long_mode = read::<u8>(facs + FACS_VERSION) >= 1
+                && read::<u32>(facs + FACS_OSPM_FLAGS) & OSPM_64BIT_WAKE != 0;
+            let mode = if long_mode {
+                WakeMode::Long
+            } else {
+                WakeMode::Protected
+            };
+            return Some((x_vector, mode));
+        }
+
+        let vector = read::<u32>(facs + FACS_FIRMWARE_WAKING_VECTOR) as u64;
+        // Real mode can only reach the first MiB
+        (vector != 0 && vector < 0x10_0000).then_some((vector, WakeMode::Real))
+    }
+}
+
+/// Resume the OS if this boot is an S3 resume
+///
+/// Returns on a normal boot. On a resume it enters the OS waking vector, or
+/// resets the system if there is none, so the next boot starts cleanly.
+pub fn handle_s3_resume(cb_info: &CorebootInfo) {
+    if !is_s3_resume(cb_info) {
+        return;
+    }
+    log::info!("S3 resume");
+
+    let vector = cb_info
+        .acpi_rsdp
+        .and_then(|rsdp| unsafe { waking_vector(rsdp) });
+    let Some((vector, mode)) = vector else {
+        log::error!("S3 resume: no OS waking vector, resetting");
+        platform::reset(ResetKind::Cold);
+    };
+
+    log::info!(
+        "S3 resume: jumping to waking vector {:#x} ({:?})",
+        vector,
+        mode
+    );
+    unsafe { wake::jump_to_waking_vector(vector, mode) }
+}
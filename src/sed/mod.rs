@@ -0,0 +1,292 @@
+//! Self-encrypting drive unlock
+//!
+//! A drive locked with TCG Opal or the ATA security feature set refuses to
+//! read until it gets its password, so its ESP can't be found. Before boot
+//! entries are discovered, every locked drive is found and the user is asked
+//! for its passphrase on the console.
+//!
+//! Credentials are derived the way the common Linux tools set them, so
+//! drives set up there unlock with the same passphrase:
+//! - Opal (NVMe and SATA): sedutil's PBKDF2-HMAC-SHA1 of the passphrase,
+//!   salted with the drive's serial number, used as the Admin1 password
+//! - ATA security (SATA): the passphrase itself, zero-padded to 32 bytes,
+//!   as `hdparm --security-unlock` sends it
+
+pub mod opal;
+
+use core::fmt::Write;
+use heapless::{String, Vec};
+
+use crate::crypto::{pbkdf2, sha1::Sha1};
+use crate::drivers::{ahci, nvme};
+use crate::menu::{self, MAX_PASSPHRASE_LEN};
+use opal::{Discovery, OpalError, Transport};
+
+/// PBKDF2 iterations of a sedutil credential
+const SEDUTIL_ITERATIONS: u32 = 75000;
+
+/// Size of a sedutil credential
+const SEDUTIL_CREDENTIAL_SIZE: usize = 32;
+
+/// Size of an ATA password
+const ATA_PASSWORD_SIZE: usize = 32;
+
+/// Passphrases tried per drive before giving up on it
+const MAX_ATTEMPTS: usize = 3;
+
+/// A drive that may need unlocking
+#[derive(Debug, Clone, Copy)]
+enum Drive {
+    Nvme {
+        controller_index: usize,
+    },
+    Ahci {
+        controller_index: usize,
+        port: usize,
+    },
+}
+
+impl Drive {
+    /// Serial number, padded with spaces as the drive reports it
+    fn serial_number(&self) -> Option<[u8; 20]> {
+        match *self {
+            Drive::Nvme { controller_index } => {
+                nvme::get_controller(controller_index).map(|c| *c.serial_number())
+            }
+            Drive::Ahci {
+                controller_index,
+                port,
+            } => ahci::get_controller(controller_index)
+                .and_then(|c| c.get_port(port))
+                .map(|p| p.serial_number),
+        }
+    }
+
+    /// Short description for the prompt
+    fn describe(&self) -> String<64> {
+        let serial = self.serial_number().unwrap_or([b' '; 20]);
+        let serial = core::str::from_utf8(&serial).unwrap_or("").trim();
+        let mut description = String::new();
+        let _ = match *self {
+            Drive::Nvme { controller_index } => {
+                write!(description, "NVMe {} ({})", controller_index, serial)
+            }
+            Drive::Ahci {
+                controller_index,
+                port,
+            } => write!(
+                description,
+                "SATA {}:{} ({})",
+                controller_index, port, serial
+            ),
+        };
+        description
+    }
+}
+
+impl Transport for Drive {
+    fn send(&mut self, protocol: u8, sp_specific: u16, data: &[u8]) -> Result<(), ()> {
+        match *self {
+            Drive::Nvme { controller_index } => nvme::get_controller(controller_index)
+                .ok_or(())?
+                .security_send(0, protocol, sp_specific, data)
+                .map_err(|_| ()),
+            Drive::Ahci {
+                controller_index,
+                port,
+            } => ahci::get_controller(controller_index)
+                .ok_or(())?
+                .trusted_send(port, protocol, sp_specific, data)
+                .map_err(|_| ()),
+        }
+    }
+
+    fn receive(&mut self, protocol: u8, sp_specific: u16, buffer: &mut [u8]) -> Result<(), ()> {
+        match *self {
+            Drive::Nvme { controller_index } => nvme::get_controller(controller_index)
+                .ok_or(())?
+                .security_receive(0, protocol, sp_specific, buffer)
+                .map(|_| ())
+                .map_err(|_| ()),
+            Drive::Ahci {
+                controller_index,
+                port,
+            } => ahci::get_controller(controller_index)
+                .ok_or(())?
+                .trusted_receive(port, protocol, sp_specific, buffer)
+                .map(|_| ())
+                .map_err(|_| ()),
+        }
+    }
+}
+
+/// How a locked drive is unlocked
+enum Lock {
+    Opal(Discovery),
+    AtaSecurity,
+}
+
+/// Find locked drives and ask for their passphrases
+///
+/// Runs after the storage controllers and keyboards are initialized and
+/// before boot entries are discovered. Drives the user skips stay locked.
+pub fn unlock_drives() {
+    let mut drives: Vec<(Drive, Lock), 16> = Vec::new();
+
+    for controller_index in 0.. {
+        let Some(controller) = nvme::get_controller(controller_index) else {
+            break;
+        };
+        if !controller.supports_security() {
+            continue;
+        }
+        let mut drive = Drive::Nvme { controller_index };
+        if let Some(discovery) = opal_lock(&mut drive) {
+            let _ = drives.push((drive, Lock::Opal(discovery)));
+        }
+    }
+
+    for controller_index in 0.. {
+        let Some(controller) = ahci::get_controller(controller_index) else {
+            break;
+        };
+        for port in 0..controller.num_active_ports() {
+            let Some(ahci_port) = controller.get_port(port) else {
+                continue;
+            };
+            if ahci_port.device_type != ahci::DeviceType::Sata {
+                continue;
+            }
+            let (ata_locked, trusted_computing) =
+                (ahci_port.is_security_locked(), ahci_port.trusted_computing);
+            let mut drive = Drive::Ahci {
+                controller_index,
+                port,
+            };
+            if ata_locked {
+                let _ = drives.push((drive, Lock::AtaSecurity));
+            } else if trusted_computing && let Some(discovery) = opal_lock(&mut drive) {
+                let _ = drives.push((drive, Lock::Opal(discovery)));
+            }
+        }
+    }
+
+    for (mut drive, lock) in drives {
+        unlock_drive(&mut drive, &lock);
+    }
+}
+
+/// Opal locking state of `drive`, if it needs unlocking
+fn opal_lock(drive: &mut Drive) -> Option<Discovery> {
+    match opal::discover(drive) {
+        Ok(discovery) if discovery.needs_unlock() => {
+            log::info!("{}: locked by Opal", drive.describe());
+            Some(discovery)
+        }
+        Ok(_) => None,
+        Err(e) => {
+            log::debug!("{}: no Opal discovery: {:?}", drive.describe(), e);
+            None
+        }
+    }
+}
+
+/// Ask for the passphrase of `drive` until it unlocks or the user gives up
+fn unlock_drive(drive: &mut Drive, lock: &Lock) {
+    let description = drive.describe();
+    if matches!(lock, Lock::AtaSecurity) && ata_count_expired(drive) {
+        log::warn!(
+            "{}: too many wrong passwords, power cycle to retry",
+            description
+        );
+        return;
+    }
+
+    let mut prompt: String<96> = String::new();
+    let _ = write!(prompt, "Passphrase for {}:", description);
+    let mut status: Option<&str> = None;
+    let mut passphrase = [0u8; MAX_PASSPHRASE_LEN];
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let Some(len) = menu::read_passphrase(&prompt, status, &mut passphrase) else {
+            log::info!("{}: skipped, stays locked", description);
+            return;
+        };
+        let result = match lock {
+            Lock::Opal(discovery) => unlock_opal(drive, discovery, &passphrase[..len]),
+            Lock::AtaSecurity => unlock_ata(drive, &passphrase[..len]),
+        };
+        passphrase.fill(0);
+
+        match result {
+            Ok(()) => {
+                log::info!("{}: unlocked", description);
+                return;
+            }
+            Err(message) => {
+                log::warn!(
+                    "{}: unlock attempt {} failed: {}",
+                    description,
+                    attempt,
+                    message
+                );
+                status = Some(message);
+            }
+        }
+    }
+    log::warn!("{}: giving up, stays locked", description);
+}
+
+/// Unlock an Opal drive with the sedutil credential of `passphrase`
+fn unlock_opal(
+    drive: &mut Drive,
+    discovery: &Discovery,
+    passphrase: &[u8],
+) -> Result<(), &'static str> {
+    let salt = drive.serial_number().ok_or("Drive disappeared")?;
+    let mut credential = [0u8; SEDUTIL_CREDENTIAL_SIZE];
+    pbkdf2::<Sha1>(passphrase, &salt, SEDUTIL_ITERATIONS, &mut credential);
+    let result = opal::unlock(drive, discovery, &credential);
+    credential.fill(0);
+
+    result.map_err(|e| match e {
+        OpalError::NotAuthorized => "Wrong passphrase",
+        OpalError::Transport => "The drive did not respond",
+        OpalError::Malformed | OpalError::Status(_) => "The drive refused to unlock",
+    })
+}
+
+/// Unlock a drive locked by ATA security with `passphrase` as user password
+fn unlock_ata(drive: &mut Drive, passphrase: &[u8]) -> Result<(), &'static str> {
+    let Drive::Ahci {
+        controller_index,
+        port,
+    } = *drive
+    else {
+        return Err("Not an ATA drive");
+    };
+    if passphrase.len() > ATA_PASSWORD_SIZE {
+        return Err("ATA passwords are at most 32 characters");
+    }
+    let mut password = [0u8; ATA_PASSWORD_SIZE];
+    password[..passphrase.len()].copy_from_slice(passphrase);
+    let controller = ahci::get_controller(controller_index).ok_or("Drive disappeared")?;
+    let result = controller.security_unlock(port, &password);
+    password.fill(0);
+
+    result.map_err(|_| "Wrong passphrase")
+}
+
+/// Whether an ATA drive refuses unlock attempts until power cycled
+fn ata_count_expired(drive: &Drive) -> bool {
+    let Drive::Ahci {
+        controller_index,
+        port,
+    } = *drive
+    else {
+        return false;
+    };
+    ahci::get_controller(controller_index)
+        .and_then(|c| c.get_port(port))
+        .is_some_and(|p| p.is_security_count_expired())
+}
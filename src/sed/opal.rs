@@ -0,0 +1,570 @@
+//! TCG Opal locking
+//!
+//! Just enough of the TCG Storage protocol to unlock a drive: Level 0
+//! Discovery to find out whether it is locked, and a session with the
+//! Locking SP as Admin1 that unlocks the global locking range and marks the
+//! shadow MBR done, as sedutil's pre-boot authorization image does.
+//!
+//! References: TCG Storage Architecture Core Specification 2.01, TCG Storage
+//! Security Subsystem Class: Opal 2.01
+
+use crate::time::delay_ms;
+
+/// Security protocol of TCG commands and Level 0 Discovery
+pub const PROTOCOL_TCG: u8 = 0x01;
+
+/// ComID of Level 0 Discovery
+const COM_ID_DISCOVERY: u16 = 0x0001;
+
+/// Size of the command and response buffers
+pub const BUFFER_SIZE: usize = 2048;
+
+/// Feature codes of Level 0 Discovery
+const FEATURE_LOCKING: u16 = 0x0002;
+/// SSCs whose feature descriptor starts with the base ComID: Opal 1, Opal 2,
+/// Opalite, Pyrite 1, Pyrite 2 and Ruby
+const FEATURES_SSC: [u16; 6] = [0x0200, 0x0203, 0x0301, 0x0302, 0x0303, 0x0304];
+
+/// Locking feature flags
+const LOCKING_ENABLED: u8 = 1 << 1;
+const LOCKED: u8 = 1 << 2;
+const MBR_ENABLED: u8 = 1 << 4;
+const MBR_DONE: u8 = 1 << 5;
+
+/// Header sizes of a ComPacket, Packet and Data SubPacket
+const COM_PACKET_HEADER: usize = 20;
+const PACKET_HEADER: usize = 24;
+const SUBPACKET_HEADER: usize = 12;
+const PAYLOAD: usize = COM_PACKET_HEADER + PACKET_HEADER + SUBPACKET_HEADER;
+
+/// Control tokens
+const START_LIST: u8 = 0xF0;
+const END_LIST: u8 = 0xF1;
+const START_NAME: u8 = 0xF2;
+const END_NAME: u8 = 0xF3;
+const CALL: u8 = 0xF8;
+const END_OF_DATA: u8 = 0xF9;
+const END_OF_SESSION: u8 = 0xFA;
+
+/// Unique IDs of the objects and methods used
+type Uid = [u8; 8];
+const SESSION_MANAGER: Uid = [0, 0, 0, 0, 0, 0, 0, 0xFF];
+const START_SESSION: Uid = [0, 0, 0, 0, 0, 0, 0xFF, 0x02];
+const LOCKING_SP: Uid = [0, 0, 0x02, 0x05, 0, 0, 0, 0x02];
+const ADMIN1: Uid = [0, 0, 0, 0x09, 0, 0x01, 0, 0x01];
+const SET: Uid = [0, 0, 0, 0x06, 0, 0, 0, 0x17];
+const LOCKING_GLOBAL_RANGE: Uid = [0, 0, 0x08, 0x02, 0, 0, 0, 0x01];
+const MBR_CONTROL: Uid = [0, 0, 0x08, 0x03, 0, 0, 0, 0x01];
+
+/// Columns set to unlock
+const COLUMN_READ_LOCKED: u64 = 7;
+const COLUMN_WRITE_LOCKED: u64 = 8;
+const COLUMN_MBR_DONE: u64 = 2;
+
+/// Host session number used for every session
+const HOST_SESSION: u32 = 0x69;
+
+/// Polls for a response before giving up
+const RESPONSE_POLLS: usize = 100;
+
+/// Method status of a failed authentication
+const STATUS_NOT_AUTHORIZED: u8 = 0x01;
+
+/// IF-SEND and IF-RECV of a drive
+pub trait Transport {
+    /// Send `data` with the security protocol and protocol specific field
+    fn send(&mut self, protocol: u8, sp_specific: u16, data: &[u8]) -> Result<(), ()>;
+
+    /// Fill `buffer` with a response
+    fn receive(&mut self, protocol: u8, sp_specific: u16, buffer: &mut [u8]) -> Result<(), ()>;
+}
+
+/// Opal error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpalError {
+    /// The drive didn't take a command or return a response
+    Transport,
+    /// A response didn't parse
+    Malformed,
+    /// The credential is wrong
+    NotAuthorized,
+    /// A method failed with this status
+    Status(u8),
+}
+
+/// Locking state from Level 0 Discovery
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Discovery {
+    /// A locking range has locking enabled
+    pub locking_enabled: bool,
+    /// A locking range is locked
+    pub locked: bool,
+    /// The shadow MBR is shown to the host
+    pub mbr_enabled: bool,
+    /// The host was told the shadow MBR is done
+    pub mbr_done: bool,
+    /// ComID for sessions, if the drive supports a known SSC
+    pub com_id: Option<u16>,
+}
+
+impl Discovery {
+    /// Parse a Level 0 Discovery response
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let length = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+        let data = data.get(..length.checked_add(4)?.min(data.len()))?;
+        let mut features = data.get(48..)?;
+
+        let mut discovery = Discovery::default();
+        while let [code_high, code_low, _version, len, rest @ ..] = features {
+            let code = u16::from_be_bytes([*code_high, *code_low]);
+            let descriptor = rest.get(..*len as usize)?;
+            if code == FEATURE_LOCKING
+                && let Some(&flags) = descriptor.first()
+            {
+                discovery.locking_enabled = flags & LOCKING_ENABLED != 0;
+                discovery.locked = flags & LOCKED != 0;
+                discovery.mbr_enabled = flags & MBR_ENABLED != 0;
+                discovery.mbr_done = flags & MBR_DONE != 0;
+            } else if FEATURES_SSC.contains(&code)
+                && discovery.com_id.is_none()
+                && let [high, low, ..] = descriptor
+            {
+                discovery.com_id = Some(u16::from_be_bytes([*high, *low]));
+            }
+            features = &rest[*len as usize..];
+        }
+        Some(discovery)
+    }
+
+    /// Whether the drive needs unlocking before it can boot
+    pub fn needs_unlock(&self) -> bool {
+        self.locking_enabled && (self.locked || (self.mbr_enabled && !self.mbr_done))
+    }
+}
+
+/// Read the Level 0 Discovery of a drive
+pub fn discover<T: Transport>(transport: &mut T) -> Result<Discovery, OpalError> {
+    let mut buffer = [0u8; BUFFER_SIZE];
+    transport
+        .receive(PROTOCOL_TCG, COM_ID_DISCOVERY, &mut buffer)
+        .map_err(|()| OpalError::Transport)?;
+    Discovery::parse(&buffer).ok_or(OpalError::Malformed)
+}
+
+/// A token of a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    Uint(u64),
+    Bytes(&'a [u8]),
+    Control(u8),
+}
+
+/// Tokens of a response payload
+struct Tokens<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Result<Token<'a>, OpalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&head, rest) = self.data.split_first()?;
+        // Stop after an error
+        self.data = &[];
+        let (is_bytes, len, rest) = match head {
+            // Tiny atom
+            0x00..=0x7F => {
+                self.data = rest;
+                return Some(Ok(Token::Uint((head & 0x3F) as u64)));
+            }
+            // Short atom
+            0x80..=0xBF => (head & 0x20 != 0, (head & 0x0F) as usize, rest),
+            // Medium atom
+            0xC0..=0xDF => match rest {
+                [low, rest @ ..] => (
+                    head & 0x10 != 0,
+                    ((head as usize & 0x07) << 8) | *low as usize,
+                    rest,
+                ),
+                [] => return Some(Err(OpalError::Malformed)),
+            },
+            // Long atom
+            0xE0..=0xE3 => match rest {
+                [a, b, c, rest @ ..] => (
+                    head & 0x02 != 0,
+                    u32::from_be_bytes([0, *a, *b, *c]) as usize,
+                    rest,
+                ),
+                _ => return Some(Err(OpalError::Malformed)),
+            },
+            _ => {
+                self.data = rest;
+                return Some(Ok(Token::Control(head)));
+            }
+        };
+        let Some(value) = rest.get(..len) else {
+            return Some(Err(OpalError::Malformed));
+        };
+        if is_bytes {
+            self.data = &rest[len..];
+            return Some(Ok(Token::Bytes(value)));
+        }
+        if len > 8 {
+            return Some(Err(OpalError::Malformed));
+        }
+        self.data = &rest[len..];
+        let uint = value
+            .iter()
+            .fold(0u64, |uint, &byte| (uint << 8) | byte as u64);
+        Some(Ok(Token::Uint(uint)))
+    }
+}
+
+/// Method status at the end of a response payload
+fn method_status(payload: &[u8]) -> Result<(), OpalError> {
+    let mut tokens = Tokens { data: payload };
+    loop {
+        match tokens.next().ok_or(OpalError::Malformed)?? {
+            Token::Control(END_OF_DATA) => break,
+            Token::Control(END_OF_SESSION) => return Ok(()),
+            _ => {}
+        }
+    }
+    match (tokens.next(), tokens.next()) {
+        (Some(Ok(Token::Control(START_LIST))), Some(Ok(Token::Uint(0)))) => Ok(()),
+        (Some(Ok(Token::Control(START_LIST))), Some(Ok(Token::Uint(status)))) => {
+            Err(match status as u8 {
+                STATUS_NOT_AUTHORIZED => OpalError::NotAuthorized,
+                status => OpalError::Status(status),
+            })
+        }
+        _ => Err(OpalError::Malformed),
+    }
+}
+
+/// Command being encoded into a ComPacket
+struct Command {
+    buffer: [u8; BUFFER_SIZE],
+    len: usize,
+}
+
+impl Command {
+    fn new() -> Self {
+        Command {
+            buffer: [0; BUFFER_SIZE],
+            len: PAYLOAD,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        // Commands are far smaller than the buffer; cut off rather than panic
+        let end = (self.len + bytes.len()).min(BUFFER_SIZE);
+        self.buffer[self.len..end].copy_from_slice(&bytes[..end - self.len]);
+        self.len = end;
+    }
+
+    fn control(&mut self, token: u8) -> &mut Self {
+        self.push(&[token]);
+        self
+    }
+
+    fn uint(&mut self, value: u64) -> &mut Self {
+        if value < 0x40 {
+            return self.control(value as u8);
+        }
+        let bytes = value.to_be_bytes();
+        let skip = value.leading_zeros() as usize / 8;
+        self.push(&[0x80 | (8 - skip) as u8]);
+        self.push(&bytes[skip..]);
+        self
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        if value.len() < 16 {
+            self.push(&[0xA0 | value.len() as u8]);
+        } else {
+            self.push(&[0xD0 | (value.len() >> 8) as u8 & 0x07, value.len() as u8]);
+        }
+        self.push(value);
+        self
+    }
+
+    /// Start a method call on `object`
+    fn call(&mut self, object: &Uid, method: &Uid) -> &mut Self {
+        self.control(CALL)
+            .bytes(object)
+            .bytes(method)
+            .control(START_LIST)
+    }
+
+    /// End the parameters of a method call
+    fn end_call(&mut self) -> &mut Self {
+        self.control(END_LIST)
+            .control(END_OF_DATA)
+            .control(START_LIST)
+            .uint(0)
+            .uint(0)
+            .uint(0)
+            .control(END_LIST)
+    }
+
+    /// Fill in the headers; returns the bytes to send
+    fn finish(&mut self, com_id: u16, tper_session: u32, host_session: u32) -> &[u8] {
+        let payload_len = self.len - PAYLOAD;
+        let padded = payload_len.next_multiple_of(4);
+        let packet_len = SUBPACKET_HEADER + padded;
+        let com_packet_len = PACKET_HEADER + packet_len;
+
+        let header = &mut self.buffer;
+        header[4..6].copy_from_slice(&com_id.to_be_bytes());
+        header[16..20].copy_from_slice(&(com_packet_len as u32).to_be_bytes());
+        header[20..24].copy_from_slice(&tper_session.to_be_bytes());
+        header[24..28].copy_from_slice(&host_session.to_be_bytes());
+        header[40..44].copy_from_slice(&(packet_len as u32).to_be_bytes());
+        header[52..56].copy_from_slice(&(payload_len as u32).to_be_bytes());
+
+        let len = (COM_PACKET_HEADER + com_packet_len)
+            .next_multiple_of(512)
+            .min(BUFFER_SIZE);
+        &self.buffer[..len]
+    }
+}
+
+/// Payload of the data subpacket of a response ComPacket
+///
+/// `None` if the TPer has no response ready yet: an empty ComPacket.
+fn response_payload(buffer: &[u8]) -> Result<Option<&[u8]>, OpalError> {
+    let field = |offset: usize| {
+        buffer
+            .get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+            .ok_or(OpalError::Malformed)
+    };
+    let com_packet_len = field(16)?;
+    if com_packet_len == 0 {
+        return Ok(None);
+    }
+    let payload_len = field(52)?;
+    if payload_len + SUBPACKET_HEADER + PACKET_HEADER > com_packet_len {
+        return Err(OpalError::Malformed);
+    }
+    buffer
+        .get(PAYLOAD..PAYLOAD + payload_len)
+        .map(Some)
+        .ok_or(OpalError::Malformed)
+}
+
+/// Send `command` and wait for its response
+fn exchange<'b, T: Transport>(
+    transport: &mut T,
+    com_id: u16,
+    command: &[u8],
+    response: &'b mut [u8; BUFFER_SIZE],
+) -> Result<&'b [u8], OpalError> {
+    transport
+        .send(PROTOCOL_TCG, com_id, command)
+        .map_err(|()| OpalError::Transport)?;
+    for _ in 0..RESPONSE_POLLS {
+        response.fill(0);
+        transport
+            .receive(PROTOCOL_TCG, com_id, response)
+            .map_err(|()| OpalError::Transport)?;
+        if response_payload(response)?.is_some() {
+            break;
+        }
+        delay_ms(10);
+    }
+    response_payload(response)?.ok_or(OpalError::Transport)
+}
+
+/// An open session with the Locking SP
+struct Session<'t, T: Transport> {
+    transport: &'t mut T,
+    com_id: u16,
+    tper_session: u32,
+}
+
+impl<'t, T: Transport> Session<'t, T> {
+    /// Authenticate to the Locking SP as Admin1 with `credential`
+    fn start(transport: &'t mut T, com_id: u16, credential: &[u8]) -> Result<Self, OpalError> {
+        let mut command = Command::new();
+        command
+            .call(&SESSION_MANAGER, &START_SESSION)
+            .uint(HOST_SESSION as u64)
+            .bytes(&LOCKING_SP)
+            .uint(1)
+            .control(START_NAME)
+            .uint(0)
+            .bytes(credential)
+            .control(END_NAME)
+            .control(START_NAME)
+            .uint(3)
+            .bytes(&ADMIN1)
+            .control(END_NAME)
+            .end_call();
+        let mut response = [0u8; BUFFER_SIZE];
+        let payload = exchange(
+            transport,
+            com_id,
+            command.finish(com_id, 0, 0),
+            &mut response,
+        );
+        command.buffer.fill(0);
+        let payload = payload?;
+        method_status(payload)?;
+
+        // SyncSession returns the host and TPer session numbers
+        let mut numbers = Tokens { data: payload }
+            .skip_while(|token| *token != Ok(Token::Control(START_LIST)))
+            .filter_map(|token| match token {
+                Ok(Token::Uint(number)) => Some(number as u32),
+                _ => None,
+            });
+        let (Some(_), Some(tper_session)) = (numbers.next(), numbers.next()) else {
+            return Err(OpalError::Malformed);
+        };
+        Ok(Session {
+            transport,
+            com_id,
+            tper_session,
+        })
+    }
+
+    /// Set `columns` of the table row `object`
+    fn set(&mut self, object: &Uid, columns: &[(u64, u64)]) -> Result<(), OpalError> {
+        let mut command = Command::new();
+        command
+            .call(object, &SET)
+            .control(START_NAME)
+            // Values
+            .uint(1)
+            .control(START_LIST);
+        for &(column, value) in columns {
+            command
+                .control(START_NAME)
+                .uint(column)
+                .uint(value)
+                .control(END_NAME);
+        }
+        command.control(END_LIST).control(END_NAME).end_call();
+
+        let mut response = [0u8; BUFFER_SIZE];
+        let command = command.finish(self.com_id, self.tper_session, HOST_SESSION);
+        method_status(exchange(
+            self.transport,
+            self.com_id,
+            command,
+            &mut response,
+        )?)
+    }
+
+    /// Close the session
+    fn end(self) {
+        let mut command = Command::new();
+        command.control(END_OF_SESSION);
+        let mut response = [0u8; BUFFER_SIZE];
+        let command = command.finish(self.com_id, self.tper_session, HOST_SESSION);
+        if let Err(e) = exchange(self.transport, self.com_id, command, &mut response) {
+            log::debug!("Opal: closing session failed: {:?}", e);
+        }
+    }
+}
+
+/// Unlock the global locking range with the Admin1 `credential`
+///
+/// Also marks the shadow MBR done if `discovery` says it is shown, so the
+/// host sees the real data instead of the pre-boot image.
+pub fn unlock<T: Transport>(
+    transport: &mut T,
+    discovery: &Discovery,
+    credential: &[u8],
+) -> Result<(), OpalError> {
+    let com_id = discovery.com_id.ok_or(OpalError::Malformed)?;
+    let mut session = Session::start(transport, com_id, credential)?;
+    let mut result = session.set(
+        &LOCKING_GLOBAL_RANGE,
+        &[(COLUMN_READ_LOCKED, 0), (COLUMN_WRITE_LOCKED, 0)],
+    );
+    if result.is_ok() && discovery.mbr_enabled {
+        result = session.set(&MBR_CONTROL, &[(COLUMN_MBR_DONE, 1)]);
+    }
+    session.end();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_discovery() {
+        let mut data = [0u8; 128];
+        // TPer feature, then Locking, then Opal 2 with base ComID 0x1000
+        let features: &[u8] = &[
+            0x00, 0x01, 0x10, 0x0C, 0x11, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, //
+            0x00, 0x02, 0x10, 0x0C, 0x17, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, //
+            0x02, 0x03, 0x10, 0x10, 0x10, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        data[48..48 + features.len()].copy_from_slice(features);
+        data[..4].copy_from_slice(&(44 + features.len() as u32).to_be_bytes());
+
+        let discovery = Discovery::parse(&data).unwrap();
+        assert_eq!(
+            discovery,
+            Discovery {
+                locking_enabled: true,
+                locked: true,
+                mbr_enabled: true,
+                mbr_done: false,
+                com_id: Some(0x1000),
+            }
+        );
+        assert!(discovery.needs_unlock());
+
+        // A descriptor running past the data
+        data[48 + 3] = 0xFF;
+        assert!(Discovery::parse(&data).is_none());
+    }
+
+    #[test]
+    fn encode_command() {
+        let mut command = Command::new();
+        command
+            .call(&LOCKING_GLOBAL_RANGE, &SET)
+            .uint(0x1234)
+            .bytes(&[0x55; 32])
+            .end_call();
+        let packet = command.finish(0x1000, 7, HOST_SESSION).to_vec();
+        assert_eq!(packet.len(), 512);
+        assert_eq!(&packet[4..6], &[0x10, 0x00]);
+
+        let payload = response_payload(&packet).unwrap().unwrap();
+        let tokens: std::vec::Vec<_> = Tokens { data: payload }.map(Result::unwrap).collect();
+        assert_eq!(
+            tokens[..6],
+            [
+                Token::Control(CALL),
+                Token::Bytes(&LOCKING_GLOBAL_RANGE),
+                Token::Bytes(&SET),
+                Token::Control(START_LIST),
+                Token::Uint(0x1234),
+                Token::Bytes(&[0x55; 32]),
+            ]
+        );
+        assert_eq!(method_status(payload), Ok(()));
+    }
+
+    #[test]
+    fn method_failure() {
+        let mut command = Command::new();
+        command
+            .call(&SESSION_MANAGER, &START_SESSION)
+            .control(END_LIST)
+            .control(END_OF_DATA)
+            .control(START_LIST)
+            .uint(STATUS_NOT_AUTHORIZED as u64);
+        let packet = command.finish(0x1000, 0, 0).to_vec();
+        let payload = response_payload(&packet).unwrap().unwrap();
+        assert_eq!(method_status(payload), Err(OpalError::NotAuthorized));
+    }
+}
@@ -0,0 +1,253 @@
+//! Setup password
+//!
+//! Kiosk and enterprise deployments lock down the firmware UI. With a setup
+//! password set, the setup hotkey, editing a boot entry and the recovery
+//! console ask for it first. Booting the entries of the menu stays open.
+//! Once entered, the password isn't asked again until the next boot.
+//!
+//! The password is stored as the non-volatile variable `SetupPassword`
+//! under [`CRABEFI_VARIABLE_GUID`]: a 16-byte salt, the PBKDF2 iteration
+//! count (u32) and the 32-byte PBKDF2-HMAC-SHA256 hash. The variable is
+//! private to the firmware: GetVariable and GetNextVariableName hide it and
+//! SetVariable can't write or delete it, so neither EFI applications nor
+//! the OS can read the hash or remove the lock; see
+//! [`variable_policy`](crate::efi::variable_policy).
+//! The recovery console's `password` command sets and clears it.
+//!
+//! A forgotten password is reset with a token provisioned in CBFS: the file
+//! `crabefi/password-reset` holds the SHA-256 hash of the token in hex, and
+//! entering the token at the password prompt clears the password. Only the
+//! hash is stored since the flash can be read back from the OS.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use r_efi::efi::{self, Status};
+
+use crate::arch::x86_64::rdtsc;
+use crate::coreboot::cbfs;
+use crate::crypto::sha256::Sha256;
+use crate::crypto::{Digest, pbkdf2};
+use crate::efi::runtime_services::{
+    CRABEFI_VARIABLE_GUID, read_rtc_time, read_variable, write_variable,
+};
+use crate::menu::{self, MAX_PASSPHRASE_LEN};
+use crate::time::delay_ms;
+
+/// CBFS file holding the hash of the reset token
+pub const RESET_CBFS_NAME: &str = "crabefi/password-reset";
+
+/// Variable holding the salted hash
+pub const VARIABLE: &str = "SetupPassword";
+
+/// PBKDF2 iterations of new passwords
+const ITERATIONS: u32 = 100_000;
+
+const SALT_SIZE: usize = 16;
+const HASH_SIZE: usize = 32;
+
+/// Size of the variable: salt, iterations and hash
+const RECORD_SIZE: usize = SALT_SIZE + 4 + HASH_SIZE;
+
+/// Wrong passwords accepted before the prompt gives up
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Title of the password prompt
+const TITLE: &str = "Setup Password";
+
+/// Help text of the password prompt
+const HELP: &str = "Enter to continue, Esc to cancel";
+
+/// Set once the password has been entered
+static UNLOCKED: AtomicBool = AtomicBool::new(false);
+
+/// A stored password
+struct Record {
+    salt: [u8; SALT_SIZE],
+    iterations: u32,
+    hash: [u8; HASH_SIZE],
+}
+
+impl Record {
+    fn new(password: &[u8], salt: [u8; SALT_SIZE], iterations: u32) -> Self {
+        let mut hash = [0u8; HASH_SIZE];
+        pbkdf2::<Sha256>(password, &salt, iterations, &mut hash);
+        Record {
+            salt,
+            iterations,
+            hash,
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; RECORD_SIZE] = bytes.try_into().ok()?;
+        let (salt, rest) = bytes.split_at(SALT_SIZE);
+        let (iterations, hash) = rest.split_at(4);
+        Some(Record {
+            salt: salt.try_into().ok()?,
+            iterations: u32::from_le_bytes(iterations.try_into().ok()?),
+            hash: hash.try_into().ok()?,
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[..SALT_SIZE].copy_from_slice(&self.salt);
+        bytes[SALT_SIZE..SALT_SIZE + 4].copy_from_slice(&self.iterations.to_le_bytes());
+        bytes[SALT_SIZE + 4..].copy_from_slice(&self.hash);
+        bytes
+    }
+
+    fn matches(&self, password: &[u8]) -> bool {
+        let candidate = Record::new(password, self.salt, self.iterations);
+        same(&candidate.hash, &self.hash)
+    }
+}
+
+/// Compare hashes in constant time
+fn same(a: &[u8; HASH_SIZE], b: &[u8; HASH_SIZE]) -> bool {
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Whether `token` hashes to the hex `listed` from CBFS
+fn is_reset_token(token: &[u8], listed: &str) -> bool {
+    let mut digest = Sha256::default();
+    digest.update(token);
+    let mut hash = [0u8; HASH_SIZE];
+    digest.finalize_into(&mut hash);
+
+    let listed = listed.trim().as_bytes();
+    listed.len() == HASH_SIZE * 2
+        && hash.iter().zip(listed.chunks(2)).all(|(byte, pair)| {
+            core::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                == Some(*byte)
+        })
+}
+
+/// The stored password, if one is set
+fn stored() -> Option<Record> {
+    let mut bytes = [0u8; RECORD_SIZE];
+    let size = read_variable(VARIABLE, &CRABEFI_VARIABLE_GUID, &mut bytes)?;
+    let record = Record::parse(&bytes[..size]);
+    if record.is_none() {
+        log::warn!("{} is malformed, ignoring it", VARIABLE);
+    }
+    record
+}
+
+/// A salt from the TSC and the RTC, there is no random number generator
+fn new_salt() -> [u8; SALT_SIZE] {
+    let (year, month, day, hour, minute, second) = read_rtc_time();
+    let mut digest = Sha256::default();
+    digest.update(&rdtsc().to_le_bytes());
+    digest.update(&[month, day, hour, minute, second]);
+    digest.update(&year.to_le_bytes());
+    let mut hash = [0u8; HASH_SIZE];
+    digest.finalize_into(&mut hash);
+    let mut salt = [0u8; SALT_SIZE];
+    salt.copy_from_slice(&hash[..SALT_SIZE]);
+    salt
+}
+
+/// Set the setup password, or clear it with an empty one
+pub fn set(password: &[u8]) -> Status {
+    let attributes = efi::VARIABLE_NON_VOLATILE | efi::VARIABLE_BOOTSERVICE_ACCESS;
+    let status = if password.is_empty() {
+        write_variable(VARIABLE, &CRABEFI_VARIABLE_GUID, attributes, &[])
+    } else {
+        let record = Record::new(password, new_salt(), ITERATIONS);
+        write_variable(
+            VARIABLE,
+            &CRABEFI_VARIABLE_GUID,
+            attributes,
+            &record.to_bytes(),
+        )
+    };
+    if status == Status::SUCCESS {
+        log::info!(
+            "Setup password {}",
+            if password.is_empty() {
+                "cleared"
+            } else {
+                "set"
+            }
+        );
+    }
+    status
+}
+
+/// Ask for the setup password before `action`
+///
+/// Returns true right away if no password is set or it was entered before
+/// during this boot, and false if the user gives up or runs out of tries.
+pub fn authorize(action: &str) -> bool {
+    if UNLOCKED.load(Ordering::Relaxed) {
+        return true;
+    }
+    let Some(record) = stored() else {
+        return true;
+    };
+    let reset_token = cbfs::find_file(RESET_CBFS_NAME).and_then(|t| core::str::from_utf8(t).ok());
+
+    let mut prompt: heapless::String<64> = heapless::String::new();
+    let _ = core::fmt::write(&mut prompt, format_args!("Password for {}:", action));
+    let mut password = [0u8; MAX_PASSPHRASE_LEN];
+    let mut status = None;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let Some(len) = menu::read_secret(TITLE, HELP, &prompt, status, &mut password) else {
+            return false;
+        };
+        let entered = &password[..len];
+
+        let unlocked = if record.matches(entered) {
+            true
+        } else if reset_token.is_some_and(|token| is_reset_token(entered, token)) {
+            log::warn!("Setup password reset with the CBFS token");
+            set(&[]) == Status::SUCCESS
+        } else {
+            false
+        };
+        password.fill(0);
+        if unlocked {
+            UNLOCKED.store(true, Ordering::Relaxed);
+            return true;
+        }
+
+        log::warn!("Wrong setup password for {}", action);
+        status = Some("Wrong password");
+        // Slow down guessing
+        delay_ms(1000 * attempt as u64);
+    }
+    false
+}
+
+/// Remember that the password was entered, e.g. after setting it
+pub fn unlock() {
+    UNLOCKED.store(true, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_passwords() {
+        let record = Record::new(b"hunter2", [7; SALT_SIZE], 2);
+        let record = Record::parse(&record.to_bytes()).unwrap();
+        assert_eq!(record.iterations, 2);
+        assert!(record.matches(b"hunter2"));
+        assert!(!record.matches(b"hunter3"));
+        assert!(!record.matches(b""));
+        assert!(Record::parse(&[0; RECORD_SIZE - 1]).is_none());
+    }
+
+    #[test]
+    fn checks_reset_token() {
+        // sha256("abc")
+        let listed = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD\n";
+        assert!(is_reset_token(b"abc", listed));
+        assert!(!is_reset_token(b"abd", listed));
+        assert!(!is_reset_token(b"abc", ""));
+    }
+}
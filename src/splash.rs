@@ -0,0 +1,318 @@
+//! Boot logo
+//!
+//! With a bitmap in the CBFS file `crabefi/logo.bmp`, the screen is cleared
+//! to black and the logo drawn right before a boot loader starts. The logo
+//! is centered horizontally, with its center 38.2% down the screen where
+//! Windows draws its progress indicator below it.
+//!
+//! The BGRT ACPI table tells the OS where the logo is, so Linux and Windows
+//! can keep it on screen while they take over the display. The table is
+//! added to the root table at EFI initialization and marked as displayed
+//! once the logo is drawn. It points at a copy of the bitmap in boot
+//! services data, which the OS reclaims after reading it.
+//!
+//! Without a logo, the `.splash` bitmap of a Unified Kernel Image is drawn
+//! when its kernel starts, as systemd-stub does; it gets no BGRT.
+//!
+//! Only uncompressed 24 and 32 bit bitmaps are supported, the formats
+//! operating systems expect in the BGRT:
+//!
+//! ```text
+//! convert logo.png -type TrueColor BMP3:logo.bmp
+//! cbfstool coreboot.rom add -f logo.bmp -n crabefi/logo.bmp -t raw
+//! ```
+
+use r_efi::efi;
+use spin::Mutex;
+
+use crate::coreboot::{self, FramebufferInfo, cbfs};
+use crate::efi::allocator::{self, AllocateType, MemoryType, PAGE_SIZE};
+use crate::fb_shadow::{self, DirtyRect};
+
+/// CBFS file holding the logo
+pub const CBFS_NAME: &str = "crabefi/logo.bmp";
+
+/// Size of the BGRT
+const BGRT_LENGTH: usize = 56;
+
+/// BGRT status: the logo is on screen
+const BGRT_DISPLAYED: u8 = 1 << 0;
+
+/// Offset of the status byte in the BGRT
+const BGRT_STATUS: usize = 38;
+
+/// An uncompressed bitmap
+struct Bitmap<'a> {
+    width: u32,
+    height: u32,
+    /// Bytes per pixel, 3 or 4
+    bytes_per_pixel: usize,
+    /// Whether the first row is the top one, instead of the bottom one
+    top_down: bool,
+    /// Bytes per row, padded to 4 bytes
+    stride: usize,
+    pixels: &'a [u8],
+}
+
+impl<'a> Bitmap<'a> {
+    /// Parse a BMP file
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        let u16_at = |offset: usize| {
+            Some(u16::from_le_bytes(
+                data.get(offset..offset + 2)?.try_into().ok()?,
+            ))
+        };
+        let u32_at = |offset: usize| {
+            Some(u32::from_le_bytes(
+                data.get(offset..offset + 4)?.try_into().ok()?,
+            ))
+        };
+
+        if data.get(..2)? != b"BM" || u32_at(14)? < 40 {
+            return None;
+        }
+        let pixel_offset = u32_at(10)? as usize;
+        let width = u32_at(18)? as i32;
+        let height = u32_at(22)? as i32;
+        let bytes_per_pixel = match (u16_at(28)?, u32_at(30)?) {
+            (24, 0) => 3,
+            (32, 0) => 4,
+            _ => return None,
+        };
+        if width <= 0 || height == 0 {
+            return None;
+        }
+
+        let top_down = height < 0;
+        let (width, height) = (width as u32, height.unsigned_abs());
+        let stride = (width as usize * bytes_per_pixel).next_multiple_of(4);
+        let pixels = data.get(pixel_offset..pixel_offset + stride * height as usize)?;
+        Some(Bitmap {
+            width,
+            height,
+            bytes_per_pixel,
+            top_down,
+            stride,
+            pixels,
+        })
+    }
+
+    /// Red, green and blue of the pixel at (x, y), counted from the top left
+    fn pixel(&self, x: u32, y: u32) -> (u8, u8, u8) {
+        let row = if self.top_down {
+            y
+        } else {
+            self.height - 1 - y
+        };
+        let offset = row as usize * self.stride + x as usize * self.bytes_per_pixel;
+        let bgr = &self.pixels[offset..offset + 3];
+        (bgr[2], bgr[1], bgr[0])
+    }
+}
+
+/// Build a BGRT for the bitmap at `image`, drawn at (`x`, `y`)
+fn bgrt(image: u64, x: u32, y: u32) -> [u8; BGRT_LENGTH] {
+    let mut table = [0u8; BGRT_LENGTH];
+    table[0..4].copy_from_slice(b"BGRT");
+    table[4..8].copy_from_slice(&(BGRT_LENGTH as u32).to_le_bytes());
+    table[8] = 1;
+    table[10..16].copy_from_slice(b"CRABEF");
+    table[16..24].copy_from_slice(b"CRABEFI ");
+    table[24..28].copy_from_slice(&1u32.to_le_bytes());
+    table[28..32].copy_from_slice(b"CRAB");
+    table[32..36].copy_from_slice(&1u32.to_le_bytes());
+    // Version 1, status, image type 0 (bitmap)
+    table[36..38].copy_from_slice(&1u16.to_le_bytes());
+    table[40..48].copy_from_slice(&image.to_le_bytes());
+    table[48..52].copy_from_slice(&x.to_le_bytes());
+    table[52..56].copy_from_slice(&y.to_le_bytes());
+    crate::acpi::set_checksum(&mut table, 9);
+    table
+}
+
+/// The logo, ready to be drawn
+struct Splash {
+    /// Copy of the bitmap in boot services data
+    image: u64,
+    /// Size of the bitmap
+    size: usize,
+    /// Position of the top left corner on screen
+    x: u32,
+    y: u32,
+    /// The BGRT in ACPI reclaim memory
+    bgrt: u64,
+}
+
+/// The logo, if there is one
+static SPLASH: Mutex<Option<Splash>> = Mutex::new(None);
+
+/// Allocate pages of `memory_type` below 4 GiB, as the BGRT needs
+fn allocate_low(memory_type: MemoryType, size: usize) -> Option<u64> {
+    let mut address = u32::MAX as u64;
+    let status = allocator::allocate_pages(
+        AllocateType::AllocateMaxAddress,
+        memory_type,
+        (size as u64).div_ceil(PAGE_SIZE),
+        &mut address,
+    );
+    (status == efi::Status::SUCCESS).then_some(address)
+}
+
+/// Load the logo from CBFS and add its BGRT to the ACPI tables
+pub fn init(fb: Option<&FramebufferInfo>, acpi_rsdp: Option<u64>) {
+    let Some(data) = cbfs::find_file(CBFS_NAME) else {
+        return;
+    };
+    let (Some(fb), Some(rsdp)) = (fb, acpi_rsdp) else {
+        log::debug!("Splash: no framebuffer or ACPI tables for the logo");
+        return;
+    };
+    let Some(bitmap) = Bitmap::parse(data) else {
+        log::warn!(
+            "Splash: {} is not an uncompressed 24 or 32 bit bitmap",
+            CBFS_NAME
+        );
+        return;
+    };
+    if bitmap.width > fb.x_resolution || bitmap.height > fb.y_resolution {
+        log::warn!(
+            "Splash: {}x{} logo doesn't fit on the screen",
+            bitmap.width,
+            bitmap.height
+        );
+        return;
+    }
+    let x = (fb.x_resolution - bitmap.width) / 2;
+    let y = (fb.y_resolution * 382 / 1000).saturating_sub(bitmap.height / 2);
+    let y = y.min(fb.y_resolution - bitmap.height);
+
+    let Some(image) = allocate_low(MemoryType::BootServicesData, data.len()) else {
+        log::warn!("Splash: no memory for the logo");
+        return;
+    };
+    let Some(table) = allocate_low(MemoryType::AcpiReclaimMemory, BGRT_LENGTH) else {
+        let _ = allocator::free_pages(image, (data.len() as u64).div_ceil(PAGE_SIZE));
+        log::warn!("Splash: no memory for the BGRT");
+        return;
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(data.as_ptr(), image as *mut u8, data.len());
+        core::ptr::write(table as *mut [u8; BGRT_LENGTH], bgrt(image, x, y));
+    }
+
+    let status = unsafe { crate::acpi::add_table(rsdp, table) };
+    if status != efi::Status::SUCCESS {
+        log::warn!("Splash: failed to add the BGRT: {:?}", status);
+    }
+
+    log::info!(
+        "Splash: {}x{} logo at ({}, {})",
+        bitmap.width,
+        bitmap.height,
+        x,
+        y
+    );
+    *SPLASH.lock() = Some(Splash {
+        image,
+        size: data.len(),
+        x,
+        y,
+        bgrt: table,
+    });
+}
+
+/// Draw the logo on a black screen and mark it displayed in the BGRT
+pub fn show() {
+    let guard = SPLASH.lock();
+    let Some(splash) = guard.as_ref() else {
+        return;
+    };
+    let data = unsafe { core::slice::from_raw_parts(splash.image as *const u8, splash.size) };
+    let Some(bitmap) = Bitmap::parse(data) else {
+        return;
+    };
+    if !draw(&bitmap, splash.x, splash.y) {
+        return;
+    }
+
+    let table = unsafe { &mut *(splash.bgrt as *mut [u8; BGRT_LENGTH]) };
+    table[BGRT_STATUS] |= BGRT_DISPLAYED;
+    crate::acpi::set_checksum(table, 9);
+}
+
+/// Draw a boot loader's own bitmap, such as a UKI's `.splash`, centered
+///
+/// The firmware logo, if there is one, takes precedence: the BGRT points
+/// at it.
+pub fn show_image(data: &[u8]) {
+    if SPLASH.lock().is_some() {
+        return;
+    }
+    let (Some(bitmap), Some(fb)) = (Bitmap::parse(data), coreboot::get_framebuffer()) else {
+        return;
+    };
+    if bitmap.width > fb.x_resolution || bitmap.height > fb.y_resolution {
+        return;
+    }
+    let x = (fb.x_resolution - bitmap.width) / 2;
+    let y = (fb.y_resolution - bitmap.height) / 2;
+    draw(&bitmap, x, y);
+}
+
+/// Clear the screen to black and draw `bitmap` at (`x`, `y`)
+///
+/// Returns `false` without a framebuffer.
+fn draw(bitmap: &Bitmap<'_>, x: u32, y: u32) -> bool {
+    let Some(vram) = coreboot::get_framebuffer() else {
+        return false;
+    };
+    let fb = fb_shadow::framebuffer(&vram);
+    unsafe {
+        fb.clear(0, 0, 0);
+        for row in 0..bitmap.height {
+            for column in 0..bitmap.width {
+                let (r, g, b) = bitmap.pixel(column, row);
+                fb.write_pixel(x + column, y + row, r, g, b);
+            }
+        }
+    }
+    let mut dirty = DirtyRect::new();
+    dirty.add(0, 0, fb.x_resolution, fb.y_resolution);
+    fb_shadow::flush(&fb, dirty);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bottom_up_bitmap() {
+        // 2x2, 24 bit: rows padded to 8 bytes, the bottom row first
+        let mut bmp = std::vec![0u8; 54];
+        bmp[..2].copy_from_slice(b"BM");
+        bmp[10..14].copy_from_slice(&54u32.to_le_bytes());
+        bmp[14..18].copy_from_slice(&40u32.to_le_bytes());
+        bmp[18..22].copy_from_slice(&2u32.to_le_bytes());
+        bmp[22..26].copy_from_slice(&2u32.to_le_bytes());
+        bmp[26..28].copy_from_slice(&1u16.to_le_bytes());
+        bmp[28..30].copy_from_slice(&24u16.to_le_bytes());
+        bmp.extend([0, 0, 255, 0, 255, 0, 0, 0]);
+        bmp.extend([255, 0, 0, 255, 255, 255, 0, 0]);
+
+        let bitmap = Bitmap::parse(&bmp).unwrap();
+        assert_eq!((bitmap.width, bitmap.height), (2, 2));
+        assert_eq!(bitmap.pixel(0, 0), (0, 0, 255));
+        assert_eq!(bitmap.pixel(1, 0), (255, 255, 255));
+        assert_eq!(bitmap.pixel(0, 1), (255, 0, 0));
+        assert_eq!(bitmap.pixel(1, 1), (0, 255, 0));
+
+        assert!(Bitmap::parse(&bmp[..60]).is_none());
+        bmp[28] = 8;
+        assert!(Bitmap::parse(&bmp).is_none());
+
+        let table = bgrt(0x1000, 10, 20);
+        assert_eq!(table.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)), 0);
+        assert_eq!(table[BGRT_STATUS], 0);
+    }
+}
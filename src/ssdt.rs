@@ -0,0 +1,211 @@
+//! Extra SSDTs
+//!
+//! Device quirks such as a missing battery or touchpad node can be fixed
+//! with an SSDT instead of a coreboot rebuild. CrabEFI adds SSDTs to the
+//! ACPI tables coreboot built from two places:
+//!
+//! - CBFS files named `crabefi/ssdt/*`, at EFI initialization
+//! - `*.aml` files in `\EFI\crabefi\ssdt\` on the ESP of the first boot
+//!   loader started, right before it runs. With a
+//!   [hash list](crate::verity), they must be listed like every other file
+//!   read from the ESP.
+//!
+//! Each file holds one compiled table, as `iasl` writes it:
+//!
+//! ```text
+//! iasl battery.asl
+//! cbfstool coreboot.rom add -f battery.aml -n crabefi/ssdt/battery.aml -t raw
+//! ```
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use r_efi::efi;
+
+use crate::coreboot::cbfs;
+use crate::efi::allocator::{self, AllocateType, MemoryType, PAGE_SIZE};
+use crate::fs::fat::FatFilesystem;
+
+/// Prefix of the CBFS files holding SSDTs
+pub const CBFS_PREFIX: &str = "crabefi/ssdt/";
+
+/// ESP directory holding SSDTs
+pub const ESP_DIRECTORY: &str = "EFI\\crabefi\\ssdt";
+
+/// Size of the ACPI table header
+const HEADER_SIZE: usize = 36;
+
+/// Largest SSDT loaded
+const MAX_SSDT_SIZE: usize = 1024 * 1024;
+
+/// Most SSDTs loaded from the ESP
+const MAX_ESP_SSDTS: usize = 16;
+
+/// RSDP the SSDTs are added to, 0 without ACPI
+static RSDP: AtomicU64 = AtomicU64::new(0);
+
+/// Set once the ESP was searched, so SSDTs aren't added twice when a boot
+/// loader returns and another one starts
+static ESP_SEARCHED: AtomicBool = AtomicBool::new(false);
+
+/// Why a file isn't used as an SSDT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SsdtError {
+    /// Too short, or shorter than its header says
+    Truncated,
+    /// Not an SSDT
+    Signature,
+    /// The bytes don't sum to zero
+    Checksum,
+}
+
+/// Check that `data` starts with a complete SSDT, returning its length
+fn check(data: &[u8]) -> Result<usize, SsdtError> {
+    let header = data.get(..HEADER_SIZE).ok_or(SsdtError::Truncated)?;
+    if &header[..4] != b"SSDT" {
+        return Err(SsdtError::Signature);
+    }
+    let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let table = data
+        .get(..length)
+        .filter(|_| length >= HEADER_SIZE)
+        .ok_or(SsdtError::Truncated)?;
+    if table.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+        return Err(SsdtError::Checksum);
+    }
+    Ok(length)
+}
+
+/// Allocate ACPI reclaim memory below 4 GiB, where the RSDT can list it
+fn allocate(size: usize) -> Option<u64> {
+    let mut address = u32::MAX as u64;
+    let status = allocator::allocate_pages(
+        AllocateType::AllocateMaxAddress,
+        MemoryType::AcpiReclaimMemory,
+        (size as u64).div_ceil(PAGE_SIZE),
+        &mut address,
+    );
+    (status == efi::Status::SUCCESS).then_some(address)
+}
+
+/// Check the table in ACPI memory at `table` and add it to the root table
+fn add(name: &str, table: u64, size: usize) -> bool {
+    let data = unsafe { core::slice::from_raw_parts(table as *const u8, size) };
+    if let Err(e) = check(data) {
+        log::warn!("SSDT: ignoring {}: {:?}", name, e);
+        return false;
+    }
+    let status = unsafe { crate::acpi::add_table(RSDP.load(Ordering::Relaxed), table) };
+    if status != efi::Status::SUCCESS {
+        log::warn!("SSDT: failed to add {}: {:?}", name, status);
+        return false;
+    }
+    log::info!("SSDT: added {}", name);
+    true
+}
+
+/// Add the SSDTs from CBFS
+pub fn init(acpi_rsdp: Option<u64>) {
+    let Some(rsdp) = acpi_rsdp else {
+        return;
+    };
+    RSDP.store(rsdp, Ordering::Relaxed);
+
+    for (name, data) in cbfs::find_files(CBFS_PREFIX) {
+        if data.len() > MAX_SSDT_SIZE {
+            log::warn!("SSDT: {} is too large", name);
+            continue;
+        }
+        let Some(table) = allocate(data.len()) else {
+            log::warn!("SSDT: no memory for {}", name);
+            return;
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), table as *mut u8, data.len());
+        }
+        if !add(name, table, data.len()) {
+            let _ = allocator::free_pages(table, (data.len() as u64).div_ceil(PAGE_SIZE));
+        }
+    }
+}
+
+/// Add the SSDTs from the ESP `fat`, the first time a boot loader starts
+pub fn load_from_esp(fat: &mut FatFilesystem<'_>) {
+    if RSDP.load(Ordering::Relaxed) == 0 || ESP_SEARCHED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let Ok(directory) = fat.find_file(ESP_DIRECTORY) else {
+        return;
+    };
+    if !directory.is_directory() {
+        return;
+    }
+
+    // Collect the names first, reading the files needs the filesystem
+    let mut files: heapless::Vec<(heapless::String<12>, u32), MAX_ESP_SSDTS> = heapless::Vec::new();
+    let cluster = directory.first_cluster();
+    for position in 0.. {
+        let Ok(Some(entry)) = fat.get_directory_entry_at_position(cluster, position) else {
+            break;
+        };
+        let name = entry.short_name();
+        let is_aml = name
+            .rsplit_once('.')
+            .is_some_and(|(_, ext)| ext.eq_ignore_ascii_case("AML"));
+        if entry.is_file() && is_aml && files.push((name, entry.file_size())).is_err() {
+            log::warn!(
+                "SSDT: more than {} files in {}",
+                MAX_ESP_SSDTS,
+                ESP_DIRECTORY
+            );
+            break;
+        }
+    }
+
+    for (name, size) in files {
+        let mut path: heapless::String<64> = heapless::String::new();
+        let _ = write!(path, "{}\\{}", ESP_DIRECTORY, name);
+        let size = size as usize;
+        if size > MAX_SSDT_SIZE {
+            log::warn!("SSDT: {} is too large", path);
+            continue;
+        }
+        let Some(table) = allocate(size) else {
+            log::warn!("SSDT: no memory for {}", path);
+            return;
+        };
+        let buffer = unsafe { core::slice::from_raw_parts_mut(table as *mut u8, size) };
+        let loaded = fat
+            .read_file_all(&path, buffer, None)
+            .is_ok_and(|read| read == size)
+            && crate::verity::check(&path, buffer).is_ok()
+            && add(&path, table, size);
+        if !loaded {
+            let _ = allocator::free_pages(table, (size as u64).div_ceil(PAGE_SIZE));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_tables() {
+        let mut table = std::vec![0u8; 40];
+        table[..4].copy_from_slice(b"SSDT");
+        table[4..8].copy_from_slice(&40u32.to_le_bytes());
+        crate::acpi::set_checksum(&mut table, 9);
+        assert_eq!(check(&table), Ok(40));
+
+        // Trailing bytes after the table are fine
+        table.push(0xff);
+        assert_eq!(check(&table), Ok(40));
+        assert_eq!(check(&table[..39]), Err(SsdtError::Truncated));
+
+        table[20] ^= 1;
+        assert_eq!(check(&table), Err(SsdtError::Checksum));
+        table[..4].copy_from_slice(b"DSDT");
+        assert_eq!(check(&table), Err(SsdtError::Signature));
+    }
+}
@@ -19,7 +19,7 @@
 //! FirmwareState on stack
 //!   |
 //!   +-- efi: EfiState
-//!   |     +-- handles, events, loaded_images
+//!   |     +-- events, loaded_images
 //!   |     +-- config_tables, variables
 //!   |     +-- allocator
 //!   |
@@ -32,11 +32,16 @@
 //!         +-- input state
 //! ```
 //!
+//! The handle database is not part of `FirmwareState`: it grows through the
+//! pool allocator, which lives in `EfiState`, so it has its own cell in
+//! `efi::handles`.
+//!
 //! # Thread Safety
 //!
 //! CrabEFI is single-threaded firmware. We use `UnsafeCell` for interior
 //! mutability without the overhead of `Mutex`. The UEFI spec guarantees
-//! that Boot Services are not reentrant.
+//! that Boot Services are not reentrant. Parallel device init on the APs
+//! keeps this true by running one task at a time (see `parallel`).
 
 use core::sync::atomic::{AtomicPtr, Ordering};
 
@@ -105,7 +110,7 @@ pub fn get_mut_ptr() -> *mut FirmwareState {
 ///
 /// ```ignore
 /// state::with_mut(|state| {
-///     state.efi.handle_count += 1;
+///     state.efi.config_table_count += 1;
 /// });
 /// ```
 #[inline]
@@ -151,7 +156,7 @@ pub fn try_get_mut_ptr() -> Option<*mut FirmwareState> {
 /// This struct holds all mutable state for the firmware, organized into
 /// logical subsystems.
 pub struct FirmwareState {
-    /// EFI subsystem state (handles, events, allocator, etc.)
+    /// EFI subsystem state (events, allocator, etc.)
     pub efi: EfiState,
 
     /// Hardware driver state
@@ -186,14 +191,9 @@ impl Default for FirmwareState {
 // ============================================================================
 
 use crate::efi::allocator::MemoryAllocator;
+use crate::time::Timeout;
 use r_efi::efi::{self, Guid, Handle};
 
-/// Maximum number of handles we can track
-pub const MAX_HANDLES: usize = 64;
-
-/// Maximum number of protocols per handle
-pub const MAX_PROTOCOLS_PER_HANDLE: usize = 8;
-
 /// Maximum number of events we can track
 pub const MAX_EVENTS: usize = 32;
 
@@ -212,55 +212,6 @@ pub const MAX_VARIABLE_NAME_LEN: usize = 64;
 /// Maximum variable data size
 pub const MAX_VARIABLE_DATA_SIZE: usize = 1024;
 
-/// Protocol interface entry
-#[derive(Clone, Copy)]
-pub struct ProtocolEntry {
-    pub guid: Guid,
-    pub interface: *mut core::ffi::c_void,
-}
-
-// SAFETY: ProtocolEntry contains raw pointers to protocol interfaces.
-// These pointers are:
-// 1. Only dereferenced while holding the global HANDLES lock
-// 2. Point to memory allocated via the EFI allocator which remains valid
-//    for the lifetime of the firmware
-// 3. CrabEFI runs single-threaded with interrupts disabled during protocol calls
-unsafe impl Send for ProtocolEntry {}
-unsafe impl Sync for ProtocolEntry {}
-
-impl ProtocolEntry {
-    pub const fn empty() -> Self {
-        Self {
-            guid: Guid::from_fields(0, 0, 0, 0, 0, &[0, 0, 0, 0, 0, 0]),
-            interface: core::ptr::null_mut(),
-        }
-    }
-}
-
-/// Handle entry in the handle database
-pub struct HandleEntry {
-    pub handle: Handle,
-    pub protocols: [ProtocolEntry; MAX_PROTOCOLS_PER_HANDLE],
-    pub protocol_count: usize,
-}
-
-// SAFETY: HandleEntry contains EFI Handle (raw pointer) and ProtocolEntry array.
-// Handles are opaque identifiers that remain valid until explicitly closed.
-// All access is protected by the global HANDLES mutex, and the firmware
-// is single-threaded with no concurrent access to handle data.
-unsafe impl Send for HandleEntry {}
-unsafe impl Sync for HandleEntry {}
-
-impl HandleEntry {
-    pub const fn empty() -> Self {
-        Self {
-            handle: core::ptr::null_mut(),
-            protocols: [ProtocolEntry::empty(); MAX_PROTOCOLS_PER_HANDLE],
-            protocol_count: 0,
-        }
-    }
-}
-
 /// Event entry for tracking created events
 #[derive(Clone, Copy)]
 pub struct EventEntry {
@@ -268,7 +219,25 @@ pub struct EventEntry {
     pub notify_tpl: efi::Tpl,
     pub signaled: bool,
     pub is_keyboard_event: bool,
-}
+    /// Whether the slot holds an event
+    pub in_use: bool,
+    /// Notification function, for `EVT_NOTIFY_SIGNAL`/`EVT_NOTIFY_WAIT`
+    pub notify_function: Option<efi::EventNotify>,
+    /// Context passed to the notification function
+    pub notify_context: *mut core::ffi::c_void,
+    /// Event group the event belongs to
+    pub group: Option<Guid>,
+    /// Expiry of an armed timer
+    pub deadline: Option<Timeout>,
+    /// Period of a periodic timer in microseconds
+    pub period_us: Option<u64>,
+}
+
+// SAFETY: the notify context is an opaque pointer owned by the EFI
+// application that created the event; the firmware only passes it back to
+// the notification function, from the single firmware thread.
+unsafe impl Send for EventEntry {}
+unsafe impl Sync for EventEntry {}
 
 impl EventEntry {
     pub const fn empty() -> Self {
@@ -277,6 +246,12 @@ impl EventEntry {
             notify_tpl: 0,
             signaled: false,
             is_keyboard_event: false,
+            in_use: false,
+            notify_function: None,
+            notify_context: core::ptr::null_mut(),
+            group: None,
+            deadline: None,
+            period_us: None,
         }
     }
 }
@@ -352,6 +327,8 @@ pub struct VariableEntry {
     pub attributes: u32,
     pub data: [u8; MAX_VARIABLE_DATA_SIZE],
     pub data_size: usize,
+    /// Timestamp of the last time-based authenticated write (`EFI_TIME`)
+    pub timestamp: [u8; 16],
     pub in_use: bool,
 }
 
@@ -363,6 +340,7 @@ impl VariableEntry {
             attributes: 0,
             data: [0; MAX_VARIABLE_DATA_SIZE],
             data_size: 0,
+            timestamp: [0; 16],
             in_use: false,
         }
     }
@@ -370,17 +348,8 @@ impl VariableEntry {
 
 /// EFI subsystem state
 pub struct EfiState {
-    /// Handle database
-    pub handles: [HandleEntry; MAX_HANDLES],
-    /// Number of active handles
-    pub handle_count: usize,
-    /// Next handle value (unique identifier)
-    pub next_handle: usize,
-
-    /// Event database
+    /// Event database, indexed by event ID (1 is reserved for keyboard)
     pub events: [EventEntry; MAX_EVENTS],
-    /// Next event ID (starting at 2, 1 is reserved for keyboard)
-    pub next_event_id: usize,
 
     /// Loaded images database
     pub loaded_images: [LoadedImageEntry; MAX_LOADED_IMAGES],
@@ -406,11 +375,7 @@ pub struct EfiState {
 impl EfiState {
     pub const fn new() -> Self {
         Self {
-            handles: [const { HandleEntry::empty() }; MAX_HANDLES],
-            handle_count: 0,
-            next_handle: 1,
             events: [const { EventEntry::empty() }; MAX_EVENTS],
-            next_event_id: 2, // Start at 2, reserve 1 for keyboard
             loaded_images: [const { LoadedImageEntry::empty() }; MAX_LOADED_IMAGES],
             config_tables: [ConfigurationTable::empty(); MAX_CONFIG_TABLES],
             config_table_count: 0,
@@ -434,10 +399,17 @@ impl Default for EfiState {
 
 use crate::coreboot::FramebufferInfo;
 use crate::drivers::pci::PciDevice;
-use heapless::Vec as HeaplessVec;
+use crate::drivers::serial_keys::KeyDecoder;
+use heapless::{Deque as HeaplessDeque, Vec as HeaplessVec};
+use r_efi::protocols::simple_text_input_ex::KeyData;
 
 /// Maximum number of PCI devices
-pub const MAX_PCI_DEVICES: usize = 64;
+///
+/// Every Thunderbolt dock or enclosure adds a switch with a bridge per port.
+pub const MAX_PCI_DEVICES: usize = 128;
+
+/// Maximum number of processors, BSP included
+pub const MAX_PROCESSORS: usize = 64;
 
 /// Maximum number of storage controllers
 pub const MAX_STORAGE_CONTROLLERS: usize = 4;
@@ -569,19 +541,17 @@ impl Default for ConsoleState {
     }
 }
 
-/// Maximum size of the escape sequence buffer
-pub const ESCAPE_BUF_SIZE: usize = 8;
+/// Keys the input queue holds
+pub const INPUT_QUEUE_SIZE: usize = 32;
 
-/// Input state for escape sequence parsing
+/// Console input state
 pub struct InputState {
-    /// Buffer for escape sequence bytes
-    pub escape_buf: [u8; ESCAPE_BUF_SIZE],
-    /// Number of bytes in the escape buffer
-    pub escape_len: usize,
-    /// Whether we're currently in an escape sequence
-    pub in_escape: bool,
-    /// Queued key to return (scan_code, unicode_char)
-    pub queued_key: Option<(u16, u16)>,
+    /// Keys typed but not read yet, from all input sources
+    pub keys: HeaplessDeque<KeyData, INPUT_QUEUE_SIZE>,
+    /// Decoder of the serial input
+    pub decoder: KeyDecoder,
+    /// When to give up on the escape sequence being received
+    pub escape_timeout: Option<Timeout>,
 }
 
 impl Default for InputState {
@@ -593,10 +563,9 @@ impl Default for InputState {
 impl InputState {
     pub const fn new() -> Self {
         Self {
-            escape_buf: [0; ESCAPE_BUF_SIZE],
-            escape_len: 0,
-            in_escape: false,
-            queued_key: None,
+            keys: HeaplessDeque::new(),
+            decoder: KeyDecoder::new(),
+            escape_timeout: None,
         }
     }
 }
@@ -0,0 +1,1172 @@
+//! Fixtures for host-side unit tests
+//!
+//! Disk images are generated in memory rather than checked in, so every test
+//! spells out the layout it depends on. The builders follow what the usual
+//! tools produce (mkfs.fat, parted, xorriso, mksquashfs) closely enough for
+//! the parsers, but only fill in the fields CrabEFI reads plus the obvious
+//! signatures.
+
+pub use crate::drivers::block::MemoryDisk;
+use crate::fs::fat::{FatType, SECTOR_SIZE};
+use std::collections::BTreeMap;
+
+/// Write a little-endian u16 at `offset`
+pub fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Write a little-endian u32 at `offset`
+pub fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Write a little-endian u64 at `offset`
+pub fn put_u64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Recognisable file contents: `len` bytes of a pattern seeded by `seed`
+pub fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed) ^ (i >> 8) as u8)
+        .collect()
+}
+
+// ============================================================================
+// FAT
+// ============================================================================
+
+/// Directory entry attributes
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LFN: u8 = 0x0F;
+
+/// Geometry of a generated FAT image
+struct FatGeometry {
+    sectors_per_cluster: u8,
+    total_sectors: u32,
+    reserved_sectors: u16,
+    root_entry_count: u16,
+}
+
+impl FatGeometry {
+    /// Smallest sensible volume of each type (the type follows from the cluster count)
+    fn for_type(fat_type: FatType) -> Self {
+        match fat_type {
+            // 1 MiB, ~2000 clusters
+            FatType::Fat12 => Self {
+                sectors_per_cluster: 1,
+                total_sectors: 2048,
+                reserved_sectors: 1,
+                root_entry_count: 224,
+            },
+            // 16 MiB, ~8150 clusters of 2 KiB
+            FatType::Fat16 => Self {
+                sectors_per_cluster: 4,
+                total_sectors: 32768,
+                reserved_sectors: 4,
+                root_entry_count: 512,
+            },
+            // 34 MiB, ~67000 clusters
+            FatType::Fat32 => Self {
+                sectors_per_cluster: 1,
+                total_sectors: 68000,
+                reserved_sectors: 32,
+                root_entry_count: 0,
+            },
+        }
+    }
+}
+
+/// A directory or file in a generated FAT image
+enum FatNode<'a> {
+    Dir(&'a str),
+    File(&'a str, &'a [u8]),
+}
+
+/// Build a FAT image of the given type containing `files`
+///
+/// Paths use `/` and every component must be a valid 8.3 name; components
+/// with lowercase letters get a long file name entry, as real tools write.
+/// Directories are created as needed, files are stored in contiguous cluster
+/// chains in the order given.
+pub fn fat_image(fat_type: FatType, files: &[(&str, &[u8])]) -> Vec<u8> {
+    let geometry = FatGeometry::for_type(fat_type);
+    let bits = match fat_type {
+        FatType::Fat12 => 12,
+        FatType::Fat16 => 16,
+        FatType::Fat32 => 32,
+    };
+    let end_of_chain = match fat_type {
+        FatType::Fat12 => 0xFFF,
+        FatType::Fat16 => 0xFFFF,
+        FatType::Fat32 => 0x0FFF_FFFF,
+    };
+
+    let spc = geometry.sectors_per_cluster as u32;
+    let max_clusters = geometry.total_sectors / spc + 2;
+    let sectors_per_fat = (max_clusters * bits).div_ceil(8 * SECTOR_SIZE as u32);
+    let root_dir_sectors = (geometry.root_entry_count as u32 * 32).div_ceil(SECTOR_SIZE as u32);
+    let fat_start = geometry.reserved_sectors as u32;
+    let root_dir_start = fat_start + 2 * sectors_per_fat;
+    let data_start = root_dir_start + root_dir_sectors;
+    let cluster_size = (spc as usize) * SECTOR_SIZE;
+    let cluster_offset = |cluster: u32| (data_start + (cluster - 2) * spc) as usize * SECTOR_SIZE;
+
+    let mut image = vec![0u8; geometry.total_sectors as usize * SECTOR_SIZE];
+
+    // Boot sector
+    image[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    image[3..11].copy_from_slice(b"CRABEFI ");
+    put_u16(&mut image, 11, SECTOR_SIZE as u16);
+    image[13] = geometry.sectors_per_cluster;
+    put_u16(&mut image, 14, geometry.reserved_sectors);
+    image[16] = 2;
+    put_u16(&mut image, 17, geometry.root_entry_count);
+    if geometry.total_sectors < 0x10000 {
+        put_u16(&mut image, 19, geometry.total_sectors as u16);
+    } else {
+        put_u32(&mut image, 32, geometry.total_sectors);
+    }
+    image[21] = 0xF8;
+    put_u16(&mut image, 24, 32);
+    put_u16(&mut image, 26, 64);
+    if fat_type == FatType::Fat32 {
+        put_u32(&mut image, 36, sectors_per_fat);
+        put_u32(&mut image, 44, 2);
+        put_u16(&mut image, 48, 1);
+        put_u16(&mut image, 50, 6);
+        image[64] = 0x80;
+        image[66] = 0x29;
+        image[71..82].copy_from_slice(b"CRABEFI    ");
+        image[82..90].copy_from_slice(b"FAT32   ");
+    } else {
+        put_u16(&mut image, 22, sectors_per_fat as u16);
+        image[36] = 0x80;
+        image[38] = 0x29;
+        image[43..54].copy_from_slice(b"CRABEFI    ");
+        image[54..62].copy_from_slice(if fat_type == FatType::Fat12 {
+            b"FAT12   "
+        } else {
+            b"FAT16   "
+        });
+    }
+    image[510] = 0x55;
+    image[511] = 0xAA;
+
+    // Directory tree, keyed by path ("" is the root directory)
+    let mut dirs: BTreeMap<String, Vec<FatNode>> = BTreeMap::new();
+    dirs.insert(String::new(), Vec::new());
+    for &(path, data) in files {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let mut dir = String::new();
+        for part in parent.split('/').filter(|part| !part.is_empty()) {
+            let child = join_path(&dir, part);
+            if !dirs.contains_key(&child) {
+                dirs.get_mut(&dir).unwrap().push(FatNode::Dir(part));
+                dirs.insert(child.clone(), Vec::new());
+            }
+            dir = child;
+        }
+        dirs.get_mut(&dir).unwrap().push(FatNode::File(name, data));
+    }
+
+    // Allocate the directories first, so the FAT32 root directory gets cluster 2
+    let mut fat = vec![0u32; max_clusters as usize];
+    fat[0] = end_of_chain - 7; // media descriptor 0xF8
+    fat[1] = end_of_chain;
+    let mut next_free = 2u32;
+    let mut allocate = |fat: &mut [u32], bytes: usize| -> u32 {
+        let count = bytes.div_ceil(cluster_size) as u32;
+        if count == 0 {
+            return 0;
+        }
+        let first = next_free;
+        next_free += count;
+        assert!(next_free <= max_clusters, "FAT image full");
+        for cluster in first..first + count - 1 {
+            fat[cluster as usize] = cluster + 1;
+        }
+        fat[(first + count - 1) as usize] = end_of_chain;
+        first
+    };
+
+    let mut dir_clusters = BTreeMap::new();
+    for (path, children) in &dirs {
+        if path.is_empty() && fat_type != FatType::Fat32 {
+            continue;
+        }
+        let entries = 2 + children
+            .iter()
+            .map(|child| match child {
+                FatNode::Dir(name) | FatNode::File(name, _) => 1 + has_lfn(name) as usize,
+            })
+            .sum::<usize>();
+        dir_clusters.insert(path.clone(), allocate(&mut fat, entries * 32));
+    }
+
+    // Write the directories and file contents
+    for (path, children) in &dirs {
+        let cluster = dir_clusters.get(path).copied().unwrap_or(0);
+        let mut entries: Vec<[u8; 32]> = Vec::new();
+
+        if path.is_empty() {
+            entries.push(dir_entry(*b"CRABEFI    ", ATTR_VOLUME_ID, 0, 0));
+        } else {
+            let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            // ".." of a top-level directory points at cluster 0, even on FAT32
+            let parent_cluster = if parent.is_empty() {
+                0
+            } else {
+                dir_clusters[parent]
+            };
+            entries.push(dir_entry(*b".          ", ATTR_DIRECTORY, cluster, 0));
+            entries.push(dir_entry(
+                *b"..         ",
+                ATTR_DIRECTORY,
+                parent_cluster,
+                0,
+            ));
+        }
+
+        for child in children {
+            let (name, attr, first_cluster, size) = match *child {
+                FatNode::Dir(name) => (
+                    name,
+                    ATTR_DIRECTORY,
+                    dir_clusters[&join_path(path, name)],
+                    0,
+                ),
+                FatNode::File(name, data) => {
+                    let first_cluster = allocate(&mut fat, data.len());
+                    if first_cluster != 0 {
+                        let offset = cluster_offset(first_cluster);
+                        image[offset..offset + data.len()].copy_from_slice(data);
+                    }
+                    (name, ATTR_ARCHIVE, first_cluster, data.len() as u32)
+                }
+            };
+
+            let short_name = short_name(name);
+            if has_lfn(name) {
+                entries.push(lfn_entry(name, &short_name));
+            }
+            entries.push(dir_entry(short_name, attr, first_cluster, size));
+        }
+
+        let offset = if cluster == 0 {
+            assert!(entries.len() <= geometry.root_entry_count as usize);
+            root_dir_start as usize * SECTOR_SIZE
+        } else {
+            cluster_offset(cluster)
+        };
+        for (i, entry) in entries.iter().enumerate() {
+            image[offset + i * 32..offset + (i + 1) * 32].copy_from_slice(entry);
+        }
+    }
+
+    // Both copies of the FAT
+    for copy in 0..2 {
+        let start = (fat_start + copy * sectors_per_fat) as usize * SECTOR_SIZE;
+        let table = &mut image[start..start + sectors_per_fat as usize * SECTOR_SIZE];
+        for (cluster, &value) in fat.iter().enumerate() {
+            match fat_type {
+                FatType::Fat12 => {
+                    let offset = cluster * 3 / 2;
+                    if cluster & 1 == 0 {
+                        table[offset] = value as u8;
+                        table[offset + 1] = (table[offset + 1] & 0xF0) | (value >> 8) as u8 & 0x0F;
+                    } else {
+                        table[offset] = (table[offset] & 0x0F) | ((value & 0x0F) << 4) as u8;
+                        table[offset + 1] = (value >> 4) as u8;
+                    }
+                }
+                FatType::Fat16 => put_u16(table, cluster * 2, value as u16),
+                FatType::Fat32 => put_u32(table, cluster * 4, value),
+            }
+        }
+    }
+
+    image
+}
+
+/// Join a directory path and a name
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Whether a name needs a long file name entry
+fn has_lfn(name: &str) -> bool {
+    name.bytes().any(|c| c.is_ascii_lowercase())
+}
+
+/// Padded 8.3 name of a path component
+fn short_name(name: &str) -> [u8; 11] {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    assert!(
+        !base.is_empty() && base.len() <= 8 && ext.len() <= 3,
+        "not an 8.3 name: {}",
+        name
+    );
+
+    let mut short = [b' '; 11];
+    for (dst, c) in short.iter_mut().zip(base.bytes()) {
+        *dst = c.to_ascii_uppercase();
+    }
+    for (dst, c) in short[8..].iter_mut().zip(ext.bytes()) {
+        *dst = c.to_ascii_uppercase();
+    }
+    short
+}
+
+/// Short directory entry
+fn dir_entry(name: [u8; 11], attr: u8, first_cluster: u32, size: u32) -> [u8; 32] {
+    let mut entry = [0u8; 32];
+    entry[..11].copy_from_slice(&name);
+    entry[11] = attr;
+    put_u16(&mut entry, 20, (first_cluster >> 16) as u16);
+    put_u16(&mut entry, 26, first_cluster as u16);
+    put_u32(&mut entry, 28, size);
+    entry
+}
+
+/// Single long file name entry (names of up to 13 characters)
+fn lfn_entry(name: &str, short_name: &[u8; 11]) -> [u8; 32] {
+    assert!(name.len() <= 13, "long name too long: {}", name);
+
+    let checksum = short_name
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c));
+
+    // UTF-16 characters, NUL-terminated and padded with 0xFFFF
+    let mut chars = [0xFFFFu16; 13];
+    for (i, c) in name.encode_utf16().chain(Some(0)).take(13).enumerate() {
+        chars[i] = c;
+    }
+
+    let mut entry = [0u8; 32];
+    entry[0] = 0x41; // first and last entry of the sequence
+    entry[11] = ATTR_LFN;
+    entry[13] = checksum;
+    let offsets = (1..11)
+        .step_by(2)
+        .chain((14..26).step_by(2))
+        .chain((28..32).step_by(2));
+    for (offset, c) in offsets.zip(chars) {
+        put_u16(&mut entry, offset, c);
+    }
+    entry
+}
+
+// ============================================================================
+// GPT
+// ============================================================================
+
+/// Number of entries in a generated partition table
+const GPT_ENTRIES: usize = 128;
+
+/// First LBA of the first partition (1 MiB alignment, as parted does)
+const GPT_FIRST_PARTITION_LBA: u64 = 2048;
+
+/// Build a GPT disk with 512-byte sectors holding `partitions`
+///
+/// Each partition is given by its type GUID and contents; partitions are
+/// placed back to back from LBA 2048, each rounded up to 1 MiB.
+pub fn gpt_image(partitions: &[([u8; 16], &[u8])]) -> Vec<u8> {
+    let entries_sectors = (GPT_ENTRIES * 128 / SECTOR_SIZE) as u64;
+
+    let mut lba = GPT_FIRST_PARTITION_LBA;
+    let mut ranges = Vec::new();
+    for (_, data) in partitions {
+        let sectors = (data.len() as u64)
+            .div_ceil(SECTOR_SIZE as u64)
+            .next_multiple_of(GPT_FIRST_PARTITION_LBA)
+            .max(GPT_FIRST_PARTITION_LBA);
+        ranges.push((lba, lba + sectors - 1));
+        lba += sectors;
+    }
+    let last_usable = lba - 1;
+    let total_sectors = lba + entries_sectors + 1;
+
+    let mut image = vec![0u8; total_sectors as usize * SECTOR_SIZE];
+
+    // Protective MBR
+    image[0x1BE + 4] = 0xEE;
+    put_u32(&mut image, 0x1BE + 8, 1);
+    put_u32(
+        &mut image,
+        0x1BE + 12,
+        (total_sectors - 1).min(0xFFFF_FFFF) as u32,
+    );
+    image[510] = 0x55;
+    image[511] = 0xAA;
+
+    // Partition entries, at LBA 2 and again before the backup header
+    let mut entries = vec![0u8; GPT_ENTRIES * 128];
+    for (i, ((type_guid, data), (first, last))) in partitions.iter().zip(&ranges).enumerate() {
+        let entry = &mut entries[i * 128..(i + 1) * 128];
+        entry[0..16].copy_from_slice(type_guid);
+        entry[16..32].fill(i as u8 + 1);
+        put_u64(entry, 32, *first);
+        put_u64(entry, 40, *last);
+        for (j, c) in format!("Partition {}", i + 1).encode_utf16().enumerate() {
+            put_u16(entry, 56 + j * 2, c);
+        }
+
+        let offset = *first as usize * SECTOR_SIZE;
+        image[offset..offset + data.len()].copy_from_slice(data);
+    }
+    let backup_entries_lba = total_sectors - 1 - entries_sectors;
+    for lba in [2, backup_entries_lba] {
+        let offset = lba as usize * SECTOR_SIZE;
+        image[offset..offset + entries.len()].copy_from_slice(&entries);
+    }
+
+    // Primary and backup headers
+    for (lba, other, entries_lba) in [
+        (1, total_sectors - 1, 2),
+        (total_sectors - 1, 1, backup_entries_lba),
+    ] {
+        let header = &mut image[lba as usize * SECTOR_SIZE..][..SECTOR_SIZE];
+        header[0..8].copy_from_slice(b"EFI PART");
+        put_u32(header, 8, 0x0001_0000);
+        put_u32(header, 12, 92);
+        put_u64(header, 24, lba);
+        put_u64(header, 32, other);
+        put_u64(header, 40, GPT_FIRST_PARTITION_LBA);
+        put_u64(header, 48, last_usable);
+        header[56..72].fill(0xC8);
+        put_u64(header, 72, entries_lba);
+        put_u32(header, 80, GPT_ENTRIES as u32);
+        put_u32(header, 84, 128);
+    }
+
+    image
+}
+
+// ============================================================================
+// ISO9660 / El Torito
+// ============================================================================
+
+/// ISO9660 sector size
+const ISO_SECTOR: usize = 2048;
+
+/// Sector of the El Torito boot catalog in generated images
+const ISO_CATALOG_SECTOR: usize = 19;
+
+/// Sector of the boot image in generated images
+pub const ISO_BOOT_IMAGE_SECTOR: usize = 20;
+
+/// Build an ISO9660 image with an El Torito EFI boot image
+///
+/// With `sections` the default entry is a BIOS (x86) entry and the EFI image
+/// is listed in a section, as on hybrid BIOS/UEFI installer images. Without
+/// it the default entry itself is the EFI entry.
+pub fn iso_image(boot_image: &[u8], sections: bool) -> Vec<u8> {
+    let boot_sectors = boot_image.len().div_ceil(ISO_SECTOR);
+    let mut image = vec![0u8; (ISO_BOOT_IMAGE_SECTOR + boot_sectors) * ISO_SECTOR];
+
+    // Volume descriptors: primary, boot record, terminator
+    for (sector, kind) in [(16, 1u8), (17, 0), (18, 255)] {
+        let descriptor = &mut image[sector * ISO_SECTOR..][..ISO_SECTOR];
+        descriptor[0] = kind;
+        descriptor[1..6].copy_from_slice(b"CD001");
+        descriptor[6] = 1;
+    }
+    let boot_record = &mut image[17 * ISO_SECTOR..][..ISO_SECTOR];
+    boot_record[7..30].copy_from_slice(b"EL TORITO SPECIFICATION");
+    put_u32(boot_record, 0x47, ISO_CATALOG_SECTOR as u32);
+
+    // Boot catalog; sector counts are in 512-byte virtual sectors
+    let count = boot_image.len().div_ceil(512) as u16;
+    let catalog = &mut image[ISO_CATALOG_SECTOR * ISO_SECTOR..][..ISO_SECTOR];
+    catalog[0] = 0x01;
+    catalog[1] = if sections { 0x00 } else { 0xEF };
+    catalog[4..11].copy_from_slice(b"CrabEFI");
+    catalog[30] = 0x55;
+    catalog[31] = 0xAA;
+    let sum = catalog[..32].chunks(2).fold(0u16, |sum, word| {
+        sum.wrapping_add(u16::from_le_bytes([word[0], word[1]]))
+    });
+    put_u16(catalog, 28, sum.wrapping_neg());
+
+    let efi_entry = if sections {
+        // BIOS default entry pointing at a (missing) boot sector image
+        catalog[32] = 0x88;
+        put_u16(catalog, 38, 4);
+        put_u32(catalog, 40, 0);
+        // Final section header with one EFI entry
+        catalog[64] = 0x91;
+        catalog[65] = 0xEF;
+        put_u16(catalog, 66, 1);
+        96
+    } else {
+        32
+    };
+    catalog[efi_entry] = 0x88;
+    put_u16(catalog, efi_entry + 6, count);
+    put_u32(catalog, efi_entry + 8, ISO_BOOT_IMAGE_SECTOR as u32);
+
+    image[ISO_BOOT_IMAGE_SECTOR * ISO_SECTOR..][..boot_image.len()].copy_from_slice(boot_image);
+    image
+}
+
+/// Node of the directory tree of a generated image
+enum TreeNode {
+    Dir(BTreeMap<String, TreeNode>),
+    /// Index into the files
+    File(usize),
+    Link(String),
+}
+
+/// Directory tree of `files` and symbolic links `links`, given by their path
+/// and target
+fn file_tree(files: &[(&str, &[u8])], links: &[(&str, &str)]) -> BTreeMap<String, TreeNode> {
+    let mut root = BTreeMap::new();
+    let nodes = files
+        .iter()
+        .enumerate()
+        .map(|(i, (path, _))| (*path, TreeNode::File(i)))
+        .chain(
+            links
+                .iter()
+                .map(|(path, target)| (*path, TreeNode::Link(target.to_string()))),
+        );
+    for (path, node) in nodes {
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let mut tree = &mut root;
+        for component in dir.split('/').filter(|c| !c.is_empty()) {
+            let entry = tree
+                .entry(component.to_string())
+                .or_insert_with(|| TreeNode::Dir(BTreeMap::new()));
+            let TreeNode::Dir(subtree) = entry else {
+                panic!("{} is not a directory", component);
+            };
+            tree = subtree;
+        }
+        tree.insert(name.to_string(), node);
+    }
+    root
+}
+
+/// Block size of generated squashfs images
+pub const SQUASHFS_BLOCK_SIZE: usize = 4096;
+
+/// Size of a squashfs metadata block
+const SQUASHFS_METADATA: usize = 8192;
+
+/// Where a file's data is stored
+struct SquashfsFile {
+    start: u64,
+    sizes: Vec<u32>,
+    fragment: u32,
+    offset: u32,
+}
+
+/// Inode and directory tables of a squashfs image being built
+struct SquashfsTables {
+    inodes: Vec<u8>,
+    dirs: Vec<u8>,
+    next_inode: u32,
+}
+
+/// Reference to offset `pos` of a table written by [`squashfs_metadata`]
+fn squashfs_ref(pos: usize) -> u64 {
+    (((pos / SQUASHFS_METADATA) * (SQUASHFS_METADATA + 2)) as u64) << 16
+        | (pos % SQUASHFS_METADATA) as u64
+}
+
+/// Append `table` as uncompressed metadata blocks
+fn squashfs_metadata(image: &mut Vec<u8>, table: &[u8]) {
+    for chunk in table.chunks(SQUASHFS_METADATA) {
+        image.extend_from_slice(&(0x8000 | chunk.len() as u16).to_le_bytes());
+        image.extend_from_slice(chunk);
+    }
+}
+
+/// Wrap `data` in a zlib stream of one stored deflate block
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    let mut stream = vec![0x78, 0x01, 0x01];
+    stream.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    stream.extend_from_slice(&(!(data.len() as u16)).to_le_bytes());
+    stream.extend_from_slice(data);
+    stream.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    stream
+}
+
+impl SquashfsTables {
+    /// Append the header common to all inodes, returning the reference
+    fn header(&mut self, inode_type: u16, mode: u16, number: u32) -> u64 {
+        let reference = squashfs_ref(self.inodes.len());
+        for value in [inode_type, mode, 0, 0] {
+            self.inodes.extend_from_slice(&value.to_le_bytes());
+        }
+        self.inodes.extend_from_slice(&0u32.to_le_bytes());
+        self.inodes.extend_from_slice(&number.to_le_bytes());
+        reference
+    }
+
+    /// Append a file inode, extended from two blocks on as mksquashfs does
+    /// for large files
+    fn file(&mut self, number: u32, size: usize, file: &SquashfsFile) -> u64 {
+        let extended = size >= 2 * SQUASHFS_BLOCK_SIZE;
+        let reference = self.header(if extended { 9 } else { 2 }, 0o644, number);
+        let mut body = Vec::new();
+        if extended {
+            body.extend_from_slice(&file.start.to_le_bytes());
+            body.extend_from_slice(&(size as u64).to_le_bytes());
+            body.extend_from_slice(&0u64.to_le_bytes());
+            body.extend_from_slice(&1u32.to_le_bytes());
+            body.extend_from_slice(&file.fragment.to_le_bytes());
+            body.extend_from_slice(&file.offset.to_le_bytes());
+            body.extend_from_slice(&u32::MAX.to_le_bytes());
+        } else {
+            body.extend_from_slice(&(file.start as u32).to_le_bytes());
+            body.extend_from_slice(&file.fragment.to_le_bytes());
+            body.extend_from_slice(&file.offset.to_le_bytes());
+            body.extend_from_slice(&(size as u32).to_le_bytes());
+        }
+        for size in &file.sizes {
+            body.extend_from_slice(&size.to_le_bytes());
+        }
+        self.inodes.extend_from_slice(&body);
+        reference
+    }
+
+    /// Append a symbolic link inode
+    fn symlink(&mut self, number: u32, target: &str) -> u64 {
+        let reference = self.header(3, 0o777, number);
+        self.inodes.extend_from_slice(&1u32.to_le_bytes());
+        self.inodes
+            .extend_from_slice(&(target.len() as u32).to_le_bytes());
+        self.inodes.extend_from_slice(target.as_bytes());
+        reference
+    }
+
+    /// Append a directory after its children, returning its reference
+    fn dir(
+        &mut self,
+        tree: &BTreeMap<String, TreeNode>,
+        number: u32,
+        parent: u32,
+        files: &[(&str, &[u8])],
+        layouts: &[SquashfsFile],
+    ) -> u64 {
+        let mut entries = Vec::new();
+        for (name, node) in tree {
+            let child = self.next_inode;
+            self.next_inode += 1;
+            let (kind, reference) = match node {
+                TreeNode::Dir(subtree) => (1u16, self.dir(subtree, child, number, files, layouts)),
+                TreeNode::File(i) => (2, self.file(child, files[*i].1.len(), &layouts[*i])),
+                TreeNode::Link(target) => (3, self.symlink(child, target)),
+            };
+            entries.push((name, kind, reference, child));
+        }
+
+        // A header per run of entries with inodes in the same block
+        let listing = self.dirs.len();
+        let mut i = 0;
+        while i < entries.len() {
+            let block = entries[i].2 >> 16;
+            let base = entries[i].3;
+            let run = entries[i..]
+                .iter()
+                .take(256)
+                .take_while(|entry| entry.2 >> 16 == block)
+                .count();
+            for value in [run as u32 - 1, block as u32, base] {
+                self.dirs.extend_from_slice(&value.to_le_bytes());
+            }
+            for (name, kind, reference, child) in &entries[i..i + run] {
+                self.dirs
+                    .extend_from_slice(&(*reference as u16).to_le_bytes());
+                self.dirs
+                    .extend_from_slice(&((*child - base) as i16).to_le_bytes());
+                self.dirs.extend_from_slice(&kind.to_le_bytes());
+                self.dirs
+                    .extend_from_slice(&(name.len() as u16 - 1).to_le_bytes());
+                self.dirs.extend_from_slice(name.as_bytes());
+            }
+            i += run;
+        }
+        let size = self.dirs.len() - listing;
+
+        let reference = self.header(1, 0o755, number);
+        let start = (listing / SQUASHFS_METADATA) * (SQUASHFS_METADATA + 2);
+        self.inodes.extend_from_slice(&(start as u32).to_le_bytes());
+        self.inodes.extend_from_slice(&2u32.to_le_bytes());
+        self.inodes
+            .extend_from_slice(&((size + 3) as u16).to_le_bytes());
+        self.inodes
+            .extend_from_slice(&((listing % SQUASHFS_METADATA) as u16).to_le_bytes());
+        self.inodes.extend_from_slice(&parent.to_le_bytes());
+        reference
+    }
+}
+
+/// Build a gzip-compressed squashfs 4.0 image holding `files` and `links`
+///
+/// Links are given by their path and target. The first block of each file
+/// is zlib-compressed (as a stored block), its other full blocks are stored
+/// uncompressed and its tail goes into a fragment. Metadata is uncompressed,
+/// split into blocks as mksquashfs does.
+pub fn squashfs_image(files: &[(&str, &[u8])], links: &[(&str, &str)]) -> Vec<u8> {
+    let mut image = vec![0u8; 96];
+
+    // File data, then the fragment blocks
+    let mut fragments: Vec<Vec<u8>> = Vec::new();
+    let mut layouts = Vec::new();
+    for (_, data) in files {
+        let start = image.len() as u64;
+        let full = data.len() / SQUASHFS_BLOCK_SIZE;
+        let mut sizes = Vec::new();
+        for (i, block) in data.chunks(SQUASHFS_BLOCK_SIZE).take(full).enumerate() {
+            if i == 0 {
+                let stream = zlib_stored(block);
+                sizes.push(stream.len() as u32);
+                image.extend_from_slice(&stream);
+            } else {
+                sizes.push(block.len() as u32 | 1 << 24);
+                image.extend_from_slice(block);
+            }
+        }
+        let tail = &data[full * SQUASHFS_BLOCK_SIZE..];
+        let (fragment, offset) = if tail.is_empty() {
+            (u32::MAX, 0)
+        } else {
+            if fragments
+                .last()
+                .is_none_or(|block| block.len() + tail.len() > SQUASHFS_BLOCK_SIZE)
+            {
+                fragments.push(Vec::new());
+            }
+            let block = fragments.last_mut().unwrap();
+            let offset = block.len() as u32;
+            block.extend_from_slice(tail);
+            (fragments.len() as u32 - 1, offset)
+        };
+        layouts.push(SquashfsFile {
+            start,
+            sizes,
+            fragment,
+            offset,
+        });
+    }
+    let mut fragment_entries = Vec::new();
+    for block in &fragments {
+        fragment_entries.extend_from_slice(&(image.len() as u64).to_le_bytes());
+        fragment_entries.extend_from_slice(&(block.len() as u32 | 1 << 24).to_le_bytes());
+        fragment_entries.extend_from_slice(&0u32.to_le_bytes());
+        image.extend_from_slice(block);
+    }
+    assert!(fragment_entries.len() <= SQUASHFS_METADATA);
+
+    let root = file_tree(files, links);
+    let mut tables = SquashfsTables {
+        inodes: Vec::new(),
+        dirs: Vec::new(),
+        next_inode: 2,
+    };
+    let root_inode = tables.dir(&root, 1, 0, files, &layouts);
+
+    let inode_table = image.len() as u64;
+    squashfs_metadata(&mut image, &tables.inodes);
+    let dir_table = image.len() as u64;
+    squashfs_metadata(&mut image, &tables.dirs);
+    let fragment_metadata = image.len() as u64;
+    squashfs_metadata(&mut image, &fragment_entries);
+    let fragment_table = image.len() as u64;
+    image.extend_from_slice(&fragment_metadata.to_le_bytes());
+    let bytes_used = image.len() as u64;
+    image.resize(image.len().next_multiple_of(4096), 0);
+
+    // Superblock
+    put_u32(&mut image, 0, 0x7371_7368);
+    put_u32(&mut image, 4, tables.next_inode - 1);
+    put_u32(&mut image, 12, SQUASHFS_BLOCK_SIZE as u32);
+    put_u32(&mut image, 16, fragments.len() as u32);
+    put_u16(&mut image, 20, 1);
+    put_u16(&mut image, 22, SQUASHFS_BLOCK_SIZE.trailing_zeros() as u16);
+    put_u16(&mut image, 28, 4);
+    put_u16(&mut image, 30, 0);
+    put_u64(&mut image, 32, root_inode);
+    put_u64(&mut image, 40, bytes_used);
+    for offset in [48, 56, 88] {
+        put_u64(&mut image, offset, u64::MAX);
+    }
+    put_u64(&mut image, 64, inode_table);
+    put_u64(&mut image, 72, dir_table);
+    put_u64(&mut image, 80, fragment_table);
+    image
+}
+
+/// Node and sector size of generated btrfs images
+pub const BTRFS_NODE_SIZE: usize = 4096;
+
+/// Logical and physical addresses of the system chunk holding the chunk tree
+const BTRFS_SYSTEM_LOGICAL: u64 = 0x100_0000;
+const BTRFS_SYSTEM_PHYSICAL: usize = 0x2_0000;
+const BTRFS_SYSTEM_LENGTH: usize = 0x1_0000;
+
+/// Logical and physical addresses of the chunk holding everything else
+const BTRFS_MAIN_LOGICAL: u64 = 0x200_0000;
+const BTRFS_MAIN_PHYSICAL: usize = BTRFS_SYSTEM_PHYSICAL + BTRFS_SYSTEM_LENGTH;
+
+/// Files up to this size are stored inline
+const BTRFS_MAX_INLINE: usize = 1024;
+
+/// Largest extent written for a file
+const BTRFS_EXTENT_SIZE: usize = 8192;
+
+/// Subvolume holding the files, named `@` in the top level
+const BTRFS_SUBVOLUME: u64 = 256;
+
+/// Items of a btrfs tree by key
+type BtrfsItems = BTreeMap<(u64, u8, u64), Vec<u8>>;
+
+/// A chunk of a btrfs image being built, with nodes and data appended
+struct BtrfsChunk {
+    logical: u64,
+    data: Vec<u8>,
+}
+
+impl BtrfsChunk {
+    /// Append `bytes`, returning their logical address
+    fn append(&mut self, bytes: &[u8]) -> u64 {
+        let logical = self.logical + self.data.len() as u64;
+        self.data.extend_from_slice(bytes);
+        logical
+    }
+
+    /// Write the tree of `items`, returning its root and level
+    fn tree(&mut self, owner: u64, items: &BtrfsItems) -> (u64, u8) {
+        let key_bytes = |key: &(u64, u8, u64)| {
+            let mut bytes = key.0.to_le_bytes().to_vec();
+            bytes.push(key.1);
+            bytes.extend_from_slice(&key.2.to_le_bytes());
+            bytes
+        };
+        let header = |node: &mut [u8], bytenr: u64, count: usize, level: u8| {
+            put_u64(node, 0x30, bytenr);
+            put_u64(node, 0x58, owner);
+            put_u32(node, 0x60, count as u32);
+            node[0x64] = level;
+        };
+
+        // Leaves, with item data packed from the end
+        let mut leaves: Vec<Vec<(&(u64, u8, u64), &Vec<u8>)>> = vec![Vec::new()];
+        let mut used = 101;
+        for item in items {
+            if used + 25 + item.1.len() > BTRFS_NODE_SIZE {
+                leaves.push(Vec::new());
+                used = 101;
+            }
+            used += 25 + item.1.len();
+            leaves.last_mut().unwrap().push(item);
+        }
+        let mut level_nodes = Vec::new();
+        for leaf in &leaves {
+            let mut node = vec![0u8; BTRFS_NODE_SIZE];
+            let bytenr = self.logical + self.data.len() as u64;
+            header(&mut node, bytenr, leaf.len(), 0);
+            let mut end = BTRFS_NODE_SIZE - 101;
+            for (slot, (key, data)) in leaf.iter().enumerate() {
+                end -= data.len();
+                let entry = 101 + slot * 25;
+                node[entry..entry + 17].copy_from_slice(&key_bytes(key));
+                put_u32(&mut node, entry + 17, end as u32);
+                put_u32(&mut node, entry + 21, data.len() as u32);
+                node[101 + end..101 + end + data.len()].copy_from_slice(data);
+            }
+            self.append(&node);
+            level_nodes.push((leaf.first().map_or((0, 0, 0), |item| *item.0), bytenr));
+        }
+
+        // Internal nodes up to a single root
+        let mut level = 0;
+        while level_nodes.len() > 1 {
+            level += 1;
+            let mut parents = Vec::new();
+            for children in level_nodes.chunks((BTRFS_NODE_SIZE - 101) / 33) {
+                let mut node = vec![0u8; BTRFS_NODE_SIZE];
+                let bytenr = self.logical + self.data.len() as u64;
+                header(&mut node, bytenr, children.len(), level);
+                for (slot, (key, child)) in children.iter().enumerate() {
+                    let entry = 101 + slot * 33;
+                    node[entry..entry + 17].copy_from_slice(&key_bytes(key));
+                    put_u64(&mut node, entry + 17, *child);
+                }
+                self.append(&node);
+                parents.push((children[0].0, bytenr));
+            }
+            level_nodes = parents;
+        }
+        (level_nodes[0].1, level)
+    }
+}
+
+/// Inode item of the given mode and size
+fn btrfs_inode(mode: u32, size: usize) -> Vec<u8> {
+    let mut inode = vec![0u8; 160];
+    put_u64(&mut inode, 16, size as u64);
+    put_u32(&mut inode, 40, 1);
+    put_u32(&mut inode, 52, mode);
+    inode
+}
+
+/// Directory item naming `location`
+fn btrfs_dir_item(location: (u64, u8, u64), file_type: u8, name: &str) -> Vec<u8> {
+    let mut item = vec![0u8; 30];
+    put_u64(&mut item, 0, location.0);
+    item[8] = location.1;
+    put_u64(&mut item, 9, location.2);
+    put_u16(&mut item, 27, name.len() as u16);
+    item[29] = file_type;
+    item.extend_from_slice(name.as_bytes());
+    item
+}
+
+/// Add a directory entry to the items of directory `dir`
+fn btrfs_link(
+    items: &mut BtrfsItems,
+    dir: u64,
+    index: u64,
+    name: &str,
+    location: (u64, u8, u64),
+    file_type: u8,
+) {
+    let item = btrfs_dir_item(location, file_type, name);
+    let hash = crate::fs::btrfs::name_hash(name.as_bytes());
+    items
+        .entry((dir, 84, hash))
+        .or_default()
+        .extend_from_slice(&item);
+    items.insert((dir, 96, index), item);
+}
+
+/// Inline extent item holding `data`
+fn btrfs_inline_extent(data: &[u8]) -> Vec<u8> {
+    let mut item = vec![0u8; 21];
+    put_u64(&mut item, 8, data.len() as u64);
+    item.extend_from_slice(data);
+    item
+}
+
+/// Add the inode and extents of a file
+///
+/// Small files are inline. Larger ones get extents of up to 8 KiB, all but
+/// zeroed ones, which are left as holes: odd extents are zlib-compressed (as
+/// a stored block), even ones point into a larger extent on disk.
+fn btrfs_file(items: &mut BtrfsItems, main: &mut BtrfsChunk, ino: u64, data: &[u8]) {
+    items.insert((ino, 1, 0), btrfs_inode(0o100644, data.len()));
+    if data.is_empty() {
+        return;
+    }
+    if data.len() <= BTRFS_MAX_INLINE {
+        items.insert((ino, 108, 0), btrfs_inline_extent(data));
+        return;
+    }
+    for (i, chunk) in data.chunks(BTRFS_EXTENT_SIZE).enumerate() {
+        if chunk.iter().all(|&byte| byte == 0) {
+            continue;
+        }
+        let mut padded = chunk.to_vec();
+        padded.resize(chunk.len().next_multiple_of(BTRFS_NODE_SIZE), 0);
+        let (mut disk, offset, compression) = if i % 2 == 1 {
+            (zlib_stored(&padded), 0, 1)
+        } else {
+            let mut disk = vec![0xAA; BTRFS_NODE_SIZE];
+            disk.extend_from_slice(&padded);
+            (disk, BTRFS_NODE_SIZE, 0)
+        };
+        let disk_len = disk.len().next_multiple_of(BTRFS_NODE_SIZE);
+        disk.resize(disk_len, 0);
+        let disk_bytenr = main.append(&disk);
+
+        let mut item = vec![0u8; 53];
+        put_u64(
+            &mut item,
+            8,
+            if compression == 0 {
+                disk_len
+            } else {
+                padded.len()
+            } as u64,
+        );
+        item[16] = compression;
+        item[20] = 1;
+        put_u64(&mut item, 21, disk_bytenr);
+        put_u64(&mut item, 29, disk_len as u64);
+        put_u64(&mut item, 37, offset as u64);
+        put_u64(&mut item, 45, padded.len() as u64);
+        items.insert((ino, 108, (i * BTRFS_EXTENT_SIZE) as u64), item);
+    }
+}
+
+/// Add directory `ino` and everything below it
+fn btrfs_dir(
+    items: &mut BtrfsItems,
+    main: &mut BtrfsChunk,
+    tree: &BTreeMap<String, TreeNode>,
+    ino: u64,
+    next_ino: &mut u64,
+    files: &[(&str, &[u8])],
+) {
+    items.insert((ino, 1, 0), btrfs_inode(0o040755, 0));
+    for (index, (name, node)) in tree.iter().enumerate() {
+        let child = *next_ino;
+        *next_ino += 1;
+        let file_type = match node {
+            TreeNode::Dir(subtree) => {
+                btrfs_dir(items, main, subtree, child, next_ino, files);
+                2
+            }
+            TreeNode::File(i) => {
+                btrfs_file(items, main, child, files[*i].1);
+                1
+            }
+            TreeNode::Link(target) => {
+                items.insert((child, 1, 0), btrfs_inode(0o120777, target.len()));
+                items.insert((child, 108, 0), btrfs_inline_extent(target.as_bytes()));
+                7
+            }
+        };
+        btrfs_link(items, ino, index as u64 + 2, name, (child, 1, 0), file_type);
+    }
+}
+
+/// Chunk item of a single stripe at `physical` on device 1
+fn btrfs_chunk_item(length: u64, chunk_type: u64, physical: u64) -> Vec<u8> {
+    let mut item = vec![0u8; 80];
+    put_u64(&mut item, 0, length);
+    put_u64(&mut item, 8, 2);
+    put_u64(&mut item, 16, 0x10000);
+    put_u64(&mut item, 24, chunk_type);
+    for offset in [32, 36, 40] {
+        put_u32(&mut item, offset, BTRFS_NODE_SIZE as u32);
+    }
+    put_u16(&mut item, 44, 1);
+    put_u64(&mut item, 48, 1);
+    put_u64(&mut item, 56, physical);
+    item
+}
+
+/// Build a single-device btrfs image holding `files` and `links`
+///
+/// As on openSUSE, they go in subvolume 256, named `@` in the top level
+/// subvolume. With `default_subvolume` it is the default subvolume,
+/// otherwise the top level is. Links are given by their path and target.
+/// Trees are split into leaves and internal nodes as they fill up.
+pub fn btrfs_image(
+    files: &[(&str, &[u8])],
+    links: &[(&str, &str)],
+    default_subvolume: bool,
+) -> Vec<u8> {
+    let mut main = BtrfsChunk {
+        logical: BTRFS_MAIN_LOGICAL,
+        data: Vec::new(),
+    };
+
+    // The subvolume, then the top level naming it
+    let mut items = BtrfsItems::new();
+    btrfs_dir(
+        &mut items,
+        &mut main,
+        &file_tree(files, links),
+        256,
+        &mut 257,
+        files,
+    );
+    let subvolume = main.tree(BTRFS_SUBVOLUME, &items);
+
+    let mut items = BtrfsItems::new();
+    items.insert((256, 1, 0), btrfs_inode(0o040755, 0));
+    btrfs_link(&mut items, 256, 2, "@", (BTRFS_SUBVOLUME, 132, u64::MAX), 2);
+    let top_level = main.tree(5, &items);
+
+    // Root tree
+    let root_item = |(bytenr, level): (u64, u8)| {
+        let mut item = vec![0u8; 439];
+        put_u64(&mut item, 168, 256);
+        put_u64(&mut item, 176, bytenr);
+        item[238] = level;
+        item
+    };
+    let default = if default_subvolume {
+        BTRFS_SUBVOLUME
+    } else {
+        5
+    };
+    let mut items = BtrfsItems::new();
+    items.insert((5, 132, 0), root_item(top_level));
+    items.insert((BTRFS_SUBVOLUME, 132, 0), root_item(subvolume));
+    let hash = crate::fs::btrfs::name_hash(b"default");
+    items.insert(
+        (6, 84, hash),
+        btrfs_dir_item((default, 132, u64::MAX), 2, "default"),
+    );
+    let (root, root_level) = main.tree(1, &items);
+    let main_length = main.data.len().next_multiple_of(0x10000) as u64;
+    main.data.resize(main_length as usize, 0);
+
+    // Chunk tree in the system chunk
+    let system_item = btrfs_chunk_item(
+        BTRFS_SYSTEM_LENGTH as u64,
+        0x2,
+        BTRFS_SYSTEM_PHYSICAL as u64,
+    );
+    let mut items = BtrfsItems::new();
+    items.insert((256, 228, BTRFS_SYSTEM_LOGICAL), system_item.clone());
+    items.insert(
+        (256, 228, BTRFS_MAIN_LOGICAL),
+        btrfs_chunk_item(main_length, 0x1 | 0x4, BTRFS_MAIN_PHYSICAL as u64),
+    );
+    let mut system = BtrfsChunk {
+        logical: BTRFS_SYSTEM_LOGICAL,
+        data: Vec::new(),
+    };
+    let (chunk_root, chunk_root_level) = system.tree(3, &items);
+    assert!(system.data.len() <= BTRFS_SYSTEM_LENGTH);
+
+    let mut image = vec![0u8; BTRFS_MAIN_PHYSICAL + main.data.len()];
+    image[BTRFS_SYSTEM_PHYSICAL..][..system.data.len()].copy_from_slice(&system.data);
+    image[BTRFS_MAIN_PHYSICAL..].copy_from_slice(&main.data);
+
+    // Superblock, with the system chunk in its array
+    let total_bytes = image.len() as u64;
+    let superblock = &mut image[0x10000..0x11000];
+    put_u64(superblock, 0x30, 0x10000);
+    superblock[0x40..0x48].copy_from_slice(b"_BHRfS_M");
+    put_u64(superblock, 0x48, 1);
+    put_u64(superblock, 0x50, root);
+    put_u64(superblock, 0x58, chunk_root);
+    put_u64(superblock, 0x70, total_bytes);
+    put_u64(superblock, 0x80, 6);
+    put_u64(superblock, 0x88, 1);
+    for offset in [0x90, 0x94, 0x98, 0x9C] {
+        put_u32(superblock, offset, BTRFS_NODE_SIZE as u32);
+    }
+    put_u32(superblock, 0xA0, (17 + system_item.len()) as u32);
+    // Mixed backrefs, default subvolume, big metadata, extended inode refs,
+    // skinny metadata and no holes, as mkfs.btrfs sets
+    put_u64(superblock, 0xBC, 0x363);
+    superblock[0xC6] = root_level;
+    superblock[0xC7] = chunk_root_level;
+    put_u64(superblock, 0xC9, 1);
+    put_u64(superblock, 0x32B, 256);
+    superblock[0x333] = 228;
+    put_u64(superblock, 0x334, BTRFS_SYSTEM_LOGICAL);
+    superblock[0x33C..0x33C + system_item.len()].copy_from_slice(&system_item);
+    image
+}
@@ -284,13 +284,17 @@ pub fn tsc_frequency() -> u64 {
 }
 
 /// Spin-wait for approximately `us` microseconds
+///
+/// During parallel device init other tasks run meanwhile.
 #[inline]
 pub fn delay_us(us: u64) {
     let cycles = us * TSC_CYCLES_PER_US.load(Ordering::Relaxed);
     let start = rdtsc();
-    while rdtsc().wrapping_sub(start) < cycles {
-        core::hint::spin_loop();
-    }
+    crate::parallel::wait(|| {
+        while rdtsc().wrapping_sub(start) < cycles {
+            core::hint::spin_loop();
+        }
+    });
 }
 
 /// Spin-wait for approximately `ms` milliseconds
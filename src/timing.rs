@@ -0,0 +1,181 @@
+//! Boot-time telemetry
+//!
+//! The slow parts of a boot (PCI scan, each storage driver, mounting the
+//! ESP, loading PE images) are wrapped in [`measure`]. Their durations are
+//! summed per stage and printed slowest first when the OS takes over, and
+//! every run is appended to coreboot's timestamp table so `cbmem -t` shows
+//! it next to the coreboot stages.
+//!
+//! Drivers initialized in parallel overlap, so the stage totals can add up
+//! to more than the wall time.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::time::{self, rdtsc};
+
+/// First coreboot timestamp ID used by CrabEFI
+///
+/// Well clear of the IDs coreboot and depthcharge use; `cbmem -t` lists
+/// unknown IDs by number. Each stage gets a start and an end ID.
+const TIMESTAMP_ID_BASE: u32 = 20000;
+
+/// coreboot `struct timestamp_table` field offsets
+const TABLE_BASE_TIME: u64 = 0;
+const TABLE_MAX_ENTRIES: u64 = 8;
+const TABLE_NUM_ENTRIES: u64 = 12;
+const TABLE_ENTRIES: u64 = 16;
+
+/// Size of a `struct timestamp_entry`: a u32 ID and an i64 stamp
+const ENTRY_SIZE: u64 = 12;
+
+/// A measured part of the boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    PciScan,
+    Nvme,
+    Ahci,
+    Usb,
+    Sdhci,
+    FsMount,
+    PeLoad,
+}
+
+impl Stage {
+    const ALL: [Stage; 7] = [
+        Stage::PciScan,
+        Stage::Nvme,
+        Stage::Ahci,
+        Stage::Usb,
+        Stage::Sdhci,
+        Stage::FsMount,
+        Stage::PeLoad,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::PciScan => "PCI scan",
+            Stage::Nvme => "NVMe",
+            Stage::Ahci => "AHCI",
+            Stage::Usb => "USB",
+            Stage::Sdhci => "SDHCI",
+            Stage::FsMount => "FS mount",
+            Stage::PeLoad => "PE load",
+        }
+    }
+
+    /// coreboot timestamp ID of the start of the stage; the end is one more
+    fn timestamp_id(self) -> u32 {
+        TIMESTAMP_ID_BASE + 2 * self as u32
+    }
+}
+
+/// Address of coreboot's timestamp table, 0 if there is none
+static TIMESTAMP_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// TSC cycles and number of runs per stage
+static TOTALS: Mutex<[(u64, u32); Stage::ALL.len()]> = Mutex::new([(0, 0); Stage::ALL.len()]);
+
+/// Remember coreboot's timestamp table
+pub fn init(timestamp_table: Option<u64>) {
+    TIMESTAMP_TABLE.store(timestamp_table.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Append an entry to a coreboot timestamp table
+///
+/// Stamps are TSC values relative to the table's base time, like coreboot's
+/// own on x86. A full table is left alone.
+///
+/// # Safety
+///
+/// `table` must point to a coreboot timestamp table.
+unsafe fn append_timestamp(table: u64, id: u32, tsc: u64) {
+    unsafe {
+        let base_time = core::ptr::read_unaligned((table + TABLE_BASE_TIME) as *const u64);
+        let max_entries = core::ptr::read_unaligned((table + TABLE_MAX_ENTRIES) as *const u16);
+        let num_entries_ptr = (table + TABLE_NUM_ENTRIES) as *mut u32;
+        let num_entries = core::ptr::read_unaligned(num_entries_ptr);
+        if num_entries >= max_entries as u32 {
+            return;
+        }
+
+        let entry = table + TABLE_ENTRIES + num_entries as u64 * ENTRY_SIZE;
+        core::ptr::write_unaligned(entry as *mut u32, id);
+        core::ptr::write_unaligned((entry + 4) as *mut i64, tsc.wrapping_sub(base_time) as i64);
+        core::ptr::write_unaligned(num_entries_ptr, num_entries + 1);
+    }
+}
+
+/// Add a timestamp to coreboot's table, if there is one
+fn record_timestamp(id: u32, tsc: u64) {
+    let table = TIMESTAMP_TABLE.load(Ordering::Relaxed);
+    if table != 0 {
+        unsafe { append_timestamp(table, id, tsc) };
+    }
+}
+
+/// Run `f` as part of `stage`, recording how long it took
+pub fn measure<R>(stage: Stage, f: impl FnOnce() -> R) -> R {
+    let start = rdtsc();
+    record_timestamp(stage.timestamp_id(), start);
+    let result = f();
+    let end = rdtsc();
+    record_timestamp(stage.timestamp_id() + 1, end);
+
+    let mut totals = TOTALS.lock();
+    let (cycles, runs) = &mut totals[stage as usize];
+    *cycles += end.wrapping_sub(start);
+    *runs += 1;
+    result
+}
+
+/// Print the time spent per stage, slowest first
+pub fn report() {
+    let totals = *TOTALS.lock();
+    let mut stages = Stage::ALL;
+    stages.sort_unstable_by_key(|&stage| core::cmp::Reverse(totals[stage as usize].0));
+
+    let cycles_per_us = (time::tsc_frequency() / 1_000_000).max(1);
+    log::info!("Boot time report:");
+    for stage in stages {
+        let (cycles, runs) = totals[stage as usize];
+        if runs == 0 {
+            continue;
+        }
+        let us = cycles / cycles_per_us;
+        log::info!(
+            "  {:<10} {:>5}.{:03} ms ({} run{})",
+            stage.name(),
+            us / 1000,
+            us % 1000,
+            runs,
+            if runs == 1 { "" } else { "s" }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{put_u16, put_u32, put_u64};
+
+    #[test]
+    fn appends_to_timestamp_table() {
+        let mut table = std::vec![0u8; (TABLE_ENTRIES + 2 * ENTRY_SIZE) as usize];
+        put_u64(&mut table, TABLE_BASE_TIME as usize, 1000);
+        put_u16(&mut table, TABLE_MAX_ENTRIES as usize, 2);
+        put_u32(&mut table, TABLE_NUM_ENTRIES as usize, 1);
+        let address = table.as_mut_ptr() as u64;
+
+        unsafe {
+            append_timestamp(address, Stage::Usb.timestamp_id(), 5000);
+            // Full
+            append_timestamp(address, Stage::Usb.timestamp_id() + 1, 6000);
+        }
+
+        let entry = &table[(TABLE_ENTRIES + ENTRY_SIZE) as usize..];
+        assert_eq!(table[TABLE_NUM_ENTRIES as usize], 2);
+        assert_eq!(entry[..4], 20006u32.to_le_bytes());
+        assert_eq!(entry[4..12], 4000i64.to_le_bytes());
+    }
+}
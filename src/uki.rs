@@ -0,0 +1,280 @@
+//! Unified Kernel Images
+//!
+//! A UKI is a single EFI application made of systemd-stub with the kernel,
+//! initrd and command line added as PE sections. CrabEFI does the stub's
+//! job itself: it starts the kernel in `.linux` directly, with `.cmdline` as
+//! its load options and `.initrd` served by the Load File 2 protocol on the
+//! Linux initrd media device path, where the kernel's EFI stub looks for it.
+//! A `.splash` bitmap is drawn while the kernel starts.
+//!
+//! Per the Boot Loader Specification (Type #2 entries), UKIs live in
+//! `\EFI\Linux` on the ESP. Each gets a boot menu entry titled from the
+//! os-release data in its `.osrel` section.
+
+use core::fmt::Write;
+
+use heapless::{String, Vec};
+
+use crate::fs::fat::{DirectoryEntry, FatFilesystem};
+use crate::pe;
+
+/// Directory holding UKIs on the ESP
+pub const DIRECTORY: &str = "EFI\\Linux";
+
+/// Most UKIs listed on one ESP
+pub const MAX_IMAGES: usize = 8;
+
+/// Bytes read to find the section table of a UKI in the menu
+const HEADERS_SIZE: usize = 4096;
+
+/// Largest `.osrel` section read for the menu
+const MAX_OSREL_SIZE: usize = 2048;
+
+/// A Unified Kernel Image read into memory
+#[derive(Debug, Clone, Copy)]
+pub struct Uki<'a> {
+    /// The kernel, an EFI application itself
+    pub linux: &'a [u8],
+    /// The initrd, empty for none
+    pub initrd: &'a [u8],
+    /// The kernel command line, empty for none
+    pub cmdline: &'a str,
+    /// The os-release file of the distribution
+    pub osrel: &'a str,
+    /// A BMP shown while the kernel starts, empty for none
+    pub splash: &'a [u8],
+}
+
+impl<'a> Uki<'a> {
+    /// Parse a UKI file, `None` if `data` is some other EFI application
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let linux = pe::file_section(data, ".linux")?;
+        let section = |name| pe::file_section(data, name).unwrap_or(&[]);
+        let text = |name| trim(core::str::from_utf8(section(name)).unwrap_or(""));
+        Some(Uki {
+            linux,
+            initrd: section(".initrd"),
+            cmdline: text(".cmdline"),
+            osrel: text(".osrel"),
+            splash: section(".splash"),
+        })
+    }
+
+    /// Title for the boot menu, from the os-release data
+    pub fn title(&self) -> String<64> {
+        title(self.osrel)
+    }
+}
+
+/// Strip the trailing null and newlines tools leave in text sections
+fn trim(text: &str) -> &str {
+    text.trim_end_matches(['\0', '\n', '\r', ' '])
+}
+
+/// Look up a value in os-release data, without its quotes
+pub fn os_release_value<'a>(osrel: &'a str, key: &str) -> Option<&'a str> {
+    osrel.lines().find_map(|line| {
+        let (name, value) = line.trim().split_once('=')?;
+        if name != key {
+            return None;
+        }
+        let value = value.trim();
+        let unquoted = ['"', '\'']
+            .into_iter()
+            .find_map(|quote| value.strip_prefix(quote)?.strip_suffix(quote));
+        Some(unquoted.unwrap_or(value))
+    })
+}
+
+/// Menu title from os-release data, as systemd-boot builds it
+///
+/// `PRETTY_NAME`, or else `NAME` and `VERSION_ID`, with the image version
+/// appended when there is one.
+pub fn title(osrel: &str) -> String<64> {
+    let value = |key| os_release_value(osrel, key).filter(|v| !v.is_empty());
+    let mut title = String::new();
+    match (value("PRETTY_NAME"), value("NAME")) {
+        (Some(pretty_name), _) => {
+            let _ = title.push_str(pretty_name);
+        }
+        (None, Some(name)) => {
+            let _ = title.push_str(name);
+            if let Some(version) = value("VERSION_ID") {
+                let _ = write!(title, " {}", version);
+            }
+        }
+        (None, None) => {
+            let _ = title.push_str("Linux");
+        }
+    }
+    if let Some(version) = value("IMAGE_VERSION") {
+        let _ = write!(title, " ({})", version);
+    }
+    title
+}
+
+/// A UKI found on the ESP
+#[derive(Debug, Clone)]
+pub struct Image {
+    /// Path of the file on the ESP
+    pub path: String<32>,
+    /// Title for the boot menu
+    pub title: String<64>,
+}
+
+/// Read the menu title of the UKI `entry`, `None` if it isn't a UKI
+fn read_title(fat: &mut FatFilesystem<'_>, entry: &DirectoryEntry) -> Option<String<64>> {
+    let mut headers = [0u8; HEADERS_SIZE];
+    let len = fat.read_file(entry, 0, &mut headers).ok()?;
+    let headers = &headers[..len];
+    pe::file_section_range(headers, ".linux")?;
+
+    let Some(range) = pe::file_section_range(headers, ".osrel") else {
+        return Some(title(""));
+    };
+    let mut osrel = [0u8; MAX_OSREL_SIZE];
+    let size = range.len().min(MAX_OSREL_SIZE);
+    let len = fat
+        .read_file(entry, range.start as u32, &mut osrel[..size])
+        .ok()?;
+    let osrel = core::str::from_utf8(&osrel[..len]).unwrap_or("");
+    Some(title(trim(osrel)))
+}
+
+/// Find the UKIs in `\EFI\Linux` on an ESP, ordered by file name
+pub fn find_images(fat: &mut FatFilesystem<'_>) -> Vec<Image, MAX_IMAGES> {
+    let mut images: Vec<Image, MAX_IMAGES> = Vec::new();
+    let Ok(directory) = fat.find_file(DIRECTORY) else {
+        return images;
+    };
+    if !directory.is_directory() {
+        return images;
+    }
+
+    let cluster = directory.first_cluster();
+    for position in 0.. {
+        let Ok(Some(entry)) = fat.get_directory_entry_at_position(cluster, position) else {
+            break;
+        };
+        let name = entry.short_name();
+        let is_efi = name
+            .rsplit_once('.')
+            .is_some_and(|(_, ext)| ext.eq_ignore_ascii_case("EFI"));
+        if !entry.is_file() || !is_efi {
+            continue;
+        }
+        let Some(title) = read_title(fat, &entry) else {
+            log::debug!("{}\\{} is not a UKI", DIRECTORY, name);
+            continue;
+        };
+        let mut path = String::new();
+        let _ = write!(path, "{}\\{}", DIRECTORY, name);
+        log::info!("Found UKI {}: {}", path, title);
+        if images.push(Image { path, title }).is_err() {
+            log::warn!("More than {} UKIs on the ESP", MAX_IMAGES);
+            break;
+        }
+    }
+    images.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+    images
+}
+
+/// Whether `path` on the ESP is in the UKI directory
+pub fn is_uki_path(path: &str) -> bool {
+    let path = path.trim_start_matches('\\');
+    path.len() > DIRECTORY.len()
+        && path.is_char_boundary(DIRECTORY.len())
+        && path[..DIRECTORY.len()].eq_ignore_ascii_case(DIRECTORY)
+        && path[DIRECTORY.len()..].starts_with('\\')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fat::FatType;
+    use crate::testing::{MemoryDisk, fat_image, put_u16, put_u32};
+
+    /// Build a PE file with one section per `(name, data)`, file alignment 0x200
+    fn pe_file(sections: &[(&str, &[u8])]) -> std::vec::Vec<u8> {
+        let mut file = std::vec![0u8; 0x200];
+        file[0..2].copy_from_slice(b"MZ");
+        put_u32(&mut file, 0x3C, 0x40);
+        file[0x40..0x44].copy_from_slice(b"PE\0\0");
+        put_u16(&mut file, 0x46, sections.len() as u16);
+        put_u16(&mut file, 0x54, 0xF0);
+        for (i, (name, data)) in sections.iter().enumerate() {
+            let header = 0x44 + 20 + 0xF0 + i * 40;
+            let raw_size = data.len().next_multiple_of(0x200);
+            file[header..header + name.len()].copy_from_slice(name.as_bytes());
+            put_u32(&mut file, header + 8, data.len() as u32);
+            put_u32(&mut file, header + 16, raw_size as u32);
+            let offset = file.len() as u32;
+            put_u32(&mut file, header + 20, offset);
+            file.extend_from_slice(data);
+            file.resize(file.len() + raw_size - data.len(), 0);
+        }
+        file
+    }
+
+    const OSREL: &[u8] = b"NAME=\"Arch Linux\"\nPRETTY_NAME=\"Arch Linux\"\nID=arch\n\0";
+
+    #[test]
+    fn parses_sections() {
+        let file = pe_file(&[
+            (".osrel", OSREL),
+            (".cmdline", b"root=LABEL=arch rw quiet\n\0"),
+            (".initrd", b"initramfs"),
+            (".linux", b"MZkernel"),
+        ]);
+        let uki = Uki::parse(&file).unwrap();
+        assert_eq!(uki.linux, b"MZkernel");
+        assert_eq!(uki.initrd, b"initramfs");
+        assert_eq!(uki.cmdline, "root=LABEL=arch rw quiet");
+        assert!(uki.splash.is_empty());
+        assert_eq!(uki.title().as_str(), "Arch Linux");
+
+        // systemd-boot itself and addons have no kernel
+        assert!(Uki::parse(&pe_file(&[(".osrel", OSREL)])).is_none());
+    }
+
+    #[test]
+    fn titles() {
+        assert_eq!(
+            title("NAME='Fedora Linux'\nVERSION_ID=40").as_str(),
+            "Fedora Linux 40"
+        );
+        assert_eq!(
+            title("PRETTY_NAME=\"Debian 12\"\nIMAGE_VERSION=1.2").as_str(),
+            "Debian 12 (1.2)"
+        );
+        assert_eq!(title("PRETTY_NAME=\nID=x").as_str(), "Linux");
+        assert_eq!(os_release_value(" ID = arch ", "ID"), None);
+        assert_eq!(os_release_value("ID=arch", "ID"), Some("arch"));
+    }
+
+    #[test]
+    fn finds_images() {
+        let arch = pe_file(&[(".osrel", OSREL), (".linux", b"MZkernel")]);
+        let addon = pe_file(&[(".cmdline", b"debug")]);
+        let esp = fat_image(
+            FatType::Fat16,
+            &[
+                ("EFI/LINUX/ARCH.EFI", &arch),
+                ("EFI/LINUX/ADDON.EFI", &addon),
+                ("EFI/LINUX/README.TXT", b"not a UKI"),
+            ],
+        );
+        let mut disk = MemoryDisk::new(esp, 512);
+        let mut fat = FatFilesystem::new(&mut disk, 0).unwrap();
+
+        let images = find_images(&mut fat);
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].path.as_str(), "EFI\\Linux\\ARCH.EFI");
+        assert_eq!(images[0].title.as_str(), "Arch Linux");
+
+        assert!(is_uki_path(&images[0].path));
+        assert!(is_uki_path("\\efi\\linux\\arch.efi"));
+        assert!(!is_uki_path("EFI\\LinuxX\\arch.efi"));
+        assert!(!is_uki_path("EFI\\BOOT\\BOOTX64.EFI"));
+    }
+}
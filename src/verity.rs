@@ -0,0 +1,187 @@
+//! Verified boot images
+//!
+//! For appliances that boot a fixed kernel and initrd, a list of SHA-256
+//! hashes can be built into the firmware image as the CBFS file
+//! `crabefi/hashes`. CBFS lives in the write-protected boot flash, so the
+//! list is as trustworthy as the firmware itself and no signing keys are
+//! involved at boot.
+//!
+//! When the list is present, every file CrabEFI reads from the ESP must be
+//! listed with a matching hash: the bootloader it starts, and every file a
+//! loader opens through the Simple File System protocol, such as the
+//! kernel and initrd. Unlisted or modified files are refused, so the list
+//! has to name everything the boot chain reads, configuration included.
+//!
+//! The list uses the `sha256sum` format, one file per line with paths
+//! relative to the ESP root; lines starting with `#` are ignored:
+//!
+//! ```text
+//! # cbfstool coreboot.rom add -f hashes -n crabefi/hashes -t raw
+//! 3a7bd3e2360a3d29eea436fcfb7e44c735d117c42d1c1835420b6b9942dd4f1b  EFI/Linux/vmlinuz.efi
+//! 0d1e2f...  EFI/Linux/initrd.img
+//! ```
+
+use spin::Mutex;
+
+use crate::coreboot::cbfs;
+use crate::crypto::Digest;
+use crate::crypto::sha256::Sha256;
+
+/// CBFS file holding the hash list
+pub const CBFS_NAME: &str = "crabefi/hashes";
+
+/// Size of a SHA-256 hash
+const HASH_SIZE: usize = 32;
+
+/// Why a file may not be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerityError {
+    /// The file is not in the hash list
+    NotListed,
+    /// The file's hash differs from the listed one
+    Mismatch,
+}
+
+/// A list of files and their hashes
+#[derive(Clone, Copy)]
+pub struct HashList<'a> {
+    text: &'a str,
+}
+
+impl<'a> HashList<'a> {
+    pub fn new(text: &'a str) -> Self {
+        HashList { text }
+    }
+
+    /// Listed hash and path of each valid line
+    fn entries(&self) -> impl Iterator<Item = ([u8; HASH_SIZE], &'a str)> {
+        self.text.lines().filter_map(|line| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let entry = parse_line(line);
+            if entry.is_none() {
+                log::warn!("Verity: ignoring invalid line {:?}", line);
+            }
+            entry
+        })
+    }
+
+    /// Check `data` read from `path` against the list
+    pub fn check(&self, path: &str, data: &[u8]) -> Result<(), VerityError> {
+        let (expected, _) = self
+            .entries()
+            .find(|&(_, listed)| same_path(listed, path))
+            .ok_or(VerityError::NotListed)?;
+        let mut digest = Sha256::default();
+        digest.update(data);
+        let mut hash = [0u8; HASH_SIZE];
+        digest.finalize_into(&mut hash);
+        if hash == expected {
+            Ok(())
+        } else {
+            Err(VerityError::Mismatch)
+        }
+    }
+}
+
+/// Parse `<hex hash>  <path>`; `sha256sum` marks binary mode with `*`
+fn parse_line(line: &str) -> Option<([u8; HASH_SIZE], &str)> {
+    let (hex, path) = line.split_once(char::is_whitespace)?;
+    let path = path.trim_start();
+    let path = path.strip_prefix('*').unwrap_or(path);
+    if hex.len() != HASH_SIZE * 2 || path.is_empty() {
+        return None;
+    }
+    let mut hash = [0u8; HASH_SIZE];
+    for (byte, pair) in hash.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some((hash, path))
+}
+
+/// Compare ESP paths the way FAT does: without case, with either separator
+/// and with or without a leading one
+fn same_path(a: &str, b: &str) -> bool {
+    fn components(path: &str) -> impl Iterator<Item = &str> {
+        path.split(['/', '\\']).filter(|c| !c.is_empty())
+    }
+    components(a).count() == components(b).count()
+        && components(a)
+            .zip(components(b))
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+/// The hash list built into the firmware, if any
+static HASH_LIST: Mutex<Option<HashList<'static>>> = Mutex::new(None);
+
+/// Enforce the hash list from CBFS, if the firmware has one
+pub fn init() {
+    let Some(data) = cbfs::find_file(CBFS_NAME) else {
+        return;
+    };
+    // An unreadable list still enforces: nothing matches it
+    let text = core::str::from_utf8(data).unwrap_or_else(|_| {
+        log::error!("Verity: {} is not text, refusing all files", CBFS_NAME);
+        ""
+    });
+    let list = HashList::new(text);
+    log::info!(
+        "Verity: enforcing {} image hashes from CBFS",
+        list.entries().count()
+    );
+    *HASH_LIST.lock() = Some(list);
+}
+
+/// Whether files must match the hash list
+pub fn is_enforcing() -> bool {
+    HASH_LIST.lock().is_some()
+}
+
+/// Check `data` read from `path` against the hash list
+///
+/// Always succeeds when no hash list is enforced.
+pub fn check(path: &str, data: &[u8]) -> Result<(), VerityError> {
+    let Some(list) = *HASH_LIST.lock() else {
+        return Ok(());
+    };
+    let result = list.check(path, data);
+    match result {
+        Ok(()) => log::info!("Verity: {} verified", path),
+        Err(e) => log::error!("Verity: refusing {}: {:?}", path, e),
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_listed_files() {
+        // sha256("abc") and sha256("")
+        let list = HashList::new(
+            "# appliance\n\
+             ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  EFI/Linux/vmlinuz.efi\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 *\\initrd.img\n\
+             not a hash  EFI/BOOT/BOOTX64.EFI\n",
+        );
+
+        assert_eq!(list.check("\\EFI\\linux\\VMLINUZ.EFI", b"abc"), Ok(()));
+        assert_eq!(
+            list.check("/EFI/Linux/vmlinuz.efi", b"abd"),
+            Err(VerityError::Mismatch)
+        );
+        assert_eq!(list.check("initrd.img", b""), Ok(()));
+        assert_eq!(
+            list.check("EFI/BOOT/BOOTX64.EFI", b""),
+            Err(VerityError::NotListed)
+        );
+        assert_eq!(
+            list.check("EFI/Linux/vmlinuz.efi/x", b"abc"),
+            Err(VerityError::NotListed)
+        );
+        assert_eq!(list.check("EFI/Linux", b"abc"), Err(VerityError::NotListed));
+    }
+}
@@ -0,0 +1,87 @@
+//! ExitBootServices test application
+//!
+//! This application fetches the memory map, calls ExitBootServices and halts.
+//! Used by the QEMU integration tests to check that CrabEFI hands over to an
+//! OS loader correctly.
+
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use r_efi::efi::{self, Char16, Handle, Status, SystemTable};
+
+/// Size of the buffer receiving the memory map
+const MEMORY_MAP_SIZE: usize = 16 * 1024;
+
+/// Memory map buffer, aligned for `MemoryDescriptor`
+#[repr(C, align(8))]
+struct MemoryMapBuffer([u8; MEMORY_MAP_SIZE]);
+
+static mut MEMORY_MAP: MemoryMapBuffer = MemoryMapBuffer([0; MEMORY_MAP_SIZE]);
+
+/// Print an ASCII string on the console
+fn print(system_table: *mut SystemTable, s: &str) {
+    let mut buf = [0 as Char16; 128];
+    for (dst, b) in buf.iter_mut().zip(s.bytes().take(127)) {
+        *dst = b as Char16;
+    }
+
+    unsafe {
+        let con_out = (*system_table).con_out;
+        if !con_out.is_null() {
+            ((*con_out).output_string)(con_out, buf.as_mut_ptr());
+        }
+    }
+}
+
+/// EFI entry point
+#[no_mangle]
+pub extern "efiapi" fn efi_main(image_handle: Handle, system_table: *mut SystemTable) -> Status {
+    print(system_table, "Calling ExitBootServices\r\n");
+
+    let boot_services = unsafe { (*system_table).boot_services };
+
+    // The first attempt may fail if the memory map changed in between,
+    // in which case the spec requires fetching the map again
+    let mut status = Status::INVALID_PARAMETER;
+    for _ in 0..2 {
+        let mut map_size = MEMORY_MAP_SIZE;
+        let mut map_key = 0;
+        let mut descriptor_size = 0;
+        let mut descriptor_version = 0;
+
+        status = unsafe {
+            ((*boot_services).get_memory_map)(
+                &mut map_size,
+                core::ptr::addr_of_mut!(MEMORY_MAP) as *mut efi::MemoryDescriptor,
+                &mut map_key,
+                &mut descriptor_size,
+                &mut descriptor_version,
+            )
+        };
+        if status.is_error() {
+            print(system_table, "GetMemoryMap failed\r\n");
+            return status;
+        }
+
+        status = unsafe { ((*boot_services).exit_boot_services)(image_handle, map_key) };
+        if !status.is_error() {
+            break;
+        }
+    }
+
+    if status.is_error() {
+        print(system_table, "ExitBootServices failed\r\n");
+        return status;
+    }
+
+    // Boot services are gone; there is nothing to return to
+    loop {
+        unsafe { core::arch::asm!("hlt") };
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
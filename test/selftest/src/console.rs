@@ -0,0 +1,66 @@
+//! Console output for the self-test
+//!
+//! Implements `core::fmt::Write` on top of the EFI text output protocol so
+//! results can be formatted without an allocator.
+
+use core::fmt;
+use r_efi::efi::{Char16, SystemTable};
+use r_efi::protocols::simple_text_output::Protocol as SimpleTextOutput;
+
+/// Console wrapper for EFI text output
+pub struct Console {
+    con_out: *mut SimpleTextOutput,
+}
+
+impl Console {
+    /// Create a new console from the system table
+    pub fn new(system_table: *mut SystemTable) -> Self {
+        let con_out = unsafe { (*system_table).con_out };
+        Self { con_out }
+    }
+
+    /// Output a null-terminated UCS-2 buffer
+    fn output(&mut self, buffer: &mut [Char16]) {
+        unsafe {
+            ((*self.con_out).output_string)(self.con_out, buffer.as_mut_ptr());
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.con_out.is_null() {
+            return Ok(());
+        }
+
+        // Convert to UCS-2 in chunks, expanding '\n' to CRLF
+        let mut buffer: [Char16; 128] = [0; 128];
+        let mut len = 0;
+
+        for c in s.chars() {
+            if c == '\n' {
+                buffer[len] = '\r' as Char16;
+                len += 1;
+            }
+            buffer[len] = if (c as u32) <= 0xFFFF {
+                c as Char16
+            } else {
+                '?' as Char16
+            };
+            len += 1;
+
+            if len >= buffer.len() - 3 {
+                buffer[len] = 0;
+                self.output(&mut buffer);
+                len = 0;
+            }
+        }
+
+        if len > 0 {
+            buffer[len] = 0;
+            self.output(&mut buffer);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+//! Event services: CreateEvent, SignalEvent, CheckEvent, WaitForEvent, SetTimer
+
+use crate::{check, ensure, Context, TestResult};
+use r_efi::efi::{self, Event, Status};
+
+/// Upper bound for waiting on a timer, in microseconds
+const TIMER_TIMEOUT_US: usize = 500_000;
+
+/// Signal an event by hand and observe it through CheckEvent and WaitForEvent
+pub fn test_events(ctx: &Context) -> TestResult {
+    let bs = ctx.bs();
+
+    let mut event: Event = core::ptr::null_mut();
+    check(
+        (bs.create_event)(
+            0,
+            efi::TPL_APPLICATION,
+            None,
+            core::ptr::null_mut(),
+            &mut event,
+        ),
+        "CreateEvent",
+    )?;
+
+    let result = (|| {
+        ensure(
+            (bs.check_event)(event) == Status::NOT_READY,
+            "CheckEvent on fresh event not NOT_READY",
+        )?;
+        check((bs.signal_event)(event), "SignalEvent")?;
+
+        // WaitForEvent must return at once for a signaled event; CheckEvent
+        // is used first so that a broken implementation cannot hang us
+        check((bs.check_event)(event), "CheckEvent after SignalEvent")?;
+        check((bs.signal_event)(event), "SignalEvent")?;
+
+        let mut index = usize::MAX;
+        check(
+            (bs.wait_for_event)(1, &mut event, &mut index),
+            "WaitForEvent",
+        )?;
+        ensure(index == 0, "WaitForEvent returned wrong index")
+    })();
+
+    check((bs.close_event)(event), "CloseEvent")?;
+    result
+}
+
+/// Arm a relative timer and poll until it fires
+pub fn test_timer(ctx: &Context) -> TestResult {
+    let bs = ctx.bs();
+
+    let mut event: Event = core::ptr::null_mut();
+    check(
+        (bs.create_event)(
+            efi::EVT_TIMER,
+            efi::TPL_APPLICATION,
+            None,
+            core::ptr::null_mut(),
+            &mut event,
+        ),
+        "CreateEvent(EVT_TIMER)",
+    )?;
+
+    let result = (|| {
+        // 10ms, in 100ns units
+        check(
+            (bs.set_timer)(event, efi::TIMER_RELATIVE, 100_000),
+            "SetTimer",
+        )?;
+
+        let mut waited = 0;
+        loop {
+            let status = (bs.check_event)(event);
+            if status != Status::NOT_READY {
+                return check(status, "CheckEvent on timer");
+            }
+
+            ensure(waited < TIMER_TIMEOUT_US, "timer never fired")?;
+            check((bs.stall)(1000), "Stall")?;
+            waited += 1000;
+        }
+    })();
+
+    check((bs.close_event)(event), "CloseEvent")?;
+    result
+}
@@ -0,0 +1,133 @@
+//! File I/O: Simple File System on the boot device, reading our own image
+
+use crate::{check, ensure, fail, Context, TestResult};
+use core::ffi::c_void;
+use r_efi::efi::Char16;
+use r_efi::protocols::{device_path, file, loaded_image, simple_file_system};
+
+/// Maximum path length (in UCS-2 characters) we handle
+const MAX_PATH: usize = 128;
+
+/// Open our own image file through the boot device's file system and read it
+pub fn test_file_io(ctx: &Context) -> TestResult {
+    let bs = ctx.bs();
+
+    let mut interface: *mut c_void = core::ptr::null_mut();
+    let mut guid = loaded_image::PROTOCOL_GUID;
+    check(
+        (bs.handle_protocol)(ctx.image_handle, &mut guid, &mut interface),
+        "HandleProtocol(LoadedImage)",
+    )?;
+    let image = unsafe { &*(interface as *const loaded_image::Protocol) };
+
+    let mut path = [0 as Char16; MAX_PATH];
+    if !file_path(image.file_path, &mut path) {
+        return fail("no file path node in LoadedImage.FilePath");
+    }
+
+    let mut guid = simple_file_system::PROTOCOL_GUID;
+    check(
+        (bs.handle_protocol)(image.device_handle, &mut guid, &mut interface),
+        "HandleProtocol(SimpleFileSystem)",
+    )?;
+    let fs = interface as *mut simple_file_system::Protocol;
+
+    let mut root: *mut file::Protocol = core::ptr::null_mut();
+    check(unsafe { ((*fs).open_volume)(fs, &mut root) }, "OpenVolume")?;
+
+    let result = read_image_file(root, &mut path);
+
+    check(unsafe { ((*root).close)(root) }, "Close(root)")?;
+    result
+}
+
+/// Open `path` below `root`, check its size and PE signature
+fn read_image_file(root: *mut file::Protocol, path: &mut [Char16]) -> TestResult {
+    let mut handle: *mut file::Protocol = core::ptr::null_mut();
+    check(
+        unsafe { ((*root).open)(root, &mut handle, path.as_mut_ptr(), file::MODE_READ, 0) },
+        "Open(own image)",
+    )?;
+
+    let result = (|| {
+        let mut info: file::Info<MAX_PATH> = unsafe { core::mem::zeroed() };
+        let mut size = core::mem::size_of_val(&info);
+        let mut guid = file::INFO_ID;
+        check(
+            unsafe {
+                ((*handle).get_info)(
+                    handle,
+                    &mut guid,
+                    &mut size,
+                    &mut info as *mut _ as *mut c_void,
+                )
+            },
+            "GetInfo(FileInfo)",
+        )?;
+        ensure(info.file_size > 64, "file size implausibly small")?;
+
+        let mut header = [0u8; 2];
+        let mut size = header.len();
+        check(
+            unsafe { ((*handle).read)(handle, &mut size, header.as_mut_ptr() as *mut c_void) },
+            "Read",
+        )?;
+        ensure(
+            size == 2 && header == *b"MZ",
+            "image does not start with MZ",
+        )?;
+
+        let mut position = 0;
+        check(
+            unsafe { ((*handle).get_position)(handle, &mut position) },
+            "GetPosition",
+        )?;
+        ensure(position == 2, "GetPosition does not reflect the read")?;
+
+        // Reading at end of file must succeed and return nothing
+        check(
+            unsafe { ((*handle).set_position)(handle, info.file_size) },
+            "SetPosition",
+        )?;
+        let mut size = header.len();
+        check(
+            unsafe { ((*handle).read)(handle, &mut size, header.as_mut_ptr() as *mut c_void) },
+            "Read at EOF",
+        )?;
+        ensure(size == 0, "read past end of file")
+    })();
+
+    check(unsafe { ((*handle).close)(handle) }, "Close(own image)")?;
+    result
+}
+
+/// Copy the first file path node of a device path into `out`
+fn file_path(mut node: *const device_path::Protocol, out: &mut [Char16]) -> bool {
+    if node.is_null() {
+        return false;
+    }
+
+    unsafe {
+        loop {
+            let kind = (*node).r#type;
+            let sub_type = (*node).sub_type;
+            let length = u16::from_le_bytes((*node).length) as usize;
+            if kind == device_path::TYPE_END || length < 4 {
+                return false;
+            }
+
+            if kind == device_path::TYPE_MEDIA && sub_type == device_path::Media::SUBTYPE_FILE_PATH
+            {
+                let chars = (node as *const u8).add(4) as *const Char16;
+                let count = ((length - 4) / 2).min(out.len() - 1);
+                for (i, c) in out.iter_mut().take(count).enumerate() {
+                    *c = core::ptr::read_unaligned(chars.add(i));
+                }
+                out[count] = 0;
+                return true;
+            }
+
+            node = (node as *const u8).add(length) as *const device_path::Protocol;
+        }
+    }
+}
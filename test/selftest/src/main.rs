@@ -0,0 +1,168 @@
+//! CrabEFI Self-Test Application
+//!
+//! This EFI application exercises the boot services implemented by CrabEFI
+//! and reports which ones work. It is meant for bringing up CrabEFI on a new
+//! coreboot board: install it as `EFI\CRABEFI\SELFTEST.EFI` on the ESP and
+//! CrabEFI adds a "CrabEFI Diagnostics" entry to its boot menu.
+//!
+//! Tests performed:
+//! 1. Memory map retrieval
+//! 2. Page and pool allocation
+//! 3. Event creation, signaling and waiting
+//! 4. Timer events
+//! 5. Protocol installation and lookup
+//! 6. File I/O through Simple File System
+//! 7. Miscellaneous services (CRC32, monotonic count, GetTime)
+//!
+//! Every test prints `[PASS]` or `[FAIL]` with the failing call and status;
+//! the application returns `EFI_ABORTED` if any test failed.
+
+#![no_std]
+#![no_main]
+
+mod console;
+mod events;
+mod file_io;
+mod memory;
+mod misc;
+mod protocols;
+
+use console::Console;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use r_efi::efi::{BootServices, Handle, Status, SystemTable};
+
+/// Global console, used by the panic handler
+static mut CONSOLE: Option<Console> = None;
+
+/// Everything a test needs to call into the firmware
+pub struct Context {
+    pub image_handle: Handle,
+    pub system_table: *mut SystemTable,
+    pub boot_services: *mut BootServices,
+}
+
+impl Context {
+    /// Boot services table
+    pub fn bs(&self) -> &BootServices {
+        unsafe { &*self.boot_services }
+    }
+}
+
+/// Why a test failed
+pub struct Failure {
+    /// What went wrong, usually the failing call
+    pub what: &'static str,
+    /// Status returned by the firmware, if any
+    pub status: Option<Status>,
+}
+
+/// Result of a single test
+pub type TestResult = Result<(), Failure>;
+
+/// Fail with a message and no status
+pub fn fail(what: &'static str) -> TestResult {
+    Err(Failure { what, status: None })
+}
+
+/// Turn an error status from `what` into a failure
+pub fn check(status: Status, what: &'static str) -> TestResult {
+    if status.is_error() {
+        Err(Failure {
+            what,
+            status: Some(status),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Fail with `what` unless `condition` holds
+pub fn ensure(condition: bool, what: &'static str) -> TestResult {
+    if condition {
+        Ok(())
+    } else {
+        fail(what)
+    }
+}
+
+/// A test function
+type Test = fn(&Context) -> TestResult;
+
+/// All tests, in the order they run
+const TESTS: &[(&str, Test)] = &[
+    ("Memory map", memory::test_memory_map),
+    ("Page allocation", memory::test_pages),
+    ("Pool allocation", memory::test_pool),
+    ("Events", events::test_events),
+    ("Timer events", events::test_timer),
+    ("Protocol database", protocols::test_protocol_database),
+    ("Loaded image", protocols::test_loaded_image),
+    ("File I/O", file_io::test_file_io),
+    ("CRC32", misc::test_crc32),
+    ("Monotonic count", misc::test_monotonic_count),
+    ("Runtime GetTime", misc::test_get_time),
+];
+
+/// EFI entry point
+#[no_mangle]
+extern "efiapi" fn efi_main(image_handle: Handle, system_table: *mut SystemTable) -> Status {
+    let console = unsafe {
+        CONSOLE = Some(Console::new(system_table));
+        (*core::ptr::addr_of_mut!(CONSOLE)).as_mut().unwrap()
+    };
+
+    let ctx = Context {
+        image_handle,
+        system_table,
+        boot_services: unsafe { (*system_table).boot_services },
+    };
+
+    let _ = writeln!(console, "==============================================");
+    let _ = writeln!(console, "  CrabEFI Self-Test");
+    let _ = writeln!(console, "==============================================");
+
+    let mut failed = 0;
+    for (i, (name, test)) in TESTS.iter().enumerate() {
+        let _ = write!(console, "[{:2}] {:<20} ", i + 1, name);
+        match test(&ctx) {
+            Ok(()) => {
+                let _ = writeln!(console, "[PASS]");
+            }
+            Err(failure) => {
+                failed += 1;
+                let _ = write!(console, "[FAIL] {}", failure.what);
+                if let Some(status) = failure.status {
+                    let _ = write!(console, " ({:#x})", status.as_usize());
+                }
+                let _ = writeln!(console);
+            }
+        }
+    }
+
+    let _ = writeln!(console, "==============================================");
+    let _ = writeln!(
+        console,
+        "Self-test finished: {} passed, {} failed",
+        TESTS.len() - failed,
+        failed
+    );
+
+    if failed == 0 {
+        Status::SUCCESS
+    } else {
+        Status::ABORTED
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    unsafe {
+        if let Some(console) = (*core::ptr::addr_of_mut!(CONSOLE)).as_mut() {
+            let _ = writeln!(console, "\n!!! PANIC: {}", info);
+        }
+    }
+    loop {
+        core::hint::spin_loop();
+    }
+}
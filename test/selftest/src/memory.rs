@@ -0,0 +1,148 @@
+//! Memory services: GetMemoryMap, AllocatePages/FreePages, AllocatePool/FreePool
+
+use crate::{check, ensure, fail, Context, TestResult};
+use core::ffi::c_void;
+use r_efi::efi::{self, MemoryDescriptor, Status};
+
+/// Fetch the memory map and sanity check its contents
+pub fn test_memory_map(ctx: &Context) -> TestResult {
+    let bs = ctx.bs();
+
+    // A zero-sized buffer must report the required size
+    let mut map_size = 0;
+    let mut map_key = 0;
+    let mut descriptor_size = 0;
+    let mut descriptor_version = 0;
+    let status = (bs.get_memory_map)(
+        &mut map_size,
+        core::ptr::null_mut(),
+        &mut map_key,
+        &mut descriptor_size,
+        &mut descriptor_version,
+    );
+    if status != Status::BUFFER_TOO_SMALL {
+        check(status, "GetMemoryMap(size 0)")?;
+        return fail("GetMemoryMap(size 0) did not return BUFFER_TOO_SMALL");
+    }
+    ensure(map_size > 0, "GetMemoryMap reported size 0")?;
+    ensure(
+        descriptor_size >= core::mem::size_of::<MemoryDescriptor>(),
+        "descriptor size too small",
+    )?;
+
+    // Leave room for the descriptors added by our own pool allocation
+    map_size += 4 * descriptor_size;
+    let mut buffer: *mut c_void = core::ptr::null_mut();
+    check(
+        (bs.allocate_pool)(efi::LOADER_DATA, map_size, &mut buffer),
+        "AllocatePool",
+    )?;
+
+    let result = (|| {
+        check(
+            (bs.get_memory_map)(
+                &mut map_size,
+                buffer as *mut MemoryDescriptor,
+                &mut map_key,
+                &mut descriptor_size,
+                &mut descriptor_version,
+            ),
+            "GetMemoryMap",
+        )?;
+        ensure(
+            descriptor_version == efi::MEMORY_DESCRIPTOR_VERSION,
+            "unexpected descriptor version",
+        )?;
+
+        let count = map_size / descriptor_size;
+        ensure(count > 0, "memory map is empty")?;
+
+        let descriptor = |i: usize| unsafe {
+            core::ptr::read_unaligned(
+                (buffer as *const u8).add(i * descriptor_size) as *const MemoryDescriptor
+            )
+        };
+        let end = |desc: &MemoryDescriptor| desc.physical_start + desc.number_of_pages * 4096;
+
+        let mut conventional_pages = 0;
+        for i in 0..count {
+            let desc = descriptor(i);
+            ensure(desc.number_of_pages > 0, "descriptor with zero pages")?;
+            ensure(desc.physical_start & 0xFFF == 0, "unaligned descriptor")?;
+
+            // The spec does not require sorting, so compare every pair
+            for j in 0..i {
+                let other = descriptor(j);
+                ensure(
+                    end(&desc) <= other.physical_start || end(&other) <= desc.physical_start,
+                    "overlapping descriptors",
+                )?;
+            }
+
+            if desc.r#type == efi::CONVENTIONAL_MEMORY {
+                conventional_pages += desc.number_of_pages;
+            }
+        }
+        ensure(conventional_pages > 0, "no conventional memory")
+    })();
+
+    check((bs.free_pool)(buffer), "FreePool")?;
+    result
+}
+
+/// Allocate, use and free a few pages
+pub fn test_pages(ctx: &Context) -> TestResult {
+    let bs = ctx.bs();
+    const PAGES: usize = 4;
+
+    let mut address: efi::PhysicalAddress = 0;
+    check(
+        (bs.allocate_pages)(
+            efi::ALLOCATE_ANY_PAGES,
+            efi::LOADER_DATA,
+            PAGES,
+            &mut address,
+        ),
+        "AllocatePages",
+    )?;
+    ensure(address != 0, "AllocatePages returned address 0")?;
+    ensure(
+        address & 0xFFF == 0,
+        "AllocatePages returned unaligned address",
+    )?;
+
+    let words = unsafe { core::slice::from_raw_parts_mut(address as *mut u64, PAGES * 512) };
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = i as u64 ^ 0x5A5A_5A5A_5A5A_5A5A;
+    }
+    let intact = words
+        .iter()
+        .enumerate()
+        .all(|(i, &word)| word == i as u64 ^ 0x5A5A_5A5A_5A5A_5A5A);
+
+    check((bs.free_pages)(address, PAGES), "FreePages")?;
+    ensure(intact, "page contents corrupted")
+}
+
+/// Allocate and free pool memory of a few sizes
+pub fn test_pool(ctx: &Context) -> TestResult {
+    let bs = ctx.bs();
+
+    for size in [1usize, 64, 4000, 64 * 1024] {
+        let mut buffer: *mut c_void = core::ptr::null_mut();
+        check(
+            (bs.allocate_pool)(efi::LOADER_DATA, size, &mut buffer),
+            "AllocatePool",
+        )?;
+        ensure(!buffer.is_null(), "AllocatePool returned NULL")?;
+        ensure(
+            (buffer as usize).is_multiple_of(8),
+            "pool buffer not 8-byte aligned",
+        )?;
+
+        unsafe { core::ptr::write_bytes(buffer as *mut u8, 0xA5, size) };
+        check((bs.free_pool)(buffer), "FreePool")?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+//! Miscellaneous services: CalculateCrc32, GetNextMonotonicCount, GetTime
+
+use crate::{check, ensure, Context, TestResult};
+use core::ffi::c_void;
+use r_efi::efi::Time;
+
+/// CRC32 of the standard check input
+pub fn test_crc32(ctx: &Context) -> TestResult {
+    let bs = ctx.bs();
+
+    let mut data = *b"123456789";
+    let mut crc = 0;
+    check(
+        (bs.calculate_crc32)(data.as_mut_ptr() as *mut c_void, data.len(), &mut crc),
+        "CalculateCrc32",
+    )?;
+    ensure(crc == 0xCBF4_3926, "CalculateCrc32 returned wrong value")
+}
+
+/// The monotonic count must increase on every call
+pub fn test_monotonic_count(ctx: &Context) -> TestResult {
+    let bs = ctx.bs();
+
+    let mut first = 0;
+    let mut second = 0;
+    check(
+        (bs.get_next_monotonic_count)(&mut first),
+        "GetNextMonotonicCount",
+    )?;
+    check(
+        (bs.get_next_monotonic_count)(&mut second),
+        "GetNextMonotonicCount",
+    )?;
+    ensure(second > first, "monotonic count did not increase")
+}
+
+/// Read the RTC through runtime services
+pub fn test_get_time(ctx: &Context) -> TestResult {
+    let rt = unsafe { (*ctx.system_table).runtime_services };
+    ensure(!rt.is_null(), "RuntimeServices is NULL")?;
+
+    let mut time = Time::default();
+    check(
+        unsafe { ((*rt).get_time)(&mut time, core::ptr::null_mut()) },
+        "GetTime",
+    )?;
+    ensure(
+        (1998..=2099).contains(&time.year)
+            && (1..=12).contains(&time.month)
+            && (1..=31).contains(&time.day)
+            && time.hour < 24
+            && time.minute < 60
+            && time.second < 60,
+        "GetTime returned an invalid date",
+    )
+}
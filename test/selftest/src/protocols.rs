@@ -0,0 +1,124 @@
+//! Protocol services: Install/UninstallProtocolInterface, HandleProtocol,
+//! LocateProtocol, LocateHandleBuffer
+
+use crate::{check, ensure, fail, Context, TestResult};
+use core::ffi::c_void;
+use r_efi::efi::{self, Guid, Handle, Status};
+use r_efi::protocols::loaded_image;
+
+/// Private GUID for the test protocol, not used by anything else
+const TEST_PROTOCOL_GUID: Guid = Guid::from_fields(
+    0x5c3e_4f2a,
+    0x9d1b,
+    0x4e6f,
+    0x8a,
+    0x27,
+    &[0xc4, 0x1d, 0x5e, 0x90, 0x3b, 0x62],
+);
+
+/// Interface installed for the test protocol
+static TEST_INTERFACE: u64 = 0xC8AB_EF1C_5E1F_7E57;
+
+/// Install a protocol on a new handle, find it again and remove it
+pub fn test_protocol_database(ctx: &Context) -> TestResult {
+    let bs = ctx.bs();
+    let mut guid = TEST_PROTOCOL_GUID;
+    let interface = core::ptr::addr_of!(TEST_INTERFACE) as *mut c_void;
+
+    let mut handle: Handle = core::ptr::null_mut();
+    check(
+        (bs.install_protocol_interface)(&mut handle, &mut guid, efi::NATIVE_INTERFACE, interface),
+        "InstallProtocolInterface",
+    )?;
+    ensure(
+        !handle.is_null(),
+        "InstallProtocolInterface returned NULL handle",
+    )?;
+
+    let result = (|| {
+        let mut found: *mut c_void = core::ptr::null_mut();
+        check(
+            (bs.handle_protocol)(handle, &mut guid, &mut found),
+            "HandleProtocol",
+        )?;
+        ensure(
+            found == interface,
+            "HandleProtocol returned wrong interface",
+        )?;
+
+        found = core::ptr::null_mut();
+        check(
+            (bs.locate_protocol)(&mut guid, core::ptr::null_mut(), &mut found),
+            "LocateProtocol",
+        )?;
+        ensure(
+            found == interface,
+            "LocateProtocol returned wrong interface",
+        )?;
+
+        let mut count = 0;
+        let mut handles: *mut Handle = core::ptr::null_mut();
+        check(
+            (bs.locate_handle_buffer)(
+                efi::BY_PROTOCOL,
+                &mut guid,
+                core::ptr::null_mut(),
+                &mut count,
+                &mut handles,
+            ),
+            "LocateHandleBuffer",
+        )?;
+        let matches = !handles.is_null()
+            && unsafe { core::slice::from_raw_parts(handles, count) } == [handle];
+        if !handles.is_null() {
+            check((bs.free_pool)(handles as *mut c_void), "FreePool")?;
+        }
+        ensure(matches, "LocateHandleBuffer returned wrong handles")
+    })();
+
+    check(
+        (bs.uninstall_protocol_interface)(handle, &mut guid, interface),
+        "UninstallProtocolInterface",
+    )?;
+    result?;
+
+    let mut found: *mut c_void = core::ptr::null_mut();
+    let status = (bs.locate_protocol)(&mut guid, core::ptr::null_mut(), &mut found);
+    if status != Status::NOT_FOUND {
+        return fail("protocol still present after uninstall");
+    }
+
+    Ok(())
+}
+
+/// Check the Loaded Image protocol installed on our own image handle
+pub fn test_loaded_image(ctx: &Context) -> TestResult {
+    let bs = ctx.bs();
+    let mut guid = loaded_image::PROTOCOL_GUID;
+
+    let mut interface: *mut c_void = core::ptr::null_mut();
+    check(
+        (bs.handle_protocol)(ctx.image_handle, &mut guid, &mut interface),
+        "HandleProtocol(LoadedImage)",
+    )?;
+    ensure(!interface.is_null(), "LoadedImage is NULL")?;
+
+    let image = unsafe { &*(interface as *const loaded_image::Protocol) };
+    ensure(
+        image.system_table == ctx.system_table,
+        "LoadedImage.SystemTable mismatch",
+    )?;
+
+    // Our own code must lie within the reported image
+    let base = image.image_base as u64;
+    let entry = crate::efi_main as *const () as u64;
+    ensure(
+        entry >= base && entry < base + image.image_size,
+        "entry point outside LoadedImage.ImageBase/ImageSize",
+    )?;
+    ensure(
+        !image.device_handle.is_null(),
+        "LoadedImage.DeviceHandle is NULL",
+    )?;
+    ensure(!image.file_path.is_null(), "LoadedImage.FilePath is NULL")
+}
@@ -0,0 +1,399 @@
+//! Host-side harness implementation
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Harness result; errors are human-readable messages
+pub type Result<T> = std::result::Result<T, String>;
+
+/// Disk image size (64 MiB)
+const DISK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// First sector of the ESP (1 MiB aligned)
+const ESP_START_SECTOR: u64 = 2048;
+
+/// Last sector of the ESP, 1 MiB aligned and clear of the backup GPT
+const ESP_END_SECTOR: u64 = 129023;
+
+/// Default time to wait for a marker
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+/// Root of the CrabEFI repository
+pub fn project_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .ancestors()
+        .nth(2)
+        .expect("harness crate lives in tests/qemu")
+        .to_path_buf()
+}
+
+/// Scratch directory for ROMs and disk images
+pub fn work_dir() -> PathBuf {
+    let dir = project_dir().join("target").join("qemu-tests");
+    fs::create_dir_all(&dir).expect("failed to create work directory");
+    dir
+}
+
+/// Marker timeout from `CRABEFI_QEMU_TIMEOUT`
+pub fn timeout() -> Duration {
+    let secs = std::env::var("CRABEFI_QEMU_TIMEOUT")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Run a command to completion, failing on a non-zero exit status
+fn run(cmd: &mut Command) -> Result<()> {
+    let output = cmd
+        .output()
+        .map_err(|e| format!("failed to run {:?}: {}", cmd.get_program(), e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{:?} failed with {}:\n{}",
+            cmd,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(())
+}
+
+/// Check whether a tool can be executed
+fn have_tool(tool: &str) -> bool {
+    Command::new(tool)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+/// A `cargo` command for building one of the no_std crates in the tree
+///
+/// Those crates pick their target from their own `.cargo/config.toml`, so
+/// the target-related environment of the outer `cargo test` must not leak in.
+fn cargo(dir: &Path) -> Command {
+    let mut cmd = Command::new(option_env!("CARGO").unwrap_or("cargo"));
+    cmd.current_dir(dir)
+        .env_remove("CARGO_BUILD_TARGET")
+        .env_remove("CARGO_TARGET_DIR")
+        .env_remove("RUSTFLAGS")
+        .env_remove("CARGO_ENCODED_RUSTFLAGS");
+    cmd
+}
+
+/// Build the CrabEFI payload and return the path of the ELF
+pub fn build_firmware() -> Result<PathBuf> {
+    let dir = project_dir();
+    run(cargo(&dir).args(["build", "--release", "--bin", "crabefi"]))?;
+    Ok(dir.join("target/x86_64-unknown-none/release/crabefi"))
+}
+
+/// Build a test EFI application from `test/<dir>` and return the path of
+/// the `.efi` binary named `bin`
+pub fn build_efi_app(dir: &str, bin: &str) -> Result<PathBuf> {
+    let dir = project_dir().join("test").join(dir);
+    run(cargo(&dir).args(["build", "--release"]))?;
+    Ok(dir.join(format!("target/x86_64-unknown-uefi/release/{}.efi", bin)))
+}
+
+/// Copy the base coreboot ROM and replace its payload with `payload`
+pub fn build_rom(base: &Path, payload: &Path) -> Result<PathBuf> {
+    let cbfstool = cbfstool_binary();
+    let rom = work_dir().join("coreboot.rom");
+    fs::copy(base, &rom).map_err(|e| format!("failed to copy {}: {}", base.display(), e))?;
+
+    // The base ROM may have been built without a payload
+    let _ = run(Command::new(&cbfstool)
+        .arg(&rom)
+        .args(["remove", "-n", "fallback/payload"]));
+
+    run(Command::new(&cbfstool)
+        .arg(&rom)
+        .args(["add-payload", "-n", "fallback/payload", "-c", "lzma", "-f"])
+        .arg(payload))?;
+
+    Ok(rom)
+}
+
+/// Test environment shared by all tests of a binary
+pub struct TestEnv {
+    /// coreboot ROM with CrabEFI as payload
+    pub rom: PathBuf,
+}
+
+impl TestEnv {
+    /// Build the firmware and ROM once per test binary
+    ///
+    /// Returns `None`, after printing why, if the environment lacks the
+    /// coreboot ROM or the required tools; callers should skip the test.
+    /// Panics if building fails.
+    pub fn get() -> Option<&'static TestEnv> {
+        static ENV: OnceLock<Option<TestEnv>> = OnceLock::new();
+
+        ENV.get_or_init(|| {
+            let Some(base) = std::env::var_os("CRABEFI_COREBOOT_ROM") else {
+                eprintln!("skipping QEMU tests: CRABEFI_COREBOOT_ROM is not set");
+                return None;
+            };
+
+            let tools = [cbfstool_binary(), qemu_binary()];
+            for tool in tools
+                .iter()
+                .map(String::as_str)
+                .chain(["parted", "mformat", "mcopy"])
+            {
+                if !have_tool(tool) {
+                    eprintln!("skipping QEMU tests: {} not found", tool);
+                    return None;
+                }
+            }
+
+            let firmware = build_firmware().expect("failed to build CrabEFI");
+            let rom = build_rom(Path::new(&base), &firmware).expect("failed to build ROM");
+            Some(TestEnv { rom })
+        })
+        .as_ref()
+    }
+
+    /// Boot the ROM with `disk` attached as an NVMe drive
+    pub fn boot(&self, disk: &DiskImage) -> Result<QemuRun> {
+        Qemu::new(&self.rom).nvme_disk(disk.path()).spawn()
+    }
+}
+
+/// GPT disk image with a single FAT32 EFI System Partition
+pub struct DiskImage {
+    path: PathBuf,
+}
+
+impl DiskImage {
+    /// Create an image named `name` in the work directory
+    ///
+    /// `files` maps destination paths on the ESP (e.g. `EFI/BOOT/BOOTX64.EFI`)
+    /// to host files; missing directories are created.
+    pub fn create(name: &str, files: &[(&str, &Path)]) -> Result<Self> {
+        let path = work_dir().join(name);
+        let _ = fs::remove_file(&path);
+        File::create(&path)
+            .and_then(|f| f.set_len(DISK_SIZE))
+            .map_err(|e| format!("failed to create {}: {}", path.display(), e))?;
+
+        run(Command::new("parted").arg("-s").arg(&path).args([
+            "mklabel",
+            "gpt",
+            "mkpart",
+            "ESP",
+            "fat32",
+            &format!("{}s", ESP_START_SECTOR),
+            &format!("{}s", ESP_END_SECTOR),
+            "set",
+            "1",
+            "esp",
+            "on",
+        ]))?;
+
+        // mtools addresses the partition through an `image@@offset` spec
+        let image = format!("{}@@{}", path.display(), ESP_START_SECTOR * 512);
+        let sectors = ESP_END_SECTOR - ESP_START_SECTOR + 1;
+
+        run(mtools("mformat").args([
+            "-i",
+            &image,
+            "-F",
+            "-T",
+            &sectors.to_string(),
+            "-v",
+            "CRABEFI",
+            "::",
+        ]))?;
+
+        for (dest, src) in files {
+            let dest = dest.trim_start_matches('/');
+            if let Some((parents, _)) = dest.rsplit_once('/') {
+                let mut dir = String::from("::");
+                for component in parents.split('/') {
+                    dir.push('/');
+                    dir.push_str(component);
+                    // Fails harmlessly if the directory already exists
+                    let _ = run(mtools("mmd").args(["-i", &image, &dir]));
+                }
+            }
+
+            run(mtools("mcopy")
+                .args(["-o", "-i", &image])
+                .arg(src)
+                .arg(format!("::/{}", dest)))?;
+        }
+
+        Ok(DiskImage { path })
+    }
+
+    /// Path of the image file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// An mtools command that does not insist on a sane disk geometry
+fn mtools(tool: &str) -> Command {
+    let mut cmd = Command::new(tool);
+    cmd.env("MTOOLS_SKIP_CHECK", "1");
+    cmd
+}
+
+/// cbfstool binary from `CRABEFI_CBFSTOOL`
+fn cbfstool_binary() -> String {
+    std::env::var("CRABEFI_CBFSTOOL").unwrap_or_else(|_| "cbfstool".into())
+}
+
+/// QEMU binary from `CRABEFI_QEMU`
+fn qemu_binary() -> String {
+    std::env::var("CRABEFI_QEMU").unwrap_or_else(|_| "qemu-system-x86_64".into())
+}
+
+/// QEMU invocation builder
+pub struct Qemu {
+    cmd: Command,
+}
+
+impl Qemu {
+    /// Create a headless QEMU that boots `rom` and exits instead of rebooting
+    pub fn new(rom: &Path) -> Self {
+        let mut cmd = Command::new(qemu_binary());
+        cmd.arg("-bios")
+            .arg(rom)
+            .args(["-m", "512M", "-serial", "stdio", "-display", "none"])
+            .args(["-monitor", "none", "-no-reboot"]);
+
+        if File::options()
+            .read(true)
+            .write(true)
+            .open("/dev/kvm")
+            .is_ok()
+        {
+            cmd.args(["-enable-kvm", "-cpu", "host"]);
+        } else {
+            cmd.args(["-cpu", "qemu64"]);
+        }
+
+        Qemu { cmd }
+    }
+
+    /// Attach a raw disk image as an NVMe drive
+    pub fn nvme_disk(mut self, image: &Path) -> Self {
+        let mut drive = std::ffi::OsString::from("file=");
+        drive.push(image);
+        drive.push(",if=none,id=nvme0,format=raw");
+        self.cmd
+            .arg("-drive")
+            .arg(drive)
+            .args(["-device", "nvme,serial=deadbeef,drive=nvme0"]);
+        self
+    }
+
+    /// Add raw QEMU arguments
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        self.cmd.args(args);
+        self
+    }
+
+    /// Start QEMU and begin capturing the serial console
+    pub fn spawn(mut self) -> Result<QemuRun> {
+        let mut child = self
+            .cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| format!("failed to start QEMU: {}", e))?;
+
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let serial = Arc::new(Mutex::new(String::new()));
+        let sink = serial.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok(n) = stdout.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                sink.lock()
+                    .unwrap()
+                    .push_str(&String::from_utf8_lossy(&buf[..n]));
+            }
+        });
+
+        Ok(QemuRun { child, serial })
+    }
+}
+
+/// A running QEMU instance; killed when dropped
+pub struct QemuRun {
+    child: Child,
+    serial: Arc<Mutex<String>>,
+}
+
+impl QemuRun {
+    /// Serial output captured so far
+    pub fn serial(&self) -> String {
+        self.serial.lock().unwrap().clone()
+    }
+
+    /// Wait until `marker` appears on the serial console
+    ///
+    /// Fails if QEMU exits or the timeout expires first. The error contains
+    /// the full serial log.
+    pub fn wait_for(&mut self, marker: &str, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.serial.lock().unwrap().contains(marker) {
+                return Ok(());
+            }
+
+            let exited = self.child.try_wait().ok().flatten();
+            if exited.is_some() || Instant::now() >= deadline {
+                // Give the reader thread a moment to drain the pipe
+                thread::sleep(Duration::from_millis(100));
+                let serial = self.serial();
+                if serial.contains(marker) {
+                    return Ok(());
+                }
+                let reason = match exited {
+                    Some(status) => format!("QEMU exited with {}", status),
+                    None => format!("timed out after {:?}", timeout),
+                };
+                return Err(format!(
+                    "marker {:?} not found ({})\n--- serial log ---\n{}",
+                    marker, reason, serial
+                ));
+            }
+
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Wait for each marker in turn, using the default timeout
+    pub fn expect_all(&mut self, markers: &[&str]) -> Result<()> {
+        let timeout = timeout();
+        markers
+            .iter()
+            .try_for_each(|marker| self.wait_for(marker, timeout))
+    }
+}
+
+impl Drop for QemuRun {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
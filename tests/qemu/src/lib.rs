@@ -0,0 +1,36 @@
+//! QEMU integration test harness for CrabEFI
+//!
+//! This crate builds the firmware and the test EFI applications, creates
+//! GPT/FAT disk images, puts CrabEFI into a coreboot ROM as its payload and
+//! boots it in QEMU, capturing the serial console so tests can assert on
+//! markers in the boot log.
+//!
+//! # Requirements
+//!
+//! - A coreboot ROM for QEMU q35 or i440fx, given by `CRABEFI_COREBOOT_ROM`.
+//!   Its payload is replaced with the freshly built CrabEFI. Tests are skipped
+//!   if this is not set.
+//! - `cbfstool` from the coreboot tree (override with `CRABEFI_CBFSTOOL`)
+//! - `qemu-system-x86_64` (override with `CRABEFI_QEMU`)
+//! - `parted` and mtools (`mformat`, `mmd`, `mcopy`); no root is needed
+//!
+//! `CRABEFI_QEMU_TIMEOUT` sets how many seconds to wait for a marker
+//! (default: 60).
+//!
+//! # Running
+//!
+//! ```sh
+//! CRABEFI_COREBOOT_ROM=~/src/coreboot/build/coreboot.rom cargo qemu-test
+//! ```
+//!
+//! The firmware crate is `no_std` and builds for `x86_64-unknown-none` by
+//! default, so this crate is empty for that target and the `qemu-test` alias
+//! builds it for the host instead.
+
+#![cfg_attr(target_os = "none", no_std)]
+
+#[cfg(not(target_os = "none"))]
+mod harness;
+
+#[cfg(not(target_os = "none"))]
+pub use harness::*;
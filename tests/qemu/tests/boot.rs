@@ -0,0 +1,106 @@
+//! Boot tests: CrabEFI on coreboot in QEMU, booting from an NVMe ESP
+
+use crabefi_qemu_tests::{DiskImage, TestEnv, build_efi_app};
+
+/// Removable media boot path on x86_64
+const BOOT_PATH: &str = "EFI/BOOT/BOOTX64.EFI";
+
+/// Where CrabEFI looks for the self-test application
+const SELFTEST_PATH: &str = "EFI/CRABEFI/SELFTEST.EFI";
+
+/// Firmware initializes and installs its protocols
+#[test]
+fn protocols_installed() {
+    let Some(env) = TestEnv::get() else {
+        return;
+    };
+
+    let app = build_efi_app("hello", "hello-efi").unwrap();
+    let disk = DiskImage::create("protocols.img", &[(BOOT_PATH, &app)]).unwrap();
+    let mut qemu = env.boot(&disk).unwrap();
+
+    qemu.expect_all(&[
+        "Console protocols installed",
+        "Unicode Collation protocols installed",
+        "Memory Attribute protocol installed",
+        "Serial IO protocol installed",
+        "Console Control protocol installed",
+        "EFI environment initialized",
+        "CrabEFI initialized successfully!",
+    ])
+    .unwrap();
+}
+
+/// The default boot entry is found and an EFI application runs to completion
+#[test]
+fn hello_app_runs() {
+    let Some(env) = TestEnv::get() else {
+        return;
+    };
+
+    let app = build_efi_app("hello", "hello-efi").unwrap();
+    let disk = DiskImage::create("hello.img", &[(BOOT_PATH, &app)]).unwrap();
+    let mut qemu = env.boot(&disk).unwrap();
+
+    qemu.expect_all(&[
+        "Booting:",
+        "Hello from CrabEFI!",
+        "EFI app executed successfully!",
+        "BS.StartImage: Image returned with status: Status(0)",
+    ])
+    .unwrap();
+}
+
+/// An OS loader can fetch the memory map and exit boot services
+#[test]
+fn exit_boot_services_reached() {
+    let Some(env) = TestEnv::get() else {
+        return;
+    };
+
+    let app = build_efi_app("exit-boot-services", "exit-boot-services-efi").unwrap();
+    let disk = DiskImage::create("exit-boot-services.img", &[(BOOT_PATH, &app)]).unwrap();
+    let mut qemu = env.boot(&disk).unwrap();
+
+    qemu.expect_all(&[
+        "Calling ExitBootServices",
+        "ExitBootServices SUCCESS - transitioning to OS",
+    ])
+    .unwrap();
+}
+
+/// The self-test application is offered as a diagnostics entry next to the
+/// default bootloader, which still boots by default
+#[test]
+fn diagnostics_entry_listed() {
+    let Some(env) = TestEnv::get() else {
+        return;
+    };
+
+    let hello = build_efi_app("hello", "hello-efi").unwrap();
+    let selftest = build_efi_app("selftest", "selftest").unwrap();
+    let disk = DiskImage::create(
+        "diagnostics.img",
+        &[(BOOT_PATH, &hello), (SELFTEST_PATH, &selftest)],
+    )
+    .unwrap();
+    let mut qemu = env.boot(&disk).unwrap();
+
+    qemu.expect_all(&["CrabEFI Diagnostics", "Hello from CrabEFI!"])
+        .unwrap();
+}
+
+/// The self-test application runs all of its tests and reports a summary
+#[test]
+fn selftest_runs() {
+    let Some(env) = TestEnv::get() else {
+        return;
+    };
+
+    let app = build_efi_app("selftest", "selftest").unwrap();
+    let disk = DiskImage::create("selftest.img", &[(BOOT_PATH, &app)]).unwrap();
+    let mut qemu = env.boot(&disk).unwrap();
+
+    qemu.expect_all(&["CrabEFI Self-Test", "Self-test finished:"])
+        .unwrap();
+}